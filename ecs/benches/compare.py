@@ -0,0 +1,74 @@
+#!/usr/bin/env python3
+"""Compare the last `cargo bench -p ecs` run against the checked in baseline.
+
+usage: compare.py [--update] [--threshold PERCENT] [--target DIR]
+
+Reads criterion's estimates from target/criterion, prints every benchmark's change relative to
+benches/baseline.json and warns about regressions over the threshold (10% by default). With
+--update, the baseline is overwritten with the last run instead.
+"""
+
+import argparse
+import json
+import sys
+from pathlib import Path
+
+HERE = Path(__file__).resolve().parent
+BASELINE = HERE / "baseline.json"
+
+
+def last_run(criterion_dir):
+    """Mean time (ns) of every benchmark of the last run, keyed by benchmark id"""
+    results = {}
+    for estimates in criterion_dir.glob("**/new/estimates.json"):
+        # benchmark.json holds the full id, directory names are sanitized
+        with open(estimates.parent / "benchmark.json") as f:
+            bench_id = json.load(f)["full_id"]
+        with open(estimates) as f:
+            results[bench_id] = json.load(f)["mean"]["point_estimate"]
+    return dict(sorted(results.items()))
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--update", action="store_true", help="overwrite the baseline")
+    parser.add_argument("--threshold", type=float, default=10.0, help="regression threshold (%%)")
+    parser.add_argument("--target", type=Path, default=HERE.parent.parent / "target")
+    args = parser.parse_args()
+
+    results = last_run(args.target / "criterion")
+    if not results:
+        sys.exit("No benchmark results found, run `cargo bench -p ecs` first")
+
+    if args.update:
+        with open(BASELINE, "w") as f:
+            json.dump(results, f, indent=4)
+            f.write("\n")
+        print(f"Updated baseline with {len(results)} benchmarks")
+        return
+
+    with open(BASELINE) as f:
+        baseline = json.load(f)
+
+    regressions = 0
+    width = max(map(len, results))
+    for bench_id, time in results.items():
+        if bench_id not in baseline:
+            print(f"{bench_id:<{width}}  {time:>14.1f} ns  (new)")
+            continue
+        change = (time - baseline[bench_id]) / baseline[bench_id] * 100
+        flag = ""
+        if change > args.threshold:
+            flag = "  <- REGRESSION"
+            regressions += 1
+        print(f"{bench_id:<{width}}  {time:>14.1f} ns  {change:+7.1f}%{flag}")
+
+    for bench_id in baseline.keys() - results.keys():
+        print(f"{bench_id:<{width}}  missing from the last run")
+
+    if regressions:
+        print(f"\nwarning: {regressions} benchmark(s) regressed by more than {args.threshold}%")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,249 @@
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use ecs::{Entity, Executor, World};
+
+mod harness;
+
+use harness::{Harness, Position, Velocity};
+
+const ENTITIES: usize = 100_000;
+
+fn spawn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("spawn");
+    group.throughput(Throughput::Elements(ENTITIES as u64));
+    group.sample_size(20);
+    for archetypes in [1, 8, 64] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(archetypes),
+            &archetypes,
+            |b, &archetypes| {
+                b.iter_batched(
+                    World::new,
+                    |mut world| {
+                        Harness::spawn_spread(&mut world, ENTITIES, archetypes);
+                        world
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.bench_function("spawn_many", |b| {
+        b.iter_batched(
+            World::new,
+            |mut world| {
+                world.spawn_many(
+                    (0..ENTITIES).map(|_| (Position::default(), Velocity::default())),
+                );
+                world
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn iterate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("iterate");
+    group.throughput(Throughput::Elements(ENTITIES as u64));
+    for archetypes in [1, 64] {
+        let world = Harness::world(ENTITIES, archetypes);
+        group.bench_function(BenchmarkId::new("dense", archetypes), |b| {
+            b.iter(|| {
+                let mut sum = 0.0;
+                for (p, v) in world.query::<(&Position, &Velocity)>() {
+                    sum += p.0[0] * v.0[1];
+                }
+                black_box(sum)
+            })
+        });
+    }
+    let world = Harness::sparse_world(ENTITIES);
+    group.bench_function("option", |b| {
+        b.iter(|| {
+            let mut sum = 0.0;
+            for (p, v) in world.query::<(&Position, Option<&Velocity>)>() {
+                sum += p.0[0] * v.map(|v| v.0[1]).unwrap_or(1.0);
+            }
+            black_box(sum)
+        })
+    });
+    let world = Harness::world(ENTITIES, 1);
+    group.bench_function("entity", |b| {
+        b.iter(|| {
+            for (e, p) in world.query::<(Entity, &Position)>() {
+                black_box((e, p));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn mutate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mutate");
+    group.throughput(Throughput::Elements(ENTITIES as u64));
+    for archetypes in [1, 64] {
+        let world = Harness::world(ENTITIES, archetypes);
+        group.bench_function(BenchmarkId::from_parameter(archetypes), |b| {
+            b.iter(|| {
+                for (p, v) in world.query::<(&mut Position, &Velocity)>() {
+                    p.0[0] += v.0[0];
+                    p.0[1] += v.0[1];
+                    p.0[2] += v.0[2];
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bulk(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk");
+    group.throughput(Throughput::Elements(ENTITIES as u64));
+    let mut executor = Executor::new();
+    for archetypes in [1, 64] {
+        let mut world = Harness::world(ENTITIES, archetypes);
+        group.bench_function(BenchmarkId::new("query", archetypes), |b| {
+            b.iter(|| {
+                for v in world.query::<&mut Velocity>() {
+                    v.0[1] -= 0.01;
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("for_each", archetypes), |b| {
+            b.iter(|| world.for_each_mut(|v: &mut Velocity| v.0[1] -= 0.01))
+        });
+        group.bench_function(BenchmarkId::new("par_for_each", archetypes), |b| {
+            b.iter(|| world.par_for_each_mut(&mut executor, |v: &mut Velocity| v.0[1] -= 0.01))
+        });
+        group.bench_function(BenchmarkId::new("pair_query", archetypes), |b| {
+            b.iter(|| {
+                for (p, v) in world.query::<(&mut Position, &Velocity)>() {
+                    p.0[0] += v.0[0];
+                    p.0[1] += v.0[1];
+                    p.0[2] += v.0[2];
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("pair_for_each", archetypes), |b| {
+            b.iter(|| {
+                world.for_each_pair_mut(|p: &mut Position, v: &mut Velocity| {
+                    p.0[0] += v.0[0];
+                    p.0[1] += v.0[1];
+                    p.0[2] += v.0[2];
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn component_churn(c: &mut Criterion) {
+    const COUNT: usize = 1_000;
+    let mut group = c.benchmark_group("component_churn");
+    group.throughput(Throughput::Elements(COUNT as u64));
+    let mut world = World::new();
+    let entities = world.spawn_many((0..COUNT).map(|_| (Position::default(),)));
+    group.bench_function("add_take", |b| {
+        b.iter(|| {
+            for &e in &entities {
+                world.add_component(e, (Velocity::default(),));
+            }
+            for &e in &entities {
+                black_box(world.take_component::<(Velocity,)>(e));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn archetype_churn(c: &mut Criterion) {
+    const COUNT: usize = 4_096;
+    let churn = |pooled: bool| {
+        move || {
+            let mut world = World::new();
+            if !pooled {
+                world.set_pool_threshold(0);
+            }
+            Harness::spawn_spread(&mut world, COUNT, harness::MARKERS);
+            // remove_many only handles entities next to each other in the same archetype
+            for entity in world.query::<Entity>().collect::<Vec<_>>() {
+                world.remove(entity);
+            }
+            world
+        }
+    };
+    // Criterion doesn't measure allocator calls, so report them here
+    for pooled in [true, false] {
+        let (world, allocations) = harness::count_allocations(churn(pooled));
+        eprintln!(
+            "archetype_churn (pool {}): {allocations} allocator calls, pool hit rate {:.0}%",
+            if pooled { "on" } else { "off" },
+            world.stats().pool.hit_rate() * 100.0,
+        );
+    }
+    let mut group = c.benchmark_group("archetype_churn");
+    group.throughput(Throughput::Elements(COUNT as u64));
+    for pooled in [true, false] {
+        group.bench_function(if pooled { "pooled" } else { "unpooled" }, |b| {
+            b.iter_batched(|| (), |_| churn(pooled)(), BatchSize::SmallInput)
+        });
+    }
+    group.finish();
+}
+
+fn schedule_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("schedule_build");
+    group.sample_size(20);
+    for systems in [10, 50, 200] {
+        for conflicts in [0.0, 0.25, 1.0] {
+            group.bench_function(
+                BenchmarkId::new(format!("conflicts_{conflicts}"), systems),
+                |b| {
+                    b.iter_batched(
+                        Harness::executor,
+                        |mut executor| {
+                            let schedule = Harness::systems(executor.schedule(), systems, conflicts)
+                                .build();
+                            (executor, schedule)
+                        },
+                        BatchSize::SmallInput,
+                    )
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn execute(c: &mut Criterion) {
+    let mut group = c.benchmark_group("execute");
+    for systems in [10, 50, 200] {
+        // Throughput in systems, to get the per system fixed cost
+        group.throughput(Throughput::Elements(systems as u64));
+        for conflicts in [0.0, 1.0] {
+            let mut world = World::new();
+            let mut executor = Harness::executor();
+            let schedule = Harness::systems(executor.schedule(), systems, conflicts).build();
+            group.bench_function(
+                BenchmarkId::new(format!("conflicts_{conflicts}"), systems),
+                |b| b.iter(|| executor.execute(&schedule, &mut world)),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    spawn,
+    iterate,
+    mutate,
+    bulk,
+    component_churn,
+    archetype_churn,
+    schedule_build,
+    execute
+);
+criterion_main!(benches);
@@ -0,0 +1,158 @@
+//! Synthetic worlds and systems shared by the benchmarks
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use ecs::{Executor, Scheduler, World};
+
+/// Global allocator counting the calls to alloc and realloc
+pub struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Allocations and reallocations made by `f`
+pub fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let res = f();
+    (res, ALLOCATIONS.load(Ordering::Relaxed) - before)
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Position(pub [f32; 3]);
+
+#[derive(Clone, Copy, Default)]
+pub struct Velocity(pub [f32; 3]);
+
+/// Marker component, used to spread entities over different archetypes
+pub struct Marker<const N: usize>;
+
+/// Resource used by the synthetic systems
+pub struct Res<const N: usize>(pub u64);
+
+/// Number of distinct markers, and so the maximum number of archetypes the harness can produce
+pub const MARKERS: usize = 64;
+/// Number of resources the non conflicting systems spread their reads over
+pub const RESOURCES: usize = 16;
+
+fn spawn_marked<const N: usize>(world: &mut World, count: usize) {
+    for i in 0..count {
+        let f = i as f32;
+        world.spawn((Position([f, 0.0, 0.0]), Velocity([1.0, f, 0.0]), Marker::<N>));
+    }
+}
+
+fn read_system<const N: usize>(res: &Res<N>) {
+    criterion::black_box(res.0);
+}
+
+fn write_system<const N: usize>(res: &mut Res<N>) {
+    res.0 = res.0.wrapping_add(1);
+}
+
+fn add_reader<const N: usize>(scheduler: Scheduler) -> Scheduler {
+    scheduler.then(read_system::<N>)
+}
+
+type Spawner = fn(&mut World, usize);
+type SystemAdder = for<'a> fn(Scheduler<'a>) -> Scheduler<'a>;
+
+macro_rules! tables {
+    ($($n:literal)*) => {
+        const SPAWNERS: [Spawner; MARKERS] = [$(spawn_marked::<$n>),*];
+    };
+}
+tables!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32 33 34
+    35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+);
+// Res<0> is reserved for the conflicting systems
+const READERS: [SystemAdder; RESOURCES] = [
+    add_reader::<1>,
+    add_reader::<2>,
+    add_reader::<3>,
+    add_reader::<4>,
+    add_reader::<5>,
+    add_reader::<6>,
+    add_reader::<7>,
+    add_reader::<8>,
+    add_reader::<9>,
+    add_reader::<10>,
+    add_reader::<11>,
+    add_reader::<12>,
+    add_reader::<13>,
+    add_reader::<14>,
+    add_reader::<15>,
+    add_reader::<16>,
+];
+
+pub struct Harness;
+
+impl Harness {
+    /// Spawn `count` (Position, Velocity, Marker) entities spread evenly over `archetypes`
+    /// archetypes, entity by entity.
+    pub fn spawn_spread(world: &mut World, count: usize, archetypes: usize) {
+        assert!(archetypes > 0 && archetypes <= MARKERS);
+        for (i, spawn) in SPAWNERS.iter().take(archetypes).enumerate() {
+            // Give the remainder to the first archetypes
+            let n = count / archetypes + usize::from(i < count % archetypes);
+            spawn(world, n);
+        }
+    }
+    /// A world of `count` entities over `archetypes` archetypes
+    pub fn world(count: usize, archetypes: usize) -> World {
+        let mut world = World::new();
+        Self::spawn_spread(&mut world, count, archetypes);
+        world
+    }
+    /// A world where every other entity has no Velocity (for Option queries)
+    pub fn sparse_world(count: usize) -> World {
+        let mut world = World::new();
+        world.spawn_many((0..count / 2).map(|i| (Position([i as f32; 3]), Velocity([1.0; 3]))));
+        world.spawn_many((0..count - count / 2).map(|i| (Position([i as f32; 3]),)));
+        world
+    }
+    /// An executor holding every resource the synthetic systems use
+    pub fn executor() -> Executor {
+        let mut executor = Executor::new();
+        macro_rules! add {
+            ($($n:literal)*) => { $(executor.add_resource(Res::<$n>(0));)* };
+        }
+        add!(0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16);
+        executor
+    }
+    /// Add `count` trivial systems to a scheduler. A `conflicts` fraction of them (evenly spread)
+    /// write to the same resource and so have to run sequentially, the rest only read.
+    pub fn systems(mut scheduler: Scheduler, count: usize, conflicts: f32) -> Scheduler {
+        assert!((0.0..=1.0).contains(&conflicts));
+        let mut acc = 0.0;
+        for i in 0..count {
+            acc += conflicts;
+            scheduler = if acc >= 1.0 {
+                acc -= 1.0;
+                scheduler.then(write_system::<0>)
+            } else {
+                READERS[i % RESOURCES](scheduler)
+            };
+        }
+        scheduler
+    }
+}
@@ -1,21 +1,35 @@
 use std::{
     alloc::{self, Layout},
     any::TypeId,
+    cell::UnsafeCell,
+    cmp::Reverse,
     collections::HashMap,
     mem::MaybeUninit,
-    ops::{Bound, RangeBounds},
+    ops::{Bound, Range, RangeBounds},
     ptr::NonNull,
 };
 
+#[cfg(feature = "codegen")]
 use ecs_macros::impl_archetype;
 
 use crate::{
     bitset::{ArchetypeBitset, ArchetypeBitsetBuilder, ArchetypeBitsetMapping, BitsetBuilder},
+    dynamic::DynamicComponentId,
     entity::LocationMap,
-    query::{Query, QueryIter},
+    pool::{self, SharedPool},
+    query::{Query, QueryIter, QueryTicks},
 };
 
-type DropInPlace = fn(*mut ());
+pub(crate) type DropInPlace = fn(*mut ());
+
+/// The layout of `n` consecutive elements of `layout` (padded to its alignment), and the stride
+/// between them. Same as the unstable `Layout::repeat`, returns None on overflow.
+fn repeat_layout(layout: Layout, n: usize) -> Option<(Layout, usize)> {
+    let stride = layout.pad_to_align().size();
+    let size = stride.checked_mul(n)?;
+    let layout = Layout::from_size_align(size, layout.align()).ok()?;
+    Some((layout, stride))
+}
 
 // Most of the code here is *heavily* inspired by the implementing Vec chapter of the Rustonomicon
 // https://doc.rust-lang.org/nomicon/vec/vec.html
@@ -26,9 +40,51 @@ pub struct ArchetypeStorage {
     capacity: usize,
     length: usize,
     archetype: Archetype,
+    /// Ticks of each entity, written by the queries borrowing components mutably
+    ticks: UnsafeCell<Vec<RowTicks>>,
+    /// Ticks of each component of each entity, for `Changed` and `Added`
+    component_ticks: HashMap<ComponentKey, UnsafeCell<Vec<RowTicks>>>,
+    /// Where the memory comes from, the global allocator if None
+    pool: Option<SharedPool>,
+    /// Size class of the current block, if it comes from the pool
+    class: Option<usize>,
+}
+
+/// When an entity (or one of its components) was added to the world, and last changed (see
+/// `World::tick`). For an entity, any mutable access to any of its components counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RowTicks {
+    pub added: u32,
+    pub changed: u32,
+}
+
+/// What the components of an archetype are keyed by, a rust type or a component registered with
+/// `World::register_dynamic`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ComponentKey {
+    Type(TypeId),
+    Dynamic(DynamicComponentId),
+}
+
+impl ComponentKey {
+    pub fn of<T: 'static>() -> Self {
+        Self::Type(TypeId::of::<T>())
+    }
+}
+
+impl From<TypeId> for ComponentKey {
+    fn from(id: TypeId) -> Self {
+        Self::Type(id)
+    }
 }
 
-#[derive(PartialEq, Eq, Clone)]
+impl From<DynamicComponentId> for ComponentKey {
+    fn from(id: DynamicComponentId) -> Self {
+        Self::Dynamic(id)
+    }
+}
+
+#[derive(Clone)]
 pub struct ComponentType {
     /// The offset from the begining of the entity
     offset: usize,
@@ -38,12 +94,28 @@ pub struct ComponentType {
     size: usize,
     /// The min alignment of the component
     alignment: usize,
+    /// Name of the type, for debugging
+    name: &'static str,
+}
+
+// Comparing the drop fn pointers isn't reliable (the same function can have several addresses),
+// and they only differ if the types do, which the rest of the fields already tell.
+impl PartialEq for ComponentType {
+    fn eq(&self, other: &Self) -> bool {
+        self.offset == other.offset
+            && self.drop.is_some() == other.drop.is_some()
+            && self.size == other.size
+            && self.alignment == other.alignment
+            && self.name == other.name
+    }
 }
 
+impl Eq for ComponentType {}
+
 #[derive(Clone)]
 pub struct Archetype {
     /// Info about each type
-    info: HashMap<TypeId, ComponentType>,
+    info: HashMap<ComponentKey, ComponentType>,
     /// Memory layout of an entity of this archetype
     layout: Layout,
 }
@@ -101,11 +173,43 @@ impl Archetype {
     }
     /// Get the offset of the value of a type in the memory layout of this archetype
     pub fn offset<T: Component>(&self) -> usize {
-        self.info[&TypeId::of::<T>()].offset
+        self.info[&ComponentKey::of::<T>()].offset
     }
     /// Check if the archetype contains a type
     pub fn has<T: Component>(&self) -> bool {
-        self.info.contains_key(&TypeId::of::<T>())
+        self.info.contains_key(&ComponentKey::of::<T>())
+    }
+    /// Offset of a type, if the archetype contains it
+    pub fn offset_of(&self, id: impl Into<ComponentKey>) -> Option<usize> {
+        self.info.get(&id.into()).map(|info| info.offset)
+    }
+    /// Size of a component, if the archetype contains it
+    pub fn size_of(&self, id: impl Into<ComponentKey>) -> Option<usize> {
+        self.info.get(&id.into()).map(|info| info.size)
+    }
+    /// The components of the archetype with the names of their types, in no particular order
+    pub fn components(&self) -> impl Iterator<Item = (ComponentKey, &'static str)> + '_ {
+        self.info.iter().map(|(&id, info)| (id, info.name))
+    }
+    /// An archetype of a single component stored as raw bytes, merged into others to build the
+    /// archetypes of dynamic components
+    pub(crate) fn raw(
+        id: ComponentKey,
+        layout: Layout,
+        drop: Option<DropInPlace>,
+        name: &'static str,
+    ) -> Self {
+        let info = ComponentType {
+            offset: 0,
+            drop,
+            size: layout.size(),
+            alignment: layout.align(),
+            name,
+        };
+        Self {
+            info: HashMap::from([(id, info)]),
+            layout: layout.pad_to_align(),
+        }
     }
     /// Copy the components from a location with this archetype to another location following
     /// another archetype.
@@ -122,34 +226,34 @@ impl Archetype {
             std::ptr::copy(src, dst, src_c.size);
         }
     }
-    /// Merge the archetypes to create a new one. Note that this can create an archetype with a non
-    /// repr(rust) memory layout, that can't be reinterpreted as a rust tuple.
+    /// Merge the archetypes to create a new one. Note that this recomputes the memory layout (see
+    /// `relayout`), which can't be reinterpreted as a rust tuple anymore.
     pub fn merge(&mut self, other: Archetype) {
-        let (layout, offset) = self
-            .layout
-            .extend(other.layout)
-            .expect("Archetype overflow");
-        for (id, mut info) in other.info {
-            info.offset += offset;
-            self.info.insert(id, info);
-        }
-        self.layout = layout;
-    }
-    /// Remove the components of other from self. Note that this will recompute the memory layout
-    /// of the archetype and will not be interpretable as a valid rust tuple anymore (if it was).
+        self.info.extend(other.info);
+        self.relayout();
+    }
+    /// Remove the components of other from self. Note that this recomputes the memory layout (see
+    /// `relayout`), which can't be reinterpreted as a rust tuple anymore.
     pub fn subtract(&mut self, other: Archetype) {
         for id in other.info.keys() {
             self.info.remove(id);
         }
-        // recompute memory layout
-        self.layout = Layout::from_size_align(0, 1).unwrap();
-        for info in self.info.values_mut() {
+        self.relayout();
+    }
+    /// Place the components by decreasing alignment then by key, so that the layout only depends
+    /// on the set of components and not on the merges and subtractions that led to it
+    fn relayout(&mut self) {
+        let mut components = self.info.iter_mut().collect::<Vec<_>>();
+        components.sort_unstable_by_key(|(&id, info)| (Reverse(info.alignment), id));
+        let mut layout = Layout::from_size_align(0, 1).unwrap();
+        for (_, info) in components {
             let field = Layout::from_size_align(info.size, info.alignment).unwrap();
-            let (new_layout, offset) = self.layout.extend(field).unwrap();
+            let (extended, offset) = layout.extend(field).expect("Archetype overflow");
             info.offset = offset;
-            self.layout = new_layout;
+            layout = extended;
         }
-        self.layout = self.layout.pad_to_align();
+        // Padded like a tuple, the size is the stride between entities
+        self.layout = layout.pad_to_align();
     }
     /// returns the size of an element of the archetype
     pub fn size(&self) -> usize {
@@ -166,13 +270,28 @@ impl ArchetypeStorage {
         // If size is 0, no allocation is needed, so we set capacity to the max:
         // The allocated bytes (none) is enough to hold an infinity of elements
         let capacity = if archetype.is_zst() { !0 } else { 0 };
+        let component_ticks = archetype
+            .info
+            .keys()
+            .map(|&id| (id, UnsafeCell::new(Vec::new())))
+            .collect();
         Self {
             archetype,
+            component_ticks,
             data: NonNull::dangling(),
             capacity,
             length: 0,
+            ticks: UnsafeCell::new(Vec::new()),
+            pool: None,
+            class: None,
         }
     }
+    /// Allocate from a pool instead of the global allocator, before anything is pushed
+    pub(crate) fn in_pool(mut self, pool: SharedPool) -> Self {
+        debug_assert!(self.length == 0);
+        self.pool = Some(pool);
+        self
+    }
     #[inline(always)]
     unsafe fn get_ptr_mut_unchecked(&mut self, index: usize) -> *mut u8 {
         self.data.as_ptr().add(self.archetype.layout.size() * index)
@@ -203,23 +322,108 @@ impl ArchetypeStorage {
         }
 
         self.length += 1;
+        self.ticks.get_mut().push(RowTicks::default());
+        for ticks in self.component_ticks.values_mut() {
+            ticks.get_mut().push(RowTicks::default());
+        }
+    }
+    /// Push an entity from the bytes of its components, which must be every component of the
+    /// archetype with their exact size
+    pub(crate) unsafe fn push_raw(&mut self, components: &[(ComponentKey, &[u8])]) {
+        if self.capacity == self.length {
+            self.grow(self.capacity + 1);
+        }
+
+        let slot = self.get_ptr_mut_unchecked(self.length);
+        for (id, bytes) in components {
+            let offset = self.archetype.offset_of(*id).unwrap();
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), slot.add(offset), bytes.len());
+        }
+
+        self.length += 1;
+        self.ticks.get_mut().push(RowTicks::default());
+        for ticks in self.component_ticks.values_mut() {
+            ticks.get_mut().push(RowTicks::default());
+        }
     }
     /// Push multiple entities, optimized for allocations where possible
     pub fn extend<T: IntoArchetype>(&mut self, values: impl IntoIterator<Item = T>) {
         let iter = values.into_iter();
-        let hint = iter.size_hint().1;
-        if let Some(len) = hint {
-            if self.capacity < self.length + len {
-                self.grow(self.capacity + len);
-            }
-        }
+        let (lower, upper) = iter.size_hint();
+        self.reserve(upper.unwrap_or(lower));
         for value in iter {
             self.push(value);
         }
     }
+    /// Push entities from an iterator of known length, allocating once. Items past the reported
+    /// length are ignored.
+    pub fn extend_exact<T: IntoArchetype>(&mut self, values: impl ExactSizeIterator<Item = T>) {
+        let len = values.len();
+        self.reserve(len);
+        let end = self.length + len;
+        // Filled first, a panicking iterator leaves extra ticks rather than rows without any
+        self.ticks.get_mut().resize(end, RowTicks::default());
+        for ticks in self.component_ticks.values_mut() {
+            ticks.get_mut().resize(end, RowTicks::default());
+        }
+        for value in values.take(len) {
+            unsafe {
+                let slot = self.get_ptr_mut_unchecked(self.length);
+                value.write(slot, &self.archetype);
+            }
+            self.length += 1;
+        }
+        self.ticks.get_mut().truncate(self.length);
+        for ticks in self.component_ticks.values_mut() {
+            ticks.get_mut().truncate(self.length);
+        }
+    }
+    /// Make room for at least `additional` more entities
+    pub fn reserve(&mut self, additional: usize) {
+        if self.capacity - self.length < additional {
+            let needed = self
+                .length
+                .checked_add(additional)
+                .expect("ArchetypeStorage overflow");
+            self.grow(needed);
+        }
+    }
+    /// Release the memory past the entities stored (down to the size class of the pool if the
+    /// storage allocates from one)
+    pub fn shrink_to_fit(&mut self) {
+        // Zero sized archetypes never allocate
+        if self.archetype.is_zst() || self.capacity == self.length {
+            return;
+        }
+        let (data, capacity, class) = (self.data, self.capacity, self.class);
+        self.data = NonNull::dangling();
+        self.capacity = 0;
+        self.class = None;
+        if self.length > 0 {
+            // From nothing, grow doesn't copy or free the old block
+            self.grow(self.length);
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    data.as_ptr(),
+                    self.data.as_ptr(),
+                    self.length * self.archetype.size(),
+                );
+            }
+        }
+        unsafe { self.dealloc(data, capacity, class) };
+        self.ticks.get_mut().shrink_to_fit();
+        for ticks in self.component_ticks.values_mut() {
+            ticks.get_mut().shrink_to_fit();
+        }
+    }
     /// Fill the gap the vector from index start and for length element and set the new length
     #[inline(always)]
     fn fill_gap(&mut self, start: usize, length: usize) {
+        debug_assert!(
+            start + length <= self.length,
+            "fill_gap out of bounds ({start} + {length} > {})",
+            self.length
+        );
         // If the archetype is zero sized there is no allocation, so no gap
         if start + length < self.length && !self.archetype.is_zst() {
             let copy_to = self.get_ptr_mut(start);
@@ -230,6 +434,10 @@ impl ArchetypeStorage {
             }
         }
         self.length -= length;
+        self.ticks.get_mut().drain(start..start + length);
+        for ticks in self.component_ticks.values_mut() {
+            ticks.get_mut().drain(start..start + length);
+        }
     }
     /// Remove and drop and entity from the array
     pub fn remove(&mut self, index: usize) {
@@ -242,13 +450,13 @@ impl ArchetypeStorage {
         // start index (inclusive)
         let start = match bounds.start_bound() {
             Bound::Unbounded => 0,
-            Bound::Included(&i) => i.min(self.length - 1),
-            Bound::Excluded(e) => (e + 1).min(self.length - 1),
+            Bound::Included(&i) => i.min(self.length),
+            Bound::Excluded(&e) => e.saturating_add(1).min(self.length),
         };
         // end index, exclusive
         let end = match bounds.end_bound() {
             Bound::Unbounded => self.length,
-            Bound::Included(i) => i.saturating_sub(1).min(self.length),
+            Bound::Included(&i) => i.saturating_add(1).min(self.length),
             Bound::Excluded(&e) => e.min(self.length),
         };
         for i in start..end {
@@ -276,6 +484,16 @@ impl ArchetypeStorage {
             &other.archetype,
         );
         other.length += 1;
+        let ticks = self.ticks.get_mut()[index];
+        other.ticks.get_mut().push(ticks);
+        // Components the destination adds get their ticks from the caller
+        for (id, ticks) in &mut other.component_ticks {
+            let old = self
+                .component_ticks
+                .get_mut(id)
+                .map(|old| old.get_mut()[index]);
+            ticks.get_mut().push(old.unwrap_or_default());
+        }
         self.fill_gap(index, 1);
         new_index
     }
@@ -290,15 +508,98 @@ impl ArchetypeStorage {
         T::read(self.get_ptr(index), &self.archetype)
     }
     /// Take an entity and return it, the archetype needs to matche the storage's
+    ///
+    /// The slot is considered moved out before the read starts: if `T::read` panics, the
+    /// components that weren't read yet are leaked instead of being dropped a second time when the
+    /// storage is.
     pub fn take<T: IntoArchetype>(&mut self, index: usize) -> T {
+        /// Fills the gap left by the taken entity, even on unwind
+        struct Gap<'a> {
+            storage: &'a mut ArchetypeStorage,
+            index: usize,
+        }
+
+        impl Drop for Gap<'_> {
+            fn drop(&mut self) {
+                self.storage.fill_gap(self.index, 1);
+            }
+        }
+
         let ptr = self.get_ptr_mut(index);
-        let value = unsafe { T::read(ptr, &self.archetype) };
-        self.fill_gap(index, 1);
+        let gap = Gap {
+            storage: self,
+            index,
+        };
+        let value = unsafe { T::read(ptr, &gap.storage.archetype) };
+        drop(gap);
         value
     }
     pub fn len(&self) -> usize {
         self.length
     }
+    /// Ticks of the entities, this must not be called while a query mutably borrowing the
+    /// storage is alive.
+    pub fn ticks(&self) -> &[RowTicks] {
+        unsafe { &*self.ticks.get() }
+    }
+    /// Ticks of a component of the entities, None if the archetype doesn't have it. Same
+    /// restrictions as `ticks`.
+    pub fn component_ticks(&self, id: TypeId) -> Option<&[RowTicks]> {
+        self.component_ticks
+            .get(&id.into())
+            .map(|ticks| unsafe { &*ticks.get() } as &[_])
+    }
+    /// Set the ticks of a range of entities, and of all their components
+    pub fn set_ticks(&mut self, rows: Range<usize>, ticks: RowTicks) {
+        self.ticks.get_mut()[rows.clone()].fill(ticks);
+        for column in self.component_ticks.values_mut() {
+            column.get_mut()[rows.clone()].fill(ticks);
+        }
+    }
+    /// Mark an entity as changed at tick
+    pub fn mark_changed(&mut self, row: usize, tick: u32) {
+        self.ticks.get_mut()[row].changed = tick;
+    }
+    /// Mark a component of an entity (and so the entity) as changed at tick
+    pub fn mark_component_changed(&mut self, row: usize, id: impl Into<ComponentKey>, tick: u32) {
+        self.mark_changed(row, tick);
+        if let Some(ticks) = self.component_ticks.get_mut(&id.into()) {
+            ticks.get_mut()[row].changed = tick;
+        }
+    }
+    /// Mark components of an entity (and so the entity) as added at tick
+    pub fn mark_components_added(&mut self, row: usize, ids: &[TypeId], tick: u32) {
+        self.mark_changed(row, tick);
+        for id in ids {
+            if let Some(ticks) = self.component_ticks.get_mut(&(*id).into()) {
+                ticks.get_mut()[row] = RowTicks {
+                    added: tick,
+                    changed: tick,
+                };
+            }
+        }
+    }
+    /// Pointer to the first row and stride between rows, to walk the rows directly. Only valid
+    /// until the storage grows or is dropped.
+    pub fn raw_rows(&mut self) -> (*mut u8, usize) {
+        (self.data.as_ptr(), self.archetype.layout.size())
+    }
+    /// Mark a component of every entity (and so every entity) as changed at tick
+    pub fn mark_all_changed(&mut self, id: TypeId, tick: u32) {
+        for ticks in self.ticks.get_mut() {
+            ticks.changed = tick;
+        }
+        if let Some(ticks) = self.component_ticks.get_mut(&id.into()) {
+            for ticks in ticks.get_mut() {
+                ticks.changed = tick;
+            }
+        }
+    }
+    /// Pointer to a component of an entity, if the archetype has it
+    pub fn component_ptr(&self, row: usize, id: impl Into<ComponentKey>) -> Option<*mut u8> {
+        let offset = self.archetype.offset_of(id)?;
+        Some(unsafe { (self.get_ptr(row) as *mut u8).add(offset) })
+    }
     /// Get a slice of the entities, the archetypes must exactly match
     pub fn as_slice<T: IntoArchetype>(&self) -> &[T] {
         if !T::into_archetype().exact_match(&self.archetype) {
@@ -308,17 +609,43 @@ impl ArchetypeStorage {
     }
     /// Create an QueryIter of this storage, this doesn't have any memory safety checks and will
     /// break if used after drop of this storage, or if used concurently.
+    ///
+    /// Entities yielded by queries with mutable borrows are marked as changed at tick, along with
+    /// the components borrowed. `Changed` and `Added` compare the ticks of components to since.
     pub unsafe fn iter_query<Q: Query>(
         &self,
         index: usize,
         location_map: Option<&LocationMap>,
+        tick: u32,
+        since: u32,
     ) -> QueryIter<Q> {
+        let column = |id| {
+            self.component_ticks
+                .get(&ComponentKey::Type(id))
+                .map(|ticks| (*ticks.get()).as_mut_ptr())
+        };
+        let mut columns = Vec::new();
+        let mut filters = Vec::new();
+        for access in Q::access() {
+            match (column(access.ty), access.filter) {
+                (Some(ticks), Some(filter)) => filters.push((ticks as *const RowTicks, filter)),
+                (Some(ticks), None) if access.mutable => columns.push(ticks),
+                _ => {}
+            }
+        }
         QueryIter::new(
             self.data,
             self.length,
             &self.archetype as *const Archetype,
             index,
             location_map.map(|v| v as *const LocationMap),
+            QueryTicks {
+                rows: (*self.ticks.get()).as_mut_ptr(),
+                columns,
+                filters,
+                tick,
+                since,
+            },
         )
     }
     /// Get the archetype of this storage
@@ -335,19 +662,82 @@ impl ArchetypeStorage {
             .max(new_cap);
 
         // The offset is always just self.entity_layout.size(), so we ignore it
-        let (layout, _) = self
-            .archetype
-            .layout
-            .repeat(new_cap)
-            .expect("ArchetypeStorage overflow");
+        let (layout, _) =
+            repeat_layout(self.archetype.layout, new_cap).expect("ArchetypeStorage overflow");
 
+        if let Some(pool) = &self.pool {
+            let mut pool = pool.lock();
+            let (ptr, capacity, class) = match pool.alloc(layout) {
+                // The whole block is usable
+                Some((ptr, class)) => (
+                    ptr,
+                    pool::class_size(class) / self.archetype.size(),
+                    Some(class),
+                ),
+                None => match self.class {
+                    // Outgrew the pool, the old block can't be reallocated
+                    Some(_) => {
+                        let ptr = NonNull::new(unsafe { alloc::alloc(layout) })
+                            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+                        (ptr, new_cap, None)
+                    }
+                    None => {
+                        drop(pool);
+                        return self.realloc(layout, new_cap);
+                    }
+                },
+            };
+            if self.capacity > 0 {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        self.data.as_ptr(),
+                        ptr.as_ptr(),
+                        self.length * self.archetype.size(),
+                    );
+                    match self.class {
+                        Some(old) => pool.free(self.data, old),
+                        // Allocated before the threshold was raised
+                        None => {
+                            let (old_layout, _) =
+                                repeat_layout(self.archetype.layout, self.capacity).unwrap();
+                            alloc::dealloc(self.data.as_ptr(), old_layout);
+                        }
+                    }
+                }
+            }
+            self.data = ptr;
+            self.capacity = capacity;
+            self.class = class;
+        } else {
+            self.realloc(layout, new_cap);
+        }
+    }
+    /// Free a block of the storage, to the pool if it came from it
+    ///
+    /// # Safety
+    ///
+    /// The block must have been allocated by the storage with that capacity (and class), and not
+    /// be used after
+    unsafe fn dealloc(&self, data: NonNull<u8>, capacity: usize, class: Option<usize>) {
+        if capacity == 0 || self.archetype.is_zst() {
+            return;
+        }
+        match (&self.pool, class) {
+            (Some(pool), Some(class)) => pool.lock().free(data, class),
+            _ => {
+                let (layout, _) = repeat_layout(self.archetype.layout, capacity).unwrap();
+                alloc::dealloc(data.as_ptr(), layout);
+            }
+        }
+    }
+    /// Grow with the global allocator
+    fn realloc(&mut self, layout: Layout, new_cap: usize) {
         let ptr = if self.capacity == 0 {
             // We haven't allocated yet
-            self.capacity = new_cap;
             unsafe { alloc::alloc(layout) }
         } else {
             // We need to reallocated
-            let (old_layout, _) = self.archetype.layout.repeat(self.capacity).unwrap();
+            let (old_layout, _) = repeat_layout(self.archetype.layout, self.capacity).unwrap();
             unsafe { alloc::realloc(self.data.as_ptr(), old_layout, layout.size()) }
         };
 
@@ -362,13 +752,7 @@ impl ArchetypeStorage {
 impl Drop for ArchetypeStorage {
     fn drop(&mut self) {
         self.clear(..);
-        // dealloc memory
-        if self.capacity > 0 && !self.archetype.is_zst() {
-            unsafe {
-                let layout = self.archetype.layout.repeat(self.capacity).unwrap().0;
-                alloc::dealloc(self.data.as_ptr(), layout);
-            }
-        }
+        unsafe { self.dealloc(self.data, self.capacity, self.class) };
     }
 }
 
@@ -397,19 +781,114 @@ pub trait IntoArchetype {
 pub trait Component: 'static + Send {}
 impl<T: 'static + Send> Component for T {}
 
-// Implement IntoArchetype for generic tuples of length 0 to 16
-// see ecs_macros for implementation
-#[cfg(not(feature = "extended_limits"))]
-impl_archetype!(16);
+// Implement IntoArchetype for generic tuples, mirrors ecs_macros::impl_archetype
+macro_rules! impl_archetype_tuple {
+    ($($t:ident $i:tt),*) => {
+        #[allow(unused_variables, unused_mut, unused_unsafe)]
+        impl<$($t: Component),*> IntoArchetype for ($($t,)*) {
+            fn into_archetype() -> Archetype {
+                let layout = Layout::new::<Self>();
+                let mut info = HashMap::with_capacity(count!($($t)*));
+                unsafe {
+                    let val = MaybeUninit::<Self>::uninit();
+                    $(
+                        info.insert(ComponentKey::of::<$t>(), ComponentType {
+                            offset: std::ptr::addr_of!((*val.as_ptr()).$i) as usize
+                                - val.as_ptr() as usize,
+                            drop: match std::mem::needs_drop::<$t>() {
+                                true => Some(get_drop::<$t>()),
+                                false => None,
+                            },
+                            size: std::mem::size_of::<$t>(),
+                            alignment: std::mem::align_of::<$t>(),
+                            name: std::any::type_name::<$t>(),
+                        });
+                    )*
+                }
+                Archetype { info, layout }
+            }
+            fn match_archetype(archetype: &Archetype) -> bool {
+                archetype.info.len() == count!($($t)*) && Self::archetype_contains(archetype)
+            }
+            fn archetype_contains(archetype: &Archetype) -> bool {
+                true $(&& archetype.has::<$t>())*
+            }
+            fn bitset(mapping: &ArchetypeBitsetMapping) -> Option<ArchetypeBitset> {
+                ArchetypeBitsetBuilder::start(mapping)
+                    $(.add::<$t>())*
+                    .build()
+            }
+            unsafe fn write(self, dst: *mut u8, archetype: &Archetype) {
+                #[cfg(debug_assertions)]
+                if !Self::archetype_contains(archetype) {
+                    panic!("Archetypes do not match");
+                }
+                // Partial moves of every field -> self is completely moved
+                $(std::ptr::write(dst.add(archetype.offset::<$t>()) as *mut $t, self.$i);)*
+            }
+            unsafe fn read(src: *const u8, archetype: &Archetype) -> Self {
+                #[cfg(debug_assertions)]
+                if !Self::archetype_contains(archetype) {
+                    panic!("Archetypes do not match");
+                }
+                let mut value = MaybeUninit::<Self>::uninit();
+                $(std::ptr::copy(
+                    src.add(archetype.offset::<$t>()) as *const $t,
+                    &mut (*value.as_mut_ptr()).$i as *mut $t,
+                    1,
+                );)*
+                value.assume_init()
+            }
+            fn types() -> Vec<TypeId> {
+                vec![$(TypeId::of::<$t>()),*]
+            }
+        }
+    };
+}
+
+// Tuples of length 0 to 8 are implemented here, larger ones are generated by ecs_macros (see the
+// codegen feature).
+for_tuples!(impl_archetype_tuple; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+#[cfg(all(feature = "codegen", not(feature = "extended_limits")))]
+impl_archetype!(9..=16);
 #[cfg(feature = "extended_limits")]
-impl_archetype!(24);
+impl_archetype!(9..=24);
 
 #[cfg(test)]
 mod tests {
-    use std::sync::atomic::AtomicU8;
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        sync::atomic::{AtomicU8, AtomicUsize, Ordering::SeqCst},
+    };
 
     use super::*;
 
+    /// Counts its drops in the referenced counter
+    struct Counted(&'static AtomicUsize);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, SeqCst);
+        }
+    }
+
+    fn counter() -> &'static AtomicUsize {
+        Box::leak(Box::new(AtomicUsize::new(0)))
+    }
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    static TAG_DROPS: [AtomicUsize; 2] = [ZERO; 2];
+
+    /// Zero sized counterpart of Counted, each N gets its own counter
+    struct Tag<const N: usize>;
+
+    impl<const N: usize> Drop for Tag<N> {
+        fn drop(&mut self) {
+            TAG_DROPS[N].fetch_add(1, SeqCst);
+        }
+    }
+
     #[test]
     fn cursed_drop() {
         type D = i32;
@@ -417,7 +896,6 @@ mod tests {
         let mut v: D = 32;
         let ptr = &mut v as *mut D as *mut ();
         drop(ptr);
-        std::mem::forget(v);
     }
 
     #[test]
@@ -434,7 +912,7 @@ mod tests {
         at.remove(1);
         let v = at.take::<(u16, bool, String)>(1);
         assert_eq!(v.0, 57);
-        assert_eq!(v.1, false);
+        assert!(!v.1);
         assert_eq!(v.2, "thing");
         at.clear(..);
     }
@@ -459,6 +937,62 @@ mod tests {
         println!("post clear: {:?}", at.as_slice::<(u16, u64)>());
     }
 
+    #[test]
+    fn extend_exact() {
+        let values = (0..100u32).map(|i| (i.to_string(), i)).collect::<Vec<_>>();
+        let mut pushed = ArchetypeStorage::new::<(String, u32)>();
+        for value in values.iter().cloned() {
+            pushed.push(value);
+        }
+        let mut batch = ArchetypeStorage::new::<(String, u32)>();
+        batch.push(values[0].clone());
+        batch.extend_exact(values[1..].iter().cloned());
+        assert_eq!(
+            pushed.as_slice::<(String, u32)>(),
+            batch.as_slice::<(String, u32)>()
+        );
+        assert_eq!(100, batch.ticks().len());
+        assert_eq!(
+            Some(100),
+            batch.component_ticks(TypeId::of::<u32>()).map(<[_]>::len)
+        );
+        // Grown once, to exactly what was needed
+        assert_eq!(100, batch.capacity);
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let pools = [None, Some(crate::pool::StoragePool::shared())];
+        for pool in pools {
+            let mut at = ArchetypeStorage::new::<(u64, u16)>();
+            if let Some(pool) = pool {
+                at = at.in_pool(pool);
+            }
+            at.extend((0..1000u64).map(|i| (i, i as u16)));
+            at.clear(10..);
+            at.shrink_to_fit();
+            assert!(at.capacity >= 10 && at.capacity < 1000);
+            at.extend((10..20u64).map(|i| (i, i as u16)));
+            let expected = (0..20u64).map(|i| (i, i as u16)).collect::<Vec<_>>();
+            assert_eq!(&expected[..], at.as_slice::<(u64, u16)>());
+
+            at.clear(..);
+            at.shrink_to_fit();
+            assert_eq!(0, at.capacity);
+            at.push((1u64, 1u16));
+            assert_eq!(&[(1, 1)], at.as_slice::<(u64, u16)>());
+        }
+
+        // Nothing to release, and the capacity stays unbounded
+        let mut at = ArchetypeStorage::new::<(Tag<1>,)>();
+        at.extend((0..10).map(|_| (Tag::<1>,)));
+        at.clear(..);
+        at.shrink_to_fit();
+        assert_eq!(!0, at.capacity);
+        at.push((Tag::<1>,));
+        assert_eq!(1, at.len());
+    }
+
     #[test]
     fn half_zst() {
         #[derive(Debug, PartialEq, Eq)]
@@ -515,6 +1049,114 @@ mod tests {
         at.remove(0);
         assert_eq!(DROPPED.load(std::sync::atomic::Ordering::SeqCst), 3);
     }
+
+    #[test]
+    fn clear_bounds() {
+        fn check(bounds: impl RangeBounds<usize>, remaining: &[u32]) {
+            let drops = counter();
+            let mut at = ArchetypeStorage::new::<(Counted, u32)>();
+            at.extend((0..5u32).map(|i| (Counted(drops), i)));
+            at.clear(bounds);
+            assert_eq!(drops.load(SeqCst), 5 - remaining.len());
+            let values: Vec<u32> = unsafe { at.iter_query::<&u32>(0, None, 0, 0) }
+                .copied()
+                .collect();
+            assert_eq!(values, remaining);
+            drop(at);
+            assert_eq!(drops.load(SeqCst), 5);
+        }
+
+        check(.., &[]);
+        check(2.., &[0, 1]);
+        check(..2, &[2, 3, 4]);
+        check(..=2, &[3, 4]);
+        check(1..=2, &[0, 3, 4]);
+        check(1..3, &[0, 3, 4]);
+        check((Bound::Excluded(0), Bound::Included(3)), &[0, 4]);
+        check(4..=4, &[0, 1, 2, 3]);
+        check(2..2, &[0, 1, 2, 3, 4]);
+        check(3..=10, &[0, 1, 2]);
+        check(7.., &[0, 1, 2, 3, 4]);
+
+        // Clearing an empty storage is a no-op, whatever the bounds
+        let mut at = ArchetypeStorage::new::<(Counted, u32)>();
+        at.clear(1..);
+        at.clear(..=0);
+        at.clear((Bound::Excluded(0), Bound::Unbounded));
+        assert_eq!(at.len(), 0);
+    }
+
+    #[test]
+    fn clear_bounds_zst() {
+        let mut at = ArchetypeStorage::new::<(Tag<0>, ())>();
+        at.extend((0..5).map(|_| (Tag::<0>, ())));
+        at.clear(..=1);
+        assert_eq!(at.len(), 3);
+        assert_eq!(TAG_DROPS[0].load(SeqCst), 2);
+        at.clear(1..=1);
+        assert_eq!(at.len(), 2);
+        assert_eq!(TAG_DROPS[0].load(SeqCst), 3);
+        drop(at);
+        assert_eq!(TAG_DROPS[0].load(SeqCst), 5);
+    }
+
+    #[test]
+    fn clear_bounds_half_zst() {
+        let mut at = ArchetypeStorage::new::<(Tag<1>, u32)>();
+        at.extend((0..5u32).map(|i| (Tag::<1>, i)));
+        at.clear(1..=3);
+        assert_eq!(TAG_DROPS[1].load(SeqCst), 3);
+        let values: Vec<u32> = unsafe { at.iter_query::<&u32>(0, None, 0, 0) }
+            .copied()
+            .collect();
+        assert_eq!(values, [0, 4]);
+        drop(at);
+        assert_eq!(TAG_DROPS[1].load(SeqCst), 5);
+    }
+
+    #[test]
+    fn take_panic() {
+        /// Same archetype as (Counted,), but panics after reading its component
+        struct PanicOnRead;
+
+        impl IntoArchetype for PanicOnRead {
+            fn into_archetype() -> Archetype {
+                <(Counted,)>::into_archetype()
+            }
+            fn match_archetype(archetype: &Archetype) -> bool {
+                <(Counted,)>::match_archetype(archetype)
+            }
+            fn archetype_contains(archetype: &Archetype) -> bool {
+                <(Counted,)>::archetype_contains(archetype)
+            }
+            fn bitset(mapping: &ArchetypeBitsetMapping) -> Option<ArchetypeBitset> {
+                <(Counted,)>::bitset(mapping)
+            }
+            unsafe fn write(self, _dst: *mut u8, _archetype: &Archetype) {
+                unreachable!("PanicOnRead is only taken out of storages, never pushed")
+            }
+            unsafe fn read(src: *const u8, archetype: &Archetype) -> Self {
+                let _value = <(Counted,)>::read(src, archetype);
+                panic!("read panicked");
+            }
+            fn types() -> Vec<TypeId> {
+                <(Counted,)>::types()
+            }
+        }
+
+        let drops = counter();
+        let mut at = ArchetypeStorage::new::<(Counted,)>();
+        at.push((Counted(drops),));
+        at.push((Counted(drops),));
+        let res = catch_unwind(AssertUnwindSafe(|| at.take::<PanicOnRead>(0)));
+        assert!(res.is_err());
+        // The value read before the panic has been dropped during unwind, and its slot is gone
+        assert_eq!(drops.load(SeqCst), 1);
+        assert_eq!(at.len(), 1);
+        drop(at);
+        assert_eq!(drops.load(SeqCst), 2);
+    }
+
     #[test]
     fn query() {
         macro_rules! eq {
@@ -535,15 +1177,16 @@ mod tests {
         at.push((12u8, 34i32, "str".to_owned(), (), false));
         at.push((25i32, "abc".to_owned(), (), 17u8, true));
         at.push(("bob".to_owned(), (), 99u8, 68i32, false));
-        let mut iter =
-            unsafe { at.iter_query::<(&String, &i32, Option<&bool>, Option<&u128>)>(0, None) };
+        let mut iter = unsafe {
+            at.iter_query::<(&String, &i32, Option<&bool>, Option<&u128>)>(0, None, 0, 0)
+        };
 
         eq!(Some(("str", 34i32, Some(false), None)), iter.next());
         eq!(Some(("abc", 25i32, Some(true), None)), iter.next());
         eq!(Some(("bob", 68i32, Some(false), None)), iter.next());
         assert_eq!(None, iter.next());
 
-        let iter = unsafe { at.iter_query::<&mut i32>(0, None) };
+        let iter = unsafe { at.iter_query::<&mut i32>(0, None, 0, 0) };
         for i in iter {
             *i = 69;
         }
@@ -552,4 +1195,73 @@ mod tests {
         assert_eq!(s[1].3, 69);
         assert_eq!(s[2].3, 69);
     }
+
+    #[test]
+    fn merge_alignment() {
+        #[repr(align(16))]
+        struct Aligned(u32);
+        let mut archetype = <(Aligned,)>::into_archetype();
+        archetype.merge(<(u32,)>::into_archetype());
+        assert_eq!(16, archetype.offset::<u32>());
+        // Not 20, the next entity would be misaligned
+        assert_eq!(32, archetype.size());
+    }
+
+    #[test]
+    fn merged_storage_alignment() {
+        #[repr(align(16))]
+        struct Aligned(u32);
+        let mut archetype = <(Aligned,)>::into_archetype();
+        archetype.merge(<(u32,)>::into_archetype());
+        let mut storage = ArchetypeStorage::new_from_archetype(archetype);
+        for i in 0..4 {
+            storage.push((Aligned(i), i));
+        }
+        // Every entity, not only the first, has its components aligned
+        for row in 0..4 {
+            let ptr = storage.component_ptr(row, TypeId::of::<Aligned>()).unwrap();
+            assert_eq!(0, ptr as usize % 16, "entity {row} misaligned");
+            assert_eq!(row as u32, unsafe { (*(ptr as *const Aligned)).0 });
+            let ptr = storage.component_ptr(row, TypeId::of::<u32>()).unwrap();
+            assert_eq!(row as u32, unsafe { *(ptr as *const u32) });
+        }
+    }
+
+    #[test]
+    fn canonical_layout() {
+        let mut a = <(u8, String)>::into_archetype();
+        a.merge(<(u16, u64)>::into_archetype());
+        let mut b = <(u64, u8, u16, String, bool)>::into_archetype();
+        b.subtract(<(bool,)>::into_archetype());
+        let mut c = <(u16,)>::into_archetype();
+        c.merge(<(String,)>::into_archetype());
+        c.merge(<(u8, u64)>::into_archetype());
+        assert!(a.exact_match(&b));
+        assert!(a.exact_match(&c));
+        // No padding needed between the components
+        assert_eq!(std::mem::size_of::<(u8, String, u16, u64)>(), a.size());
+    }
+
+    #[test]
+    fn repeat_layout_math() {
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+        // padded to the alignment
+        assert_eq!(repeat_layout(layout(3, 2), 5), Some((layout(20, 2), 4)));
+        assert_eq!(repeat_layout(layout(8, 8), 1), Some((layout(8, 8), 8)));
+        assert_eq!(repeat_layout(layout(5, 4), 0), Some((layout(0, 4), 8)));
+        assert_eq!(
+            repeat_layout(layout(0, 1), usize::MAX),
+            Some((layout(0, 1), 0))
+        );
+        // size * n overflows usize
+        assert_eq!(repeat_layout(layout(2, 1), usize::MAX / 2 + 1), None);
+        assert_eq!(repeat_layout(layout(3, 4), usize::MAX / 3), None);
+        // fits in usize but not in isize (once rounded to the alignment)
+        assert_eq!(repeat_layout(layout(1, 1), isize::MAX as usize + 1), None);
+        assert_eq!(
+            repeat_layout(layout(8, 8), isize::MAX as usize / 8 + 1),
+            None
+        );
+        assert!(repeat_layout(layout(1, 1), isize::MAX as usize).is_some());
+    }
 }
@@ -1,7 +1,9 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::hash::Hash;
 use std::ops::{self, Deref};
-use std::{any::TypeId, collections::HashMap};
+
+use crate::archetype::ComponentKey;
 
 /// What are bitsets composed of
 type BitsetComp = u128;
@@ -240,18 +242,19 @@ macro_rules! bitset_builder {
 
 bitset_builder! {
     BorrowBitset {
-        type Key = TypeId;
+        type Key = ComponentKey;
         type Builder = BorrowBitsetBuilder;
         type Mapping = BorrowBitsetMapping;
         fields {
             borrow,
             mutable,
             required,
+            excluded,
         }
     }
 
     ArchetypeBitset {
-        type Key = TypeId;
+        type Key = ComponentKey;
         type Builder = ArchetypeBitsetBuilder;
         type Mapping = ArchetypeBitsetMapping;
         fields {
@@ -262,7 +265,7 @@ bitset_builder! {
 
 impl<'a> BorrowBitsetBuilder<'a> {
     fn set_with_bit<T: 'static>(&mut self) -> Bitset {
-        match self.mapping.index_of(&TypeId::of::<T>()) {
+        match self.mapping.index_of(&ComponentKey::of::<T>()) {
             Some(index) => Bitset::new_with_bit(index),
             None => {
                 self.invalid = true;
@@ -294,11 +297,25 @@ impl<'a> BorrowBitsetBuilder<'a> {
         self.mutable |= set;
         self
     }
+    /// Require T without borrowing it
+    pub fn with<T: 'static>(mut self) -> Self {
+        let set = self.set_with_bit::<T>();
+        self.required |= set;
+        self
+    }
+    /// Exclude the archetypes with T. A type never registered is in no archetype, so it doesn't
+    /// make the set invalid.
+    pub fn without<T: 'static>(mut self) -> Self {
+        if let Some(index) = self.mapping.index_of(&ComponentKey::of::<T>()) {
+            self.excluded |= Bitset::new_with_bit(index);
+        }
+        self
+    }
 }
 
 impl<'a> ArchetypeBitsetBuilder<'a> {
-    fn set_with_bit<T: 'static>(&mut self) -> Bitset {
-        match self.mapping.index_of(&TypeId::of::<T>()) {
+    fn set_with_bit(&mut self, key: ComponentKey) -> Bitset {
+        match self.mapping.index_of(&key) {
             Some(index) => Bitset::new_with_bit(index),
             None => {
                 self.invalid = true;
@@ -306,8 +323,11 @@ impl<'a> ArchetypeBitsetBuilder<'a> {
             }
         }
     }
-    pub fn add<T: 'static>(mut self) -> Self {
-        let set = self.set_with_bit::<T>();
+    pub fn add<T: 'static>(self) -> Self {
+        self.add_key(ComponentKey::of::<T>())
+    }
+    pub fn add_key(mut self, key: ComponentKey) -> Self {
+        let set = self.set_with_bit(key);
         self.types |= set;
         self
     }
@@ -362,6 +382,7 @@ pub struct BorrowBitset {
     borrow: Bitset,
     mutable: Bitset,
     required: Bitset,
+    excluded: Bitset,
 }
 
 impl BorrowBitset {
@@ -382,6 +403,11 @@ impl BorrowBitset {
             types: self.required,
         }
     }
+    pub fn excluded(self) -> ArchetypeBitset {
+        ArchetypeBitset {
+            types: self.excluded,
+        }
+    }
     pub fn iter(&self) -> BorrowBitsetIter {
         BorrowBitsetIter {
             borrow: self.borrow.iter(),
@@ -5,7 +5,10 @@ use std::{
 
 use parking_lot::Mutex;
 
-use crate::bitset::{BorrowBitset, BorrowKind};
+use crate::{
+    bitset::{BorrowBitset, BorrowKind},
+    EcsError,
+};
 
 pub struct Borrows {
     ref_count: Vec<AtomicU8>,
@@ -23,9 +26,20 @@ impl Borrows {
         self.ref_count
             .extend(std::iter::repeat_with(|| AtomicU8::new(0)).take(len))
     }
-    pub fn borrow<T>(&self, borrow: BorrowBitset, value: T) -> BorrowGuard<T> {
+    /// Borrow value with a set of borrows, panics if they collide with existing ones
+    pub fn borrow<T>(&self, borrow: BorrowBitset, value: T) -> BorrowGuard<'_, T> {
+        self.try_borrow(borrow, value)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+    pub fn try_borrow<T>(
+        &self,
+        borrow: BorrowBitset,
+        value: T,
+    ) -> Result<BorrowGuard<'_, T>, EcsError> {
         if self.bitset.lock().collide(borrow) {
-            panic!("Borrow collision");
+            return Err(EcsError::BorrowConflict {
+                details: "Borrow collision".to_owned(),
+            });
         }
         for (i, b) in &borrow {
             match b {
@@ -39,11 +53,11 @@ impl Borrows {
             }
         }
         self.bitset.lock().merge(borrow);
-        BorrowGuard {
+        Ok(BorrowGuard {
             borrows: Some(self),
             bitset: borrow,
             val: value,
-        }
+        })
     }
     pub fn release(&self, borrow: BorrowBitset) {
         for (i, b) in &borrow {
@@ -99,8 +113,13 @@ impl<'a, T: Iterator> Iterator for BorrowGuard<'a, T> {
     fn next(&mut self) -> Option<Self::Item> {
         self.deref_mut().next()
     }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.deref().size_hint()
+    }
 }
 
+impl<'a, T: ExactSizeIterator> ExactSizeIterator for BorrowGuard<'a, T> {}
+
 impl<'a, T> Drop for BorrowGuard<'a, T> {
     fn drop(&mut self) {
         if let Some(borrows) = self.borrows {
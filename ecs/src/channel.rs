@@ -0,0 +1,334 @@
+//! Typed channels, to pass data between executors (and their worlds) without sharing them.
+//!
+//! `Executor::create_channel` gives a writer and a reader, each is added as a resource to the
+//! executor that uses it. Systems send through `&ChannelWriter<T>`: the queue is behind a mutex, so
+//! any number of systems (and clones of the writer, in other executors) can send at the same time.
+//! Systems drain it through `&mut ChannelReader<T>`, the schedule never runs two of those in
+//! parallel. The two executors may execute at the same time on different threads, a channel only
+//! synchronizes the items themselves: the order in which they are sent and received is the order
+//! of the writes, there is no frame alignment between the two executors.
+
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::executor::Resource;
+
+/// What a send does when the channel is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Drop the oldest item in the queue to make room
+    DropOldest,
+    /// Drop the item being sent
+    DropNewest,
+    /// Wait for the reader to make room, dropping the item being sent if it takes longer than
+    /// the timeout. This blocks the system sending (and its worker) in the meantime.
+    Block(Duration),
+}
+
+struct Shared<T> {
+    queue: Mutex<VecDeque<T>>,
+    /// Notified when the reader drains the queue
+    drained: Condvar,
+    capacity: usize,
+    backpressure: Backpressure,
+    dropped: AtomicU64,
+}
+
+/// Sending half of a channel, see the module documentation. Cloning it gives another writer to
+/// the same channel.
+pub struct ChannelWriter<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Receiving half of a channel, see the module documentation
+pub struct ChannelReader<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub(crate) fn channel<T: Send + 'static>(
+    capacity: usize,
+    backpressure: Backpressure,
+) -> (ChannelWriter<T>, ChannelReader<T>) {
+    assert!(capacity > 0, "A channel needs room for at least one item");
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        drained: Condvar::new(),
+        capacity,
+        backpressure,
+        dropped: AtomicU64::new(0),
+    });
+    (
+        ChannelWriter {
+            shared: shared.clone(),
+        },
+        ChannelReader { shared },
+    )
+}
+
+impl<T> ChannelWriter<T> {
+    /// Queue an item, returns the item dropped to respect the capacity if there was one (the
+    /// oldest queued with `Backpressure::DropOldest`, `item` itself otherwise)
+    pub fn send(&self, item: T) -> Option<T> {
+        let shared = &*self.shared;
+        let mut queue = shared.queue.lock();
+        let dropped = if queue.len() < shared.capacity {
+            None
+        } else {
+            match shared.backpressure {
+                Backpressure::DropOldest => queue.pop_front(),
+                Backpressure::DropNewest => return self.reject(item),
+                Backpressure::Block(timeout) => {
+                    let deadline = Instant::now() + timeout;
+                    while queue.len() >= shared.capacity {
+                        if shared.drained.wait_until(&mut queue, deadline).timed_out()
+                            && queue.len() >= shared.capacity
+                        {
+                            drop(queue);
+                            return self.reject(item);
+                        }
+                    }
+                    None
+                }
+            }
+        };
+        queue.push_back(item);
+        if dropped.is_some() {
+            shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        dropped
+    }
+    fn reject(&self, item: T) -> Option<T> {
+        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        Some(item)
+    }
+    /// Items dropped by the backpressure since the channel was created
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+}
+
+impl<T> Clone for ChannelWriter<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> ChannelReader<T> {
+    /// Take every item queued so far, oldest first. Items sent while iterating are left for the
+    /// next call.
+    pub fn try_iter(&mut self) -> impl Iterator<Item = T> {
+        let items = std::mem::take(&mut *self.shared.queue.lock());
+        self.shared.drained.notify_all();
+        items.into_iter()
+    }
+    /// Items waiting in the queue
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Items dropped by the backpressure since the channel was created
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Forwards the items of a channel into a resource of the executor the reader belongs to, to
+/// handle them like any other data of that world. The target is anything items can be pushed to
+/// (`Vec<T>`, `VecDeque<T>`, ...):
+///
+/// ```ignore
+/// executor.schedule().then(BridgeSystem::<Message, Vec<Message>>::run)
+/// ```
+pub struct BridgeSystem<T, R>(PhantomData<fn(T) -> R>);
+
+impl<T: Send + 'static, R: Resource + Extend<T>> BridgeSystem<T, R> {
+    pub fn run(reader: &mut ChannelReader<T>, target: &mut R) {
+        target.extend(reader.try_iter());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::{Executor, World};
+
+    use super::*;
+
+    #[test]
+    fn cross_executor() {
+        const ITEMS: u32 = 2000;
+        let (writer, reader) =
+            Executor::create_channel::<u32>(16, Backpressure::Block(Duration::from_secs(10)));
+
+        fn produce(writer: &ChannelWriter<u32>, next: &mut u32) {
+            // A few per execute, more than the capacity so the producer has to wait
+            for _ in 0..20 {
+                if *next < ITEMS {
+                    assert!(writer.send(*next).is_none());
+                    *next += 1;
+                }
+            }
+        }
+        fn consume(reader: &mut ChannelReader<u32>, received: &mut Vec<u32>) {
+            received.extend(reader.try_iter());
+        }
+
+        let producer = thread::spawn(move || {
+            let mut world = World::new();
+            let mut exe = Executor::new();
+            exe.add_resource(writer);
+            exe.add_resource(0u32);
+            let schedule = exe.schedule().then(produce).build();
+            while *exe.get_resource::<u32>().unwrap() < ITEMS {
+                exe.execute(&schedule, &mut world);
+            }
+        });
+        let consumer = thread::spawn(move || {
+            let mut world = World::new();
+            let mut exe = Executor::new();
+            exe.add_resource(reader);
+            exe.add_resource(Vec::<u32>::new());
+            let schedule = exe.schedule().then(consume).build();
+            while exe.get_resource::<Vec<u32>>().unwrap().len() < ITEMS as usize {
+                exe.execute(&schedule, &mut world);
+            }
+            let received = exe.get_resource::<Vec<u32>>().unwrap().clone();
+            let dropped = exe.get_resource::<ChannelReader<u32>>().unwrap().dropped();
+            (received, dropped)
+        });
+        producer.join().unwrap();
+        let (received, dropped) = consumer.join().unwrap();
+        assert_eq!((0..ITEMS).collect::<Vec<_>>(), received);
+        assert_eq!(0, dropped);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (writer, mut reader) = Executor::create_channel(3, Backpressure::DropOldest);
+        for i in 0..3 {
+            assert_eq!(None, writer.send(i));
+        }
+        assert_eq!(Some(0), writer.send(3));
+        assert_eq!(Some(1), writer.send(4));
+        assert_eq!(vec![2, 3, 4], reader.try_iter().collect::<Vec<_>>());
+        assert_eq!(2, reader.dropped());
+        // Room again
+        assert_eq!(None, writer.send(5));
+        assert_eq!(vec![5], reader.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (writer, mut reader) = Executor::create_channel(3, Backpressure::DropNewest);
+        let other = writer.clone();
+        for i in 0..3 {
+            assert_eq!(None, writer.send(i));
+        }
+        assert_eq!(Some(3), writer.send(3));
+        assert_eq!(Some(4), other.send(4));
+        assert_eq!(vec![0, 1, 2], reader.try_iter().collect::<Vec<_>>());
+        assert_eq!(2, writer.dropped());
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn block_with_timeout() {
+        let timeout = Duration::from_millis(50);
+        let (writer, _reader) = Executor::create_channel(2, Backpressure::Block(timeout));
+        writer.send(0);
+        writer.send(1);
+        // Nobody drains it: dropped after the timeout
+        let start = Instant::now();
+        assert_eq!(Some(2), writer.send(2));
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(1, writer.dropped());
+
+        // Drained while waiting: sent
+        let (writer, mut reader) =
+            Executor::create_channel(1, Backpressure::Block(Duration::from_secs(10)));
+        writer.send(0);
+        let sender = thread::spawn(move || writer.send(1));
+        // Give it time to block
+        thread::sleep(Duration::from_millis(10));
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            received.extend(reader.try_iter());
+            thread::yield_now();
+        }
+        assert_eq!(None, sender.join().unwrap());
+        assert_eq!(vec![0, 1], received);
+    }
+
+    #[test]
+    fn reader_exclusivity() {
+        let mut exe = Executor::new();
+        let (writer, reader) = Executor::create_channel::<u32>(8, Backpressure::DropNewest);
+        exe.add_resource(writer);
+        exe.add_resource(reader);
+        fn send_a(writer: &ChannelWriter<u32>) {
+            writer.send(1);
+        }
+        fn send_b(writer: &ChannelWriter<u32>) {
+            writer.send(2);
+        }
+        fn read_a(reader: &mut ChannelReader<u32>) {
+            reader.try_iter().for_each(drop);
+        }
+        fn read_b(reader: &mut ChannelReader<u32>) {
+            reader.try_iter().for_each(drop);
+        }
+        // Writers share the channel, readers don't
+        let writers = exe.schedule().then(send_a).then(send_b).build();
+        assert_eq!(2, writers.report().threads);
+        let readers = exe.schedule().then(read_a).then(read_b).build();
+        assert_eq!(1, readers.report().threads);
+        let mut world = World::new();
+        exe.execute(&writers, &mut world);
+        exe.execute(&readers, &mut world);
+        assert!(exe.get_resource::<ChannelReader<u32>>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bridge() {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Message(&'static str);
+
+        let (writer, reader) = Executor::create_channel(8, Backpressure::DropOldest);
+        let mut exe = Executor::new();
+        exe.add_resource(reader);
+        exe.add_resource(Vec::<Message>::new());
+        let schedule = exe
+            .schedule()
+            .then(BridgeSystem::<Message, Vec<Message>>::run)
+            .build();
+        let mut world = World::new();
+
+        writer.send(Message("a"));
+        writer.send(Message("b"));
+        exe.execute(&schedule, &mut world);
+        writer.send(Message("c"));
+        exe.execute(&schedule, &mut world);
+        exe.execute(&schedule, &mut world);
+        assert_eq!(
+            &vec![Message("a"), Message("b"), Message("c")],
+            exe.get_resource::<Vec<Message>>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! Structural changes queued by systems, which only get a shared World, see `Commands`.
+
+use parking_lot::Mutex;
+
+use crate::{
+    archetype::IntoArchetype,
+    executor::ExecutionContext,
+    system::{RequirementsBuilder, RequirementsMappings, SystemArgument},
+    Entity, World,
+};
+
+/// Generation of the placeholders handed by `Commands::spawn`, the slots counting down from the
+/// last index. No real entity gets there.
+const PLACEHOLDER_GENERATION: u32 = u32::MAX;
+
+/// A command, returning the entity it spawned if any
+type Command = Box<dyn FnOnce(&mut World, &[Entity]) -> Option<Entity> + Send>;
+
+#[derive(Default)]
+struct Queue {
+    commands: Vec<Command>,
+    spawns: u32,
+}
+
+/// The commands of a system, applied once the schedule ran
+#[derive(Default)]
+pub(crate) struct CommandQueue(Mutex<Queue>);
+
+impl CommandQueue {
+    /// Apply the commands in the order they were queued
+    pub(crate) fn apply(&self, world: &mut World) {
+        let queue = std::mem::take(&mut *self.0.lock());
+        let mut spawned = Vec::with_capacity(queue.spawns as usize);
+        for command in queue.commands {
+            spawned.extend(command(world, &spawned));
+        }
+    }
+}
+
+/// The entity a placeholder was spawned as, other entities are left as is
+fn resolve(entity: Entity, spawned: &[Entity]) -> Entity {
+    if entity.generation() != PLACEHOLDER_GENERATION {
+        return entity;
+    }
+    let index = u32::MAX - entity.index().0;
+    spawned.get(index as usize).copied().unwrap_or(entity)
+}
+
+/// Spawns, despawns, and component additions and removals, queued by a system and applied to the
+/// World in order once every system of the schedule ran. The commands of different systems are
+/// applied in the order the systems were added to the schedule, so parallel systems give the same
+/// world every time.
+///
+/// Commands on entities gone by the time they are applied are skipped, but adding a component an
+/// entity already has (or removing one it doesn't) panics like it does on the World.
+pub struct Commands<'a> {
+    queue: &'a Mutex<Queue>,
+}
+
+impl<'a> Commands<'a> {
+    /// Spawn an entity. The entity returned is a placeholder until the commands are applied: it
+    /// can be given to the next commands of the same system, but refers to nothing in the World.
+    pub fn spawn<T: IntoArchetype + Send + 'static>(&mut self, components: T) -> Entity {
+        let mut queue = self.queue.lock();
+        let placeholder = Entity::from_parts(u32::MAX - queue.spawns, PLACEHOLDER_GENERATION);
+        queue.spawns += 1;
+        queue
+            .commands
+            .push(Box::new(move |world, _| Some(world.spawn(components))));
+        placeholder
+    }
+    /// Despawn an entity, like `World::remove`
+    pub fn despawn(&mut self, entity: Entity) {
+        self.push(move |world, spawned| {
+            world.remove(resolve(entity, spawned));
+        });
+    }
+    /// Add components to an entity, like `World::add_component`
+    pub fn add_component<T: IntoArchetype + Send + 'static>(&mut self, entity: Entity, value: T) {
+        self.push(move |world, spawned| {
+            world.add_component(resolve(entity, spawned), value);
+        });
+    }
+    /// Remove components from an entity and drop them, like `World::take_component`
+    pub fn remove_component<T: IntoArchetype + 'static>(&mut self, entity: Entity) {
+        self.push(move |world, spawned| {
+            world.take_component::<T>(resolve(entity, spawned));
+        });
+    }
+    fn push(&mut self, command: impl FnOnce(&mut World, &[Entity]) + Send + 'static) {
+        self.queue.lock().commands.push(Box::new(|world, spawned| {
+            command(world, spawned);
+            None
+        }));
+    }
+}
+
+impl<'a> SystemArgument for Commands<'a> {
+    fn register(_mappings: &mut RequirementsMappings) {}
+    fn require(builder: RequirementsBuilder) -> RequirementsBuilder {
+        // Each system has its own queue
+        builder
+    }
+    unsafe fn fetch(context: &ExecutionContext) -> Self {
+        log::trace!("SystemArgument: Fetching Commands");
+        let queue = &context
+            .commands
+            .expect("Commands outside of a system run")
+            .0;
+        // transform lifetimes to be valid.
+        Self {
+            queue: &*(queue as *const Mutex<Queue>),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Entities, Executor};
+
+    #[test]
+    fn placeholders() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        let old = world.spawn((0u32,));
+        exe.run_once(&mut world, move |mut commands: Commands| {
+            let a = commands.spawn((1u32,));
+            let b = commands.spawn((2u32,));
+            commands.add_component(b, (2u8,));
+            commands.add_component(old, (0u8,));
+            commands.remove_component::<(u32,)>(old);
+            commands.despawn(a);
+        });
+        let mut values = world
+            .query::<(Option<&u32>, &u8)>()
+            .map(|(v, b)| (v.copied(), *b))
+            .collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(vec![(None, 0), (Some(2), 2)], values);
+        assert_eq!(2, world.stats().entities);
+    }
+
+    /// Values of the entities, with the bits of the entities
+    fn run_parallel(sequential: bool) -> Vec<(u32, u64)> {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        world.spawn_many((0..10u32).map(|v| (v,)));
+        let schedule = exe
+            .schedule()
+            .then(
+                |entities: Entities<(Entity, &u32)>, mut commands: Commands| {
+                    for (entity, &v) in entities.filter(|(_, v)| *v % 2 == 0) {
+                        commands.despawn(entity);
+                        commands.spawn((v + 100,));
+                    }
+                },
+            )
+            .then(
+                |entities: Entities<(Entity, &u32)>, mut commands: Commands| {
+                    for (entity, &v) in entities.filter(|(_, v)| *v % 2 == 1) {
+                        commands.despawn(entity);
+                        let spawned = commands.spawn((v + 200,));
+                        commands.add_component(spawned, (0u8,));
+                    }
+                },
+            )
+            .build_for(&world);
+        assert_eq!(2, schedule.report().threads);
+        match sequential {
+            true => exe.execute_sequential(&schedule, &mut world),
+            false => exe.execute(&schedule, &mut world),
+        }
+        assert_eq!(5, world.query::<&u8>().count());
+        let mut values = world
+            .query::<(Entity, &u32)>()
+            .map(|(e, &v)| (v, e.to_bits()))
+            .collect::<Vec<_>>();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn parallel_systems() {
+        let expected = run_parallel(true);
+        let values = expected.iter().map(|(v, _)| *v).collect::<Vec<_>>();
+        assert_eq!(
+            vec![100, 102, 104, 106, 108, 201, 203, 205, 207, 209],
+            values
+        );
+        for _ in 0..8 {
+            assert_eq!(expected, run_parallel(false));
+        }
+    }
+}
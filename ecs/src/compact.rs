@@ -0,0 +1,222 @@
+//! A compact set of entities, for large groups that rarely change.
+//!
+//! Entities are sorted by index and stored as the difference with the previous index followed by
+//! their generation, both as LEB128 varints. Entities spawned together have close indices and
+//! small generations, so most take 2 bytes instead of the 8 of an `Entity`. Lookups, insertions
+//! and removals decode the set, which is fine for sets iterated far more often than edited.
+
+use crate::entity::Entity;
+
+/// A sorted set of entities, encoded to take a fraction of the memory of a `Vec<Entity>`. Unlike
+/// `EntityIndex` the generations are kept: a despawned entity is never mistaken for the one that
+/// reuses its slot.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactEntityVec {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> u32 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte < 0x80 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// Entities by index then generation
+fn key(entity: &Entity) -> (u32, u32) {
+    (entity.index().0, entity.generation())
+}
+
+impl CompactEntityVec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Size of the encoded entities, in bytes
+    pub fn encoded_size(&self) -> usize {
+        self.bytes.len()
+    }
+    /// The entities, by index
+    pub fn iter(&self) -> impl Iterator<Item = Entity> + '_ {
+        let mut pos = 0;
+        let mut index = 0;
+        std::iter::from_fn(move || {
+            if pos == self.bytes.len() {
+                return None;
+            }
+            index += read_varint(&self.bytes, &mut pos);
+            let generation = read_varint(&self.bytes, &mut pos);
+            Some(Entity::from_parts(index, generation))
+        })
+    }
+    pub fn contains(&self, entity: Entity) -> bool {
+        let target = key(&entity);
+        // Sorted, no need to look past it
+        self.iter()
+            .map(|e| key(&e))
+            .take_while(|k| *k <= target)
+            .any(|k| k == target)
+    }
+    /// Returns false if the entity was already in the set
+    pub fn insert(&mut self, entity: Entity) -> bool {
+        let mut entities = self.iter().collect::<Vec<_>>();
+        match entities.binary_search_by_key(&key(&entity), key) {
+            Ok(_) => false,
+            Err(i) => {
+                entities.insert(i, entity);
+                *self = Self::encode(&entities);
+                true
+            }
+        }
+    }
+    /// Returns false if the entity wasn't in the set
+    pub fn remove(&mut self, entity: Entity) -> bool {
+        let mut entities = self.iter().collect::<Vec<_>>();
+        match entities.binary_search_by_key(&key(&entity), key) {
+            Ok(i) => {
+                entities.remove(i);
+                *self = Self::encode(&entities);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+    /// Encode sorted and deduplicated entities
+    fn encode(entities: &[Entity]) -> Self {
+        let mut bytes = Vec::with_capacity(entities.len() * 2);
+        let mut previous = 0;
+        for entity in entities {
+            let (index, generation) = key(entity);
+            write_varint(&mut bytes, index - previous);
+            write_varint(&mut bytes, generation);
+            previous = index;
+        }
+        bytes.shrink_to_fit();
+        Self {
+            bytes,
+            len: entities.len(),
+        }
+    }
+}
+
+impl FromIterator<Entity> for CompactEntityVec {
+    fn from_iter<T: IntoIterator<Item = Entity>>(iter: T) -> Self {
+        let mut entities = iter.into_iter().collect::<Vec<_>>();
+        entities.sort_by_key(key);
+        entities.dedup();
+        Self::encode(&entities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::World;
+
+    #[test]
+    fn varints() {
+        let mut bytes = Vec::new();
+        let values = [0, 1, 127, 128, 300, 16383, 16384, u32::MAX];
+        for value in values {
+            write_varint(&mut bytes, value);
+        }
+        assert_eq!(1 + 1 + 1 + 2 + 2 + 2 + 3 + 5, bytes.len());
+        let mut pos = 0;
+        for value in values {
+            assert_eq!(value, read_varint(&bytes, &mut pos));
+        }
+        assert_eq!(bytes.len(), pos);
+    }
+
+    #[test]
+    fn set_operations() {
+        let mut world = World::new();
+        let entities = (0..300).map(|_| world.spawn((0u32,))).collect::<Vec<_>>();
+        // Bump some generations
+        for &entity in entities.iter().step_by(7) {
+            world.remove(entity).unwrap();
+        }
+        let respawned = (0..20).map(|_| world.spawn((0u32,))).collect::<Vec<_>>();
+
+        let kept = entities
+            .iter()
+            .copied()
+            .step_by(3)
+            .chain(respawned.iter().copied());
+        let set = kept
+            .clone()
+            .rev()
+            .chain(kept.clone())
+            .collect::<CompactEntityVec>();
+        let mut expected = kept.collect::<Vec<_>>();
+        expected.sort_by_key(key);
+        expected.dedup();
+        assert_eq!(expected.len(), set.len());
+        assert_eq!(expected, set.iter().collect::<Vec<_>>());
+        for entity in entities.iter().chain(&respawned) {
+            assert_eq!(expected.contains(entity), set.contains(*entity));
+        }
+
+        let mut set = set;
+        let [a, b] = [entities[1], respawned[0]];
+        assert!(!set.contains(a));
+        assert!(set.insert(a));
+        assert!(!set.insert(a));
+        assert!(set.contains(a));
+        assert!(set.remove(b));
+        assert!(!set.remove(b));
+        assert!(!set.contains(b));
+        assert_eq!(expected.len(), set.len());
+        assert!(CompactEntityVec::new().iter().next().is_none());
+    }
+
+    #[test]
+    fn size() {
+        let mut world = World::new();
+        let entities = (0..10_000)
+            .map(|_| world.spawn((0u32,)))
+            .collect::<Vec<_>>();
+        let full = std::mem::size_of::<Entity>();
+        // Spawned together (static geometry)
+        let set = entities.iter().copied().collect::<CompactEntityVec>();
+        assert_eq!(2 * entities.len(), set.encoded_size());
+        assert_eq!(full * entities.len(), 4 * set.encoded_size());
+        // Scattered, every 500th entity needs a 2 bytes delta (but the first)
+        let set = entities
+            .iter()
+            .copied()
+            .step_by(500)
+            .collect::<CompactEntityVec>();
+        assert_eq!(3 * set.len() - 1, set.encoded_size());
+        // Slots reused many times
+        for _ in 0..200 {
+            let entity = world.spawn((0u32,));
+            world.remove(entity);
+        }
+        let reused = world.spawn((0u32,));
+        let set = [reused].into_iter().collect::<CompactEntityVec>();
+        assert!(set.encoded_size() < full);
+        assert!(set.contains(reused));
+    }
+}
@@ -0,0 +1,144 @@
+//! Components without a rust type, described at runtime by their layout (see
+//! `World::register_dynamic`). They are stored with the static components of their entities, but
+//! only accessible as bytes: queries only see the static ones.
+
+use std::alloc::Layout;
+
+use crate::{
+    archetype::{Archetype, ComponentKey, DropInPlace, IntoArchetype},
+    entity::Entity,
+    world::World,
+};
+
+/// A component registered with `World::register_dynamic`, only valid in that world
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DynamicComponentId(u32);
+
+struct DynamicComponent {
+    layout: Layout,
+    drop: Option<DropInPlace>,
+}
+
+/// The dynamic components of a world
+#[derive(Default)]
+pub(crate) struct DynamicRegistry {
+    components: Vec<DynamicComponent>,
+}
+
+impl DynamicRegistry {
+    fn get(&self, id: DynamicComponentId) -> Option<&DynamicComponent> {
+        self.components.get(id.0 as usize)
+    }
+}
+
+impl World {
+    /// Register a component stored as bytes of a layout. drop is called with a pointer to the
+    /// bytes when an entity with the component is despawned, or the world dropped.
+    pub fn register_dynamic(
+        &mut self,
+        layout: Layout,
+        drop: Option<fn(*mut ())>,
+    ) -> DynamicComponentId {
+        let id = DynamicComponentId(self.dynamic.components.len() as u32);
+        self.dynamic
+            .components
+            .push(DynamicComponent { layout, drop });
+        id
+    }
+    /// Spawn an entity with dynamic components, given as their bytes. Static components can be
+    /// added afterwards with `add_component`.
+    ///
+    /// # Panics
+    ///
+    /// This panics if a component isn't registered in this world, is given twice, or if its bytes
+    /// don't have the size of its layout
+    pub fn spawn_dynamic(&mut self, components: Vec<(DynamicComponentId, Box<[u8]>)>) -> Entity {
+        let mut archetype = <()>::into_archetype();
+        let mut raw = Vec::with_capacity(components.len());
+        for (id, bytes) in &components {
+            let component = self
+                .dynamic
+                .get(*id)
+                .unwrap_or_else(|| panic!("{id:?} isn't registered in this world"));
+            assert_eq!(
+                component.layout.size(),
+                bytes.len(),
+                "Wrong number of bytes for {id:?}"
+            );
+            let key = ComponentKey::Dynamic(*id);
+            assert!(archetype.offset_of(key).is_none(), "{id:?} given twice");
+            archetype.merge(Archetype::raw(
+                key,
+                component.layout,
+                component.drop,
+                "dynamic component",
+            ));
+            raw.push((key, &bytes[..]));
+        }
+        self.spawn_raw(&raw, archetype)
+    }
+    /// The bytes of a dynamic component of an entity, None if the entity doesn't exist or doesn't
+    /// have it
+    pub fn get_dynamic(&self, entity: Entity, id: DynamicComponentId) -> Option<&[u8]> {
+        let size = self.dynamic.get(id)?.layout.size();
+        let ptr = self.component_ptr(entity, id)?;
+        Some(unsafe { std::slice::from_raw_parts(ptr, size) })
+    }
+    /// Mutable bytes of a dynamic component of an entity, marking it as changed
+    pub fn get_dynamic_mut(&mut self, entity: Entity, id: DynamicComponentId) -> Option<&mut [u8]> {
+        let size = self.dynamic.get(id)?.layout.size();
+        let ptr = self.component_ptr_mut(entity, id.into())?;
+        Some(unsafe { std::slice::from_raw_parts_mut(ptr, size) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// First word of the blobs dropped
+    static DROPPED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+    fn drop_blob(ptr: *mut ()) {
+        let first = unsafe { (ptr as *const u32).read() };
+        DROPPED.lock().unwrap().push(first);
+    }
+
+    fn blob(first: u32) -> Box<[u8]> {
+        let mut bytes = vec![0xAB; 12];
+        bytes[..4].copy_from_slice(&first.to_ne_bytes());
+        bytes.into_boxed_slice()
+    }
+
+    #[test]
+    fn dynamic_components() {
+        let mut world = World::new();
+        let id = world.register_dynamic(Layout::from_size_align(12, 4).unwrap(), Some(drop_blob));
+        let a = world.spawn_dynamic(vec![(id, blob(1))]);
+        world.add_component(a, (10i32,));
+        let b = world.spawn_dynamic(vec![(id, blob(2))]);
+        world.add_component(b, (20i32,));
+        let c = world.spawn_dynamic(vec![(id, blob(3))]);
+        let d = world.spawn((40i32,));
+
+        assert_eq!(Some(&blob(1)[..]), world.get_dynamic(a, id));
+        assert_eq!(Some(&blob(2)[..]), world.get_dynamic(b, id));
+        assert_eq!(Some(&blob(3)[..]), world.get_dynamic(c, id));
+        assert_eq!(None, world.get_dynamic(d, id));
+        // Queries see the static components of the entities with dynamic ones
+        assert_eq!(70, world.query::<&i32>().copied().sum::<i32>());
+
+        world.get_dynamic_mut(c, id).unwrap()[..4].copy_from_slice(&4u32.to_ne_bytes());
+        assert_eq!(Some(&blob(4)[..]), world.get_dynamic(c, id));
+
+        world.remove(a);
+        assert_eq!(vec![1], *DROPPED.lock().unwrap());
+        assert_eq!(Some(&blob(2)[..]), world.get_dynamic(b, id));
+        drop(world);
+        let mut dropped = DROPPED.lock().unwrap().clone();
+        dropped.sort();
+        assert_eq!(vec![1, 2, 4], dropped);
+    }
+}
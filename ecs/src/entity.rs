@@ -1,11 +1,53 @@
-use std::collections::HashMap;
+//! Entity handles.
+//!
+//! `Entity` is the handle to use by default: it holds the index of the entity's slot and the
+//! generation of the slot, so a handle kept after its entity is despawned never refers to the
+//! entity that reuses the slot. Every World API takes an `Entity`.
+//!
+//! `EntityIndex` is the slot alone, half the size. It can't tell a despawned entity from the one
+//! that took its slot, so it is only for transient structures whose entities are alive by
+//! construction (built and consumed within a frame, while the world isn't changed), and is turned
+//! back into an `Entity` with `World::entity_from_index` before reaching any other API. For large
+//! sets of entities kept around, see `CompactEntityVec` instead.
 
-use slotmap::{new_key_type, SlotMap};
+use std::collections::{HashMap, VecDeque};
+
+use slotmap::{new_key_type, Key, KeyData, SlotMap};
 
 new_key_type! {
     pub struct Entity;
 }
 
+impl Entity {
+    /// The entity as an integer, stable for the lifetime of the world
+    pub fn to_bits(self) -> u64 {
+        self.data().as_ffi()
+    }
+    /// Inverse of to_bits
+    pub fn from_bits(bits: u64) -> Self {
+        KeyData::from_ffi(bits).into()
+    }
+    /// The slot of the entity, without its generation
+    pub fn index(self) -> EntityIndex {
+        EntityIndex(self.to_bits() as u32)
+    }
+    /// Incremented each time the slot is freed or reused
+    pub(crate) fn generation(self) -> u32 {
+        (self.to_bits() >> 32) as u32
+    }
+    pub(crate) fn from_parts(index: u32, generation: u32) -> Self {
+        Self::from_bits((generation as u64) << 32 | index as u64)
+    }
+}
+
+/// The slot of an entity without its generation, see the module documentation for when it can be
+/// used in place of an `Entity`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EntityIndex(pub u32);
+
+/// Default number of despawns remembered by a LocationMap
+pub const DESPAWN_HISTORY: usize = 1024;
+
 #[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
 pub struct Location {
     pub archetype: usize,
@@ -14,18 +56,64 @@ pub struct Location {
 
 pub struct LocationMap {
     entities: SlotMap<Entity, Location>,
+    /// The entity in each slot, null for free slots
+    slots: Vec<Entity>,
     locations: HashMap<Location, Entity>,
     lengths: Vec<usize>,
+    /// Ring buffer of the last despawned entities and the tick they were despawned at
+    despawned: VecDeque<(Entity, u32)>,
+    despawn_capacity: usize,
+    /// Most recent tick evicted from the despawn history
+    evicted: u32,
 }
 
 impl LocationMap {
     pub fn new() -> Self {
         Self {
             entities: SlotMap::with_key(),
+            slots: Vec::new(),
             locations: HashMap::new(),
             lengths: Vec::new(),
+            despawned: VecDeque::new(),
+            despawn_capacity: DESPAWN_HISTORY,
+            evicted: 0,
         }
     }
+    /// Remember that an entity was despawned at tick, evicting the oldest despawn if the history
+    /// is full
+    pub fn record_despawn(&mut self, entity: Entity, tick: u32) {
+        if self.despawned.len() == self.despawn_capacity {
+            match self.despawned.pop_front() {
+                Some((_, evicted)) => self.evicted = self.evicted.max(evicted),
+                None => {
+                    self.evicted = tick;
+                    return;
+                }
+            }
+        }
+        self.despawned.push_back((entity, tick));
+    }
+    /// Set how many despawns are remembered
+    pub fn set_despawn_capacity(&mut self, capacity: usize) {
+        self.despawn_capacity = capacity;
+        while self.despawned.len() > capacity {
+            let (_, evicted) = self.despawned.pop_front().unwrap();
+            self.evicted = self.evicted.max(evicted);
+        }
+    }
+    /// Entities despawned after tick, or None if some of them were evicted from the history
+    pub fn despawned_since(&self, tick: u32) -> Option<Vec<Entity>> {
+        if self.evicted > tick {
+            return None;
+        }
+        Some(
+            self.despawned
+                .iter()
+                .filter(|(_, t)| *t > tick)
+                .map(|(e, _)| *e)
+                .collect(),
+        )
+    }
     fn fetch_add_archetype_len(&mut self, archetype: usize, add: usize) -> usize {
         match self.lengths.get_mut(archetype) {
             Some(len) => {
@@ -81,6 +169,7 @@ impl LocationMap {
             entity: index,
         };
         let entity = self.entities.insert(location);
+        self.occupy(entity);
         self.locations.insert(location, entity);
         entity
     }
@@ -93,6 +182,7 @@ impl LocationMap {
                 entity: i + start,
             };
             let entity = self.entities.insert(location);
+            self.occupy(entity);
             self.locations.insert(location, entity);
             res.push(entity);
         }
@@ -100,14 +190,21 @@ impl LocationMap {
     }
     pub fn remove_single(&mut self, entity: Entity) -> Option<Location> {
         let loc = self.entities.remove(entity)?;
+        self.slots[entity.index().0 as usize] = Entity::null();
         self.locations.remove(&loc)?;
         self.shift(1, loc.entity + 1, loc.archetype);
         Some(loc)
     }
+    /// Remove entities stored next to each other, None (and nothing removed) if one doesn't exist
     pub fn remove(&mut self, entities: impl IntoIterator<Item = Entity>) -> Option<Vec<Location>> {
+        let entities = entities.into_iter().collect::<Vec<_>>();
+        if !entities.iter().all(|&e| self.entities.contains_key(e)) {
+            return None;
+        }
         let mut res = Vec::new();
         for e in entities {
             let loc = self.entities.remove(e)?;
+            self.slots[e.index().0 as usize] = Entity::null();
             self.locations.remove(&loc)?;
             res.push(loc);
         }
@@ -124,12 +221,37 @@ impl LocationMap {
         self.shift(count, index + 1, archetype);
         Some(res)
     }
+    /// Number of entities alive
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+    /// The entities alive, in no particular order
+    pub fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entities.keys()
+    }
     pub fn get_location(&self, entity: Entity) -> Option<Location> {
         self.entities.get(entity).copied()
     }
     pub fn get_entity(&self, loc: Location) -> Option<Entity> {
         self.locations.get(&loc).copied()
     }
+    fn occupy(&mut self, entity: Entity) {
+        let slot = entity.index().0 as usize;
+        if slot >= self.slots.len() {
+            self.slots.resize(slot + 1, Entity::null());
+        }
+        self.slots[slot] = entity;
+    }
+    /// The entity in a slot, None if the slot is free
+    pub fn entity_at(&self, index: EntityIndex) -> Option<Entity> {
+        self.slots
+            .get(index.0 as usize)
+            .copied()
+            .filter(|entity| !entity.is_null())
+    }
 }
 
 impl Default for LocationMap {
@@ -0,0 +1,97 @@
+//! Errors of the fallible (`try_`) API. The panicking functions are thin wrappers around their
+//! `try_` variant and panic with the error's message.
+
+use std::fmt;
+
+use crate::Entity;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EcsError {
+    /// A query collides with another one still alive, or borrows a resource mutably more than once
+    BorrowConflict {
+        details: String,
+    },
+    MissingResource {
+        type_name: &'static str,
+    },
+    /// The schedule was built from another executor
+    ForeignSchedule,
+    DuplicateResource {
+        type_name: &'static str,
+    },
+    EntityNotFound(Entity),
+    /// The entity has (or lacks) some of the components
+    ArchetypeMismatch {
+        details: String,
+    },
+    /// The system isn't registered in the executor
+    UnknownSystem,
+    /// The system is already in the schedule
+    DuplicateSystem,
+    /// `Executor::execute_with_timeout` gave up, these waits of the schedule (indices of the
+    /// waits of its trace) didn't get their notifications in time
+    Timeout {
+        pending: Vec<usize>,
+    },
+}
+
+impl EcsError {
+    pub(crate) fn missing_resource<T>() -> Self {
+        Self::MissingResource {
+            type_name: std::any::type_name::<T>(),
+        }
+    }
+}
+
+// These are also the messages of the panicking functions, keep them stable
+impl fmt::Display for EcsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BorrowConflict { details } | Self::ArchetypeMismatch { details } => {
+                write!(f, "{details}")
+            }
+            Self::MissingResource { type_name } => write!(f, "Resource not in system: {type_name}"),
+            Self::ForeignSchedule => write!(f, "Schedule wasn't built from correct executor"),
+            Self::DuplicateResource { type_name } => write!(
+                f,
+                "Trying to add resource that is already in executor: {type_name}"
+            ),
+            Self::EntityNotFound(entity) => write!(f, "Entity not found: {entity:?}"),
+            Self::UnknownSystem => write!(f, "System isn't registered in executor"),
+            Self::DuplicateSystem => write!(f, "System is already in schedule"),
+            Self::Timeout { pending } => {
+                write!(f, "Schedule timed out, waits still pending: {pending:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EcsError {}
+
+/// The ordering constraints of a schedule form a cycle, see `Scheduler::after`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleCycleError {
+    /// Type names of the systems of the cycle, each must run after the next, and the last after
+    /// the first
+    pub systems: Vec<&'static str>,
+}
+
+impl fmt::Display for ScheduleCycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Schedule constraints form a cycle: ")?;
+        for name in &self.systems {
+            write!(f, "{name} -> ")?;
+        }
+        write!(f, "{}", self.systems[0])
+    }
+}
+
+impl std::error::Error for ScheduleCycleError {}
+
+/// A system that was skipped because its arguments couldn't be fetched (see `FetchPolicy`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemError {
+    /// Type name of the system
+    pub system: &'static str,
+    pub error: EcsError,
+}
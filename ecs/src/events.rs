@@ -0,0 +1,243 @@
+//! Events passed between the systems of an executor, see `Executor::add_events`.
+//!
+//! Systems send through `EventWriter<T>` and read through `EventReader<T>`, which only gives the
+//! events sent since the last run of the system. The events of an execute are kept for the next
+//! one too, then dropped, so every reader sees them once whether it runs before or after the
+//! writer. A writer borrows `Events<T>` mutably and a reader immutably, the readers added after a
+//! writer in a schedule always see what it sent in the same execute.
+
+use std::{
+    collections::HashMap,
+    iter::{Chain, Skip},
+    slice::Iter,
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    executor::{ExecutionContext, Resource, SystemId},
+    system::{RequirementsBuilder, RequirementsMappings, SystemArgument},
+    EcsError, Executor,
+};
+
+/// Iterator over the events of an `EventReader`
+pub type Unread<'a, T> = Skip<Chain<Iter<'a, T>, Iter<'a, T>>>;
+
+/// The events of type T of the last two executes, a resource added by `Executor::add_events`
+pub struct Events<T> {
+    /// Events sent before the last update
+    previous: Vec<T>,
+    /// Events sent since the last update
+    current: Vec<T>,
+    /// Number of events dropped by the updates, the index of the first of previous
+    start: usize,
+    /// Index of the next event each reader will see
+    cursors: Mutex<HashMap<SystemId, usize>>,
+}
+
+impl<T> Events<T> {
+    pub fn new() -> Self {
+        Self {
+            previous: Vec::new(),
+            current: Vec::new(),
+            start: 0,
+            cursors: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn send(&mut self, event: T) {
+        self.current.push(event);
+    }
+    /// The events kept, oldest first
+    pub fn iter(&self) -> Chain<Iter<'_, T>, Iter<'_, T>> {
+        self.previous.iter().chain(&self.current)
+    }
+    pub fn len(&self) -> usize {
+        self.previous.len() + self.current.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Drop the events of the execute before the last, done after every execute
+    pub fn update(&mut self) {
+        self.start += self.previous.len();
+        self.previous.clear();
+        std::mem::swap(&mut self.previous, &mut self.current);
+    }
+    /// The events a reader hasn't seen yet, from its cursor (or all of them)
+    fn unread(&self, from: usize) -> Unread<'_, T> {
+        self.iter().skip(from.saturating_sub(self.start))
+    }
+    /// Move the cursor of a reader past every event, returns where it was
+    fn advance(&self, reader: SystemId) -> usize {
+        let end = self.start + self.len();
+        self.cursors.lock().insert(reader, end).unwrap_or(0)
+    }
+}
+
+impl<T> Default for Events<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Update the events of type T of an executor, see `Executor::add_events`
+pub(crate) fn update<T: Resource>(executor: &mut Executor) {
+    if let Some(events) = executor.get_resource_mut::<Events<T>>() {
+        events.update();
+    }
+}
+
+/// Sends events of type T, borrows `Events<T>` mutably
+pub struct EventWriter<'r, T> {
+    events: &'r mut Events<T>,
+}
+
+impl<'r, T> EventWriter<'r, T> {
+    pub fn send(&mut self, event: T) {
+        self.events.send(event);
+    }
+    pub fn send_batch(&mut self, events: impl IntoIterator<Item = T>) {
+        self.events.current.extend(events);
+    }
+}
+
+impl<'r, T: Resource> SystemArgument for EventWriter<'r, T> {
+    fn register(mappings: &mut RequirementsMappings) {
+        <&mut Events<T>>::register(mappings);
+    }
+    fn require(builder: RequirementsBuilder) -> RequirementsBuilder {
+        <&mut Events<T>>::require(builder)
+    }
+    fn check(context: &ExecutionContext) -> Result<(), EcsError> {
+        <&Events<T>>::check(context)
+    }
+    unsafe fn fetch(context: &ExecutionContext) -> Self {
+        log::trace!("SystemArgument: Fetching EventWriter");
+        Self {
+            events: <&mut Events<T>>::fetch(context),
+        }
+    }
+}
+
+/// Reads the events of type T sent since the last run of the system, borrows `Events<T>`
+/// immutably. Outside of a schedule (`Executor::run_once`), every event kept is unread.
+pub struct EventReader<'r, T> {
+    events: &'r Events<T>,
+    from: usize,
+}
+
+impl<'r, T> EventReader<'r, T> {
+    pub fn iter(&self) -> Unread<'r, T> {
+        self.events.unread(self.from)
+    }
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'r, T> IntoIterator for EventReader<'r, T> {
+    type Item = &'r T;
+    type IntoIter = Unread<'r, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'r, T: Resource> SystemArgument for EventReader<'r, T> {
+    fn register(mappings: &mut RequirementsMappings) {
+        <&Events<T>>::register(mappings);
+    }
+    fn require(builder: RequirementsBuilder) -> RequirementsBuilder {
+        <&Events<T>>::require(builder)
+    }
+    fn check(context: &ExecutionContext) -> Result<(), EcsError> {
+        <&Events<T>>::check(context)
+    }
+    unsafe fn fetch(context: &ExecutionContext) -> Self {
+        log::trace!("SystemArgument: Fetching EventReader");
+        let events = <&Events<T>>::fetch(context);
+        let from = context.system.map_or(0, |id| events.advance(id));
+        Self { events, from }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::World;
+
+    #[derive(Default)]
+    struct Seen(Vec<u32>);
+    #[derive(Default)]
+    struct SeenToo(Vec<u32>);
+    /// Events the writer sends next execute
+    struct Outbox(Vec<u32>);
+
+    #[test]
+    fn readers_see_events_once() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_events::<u32>();
+        exe.add_resource(Seen::default());
+        exe.add_resource(SeenToo::default());
+        exe.add_resource(Outbox(vec![1, 2]));
+        // Before the writer: sees the events of an execute in the next one
+        let before = |events: EventReader<u32>, seen: &mut Seen| seen.0.extend(events);
+        let writer = |mut events: EventWriter<u32>, outbox: &mut Outbox| {
+            events.send_batch(outbox.0.drain(..));
+        };
+        let after = |events: EventReader<u32>, seen: &mut SeenToo| seen.0.extend(events);
+        let schedule = exe.schedule().then(before).then(writer).then(after).build();
+
+        exe.execute(&schedule, &mut world);
+        assert!(exe.get_resource::<Seen>().unwrap().0.is_empty());
+        assert_eq!(vec![1, 2], exe.get_resource::<SeenToo>().unwrap().0);
+
+        exe.get_resource_mut::<Outbox>().unwrap().0.push(3);
+        exe.execute_sequential(&schedule, &mut world);
+        assert_eq!(vec![1, 2], exe.get_resource::<Seen>().unwrap().0);
+        assert_eq!(vec![1, 2, 3], exe.get_resource::<SeenToo>().unwrap().0);
+
+        exe.execute(&schedule, &mut world);
+        exe.execute(&schedule, &mut world);
+        assert_eq!(vec![1, 2, 3], exe.get_resource::<Seen>().unwrap().0);
+        assert_eq!(vec![1, 2, 3], exe.get_resource::<SeenToo>().unwrap().0);
+    }
+
+    #[test]
+    fn events_dropped_after_two_executes() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_events::<u32>();
+        let schedule = exe
+            .schedule()
+            .then(|mut events: EventWriter<u32>, sent: &mut bool| {
+                if !std::mem::replace(sent, true) {
+                    events.send(1);
+                }
+            })
+            .build();
+        exe.add_resource(false);
+        exe.add_resource(Seen::default());
+        // Sent between executes, kept for two executes as well
+        exe.get_resource_mut::<Events<u32>>().unwrap().send(0);
+        let kept = |exe: &mut Executor| {
+            exe.run_once(
+                &mut World::new(),
+                |events: EventReader<u32>, seen: &mut Seen| {
+                    seen.0 = events.iter().copied().collect();
+                },
+            );
+            std::mem::take(&mut exe.get_resource_mut::<Seen>().unwrap().0)
+        };
+        // Until the end of the execute after the one they were sent in
+        exe.execute(&schedule, &mut world);
+        assert_eq!(vec![0, 1], kept(&mut exe));
+        exe.execute(&schedule, &mut world);
+        assert!(kept(&mut exe).is_empty());
+        assert!(exe.get_resource::<Events<u32>>().unwrap().is_empty());
+    }
+}
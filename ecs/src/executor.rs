@@ -1,14 +1,30 @@
 use std::{
     any::{Any, TypeId},
-    collections::{HashMap, HashSet},
-    sync::{atomic::AtomicU64, Arc}, cell::UnsafeCell,
+    cell::UnsafeCell,
+    collections::{HashMap, HashSet, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use slotmap::{SecondaryMap, SlotMap};
+use parking_lot::Mutex;
+use slotmap::SlotMap;
 
 use crate::{
-    system::{IntoSystem, RequirementsMappings, System},
+    channel::{self, Backpressure, ChannelReader, ChannelWriter},
+    commands::CommandQueue,
+    local::Locals,
+    error::{EcsError, SystemError},
+    events::{self, Events},
+    schedule::{Schedule, Scheduler, Step},
+    system::{IntoCondition, IntoSystem, RequirementsMappings, System},
     thread_pool::{Job, ThreadPool, Wait},
+    trace::{ExecutionTrace, TraceEvent, TraceSink, WorkerTrace},
+    validate::{ValidationFailure, ValidationLog, LOG_LIMIT},
+    watchdog::{Slot, Watchdog},
     World, query::ResourceQuery,
 };
 
@@ -16,6 +32,20 @@ use crate::{
 pub struct ExecutionContext<'a> {
     pub executor: &'a Executor,
     pub world: &'a World,
+    /// Change tick of the previous run of the system being run (0 if it never ran)
+    pub(crate) last_run: u64,
+    /// Change tick of the current run of the system being run
+    pub(crate) this_run: u64,
+    /// World tick of the previous run of the system being run (0 if it never ran), see `Changed`
+    pub(crate) last_tick: u32,
+    /// World tick of the current run of the system being run
+    pub(crate) this_tick: u32,
+    /// Queue of the system being run, see `Commands`
+    pub(crate) commands: Option<&'a CommandQueue>,
+    /// The system being run, None outside of a schedule (see `EventReader`)
+    pub(crate) system: Option<SystemId>,
+    /// Values of the `Local` arguments of the system being run
+    pub(crate) locals: Option<&'a Locals>,
 }
 
 // Impl send and sync as the ExecutionContext will only be used when scheduled systems have been
@@ -23,39 +53,120 @@ pub struct ExecutionContext<'a> {
 unsafe impl<'a> Send for ExecutionContext<'a> {}
 unsafe impl<'a> Sync for ExecutionContext<'a> {}
 
-struct ExecutorJob {
-    steps: Vec<Step>,
-    waits: Arc<Vec<Wait>>,
-    // TODO: remove 'static
-    context: Arc<ExecutionContext<'static>>,
+struct ExecutorJob<'a> {
+    schedule: &'a Schedule,
+    steps: &'a [Step],
+    waits: &'a [Wait],
+    context: &'a ExecutionContext<'a>,
+    /// Set once a system panicked, the systems left are skipped. The waits and notifications go on
+    /// so that the other workers don't wait forever.
+    poisoned: &'a AtomicBool,
+    /// Slot of the worker, if the watchdog is enabled
+    watchdog: Option<(Arc<Slot>, Arc<Watchdog>)>,
+    /// Events of the worker, if tracing is enabled
+    trace: Option<WorkerTrace>,
+    /// When to give up on the waits, see `Executor::execute_with_timeout`
+    deadline: Option<Instant>,
+    /// The waits given up on, they poison the execution like a panic
+    timed_out: &'a Mutex<Vec<usize>>,
 }
 
-impl Job for ExecutorJob {
+impl Job for ExecutorJob<'_> {
     fn execute(self) {
-        for step in self.steps {
+        let mut trace = self.trace;
+        let mut panic = None;
+        for &step in self.steps {
+            let start = trace.as_ref().map_or(Duration::ZERO, WorkerTrace::now);
             match step {
                 Step::Wait(index) => {
                     log::trace!("ExecutorWorker: Waiting ({index})");
-                    self.waits[index].wait();
+                    match self.deadline {
+                        Some(deadline) => {
+                            if !self.waits[index].wait_until(deadline) {
+                                log::error!("ExecutorWorker: timed out waiting ({index})");
+                                self.poisoned.store(true, Ordering::Release);
+                                self.timed_out.lock().push(index);
+                            }
+                        }
+                        None => self.waits[index].wait(),
+                    }
+                    if let Some(trace) = &mut trace {
+                        let end = trace.now();
+                        trace.push(TraceEvent::Wait { index, start, end });
+                    }
                 }
                 Step::Notify(index) => {
                     log::trace!("ExecutorWorker: notifying ({index})");
+                    if let Some(trace) = &mut trace {
+                        trace.push(TraceEvent::Notify { index, at: start });
+                    }
                     self.waits[index].notify();
                 }
                 Step::Run(id) => {
-                    log::trace!("ExecutorWorker: running ({id:?})");
+                    if self.poisoned.load(Ordering::Acquire) {
+                        log::trace!("ExecutorWorker: skipping ({id:?})");
+                        continue;
+                    }
                     let system = self.context.executor.get_system(id).unwrap();
+                    let phase = self.schedule.label(id);
+                    if let Err(reason) = self.schedule.gate(self.context.executor, id) {
+                        log::trace!("ExecutorWorker: skipping ({id:?}): {reason}");
+                        if let Some(trace) = &mut trace {
+                            trace.system(id, system.name(), phase, start, Err(reason));
+                        }
+                        continue;
+                    }
+                    log::trace!("ExecutorWorker: running ({id:?})");
+                    if let Some((slot, watchdog)) = &self.watchdog {
+                        slot.start(watchdog.clock.now(), id);
+                    }
                     // SAFETY: Run Steps only exist in schedules, and schedules enforce no
                     // aliasing.
-                    unsafe {
-                        system.run(&self.context);
+                    let context = ExecutionContext {
+                        system: Some(id),
+                        ..*self.context
+                    };
+                    let result =
+                        panic::catch_unwind(AssertUnwindSafe(|| unsafe { system.run(&context) }));
+                    if let Some((slot, _)) = &self.watchdog {
+                        slot.end();
+                    }
+                    match result {
+                        Ok(result) => {
+                            if let Some(trace) = &mut trace {
+                                trace.system(id, system.name(), phase, start, result);
+                            }
+                        }
+                        Err(payload) => {
+                            self.poisoned.store(true, Ordering::Release);
+                            panic.get_or_insert_with(|| system_panic(system.name(), payload));
+                        }
                     }
                 }
             }
         }
+        if let Some(trace) = trace {
+            trace.finish();
+        }
+        // Resumed by the thread pool's scope, once every worker is done
+        if let Some(payload) = panic {
+            panic::resume_unwind(payload);
+        }
     }
 }
 
+/// The payload of a system's panic, its message prefixed with the name of the system
+fn system_panic(name: &str, payload: Box<dyn Any + Send>) -> Box<dyn Any + Send> {
+    let message = match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.as_str(),
+            None => "Box<dyn Any>",
+        },
+    };
+    Box::new(format!("System {name} panicked: {message}"))
+}
+
 pub trait Resource: 'static + Any + Send {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
@@ -71,6 +182,12 @@ impl<T: 'static + Any + Send> Resource for T {
     }
 }
 
+/// A resource, and the change tick of the last mutable access to it
+pub(crate) struct ResourceSlot {
+    value: Box<dyn Resource>,
+    pub(crate) changed: AtomicU64,
+}
+
 slotmap::new_key_type! {
     pub struct SystemId;
 }
@@ -95,14 +212,65 @@ impl Default for ExecutorId {
     }
 }
 
+/// What happens when a system's arguments can't be fetched (a resource is missing)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FetchPolicy {
+    #[default]
+    Panic,
+    /// Skip the system and record the error (see `Executor::take_system_errors`)
+    Skip,
+}
+
+/// A closure given exclusive access to the world and the executor, see `Executor::enqueue_external`
+pub type ExternalFn = Box<dyn FnOnce(&mut World, &mut Executor) + Send>;
+
+/// The queue of an executor's external closures. Clones push to the same queue, from any thread.
+#[derive(Clone, Default)]
+pub struct ExternalQueue(Arc<Mutex<VecDeque<ExternalFn>>>);
+
+impl ExternalQueue {
+    /// Run `f` at the start of the next execute of the executor, after the closures already queued
+    pub fn push(&self, f: impl FnOnce(&mut World, &mut Executor) + Send + 'static) {
+        self.0.lock().push_back(Box::new(f));
+    }
+    pub fn len(&self) -> usize {
+        self.0.lock().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().is_empty()
+    }
+    fn take(&self) -> VecDeque<ExternalFn> {
+        std::mem::take(&mut *self.0.lock())
+    }
+}
+
 /// A struct holding systems and resources
 pub struct Executor {
     id: ExecutorId,
     // Unsafecell because of get_resource_mut_unchecked (obtains a &mut R from a &self)
-    resources: UnsafeCell<HashMap<TypeId, Box<dyn Resource>>>,
+    resources: UnsafeCell<HashMap<TypeId, ResourceSlot>>,
+    /// Source of the change ticks of resources, bumped for every system run and every mutable
+    /// access from outside systems. 64 bits as it moves much faster than the world's tick.
+    change_tick: AtomicU64,
     systems: SlotMap<SystemId, System>,
     mappings: RequirementsMappings,
-    thread_pool: ThreadPool<ExecutorJob>,
+    thread_pool: ThreadPool,
+    /// Maximum number of workers, see `set_worker_threads`
+    worker_threads: Option<usize>,
+    watchdog: Option<Arc<Watchdog>>,
+    fetch_policy: FetchPolicy,
+    /// Systems skipped since the last take_system_errors
+    system_errors: Mutex<Vec<SystemError>>,
+    tracing: bool,
+    last_trace: Option<ExecutionTrace>,
+    /// Labels whose systems are skipped, see `set_label_enabled`
+    disabled_labels: HashSet<&'static str>,
+    validate: bool,
+    validation_log: ValidationLog,
+    last_validation: Vec<ValidationFailure>,
+    external: ExternalQueue,
+    /// Updates of the events added with `add_events`
+    events: Vec<fn(&mut Executor)>,
 }
 
 impl Executor {
@@ -110,59 +278,246 @@ impl Executor {
         Self {
             id: ExecutorId::new(),
             resources: UnsafeCell::new(HashMap::new()),
+            change_tick: AtomicU64::new(0),
             systems: SlotMap::with_key(),
             mappings: RequirementsMappings::new(),
             thread_pool: ThreadPool::new(),
+            worker_threads: None,
+            watchdog: None,
+            fetch_policy: FetchPolicy::default(),
+            system_errors: Mutex::new(Vec::new()),
+            tracing: false,
+            last_trace: None,
+            disabled_labels: HashSet::new(),
+            validate: cfg!(debug_assertions),
+            validation_log: ValidationLog::default(),
+            last_validation: Vec::new(),
+            external: ExternalQueue::default(),
+            events: Vec::new(),
+        }
+    }
+    /// Set what happens when a system's arguments can't be fetched
+    pub fn set_fetch_policy(&mut self, policy: FetchPolicy) {
+        self.fetch_policy = policy;
+    }
+    /// The systems skipped (with `FetchPolicy::Skip`) since the last call, in the order they were
+    /// skipped
+    pub fn take_system_errors(&mut self) -> Vec<SystemError> {
+        std::mem::take(self.system_errors.get_mut())
+    }
+    /// Called by a system whose arguments can't be fetched, instead of running
+    pub(crate) fn fetch_failed(&self, system: &'static str, error: EcsError) {
+        match self.fetch_policy {
+            FetchPolicy::Panic => panic!("{error}"),
+            FetchPolicy::Skip => {
+                log::error!("Skipping system {system}: {error}");
+                self.system_errors
+                    .lock()
+                    .push(SystemError { system, error });
+            }
+        }
+    }
+    /// Warn about systems running for longer than budget, and give that budget to `Budget`
+    /// arguments (see `watchdog`). None, the default, disables the watchdog and its (small)
+    /// overhead.
+    pub fn set_system_time_budget(&mut self, budget: Option<Duration>) {
+        self.watchdog = budget.map(|budget| {
+            let watchdog = Arc::new(Watchdog::new(budget));
+            for (id, system) in &self.systems {
+                watchdog.name_system(id, system.name());
+            }
+            watchdog.spawn_monitor();
+            watchdog
+        });
+    }
+    pub(crate) fn watchdog(&self) -> Option<&Watchdog> {
+        self.watchdog.as_deref()
+    }
+    /// Record what every worker does during `execute` and `execute_sequential` (see `trace`).
+    /// Disabled by default, disabling drops the last trace.
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.tracing = enabled;
+        if !enabled {
+            self.last_trace = None;
+        }
+    }
+    /// Trace of the last execute, if tracing is enabled
+    pub fn last_trace(&self) -> Option<&ExecutionTrace> {
+        self.last_trace.as_ref()
+    }
+    /// Skip the systems given this label in any schedule (see `Scheduler::then_labeled`) until
+    /// it is enabled again (`SkipReason::Disabled`). Labels are enabled by default.
+    pub fn set_label_enabled(&mut self, label: &'static str, enabled: bool) {
+        if enabled {
+            self.disabled_labels.remove(label);
+        } else {
+            self.disabled_labels.insert(label);
+        }
+    }
+    pub(crate) fn label_disabled(&self, label: &'static str) -> bool {
+        !self.disabled_labels.is_empty() && self.disabled_labels.contains(label)
+    }
+    /// Run the validators of the world (see `World::validate`) after each execute and log what
+    /// fails, with the systems that ran. Enabled by default in debug builds, a failure that
+    /// persists is only logged every few hundred executes.
+    pub fn set_validate_after_execute(&mut self, enabled: bool) {
+        self.validate = enabled;
+        if !enabled {
+            self.last_validation.clear();
+        }
+    }
+    /// Failures of the validation after the last execute, if enabled
+    pub fn last_validation(&self) -> &[ValidationFailure] {
+        &self.last_validation
+    }
+    fn validate(&mut self, schedule: &Schedule, world: &World) {
+        if !self.validate {
+            return;
+        }
+        self.last_validation = world.validate();
+        let shown = self.validation_log.filter(&self.last_validation);
+        if shown.is_empty() {
+            return;
+        }
+        // The trace tells which systems actually ran, otherwise the schedule tells which could
+        let systems = match &self.last_trace {
+            Some(trace) => trace
+                .events()
+                .filter_map(|(_, event)| match event {
+                    TraceEvent::Run { name, .. } => Some(name.to_string()),
+                    TraceEvent::Skipped { name, .. } => Some(format!("{name} (skipped)")),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            None => schedule
+                .order
+                .iter()
+                .filter_map(|&id| self.get_system(id))
+                .map(|system| system.name().to_owned())
+                .collect(),
+        };
+        let systems = systems.join(", ");
+        for failure in shown.iter().take(LOG_LIMIT) {
+            log::error!("Validation failed after [{systems}]: {failure}");
+        }
+        if shown.len() > LOG_LIMIT {
+            log::error!("{} more validation failures", shown.len() - LOG_LIMIT);
         }
     }
 
     #[inline(always)]
-    fn resources<'a>(&'a self) -> &'a HashMap<TypeId, Box<dyn Resource>> {
+    fn resources(&self) -> &HashMap<TypeId, ResourceSlot> {
         unsafe { &*self.resources.get() }
     }
     #[inline(always)]
-    fn resources_mut(&mut self) -> &mut HashMap<TypeId, Box<dyn Resource>> {
+    fn resources_mut(&mut self) -> &mut HashMap<TypeId, ResourceSlot> {
         self.resources.get_mut()
     }
+    /// Mutable access to the resources through a shared reference.
+    ///
+    /// # Safety
+    ///
+    /// The map must not be added to or removed from while the returned reference lives, and the
+    /// caller must only touch the values it has exclusive access to (the borrows of the systems
+    /// being run, see `get_resource_mut_unchecked`).
+    #[inline(always)]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn resources_mut_unchecked(&self) -> &mut HashMap<TypeId, ResourceSlot> {
+        &mut *self.resources.get()
+    }
+    /// Take a new change tick
     #[inline(always)]
-    fn resources_mut_unchecked(&self) -> &mut HashMap<TypeId, Box<dyn Resource>> {
-        unsafe { &mut *self.resources.get() }
+    pub(crate) fn next_change_tick(&self) -> u64 {
+        self.change_tick.fetch_add(1, Ordering::Relaxed) + 1
+    }
+    /// The current change tick, to be compared later with `resource_changed_since`
+    pub fn change_tick(&self) -> u64 {
+        self.change_tick.load(Ordering::Relaxed)
+    }
+    /// Whether a resource was mutably accessed after `tick` (see `change_tick`), false if there
+    /// is no such resource. Any mutable access counts, even if it didn't change the value.
+    pub fn resource_changed_since<T: Resource>(&self, tick: u64) -> bool {
+        self.resources()
+            .get(&TypeId::of::<T>())
+            .is_some_and(|slot| slot.changed.load(Ordering::Relaxed) > tick)
+    }
+    pub(crate) fn resource_slot<T: Resource>(&self) -> Option<&ResourceSlot> {
+        self.resources().get(&TypeId::of::<T>())
+    }
+    /// Get a resource and its change tick without any checks for aliasing and without marking it
+    /// changed.
+    ///
+    /// # Safety
+    ///
+    /// Same as `get_resource_mut_unchecked`
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) unsafe fn resource_mut_untracked<T: Resource>(
+        &self,
+    ) -> Option<(&mut T, &AtomicU64)> {
+        self.resources_mut_unchecked()
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|slot| {
+                let changed = &slot.changed;
+                slot.value
+                    .as_mut()
+                    .as_any_mut()
+                    .downcast_mut::<T>()
+                    .map(|value| (value, changed))
+            })
     }
 
+    /// Add a resource to the executor
+    ///
+    /// # Panics
+    ///
+    /// This panics if the executor already has a resource of this type
     pub fn add_resource<T: Resource>(&mut self, res: T) {
+        self.try_add_resource(res)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+    /// Add a resource to the executor, fails if it already has a resource of this type
+    pub fn try_add_resource<T: Resource>(&mut self, res: T) -> Result<(), EcsError> {
         if self.resources().contains_key(&TypeId::of::<T>()) {
-            panic!(
-                "Trying to add resource that is already in executor: {}",
-                std::any::type_name::<T>()
-            );
+            return Err(EcsError::DuplicateResource {
+                type_name: std::any::type_name::<T>(),
+            });
         }
-        self.resources_mut().insert(res.type_id(), Box::new(res));
+        let changed = AtomicU64::new(self.next_change_tick());
+        self.resources_mut().insert(
+            res.type_id(),
+            ResourceSlot {
+                value: Box::new(res),
+                changed,
+            },
+        );
+        Ok(())
     }
 
     pub fn get_resource<T: Resource>(&self) -> Option<&T> {
         self.resources()
             .get(&TypeId::of::<T>())
             // For some reason the as_ref here is absolutely necessary
-            .and_then(|boxed| boxed.as_ref().as_any().downcast_ref::<T>())
+            .and_then(|slot| slot.value.as_ref().as_any().downcast_ref::<T>())
     }
-    /// Get a mutable reference to a resource without any checks for aliasing.
+    /// Get a mutable reference to a resource without any checks for aliasing. The resource is
+    /// marked changed.
     ///
     /// # Safety
     ///
     /// This bypasses rust aliasing checks, and is UB if the resource is already borrowed somewhere
     /// else.
+    #[allow(clippy::mut_from_ref)]
     pub unsafe fn get_resource_mut_unchecked<T: Resource>(&self) -> Option<&mut T> {
-        #[allow(clippy::cast_ref_to_mut)]
-        let s = &mut *(self as *const Self as *mut Self);
-        s.resources_mut_unchecked()
-            .get_mut(&TypeId::of::<T>())
-            // For some reason the as_mut here is absolutely necessary
-            .and_then(|boxed| boxed.as_mut().as_any_mut().downcast_mut::<T>())
+        let (value, changed) = self.resource_mut_untracked::<T>()?;
+        changed.store(self.next_change_tick(), Ordering::Relaxed);
+        Some(value)
     }
+    /// Get a mutable reference to a resource, marking it changed
     pub fn get_resource_mut<T: Resource>(&mut self) -> Option<&mut T> {
-        self.resources_mut()
-            .get_mut(&TypeId::of::<T>())
-            .and_then(|boxed| boxed.as_mut().as_any_mut().downcast_mut::<T>())
+        let tick = self.next_change_tick();
+        let slot = self.resources_mut().get_mut(&TypeId::of::<T>())?;
+        *slot.changed.get_mut() = tick;
+        slot.value.as_mut().as_any_mut().downcast_mut::<T>()
     }
     /// Query the executor for a tuple of (possibly mutable) references of resources. If any of the
     /// requested resource is missing this function returns None.
@@ -171,14 +526,21 @@ impl Executor {
     ///
     /// This panics if the query borrows a resource multiple times with at least a mutable one.
     pub fn query_resources<'a, Q: ResourceQuery<'a>>(&'a mut self) -> Option<Q> {
+        match self.try_query_resources() {
+            Ok(query) => Some(query),
+            Err(EcsError::MissingResource { .. }) => None,
+            Err(err) => panic!("{err}"),
+        }
+    }
+    /// Query the executor for a tuple of (possibly mutable) references of resources, fails if a
+    /// resource is missing or if the query borrows a resource multiple times with at least a
+    /// mutable one.
+    pub fn try_query_resources<'a, Q: ResourceQuery<'a>>(&'a mut self) -> Result<Q, EcsError> {
         Q::fetch(self)
     }
     /// Get a scheduler used to build a schedule
-    pub fn schedule(&mut self) -> Scheduler {
-        Scheduler {
-            executor: self,
-            systems: Vec::new(),
-        }
+    pub fn schedule(&mut self) -> Scheduler<'_> {
+        Scheduler::new(self)
     }
     /// Create a schedule for a single system
     pub fn schedule_single<A>(&mut self, sys: impl IntoSystem<A>) -> Schedule {
@@ -191,284 +553,318 @@ impl Executor {
     ///
     /// Calling this multiple times with the same system returns a new id every time.
     pub fn add_system<A>(&mut self, sys: impl IntoSystem<A>) -> SystemId {
-        self.systems.insert(sys.into_system(&mut self.mappings))
+        let system = sys.into_system(&mut self.mappings);
+        self.insert_system(system)
+    }
+    /// Add a system that only runs when condition returns true, see `Scheduler::then_if`
+    pub fn add_system_if<A, C>(
+        &mut self,
+        sys: impl IntoSystem<A>,
+        condition: impl IntoCondition<C>,
+    ) -> SystemId {
+        let mut system = sys.into_system(&mut self.mappings);
+        system.set_condition(condition.into_condition(&mut self.mappings));
+        self.insert_system(system)
+    }
+    fn insert_system(&mut self, system: System) -> SystemId {
+        let name = system.name();
+        let id = self.systems.insert(system);
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.name_system(id, name);
+        }
+        id
+    }
+    /// Queue a closure to run at the start of the next execute (`execute`, `try_execute` or
+    /// `execute_sequential`), before its first system. Closures run in the order they were queued,
+    /// the ones they queue themselves wait for the execute after.
+    pub fn enqueue_external(&self, f: impl FnOnce(&mut World, &mut Executor) + Send + 'static) {
+        self.external.push(f);
     }
-    fn get_system(&self, sys: SystemId) -> Option<&System> {
+    /// A handle to the queue of `enqueue_external`, for other threads
+    pub fn external_queue(&self) -> ExternalQueue {
+        self.external.clone()
+    }
+    /// Create a channel holding up to `capacity` items, its halves are meant to be added as
+    /// resources to two executors (see the `channel` module)
+    pub fn create_channel<T: Send + 'static>(
+        capacity: usize,
+        backpressure: Backpressure,
+    ) -> (ChannelWriter<T>, ChannelReader<T>) {
+        channel::channel(capacity, backpressure)
+    }
+    /// Add an `Events<T>` resource, for the `EventWriter<T>` and `EventReader<T>` system arguments.
+    /// Its events are dropped at the end of the execute after the one they were sent in.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the executor already has events of this type
+    pub fn add_events<T: Resource>(&mut self) {
+        self.add_resource(Events::<T>::new());
+        self.events.push(events::update::<T>);
+    }
+    fn update_events(&mut self) {
+        for update in self.events.clone() {
+            update(self);
+        }
+    }
+    /// Run the queued external closures, returns how many ran
+    fn run_external(&mut self, world: &mut World) -> usize {
+        let queued = self.external.take();
+        let count = queued.len();
+        for f in queued {
+            f(world, self);
+        }
+        count
+    }
+    /// Run the queued external closures, tracing them as the first event of the first worker
+    fn run_external_traced(&mut self, world: &mut World, trace: Option<&mut WorkerTrace>) {
+        let start = trace.as_ref().map_or(Duration::ZERO, |trace| trace.now());
+        let count = self.run_external(world);
+        if let Some(trace) = trace.filter(|_| count > 0) {
+            let end = trace.now();
+            trace.push(TraceEvent::External { count, start, end });
+        }
+    }
+    pub(crate) fn get_system(&self, sys: SystemId) -> Option<&System> {
         self.systems.get(sys)
     }
+    pub(crate) fn id(&self) -> ExecutorId {
+        self.id
+    }
     /// Run a given schedule against this executor and a world
     ///
     /// # Panics
     ///
-    /// Panics if the schedule wasn't built from this executor
+    /// Panics if the schedule wasn't built from this executor, or if a system's arguments can't be
+    /// fetched with `FetchPolicy::Panic`
     pub fn execute(&mut self, schedule: &Schedule, world: &mut World) {
+        self.try_execute(schedule, world)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+    /// Run a given schedule against this executor and a world, fails if the schedule wasn't built
+    /// from this executor. Systems can still panic, the fetch policy decides what happens when
+    /// their arguments can't be fetched. When a system panics, the systems that didn't start yet
+    /// are skipped and the panic is resumed once the others are done.
+    pub fn try_execute(&mut self, schedule: &Schedule, world: &mut World) -> Result<(), EcsError> {
+        self.execute_until(schedule, world, None)
+    }
+    /// Run a schedule like `try_execute`, but give up if a worker waits on the others for longer
+    /// than timeout. The systems left are then skipped, and the error gives the waits that were
+    /// still pending. A system that never returns still blocks.
+    pub fn execute_with_timeout(
+        &mut self,
+        schedule: &Schedule,
+        world: &mut World,
+        timeout: Duration,
+    ) -> Result<(), EcsError> {
+        self.execute_until(schedule, world, Some(Instant::now() + timeout))
+    }
+    fn execute_until(
+        &mut self,
+        schedule: &Schedule,
+        world: &mut World,
+        deadline: Option<Instant>,
+    ) -> Result<(), EcsError> {
         if schedule.executor_id != self.id {
-            panic!("Schedule wasn't built from correct executor");
+            return Err(EcsError::ForeignSchedule);
+        }
+        let sink = self.tracing.then(TraceSink::new);
+        let mut first_trace = sink.as_ref().map(|sink| sink.worker(0));
+        self.run_external_traced(world, first_trace.as_mut());
+        self.init_locals(schedule, world);
+        // After the external closures, which can spawn entities
+        let (threads, waits) = schedule.placement(self, world);
+        if self.worker_threads.is_some_and(|max| threads.len() > max) {
+            // Threads waiting on each other each need a worker
+            self.run_sequential(schedule, world, first_trace.as_mut());
+            return self.finish_execute(schedule, world, sink, first_trace);
         }
         // Make sure we have enough workers
-        self.thread_pool.ensure_workers(schedule.threads.len());
+        self.thread_pool.ensure_workers(threads.len());
 
-        let context = Arc::new(ExecutionContext {
+        let context = ExecutionContext {
             executor: self,
             world,
-        });
-        let jobs = schedule.threads.iter().map(|thread| {
-            ExecutorJob {
-                waits: schedule.waits.clone(),
-                // Transmute lifetime into static
-                // TODO: remove that once I've found a better way
-                context: unsafe { std::mem::transmute(context.clone()) },
-                steps: thread.to_vec(),
+            last_run: 0,
+            this_run: 0,
+            last_tick: 0,
+            this_tick: 0,
+            commands: None,
+            system: None,
+            locals: None,
+        };
+        let poisoned = AtomicBool::new(false);
+        let timed_out = Mutex::new(Vec::new());
+        // Returns once every job is done with the context, panics if a system did
+        self.thread_pool.scope(|scope| {
+            for (i, thread) in threads.iter().enumerate() {
+                scope.run(ExecutorJob {
+                    schedule,
+                    steps: thread,
+                    waits,
+                    context: &context,
+                    poisoned: &poisoned,
+                    watchdog: self.watchdog.as_ref().map(|w| (w.slot(i), w.clone())),
+                    trace: match i {
+                        0 => first_trace.take(),
+                        _ => sink.as_ref().map(|sink| sink.worker(i)),
+                    },
+                    deadline,
+                    timed_out: &timed_out,
+                });
             }
         });
-
-        self.thread_pool.run_many(jobs).wait();
+        let mut pending = timed_out.into_inner();
+        if !pending.is_empty() {
+            // Notifications came after the deadline or never did, start over next time
+            for wait in waits.iter() {
+                wait.reset();
+            }
+            pending.sort_unstable();
+            pending.dedup();
+            return Err(EcsError::Timeout { pending });
+        }
+        self.finish_execute(schedule, world, sink, first_trace)
     }
-    /// Execute a single system, note that the prefered mean of execution should be a schedule.
-    pub fn execute_single<A>(&mut self, sys: impl IntoSystem<A>, world: &mut World) {
-        let sys = sys.into_system(&mut self.mappings);
-        let context = ExecutionContext {
-            executor: self,
-            world,
-        };
-        // SAFETY: mutable borrow of both the world and the executor guarentee no aliasing for the
-        // system.
-        unsafe {
-            sys.run(&context);
+    /// What comes after the systems of `try_execute`
+    fn finish_execute(
+        &mut self,
+        schedule: &Schedule,
+        world: &mut World,
+        sink: Option<Arc<TraceSink>>,
+        first_trace: Option<WorkerTrace>,
+    ) -> Result<(), EcsError> {
+        // Empty schedule, or run sequentially
+        if let Some(trace) = first_trace {
+            trace.finish();
+        }
+        if let Some(sink) = sink {
+            self.last_trace = Some(sink.finish());
         }
+        self.apply_commands(schedule, world);
+        self.update_events();
+        self.validate(schedule, world);
+        Ok(())
     }
-}
-
-pub struct Scheduler<'a> {
-    executor: &'a mut Executor,
-    systems: Vec<SystemId>,
-}
-
-impl<'a> Scheduler<'a> {
-    /// Add a system to the building schedule
-    pub fn then<A>(mut self, sys: impl IntoSystem<A>) -> Self {
-        self.systems.push(self.executor.add_system(sys));
-        self
+    /// The pool the schedules run on, with at least workers workers (or the maximum set with
+    /// `set_worker_threads`)
+    pub(crate) fn thread_pool(&mut self, workers: usize) -> &ThreadPool {
+        let workers = self.worker_threads.map_or(workers, |max| workers.min(max));
+        self.thread_pool.ensure_workers(workers);
+        &self.thread_pool
     }
-    /// Add a registred system to the building schedule. This sould be avoided in favor of
-    /// Scheduler::then.
+    /// Use at most `threads` worker threads (at least 1), schedules needing more run on the
+    /// calling thread like `execute_sequential`. None, the default, starts as many workers as the
+    /// schedules need.
+    pub fn set_worker_threads(&mut self, threads: Option<usize>) {
+        self.worker_threads = threads.map(|threads| threads.max(1));
+        if let Some(max) = self.worker_threads {
+            if self.thread_pool.worker_count() > max {
+                self.thread_pool = ThreadPool::with_threads(max);
+            }
+        }
+    }
+    /// Run a schedule on the calling thread, one system after the other in the order they were
+    /// added. Slower than `execute`, but the reference a parallel execution should match.
     ///
     /// # Panics
     ///
-    /// This panics if the system is already in the schedule, or if the system isn't registered in
-    /// the executor.
-    pub fn then_by_id(mut self, sys: SystemId) -> Self {
-        if self.executor.get_system(sys).is_none() {
-            panic!("System isn't registered in executor");
+    /// Panics if the schedule wasn't built from this executor, or if a system's arguments can't be
+    /// fetched with `FetchPolicy::Panic`
+    pub fn execute_sequential(&mut self, schedule: &Schedule, world: &mut World) {
+        if schedule.executor_id != self.id {
+            panic!("{}", EcsError::ForeignSchedule);
         }
-        if self.systems.contains(&sys) {
-            panic!("System is already in schedule");
+        let sink = self.tracing.then(TraceSink::new);
+        let mut trace = sink.as_ref().map(|sink| sink.worker(0));
+        self.run_external_traced(world, trace.as_mut());
+        self.init_locals(schedule, world);
+        self.run_sequential(schedule, world, trace.as_mut());
+        if let (Some(sink), Some(trace)) = (sink, trace) {
+            trace.finish();
+            self.last_trace = Some(sink.finish());
         }
-        self.systems.push(sys);
-        self
+        self.apply_commands(schedule, world);
+        self.update_events();
+        self.validate(schedule, world);
     }
-    /// Run the closure F with the scheduler
-    #[inline(always)]
-    pub fn with<F: FnOnce(Self) -> Self>(self, f: F) -> Self {
-        f(self)
-    }
-    /// Create a schedule from the added systems, the schedule is parallelized as much as possible
-    /// while keeping the same behaviour as if the systems were run sequentially.
-    ///
-    /// # Note
-    ///
-    /// Fairely expensive, and unoptimized, should only be called a few times
-    pub fn build(mut self) -> Schedule {
-        if self.systems.is_empty() {
-            return Schedule {
-                executor_id: self.executor.id,
-                threads: Arc::new(Vec::new()),
-                waits: Arc::new(Vec::new()),
+    /// Run the systems of a schedule one after the other on the calling thread
+    fn run_sequential(
+        &mut self,
+        schedule: &Schedule,
+        world: &mut World,
+        mut trace: Option<&mut WorkerTrace>,
+    ) {
+        let context = ExecutionContext {
+            executor: self,
+            world,
+            last_run: 0,
+            this_run: 0,
+            last_tick: 0,
+            this_tick: 0,
+            commands: None,
+            system: None,
+            locals: None,
+        };
+        for &id in &schedule.order {
+            let system = self.get_system(id).unwrap();
+            let start = trace.as_ref().map_or(Duration::ZERO, |trace| trace.now());
+            // SAFETY: mutable borrow of both the world and the executor guarentee no aliasing, and
+            // only one system runs at a time.
+            let context = ExecutionContext {
+                system: Some(id),
+                ..context
             };
-        }
-
-        let mut deps: SecondaryMap<SystemId, Vec<SystemId>> = SecondaryMap::new();
-        let mut depths: SecondaryMap<SystemId, u32> = SecondaryMap::new();
-
-        // find dependencies between systems
-        for (i, sys_id) in self.systems.iter().enumerate() {
-            let sys = self.executor.get_system(*sys_id).unwrap();
-            deps.insert(*sys_id, Vec::new());
-            // Loop over all the systems that come before
-            for other_id in &self.systems[0..i] {
-                let other = self.executor.get_system(*other_id).unwrap();
-
-                if sys.depends_on(other) {
-                    deps.get_mut(*sys_id).unwrap().push(*other_id)
-                }
-            }
-        }
-        // remove implicit dependencies
-        for sys_id in &self.systems {
-            // Take the dependencies from the map (and convert to set)
-            let mut sys_deps = deps
-                .remove(*sys_id)
-                .unwrap()
-                .into_iter()
-                .collect::<HashSet<_>>();
-
-            for dep in sys_deps.clone() {
-                // A set of all the systems this dependencies implies
-                let mut implies: HashSet<SystemId> = HashSet::new();
-
-                // Get all the depenencies of this dep, including sub depenencies
-                fn recurse_dependencies(
-                    id: SystemId,
-                    set: &mut HashSet<SystemId>,
-                    deps: &SecondaryMap<SystemId, Vec<SystemId>>,
-                ) {
-                    for dep in &deps[id] {
-                        set.insert(id);
-                        recurse_dependencies(*dep, set, deps);
-                    }
-                }
-                recurse_dependencies(dep, &mut implies, &deps);
-                // remove all implied depenencies from the original dep list
-                for implied in implies {
-                    sys_deps.remove(&implied);
-                }
-            }
-            // put new list back into the map
-            deps.insert(*sys_id, sys_deps.into_iter().collect::<Vec<_>>());
-        }
-
-        // compute depth of systems
-        while !self.systems.is_empty() {
-            let sys_id = self.systems.remove(0);
-
-            let deps = &deps[sys_id];
-            if deps.is_empty() {
-                // System has no dependency, its depths is 0
-                depths.insert(sys_id, 0);
-            } else {
-                // Get the maximum depth of all the dependencies, or None if not all the
-                // dependencies's depths are known.
-                let max_depth = deps
-                    .iter()
-                    .map(|id| depths.get(*id).copied())
-                    .reduce(|acc, item| acc.and_then(|acc| item.map(|item| acc.max(item))))
-                    .unwrap();
-                match max_depth {
-                    // if we have a max, add one and set that as the depth
-                    Some(m) => {
-                        depths.insert(sys_id, m + 1);
-                    }
-                    // if we don't, put back the system into the array to try again later
-                    None => {
-                        self.systems.push(sys_id);
-                    }
-                }
+            let result = schedule
+                .gate(self, id)
+                .and_then(|()| unsafe { system.run(&context) });
+            if let Some(trace) = &mut trace {
+                trace.system(id, system.name(), schedule.label(id), start, result);
             }
         }
-
-        let mut depths = depths.into_iter().collect::<Vec<_>>();
-        depths.sort_by_key(|v| v.1);
-        // Get the systems sorted by depth
-        let systems = depths.into_iter().map(|v| v.0).collect::<Vec<_>>();
-
-        let mut threads: Vec<Vec<Step>> = Vec::new();
-        let mut waits: Vec<Wait> = Vec::new();
-
-        for sys in systems {
-            let deps = deps[sys].iter().copied().collect::<HashSet<_>>();
-
-            // If a suitable thread has been found
-            let mut found = false;
-            // The index of the thread the Run has been put
-            let mut step_thread = 0usize;
-            // The index of the step the run is in the thread
-            let mut step_index = 0usize;
-
-            'outer: for dep in deps.clone() {
-                for (i, steps) in threads.iter_mut().enumerate() {
-                    let last_run = steps
-                        .iter()
-                        .filter_map(|step| {
-                            if let Step::Run(sys) = step {
-                                Some(sys)
-                            } else {
-                                None
-                            }
-                        })
-                        .last()
-                        .copied()
-                        .unwrap(); // threads have always atleast one Step::Run(...)
-                    if last_run == dep {
-                        // thread is suitable
-                        found = true;
-                        step_thread = i;
-                        step_index = steps.len();
-                        steps.push(Step::Run(sys));
-                        break 'outer;
-                    }
-                }
-            }
-            // No suitable thread found
-            if !found {
-                step_thread = threads.len();
-                step_index = 0;
-                threads.push(vec![Step::Run(sys)]);
-            }
-
-            // Here we have placed the Run at index <index> of thread <thread>, we now need to
-            // ensure that all dependencies are satisfied through syncronizations steps.
-
-            for dep in deps {
-                // Check the current thread for the dependency
-                let in_thread = threads[step_thread].contains(&Step::Run(dep));
-
-                if !in_thread {
-                    // then sync is needed
-                    // loop over the threads looking for the one that contains the dependency
-                    for (thread_index, mut dep_thread) in threads.iter_mut().enumerate() {
-                        let index = dep_thread.iter().position(|step| {
-                            if let Step::Run(s) = step {
-                                *s == dep
-                            } else {
-                                false
-                            }
-                        });
-                        if let Some(index) = index {
-                            let wait = {
-                                // If there is already a wait before the run, then this is its
-                                // index
-                                let wait_index = step_index.saturating_sub(1);
-                                let wait;
-
-                                if let Step::Wait(w) = threads[step_thread][wait_index] {
-                                    let new_limit = waits[w].limit() + 1;
-                                    waits[w].set_limit(new_limit);
-                                    wait = w;
-                                } else {
-                                    // there is no wait, we add one
-                                    let w = Wait::new(1);
-                                    wait = waits.len();
-                                    waits.push(w);
-                                    threads[step_thread].insert(step_index, Step::Wait(wait));
-                                    step_index += 1;
-                                }
-
-                                dep_thread = &mut threads[thread_index];
-                                wait
-                            };
-
-                            dep_thread.insert(index + 1, Step::Notify(wait));
-                            break;
-                        }
-                    }
-                }
+    }
+    /// Execute a single system, note that the prefered mean of execution should be a schedule.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the system's arguments can't be fetched with `FetchPolicy::Panic`
+    pub fn execute_single<A>(&mut self, sys: impl IntoSystem<A>, world: &mut World) {
+        self.run_once(world, sys);
+    }
+    /// Run a system once, for one-off access to the world and the resources between executes
+    /// with the usual system arguments. The system isn't kept, and its types aren't added to the
+    /// mappings of the schedules.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the system's arguments can't be fetched with `FetchPolicy::Panic`
+    pub fn run_once<A>(&mut self, world: &mut World, sys: impl IntoSystem<A>) {
+        // The requirements only matter to schedules
+        let sys = sys.into_system(&mut RequirementsMappings::new());
+        sys.init_locals(world, self);
+        let context = ExecutionContext {
+            executor: self,
+            world,
+            last_run: 0,
+            this_run: 0,
+            last_tick: 0,
+            this_tick: 0,
+            commands: None,
+            system: None,
+            locals: None,
+        };
+        // SAFETY: mutable borrow of both the world and the executor guarentee no aliasing for the
+        // system.
+        let _ = unsafe { sys.run(&context) };
+        sys.commands.apply(world);
+    }
+    /// Apply the commands of the systems of a schedule, in the order they were added
+    fn apply_commands(&self, schedule: &Schedule, world: &mut World) {
+        for &id in &schedule.order {
+            if let Some(system) = self.get_system(id) {
+                system.commands.apply(world);
             }
         }
-        Schedule {
-            executor_id: self.executor.id,
-            threads: Arc::new(threads),
-            waits: Arc::new(waits),
-        }
     }
 }
 
@@ -478,25 +874,14 @@ impl Default for Executor {
     }
 }
 
-#[derive(PartialEq, Clone, Copy)]
-enum Step {
-    /// Run a system
-    Run(SystemId),
-    /// Notify another thread (for sync)
-    Notify(usize),
-    /// Wait for notifications
-    Wait(usize),
-}
-
-pub struct Schedule {
-    executor_id: ExecutorId,
-    threads: Arc<Vec<Vec<Step>>>,
-    waits: Arc<Vec<Wait>>,
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        testing::TestBed, Added, Changed, ChangedRes, EcsError, Entities, Entity, FetchPolicy,
+        ResMut, SkipReason, With, Without,
+    };
+
     #[test]
     fn query() {
         let mut exe = Executor::new();
@@ -509,15 +894,15 @@ mod tests {
 
         assert_eq!(*i, 1);
         assert_eq!(*s, "Test!");
-        assert_eq!(*b, false);
+        assert!(!*b);
 
         *b = true;
 
-        assert_eq!(*exe.get_resource::<bool>().unwrap(), true);
+        assert!(*exe.get_resource::<bool>().unwrap());
     }
 
     #[test]
-    #[should_panic]
+    #[should_panic(expected = "Aliasing problem in query on i32")]
     fn query_aliasing() {
         let mut exe = Executor::new();
         
@@ -527,4 +912,708 @@ mod tests {
 
         let (_, _): (&i32, &mut i32) = exe.query_resources().unwrap();
     }
+
+    #[test]
+    fn try_variants() {
+        let mut exe = Executor::new();
+        exe.add_resource(1);
+        assert_eq!(
+            Err(EcsError::DuplicateResource { type_name: "i32" }),
+            exe.try_add_resource(2)
+        );
+        assert_eq!(1, *exe.get_resource::<i32>().unwrap());
+
+        let missing = exe.try_query_resources::<(&i32, &u8)>().map(|_| ());
+        assert_eq!(Err(EcsError::MissingResource { type_name: "u8" }), missing);
+        assert!(exe.query_resources::<(&i32, &u8)>().is_none());
+        let aliasing = exe.try_query_resources::<(&i32, &mut i32)>().map(|_| ());
+        assert_eq!(
+            Err(EcsError::BorrowConflict {
+                details: "Aliasing problem in query on i32".to_owned()
+            }),
+            aliasing
+        );
+
+        let mut other = Executor::new();
+        let schedule = other.schedule_single(|| {});
+        assert_eq!(
+            Err(EcsError::ForeignSchedule),
+            exe.try_execute(&schedule, &mut World::new())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Trying to add resource that is already in executor: i32")]
+    fn duplicate_resource() {
+        let mut exe = Executor::new();
+        exe.add_resource(1);
+        exe.add_resource(2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Schedule wasn't built from correct executor")]
+    fn foreign_schedule() {
+        let schedule = Executor::new().schedule_single(|| {});
+        Executor::new().execute(&schedule, &mut World::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "Resource not in system: u8")]
+    fn missing_resource() {
+        Executor::new().execute_single(|_: &u8| {}, &mut World::new());
+    }
+
+    #[test]
+    fn skip_missing_resources() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(0u32);
+        exe.add_resource(0u16);
+        exe.set_fetch_policy(FetchPolicy::Skip);
+        // The second system runs on another thread, and the last one waits for it: a skipped
+        // system must still notify its waiters
+        let schedule = exe
+            .schedule()
+            .then(|count: &mut u32| *count += 1)
+            .then(|_: &u8, other: &mut u16| *other += 1)
+            .then(|_: &u8, count: &mut u32| *count += 10)
+            .then(|_: ResMut<u8>, _: ChangedRes<u32>| {})
+            .then(|count: &mut u32, other: &u16| *count += 100 * *other as u32)
+            .build();
+        assert_eq!(2, schedule.report().threads);
+        assert_eq!(1, schedule.waits.len());
+        exe.execute(&schedule, &mut world);
+        assert_eq!(1, *exe.get_resource::<u32>().unwrap());
+        let errors = exe.take_system_errors();
+        assert_eq!(3, errors.len());
+        for error in errors {
+            assert_eq!(EcsError::MissingResource { type_name: "u8" }, error.error);
+        }
+        assert!(exe.take_system_errors().is_empty());
+
+        // They run once the resource is there
+        exe.add_resource(0u8);
+        exe.execute(&schedule, &mut world);
+        assert_eq!(112, *exe.get_resource::<u32>().unwrap());
+        assert!(exe.take_system_errors().is_empty());
+    }
+
+    #[test]
+    fn sequential() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(0u8);
+        exe.add_resource(0u16);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let log = |i: u32| {
+            let order = order.clone();
+            move || order.lock().push((i, std::thread::current().id()))
+        };
+        // Independent systems, the parallel schedule runs them on different threads
+        let schedule = exe
+            .schedule()
+            .then(log(0))
+            .then(log(1))
+            .then(|a: &mut u8, b: &mut u16| {
+                *a += 1;
+                *b += 1;
+            })
+            .then(log(2))
+            .build();
+        assert!(schedule.threads.len() > 1);
+        exe.execute_sequential(&schedule, &mut world);
+        exe.execute_sequential(&schedule, &mut world);
+        let order = order.lock();
+        let thread = std::thread::current().id();
+        let expected = [0, 1, 2, 0, 1, 2].map(|i| (i, thread));
+        assert_eq!(&expected[..], &order[..]);
+        assert_eq!(2, *exe.get_resource::<u8>().unwrap());
+    }
+
+    #[test]
+    fn cross_thread_wait() {
+        let mut bed = TestBed::new().parallel();
+        bed.with_resource(0u8).with_resource(0u16);
+        // The last system follows the first one, and waits for the second on another thread
+        let schedule = bed.schedule(|s| {
+            s.then(|a: &mut u8| *a += 1)
+                .then(|b: &mut u16| *b += 1)
+                .then(|a: &u8, b: &mut u16| *b += *a as u16)
+        });
+        assert_eq!(1, schedule.waits.len());
+        for _ in 0..100 {
+            bed.execute(&schedule);
+        }
+        assert_eq!(100, *bed.resource::<u8>());
+        assert_eq!(100 + 5050, *bed.resource::<u16>());
+    }
+
+    #[test]
+    fn wait_stress() {
+        let mut bed = TestBed::new().parallel();
+        bed.with_resource(0u32).with_resource(0u64);
+        bed.with_resource(0i32).with_resource(0i64);
+        bed.with_resource(0u16).with_resource(0i16);
+        bed.with_resource(0usize);
+        // Trivial systems, so that notifications often come before the waits
+        let schedule = bed.schedule(|s| {
+            s.then(|a: &mut u32| *a += 1)
+                .then(|b: &mut u64| *b += 1)
+                .then(|c: &mut i32| *c += 1)
+                .then(|d: &mut i64| *d += 1)
+                .then(|a: &u32, b: &mut u64| *b += *a as u64)
+                .then(|c: &i32, d: &mut i64| *d += *c as i64)
+                .then(|_: &u64, e: &mut u16| *e += 1)
+                .then(|_: &i64, f: &mut i16| *f += 1)
+                .then(|_: &u16, _: &i16, g: &mut usize| *g += 1)
+                .then(|a: &mut u32, c: &mut i32| {
+                    *a += 1;
+                    *c += 1;
+                })
+        });
+        assert!(schedule.report().threads > 1);
+        assert!(!schedule.waits.is_empty());
+        let runs = 1000;
+        for _ in 0..runs {
+            bed.execute(&schedule);
+        }
+        // Each run adds 2k to b and d
+        assert_eq!(2 * runs as u32, *bed.resource::<u32>());
+        assert_eq!(runs as u64 * (runs as u64 + 1), *bed.resource::<u64>());
+        assert_eq!(runs as i64 * (runs as i64 + 1), *bed.resource::<i64>());
+        assert_eq!(runs as u16, *bed.resource::<u16>());
+        assert_eq!(runs as i16, *bed.resource::<i16>());
+        assert_eq!(runs, *bed.resource::<usize>());
+    }
+
+    #[test]
+    fn timeout() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(0u8);
+        exe.add_resource(0u16);
+        // The last system follows the first one, and waits for the slow second on another thread
+        let schedule = exe
+            .schedule()
+            .then(|a: &mut u8| *a += 1)
+            .then(|b: &mut u16| {
+                std::thread::sleep(Duration::from_millis(100));
+                *b += 1;
+            })
+            .then(|a: &u8, b: &mut u16| *b += *a as u16)
+            .build();
+        assert_eq!(1, schedule.waits.len());
+        let result = exe.execute_with_timeout(&schedule, &mut world, Duration::from_millis(10));
+        assert_eq!(Err(EcsError::Timeout { pending: vec![0] }), result);
+        // The last system was skipped
+        assert_eq!(1, *exe.get_resource::<u8>().unwrap());
+        assert_eq!(1, *exe.get_resource::<u16>().unwrap());
+
+        // The late notification doesn't carry over
+        let result = exe.execute_with_timeout(&schedule, &mut world, Duration::from_secs(10));
+        assert_eq!(Ok(()), result);
+        assert_eq!(2, *exe.get_resource::<u8>().unwrap());
+        assert_eq!(4, *exe.get_resource::<u16>().unwrap());
+    }
+
+    #[test]
+    fn panic_mid_schedule() {
+        struct Started(AtomicBool);
+
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        world.spawn((0u32,));
+        exe.add_resource(0u8);
+        exe.add_resource(0u16);
+        exe.add_resource(Started(AtomicBool::new(false)));
+        // The first system panics while the second still uses the world on another thread, the
+        // last one waits for both
+        let schedule = exe
+            .schedule()
+            .then(|_: &mut u8, started: &Started| {
+                while !started.0.load(Ordering::SeqCst) {
+                    std::thread::yield_now();
+                }
+                panic!("system panicked");
+            })
+            .then(|b: &mut u16, started: &Started, entities: Entities<&mut u32>| {
+                started.0.store(true, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                entities.for_each(|v| *v += 1);
+                *b += 1;
+            })
+            .then(|a: &mut u8, b: &mut u16| {
+                *a += 1;
+                *b += 1;
+            })
+            .build();
+        assert_eq!(2, schedule.report().threads);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| exe.execute(&schedule, &mut world)));
+        let payload = result.unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("System ecs::executor::tests::panic_mid_schedule::"));
+        assert!(message.ends_with(" panicked: system panicked"));
+        // The second system was done before the panic got out of execute, the last was skipped
+        assert_eq!(1, *exe.get_resource::<u16>().unwrap());
+        assert_eq!(0, *exe.get_resource::<u8>().unwrap());
+        assert_eq!(vec![1], world.query::<&u32>().copied().collect::<Vec<_>>());
+
+        // Nothing is left waiting or borrowed
+        let schedule = exe
+            .schedule()
+            .then(|a: &mut u8| *a += 1)
+            .then(|a: &u8, b: &mut u16| *b += *a as u16)
+            .build();
+        exe.execute(&schedule, &mut world);
+        assert_eq!(1, *exe.get_resource::<u8>().unwrap());
+        assert_eq!(2, *exe.get_resource::<u16>().unwrap());
+        drop(world);
+        drop(exe);
+    }
+
+    #[test]
+    fn worker_threads() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(0u8);
+        exe.add_resource(0u16);
+        exe.add_resource(0u32);
+        // Two threads, the last system waits for both
+        let schedule = exe
+            .schedule()
+            .then(|a: &mut u8| *a += 1)
+            .then(|b: &mut u16| *b += 1)
+            .then(|a: &u8, b: &u16, c: &mut u32| *c += *a as u32 + *b as u32)
+            .build();
+        assert_eq!(2, schedule.report().threads);
+        exe.execute(&schedule, &mut world);
+        // Lowering the cap drops the extra workers, schedules needing more run sequentially
+        exe.set_worker_threads(Some(1));
+        assert_eq!(1, exe.thread_pool.worker_count());
+        exe.execute(&schedule, &mut world);
+        assert_eq!(1, exe.thread_pool.worker_count());
+        assert_eq!(6, *exe.get_resource::<u32>().unwrap());
+        exe.set_worker_threads(None);
+        exe.execute(&schedule, &mut world);
+        assert_eq!(2, exe.thread_pool.worker_count());
+        assert_eq!(12, *exe.get_resource::<u32>().unwrap());
+    }
+
+    /// Two systems mutably borrowing u32, on archetypes that never have both u8 and u16
+    fn refined(exe: &mut Executor, world: &World) -> Schedule {
+        exe.schedule()
+            .then(|entities: Entities<(&mut u32, &u8)>| entities.for_each(|(v, _)| *v += 1))
+            .then(|entities: Entities<(&mut u32, &u16)>| entities.for_each(|(v, _)| *v += 2))
+            .then(|entities: Entities<&u32>, sum: &mut u64| {
+                *sum = entities.map(|&v| v as u64).sum()
+            })
+            .build_for(world)
+    }
+
+    fn spawn_disjoint(world: &mut World) {
+        for _ in 0..1000 {
+            world.spawn((0u32, 0u8));
+            world.spawn((0u32, 0u16));
+        }
+    }
+
+    #[test]
+    fn refinement() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(0u64);
+        spawn_disjoint(&mut world);
+
+        let conservative = exe
+            .schedule()
+            .then(|_: Entities<(&mut u32, &u8)>| {})
+            .then(|_: Entities<(&mut u32, &u16)>| {})
+            .build();
+        assert_eq!(1, conservative.report().threads);
+        assert_eq!(0, conservative.report().relaxed);
+
+        let schedule = refined(&mut exe, &world);
+        let report = schedule.report();
+        assert_eq!(2, report.threads);
+        assert_eq!(1, report.relaxed);
+        assert!(!report.invalidated);
+        // Without the world's archetypes there is nothing to refine
+        let empty = refined(&mut exe, &World::new());
+        assert_eq!(3, empty.report().relaxed);
+
+        for run in 1..=200 {
+            exe.execute(&schedule, &mut world);
+            assert_eq!(run * 3000, *exe.get_resource::<u64>().unwrap());
+        }
+        assert!(!schedule.report().invalidated);
+    }
+
+    #[test]
+    fn filter_refinement() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        spawn_disjoint(&mut world);
+        let schedule = exe
+            .schedule()
+            .then(|entities: Entities<(&mut u32, With<u8>)>| entities.for_each(|(v, _)| *v += 1))
+            .then(|entities: Entities<(&mut u32, Without<u8>)>| entities.for_each(|(v, _)| *v += 2))
+            .build_for(&world);
+        assert_eq!(2, schedule.report().threads);
+        exe.execute(&schedule, &mut world);
+        let sum = world.query::<&u32>().copied().sum::<u32>();
+        assert_eq!(3000, sum);
+    }
+
+    struct Frames(u32);
+    struct Count(u32);
+
+    fn count(count: &mut Count) {
+        count.0 += 1;
+    }
+
+    #[test]
+    fn run_condition() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(false);
+        exe.add_resource(Frames(0));
+        exe.add_resource(Count(0));
+        let schedule = exe
+            .schedule()
+            .then(|on: &mut bool| *on = !*on)
+            .then(|frames: &mut Frames| frames.0 += 1)
+            .then_if(count, |on: &bool| *on)
+            // Waits for the other thread, whether count ran or not
+            .then(|frames: &Frames, count: &Count| assert!(count.0 <= frames.0))
+            .build();
+        assert_eq!(2, schedule.report().threads);
+        exe.set_tracing(true);
+        for frame in 1..=10 {
+            exe.execute(&schedule, &mut world);
+            let trace = exe.last_trace().unwrap();
+            match frame % 2 {
+                0 => trace.assert_skipped("count", SkipReason::Condition),
+                _ => trace.assert_ran("count"),
+            }
+        }
+        assert_eq!(5, exe.get_resource::<Count>().unwrap().0);
+    }
+
+    struct Hp(u32);
+    struct Poisoned;
+
+    /// Entities seen by the tick filters, sorted
+    #[derive(Default)]
+    struct Ticked {
+        changed: Vec<Entity>,
+        added: Vec<Entity>,
+    }
+
+    #[test]
+    fn changed_components() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(Ticked::default());
+        let [a, b] = [(); 2].map(|_| world.spawn((Hp(10),)));
+        let poisoned = world.spawn((Hp(10), Poisoned));
+        let schedule = exe
+            .schedule()
+            .then(|entities: Entities<(&mut Hp, With<Poisoned>)>| {
+                entities.for_each(|(hp, _)| hp.0 -= 1)
+            })
+            .then(
+                |changed: Entities<(Entity, Changed<Hp>)>,
+                 added: Entities<(Entity, Added<Poisoned>)>,
+                 ticked: &mut Ticked| {
+                    ticked.changed = changed.map(|(e, _)| e).collect();
+                    ticked.added = added.map(|(e, _)| e).collect();
+                    ticked.changed.sort();
+                    ticked.added.sort();
+                },
+            )
+            .build();
+        let ticked = |exe: &Executor| {
+            let ticked = exe.get_resource::<Ticked>().unwrap();
+            (ticked.changed.clone(), ticked.added.clone())
+        };
+
+        // Everything spawned before the first run counts
+        exe.execute(&schedule, &mut world);
+        let mut all = vec![a, b, poisoned];
+        all.sort();
+        assert_eq!((all, vec![poisoned]), ticked(&exe));
+
+        exe.execute(&schedule, &mut world);
+        assert_eq!((vec![poisoned], vec![]), ticked(&exe));
+
+        world.add_component(b, (Poisoned,)).unwrap();
+        exe.execute(&schedule, &mut world);
+        let mut both = vec![b, poisoned];
+        both.sort();
+        assert_eq!((both, vec![b]), ticked(&exe));
+        let mut hp = world.query::<&Hp>().map(|hp| hp.0).collect::<Vec<_>>();
+        hp.sort();
+        assert_eq!(vec![7, 9, 10], hp);
+    }
+
+    #[test]
+    fn refinement_revalidation() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(0u64);
+        spawn_disjoint(&mut world);
+        let schedule = refined(&mut exe, &world);
+
+        // New archetypes that don't bring the systems together keep the refinement
+        world.spawn((0u8,));
+        world.spawn((0u32, 0u64));
+        exe.execute(&schedule, &mut world);
+        assert_eq!(3000, *exe.get_resource::<u64>().unwrap());
+        assert_eq!(2, schedule.report().threads);
+
+        // Both systems now borrow the u32 of that one
+        world.spawn((0u32, 0u8, 0u16));
+        exe.execute(&schedule, &mut world);
+        let report = schedule.report();
+        assert!(report.invalidated);
+        assert_eq!(1, report.threads);
+        assert_eq!(6003, *exe.get_resource::<u64>().unwrap());
+
+        // So does another world with that archetype
+        let mut other = World::new();
+        other.spawn((0u32, 0u8, 0u16));
+        let schedule = refined(&mut exe, &world);
+        assert_eq!(1, schedule.report().threads);
+        let fresh = refined(&mut exe, &World::new());
+        exe.execute(&fresh, &mut other);
+        assert!(fresh.report().invalidated);
+        assert_eq!(3, *exe.get_resource::<u64>().unwrap());
+    }
+
+    struct Settings(u32);
+
+    /// Values of Settings seen by the change readers
+    #[derive(Default)]
+    struct Seen(Vec<u32>);
+
+    fn reader(settings: ChangedRes<Settings>, mut seen: ResMut<Seen>) {
+        if let Some(settings) = settings.get() {
+            seen.0.push(settings.0);
+        }
+    }
+
+    fn seen(exe: &mut Executor) -> Vec<u32> {
+        std::mem::take(&mut exe.get_resource_mut::<Seen>().unwrap().0)
+    }
+
+    #[test]
+    fn resource_changes() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(Settings(1));
+        exe.add_resource(Seen::default());
+        let schedule = exe.schedule_single(reader);
+
+        // First run sees the resource as changed, then only once per edit
+        exe.execute(&schedule, &mut world);
+        exe.execute(&schedule, &mut world);
+        assert_eq!(vec![1], seen(&mut exe));
+        exe.get_resource_mut::<Settings>().unwrap().0 = 2;
+        exe.execute(&schedule, &mut world);
+        exe.execute(&schedule, &mut world);
+        assert_eq!(vec![2], seen(&mut exe));
+
+        // Read only access isn't a change
+        let tick = exe.change_tick();
+        assert_eq!(2, exe.get_resource::<Settings>().unwrap().0);
+        exe.execute_single(|_: &Settings| {}, &mut world);
+        let _: (&Settings,) = exe.query_resources().unwrap();
+        exe.execute(&schedule, &mut world);
+        assert!(seen(&mut exe).is_empty());
+        assert!(!exe.resource_changed_since::<Settings>(tick));
+        // Mutable access is, whether or not it writes
+        let _: (&mut Settings,) = exe.query_resources().unwrap();
+        assert!(exe.resource_changed_since::<Settings>(tick));
+        assert!(!exe.resource_changed_since::<Settings>(exe.change_tick()));
+        assert!(!exe.resource_changed_since::<u8>(0));
+    }
+
+    #[test]
+    fn resource_changes_bypass() {
+        let mut bed = TestBed::new();
+        bed.with_resource(Settings(1)).with_resource(Seen::default());
+        let schedule = bed.schedule(|s| s.then(reader));
+        bed.execute(&schedule);
+        assert_eq!(vec![1], seen(&mut bed.executor));
+
+        bed.run(|mut settings: ResMut<Settings>| settings.bypass_change_detection().0 = 2)
+            .run(|settings: ResMut<Settings>| assert_eq!(2, settings.0))
+            .execute(&schedule);
+        assert!(seen(&mut bed.executor).is_empty());
+
+        bed.run(|mut settings: ResMut<Settings>| settings.0 = 3)
+            .execute(&schedule);
+        assert_eq!(vec![3], seen(&mut bed.executor));
+    }
+
+    #[test]
+    fn resource_changes_ordering() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(Settings(0));
+        exe.add_resource(Seen::default());
+        fn writer(mut settings: ResMut<Settings>) {
+            settings.0 += 1;
+        }
+        let before = exe.schedule().then(writer).then(reader).build();
+        let after = exe.schedule().then(reader).then(writer).build();
+
+        exe.set_tracing(true);
+        // A writer earlier in the schedule is seen during the same execute
+        exe.execute(&before, &mut world);
+        exe.last_trace().unwrap().assert_ran_before("writer", "reader");
+        exe.execute(&before, &mut world);
+        assert_eq!(vec![1, 2], seen(&mut exe));
+
+        // A writer later in the schedule is seen on the next one
+        exe.execute(&after, &mut world);
+        exe.last_trace().unwrap().assert_ran_before("reader", "writer");
+        assert_eq!(vec![2], seen(&mut exe));
+        exe.execute(&after, &mut world);
+        assert_eq!(vec![3], seen(&mut exe));
+
+        // Systems mutably borrowing the same resource are ordered
+        let a = exe.add_system(|_: &mut Settings| {});
+        let b = exe.add_system(|_: &mut Settings| {});
+        let (a, b) = (exe.get_system(a).unwrap(), exe.get_system(b).unwrap());
+        assert!(b.depends_on(a));
+    }
+
+    #[test]
+    fn run_once() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(0u8);
+        let schedule = exe.schedule().then(|a: &mut u8| *a += 1).build();
+        world.spawn((1u64, 2i16));
+        let mappings = exe.mappings.len();
+        for _ in 0..10 {
+            exe.run_once(
+                &mut world,
+                |entities: Entities<(&mut u64, &i16)>, a: &mut u8| {
+                    for (value, _) in entities {
+                        *value += 1;
+                    }
+                    *a += 1;
+                },
+            );
+        }
+        // Nothing kept
+        assert_eq!(mappings, exe.mappings.len());
+        assert_eq!(1, exe.systems.len());
+        assert_eq!(10, *exe.get_resource::<u8>().unwrap());
+        let mut values = world.query::<&u64>();
+        assert_eq!(Some(&11), values.next());
+        // The schedule is unaffected
+        drop(values);
+        exe.execute(&schedule, &mut world);
+        assert_eq!(11, *exe.get_resource::<u8>().unwrap());
+    }
+
+    #[derive(Default)]
+    struct Log(Vec<u32>);
+
+    #[test]
+    fn external_order() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(Log::default());
+        let schedule = exe.schedule().then(|log: &mut Log| log.0.push(100)).build();
+        for i in 0..3 {
+            exe.enqueue_external(move |_, exe| {
+                exe.get_resource_mut::<Log>().unwrap().0.push(i);
+                if i == 1 {
+                    // Waits for the next execute
+                    exe.enqueue_external(|_, exe| exe.get_resource_mut::<Log>().unwrap().0.push(3));
+                }
+            });
+        }
+        assert_eq!(3, exe.external_queue().len());
+        exe.execute(&schedule, &mut world);
+        assert_eq!(vec![0, 1, 2, 100], exe.get_resource::<Log>().unwrap().0);
+        exe.execute_sequential(&schedule, &mut world);
+        assert_eq!(
+            vec![0, 1, 2, 100, 3, 100],
+            exe.get_resource::<Log>().unwrap().0
+        );
+        assert!(exe.external_queue().is_empty());
+    }
+
+    #[test]
+    fn external_from_thread() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(Log::default());
+        let schedule = exe
+            .schedule()
+            .then(|entities: Entities<&u32>, log: &mut Log| log.0.extend(entities.copied()))
+            .build();
+        let queue = exe.external_queue();
+        std::thread::spawn(move || {
+            for i in 0..4u32 {
+                queue.push(move |world, _| {
+                    world.spawn((i,));
+                });
+            }
+        })
+        .join()
+        .unwrap();
+        exe.execute(&schedule, &mut world);
+        let mut seen = exe.get_resource::<Log>().unwrap().0.clone();
+        seen.sort();
+        assert_eq!(vec![0, 1, 2, 3], seen);
+    }
+
+    #[test]
+    fn external_traced() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(0u8);
+        exe.add_resource(0u16);
+        exe.set_tracing(true);
+        let schedule = exe
+            .schedule()
+            .then(|a: &mut u8| *a += 1)
+            .then(|b: &mut u16| *b += 1)
+            .build();
+        let check = |trace: &ExecutionTrace| {
+            let end = match trace.workers()[0][0] {
+                TraceEvent::External { count: 2, end, .. } => end,
+                ref event => panic!("first event is {event:?}"),
+            };
+            let runs = trace
+                .events()
+                .filter(|(_, event)| matches!(event, TraceEvent::Run { .. }))
+                .collect::<Vec<_>>();
+            assert_eq!(2, runs.len());
+            assert!(runs.iter().all(|(_, event)| event.start() >= end));
+        };
+        for _ in 0..2 {
+            exe.enqueue_external(|_, _| std::thread::sleep(Duration::from_millis(1)));
+        }
+        exe.execute(&schedule, &mut world);
+        check(exe.last_trace().unwrap());
+        for _ in 0..2 {
+            exe.enqueue_external(|_, _| std::thread::sleep(Duration::from_millis(1)));
+        }
+        exe.execute_sequential(&schedule, &mut world);
+        check(exe.last_trace().unwrap());
+        // Nothing queued, nothing traced
+        exe.execute(&schedule, &mut world);
+        let trace = exe.last_trace().unwrap();
+        assert!(trace
+            .events()
+            .all(|(_, event)| !matches!(event, TraceEvent::External { .. })));
+    }
 }
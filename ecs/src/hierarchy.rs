@@ -0,0 +1,170 @@
+//! Parent and child entities, on top of relations.
+//!
+//! A child has a `Parent` component (the `Relation` of kind `ChildOf`) pointing to its parent, and
+//! the relation index gives the children of a parent. Despawning a parent only leaves its children
+//! without one, `World::remove_recursive` despawns the whole subtree.
+
+use std::collections::HashSet;
+
+use crate::{
+    archetype::IntoArchetype,
+    entity::Entity,
+    relation::{Relation, RelationKind},
+    world::World,
+};
+
+/// The relation of a child to its parent, see `World::spawn_child`
+pub struct ChildOf;
+impl RelationKind for ChildOf {}
+
+/// The parent of an entity
+pub type Parent = Relation<ChildOf>;
+
+impl World {
+    /// Spawn an entity as a child of parent
+    ///
+    /// # Panics
+    ///
+    /// This panics if the parent doesn't exist
+    pub fn spawn_child<T: IntoArchetype>(&mut self, parent: Entity, components: T) -> Entity {
+        assert!(self.contains(parent), "No parent {parent:?} to spawn under");
+        let child = self.spawn(components);
+        self.relate::<ChildOf>(child, parent);
+        child
+    }
+    /// Make child a child of parent, replacing its previous parent. None if one of the entities
+    /// doesn't exist, or if parent is child or one of its descendants.
+    pub fn set_parent(&mut self, child: Entity, parent: Entity) -> Option<()> {
+        if child == parent || self.descendants(child).contains(&parent) {
+            return None;
+        }
+        self.relate::<ChildOf>(child, parent)
+    }
+    pub fn parent(&self, child: Entity) -> Option<Entity> {
+        self.related::<ChildOf>(child).first().copied()
+    }
+    pub fn children(&self, parent: Entity) -> Vec<Entity> {
+        self.related_to::<ChildOf>(parent).collect()
+    }
+    /// The children of entity, their children and so on, parents before their children
+    pub fn descendants(&self, entity: Entity) -> Vec<Entity> {
+        let mut seen = HashSet::from([entity]);
+        let mut descendants = Vec::new();
+        let mut next = 0;
+        let mut parent = entity;
+        loop {
+            // Relating ChildOf by hand can make cycles
+            descendants.extend(
+                self.children(parent)
+                    .into_iter()
+                    .filter(|c| seen.insert(*c)),
+            );
+            let Some(&child) = descendants.get(next) else {
+                return descendants;
+            };
+            parent = child;
+            next += 1;
+        }
+    }
+    /// Despawn the descendants of an entity, but not the entity. None if it doesn't exist.
+    pub fn despawn_children(&mut self, entity: Entity) -> Option<()> {
+        if !self.contains(entity) {
+            return None;
+        }
+        // remove_many only takes entities stored next to each other
+        let descendants = self.descendants(entity);
+        for &descendant in &descendants {
+            self.despawn(descendant);
+        }
+        self.relations_despawned(descendants);
+        Some(())
+    }
+    /// Despawn an entity and its descendants. None if it doesn't exist.
+    pub fn remove_recursive(&mut self, entity: Entity) -> Option<()> {
+        self.despawn_children(entity)?;
+        self.remove(entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Name(&'static str);
+
+    fn names(world: &World) -> Vec<&'static str> {
+        let mut names = world
+            .query::<(&Name,)>()
+            .map(|(name,)| name.0)
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn remove_recursive() {
+        let mut world = World::new();
+        let root = world.spawn((Name("root"),));
+        let arm = world.spawn_child(root, (Name("arm"),));
+        let hand = world.spawn_child(arm, (Name("hand"), 0u32));
+        let fingers = [(); 3].map(|_| world.spawn_child(hand, (Name("finger"),)));
+        let leg = world.spawn_child(root, (Name("leg"),));
+        let other = world.spawn((Name("other"),));
+
+        assert_eq!(vec![arm, leg], world.children(root));
+        assert_eq!(fingers.to_vec(), world.children(hand));
+        assert_eq!(Some(hand), world.parent(fingers[0]));
+        assert_eq!(None, world.parent(root));
+        assert_eq!(
+            vec![arm, leg, hand, fingers[0], fingers[1], fingers[2]],
+            world.descendants(root)
+        );
+        let parents = world
+            .query::<(&Name, &Parent)>()
+            .filter(|(name, _)| name.0 == "finger")
+            .map(|(_, parent)| parent.target())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![hand; 3], parents);
+
+        world.remove_recursive(arm).unwrap();
+        assert_eq!(vec!["leg", "other", "root"], names(&world));
+        assert_eq!(0, world.query::<(&u32,)>().count());
+        assert!(!world.contains(fingers[2]));
+        assert_eq!(vec![leg], world.children(root));
+        assert_eq!(None, world.remove_recursive(arm));
+
+        world.despawn_children(root).unwrap();
+        assert_eq!(vec!["other", "root"], names(&world));
+        assert!(world.children(root).is_empty());
+        world.remove_recursive(root).unwrap();
+        assert_eq!(vec!["other"], names(&world));
+        assert!(world.contains(other));
+    }
+
+    #[test]
+    fn reparent() {
+        let mut world = World::new();
+        let [a, b] = [(); 2].map(|_| world.spawn((Name("root"),)));
+        let child = world.spawn_child(a, (Name("child"),));
+        let grandchild = world.spawn_child(child, (Name("grandchild"),));
+        // No cycles
+        assert_eq!(None, world.set_parent(a, grandchild));
+        assert_eq!(None, world.set_parent(a, a));
+
+        world.set_parent(child, b).unwrap();
+        assert!(world.children(a).is_empty());
+        assert_eq!(vec![child], world.children(b));
+        // Despawning a parent only orphans its children
+        world.remove(b).unwrap();
+        assert_eq!(None, world.parent(child));
+        assert_eq!(
+            0,
+            world
+                .query::<(&Parent, &Name)>()
+                .filter(|(_, n)| n.0 == "child")
+                .count()
+        );
+        assert_eq!(Some(child), world.parent(grandchild));
+    }
+}
@@ -1,25 +1,102 @@
 #![allow(dead_code)]
-#![feature(alloc_layout_extra)]
-#![feature(once_cell)]
-// TODO: Remove those features
+#![deny(unstable_features)]
+
+/// Invoke `$m` for every prefix (including the empty one) of a list of `Type index` pairs, used to
+/// implement traits for tuples of small arities without going through ecs_macros.
+macro_rules! for_tuples {
+    ($m:ident; $($t:ident $i:tt),*) => {
+        for_tuples!(@ $m []; $($t $i),*);
+    };
+    (@ $m:ident [$($at:ident $ai:tt),*];) => {
+        $m!($($at $ai),*);
+    };
+    (@ $m:ident [$($at:ident $ai:tt),*]; $t:ident $i:tt $(, $rt:ident $ri:tt)*) => {
+        $m!($($at $ai),*);
+        for_tuples!(@ $m [$($at $ai,)* $t $i]; $($rt $ri),*);
+    };
+}
+
+/// Number of token trees passed, as a const expression
+macro_rules! count {
+    () => { 0usize };
+    ($head:tt $($tail:tt)*) => { 1usize + count!($($tail)*) };
+}
 
 mod archetype;
 mod bitset;
 mod borrows;
+mod channel;
+mod commands;
+mod compact;
+mod dynamic;
 mod entity;
+mod error;
+mod events;
 mod executor;
+mod hierarchy;
+mod local;
+mod pool;
 mod query;
+mod relation;
+mod replication;
+mod schedule;
+#[cfg(feature = "serde")]
+mod snapshot;
 mod system;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod thread_pool;
+mod trace;
+mod validate;
+mod watchdog;
 mod world;
 
 pub use archetype::Component;
-pub use entity::Entity;
+pub use channel::{Backpressure, BridgeSystem, ChannelReader, ChannelWriter};
+pub use commands::Commands;
+pub use compact::CompactEntityVec;
+pub use dynamic::DynamicComponentId;
+pub use entity::{Entity, EntityIndex};
+pub use error::{EcsError, ScheduleCycleError, SystemError};
+pub use events::{EventReader, EventWriter, Events};
 pub use executor::Executor;
-pub use executor::Schedule;
-pub use executor::Scheduler;
+pub use executor::FetchPolicy;
+pub use executor::SystemId;
+pub use executor::{ExternalFn, ExternalQueue};
+pub use hierarchy::{ChildOf, Parent};
+pub use local::{InitResource, Local};
+pub use pool::PoolStats;
+pub use query::{Added, Changed, Has, QueryCursor, With, Without};
+pub use relation::{OnTargetDespawn, Relation, RelationCommands, RelationKind};
+pub use replication::{
+    ComponentRegistry, DiffError, EntityDiff, EntityMap, WorldDiff, DIFF_VERSION,
+};
+pub use schedule::Schedule;
+pub use schedule::ScheduleReport;
+pub use schedule::Scheduler;
+pub use schedule::{ScheduleDescription, StepDescription, SyncEdge};
+#[cfg(feature = "serde")]
+pub use snapshot::{EntitySnapshot, SnapshotError, WorldSnapshot};
+pub use system::ChangedRes;
 pub use system::Entities;
+pub use system::IntoCondition;
 pub use system::IntoSystem;
+pub use system::OptionRes;
+pub use system::Res;
+pub use system::ResMut;
+#[cfg(feature = "codegen")]
+pub use ecs_macros::SystemParam;
+pub use trace::{ExecutionTrace, SkipReason, TraceEvent};
+pub use validate::{finite, ReferencesEntities, ValidationFailure};
+pub use watchdog::Budget;
 pub use world::World;
+pub use world::WorldStats;
+
+/// What the code generated by the derives of ecs_macros refers to
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::executor::ExecutionContext;
+    pub use crate::system::{RequirementsBuilder, RequirementsMappings, SystemArgument};
+}
 
 // TODO: Add component trait that requires 'static + Send + Sync
@@ -0,0 +1,207 @@
+//! Resources created from the world (`InitResource`), and `Local`, the state a system keeps
+//! between its runs.
+//!
+//! Creating a value can read any resource, which systems running in parallel may be writing to,
+//! so the locals of the systems of a schedule are created at the start of `execute`, before any
+//! system runs.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    executor::{ExecutionContext, Resource},
+    schedule::Schedule,
+    system::{RequirementsBuilder, RequirementsMappings, SystemArgument},
+    Executor, World,
+};
+
+/// A resource that can be created from the world and the other resources, for
+/// `Executor::init_resource` and `Local`. Implemented for every `Default` resource.
+pub trait InitResource: Resource {
+    fn init(world: &World, executor: &Executor) -> Self;
+}
+
+impl<T: Resource + Default> InitResource for T {
+    fn init(_world: &World, _executor: &Executor) -> Self {
+        T::default()
+    }
+}
+
+/// Create the value of a `Local`
+pub(crate) type LocalInit = (TypeId, fn(&World, &Executor) -> Box<dyn Any + Send>);
+
+fn init_local<T: InitResource>(world: &World, executor: &Executor) -> Box<dyn Any + Send> {
+    Box::new(T::init(world, executor))
+}
+
+/// The `Local` values of a system
+#[derive(Default)]
+pub(crate) struct Locals(Mutex<HashMap<TypeId, Box<dyn Any + Send>>>);
+
+impl Locals {
+    /// Create the values that don't exist yet
+    pub(crate) fn init(&self, inits: &[LocalInit], world: &World, executor: &Executor) {
+        let mut values = self.0.lock();
+        for &(id, init) in inits {
+            values.entry(id).or_insert_with(|| init(world, executor));
+        }
+    }
+    /// The value of a `Local<T>`, the box keeps it in place while the map changes
+    fn get<T: Resource>(&self) -> Option<*mut T> {
+        self.0
+            .lock()
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut::<T>())
+            .map(|value| value as *mut T)
+    }
+}
+
+/// State of a system kept between its runs, created with `InitResource` before its first run.
+/// Each system has its own (shared with its run condition), a system run with
+/// `Executor::run_once` gets a new one every time.
+pub struct Local<'r, T> {
+    value: &'r mut T,
+}
+
+impl<'r, T> Deref for Local<'r, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'r, T> DerefMut for Local<'r, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<'r, T: InitResource> SystemArgument for Local<'r, T> {
+    fn register(_mappings: &mut RequirementsMappings) {}
+    fn require(mut builder: RequirementsBuilder) -> RequirementsBuilder {
+        // Each system has its own, only a second one in the same system would alias
+        let id = TypeId::of::<T>();
+        if builder.locals.iter().any(|&(other, _)| other == id) {
+            panic!(
+                "Local<{}> is taken twice by a system",
+                std::any::type_name::<T>()
+            );
+        }
+        builder.locals.push((id, init_local::<T>));
+        builder
+    }
+    unsafe fn fetch(context: &ExecutionContext) -> Self {
+        log::trace!("SystemArgument: Fetching Local");
+        let value = context
+            .locals
+            .expect("Local outside of a system run")
+            .get::<T>()
+            .expect("Local wasn't created before the system run");
+        // Only this system uses it, and it doesn't run twice at the same time
+        Self { value: &mut *value }
+    }
+}
+
+impl Executor {
+    /// Add a resource created with `InitResource`, if the executor doesn't have one of this type
+    pub fn init_resource<T: InitResource>(&mut self, world: &World) {
+        if self.get_resource::<T>().is_none() {
+            let value = T::init(world, self);
+            self.add_resource(value);
+        }
+    }
+    /// Get a resource, creating it with `InitResource` the first time
+    pub fn get_or_init_resource<T: InitResource>(&mut self, world: &World) -> &mut T {
+        self.init_resource::<T>(world);
+        self.get_resource_mut::<T>().unwrap()
+    }
+    /// Create the locals of the systems of a schedule that don't have them yet
+    pub(crate) fn init_locals(&self, schedule: &Schedule, world: &World) {
+        for &id in &schedule.order {
+            if let Some(system) = self.get_system(id) {
+                system.init_locals(world, self);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{system::OptionRes, testing::TestBed};
+
+    /// Created from the Start resource
+    struct Counter(u32);
+
+    static INITS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Start(u32);
+
+    impl InitResource for Counter {
+        fn init(_world: &World, executor: &Executor) -> Self {
+            INITS.fetch_add(1, Ordering::SeqCst);
+            Self(executor.get_resource::<Start>().unwrap().0)
+        }
+    }
+
+    #[derive(Default)]
+    struct Seen(Vec<(u32, u32)>);
+
+    #[test]
+    fn local_isolation() {
+        let mut bed = TestBed::new();
+        bed.with_resource(Start(10)).with_resource(Seen::default());
+        let schedule = bed.schedule(|s| {
+            s.then(
+                |mut count: Local<u32>, mut counter: Local<Counter>, seen: &mut Seen| {
+                    *count += 1;
+                    counter.0 += 1;
+                    seen.0.push((*count, counter.0));
+                },
+            )
+            .then(|mut count: Local<u32>, seen: &mut Seen| {
+                *count += 100;
+                seen.0.push((*count, 0));
+            })
+        });
+        assert_eq!(0, INITS.load(Ordering::SeqCst));
+        for _ in 0..3 {
+            bed.execute(&schedule);
+        }
+        // Created once, before the first run
+        assert_eq!(1, INITS.load(Ordering::SeqCst));
+        assert_eq!(
+            vec![(1, 11), (100, 0), (2, 12), (200, 0), (3, 13), (300, 0)],
+            bed.resource::<Seen>().0
+        );
+    }
+
+    #[test]
+    fn init_resource() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.run_once(&mut world, |value: OptionRes<u32>| {
+            assert!(value.get().is_none());
+        });
+        *exe.get_or_init_resource::<u32>(&world) += 2;
+        exe.init_resource::<u32>(&world);
+        assert_eq!(2, *exe.get_resource::<u32>().unwrap());
+        exe.run_once(&mut world, |value: OptionRes<u32>| {
+            assert_eq!(Some(&2), value.get());
+        });
+        // Nothing is kept between run_once
+        for _ in 0..2 {
+            exe.run_once(&mut world, |mut count: Local<u8>| {
+                *count += 1;
+                assert_eq!(1, *count);
+            });
+        }
+    }
+}
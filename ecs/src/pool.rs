@@ -0,0 +1,277 @@
+//! Memory pool shared by the archetype storages of a world.
+//!
+//! Storages grow by doubling, so a world with many small archetypes keeps allocating blocks of
+//! the same few sizes and freeing the ones it outgrew. The pool keeps freed blocks in power of
+//! two size classes and hands them to the next storage growing into that class, instead of going
+//! through the global allocator every time. Blocks over the threshold, or for archetypes aligned
+//! to more than `POOL_ALIGN`, come from the global allocator directly.
+
+use std::{
+    alloc::{self, Layout},
+    ptr::NonNull,
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+/// Alignment of every block of the pool
+pub(crate) const POOL_ALIGN: usize = 64;
+/// Size of the blocks of the smallest size class
+const MIN_BLOCK: usize = 64;
+/// Number of size classes, the biggest blocks are 1MiB
+pub const SIZE_CLASSES: usize = 15;
+const MAX_BLOCK: usize = MIN_BLOCK << (SIZE_CLASSES - 1);
+
+/// The pool of a world, cloned into each of its storages
+pub(crate) type SharedPool = Arc<Mutex<StoragePool>>;
+
+/// Size of the blocks of a class
+pub(crate) fn class_size(class: usize) -> usize {
+    MIN_BLOCK << class
+}
+
+/// Smallest class holding `size` bytes
+fn class_of(size: usize) -> usize {
+    (size.max(MIN_BLOCK).next_power_of_two() / MIN_BLOCK).trailing_zeros() as usize
+}
+
+fn class_layout(class: usize) -> Layout {
+    Layout::from_size_align(class_size(class), POOL_ALIGN).unwrap()
+}
+
+/// Statistics of the storage pool of a world
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Free blocks held by the pool in each size class (see `block_size`)
+    pub held_blocks: [usize; SIZE_CLASSES],
+    /// Total size of the free blocks
+    pub held_bytes: usize,
+    /// Blocks reused from the pool
+    pub hits: usize,
+    /// Blocks the pool had to allocate
+    pub misses: usize,
+    /// Allocations over the threshold, or too aligned, that bypassed the pool
+    pub fallbacks: usize,
+}
+
+impl PoolStats {
+    /// Size of the blocks of a size class
+    pub fn block_size(class: usize) -> usize {
+        class_size(class)
+    }
+    /// Fraction of the pool allocations served by a freed block
+    pub fn hit_rate(&self) -> f32 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f32 / total as f32,
+        }
+    }
+}
+
+pub(crate) struct StoragePool {
+    free: [Vec<NonNull<u8>>; SIZE_CLASSES],
+    /// Size of the biggest blocks served by the pool
+    threshold: usize,
+    hits: usize,
+    misses: usize,
+    fallbacks: usize,
+}
+
+// The pool only holds unused blocks, which aren't tied to any thread
+unsafe impl Send for StoragePool {}
+
+impl StoragePool {
+    pub(crate) fn new() -> Self {
+        Self {
+            free: Default::default(),
+            threshold: MAX_BLOCK,
+            hits: 0,
+            misses: 0,
+            fallbacks: 0,
+        }
+    }
+    pub(crate) fn shared() -> SharedPool {
+        Arc::new(Mutex::new(Self::new()))
+    }
+    /// Blocks over `bytes` come from the global allocator, 0 disables the pool
+    pub(crate) fn set_threshold(&mut self, bytes: usize) {
+        self.threshold = bytes.min(MAX_BLOCK);
+    }
+    /// A block for `layout` and its size class, None if it should come from the global allocator
+    pub(crate) fn alloc(&mut self, layout: Layout) -> Option<(NonNull<u8>, usize)> {
+        let class = class_of(layout.size());
+        if layout.align() > POOL_ALIGN || class_size(class) > self.threshold {
+            self.fallbacks += 1;
+            return None;
+        }
+        let ptr = match self.free[class].pop() {
+            Some(ptr) => {
+                self.hits += 1;
+                ptr
+            }
+            None => {
+                self.misses += 1;
+                let layout = class_layout(class);
+                NonNull::new(unsafe { alloc::alloc(layout) })
+                    .unwrap_or_else(|| alloc::handle_alloc_error(layout))
+            }
+        };
+        Some((ptr, class))
+    }
+    /// Give a block back to the pool
+    /// # safety
+    /// The block must come from `alloc` with this class, and not be used anymore
+    pub(crate) unsafe fn free(&mut self, ptr: NonNull<u8>, class: usize) {
+        self.free[class].push(ptr);
+    }
+    /// Release every free block to the global allocator, returns the number of bytes released
+    pub(crate) fn trim(&mut self) -> usize {
+        let mut released = 0;
+        for (class, blocks) in self.free.iter_mut().enumerate() {
+            for ptr in blocks.drain(..) {
+                unsafe { alloc::dealloc(ptr.as_ptr(), class_layout(class)) };
+                released += class_size(class);
+            }
+        }
+        released
+    }
+    pub(crate) fn stats(&self) -> PoolStats {
+        let mut held_blocks = [0; SIZE_CLASSES];
+        for (held, blocks) in held_blocks.iter_mut().zip(&self.free) {
+            *held = blocks.len();
+        }
+        PoolStats {
+            held_bytes: held_blocks
+                .iter()
+                .enumerate()
+                .map(|(class, count)| count * class_size(class))
+                .sum(),
+            held_blocks,
+            hits: self.hits,
+            misses: self.misses,
+            fallbacks: self.fallbacks,
+        }
+    }
+}
+
+impl Drop for StoragePool {
+    fn drop(&mut self) {
+        self.trim();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{archetype::ArchetypeStorage, World};
+
+    #[test]
+    fn classes() {
+        assert_eq!(0, class_of(1));
+        assert_eq!(0, class_of(64));
+        assert_eq!(1, class_of(65));
+        assert_eq!(SIZE_CLASSES - 1, class_of(MAX_BLOCK));
+        assert_eq!(128, PoolStats::block_size(1));
+    }
+
+    #[test]
+    fn reuse_across_storages() {
+        let mut w = World::new();
+        // Growing one archetype frees blocks the next ones reuse
+        for i in 0..16u64 {
+            w.spawn((i,));
+        }
+        let first = w.stats().pool;
+        assert_eq!(0, first.hits);
+        assert!(first.held_blocks[0] > 0);
+        for i in 0..16u32 {
+            w.spawn((i,));
+        }
+        let second = w.stats().pool;
+        assert_eq!(first.misses, second.misses);
+        assert!(second.hits > 0);
+        assert!(second.hit_rate() > 0.0);
+    }
+
+    #[test]
+    fn reuse_after_drop() {
+        let pool = StoragePool::shared();
+        for _ in 0..3 {
+            let mut storage = ArchetypeStorage::new::<(u64,)>().in_pool(pool.clone());
+            storage.extend((0..16u64).map(|i| (i,)));
+        }
+        let stats = pool.lock().stats();
+        assert_eq!(1, stats.misses);
+        assert_eq!(2, stats.hits);
+        assert_eq!(1, stats.held_blocks[1]);
+    }
+
+    #[test]
+    fn high_alignment() {
+        #[repr(align(64))]
+        struct Line(u8);
+        #[repr(align(128))]
+        struct Page(u8);
+
+        let mut w = World::new();
+        for i in 0..100 {
+            w.spawn((Line(i), i as u32));
+            w.spawn((Page(i), i as u32));
+        }
+        for (line, &i) in w.query::<(&Line, &u32)>() {
+            assert_eq!(0, line as *const Line as usize % 64);
+            assert_eq!(i as u8, line.0);
+        }
+        for (page, &i) in w.query::<(&Page, &u32)>() {
+            assert_eq!(0, page as *const Page as usize % 128);
+            assert_eq!(i as u8, page.0);
+        }
+        // Only the pages are too aligned for the pool
+        let stats = w.stats().pool;
+        assert!(stats.misses > 0);
+        assert!(stats.fallbacks > 0);
+    }
+
+    #[test]
+    fn threshold_fallback() {
+        let mut w = World::new();
+        w.set_pool_threshold(256);
+        for i in 0..1000u64 {
+            w.spawn((i,));
+        }
+        let stats = w.stats().pool;
+        // 8, 16 and 32 entities fit, the rest doesn't
+        assert_eq!(3, stats.misses);
+        assert!(stats.fallbacks > 0);
+        // The last pooled block was given back when outgrown
+        assert_eq!(3, stats.held_blocks.iter().sum::<usize>());
+        assert_eq!(1000, w.query::<&u64>().count());
+        assert!(w.query::<&u64>().enumerate().all(|(i, &v)| i as u64 == v));
+
+        let mut w = World::new();
+        w.set_pool_threshold(0);
+        w.spawn_many((0..100u64).map(|i| (i,)));
+        let stats = w.stats().pool;
+        assert_eq!(0, stats.misses);
+        assert_eq!(PoolStats::default().held_blocks, stats.held_blocks);
+    }
+
+    #[test]
+    fn trim() {
+        let mut w = World::new();
+        for i in 0..100u32 {
+            w.spawn((i,));
+            w.spawn((i, i as u64));
+        }
+        let held = w.stats().pool.held_bytes;
+        assert!(held > 0);
+        assert_eq!(held, w.trim_pool());
+        let stats = w.stats().pool;
+        assert_eq!(0, stats.held_bytes);
+        assert_eq!(PoolStats::default().held_blocks, stats.held_blocks);
+        assert_eq!(0, w.trim_pool());
+        // Still usable after
+        w.spawn_many((0..100u32).map(|i| (i, "a")));
+        assert_eq!(300, w.query::<&u32>().count());
+    }
+}
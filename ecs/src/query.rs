@@ -1,23 +1,112 @@
-use std::{any::TypeId, marker::PhantomData, ptr::NonNull};
+use std::{any::TypeId, marker::PhantomData, ops::Deref, ptr::NonNull, sync::OnceLock};
 
+#[cfg(feature = "codegen")]
 use ecs_macros::{impl_query, impl_res_query};
 
 use crate::{
-    archetype::{Archetype, Component},
+    archetype::{Archetype, Component, RowTicks},
     bitset::{BitsetBuilder, BorrowBitset, BorrowBitsetBuilder, BorrowBitsetMapping},
-    entity::{Entity, Location, LocationMap}, Executor, executor::Resource,
+    borrows::BorrowGuard,
+    entity::{Entity, Location, LocationMap}, Executor, executor::Resource, EcsError,
+    thread_pool::{FnJob, ThreadPool},
 };
 
 /// A single query used in a tuple
 trait QuerySingle {
+    /// If this borrows a component mutably
+    const MUTABLE: bool = false;
     fn match_archetype(archetype: &Archetype) -> bool;
     fn build(ptr: *mut u8, archetype: &Archetype, entity: Entity) -> Self;
     #[doc(hidden)]
     fn add_to_bitset(builder: BorrowBitsetBuilder) -> BorrowBitsetBuilder;
     fn r#type() -> Option<TypeId>;
+    /// The component borrowed: its type, name and if the borrow is mutable
+    fn borrow() -> Option<(TypeId, &'static str, bool)>;
+    fn access() -> Option<ComponentAccess>;
 }
 
+/// How a query accesses a component, see `Scheduler::build_for`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComponentAccess {
+    pub ty: TypeId,
+    pub mutable: bool,
+    /// If the query only matches archetypes with the component (not an Option)
+    pub required: bool,
+    /// If the query only matches archetypes without the component (`Without`)
+    pub excluded: bool,
+    /// If the component is read or written, and not only a filter (`With` or `Without`)
+    pub borrowed: bool,
+    /// If the query only matches entities whose component was added or changed (`Added` or
+    /// `Changed`)
+    pub filter: Option<TickFilter>,
+}
+
+/// Filter on the ticks of a component, see `ComponentAccess`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickFilter {
+    Added,
+    Changed,
+}
+
+impl TickFilter {
+    fn matches(self, ticks: RowTicks, since: u32) -> bool {
+        match self {
+            Self::Added => ticks.added > since,
+            Self::Changed => ticks.changed > since,
+        }
+    }
+}
+
+/// If two queries can touch the same data in an archetype: they both match it, and either access
+/// one of its components with one mutable access, or both mark its rows as changed.
+pub(crate) fn accesses_conflict(
+    a: &[ComponentAccess],
+    b: &[ComponentAccess],
+    archetype: &Archetype,
+) -> bool {
+    let has = |ty| archetype.offset_of(ty).is_some();
+    let matches = |q: &[ComponentAccess]| {
+        q.iter()
+            .all(|c| (!c.required || has(c.ty)) && !(c.excluded && has(c.ty)))
+    };
+    if !matches(a) || !matches(b) {
+        return false;
+    }
+    let mutable = |q: &[ComponentAccess]| q.iter().any(|c| c.mutable);
+    if mutable(a) && mutable(b) {
+        return true;
+    }
+    a.iter().filter(|x| x.borrowed && has(x.ty)).any(|x| {
+        b.iter()
+            .any(|y| y.borrowed && y.ty == x.ty && (x.mutable || y.mutable))
+    })
+}
+
+/// Fails if a component is borrowed more than once with at least one mutable borrow
+pub(crate) fn check_aliasing(
+    borrows: impl IntoIterator<Item = Option<(TypeId, &'static str, bool)>>,
+) -> Result<(), EcsError> {
+    let mut seen: Vec<(TypeId, bool)> = Vec::new();
+    for (t, name, mutable) in borrows.into_iter().flatten() {
+        // If either one is mutable
+        if seen.iter().any(|&(other, m)| other == t && (m || mutable)) {
+            return Err(EcsError::BorrowConflict {
+                details: format!("Aliasing problem in query on {}", name),
+            });
+        }
+        seen.push((t, mutable));
+    }
+    Ok(())
+}
+
+/// A query on the components of entities.
+///
+/// A component can be in a query several times as long as it is only borrowed immutably:
+/// `(&T, &T)` is fine, but `(&mut T, &T)`, `(&mut T, &mut T)` or `(Option<&mut T>, &T)` would
+/// alias, and are rejected when the query is made (see `Query::check_aliasing`).
 pub trait Query {
+    /// If this borrows any component mutably, the entities iterated are then marked as changed
+    const MUTABLE: bool;
     fn match_archetype(archetype: &Archetype) -> bool;
     fn build(ptr: *mut u8, archetype: &Archetype, entity: Entity) -> Self;
     #[doc(hidden)]
@@ -27,6 +116,14 @@ pub trait Query {
         Self::add_to_bitset(builder).build()
     }
     fn types() -> Vec<TypeId>;
+    /// The components accessed by the query
+    #[doc(hidden)]
+    fn access() -> Vec<ComponentAccess>;
+    /// Check that the query doesn't borrow a component mutably more than once (including with a
+    /// shared borrow)
+    fn check_aliasing() -> Result<(), EcsError> {
+        Ok(())
+    }
 }
 
 impl QuerySingle for Entity {
@@ -42,6 +139,12 @@ impl QuerySingle for Entity {
     fn r#type() -> Option<TypeId> {
         None
     }
+    fn borrow() -> Option<(TypeId, &'static str, bool)> {
+        None
+    }
+    fn access() -> Option<ComponentAccess> {
+        None
+    }
 }
 
 impl<T: Component> QuerySingle for &T {
@@ -57,9 +160,23 @@ impl<T: Component> QuerySingle for &T {
     fn r#type() -> Option<TypeId> {
         Some(TypeId::of::<T>())
     }
+    fn borrow() -> Option<(TypeId, &'static str, bool)> {
+        Some((TypeId::of::<T>(), std::any::type_name::<T>(), false))
+    }
+    fn access() -> Option<ComponentAccess> {
+        Some(ComponentAccess {
+            ty: TypeId::of::<T>(),
+            mutable: false,
+            required: true,
+            excluded: false,
+            borrowed: true,
+            filter: None,
+        })
+    }
 }
 
 impl<T: Component> QuerySingle for &mut T {
+    const MUTABLE: bool = true;
     fn match_archetype(archetype: &Archetype) -> bool {
         archetype.has::<T>()
     }
@@ -72,6 +189,19 @@ impl<T: Component> QuerySingle for &mut T {
     fn r#type() -> Option<TypeId> {
         Some(TypeId::of::<T>())
     }
+    fn borrow() -> Option<(TypeId, &'static str, bool)> {
+        Some((TypeId::of::<T>(), std::any::type_name::<T>(), true))
+    }
+    fn access() -> Option<ComponentAccess> {
+        Some(ComponentAccess {
+            ty: TypeId::of::<T>(),
+            mutable: true,
+            required: true,
+            excluded: false,
+            borrowed: true,
+            filter: None,
+        })
+    }
 }
 
 impl<T: Component> QuerySingle for Option<&T> {
@@ -91,9 +221,23 @@ impl<T: Component> QuerySingle for Option<&T> {
     fn r#type() -> Option<TypeId> {
         Some(TypeId::of::<T>())
     }
+    fn borrow() -> Option<(TypeId, &'static str, bool)> {
+        Some((TypeId::of::<T>(), std::any::type_name::<T>(), false))
+    }
+    fn access() -> Option<ComponentAccess> {
+        Some(ComponentAccess {
+            ty: TypeId::of::<T>(),
+            mutable: false,
+            required: false,
+            excluded: false,
+            borrowed: true,
+            filter: None,
+        })
+    }
 }
 
 impl<T: Component> QuerySingle for Option<&mut T> {
+    const MUTABLE: bool = true;
     fn match_archetype(_archetype: &Archetype) -> bool {
         true
     }
@@ -110,9 +254,172 @@ impl<T: Component> QuerySingle for Option<&mut T> {
     fn r#type() -> Option<TypeId> {
         Some(TypeId::of::<T>())
     }
+    fn borrow() -> Option<(TypeId, &'static str, bool)> {
+        Some((TypeId::of::<T>(), std::any::type_name::<T>(), true))
+    }
+    fn access() -> Option<ComponentAccess> {
+        Some(ComponentAccess {
+            ty: TypeId::of::<T>(),
+            mutable: true,
+            required: false,
+            excluded: false,
+            borrowed: true,
+            filter: None,
+        })
+    }
 }
 
+/// Matches the entities with a T, without borrowing it: `(&A, With<B>)`
+pub struct With<T>(PhantomData<fn() -> T>);
+
+/// Matches the entities without a T: `(&A, Without<B>)`
+pub struct Without<T>(PhantomData<fn() -> T>);
+
+impl<T: Component> QuerySingle for With<T> {
+    fn match_archetype(archetype: &Archetype) -> bool {
+        archetype.has::<T>()
+    }
+    fn build(_: *mut u8, _: &Archetype, _: Entity) -> Self {
+        Self(PhantomData)
+    }
+    fn add_to_bitset(builder: BorrowBitsetBuilder) -> BorrowBitsetBuilder {
+        builder.with::<T>()
+    }
+    fn r#type() -> Option<TypeId> {
+        Some(TypeId::of::<T>())
+    }
+    fn borrow() -> Option<(TypeId, &'static str, bool)> {
+        None
+    }
+    fn access() -> Option<ComponentAccess> {
+        Some(ComponentAccess {
+            ty: TypeId::of::<T>(),
+            mutable: false,
+            required: true,
+            excluded: false,
+            borrowed: false,
+            filter: None,
+        })
+    }
+}
+
+impl<T: Component> QuerySingle for Without<T> {
+    fn match_archetype(archetype: &Archetype) -> bool {
+        !archetype.has::<T>()
+    }
+    fn build(_: *mut u8, _: &Archetype, _: Entity) -> Self {
+        Self(PhantomData)
+    }
+    fn add_to_bitset(builder: BorrowBitsetBuilder) -> BorrowBitsetBuilder {
+        builder.without::<T>()
+    }
+    fn r#type() -> Option<TypeId> {
+        Some(TypeId::of::<T>())
+    }
+    fn borrow() -> Option<(TypeId, &'static str, bool)> {
+        None
+    }
+    fn access() -> Option<ComponentAccess> {
+        Some(ComponentAccess {
+            ty: TypeId::of::<T>(),
+            mutable: false,
+            required: false,
+            excluded: true,
+            borrowed: false,
+            filter: None,
+        })
+    }
+}
+
+/// Whether the entity has a T, without borrowing it or filtering on it: `(&A, Has<B>)`.
+/// Dereferences to the bool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Has<T>(bool, PhantomData<fn() -> T>);
+
+impl<T> Has<T> {
+    pub fn get(self) -> bool {
+        self.0
+    }
+}
+
+impl<T> Deref for Has<T> {
+    type Target = bool;
+    fn deref(&self) -> &bool {
+        &self.0
+    }
+}
+
+impl<T: Component> QuerySingle for Has<T> {
+    fn match_archetype(_archetype: &Archetype) -> bool {
+        true
+    }
+    fn build(_: *mut u8, archetype: &Archetype, _: Entity) -> Self {
+        Self(archetype.has::<T>(), PhantomData)
+    }
+    fn add_to_bitset(builder: BorrowBitsetBuilder) -> BorrowBitsetBuilder {
+        builder
+    }
+    fn r#type() -> Option<TypeId> {
+        None
+    }
+    fn borrow() -> Option<(TypeId, &'static str, bool)> {
+        None
+    }
+    // Only the archetype is read, which systems can't change
+    fn access() -> Option<ComponentAccess> {
+        None
+    }
+}
+
+/// A T added or changed since the last run of the system (or the current tick, outside systems, see
+/// `World::query_since`): `(Entity, Changed<A>)`. Spawning an entity or adding a T counts as a
+/// change, like any mutable borrow of the T by a query.
+pub struct Changed<'a, T>(&'a T);
+
+/// A T added since the last run of the system (or the current tick, outside systems, see
+/// `World::query_since`): `(Entity, Added<A>)`
+pub struct Added<'a, T>(&'a T);
+
+macro_rules! impl_tick_filter {
+    ($name:ident, $filter:ident) => {
+        impl<T> Deref for $name<'_, T> {
+            type Target = T;
+            fn deref(&self) -> &T {
+                self.0
+            }
+        }
+
+        impl<T: Component> QuerySingle for $name<'_, T> {
+            fn match_archetype(archetype: &Archetype) -> bool {
+                archetype.has::<T>()
+            }
+            fn build(ptr: *mut u8, archetype: &Archetype, entity: Entity) -> Self {
+                Self(<&T as QuerySingle>::build(ptr, archetype, entity))
+            }
+            fn add_to_bitset(builder: BorrowBitsetBuilder) -> BorrowBitsetBuilder {
+                builder.borrow::<T>()
+            }
+            fn r#type() -> Option<TypeId> {
+                Some(TypeId::of::<T>())
+            }
+            fn borrow() -> Option<(TypeId, &'static str, bool)> {
+                <&T as QuerySingle>::borrow()
+            }
+            fn access() -> Option<ComponentAccess> {
+                Some(ComponentAccess {
+                    filter: Some(TickFilter::$filter),
+                    ..<&T as QuerySingle>::access()?
+                })
+            }
+        }
+    };
+}
+
+impl_tick_filter!(Changed, Changed);
+impl_tick_filter!(Added, Added);
+
 impl<T: QuerySingle> Query for T {
+    const MUTABLE: bool = T::MUTABLE;
     fn match_archetype(archetype: &Archetype) -> bool {
         T::match_archetype(archetype)
     }
@@ -125,12 +432,46 @@ impl<T: QuerySingle> Query for T {
     fn types() -> Vec<TypeId> {
         Self::r#type().into_iter().collect()
     }
+    fn access() -> Vec<ComponentAccess> {
+        T::access().into_iter().collect()
+    }
 }
 
-#[cfg(not(feature = "extended_limits"))]
-impl_query!(16);
+// Mirrors ecs_macros::impl_query
+macro_rules! impl_query_tuple {
+    () => {};
+    ($($t:ident $i:tt),*) => {
+        impl<$($t: QuerySingle),*> Query for ($($t,)*) {
+            const MUTABLE: bool = false $(|| $t::MUTABLE)*;
+            fn match_archetype(archetype: &Archetype) -> bool {
+                true $(&& $t::match_archetype(archetype))*
+            }
+            fn build(ptr: *mut u8, archetype: &Archetype, entity: Entity) -> Self {
+                ($($t::build(ptr, archetype, entity),)*)
+            }
+            fn add_to_bitset(mut builder: BorrowBitsetBuilder) -> BorrowBitsetBuilder {
+                $(builder = $t::add_to_bitset(builder);)*
+                builder
+            }
+            fn types() -> Vec<TypeId> {
+                [$($t::r#type()),*].into_iter().flatten().collect()
+            }
+            fn access() -> Vec<ComponentAccess> {
+                [$($t::access()),*].into_iter().flatten().collect()
+            }
+            fn check_aliasing() -> Result<(), EcsError> {
+                check_aliasing([$($t::borrow()),*])
+            }
+        }
+    };
+}
+
+// Tuples of length 1 to 8 are implemented here, larger ones are generated by ecs_macros
+for_tuples!(impl_query_tuple; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+#[cfg(all(feature = "codegen", not(feature = "extended_limits")))]
+impl_query!(9..=16);
 #[cfg(feature = "extended_limits")]
-impl_query!(24);
+impl_query!(9..=24);
 
 /// An iterator that runs a query on a storage
 ///
@@ -145,9 +486,25 @@ pub struct QueryIter<Q: Query> {
     current: usize,
     storage_index: usize,
     location_map: Option<*const LocationMap>,
+    ticks: QueryTicks,
     _phantom: PhantomData<Q>,
 }
 
+/// The ticks a QueryIter reads and writes
+#[derive(Clone)]
+pub struct QueryTicks {
+    /// Ticks of the entities of the storage, written to if Q is mutable
+    pub rows: *mut RowTicks,
+    /// Ticks of the components Q borrows mutably
+    pub columns: Vec<*mut RowTicks>,
+    /// Ticks of the components Q filters on (`Changed` or `Added`)
+    pub filters: Vec<(*const RowTicks, TickFilter)>,
+    /// The current tick, written to the changed ticks
+    pub tick: u32,
+    /// The tick the filters compare to
+    pub since: u32,
+}
+
 impl<Q: Query> QueryIter<Q> {
     pub fn new(
         data: NonNull<u8>,
@@ -155,6 +512,7 @@ impl<Q: Query> QueryIter<Q> {
         archetype: *const Archetype,
         storage_index: usize,
         location_map: Option<*const LocationMap>,
+        ticks: QueryTicks,
     ) -> Self {
         Self {
             data,
@@ -163,14 +521,39 @@ impl<Q: Query> QueryIter<Q> {
             current: 0,
             storage_index,
             location_map,
+            ticks,
             _phantom: PhantomData,
         }
     }
+    /// Whether the filters accept a row
+    fn accepts(&self, row: usize) -> bool {
+        let QueryTicks { filters, since, .. } = &self.ticks;
+        filters
+            .iter()
+            .all(|&(ticks, filter)| filter.matches(unsafe { *ticks.add(row) }, *since))
+    }
+    /// Move past the rows the filters reject
+    fn skip_filtered(&mut self) {
+        while self.current < self.length && !self.accepts(self.current) {
+            self.current += 1;
+        }
+    }
+    /// The number of items left, the ticks of the rows are read if Q has filters
+    fn remaining(&self) -> usize {
+        if self.ticks.filters.is_empty() {
+            self.length - self.current
+        } else {
+            (self.current..self.length)
+                .filter(|&row| self.accepts(row))
+                .count()
+        }
+    }
 }
 
 impl<Q: Query> Iterator for QueryIter<Q> {
     type Item = Q;
     fn next(&mut self) -> Option<Self::Item> {
+        self.skip_filtered();
         if self.current == self.length {
             None
         } else {
@@ -192,12 +575,32 @@ impl<Q: Query> Iterator for QueryIter<Q> {
                     .as_ptr()
                     .add((*self.archetype).size() * self.current)
             };
+            if Q::MUTABLE {
+                let QueryTicks {
+                    rows,
+                    columns,
+                    tick,
+                    ..
+                } = &self.ticks;
+                unsafe {
+                    (*rows.add(self.current)).changed = *tick;
+                    for ticks in columns {
+                        (*ticks.add(self.current)).changed = *tick;
+                    }
+                }
+            }
             self.current += 1;
             Some(Q::build(ptr, unsafe { &*(self.archetype) }, entity))
         }
     }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.remaining();
+        (remaining, Some(remaining))
+    }
 }
 
+impl<Q: Query> ExactSizeIterator for QueryIter<Q> {}
+
 // Can't use chain, so this will be it.
 /// An iterator chaining multiple QueryIter, iterators are run in reverse (LIFO)
 pub struct QueryIterBundle<Q: Query> {
@@ -216,6 +619,10 @@ impl<Q: Query> QueryIterBundle<Q> {
     pub fn push(&mut self, iter: QueryIter<Q>) {
         self.iters.push(iter);
     }
+    /// Whether there are no items left, reads the ticks of the rows if Q has filters
+    pub fn is_empty(&self) -> bool {
+        self.iters.iter().all(|iter| iter.remaining() == 0)
+    }
 }
 
 impl<Q: Query> Default for QueryIterBundle<Q> {
@@ -238,19 +645,237 @@ impl<Q: Query> Iterator for QueryIterBundle<Q> {
             None => None,
         }
     }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.iters.iter().map(QueryIter::remaining).sum();
+        (remaining, Some(remaining))
+    }
+    /// Counted from the lengths of the storages, without building the items
+    fn count(self) -> usize {
+        self.len()
+    }
+}
+
+impl<Q: Query> ExactSizeIterator for QueryIterBundle<Q> {}
+
+impl<Q: Query> QueryIterBundle<Q> {
+    /// Skip n items without building them, whole storages are skipped using their lengths
+    fn advance(&mut self, mut n: usize) {
+        while n > 0 {
+            let last = match self.iters.last_mut() {
+                Some(last) => last,
+                None => return,
+            };
+            if !last.ticks.filters.is_empty() {
+                // Rows can't be counted without looking at their ticks
+                last.skip_filtered();
+                if last.current < last.length {
+                    last.current += 1;
+                    n -= 1;
+                } else {
+                    self.iters.pop();
+                }
+                continue;
+            }
+            let remaining = last.length - last.current;
+            if remaining <= n {
+                n -= remaining;
+                self.iters.pop();
+            } else {
+                last.current += n;
+                n = 0;
+            }
+        }
+    }
+    /// Drop the exhausted iterators at the end of the bundle
+    fn trim(&mut self) {
+        while let Some(last) = self.iters.last_mut() {
+            last.skip_filtered();
+            if last.current < last.length {
+                break;
+            }
+            self.iters.pop();
+        }
+    }
+    /// Skip `skip` items (without iterating over them) and give at most `take` of the following
+    /// ones.
+    pub fn skip_take(&mut self, skip: usize, take: usize) -> std::iter::Take<&mut Self> {
+        self.advance(skip);
+        self.take(take)
+    }
+    /// Give at most `take` items, starting where the cursor was left and moving it forward. See
+    /// QueryCursor for what is guaranteed when the world changes between pages.
+    pub fn page<'a>(&'a mut self, cursor: &'a mut QueryCursor, take: usize) -> Page<'a, Q> {
+        cursor.complete = false;
+        if let Some((storage, row)) = cursor.position {
+            // Storages are iterated from the last one, so the ones after the cursor's have
+            // already been visited this sweep.
+            while matches!(self.iters.last(), Some(last) if last.storage_index > storage) {
+                self.iters.pop();
+            }
+            if let Some(last) = self.iters.last_mut() {
+                if last.storage_index == storage {
+                    // The storage may have shrunk since
+                    last.current = row.min(last.length);
+                }
+            }
+        }
+        self.trim();
+        if self.iters.is_empty() {
+            cursor.finish();
+        }
+        Page {
+            bundle: self,
+            cursor,
+            left: take,
+        }
+    }
+}
+
+/// Rows of a storage handed to a worker by `QueryIterBundle::par_for_each`
+struct Chunk<Q: Query>(QueryIter<Q>);
+
+// The chunks of a bundle are disjoint rows, and the items built are Send
+unsafe impl<Q: Query + Send> Send for Chunk<Q> {}
+
+impl<Q: Query> Chunk<Q> {
+    fn run(self, f: &impl Fn(Q)) {
+        self.0.for_each(f);
+    }
+}
+
+/// The pool parallel iteration runs on, separate from the executors' so that systems already
+/// running on every worker can still use it
+fn par_pool() -> &'static ThreadPool {
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let mut pool = ThreadPool::new();
+        pool.add_workers(std::thread::available_parallelism().map_or(1, usize::from));
+        pool
+    })
+}
+
+impl<Q: Query + Send> QueryIterBundle<Q> {
+    /// Call f on every item, on worker threads, by chunks of at most `chunk_size` rows of a
+    /// storage. Returns once every chunk is done. Calls can't be nested (f calling
+    /// `par_for_each`), the workers would wait on each other.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0, or if f panics (once the other chunks are done).
+    pub fn par_for_each(self, chunk_size: usize, f: impl Fn(Q) + Send + Sync) {
+        assert!(chunk_size > 0, "par_for_each with empty chunks");
+        let chunks = self
+            .iters
+            .iter()
+            .flat_map(|iter| {
+                (iter.current..iter.length)
+                    .step_by(chunk_size)
+                    .map(move |start| {
+                        let mut chunk = QueryIter::new(
+                            iter.data,
+                            iter.length.min(start + chunk_size),
+                            iter.archetype,
+                            iter.storage_index,
+                            iter.location_map,
+                            iter.ticks.clone(),
+                        );
+                        chunk.current = start;
+                        Chunk(chunk)
+                    })
+            })
+            .collect::<Vec<_>>();
+        if chunks.len() <= 1 {
+            chunks.into_iter().for_each(|chunk| chunk.run(&f));
+            return;
+        }
+        let f = &f;
+        par_pool().scope(|scope| {
+            for chunk in chunks {
+                scope.run(FnJob(move || chunk.run(f)));
+            }
+        });
+    }
+}
+
+impl<'a, Q: Query + Send> BorrowGuard<'a, QueryIterBundle<Q>> {
+    /// `QueryIterBundle::par_for_each`, the query stays borrowed until every chunk is done
+    pub fn par_for_each(mut self, chunk_size: usize, f: impl Fn(Q) + Send + Sync) {
+        std::mem::take(&mut *self).par_for_each(chunk_size, f);
+    }
+}
+
+/// A position in a query, kept across frames to process the entities of a query a few at a time
+/// (see `Entities::page`).
+///
+/// The position is remembered as a storage and a row in that storage, so between pages:
+/// - an entity that stays in place (no component added or removed, and no entity before it
+///   removed from the same storage) is visited exactly once per sweep.
+/// - an entity that moves may be visited zero or two times in the sweep it moved in.
+/// - entities spawned in a storage the cursor hasn't passed yet are visited in the current sweep,
+///   the others in the next one.
+#[derive(Debug, Clone, Default)]
+pub struct QueryCursor {
+    /// Storage index and row of the next entity, None at the start of a sweep
+    position: Option<(usize, usize)>,
+    complete: bool,
+}
+
+impl QueryCursor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Whether the last page reached the end of the query. The next page starts a new sweep.
+    pub fn is_sweep_complete(&self) -> bool {
+        self.complete
+    }
+    /// Go back to the start of the query
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+    fn finish(&mut self) {
+        self.position = None;
+        self.complete = true;
+    }
+}
+
+/// Iterator over a page of a query, see QueryIterBundle::page
+pub struct Page<'a, Q: Query> {
+    bundle: &'a mut QueryIterBundle<Q>,
+    cursor: &'a mut QueryCursor,
+    left: usize,
+}
+
+impl<'a, Q: Query> Iterator for Page<'a, Q> {
+    type Item = Q;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        let last = self.bundle.iters.last_mut()?;
+        let item = last.next()?;
+        self.left -= 1;
+        self.cursor.position = Some((last.storage_index, last.current));
+        self.bundle.trim();
+        if self.bundle.iters.is_empty() {
+            self.cursor.finish();
+        }
+        Some(item)
+    }
 }
 
 trait ResourceQuerySingle<'a>: Sized + 'a {
     fn borrow() -> (TypeId, &'static str, bool);
-    unsafe fn fetch(executor: &'a Executor) -> Option<Self>;
+    unsafe fn fetch(executor: &'a Executor) -> Result<Self, EcsError>;
 }
 
 impl<'a, T: Resource> ResourceQuerySingle<'a> for &'a T {
     fn borrow() -> (TypeId, &'static str, bool) {
         (TypeId::of::<T>(), std::any::type_name::<T>(), false)
     }
-    unsafe fn fetch(executor: &'a Executor) -> Option<Self> {
-        executor.get_resource::<T>()
+    unsafe fn fetch(executor: &'a Executor) -> Result<Self, EcsError> {
+        executor
+            .get_resource::<T>()
+            .ok_or_else(EcsError::missing_resource::<T>)
     }
 }
 
@@ -258,16 +883,316 @@ impl<'a, T: Resource> ResourceQuerySingle<'a> for &'a mut T {
     fn borrow() -> (TypeId, &'static str, bool) {
         (TypeId::of::<T>(), std::any::type_name::<T>(), true)
     }
-    unsafe fn fetch(executor: &'a Executor) -> Option<Self> {
-        executor.get_resource_mut_unchecked::<T>()
+    unsafe fn fetch(executor: &'a Executor) -> Result<Self, EcsError> {
+        executor
+            .get_resource_mut_unchecked::<T>()
+            .ok_or_else(EcsError::missing_resource::<T>)
     }
 }
 
 pub trait ResourceQuery<'a>: 'a + Sized {
-    fn fetch(executor: &'a mut Executor) -> Option<Self>;
+    fn fetch(executor: &'a mut Executor) -> Result<Self, EcsError>;
 }
 
-#[cfg(not(feature = "extended_limits"))]
-impl_res_query!(16);
+// Mirrors ecs_macros::impl_res_query
+macro_rules! impl_res_query_tuple {
+    () => {};
+    ($($t:ident $i:tt),*) => {
+        impl<'a, $($t: ResourceQuerySingle<'a>),*> ResourceQuery<'a> for ($($t,)*) {
+            fn fetch(executor: &'a mut Executor) -> Result<Self, EcsError> {
+                use std::collections::HashMap;
+                let mut muts = HashMap::with_capacity(count!($($t)*));
+                for (t, n, m) in [$($t::borrow()),*] {
+                    if let Some((name, mutable)) = muts.get(&t) {
+                        // If either one is mutable
+                        if *mutable || m {
+                            return Err(EcsError::BorrowConflict {
+                                details: format!("Aliasing problem in query on {}", name),
+                            });
+                        }
+                    }
+                    muts.insert(t, (n, m));
+                }
+
+                Ok(unsafe { ($($t::fetch(executor)?,)*) })
+            }
+        }
+    };
+}
+
+// Tuples of length 1 to 8 are implemented here, larger ones are generated by ecs_macros
+for_tuples!(impl_res_query_tuple; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+#[cfg(all(feature = "codegen", not(feature = "extended_limits")))]
+impl_res_query!(9..=17);
 #[cfg(feature = "extended_limits")]
-impl_res_query!(24);
+impl_res_query!(9..=25);
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::World;
+
+    static BUILDS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Counts how many items were built
+    struct Counted;
+
+    impl QuerySingle for Counted {
+        fn match_archetype(_archetype: &Archetype) -> bool {
+            true
+        }
+        fn build(_: *mut u8, _: &Archetype, _: Entity) -> Self {
+            BUILDS.fetch_add(1, Ordering::Relaxed);
+            Counted
+        }
+        fn add_to_bitset(builder: BorrowBitsetBuilder) -> BorrowBitsetBuilder {
+            builder
+        }
+        fn r#type() -> Option<TypeId> {
+            None
+        }
+        fn borrow() -> Option<(TypeId, &'static str, bool)> {
+            None
+        }
+        fn access() -> Option<ComponentAccess> {
+            None
+        }
+    }
+
+    /// 100 entities over 4 archetypes
+    fn world() -> World {
+        let mut w = World::new();
+        w.spawn_many((0..10u32).map(|i| (i,)));
+        w.spawn_many((10..40u32).map(|i| (i, 0u8)));
+        w.spawn_many((40..45u32).map(|i| (i, 0u16)));
+        w.spawn_many((45..100u32).map(|i| (i, 0u8, 0u16)));
+        w
+    }
+
+    #[test]
+    fn skip_take() {
+        let w = world();
+        let mut seen = Vec::new();
+        for page in 0..7 {
+            let mut query = w.query::<&u32>();
+            let len = seen.len();
+            seen.extend(query.skip_take(page * 15, 15).copied());
+            assert_eq!(15.min(100 - len), seen.len() - len);
+        }
+        seen.sort_unstable();
+        assert_eq!((0..100).collect::<Vec<_>>(), seen);
+    }
+
+    #[test]
+    fn exact_size() {
+        let mut w = World::new();
+        w.spawn_many((0..10u32).map(|i| (i,)));
+        w.spawn_many((10..40u32).map(|i| (i, 0u8)));
+        let since = w.tick();
+        w.advance_tick();
+        w.spawn_many((40..45u32).map(|i| (i, 0u16)));
+
+        let mut query = w.query::<&u32>();
+        assert_eq!(45, query.len());
+        assert_eq!((45, Some(45)), query.size_hint());
+        query.nth(19);
+        assert_eq!(25, query.len());
+        drop(query);
+        // Collecting allocates once
+        let values = w.query::<&u32>().collect::<Vec<_>>();
+        assert_eq!(45, values.len());
+        assert_eq!(45, values.capacity());
+
+        BUILDS.store(0, Ordering::Relaxed);
+        let entities = unsafe { w.query_unchecked::<(Counted, &u32)>(0, 0) };
+        assert!(!entities.is_empty());
+        assert_eq!(45, entities.count());
+        assert_eq!(0, BUILDS.load(Ordering::Relaxed));
+        // Filtered queries count the rows that pass
+        assert_eq!(5, w.query_since::<Changed<u32>>(since).len());
+        assert_eq!(0, w.query::<(&u32, &u8, &u16)>().len());
+    }
+
+    #[test]
+    fn skip_whole_storages() {
+        let w = world();
+        BUILDS.store(0, Ordering::Relaxed);
+        let page = w.query::<(Counted, &u32)>().skip_take(90, 20).count();
+        assert_eq!(10, page);
+        assert_eq!(10, BUILDS.load(Ordering::Relaxed));
+        assert_eq!(0, w.query::<&u32>().skip_take(1000, 1).count());
+    }
+
+    #[test]
+    fn cursor_static() {
+        let w = world();
+        let mut cursor = QueryCursor::new();
+        let mut seen = Vec::new();
+        let mut pages = 0;
+        while !cursor.is_sweep_complete() {
+            seen.extend(w.query::<&u32>().page(&mut cursor, 30).copied());
+            pages += 1;
+        }
+        assert_eq!(4, pages);
+        seen.sort_unstable();
+        assert_eq!((0..100).collect::<Vec<_>>(), seen);
+        // Next page starts a new sweep
+        assert_eq!(30, w.query::<&u32>().page(&mut cursor, 30).count());
+        assert!(!cursor.is_sweep_complete());
+    }
+
+    #[test]
+    fn cursor_changes() {
+        let mut w = World::new();
+        let first = w.spawn_many((0..10u32).map(|i| (i,)));
+        w.spawn_many((10..20u32).map(|i| (i, 0u8)));
+        let mut cursor = QueryCursor::new();
+        let mut seen = Vec::new();
+        seen.extend(w.query::<&u32>().page(&mut cursor, 5).copied());
+        // The storages are visited from the last one
+        assert_eq!((10..15).collect::<Vec<_>>(), seen);
+
+        // Spawned in the storage being visited, after the cursor: visited this sweep
+        w.spawn((20u32, 0u8));
+        // Spawned in a new storage, which is considered already visited
+        w.spawn((21u32, 0u64));
+        // Removed from a storage that wasn't visited yet: never visited
+        w.remove(first[0]);
+        // Moved to a storage already visited: not visited this sweep (it could be visited twice
+        // if it moved the other way)
+        w.add_component(first[1], (0u64,));
+
+        while !cursor.is_sweep_complete() {
+            seen.extend(w.query::<&u32>().page(&mut cursor, 5).copied());
+        }
+        seen.sort_unstable();
+        assert_eq!((2..21).collect::<Vec<_>>(), seen);
+        assert_eq!(
+            seen.len(),
+            seen.iter().collect::<HashSet<_>>().len(),
+            "Visited an entity twice"
+        );
+
+        // The next sweep sees everything
+        let mut next = Vec::new();
+        loop {
+            next.extend(w.query::<&u32>().page(&mut cursor, 7).copied());
+            if cursor.is_sweep_complete() {
+                break;
+            }
+        }
+        next.sort_unstable();
+        assert_eq!((1..22).collect::<Vec<_>>(), next);
+    }
+
+    #[test]
+    fn cursor_shrunk_storage() {
+        let mut w = World::new();
+        let entities = w.spawn_many((0..10u32).map(|i| (i,)));
+        let mut cursor = QueryCursor::new();
+        assert_eq!(8, w.query::<&u32>().page(&mut cursor, 8).count());
+        for &e in &entities[5..] {
+            w.remove(e);
+        }
+        // The storage shrunk below the cursor's row, the sweep ends there
+        assert_eq!(0, w.query::<&u32>().page(&mut cursor, 8).count());
+        assert!(cursor.is_sweep_complete());
+    }
+
+    #[test]
+    fn has() {
+        #[derive(Debug, PartialEq)]
+        struct Transform(u32);
+        struct Light;
+
+        let mut w = World::new();
+        w.spawn_many((0..10).map(|i| (Transform(i),)));
+        w.spawn_many((10..15).map(|i| (Transform(i), Light)));
+        w.spawn_many((15..20).map(|i| (Transform(i), Light, 0u8)));
+        w.spawn((Light,));
+        let lit = w
+            .query::<(Entity, With<Light>)>()
+            .map(|(e, _)| e)
+            .collect::<HashSet<_>>();
+        let mut seen = 0;
+        for (transform, entity, light, again) in
+            w.query::<(&Transform, Entity, Has<Light>, Entity)>()
+        {
+            assert_eq!(entity, again);
+            assert_eq!(lit.contains(&entity), *light);
+            assert_eq!(transform.0 >= 10, light.get());
+            seen += 1;
+        }
+        assert_eq!(20, seen);
+        // Never spawned, and Entity alone with a filter
+        assert!(w.query::<Has<u64>>().all(|has| !*has));
+        assert_eq!(10, w.query::<(Entity, Without<Light>)>().count());
+    }
+
+    /// 100k entities over 4 archetypes
+    fn big_world() -> World {
+        let mut w = World::new();
+        w.spawn_many((0..30_000u32).map(|i| (i,)));
+        w.spawn_many((30_000..60_000u32).map(|i| (i, 0u8)));
+        w.spawn_many((60_000..60_100u32).map(|i| (i, 0u16)));
+        w.spawn_many((60_100..100_000u32).map(|i| (i, 0u8, 0u16)));
+        w
+    }
+
+    #[test]
+    fn par_for_each_matches_sequential() {
+        let w = big_world();
+        let mut sequential = w
+            .query::<(Entity, &u32, Option<&u8>)>()
+            .map(|(e, &i, b)| (e, i, b.is_some()))
+            .collect::<Vec<_>>();
+        let parallel = Mutex::new(Vec::new());
+        w.query::<(Entity, &u32, Option<&u8>)>()
+            .par_for_each(1000, |(e, &i, b)| {
+                parallel.lock().push((e, i, b.is_some()));
+            });
+        let mut parallel = parallel.into_inner();
+        assert_eq!(100_000, parallel.len());
+        sequential.sort_unstable();
+        parallel.sort_unstable();
+        assert_eq!(sequential, parallel);
+    }
+
+    #[test]
+    fn par_for_each_mut() {
+        let mut w = big_world();
+        let rows = AtomicUsize::new(0);
+        w.query::<&mut u32>().par_for_each(4096, |i| {
+            *i += 1;
+            rows.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(100_000, rows.load(Ordering::Relaxed));
+        // Released once done
+        let mut values = w.query::<&u32>().copied().collect::<Vec<_>>();
+        values.sort_unstable();
+        assert_eq!((1..=100_000).collect::<Vec<_>>(), values);
+
+        let mut exe = Executor::new();
+        exe.run_once(&mut w, |entities: crate::Entities<(&mut u32, &u8)>| {
+            entities.par_for_each(100, |(i, _)| *i *= 2);
+        });
+        let mut values = w.query::<&u32>().copied().collect::<Vec<_>>();
+        values.sort_unstable();
+        // Only the entities with a u8 were doubled
+        let mut expected = (1..=100_000)
+            .map(|i| match (30_001..=60_000).contains(&i) || i > 60_100 {
+                true => i * 2,
+                false => i,
+            })
+            .collect::<Vec<_>>();
+        expected.sort_unstable();
+        assert_eq!(expected, values);
+    }
+}
@@ -0,0 +1,574 @@
+//! Typed relations between entities ("targets", "owned by", "attached to").
+//!
+//! A relation of kind K from a source to a target is a `Relation<K>` component on the source,
+//! made and broken with `World::relate` and `World::unrelate` only, so that the world can keep an
+//! index of both directions. When a target is despawned, the kind's `OnTargetDespawn` decides what
+//! happens to the relations pointing to it, so that no relation is left dangling unless asked for.
+
+use std::{any::TypeId, collections::HashMap, marker::PhantomData};
+
+use smallvec::SmallVec;
+
+use crate::{compact::CompactEntityVec, entity::Entity, world::World};
+
+type Targets = SmallVec<[Entity; 1]>;
+type Command = fn(&mut World, Entity, Entity);
+
+/// Sources a target can have before they are compacted
+const COMPACT_SOURCES: usize = 64;
+
+/// What happens to a relation when its target is despawned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnTargetDespawn {
+    /// The relation is dropped, the source loses its `Relation` when it has no target left
+    Remove,
+    /// The source is despawned too (and so on for the relations pointing to it)
+    Cascade,
+    /// The relation is left pointing to the despawned entity
+    Ignore,
+}
+
+/// A kind of relation, usually an empty type
+///
+/// ```ignore
+/// struct OwnedBy;
+/// impl RelationKind for OwnedBy {
+///     const ON_TARGET_DESPAWN: OnTargetDespawn = OnTargetDespawn::Cascade;
+/// }
+/// ```
+pub trait RelationKind: 'static {
+    /// Whether a source can have several targets, relating a source to a new target otherwise
+    /// replaces the old one
+    const MULTIPLE: bool = false;
+    const ON_TARGET_DESPAWN: OnTargetDespawn = OnTargetDespawn::Remove;
+}
+
+/// The targets of an entity for the relation kind K, see `World::relate`
+pub struct Relation<K: RelationKind> {
+    targets: Targets,
+    _kind: PhantomData<fn() -> K>,
+}
+
+impl<K: RelationKind> Relation<K> {
+    fn new(targets: Targets) -> Self {
+        Self {
+            targets,
+            _kind: PhantomData,
+        }
+    }
+    /// The first target
+    pub fn target(&self) -> Entity {
+        self.targets[0]
+    }
+    pub fn targets(&self) -> &[Entity] {
+        &self.targets
+    }
+}
+
+/// The sources related to a target. Targets of many sources (everything owned by a level, the
+/// static geometry of a chunk) keep them in a `CompactEntityVec`.
+enum Sources {
+    /// In the order they were related
+    List(Vec<Entity>),
+    /// Sorted by index, back to a list once half the threshold is reached
+    Compact(CompactEntityVec),
+}
+
+impl Sources {
+    fn iter(&self) -> impl Iterator<Item = Entity> + '_ {
+        let (list, compact) = match self {
+            Self::List(list) => (Some(list.iter().copied()), None),
+            Self::Compact(compact) => (None, Some(compact.iter())),
+        };
+        list.into_iter()
+            .flatten()
+            .chain(compact.into_iter().flatten())
+    }
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::List(list) => list.is_empty(),
+            Self::Compact(compact) => compact.is_empty(),
+        }
+    }
+    /// Add a source not already there
+    fn push(&mut self, source: Entity) {
+        match self {
+            Self::List(list) if list.len() < COMPACT_SOURCES => list.push(source),
+            Self::List(list) => {
+                *self = Self::Compact(list.iter().copied().chain([source]).collect());
+            }
+            Self::Compact(compact) => {
+                compact.insert(source);
+            }
+        }
+    }
+    fn remove(&mut self, source: Entity) {
+        match self {
+            Self::List(list) => list.retain(|s| *s != source),
+            Self::Compact(compact) => {
+                compact.remove(source);
+                if compact.len() <= COMPACT_SOURCES / 2 {
+                    *self = Self::List(compact.iter().collect());
+                }
+            }
+        }
+    }
+}
+
+impl Default for Sources {
+    fn default() -> Self {
+        Self::List(Vec::new())
+    }
+}
+
+/// Both directions of the relations of a kind
+struct Table {
+    policy: OnTargetDespawn,
+    multiple: bool,
+    forward: HashMap<Entity, Targets>,
+    reverse: HashMap<Entity, Sources>,
+    /// Read the targets of a Relation component
+    read: unsafe fn(*const u8) -> Targets,
+    /// Make the Relation component of a source match the index
+    sync: fn(&mut World, Entity),
+}
+
+impl Table {
+    fn new<K: RelationKind>() -> Self {
+        Self {
+            policy: K::ON_TARGET_DESPAWN,
+            multiple: K::MULTIPLE,
+            forward: HashMap::new(),
+            reverse: HashMap::new(),
+            read: |ptr| unsafe { (*(ptr as *const Relation<K>)).targets.clone() },
+            sync: sync::<K>,
+        }
+    }
+    fn link(&mut self, source: Entity, target: Entity) {
+        if !self.multiple {
+            self.drop_source(source);
+        }
+        let targets = self.forward.entry(source).or_default();
+        if !targets.contains(&target) {
+            targets.push(target);
+            self.reverse.entry(target).or_default().push(source);
+        }
+    }
+    /// Returns false if the relation didn't exist
+    fn unlink(&mut self, source: Entity, target: Entity) -> bool {
+        let targets = match self.forward.get_mut(&source) {
+            Some(targets) if targets.contains(&target) => targets,
+            _ => return false,
+        };
+        targets.retain(|t| *t != target);
+        if targets.is_empty() {
+            self.forward.remove(&source);
+        }
+        remove_from(&mut self.reverse, target, source);
+        true
+    }
+    /// Drop the relations of a source
+    fn drop_source(&mut self, source: Entity) {
+        for target in self.forward.remove(&source).unwrap_or_default() {
+            remove_from(&mut self.reverse, target, source);
+        }
+    }
+    /// Drop the relations to a target, returns their sources
+    fn drop_target(&mut self, target: Entity) -> Vec<Entity> {
+        let sources = self
+            .reverse
+            .remove(&target)
+            .map_or_else(Vec::new, |sources| sources.iter().collect());
+        for source in &sources {
+            if let Some(targets) = self.forward.get_mut(source) {
+                targets.retain(|t| *t != target);
+                if targets.is_empty() {
+                    self.forward.remove(source);
+                }
+            }
+        }
+        sources
+    }
+}
+
+fn remove_from(map: &mut HashMap<Entity, Sources>, key: Entity, value: Entity) {
+    if let Some(values) = map.get_mut(&key) {
+        values.remove(value);
+        if values.is_empty() {
+            map.remove(&key);
+        }
+    }
+}
+
+fn sync<K: RelationKind>(world: &mut World, source: Entity) {
+    let id = TypeId::of::<Relation<K>>();
+    let targets = world.relations.tables[&id].forward.get(&source).cloned();
+    let present = world.component_ptr(source, id).is_some();
+    match targets {
+        Some(targets) if present => {
+            world.component_mut::<Relation<K>>(source).unwrap().targets = targets;
+        }
+        Some(targets) => {
+            let _ = world.try_add_component_unindexed(source, (Relation::<K>::new(targets),));
+        }
+        None if present => {
+            let _ = world.try_take_component_unindexed::<(Relation<K>,)>(source);
+        }
+        None => {}
+    }
+}
+
+/// The relations of a world, by TypeId of the Relation component
+#[derive(Default)]
+pub(crate) struct RelationIndex {
+    tables: HashMap<TypeId, Table>,
+}
+
+impl RelationIndex {
+    fn table<K: RelationKind>(&self) -> Option<&Table> {
+        self.tables.get(&TypeId::of::<Relation<K>>())
+    }
+}
+
+impl World {
+    /// Relate source to target, replacing the previous target of source unless K allows multiple
+    /// ones. None if one of the entities doesn't exist.
+    pub fn relate<K: RelationKind>(&mut self, source: Entity, target: Entity) -> Option<()> {
+        if !self.contains(source) || !self.contains(target) {
+            return None;
+        }
+        self.relations
+            .tables
+            .entry(TypeId::of::<Relation<K>>())
+            .or_insert_with(Table::new::<K>)
+            .link(source, target);
+        sync::<K>(self, source);
+        Some(())
+    }
+    /// Break a relation, returns false if it didn't exist
+    pub fn unrelate<K: RelationKind>(&mut self, source: Entity, target: Entity) -> bool {
+        let unlinked = match self.relations.tables.get_mut(&TypeId::of::<Relation<K>>()) {
+            Some(table) => table.unlink(source, target),
+            None => false,
+        };
+        if unlinked {
+            sync::<K>(self, source);
+        }
+        unlinked
+    }
+    /// The targets of source
+    pub fn related<K: RelationKind>(&self, source: Entity) -> &[Entity] {
+        self.relations
+            .table::<K>()
+            .and_then(|table| table.forward.get(&source))
+            .map_or(&[], |targets| targets)
+    }
+    /// The sources related to target, in the order they were related unless there are many of
+    /// them (then by index, see `CompactEntityVec`)
+    pub fn related_to<K: RelationKind>(&self, target: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.relations
+            .table::<K>()
+            .and_then(|table| table.reverse.get(&target))
+            .into_iter()
+            .flat_map(Sources::iter)
+    }
+    /// Index the Relation components among types, just added to entity
+    pub(crate) fn relations_added(&mut self, entity: Entity, types: &[TypeId]) {
+        for id in types {
+            if let Some(ptr) = self
+                .relations
+                .tables
+                .contains_key(id)
+                .then(|| self.component_ptr(entity, *id))
+                .flatten()
+            {
+                let table = self.relations.tables.get_mut(id).unwrap();
+                table.drop_source(entity);
+                for target in unsafe { (table.read)(ptr) } {
+                    table.link(entity, target);
+                }
+            }
+        }
+    }
+    /// Drop the Relation components among types, just taken from entity, from the index
+    pub(crate) fn relations_taken(&mut self, entity: Entity, types: &[TypeId]) {
+        for id in types {
+            if let Some(table) = self.relations.tables.get_mut(id) {
+                table.drop_source(entity);
+            }
+        }
+    }
+    /// Clean up the relations of despawned entities, following the policies of their kinds
+    pub(crate) fn relations_despawned(&mut self, mut despawned: Vec<Entity>) {
+        if self.relations.tables.is_empty() {
+            return;
+        }
+        while let Some(entity) = despawned.pop() {
+            let mut resync = Vec::new();
+            let mut cascade = Vec::new();
+            for table in self.relations.tables.values_mut() {
+                table.drop_source(entity);
+                match table.policy {
+                    OnTargetDespawn::Remove => resync.extend(
+                        table
+                            .drop_target(entity)
+                            .into_iter()
+                            .map(|source| (table.sync, source)),
+                    ),
+                    OnTargetDespawn::Cascade => cascade.extend(table.drop_target(entity)),
+                    OnTargetDespawn::Ignore => {}
+                }
+            }
+            for (sync, source) in resync {
+                sync(self, source);
+            }
+            // Already despawned sources end cycles
+            for source in cascade {
+                if self.despawn(source).is_some() {
+                    despawned.push(source);
+                }
+            }
+        }
+    }
+}
+
+/// Relations to make or break later, for systems that can't get the World: add it as a resource
+/// and apply it once the schedule ran.
+#[derive(Default)]
+pub struct RelationCommands {
+    commands: Vec<(Command, Entity, Entity)>,
+}
+
+impl RelationCommands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn relate<K: RelationKind>(&mut self, source: Entity, target: Entity) {
+        self.commands.push((
+            |world, source, target| {
+                world.relate::<K>(source, target);
+            },
+            source,
+            target,
+        ));
+    }
+    pub fn unrelate<K: RelationKind>(&mut self, source: Entity, target: Entity) {
+        self.commands.push((
+            |world, source, target| {
+                world.unrelate::<K>(source, target);
+            },
+            source,
+            target,
+        ));
+    }
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+    /// Apply the commands in order, relations with entities gone since are skipped
+    pub fn apply(&mut self, world: &mut World) {
+        for (command, source, target) in self.commands.drain(..) {
+            command(world, source, target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Targets;
+    impl RelationKind for Targets {}
+
+    struct OwnedBy;
+    impl RelationKind for OwnedBy {
+        const ON_TARGET_DESPAWN: OnTargetDespawn = OnTargetDespawn::Cascade;
+    }
+
+    struct Watches;
+    impl RelationKind for Watches {
+        const MULTIPLE: bool = true;
+        const ON_TARGET_DESPAWN: OnTargetDespawn = OnTargetDespawn::Ignore;
+    }
+
+    fn related_to<K: RelationKind>(world: &World, target: Entity) -> Vec<Entity> {
+        world.related_to::<K>(target).collect()
+    }
+
+    fn targets<K: RelationKind>(world: &World, source: Entity) -> Option<Vec<Entity>> {
+        world
+            .query::<(Entity, &Relation<K>)>()
+            .find(|(entity, _)| *entity == source)
+            .map(|(_, relation)| relation.targets().to_vec())
+    }
+
+    #[test]
+    fn lookups() {
+        let mut world = World::new();
+        let [a, b, c] = [(); 3].map(|_| world.spawn((0u32,)));
+
+        world.relate::<Targets>(a, b).unwrap();
+        world.relate::<Targets>(c, b).unwrap();
+        assert_eq!(&[b], world.related::<Targets>(a));
+        assert_eq!(vec![a, c], related_to::<Targets>(&world, b));
+        assert_eq!(Some(vec![b]), targets::<Targets>(&world, a));
+        // Other kinds aren't related
+        assert!(world.related::<OwnedBy>(a).is_empty());
+
+        // Single target, relating again replaces it
+        world.relate::<Targets>(a, c).unwrap();
+        assert_eq!(&[c], world.related::<Targets>(a));
+        assert_eq!(vec![c], related_to::<Targets>(&world, b));
+        assert_eq!(vec![a], related_to::<Targets>(&world, c));
+        assert_eq!(Some(vec![c]), targets::<Targets>(&world, a));
+
+        assert!(world.unrelate::<Targets>(a, c));
+        assert!(!world.unrelate::<Targets>(a, c));
+        assert!(world.related::<Targets>(a).is_empty());
+        assert!(related_to::<Targets>(&world, c).is_empty());
+        assert_eq!(None, targets::<Targets>(&world, a));
+
+        world.remove(c);
+        assert_eq!(None, world.relate::<Targets>(a, c));
+        assert_eq!(None, world.relate::<Targets>(c, a));
+    }
+
+    #[test]
+    fn multiple() {
+        let mut world = World::new();
+        let [a, b, c] = [(); 3].map(|_| world.spawn((0u32,)));
+        world.relate::<Watches>(a, b).unwrap();
+        world.relate::<Watches>(a, c).unwrap();
+        world.relate::<Watches>(a, c).unwrap();
+        assert_eq!(&[b, c], world.related::<Watches>(a));
+        assert_eq!(Some(vec![b, c]), targets::<Watches>(&world, a));
+
+        world.unrelate::<Watches>(a, b);
+        assert_eq!(Some(vec![c]), targets::<Watches>(&world, a));
+    }
+
+    #[test]
+    fn remove_policy() {
+        let mut world = World::new();
+        let [a, b, c] = [(); 3].map(|_| world.spawn((0u32,)));
+        world.relate::<Targets>(a, c).unwrap();
+        world.relate::<Targets>(b, c).unwrap();
+        world.remove(c);
+        assert!(world.related::<Targets>(a).is_empty());
+        assert!(related_to::<Targets>(&world, c).is_empty());
+        assert_eq!(None, targets::<Targets>(&world, a));
+        assert_eq!(None, targets::<Targets>(&world, b));
+        // Sources live on
+        assert_eq!(2, world.stats().entities);
+        // Despawning a source drops its relations too
+        world.relate::<Targets>(a, b).unwrap();
+        world.remove(a);
+        assert!(related_to::<Targets>(&world, b).is_empty());
+    }
+
+    #[test]
+    fn cascade_policy() {
+        let mut world = World::new();
+        let [a, b, c, d] = [(); 4].map(|_| world.spawn((0u32,)));
+        // d owned by c owned by b owned by a
+        world.relate::<OwnedBy>(b, a).unwrap();
+        world.relate::<OwnedBy>(c, b).unwrap();
+        world.relate::<OwnedBy>(d, c).unwrap();
+        world.relate::<Targets>(a, d).unwrap();
+        world.remove(b);
+        assert_eq!(1, world.stats().entities);
+        assert!(related_to::<OwnedBy>(&world, a).is_empty());
+        // d was a target
+        assert!(world.related::<Targets>(a).is_empty());
+
+        // Cycles end
+        let [e, f, g] = [(); 3].map(|_| world.spawn((0u32,)));
+        world.relate::<OwnedBy>(e, f).unwrap();
+        world.relate::<OwnedBy>(f, g).unwrap();
+        world.relate::<OwnedBy>(g, e).unwrap();
+        world.relate::<OwnedBy>(a, a).unwrap();
+        world.remove(f).unwrap();
+        world.remove(a).unwrap();
+        assert_eq!(0, world.stats().entities);
+        assert!(world
+            .relations
+            .tables
+            .values()
+            .all(|t| t.forward.is_empty() && t.reverse.is_empty()));
+    }
+
+    #[test]
+    fn ignore_policy() {
+        let mut world = World::new();
+        let [a, b] = [(); 2].map(|_| world.spawn((0u32,)));
+        world.relate::<Watches>(a, b).unwrap();
+        world.take::<(u32,)>(b).unwrap();
+        assert_eq!(&[b], world.related::<Watches>(a));
+        assert_eq!(Some(vec![b]), targets::<Watches>(&world, a));
+    }
+
+    #[test]
+    fn take_component() {
+        let mut world = World::new();
+        let [a, b, c] = [(); 3].map(|_| world.spawn((0u32,)));
+        world.relate::<Watches>(a, b).unwrap();
+        world.relate::<Watches>(a, c).unwrap();
+
+        let (relation,) = world.take_component::<(Relation<Watches>,)>(a).unwrap();
+        assert!(world.related::<Watches>(a).is_empty());
+        assert!(related_to::<Watches>(&world, b).is_empty());
+
+        // Putting it back indexes it again, on another entity too
+        world.add_component(c, (relation,)).unwrap();
+        assert_eq!(&[b, c], world.related::<Watches>(c));
+        assert_eq!(vec![c], related_to::<Watches>(&world, b));
+        world.relate::<Watches>(c, a).unwrap();
+        assert_eq!(Some(vec![b, c, a]), targets::<Watches>(&world, c));
+    }
+
+    #[test]
+    fn many_sources() {
+        let mut world = World::new();
+        let target = world.spawn((0u32,));
+        let sources = (0..200).map(|_| world.spawn((0u32,))).collect::<Vec<_>>();
+        for &source in &sources {
+            world.relate::<Watches>(source, target).unwrap();
+        }
+        let reverse =
+            |world: &World| match &world.relations.table::<Watches>().unwrap().reverse[&target] {
+                Sources::List(_) => "list",
+                Sources::Compact(_) => "compact",
+            };
+        assert_eq!("compact", reverse(&world));
+        let mut related = related_to::<Watches>(&world, target);
+        related.sort();
+        let mut expected = sources.clone();
+        expected.sort();
+        assert_eq!(expected, related);
+
+        for &source in &sources[..170] {
+            assert!(world.unrelate::<Watches>(source, target));
+        }
+        assert_eq!("list", reverse(&world));
+        assert_eq!(&sources[170..], related_to::<Watches>(&world, target));
+        world.remove(sources[199]);
+        assert_eq!(&sources[170..199], related_to::<Watches>(&world, target));
+    }
+
+    #[test]
+    fn commands() {
+        let mut world = World::new();
+        let [a, b, c] = [(); 3].map(|_| world.spawn((0u32,)));
+        let mut commands = RelationCommands::new();
+        commands.relate::<Targets>(a, b);
+        commands.relate::<Watches>(a, c);
+        commands.unrelate::<Targets>(a, b);
+        commands.relate::<Targets>(c, b);
+        assert!(world.related::<Targets>(c).is_empty());
+        world.remove(b);
+        commands.apply(&mut world);
+        assert!(commands.is_empty());
+        assert!(world.related::<Targets>(a).is_empty());
+        assert!(world.related::<Targets>(c).is_empty());
+        assert_eq!(&[c], world.related::<Watches>(a));
+    }
+}
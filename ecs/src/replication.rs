@@ -0,0 +1,786 @@
+//! World replication through diffs.
+//!
+//! `World::diff_since` gives everything that changed in a world since a tick (see `World::tick`),
+//! as a versioned, deterministic `WorldDiff` that can be sent over the network and applied to
+//! another world with `World::apply_diff`. Only the components registered in a
+//! `ComponentRegistry` are replicated, under a stable name. Every world has one, filled by
+//! `World::register_serde` and shared with snapshots (see `World::components`).
+//!
+//! Changes are tracked per component: an entity touched by a mutable query is sent with the values
+//! of the components that changed only, and the names of the others (so that removed components
+//! are removed on the other end). A despawn is only known as long as it is in the world's despawn
+//! history.
+
+use std::{
+    any::TypeId,
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::Arc,
+};
+
+use crate::{
+    archetype::{ArchetypeStorage, Component},
+    entity::Entity,
+    world::World,
+};
+
+/// Version of the diff format, bumped on any breaking change
+pub const DIFF_VERSION: u32 = 2;
+
+type Serialize = Arc<dyn Fn(*const u8) -> Vec<u8> + Send + Sync>;
+type Apply = Arc<dyn Fn(&mut World, Entity, &[u8], &EntityMap) -> Option<()> + Send + Sync>;
+type Remove = Arc<dyn Fn(&mut World, Entity) + Send + Sync>;
+
+#[derive(Clone)]
+struct Registration {
+    type_id: TypeId,
+    serialize: Serialize,
+    apply: Apply,
+    remove: Remove,
+}
+
+/// The replicated components, with their (de)serialization functions
+#[derive(Clone, Default)]
+pub struct ComponentRegistry {
+    /// Sorted by name so that diffs are deterministic
+    components: BTreeMap<String, Registration>,
+}
+
+impl ComponentRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Register a component under a name, the name must be the same on both ends
+    pub fn register<T: Component>(
+        &mut self,
+        name: &str,
+        serialize: fn(&T) -> Vec<u8>,
+        deserialize: fn(&[u8]) -> Option<T>,
+    ) -> &mut Self {
+        self.register_mapped(name, serialize, deserialize, |_, _| {})
+    }
+    /// Register a component holding entities, map_entities translates them with the EntityMap of
+    /// the world the diff is applied to.
+    pub fn register_mapped<T: Component>(
+        &mut self,
+        name: &str,
+        serialize: fn(&T) -> Vec<u8>,
+        deserialize: fn(&[u8]) -> Option<T>,
+        map_entities: fn(&mut T, &EntityMap),
+    ) -> &mut Self {
+        let registration = Registration {
+            type_id: TypeId::of::<T>(),
+            serialize: Arc::new(move |ptr| serialize(unsafe { &*(ptr as *const T) })),
+            apply: Arc::new(move |world, entity, bytes, map| {
+                let mut value = deserialize(bytes)?;
+                map_entities(&mut value, map);
+                match world.component_mut::<T>(entity) {
+                    Some(component) => *component = value,
+                    None => world.add_component(entity, (value,))?,
+                }
+                Some(())
+            }),
+            remove: Arc::new(|world, entity| {
+                if world.component_ptr(entity, TypeId::of::<T>()).is_some() {
+                    world.take_component::<(T,)>(entity);
+                }
+            }),
+        };
+        self.components.insert(name.to_owned(), registration);
+        self
+    }
+    /// The registered components of an entity, serialized and sorted by name
+    pub fn serialize(&self, world: &World, entity: Entity) -> Vec<(String, Vec<u8>)> {
+        self.components
+            .iter()
+            .filter_map(|(name, registration)| {
+                let ptr = world.component_ptr(entity, registration.type_id)?;
+                Some((name.clone(), (registration.serialize)(ptr)))
+            })
+            .collect()
+    }
+    /// The registered components of an entity at row of storage, sorted by name, serialized only if
+    /// they changed since baseline_tick
+    fn serialize_changed(
+        &self,
+        world: &World,
+        entity: Entity,
+        storage: &ArchetypeStorage,
+        row: usize,
+        baseline_tick: u32,
+    ) -> Vec<(String, Option<Vec<u8>>)> {
+        self.components
+            .iter()
+            .filter_map(|(name, registration)| {
+                let ptr = world.component_ptr(entity, registration.type_id)?;
+                let changed = storage
+                    .component_ticks(registration.type_id)
+                    .map_or(true, |ticks| ticks[row].changed > baseline_tick);
+                Some((name.clone(), changed.then(|| (registration.serialize)(ptr))))
+            })
+            .collect()
+    }
+    /// Whether a component type is registered
+    pub fn contains(&self, type_id: TypeId) -> bool {
+        self.components
+            .values()
+            .any(|registration| registration.type_id == type_id)
+    }
+    /// Set the registered components of an entity to the serialized ones (sorted by name), removing
+    /// the registered components it has but which aren't in the list. Components listed without a
+    /// value are kept as they are, and must be there. Gives the name of the component that couldn't
+    /// be applied on failure.
+    pub(crate) fn apply<B: ComponentBytes>(
+        &self,
+        world: &mut World,
+        entity: Entity,
+        components: &[(String, B)],
+        map: &EntityMap,
+    ) -> Result<(), String> {
+        let mut present = components.iter().map(|(name, _)| name).peekable();
+        for (name, registration) in &self.components {
+            if present.next_if(|n| *n == name).is_none() {
+                (registration.remove)(world, entity);
+            }
+        }
+        for (name, bytes) in components {
+            self.components
+                .get(name)
+                .and_then(|registration| match bytes.bytes() {
+                    Some(bytes) => (registration.apply)(world, entity, bytes, map),
+                    None => world
+                        .component_ptr(entity, registration.type_id)
+                        .map(|_| ()),
+                })
+                .ok_or_else(|| name.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// A serialized component given to `ComponentRegistry::apply`, None if it is unchanged
+pub(crate) trait ComponentBytes {
+    fn bytes(&self) -> Option<&[u8]>;
+}
+
+impl ComponentBytes for Vec<u8> {
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+impl ComponentBytes for Option<Vec<u8>> {
+    fn bytes(&self) -> Option<&[u8]> {
+        self.as_deref()
+    }
+}
+
+/// The components of an entity in a diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityDiff {
+    /// The entity in the source world (`Entity::to_bits`)
+    pub entity: u64,
+    /// Every registered component of the entity, sorted by name, with its value if it changed since
+    /// the baseline (always for spawned entities)
+    pub components: Vec<(String, Option<Vec<u8>>)>,
+}
+
+/// Changes of a world between two ticks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorldDiff {
+    pub version: u32,
+    /// Tick the diff starts from (excluded), 0 for a full snapshot
+    pub baseline: u32,
+    /// Tick of the world when the diff was made
+    pub tick: u32,
+    /// Entities spawned since the baseline, sorted by entity
+    pub spawned: Vec<EntityDiff>,
+    /// Entities changed since the baseline, sorted by entity
+    pub changed: Vec<EntityDiff>,
+    /// Entities despawned since the baseline, sorted
+    pub despawned: Vec<u64>,
+    /// Some despawns since the baseline were evicted from the history and are missing, the
+    /// receiver needs a full snapshot instead.
+    pub truncated: bool,
+}
+
+/// Maps the entities of a source world to the entities of the world diffs are applied to
+#[derive(Debug, Default)]
+pub struct EntityMap {
+    entities: HashMap<u64, Entity>,
+    /// Tick of the last diff applied
+    applied: Option<u32>,
+}
+
+impl EntityMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The local entity of a source entity
+    pub fn get(&self, source: Entity) -> Option<Entity> {
+        self.entities.get(&source.to_bits()).copied()
+    }
+    /// Map a source entity to an entity that already exists in the local world, for a snapshot
+    /// applied on top of a world rebuilt the same way as the source (a scene spawned again). The
+    /// snapshot then updates that entity instead of spawning a new one.
+    pub fn insert(&mut self, source: Entity, local: Entity) {
+        self.entities.insert(source.to_bits(), local);
+    }
+    /// Tick of the last diff applied
+    pub fn last_applied(&self) -> Option<u32> {
+        self.applied
+    }
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffError {
+    /// The diff was made by another version of the format
+    Version(u32),
+    /// The bytes don't hold a valid diff
+    Malformed,
+    /// The diff is not newer than the last one applied
+    AlreadyApplied { tick: u32, applied: u32 },
+    /// The diff starts after the last one applied (or isn't a snapshot for a new EntityMap), so
+    /// some changes would be missed
+    MissingBaseline { baseline: u32, applied: Option<u32> },
+    /// Despawns are missing from the diff
+    Truncated,
+    /// A component isn't registered, or its bytes couldn't be deserialized
+    Component(String),
+}
+
+impl Display for DiffError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Version(v) => write!(f, "unsupported diff version {v} (expected {DIFF_VERSION})"),
+            Self::Malformed => write!(f, "malformed diff"),
+            Self::AlreadyApplied { tick, applied } => {
+                write!(f, "diff up to tick {tick} already applied (at {applied})")
+            }
+            Self::MissingBaseline { baseline, applied } => {
+                write!(
+                    f,
+                    "diff from tick {baseline} can't be applied after {applied:?}"
+                )
+            }
+            Self::Truncated => write!(f, "diff is missing despawns"),
+            Self::Component(name) => write!(f, "can't apply component {name}"),
+        }
+    }
+}
+
+impl std::error::Error for DiffError {}
+
+impl World {
+    /// Everything that changed since baseline_tick, for the components of the registry. Use a
+    /// baseline of 0 for a full snapshot.
+    pub fn diff_since(&self, baseline_tick: u32, registry: &ComponentRegistry) -> WorldDiff {
+        let mut spawned = Vec::new();
+        let mut changed = Vec::new();
+        let location_map = self.location_map();
+        for (archetype, storage) in self.storages().enumerate() {
+            for (row, ticks) in storage.ticks().iter().enumerate() {
+                if ticks.changed <= baseline_tick && ticks.added <= baseline_tick {
+                    continue;
+                }
+                let entity = location_map
+                    .get_entity(crate::entity::Location {
+                        archetype,
+                        entity: row,
+                    })
+                    .expect("Diffing unregistered entity");
+                if ticks.added > baseline_tick {
+                    spawned.push(EntityDiff {
+                        entity: entity.to_bits(),
+                        components: registry
+                            .serialize(self, entity)
+                            .into_iter()
+                            .map(|(name, bytes)| (name, Some(bytes)))
+                            .collect(),
+                    });
+                } else {
+                    changed.push(EntityDiff {
+                        entity: entity.to_bits(),
+                        components: registry.serialize_changed(
+                            self,
+                            entity,
+                            storage,
+                            row,
+                            baseline_tick,
+                        ),
+                    });
+                }
+            }
+        }
+        spawned.sort_by_key(|e| e.entity);
+        changed.sort_by_key(|e| e.entity);
+        // Despawns before the first tick can't matter to a snapshot
+        let (mut despawned, truncated) = match location_map.despawned_since(baseline_tick) {
+            Some(despawned) => (despawned, false),
+            None => (Vec::new(), baseline_tick > 0),
+        };
+        let mut despawned = despawned.drain(..).map(Entity::to_bits).collect::<Vec<_>>();
+        despawned.sort_unstable();
+        WorldDiff {
+            version: DIFF_VERSION,
+            baseline: baseline_tick,
+            tick: self.tick(),
+            spawned,
+            changed,
+            despawned,
+            truncated,
+        }
+    }
+    /// Apply a diff made by `diff_since` on another world, map keeps track of the entities of the
+    /// source world and of the diffs already applied. The registered components of the entities
+    /// in the diff end up matching the source exactly.
+    pub fn apply_diff(
+        &mut self,
+        diff: &WorldDiff,
+        registry: &ComponentRegistry,
+        map: &mut EntityMap,
+    ) -> Result<(), DiffError> {
+        if diff.version != DIFF_VERSION {
+            return Err(DiffError::Version(diff.version));
+        }
+        match map.applied {
+            Some(applied) if diff.tick <= applied => {
+                return Err(DiffError::AlreadyApplied {
+                    tick: diff.tick,
+                    applied,
+                })
+            }
+            Some(applied) if diff.baseline > applied => {
+                return Err(DiffError::MissingBaseline {
+                    baseline: diff.baseline,
+                    applied: map.applied,
+                })
+            }
+            None if diff.baseline != 0 => {
+                return Err(DiffError::MissingBaseline {
+                    baseline: diff.baseline,
+                    applied: None,
+                })
+            }
+            _ => {}
+        }
+        if diff.truncated {
+            return Err(DiffError::Truncated);
+        }
+
+        for bits in &diff.despawned {
+            if let Some(entity) = map.entities.remove(bits) {
+                self.remove(entity);
+            }
+        }
+        // Create every entity first, components can refer to entities later in the diff
+        for entity in diff.spawned.iter().chain(&diff.changed) {
+            map.entities
+                .entry(entity.entity)
+                .or_insert_with(|| self.spawn(()));
+        }
+        for entity in diff.spawned.iter().chain(&diff.changed) {
+            let local = map.entities[&entity.entity];
+            registry
+                .apply(self, local, &entity.components, map)
+                .map_err(DiffError::Component)?;
+        }
+        map.applied = Some(diff.tick);
+        Ok(())
+    }
+}
+
+impl WorldDiff {
+    /// Encode the diff, all integers are little endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn entities(out: &mut Vec<u8>, entities: &[EntityDiff]) {
+            out.extend((entities.len() as u32).to_le_bytes());
+            for entity in entities {
+                out.extend(entity.entity.to_le_bytes());
+                out.extend((entity.components.len() as u32).to_le_bytes());
+                for (name, bytes) in &entity.components {
+                    out.extend((name.len() as u32).to_le_bytes());
+                    out.extend(name.as_bytes());
+                    // Unchanged components only have their name
+                    out.push(bytes.is_some() as u8);
+                    if let Some(bytes) = bytes {
+                        out.extend((bytes.len() as u32).to_le_bytes());
+                        out.extend(bytes);
+                    }
+                }
+            }
+        }
+        let mut out = Vec::new();
+        out.extend(self.version.to_le_bytes());
+        out.extend(self.baseline.to_le_bytes());
+        out.extend(self.tick.to_le_bytes());
+        out.push(self.truncated as u8);
+        entities(&mut out, &self.spawned);
+        entities(&mut out, &self.changed);
+        out.extend((self.despawned.len() as u32).to_le_bytes());
+        for entity in &self.despawned {
+            out.extend(entity.to_le_bytes());
+        }
+        out
+    }
+    /// Decode a diff encoded with to_bytes, checking the version first
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DiffError> {
+        let mut reader = Reader(bytes);
+        let version = reader.u32()?;
+        if version != DIFF_VERSION {
+            return Err(DiffError::Version(version));
+        }
+        let baseline = reader.u32()?;
+        let tick = reader.u32()?;
+        let truncated = reader.take(1)?[0] != 0;
+        let spawned = reader.entities()?;
+        let changed = reader.entities()?;
+        let despawned = (0..reader.u32()?)
+            .map(|_| reader.u64())
+            .collect::<Result<_, _>>()?;
+        if !reader.0.is_empty() {
+            return Err(DiffError::Malformed);
+        }
+        Ok(Self {
+            version,
+            baseline,
+            tick,
+            spawned,
+            changed,
+            despawned,
+            truncated,
+        })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DiffError> {
+        if self.0.len() < len {
+            return Err(DiffError::Malformed);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+    fn u32(&mut self) -> Result<u32, DiffError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn u64(&mut self) -> Result<u64, DiffError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    fn entities(&mut self) -> Result<Vec<EntityDiff>, DiffError> {
+        (0..self.u32()?)
+            .map(|_| {
+                let entity = self.u64()?;
+                let components = (0..self.u32()?)
+                    .map(|_| {
+                        let len = self.u32()? as usize;
+                        let name = String::from_utf8(self.take(len)?.to_vec())
+                            .map_err(|_| DiffError::Malformed)?;
+                        let bytes = match self.take(1)?[0] {
+                            0 => None,
+                            1 => {
+                                let len = self.u32()? as usize;
+                                Some(self.take(len)?.to_vec())
+                            }
+                            _ => return Err(DiffError::Malformed),
+                        };
+                        Ok((name, bytes))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(EntityDiff { entity, components })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Position(f32, f32);
+    #[derive(Debug, Clone, PartialEq)]
+    struct Name(String);
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Parent(Entity);
+
+    fn registry() -> ComponentRegistry {
+        let mut registry = ComponentRegistry::new();
+        registry
+            .register::<Position>(
+                "position",
+                |p| [p.0.to_le_bytes(), p.1.to_le_bytes()].concat(),
+                |b| {
+                    Some(Position(
+                        f32::from_le_bytes(b.get(0..4)?.try_into().ok()?),
+                        f32::from_le_bytes(b.get(4..8)?.try_into().ok()?),
+                    ))
+                },
+            )
+            .register::<Name>(
+                "name",
+                |n| n.0.as_bytes().to_vec(),
+                |b| String::from_utf8(b.to_vec()).ok().map(Name),
+            )
+            .register_mapped::<Parent>(
+                "parent",
+                |p| p.0.to_bits().to_le_bytes().to_vec(),
+                |b| {
+                    Some(Parent(Entity::from_bits(u64::from_le_bytes(
+                        b.try_into().ok()?,
+                    ))))
+                },
+                |p, map| p.0 = map.get(p.0).unwrap_or_default(),
+            );
+        registry
+    }
+
+    /// Check that the replica holds the same entities with the same components, with parents
+    /// pointing to the mapped entities.
+    fn assert_converged(
+        source: &World,
+        replica: &World,
+        registry: &ComponentRegistry,
+        map: &EntityMap,
+    ) {
+        let entities = source.query::<Entity>().collect::<Vec<_>>();
+        assert_eq!(entities.len(), replica.stats().entities);
+        assert_eq!(entities.len(), map.len());
+        for entity in entities {
+            let local = map.get(entity).unwrap();
+            let mut expected = registry.serialize(source, entity);
+            for (name, bytes) in &mut expected {
+                if name == "parent" {
+                    let parent =
+                        Entity::from_bits(u64::from_le_bytes(bytes[..].try_into().unwrap()));
+                    *bytes = map.get(parent).unwrap().to_bits().to_le_bytes().to_vec();
+                }
+            }
+            assert_eq!(expected, registry.serialize(replica, local));
+        }
+    }
+
+    /// Diff, encode, decode and apply
+    fn sync(
+        source: &World,
+        replica: &mut World,
+        registry: &ComponentRegistry,
+        map: &mut EntityMap,
+    ) -> Result<(), DiffError> {
+        let baseline = map.last_applied().unwrap_or(0);
+        let diff = source.diff_since(baseline, registry);
+        let decoded = WorldDiff::from_bytes(&diff.to_bytes())?;
+        assert_eq!(diff, decoded);
+        replica.apply_diff(&decoded, registry, map)
+    }
+
+    #[test]
+    fn convergence() {
+        let registry = registry();
+        let mut source = World::new();
+        let mut replica = World::new();
+        let mut map = EntityMap::new();
+
+        let root = source.spawn((Position(0.0, 0.0), Name("root".to_owned())));
+        let children = source.spawn_many((0..4).map(|i| (Position(i as f32, 1.0), Parent(root))));
+        source.spawn((42u32,));
+        sync(&source, &mut replica, &registry, &mut map).unwrap();
+        assert_converged(&source, &replica, &registry, &map);
+
+        source.advance_tick();
+        for position in source.query::<&mut Position>() {
+            position.1 += 1.0;
+        }
+        source.remove(children[1]);
+        source.add_component(children[2], (Name("named".to_owned()),));
+        source.take_component::<(Name,)>(root);
+        let late = source.spawn((Position(9.0, 9.0), Parent(children[3])));
+        let diff = source.diff_since(map.last_applied().unwrap(), &registry);
+        assert_eq!(vec![children[1].to_bits()], diff.despawned);
+        assert_eq!(
+            vec![late.to_bits()],
+            diff.spawned.iter().map(|e| e.entity).collect::<Vec<_>>()
+        );
+        sync(&source, &mut replica, &registry, &mut map).unwrap();
+        assert_converged(&source, &replica, &registry, &map);
+
+        // Only entities touched by a mutable query are sent
+        source.advance_tick();
+        for _ in source.query::<&Position>() {}
+        for name in source.query::<&mut Name>() {
+            name.0.push('!');
+        }
+        let diff = source.diff_since(map.last_applied().unwrap(), &registry);
+        assert!(diff.spawned.is_empty());
+        assert_eq!(
+            vec![children[2].to_bits()],
+            diff.changed.iter().map(|e| e.entity).collect::<Vec<_>>()
+        );
+        sync(&source, &mut replica, &registry, &mut map).unwrap();
+        assert_converged(&source, &replica, &registry, &map);
+
+        // Deterministic
+        let a = source.diff_since(0, &registry).to_bytes();
+        let b = source.diff_since(0, &registry).to_bytes();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn idempotency() {
+        let registry = registry();
+        let mut source = World::new();
+        let mut replica = World::new();
+        let mut map = EntityMap::new();
+        source.spawn((Position(1.0, 2.0),));
+
+        let snapshot = source.diff_since(0, &registry);
+        replica.apply_diff(&snapshot, &registry, &mut map).unwrap();
+        assert_eq!(
+            Err(DiffError::AlreadyApplied {
+                tick: 1,
+                applied: 1
+            }),
+            replica.apply_diff(&snapshot, &registry, &mut map)
+        );
+        assert_eq!(1, replica.stats().entities);
+
+        source.advance_tick();
+        source.spawn((Position(3.0, 4.0),));
+        let first = source.diff_since(1, &registry);
+        source.advance_tick();
+        source.spawn((Position(5.0, 6.0),));
+        let second = source.diff_since(2, &registry);
+        // Skipping a diff is detected
+        assert_eq!(
+            Err(DiffError::MissingBaseline {
+                baseline: 2,
+                applied: Some(1)
+            }),
+            replica.apply_diff(&second, &registry, &mut map)
+        );
+        replica.apply_diff(&first, &registry, &mut map).unwrap();
+        replica.apply_diff(&second, &registry, &mut map).unwrap();
+        assert_converged(&source, &replica, &registry, &map);
+
+        let mut bytes = second.to_bytes();
+        bytes[0] = 3;
+        assert_eq!(Err(DiffError::Version(3)), WorldDiff::from_bytes(&bytes));
+        assert_eq!(
+            Err(DiffError::Malformed),
+            WorldDiff::from_bytes(&second.to_bytes()[..20])
+        );
+    }
+
+    #[test]
+    fn eviction() {
+        let registry = registry();
+        let mut source = World::new();
+        let mut replica = World::new();
+        let mut map = EntityMap::new();
+        source.set_despawn_history(2);
+        let entities = source.spawn_many((0..4).map(|i| (Position(i as f32, 0.0),)));
+        sync(&source, &mut replica, &registry, &mut map).unwrap();
+
+        for entity in &entities[..3] {
+            source.advance_tick();
+            source.remove(*entity);
+        }
+        // The first despawn was evicted
+        let diff = source.diff_since(1, &registry);
+        assert!(diff.truncated);
+        assert_eq!(
+            Err(DiffError::Truncated),
+            replica.apply_diff(&diff, &registry, &mut map)
+        );
+        // Still fine for a receiver that saw the evicted despawn
+        let diff = source.diff_since(2, &registry);
+        assert!(!diff.truncated);
+        assert_eq!(2, diff.despawned.len());
+        // And for a new receiver
+        let mut fresh = World::new();
+        let mut fresh_map = EntityMap::new();
+        sync(&source, &mut fresh, &registry, &mut fresh_map).unwrap();
+        assert_converged(&source, &fresh, &registry, &fresh_map);
+    }
+
+    #[test]
+    fn seeded_map() {
+        let registry = registry();
+        let mut source = World::new();
+        let mut replica = World::new();
+        let mut map = EntityMap::new();
+        // Both worlds start from the same "scene"
+        let scene = source.spawn((Position(0.0, 0.0), 7u8));
+        let local = replica.spawn((Position(0.0, 0.0), 7u8));
+        map.insert(scene, local);
+
+        source.query::<&mut Position>().for_each(|p| p.0 = 5.0);
+        let child = source.spawn((Parent(scene),));
+        sync(&source, &mut replica, &registry, &mut map).unwrap();
+        assert_converged(&source, &replica, &registry, &map);
+        // Updated in place, unregistered components kept
+        assert_eq!(2, replica.stats().entities);
+        assert_eq!(
+            Some(Position(5.0, 0.0)),
+            replica.component_mut::<Position>(local).copied()
+        );
+        assert_eq!(Some(7), replica.component_mut::<u8>(local).copied());
+        let mapped = map.get(child).unwrap();
+        assert_eq!(
+            Some(Parent(local)),
+            replica.component_mut::<Parent>(mapped).copied()
+        );
+    }
+
+    #[test]
+    fn changed_components_only() {
+        let registry = registry();
+        let mut source = World::new();
+        let mut replica = World::new();
+        let mut map = EntityMap::new();
+        let entity = source.spawn((Position(0.0, 0.0), Name("a".to_owned())));
+        sync(&source, &mut replica, &registry, &mut map).unwrap();
+
+        source.advance_tick();
+        for position in source.query::<&mut Position>() {
+            position.0 = 1.0;
+        }
+        let diff = source.diff_since(map.last_applied().unwrap(), &registry);
+        assert_eq!(
+            vec![EntityDiff {
+                entity: entity.to_bits(),
+                components: vec![
+                    ("name".to_owned(), None),
+                    (
+                        "position".to_owned(),
+                        Some(registry.serialize(&source, entity)[1].1.clone())
+                    ),
+                ],
+            }],
+            diff.changed
+        );
+        sync(&source, &mut replica, &registry, &mut map).unwrap();
+        assert_converged(&source, &replica, &registry, &map);
+
+        // A removed component is left out, and removed on the other end
+        source.advance_tick();
+        source.take_component::<(Position,)>(entity);
+        let diff = source.diff_since(map.last_applied().unwrap(), &registry);
+        assert_eq!(vec![("name".to_owned(), None)], diff.changed[0].components);
+        sync(&source, &mut replica, &registry, &mut map).unwrap();
+        assert_converged(&source, &replica, &registry, &map);
+        assert_eq!(
+            None,
+            replica.component_mut::<Position>(map.get(entity).unwrap())
+        );
+    }
+}
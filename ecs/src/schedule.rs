@@ -0,0 +1,1060 @@
+//! Building schedules: finding the dependencies between systems and spreading them over threads
+//! with synchronization steps where a system depends on one from another thread.
+
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+use slotmap::SecondaryMap;
+
+use crate::{
+    error::{EcsError, ScheduleCycleError},
+    executor::{Executor, ExecutorId, SystemId},
+    system::{IntoCondition, IntoSystem},
+    thread_pool::Wait,
+    trace::SkipReason,
+    world::World,
+};
+
+/// Dependencies of each system, on systems that come before it
+type Dependencies = SecondaryMap<SystemId, Vec<SystemId>>;
+
+/// An ordering constraint of a system on the systems with a label
+enum Constraint {
+    After(&'static str),
+    Before(&'static str),
+}
+
+pub struct Scheduler<'a> {
+    executor: &'a mut Executor,
+    systems: Vec<SystemId>,
+    /// Labels given with `then_labeled`
+    labels: Vec<(&'static str, SystemId)>,
+    constraints: Vec<(SystemId, Constraint)>,
+    /// Given with `throttle`
+    throttles: Vec<(SystemId, u32)>,
+}
+
+impl<'a> Scheduler<'a> {
+    pub(crate) fn new(executor: &'a mut Executor) -> Self {
+        Self {
+            executor,
+            systems: Vec::new(),
+            labels: Vec::new(),
+            constraints: Vec::new(),
+            throttles: Vec::new(),
+        }
+    }
+    /// Add a system to the building schedule
+    pub fn then<A>(mut self, sys: impl IntoSystem<A>) -> Self {
+        self.systems.push(self.executor.add_system(sys));
+        self
+    }
+    /// Add a system with a label, for `after` and `before`. Several systems can share a label.
+    pub fn then_labeled<A>(mut self, sys: impl IntoSystem<A>, label: &'static str) -> Self {
+        self = self.then(sys);
+        self.labels.push((label, *self.systems.last().unwrap()));
+        self
+    }
+    /// Make the last system added run after the systems with the label, even if they don't
+    /// depend on each other. Labels of no system are ignored.
+    ///
+    /// # Panics
+    ///
+    /// This panics if no system was added yet
+    pub fn after(mut self, label: &'static str) -> Self {
+        let sys = *self.systems.last().expect("No system to constrain");
+        self.constraints.push((sys, Constraint::After(label)));
+        self
+    }
+    /// Make the last system added run before the systems with the label, see `after`
+    ///
+    /// # Panics
+    ///
+    /// This panics if no system was added yet
+    pub fn before(mut self, label: &'static str) -> Self {
+        let sys = *self.systems.last().expect("No system to constrain");
+        self.constraints.push((sys, Constraint::Before(label)));
+        self
+    }
+    /// Only run the last system added once every `every` executes of the schedule, starting with
+    /// the first one. It is skipped (`SkipReason::Throttled`) the others.
+    ///
+    /// # Panics
+    ///
+    /// This panics if no system was added yet
+    pub fn throttle(mut self, every: u32) -> Self {
+        let sys = *self.systems.last().expect("No system to throttle");
+        self.throttles.push((sys, every.max(1)));
+        self
+    }
+    /// Add a system that only runs when condition returns true. The condition takes system
+    /// arguments and is called right before the system would run, the schedule orders the system
+    /// as if it borrowed what the condition does too.
+    pub fn then_if<A, C>(
+        mut self,
+        sys: impl IntoSystem<A>,
+        condition: impl IntoCondition<C>,
+    ) -> Self {
+        self.systems
+            .push(self.executor.add_system_if(sys, condition));
+        self
+    }
+    /// Add a registred system to the building schedule. This sould be avoided in favor of
+    /// Scheduler::then.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the system is already in the schedule, or if the system isn't registered in
+    /// the executor.
+    pub fn then_by_id(self, sys: SystemId) -> Self {
+        self.try_then_by_id(sys)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+    /// Add a registred system to the building schedule, fails if the system is already in the
+    /// schedule or isn't registered in the executor.
+    pub fn try_then_by_id(mut self, sys: SystemId) -> Result<Self, EcsError> {
+        if self.executor.get_system(sys).is_none() {
+            return Err(EcsError::UnknownSystem);
+        }
+        if self.systems.contains(&sys) {
+            return Err(EcsError::DuplicateSystem);
+        }
+        self.systems.push(sys);
+        Ok(self)
+    }
+    /// Run the closure F with the scheduler
+    #[inline(always)]
+    pub fn with<F: FnOnce(Self) -> Self>(self, f: F) -> Self {
+        f(self)
+    }
+    /// The labels and throttles of the systems
+    fn gates(&self) -> SecondaryMap<SystemId, Gate> {
+        let mut gates = SecondaryMap::<SystemId, Gate>::new();
+        for &(label, sys) in &self.labels {
+            let gate = gates.entry(sys).unwrap().or_default();
+            gate.label.get_or_insert(label);
+        }
+        for &(sys, every) in &self.throttles {
+            let gate = gates.entry(sys).unwrap().or_default();
+            gate.throttle = Some((every, AtomicU32::new(0)));
+        }
+        gates
+    }
+    /// Pairs of a system and a system it must run after, from the constraints
+    fn constraint_pairs(&self) -> Vec<(SystemId, SystemId)> {
+        let labeled = |label: &'static str| {
+            let systems = self
+                .labels
+                .iter()
+                .filter(|(l, _)| *l == label)
+                .map(|&(_, sys)| sys)
+                .collect::<Vec<_>>();
+            if systems.is_empty() {
+                log::warn!("Schedule: no system has the label {label}");
+            }
+            systems
+        };
+        let mut pairs = Vec::new();
+        for &(sys, ref constraint) in &self.constraints {
+            match *constraint {
+                Constraint::After(label) => {
+                    pairs.extend(labeled(label).into_iter().map(|dep| (sys, dep)))
+                }
+                Constraint::Before(label) => {
+                    pairs.extend(labeled(label).into_iter().map(|other| (other, sys)))
+                }
+            }
+        }
+        // A system labeled with what it is constrained by
+        pairs.retain(|(sys, dep)| sys != dep);
+        pairs
+    }
+    /// The systems in the order they were added, moved after the systems they are constrained
+    /// to run after. Fails with the systems of a cycle if the constraints can't be satisfied.
+    fn constrained_order(&self) -> Result<(Vec<SystemId>, Dependencies), ScheduleCycleError> {
+        let mut constraints: Dependencies = SecondaryMap::new();
+        for &sys in &self.systems {
+            constraints.insert(sys, Vec::new());
+        }
+        for (sys, dep) in self.constraint_pairs() {
+            constraints[sys].push(dep);
+        }
+        match topological_order(&self.systems, &constraints) {
+            Ok(order) => Ok((order, constraints)),
+            Err(cycle) => Err(ScheduleCycleError {
+                systems: cycle
+                    .into_iter()
+                    .map(|id| self.executor.get_system(id).unwrap().name())
+                    .collect(),
+            }),
+        }
+    }
+    /// Create a schedule from the added systems, the schedule is parallelized as much as possible
+    /// while keeping the same behaviour as if the systems were run sequentially, in the order they
+    /// were added (with the systems constrained by `after` and `before` moved after what they
+    /// must run after).
+    ///
+    /// # Panics
+    ///
+    /// This panics if the constraints form a cycle, see `try_build`
+    ///
+    /// # Note
+    ///
+    /// Fairely expensive, and unoptimized, should only be called a few times
+    pub fn build(self) -> Schedule {
+        self.try_build().unwrap_or_else(|err| panic!("{err}"))
+    }
+    /// Like `build`, fails if the constraints form a cycle
+    pub fn try_build(self) -> Result<Schedule, ScheduleCycleError> {
+        let (order, constraints) = self.constrained_order()?;
+        let executor = &*self.executor;
+        let mut deps = dependencies(&order, |sys, other| {
+            let (sys, other) = (executor.get_system(sys), executor.get_system(other));
+            sys.unwrap().depends_on(other.unwrap())
+        });
+        merge_dependencies(&mut deps, &constraints);
+        let (threads, waits, dependencies) = placement(&order, deps);
+        Ok(Schedule {
+            executor_id: executor.id(),
+            threads: Arc::new(threads),
+            waits: Arc::new(waits),
+            dependencies,
+            order,
+            gates: self.gates(),
+            refinement: None,
+        })
+    }
+    /// Like `build`, but only orders systems whose queries touch the same data in an archetype of
+    /// the world: two systems borrowing a component (one of them mutably) can run in parallel if
+    /// no archetype matches the queries of both.
+    ///
+    /// The analysis only holds for the archetypes the world has now. When the schedule is executed
+    /// with new archetypes (or another world), they are checked first, and if one of them makes
+    /// two parallel systems conflict, the schedule falls back to the placement `build` would have
+    /// made, for good.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the constraints form a cycle, see `try_build_for`
+    pub fn build_for(self, world: &World) -> Schedule {
+        self.try_build_for(world)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+    /// Like `build_for`, fails if the constraints form a cycle
+    pub fn try_build_for(self, world: &World) -> Result<Schedule, ScheduleCycleError> {
+        let (order, constraints) = self.constrained_order()?;
+        let executor = &*self.executor;
+        let system = |id| executor.get_system(id).unwrap();
+        let mut relaxed = Vec::new();
+        let mut refined = dependencies(&order, |sys, other| {
+            if !system(sys).depends_on(system(other)) {
+                return false;
+            }
+            let depends = system(sys).depends_on_in(system(other), world.archetypes());
+            if !depends {
+                relaxed.push((sys, other));
+            }
+            depends
+        });
+        let mut conservative =
+            dependencies(&order, |sys, other| system(sys).depends_on(system(other)));
+        merge_dependencies(&mut refined, &constraints);
+        merge_dependencies(&mut conservative, &constraints);
+
+        let (threads, waits, dependencies) = placement(&order, refined);
+        let conservative = placement(&order, conservative);
+        Ok(Schedule {
+            executor_id: executor.id(),
+            threads: Arc::new(threads),
+            waits: Arc::new(waits),
+            dependencies,
+            order,
+            gates: self.gates(),
+            refinement: Some(Refinement {
+                validated: Mutex::new((world.id(), world.archetypes().count())),
+                relaxed,
+                invalidated: AtomicBool::new(false),
+                threads: Arc::new(conservative.0),
+                waits: Arc::new(conservative.1),
+                dependencies: conservative.2,
+            }),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Step {
+    /// Run a system
+    Run(SystemId),
+    /// Notify another thread (for sync)
+    Notify(usize),
+    /// Wait for notifications
+    Wait(usize),
+}
+
+pub struct Schedule {
+    pub(crate) executor_id: ExecutorId,
+    pub(crate) threads: Arc<Vec<Vec<Step>>>,
+    pub(crate) waits: Arc<Vec<Wait>>,
+    /// Pairs of a system and a system it depends on, see `Schedule::describe`
+    dependencies: Vec<(SystemId, SystemId)>,
+    /// The systems in the order they were added, see `Executor::execute_sequential`
+    pub(crate) order: Vec<SystemId>,
+    /// Systems with a label or a throttle
+    gates: SecondaryMap<SystemId, Gate>,
+    /// Set by `Scheduler::build_for`
+    refinement: Option<Refinement>,
+}
+
+/// What decides whether a system of a schedule runs, before its arguments and its condition
+#[derive(Default)]
+struct Gate {
+    /// The first label of the system: its phase in traces, and what
+    /// `Executor::set_label_enabled` disables
+    label: Option<&'static str>,
+    /// Runs once every that many executes, and the executes so far
+    throttle: Option<(u32, AtomicU32)>,
+}
+
+/// What the archetype aware analysis relaxed, to check it still holds
+struct Refinement {
+    /// Id of the world and number of its archetypes that were checked
+    validated: Mutex<(u64, usize)>,
+    /// Pairs of systems the type level analysis orders but that run in parallel
+    relaxed: Vec<(SystemId, SystemId)>,
+    /// A new archetype made a relaxed pair conflict, the conservative placement is used
+    invalidated: AtomicBool,
+    /// The conservative placement, the one of `Scheduler::build`
+    threads: Arc<Vec<Vec<Step>>>,
+    waits: Arc<Vec<Wait>>,
+    dependencies: Vec<(SystemId, SystemId)>,
+}
+
+/// The steps of the threads of a schedule, see `Schedule::describe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleDescription {
+    /// The steps of each thread, in order
+    pub threads: Vec<Vec<StepDescription>>,
+    /// Every Notify step and the Wait it is for
+    pub syncs: Vec<SyncEdge>,
+    /// Pairs of a system and a system it depends on, the dependencies implied by others left out
+    pub dependencies: Vec<(&'static str, &'static str)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepDescription {
+    /// Run the system with this name
+    Run(&'static str),
+    /// Notify the wait with this index
+    Notify(usize),
+    /// Wait for the notifications of the wait with this index
+    Wait(usize),
+}
+
+/// The thread `from` notifies the wait `wait`, that the thread `to` waits on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncEdge {
+    pub wait: usize,
+    pub from: usize,
+    pub to: usize,
+}
+
+/// Shape of a schedule, see `Schedule::report`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleReport {
+    /// Threads the systems are spread over
+    pub threads: usize,
+    /// Synchronization points between the threads
+    pub waits: usize,
+    /// Pairs of systems only the archetype aware analysis lets run in parallel
+    pub relaxed: usize,
+    /// The archetype aware placement was dropped for the conservative one
+    pub invalidated: bool,
+}
+
+impl Schedule {
+    /// The label of a system in this schedule (the first one given), its phase in traces
+    pub(crate) fn label(&self, sys: SystemId) -> Option<&'static str> {
+        self.gates.get(sys).and_then(|gate| gate.label)
+    }
+    /// Whether a system can run this execute: its label isn't disabled and it isn't throttled.
+    /// Called once per execute for every system of the schedule, the throttle counts the calls.
+    pub(crate) fn gate(&self, executor: &Executor, sys: SystemId) -> Result<(), SkipReason> {
+        let Some(gate) = self.gates.get(sys) else {
+            return Ok(());
+        };
+        if let Some(label) = gate.label.filter(|&label| executor.label_disabled(label)) {
+            return Err(SkipReason::Disabled(label));
+        }
+        match &gate.throttle {
+            Some((every, count)) if count.fetch_add(1, Ordering::Relaxed) % every != 0 => {
+                Err(SkipReason::Throttled)
+            }
+            _ => Ok(()),
+        }
+    }
+    pub fn report(&self) -> ScheduleReport {
+        let (threads, waits) = self.current();
+        ScheduleReport {
+            threads: threads.len(),
+            waits: waits.len(),
+            relaxed: self.refinement.as_ref().map_or(0, |r| r.relaxed.len()),
+            invalidated: self
+                .refinement
+                .as_ref()
+                .is_some_and(|r| r.invalidated.load(Ordering::Acquire)),
+        }
+    }
+    /// The steps of each thread of the schedule with the names of the systems, the syncs between
+    /// threads, and the dependencies they come from
+    pub fn describe(&self, executor: &Executor) -> ScheduleDescription {
+        let name = |id| {
+            executor
+                .get_system(id)
+                .map_or("<unknown>", |sys| sys.name())
+        };
+        let (threads, _) = self.current();
+        let mut syncs = Vec::new();
+        for (from, steps) in threads.iter().enumerate() {
+            for step in steps {
+                if let Step::Notify(wait) = *step {
+                    let to = threads
+                        .iter()
+                        .position(|steps| steps.contains(&Step::Wait(wait)))
+                        .expect("Notify without a Wait");
+                    syncs.push(SyncEdge { wait, from, to });
+                }
+            }
+        }
+        ScheduleDescription {
+            threads: threads
+                .iter()
+                .map(|steps| {
+                    steps
+                        .iter()
+                        .map(|step| match *step {
+                            Step::Run(id) => StepDescription::Run(name(id)),
+                            Step::Notify(wait) => StepDescription::Notify(wait),
+                            Step::Wait(wait) => StepDescription::Wait(wait),
+                        })
+                        .collect()
+                })
+                .collect(),
+            syncs,
+            dependencies: self
+                .current_dependencies()
+                .iter()
+                .map(|&(sys, dep)| (name(sys), name(dep)))
+                .collect(),
+        }
+    }
+    /// The dependencies between the systems as a Graphviz digraph, with an edge from each system
+    /// to the ones that depend on it
+    pub fn to_dot(&self, executor: &Executor) -> String {
+        let name = |id| {
+            executor
+                .get_system(id)
+                .map_or("<unknown>", |sys| sys.name())
+        };
+        let node = |id| self.order.iter().position(|&sys| sys == id).unwrap();
+        let mut dot = String::from("digraph schedule {\n");
+        for (i, &id) in self.order.iter().enumerate() {
+            let label = name(id).replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(dot, "    s{i} [label=\"{label}\"];");
+        }
+        for &(sys, dep) in self.current_dependencies() {
+            let _ = writeln!(dot, "    s{} -> s{};", node(dep), node(sys));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+    /// The placement in use
+    fn current(&self) -> (&Arc<Vec<Vec<Step>>>, &Arc<Vec<Wait>>) {
+        match &self.refinement {
+            Some(refinement) if refinement.invalidated.load(Ordering::Acquire) => {
+                (&refinement.threads, &refinement.waits)
+            }
+            _ => (&self.threads, &self.waits),
+        }
+    }
+    /// The dependencies of the placement in use
+    fn current_dependencies(&self) -> &[(SystemId, SystemId)] {
+        match &self.refinement {
+            Some(refinement) if refinement.invalidated.load(Ordering::Acquire) => {
+                &refinement.dependencies
+            }
+            _ => &self.dependencies,
+        }
+    }
+    /// The placement to execute the schedule with on a world, checks the archetypes the
+    /// refinement hasn't seen yet
+    pub(crate) fn placement(
+        &self,
+        executor: &Executor,
+        world: &World,
+    ) -> (&Arc<Vec<Vec<Step>>>, &Arc<Vec<Wait>>) {
+        if let Some(refinement) = &self.refinement {
+            let mut validated = refinement.validated.lock();
+            let count = world.archetypes().count();
+            if !refinement.invalidated.load(Ordering::Acquire) && *validated != (world.id(), count)
+            {
+                // Archetypes are only ever added, in another world they all need checking
+                let seen = if validated.0 == world.id() {
+                    validated.1
+                } else {
+                    0
+                };
+                let system = |id| executor.get_system(id).unwrap();
+                let conflict = world.archetypes().skip(seen).any(|archetype| {
+                    refinement
+                        .relaxed
+                        .iter()
+                        .any(|&(a, b)| system(a).conflicts_in(system(b), archetype))
+                });
+                if conflict {
+                    log::debug!("Schedule: a new archetype invalidates the refinement");
+                    refinement.invalidated.store(true, Ordering::Release);
+                }
+                *validated = (world.id(), count);
+            }
+        }
+        self.current()
+    }
+}
+
+/// Find the systems each system depends on, among the ones before it
+fn dependencies(
+    systems: &[SystemId],
+    mut depends_on: impl FnMut(SystemId, SystemId) -> bool,
+) -> Dependencies {
+    let mut deps = SecondaryMap::new();
+    for (i, &sys) in systems.iter().enumerate() {
+        let sys_deps = systems[0..i]
+            .iter()
+            .copied()
+            .filter(|&other| depends_on(sys, other))
+            .collect();
+        deps.insert(sys, sys_deps);
+    }
+    deps
+}
+
+/// Add the dependencies of other to deps
+fn merge_dependencies(deps: &mut Dependencies, other: &Dependencies) {
+    for (sys, other) in other {
+        let deps = &mut deps[sys];
+        for &dep in other {
+            if !deps.contains(&dep) {
+                deps.push(dep);
+            }
+        }
+    }
+}
+
+/// Order systems so that each comes after its dependencies, otherwise keeping their order. Fails
+/// with the systems of a cycle, each depending on the next and the last on the first.
+fn topological_order(
+    systems: &[SystemId],
+    deps: &Dependencies,
+) -> Result<Vec<SystemId>, Vec<SystemId>> {
+    let mut order = Vec::with_capacity(systems.len());
+    let mut placed = HashSet::new();
+    let mut remaining = systems.to_vec();
+    while !remaining.is_empty() {
+        let ready = remaining
+            .iter()
+            .position(|&sys| deps[sys].iter().all(|dep| placed.contains(dep)));
+        match ready {
+            Some(i) => {
+                let sys = remaining.remove(i);
+                placed.insert(sys);
+                order.push(sys);
+            }
+            None => {
+                // Every remaining system has a remaining dependency, following them loops
+                let mut path = vec![remaining[0]];
+                loop {
+                    let last = *path.last().unwrap();
+                    let next = *deps[last]
+                        .iter()
+                        .find(|dep| !placed.contains(*dep))
+                        .unwrap();
+                    if let Some(start) = path.iter().position(|&sys| sys == next) {
+                        return Err(path.split_off(start));
+                    }
+                    path.push(next);
+                }
+            }
+        }
+    }
+    Ok(order)
+}
+
+/// Threads and waits of a placement, with the pairs of a system and a dependency placed
+type Placement = (Vec<Vec<Step>>, Vec<Wait>, Vec<(SystemId, SystemId)>);
+
+/// Place systems from their dependencies, also returns the pairs of a system and a dependency
+/// placed (without the implied ones)
+fn placement(systems: &[SystemId], mut deps: Dependencies) -> Placement {
+    remove_implied(systems, &mut deps);
+    let sorted = sort_by_depth(systems, &deps);
+    let (threads, waits) = place(&sorted, &deps);
+    let pairs = systems
+        .iter()
+        .flat_map(|&sys| deps[sys].iter().map(move |&dep| (sys, dep)))
+        .collect();
+    (threads, waits, pairs)
+}
+
+/// Remove the dependencies already implied by another dependency (if c depends on b and a, and b
+/// depends on a, c only needs to depend on b).
+fn remove_implied(systems: &[SystemId], deps: &mut Dependencies) {
+    // Add every dependency of a system (and their dependencies) to a set
+    fn recurse_dependencies(id: SystemId, set: &mut HashSet<SystemId>, deps: &Dependencies) {
+        for &dep in &deps[id] {
+            if set.insert(dep) {
+                recurse_dependencies(dep, set, deps);
+            }
+        }
+    }
+    for &sys in systems {
+        let mut implied = HashSet::new();
+        for &dep in &deps[sys] {
+            recurse_dependencies(dep, &mut implied, deps);
+        }
+        deps[sys].retain(|dep| !implied.contains(dep));
+    }
+}
+
+/// Sort systems by depth (the length of the longest chain of dependencies leading to them),
+/// systems of the same depth stay in the same order.
+fn sort_by_depth(systems: &[SystemId], deps: &Dependencies) -> Vec<SystemId> {
+    let mut depths: SecondaryMap<SystemId, u32> = SecondaryMap::new();
+    // Dependencies always come before, so their depth is known
+    for &sys in systems {
+        let depth = deps[sys].iter().map(|&dep| depths[dep] + 1).max();
+        depths.insert(sys, depth.unwrap_or(0));
+    }
+    let mut sorted = systems.to_vec();
+    sorted.sort_by_key(|&sys| depths[sys]);
+    sorted
+}
+
+/// Spread systems (sorted by depth) over threads. A system goes after one of its dependencies if
+/// it was the last system of a thread, on a new thread otherwise, and waits for the dependencies
+/// on other threads to notify it.
+fn place(systems: &[SystemId], deps: &Dependencies) -> (Vec<Vec<Step>>, Vec<Wait>) {
+    let mut threads: Vec<Vec<Step>> = Vec::new();
+    let mut waits: Vec<Wait> = Vec::new();
+
+    for &sys in systems {
+        let last_run = |steps: &Vec<Step>| {
+            steps.iter().rev().find_map(|step| match step {
+                Step::Run(sys) => Some(*sys),
+                _ => None,
+            })
+        };
+        // The index of the thread the Run is put in, and of the step in that thread
+        let found = deps[sys].iter().find_map(|&dep| {
+            threads
+                .iter()
+                .position(|steps| last_run(steps) == Some(dep))
+        });
+        let (step_thread, mut step_index) = match found {
+            Some(thread) => {
+                threads[thread].push(Step::Run(sys));
+                (thread, threads[thread].len() - 1)
+            }
+            None => {
+                threads.push(vec![Step::Run(sys)]);
+                (threads.len() - 1, 0)
+            }
+        };
+
+        // Here we have placed the Run at index <index> of thread <thread>, we now need to
+        // ensure that all dependencies are satisfied through syncronizations steps.
+        for &dep in &deps[sys] {
+            if threads[step_thread].contains(&Step::Run(dep)) {
+                continue;
+            }
+            // Look for the thread that contains the dependency
+            let (dep_thread, index) = threads
+                .iter()
+                .enumerate()
+                .find_map(|(i, steps)| {
+                    let index = steps.iter().position(|step| *step == Step::Run(dep))?;
+                    Some((i, index))
+                })
+                .expect("Dependency wasn't placed before the system");
+            // If there is already a wait before the run, wait for one more notification
+            let wait = match step_index.checked_sub(1).map(|i| threads[step_thread][i]) {
+                Some(Step::Wait(w)) => {
+                    waits[w].set_limit(waits[w].limit() + 1);
+                    w
+                }
+                _ => {
+                    let w = waits.len();
+                    waits.push(Wait::new(1));
+                    threads[step_thread].insert(step_index, Step::Wait(w));
+                    step_index += 1;
+                    w
+                }
+            };
+            threads[dep_thread].insert(index + 1, Step::Notify(wait));
+        }
+    }
+    (threads, waits)
+}
+
+#[cfg(test)]
+mod tests {
+    use slotmap::SlotMap;
+
+    use super::*;
+    use crate::{Res, ResMut};
+
+    fn ids(count: usize) -> Vec<SystemId> {
+        let mut map: SlotMap<SystemId, ()> = SlotMap::with_key();
+        (0..count).map(|_| map.insert(())).collect()
+    }
+
+    /// Dependencies from (system, dependency) index pairs
+    fn deps(systems: &[SystemId], pairs: &[(usize, usize)]) -> Dependencies {
+        dependencies(systems, |sys, other| {
+            let index = |id| systems.iter().position(|&s| s == id).unwrap();
+            pairs.contains(&(index(sys), index(other)))
+        })
+    }
+
+    /// Check that in a placement, every system runs once and after all its dependencies
+    fn check_placement(systems: &[SystemId], deps: &Dependencies) {
+        let (threads, waits) = place(systems, deps);
+        let find = |step: Step| {
+            threads.iter().enumerate().find_map(|(t, steps)| {
+                let i = steps.iter().position(|s| *s == step)?;
+                Some((t, i))
+            })
+        };
+        for &sys in systems {
+            let runs = threads.iter().flatten().filter(|s| **s == Step::Run(sys));
+            assert_eq!(runs.count(), 1);
+            let (thread, index) = find(Step::Run(sys)).unwrap();
+            for &dep in &deps[sys] {
+                let (dep_thread, dep_index) = find(Step::Run(dep)).unwrap();
+                if dep_thread == thread {
+                    assert!(dep_index < index);
+                    continue;
+                }
+                // The dependency notifies a wait right before the system
+                let wait = match threads[thread][index - 1] {
+                    Step::Wait(w) => w,
+                    step => panic!("Expected a wait, found {step:?}"),
+                };
+                let notify = threads[dep_thread][dep_index + 1..]
+                    .iter()
+                    .take_while(|s| !matches!(s, Step::Run(_)));
+                assert!(notify.into_iter().any(|s| *s == Step::Notify(wait)));
+            }
+        }
+        // Every wait gets as many notifications as its limit
+        for (w, wait) in waits.iter().enumerate() {
+            let notifies = threads.iter().flatten().filter(|s| **s == Step::Notify(w));
+            assert_eq!(notifies.count() as u32, wait.limit());
+        }
+    }
+
+    #[test]
+    fn direct_dependencies() {
+        let s = ids(4);
+        // Only systems before count, even if a later one would conflict
+        let deps = deps(&s, &[(1, 0), (3, 0), (3, 2), (0, 3)]);
+        assert!(deps[s[0]].is_empty());
+        assert_eq!(deps[s[1]], [s[0]]);
+        assert!(deps[s[2]].is_empty());
+        assert_eq!(deps[s[3]], [s[0], s[2]]);
+    }
+
+    #[test]
+    fn implied_dependencies() {
+        let s = ids(5);
+        // Chain: 0 <- 1 <- 2 <- 3, with 3 also depending on everything
+        let mut chain = deps(&s[..4], &[(1, 0), (2, 1), (2, 0), (3, 0), (3, 1), (3, 2)]);
+        remove_implied(&s[..4], &mut chain);
+        assert_eq!(chain[s[1]], [s[0]]);
+        assert_eq!(chain[s[2]], [s[1]]);
+        assert_eq!(chain[s[3]], [s[2]]);
+
+        // Diamond: 1 and 2 depend on 0, 3 on both, 4 on everything
+        let pairs = [
+            (1, 0),
+            (2, 0),
+            (3, 1),
+            (3, 2),
+            (3, 0),
+            (4, 0),
+            (4, 1),
+            (4, 2),
+            (4, 3),
+        ];
+        let mut diamond = deps(&s, &pairs);
+        remove_implied(&s, &mut diamond);
+        assert_eq!(diamond[s[3]], [s[1], s[2]]);
+        assert_eq!(diamond[s[4]], [s[3]]);
+    }
+
+    #[test]
+    fn depths() {
+        let s = ids(5);
+        let deps = deps(&s, &[(1, 0), (3, 1), (4, 2)]);
+        // Depths: 0, 1, 0, 2, 1
+        assert_eq!(sort_by_depth(&s, &deps), [s[0], s[2], s[1], s[4], s[3]]);
+    }
+
+    #[test]
+    fn placement() {
+        let s = ids(3);
+        // 1 and 2 both depend on 0: 1 follows 0, 2 waits for it on another thread
+        let deps = deps(&s, &[(1, 0), (2, 0)]);
+        let (threads, waits) = place(&s, &deps);
+        assert_eq!(
+            threads,
+            [
+                vec![Step::Run(s[0]), Step::Notify(0), Step::Run(s[1])],
+                vec![Step::Wait(0), Step::Run(s[2])],
+            ]
+        );
+        assert_eq!(waits.len(), 1);
+        assert_eq!(waits[0].limit(), 1);
+
+        // Independent systems each get a thread
+        let (threads, waits) = place(&s, &self::deps(&s, &[]));
+        assert_eq!(threads.len(), 3);
+        assert!(waits.is_empty());
+
+        // A system depending on three threads follows one and waits for the two others
+        let s = ids(4);
+        let deps = self::deps(&s, &[(3, 0), (3, 1), (3, 2)]);
+        let (threads, waits) = place(&s, &deps);
+        assert_eq!(
+            threads[0],
+            [Step::Run(s[0]), Step::Wait(0), Step::Run(s[3])]
+        );
+        assert_eq!(threads[1], [Step::Run(s[1]), Step::Notify(0)]);
+        assert_eq!(threads[2], [Step::Run(s[2]), Step::Notify(0)]);
+        assert_eq!(waits[0].limit(), 2);
+    }
+
+    #[test]
+    fn try_then_by_id() {
+        let mut executor = Executor::new();
+        let id = executor.add_system(|| {});
+        let scheduler = executor.schedule().try_then_by_id(id).unwrap();
+        assert_eq!(
+            Some(EcsError::DuplicateSystem),
+            scheduler.try_then_by_id(id).err()
+        );
+        let unknown = executor.schedule().try_then_by_id(SystemId::default());
+        assert_eq!(Some(EcsError::UnknownSystem), unknown.err());
+    }
+
+    #[test]
+    fn resource_dependencies() {
+        struct Score(u32);
+        struct Settings(u32);
+
+        let mut executor = Executor::new();
+        executor.add_resource(Score(0));
+        executor.add_resource(Settings(2));
+        let write = executor
+            .add_system(|settings: Res<Settings>, mut score: ResMut<Score>| score.0 += settings.0);
+        let read = executor.add_system(|score: Res<Score>| assert_eq!(2, score.0));
+        let other = executor.add_system(|settings: Res<Settings>| assert_eq!(2, settings.0));
+        let system = |id| executor.get_system(id).unwrap();
+        assert!(system(read).depends_on(system(write)));
+        assert!(!system(other).depends_on(system(write)));
+        assert!(!system(other).depends_on(system(read)));
+
+        let schedule = executor
+            .schedule()
+            .then_by_id(write)
+            .then_by_id(read)
+            .then_by_id(other)
+            .build();
+        let report = schedule.report();
+        assert_eq!((2, 0), (report.threads, report.waits));
+        executor.execute(&schedule, &mut World::new());
+        assert_eq!(2, executor.get_resource::<Score>().unwrap().0);
+    }
+
+    #[test]
+    fn constraints() {
+        let mut executor = Executor::new();
+        executor.add_resource(0u8);
+        executor.add_resource(0u16);
+        executor.add_resource(0u32);
+        let first = |a: &mut u8| *a += 1;
+        let second = |b: &mut u16| *b += 1;
+        let schedule = executor.schedule().then(first).then(second).build();
+        assert_eq!(2, schedule.report().threads);
+
+        // An after edge serializes them
+        let schedule = executor
+            .schedule()
+            .then_labeled(first, "first")
+            .then(second)
+            .after("first")
+            .build();
+        assert_eq!(1, schedule.report().threads);
+        assert_eq!(1, schedule.dependencies.len());
+
+        // A system is moved right after what it must run after, the schedule behaves as if the
+        // systems ran in that order
+        fn write_c(b: &u16, c: &mut u32) {
+            *c = *b as u32;
+        }
+        fn read_c(c: &u32) {
+            assert_eq!(0, *c);
+        }
+        fn write_b(b: &mut u16) {
+            *b += 1;
+        }
+        let schedule = executor
+            .schedule()
+            .then(write_c)
+            .after("write_b")
+            .then(read_c)
+            .then_labeled(write_b, "write_b")
+            .build();
+        assert_eq!(2, schedule.describe(&executor).dependencies.len());
+        executor.set_tracing(true);
+        executor.execute(&schedule, &mut World::new());
+        assert_eq!(1, *executor.get_resource::<u32>().unwrap());
+        let trace = executor.last_trace().unwrap();
+        trace.assert_ran_before("read_c", "write_c");
+        trace.assert_ran_before("write_b", "write_c");
+        // The sequential order is the constrained one
+        *executor.get_resource_mut::<u32>().unwrap() = 0;
+        executor.execute_sequential(&schedule, &mut World::new());
+        let trace = executor.last_trace().unwrap();
+        trace.assert_ran_before("read_c", "write_b");
+        trace.assert_ran_before("write_b", "write_c");
+    }
+
+    #[test]
+    fn constraint_cycle() {
+        let mut executor = Executor::new();
+        executor.add_resource(0u8);
+        executor.add_resource(0u16);
+        fn first(_: &u8) {}
+        fn second(_: &u16) {}
+        fn third() {}
+        let result = executor
+            .schedule()
+            .then(third)
+            .then_labeled(first, "first")
+            .after("second")
+            .then_labeled(second, "second")
+            .after("first")
+            .try_build();
+        let mut systems = result.err().unwrap().systems;
+        systems.sort();
+        assert_eq!(2, systems.len());
+        assert!(systems[0].ends_with("::first"));
+        assert!(systems[1].ends_with("::second"));
+    }
+
+    struct Input(u32);
+    struct Physics(u32);
+
+    fn input(input: &mut Input) {
+        input.0 += 1;
+    }
+    fn physics(input: &Input, physics: &mut Physics) {
+        physics.0 += input.0;
+    }
+    fn audio(_: &Input) {}
+    fn render(_: &Physics) {}
+
+    #[test]
+    fn describe() {
+        let mut executor = Executor::new();
+        let schedule = executor
+            .schedule()
+            .then(input)
+            .then(physics)
+            .then(audio)
+            .then(render)
+            .build();
+        let name = |s: &str| format!("ecs::schedule::tests::{s}");
+        let description = schedule.describe(&executor);
+        let dependencies = description
+            .dependencies
+            .iter()
+            .map(|&(sys, dep)| (sys.to_owned(), dep.to_owned()))
+            .collect::<Vec<_>>();
+        // audio and physics both only read the input, render comes after physics anyway
+        assert_eq!(
+            vec![
+                (name("physics"), name("input")),
+                (name("audio"), name("input")),
+                (name("render"), name("physics")),
+            ],
+            dependencies
+        );
+        assert_eq!(2, description.threads.len());
+        assert_eq!(
+            description.syncs.len(),
+            description
+                .threads
+                .iter()
+                .flatten()
+                .filter(|step| matches!(step, StepDescription::Notify(_)))
+                .count()
+        );
+        for sync in &description.syncs {
+            assert!(description.threads[sync.to].contains(&StepDescription::Wait(sync.wait)));
+        }
+
+        executor.add_resource(Input(0));
+        executor.add_resource(Physics(0));
+        executor.set_tracing(true);
+        executor.execute(&schedule, &mut World::new());
+        let trace = executor.last_trace().unwrap();
+        trace.assert_ran_before("input", "physics");
+        trace.assert_ran_before("input", "audio");
+        trace.assert_ran_before("physics", "render");
+
+        let dot = schedule.to_dot(&executor);
+        assert!(dot.starts_with("digraph schedule {\n"));
+        assert!(dot.contains(&format!("    s1 [label=\"{}\"];\n", name("physics"))));
+        assert!(dot.contains("    s0 -> s1;\n    s0 -> s2;\n    s1 -> s3;\n"));
+    }
+
+    #[test]
+    fn placement_sync() {
+        let s = ids(8);
+        let pairs = [
+            (2, 0),
+            (2, 1),
+            (3, 0),
+            (4, 2),
+            (4, 3),
+            (5, 1),
+            (6, 4),
+            (6, 5),
+            (7, 0),
+            (7, 6),
+        ];
+        let mut deps = deps(&s, &pairs);
+        remove_implied(&s, &mut deps);
+        let systems = sort_by_depth(&s, &deps);
+        check_placement(&systems, &deps);
+    }
+}
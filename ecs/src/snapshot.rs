@@ -0,0 +1,290 @@
+//! Saving and loading worlds.
+//!
+//! Components are opted in with `World::register_serde`, under a name that must stay the same
+//! between runs. They go in the world's `ComponentRegistry`, the same one diffs are made with, so
+//! a component is registered once for both. `World::snapshot` encodes every registered component
+//! of every entity with bincode, and `World::restore` spawns the entities again in a new world.
+//! Layouts can change between runs, so nothing is copied as raw memory.
+
+use std::{collections::BTreeSet, fmt::Display};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    archetype::{Component, ComponentKey},
+    entity::{Entity, Location},
+    replication::{ComponentRegistry, EntityMap},
+    world::World,
+};
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    bincode::serialize(value).expect("Couldn't encode component")
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Option<T> {
+    bincode::deserialize(bytes).ok()
+}
+
+/// An entity of a snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntitySnapshot {
+    /// The entity in the world the snapshot was taken from (`Entity::to_bits`)
+    pub entity: u64,
+    /// The registered components of the entity, sorted by name
+    pub components: Vec<(String, Vec<u8>)>,
+}
+
+/// The registered components of every entity of a world, see `World::snapshot`. Serializable
+/// itself, to be written to a save file.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct WorldSnapshot {
+    /// Sorted by entity
+    pub entities: Vec<EntitySnapshot>,
+    /// Names of the component types found in the world but not registered, left out of the
+    /// snapshot
+    pub skipped: BTreeSet<String>,
+}
+
+impl WorldSnapshot {
+    /// Encode the snapshot with bincode
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Couldn't encode snapshot")
+    }
+    /// Decode a snapshot encoded with to_bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        bincode::deserialize(bytes).map_err(|_| SnapshotError::Malformed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The bytes don't hold a valid snapshot
+    Malformed,
+    /// A component isn't registered under this name, or its bytes couldn't be decoded
+    Component(String),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed snapshot"),
+            Self::Component(name) => write!(f, "can't restore component {name}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl World {
+    /// Include a component in snapshots and diffs (see `components`) under a name, which must be
+    /// the same in the world the snapshot is restored from
+    pub fn register_serde<T: Component + Serialize + DeserializeOwned>(&mut self, name: &str) {
+        self.components
+            .register::<T>(name, encode::<T>, decode::<T>);
+    }
+    /// Encode the registered components of every entity
+    pub fn snapshot(&self) -> WorldSnapshot {
+        self.snapshot_with(&self.components)
+    }
+    /// Encode the components of every entity registered in another registry
+    pub fn snapshot_with(&self, registry: &ComponentRegistry) -> WorldSnapshot {
+        let mut snapshot = WorldSnapshot::default();
+        for (archetype, storage) in self.storages().enumerate() {
+            for (key, type_name) in storage.archetype().components() {
+                let registered = match key {
+                    ComponentKey::Type(id) => registry.contains(id),
+                    ComponentKey::Dynamic(_) => false,
+                };
+                if !registered {
+                    snapshot.skipped.insert(type_name.to_owned());
+                }
+            }
+            for row in 0..storage.len() {
+                let entity = self
+                    .location_map()
+                    .get_entity(Location {
+                        archetype,
+                        entity: row,
+                    })
+                    .expect("Snapshot of unregistered entity");
+                snapshot.entities.push(EntitySnapshot {
+                    entity: entity.to_bits(),
+                    components: registry.serialize(self, entity),
+                });
+            }
+        }
+        snapshot.entities.sort_by_key(|e| e.entity);
+        snapshot
+    }
+    /// Spawn the entities of a snapshot in a new world, which gets the registrations of this one.
+    /// The entities are new, the map gives them from the entities of the snapshot.
+    pub fn restore(&self, snapshot: &WorldSnapshot) -> Result<(World, EntityMap), SnapshotError> {
+        let mut world = World::new();
+        world.components = self.components.clone();
+        let mut map = EntityMap::new();
+        world.restore_snapshot(snapshot, &self.components, &mut map)?;
+        Ok((world, map))
+    }
+    /// Restore a snapshot in this world. The entities already in map (a scene spawned the same way
+    /// as in the source world) are updated in place, keeping their unregistered components, the
+    /// others are spawned. Components holding entities are mapped once every entity exists.
+    pub fn restore_snapshot(
+        &mut self,
+        snapshot: &WorldSnapshot,
+        registry: &ComponentRegistry,
+        map: &mut EntityMap,
+    ) -> Result<(), SnapshotError> {
+        for entity in &snapshot.entities {
+            let source = Entity::from_bits(entity.entity);
+            if map.get(source).is_none() {
+                let local = self.spawn(());
+                map.insert(source, local);
+            }
+        }
+        for entity in &snapshot.entities {
+            let local = map.get(Entity::from_bits(entity.entity)).unwrap();
+            registry
+                .apply(self, local, &entity.components, map)
+                .map_err(SnapshotError::Component)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::TypeId;
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Name(String);
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+    struct Position(f32, f32);
+    /// Not registered
+    struct Cache;
+
+    fn registered() -> World {
+        let mut world = World::new();
+        world.register_serde::<Name>("name");
+        world.register_serde::<Position>("position");
+        world
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut world = registered();
+        let a = world.spawn((Name("a".into()), Position(1.0, 2.0)));
+        let b = world.spawn((Position(3.0, 4.0), Cache));
+        world.spawn((Name("c".into()),));
+        let removed = world.spawn((Position(0.0, 0.0),));
+        world.remove(removed);
+
+        let snapshot = world.snapshot();
+        assert_eq!(3, snapshot.entities.len());
+        assert_eq!(1, snapshot.skipped.len());
+        assert!(snapshot.skipped.iter().next().unwrap().ends_with("Cache"));
+        // The snapshot goes through a save file
+        let snapshot = WorldSnapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+
+        let (restored, map) = registered().restore(&snapshot).unwrap();
+        assert_eq!(3, restored.entity_count());
+        assert_eq!(3, map.len());
+        let mut values = restored
+            .query::<(Option<&Name>, Option<&Position>)>()
+            .map(|(n, p)| (n.cloned(), p.copied()))
+            .collect::<Vec<_>>();
+        values.sort_by_key(|(n, _)| n.as_ref().map(|n| n.0.clone()));
+        assert_eq!(
+            vec![
+                (None, Some(Position(3.0, 4.0))),
+                (Some(Name("a".into())), Some(Position(1.0, 2.0))),
+                (Some(Name("c".into())), None),
+            ],
+            values
+        );
+        let a = map.get(a).unwrap();
+        let position = restored.component_ptr(a, TypeId::of::<Position>()).unwrap();
+        assert_eq!(Position(1.0, 2.0), unsafe {
+            *(position as *const Position)
+        });
+        assert!(map.get(b).is_some());
+        // The registrations carry over
+        assert_eq!(3, restored.snapshot().entities.len());
+    }
+
+    #[test]
+    fn unregistered() {
+        let mut world = registered();
+        world.spawn((Name("a".into()),));
+        let snapshot = world.snapshot();
+        let result = World::new().restore(&snapshot);
+        assert_eq!(Some(SnapshotError::Component("name".into())), result.err());
+        assert_eq!(
+            Some(SnapshotError::Malformed),
+            WorldSnapshot::from_bytes(&[1, 2, 3]).err()
+        );
+    }
+
+    #[test]
+    fn shared_with_diffs() {
+        let mut source = registered();
+        source.spawn((Name("a".into()), Position(1.0, 2.0)));
+        let mut replica = registered();
+        let registry = replica.components().clone();
+        let mut map = EntityMap::new();
+        let diff = source.diff_since(0, source.components());
+        replica.apply_diff(&diff, &registry, &mut map).unwrap();
+        assert_eq!(
+            source.snapshot().entities.len(),
+            replica.snapshot().entities.len()
+        );
+        assert_eq!(
+            source.snapshot().entities[0].components,
+            replica.snapshot().entities[0].components
+        );
+    }
+
+    #[test]
+    fn seeded_map() {
+        #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+        struct Target(u64);
+
+        let mut world = registered();
+        world.components_mut().register_mapped::<Target>(
+            "target",
+            |t| t.0.to_le_bytes().to_vec(),
+            |b| Some(Target(u64::from_le_bytes(b.try_into().ok()?))),
+            |t, map| {
+                t.0 = map
+                    .get(Entity::from_bits(t.0))
+                    .unwrap_or_default()
+                    .to_bits()
+            },
+        );
+        let scene = world.spawn((Position(0.0, 0.0), 7u8));
+        world.component_mut::<Position>(scene).unwrap().0 = 5.0;
+        let follower = world.spawn((Target(scene.to_bits()),));
+        let snapshot = world.snapshot();
+
+        // The same scene, spawned again
+        let mut restored = World::new();
+        let local = restored.spawn((Position(0.0, 0.0), 7u8));
+        let mut map = EntityMap::new();
+        map.insert(scene, local);
+        restored
+            .restore_snapshot(&snapshot, world.components(), &mut map)
+            .unwrap();
+        assert_eq!(2, restored.entity_count());
+        assert_eq!(
+            Some(Position(5.0, 0.0)),
+            restored.component_mut::<Position>(local).copied()
+        );
+        assert_eq!(Some(7), restored.component_mut::<u8>(local).copied());
+        let follower = map.get(follower).unwrap();
+        assert_eq!(
+            Some(Target(local.to_bits())),
+            restored.component_mut::<Target>(follower).copied()
+        );
+    }
+}
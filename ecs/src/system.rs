@@ -1,14 +1,41 @@
 use crate::{
+    archetype::{Archetype, ComponentKey},
     bitset::{BitsetBuilder, BorrowBitset, BorrowBitsetBuilder, BorrowBitsetMapping},
+    commands::CommandQueue,
     executor::{ExecutionContext, Resource},
-    query::{Query, QueryIterBundle},
+    local::{LocalInit, Locals},
+    query::{accesses_conflict, ComponentAccess, Query, QueryIterBundle},
+    trace::SkipReason,
+    EcsError, Executor, World,
 };
+#[cfg(feature = "codegen")]
 use ecs_macros::impl_system;
-use std::any::TypeId;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
 
 pub struct Requirements {
     components: BorrowBitset,
     resources: BorrowBitset,
+    /// Accesses of each query, for the archetype aware analysis
+    queries: Vec<Vec<ComponentAccess>>,
+    /// The `Local` arguments, created before the first run
+    locals: Vec<LocalInit>,
+}
+
+impl Requirements {
+    /// Add the requirements of something run right before or after, like a run condition
+    fn merge(&mut self, other: Requirements) {
+        self.components.merge(other.components);
+        self.resources.merge(other.resources);
+        self.queries.extend(other.queries);
+        for local in other.locals {
+            if !self.locals.iter().any(|&(id, _)| id == local.0) {
+                self.locals.push(local);
+            }
+        }
+    }
 }
 
 pub struct RequirementsMappings {
@@ -23,6 +50,10 @@ impl RequirementsMappings {
             resources: BorrowBitsetMapping::new(),
         }
     }
+    /// Number of types mapped
+    pub(crate) fn len(&self) -> usize {
+        self.components.next() + self.resources.next()
+    }
 }
 
 impl Default for RequirementsMappings {
@@ -34,6 +65,8 @@ impl Default for RequirementsMappings {
 pub struct RequirementsBuilder<'a> {
     components: BorrowBitsetBuilder<'a>,
     resources: BorrowBitsetBuilder<'a>,
+    queries: Vec<Vec<ComponentAccess>>,
+    pub(crate) locals: Vec<LocalInit>,
 }
 
 impl<'a> RequirementsBuilder<'a> {
@@ -41,6 +74,8 @@ impl<'a> RequirementsBuilder<'a> {
         Self {
             components: BorrowBitsetBuilder::start(&mappings.components),
             resources: BorrowBitsetBuilder::start(&mappings.resources),
+            queries: Vec::new(),
+            locals: Vec::new(),
         }
     }
     pub fn build(self) -> Option<Requirements> {
@@ -49,6 +84,8 @@ impl<'a> RequirementsBuilder<'a> {
         Some(Requirements {
             components,
             resources,
+            queries: self.queries,
+            locals: self.locals,
         })
     }
 }
@@ -59,9 +96,26 @@ pub trait IntoSystem<A> {
     fn into_system(self, mappings: &mut RequirementsMappings) -> System;
 }
 
-trait SystemArgument {
+/// Something a system can take as an argument. Implemented by `ecs::SystemParam` for composite
+/// arguments, the trait itself isn't meant to be implemented outside of ecs.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be an argument of a system",
+    label = "not a system argument",
+    note = "system arguments are resources (`&T`, `&mut T`, `ResMut<T>`, `ChangedRes<T>`, \
+            `OptionRes<T>`), `Entities<Q>`, `Local<T>`, `Budget`, and structs deriving \
+            `SystemParam`"
+)]
+pub trait SystemArgument {
     /// Fetch the argument from an ExecutionContext, this ignores aliasing and is unsafe
+    ///
+    /// # Safety
+    ///
+    /// `check` must have succeeded, and nothing else may be accessing what `require` declares
     unsafe fn fetch(context: &ExecutionContext) -> Self;
+    /// Check that the argument can be fetched, before fetching any argument of the system
+    fn check(_context: &ExecutionContext) -> Result<(), EcsError> {
+        Ok(())
+    }
     /// Get the requirements that this argument implies
     fn require(builder: RequirementsBuilder) -> RequirementsBuilder;
     /// Register the types that this argument references in the mapping
@@ -73,31 +127,43 @@ pub type Entities<Q> = QueryIterBundle<Q>;
 impl<Q: Query> SystemArgument for Entities<Q> {
     fn register(mappings: &mut RequirementsMappings) {
         for ty in Q::types() {
-            if !mappings.components.has(&ty) {
-                mappings.components.map(ty);
+            if !mappings.components.has(&ty.into()) {
+                mappings.components.map(ty.into());
             }
         }
     }
     fn require(mut builder: RequirementsBuilder) -> RequirementsBuilder {
         builder.components = Q::add_to_bitset(builder.components);
+        builder.queries.push(Q::access());
         builder
     }
+    fn check(_context: &ExecutionContext) -> Result<(), EcsError> {
+        Q::check_aliasing()
+    }
     unsafe fn fetch(context: &ExecutionContext) -> Self {
         log::trace!("SystemArgument: Fetching Query");
-        std::mem::transmute(context.world.query_unchecked::<Q>())
+        context
+            .world
+            .query_unchecked::<Q>(context.this_tick, context.last_tick)
     }
 }
 
-impl<'r, T: Resource> SystemArgument for &'r T {
+impl<T: Resource> SystemArgument for &T {
     fn register(mappings: &mut RequirementsMappings) {
-        if !mappings.resources.has(&TypeId::of::<T>()) {
-            mappings.resources.map(TypeId::of::<T>());
+        if !mappings.resources.has(&ComponentKey::of::<T>()) {
+            mappings.resources.map(ComponentKey::of::<T>());
         }
     }
     fn require(mut builder: RequirementsBuilder) -> RequirementsBuilder {
         builder.resources = builder.resources.borrow::<T>();
         builder
     }
+    fn check(context: &ExecutionContext) -> Result<(), EcsError> {
+        match context.executor.resource_slot::<T>() {
+            Some(_) => Ok(()),
+            None => Err(EcsError::missing_resource::<T>()),
+        }
+    }
     unsafe fn fetch(context: &ExecutionContext) -> Self {
         log::trace!("SystemArgument: Fetching &Resource");
         let res = context
@@ -109,34 +175,227 @@ impl<'r, T: Resource> SystemArgument for &'r T {
     }
 }
 
-impl<'r, T: Resource> SystemArgument for &'r mut T {
+impl<T: Resource> SystemArgument for &mut T {
     fn register(mappings: &mut RequirementsMappings) {
-        if !mappings.resources.has(&TypeId::of::<T>()) {
-            mappings.resources.map(TypeId::of::<T>());
+        if !mappings.resources.has(&ComponentKey::of::<T>()) {
+            mappings.resources.map(ComponentKey::of::<T>());
         }
     }
     fn require(mut builder: RequirementsBuilder) -> RequirementsBuilder {
-        builder.resources = builder.resources.borrow::<T>();
+        builder.resources = builder.resources.borrow_mut::<T>();
         builder
     }
+    fn check(context: &ExecutionContext) -> Result<(), EcsError> {
+        <&T>::check(context)
+    }
     unsafe fn fetch(context: &ExecutionContext) -> Self {
         log::trace!("SystemArgument: Fetching &mut Resource");
-        let res = context
+        let (res, changed) = context
             .executor
-            .get_resource_mut_unchecked::<T>()
+            .resource_mut_untracked::<T>()
             .unwrap_or_else(|| panic!("Resource not in system: {}", std::any::type_name::<T>()));
+        // Can't know if it will be written to
+        changed.store(context.this_run, Ordering::Relaxed);
         // transform lifetime to be valid.
         &mut *(res as *mut T)
     }
 }
 
+/// Shared access to a resource, same as `&T`
+pub struct Res<'r, T>(&'r T);
+
+impl<'r, T> Res<'r, T> {
+    /// The resource, with the lifetime of the borrow
+    pub fn get(&self) -> &'r T {
+        self.0
+    }
+}
+
+impl<'r, T> Deref for Res<'r, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.0
+    }
+}
+
+impl<'r, T: Resource> SystemArgument for Res<'r, T> {
+    fn register(mappings: &mut RequirementsMappings) {
+        <&T>::register(mappings);
+    }
+    fn require(builder: RequirementsBuilder) -> RequirementsBuilder {
+        <&T>::require(builder)
+    }
+    fn check(context: &ExecutionContext) -> Result<(), EcsError> {
+        <&T>::check(context)
+    }
+    unsafe fn fetch(context: &ExecutionContext) -> Self {
+        Self(<&T>::fetch(context))
+    }
+}
+
+/// Mutable access to a resource, only marking it changed (see `ChangedRes`) when dereferenced
+/// mutably, unlike `&mut T` which always does. A mutable dereference counts even if nothing is
+/// written.
+pub struct ResMut<'r, T> {
+    value: &'r mut T,
+    changed: &'r AtomicU64,
+    tick: u64,
+}
+
+impl<'r, T> ResMut<'r, T> {
+    /// Mutable access without marking the resource changed, for resources updated every frame
+    /// that nothing should react to.
+    pub fn bypass_change_detection(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<'r, T> Deref for ResMut<'r, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'r, T> DerefMut for ResMut<'r, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.changed.store(self.tick, Ordering::Relaxed);
+        self.value
+    }
+}
+
+impl<'r, T: Resource> SystemArgument for ResMut<'r, T> {
+    fn register(mappings: &mut RequirementsMappings) {
+        <&mut T>::register(mappings);
+    }
+    fn require(builder: RequirementsBuilder) -> RequirementsBuilder {
+        <&mut T>::require(builder)
+    }
+    fn check(context: &ExecutionContext) -> Result<(), EcsError> {
+        <&T>::check(context)
+    }
+    unsafe fn fetch(context: &ExecutionContext) -> Self {
+        log::trace!("SystemArgument: Fetching ResMut");
+        let (value, changed) = context
+            .executor
+            .resource_mut_untracked::<T>()
+            .unwrap_or_else(|| panic!("Resource not in system: {}", std::any::type_name::<T>()));
+        // transform lifetimes to be valid.
+        Self {
+            value: &mut *(value as *mut T),
+            changed: &*(changed as *const AtomicU64),
+            tick: context.this_run,
+        }
+    }
+}
+
+/// Shared access to a resource, but only if it changed since the previous run of the system (its
+/// first run sees every resource as changed). Changes made earlier in the same schedule are seen.
+pub struct ChangedRes<'r, T>(Option<&'r T>);
+
+impl<'r, T> ChangedRes<'r, T> {
+    /// The resource, None if it didn't change
+    pub fn get(&self) -> Option<&'r T> {
+        self.0
+    }
+}
+
+impl<'r, T: Resource> SystemArgument for ChangedRes<'r, T> {
+    fn register(mappings: &mut RequirementsMappings) {
+        <&T>::register(mappings);
+    }
+    fn require(builder: RequirementsBuilder) -> RequirementsBuilder {
+        <&T>::require(builder)
+    }
+    fn check(context: &ExecutionContext) -> Result<(), EcsError> {
+        <&T>::check(context)
+    }
+    unsafe fn fetch(context: &ExecutionContext) -> Self {
+        log::trace!("SystemArgument: Fetching ChangedRes");
+        let changed = context
+            .executor
+            .resource_slot::<T>()
+            .unwrap_or_else(|| panic!("Resource not in system: {}", std::any::type_name::<T>()))
+            .changed
+            .load(Ordering::Relaxed);
+        Self((changed > context.last_run).then(|| <&T>::fetch(context)))
+    }
+}
+
+/// Shared access to a resource if the executor has it, the system runs either way
+pub struct OptionRes<'r, T>(Option<&'r T>);
+
+impl<'r, T> OptionRes<'r, T> {
+    /// The resource, None if the executor doesn't have it
+    pub fn get(&self) -> Option<&'r T> {
+        self.0
+    }
+}
+
+impl<'r, T: Resource> SystemArgument for OptionRes<'r, T> {
+    fn register(mappings: &mut RequirementsMappings) {
+        <&T>::register(mappings);
+    }
+    fn require(builder: RequirementsBuilder) -> RequirementsBuilder {
+        <&T>::require(builder)
+    }
+    unsafe fn fetch(context: &ExecutionContext) -> Self {
+        log::trace!("SystemArgument: Fetching OptionRes");
+        // transform lifetime to be valid
+        Self(
+            context
+                .executor
+                .get_resource::<T>()
+                .map(|res| &*(res as *const T)),
+        )
+    }
+}
+
+/// Check that the arguments of a system can be fetched
+type ArgumentsCheck = dyn Fn(&ExecutionContext) -> Result<(), EcsError>;
+
 /// A struct representing a system with some metadata
 pub struct System {
+    name: &'static str,
     requirements: Requirements,
     run: Box<dyn Fn(&ExecutionContext)>,
+    check: Box<ArgumentsCheck>,
+    /// Change tick of the last run (see `ChangedRes`)
+    last_run: AtomicU64,
+    /// World tick of the last run (see `Changed`)
+    last_tick: AtomicU32,
+    /// Commands queued by the last run, see `Commands`
+    pub(crate) commands: CommandQueue,
+    /// Values of the `Local` arguments
+    pub(crate) locals: Locals,
+    /// Decides if the system runs, see `Scheduler::then_if`
+    condition: Option<ConditionRun>,
+}
+
+/// A closure returning a bool, with system arguments, deciding if a system runs (see
+/// `Scheduler::then_if`)
+pub struct Condition {
+    requirements: Requirements,
+    run: ConditionRun,
+}
+
+/// The part of a condition the system keeps, its requirements are merged with the system's
+struct ConditionRun {
+    run: Box<dyn Fn(&ExecutionContext) -> bool>,
+    check: Box<ArgumentsCheck>,
+}
+
+/// A trait implemented on all Fn returning a bool that are conditions
+pub trait IntoCondition<A> {
+    /// Create a Condition struct representing the condition
+    fn into_condition(self, mappings: &mut RequirementsMappings) -> Condition;
 }
 
 impl System {
+    /// Type name of the function
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
     /// Check if the system depends on another
     pub fn depends_on(&self, other: &Self) -> bool {
         self.requirements
@@ -147,17 +406,146 @@ impl System {
                 .resources
                 .collide(other.requirements.resources)
     }
+    /// Check if the system depends on another in a world with these archetypes. Like
+    /// `depends_on`, but a component borrowed by both only counts if an archetype has data the
+    /// queries of both systems touch.
+    pub(crate) fn depends_on_in<'a>(
+        &self,
+        other: &Self,
+        archetypes: impl IntoIterator<Item = &'a Archetype>,
+    ) -> bool {
+        let (this, that) = (&self.requirements, &other.requirements);
+        if this.resources.collide(that.resources) {
+            return true;
+        }
+        if !this.components.collide(that.components) {
+            return false;
+        }
+        archetypes
+            .into_iter()
+            .any(|archetype| self.conflicts_in(other, archetype))
+    }
+    /// If queries of both systems touch the same data in an archetype
+    pub(crate) fn conflicts_in(&self, other: &Self, archetype: &Archetype) -> bool {
+        let queries = &other.requirements.queries;
+        self.requirements
+            .queries
+            .iter()
+            .any(|a| queries.iter().any(|b| accesses_conflict(a, b, archetype)))
+    }
+    /// Create the values of the `Local` arguments that don't exist yet, no system may be running
+    pub(crate) fn init_locals(&self, world: &World, executor: &Executor) {
+        if !self.requirements.locals.is_empty() {
+            self.locals.init(&self.requirements.locals, world, executor);
+        }
+    }
+    /// Make the system run only when condition returns true, its requirements are added to the
+    /// system's
+    pub(crate) fn set_condition(&mut self, condition: Condition) {
+        self.requirements.merge(condition.requirements);
+        self.condition = Some(condition.run);
+    }
     /// Execute the system, this bypasses any aliasing checks and should only be used when proven
-    /// safe
-    pub unsafe fn run(&self, context: &ExecutionContext) {
-        (self.run)(context);
+    /// safe. If an argument can't be fetched, the executor's `FetchPolicy` decides what happens,
+    /// and the reason is returned when the system is skipped. A system skipped by its condition
+    /// sees the changes since its last actual run the next time it runs.
+    pub unsafe fn run(&self, context: &ExecutionContext) -> Result<(), SkipReason> {
+        let checked = (self.check)(context).and_then(|()| match &self.condition {
+            Some(condition) => (condition.check)(context),
+            None => Ok(()),
+        });
+        if let Err(error) = checked {
+            context.executor.fetch_failed(self.name, error.clone());
+            return Err(SkipReason::Fetch(error));
+        }
+        let this_run = context.executor.next_change_tick();
+        let this_tick = context.world.take_tick();
+        let context = ExecutionContext {
+            last_run: self.last_run.load(Ordering::Relaxed),
+            this_run,
+            last_tick: self.last_tick.load(Ordering::Relaxed),
+            this_tick,
+            commands: Some(&self.commands),
+            locals: Some(&self.locals),
+            ..*context
+        };
+        if let Some(condition) = &self.condition {
+            if !(condition.run)(&context) {
+                return Err(SkipReason::Condition);
+            }
+        }
+        self.last_run.store(this_run, Ordering::Relaxed);
+        self.last_tick.store(this_tick, Ordering::Relaxed);
+        (self.run)(&context);
+        Ok(())
     }
 }
 
-#[cfg(not(feature = "extended_limits"))]
-impl_system!(16);
+// Mirrors ecs_macros::impl_system
+macro_rules! impl_system_tuple {
+    ($($t:ident $i:tt),*) => {
+        #[allow(unused_parens, unused_variables, unused_mut, unused_unsafe)]
+        impl<Func: Fn($($t),*) + 'static, $($t: SystemArgument),*> IntoSystem<($($t),*)> for Func {
+            fn into_system(self, mappings: &mut RequirementsMappings) -> System {
+                $($t::register(mappings);)*
+                let mut builder = RequirementsBuilder::start(mappings);
+                $(builder = $t::require(builder);)*
+                // Arguments have been registered so unwrap is safe
+                let requirements = builder.build().unwrap();
+                System {
+                    name: std::any::type_name::<Func>(),
+                    requirements,
+                    run: Box::new(move |context| unsafe { self($($t::fetch(context)),*) }),
+                    check: Box::new(|context| {
+                        $($t::check(context)?;)*
+                        Ok(())
+                    }),
+                    last_run: AtomicU64::new(0),
+                    last_tick: AtomicU32::new(0),
+                    commands: Default::default(),
+                    locals: Default::default(),
+                    condition: None,
+                }
+            }
+        }
+    };
+}
+
+// Mirrors impl_system_tuple, conditions only go up to 8 arguments
+macro_rules! impl_condition_tuple {
+    ($($t:ident $i:tt),*) => {
+        #[allow(unused_parens, unused_variables, unused_mut, unused_unsafe)]
+        impl<Func: Fn($($t),*) -> bool + 'static, $($t: SystemArgument),*> IntoCondition<($($t),*)>
+            for Func
+        {
+            fn into_condition(self, mappings: &mut RequirementsMappings) -> Condition {
+                $($t::register(mappings);)*
+                let mut builder = RequirementsBuilder::start(mappings);
+                $(builder = $t::require(builder);)*
+                // Arguments have been registered so unwrap is safe
+                let requirements = builder.build().unwrap();
+                Condition {
+                    requirements,
+                    run: ConditionRun {
+                        run: Box::new(move |context| unsafe { self($($t::fetch(context)),*) }),
+                        check: Box::new(|context| {
+                            $($t::check(context)?;)*
+                            Ok(())
+                        }),
+                    },
+                }
+            }
+        }
+    };
+}
+
+// Functions of 0 to 8 arguments are implemented here, larger ones are generated by ecs_macros
+for_tuples!(impl_system_tuple; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+for_tuples!(impl_condition_tuple; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+#[cfg(all(feature = "codegen", not(feature = "extended_limits")))]
+impl_system!(9..=16);
 #[cfg(feature = "extended_limits")]
-impl_system!(24);
+impl_system!(9..=24);
 
 // Annoyingly enough, this can't really be tested as is, because systems rely on an
 // ExecutionContext and a Schedule guarenteeing safety.
@@ -0,0 +1,359 @@
+//! Scaffolding for the tests of systems: a world and an executor to spawn entities in, run systems
+//! on and assert on, behind the `testing` feature.
+//!
+//! ```ignore
+//! let mut bed = TestBed::new();
+//! let [entity] = bed.with_entities([(Position(0.0),)])[..] else { unreachable!() };
+//! bed.with_resource(Velocity(2.0))
+//!     .run(movement)
+//!     .assert_component(entity, Position(2.0));
+//! ```
+//!
+//! Schedules run sequentially on the calling thread by default, so that tests are deterministic.
+//! `TestBed::parallel` runs them like the game does instead, for the tests of the concurrency.
+
+use std::{any::TypeId, fmt::Debug, time::Duration};
+
+use crate::{
+    archetype::{Component, IntoArchetype},
+    entity::Entity,
+    executor::{Executor, Resource},
+    query::Query,
+    schedule::{Schedule, Scheduler},
+    system::IntoSystem,
+    world::World,
+};
+
+/// A clock moved by hand with `advance`, to stand in for the real time in systems that run on a
+/// timer or at a fixed step
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TickClock {
+    now: Duration,
+    /// Duration of the last advance
+    delta: Duration,
+}
+
+impl TickClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn advance(&mut self, by: Duration) {
+        self.now += by;
+        self.delta = by;
+    }
+    /// Time elapsed since the creation of the clock
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+    /// Duration of the last advance
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+}
+
+/// A world and an executor, see the module documentation
+pub struct TestBed {
+    pub world: World,
+    pub executor: Executor,
+    parallel: bool,
+}
+
+impl TestBed {
+    pub fn new() -> Self {
+        Self {
+            world: World::new(),
+            executor: Executor::new(),
+            parallel: false,
+        }
+    }
+    /// Run the schedules with `Executor::execute`, on the worker threads
+    pub fn parallel(mut self) -> Self {
+        self.parallel = true;
+        self
+    }
+    /// Spawn entities, returns them in order
+    pub fn with_entities<T: IntoArchetype>(
+        &mut self,
+        entities: impl IntoIterator<Item = T>,
+    ) -> Vec<Entity> {
+        self.world.spawn_many(entities)
+    }
+    pub fn with_resource<T: Resource>(&mut self, resource: T) -> &mut Self {
+        self.executor.add_resource(resource);
+        self
+    }
+    /// # Panics
+    ///
+    /// Panics if the resource isn't in the executor
+    pub fn resource<T: Resource>(&self) -> &T {
+        self.executor
+            .get_resource()
+            .unwrap_or_else(|| panic!("No resource {}", short_type_name::<T>()))
+    }
+    /// # Panics
+    ///
+    /// Panics if the resource isn't in the executor
+    pub fn resource_mut<T: Resource>(&mut self) -> &mut T {
+        self.executor
+            .get_resource_mut()
+            .unwrap_or_else(|| panic!("No resource {}", short_type_name::<T>()))
+    }
+    /// Advance the `TickClock`, added on the first call
+    pub fn advance(&mut self, by: Duration) -> &mut Self {
+        match self.executor.get_resource_mut::<TickClock>() {
+            Some(clock) => clock.advance(by),
+            None => {
+                let mut clock = TickClock::new();
+                clock.advance(by);
+                self.executor.add_resource(clock);
+            }
+        }
+        self
+    }
+    /// Run a single system once
+    pub fn run<A>(&mut self, system: impl IntoSystem<A>) -> &mut Self {
+        let schedule = self.executor.schedule_single(system);
+        self.execute(&schedule)
+    }
+    /// Build a schedule and run it once
+    pub fn run_schedule(&mut self, build: impl FnOnce(Scheduler) -> Scheduler) -> &mut Self {
+        let schedule = self.schedule(build);
+        self.execute(&schedule)
+    }
+    /// Build a schedule, to run it several times with `execute` (systems keep their state and
+    /// their change detection between runs)
+    pub fn schedule(&mut self, build: impl FnOnce(Scheduler) -> Scheduler) -> Schedule {
+        build(self.executor.schedule()).build()
+    }
+    pub fn execute(&mut self, schedule: &Schedule) -> &mut Self {
+        if self.parallel {
+            self.executor.execute(schedule, &mut self.world);
+        } else {
+            self.executor.execute_sequential(schedule, &mut self.world);
+        }
+        self
+    }
+    /// # Panics
+    ///
+    /// Panics if the entity doesn't exist, doesn't have a T, or if its T isn't expected
+    #[track_caller]
+    pub fn assert_component<T: Component + Debug + PartialEq>(
+        &mut self,
+        entity: Entity,
+        expected: T,
+    ) -> &mut Self {
+        let name = short_type_name::<T>();
+        if !self.world.contains(entity) {
+            panic!("assert_component::<{name}>: {entity:?} doesn't exist");
+        }
+        let Some(ptr) = self.world.component_ptr(entity, TypeId::of::<T>()) else {
+            panic!("assert_component::<{name}>: {entity:?} has no {name}");
+        };
+        // SAFETY: the pointer is to a T, and the world isn't borrowed mutably as self isn't
+        let found = unsafe { &*(ptr as *const T) };
+        if *found != expected {
+            panic!(
+                "assert_component::<{name}>: {entity:?} doesn't have the expected value\n  \
+                 expected: {expected:?}\n     found: {found:?}"
+            );
+        }
+        self
+    }
+    /// # Panics
+    ///
+    /// Panics if Q doesn't match exactly count entities
+    #[track_caller]
+    pub fn assert_query_count<Q: Query>(&mut self, count: usize) -> &mut Self {
+        let found = self.world.query::<Q>().count();
+        if found != count {
+            panic!(
+                "assert_query_count::<{}>: expected {count} entities, found {found}",
+                short_type_name::<Q>()
+            );
+        }
+        self
+    }
+}
+
+impl Default for TestBed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The name of a type without the paths, `(&Position, Option<&Velocity>)` rather than
+/// `(&my_crate::components::Position, core::option::Option<&my_crate::components::Velocity>)`
+pub fn short_type_name<T: ?Sized>() -> String {
+    let name = std::any::type_name::<T>();
+    let mut short = String::with_capacity(name.len());
+    let mut segment = String::new();
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            segment.clear();
+        } else if c.is_alphanumeric() || c == '_' {
+            segment.push(c);
+        } else {
+            short.push_str(&segment);
+            segment.clear();
+            short.push(c);
+        }
+    }
+    short.push_str(&segment);
+    short
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use super::*;
+    use crate::Entities;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Position(f32);
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Velocity(f32);
+
+    fn movement(entities: Entities<(&mut Position, &Velocity)>) {
+        for (position, velocity) in entities {
+            position.0 += velocity.0;
+        }
+    }
+
+    /// The message of the panic of f
+    fn panic_message(f: impl FnOnce()) -> String {
+        let payload = catch_unwind(AssertUnwindSafe(f)).expect_err("Didn't panic");
+        match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload.downcast_ref::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn type_names() {
+        assert_eq!("Position", short_type_name::<Position>());
+        assert_eq!(
+            "(&Position, Option<&mut Velocity>)",
+            short_type_name::<(&Position, Option<&mut Velocity>)>()
+        );
+        assert_eq!("Vec<u8>", short_type_name::<Vec<u8>>());
+    }
+
+    #[test]
+    fn assertion_messages() {
+        let mut bed = TestBed::new();
+        let entities = bed.with_entities([(Position(1.0),), (Position(2.0),)]);
+        let gone = bed.world.spawn((Velocity(0.0),));
+        bed.world.remove(gone);
+
+        let message = panic_message(|| {
+            bed.assert_component(entities[0], Position(3.0));
+        });
+        assert_eq!(
+            format!(
+                "assert_component::<Position>: {:?} doesn't have the expected value\n  \
+                 expected: Position(3.0)\n     found: Position(1.0)",
+                entities[0]
+            ),
+            message
+        );
+        let message = panic_message(|| {
+            bed.assert_component(entities[1], Velocity(1.0));
+        });
+        assert_eq!(
+            format!(
+                "assert_component::<Velocity>: {:?} has no Velocity",
+                entities[1]
+            ),
+            message
+        );
+        let message = panic_message(|| {
+            bed.assert_component(gone, Velocity(0.0));
+        });
+        assert_eq!(
+            format!("assert_component::<Velocity>: {gone:?} doesn't exist"),
+            message
+        );
+        let message = panic_message(|| {
+            bed.assert_query_count::<(&Position, &Velocity)>(2);
+        });
+        assert_eq!(
+            "assert_query_count::<(&Position, &Velocity)>: expected 2 entities, found 0",
+            message
+        );
+        let message = panic_message(|| {
+            bed.resource::<TickClock>();
+        });
+        assert_eq!("No resource TickClock", message);
+    }
+
+    #[test]
+    fn chaining() {
+        let mut bed = TestBed::new();
+        let entities = bed.with_entities([(Position(0.0), Velocity(1.0))]);
+        let still = bed.with_entities([(Position(5.0),)]);
+        bed.with_resource(0u32)
+            .run(movement)
+            .run(movement)
+            .run(|runs: &mut u32| *runs += 1)
+            .assert_component(entities[0], Position(2.0))
+            .assert_component(still[0], Position(5.0))
+            .run_schedule(|s| s.then(movement).then(|runs: &mut u32| *runs += 1))
+            .assert_component(entities[0], Position(3.0))
+            .assert_query_count::<&Position>(2)
+            .assert_query_count::<(&Position, &Velocity)>(1);
+        assert_eq!(2, *bed.resource::<u32>());
+    }
+
+    #[test]
+    fn throttled_system() {
+        /// Runs at most every 100ms of the clock
+        #[derive(Default)]
+        struct Throttle {
+            last: Option<Duration>,
+            runs: u32,
+        }
+        fn throttled(clock: &TickClock, throttle: &mut Throttle) {
+            let due = throttle.last.map_or(true, |last| {
+                clock.now() - last >= Duration::from_millis(100)
+            });
+            if due {
+                throttle.last = Some(clock.now());
+                throttle.runs += 1;
+            }
+        }
+
+        let mut bed = TestBed::new();
+        bed.with_resource(Throttle::default());
+        let schedule = bed.advance(Duration::ZERO).schedule(|s| s.then(throttled));
+        let ms = Duration::from_millis;
+        for advance in [ms(0), ms(40), ms(40), ms(40), ms(100), ms(10), ms(250)] {
+            bed.advance(advance).execute(&schedule);
+        }
+        // At 0, 120, 220 and 480
+        assert_eq!(4, bed.resource::<Throttle>().runs);
+        assert_eq!(ms(480), bed.resource::<TickClock>().now());
+        assert_eq!(ms(250), bed.resource::<TickClock>().delta());
+    }
+
+    #[test]
+    fn parallel() {
+        let mut bed = TestBed::new().parallel();
+        let entities = bed.with_entities((0..64).map(|i| (Position(i as f32), Velocity(1.0))));
+        bed.with_resource(0u8).with_resource(0u16);
+        let schedule = bed.schedule(|s| {
+            s.then(movement)
+                .then(|a: &mut u8| *a += 1)
+                .then(|b: &mut u16| *b += 1)
+        });
+        assert!(schedule.report().threads > 1);
+        for _ in 0..10 {
+            bed.execute(&schedule);
+        }
+        bed.assert_component(entities[63], Position(73.0));
+        assert_eq!(10, *bed.resource::<u8>());
+        assert_eq!(10, *bed.resource::<u16>());
+    }
+}
@@ -1,30 +1,41 @@
+//! Pool of worker threads. Jobs are submitted in a scope (see `ThreadPool::scope`), which doesn't
+//! return before all of them have completed, so they can borrow from the caller.
+
 use std::{
+    any::Any,
     fmt::Debug,
     marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
     sync::{atomic::AtomicU32, mpsc, Arc},
     thread::{self, JoinHandle},
+    time::Instant,
 };
 
 use parking_lot::{Condvar, Mutex};
 use std::sync::mpsc::{Receiver, Sender};
 
-pub struct ThreadPool<J: Job> {
-    workers: Vec<Worker<J>>,
-    actions: Sender<Action<J>>,
-    actions_receiver: Arc<Mutex<Receiver<Action<J>>>>,
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    actions: Sender<Action>,
+    actions_receiver: Arc<Mutex<Receiver<Action>>>,
 }
 
-pub struct Worker<J> {
+pub struct Worker {
     thread: JoinHandle<()>,
-    _phantom: PhantomData<J>,
 }
 
-enum Action<J: Job> {
-    Job(J, Arc<Wait>),
+/// A job submitted to a scope, its lifetime erased. Only valid until the scope is done waiting.
+struct ScopedJob {
+    job: Box<dyn FnOnce() + Send + 'static>,
+    scope: Arc<ScopeState>,
+}
+
+enum Action {
+    Job(ScopedJob),
     Stop,
 }
 
-impl<J: Job> Debug for Action<J> {
+impl Debug for Action {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Action::Job(..) => write!(f, "Action::Job"),
@@ -33,20 +44,28 @@ impl<J: Job> Debug for Action<J> {
     }
 }
 
-impl<J: Job> Worker<J> {
-    fn new(actions: Arc<Mutex<Receiver<Action<J>>>>, id: u64) -> Self {
-        Self {
-            thread: thread::spawn(move || {
+impl Worker {
+    fn new(actions: Arc<Mutex<Receiver<Action>>>, id: u64) -> Self {
+        let thread = thread::Builder::new()
+            .name(format!("ecs-worker-{id}"))
+            .spawn(move || {
                 log::trace!("Worker({id}): Started");
                 log::trace!("Worker({id}): Listening for action");
-                while let Ok(action) = actions.lock().recv() {
+                loop {
+                    // The lock must be released before running the job, or the other workers
+                    // can't get theirs (and a job waiting on another deadlocks)
+                    let action = match actions.lock().recv() {
+                        Ok(action) => action,
+                        Err(_) => break,
+                    };
                     log::trace!("Worker({id}): Got action {action:?}");
                     match action {
-                        Action::Job(job, wait) => {
-                            job.execute();
+                        Action::Job(ScopedJob { job, scope }) => {
+                            // The job is consumed (or dropped by the unwinding) before completing,
+                            // nothing it borrows is touched past that point
+                            let result = panic::catch_unwind(AssertUnwindSafe(job));
                             log::trace!("Worker({id}): Finished job");
-                            // Notify once we're done
-                            wait.notify();
+                            scope.complete(result.err());
                         }
                         Action::Stop => {
                             break;
@@ -54,13 +73,13 @@ impl<J: Job> Worker<J> {
                     }
                 }
                 log::trace!("Worker({id}): Stopping");
-            }),
-            _phantom: PhantomData,
-        }
+            })
+            .expect("Couldn't spawn worker thread");
+        Self { thread }
     }
 }
 
-impl<J: Job> ThreadPool<J> {
+impl ThreadPool {
     /// Create a new thread pool with no worker
     pub fn new() -> Self {
         let (sender, receiver) = mpsc::channel();
@@ -70,6 +89,12 @@ impl<J: Job> ThreadPool<J> {
             actions_receiver: Arc::new(Mutex::new(receiver)),
         }
     }
+    /// Create a new thread pool with count workers
+    pub fn with_threads(count: usize) -> Self {
+        let mut pool = Self::new();
+        pool.add_workers(count);
+        pool
+    }
     /// Get the number of workers in the pool
     #[inline(always)]
     pub fn worker_count(&self) -> usize {
@@ -93,47 +118,32 @@ impl<J: Job> ThreadPool<J> {
             self.add_workers(count - current);
         }
     }
-    /// Run a job on a worker, return a Wait that will end when the job is finished
-    pub fn run(&self, job: J) -> Arc<Wait> {
-        let wait = Arc::new(Wait::new(1));
-        self.actions
-            .send(Action::Job(job, wait.clone()))
-            .expect("Error when sending job to workers");
-        wait
-    }
-    /// Run multiple jobs on in the pool, returns a Wait that will end when all jobs are finished
-    pub fn run_many(&self, jobs: impl IntoIterator<Item = J>) -> Arc<Wait> {
-        let iter = jobs.into_iter();
-        let mut wait_size: u32 = {
-            let (lower, upper) = iter.size_hint();
-            upper.unwrap_or(lower).try_into().unwrap_or(0)
+    /// Call `f` with a scope to run jobs on the workers, and wait for all of them to complete.
+    /// Jobs can borrow anything that outlives the call. If `f` or a job panics, the panic is
+    /// resumed once every job has completed.
+    ///
+    /// Jobs that wait on each other need as many workers as there are jobs waiting at once.
+    pub fn scope<'env, F, R>(&'env self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState::default()),
+            _scope: PhantomData,
+            _env: PhantomData,
         };
-
-        let wait = Arc::new(Wait::new(wait_size));
-        let mut count = 0;
-        for job in iter {
-            count += 1;
-            // If there are more jobs than expected
-            if count > wait_size {
-                // Update the limit
-                wait_size = count + 5;
-                wait.set_limit(wait_size);
-            }
-
-            self.actions
-                .send(Action::Job(job, wait.clone()))
-                .expect("Error when sending job to workers");
-        }
-        // If the hint isn't exact, we overshoot, so we correct at the end.
-        if wait_size > count {
-            wait.set_limit(count);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // Even when unwinding, the jobs can still be using what they borrow
+        scope.state.wait();
+        if let Some(payload) = scope.state.panic.lock().take() {
+            panic::resume_unwind(payload);
         }
-
-        wait
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
     }
 }
 
-impl<J: Job> Drop for ThreadPool<J> {
+impl Drop for ThreadPool {
     fn drop(&mut self) {
         for _ in 0..self.worker_count() {
             self.actions
@@ -146,10 +156,80 @@ impl<J: Job> Drop for ThreadPool<J> {
     }
 }
 
-pub trait Job: Send + Sync + 'static {
+#[derive(Default)]
+struct ScopeState {
+    /// Jobs submitted and not completed yet
+    pending: Mutex<usize>,
+    completed: Condvar,
+    /// Payload of the first job that panicked
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl ScopeState {
+    fn complete(&self, panic: Option<Box<dyn Any + Send>>) {
+        if let Some(payload) = panic {
+            self.panic.lock().get_or_insert(payload);
+        }
+        let mut pending = self.pending.lock();
+        *pending -= 1;
+        if *pending == 0 {
+            self.completed.notify_all();
+        }
+    }
+    fn wait(&self) {
+        let mut pending = self.pending.lock();
+        while *pending > 0 {
+            self.completed.wait(&mut pending);
+        }
+    }
+}
+
+/// Jobs submitted through a scope can borrow anything that lives for `'scope`, see
+/// `ThreadPool::scope`
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'env ThreadPool,
+    state: Arc<ScopeState>,
+    // Invariant, like std::thread::Scope, so that the lifetimes can't be shrunk or extended
+    _scope: PhantomData<&'scope mut &'scope ()>,
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Run a job on a worker
+    pub fn run<J: Job + 'scope>(&'scope self, job: J) {
+        // Counted before sending, the job can complete before `send` returns
+        *self.state.pending.lock() += 1;
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || job.execute());
+        // SAFETY: The job is only valid for 'scope, but workers are 'static. `ThreadPool::scope`
+        // waits (even when unwinding) for every job submitted to complete before returning, and
+        // workers drop a job before completing it, catching its panics. So the job and what it
+        // borrows are never used past 'scope. The scope can't escape `ThreadPool::scope` (higher
+        // ranked lifetime) and there is no guard to forget.
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { std::mem::transmute(job) };
+        // The pool holds the receiver, the send can't fail
+        self.pool
+            .actions
+            .send(Action::Job(ScopedJob {
+                job,
+                scope: self.state.clone(),
+            }))
+            .expect("Error when sending job to workers");
+    }
+}
+
+pub trait Job: Send {
     fn execute(self);
 }
 
+/// A closure as a job
+pub struct FnJob<F>(pub F);
+
+impl<F: FnOnce() + Send> Job for FnJob<F> {
+    fn execute(self) {
+        (self.0)()
+    }
+}
+
 /// A barrier like syncronizations struct, waits for a ceratin number of notifications.
 pub struct Wait {
     cond: Condvar,
@@ -182,8 +262,7 @@ impl Wait {
     pub fn notify(&self) {
         let mut count = self.count.lock();
         *count += 1;
-        if *count == self.limit() {
-            *count = 0;
+        if *count >= self.limit() {
             // release the lock
             drop(count);
 
@@ -195,84 +274,191 @@ impl Wait {
     pub fn set_limit(&self, limit: u32) {
         self.limit.store(limit, std::sync::atomic::Ordering::SeqCst);
         if *self.count.lock() >= limit {
-            self.reset();
             self.cond.notify_all();
         }
     }
-    /// Wait for limit notifications
+    /// Wait for limit notifications, the notifications can come before the call. Consumes them,
+    /// so that the Wait can be used again.
     pub fn wait(&self) {
-        self.cond.wait(&mut self.count.lock());
+        let mut count = self.count.lock();
+        while *count < self.limit() {
+            self.cond.wait(&mut count);
+        }
+        *count -= self.limit();
+    }
+    /// Like `wait`, but gives up at the deadline. Returns false if it did, without consuming the
+    /// notifications received so far.
+    pub fn wait_until(&self, deadline: Instant) -> bool {
+        let mut count = self.count.lock();
+        while *count < self.limit() {
+            if self.cond.wait_until(&mut count, deadline).timed_out() && *count < self.limit() {
+                return false;
+            }
+        }
+        *count -= self.limit();
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::atomic::AtomicU32;
-
-    use parking_lot::Mutex;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
 
     use super::*;
 
-    // Counter used to check if a job has been run
-    static TOTAL: AtomicU32 = AtomicU32::new(0);
-    // Lock to force single threading (no two test run at once, as they would both increment the
-    // TOTAL)
-    static LOCK: Mutex<()> = Mutex::new(());
-
     #[derive(Clone, Copy)]
-    struct J {
+    struct J<'a> {
         data: u32,
+        total: &'a AtomicU32,
     }
 
-    impl Job for J {
+    impl Job for J<'_> {
         fn execute(self) {
-            TOTAL.fetch_add(self.data, std::sync::atomic::Ordering::SeqCst);
+            self.total.fetch_add(self.data, Ordering::SeqCst);
         }
     }
 
-    fn reset_total() {
-        TOTAL.store(0, std::sync::atomic::Ordering::SeqCst);
+    /// Sums a chunk of a borrowed slice
+    struct Sum<'a> {
+        values: &'a [u64],
+        sum: &'a mut u64,
     }
 
-    fn assert_total(val: u32) {
-        let total = TOTAL.load(std::sync::atomic::Ordering::SeqCst);
-        assert_eq!(val, total);
+    impl Job for Sum<'_> {
+        fn execute(self) {
+            *self.sum = self.values.iter().sum();
+        }
     }
 
     #[test]
     fn init() {
-        let mut pool = ThreadPool::<J>::new();
+        let mut pool = ThreadPool::new();
         pool.add_workers(20);
     }
 
     #[test]
-    fn single() {
-        let _lock = LOCK.lock();
-        reset_total();
+    fn worker_names() {
+        let pool = ThreadPool::with_threads(2);
+        assert_eq!(2, pool.worker_count());
+        let name = Mutex::new(None);
+        pool.scope(|scope| {
+            scope.run(FnJob(|| {
+                *name.lock() = thread::current().name().map(str::to_owned);
+            }))
+        });
+        assert!(name.into_inner().unwrap().starts_with("ecs-worker-"));
+    }
 
-        assert_total(0); // total has been reset so 0
+    #[test]
+    fn single() {
+        let total = AtomicU32::new(0);
         let mut pool = ThreadPool::new();
-
-        let wait = pool.run(J { data: 10 });
-        // Pool doesn't have any workers, so still 0
-        assert_total(0);
         pool.add_workers(1);
-        wait.wait();
-        assert_total(10);
+        pool.scope(|scope| {
+            scope.run(J {
+                data: 10,
+                total: &total,
+            })
+        });
+        assert_eq!(10, total.load(Ordering::SeqCst));
     }
 
     #[test]
     fn many() {
-        let _lock = LOCK.lock();
-        reset_total();
-        assert_total(0); // total has been reset so 0
-
-        let jobs = [J { data: 5 }; 10];
+        let total = AtomicU32::new(0);
         let mut pool = ThreadPool::new();
         pool.add_workers(5);
+        pool.scope(|scope| {
+            for _ in 0..10 {
+                scope.run(J {
+                    data: 5,
+                    total: &total,
+                });
+            }
+        });
+        assert_eq!(50, total.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn panicking_job() {
+        let total = AtomicU32::new(0);
+        let mut pool = ThreadPool::new();
+        pool.add_workers(4);
 
-        let wait = pool.run_many(jobs);
+        struct Panic<'a>(&'a AtomicBool);
+        impl Job for Panic<'_> {
+            fn execute(self) {
+                // Let the other jobs start
+                thread::sleep(Duration::from_millis(5));
+                self.0.store(true, Ordering::SeqCst);
+                panic!("job panicked");
+            }
+        }
+        struct Slow<'a>(&'a AtomicU32);
+        impl Job for Slow<'_> {
+            fn execute(self) {
+                thread::sleep(Duration::from_millis(20));
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let panicked = AtomicBool::new(false);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.run(Panic(&panicked));
+                for _ in 0..3 {
+                    scope.run(Slow(&total));
+                }
+            })
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(Some(&"job panicked"), payload.downcast_ref::<&str>());
+        assert!(panicked.load(Ordering::SeqCst));
+        // The panic only surfaced once the slower jobs were done with what they borrow
+        assert_eq!(3, total.load(Ordering::SeqCst));
+
+        // The worker survived
+        pool.scope(|scope| {
+            for _ in 0..8 {
+                scope.run(J {
+                    data: 1,
+                    total: &total,
+                });
+            }
+        });
+        assert_eq!(11, total.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stress() {
+        let mut pool = ThreadPool::new();
+        pool.add_workers(8);
+        for round in 0..1000u64 {
+            let values = (0..256).map(|i| i * round).collect::<Vec<_>>();
+            let mut sums = vec![0; 16];
+            pool.scope(|scope| {
+                for (values, sum) in values.chunks(16).zip(&mut sums) {
+                    scope.run(Sum { values, sum });
+                }
+            });
+            assert_eq!(values.iter().sum::<u64>(), sums.iter().sum::<u64>());
+        }
+    }
+
+    #[test]
+    fn notify_before_wait() {
+        let wait = Wait::new(2);
+        wait.notify();
+        wait.notify();
+        // Doesn't block, the notifications already arrived
+        wait.wait();
+        assert_eq!(0, wait.count());
+        // And the Wait can be reused
+        wait.notify();
+        wait.set_limit(1);
         wait.wait();
-        assert_total(50);
     }
 }
@@ -0,0 +1,629 @@
+//! Execution traces: what every worker of an executor did during the last execute.
+//!
+//! When enabled (`Executor::set_tracing`), each worker records the systems it ran or skipped (with
+//! their phase, the label they were given in the schedule) and the sync points it waited on or
+//! notified, timed from the start of the execute. Workers only
+//! push to their own list, so the cost is a clock read per step. When disabled nothing is
+//! recorded and the only cost is a branch per step.
+//!
+//! Traces can be checked with the `assert_*` helpers (schedule tests), or exported as Chrome trace
+//! JSON (`to_chrome_json`) to be opened in `chrome://tracing` or Perfetto.
+
+use std::{
+    fmt::{self, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::{executor::SystemId, EcsError};
+
+/// Why a system didn't run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// One of its arguments couldn't be fetched, with `FetchPolicy::Skip`
+    Fetch(EcsError),
+    /// Its run condition was false, see `Scheduler::then_if`
+    Condition,
+    /// Not its turn, see `Scheduler::throttle`
+    Throttled,
+    /// Its label is disabled, see `Executor::set_label_enabled`
+    Disabled(&'static str),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fetch(error) => write!(f, "fetch failed ({error})"),
+            Self::Condition => write!(f, "run condition false"),
+            Self::Throttled => write!(f, "throttled"),
+            Self::Disabled(label) => write!(f, "label {label} disabled"),
+        }
+    }
+}
+
+/// Something a worker did, times are relative to the start of the execute
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    Run {
+        system: SystemId,
+        name: &'static str,
+        /// Label of the system in the schedule
+        phase: Option<&'static str>,
+        start: Duration,
+        end: Duration,
+    },
+    Skipped {
+        system: SystemId,
+        name: &'static str,
+        phase: Option<&'static str>,
+        at: Duration,
+        reason: SkipReason,
+    },
+    /// Waited on another worker, for the sync point `index` of the schedule
+    Wait {
+        index: usize,
+        start: Duration,
+        end: Duration,
+    },
+    /// Signaled the sync point `index` of the schedule
+    Notify { index: usize, at: Duration },
+    /// Ran the closures queued with `Executor::enqueue_external`, before any system (first event
+    /// of the first worker)
+    External {
+        count: usize,
+        start: Duration,
+        end: Duration,
+    },
+}
+
+impl TraceEvent {
+    /// Name of the system, for Run and Skipped
+    pub fn system_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Run { name, .. } | Self::Skipped { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+    /// Label of the system in the schedule, for Run and Skipped
+    pub fn phase(&self) -> Option<&'static str> {
+        match self {
+            Self::Run { phase, .. } | Self::Skipped { phase, .. } => *phase,
+            _ => None,
+        }
+    }
+    /// When the event started
+    pub fn start(&self) -> Duration {
+        match *self {
+            Self::Run { start, .. } | Self::Wait { start, .. } | Self::External { start, .. } => {
+                start
+            }
+            Self::Skipped { at, .. } | Self::Notify { at, .. } => at,
+        }
+    }
+}
+
+/// Whether a system name (a type name) matches `name`: the full path, or its last segments
+/// (`"update"` matches `"game::physics::update"`).
+fn name_matches(full: &str, name: &str) -> bool {
+    full == name
+        || full
+            .strip_suffix(name)
+            .is_some_and(|prefix| prefix.ends_with("::"))
+}
+
+/// The events of an execute, per worker (a single one for `execute_sequential`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionTrace {
+    workers: Vec<Vec<TraceEvent>>,
+}
+
+impl ExecutionTrace {
+    pub(crate) fn new(workers: Vec<Vec<TraceEvent>>) -> Self {
+        Self { workers }
+    }
+    /// Events of each worker, in the order they happened
+    pub fn workers(&self) -> &[Vec<TraceEvent>] {
+        &self.workers
+    }
+    /// All the events, with the index of their worker
+    pub fn events(&self) -> impl Iterator<Item = (usize, &TraceEvent)> {
+        self.workers
+            .iter()
+            .enumerate()
+            .flat_map(|(worker, events)| events.iter().map(move |event| (worker, event)))
+    }
+    /// First event of a system (see `assert_ran` for how names match)
+    fn find(&self, name: &str) -> Option<&TraceEvent> {
+        self.events()
+            .map(|(_, event)| event)
+            .filter(|event| event.system_name().is_some_and(|n| name_matches(n, name)))
+            .min_by_key(|event| event.start())
+    }
+    /// Names of the systems that ran, in the order they started
+    fn ran_names(&self) -> Vec<&'static str> {
+        let mut runs = self
+            .events()
+            .filter_map(|(_, event)| match event {
+                TraceEvent::Run { name, start, .. } => Some((*start, *name)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        runs.sort();
+        runs.into_iter().map(|(_, name)| name).collect()
+    }
+    /// Start and end of the first run of a system
+    fn run(&self, name: &str) -> Result<(Duration, Duration), String> {
+        match self.find(name) {
+            Some(&TraceEvent::Run { start, end, .. }) => Ok((start, end)),
+            Some(TraceEvent::Skipped { reason, .. }) => {
+                Err(format!("system `{name}` was skipped: {reason}"))
+            }
+            _ => Err(format!(
+                "system `{name}` didn't run (ran: {:?})",
+                self.ran_names()
+            )),
+        }
+    }
+    /// Whether a system ran
+    pub fn ran(&self, name: &str) -> bool {
+        self.run(name).is_ok()
+    }
+    /// Panic unless a system ran. Names match the full path of the system (its type name) or its
+    /// last segments, so a `fn update` in any module matches `"update"`.
+    #[track_caller]
+    pub fn assert_ran(&self, name: &str) {
+        if let Err(message) = self.run(name) {
+            panic!("{message}");
+        }
+    }
+    /// Panic unless both systems ran and `before` ended before `after` started
+    #[track_caller]
+    pub fn assert_ran_before(&self, before: &str, after: &str) {
+        let (a, b) = match (self.run(before), self.run(after)) {
+            (Ok(a), Ok(b)) => (a, b),
+            (Err(message), _) | (_, Err(message)) => panic!("{message}"),
+        };
+        if a.1 > b.0 {
+            panic!(
+                "system `{before}` didn't finish before `{after}` started ({before}: {:?}..{:?}, \
+                 {after}: {:?}..{:?})",
+                a.0, a.1, b.0, b.1
+            );
+        }
+    }
+    /// Panic unless a system was skipped for `reason`
+    #[track_caller]
+    pub fn assert_skipped(&self, name: &str, reason: SkipReason) {
+        match self.find(name) {
+            Some(TraceEvent::Skipped { reason: actual, .. }) if *actual == reason => {}
+            Some(TraceEvent::Skipped { reason: actual, .. }) => {
+                panic!("system `{name}` was skipped for {actual}, not {reason}")
+            }
+            Some(_) => panic!("system `{name}` ran, expected it to be skipped for {reason}"),
+            None => panic!("system `{name}` isn't in the trace"),
+        }
+    }
+    /// The trace in the Chrome trace event format. Systems and waits are complete events, skips
+    /// and notifies instant ones, with a thread per worker. The phase of a system and why it was
+    /// skipped are in its args.
+    pub fn to_chrome_json(&self) -> String {
+        let micros = |d: Duration| d.as_nanos() as f64 / 1000.0;
+        let mut out = String::from("{\"traceEvents\":[");
+        for (i, (worker, event)) in self.events().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let (name, cat, duration, reason) = match event {
+                TraceEvent::Run {
+                    name, start, end, ..
+                } => (name.to_string(), "system", Some(*end - *start), None),
+                TraceEvent::Skipped { name, reason, .. } => {
+                    (name.to_string(), "skipped", None, Some(reason.to_string()))
+                }
+                TraceEvent::Wait { index, start, end } => {
+                    (format!("wait {index}"), "sync", Some(*end - *start), None)
+                }
+                TraceEvent::Notify { index, .. } => (format!("notify {index}"), "sync", None, None),
+                TraceEvent::External { count, start, end } => (
+                    format!("{count} external"),
+                    "external",
+                    Some(*end - *start),
+                    None,
+                ),
+            };
+            let _ = write!(
+                out,
+                "{{\"name\":\"{}\",\"cat\":\"{cat}\",\"pid\":0,\"tid\":{worker},\"ts\":{:.3}",
+                escape(&name),
+                micros(event.start())
+            );
+            let _ = match duration {
+                Some(duration) => write!(out, ",\"ph\":\"X\",\"dur\":{:.3}", micros(duration)),
+                None => write!(out, ",\"ph\":\"i\",\"s\":\"t\""),
+            };
+            let args = [
+                ("phase", event.phase().map(str::to_owned)),
+                ("reason", reason),
+            ];
+            let args = args
+                .into_iter()
+                .filter_map(|(key, value)| Some(format!("\"{key}\":\"{}\"", escape(&value?))))
+                .collect::<Vec<_>>();
+            if !args.is_empty() {
+                let _ = write!(out, ",\"args\":{{{}}}", args.join(","));
+            }
+            out.push('}');
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+/// Escape a string for a JSON string literal
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Where the workers of a traced execute put their events
+pub(crate) struct TraceSink {
+    origin: Instant,
+    workers: Mutex<Vec<Vec<TraceEvent>>>,
+}
+
+impl TraceSink {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self {
+            origin: Instant::now(),
+            workers: Mutex::new(Vec::new()),
+        })
+    }
+    pub(crate) fn worker(self: &Arc<Self>, worker: usize) -> WorkerTrace {
+        WorkerTrace {
+            sink: self.clone(),
+            worker,
+            events: Vec::new(),
+        }
+    }
+    /// The trace, once all the workers are done
+    pub(crate) fn finish(&self) -> ExecutionTrace {
+        ExecutionTrace::new(std::mem::take(&mut *self.workers.lock()))
+    }
+}
+
+/// Events of a single worker, given to the sink when done
+pub(crate) struct WorkerTrace {
+    sink: Arc<TraceSink>,
+    worker: usize,
+    events: Vec<TraceEvent>,
+}
+
+impl WorkerTrace {
+    pub(crate) fn now(&self) -> Duration {
+        self.sink.origin.elapsed()
+    }
+    pub(crate) fn push(&mut self, event: TraceEvent) {
+        self.events.push(event);
+    }
+    /// Record a system that started at `start`, and the result of its run
+    pub(crate) fn system(
+        &mut self,
+        system: SystemId,
+        name: &'static str,
+        phase: Option<&'static str>,
+        start: Duration,
+        result: Result<(), SkipReason>,
+    ) {
+        let event = match result {
+            Ok(()) => TraceEvent::Run {
+                system,
+                name,
+                phase,
+                start,
+                end: self.now(),
+            },
+            Err(reason) => TraceEvent::Skipped {
+                system,
+                name,
+                phase,
+                at: start,
+                reason,
+            },
+        };
+        self.push(event);
+    }
+    pub(crate) fn finish(self) {
+        let mut workers = self.sink.workers.lock();
+        if workers.len() <= self.worker {
+            workers.resize(self.worker + 1, Vec::new());
+        }
+        workers[self.worker] = self.events;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use super::*;
+    use crate::{Executor, FetchPolicy, World};
+
+    fn first(a: &mut u8) {
+        *a += 1;
+    }
+    fn second(b: &mut u16) {
+        *b += 1;
+    }
+    fn third(a: &u8, b: &mut u16) {
+        *b += *a as u16;
+    }
+    fn missing(_: &u32) {}
+
+    #[test]
+    fn traced_execute() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(0u8);
+        exe.add_resource(0u16);
+        exe.set_fetch_policy(FetchPolicy::Skip);
+        let schedule = exe
+            .schedule()
+            .then(first)
+            .then(second)
+            .then(third)
+            .then(missing)
+            .build();
+        assert!(schedule.threads.len() > 1);
+
+        exe.execute(&schedule, &mut world);
+        assert!(exe.last_trace().is_none());
+
+        exe.set_tracing(true);
+        for _ in 0..20 {
+            exe.execute(&schedule, &mut world);
+            let trace = exe.last_trace().unwrap();
+            assert_eq!(schedule.threads.len(), trace.workers().len());
+            trace.assert_ran_before("first", "third");
+            trace.assert_ran_before("second", "third");
+            trace.assert_ran("ecs::trace::tests::second");
+            trace.assert_skipped(
+                "missing",
+                SkipReason::Fetch(EcsError::MissingResource { type_name: "u32" }),
+            );
+            // Every sync point is notified once and waited on once
+            for kind in [0, 1] {
+                let count = trace
+                    .events()
+                    .filter(|(_, event)| match event {
+                        TraceEvent::Notify { .. } => kind == 0,
+                        TraceEvent::Wait { .. } => kind == 1,
+                        _ => false,
+                    })
+                    .count();
+                assert_eq!(schedule.waits.len(), count);
+            }
+        }
+
+        exe.execute_sequential(&schedule, &mut world);
+        let trace = exe.last_trace().unwrap();
+        assert_eq!(1, trace.workers().len());
+        let order = trace.workers()[0]
+            .iter()
+            .map(|event| event.system_name().unwrap())
+            .collect::<Vec<_>>();
+        let names = ["first", "second", "third", "missing"];
+        assert_eq!(
+            names.map(|n| format!("ecs::trace::tests::{n}")).to_vec(),
+            order
+        );
+
+        exe.set_tracing(false);
+        assert!(exe.last_trace().is_none());
+    }
+
+    #[test]
+    fn gates() {
+        let mut exe = Executor::new();
+        let mut world = World::new();
+        exe.add_resource(0u8);
+        exe.add_resource(0u16);
+        let schedule = exe
+            .schedule()
+            .then_labeled(first, "input")
+            .then(second)
+            .throttle(3)
+            .then_labeled(third, "simulation")
+            .build();
+        assert!(schedule.threads.len() > 1);
+        exe.set_tracing(true);
+        for execute in 0..6 {
+            if execute == 4 {
+                exe.set_label_enabled("simulation", false);
+            }
+            exe.execute(&schedule, &mut world);
+            let trace = exe.last_trace().unwrap();
+            match execute % 3 {
+                0 => trace.assert_ran("second"),
+                _ => trace.assert_skipped("second", SkipReason::Throttled),
+            }
+            match execute {
+                4.. => trace.assert_skipped("third", SkipReason::Disabled("simulation")),
+                _ => trace.assert_ran_before("first", "third"),
+            }
+            let phases = trace
+                .events()
+                .filter_map(|(_, event)| Some((event.system_name()?, event.phase())))
+                .map(|(name, phase)| (name.rsplit("::").next().unwrap(), phase))
+                .collect::<std::collections::HashMap<_, _>>();
+            assert_eq!(Some("input"), phases["first"]);
+            assert_eq!(None, phases["second"]);
+            assert_eq!(Some("simulation"), phases["third"]);
+        }
+        // second on executes 0 and 3, third on the first four (a from 1 to 4)
+        assert_eq!(6, *exe.get_resource::<u8>().unwrap());
+        assert_eq!(2 + 10, *exe.get_resource::<u16>().unwrap());
+
+        exe.set_label_enabled("simulation", true);
+        exe.execute_sequential(&schedule, &mut world);
+        let trace = exe.last_trace().unwrap();
+        trace.assert_ran("third");
+        trace.assert_ran("second");
+    }
+
+    fn sample() -> ExecutionTrace {
+        let ms = Duration::from_millis;
+        let system = SystemId::default();
+        ExecutionTrace::new(vec![
+            vec![
+                TraceEvent::Run {
+                    system,
+                    name: "game::input",
+                    phase: Some("input"),
+                    start: ms(0),
+                    end: ms(2),
+                },
+                TraceEvent::Notify {
+                    index: 0,
+                    at: ms(2),
+                },
+                TraceEvent::Skipped {
+                    system,
+                    name: "game::audio",
+                    phase: None,
+                    at: ms(3),
+                    reason: SkipReason::Fetch(EcsError::MissingResource { type_name: "Mixer" }),
+                },
+            ],
+            vec![
+                TraceEvent::Run {
+                    system,
+                    name: "game::\"physics\"",
+                    phase: Some("simulation"),
+                    start: ms(1),
+                    end: ms(4),
+                },
+                TraceEvent::Wait {
+                    index: 0,
+                    start: ms(4),
+                    end: ms(5),
+                },
+                TraceEvent::Run {
+                    system,
+                    name: "game::render",
+                    phase: None,
+                    start: ms(5),
+                    end: ms(9),
+                },
+            ],
+        ])
+    }
+
+    fn panic_message(f: impl FnOnce()) -> String {
+        let error = catch_unwind(AssertUnwindSafe(f)).unwrap_err();
+        match error.downcast::<String>() {
+            Ok(message) => *message,
+            Err(error) => error.downcast::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn assertions() {
+        let trace = sample();
+        trace.assert_ran("input");
+        trace.assert_ran("game::render");
+        trace.assert_ran_before("input", "render");
+        trace.assert_skipped(
+            "audio",
+            SkipReason::Fetch(EcsError::MissingResource { type_name: "Mixer" }),
+        );
+        assert!(!trace.ran("put"));
+        assert!(!trace.ran("audio"));
+
+        assert_eq!(
+            "system `ui` didn't run (ran: [\"game::input\", \"game::\\\"physics\\\"\", \
+             \"game::render\"])",
+            panic_message(|| trace.assert_ran("ui"))
+        );
+        assert_eq!(
+            "system `audio` was skipped: fetch failed (Resource not in system: Mixer)",
+            panic_message(|| trace.assert_ran_before("input", "audio"))
+        );
+        assert_eq!(
+            "system `input` didn't finish before `\"physics\"` started (input: 0ns..2ms, \
+             \"physics\": 1ms..4ms)",
+            panic_message(|| trace.assert_ran_before("input", "\"physics\""))
+        );
+        assert_eq!(
+            "system `audio` was skipped for fetch failed (Resource not in system: Mixer), not \
+             fetch failed (Resource not in system: Bus)",
+            panic_message(|| trace.assert_skipped(
+                "audio",
+                SkipReason::Fetch(EcsError::MissingResource { type_name: "Bus" })
+            ))
+        );
+        assert_eq!(
+            "system `render` ran, expected it to be skipped for fetch failed (Resource not in \
+             system: Bus)",
+            panic_message(|| trace.assert_skipped(
+                "render",
+                SkipReason::Fetch(EcsError::MissingResource { type_name: "Bus" })
+            ))
+        );
+    }
+
+    #[test]
+    fn chrome_json() {
+        let trace = sample();
+        let json: serde_json::Value = serde_json::from_str(&trace.to_chrome_json()).unwrap();
+        let events = json["traceEvents"].as_array().unwrap();
+        assert_eq!(6, events.len());
+        for ((worker, event), json) in trace.events().zip(events) {
+            assert_eq!(worker as u64, json["tid"].as_u64().unwrap());
+            let ts = json["ts"].as_f64().unwrap();
+            assert_eq!(event.start().as_micros() as f64, ts);
+            let name = json["name"].as_str().unwrap();
+            match event {
+                TraceEvent::Run {
+                    name: n,
+                    start,
+                    end,
+                    ..
+                } => {
+                    assert_eq!(*n, name);
+                    assert_eq!("X", json["ph"]);
+                    let dur = json["dur"].as_f64().unwrap();
+                    assert_eq!((*end - *start).as_micros() as f64, dur);
+                }
+                TraceEvent::Skipped {
+                    name: n, reason, ..
+                } => {
+                    assert_eq!(*n, name);
+                    assert_eq!("i", json["ph"]);
+                    assert_eq!(reason.to_string(), json["args"]["reason"]);
+                }
+                TraceEvent::Wait { index, .. } => assert_eq!(format!("wait {index}"), name),
+                TraceEvent::Notify { index, .. } => assert_eq!(format!("notify {index}"), name),
+                TraceEvent::External { count, .. } => assert_eq!(format!("{count} external"), name),
+            }
+        }
+        assert_eq!("game::\"physics\"", events[3]["name"]);
+        assert_eq!("simulation", events[3]["args"]["phase"]);
+        assert!(events[5].get("args").is_none());
+        assert_eq!(
+            "{\"traceEvents\":[]}",
+            ExecutionTrace::default().to_chrome_json()
+        );
+    }
+}
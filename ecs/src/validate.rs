@@ -0,0 +1,270 @@
+//! Checks of component values, to catch corrupted data (NaN transforms, dangling entities) right
+//! after the schedule that produced it instead of wherever it ends up breaking something.
+//!
+//! Validators are registered on the world (`World::register_validator`) and run by `World::validate`,
+//! the executor runs them after each execute in debug builds (see
+//! `Executor::set_validate_after_execute`).
+
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{archetype::Component, entity::Entity, World};
+
+/// Executes between two logs of a failure that persists
+pub(crate) const LOG_INTERVAL: u64 = 300;
+/// Failures logged at most per execute, the others are counted
+pub(crate) const LOG_LIMIT: usize = 16;
+
+/// A component that didn't pass a validator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationFailure {
+    pub entity: Entity,
+    pub type_name: &'static str,
+    pub message: String,
+}
+
+impl Display for ValidationFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {:?}: {}",
+            self.type_name, self.entity, self.message
+        )
+    }
+}
+
+/// A component holding entities, which should all be alive (see
+/// `World::register_entity_validator`)
+pub trait ReferencesEntities {
+    fn entities(&self) -> impl Iterator<Item = Entity> + '_;
+}
+
+/// Validator of components only made of floats (glam vectors, matrices and quaternions, or
+/// `repr(C)` structs of those), failing on NaN and infinities. Other fields would be read as
+/// floats too.
+pub fn finite<T: bytemuck::Pod>(value: &T, _entity: Entity) -> Result<(), String> {
+    let bytes = bytemuck::bytes_of(value);
+    match bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_ne_bytes(chunk.try_into().unwrap()))
+        .position(|float| !float.is_finite())
+    {
+        Some(index) => Err(format!(
+            "float {index} is {}",
+            f32::from_ne_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap())
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Checks every component of a type, pushing its failures
+pub(crate) type Validator = Box<dyn Fn(&World, &mut Vec<ValidationFailure>) + Send + Sync>;
+
+fn validator<T: Component>(
+    check: impl Fn(&World, Entity, &T) -> Result<(), String> + Send + Sync + 'static,
+) -> Validator {
+    let type_name = std::any::type_name::<T>();
+    Box::new(move |world, failures| {
+        let Ok(query) = world.try_query::<(Entity, &T)>() else {
+            log::warn!("Couldn't validate {type_name}, it is borrowed");
+            return;
+        };
+        for (entity, value) in query {
+            if let Err(message) = check(world, entity, value) {
+                failures.push(ValidationFailure {
+                    entity,
+                    type_name,
+                    message,
+                });
+            }
+        }
+    })
+}
+
+pub(crate) fn component_validator<T: Component>(
+    check: fn(&T, Entity) -> Result<(), String>,
+) -> Validator {
+    validator(move |_, entity, value| check(value, entity))
+}
+
+pub(crate) fn liveness_validator<T: Component + ReferencesEntities>() -> Validator {
+    validator::<T>(|world, _, value| {
+        let dead = value
+            .entities()
+            .filter(|&entity| !world.contains(entity))
+            .map(|entity| format!("{entity:?}"))
+            .collect::<Vec<_>>();
+        if dead.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("references dead entities {}", dead.join(", ")))
+        }
+    })
+}
+
+/// Decides which failures get logged: a failure (of an entity's component) is logged when it
+/// appears, then every `LOG_INTERVAL` executes while it persists.
+#[derive(Default)]
+pub(crate) struct ValidationLog {
+    executes: u64,
+    /// Execute a persisting failure was last logged at
+    logged: HashMap<(Entity, &'static str), u64>,
+}
+
+impl ValidationLog {
+    pub fn filter<'a>(&mut self, failures: &'a [ValidationFailure]) -> Vec<&'a ValidationFailure> {
+        self.executes += 1;
+        let executes = self.executes;
+        let mut logged = HashMap::with_capacity(failures.len());
+        let mut shown = Vec::new();
+        for failure in failures {
+            let key = (failure.entity, failure.type_name);
+            let last = match self.logged.get(&key) {
+                Some(&last) if executes - last < LOG_INTERVAL => last,
+                _ => {
+                    shown.push(failure);
+                    executes
+                }
+            };
+            logged.insert(key, last);
+        }
+        // Failures that went away are logged again if they come back
+        self.logged = logged;
+        shown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{Entities, Executor};
+
+    use super::*;
+
+    struct Health(f32);
+
+    struct Target(Option<Entity>);
+
+    impl ReferencesEntities for Target {
+        fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+            self.0.into_iter()
+        }
+    }
+
+    fn positive(health: &Health, _: Entity) -> Result<(), String> {
+        if health.0 >= 0.0 {
+            Ok(())
+        } else {
+            Err(format!("negative health {}", health.0))
+        }
+    }
+
+    #[test]
+    fn planted_nan() {
+        let mut world = World::new();
+        world.register_validator::<[f32; 3]>(finite);
+        world.register_validator(positive);
+        world.spawn(([0.0f32, 1.0, 2.0], Health(1.0)));
+        assert!(world.validate().is_empty());
+
+        let bad = world.spawn(([0.0f32, f32::NAN, 2.0], Health(-1.0)));
+        let mut failures = world.validate();
+        failures.sort_by_key(|failure| failure.type_name);
+        assert_eq!(
+            vec![
+                ValidationFailure {
+                    entity: bad,
+                    type_name: std::any::type_name::<[f32; 3]>(),
+                    message: "float 1 is NaN".to_owned(),
+                },
+                ValidationFailure {
+                    entity: bad,
+                    type_name: std::any::type_name::<Health>(),
+                    message: "negative health -1".to_owned(),
+                },
+            ],
+            failures
+        );
+    }
+
+    #[test]
+    fn dangling_entity() {
+        let mut world = World::new();
+        world.register_entity_validator::<Target>();
+        let target = world.spawn((1u32,));
+        let follower = world.spawn((Target(Some(target)),));
+        world.spawn((Target(None),));
+        assert!(world.validate().is_empty());
+
+        world.remove(target);
+        let failures = world.validate();
+        assert_eq!(1, failures.len());
+        assert_eq!(follower, failures[0].entity);
+        assert_eq!(
+            format!("references dead entities {target:?}"),
+            failures[0].message
+        );
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut world = World::new();
+        let a = world.spawn((Health(-1.0),));
+        let b = world.spawn((Health(-2.0),));
+        let failure = |entity| ValidationFailure {
+            entity,
+            type_name: "Health",
+            message: String::new(),
+        };
+        let both = [failure(a), failure(b)];
+
+        let mut log = ValidationLog::default();
+        assert_eq!(2, log.filter(&both).len());
+        for _ in 1..LOG_INTERVAL {
+            assert!(log.filter(&both).is_empty());
+        }
+        // Still failing, logged again
+        assert_eq!(2, log.filter(&both).len());
+        // b goes away and comes back, it is new again while a is still limited
+        assert!(log.filter(&both[..1]).is_empty());
+        assert_eq!(vec![&both[1]], log.filter(&both));
+    }
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn counted(_: &Health, _: Entity) -> Result<(), String> {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn hurt(healths: Entities<&mut Health>) {
+        for health in healths {
+            health.0 -= 1.0;
+        }
+    }
+
+    #[test]
+    fn after_execute() {
+        let mut world = World::new();
+        world.register_validator(counted);
+        world.register_validator(positive);
+        world.spawn((Health(0.5),));
+
+        let mut exe = Executor::new();
+        let schedule = exe.schedule().then(hurt).build();
+        exe.set_validate_after_execute(false);
+        exe.execute(&schedule, &mut world);
+        exe.execute_sequential(&schedule, &mut world);
+        // Disabled, nothing iterated
+        assert_eq!(0, CALLS.load(Ordering::Relaxed));
+        assert!(exe.last_validation().is_empty());
+
+        exe.set_validate_after_execute(true);
+        exe.execute(&schedule, &mut world);
+        assert_eq!(1, CALLS.load(Ordering::Relaxed));
+        assert_eq!(1, exe.last_validation().len());
+        assert_eq!("negative health -2.5", exe.last_validation()[0].message);
+        exe.execute_sequential(&schedule, &mut world);
+        assert_eq!(2, CALLS.load(Ordering::Relaxed));
+    }
+}
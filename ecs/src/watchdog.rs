@@ -0,0 +1,339 @@
+//! Watchdog for systems running over a time budget.
+//!
+//! When enabled (`Executor::set_system_time_budget`), every worker of the executor stores the
+//! start time and id of the system it runs in a slot (a single atomic store when a system starts
+//! and when it ends), and a monitor thread checks the slots a few times per budget. A system over
+//! budget is logged with its name and how long it has been running, then again after 2, 4, 8...
+//! budgets. std can't capture the backtrace of another thread, so only the worker index is given
+//! to find it in a debugger.
+//!
+//! Long running systems can also take a `Budget` argument to stop by themselves once their part of
+//! the budget is spent.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use parking_lot::RwLock;
+use slotmap::Key;
+
+use crate::{
+    executor::{ExecutionContext, SystemId},
+    system::{RequirementsBuilder, RequirementsMappings, SystemArgument},
+};
+
+/// Source of time of the watchdog, mocked in tests
+pub(crate) trait Clock: Send + Sync {
+    /// Time elapsed since an arbitrary origin
+    fn now(&self) -> Duration;
+}
+
+struct SystemClock(Instant);
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+/// Bits of a slot holding the system index, the rest is the start time in milliseconds
+const INDEX_BITS: u32 = 24;
+
+fn system_index(id: SystemId) -> u64 {
+    id.data().as_ffi() & ((1 << INDEX_BITS) - 1)
+}
+
+/// What a worker is running: 0 when idle, otherwise the start time and index of the system
+#[derive(Default)]
+pub(crate) struct Slot(AtomicU64);
+
+impl Slot {
+    #[inline]
+    pub(crate) fn start(&self, now: Duration, id: SystemId) {
+        let millis = now.as_millis() as u64;
+        // Index 0 is reserved for idle slots
+        let value = (millis << INDEX_BITS) | (system_index(id) + 1);
+        self.0.store(value, Ordering::Relaxed);
+    }
+    #[inline]
+    pub(crate) fn end(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+/// State shared by the executor, its workers and the monitor thread
+pub(crate) struct Watchdog {
+    pub(crate) budget: Duration,
+    pub(crate) clock: Arc<dyn Clock>,
+    slots: RwLock<Vec<Arc<Slot>>>,
+    names: RwLock<HashMap<u64, &'static str>>,
+}
+
+/// A system that went over budget
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Violation {
+    pub(crate) system: &'static str,
+    pub(crate) worker: usize,
+    pub(crate) running: Duration,
+}
+
+impl Watchdog {
+    pub(crate) fn new(budget: Duration) -> Self {
+        Self::with_clock(budget, Arc::new(SystemClock(Instant::now())))
+    }
+    pub(crate) fn with_clock(budget: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            budget,
+            clock,
+            slots: RwLock::new(Vec::new()),
+            names: RwLock::new(HashMap::new()),
+        }
+    }
+    /// Start the monitor thread, it stops once the watchdog is dropped
+    pub(crate) fn spawn_monitor(self: &Arc<Self>) {
+        let watchdog = Arc::downgrade(self);
+        let period = (self.budget / 4).clamp(Duration::from_millis(1), Duration::from_millis(100));
+        thread::Builder::new()
+            .name("ecs watchdog".to_owned())
+            .spawn(move || monitor(watchdog, period))
+            .expect("Couldn't spawn the watchdog thread");
+    }
+    pub(crate) fn name_system(&self, id: SystemId, name: &'static str) {
+        self.names.write().insert(system_index(id), name);
+    }
+    /// The slot of a worker
+    pub(crate) fn slot(&self, worker: usize) -> Arc<Slot> {
+        let mut slots = self.slots.write();
+        if slots.len() <= worker {
+            slots.resize_with(worker + 1, Default::default);
+        }
+        slots[worker].clone()
+    }
+}
+
+/// Checks the slots of a watchdog, remembering what was already reported
+#[derive(Default)]
+pub(crate) struct Monitor {
+    /// For each slot, the value it had and when to report it next
+    reports: Vec<Option<(u64, Duration)>>,
+}
+
+impl Monitor {
+    pub(crate) fn check(&mut self, watchdog: &Watchdog) -> Vec<Violation> {
+        let now = watchdog.clock.now();
+        let slots = watchdog.slots.read();
+        self.reports.resize(slots.len(), None);
+        let mut violations = Vec::new();
+        for (worker, (slot, report)) in slots.iter().zip(&mut self.reports).enumerate() {
+            let value = slot.0.load(Ordering::Relaxed);
+            if value == 0 {
+                *report = None;
+                continue;
+            }
+            let (reported, next) = report.get_or_insert((value, watchdog.budget));
+            if *reported != value {
+                // Another system started since
+                *reported = value;
+                *next = watchdog.budget;
+            }
+            let start = Duration::from_millis(value >> INDEX_BITS);
+            let running = now.saturating_sub(start);
+            if running >= *next {
+                *next = next.saturating_mul(2);
+                let index = (value & ((1 << INDEX_BITS) - 1)) - 1;
+                violations.push(Violation {
+                    system: watchdog
+                        .names
+                        .read()
+                        .get(&index)
+                        .copied()
+                        .unwrap_or("<unknown>"),
+                    worker,
+                    running,
+                });
+            }
+        }
+        violations
+    }
+}
+
+fn monitor(watchdog: Weak<Watchdog>, period: Duration) {
+    let mut monitor = Monitor::default();
+    loop {
+        thread::sleep(period);
+        let watchdog = match watchdog.upgrade() {
+            Some(watchdog) => watchdog,
+            None => return,
+        };
+        for violation in monitor.check(&watchdog) {
+            log::warn!(
+                "System {} has been running for {:?} on worker {} (budget: {:?})",
+                violation.system,
+                violation.running,
+                violation.worker,
+                watchdog.budget
+            );
+        }
+    }
+}
+
+/// System argument telling a long running system when it should stop and resume next frame. The
+/// deadline is the start of the system plus the executor's system time budget, without a budget it
+/// never has to yield.
+pub struct Budget {
+    deadline: Option<(Duration, Arc<dyn Clock>)>,
+}
+
+impl Budget {
+    /// Whether the system is past its deadline
+    pub fn should_yield(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+    /// Time left before the deadline, None if there is no budget
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .as_ref()
+            .map(|(deadline, clock)| deadline.saturating_sub(clock.now()))
+    }
+}
+
+impl SystemArgument for Budget {
+    fn register(_: &mut RequirementsMappings) {}
+    fn require(builder: RequirementsBuilder) -> RequirementsBuilder {
+        builder
+    }
+    unsafe fn fetch(context: &ExecutionContext) -> Self {
+        Self {
+            deadline: context
+                .executor
+                .watchdog()
+                .map(|w| (w.clock.now() + w.budget, w.clock.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        sync::Mutex,
+    };
+
+    use super::*;
+    use crate::{Executor, World};
+
+    #[derive(Default)]
+    struct MockClock(Mutex<Duration>);
+
+    impl MockClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Duration {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn watchdog() -> (Arc<MockClock>, Watchdog, SystemId) {
+        let clock = Arc::new(MockClock::default());
+        let watchdog = Watchdog::with_clock(ms(10), clock.clone());
+        let mut executor = Executor::new();
+        let id = executor.add_system(|| {});
+        watchdog.name_system(id, "slow");
+        (clock, watchdog, id)
+    }
+
+    #[test]
+    fn violations() {
+        let (clock, watchdog, id) = watchdog();
+        let mut monitor = Monitor::default();
+        let slot = watchdog.slot(1);
+        clock.advance(ms(5));
+        slot.start(clock.now(), id);
+        let mut reports = Vec::new();
+        for _ in 0..100 {
+            clock.advance(ms(1));
+            for violation in monitor.check(&watchdog) {
+                assert_eq!("slow", violation.system);
+                assert_eq!(1, violation.worker);
+                reports.push(violation.running.as_millis());
+            }
+        }
+        // Reported at 1, 2, 4 and 8 budgets
+        assert_eq!(vec![10, 20, 40, 80], reports);
+
+        // A new system starts over
+        slot.end();
+        assert!(monitor.check(&watchdog).is_empty());
+        slot.start(clock.now(), id);
+        clock.advance(ms(9));
+        assert!(monitor.check(&watchdog).is_empty());
+        clock.advance(ms(1));
+        assert_eq!(1, monitor.check(&watchdog).len());
+    }
+
+    #[test]
+    fn budget() {
+        let clock = Arc::new(MockClock::default());
+        let budget = Budget {
+            deadline: Some((ms(10), clock.clone())),
+        };
+        assert_eq!(Some(ms(10)), budget.remaining());
+        clock.advance(ms(9));
+        assert!(!budget.should_yield());
+        clock.advance(ms(1));
+        assert!(budget.should_yield());
+        clock.advance(ms(1));
+        assert!(budget.should_yield());
+        assert!(!Budget { deadline: None }.should_yield());
+    }
+
+    #[test]
+    fn executor() {
+        static YIELDS: AtomicUsize = AtomicUsize::new(0);
+        let mut executor = Executor::new();
+        let mut world = World::new();
+        let schedule = executor
+            .schedule()
+            .then(|budget: Budget| {
+                assert!(budget.remaining().is_none());
+            })
+            .build();
+        // Off: nothing is recorded
+        executor.execute(&schedule, &mut world);
+        assert!(executor.watchdog().is_none());
+
+        executor.set_system_time_budget(Some(ms(1)));
+        let schedule = executor
+            .schedule()
+            .then(|budget: Budget| {
+                while !budget.should_yield() {
+                    std::hint::spin_loop();
+                }
+                YIELDS.fetch_add(1, Ordering::SeqCst);
+            })
+            .build();
+        executor.execute(&schedule, &mut world);
+        assert_eq!(1, YIELDS.load(Ordering::SeqCst));
+        let watchdog = executor.watchdog().unwrap();
+        // The slot was cleared when the system ended
+        assert!(Monitor::default().check(watchdog).is_empty());
+        assert_eq!(1, watchdog.slots.read().len());
+
+        executor.set_system_time_budget(None);
+        assert!(executor.watchdog().is_none());
+    }
+}
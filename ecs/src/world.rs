@@ -1,18 +1,55 @@
-use std::{any::TypeId, mem::MaybeUninit};
+use std::{
+    any::{type_name, TypeId},
+    mem::{size_of, MaybeUninit},
+    ops::Range,
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
 
 use crate::{
-    archetype::{ArchetypeStorage, IntoArchetype},
-    bitset::{ArchetypeBitset, BitsetMapping, BorrowBitset},
+    archetype::{Archetype, ArchetypeStorage, Component, ComponentKey, IntoArchetype, RowTicks},
+    bitset::{ArchetypeBitset, ArchetypeBitsetBuilder, BitsetBuilder, BitsetMapping, BorrowBitset},
     borrows::{BorrowGuard, Borrows},
-    entity::{Entity, LocationMap},
+    dynamic::DynamicRegistry,
+    entity::{Entity, EntityIndex, LocationMap},
+    pool::{PoolStats, SharedPool, StoragePool},
     query::{Query, QueryIterBundle},
+    relation::RelationIndex,
+    replication::ComponentRegistry,
+    thread_pool::FnJob,
+    validate::{self, ReferencesEntities, ValidationFailure, Validator},
+    EcsError, Executor,
 };
 
+static WORLD_IDS: AtomicU64 = AtomicU64::new(0);
+
 pub struct World {
-    mapping: BitsetMapping<TypeId>,
+    /// Tells worlds apart, see `Schedule`
+    id: u64,
+    mapping: BitsetMapping<ComponentKey>,
     archetypes: Vec<(ArchetypeStorage, ArchetypeBitset)>,
     borrows: Borrows,
     location_map: LocationMap,
+    tick: AtomicU32,
+    /// Memory of the archetype storages
+    pool: SharedPool,
+    pub(crate) relations: RelationIndex,
+    validators: Vec<Validator>,
+    /// Components included in snapshots, and in diffs made with `components`
+    pub(crate) components: ComponentRegistry,
+    /// Components registered with `register_dynamic`
+    pub(crate) dynamic: DynamicRegistry,
+}
+
+/// Entity and archetype counts of a world
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorldStats {
+    pub entities: usize,
+    pub archetypes: usize,
+    /// Archetypes without any entity left
+    pub empty_archetypes: usize,
+    /// Memory pool of the storages
+    pub pool: PoolStats,
 }
 
 // This needs to move, a utils mod maybe ?
@@ -37,7 +74,7 @@ where
         for (i, index) in indices.iter().enumerate().take(N) {
             (res.as_mut_ptr() as *mut Option<&mut T>)
                 .add(i)
-                .write((*s).get_mut(*index));
+                .write((&mut *s).get_mut(*index));
         }
         res.assume_init()
     }
@@ -59,13 +96,73 @@ impl World {
     /// Hello World
     pub fn new() -> Self {
         Self {
+            id: WORLD_IDS.fetch_add(1, Ordering::Relaxed),
             mapping: BitsetMapping::new(),
             borrows: Borrows::new(),
             archetypes: Vec::with_capacity(8),
             location_map: LocationMap::new(),
+            tick: AtomicU32::new(1),
+            pool: StoragePool::shared(),
+            relations: RelationIndex::default(),
+            validators: Vec::new(),
+            components: ComponentRegistry::default(),
+            dynamic: DynamicRegistry::default(),
         }
     }
-    fn register_component_if_needed(&mut self, id: TypeId) {
+    /// The current tick of the world. Spawns, component additions and removals, and mutable
+    /// queries mark the entities they touch with it (see `diff_since`), it only moves forward with
+    /// `advance_tick` and when the executor runs a system.
+    pub fn tick(&self) -> u32 {
+        self.tick.load(Ordering::Relaxed)
+    }
+    /// Start a new tick and return it, typically once per frame or network update
+    pub fn advance_tick(&mut self) -> u32 {
+        *self.tick.get_mut() += 1;
+        *self.tick.get_mut()
+    }
+    /// Take the current tick for a system run and start a new one. Each run gets its own, and
+    /// changes made outside systems come after the runs before them, so that `Changed` sees the
+    /// changes made after the previous run of a system exactly once.
+    pub(crate) fn take_tick(&self) -> u32 {
+        self.tick.fetch_add(1, Ordering::Relaxed)
+    }
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+    /// The archetypes of the world, in the order they were added. They are never removed, so new
+    /// ones are always at the end.
+    pub(crate) fn archetypes(&self) -> impl Iterator<Item = &Archetype> {
+        self.archetypes
+            .iter()
+            .map(|(storage, _)| storage.archetype())
+    }
+    /// Set how many despawns are remembered for diffs (1024 by default), a diff whose baseline is
+    /// older than the oldest despawn remembered is marked as truncated.
+    pub fn set_despawn_history(&mut self, capacity: usize) {
+        self.location_map.set_despawn_capacity(capacity);
+    }
+    /// The components registered in this world (`register_serde`), for snapshots and diffs
+    pub fn components(&self) -> &ComponentRegistry {
+        &self.components
+    }
+    /// Register components with their own (de)serialization functions, see `ComponentRegistry`
+    pub fn components_mut(&mut self) -> &mut ComponentRegistry {
+        &mut self.components
+    }
+    /// Mark the last count entities of a storage as just spawned
+    fn mark_spawned(&mut self, archetype: usize, count: usize) {
+        let tick = self.tick();
+        let storage = &mut self.archetypes[archetype].0;
+        let len = storage.len();
+        storage.set_ticks(
+            len - count..len,
+            RowTicks {
+                added: tick,
+                changed: tick,
+            },
+        );
+    }
+    fn register_component_if_needed(&mut self, id: ComponentKey) {
         let mapping = &mut self.mapping;
         if !mapping.has(&id) {
             mapping.map(id);
@@ -75,10 +172,10 @@ impl World {
     fn add_archetype<T: IntoArchetype>(&mut self) -> &mut ArchetypeStorage {
         let index = self.archetypes.len();
         for t in T::types() {
-            self.register_component_if_needed(t);
+            self.register_component_if_needed(t.into());
         }
         let set = T::bitset(&self.mapping).unwrap();
-        let ats = ArchetypeStorage::new::<T>();
+        let ats = ArchetypeStorage::new::<T>().in_pool(self.pool.clone());
         self.archetypes.push((ats, set));
         &mut self.archetypes[index].0
     }
@@ -92,14 +189,49 @@ impl World {
         {
             Some((i, (storage, _))) => {
                 storage.push(entity);
+                self.mark_spawned(i, 1);
                 self.location_map.add_single(i)
             }
             None => {
                 self.add_archetype::<T>().push(entity);
+                self.mark_spawned(self.archetypes.len() - 1, 1);
                 self.location_map.add_single(self.archetypes.len() - 1)
             }
         };
         log::debug!("Spawned {e:?}!");
+        self.relations_added(e, &T::types());
+        e
+    }
+    /// Spawn an entity from the bytes of its components, of the given archetype
+    pub(crate) fn spawn_raw(
+        &mut self,
+        components: &[(ComponentKey, &[u8])],
+        archetype: Archetype,
+    ) -> Entity {
+        for &(id, _) in components {
+            self.register_component_if_needed(id);
+        }
+        let set = components
+            .iter()
+            .fold(
+                ArchetypeBitsetBuilder::start(&self.mapping),
+                |builder, &(id, _)| builder.add_key(id),
+            )
+            .build()
+            .unwrap();
+        let index = match self.archetypes.iter().position(|(_, aset)| *aset == set) {
+            Some(i) => i,
+            None => {
+                let ats =
+                    ArchetypeStorage::new_from_archetype(archetype).in_pool(self.pool.clone());
+                self.archetypes.push((ats, set));
+                self.archetypes.len() - 1
+            }
+        };
+        unsafe { self.archetypes[index].0.push_raw(components) };
+        self.mark_spawned(index, 1);
+        let e = self.location_map.add_single(index);
+        log::debug!("Spawned {e:?}!");
         e
     }
     /// Spawn many entities in the world
@@ -107,76 +239,167 @@ impl World {
         &mut self,
         entities: impl IntoIterator<Item = T>,
     ) -> Vec<Entity> {
-        let res = match self
+        self.spawn_with::<T>(|storage| storage.extend(entities))
+    }
+    /// Spawn many entities from an iterator of known length, faster than `spawn_many` for large
+    /// batches: the storage is grown once and filled without checking its capacity
+    pub fn spawn_batch<T: IntoArchetype, I>(&mut self, entities: I) -> Vec<Entity>
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        self.spawn_with::<T>(|storage| storage.extend_exact(entities.into_iter()))
+    }
+    /// Spawn the entities fill pushes to the storage of T
+    fn spawn_with<T: IntoArchetype>(
+        &mut self,
+        fill: impl FnOnce(&mut ArchetypeStorage),
+    ) -> Vec<Entity> {
+        let index = match self
             .archetypes
-            .iter_mut()
-            .enumerate()
-            .find(|(_, (storage, _))| T::match_archetype(storage.archetype()))
+            .iter()
+            .position(|(storage, _)| T::match_archetype(storage.archetype()))
         {
-            Some((i, (storage, _))) => {
-                let mut len = storage.len();
-                storage.extend(entities);
-                len = storage.len() - len;
-                self.location_map.add(i, len)
-            }
+            Some(i) => i,
             None => {
-                let storage = self.add_archetype::<T>();
-                storage.extend(entities);
-                let len = storage.len();
-                self.location_map.add(self.archetypes.len() - 1, len)
+                self.add_archetype::<T>();
+                self.archetypes.len() - 1
             }
         };
+        let storage = &mut self.archetypes[index].0;
+        let before = storage.len();
+        fill(storage);
+        let len = storage.len() - before;
+        self.mark_spawned(index, len);
+        let res = self.location_map.add(index, len);
         if log::log_enabled!(log::Level::Debug) {
             for e in &res {
                 log::debug!("Spawned {e:?}!");
             }
         }
+        let types = T::types();
+        for &e in &res {
+            self.relations_added(e, &types);
+        }
         res
     }
     /// Delete an entity from the world (calls drop), unlike take, this doesn't need to know the
     /// type of the components of the entity.
     pub fn remove(&mut self, entity: Entity) -> Option<()> {
+        self.despawn(entity)?;
+        self.relations_despawned(vec![entity]);
+        Some(())
+    }
+    /// Remove without cleaning up the relations
+    pub(crate) fn despawn(&mut self, entity: Entity) -> Option<()> {
         let loc = self.location_map.remove_single(entity)?;
+        self.location_map.record_despawn(entity, self.tick());
         self.archetypes[loc.archetype].0.remove(loc.entity);
         Some(())
     }
     /// Like remove, for multiple entities
     pub fn remove_many(&mut self, entities: impl IntoIterator<Item = Entity>) -> Option<()> {
-        let locs = self.location_map.remove(entities)?;
+        let entities = entities.into_iter().collect::<Vec<_>>();
+        let locs = self.location_map.remove(entities.iter().copied())?;
+        for &entity in &entities {
+            self.location_map.record_despawn(entity, self.tick());
+        }
         for loc in locs {
             self.archetypes[loc.archetype].0.remove(loc.entity);
         }
+        self.relations_despawned(entities);
         Some(())
     }
     /// Take an entity away from the world, unlike remove, this returns the entity, but needs to
-    /// know the type of its components
+    /// know the type of its components. None if the entity doesn't exist.
+    ///
+    /// # Panics
+    ///
+    /// This panics if T isn't exactly the components of the entity
     pub fn take<T: IntoArchetype>(&mut self, entity: Entity) -> Option<T> {
-        let loc = self.location_map.remove_single(entity)?;
-        Some(self.archetypes[loc.archetype].0.take(loc.entity))
+        match self.try_take(entity) {
+            Ok(value) => Some(value),
+            Err(EcsError::EntityNotFound(_)) => None,
+            Err(err) => panic!("{err}"),
+        }
+    }
+    /// Take an entity away from the world, the entity is left untouched on error
+    pub fn try_take<T: IntoArchetype>(&mut self, entity: Entity) -> Result<T, EcsError> {
+        let loc = self
+            .location_map
+            .get_location(entity)
+            .ok_or(EcsError::EntityNotFound(entity))?;
+        if !T::match_archetype(self.archetypes[loc.archetype].0.archetype()) {
+            return Err(EcsError::ArchetypeMismatch {
+                details: "Archetypes do not match".to_owned(),
+            });
+        }
+        self.location_map.remove_single(entity);
+        self.location_map.record_despawn(entity, self.tick());
+        let value = self.archetypes[loc.archetype].0.take(loc.entity);
+        self.relations_despawned(vec![entity]);
+        Ok(value)
     }
     /// Like take, for multiple entities
     pub fn take_many<T: IntoArchetype>(
         &mut self,
         entities: impl IntoIterator<Item = Entity>,
     ) -> Option<Vec<T>> {
-        let locs = self.location_map.remove(entities)?;
+        let entities = entities.into_iter().collect::<Vec<_>>();
+        let locs = self.location_map.remove(entities.iter().copied())?;
+        for &entity in &entities {
+            self.location_map.record_despawn(entity, self.tick());
+        }
         let mut res = Vec::with_capacity(locs.len());
         for loc in locs {
             res.push(self.archetypes[loc.archetype].0.take(loc.entity));
         }
+        self.relations_despawned(entities);
         Some(res)
     }
-    /// Add a component to an entity, this is very slow (comparatively) and should be avoided
+    /// Add a component to an entity, this is very slow (comparatively) and should be avoided.
+    /// None if the entity doesn't exist.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the entity already has one of the components
     pub fn add_component<T: IntoArchetype>(&mut self, entity: Entity, value: T) -> Option<()> {
-        let loc = self.location_map.get_location(entity)?;
+        match self.try_add_component(entity, value) {
+            Ok(()) => Some(()),
+            Err(EcsError::EntityNotFound(_)) => None,
+            Err(err) => panic!("{err}"),
+        }
+    }
+    /// Add a component to an entity, the entity is left untouched on error
+    pub fn try_add_component<T: IntoArchetype>(
+        &mut self,
+        entity: Entity,
+        value: T,
+    ) -> Result<(), EcsError> {
+        self.try_add_component_unindexed(entity, value)?;
+        self.relations_added(entity, &T::types());
+        Ok(())
+    }
+    /// try_add_component without indexing the relations
+    pub(crate) fn try_add_component_unindexed<T: IntoArchetype>(
+        &mut self,
+        entity: Entity,
+        value: T,
+    ) -> Result<(), EcsError> {
+        let loc = self
+            .location_map
+            .get_location(entity)
+            .ok_or(EcsError::EntityNotFound(entity))?;
         let archetype_bitset = self.archetypes[loc.archetype].1;
         let mut archetype = self.archetypes[loc.archetype].0.archetype().clone();
         for t in T::types() {
-            self.register_component_if_needed(t);
+            self.register_component_if_needed(t.into());
         }
         let t_bitset = T::bitset(&self.mapping).unwrap();
         if (t_bitset & archetype_bitset).any() {
-            panic!("Can't add a component to an entity that already has one");
+            return Err(EcsError::ArchetypeMismatch {
+                details: "Can't add a component to an entity that already has one".to_owned(),
+            });
         }
         let set = t_bitset | archetype_bitset;
 
@@ -189,7 +412,8 @@ impl World {
             Some((i, (_, _))) => i,
             None => {
                 archetype.merge(T::into_archetype());
-                let ats = ArchetypeStorage::new_from_archetype(archetype);
+                let ats =
+                    ArchetypeStorage::new_from_archetype(archetype).in_pool(self.pool.clone());
                 let i = self.archetypes.len();
                 self.archetypes.push((ats, set));
                 i
@@ -202,21 +426,51 @@ impl World {
         unsafe {
             let index = src_storage.move_entity(loc.entity, dst_storage);
             dst_storage.write(index, value);
+            dst_storage.mark_components_added(index, &T::types(), *self.tick.get_mut());
         }
 
         self.location_map.move_archetype(entity, dst_index);
 
-        Some(())
+        Ok(())
     }
-    /// Take a component from an entity, this is very slow (comparatively) and should be avoided
+    /// Take a component from an entity, this is very slow (comparatively) and should be avoided.
+    /// None if the entity doesn't exist.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the entity lacks one of the components
     pub fn take_component<T: IntoArchetype>(&mut self, entity: Entity) -> Option<T> {
-        let loc = self.location_map.get_location(entity)?;
+        match self.try_take_component(entity) {
+            Ok(value) => Some(value),
+            Err(EcsError::EntityNotFound(_)) => None,
+            Err(err) => panic!("{err}"),
+        }
+    }
+    /// Take a component from an entity, the entity is left untouched on error
+    pub fn try_take_component<T: IntoArchetype>(&mut self, entity: Entity) -> Result<T, EcsError> {
+        let value = self.try_take_component_unindexed(entity)?;
+        self.relations_taken(entity, &T::types());
+        Ok(value)
+    }
+    /// try_take_component without dropping the relations from the index
+    pub(crate) fn try_take_component_unindexed<T: IntoArchetype>(
+        &mut self,
+        entity: Entity,
+    ) -> Result<T, EcsError> {
+        let loc = self
+            .location_map
+            .get_location(entity)
+            .ok_or(EcsError::EntityNotFound(entity))?;
         let archetype_bitset = self.archetypes[loc.archetype].1;
         let mut archetype = self.archetypes[loc.archetype].0.archetype().clone();
 
-        let t_bitset = T::bitset(&self.mapping).unwrap();
+        let mismatch = || EcsError::ArchetypeMismatch {
+            details: "Can't take a component from an entity that doesn't have one".to_owned(),
+        };
+        // Components never registered can't be on the entity
+        let t_bitset = T::bitset(&self.mapping).ok_or_else(mismatch)?;
         if t_bitset & archetype_bitset != t_bitset {
-            panic!("Can't take a component from an entity that doesn't have one");
+            return Err(mismatch());
         }
         let set = archetype_bitset & !t_bitset;
 
@@ -229,7 +483,8 @@ impl World {
             Some((i, (_, _))) => i,
             None => {
                 archetype.subtract(T::into_archetype());
-                let ats = ArchetypeStorage::new_from_archetype(archetype);
+                let ats =
+                    ArchetypeStorage::new_from_archetype(archetype).in_pool(self.pool.clone());
                 let i = self.archetypes.len();
                 self.archetypes.push((ats, set));
                 i
@@ -241,29 +496,32 @@ impl World {
         let res;
         unsafe {
             res = src_storage.read(loc.entity);
-            src_storage.move_entity(loc.entity, dst_storage);
+            let index = src_storage.move_entity(loc.entity, dst_storage);
+            dst_storage.mark_changed(index, *self.tick.get_mut());
         }
 
         self.location_map.move_archetype(entity, dst_index);
 
-        Some(res)
+        Ok(res)
     }
-    fn query_iter<Q: Query>(&self, set: BorrowBitset) -> QueryIterBundle<Q> {
+    fn query_iter<Q: Query>(&self, set: BorrowBitset, tick: u32, since: u32) -> QueryIterBundle<Q> {
         let requirements = set.required();
+        let excluded = set.excluded();
         let storages = self
             .archetypes
             .iter()
             .enumerate()
-            .filter_map(
-                |(index, (storage, set))| match *set & requirements == requirements {
+            .filter_map(|(index, (storage, set))| {
+                match *set & requirements == requirements && !(*set & excluded).any() {
                     true => Some((index, storage)),
                     false => None,
-                },
-            );
-        // TODO: use with_capacity
-        let mut iter = QueryIterBundle::new();
+                }
+            });
+        let mut iter = QueryIterBundle::with_capacity(storages.clone().count());
         for (index, storage) in storages {
-            iter.push(unsafe { storage.iter_query::<Q>(index, Some(&self.location_map)) });
+            iter.push(unsafe {
+                storage.iter_query::<Q>(index, Some(&self.location_map), tick, since)
+            });
         }
         iter
     }
@@ -272,35 +530,329 @@ impl World {
     /// # Safety
     ///
     /// This should only be used when the query has been proven to not alias with any other
-    /// existing query.
-    pub(crate) unsafe fn query_unchecked<Q: Query>(&self) -> QueryIterBundle<Q> {
+    /// existing query. Changes are marked at tick, and `Changed` and `Added` compare to since.
+    pub(crate) unsafe fn query_unchecked<Q: Query>(
+        &self,
+        tick: u32,
+        since: u32,
+    ) -> QueryIterBundle<Q> {
         let set = match Q::bitset(&self.mapping) {
             Some(set) => set,
             None => return QueryIterBundle::new(),
         };
-        self.query_iter::<Q>(set)
+        self.query_iter::<Q>(set, tick, since)
     }
     /// Query the world
     ///
     /// # Panics
     ///
-    /// This panics if another existing query collide with this one
+    /// This panics if another existing query collide with this one, or if the query aliases (see
+    /// `Query`)
     pub fn query<Q: Query>(&self) -> BorrowGuard<'_, QueryIterBundle<Q>> {
+        self.try_query().unwrap_or_else(|err| panic!("{err}"))
+    }
+    /// Query the world, fails if another existing query collide with this one or if the query
+    /// aliases
+    pub fn try_query<Q: Query>(&self) -> Result<BorrowGuard<'_, QueryIterBundle<Q>>, EcsError> {
+        self.try_query_since(self.tick().saturating_sub(1))
+    }
+    /// Query the world, with `Changed` and `Added` matching the changes made after tick (the
+    /// changes of the current tick for `query`)
+    ///
+    /// # Panics
+    ///
+    /// This panics if another existing query collide with this one, or if the query aliases (see
+    /// `Query`)
+    pub fn query_since<Q: Query>(&self, tick: u32) -> BorrowGuard<'_, QueryIterBundle<Q>> {
+        self.try_query_since(tick)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+    /// Query the world with `Changed` and `Added` matching the changes made after tick, fails if
+    /// another existing query collide with this one or if the query aliases
+    pub fn try_query_since<Q: Query>(
+        &self,
+        tick: u32,
+    ) -> Result<BorrowGuard<'_, QueryIterBundle<Q>>, EcsError> {
+        Q::check_aliasing()?;
         let set = match Q::bitset(&self.mapping) {
             Some(set) => set,
-            None => return BorrowGuard::dummy(QueryIterBundle::new()),
+            None => return Ok(BorrowGuard::dummy(QueryIterBundle::new())),
         };
-        let iter = self.query_iter::<Q>(set);
-        self.borrows.borrow(set, iter)
+        let iter = self.query_iter::<Q>(set, self.tick(), tick);
+        self.borrows.try_borrow(set, iter)
     }
     /// Query a single entity from the world
+    ///
+    /// # Panics
+    ///
+    /// This panics if another existing query collide with this one, or if the query aliases (see
+    /// `Query`)
     pub fn query_single<Q: Query>(&self) -> Option<BorrowGuard<'_, Q>> {
-        let set = Q::bitset(&self.mapping)?;
-        let mut iter = self.query_iter::<Q>(set);
-        iter.next().map(|q| self.borrows.borrow(set, q))
+        self.try_query_single()
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+    /// Query a single entity from the world, fails if another existing query collide with this
+    /// one or if the query aliases
+    pub fn try_query_single<Q: Query>(&self) -> Result<Option<BorrowGuard<'_, Q>>, EcsError> {
+        Q::check_aliasing()?;
+        let set = match Q::bitset(&self.mapping) {
+            Some(set) => set,
+            None => return Ok(None),
+        };
+        let tick = self.tick();
+        let mut iter = self.query_iter::<Q>(set, tick, tick.saturating_sub(1));
+        iter.next()
+            .map(|q| self.borrows.try_borrow(set, q))
+            .transpose()
+    }
+    /// Call f on the T of every entity that has one. Each archetype is walked as a whole with the
+    /// offset of T and the stride of its rows, which is faster than a query for uniform updates
+    /// over many entities. The entities walked are marked as changed.
+    pub fn for_each_mut<T: Component>(&mut self, mut f: impl FnMut(&mut T)) {
+        for [column] in self.columns([TypeId::of::<T>()]) {
+            for row in 0..column.len {
+                f(unsafe { column.get(row) });
+            }
+        }
+    }
+    /// `for_each_mut` on two components of the same entity
+    ///
+    /// # Panics
+    ///
+    /// Panics if A and B are the same type
+    pub fn for_each_pair_mut<A: Component, B: Component>(
+        &mut self,
+        mut f: impl FnMut(&mut A, &mut B),
+    ) {
+        for [a, b] in self.pair_columns::<A, B>() {
+            for row in 0..a.len {
+                unsafe { f(a.get(row), b.get(row)) };
+            }
+        }
+    }
+    /// `for_each_mut` on the worker threads of executor, the rows are split in chunks
+    pub fn par_for_each_mut<T: Component>(
+        &mut self,
+        executor: &mut Executor,
+        f: impl Fn(&mut T) + Sync,
+    ) {
+        let columns = self.columns([TypeId::of::<T>()]);
+        par_rows(executor, &columns, |[column], rows| {
+            for row in rows {
+                f(unsafe { column.get(row) });
+            }
+        });
+    }
+    /// `for_each_pair_mut` on the worker threads of executor, the rows are split in chunks
+    ///
+    /// # Panics
+    ///
+    /// Panics if A and B are the same type
+    pub fn par_for_each_pair_mut<A: Component, B: Component>(
+        &mut self,
+        executor: &mut Executor,
+        f: impl Fn(&mut A, &mut B) + Sync,
+    ) {
+        let columns = self.pair_columns::<A, B>();
+        par_rows(executor, &columns, |[a, b], rows| {
+            for row in rows {
+                unsafe { f(a.get(row), b.get(row)) };
+            }
+        });
+    }
+    fn pair_columns<A: Component, B: Component>(&mut self) -> Vec<[Column; 2]> {
+        if TypeId::of::<A>() == TypeId::of::<B>() {
+            panic!(
+                "Aliasing problem in for_each_pair_mut on {}",
+                type_name::<A>()
+            );
+        }
+        self.columns([TypeId::of::<A>(), TypeId::of::<B>()])
+    }
+    /// The columns of the components in every non empty storage that has all of them, marking
+    /// them and their entities as changed
+    fn columns<const N: usize>(&mut self, ids: [TypeId; N]) -> Vec<[Column; N]> {
+        let tick = self.tick();
+        let mut columns = Vec::new();
+        for (storage, _) in &mut self.archetypes {
+            let offsets = ids.map(|id| storage.archetype().offset_of(id));
+            if storage.len() == 0 || offsets.contains(&None) {
+                continue;
+            }
+            for id in ids {
+                storage.mark_all_changed(id, tick);
+            }
+            let len = storage.len();
+            let (base, stride) = storage.raw_rows();
+            columns.push(offsets.map(|offset| Column {
+                base: unsafe { base.add(offset.unwrap()) },
+                stride,
+                len,
+            }));
+        }
+        columns
+    }
+    /// Count the entities and archetypes of the world and the memory pooled for their storages,
+    /// this doesn't touch any component so it can be called while queries are alive
+    pub fn stats(&self) -> WorldStats {
+        let mut stats = WorldStats {
+            archetypes: self.archetypes.len(),
+            pool: self.pool.lock().stats(),
+            ..Default::default()
+        };
+        for (storage, _) in &self.archetypes {
+            stats.entities += storage.len();
+            if storage.len() == 0 {
+                stats.empty_archetypes += 1;
+            }
+        }
+        stats
+    }
+    /// Release the memory the storages keep past their entities, after a mass despawn. The
+    /// storages grow again on the next spawns.
+    pub fn shrink_to_fit(&mut self) {
+        for (storage, _) in &mut self.archetypes {
+            storage.shrink_to_fit();
+        }
+    }
+    /// Release the memory the storages freed and the pool kept for reuse, returns the number of
+    /// bytes released
+    pub fn trim_pool(&mut self) -> usize {
+        self.pool.lock().trim()
+    }
+    /// Storages allocations over `bytes` bypass the pool (1MiB at most, the default), 0 disables
+    /// the pool
+    pub fn set_pool_threshold(&mut self, bytes: usize) {
+        self.pool.lock().set_threshold(bytes);
+    }
+    /// Check every component of type T with `validator` in `validate`, there can be any number
+    /// of validators per type. See `finite` for components made of floats.
+    pub fn register_validator<T: Component>(
+        &mut self,
+        validator: fn(&T, Entity) -> Result<(), String>,
+    ) {
+        self.validators
+            .push(validate::component_validator(validator));
+    }
+    /// Check that the entities referenced by every component of type T are alive in `validate`
+    pub fn register_entity_validator<T: Component + ReferencesEntities>(&mut self) {
+        self.validators.push(validate::liveness_validator::<T>());
+    }
+    /// Run the validators on every component of their type. Components borrowed by a query are
+    /// skipped (with a warning).
+    pub fn validate(&self) -> Vec<ValidationFailure> {
+        let mut failures = Vec::new();
+        for validator in &self.validators {
+            validator(self, &mut failures);
+        }
+        failures
+    }
+    /// Returns true if the entity exists
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.location_map.get_location(entity).is_some()
+    }
+    /// Number of entities alive
+    pub fn entity_count(&self) -> usize {
+        self.location_map.len()
+    }
+    /// The entities alive, in no particular order. For debugging, queries are much faster to
+    /// iterate.
+    pub fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.location_map.entities()
+    }
+    /// The entity alive in the slot of index. The index of a despawned entity resolves to None
+    /// until its slot is reused, then to the entity reusing it: only resolve indices taken from
+    /// entities known to be alive (see `EntityIndex`).
+    pub fn entity_from_index(&self, index: EntityIndex) -> Option<Entity> {
+        self.location_map.entity_at(index)
+    }
+    /// Pointer to a component of an entity
+    pub(crate) fn component_ptr(
+        &self,
+        entity: Entity,
+        id: impl Into<ComponentKey>,
+    ) -> Option<*mut u8> {
+        let loc = self.location_map.get_location(entity)?;
+        self.archetypes[loc.archetype]
+            .0
+            .component_ptr(loc.entity, id)
+    }
+    /// Mutable reference to a component of an entity, marking the entity as changed
+    pub(crate) fn component_mut<T: Component>(&mut self, entity: Entity) -> Option<&mut T> {
+        let ptr = self.component_ptr_mut(entity, ComponentKey::of::<T>())?;
+        Some(unsafe { &mut *(ptr as *mut T) })
+    }
+    /// Pointer to a component of an entity, marking it as changed
+    pub(crate) fn component_ptr_mut(
+        &mut self,
+        entity: Entity,
+        id: ComponentKey,
+    ) -> Option<*mut u8> {
+        let loc = self.location_map.get_location(entity)?;
+        let storage = &mut self.archetypes[loc.archetype].0;
+        let ptr = storage.component_ptr(loc.entity, id)?;
+        storage.mark_component_changed(loc.entity, id, *self.tick.get_mut());
+        Some(ptr)
+    }
+    pub(crate) fn storages(&self) -> impl Iterator<Item = &ArchetypeStorage> {
+        self.archetypes.iter().map(|(storage, _)| storage)
+    }
+    pub(crate) fn location_map(&self) -> &LocationMap {
+        &self.location_map
+    }
+}
+
+/// A component in the rows of a storage, see `World::for_each_mut`
+#[derive(Clone, Copy)]
+struct Column {
+    base: *mut u8,
+    stride: usize,
+    len: usize,
+}
+
+// The columns handed to the workers are disjoint (different components or different rows)
+unsafe impl Send for Column {}
+unsafe impl Sync for Column {}
+
+impl Column {
+    /// # Safety
+    ///
+    /// T must be the type of the column, row below its length, and the component not borrowed
+    /// elsewhere for 'a
+    unsafe fn get<'a, T>(self, row: usize) -> &'a mut T {
+        if size_of::<T>() == 0 {
+            // The storage of a zst archetype is only aligned to 1
+            &mut *NonNull::<T>::dangling().as_ptr()
+        } else {
+            &mut *(self.base.add(row * self.stride) as *mut T)
+        }
     }
 }
 
+/// Rows handed to a worker at once by the parallel `for_each`
+const PAR_CHUNK: usize = 4096;
+
+/// Run f on chunks of the rows of the columns, on the workers of executor
+fn par_rows<const N: usize>(
+    executor: &mut Executor,
+    columns: &[[Column; N]],
+    f: impl Fn([Column; N], Range<usize>) + Sync,
+) {
+    let rows = columns.iter().map(|column| column[0].len).sum::<usize>();
+    let workers = std::thread::available_parallelism().map_or(1, usize::from);
+    let pool = executor.thread_pool(workers.min(rows.div_ceil(PAR_CHUNK)));
+    let f = &f;
+    pool.scope(|scope| {
+        for &column in columns {
+            let len = column[0].len;
+            for start in (0..len).step_by(PAR_CHUNK) {
+                let rows = start..len.min(start + PAR_CHUNK);
+                scope.run(FnJob(move || f(column, rows)));
+            }
+        }
+    });
+}
+
 impl Default for World {
     fn default() -> Self {
         Self::new()
@@ -314,9 +866,89 @@ mod tests {
         sync::atomic::{AtomicU64, Ordering},
     };
 
-    use crate::{Entities, Executor};
+    use crate::{testing::TestBed, EcsError, Entities, With, Without};
 
     use super::*;
+    #[test]
+    fn entity_indices() {
+        let mut world = World::new();
+        let [a, b] = [(); 2].map(|_| world.spawn((0u32,)));
+        assert_ne!(a.index(), b.index());
+        assert_eq!(Some(a), world.entity_from_index(a.index()));
+        assert_eq!(Some(b), world.entity_from_index(b.index()));
+        assert_eq!(None, world.entity_from_index(EntityIndex(u32::MAX)));
+
+        // The generation advanced, the index of a despawned entity doesn't resolve
+        world.remove(a).unwrap();
+        assert_eq!(None, world.entity_from_index(a.index()));
+        assert_eq!(Some(b), world.entity_from_index(b.index()));
+        // Until its slot is reused
+        let c = world.spawn((1u32,));
+        assert_eq!(a.index(), c.index());
+        assert_ne!(a, c);
+        assert_eq!(Some(c), world.entity_from_index(a.index()));
+        assert!(!world.contains(a));
+
+        world.remove(b).unwrap();
+        world.remove(c).unwrap();
+        assert_eq!(None, world.entity_from_index(b.index()));
+        assert_eq!(None, world.entity_from_index(c.index()));
+    }
+
+    #[test]
+    fn spawn_batch() {
+        let mut batch = World::new();
+        let mut many = World::new();
+        batch.spawn((0u32, "a"));
+        many.spawn((0u32, "a"));
+        let batched = batch.spawn_batch((1..50_000u32).map(|i| (i, "b")));
+        let spawned = many.spawn_many((1..50_000u32).map(|i| (i, "b")));
+        assert_eq!(spawned, batched);
+        let values = |w: &World| {
+            w.query::<(Entity, &u32, &&str)>()
+                .map(|(e, &i, &s)| (e, i, s))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(&many), values(&batch));
+        assert_eq!(50_000, batch.entity_count());
+    }
+
+    #[test]
+    fn stale_handles() {
+        let mut world = World::new();
+        let [a, b] = [(); 2].map(|_| world.spawn((0u32,)));
+        world.remove(a).unwrap();
+        let c = world.spawn((1u32,));
+        assert_eq!(a.index(), c.index());
+        assert_eq!(2, world.entity_count());
+        let mut alive = world.entities().collect::<Vec<_>>();
+        alive.sort();
+        let mut expected = vec![b, c];
+        expected.sort();
+        assert_eq!(expected, alive);
+
+        // The stale handle never reaches the entity reusing its slot
+        assert!(!world.contains(a));
+        assert_eq!(None, world.add_component(a, (0u8,)));
+        assert!(matches!(
+            world.try_add_component(a, (0u8,)),
+            Err(EcsError::EntityNotFound(e)) if e == a
+        ));
+        assert_eq!(None, world.take_component::<(u32,)>(a));
+        assert_eq!(None, world.take::<(u32,)>(a));
+        assert_eq!(None, world.remove(a));
+        // Nothing is removed when one of the entities is stale
+        assert_eq!(None, world.remove_many([b, a]));
+        assert_eq!(None, world.take_many::<(u32,)>([b, a]));
+        assert_eq!(2, world.entity_count());
+
+        let mut values = world.query::<(Entity, &u32)>().collect::<Vec<_>>();
+        values.sort();
+        let mut expected = vec![(b, &0), (c, &1)];
+        expected.sort();
+        assert_eq!(expected, values);
+    }
+
     #[test]
     fn push() {
         let mut w = World::new();
@@ -326,7 +958,37 @@ mod tests {
         assert_eq!("a", iter.next().unwrap());
     }
     #[test]
-    #[should_panic]
+    fn stats() {
+        let mut w = World::new();
+        assert_eq!(WorldStats::default(), w.stats());
+        let a = w.spawn((1u32,));
+        w.spawn_many([(2u32, 1.0f32), (3u32, 2.0f32)]);
+        let guard = w.query::<&mut u32>();
+        let pool = w.stats().pool;
+        assert_eq!(
+            WorldStats {
+                entities: 3,
+                archetypes: 2,
+                empty_archetypes: 0,
+                pool
+            },
+            w.stats()
+        );
+        drop(guard);
+        w.remove(a);
+        // Removing doesn't shrink storages
+        assert_eq!(
+            WorldStats {
+                entities: 2,
+                archetypes: 2,
+                empty_archetypes: 1,
+                pool
+            },
+            w.stats()
+        );
+    }
+    #[test]
+    #[should_panic(expected = "Borrow collision")]
     fn borrow_collision() {
         let mut w = World::new();
         w.spawn(("a".to_owned(), 34));
@@ -348,13 +1010,48 @@ mod tests {
     }
     #[test]
     fn multiple_archetypes() {
-        let mut w = World::new();
-        w.spawn((12, false));
-        w.spawn((12, "test"));
-        w.spawn((12, ()));
-        for i in w.query::<&i32>() {
-            assert_eq!(*i, 12);
+        let mut bed = TestBed::new();
+        let entities = [
+            bed.world.spawn((12, false)),
+            bed.world.spawn((12, "test")),
+            bed.world.spawn((12, ())),
+        ];
+        for e in entities {
+            bed.assert_component(e, 12);
+        }
+        bed.assert_query_count::<&i32>(3)
+            .assert_query_count::<(&i32, &bool)>(1);
+    }
+    #[test]
+    fn filters() {
+        let mut bed = TestBed::new();
+        bed.world.spawn((12, false));
+        bed.world.spawn((12, "test"));
+        bed.world.spawn((12, ()));
+        bed.world.spawn((12, false, ()));
+        bed.assert_query_count::<(&i32, Without<bool>)>(2)
+            .assert_query_count::<(&i32, With<bool>)>(2)
+            .assert_query_count::<(&i32, With<bool>, Without<()>)>(1)
+            .assert_query_count::<(&i32, Without<bool>, Without<()>)>(1)
+            // Never registered: nothing has it
+            .assert_query_count::<(&i32, Without<u64>)>(4)
+            .assert_query_count::<(&i32, With<u64>)>(0);
+        // Filters don't borrow, so they don't alias
+        bed.assert_query_count::<(&mut bool, With<bool>, Without<&str>)>(2);
+        let mut query = bed.world.query::<(&mut i32, Without<()>)>();
+        for (value, _) in &mut query {
+            *value += 1;
         }
+        drop(query);
+        let values = bed
+            .world
+            .query::<(&i32, Option<&()>)>()
+            .map(|(v, unit)| (*v, unit.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(4, values.len());
+        assert!(values
+            .iter()
+            .all(|&(v, unit)| v == if unit { 12 } else { 13 }));
     }
     #[test]
     fn drop_world() {
@@ -374,28 +1071,256 @@ mod tests {
     }
     #[test]
     fn remove_component() {
+        let mut bed = TestBed::new();
+        let e = bed.world.spawn((24, true));
+        bed.assert_component(e, true);
+        assert_eq!(Some((true,)), bed.world.take_component::<(bool,)>(e));
+        bed.assert_query_count::<&bool>(0).assert_component(e, 24);
+    }
+    #[test]
+    fn add_component() {
+        let mut bed = TestBed::new();
+        let e = bed.world.spawn((24,));
+        bed.assert_query_count::<&bool>(0);
+        bed.world.add_component(e, (true,));
+        bed.assert_component(e, true).assert_component(e, 24);
+    }
+    #[test]
+    fn component_paths() {
+        let mut world = World::new();
+        // Reaches (u8, u16, u64, String) by additions
+        let a = world.spawn((1u8,));
+        world.add_component(a, ("a".to_owned(),));
+        world.add_component(a, (2u64, 3u16));
+        // Then reuses its storage by removals, and the other way around
+        let b = world.spawn((4u64, "b".to_owned(), true, 5u16, 6u8));
+        world.take_component::<(bool,)>(b);
+        let c = world.spawn((7u16, 8u8, 9i32, "c".to_owned(), 10u64));
+        world.take_component::<(i32,)>(c);
+        world.take_component::<(u64,)>(c);
+        world.add_component(c, (11u64,));
+        let mut values = world
+            .query::<(&u8, &u16, &u64, &String)>()
+            .map(|(a, b, c, d)| (*a, *b, *c, d.clone()))
+            .collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(
+            vec![
+                (1, 3, 2, "a".to_owned()),
+                (6, 5, 4, "b".to_owned()),
+                (8, 7, 11, "c".to_owned()),
+            ],
+            values
+        );
+    }
+    #[test]
+    fn try_variants() {
         let mut w = World::new();
         let e = w.spawn((24, true));
-        assert_eq!(true, **w.query_single::<&bool>().unwrap());
-        w.take_component::<(bool,)>(e);
-        assert!(w.query_single::<&bool>().is_none());
+        {
+            let _guard = w.query::<&mut i32>();
+            let conflict = EcsError::BorrowConflict {
+                details: "Borrow collision".to_owned(),
+            };
+            assert_eq!(Some(conflict.clone()), w.try_query::<&i32>().err());
+            assert_eq!(Some(conflict), w.try_query_single::<&i32>().err());
+            assert!(w.try_query::<&bool>().is_ok());
+        }
+
+        let gone = w.spawn((0u8,));
+        w.remove(gone);
+        let not_found = Err(EcsError::EntityNotFound(gone));
+        assert_eq!(not_found, w.try_add_component(gone, (1.0f32,)));
+        assert_eq!(not_found, w.try_take_component::<(bool,)>(gone).map(|_| ()));
+        assert_eq!(not_found, w.try_take::<(u8,)>(gone).map(|_| ()));
+        assert!(w.add_component(gone, (1.0f32,)).is_none());
+        assert!(w.take_component::<(bool,)>(gone).is_none());
+
+        let mismatch = |result: Result<(), EcsError>| {
+            matches!(result, Err(EcsError::ArchetypeMismatch { .. }))
+        };
+        assert!(mismatch(w.try_add_component(e, (false,))));
+        assert!(mismatch(w.try_take_component::<(f64,)>(e).map(|_| ())));
+        assert!(mismatch(w.try_take::<(i32,)>(e).map(|_| ())));
+        // The entity is untouched by the errors
         assert_eq!(24, **w.query_single::<&i32>().unwrap());
+        assert_eq!(Ok((24, true)), w.try_take::<(i32, bool)>(e));
+        assert_eq!(
+            Err(EcsError::EntityNotFound(e)),
+            w.try_take::<(i32, bool)>(e)
+        );
     }
     #[test]
-    fn add_component() {
+    #[should_panic(expected = "Can't add a component to an entity that already has one")]
+    fn add_existing_component() {
         let mut w = World::new();
         let e = w.spawn((24,));
-        assert!(w.query_single::<&bool>().is_none());
-        w.add_component(e, (true,));
-        assert_eq!(true, **w.query_single::<&bool>().unwrap());
-        assert_eq!(24, **w.query_single::<&i32>().unwrap());
+        w.add_component(e, (12,));
     }
     #[test]
-    fn query_id() {
+    #[should_panic(expected = "Can't take a component from an entity that doesn't have one")]
+    fn take_missing_component() {
+        let mut w = World::new();
+        let e = w.spawn((24,));
+        w.spawn((true,));
+        w.take_component::<(bool,)>(e);
+    }
+    #[test]
+    #[should_panic(expected = "Aliasing problem in query on alloc::string::String")]
+    fn query_aliasing_mutable() {
+        let mut w = World::new();
+        w.spawn(("a".to_owned(),));
+        w.query::<(&mut String, &String)>();
+    }
+    #[test]
+    fn query_aliasing() {
+        let mut w = World::new();
+        let e = w.spawn(("a".to_owned(), 12));
+        let aliasing = |err: Option<EcsError>| match err {
+            Some(EcsError::BorrowConflict { details }) => details.ends_with("i32"),
+            _ => false,
+        };
+        assert!(aliasing(w.try_query::<(&mut i32, &mut i32)>().err()));
+        assert!(aliasing(w.try_query::<(Entity, &i32, &mut i32)>().err()));
+        assert!(aliasing(w.try_query::<(Option<&mut i32>, &i32)>().err()));
+        assert!(aliasing(w.try_query::<(&mut i32, Option<&i32>)>().err()));
+        assert!(aliasing(
+            w.try_query_single::<(Option<&mut i32>, Option<&mut i32>)>()
+                .err()
+        ));
+        // Shared borrows of the same component are fine
+        let (entity, a, b) = *w.query_single::<(Entity, &String, &String)>().unwrap();
+        assert_eq!(e, entity);
+        assert!(std::ptr::eq(a, b));
+        assert_eq!(1, w.query::<(Option<&i32>, &i32, &String)>().count());
+    }
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[repr(align(32))]
+    struct Aligned(u64);
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Marker;
+
+    /// Entities spread over archetypes with zsts and high alignment components, with their u32
+    /// set to their number
+    fn bulk_world(count: u32) -> (World, Vec<Entity>) {
         let mut w = World::new();
-        let mut e = Executor::new();
-        let entities_id = std::iter::repeat_with(|| w.spawn((0,)))
-            .take(10)
+        let entities = (0..count)
+            .map(|i| match i % 5 {
+                0 => w.spawn((i, 0.5f32)),
+                1 => w.spawn((i, Aligned(i as u64), Marker)),
+                2 => w.spawn((Marker, i)),
+                3 => w.spawn((true, Aligned(0), i, 1.5f32)),
+                _ => w.spawn((Aligned(0),)),
+            })
+            .collect();
+        (w, entities)
+    }
+
+    /// The u32 and Aligned of the entities, sorted
+    fn bulk_state(w: &World) -> Vec<(Entity, Option<u32>, Option<Aligned>)> {
+        let mut state = w
+            .query::<(Entity, Option<&u32>, Option<&Aligned>)>()
+            .map(|(e, i, a)| (e, i.copied(), a.copied()))
+            .collect::<Vec<_>>();
+        state.sort_by_key(|(e, ..)| *e);
+        state
+    }
+
+    #[test]
+    fn for_each_mut() {
+        let (mut bulk, _) = bulk_world(500);
+        let (queried, _) = bulk_world(500);
+        let tick = bulk.advance_tick();
+        bulk.for_each_mut(|i: &mut u32| *i = *i * 3 + 1);
+        bulk.for_each_mut(|a: &mut Aligned| {
+            assert_eq!(0, a as *mut Aligned as usize % 32);
+            a.0 += 7;
+        });
+        let mut markers = 0;
+        bulk.for_each_mut(|_: &mut Marker| markers += 1);
+        for i in queried.query::<&mut u32>() {
+            *i = *i * 3 + 1;
+        }
+        for a in queried.query::<&mut Aligned>() {
+            a.0 += 7;
+        }
+        assert_eq!(bulk_state(&queried), bulk_state(&bulk));
+        assert_eq!(queried.query::<&Marker>().count(), markers);
+        // Like mutable queries, every entity walked is changed
+        let changed = bulk
+            .storages()
+            .flat_map(|storage| storage.ticks())
+            .filter(|ticks| ticks.changed == tick)
+            .count();
+        assert_eq!(500, changed);
+        // Nothing to walk
+        bulk.for_each_mut(|_: &mut u8| unreachable!());
+    }
+
+    #[test]
+    fn for_each_pair_mut() {
+        let (mut w, _) = bulk_world(500);
+        let mut pairs = 0;
+        w.for_each_pair_mut(|i: &mut u32, a: &mut Aligned| {
+            // Only in the archetypes with both, from the same entity
+            assert!(*i % 5 == 1 || *i % 5 == 3);
+            assert!(a.0 == 0 || a.0 == *i as u64);
+            a.0 = *i as u64 * 2;
+            pairs += 1;
+        });
+        assert_eq!(200, pairs);
+        for (i, a) in w.query::<(&u32, &Aligned)>() {
+            assert_eq!(*i as u64 * 2, a.0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Aliasing problem in for_each_pair_mut on u32")]
+    fn for_each_pair_aliasing() {
+        let (mut w, _) = bulk_world(10);
+        w.for_each_pair_mut(|_: &mut u32, _: &mut u32| {});
+    }
+
+    #[test]
+    fn par_for_each_mut() {
+        // Enough for several chunks per archetype
+        let (mut w, entities) = bulk_world(PAR_CHUNK as u32 * 12 + 17);
+        let mut executor = Executor::new();
+        let touched = AtomicU64::new(0);
+        w.par_for_each_mut(&mut executor, |i: &mut u32| {
+            *i += 1;
+            touched.fetch_add(1, Ordering::Relaxed);
+        });
+        let count = |rests: &[usize]| {
+            (0..entities.len())
+                .filter(|n| rests.contains(&(n % 5)))
+                .count()
+        };
+        assert_eq!(count(&[0, 1, 2, 3]) as u64, touched.load(Ordering::Relaxed));
+        // Each exactly once
+        for (n, e) in entities.iter().enumerate() {
+            if n % 5 != 4 {
+                assert_eq!(Some(&mut (n as u32 + 1)), w.component_mut::<u32>(*e));
+            }
+        }
+
+        let pairs = AtomicU64::new(0);
+        w.par_for_each_pair_mut(&mut executor, |i: &mut u32, a: &mut Aligned| {
+            a.0 = *i as u64;
+            pairs.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(count(&[1, 3]) as u64, pairs.load(Ordering::Relaxed));
+        for (i, a) in w.query::<(&u32, &Aligned)>() {
+            assert_eq!(*i as u64, a.0);
+        }
+    }
+
+    #[test]
+    fn query_id() {
+        let mut bed = TestBed::new().parallel();
+        let entities_id = bed
+            .with_entities([(0,); 10])
+            .into_iter()
             .collect::<HashSet<Entity>>();
 
         let sys = move |entities: Entities<Entity>| {
@@ -405,7 +1330,6 @@ mod tests {
                 assert!(entities_id.contains(&entity));
             }
         };
-        let s = e.schedule().then(sys).build();
-        e.execute(&s, &mut w);
+        bed.run(sys);
     }
 }
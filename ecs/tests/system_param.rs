@@ -0,0 +1,152 @@
+#![cfg(feature = "codegen")]
+
+use ecs::{ChangedRes, Entities, Executor, FetchPolicy, ResMut, SystemParam, World};
+
+#[derive(Debug, Default, PartialEq)]
+struct Gfx(u32);
+#[derive(Debug, Default, PartialEq)]
+struct Renderer(u32);
+#[derive(Debug, Default, PartialEq)]
+struct Stats(u32);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position(f32);
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Light(f32);
+
+#[derive(SystemParam)]
+struct RenderCtx<'w> {
+    gfx: &'w Gfx,
+    renderer: ResMut<'w, Renderer>,
+    #[system_param(skip)]
+    scratch: Vec<u32>,
+}
+
+/// Nested, with a query and a tuple struct
+#[derive(SystemParam)]
+struct Frame<'w>(RenderCtx<'w>, Entities<&'w mut Position>, &'w mut Stats);
+
+#[derive(SystemParam)]
+struct Watch<'w, 's> {
+    stats: ChangedRes<'w, Stats>,
+    gfx: &'s Gfx,
+}
+
+fn render(mut ctx: RenderCtx, lights: Entities<&Light>) {
+    assert!(ctx.scratch.is_empty());
+    ctx.renderer.0 = ctx.gfx.0 + lights.map(|l| l.0 as u32).sum::<u32>();
+}
+
+fn frame(Frame(ctx, positions, stats): Frame) {
+    for position in positions {
+        position.0 += ctx.gfx.0 as f32;
+    }
+    stats.0 += 1;
+}
+
+fn read_gfx(gfx: &Gfx, _lights: Entities<&Light>) {
+    assert_eq!(3, gfx.0);
+}
+
+fn write_renderer(renderer: &mut Renderer) {
+    renderer.0 += 1;
+}
+
+fn write_positions(positions: Entities<&mut Position>) {
+    for position in positions {
+        position.0 *= 2.0;
+    }
+}
+
+fn setup() -> (World, Executor) {
+    let mut world = World::new();
+    world.spawn_many([(Light(1.0),), (Light(2.0),)]);
+    world.spawn_many([(Position(1.0),), (Position(2.0),)]);
+    let mut executor = Executor::new();
+    executor.add_resource(Gfx(3));
+    executor.add_resource(Renderer::default());
+    executor.add_resource(Stats::default());
+    (world, executor)
+}
+
+#[test]
+fn derived() {
+    let (mut world, mut executor) = setup();
+    let schedule = executor.schedule().then(render).then(frame).build();
+    executor.execute(&schedule, &mut world);
+    assert_eq!(Some(&Renderer(6)), executor.get_resource());
+    assert_eq!(Some(&Stats(1)), executor.get_resource());
+    let mut positions = world.query::<&Position>().map(|p| p.0).collect::<Vec<_>>();
+    positions.sort_by(f32::total_cmp);
+    assert_eq!(vec![4.0, 5.0], positions);
+}
+
+#[test]
+fn conflicts() {
+    let (_, mut executor) = setup();
+    let threads = |executor: &mut Executor, build: fn(ecs::Scheduler) -> ecs::Scheduler| {
+        build(executor.schedule()).build().report().threads
+    };
+    // Reading what the param reads runs alongside it, writing what it reads or writes doesn't
+    assert_eq!(2, threads(&mut executor, |s| s.then(render).then(read_gfx)));
+    assert_eq!(
+        1,
+        threads(&mut executor, |s| s.then(render).then(write_renderer))
+    );
+    // The nested params and the query count too
+    assert_eq!(2, threads(&mut executor, |s| s.then(frame).then(read_gfx)));
+    assert_eq!(
+        1,
+        threads(&mut executor, |s| s.then(frame).then(write_renderer))
+    );
+    assert_eq!(
+        1,
+        threads(&mut executor, |s| s.then(frame).then(write_positions))
+    );
+    assert_eq!(1, threads(&mut executor, |s| s.then(frame).then(render)));
+}
+
+#[test]
+fn missing_resource() {
+    let mut world = World::new();
+    let mut executor = Executor::new();
+    executor.add_resource(Gfx(0));
+    executor.add_resource(Stats::default());
+    executor.set_fetch_policy(FetchPolicy::Skip);
+    // Renderer is missing, checked before anything is fetched
+    let schedule = executor
+        .schedule()
+        .then(|_: RenderCtx| panic!("Ran"))
+        .build();
+    executor.execute(&schedule, &mut world);
+    assert_eq!(1, executor.take_system_errors().len());
+}
+
+#[test]
+fn change_detection() {
+    let (mut world, mut executor) = setup();
+    let changes = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let seen = changes.clone();
+    let watch = move |watch: Watch| {
+        assert_eq!(3, watch.gfx.0);
+        if watch.stats.get().is_some() {
+            seen.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    };
+    let schedule = executor.schedule().then(watch).build();
+    let bump = executor.schedule().then(frame).build();
+    for run in [true, false, true, true] {
+        if run {
+            executor.execute(&bump, &mut world);
+        }
+        executor.execute(&schedule, &mut world);
+    }
+    // The first run, and the runs after bumps
+    assert_eq!(3, changes.load(std::sync::atomic::Ordering::Relaxed));
+}
+
+/// The derives that must be rejected, and their errors (TRYBUILD=overwrite to update them)
+#[test]
+fn errors() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,6 @@
+#[derive(ecs::SystemParam)]
+struct Param<'w, const N: usize> {
+    res: &'w [u32; N],
+}
+
+fn main() {}
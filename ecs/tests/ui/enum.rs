@@ -0,0 +1,7 @@
+#[derive(ecs::SystemParam)]
+enum Param<'w> {
+    A(&'w u32),
+    B(&'w u64),
+}
+
+fn main() {}
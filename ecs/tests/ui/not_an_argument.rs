@@ -0,0 +1,10 @@
+// Fields have to be system arguments, unless skipped
+#[derive(ecs::SystemParam)]
+struct Param<'w> {
+    res: &'w u32,
+    count: u32,
+    #[system_param(skip)]
+    skipped: u64,
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[derive(ecs::SystemParam)]
+struct Param<'w, T> {
+    res: &'w T,
+}
+
+fn main() {}
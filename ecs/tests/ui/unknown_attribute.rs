@@ -0,0 +1,9 @@
+// skip is the only attribute
+#[derive(ecs::SystemParam)]
+struct Param<'w> {
+    res: &'w u32,
+    #[system_param(default)]
+    count: u32,
+}
+
+fn main() {}
@@ -0,0 +1,52 @@
+use ecs::{Entities, Entity, Executor, World};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position(f32, f32);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Velocity(f32, f32);
+
+struct Frame(u32);
+
+fn movement(entities: Entities<(&mut Position, &Velocity)>) {
+    for (p, v) in entities {
+        p.0 += v.0;
+        p.1 += v.1;
+    }
+}
+
+fn count_frames(frame: &mut Frame) {
+    frame.0 += 1;
+}
+
+fn report(entities: Entities<(Entity, &Position)>, frame: &Frame) {
+    for (e, p) in entities {
+        println!("frame {}: {e:?} at ({}, {})", frame.0, p.0, p.1);
+    }
+}
+
+fn main() {
+    let mut world = World::new();
+    let mut executor = Executor::new();
+    executor.add_resource(Frame(0));
+
+    let moving = world.spawn((Position(0.0, 0.0), Velocity(1.0, 2.0)));
+    let fixed = world.spawn((Position(5.0, 5.0),));
+
+    let schedule = executor
+        .schedule()
+        .then(count_frames)
+        .then(movement)
+        .then(report)
+        .build();
+    executor.execute(&schedule, &mut world);
+    executor.execute_single(movement, &mut world);
+
+    let positions = world
+        .query::<(Entity, &Position)>()
+        .map(|(e, p)| (e, *p))
+        .collect::<Vec<_>>();
+    assert!(positions.contains(&(moving, Position(2.0, 4.0))));
+    assert!(positions.contains(&(fixed, Position(5.0, 5.0))));
+    assert_eq!(1, executor.get_resource::<Frame>().unwrap().0);
+}
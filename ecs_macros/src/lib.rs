@@ -1,22 +1,46 @@
 #![allow(dead_code)]
-#![feature(proc_macro_diagnostic)]
-#![feature(proc_macro_span_shrink)]
+#![deny(unstable_features)]
 
-use proc_macro2::{Ident, Span};
-use quote::quote;
-use syn::{parse::Parse, parse_macro_input, Index, LitInt};
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::{quote, quote_spanned};
+use std::ops::RangeInclusive;
 
-struct Count {
-    count: u64,
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    spanned::Spanned,
+    Data, DeriveInput, Field, Index, LitInt, Member, Token,
+};
+
+/// The tuple arities to implement a trait for, either `N` (every arity up to N, the lower bound
+/// depends on the macro) or an inclusive range `A..=B`.
+struct Arities {
+    start: Option<u64>,
+    end: u64,
+}
+
+impl Arities {
+    fn range(&self, default_start: u64) -> RangeInclusive<u64> {
+        self.start.unwrap_or(default_start)..=self.end
+    }
 }
 
-impl Parse for Count {
+impl Parse for Arities {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
         let lookahead = input.lookahead1();
         if lookahead.peek(LitInt) {
-            let lit: LitInt = input.parse()?;
+            let first = input.parse::<LitInt>()?.base10_parse::<u64>()?;
+            if input.is_empty() {
+                return Ok(Self {
+                    start: None,
+                    end: first,
+                });
+            }
+            input.parse::<Token![..=]>()?;
+            let end = input.parse::<LitInt>()?.base10_parse::<u64>()?;
             Ok(Self {
-                count: lit.base10_parse::<u64>()?,
+                start: Some(first),
+                end,
             })
         } else {
             Err(lookahead.error())
@@ -50,9 +74,9 @@ fn n_to_type(mut n: u64, total: u64) -> Ident {
 
 #[proc_macro]
 pub fn impl_archetype(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let count = parse_macro_input!(input as Count).count;
+    let arities = parse_macro_input!(input as Arities);
     let output = {
-        let impls = (0..=count).map(|count| {
+        let impls = arities.range(0).map(|count| {
             // eg "2"
             let cap = count as usize;
             // eg "A", "B"
@@ -108,7 +132,7 @@ pub fn impl_archetype(input: proc_macro::TokenStream) -> proc_macro::TokenStream
                         unsafe {
                             let val = MaybeUninit::<#tuple>::uninit();
                             #(
-                                info.insert(TypeId::of::<#types>(), ComponentType {
+                                info.insert(ComponentKey::of::<#types>(), ComponentType {
                                     offset: std::ptr::addr_of!((*val.as_ptr()).#indices) as usize - val.as_ptr() as usize,
                                     drop: match std::mem::needs_drop::<#types>() {
                                         true => Some(get_drop::<#types>()),
@@ -116,6 +140,7 @@ pub fn impl_archetype(input: proc_macro::TokenStream) -> proc_macro::TokenStream
                                     },
                                     size: std::mem::size_of::<#types>(),
                                     alignment: std::mem::align_of::<#types>(),
+                                    name: std::any::type_name::<#types>(),
                                 });
                             )*
                         }
@@ -175,9 +200,9 @@ pub fn impl_archetype(input: proc_macro::TokenStream) -> proc_macro::TokenStream
 
 #[proc_macro]
 pub fn impl_query(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let count = parse_macro_input!(input as Count).count;
+    let arities = parse_macro_input!(input as Arities);
     let output = {
-        let impls = (1..=count).map(|count| {
+        let impls = arities.range(1).map(|count| {
             // eg "A", "B"
             let types = (0..count).map(|v| n_to_type(v, count));
             // eg "(A, B,)"
@@ -201,8 +226,21 @@ pub fn impl_query(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                 let types = types.clone();
                 quote!(#(#types::r#type()),*)
             };
+            let mutable = {
+                let types = types.clone();
+                quote!(#(|| #types::MUTABLE)*)
+            };
+            let borrows = {
+                let types = types.clone();
+                quote!([#(#types::borrow()),*])
+            };
+            let accesses = {
+                let types = types.clone();
+                quote!([#(#types::access()),*])
+            };
             quote! {
                 impl #generics Query for #tuple {
+                    const MUTABLE: bool = false #mutable;
                     fn match_archetype(archetype: &Archetype) -> bool {
                         true #matches
                     }
@@ -218,6 +256,12 @@ pub fn impl_query(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                     fn types() -> Vec<TypeId> {
                         [#typeids].into_iter().flatten().collect()
                     }
+                    fn access() -> Vec<ComponentAccess> {
+                        #accesses.into_iter().flatten().collect()
+                    }
+                    fn check_aliasing() -> Result<(), EcsError> {
+                        check_aliasing(#borrows)
+                    }
                 }
             }
         });
@@ -230,8 +274,8 @@ pub fn impl_query(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 
 #[proc_macro]
 pub fn impl_system(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let count = parse_macro_input!(input as Count).count;
-    let impls = (0..=count).map(|count| {
+    let arities = parse_macro_input!(input as Arities);
+    let impls = arities.range(0).map(|count| {
         // eg "A", "B"
         let types = (0..count).map(|v| n_to_type(v, count));
         let registers = {
@@ -253,6 +297,10 @@ pub fn impl_system(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                 #(#types::fetch(context)),*
             }
         };
+        let checks = {
+            let types = types.clone();
+            quote!(#(#types::check(context)?;)*)
+        };
         quote! {
             impl #generics IntoSystem<(#(#types),*)> for Func {
                 fn into_system(self, mappings: &mut RequirementsMappings) -> System {
@@ -262,10 +310,20 @@ pub fn impl_system(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                     // Arguments have been registering so unwrap is safe
                     let requirements = builder.build().unwrap();
                     System {
+                        name: std::any::type_name::<Func>(),
                         requirements,
                         run: Box::new(move |context| unsafe {
                             self(#args)
                         }),
+                        check: Box::new(|context| {
+                            #checks
+                            Ok(())
+                        }),
+                        last_run: std::sync::atomic::AtomicU64::new(0),
+                        last_tick: std::sync::atomic::AtomicU32::new(0),
+                        commands: Default::default(),
+                        locals: Default::default(),
+                        condition: None,
                     }
                 }
             }
@@ -276,9 +334,14 @@ pub fn impl_system(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 
 #[proc_macro]
 pub fn impl_res_query(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let count = parse_macro_input!(input as Count).count;
-    let impls = (0..=count).map(|count| {
-        let types = (0..=count).map(|v| n_to_type(v, count));
+    let arities = parse_macro_input!(input as Arities);
+    // A bare count N means arities 1 to N + 1
+    let range = match arities.start {
+        Some(_) => arities.range(1),
+        None => 1..=arities.end + 1,
+    };
+    let impls = range.map(|count| {
+        let types = (0..count).map(|v| n_to_type(v, count));
         let generics = {
             let types = types.clone();
             quote!(<'a, #(#types: ResourceQuerySingle<'a>),*>)
@@ -293,20 +356,22 @@ pub fn impl_res_query(input: proc_macro::TokenStream) -> proc_macro::TokenStream
         };
         quote! {
             impl #generics ResourceQuery<'a> for #tuple {
-                fn fetch(executor: &'a mut Executor) -> Option<Self> {
+                fn fetch(executor: &'a mut Executor) -> Result<Self, EcsError> {
                     use std::collections::HashMap;
                     let mut muts = HashMap::with_capacity(#count as usize);
                     for (t, n, m) in #borrows {
                         if let Some((name, mutable)) = muts.get(&t) {
                             // If either one is mutable
                             if *mutable || m {
-                                panic!("Aliasing problem in query on {}", name);
+                                return Err(EcsError::BorrowConflict {
+                                    details: format!("Aliasing problem in query on {}", name),
+                                });
                             }
                         }   
                         muts.insert(t, (n, m));
                     }
 
-                    Some(unsafe {(
+                    Ok(unsafe {(
                         #(
                             #types::fetch(executor)?
                         ),*,
@@ -317,3 +382,119 @@ pub fn impl_res_query(input: proc_macro::TokenStream) -> proc_macro::TokenStream
     });
     quote!(#(#impls)*).into()
 }
+
+/// A struct of system arguments usable as a single argument, to not repeat the arguments a lot
+/// of systems share:
+///
+/// ```ignore
+/// #[derive(SystemParam)]
+/// struct RenderCtx<'w> {
+///     gfx: &'w GraphicContext,
+///     renderer: &'w mut WorldRenderer,
+///     #[system_param(skip)]
+///     scratch: Vec<u8>,
+/// }
+///
+/// fn render(ctx: RenderCtx, lights: Entities<&LightComponent>) {}
+/// ```
+///
+/// The system has the requirements of all the fields, which can be derived params themselves.
+/// Skipped fields are built with `Default::default`. The struct can have lifetimes, but no type
+/// or const parameters.
+#[proc_macro_derive(SystemParam, attributes(system_param))]
+pub fn derive_system_param(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    system_param(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn system_param(input: DeriveInput) -> syn::Result<TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "SystemParam can only be derived for structs",
+            ))
+        }
+    };
+    if let Some(param) = input.generics.type_params().next() {
+        return Err(syn::Error::new_spanned(
+            param,
+            "SystemParam can't be derived for structs with type parameters, only lifetimes",
+        ));
+    }
+    if let Some(param) = input.generics.const_params().next() {
+        return Err(syn::Error::new_spanned(
+            param,
+            "SystemParam can't be derived for structs with const parameters, only lifetimes",
+        ));
+    }
+
+    let mut types = Vec::new();
+    let mut inits = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        };
+        if skipped(field)? {
+            inits.push(quote_spanned!(field.span()=> #member: ::core::default::Default::default()));
+            continue;
+        }
+        // Spanned on the type, so that a field that isn't an argument is pointed at
+        let ty = &field.ty;
+        inits.push(quote_spanned! {ty.span()=>
+            #member: <#ty as ::ecs::__private::SystemArgument>::fetch(context)
+        });
+        types.push(ty);
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let arg = quote!(::ecs::__private::SystemArgument);
+    Ok(quote! {
+        impl #impl_generics #arg for #name #ty_generics #where_clause {
+            #[allow(unused_unsafe)]
+            unsafe fn fetch(context: &::ecs::__private::ExecutionContext) -> Self {
+                unsafe { Self { #(#inits),* } }
+            }
+            fn check(
+                context: &::ecs::__private::ExecutionContext,
+            ) -> ::core::result::Result<(), ::ecs::EcsError> {
+                #(<#types as #arg>::check(context)?;)*
+                ::core::result::Result::Ok(())
+            }
+            fn require(
+                builder: ::ecs::__private::RequirementsBuilder,
+            ) -> ::ecs::__private::RequirementsBuilder {
+                #(let builder = <#types as #arg>::require(builder);)*
+                builder
+            }
+            fn register(mappings: &mut ::ecs::__private::RequirementsMappings) {
+                #(<#types as #arg>::register(mappings);)*
+            }
+        }
+    })
+}
+
+/// If the field is marked `#[system_param(skip)]`
+fn skipped(field: &Field) -> syn::Result<bool> {
+    let mut skip = false;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|a| a.path.is_ident("system_param"))
+    {
+        attr.parse_args_with(|input: ParseStream| {
+            let ident = input.parse::<Ident>()?;
+            if ident != "skip" {
+                return Err(syn::Error::new(ident.span(), "expected `skip`"));
+            }
+            skip = true;
+            Ok(())
+        })?;
+    }
+    Ok(skip)
+}
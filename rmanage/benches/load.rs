@@ -0,0 +1,94 @@
+//! Loads from several threads, to compare the locking of the manager between changes. The
+//! contention only shows with as many cores as threads, results from a machine with fewer cores
+//! say nothing about it.
+
+use std::thread;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mktemp::Temp;
+use rmanage::{Resource, ResourceManager, ResourceManagerBuilder};
+
+const FILES: usize = 64;
+const FILE_SIZE: usize = 64 * 1024;
+
+/// A manager with FILES physical resources of FILE_SIZE bytes
+fn setup() -> (ResourceManager, Vec<Resource>, Temp) {
+    let dir = Temp::new_dir().unwrap();
+    let rm = ResourceManagerBuilder::begin()
+        .with_resource_path(dir.as_path())
+        .build();
+    let resources = (0..FILES)
+        .map(|i| {
+            let path = dir.as_path().join(i.to_string());
+            std::fs::write(&path, vec![i as u8; FILE_SIZE]).unwrap();
+            rm.add_physical(&path).unwrap()
+        })
+        .collect();
+    (rm, resources, dir)
+}
+
+/// Every thread loads all the resources, starting at a different offset so they overlap
+fn parallel_load(c: &mut Criterion) {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    if cores < 8 {
+        eprintln!("parallel_load: only {cores} cores, the results don't measure contention");
+    }
+    let mut group = c.benchmark_group("parallel_load");
+    group.throughput(Throughput::Bytes((FILES * FILE_SIZE) as u64));
+    let (rm, resources, _dir) = setup();
+    for threads in [1, 4, 8] {
+        group.bench_function(BenchmarkId::from_parameter(threads), |b| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for t in 0..threads {
+                        let (rm, resources) = (&rm, &resources);
+                        s.spawn(move || {
+                            for i in 0..FILES {
+                                let res = resources[(i + t * FILES / threads) % FILES];
+                                black_box(rm.get_resource(res).unwrap());
+                            }
+                        });
+                    }
+                });
+                for &res in &resources {
+                    rm.free(res).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Reads of loaded resources while another thread keeps loading and freeing others
+fn read_while_loading(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_while_loading");
+    group.throughput(Throughput::Elements(FILES as u64));
+    let (rm, resources, _dir) = setup();
+    let (hot, cold) = resources.split_at(FILES / 2);
+    for &res in hot {
+        rm.ensure_loaded(res).unwrap();
+    }
+    let stop = std::sync::atomic::AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+                for &res in cold {
+                    rm.ensure_loaded(res).unwrap();
+                    rm.free(res).unwrap();
+                }
+            }
+        });
+        group.bench_function("hot", |b| {
+            b.iter(|| {
+                for &res in hot {
+                    black_box(rm.get_resource(res).unwrap());
+                }
+            })
+        });
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parallel_load, read_while_loading);
+criterion_main!(benches);
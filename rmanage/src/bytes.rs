@@ -0,0 +1,85 @@
+use std::{fmt::Debug, fs::File, ops::Deref, path::Path, sync::Arc};
+
+use fs2::FileExt;
+use memmap2::Mmap;
+
+/// The data of a resource. Cheap to clone, and derefs to the bytes.
+#[derive(Clone)]
+pub enum ResourceBytes {
+    /// Read in memory
+    Owned(Arc<[u8]>),
+    /// Memory mapped from the file, see `ResourceManager::add_physical_mapped`
+    Mapped(Arc<MappedFile>),
+}
+
+impl ResourceBytes {
+    /// Map a file, read only
+    pub(crate) fn map(path: &Path) -> std::io::Result<Self> {
+        MappedFile::open(path).map(|file| Self::Mapped(Arc::new(file)))
+    }
+    /// Returns true if the bytes are mapped from a file
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Self::Mapped(_))
+    }
+}
+
+impl Deref for ResourceBytes {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Owned(bytes) => bytes,
+            Self::Mapped(file) => &file.map,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ResourceBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Arc<[u8]>> for ResourceBytes {
+    fn from(bytes: Arc<[u8]>) -> Self {
+        Self::Owned(bytes)
+    }
+}
+
+impl From<Vec<u8>> for ResourceBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Owned(bytes.into())
+    }
+}
+
+impl Debug for ResourceBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.is_mapped() { "Mapped" } else { "Owned" };
+        write!(f, "ResourceBytes::{kind}({} bytes)", self.len())
+    }
+}
+
+/// A read only memory map of a file. The file is locked (shared) as long as it is mapped.
+pub struct MappedFile {
+    map: Mmap,
+    // Holds the lock
+    _file: File,
+}
+
+impl MappedFile {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        match FileExt::try_lock_shared(&file) {
+            Ok(()) => {}
+            // Someone is writing the file
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                return Err(e)
+            }
+            // Locks aren't supported here (some network file systems), map it anyway
+            Err(_) => {}
+        }
+        // SAFETY: The lock keeps away writers that lock the file, any other change to the file
+        // while mapped is UB, see `ResourceManager::add_physical_mapped`.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self { map, _file: file })
+    }
+}
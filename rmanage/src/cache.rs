@@ -0,0 +1,428 @@
+//! What the cache directory holds.
+//!
+//! The state of the manager is in the `manifest`, and each `ResourceManager::cache` appends what
+//! changed since the previous one to the `journal`, so that unchanged metadata isn't rewritten.
+//! Once the journal grows past a limit it is folded back into a new manifest (compacted). The
+//! data of each virtual resource is in its own file, named after the resource, and is only
+//! written when the resource is added or replaced. With
+//! `ResourceManagerBuilder::with_physical_cache` the data of the physical resources is kept too,
+//! in the `physical` directory and named after its hash, so that files that didn't change aren't
+//! read again (they may be far away).
+//!
+//! Caches from before the journal (a `cache` and a `meta` file) are still read, and replaced by
+//! the next `ResourceManager::cache`.
+
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufReader, ErrorKind, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use slotmap::{Key, SecondaryMap, SlotMap};
+
+use crate::{RawResourceManager, Resource, ResourceError, HASH_SEED};
+
+const MANIFEST: &str = "manifest";
+const JOURNAL: &str = "journal";
+const PHYSICAL: &str = "physical";
+/// The files of the monolithic format
+pub(crate) const LEGACY_CACHE: &str = "cache";
+pub(crate) const LEGACY_META: &str = "meta";
+
+/// Size of the journal past which it is compacted, by default
+pub(crate) const JOURNAL_LIMIT: u64 = 1 << 20;
+
+/// How long after a file is checked its modification time stays unreliable: a write in the same
+/// tick of the file system's clock wouldn't change it. Also covers clocks slightly off.
+const MTIME_MARGIN: Duration = Duration::from_secs(2);
+
+/// What `ResourceManager::cache` wrote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheReport {
+    /// Resources whose data was written
+    pub blobs_written: usize,
+    pub blob_bytes: usize,
+    /// Data files of removed resources deleted
+    pub blobs_removed: usize,
+    /// Physical resources hashed, the others were known unchanged from the size and modification
+    /// time of their file
+    pub hashed: usize,
+    /// Changes of the resources and relations recorded
+    pub changes: usize,
+    /// Whether the manifest was rewritten whole (on the first cache, and to compact the journal)
+    pub compacted: bool,
+}
+
+/// What `ResourceManager::sync_cache` read
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Virtual resources loaded from the cache
+    pub loaded: usize,
+    /// Physical resources loaded from the cache instead of their file
+    pub physical_loaded: usize,
+    /// Resources of the cache that are dead: their file changed or couldn't be read, they were
+    /// derived from a dead one, or nothing pointed to them anymore
+    pub dropped: Vec<Resource>,
+    /// The files that couldn't be read
+    pub errors: Vec<(PathBuf, std::io::Error)>,
+}
+
+/// What changed since the last `ResourceManager::cache`
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Dirty {
+    /// Resources added or removed
+    pub(crate) resources: HashSet<Resource>,
+    /// Virtual resources whose data was replaced
+    pub(crate) data: HashSet<Resource>,
+    /// Relations set or removed
+    pub(crate) relations: HashSet<(Resource, String)>,
+}
+
+impl Dirty {
+    /// Put back the changes of a cache that failed
+    pub(crate) fn merge(&mut self, other: Dirty) {
+        self.resources.extend(other.resources);
+        self.data.extend(other.data);
+        self.relations.extend(other.relations);
+    }
+}
+
+/// What a physical resource was when the virtual resources derived from it were cached
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct PhysicalResource {
+    pub(crate) path: PathBuf,
+    pub(crate) size: u64,
+    pub(crate) hash: u128,
+    /// Modification time of the file, None if it doesn't tell anything about the data
+    pub(crate) mtime: Option<SystemTime>,
+    /// When the file was hashed
+    pub(crate) checked: SystemTime,
+}
+
+impl PhysicalResource {
+    /// Whether the file is known to be unchanged from its size and modification time, without
+    /// hashing it. Never if it was modified too close to the time it was checked, or after (the
+    /// clock was off).
+    pub(crate) fn unchanged(&self, size: u64, mtime: Option<SystemTime>) -> bool {
+        let Some(recorded) = self.mtime else {
+            return false;
+        };
+        size == self.size
+            && mtime == Some(recorded)
+            && recorded
+                .checked_add(MTIME_MARGIN)
+                .is_some_and(|t| t <= self.checked)
+    }
+}
+
+/// Size and modification time of a file
+pub(crate) fn stat(path: &Path) -> std::io::Result<(u64, Option<SystemTime>)> {
+    let meta = std::fs::metadata(path)?;
+    Ok((meta.len(), meta.modified().ok()))
+}
+
+/// Where the data of a physical resource is kept
+pub(crate) fn physical_blob(cache_path: &Path, hash: u128) -> PathBuf {
+    cache_path.join(PHYSICAL).join(format!("{hash:032x}"))
+}
+
+/// Write the data of a physical resource
+pub(crate) fn write_physical(cache_path: &Path, hash: u128, data: &[u8]) -> std::io::Result<()> {
+    std::fs::create_dir_all(cache_path.join(PHYSICAL))?;
+    std::fs::write(physical_blob(cache_path, hash), data)
+}
+
+/// The data of a physical resource, if it is kept whole. Data that doesn't match (cut short by a
+/// crash while writing it) is deleted, for the next `ResourceManager::cache` to write it again.
+pub(crate) fn read_physical(cache_path: &Path, physical: &PhysicalResource) -> Option<Vec<u8>> {
+    let path = physical_blob(cache_path, physical.hash);
+    let data = std::fs::read(&path).ok()?;
+    if data.len() as u64 == physical.size && crate::hash(&data, HASH_SEED) == physical.hash {
+        Some(data)
+    } else {
+        std::fs::remove_file(path).ok();
+        None
+    }
+}
+
+/// Delete the data of the physical resources whose hash isn't in keep, returns how many there
+/// were
+pub(crate) fn prune_physical(cache_path: &Path, keep: &HashSet<u128>) -> std::io::Result<usize> {
+    let entries = match std::fs::read_dir(cache_path.join(PHYSICAL)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let hash = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| u128::from_str_radix(name, 16).ok());
+        if !hash.is_some_and(|hash| keep.contains(&hash)) {
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// What the cache directory holds, as far as the manager knows
+#[derive(Debug, Default)]
+pub(crate) struct CacheState {
+    pub(crate) generation: u64,
+    /// Length of the valid part of the journal
+    pub(crate) journal_len: u64,
+    pub(crate) physical: SecondaryMap<Resource, PhysicalResource>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Manifest {
+    /// Changed on each compaction, a journal of another generation is stale
+    pub(crate) generation: u64,
+    pub(crate) state: RawResourceManager,
+    pub(crate) physical: SecondaryMap<Resource, PhysicalResource>,
+}
+
+/// A change recorded in the journal
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) enum Change {
+    Added {
+        res: Resource,
+        is_virtual: bool,
+    },
+    Removed(Resource),
+    /// A physical resource was hashed (with its location)
+    Physical(Resource, PhysicalResource),
+    /// A relation was set, or removed if None
+    Relation {
+        from: Resource,
+        relation: String,
+        to: Option<Resource>,
+    },
+}
+
+/// The changes of a `ResourceManager::cache`, written at once
+#[derive(Serialize, Deserialize)]
+struct Batch {
+    generation: u64,
+    changes: Vec<Change>,
+}
+
+/// A slot of a `SlotMap` as it serializes it. The keys have to be restored exactly, which
+/// SlotMap can't do otherwise.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct Slot {
+    value: Option<()>,
+    version: u32,
+}
+
+fn slots(resources: &SlotMap<Resource, ()>) -> Result<Vec<Slot>, ResourceError> {
+    Ok(bincode::deserialize(&bincode::serialize(resources)?)?)
+}
+
+fn slot_map(slots: &[Slot]) -> Result<SlotMap<Resource, ()>, ResourceError> {
+    Ok(bincode::deserialize(&bincode::serialize(slots)?)?)
+}
+
+/// Occupy (or free) the slot of a key. Versions of a slot only go up, the highest is the
+/// current one: odd while occupied, then the next even one once freed.
+fn set_slot(slots: &mut Vec<Slot>, res: Resource, occupied: bool) {
+    let ffi = res.data().as_ffi();
+    let (index, version) = (ffi as u32 as usize, (ffi >> 32) as u32);
+    let version = if occupied { version } else { version + 1 };
+    if slots.len() <= index {
+        slots.resize(
+            index + 1,
+            Slot {
+                value: None,
+                version: 0,
+            },
+        );
+    }
+    let slot = &mut slots[index];
+    if version > slot.version {
+        *slot = Slot {
+            value: occupied.then_some(()),
+            version,
+        };
+    }
+}
+
+impl Manifest {
+    fn apply(&mut self, slots: &mut Vec<Slot>, changes: Vec<Change>) {
+        let state = &mut self.state;
+        for change in changes {
+            match change {
+                Change::Added { res, is_virtual } => {
+                    set_slot(slots, res, true);
+                    if is_virtual {
+                        state.virtual_resources.insert(res, ());
+                    }
+                }
+                Change::Removed(res) => {
+                    set_slot(slots, res, false);
+                    state.virtual_resources.remove(res);
+                    state.locations.remove_by_right(&res);
+                    self.physical.remove(res);
+                }
+                Change::Physical(res, physical) => {
+                    state.locations.insert(physical.path.clone(), res);
+                    self.physical.insert(res, physical);
+                }
+                Change::Relation { from, relation, to } => {
+                    let key = (from, relation);
+                    match to {
+                        Some(to) => state.relations.insert(key, to),
+                        None => state.relations.remove(&key),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// What was read from the cache directory
+pub(crate) struct Contents {
+    /// With the journal applied
+    pub(crate) manifest: Manifest,
+    pub(crate) journal_len: u64,
+    /// Seed of the hashes of the physical resources
+    pub(crate) seed: u64,
+    /// Read from the monolithic format
+    pub(crate) legacy: bool,
+}
+
+/// Read the cache directory. A journal cut short (by a crash while appending to it) is read up to
+/// its last whole batch.
+pub(crate) fn read(cache_path: &Path) -> Result<Contents, ResourceError> {
+    let file = match File::open(cache_path.join(MANIFEST)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return read_legacy(cache_path),
+        Err(e) => return Err(e.into()),
+    };
+    let mut manifest: Manifest = bincode::deserialize_from(BufReader::new(file))?;
+    let journal = match std::fs::read(cache_path.join(JOURNAL)) {
+        Ok(journal) => journal,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut slots = slots(&manifest.state.resources)?;
+    let mut rest = journal.as_slice();
+    let mut journal_len = 0;
+    while !rest.is_empty() {
+        let Ok(batch) = bincode::deserialize_from::<_, Batch>(&mut rest) else {
+            break;
+        };
+        // Left by a compaction that didn't get to remove it
+        if batch.generation != manifest.generation {
+            break;
+        }
+        manifest.apply(&mut slots, batch.changes);
+        journal_len = (journal.len() - rest.len()) as u64;
+    }
+    manifest.state.resources = slot_map(&slots)?;
+
+    Ok(Contents {
+        manifest,
+        journal_len,
+        seed: HASH_SEED,
+        legacy: false,
+    })
+}
+
+/// Replace the manifest, which makes the journal stale. The files of the monolithic format go
+/// too.
+pub(crate) fn write_manifest(cache_path: &Path, manifest: &Manifest) -> Result<(), ResourceError> {
+    let temp = cache_path.join("manifest.tmp");
+    std::fs::write(&temp, bincode::serialize(manifest)?)?;
+    std::fs::rename(&temp, cache_path.join(MANIFEST))?;
+    for stale in [JOURNAL, LEGACY_CACHE, LEGACY_META] {
+        match std::fs::remove_file(cache_path.join(stale)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Size the changes would take in the journal
+pub(crate) fn batch_size(generation: u64, changes: &[Change]) -> Result<u64, ResourceError> {
+    Ok(bincode::serialized_size(&(generation, changes))?)
+}
+
+/// Append changes to the valid part of the journal, returns its new length
+pub(crate) fn append(
+    cache_path: &Path,
+    journal_len: u64,
+    generation: u64,
+    changes: Vec<Change>,
+) -> Result<u64, ResourceError> {
+    let bytes = bincode::serialize(&Batch {
+        generation,
+        changes,
+    })?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(cache_path.join(JOURNAL))?;
+    // Drop what a crash left after the last whole batch
+    file.set_len(journal_len)?;
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(&bytes)?;
+    Ok(journal_len + bytes.len() as u64)
+}
+
+/// A physical resource in the monolithic format, hashed with the seed of the cache
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct LegacyPhysicalResource {
+    pub(crate) path: PathBuf,
+    pub(crate) size: usize,
+    pub(crate) hash: u128,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct LegacyMeta {
+    pub(crate) physical_resources: SecondaryMap<Resource, LegacyPhysicalResource>,
+    pub(crate) seed: u64,
+}
+
+/// Read a cache in the monolithic format. Only kept for one release, to migrate the caches
+/// written before the journal.
+fn read_legacy(cache_path: &Path) -> Result<Contents, ResourceError> {
+    let cache_file = File::open(cache_path.join(LEGACY_CACHE))?;
+    let meta_file = File::open(cache_path.join(LEGACY_META))?;
+    let state: RawResourceManager = bincode::deserialize_from(BufReader::new(cache_file))?;
+    let meta: LegacyMeta = bincode::deserialize_from(BufReader::new(meta_file))?;
+    let physical = meta
+        .physical_resources
+        .into_iter()
+        .map(|(res, physical)| {
+            let physical = PhysicalResource {
+                path: physical.path,
+                size: physical.size as u64,
+                hash: physical.hash,
+                // Always hashed
+                mtime: None,
+                checked: SystemTime::UNIX_EPOCH,
+            };
+            (res, physical)
+        })
+        .collect();
+    Ok(Contents {
+        manifest: Manifest {
+            generation: 0,
+            state,
+            physical,
+        },
+        journal_len: 0,
+        seed: meta.seed,
+        legacy: true,
+    })
+}
@@ -0,0 +1,374 @@
+//! The resources and the relations between them as a graph, to export for build tooling and to
+//! debug what depends on what.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::Resource;
+
+/// How the data of a resource is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResourceKind {
+    /// Read from a file
+    Physical,
+    /// Memory mapped from a file
+    Mapped,
+    /// Created in memory, there is no file
+    Virtual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceNode {
+    pub resource: Resource,
+    /// None for virtual resources
+    pub path: Option<PathBuf>,
+    pub kind: ResourceKind,
+}
+
+impl ResourceNode {
+    pub fn is_virtual(&self) -> bool {
+        self.kind == ResourceKind::Virtual
+    }
+    /// The path, or a placeholder for virtual resources
+    pub fn label(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => "<virtual>".to_owned(),
+        }
+    }
+}
+
+/// `from` depends on `to`, `relation` tells how
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResourceEdge {
+    pub from: Resource,
+    pub to: Resource,
+    pub relation: String,
+}
+
+/// A directed graph of resources. Unlike the relations of the `ResourceManager` a resource can
+/// have any number of edges of a kind, so loaders can add the references they find to it.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceGraph {
+    nodes: Vec<ResourceNode>,
+    edges: Vec<ResourceEdge>,
+}
+
+#[derive(Serialize)]
+struct JsonNode<'a> {
+    id: usize,
+    path: Option<&'a Path>,
+    kind: ResourceKind,
+    #[serde(rename = "virtual")]
+    is_virtual: bool,
+}
+
+#[derive(Serialize)]
+struct JsonEdge<'a> {
+    from: usize,
+    to: usize,
+    relation: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonGraph<'a> {
+    nodes: Vec<JsonNode<'a>>,
+    edges: Vec<JsonEdge<'a>>,
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl ResourceGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn nodes(&self) -> &[ResourceNode] {
+        &self.nodes
+    }
+    pub fn edges(&self) -> &[ResourceEdge] {
+        &self.edges
+    }
+    pub fn node(&self, res: Resource) -> Option<&ResourceNode> {
+        self.nodes.iter().find(|node| node.resource == res)
+    }
+    /// The resource of a path
+    pub fn find(&self, path: impl AsRef<Path>) -> Option<Resource> {
+        let path = path.as_ref();
+        self.nodes
+            .iter()
+            .find(|node| node.path.as_deref() == Some(path))
+            .map(|node| node.resource)
+    }
+    /// Add a node, replacing the one of the same resource if any
+    pub fn add_node(&mut self, node: ResourceNode) {
+        match self.nodes.iter_mut().find(|n| n.resource == node.resource) {
+            Some(existing) => *existing = node,
+            None => self.nodes.push(node),
+        }
+    }
+    /// Add an edge, the same edge is only kept once. Both ends should have a node.
+    pub fn add_edge(&mut self, from: Resource, relation: &str, to: Resource) {
+        let edge = ResourceEdge {
+            from,
+            to,
+            relation: relation.to_owned(),
+        };
+        if !self.edges.contains(&edge) {
+            self.edges.push(edge);
+        }
+    }
+    /// Add the nodes and edges of another graph, its nodes replace the ones of this graph
+    pub fn merge(&mut self, other: ResourceGraph) {
+        for node in other.nodes {
+            self.add_node(node);
+        }
+        for edge in other.edges {
+            self.add_edge(edge.from, &edge.relation, edge.to);
+        }
+    }
+    /// Everything reachable from `roots` following edges forward (or backward), roots excluded
+    /// unless part of a cycle. Resources are in breadth first order.
+    fn closure(&self, roots: &[Resource], reverse: bool) -> Vec<Resource> {
+        let mut seen = roots.iter().copied().collect::<HashSet<_>>();
+        let mut queue = roots.iter().copied().collect::<VecDeque<_>>();
+        let mut found = Vec::new();
+        while let Some(res) = queue.pop_front() {
+            for edge in &self.edges {
+                let (from, to) = if reverse {
+                    (edge.to, edge.from)
+                } else {
+                    (edge.from, edge.to)
+                };
+                if from == res && seen.insert(to) {
+                    found.push(to);
+                    queue.push_back(to);
+                }
+            }
+        }
+        found
+    }
+    /// Everything `res` depends on, directly or not
+    pub fn dependencies(&self, res: Resource) -> Vec<Resource> {
+        self.closure(&[res], false)
+    }
+    /// Everything that depends on `res`, directly or not
+    pub fn dependents(&self, res: Resource) -> Vec<Resource> {
+        self.closure(&[res], true)
+    }
+    /// The part of the graph made of `roots` and their dependencies (or dependents)
+    pub fn subgraph(&self, roots: &[Resource], reverse: bool) -> ResourceGraph {
+        let mut kept = roots.iter().copied().collect::<HashSet<_>>();
+        kept.extend(self.closure(roots, reverse));
+        ResourceGraph {
+            nodes: self
+                .nodes
+                .iter()
+                .filter(|node| kept.contains(&node.resource))
+                .cloned()
+                .collect(),
+            edges: self
+                .edges
+                .iter()
+                .filter(|edge| kept.contains(&edge.from) && kept.contains(&edge.to))
+                .cloned()
+                .collect(),
+        }
+    }
+    /// The same graph with the paths under `root` made relative to it, for stable exports
+    pub fn relative_to(mut self, root: impl AsRef<Path>) -> Self {
+        let root = root.as_ref();
+        for node in &mut self.nodes {
+            if let Some(relative) = node.path.as_ref().and_then(|p| p.strip_prefix(root).ok()) {
+                node.path = Some(relative.to_owned());
+            }
+        }
+        self
+    }
+    /// Nodes sorted (physical ones by path, then virtual ones) with the index of each resource,
+    /// and edges sorted by those indices, so exports don't depend on insertion order.
+    fn sorted(
+        &self,
+    ) -> (
+        Vec<&ResourceNode>,
+        HashMap<Resource, usize>,
+        Vec<&ResourceEdge>,
+    ) {
+        let mut nodes = self.nodes.iter().collect::<Vec<_>>();
+        nodes.sort_by(|a, b| {
+            (a.path.is_none(), &a.path, a.resource).cmp(&(b.path.is_none(), &b.path, b.resource))
+        });
+        let ids = nodes
+            .iter()
+            .enumerate()
+            .map(|(id, node)| (node.resource, id))
+            .collect::<HashMap<_, _>>();
+        let mut edges = self
+            .edges
+            .iter()
+            .filter(|edge| ids.contains_key(&edge.from) && ids.contains_key(&edge.to))
+            .collect::<Vec<_>>();
+        edges.sort_by_key(|edge| (ids[&edge.from], &edge.relation, ids[&edge.to]));
+        (nodes, ids, edges)
+    }
+    /// Graphviz source of the graph, virtual resources are dashed
+    pub fn to_dot(&self) -> String {
+        let (nodes, ids, edges) = self.sorted();
+        let mut dot = String::from("digraph resources {\n");
+        for (id, node) in nodes.iter().enumerate() {
+            let style = if node.is_virtual() {
+                ", style=dashed"
+            } else {
+                ""
+            };
+            let _ = writeln!(
+                dot,
+                "    r{id} [label=\"{}\"{style}];",
+                escape(&node.label())
+            );
+        }
+        for edge in edges {
+            let _ = writeln!(
+                dot,
+                "    r{} -> r{} [label=\"{}\"];",
+                ids[&edge.from],
+                ids[&edge.to],
+                escape(&edge.relation)
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+    /// JSON of the graph: nodes with an id, their path, kind and virtual flag, and edges between
+    /// node ids
+    pub fn to_json(&self) -> String {
+        let (nodes, ids, edges) = self.sorted();
+        let graph = JsonGraph {
+            nodes: nodes
+                .iter()
+                .enumerate()
+                .map(|(id, node)| JsonNode {
+                    id,
+                    path: node.path.as_deref(),
+                    kind: node.kind,
+                    is_virtual: node.is_virtual(),
+                })
+                .collect(),
+            edges: edges
+                .iter()
+                .map(|edge| JsonEdge {
+                    from: ids[&edge.from],
+                    to: ids[&edge.to],
+                    relation: &edge.relation,
+                })
+                .collect(),
+        };
+        serde_json::to_string_pretty(&graph).expect("the graph is always serializable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slotmap::SlotMap;
+
+    fn physical(resource: Resource, path: &str) -> ResourceNode {
+        ResourceNode {
+            resource,
+            path: Some(path.into()),
+            kind: ResourceKind::Physical,
+        }
+    }
+
+    /// scene -> a, b; a, b -> shared -> virtual
+    fn diamond() -> (ResourceGraph, [Resource; 5]) {
+        let mut keys = SlotMap::<Resource, ()>::with_key();
+        let [scene, a, b, shared, derived] = [(); 5].map(|_| keys.insert(()));
+        let mut graph = ResourceGraph::new();
+        graph.add_node(physical(scene, "scene.gltf"));
+        graph.add_node(physical(a, "a.png"));
+        graph.add_node(physical(b, "b.png"));
+        graph.add_node(physical(shared, "shared.bin"));
+        graph.add_node(ResourceNode {
+            resource: derived,
+            path: None,
+            kind: ResourceKind::Virtual,
+        });
+        graph.add_edge(scene, "image", a);
+        graph.add_edge(scene, "image", b);
+        graph.add_edge(a, "buffer", shared);
+        graph.add_edge(b, "buffer", shared);
+        graph.add_edge(shared, "decoded", derived);
+        // Added twice by two loaders
+        graph.add_edge(scene, "image", a);
+        (graph, [scene, a, b, shared, derived])
+    }
+
+    #[test]
+    fn closures() {
+        let (graph, [scene, a, b, shared, derived]) = diamond();
+        assert_eq!(5, graph.edges().len());
+        assert_eq!(vec![a, b, shared, derived], graph.dependencies(scene));
+        assert_eq!(vec![shared, derived], graph.dependencies(a));
+        assert!(graph.dependencies(derived).is_empty());
+        assert_eq!(vec![shared, a, b, scene], graph.dependents(derived));
+        assert_eq!(vec![scene], graph.dependents(b));
+        assert_eq!(Some(shared), graph.find("shared.bin"));
+
+        let sub = graph.subgraph(&[a], false);
+        assert_eq!(3, sub.nodes().len());
+        assert_eq!(2, sub.edges().len());
+        let sub = graph.subgraph(&[shared], true);
+        assert_eq!(4, sub.nodes().len());
+        assert!(sub.node(derived).is_none());
+    }
+
+    #[test]
+    fn exports() {
+        let (graph, _) = diamond();
+        assert_eq!(
+            "digraph resources {
+    r0 [label=\"a.png\"];
+    r1 [label=\"b.png\"];
+    r2 [label=\"scene.gltf\"];
+    r3 [label=\"shared.bin\"];
+    r4 [label=\"<virtual>\", style=dashed];
+    r0 -> r3 [label=\"buffer\"];
+    r1 -> r3 [label=\"buffer\"];
+    r2 -> r0 [label=\"image\"];
+    r2 -> r1 [label=\"image\"];
+    r3 -> r4 [label=\"decoded\"];
+}
+",
+            graph.to_dot()
+        );
+
+        let json = serde_json::from_str::<serde_json::Value>(&graph.to_json()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "nodes": [
+                    { "id": 0, "path": "a.png", "kind": "physical", "virtual": false },
+                    { "id": 1, "path": "b.png", "kind": "physical", "virtual": false },
+                    { "id": 2, "path": "scene.gltf", "kind": "physical", "virtual": false },
+                    { "id": 3, "path": "shared.bin", "kind": "physical", "virtual": false },
+                    { "id": 4, "path": null, "kind": "virtual", "virtual": true },
+                ],
+                "edges": [
+                    { "from": 0, "to": 3, "relation": "buffer" },
+                    { "from": 1, "to": 3, "relation": "buffer" },
+                    { "from": 2, "to": 0, "relation": "image" },
+                    { "from": 2, "to": 1, "relation": "image" },
+                    { "from": 3, "to": 4, "relation": "decoded" },
+                ],
+            }),
+            json
+        );
+    }
+}
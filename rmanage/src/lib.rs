@@ -1,21 +1,39 @@
-#![feature(once_cell)]
+#![deny(unstable_features)]
 
 use bimap::BiHashMap;
 use directories::BaseDirs;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 use slotmap::{SecondaryMap, SlotMap};
 use std::{
-    collections::HashMap,
-    fs::File,
+    collections::{hash_map::Entry, HashMap, HashSet},
     hash::Hash,
-    lazy::SyncOnceCell,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, OnceLock},
+    time::SystemTime,
 };
 use thiserror::Error;
 use twox_hash::{xxh3::HasherExt, Xxh3Hash128};
 
+mod bytes;
+mod cache;
+mod graph;
+mod loader;
+mod typed;
+mod watcher;
+
+pub use bytes::{MappedFile, ResourceBytes};
+pub use cache::{CacheReport, SyncReport};
+pub use graph::{ResourceEdge, ResourceGraph, ResourceKind, ResourceNode};
+pub use loader::ResourceLoadHandle;
+pub use typed::ResourceDecode;
+pub use watcher::ResourceEvent;
+
+use cache::{CacheState, Change, Contents, Dirty, Manifest, PhysicalResource};
+use loader::{Loader, PendingLoad};
+use typed::DecodedSlot;
+use watcher::{FileEvent, Watcher};
+
 slotmap::new_key_type! {
     pub struct Resource;
 }
@@ -28,14 +46,38 @@ pub enum ResourceError {
     IOError(std::io::Error),
     #[error("The resource already has a relation of this type")]
     WouldOverwriteRelation,
+    #[error("The resource has no relation of this type")]
+    NoSuchRelation,
     #[error("The resource is virtual")]
     ResourceIsVirtual,
+    #[error("The resource is physical")]
+    ResourceIsPhysical,
     #[error("The resource doesn't exist")]
     NoSuchResource,
     #[error("The resource manager doesn't have a cache path")]
     NoCachePath,
     #[error("A Bincode error occured on (de)serialization: {0}")]
     BinCodeError(bincode::ErrorKind),
+    #[error("The resource couldn't be decoded: {0}")]
+    DecodeError(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl ResourceError {
+    /// A copy of the error, for the handles sharing the result of a load
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Self::AlreadyInitialized => Self::AlreadyInitialized,
+            Self::IOError(e) => Self::IOError(std::io::Error::new(e.kind(), e.to_string())),
+            Self::WouldOverwriteRelation => Self::WouldOverwriteRelation,
+            Self::NoSuchRelation => Self::NoSuchRelation,
+            Self::ResourceIsVirtual => Self::ResourceIsVirtual,
+            Self::ResourceIsPhysical => Self::ResourceIsPhysical,
+            Self::NoSuchResource => Self::NoSuchResource,
+            Self::NoCachePath => Self::NoCachePath,
+            Self::BinCodeError(e) => Self::BinCodeError(bincode::ErrorKind::Custom(e.to_string())),
+            Self::DecodeError(e) => Self::DecodeError(e.to_string().into()),
+        }
+    }
 }
 
 impl From<std::io::Error> for ResourceError {
@@ -50,10 +92,10 @@ impl From<Box<bincode::ErrorKind>> for ResourceError {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// Serialized state of the manager (what goes in the cache), the data of the resources is
+/// written separately.
+#[derive(Deserialize, Serialize, Debug, Default)]
 struct RawResourceManager {
-    #[serde(skip)]
-    resources_data: SecondaryMap<Resource, Option<Arc<[u8]>>>,
     // Annoying but necessary as there is no other way to keep the same keys otherwise
     resources: SlotMap<Resource, ()>,
     relations: HashMap<(Resource, String), Resource>,
@@ -61,23 +103,111 @@ struct RawResourceManager {
     virtual_resources: SecondaryMap<Resource, ()>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct PhysicalResource {
-    path: PathBuf,
-    size: usize,
-    hash: u128,
+/// The data of a resource, None if it is physical and not loaded. Each slot has its own lock so
+/// loading a resource doesn't block any other.
+type DataSlot = Arc<RwLock<Option<ResourceBytes>>>;
+
+/// Seed of the hashes of the physical resources, kept to revalidate them and in the cache
+const HASH_SEED: u64 = 0;
+
+fn hash(data: &[u8], seed: u64) -> u128 {
+    let mut hasher = Xxh3Hash128::with_seed(seed);
+    data.hash(&mut hasher);
+    hasher.finish_ext()
+}
+
+/// What `ResourceManager::remove_resources` removes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Removal {
+    /// The resource only
+    Resource,
+    /// The resource and the virtual resources derived from it
+    Cascade,
+    /// The virtual resources derived from the resource
+    Derived,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct PhysicalResourcesMeta {
-    physical_resources: SecondaryMap<Resource, PhysicalResource>,
-    seed: u64,
+/// Name of the cache file of a virtual resource
+fn blob_name(res: Resource) -> String {
+    res.0.as_ffi().to_string()
 }
 
+/// Delete the files of the virtual resources not in keep from the cache directory, returns how
+/// many there were
+fn prune_blobs(cache_path: &Path, keep: &HashSet<String>) -> Result<usize, ResourceError> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(cache_path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name.parse::<u64>().is_ok() && !keep.contains(name) {
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Read the file of a physical resource
+fn read(path: &Path, mapped: bool) -> Result<ResourceBytes, ResourceError> {
+    Ok(if mapped {
+        ResourceBytes::map(path)?
+    } else {
+        ResourceBytes::from(std::fs::read(path)?)
+    })
+}
+
+/// Memory used by the loaded resources
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceStats {
+    /// Resources read in memory (virtual ones included)
+    pub owned: usize,
+    pub owned_bytes: usize,
+    /// Memory mapped resources. The OS can reclaim their pages, so they don't weigh on the memory
+    /// like owned bytes do.
+    pub mapped: usize,
+    pub mapped_bytes: usize,
+}
+
+/// The state is split in independently locked pieces. When several are needed they must be
+/// locked in the order of the fields (locations, resources, virtual_resources, mapped, data,
+/// relations), data slots, pending and hashes are only locked while holding none of them (but
+/// hashes can be locked while holding a data slot). dirty, decoded and events can be locked
+/// while holding any of them, but nothing else can be locked while holding them. Decoded slots are
+/// only locked while holding none of them.
 pub struct ResourceManager {
     resources_path: PathBuf,
     cache_path: Option<PathBuf>,
-    raw: RwLock<RawResourceManager>,
+    locations: RwLock<BiHashMap<PathBuf, Resource>>,
+    resources: RwLock<SlotMap<Resource, ()>>,
+    virtual_resources: RwLock<SecondaryMap<Resource, ()>>,
+    /// Physical resources loaded by mapping their file
+    mapped: RwLock<SecondaryMap<Resource, ()>>,
+    data: RwLock<SecondaryMap<Resource, DataSlot>>,
+    relations: RwLock<HashMap<(Resource, String), Resource>>,
+    /// Serializes cache and sync_cache. What the cache directory holds, None if the next cache
+    /// has to write it whole.
+    cache_state: Mutex<Option<CacheState>>,
+    dirty: Mutex<Dirty>,
+    /// Size of the journal past which it is compacted
+    journal_limit: u64,
+    /// Whether the data of physical resources is cached too
+    physical_cache: bool,
+    /// Loads in flight on the loader
+    pending: Arc<Mutex<SecondaryMap<Resource, Arc<PendingLoad>>>>,
+    loader: Loader,
+    /// Hashes of the physical resources when they were last read, mapped ones aren't hashed
+    hashes: Arc<Mutex<SecondaryMap<Resource, u128>>>,
+    /// Values decoded from the resources by get_typed
+    decoded: RwLock<SecondaryMap<Resource, DecodedSlot>>,
+    /// Events not polled yet
+    events: Mutex<Vec<ResourceEvent>>,
+    watcher: Option<Watcher>,
+    /// Number of files read, to check resources are read once
+    #[cfg(test)]
+    reads: Arc<std::sync::atomic::AtomicUsize>,
 }
 
 fn mkdir(path: impl AsRef<Path>) {
@@ -103,7 +233,7 @@ impl ResourceManager {
     ///
     /// This may be innacurate if the content of the file has been changed when the file has
     /// already been loaded (subsequent loads will act as if the data is still valid when it
-    /// isn't). There is currently no way around that.
+    /// isn't). Use `ResourceManager::revalidate` to check.
     pub fn add_physical(&self, path: impl AsRef<Path>) -> Result<Resource, ResourceError> {
         let path = path.as_ref();
         let path = if path.is_relative() {
@@ -112,26 +242,65 @@ impl ResourceManager {
             path.to_path_buf().canonicalize()?
         };
 
-        if let Some(res) = self.raw.read().locations.get_by_left(&path) {
+        if let Some(res) = self.locations.read().get_by_left(&path) {
             return Ok(*res);
         }
 
-        let mut raw = self.raw.write();
-        let res = raw.resources.insert(());
-        raw.resources_data.insert(res, None);
-        raw.locations.insert(path, res);
+        let mut locations = self.locations.write();
+        // Someone could have added it while we weren't holding the lock
+        if let Some(res) = locations.get_by_left(&path) {
+            return Ok(*res);
+        }
+        let res = self.resources.write().insert(());
+        self.data.write().insert(res, Default::default());
+        locations.insert(path, res);
+        self.dirty.lock().resources.insert(res);
+        Ok(res)
+    }
+    /// Add a physical resource loaded by memory mapping its file instead of reading it, for very
+    /// large read only files. The OS then decides which parts are in memory. Otherwise the same as
+    /// `ResourceManager::add_physical`, a resource already added becomes mapped the next time it
+    /// is loaded.
+    ///
+    /// The map lives until the resource is freed and every `ResourceBytes` from it is dropped.
+    ///
+    /// # Note
+    ///
+    /// Changing the file while it is mapped is undefined behaviour (truncating it makes reads
+    /// fault). Mapped files are locked with a shared advisory lock, which only keeps away the
+    /// writers that lock the file too. Where locks aren't supported the file is mapped without.
+    pub fn add_physical_mapped(&self, path: impl AsRef<Path>) -> Result<Resource, ResourceError> {
+        let res = self.add_physical(path)?;
+        self.mapped.write().insert(res, ());
         Ok(res)
     }
     /// Create a virtual resource with the associated data.
     /// There is no way to access the created resource without its `Resource` handle (if no
     /// relation point to it), and dropping it will effectively be a memory leak.
     pub fn add_virtual(&self, data: &[u8]) -> Resource {
-        let mut raw = self.raw.write();
-        let res = raw.resources.insert(());
-        raw.resources_data.insert(res, Some(Arc::from(data)));
-        raw.virtual_resources.insert(res, ());
+        let mut resources = self.resources.write();
+        let res = resources.insert(());
+        self.virtual_resources.write().insert(res, ());
+        self.data.write().insert(
+            res,
+            Arc::new(RwLock::new(Some(ResourceBytes::Owned(Arc::from(data))))),
+        );
+        self.dirty.lock().resources.insert(res);
         res
     }
+    /// Replace the data of a virtual resource, what was derived from it stays as is
+    pub fn replace_virtual(&self, res: Resource, data: &[u8]) -> Result<(), ResourceError> {
+        if !self.contains(res) {
+            return Err(ResourceError::NoSuchResource);
+        }
+        if !self.contains_virtual(res) {
+            return Err(ResourceError::ResourceIsPhysical);
+        }
+        *self.slot(res)?.write() = Some(ResourceBytes::Owned(Arc::from(data)));
+        self.decoded.write().remove(res);
+        self.dirty.lock().data.insert(res);
+        Ok(())
+    }
     /// Set the relation between two resources. A relation between two resources implies that one
     /// is derived from another.
     /// Currently relations are one to one and directed, a resource can only have one relation of a
@@ -143,79 +312,266 @@ impl ResourceManager {
         from: Resource,
         to: Resource,
     ) -> Result<(), ResourceError> {
-        let key = (from, relation.to_owned());
-        if self.raw.read().relations.contains_key(&key) {
-            Err(ResourceError::WouldOverwriteRelation)
-        } else {
-            self.raw.write().relations.insert(key, to);
-            Ok(())
+        match self.relations.write().entry((from, relation.to_owned())) {
+            Entry::Occupied(_) => Err(ResourceError::WouldOverwriteRelation),
+            Entry::Vacant(entry) => {
+                self.dirty.lock().relations.insert(entry.key().clone());
+                entry.insert(to);
+                Ok(())
+            }
         }
     }
-    /// Ensure a physical resource is in ram. Physical resources are lazy loaded.
-    pub fn ensure_loaded(&self, res: Resource) -> Result<(), ResourceError> {
-        let unloaded = self
-            .raw
+    /// Remove a relation, returns the resource it pointed to. A virtual resource no relation
+    /// points to anymore is pruned on the next `sync_cache`.
+    pub fn remove_relation(
+        &self,
+        relation: &str,
+        from: Resource,
+    ) -> Result<Resource, ResourceError> {
+        let mut relations = self.relations.write();
+        let key = (from, relation.to_owned());
+        let to = relations
+            .remove(&key)
+            .ok_or(ResourceError::NoSuchRelation)?;
+        self.dirty.lock().relations.insert(key);
+        Ok(to)
+    }
+    /// Set a relation whether or not it already exists, returns the resource it pointed to before.
+    /// Unlike a `remove_relation` followed by a `set_relation`, no one can set the relation in
+    /// between.
+    pub fn replace_relation(
+        &self,
+        relation: &str,
+        from: Resource,
+        to: Resource,
+    ) -> Option<Resource> {
+        let mut relations = self.relations.write();
+        let key = (from, relation.to_owned());
+        self.dirty.lock().relations.insert(key.clone());
+        relations.insert(key, to)
+    }
+    /// The data slot of a resource
+    fn slot(&self, res: Resource) -> Result<DataSlot, ResourceError> {
+        self.data
             .read()
-            .resources_data
             .get(res)
-            .ok_or(ResourceError::NoSuchResource)?
-            .is_none();
-
-        if unloaded {
-            let raw = self.raw.read();
-            // If resource is physical
-            if let Some(loc) = raw.locations.get_by_right(&res) {
-                let bytes = Arc::from(std::fs::read(loc)?.into_boxed_slice());
-
-                drop(raw);
-                self.raw
-                    .write()
-                    .resources_data
-                    .get_mut(res)
-                    .unwrap()
-                    .replace(bytes);
+            .cloned()
+            .ok_or(ResourceError::NoSuchResource)
+    }
+    /// Get the data of a resource, reading it if needed. The slot stays locked during the read
+    /// so a resource is only read once, whoever comes next waits and finds it loaded.
+    fn load(&self, res: Resource) -> Result<ResourceBytes, ResourceError> {
+        let slot = self.slot(res)?;
+        if let Some(data) = &*slot.read() {
+            return Ok(data.clone());
+        }
+        // Only physical resources can be unloaded
+        let path = self
+            .locations
+            .read()
+            .get_by_right(&res)
+            .cloned()
+            .ok_or(ResourceError::NoSuchResource)?;
+        let mapped = self.mapped.read().contains_key(res);
+        let mut data = slot.write();
+        match &*data {
+            Some(data) => Ok(data.clone()),
+            None => {
+                #[cfg(test)]
+                self.reads
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let bytes = read(&path, mapped)?;
+                if !mapped {
+                    self.hashes.lock().insert(res, hash(&bytes, HASH_SEED));
+                }
+                *data = Some(bytes.clone());
+                Ok(bytes)
             }
-            Ok(())
-        } else {
-            Ok(())
         }
     }
+    /// Load a resource on a background thread, to not stall the caller on large files. The
+    /// handle can be polled or waited on for the data.
+    ///
+    /// Concurrent loads of a resource share the same read. A `ResourceManager::free` while the
+    /// load is in flight is applied once it completes: the handles still get the data, but the
+    /// resource is left unloaded.
+    pub fn load_async(&self, res: Resource) -> ResourceLoadHandle {
+        let slot = match self.slot(res) {
+            Ok(slot) => slot,
+            Err(e) => return ResourceLoadHandle::ready(Err(e)),
+        };
+        if let Some(data) = &*slot.read() {
+            return ResourceLoadHandle::ready(Ok(data.clone()));
+        }
+        let Some(path) = self.locations.read().get_by_right(&res).cloned() else {
+            return ResourceLoadHandle::ready(Err(ResourceError::NoSuchResource));
+        };
+        let mapped = self.mapped.read().contains_key(res);
+
+        let load = {
+            let mut pending = self.pending.lock();
+            match pending.get(res) {
+                Some(load) if !load.is_cancelled() => return ResourceLoadHandle::new(load.clone()),
+                _ => {}
+            }
+            let load = Arc::new(PendingLoad::new());
+            pending.insert(res, load.clone());
+            load
+        };
+        let handle = ResourceLoadHandle::new(load.clone());
+        let pending = self.pending.clone();
+        let hashes = self.hashes.clone();
+        #[cfg(test)]
+        let reads = self.reads.clone();
+        self.loader.submit(move || {
+            // Same as load: the slot stays locked during the read, whoever else wants the
+            // resource waits for it
+            let result = {
+                let mut data = slot.write();
+                match &*data {
+                    Some(data) => Ok(data.clone()),
+                    None => {
+                        #[cfg(test)]
+                        reads.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let result = read(&path, mapped);
+                        if let Ok(bytes) = &result {
+                            if !mapped {
+                                hashes.lock().insert(res, hash(bytes, HASH_SEED));
+                            }
+                            if !load.is_cancelled() {
+                                *data = Some(bytes.clone());
+                            }
+                        }
+                        result
+                    }
+                }
+            };
+            {
+                let mut pending = pending.lock();
+                if pending.get(res).is_some_and(|other| Arc::ptr_eq(other, &load)) {
+                    pending.remove(res);
+                }
+            }
+            load.complete(result);
+        });
+        handle
+    }
+    /// Ensure a physical resource is in ram. Physical resources are lazy loaded.
+    pub fn ensure_loaded(&self, res: Resource) -> Result<(), ResourceError> {
+        self.load_async(res).wait().map(|_| ())
+    }
     /// Get a resource's data. This may block for IO if the resource isn't already loaded.
     /// A resource can be preloaded witth `ResourceManager::ensure_loaded`.
-    pub fn get_resource(&self, res: Resource) -> Result<Arc<[u8]>, ResourceError> {
-        self.ensure_loaded(res)?;
-        Ok(self
-            .raw
-            .read()
-            .resources_data
-            .get(res)
-            .unwrap() // Alright because ensure loaded would have returned an error already
-            .as_ref()
-            .unwrap() // Alright because thats what ensure loaded guarentees
-            .clone())
+    pub fn get_resource(&self, res: Resource) -> Result<ResourceBytes, ResourceError> {
+        self.load(res)
+    }
+    /// Get a resource decoded into a `T`, decoding it if needed. The value is kept until the
+    /// resource is freed, and shared with the next calls, concurrent ones wait for it to be
+    /// decoded once. A resource keeps the value of a single type, getting it as another type
+    /// decodes it again and replaces it.
+    pub fn get_typed<T: ResourceDecode>(&self, res: Resource) -> Result<Arc<T>, ResourceError> {
+        if !self.contains(res) {
+            return Err(ResourceError::NoSuchResource);
+        }
+        let slot = self.decoded.read().get(res).cloned();
+        let slot = match slot {
+            Some(slot) => slot,
+            None => self
+                .decoded
+                .write()
+                .entry(res)
+                .ok_or(ResourceError::NoSuchResource)?
+                .or_default()
+                .clone(),
+        };
+        let mut decoded = slot.lock();
+        if let Some(value) = decoded.as_ref().and_then(|v| v.downcast_ref::<Arc<T>>()) {
+            return Ok(value.clone());
+        }
+        let bytes = self.load(res)?;
+        let value = Arc::new(T::decode(&bytes).map_err(|e| ResourceError::DecodeError(e.into()))?);
+        *decoded = Some(Box::new(value.clone()));
+        Ok(value)
+    }
+    /// Memory used by the loaded resources
+    pub fn stats(&self) -> ResourceStats {
+        // Data slots can't be locked while holding the data lock
+        let slots = self.data.read().values().cloned().collect::<Vec<_>>();
+        let mut stats = ResourceStats::default();
+        for slot in slots {
+            match &*slot.read() {
+                Some(bytes) if bytes.is_mapped() => {
+                    stats.mapped += 1;
+                    stats.mapped_bytes += bytes.len();
+                }
+                Some(bytes) => {
+                    stats.owned += 1;
+                    stats.owned_bytes += bytes.len();
+                }
+                None => {}
+            }
+        }
+        stats
     }
     /// Get a related resource
     pub fn get_related(&self, res: Resource, relation: &str) -> Option<Resource> {
-        self.raw
+        self.relations
             .read()
-            .relations
             .get(&(res, relation.to_owned()))
             .copied()
     }
+    /// Get the (canonical) path of a physical resource, None for virtual resources
+    pub fn path(&self, res: Resource) -> Option<PathBuf> {
+        self.locations.read().get_by_right(&res).cloned()
+    }
     /// Returns true if the ResourceManager contains the resource
     pub fn contains(&self, res: Resource) -> bool {
-        self.raw.read().resources.contains_key(res)
+        self.resources.read().contains_key(res)
     }
     /// Returns true if the ResourceManager contains the resource, and if it is virtual
     pub fn contains_virtual(&self, res: Resource) -> bool {
-        self.raw.read().virtual_resources.contains_key(res)
+        self.virtual_resources.read().contains_key(res)
     }
     /// Returns true if the ResourceManager contains the resource, and if it is physical
     pub fn contains_physical(&self, res: Resource) -> bool {
-        self.raw.read().locations.contains_right(&res)
+        self.locations.read().contains_right(&res)
+    }
+    /// Returns true if the ResourceManager contains the resource, and if it is memory mapped
+    pub fn contains_mapped(&self, res: Resource) -> bool {
+        self.mapped.read().contains_key(res)
+    }
+    /// Every resource and the relations between them, as a graph to export or to extend with the
+    /// references loaders find.
+    pub fn dependency_graph(&self) -> ResourceGraph {
+        let locations = self.locations.read();
+        let resources = self.resources.read();
+        let virtual_resources = self.virtual_resources.read();
+        let mapped = self.mapped.read();
+        let relations = self.relations.read();
+
+        let mut graph = ResourceGraph::new();
+        for res in resources.keys() {
+            let kind = if virtual_resources.contains_key(res) {
+                ResourceKind::Virtual
+            } else if mapped.contains_key(res) {
+                ResourceKind::Mapped
+            } else {
+                ResourceKind::Physical
+            };
+            graph.add_node(ResourceNode {
+                resource: res,
+                path: locations.get_by_right(&res).cloned(),
+                kind,
+            });
+        }
+        for ((from, relation), to) in relations.iter() {
+            graph.add_edge(*from, relation, *to);
+        }
+        graph
     }
-    /// Free a physical resource. This doesn't delete it, but simply removes it from ram.
-    /// Calling `ResourceManager::get_resource` on a freed resource will result in a blocking read.
+    /// Free a physical resource. This doesn't delete it, but simply removes it from ram (or unmaps
+    /// it). Calling `ResourceManager::get_resource` on a freed resource will result in a blocking
+    /// read.
     ///
     /// This is meaningless for virtual (not loaded from files) resources as once freed there is no
     /// way to recover one, so this returns an `Err(ResourceError::ResourceIsVirtual)` in that
@@ -229,76 +585,392 @@ impl ResourceManager {
         if self.contains_virtual(res) {
             Err(ResourceError::ResourceIsVirtual)
         } else {
-            self.raw.write().resources_data.get_mut(res).unwrap().take();
+            // A load that hasn't started yet would undo this
+            if let Some(load) = self.pending.lock().get(res) {
+                load.cancel();
+            }
+            // And one that has holds the slot until it's done
+            self.slot(res)?.write().take();
+            self.decoded.write().remove(res);
             Ok(())
         }
     }
-    /// Write virtual resources to cache if the cache directory is set.
-    pub fn cache(&self) -> Result<(), ResourceError> {
-        let cache_path = self.cache_path.as_ref().ok_or(ResourceError::NoCachePath)?;
-        let mut meta = PhysicalResourcesMeta {
-            seed: rand::random(),
-            physical_resources: SecondaryMap::new(),
+    /// The changes of the files of physical resources since the last call, when watching the
+    /// resources directory (see `ResourceManagerBuilder::with_watcher`). Modified resources are
+    /// freed so the next access reads the new file, the virtual resources derived from them are
+    /// left as they are (`ResourceManager::revalidate` removes them). Deleted ones keep what was
+    /// loaded.
+    pub fn poll_events(&self) -> Vec<ResourceEvent> {
+        if let Some(watcher) = &self.watcher {
+            for event in watcher.events() {
+                self.notify(event);
+            }
+        }
+        std::mem::take(&mut *self.events.lock())
+    }
+    /// Handle a change of a file of the resources directory, files that aren't of a physical
+    /// resource are ignored
+    pub(crate) fn notify(&self, event: FileEvent) {
+        let (FileEvent::Modified(path) | FileEvent::Removed(path)) = &event;
+        let Some(&res) = self.locations.read().get_by_left(path) else {
+            return;
         };
-
-        let locations = self
-            .raw
-            .read()
+        let event = match event {
+            FileEvent::Modified(_) => {
+                // Physical, can't fail
+                self.free(res).ok();
+                ResourceEvent::Modified(res)
+            }
+            FileEvent::Removed(_) => ResourceEvent::Removed(res),
+        };
+        let mut events = self.events.lock();
+        if !events.contains(&event) {
+            events.push(event);
+        }
+    }
+    /// Check a physical resource against its file, in case the file changed since it was read.
+    /// If it did, the resource is freed and every virtual resource derived from it (reachable
+    /// through relations, directly or through other virtual resources) is removed along with the
+    /// relations to it, as it is stale. Returns whether the resource was invalidated.
+    ///
+    /// Resources that were never read, and mapped ones (which must not change anyway), are
+    /// always valid.
+    pub fn revalidate(&self, res: Resource) -> Result<bool, ResourceError> {
+        if !self.contains(res) {
+            return Err(ResourceError::NoSuchResource);
+        }
+        if self.contains_virtual(res) {
+            return Err(ResourceError::ResourceIsVirtual);
+        }
+        let Some(path) = self.path(res) else {
+            return Err(ResourceError::NoSuchResource);
+        };
+        let Some(last) = self.hashes.lock().get(res).copied() else {
+            return Ok(false);
+        };
+        if hash(&std::fs::read(path)?, HASH_SEED) == last {
+            return Ok(false);
+        }
+        self.free(res)?;
+        self.hashes.lock().remove(res);
+        self.remove_resources(res, Removal::Derived)?;
+        Ok(true)
+    }
+    /// `ResourceManager::revalidate` every physical resource, returns the ones invalidated
+    pub fn revalidate_all(&self) -> Result<Vec<Resource>, ResourceError> {
+        let physical = self
             .locations
-            .iter()
-            .map(|(p, r)| (p.clone(), *r))
-            .collect::<Vec<_>>(); // Necessary to release self.raw
-
-        for (path, res) in locations {
-            let data = self.get_resource(res)?;
-            let size = data.len();
-            let mut hasher = Xxh3Hash128::with_seed(meta.seed);
-            data.hash(&mut hasher);
-            let hash = hasher.finish_ext();
-
-            meta.physical_resources
-                .insert(res, PhysicalResource { path, size, hash });
-        }
-
-        for res in self.raw.read().resources.keys() {
-            if !meta.physical_resources.contains_key(res) {
-                let name = res.0.as_ffi().to_string();
-                let data = self.get_resource(res)?;
-                let path = cache_path.join(name);
-                std::fs::write(path, &data)?;
+            .read()
+            .right_values()
+            .copied()
+            .collect::<Vec<_>>();
+        let mut invalidated = Vec::new();
+        for res in physical {
+            if self.revalidate(res)? {
+                invalidated.push(res);
             }
         }
+        Ok(invalidated)
+    }
+    /// Remove a resource from the manager entirely, along with every relation from or to it. The
+    /// virtual resources derived from it are left without it (and pruned on the next
+    /// `sync_cache` if nothing else points to them), see `ResourceManager::remove_cascade`.
+    ///
+    /// The `Resource` handle is invalid afterwards, adding the same file again gives a new one.
+    pub fn remove(&self, res: Resource) -> Result<(), ResourceError> {
+        self.remove_resources(res, Removal::Resource)
+    }
+    /// Remove a resource like `ResourceManager::remove`, and the virtual resources derived from
+    /// it, reachable through relations directly or through other virtual resources
+    pub fn remove_cascade(&self, res: Resource) -> Result<(), ResourceError> {
+        self.remove_resources(res, Removal::Cascade)
+    }
+    fn remove_resources(&self, res: Resource, removal: Removal) -> Result<(), ResourceError> {
+        {
+            let mut locations = self.locations.write();
+            let mut resources = self.resources.write();
+            let mut virtual_resources = self.virtual_resources.write();
+            let mut mapped = self.mapped.write();
+            let mut data = self.data.write();
+            let mut relations = self.relations.write();
+            if !resources.contains_key(res) {
+                return Err(ResourceError::NoSuchResource);
+            }
 
-        let cache = bincode::serialize(&*self.raw.read())?;
-        let meta = bincode::serialize(&meta)?;
-        std::fs::write(cache_path.join("cache"), &cache)?;
-        std::fs::write(cache_path.join("meta"), &meta)?;
+            let mut removed = HashSet::new();
+            if removal != Removal::Resource {
+                // The virtual resources reachable from it
+                let mut stack = vec![res];
+                while let Some(from) = stack.pop() {
+                    for ((source, _), to) in relations.iter() {
+                        if *source == from
+                            && virtual_resources.contains_key(*to)
+                            && removed.insert(*to)
+                        {
+                            stack.push(*to);
+                        }
+                    }
+                }
+                // Through a cycle back to it
+                removed.remove(&res);
+            }
+            if removal != Removal::Derived {
+                removed.insert(res);
+            }
+            let mut decoded = self.decoded.write();
+            for &res in &removed {
+                resources.remove(res);
+                virtual_resources.remove(res);
+                mapped.remove(res);
+                data.remove(res);
+                locations.remove_by_right(&res);
+                decoded.remove(res);
+            }
+            drop(decoded);
+            let mut dirty = self.dirty.lock();
+            relations.retain(|key, to| {
+                let retain = !removed.contains(&key.0) && !removed.contains(to);
+                if !retain {
+                    dirty.relations.insert(key.clone());
+                }
+                retain
+            });
+            dirty.resources.extend(removed);
+        }
+
+        if removal != Removal::Derived {
+            // A load in flight is left without a slot to fill
+            if let Some(load) = self.pending.lock().remove(res) {
+                load.cancel();
+            }
+            self.hashes.lock().remove(res);
+        }
         Ok(())
     }
+    /// Write what changed since the last cache to the cache directory: the data of the virtual
+    /// resources added or replaced, and the changes of the resources and relations, appended to
+    /// the journal (see the `cache` module). Physical resources are only hashed again if the size
+    /// or the modification time of their file changed. With
+    /// `ResourceManagerBuilder::with_physical_cache`, the data of physical resources (but mapped
+    /// ones) is written once per hash.
+    pub fn cache(&self) -> Result<CacheReport, ResourceError> {
+        let cache_path = self.cache_path.as_ref().ok_or(ResourceError::NoCachePath)?;
+        let mut cache_state = self.cache_state.lock();
+        let (snapshot, dirty) = self.snapshot();
+        match self.write_cache(cache_path, cache_state.take(), snapshot, &dirty) {
+            Ok((state, report)) => {
+                *cache_state = Some(state);
+                Ok(report)
+            }
+            Err(e) => {
+                // The cache directory is written whole the next time, but keep them in case it's
+                // synced before
+                self.dirty.lock().merge(dirty);
+                Err(e)
+            }
+        }
+    }
+    fn write_cache(
+        &self,
+        cache_path: &Path,
+        state: Option<CacheState>,
+        snapshot: RawResourceManager,
+        dirty: &Dirty,
+    ) -> Result<(CacheState, CacheReport), ResourceError> {
+        let mut report = CacheReport::default();
+        let whole = state.is_none();
+        let mut state = state.unwrap_or_default();
+        let mut changes = dirty
+            .resources
+            .iter()
+            .map(|&res| {
+                if snapshot.resources.contains_key(res) {
+                    let is_virtual = snapshot.virtual_resources.contains_key(res);
+                    Change::Added { res, is_virtual }
+                } else {
+                    Change::Removed(res)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut physical = SecondaryMap::new();
+        for (path, &res) in snapshot.locations.iter() {
+            let checked = SystemTime::now();
+            // A missing file can't match, the loaded data is hashed
+            let (size, mtime) = cache::stat(path).unwrap_or((0, None));
+            match state.physical.remove(res) {
+                Some(old) if old.path == *path && old.unchanged(size, mtime) => {
+                    physical.insert(res, old);
+                    continue;
+                }
+                _ => {}
+            }
+            let data = match self.get_resource(res) {
+                // Removed in the meantime
+                Err(ResourceError::NoSuchResource) => continue,
+                data => data?,
+            };
+            report.hashed += 1;
+            let record = PhysicalResource {
+                path: path.clone(),
+                size: data.len() as u64,
+                hash: hash(&data, HASH_SEED),
+                // The file changed since it was read, its time says nothing of the data
+                mtime: mtime.filter(|_| size == data.len() as u64),
+                checked,
+            };
+            changes.push(Change::Physical(res, record.clone()));
+            physical.insert(res, record);
+        }
+        state.physical = physical;
+
+        if self.physical_cache {
+            let mut keep = HashSet::new();
+            for (res, record) in state.physical.iter() {
+                if self.contains_mapped(res) {
+                    continue;
+                }
+                if !cache::physical_blob(cache_path, record.hash).exists() {
+                    let data = match self.get_resource(res) {
+                        Err(ResourceError::NoSuchResource) => continue,
+                        data => data?,
+                    };
+                    // Loaded before the file changed
+                    if hash(&data, HASH_SEED) != record.hash {
+                        continue;
+                    }
+                    cache::write_physical(cache_path, record.hash, &data)?;
+                    report.blobs_written += 1;
+                    report.blob_bytes += data.len();
+                }
+                keep.insert(record.hash);
+            }
+            report.blobs_removed += cache::prune_physical(cache_path, &keep)?;
+        }
+
+        let mut blobs = HashSet::new();
+        for res in snapshot.virtual_resources.keys() {
+            if !(whole || dirty.resources.contains(&res) || dirty.data.contains(&res)) {
+                continue;
+            }
+            let data = match self.get_resource(res) {
+                Err(ResourceError::NoSuchResource) => continue,
+                data => data?,
+            };
+            let name = blob_name(res);
+            std::fs::write(cache_path.join(&name), &data)?;
+            blobs.insert(name);
+            report.blobs_written += 1;
+            report.blob_bytes += data.len();
+        }
+        if whole {
+            // Those of resources removed since the last time
+            report.blobs_removed = prune_blobs(cache_path, &blobs)?;
+        } else {
+            for &res in &dirty.resources {
+                if snapshot.resources.contains_key(res) {
+                    continue;
+                }
+                match std::fs::remove_file(cache_path.join(blob_name(res))) {
+                    Ok(()) => report.blobs_removed += 1,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        changes.extend(dirty.relations.iter().map(|key| Change::Relation {
+            from: key.0,
+            relation: key.1.clone(),
+            to: snapshot.relations.get(key).copied(),
+        }));
+        report.changes = changes.len();
+        if whole
+            || state.journal_len + cache::batch_size(state.generation, &changes)?
+                > self.journal_limit
+        {
+            let manifest = Manifest {
+                generation: state.generation.wrapping_add(1),
+                state: snapshot,
+                physical: std::mem::take(&mut state.physical),
+            };
+            cache::write_manifest(cache_path, &manifest)?;
+            state.generation = manifest.generation;
+            state.physical = manifest.physical;
+            state.journal_len = 0;
+            report.compacted = true;
+        } else if !changes.is_empty() {
+            state.journal_len =
+                cache::append(cache_path, state.journal_len, state.generation, changes)?;
+        }
+        Ok((state, report))
+    }
     /// This tries to read the cache and get virtual resources from it. This overrides any
     /// resources previously put. This should be called at the start of the application, but can be
     /// called anytime as long as the side effects are handled.
-    pub fn sync_cache(&self) -> Result<(), ResourceError> {
+    ///
+    /// Files of resources that can't be read only drop the resources (and what's derived from
+    /// them), they are in the report. Only a cache directory that can't be read at all is an
+    /// error.
+    ///
+    /// With `ResourceManagerBuilder::with_physical_cache`, the physical resources whose file
+    /// didn't change are loaded from the cache, and the data of the others is deleted from it.
+    pub fn sync_cache(&self) -> Result<SyncReport, ResourceError> {
         let cache_path = self.cache_path.as_ref().ok_or(ResourceError::NoCachePath)?;
-        let cache_file = File::open(cache_path.join("cache"))?;
-        let meta_file = File::open(cache_path.join("meta"))?;
-
-        let mut cache: RawResourceManager = bincode::deserialize_from(cache_file)?;
-        let mut meta: PhysicalResourcesMeta = bincode::deserialize_from(meta_file)?;
+        let mut cache_state = self.cache_state.lock();
+        let Contents {
+            manifest,
+            journal_len,
+            seed,
+            legacy,
+        } = cache::read(cache_path)?;
+        let Manifest {
+            generation,
+            state: mut cache,
+            mut physical,
+        } = manifest;
+        let counts = (cache.resources.len(), cache.relations.len());
+        let mut report = SyncReport::default();
 
         // Remove dead physical resources. A physical resource is dead if the file at the
-        // resource's path doesn't match in size of hash with the resource
-        meta.physical_resources.retain(|res, info| {
-            let retain = std::fs::read(&info.path)
-                .map(|buf| {
-                    let size = buf.len();
-                    let mut hasher = Xxh3Hash128::with_seed(meta.seed);
-                    buf.hash(&mut hasher);
-                    let hash = hasher.finish_ext();
-
-                    size == info.size && hash == info.hash
-                })
-                .unwrap_or_default();
+        // resource's path doesn't match in size of hash with the resource, which is only hashed
+        // if its size or modification time changed
+        let mapped = self.mapped.read().clone();
+        let mut hashes = SecondaryMap::new();
+        let mut physical_data = SecondaryMap::new();
+        physical.retain(|res, info| {
+            let (size, mtime) = cache::stat(&info.path).unwrap_or((0, None));
+            let cached = self.physical_cache && !mapped.contains_key(res);
+            let retain = if seed == HASH_SEED && info.unchanged(size, mtime) {
+                hashes.insert(res, info.hash);
+                if let Some(data) = cached
+                    .then(|| cache::read_physical(cache_path, info))
+                    .flatten()
+                {
+                    report.physical_loaded += 1;
+                    physical_data.insert(res, data);
+                }
+                true
+            } else {
+                match std::fs::read(&info.path) {
+                    Ok(buf) => {
+                        let alive = buf.len() as u64 == info.size && hash(&buf, seed) == info.hash;
+                        // What the cached virtual resources were derived from, for revalidate
+                        if alive {
+                            hashes.insert(res, hash(&buf, HASH_SEED));
+                            // Read anyway
+                            if cached {
+                                physical_data.insert(res, buf);
+                            }
+                        }
+                        alive
+                    }
+                    Err(e) => {
+                        report.errors.push((info.path.clone(), e));
+                        false
+                    }
+                }
+            };
 
             if !retain {
                 cache.locations.remove_by_right(&res);
@@ -306,6 +978,31 @@ impl ResourceManager {
 
             retain
         });
+        cache.locations.retain(|_, res| physical.contains_key(*res));
+
+        // Remove orphaned virtual resources, that no relation points to anymore (their relation was
+        // removed or replaced). Nothing can reach them.
+        let targets = cache.relations.values().copied().collect::<HashSet<_>>();
+        cache
+            .virtual_resources
+            .retain(|res, _| targets.contains(&res));
+
+        // Load the virtual resource's data from their cache file, those that can't be read are
+        // dead
+        let mut blobs = SecondaryMap::new();
+        cache.virtual_resources.retain(|res, _| {
+            let path = cache_path.join(blob_name(res));
+            match std::fs::read(&path) {
+                Ok(bytes) => {
+                    blobs.insert(res, ResourceBytes::from(bytes));
+                    true
+                }
+                Err(e) => {
+                    report.errors.push((path, e));
+                    false
+                }
+            }
+        });
 
         // Remove dead virtual resources, virtual resources that are related to dead physical ones,
         // either directly or indirectly.
@@ -319,48 +1016,84 @@ impl ResourceManager {
                 if kill {
                     delta += 1;
                     cache.virtual_resources.remove(*to);
-                    let filename = to.0.as_ffi().to_string();
-                    std::fs::remove_file(cache_path.join(filename)).ok();
                 }
                 !kill // remove the relation if both resources are dead
             });
         }
 
-        // Load the virtual resource's data and remove the keys of dead resources
+        // Remove the keys of dead resources
+        let mut data = SecondaryMap::new();
         cache.resources.retain(|res, _| {
             let value = if cache.virtual_resources.contains_key(res) {
-                // Resource is virtual: we load it from it's cache file
-                //
-                let filename = res.0.as_ffi().to_string();
-                // Not a fan of the unwrap here
-                let bytes = std::fs::read(cache_path.join(filename)).unwrap();
-                Some(Arc::from(bytes.into_boxed_slice()))
-            } else if meta.physical_resources.contains_key(res) {
-                // Resource is physical: we lazy load it
-                None
+                report.loaded += 1;
+                blobs.remove(res)
+            } else if physical.contains_key(res) {
+                // Resource is physical: we lazy load it, unless it was cached
+                physical_data.remove(res).map(ResourceBytes::from)
             } else {
                 // Resource is neither virtual or physical: it's dead
+                report.dropped.push(res);
                 return false;
             };
-            cache.resources_data.insert(res, value);
+            data.insert(res, Arc::new(RwLock::new(value)));
             true
         });
+        // And the relations left from or to them
+        cache
+            .relations
+            .retain(|(from, _), to| data.contains_key(*from) && data.contains_key(*to));
+        // The files of the dead virtual resources, and of the removed ones
+        let blobs = cache.virtual_resources.keys().map(blob_name).collect();
+        prune_blobs(cache_path, &blobs)?;
+        // The data of the physical resources that changed
+        let keep = if self.physical_cache {
+            physical.values().map(|info| info.hash).collect()
+        } else {
+            HashSet::new()
+        };
+        cache::prune_physical(cache_path, &keep)?;
 
-        *self.raw.write() = cache;
+        // What was dropped is still in the cache directory, as is a cache in the old format
+        let pruned = counts != (cache.resources.len(), cache.relations.len());
+        *cache_state = (!legacy && !pruned).then_some(CacheState {
+            generation,
+            journal_len,
+            physical,
+        });
 
-        Ok(())
-    }
-}
+        *self.hashes.lock() = hashes;
+        // Swap everything at once, so no one sees a half synced manager
+        let mut locations = self.locations.write();
+        let mut resources = self.resources.write();
+        let mut virtual_resources = self.virtual_resources.write();
+        let mut mapped = self.mapped.write();
+        let mut data_lock = self.data.write();
+        let mut relations = self.relations.write();
+        // Mapping isn't cached, keep it for the resources that survived
+        mapped.retain(|res, _| cache.locations.contains_right(&res));
+        *locations = cache.locations;
+        *resources = cache.resources;
+        *virtual_resources = cache.virtual_resources;
+        *data_lock = data;
+        *relations = cache.relations;
+        *self.decoded.write() = SecondaryMap::new();
+        *self.dirty.lock() = Dirty::default();
 
-impl Default for RawResourceManager {
-    fn default() -> Self {
-        Self {
-            resources: SlotMap::with_key(),
-            relations: HashMap::new(),
-            locations: BiHashMap::new(),
-            virtual_resources: SecondaryMap::new(),
-            resources_data: SecondaryMap::new(),
-        }
+        Ok(report)
+    }
+    /// A consistent copy of the state to serialize, and what changed since the last one
+    fn snapshot(&self) -> (RawResourceManager, Dirty) {
+        let locations = self.locations.read();
+        let resources = self.resources.read();
+        let virtual_resources = self.virtual_resources.read();
+        let relations = self.relations.read();
+        let snapshot = RawResourceManager {
+            resources: resources.clone(),
+            relations: relations.clone(),
+            locations: locations.clone(),
+            virtual_resources: virtual_resources.clone(),
+        };
+        (snapshot, std::mem::take(&mut *self.dirty.lock()))
     }
 }
 
@@ -368,6 +1101,9 @@ impl Default for RawResourceManager {
 pub struct ResourceManagerBuilder {
     res_path: Option<PathBuf>,
     cache_path: Option<PathBuf>,
+    journal_limit: Option<u64>,
+    physical_cache: bool,
+    watch: bool,
 }
 
 impl ResourceManagerBuilder {
@@ -381,11 +1117,10 @@ impl ResourceManagerBuilder {
             let res = match app_name {
                 Some(name) => d.cache_dir().join(name).join("resources_cache"),
                 None => d.cache_dir().join(".resources_cache"),
-            }
-            .canonicalize()
-            .ok()?;
+            };
+            // Can't canonicalize a directory that doesn't exist yet
             mkdir(&res);
-            Some(res)
+            res.canonicalize().ok()
         });
         self
     }
@@ -394,6 +1129,24 @@ impl ResourceManagerBuilder {
         self.cache_path = Some(path.as_ref().to_owned());
         self
     }
+    /// Set the size of the cache journal past which it is compacted
+    pub fn with_journal_limit(mut self, bytes: u64) -> Self {
+        self.journal_limit = Some(bytes);
+        self
+    }
+    /// Watch the resources directory for changes of the files of physical resources, see
+    /// `ResourceManager::poll_events`
+    pub fn with_watcher(mut self) -> Self {
+        self.watch = true;
+        self
+    }
+    /// Also cache the data of physical resources, so that `ResourceManager::sync_cache` doesn't
+    /// need to read the files that didn't change again. Useful when the resources directory is
+    /// slow to read (on the network), at the cost of a copy of the resources in the cache.
+    pub fn with_physical_cache(mut self, enabled: bool) -> Self {
+        self.physical_cache = enabled;
+        self
+    }
     /// Set the resources directory path
     pub fn with_resource_path(mut self, path: impl AsRef<Path>) -> Self {
         self.res_path = Some(path.as_ref().to_owned());
@@ -407,16 +1160,34 @@ impl ResourceManagerBuilder {
         let cache_path = self.cache_path;
         mkdir(&resources_path);
         let resources_path = resources_path.canonicalize().unwrap();
+        let watcher = self.watch.then(|| Watcher::start(resources_path.clone()));
 
         ResourceManager {
             resources_path,
             cache_path,
-            raw: Default::default(),
+            locations: Default::default(),
+            resources: Default::default(),
+            virtual_resources: Default::default(),
+            mapped: Default::default(),
+            data: Default::default(),
+            relations: Default::default(),
+            cache_state: Default::default(),
+            dirty: Default::default(),
+            journal_limit: self.journal_limit.unwrap_or(cache::JOURNAL_LIMIT),
+            physical_cache: self.physical_cache,
+            pending: Default::default(),
+            loader: Default::default(),
+            hashes: Default::default(),
+            decoded: Default::default(),
+            events: Default::default(),
+            watcher,
+            #[cfg(test)]
+            reads: Default::default(),
         }
     }
 }
 
-static RESOURCE_MANAGER: SyncOnceCell<ResourceManager> = SyncOnceCell::new();
+static RESOURCE_MANAGER: OnceLock<ResourceManager> = OnceLock::new();
 
 /// initialize the resource manager. Must be called before `instance`, ideally at the begining of
 /// main.
@@ -439,12 +1210,17 @@ pub fn instance() -> &'static ResourceManager {
         .get()
         .expect("ResourceManager hasn't been initialized")
 }
+/// Get the global instance of the resource manager, None if it hasn't been initialized
+pub fn try_instance() -> Option<&'static ResourceManager> {
+    RESOURCE_MANAGER.get()
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use mktemp::Temp;
     use std::ops::Deref;
+    use std::{fs::File, time::Duration};
     // Keep the temp directory alive
     struct G(ResourceManager, Temp, Temp);
     impl Deref for G {
@@ -481,6 +1257,10 @@ mod tests {
         let res = rm.add_physical(temp.as_path()).unwrap();
         let res2 = rm.add_physical(temp.as_path()).unwrap();
         assert_eq!(res, res2);
+        assert_eq!(
+            rm.path(res),
+            Some(temp.as_path().canonicalize().unwrap())
+        );
 
         let bytes = rm.get_resource(res).unwrap();
         let s = std::str::from_utf8(&bytes).unwrap();
@@ -512,10 +1292,11 @@ mod tests {
         let pb = rm.get_resource(p).unwrap();
         let pb = std::str::from_utf8(&pb).unwrap();
         let vb = &rm.get_resource(v).unwrap();
-        let vb = std::str::from_utf8(&vb).unwrap();
+        let vb = std::str::from_utf8(vb).unwrap();
 
         assert_eq!(pb, content);
         assert_eq!(vb, content.to_uppercase());
+        assert_eq!(rm.path(v), None);
     }
 
     #[test]
@@ -525,7 +1306,7 @@ mod tests {
         {
             let content = "This is a String!";
             temp = Temp::new_file_in(rm.directory()).unwrap();
-            std::fs::write(temp.as_path(), &content).unwrap();
+            std::fs::write(temp.as_path(), content).unwrap();
             let pr = rm.add_physical(temp.as_path()).unwrap();
 
             {
@@ -570,4 +1351,1109 @@ mod tests {
         let data = rm.get_resource(v2).unwrap();
         assert_eq!("this is a string!", std::str::from_utf8(&data).unwrap());
     }
+
+    #[test]
+    fn remove_relation() {
+        let rm = _init();
+        let p = files(&rm, 1)[0];
+        let v = rm.add_virtual(b"FILE");
+        assert!(matches!(
+            rm.remove_relation(UPPERCASE, p),
+            Err(ResourceError::NoSuchRelation)
+        ));
+        rm.set_relation(UPPERCASE, p, v).unwrap();
+        assert_eq!(v, rm.remove_relation(UPPERCASE, p).unwrap());
+        assert_eq!(None, rm.get_related(p, UPPERCASE));
+        // Can be set again, and the resource is still there until the next sync
+        let v2 = rm.add_virtual(b"FILE0");
+        rm.set_relation(UPPERCASE, p, v2).unwrap();
+        assert_eq!(Some(v2), rm.get_related(p, UPPERCASE));
+        assert!(rm.contains_virtual(v));
+    }
+
+    #[test]
+    fn replace_relation() {
+        const THREADS: usize = 8;
+        let rm = Arc::new(_init());
+        let p = files(&rm, 1)[0];
+        let first = rm.add_virtual(&[]);
+        assert_eq!(None, rm.replace_relation(UPPERCASE, p, first));
+        let rm2 = rm.clone();
+        let replaced = Arc::new(Mutex::new(Vec::new()));
+        let set = Arc::new(Mutex::new(Vec::new()));
+        let (replaced2, set2) = (replaced.clone(), set.clone());
+        deadline(30, move || {
+            std::thread::scope(|s| {
+                for t in 0..THREADS {
+                    let (rm, replaced, set) = (&rm2, &replaced2, &set2);
+                    s.spawn(move || {
+                        for i in 0..50 {
+                            let v = rm.add_virtual(&[t as u8, i]);
+                            set.lock().push(v);
+                            let previous = rm.replace_relation(UPPERCASE, p, v);
+                            replaced.lock().push(previous.unwrap());
+                        }
+                    });
+                }
+            });
+        });
+        // Every target was replaced exactly once, except the last one which is still there
+        let mut replaced = std::mem::take(&mut *replaced.lock());
+        let mut set = std::mem::take(&mut *set.lock());
+        set.push(first);
+        replaced.push(rm.get_related(p, UPPERCASE).unwrap());
+        replaced.sort();
+        set.sort();
+        assert_eq!(set, replaced);
+    }
+
+    #[test]
+    fn cache_prunes_orphans() {
+        let rm = _init();
+        let path = rm.directory().join("params");
+        std::fs::write(&path, "Params").unwrap();
+        let p = rm.add_physical(&path).unwrap();
+        let old = rm.add_virtual(b"OLD");
+        let derived = rm.add_virtual(b"DERIVED");
+        let kept = rm.add_virtual(b"KEPT");
+        rm.set_relation(UPPERCASE, p, old).unwrap();
+        rm.set_relation(LOWERCASE, old, derived).unwrap();
+        rm.set_relation(LOWERCASE, p, kept).unwrap();
+        // The processing parameters changed
+        let new = rm.add_virtual(b"NEW");
+        assert_eq!(Some(old), rm.replace_relation(UPPERCASE, p, new));
+        rm.cache().unwrap();
+
+        rm.sync_cache().unwrap();
+        assert_eq!(Some(new), rm.get_related(p, UPPERCASE));
+        assert_eq!(b"NEW", &*rm.get_resource(new).unwrap());
+        assert_eq!(Some(kept), rm.get_related(p, LOWERCASE));
+        // The old result and what was derived from it are gone, files included
+        let cache_path = rm.cache_path.as_ref().unwrap();
+        for res in [old, derived] {
+            assert!(!rm.contains(res));
+            assert!(!cache_path.join(res.0.as_ffi().to_string()).exists());
+        }
+        assert_eq!(None, rm.get_related(old, LOWERCASE));
+    }
+
+    /// Run f on another thread, failing instead of hanging if it deadlocks
+    fn deadline(secs: u64, f: impl FnOnce() + Send + 'static) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            f();
+            tx.send(()).ok();
+        });
+        match rx.recv_timeout(std::time::Duration::from_secs(secs)) {
+            Ok(()) => handle.join().unwrap(),
+            // The thread panicked, get the panic
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => handle.join().unwrap(),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => panic!("Deadlock"),
+        }
+    }
+
+    fn files(rm: &ResourceManager, count: usize) -> Vec<Resource> {
+        (0..count)
+            .map(|i| {
+                let path = rm.directory().join(format!("file{i}"));
+                std::fs::write(&path, i.to_string()).unwrap();
+                rm.add_physical(path).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn concurrent_loads() {
+        const FILES: usize = 32;
+        const THREADS: usize = 16;
+        let rm = Arc::new(_init());
+        let resources = files(&rm, FILES);
+        let rm2 = rm.clone();
+        deadline(30, move || {
+            let rm = rm2;
+            std::thread::scope(|s| {
+                for t in 0..THREADS {
+                    let (rm, resources) = (&rm, &resources);
+                    s.spawn(move || {
+                        // Overlapping sets, each resource is wanted by half the threads
+                        for (i, &res) in resources.iter().enumerate() {
+                            if (i + t) % 2 == 0 {
+                                let data = rm.get_resource(res).unwrap();
+                                assert_eq!(i.to_string().as_bytes(), &*data);
+                            }
+                        }
+                        // Concurrent add_physical of the same path give the same resource
+                        let path = rm.directory().join(format!("file{t}"));
+                        assert_eq!(resources[t], rm.add_physical(path).unwrap());
+                    });
+                }
+            });
+        });
+        assert_eq!(FILES, rm.reads.load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn load_async() {
+        let rm = _init();
+        let res = files(&rm, 1)[0];
+        let reads = || rm.reads.load(std::sync::atomic::Ordering::Relaxed);
+        let (a, b) = (rm.load_async(res), rm.load_async(res));
+        assert_eq!(b"0", &*b.wait().unwrap());
+        assert_eq!(b"0", &*a.wait().unwrap());
+        assert!(a.is_ready());
+        assert_eq!(1, reads());
+        assert!(rm.load_async(res).is_ready());
+        assert_eq!(1, reads());
+
+        // Freed while in flight, the handle gets the data but it isn't kept
+        rm.free(res).unwrap();
+        let handle = rm.load_async(res);
+        rm.free(res).unwrap();
+        assert_eq!(b"0", &*handle.wait().unwrap());
+        assert_eq!(0, rm.stats().owned);
+        // And loaded again after
+        rm.ensure_loaded(res).unwrap();
+        assert_eq!(1, rm.stats().owned);
+
+        assert!(matches!(
+            rm.load_async(Resource::default()).wait(),
+            Err(ResourceError::NoSuchResource)
+        ));
+        rm.free(res).unwrap();
+        std::fs::remove_file(rm.directory().join("file0")).unwrap();
+        let handle = rm.load_async(res);
+        assert!(matches!(handle.wait(), Err(ResourceError::IOError(_))));
+        assert!(matches!(handle.wait(), Err(ResourceError::IOError(_))));
+    }
+
+    #[test]
+    fn async_churn() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let rm = _init();
+        let res = files(&rm, 1)[0];
+        deadline(60, move || {
+            let frees = AtomicUsize::new(0);
+            std::thread::scope(|s| {
+                for t in 0..8 {
+                    let (rm, frees) = (&rm, &frees);
+                    s.spawn(move || {
+                        let mut handles = Vec::new();
+                        for i in 0..300 {
+                            match (i + t) % 3 {
+                                0 => assert_eq!(b"0", &*rm.get_resource(res).unwrap()),
+                                1 => handles.push(rm.load_async(res)),
+                                _ => {
+                                    rm.free(res).unwrap();
+                                    frees.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                        }
+                        for handle in handles {
+                            assert_eq!(b"0", &*handle.wait().unwrap());
+                        }
+                    });
+                }
+            });
+            // Only read again after being freed
+            let reads = rm.reads.load(Ordering::Relaxed);
+            assert!(reads <= frees.load(Ordering::Relaxed) + 1, "{reads} reads");
+            assert!(rm.pending.lock().is_empty());
+        });
+    }
+
+    #[test]
+    fn mapped() {
+        let rm = _init();
+        let content = (0..64 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let path = rm.directory().join("big");
+        std::fs::write(&path, &content).unwrap();
+
+        let res = rm.add_physical_mapped(&path).unwrap();
+        assert_eq!(res, rm.add_physical(&path).unwrap());
+        assert!(rm.contains_mapped(res));
+        let data = rm.get_resource(res).unwrap();
+        assert!(data.is_mapped());
+        assert_eq!(&content[..], &data[..]);
+        assert_eq!(content.len(), data.len());
+        assert_eq!(255, *data.iter().max().unwrap());
+
+        // Empty files too
+        let path = rm.directory().join("empty");
+        std::fs::write(&path, []).unwrap();
+        let res = rm.add_physical_mapped(&path).unwrap();
+        assert!(rm.get_resource(res).unwrap().is_empty());
+    }
+
+    #[test]
+    fn mapped_free() {
+        let rm = _init();
+        let res = files(&rm, 1)[0];
+        let path = rm.directory().join("file0");
+        // Already added, mapped from the next load
+        assert_eq!(res, rm.add_physical_mapped(path).unwrap());
+        let data = rm.get_resource(res).unwrap();
+        assert_eq!(1, rm.stats().mapped);
+
+        rm.free(res).unwrap();
+        assert_eq!(ResourceStats::default(), rm.stats());
+        // Still mapped until dropped
+        assert_eq!(b"0", &*data);
+        drop(data);
+
+        rm.ensure_loaded(res).unwrap();
+        assert_eq!(1, rm.stats().mapped);
+        assert!(rm.get_resource(res).unwrap().is_mapped());
+        assert_eq!(2, rm.reads.load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn mapped_stats() {
+        let rm = _init();
+        let resources = files(&rm, 3);
+        let path = rm.directory().join("file2");
+        rm.add_physical_mapped(path).unwrap();
+        rm.add_virtual(&[0; 10]);
+        let expected = ResourceStats {
+            owned: 1,
+            owned_bytes: 10,
+            ..Default::default()
+        };
+        assert_eq!(expected, rm.stats());
+
+        for &res in &resources {
+            rm.ensure_loaded(res).unwrap();
+        }
+        let expected = ResourceStats {
+            owned: 3,
+            owned_bytes: 12,
+            mapped: 1,
+            mapped_bytes: 1,
+        };
+        assert_eq!(expected, rm.stats());
+    }
+
+    #[test]
+    fn mapped_cache() {
+        let rm = _init();
+        let path = rm.directory().join("mapped");
+        std::fs::write(&path, "Mapped").unwrap();
+        let pr = rm.add_physical_mapped(&path).unwrap();
+        let upper = rm
+            .get_resource(pr)
+            .unwrap()
+            .iter()
+            .map(u8::to_ascii_uppercase)
+            .collect::<Vec<_>>();
+        let v = rm.add_virtual(&upper);
+        rm.set_relation(UPPERCASE, pr, v).unwrap();
+        rm.cache().unwrap();
+
+        // The hash of the mapped file matches, so it and its relation survive
+        rm.sync_cache().unwrap();
+        assert!(rm.contains_mapped(pr));
+        assert_eq!(b"Mapped", &*rm.get_resource(pr).unwrap());
+        assert!(rm.get_resource(pr).unwrap().is_mapped());
+        let v = rm.get_related(pr, UPPERCASE).unwrap();
+        assert_eq!(b"MAPPED", &*rm.get_resource(v).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mapped_lock() {
+        use fs2::FileExt;
+
+        let rm = _init();
+        let res = files(&rm, 1)[0];
+        let path = rm.directory().join("file0");
+        rm.add_physical_mapped(&path).unwrap();
+        let writer = File::open(&path).unwrap();
+
+        rm.ensure_loaded(res).unwrap();
+        assert!(FileExt::try_lock_exclusive(&writer).is_err());
+        rm.free(res).unwrap();
+        FileExt::try_lock_exclusive(&writer).unwrap();
+        // Can't map a file being written
+        assert!(matches!(
+            rm.ensure_loaded(res),
+            Err(ResourceError::IOError(_))
+        ));
+        FileExt::unlock(&writer).unwrap();
+        rm.ensure_loaded(res).unwrap();
+    }
+
+    #[test]
+    fn churn() {
+        let rm = _init();
+        let resources = files(&rm, 8);
+        deadline(60, move || {
+            std::thread::scope(|s| {
+                for t in 0..8 {
+                    let (rm, resources) = (&rm, &resources);
+                    s.spawn(move || {
+                        for i in 0..200 {
+                            let res = resources[(i + t) % resources.len()];
+                            match (i + t) % 4 {
+                                0 => rm.ensure_loaded(res).unwrap(),
+                                1 => rm.free(res).unwrap(),
+                                2 => {
+                                    let v = rm.add_virtual(&[t as u8]);
+                                    // Only the first one of each relation succeeds
+                                    let _ = rm.set_relation(&format!("r{i}"), res, v);
+                                    assert_eq!(&[t as u8], &*rm.get_resource(v).unwrap());
+                                }
+                                _ => {
+                                    if let Some(v) = rm.get_related(res, &format!("r{}", i.saturating_sub(1))) {
+                                        assert!(rm.contains_virtual(v));
+                                    }
+                                    assert!(rm.contains_physical(res));
+                                }
+                            }
+                        }
+                        if t == 0 {
+                            rm.cache().unwrap();
+                        }
+                    });
+                }
+            });
+        });
+    }
+
+    /// Counts its decodes
+    #[derive(Debug, PartialEq)]
+    struct Words(Vec<String>);
+
+    static DECODES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    impl ResourceDecode for Words {
+        type Error = std::str::Utf8Error;
+
+        fn decode(bytes: &[u8]) -> Result<Self, Self::Error> {
+            DECODES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            // Long enough for the other threads to come
+            std::thread::sleep(Duration::from_millis(20));
+            let words = std::str::from_utf8(bytes)?.split_whitespace();
+            Ok(Words(words.map(String::from).collect()))
+        }
+    }
+
+    #[test]
+    fn typed() {
+        let decodes = || DECODES.load(std::sync::atomic::Ordering::Relaxed);
+        let rm = _init();
+        let path = rm.directory().join("words");
+        std::fs::write(&path, "a b c").unwrap();
+        let p = rm.add_physical(&path).unwrap();
+
+        let words = std::thread::scope(|s| {
+            let handles = (0..8)
+                .map(|_| s.spawn(|| rm.get_typed::<Words>(p).unwrap()))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(1, decodes());
+        assert!(words.iter().all(|w| Arc::ptr_eq(w, &words[0])));
+        assert_eq!(vec!["a", "b", "c"], words[0].0);
+        // Another type replaces it
+        assert_eq!("a b c", *rm.get_typed::<String>(p).unwrap());
+        rm.get_typed::<Words>(p).unwrap();
+        assert_eq!(2, decodes());
+        rm.get_typed::<Words>(p).unwrap();
+        assert_eq!(2, decodes());
+        // Freed with the bytes
+        rm.free(p).unwrap();
+        let reads = rm.reads.load(std::sync::atomic::Ordering::Relaxed);
+        rm.get_typed::<Words>(p).unwrap();
+        assert_eq!(3, decodes());
+        assert_eq!(
+            reads + 1,
+            rm.reads.load(std::sync::atomic::Ordering::Relaxed)
+        );
+
+        let v = rm.add_virtual(&[0xff, 0xfe]);
+        assert!(matches!(
+            rm.get_typed::<String>(v),
+            Err(ResourceError::DecodeError(_))
+        ));
+        assert_eq!(vec![0xff, 0xfe], *rm.get_typed::<Vec<u8>>(v).unwrap());
+        rm.replace_virtual(v, b"replaced").unwrap();
+        assert_eq!(b"replaced", &rm.get_typed::<Vec<u8>>(v).unwrap()[..]);
+        rm.remove(v).unwrap();
+        assert!(matches!(
+            rm.get_typed::<Vec<u8>>(v),
+            Err(ResourceError::NoSuchResource)
+        ));
+    }
+
+    #[test]
+    fn events() {
+        let rm = _init();
+        let res = files(&rm, 2);
+        let path = |i: usize| rm.path(res[i]).unwrap();
+        rm.get_resource(res[0]).unwrap();
+        assert!(rm.poll_events().is_empty());
+
+        rm.notify(FileEvent::Modified(path(0)));
+        rm.notify(FileEvent::Modified(path(0)));
+        rm.notify(FileEvent::Removed(path(1)));
+        rm.notify(FileEvent::Modified(rm.directory().join("unknown")));
+        assert_eq!(
+            vec![
+                ResourceEvent::Modified(res[0]),
+                ResourceEvent::Removed(res[1])
+            ],
+            rm.poll_events()
+        );
+        assert!(rm.poll_events().is_empty());
+        // Modified resources are read again
+        let reads = rm.reads.load(std::sync::atomic::Ordering::Relaxed);
+        rm.get_resource(res[0]).unwrap();
+        assert_eq!(
+            reads + 1,
+            rm.reads.load(std::sync::atomic::Ordering::Relaxed)
+        );
+        assert!(rm.contains(res[1]));
+    }
+
+    #[test]
+    fn watcher() {
+        let temp = Temp::new_dir().unwrap();
+        let rm = ResourceManagerBuilder::begin()
+            .with_resource_path(temp.as_path())
+            .with_watcher()
+            .build();
+        let res = files(&rm, 2);
+        let path = |i: usize| rm.path(res[i]).unwrap();
+        // Let the watcher see the files first
+        std::thread::sleep(Duration::from_millis(500));
+        std::fs::write(path(0), "changed").unwrap();
+        std::fs::remove_file(path(1)).unwrap();
+
+        let mut events = Vec::new();
+        for _ in 0..50 {
+            events.extend(rm.poll_events());
+            if events.len() >= 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        events.sort_by_key(|e| matches!(e, ResourceEvent::Removed(_)));
+        assert_eq!(
+            vec![
+                ResourceEvent::Modified(res[0]),
+                ResourceEvent::Removed(res[1])
+            ],
+            events
+        );
+        assert_eq!(b"changed", &rm.get_resource(res[0]).unwrap()[..]);
+    }
+
+    #[test]
+    fn dependency_graph() {
+        let rm = _init();
+        let scene = rm.directory().join("scene.gltf");
+        let texture = rm.directory().join("texture.png");
+        std::fs::write(&scene, "{}").unwrap();
+        std::fs::write(&texture, [0u8; 4]).unwrap();
+
+        let scene = rm.add_physical(scene).unwrap();
+        let texture = rm.add_physical_mapped(texture).unwrap();
+        let decoded = rm.add_virtual(&[1, 2, 3]);
+        rm.set_relation("texture", scene, texture).unwrap();
+        rm.set_relation("decoded", texture, decoded).unwrap();
+
+        let graph = rm.dependency_graph();
+        assert_eq!(3, graph.nodes().len());
+        assert_eq!(ResourceKind::Physical, graph.node(scene).unwrap().kind);
+        assert_eq!(ResourceKind::Mapped, graph.node(texture).unwrap().kind);
+        assert!(graph.node(decoded).unwrap().is_virtual());
+        assert_eq!(Some(texture), graph.find(rm.path(texture).unwrap()));
+        assert_eq!(vec![texture, decoded], graph.dependencies(scene));
+        assert_eq!(vec![texture, scene], graph.dependents(decoded));
+
+        let root = rm.directory().canonicalize().unwrap();
+        let dot = graph.relative_to(root).to_dot();
+        assert!(dot.contains("r1 [label=\"texture.png\"];"), "{dot}");
+        assert!(dot.contains("r0 -> r1 [label=\"texture\"];"), "{dot}");
+        assert!(dot.contains("r1 -> r2 [label=\"decoded\"];"), "{dot}");
+    }
+
+    #[test]
+    fn revalidate() {
+        let rm = _init();
+        let [p, other] = files(&rm, 2)[..] else {
+            unreachable!()
+        };
+        let data = rm.get_resource(p).unwrap();
+        let v = rm.add_virtual(&data);
+        let derived = rm.add_virtual(&data);
+        rm.set_relation(UPPERCASE, p, v).unwrap();
+        rm.set_relation(LOWERCASE, v, derived).unwrap();
+        // Another resource pointing to the stale one
+        rm.set_relation(UPPERCASE, other, derived).unwrap();
+
+        assert!(!rm.revalidate(p).unwrap());
+        assert!(!rm.revalidate(other).unwrap());
+        assert_eq!(Some(v), rm.get_related(p, UPPERCASE));
+
+        std::fs::write(rm.path(p).unwrap(), "changed").unwrap();
+        assert!(rm.revalidate(p).unwrap());
+        assert_eq!(None, rm.get_related(p, UPPERCASE));
+        assert_eq!(None, rm.get_related(other, UPPERCASE));
+        assert!(!rm.contains(v) && !rm.contains(derived));
+        assert_eq!(0, rm.stats().owned);
+        assert_eq!(b"changed", &rm.get_resource(p).unwrap()[..]);
+        assert!(!rm.revalidate(p).unwrap());
+        assert!(matches!(
+            rm.revalidate(v),
+            Err(ResourceError::NoSuchResource)
+        ));
+        let v = rm.add_virtual(b"");
+        assert!(matches!(
+            rm.revalidate(v),
+            Err(ResourceError::ResourceIsVirtual)
+        ));
+    }
+
+    #[test]
+    fn revalidate_all() {
+        let G(rm, res_temp, cache_temp) = _init();
+        let resources = files(&rm, 3);
+        for &res in &resources {
+            let v = rm.add_virtual(&rm.get_resource(res).unwrap());
+            rm.set_relation(UPPERCASE, res, v).unwrap();
+        }
+        rm.cache().unwrap();
+        drop(rm);
+
+        // The cached virtual resources are checked against the files they were derived from,
+        // without reading them again
+        let rm = ResourceManagerBuilder::begin()
+            .with_resource_path(res_temp.as_path())
+            .with_cache_path(cache_temp.as_path())
+            .build();
+        rm.sync_cache().unwrap();
+        assert_eq!(Vec::<Resource>::new(), rm.revalidate_all().unwrap());
+        std::fs::write(rm.path(resources[1]).unwrap(), "changed").unwrap();
+        assert_eq!(vec![resources[1]], rm.revalidate_all().unwrap());
+        assert_eq!(None, rm.get_related(resources[1], UPPERCASE));
+        assert!(rm.get_related(resources[0], UPPERCASE).is_some());
+        assert!(rm.get_related(resources[2], UPPERCASE).is_some());
+        assert_eq!(2, rm.stats().owned);
+    }
+
+    #[test]
+    fn remove() {
+        let rm = _init();
+        let [p, other] = files(&rm, 2)[..] else {
+            unreachable!()
+        };
+        let v = rm.add_virtual(b"v");
+        rm.set_relation(UPPERCASE, p, v).unwrap();
+        rm.set_relation(LOWERCASE, other, p).unwrap();
+        let path = rm.path(p).unwrap();
+
+        rm.remove(p).unwrap();
+        assert!(!rm.contains(p));
+        assert_eq!(None, rm.path(p));
+        assert_eq!(None, rm.get_related(other, LOWERCASE));
+        assert!(matches!(rm.free(p), Err(ResourceError::NoSuchResource)));
+        assert!(matches!(rm.remove(p), Err(ResourceError::NoSuchResource)));
+        // What was derived from it stays
+        assert_eq!(b"v", &rm.get_resource(v).unwrap()[..]);
+        // The file can be added again, as a new resource
+        let again = rm.add_physical(&path).unwrap();
+        assert_ne!(p, again);
+        assert!(rm.get_resource(again).is_ok());
+        rm.remove(v).unwrap();
+        assert!(!rm.contains(v));
+    }
+
+    #[test]
+    fn remove_cascade() {
+        let rm = _init();
+        let [p, other] = files(&rm, 2)[..] else {
+            unreachable!()
+        };
+        let data = rm.get_resource(p).unwrap();
+        let upper = rm.add_virtual(&data.to_ascii_uppercase());
+        let lower = rm.add_virtual(&data.to_ascii_lowercase());
+        rm.set_relation(UPPERCASE, p, upper).unwrap();
+        rm.set_relation(LOWERCASE, upper, lower).unwrap();
+        rm.cache().unwrap();
+        let cache_path = rm.cache_path.clone().unwrap();
+        let blobs = || {
+            let mut names = std::fs::read_dir(&cache_path)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(3, blobs().len());
+
+        rm.remove_cascade(p).unwrap();
+        assert!(!rm.contains(p) && !rm.contains(upper) && !rm.contains(lower));
+        assert!(rm.get_resource(other).is_ok());
+        rm.cache().unwrap();
+        assert_eq!(vec!["journal", "manifest"], blobs());
+
+        // sync_cache cleans up files left by anything else
+        let kept = rm.add_virtual(b"kept");
+        rm.set_relation(UPPERCASE, other, kept).unwrap();
+        rm.cache().unwrap();
+        std::fs::write(cache_path.join(blob_name(upper)), "stray").unwrap();
+        rm.sync_cache().unwrap();
+        assert_eq!(
+            vec![blob_name(kept), "journal".into(), "manifest".into()],
+            blobs()
+        );
+        assert_eq!(b"kept", &rm.get_resource(kept).unwrap()[..]);
+    }
+
+    fn reopen(res_temp: &Temp, cache_temp: &Temp) -> ResourceManager {
+        ResourceManagerBuilder::begin()
+            .with_resource_path(res_temp.as_path())
+            .with_cache_path(cache_temp.as_path())
+            .build()
+    }
+
+    fn set_mtime(path: &Path, mtime: SystemTime) {
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(mtime).unwrap();
+    }
+
+    fn hour_ago() -> SystemTime {
+        SystemTime::now() - Duration::from_secs(3600)
+    }
+
+    /// The files of the cache directory, with their size and modification time
+    fn listing(cache_path: &Path) -> Vec<(String, u64, SystemTime)> {
+        let mut files = std::fs::read_dir(cache_path)
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                let meta = e.metadata().unwrap();
+                let name = e.file_name().into_string().unwrap();
+                (name, meta.len(), meta.modified().unwrap())
+            })
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        files
+    }
+
+    #[test]
+    fn missing_blob() {
+        let G(rm, res_temp, cache_temp) = _init();
+        let [p, q] = files(&rm, 2)[..] else {
+            unreachable!()
+        };
+        let v = rm.add_virtual(b"v");
+        let derived = rm.add_virtual(b"derived");
+        let w = rm.add_virtual(b"w");
+        rm.set_relation(UPPERCASE, p, v).unwrap();
+        rm.set_relation(LOWERCASE, v, derived).unwrap();
+        rm.set_relation(UPPERCASE, q, w).unwrap();
+        rm.cache().unwrap();
+        let missing = cache_temp.as_path().join(blob_name(v));
+        std::fs::remove_file(&missing).unwrap();
+        drop(rm);
+
+        let rm = reopen(&res_temp, &cache_temp);
+        let mut report = rm.sync_cache().unwrap();
+        assert_eq!(1, report.loaded);
+        report.dropped.sort();
+        assert_eq!(vec![v, derived], report.dropped);
+        let [(path, e)] = &report.errors[..] else {
+            panic!("{:?}", report.errors);
+        };
+        assert_eq!(&missing, path);
+        assert_eq!(std::io::ErrorKind::NotFound, e.kind());
+        assert_eq!(b"w", &rm.get_resource(w).unwrap()[..]);
+        assert!(rm.contains(p) && !rm.contains(v) && !rm.contains(derived));
+        assert_eq!(None, rm.get_related(p, UPPERCASE));
+
+        // A manifest that can't be read is still an error
+        std::fs::write(cache_temp.as_path().join("manifest"), "garbage").unwrap();
+        assert!(matches!(
+            rm.sync_cache(),
+            Err(ResourceError::BinCodeError(_))
+        ));
+    }
+
+    #[test]
+    fn dirty() {
+        let G(rm, _res_temp, cache_temp) = _init();
+        let clean = |rm: &ResourceManager| *rm.dirty.lock() == Dirty::default();
+        let key = |from, relation: &str| (from, relation.to_owned());
+        let [p, q] = files(&rm, 2)[..] else {
+            unreachable!()
+        };
+        let v = rm.add_virtual(b"v");
+        let w = rm.add_virtual(b"w");
+        rm.set_relation(UPPERCASE, p, v).unwrap();
+        rm.set_relation(LOWERCASE, v, w).unwrap();
+        assert_eq!(HashSet::from([p, q, v, w]), rm.dirty.lock().resources);
+        assert_eq!(
+            HashSet::from([key(p, UPPERCASE), key(v, LOWERCASE)]),
+            rm.dirty.lock().relations
+        );
+        rm.cache().unwrap();
+        assert!(clean(&rm));
+
+        // Nothing cached changes
+        assert!(rm.set_relation(UPPERCASE, p, w).is_err());
+        assert!(rm.remove_relation(UPPERCASE, q).is_err());
+        rm.free(p).unwrap();
+        rm.ensure_loaded(p).unwrap();
+        assert_eq!(p, rm.add_physical_mapped(rm.path(p).unwrap()).unwrap());
+        assert!(clean(&rm));
+
+        rm.replace_virtual(w, b"W").unwrap();
+        assert!(matches!(
+            rm.replace_virtual(p, b""),
+            Err(ResourceError::ResourceIsPhysical)
+        ));
+        assert_eq!(HashSet::from([w]), rm.dirty.lock().data);
+        assert_eq!(v, rm.remove_relation(UPPERCASE, p).unwrap());
+        assert_eq!(None, rm.replace_relation(UPPERCASE, q, w));
+        assert_eq!(
+            HashSet::from([key(p, UPPERCASE), key(q, UPPERCASE)]),
+            rm.dirty.lock().relations
+        );
+        rm.cache().unwrap();
+
+        // Removals, with the relations from and to what's removed
+        rm.remove(v).unwrap();
+        assert_eq!(HashSet::from([v]), rm.dirty.lock().resources);
+        assert_eq!(
+            HashSet::from([key(v, LOWERCASE)]),
+            rm.dirty.lock().relations
+        );
+        rm.cache().unwrap();
+        rm.remove_cascade(q).unwrap();
+        assert_eq!(HashSet::from([q, w]), rm.dirty.lock().resources);
+        assert_eq!(
+            HashSet::from([key(q, UPPERCASE)]),
+            rm.dirty.lock().relations
+        );
+        rm.cache().unwrap();
+        let x = rm.add_virtual(b"x");
+        rm.set_relation(LOWERCASE, p, x).unwrap();
+        rm.cache().unwrap();
+        std::fs::write(rm.path(p).unwrap(), "changed").unwrap();
+        assert!(rm.revalidate(p).unwrap());
+        assert_eq!(HashSet::from([x]), rm.dirty.lock().resources);
+
+        // A cache that fails keeps them, and writes everything the next time
+        std::fs::remove_dir_all(cache_temp.as_path()).unwrap();
+        assert!(rm.cache().is_err());
+        assert_eq!(HashSet::from([x]), rm.dirty.lock().resources);
+        std::fs::create_dir(cache_temp.as_path()).unwrap();
+        assert!(rm.cache().unwrap().compacted);
+        assert!(clean(&rm));
+        // Nor does a sync leave any
+        rm.add_virtual(b"y");
+        rm.sync_cache().unwrap();
+        assert!(clean(&rm));
+    }
+
+    #[test]
+    fn incremental_cache() {
+        let G(rm, res_temp, cache_temp) = _init();
+        let cache_path = cache_temp.as_path();
+        let resources = files(&rm, 3);
+        let derived = resources
+            .iter()
+            .map(|&res| {
+                set_mtime(&rm.path(res).unwrap(), hour_ago());
+                let v = rm.add_virtual(&rm.get_resource(res).unwrap());
+                rm.set_relation(UPPERCASE, res, v).unwrap();
+                v
+            })
+            .collect::<Vec<_>>();
+        let report = rm.cache().unwrap();
+        assert!(report.compacted);
+        assert_eq!((3, 3), (report.blobs_written, report.hashed));
+
+        // Nothing changed, nothing is written or even hashed
+        let before = listing(cache_path);
+        assert_eq!(CacheReport::default(), rm.cache().unwrap());
+        assert_eq!(before, listing(cache_path));
+
+        // Only the data
+        rm.replace_virtual(derived[0], b"replaced").unwrap();
+        let expected = CacheReport {
+            blobs_written: 1,
+            blob_bytes: 8,
+            ..Default::default()
+        };
+        assert_eq!(expected, rm.cache().unwrap());
+        let added = rm.add_virtual(b"added");
+        rm.set_relation(LOWERCASE, resources[1], added).unwrap();
+        rm.remove(derived[2]).unwrap();
+        let expected = CacheReport {
+            blobs_written: 1,
+            blob_bytes: 5,
+            blobs_removed: 1,
+            // Added, Removed, and the two relations
+            changes: 4,
+            ..Default::default()
+        };
+        assert_eq!(expected, rm.cache().unwrap());
+        // The manifest and the untouched data are as they were
+        let after = listing(cache_path);
+        for name in ["manifest".to_owned(), blob_name(derived[1])] {
+            let file = |files: &[(String, u64, SystemTime)]| {
+                files.iter().find(|f| f.0 == name).cloned().unwrap()
+            };
+            assert_eq!(file(&before), file(&after));
+        }
+        assert!(after.iter().any(|f| f.0 == "journal"));
+        assert!(!after.iter().any(|f| f.0 == blob_name(derived[2])));
+
+        drop(rm);
+        let rm = reopen(&res_temp, &cache_temp);
+        rm.sync_cache().unwrap();
+        assert_eq!(b"replaced", &rm.get_resource(derived[0]).unwrap()[..]);
+        assert_eq!(Some(derived[1]), rm.get_related(resources[1], UPPERCASE));
+        assert_eq!(Some(added), rm.get_related(resources[1], LOWERCASE));
+        assert_eq!(None, rm.get_related(resources[2], UPPERCASE));
+        assert!(!rm.contains(derived[2]));
+        assert_eq!(CacheReport::default(), rm.cache().unwrap());
+        // The keys are restored with their versions, removed ones aren't given again
+        let fresh = rm.add_virtual(b"fresh");
+        assert_ne!(derived[2], fresh);
+        assert!(!resources.contains(&fresh) && !derived.contains(&fresh));
+    }
+
+    #[test]
+    fn journal_compaction() {
+        const LIMIT: u64 = 1024;
+        let res_temp = Temp::new_dir().unwrap();
+        let cache_temp = Temp::new_dir().unwrap();
+        let journal = cache_temp.as_path().join("journal");
+        let rm = ResourceManagerBuilder::begin()
+            .with_resource_path(res_temp.as_path())
+            .with_cache_path(cache_temp.as_path())
+            .with_journal_limit(LIMIT)
+            .build();
+        let p = files(&rm, 1)[0];
+        rm.cache().unwrap();
+        let state = |rm: &ResourceManager| {
+            let mut resources = rm.resources.read().keys().collect::<Vec<_>>();
+            resources.sort();
+            (resources, rm.relations.read().clone())
+        };
+
+        let mut compactions = 0;
+        for i in 0..40u8 {
+            let v = rm.add_virtual(&[i]);
+            if let Some(old) = rm.replace_relation(UPPERCASE, p, v) {
+                rm.remove(old).unwrap();
+            }
+            rm.set_relation(&format!("r{i}"), v, p).unwrap();
+            let report = rm.cache().unwrap();
+            compactions += report.compacted as usize;
+            let journal_len = std::fs::metadata(&journal).map_or(0, |m| m.len());
+            assert!(journal_len <= LIMIT, "{journal_len}");
+            if i % 8 == 7 {
+                let reader = reopen(&res_temp, &cache_temp);
+                reader.sync_cache().unwrap();
+                assert_eq!(state(&rm), state(&reader));
+                assert_eq!(&[i], &reader.get_resource(v).unwrap()[..]);
+            }
+        }
+        assert!(compactions >= 2, "{compactions}");
+
+        // A journal cut short is read up to its last whole batch, and the next batch replaces
+        // what's left of it
+        let expected = state(&rm);
+        let v = rm.add_virtual(b"lost");
+        rm.set_relation(LOWERCASE, p, v).unwrap();
+        assert!(!rm.cache().unwrap().compacted);
+        let len = std::fs::metadata(&journal).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&journal)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        drop(rm);
+        let rm = reopen(&res_temp, &cache_temp);
+        rm.sync_cache().unwrap();
+        assert_eq!(expected, state(&rm));
+        let v = rm.add_virtual(b"kept");
+        rm.set_relation(LOWERCASE, p, v).unwrap();
+        assert!(!rm.cache().unwrap().compacted);
+        let reader = reopen(&res_temp, &cache_temp);
+        reader.sync_cache().unwrap();
+        assert_eq!(state(&rm), state(&reader));
+        assert_eq!(b"kept", &reader.get_resource(v).unwrap()[..]);
+    }
+
+    #[test]
+    fn legacy_migration() {
+        let G(rm, res_temp, cache_temp) = _init();
+        let cache_path = cache_temp.as_path();
+        let [p, q] = files(&rm, 2)[..] else {
+            unreachable!()
+        };
+        let derived = [p, q].map(|res| {
+            let v = rm.add_virtual(b"derived");
+            rm.set_relation(UPPERCASE, res, v).unwrap();
+            std::fs::write(cache_path.join(blob_name(v)), b"derived").unwrap();
+            v
+        });
+        // As caches were written before the journal
+        let seed = 42;
+        let (state, _) = rm.snapshot();
+        let physical_resources = state
+            .locations
+            .iter()
+            .map(|(path, &res)| {
+                let data = std::fs::read(path).unwrap();
+                let physical = cache::LegacyPhysicalResource {
+                    path: path.clone(),
+                    size: data.len(),
+                    hash: hash(&data, seed),
+                };
+                (res, physical)
+            })
+            .collect();
+        let meta = cache::LegacyMeta {
+            physical_resources,
+            seed,
+        };
+        let legacy_cache = cache_path.join(cache::LEGACY_CACHE);
+        let legacy_meta = cache_path.join(cache::LEGACY_META);
+        std::fs::write(&legacy_cache, bincode::serialize(&state).unwrap()).unwrap();
+        std::fs::write(&legacy_meta, bincode::serialize(&meta).unwrap()).unwrap();
+        // Changed since
+        std::fs::write(rm.path(q).unwrap(), "changed").unwrap();
+        drop(rm);
+
+        let check = |rm: &ResourceManager| {
+            assert_eq!(Some(derived[0]), rm.get_related(p, UPPERCASE));
+            assert_eq!(b"derived", &rm.get_resource(derived[0]).unwrap()[..]);
+            assert_eq!(None, rm.get_related(q, UPPERCASE));
+            assert!(!rm.contains(derived[1]));
+            // Hashed for revalidate, with the seed of the manager
+            assert!(!rm.revalidate(p).unwrap());
+        };
+        let rm = reopen(&res_temp, &cache_temp);
+        rm.sync_cache().unwrap();
+        check(&rm);
+        // Replaced by the new format
+        let report = rm.cache().unwrap();
+        assert!(report.compacted);
+        assert!(!legacy_cache.exists() && !legacy_meta.exists());
+        assert!(cache_path.join("manifest").exists());
+        drop(rm);
+        let rm = reopen(&res_temp, &cache_temp);
+        rm.sync_cache().unwrap();
+        check(&rm);
+    }
+
+    #[test]
+    fn physical_cache() {
+        let G(rm, res_temp, cache_temp) = _init();
+        let open = |physical_cache| {
+            ResourceManagerBuilder::begin()
+                .with_resource_path(res_temp.as_path())
+                .with_cache_path(cache_temp.as_path())
+                .with_physical_cache(physical_cache)
+                .build()
+        };
+        let reads = |rm: &ResourceManager| rm.reads.load(std::sync::atomic::Ordering::Relaxed);
+        let blobs = || {
+            std::fs::read_dir(cache_temp.join("physical"))
+                .map(|d| d.count())
+                .unwrap_or(0)
+        };
+        drop(rm);
+        let rm = open(true);
+        let res = files(&rm, 3);
+        for &p in &res {
+            set_mtime(&rm.path(p).unwrap(), hour_ago());
+        }
+        let report = rm.cache().unwrap();
+        assert_eq!((3, 3), (report.blobs_written, report.blob_bytes));
+        assert_eq!(0, rm.cache().unwrap().blobs_written);
+        assert_eq!(3, blobs());
+
+        // Changed, so dead along with its data
+        std::fs::write(rm.path(res[1]).unwrap(), "changed").unwrap();
+        drop(rm);
+        let rm = open(true);
+        let report = rm.sync_cache().unwrap();
+        assert_eq!((2, vec![res[1]]), (report.physical_loaded, report.dropped));
+        assert_eq!(2, blobs());
+        assert_eq!(b"2", &rm.get_resource(res[2]).unwrap()[..]);
+        assert_eq!(0, reads(&rm));
+
+        // A blob that doesn't match is read from the file
+        let blob = cache::physical_blob(&cache_temp, hash(b"2", HASH_SEED));
+        std::fs::write(blob, "3").unwrap();
+        drop(rm);
+        let rm = open(true);
+        assert_eq!(1, rm.sync_cache().unwrap().physical_loaded);
+        assert_eq!(b"2", &rm.get_resource(res[2]).unwrap()[..]);
+        assert_eq!(1, reads(&rm));
+        // Which the next cache fixes
+        assert_eq!(1, rm.cache().unwrap().blobs_written);
+        drop(rm);
+        let rm = open(true);
+        assert_eq!(2, rm.sync_cache().unwrap().physical_loaded);
+
+        // Without the option the data is deleted
+        drop(rm);
+        let rm = open(false);
+        let report = rm.sync_cache().unwrap();
+        assert_eq!((0, 0), (report.physical_loaded, blobs()));
+        assert!(rm.contains(res[0]));
+    }
+
+    #[test]
+    fn mtime_skip() {
+        let G(rm, res_temp, cache_temp) = _init();
+        let p = files(&rm, 1)[0];
+        let path = rm.path(p).unwrap();
+        let v = rm.add_virtual(b"derived");
+        rm.set_relation(UPPERCASE, p, v).unwrap();
+        let past = hour_ago();
+        set_mtime(&path, past);
+        assert_eq!(1, rm.cache().unwrap().hashed);
+        assert_eq!(0, rm.cache().unwrap().hashed);
+        // Touched, hashed again to record the new time
+        set_mtime(&path, past - Duration::from_secs(60));
+        let report = rm.cache().unwrap();
+        assert_eq!((1, 1), (report.hashed, report.changes));
+        assert_eq!(0, rm.cache().unwrap().hashed);
+
+        // Same size and time: the file isn't hashed, so the change isn't seen
+        std::fs::write(&path, "X").unwrap();
+        set_mtime(&path, past - Duration::from_secs(60));
+        drop(rm);
+        let rm = reopen(&res_temp, &cache_temp);
+        rm.sync_cache().unwrap();
+        assert_eq!(Some(v), rm.get_related(p, UPPERCASE));
+
+        // A time after the check (a clock that was off) says nothing, the file is always hashed
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        set_mtime(&path, future);
+        assert_eq!(1, rm.cache().unwrap().hashed);
+        assert_eq!(1, rm.cache().unwrap().hashed);
+        std::fs::write(&path, "Y").unwrap();
+        set_mtime(&path, future);
+        drop(rm);
+        let rm = reopen(&res_temp, &cache_temp);
+        rm.sync_cache().unwrap();
+        assert!(!rm.contains(p) && !rm.contains(v));
+        let p = rm.add_physical(&path).unwrap();
+        assert_eq!(b"Y", &rm.get_resource(p).unwrap()[..]);
+    }
 }
@@ -0,0 +1,117 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::{ResourceBytes, ResourceError};
+
+/// Threads of the loader. Reads are bound by the disk, more wouldn't make them faster.
+const THREADS: usize = 2;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A few threads reading resources in the background, started on the first job and stopped
+/// when dropped (after the jobs left).
+#[derive(Default)]
+pub(crate) struct Loader {
+    jobs: Mutex<Option<Sender<Job>>>,
+}
+
+impl Loader {
+    pub(crate) fn submit(&self, job: impl FnOnce() + Send + 'static) {
+        self.jobs
+            .lock()
+            .get_or_insert_with(Self::start)
+            .send(Box::new(job))
+            .expect("The loader threads are gone");
+    }
+    fn start() -> Sender<Job> {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..THREADS {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("rmanage loader {i}"))
+                .spawn(move || Self::work(&receiver))
+                .expect("Couldn't start a loader thread");
+        }
+        sender
+    }
+    fn work(receiver: &Mutex<Receiver<Job>>) {
+        loop {
+            let job = receiver.lock().recv();
+            match job {
+                Ok(job) => job(),
+                // The manager was dropped
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// A load in flight, shared by the handles of the resource
+pub(crate) struct PendingLoad {
+    result: Mutex<Option<Result<ResourceBytes, ResourceError>>>,
+    done: Condvar,
+    /// Set by `ResourceManager::free`, the data isn't kept once read
+    pub(crate) cancelled: AtomicBool,
+}
+
+impl PendingLoad {
+    pub(crate) fn new() -> Self {
+        Self {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+    pub(crate) fn complete(&self, result: Result<ResourceBytes, ResourceError>) {
+        *self.result.lock() = Some(result);
+        self.done.notify_all();
+    }
+}
+
+/// The data of a resource being loaded, see `ResourceManager::load_async`
+#[derive(Clone)]
+pub struct ResourceLoadHandle {
+    load: Arc<PendingLoad>,
+}
+
+impl ResourceLoadHandle {
+    pub(crate) fn new(load: Arc<PendingLoad>) -> Self {
+        Self { load }
+    }
+    /// A handle to a load that's already over
+    pub(crate) fn ready(result: Result<ResourceBytes, ResourceError>) -> Self {
+        let load = PendingLoad::new();
+        load.complete(result);
+        Self::new(Arc::new(load))
+    }
+    /// Returns true if `ResourceLoadHandle::wait` won't block
+    pub fn is_ready(&self) -> bool {
+        self.load.result.lock().is_some()
+    }
+    /// Block until the resource is loaded
+    pub fn wait(&self) -> Result<ResourceBytes, ResourceError> {
+        let mut result = self.load.result.lock();
+        loop {
+            match &*result {
+                Some(Ok(data)) => return Ok(data.clone()),
+                Some(Err(e)) => return Err(e.duplicate()),
+                None => self.load.done.wait(&mut result),
+            }
+        }
+    }
+}
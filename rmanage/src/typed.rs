@@ -0,0 +1,33 @@
+//! Resources decoded into values, see `ResourceManager::get_typed`.
+
+use std::{any::Any, convert::Infallible, string::FromUtf8Error, sync::Arc};
+
+use parking_lot::Mutex;
+
+/// A value a resource can be decoded into. Implement it for your own types to get them with
+/// `ResourceManager::get_typed`.
+pub trait ResourceDecode: Sized + Send + Sync + 'static {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn decode(bytes: &[u8]) -> Result<Self, Self::Error>;
+}
+
+impl ResourceDecode for Vec<u8> {
+    type Error = Infallible;
+
+    fn decode(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl ResourceDecode for String {
+    type Error = FromUtf8Error;
+
+    fn decode(bytes: &[u8]) -> Result<Self, Self::Error> {
+        String::from_utf8(bytes.to_vec())
+    }
+}
+
+/// The decoded value of a resource (an `Arc<T>`), locked while decoding so concurrent calls
+/// wait for it instead of decoding again
+pub(crate) type DecodedSlot = Arc<Mutex<Option<Box<dyn Any + Send + Sync>>>>;
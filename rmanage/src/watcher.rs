@@ -0,0 +1,125 @@
+//! Watching the resources directory for changes, see `ResourceManagerBuilder::with_watcher`.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use parking_lot::Mutex;
+
+use crate::Resource;
+
+/// Time between two scans of the resources directory
+const INTERVAL: Duration = Duration::from_millis(200);
+
+/// A change of the file of a physical resource, see `ResourceManager::poll_events`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceEvent {
+    /// The file changed, the resource was freed so the next access reads it again
+    Modified(Resource),
+    /// The file was deleted, the resource keeps what was loaded
+    Removed(Resource),
+}
+
+/// A change of a file of the resources directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FileEvent {
+    Modified(PathBuf),
+    Removed(PathBuf),
+}
+
+/// A thread scanning the resources directory for files that changed (by size or modification
+/// time) or were deleted. Polling works everywhere, and is cheap enough at the size of a
+/// resources directory.
+pub(crate) struct Watcher {
+    events: Mutex<Receiver<FileEvent>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+type Stamps = HashMap<PathBuf, (u64, Option<SystemTime>)>;
+
+/// Size and modification time of every file under a directory
+fn scan(dir: &Path, stamps: &mut Stamps) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        if meta.is_dir() {
+            scan(&entry.path(), stamps);
+        } else {
+            stamps.insert(entry.path(), (meta.len(), meta.modified().ok()));
+        }
+    }
+}
+
+/// Send the changes from one scan to the next, false if no one listens anymore
+fn diff(before: &Stamps, after: &Stamps, sender: &Sender<FileEvent>) -> bool {
+    let modified = after
+        .iter()
+        .filter(|(path, stamp)| before.get(*path).is_some_and(|old| old != *stamp))
+        .map(|(path, _)| FileEvent::Modified(path.clone()));
+    let removed = before
+        .keys()
+        .filter(|path| !after.contains_key(*path))
+        .map(|path| FileEvent::Removed(path.clone()));
+    modified
+        .chain(removed)
+        .all(|event| sender.send(event).is_ok())
+}
+
+impl Watcher {
+    pub(crate) fn start(dir: PathBuf) -> Self {
+        let (sender, receiver) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::Builder::new()
+            .name("rmanage watcher".to_owned())
+            .spawn(move || {
+                let mut stamps = Stamps::new();
+                scan(&dir, &mut stamps);
+                loop {
+                    thread::park_timeout(INTERVAL);
+                    if stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let mut next = Stamps::new();
+                    scan(&dir, &mut next);
+                    if !diff(&stamps, &next, &sender) {
+                        break;
+                    }
+                    stamps = next;
+                }
+            })
+            .expect("Couldn't start the watcher thread");
+        Self {
+            events: Mutex::new(receiver),
+            stop,
+            thread: Some(thread),
+        }
+    }
+    /// The changes since the last call
+    pub(crate) fn events(&self) -> Vec<FileEvent> {
+        self.events.lock().try_iter().collect()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            thread.join().ok();
+        }
+    }
+}
@@ -0,0 +1,8 @@
+//! Render a thumbnail of a glTF model without a window:
+//! `cargo run -p sg --example render_thumbnail -- <model.glb> <out.png> [--size N]`
+
+fn main() {
+    sg::crash::init_logger();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    std::process::exit(sg::thumbnail::main(&args));
+}
@@ -0,0 +1,594 @@
+//! Performance regression benchmarks: `sg bench --builtin <scene>` runs one of the generated scenes
+//! of `bench_scenes` for a fixed number of frames along a scripted camera path, records the
+//! timings of every frame into a JSON report, and compares them to an older report with
+//! `--compare old.json`.
+//!
+//! Windowed, a frame is timed from the start of the schedule to the end of the submission, plus
+//! the wait for the GPU (vsync is off and the GPU is synced every frame). Headless only runs the
+//! simulation side of the scene, there is no GPU time.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use ecs::{Executor, World};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bench_scenes::{self, BenchCamera, SceneDesc, SceneKind, SceneParams},
+    systems::{graphics::FrameTimings, time::Time},
+};
+
+/// Step of the fixed time of the benchmarks, so the camera and lights are at the same place at the
+/// same frame whatever the frame rate
+pub const STEP: Duration = Duration::from_micros(16_667);
+/// Default regression threshold, in percent
+pub const THRESHOLD: f32 = 10.0;
+
+/// Arguments of `sg bench`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchArgs {
+    /// None when only comparing two reports
+    pub scene: Option<(SceneKind, SceneParams)>,
+    pub headless: bool,
+    pub out: Option<PathBuf>,
+    pub compare: Option<PathBuf>,
+    /// Report compared to `compare` without running anything
+    pub against: Option<PathBuf>,
+    /// Percent of slow down past which a metric is a regression
+    pub threshold: f32,
+}
+
+impl BenchArgs {
+    pub const USAGE: &'static str =
+        "usage: sg bench --builtin <scene> [--seed N] [--frames N] [--warmup N] \
+[--objects N] [--materials N] [--subdivisions N] [--lights N] [--windows N] [--headless] \
+[--out report.json] [--compare old.json] [--threshold percent]
+       sg bench --compare old.json new.json [--threshold percent]";
+
+    /// Parse the arguments following `bench`
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut scene = None;
+        let mut overrides = Vec::new();
+        let mut bench = Self {
+            scene: None,
+            headless: false,
+            out: None,
+            compare: None,
+            against: None,
+            threshold: THRESHOLD,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("{arg} expects a value"))
+            };
+            match arg.as_str() {
+                "--builtin" => {
+                    let name = value()?;
+                    let names = SceneKind::ALL.map(SceneKind::name).join(", ");
+                    scene = Some(SceneKind::from_name(name).with_context(|| {
+                        format!("no builtin scene '{name}', expected one of {names}")
+                    })?);
+                }
+                "--headless" => bench.headless = true,
+                "--out" => bench.out = Some(value()?.into()),
+                "--compare" => bench.compare = Some(value()?.into()),
+                "--threshold" => {
+                    let value = value()?;
+                    bench.threshold = value
+                        .parse()
+                        .ok()
+                        .filter(|threshold: &f32| *threshold >= 0.0)
+                        .with_context(|| format!("invalid threshold '{value}'"))?;
+                }
+                "--seed" | "--frames" | "--warmup" | "--objects" | "--materials"
+                | "--subdivisions" | "--lights" | "--windows" => {
+                    let value = value()?;
+                    let parsed = value
+                        .parse::<u64>()
+                        .with_context(|| format!("invalid value '{value}' for {arg}"))?;
+                    overrides.push((arg.as_str(), parsed));
+                }
+                _ if !arg.starts_with("--") && bench.against.is_none() => {
+                    bench.against = Some(arg.into())
+                }
+                _ => bail!("unexpected argument '{arg}'\n{}", Self::USAGE),
+            }
+        }
+
+        match scene {
+            Some(kind) => {
+                if bench.against.is_some() {
+                    bail!("a report to compare and a scene to run were both given");
+                }
+                let mut params = kind.params();
+                for (arg, value) in overrides {
+                    let small =
+                        || u32::try_from(value).with_context(|| format!("{arg} is too large"));
+                    match arg {
+                        "--seed" => params.seed = value,
+                        "--frames" => params.frames = small()?,
+                        "--warmup" => params.warmup = small()?,
+                        "--objects" => params.objects = small()?,
+                        "--materials" => params.materials = small()?,
+                        "--subdivisions" => params.subdivisions = small()?,
+                        "--lights" => params.lights = small()?,
+                        _ => params.windows = small()?,
+                    }
+                }
+                params.validate()?;
+                bench.scene = Some((kind, params));
+            }
+            None if bench.against.is_some() && bench.compare.is_some() => {}
+            None => bail!("no scene to run\n{}", Self::USAGE),
+        }
+        Ok(bench)
+    }
+    /// Where the report is written
+    pub fn out(&self, kind: SceneKind) -> PathBuf {
+        self.out
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("bench-{kind}.json")))
+    }
+}
+
+/// Timings of a measured frame, in milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameSample {
+    pub cpu_ms: f32,
+    pub gpu_ms: Option<f32>,
+    pub passes: BTreeMap<String, f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub mean: f32,
+    pub median: f32,
+    pub p95: f32,
+    pub max: f32,
+}
+
+impl Summary {
+    /// None without any value
+    pub fn of(values: &[f32]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f32::total_cmp);
+        // Nearest rank
+        let rank =
+            |p: f32| sorted[((p * sorted.len() as f32).ceil() as usize).clamp(1, sorted.len()) - 1];
+        Some(Self {
+            mean: values.iter().sum::<f32>() / values.len() as f32,
+            median: rank(0.5),
+            p95: rank(0.95),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchReport {
+    pub scene: SceneKind,
+    pub params: SceneParams,
+    pub headless: bool,
+    /// Name of the GPU, none headless
+    pub adapter: Option<String>,
+    /// Summary of every metric ("cpu_ms", "gpu_ms", and "pass.<name>")
+    pub summary: BTreeMap<String, Summary>,
+    pub frames: Vec<FrameSample>,
+}
+
+impl BenchReport {
+    pub fn new(
+        scene: SceneKind,
+        params: SceneParams,
+        adapter: Option<String>,
+        frames: Vec<FrameSample>,
+    ) -> Self {
+        let mut metrics = BTreeMap::<String, Vec<f32>>::new();
+        for frame in &frames {
+            metrics
+                .entry("cpu_ms".into())
+                .or_default()
+                .push(frame.cpu_ms);
+            if let Some(gpu) = frame.gpu_ms {
+                metrics.entry("gpu_ms".into()).or_default().push(gpu);
+            }
+            for (pass, ms) in &frame.passes {
+                metrics.entry(format!("pass.{pass}")).or_default().push(*ms);
+            }
+        }
+        let summary = metrics
+            .into_iter()
+            .filter_map(|(metric, values)| Some((metric, Summary::of(&values)?)))
+            .collect();
+        Self {
+            scene,
+            params,
+            headless: adapter.is_none(),
+            adapter,
+            summary,
+            frames,
+        }
+    }
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            std::fs::File::open(path).with_context(|| format!("can't open {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("{} isn't a benchmark report", path.display()))
+    }
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("can't create {}", path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+/// A statistic of a metric in two reports
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    /// "<metric>.<statistic>"
+    pub metric: String,
+    pub old: f32,
+    pub new: f32,
+    /// Percent, positive when slower
+    pub change: f32,
+    pub regression: bool,
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<24} {:>9.3}ms -> {:>9.3}ms ({:+.1}%){}",
+            self.metric,
+            self.old,
+            self.new,
+            self.change,
+            if self.regression { "  REGRESSION" } else { "" }
+        )
+    }
+}
+
+/// Compare the medians and 95th percentiles of the metrics in both reports. A metric is a
+/// regression when it got slower by more than `threshold` percent.
+pub fn compare(old: &BenchReport, new: &BenchReport, threshold: f32) -> Vec<Comparison> {
+    let mut comparisons = Vec::new();
+    for (metric, new_summary) in &new.summary {
+        let old_summary = match old.summary.get(metric) {
+            Some(summary) => summary,
+            None => continue,
+        };
+        let stats = [
+            ("median", old_summary.median, new_summary.median),
+            ("p95", old_summary.p95, new_summary.p95),
+        ];
+        for (stat, old, new) in stats {
+            let change = if old > 0.0 {
+                (new - old) / old * 100.0
+            } else if new > 0.0 {
+                f32::INFINITY
+            } else {
+                0.0
+            };
+            comparisons.push(Comparison {
+                metric: format!("{metric}.{stat}"),
+                old,
+                new,
+                change,
+                regression: change > threshold,
+            });
+        }
+    }
+    comparisons
+}
+
+/// Collects the frames of a benchmark, past its warmup
+pub struct Recorder {
+    kind: SceneKind,
+    params: SceneParams,
+    frame: u32,
+    frames: Vec<FrameSample>,
+}
+
+impl Recorder {
+    pub fn new(kind: SceneKind, params: SceneParams) -> Self {
+        Self {
+            kind,
+            params,
+            frame: 0,
+            frames: Vec::with_capacity(params.frames as usize),
+        }
+    }
+    /// Record a frame, `frame` being its whole duration (the GPU wait is taken off the CPU time).
+    /// Returns true once all the frames have been measured.
+    pub fn record(&mut self, frame: Duration, timings: &FrameTimings) -> bool {
+        self.frame += 1;
+        if self.frame > self.params.warmup {
+            let mut passes = timings
+                .passes
+                .iter()
+                .map(|(pass, ms)| (pass.to_string(), *ms))
+                .collect::<BTreeMap<_, _>>();
+            for (pass, ms) in &timings.gpu_passes {
+                passes.insert(format!("{pass}_gpu"), *ms);
+            }
+            self.frames.push(FrameSample {
+                cpu_ms: frame.as_secs_f32() * 1000.0 - timings.gpu_ms.unwrap_or(0.0),
+                gpu_ms: timings.gpu_ms,
+                passes,
+            });
+        }
+        self.frames.len() >= self.params.frames as usize
+    }
+    pub fn finish(&mut self, adapter: Option<String>) -> BenchReport {
+        let frames = std::mem::take(&mut self.frames);
+        BenchReport::new(self.kind, self.params, adapter, frames)
+    }
+}
+
+/// Run the simulation side of a scene, without a window or a GPU
+pub fn run_headless(kind: SceneKind, params: SceneParams) -> Result<BenchReport> {
+    let scene = SceneDesc::generate(kind, params)?;
+    let mut world = World::new();
+    let mut executor = Executor::new();
+    scene.spawn(&mut world, None)?;
+    executor.add_resource(Time::fixed(STEP));
+    executor.add_resource(scene.camera());
+    let schedule = executor
+        .schedule()
+        .then(Time::update)
+        .then(bench_scenes::animate_lights)
+        .then(|time: &Time, camera: &mut BenchCamera| camera.update(time))
+        .build();
+
+    let mut recorder = Recorder::new(kind, params);
+    loop {
+        let start = Instant::now();
+        executor.execute(&schedule, &mut world);
+        let mut timings = FrameTimings::default();
+        timings
+            .passes
+            .push(("simulation", start.elapsed().as_secs_f32() * 1000.0));
+        if recorder.record(start.elapsed(), &timings) {
+            return Ok(recorder.finish(None));
+        }
+    }
+}
+
+/// Write the report and compare it to the old one, returns true if there are regressions
+pub fn finish(args: &BenchArgs, report: &BenchReport) -> Result<bool> {
+    let out = args.out(report.scene);
+    report.save(&out)?;
+    println!(
+        "{} ({} frames{}) written to {}",
+        report.scene,
+        report.frames.len(),
+        report
+            .adapter
+            .as_ref()
+            .map(|name| format!(" on {name}"))
+            .unwrap_or_default(),
+        out.display()
+    );
+    for (metric, summary) in &report.summary {
+        println!(
+            "  {metric:<20} mean {:>8.3}ms  median {:>8.3}ms  p95 {:>8.3}ms  max {:>8.3}ms",
+            summary.mean, summary.median, summary.p95, summary.max
+        );
+    }
+    match &args.compare {
+        Some(old) => print_comparison(&BenchReport::load(old)?, report, args.threshold),
+        None => Ok(false),
+    }
+}
+
+/// Returns true if there are regressions
+fn print_comparison(old: &BenchReport, new: &BenchReport, threshold: f32) -> Result<bool> {
+    if (old.scene, old.params, old.headless) != (new.scene, new.params, new.headless) {
+        log::warn!(
+            "The reports aren't of the same scene and parameters, the comparison is meaningless"
+        );
+    }
+    if old.adapter != new.adapter {
+        log::warn!(
+            "The reports were made on different GPUs ({:?} and {:?})",
+            old.adapter,
+            new.adapter
+        );
+    }
+    let comparisons = compare(old, new, threshold);
+    let regressions = comparisons.iter().filter(|c| c.regression).count();
+    for comparison in &comparisons {
+        println!("{comparison}");
+    }
+    if regressions > 0 {
+        println!("{regressions} regressions over {threshold}%");
+    } else {
+        println!("No regression over {threshold}%");
+    }
+    Ok(regressions > 0)
+}
+
+/// `sg bench`, everything but the windowed runs. Returns the exit code, None if the scene needs
+/// to run windowed.
+pub fn main(args: &BenchArgs) -> Option<i32> {
+    let result = match (args.scene, &args.against) {
+        (Some((kind, params)), _) if args.headless => {
+            run_headless(kind, params).and_then(|report| finish(args, &report))
+        }
+        (Some(_), _) => return None,
+        (None, Some(new)) => BenchReport::load(new).and_then(|new| {
+            let old = args.compare.as_ref().expect("checked by BenchArgs::parse");
+            let old = BenchReport::load(old)?;
+            print_comparison(&old, &new, args.threshold)
+        }),
+        (None, None) => unreachable!("checked by BenchArgs::parse"),
+    };
+    Some(exit_code(result))
+}
+
+pub fn exit_code(result: Result<bool>) -> i32 {
+    match result {
+        Ok(false) => 0,
+        Ok(true) => 1,
+        Err(e) => {
+            eprintln!("{e:#}");
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Result<BenchArgs> {
+        BenchArgs::parse(
+            &args
+                .split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn report(cpu: &[f32], gpu: Option<f32>) -> BenchReport {
+        let frames = cpu
+            .iter()
+            .map(|&cpu_ms| FrameSample {
+                cpu_ms,
+                gpu_ms: gpu,
+                passes: BTreeMap::from([("world".to_owned(), cpu_ms / 2.0)]),
+            })
+            .collect();
+        let kind = SceneKind::ManyObjects;
+        BenchReport::new(kind, kind.params(), Some("gpu".into()), frames)
+    }
+
+    #[test]
+    fn summary() {
+        assert_eq!(None, Summary::of(&[]));
+        let values = (1..=100).rev().map(|v| v as f32).collect::<Vec<_>>();
+        let summary = Summary::of(&values).unwrap();
+        assert_eq!(50.5, summary.mean);
+        assert_eq!(50.0, summary.median);
+        assert_eq!(95.0, summary.p95);
+        assert_eq!(100.0, summary.max);
+        let single = Summary::of(&[3.0]).unwrap();
+        assert_eq!((3.0, 3.0, 3.0), (single.median, single.p95, single.max));
+    }
+
+    #[test]
+    fn comparison() {
+        let old = report(&[10.0; 20], Some(0.0));
+        // 5% slower, 20% slower
+        let same = report(&[10.5; 20], Some(0.0));
+        let slower = report(&[12.0; 20], Some(0.0));
+
+        let comparisons = compare(&old, &same, THRESHOLD);
+        assert!(comparisons.iter().all(|c| !c.regression));
+        let cpu = comparisons
+            .iter()
+            .find(|c| c.metric == "cpu_ms.median")
+            .unwrap();
+        assert!((cpu.change - 5.0).abs() < 1e-3);
+
+        let comparisons = compare(&old, &slower, THRESHOLD);
+        let regressions = comparisons
+            .iter()
+            .filter(|c| c.regression)
+            .map(|c| c.metric.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "cpu_ms.median",
+                "cpu_ms.p95",
+                "pass.world.median",
+                "pass.world.p95"
+            ],
+            regressions
+        );
+        // Faster is never a regression, 0 stays 0
+        assert!(compare(&slower, &old, 0.0)
+            .iter()
+            .all(|c| !c.regression && c.change <= 0.0));
+
+        // Metrics missing from one of the reports are skipped
+        let headless = report(&[12.0; 20], None);
+        assert!(compare(&old, &headless, THRESHOLD)
+            .iter()
+            .all(|c| !c.metric.starts_with("gpu")));
+        let zero = compare(
+            &report(&[0.0; 4], None),
+            &report(&[1.0; 4], None),
+            THRESHOLD,
+        );
+        assert!(zero
+            .iter()
+            .all(|c| c.regression && c.change == f32::INFINITY));
+    }
+
+    #[test]
+    fn report_roundtrip() {
+        let report = report(&[1.0, 2.0, 3.0], Some(1.5));
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(report, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn arguments() {
+        let bench =
+            args("--builtin many_lights --seed 7 --lights 12 --headless --threshold 5").unwrap();
+        let (kind, params) = bench.scene.unwrap();
+        assert_eq!(SceneKind::ManyLights, kind);
+        assert_eq!((7, 12), (params.seed, params.lights));
+        assert_eq!(SceneKind::ManyLights.params().frames, params.frames);
+        assert!(bench.headless);
+        assert_eq!(5.0, bench.threshold);
+        assert_eq!(PathBuf::from("bench-many_lights.json"), bench.out(kind));
+
+        let bench = args("--compare old.json new.json").unwrap();
+        assert_eq!(None, bench.scene);
+        assert_eq!(Some(PathBuf::from("new.json")), bench.against);
+
+        for invalid in [
+            "",
+            "--builtin",
+            "--builtin nothing",
+            "--builtin mixed --frames 0",
+            "--builtin mixed --subdivisions 12",
+            "--builtin mixed --lights many",
+            "--builtin mixed --threshold -1",
+            "--builtin mixed --objects 99999999999",
+            "--builtin mixed --unknown",
+            "--builtin mixed new.json",
+            "new.json",
+        ] {
+            assert!(args(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn headless() {
+        let params = SceneParams {
+            frames: 5,
+            warmup: 2,
+            ..SceneKind::Mixed.params()
+        };
+        let report = run_headless(SceneKind::Mixed, params).unwrap();
+        assert_eq!(5, report.frames.len());
+        assert!(report.headless);
+        assert!(report.summary.contains_key("pass.simulation"));
+        assert!(!report.summary.contains_key("gpu_ms"));
+    }
+}
@@ -0,0 +1,561 @@
+//! Procedurally generated scenes for benchmarks (see `bench`). A scene is generated from a seed
+//! and its parameters only, so the same scene can be measured on any machine, and spawns on the
+//! windowed path (with meshes and materials) as well as headless (transforms and lights only).
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    f32::consts::TAU,
+    fmt::Display,
+    hash::{Hash, Hasher},
+};
+
+use anyhow::{bail, Result};
+use ecs::{Entities, World};
+use glam::{EulerRot, Quat, Vec3, Vec4};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::{GraphicsComponent, LightComponent, TransformsComponent},
+    systems::{
+        graphics::{
+            mesh_manager::{Mesh, Primitives},
+            renderer::WorldRenderer,
+            texture_manager::SingleValue,
+            GraphicContext, Light, Material, PointLight,
+        },
+        path::{Interpolation, PathComponent},
+        rng::GameRng,
+        time::Time,
+    },
+};
+
+/// Past that, the vertex buffer of the mesh goes over a few hundred MiB
+pub const MAX_SUBDIVISIONS: u32 = 9;
+pub const MAX_OBJECTS: u32 = 100_000;
+pub const MAX_LIGHTS: u32 = 4096;
+pub const MAX_WINDOWS: u32 = 2000;
+/// Distance between two objects of the grid
+const SPACING: f32 = 2.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SceneKind {
+    /// Thousands of icospheres in a grid, with varied materials
+    ManyObjects,
+    /// Hundreds of moving point lights over a plane
+    ManyLights,
+    /// A few objects sharing a multi-million triangles mesh
+    HeavyGeometry,
+    /// Hundreds of egui windows over a small scene
+    UiStress,
+    /// A bit of everything
+    Mixed,
+}
+
+impl SceneKind {
+    pub const ALL: [Self; 5] = [
+        Self::ManyObjects,
+        Self::ManyLights,
+        Self::HeavyGeometry,
+        Self::UiStress,
+        Self::Mixed,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ManyObjects => "many_objects",
+            Self::ManyLights => "many_lights",
+            Self::HeavyGeometry => "heavy_geometry",
+            Self::UiStress => "ui_stress",
+            Self::Mixed => "mixed",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+    /// The default parameters of the scene
+    pub fn params(self) -> SceneParams {
+        let base = SceneParams {
+            seed: 0,
+            objects: 0,
+            materials: 8,
+            subdivisions: 2,
+            lights: 0,
+            windows: 0,
+            frames: 600,
+            warmup: 30,
+        };
+        match self {
+            Self::ManyObjects => SceneParams {
+                objects: 4000,
+                materials: 32,
+                lights: 4,
+                ..base
+            },
+            Self::ManyLights => SceneParams {
+                lights: 256,
+                ..base
+            },
+            Self::HeavyGeometry => SceneParams {
+                objects: 4,
+                subdivisions: 8,
+                lights: 4,
+                ..base
+            },
+            Self::UiStress => SceneParams {
+                objects: 16,
+                lights: 4,
+                windows: 300,
+                ..base
+            },
+            Self::Mixed => SceneParams {
+                objects: 1000,
+                materials: 16,
+                subdivisions: 3,
+                lights: 64,
+                windows: 20,
+                ..base
+            },
+        }
+    }
+}
+
+impl Display for SceneKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What a scene is generated from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SceneParams {
+    pub seed: u64,
+    pub objects: u32,
+    /// Distinct materials the objects pick from
+    pub materials: u32,
+    /// Subdivisions of the icosphere of the objects, 20 * 4^n triangles
+    pub subdivisions: u32,
+    pub lights: u32,
+    /// egui windows drawn every frame
+    pub windows: u32,
+    /// Frames measured
+    pub frames: u32,
+    /// Frames run before measuring (pipeline compilation, uploads)
+    pub warmup: u32,
+}
+
+impl SceneParams {
+    pub fn validate(&self) -> Result<()> {
+        if self.frames == 0 {
+            bail!("frames must be at least 1");
+        }
+        if self.objects > MAX_OBJECTS {
+            bail!("{} objects, at most {MAX_OBJECTS}", self.objects);
+        }
+        if self.objects > 0 && self.materials == 0 {
+            bail!("objects need at least one material");
+        }
+        if self.subdivisions > MAX_SUBDIVISIONS {
+            bail!(
+                "{} subdivisions, at most {MAX_SUBDIVISIONS}",
+                self.subdivisions
+            );
+        }
+        if self.lights > MAX_LIGHTS {
+            bail!("{} lights, at most {MAX_LIGHTS}", self.lights);
+        }
+        if self.windows > MAX_WINDOWS {
+            bail!("{} windows, at most {MAX_WINDOWS}", self.windows);
+        }
+        Ok(())
+    }
+    /// Triangles of the object mesh
+    pub fn triangles(&self) -> u64 {
+        20 * 4u64.pow(self.subdivisions)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MaterialDesc {
+    pub color: Vec4,
+    pub metallic: f32,
+    pub roughness: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ObjectDesc {
+    pub position: Vec3,
+    pub scale: f32,
+    /// Index in `SceneDesc::materials`
+    pub material: usize,
+}
+
+/// A point light moving in a circle
+#[derive(Debug, Clone, Copy)]
+pub struct BenchLightComponent {
+    pub center: Vec3,
+    pub radius: f32,
+    /// Radians per second
+    pub speed: f32,
+    pub phase: f32,
+}
+
+impl BenchLightComponent {
+    pub fn position(&self, time: f32) -> Vec3 {
+        let angle = self.phase + self.speed * time;
+        self.center + Vec3::new(angle.cos(), 0.0, angle.sin()) * self.radius
+    }
+}
+
+/// A generated scene, before it's spawned
+#[derive(Debug, Clone)]
+pub struct SceneDesc {
+    pub kind: SceneKind,
+    pub params: SceneParams,
+    pub materials: Vec<MaterialDesc>,
+    pub objects: Vec<ObjectDesc>,
+    pub lights: Vec<(BenchLightComponent, Vec4)>,
+    /// Size of the ground plane, none without lights to show
+    pub ground: Option<f32>,
+    /// Center and radius of the scene, for the camera
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl SceneDesc {
+    pub fn generate(kind: SceneKind, params: SceneParams) -> Result<Self> {
+        params.validate()?;
+        let mut rng = GameRng::new(params.seed);
+
+        let materials = (0..params.materials)
+            .map(|_| MaterialDesc {
+                color: Vec4::new(rng.gen(), rng.gen(), rng.gen(), 1.0),
+                metallic: rng.gen_range(0.0..1.0),
+                roughness: rng.gen_range(0.1..1.0),
+            })
+            .collect::<Vec<_>>();
+
+        // Objects on a square grid centered on the origin, heavy ones are spread wider
+        let side = (params.objects as f32).sqrt().ceil().max(1.0) as u32;
+        let spacing = if kind == SceneKind::HeavyGeometry {
+            SPACING * 2.0
+        } else {
+            SPACING
+        };
+        let half = (side - 1) as f32 * spacing / 2.0;
+        let objects = (0..params.objects)
+            .map(|i| {
+                let (x, z) = ((i % side) as f32, (i / side) as f32);
+                ObjectDesc {
+                    position: Vec3::new(
+                        x * spacing - half,
+                        rng.gen_range(0.0..0.5),
+                        z * spacing - half,
+                    ),
+                    scale: if kind == SceneKind::HeavyGeometry {
+                        2.0
+                    } else {
+                        rng.gen_range(0.5..1.0)
+                    },
+                    material: rng.gen_range(0..params.materials as usize),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let radius = (half * std::f32::consts::SQRT_2).max(8.0);
+        let lights = (0..params.lights)
+            .map(|_| {
+                let light = BenchLightComponent {
+                    center: Vec3::new(
+                        rng.gen_range(-radius..radius),
+                        rng.gen_range(1.0..4.0),
+                        rng.gen_range(-radius..radius),
+                    ),
+                    radius: rng.gen_range(0.5..3.0),
+                    speed: rng.gen_range(-2.0..2.0),
+                    phase: rng.gen_range(0.0..TAU),
+                };
+                let color = Vec4::new(rng.gen(), rng.gen(), rng.gen(), 1.0) * 5.0;
+                (light, color.truncate().extend(1.0))
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Self {
+            kind,
+            params,
+            materials,
+            objects,
+            ground: (!lights.is_empty()).then_some(radius * 2.5),
+            lights,
+            center: Vec3::ZERO,
+            radius,
+        })
+    }
+    /// Hash of the positions of the scene, the same for a seed on every machine
+    pub fn position_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for object in &self.objects {
+            object
+                .position
+                .to_array()
+                .map(f32::to_bits)
+                .hash(&mut hasher);
+            object.scale.to_bits().hash(&mut hasher);
+            object.material.hash(&mut hasher);
+        }
+        for (light, _) in &self.lights {
+            light
+                .position(0.0)
+                .to_array()
+                .map(f32::to_bits)
+                .hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+    /// Spawn the scene, with the meshes and materials if there is a graphic context (windowed
+    /// path), with only the transforms and lights otherwise (headless path). Returns the number of
+    /// entities spawned.
+    pub fn spawn(&self, world: &mut World, gfx: Option<&mut GraphicContext>) -> Result<usize> {
+        let mut spawned = 0;
+        let transforms = |position: Vec3, scale: Vec3| {
+            let mut tsm = TransformsComponent::new();
+            tsm.set_translation(position).set_scale(scale);
+            tsm
+        };
+
+        match gfx {
+            Some(gfx) => {
+                let sphere = gfx
+                    .mesh_manager
+                    .add(&gfx.device, &Mesh::new_icosphere(self.params.subdivisions));
+                let materials = self
+                    .materials
+                    .iter()
+                    .map(|desc| material(gfx, desc.color, desc.metallic, desc.roughness))
+                    .collect::<Result<Vec<_>>>()?;
+                for object in &self.objects {
+                    let gfc = GraphicsComponent {
+                        mesh: sphere,
+                        material: materials[object.material],
+                    };
+                    world.spawn((gfc, transforms(object.position, Vec3::splat(object.scale))));
+                    spawned += 1;
+                }
+                if let Some(size) = self.ground {
+                    let cube = gfx.mesh_manager.add(&gfx.device, &Mesh::new_cube());
+                    let material = material(gfx, Vec4::new(0.6, 0.6, 0.6, 1.0), 0.0, 0.9)?;
+                    let tsm = transforms(Vec3::new(0.0, -0.6, 0.0), Vec3::new(size, 0.2, size));
+                    world.spawn((
+                        GraphicsComponent {
+                            mesh: cube,
+                            material,
+                        },
+                        tsm,
+                    ));
+                    spawned += 1;
+                }
+            }
+            None => {
+                for object in &self.objects {
+                    world.spawn((transforms(object.position, Vec3::splat(object.scale)),));
+                    spawned += 1;
+                }
+                if let Some(size) = self.ground {
+                    let tsm = transforms(Vec3::new(0.0, -0.6, 0.0), Vec3::new(size, 0.2, size));
+                    world.spawn((tsm,));
+                    spawned += 1;
+                }
+            }
+        }
+
+        for &(light, color) in &self.lights {
+            let position = light.position(0.0);
+            world.spawn((
+                LightComponent::new(Light::Point(PointLight::new(position, color))),
+                light,
+            ));
+            spawned += 1;
+        }
+        Ok(spawned)
+    }
+    /// The camera of the scene, going around it
+    pub fn camera(&self) -> BenchCamera {
+        // Alternating heights, so the path isn't a flat circle
+        let points = (0..8)
+            .map(|i| {
+                let angle = i as f32 / 8.0 * TAU;
+                let height = if i % 2 == 0 { 0.35 } else { 0.6 } * self.radius;
+                self.center
+                    + Vec3::new(angle.cos() * self.radius, height, angle.sin() * self.radius)
+            })
+            .collect();
+        BenchCamera {
+            path: PathComponent::new(points, Interpolation::CatmullRom, true),
+            target: self.center,
+            frames: self.params.frames + self.params.warmup,
+            position: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+        }
+    }
+}
+
+fn material(
+    gfx: &mut GraphicContext,
+    color: Vec4,
+    metallic: f32,
+    roughness: f32,
+) -> Result<Material> {
+    let albedo = gfx.texture_manager.get_or_add_single_value_texture(
+        &gfx.device,
+        &gfx.queue,
+        SingleValue::Color(color),
+    );
+    Material::new_with_values(albedo, None, metallic, roughness, None, gfx)
+}
+
+/// Scripted camera of a bench, goes around the path once over the frames of the bench
+pub struct BenchCamera {
+    path: PathComponent,
+    target: Vec3,
+    frames: u32,
+    pub position: Vec3,
+    pub rotation: Quat,
+}
+
+impl BenchCamera {
+    pub fn update(&mut self, time: &Time) {
+        let t = (time.frame() % self.frames as u64) as f32 / self.frames as f32;
+        let (position, _) = self.path.sample(t);
+        let forward = (self.target - position).normalize_or_zero();
+        self.position = position;
+        // The camera looks down +z without rotation
+        let yaw = forward.x.atan2(forward.z);
+        let pitch = -forward.y.asin();
+        self.rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, 0.0);
+    }
+    /// Move the renderer's camera (windowed path)
+    pub fn apply(&self, wr: &mut WorldRenderer) {
+        wr.camera.set_position(self.position);
+        wr.camera.set_rotation(self.rotation);
+    }
+}
+
+/// Move the renderer's camera along the path of the bench (windowed path)
+pub fn follow_camera(time: &Time, camera: &mut BenchCamera, wr: &mut WorldRenderer) {
+    camera.update(time);
+    camera.apply(wr);
+    // The lights moved
+    wr.invalidate_lights();
+}
+
+/// Move the bench lights along their circle
+pub fn animate_lights(time: &Time, lights: Entities<(&BenchLightComponent, &mut LightComponent)>) {
+    let elapsed = time.elapsed().as_secs_f32();
+    for (bench, light) in lights {
+        if let Light::Point(point) = &mut light.light {
+            point.set_position(bench.position(elapsed));
+        }
+    }
+}
+
+/// Panel of filler windows drawn over the UI (`SceneParams::windows`), added to `UiDraw`
+pub fn stress_windows(windows: u32) -> impl FnMut(&egui::Context) + Send {
+    move |ctx| {
+        for i in 0..windows {
+            let pos = egui::pos2((i % 20) as f32 * 40.0, (i / 20 % 15) as f32 * 40.0);
+            egui::Window::new(format!("stress {i}")).default_pos(pos).show(ctx, |ui| {
+                ui.label(format!("Window {i}"));
+                ui.add(egui::ProgressBar::new((i % 100) as f32 / 100.0));
+                let _ = ui.button("Button");
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic() {
+        for kind in SceneKind::ALL {
+            let params = kind.params();
+            let a = SceneDesc::generate(kind, params).unwrap();
+            let b = SceneDesc::generate(kind, params).unwrap();
+            assert_eq!(a.position_hash(), b.position_hash(), "{kind}");
+
+            let mut world = World::new();
+            let spawned = a.spawn(&mut world, None).unwrap();
+            let ground = a.ground.is_some() as usize;
+            assert_eq!((params.objects + params.lights) as usize + ground, spawned);
+            assert_eq!(spawned, world.stats().entities);
+        }
+        // Pinned, a change here means benchmarks of before aren't comparable
+        let params = SceneKind::Mixed.params();
+        let scene = SceneDesc::generate(SceneKind::Mixed, params).unwrap();
+        assert_eq!(1000, scene.objects.len());
+        assert_eq!(64, scene.lights.len());
+        let other = SceneDesc::generate(SceneKind::Mixed, SceneParams { seed: 1, ..params });
+        assert_ne!(scene.position_hash(), other.unwrap().position_hash());
+    }
+
+    #[test]
+    fn validation() {
+        for kind in SceneKind::ALL {
+            kind.params().validate().unwrap();
+            assert_eq!(Some(kind), SceneKind::from_name(kind.name()));
+        }
+        assert_eq!(None, SceneKind::from_name("nothing"));
+        let params = SceneKind::ManyObjects.params();
+        let invalid = [
+            SceneParams {
+                frames: 0,
+                ..params
+            },
+            SceneParams {
+                objects: MAX_OBJECTS + 1,
+                ..params
+            },
+            SceneParams {
+                materials: 0,
+                ..params
+            },
+            SceneParams {
+                subdivisions: MAX_SUBDIVISIONS + 1,
+                ..params
+            },
+            SceneParams {
+                lights: MAX_LIGHTS + 1,
+                ..params
+            },
+            SceneParams {
+                windows: MAX_WINDOWS + 1,
+                ..params
+            },
+        ];
+        for params in invalid {
+            assert!(params.validate().is_err(), "{params:?}");
+            assert!(SceneDesc::generate(SceneKind::ManyObjects, params).is_err());
+        }
+        assert_eq!(
+            20 * 4u64.pow(8),
+            SceneKind::HeavyGeometry.params().triangles()
+        );
+    }
+
+    #[test]
+    fn camera() {
+        let scene = SceneDesc::generate(SceneKind::ManyLights, SceneKind::ManyLights.params());
+        let mut camera = scene.unwrap().camera();
+        let mut time = Time::fixed(std::time::Duration::from_millis(16));
+        for _ in 0..100 {
+            time.update();
+            camera.update(&time);
+            // Looking at the center
+            let forward = camera.rotation * Vec3::Z;
+            let to_center = (Vec3::ZERO - camera.position).normalize();
+            assert!(forward.dot(to_center) > 0.999, "{forward} {to_center}");
+        }
+    }
+}
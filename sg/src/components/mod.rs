@@ -2,6 +2,16 @@ use glam::{Mat4, Quat, Vec3};
 
 use crate::systems::graphics::{mesh_manager::MeshHandle, Light, Material};
 
+pub use crate::systems::ambience::AudioZoneComponent;
+pub use crate::systems::character::CharacterControllerComponent;
+pub use crate::systems::collision::{StaticGeometryComponent, SurfaceMaterialComponent};
+pub use crate::systems::graphics::minimap::MinimapMarkerComponent;
+pub use crate::systems::graphics::particles::ParticleEmitterComponent;
+pub use crate::systems::graphics::sprites::{SpriteComponent, SpriteSize, WorldAnchorComponent};
+pub use crate::systems::navmesh::NavAgentComponent;
+pub use crate::systems::path::{PathComponent, PathFollowComponent};
+pub use crate::systems::weather::PrecipitationComponent;
+
 #[derive(Debug, Clone, Copy)]
 pub struct PositionComponent {
     pub x: f64,
@@ -11,8 +21,8 @@ pub struct PositionComponent {
 
 #[derive(Clone, Copy)]
 pub struct GraphicsComponent {
-    pub(crate) mesh: MeshHandle,
-    pub(crate) material: Material,
+    pub mesh: MeshHandle,
+    pub material: Material,
 }
 
 #[derive(Clone, Copy)]
@@ -26,6 +36,12 @@ impl LightComponent {
     }
 }
 
+/// Linear velocity in units per second, moved by `navmesh::apply_velocities`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VelocityComponent {
+    pub linear: Vec3,
+}
+
 #[derive(Clone)]
 pub struct TransformsComponent {
     translate: Vec3,
@@ -66,6 +82,15 @@ impl TransformsComponent {
         self.update();
         self
     }
+    pub fn translation(&self) -> Vec3 {
+        self.translate
+    }
+    pub fn scale(&self) -> Vec3 {
+        self.scale
+    }
+    pub fn rotation(&self) -> Quat {
+        self.rotate
+    }
     pub fn mat(&self) -> Mat4 {
         self.matrix
     }
@@ -0,0 +1,1073 @@
+//! In-game console.
+//!
+//! A panel toggled with `TOGGLE_KEY`, running commands from a registry any module can add to
+//! (`Console::register`). A line is split into arguments by `tokenize`: words, numbers and
+//! `"quoted strings"` (with the `\"`, `\\`, `\n` and `\t` escapes), and `#` starts a comment.
+//!
+//! Commands run between frames (`Console::run_pending`) with the world and the executor, so they
+//! can edit both freely. Resources registered with `register_inspectable` can be read and written
+//! by name (`get fog`, `set fog.density 0.1`), and components registered with
+//! `register_component` counted (`entities`). Warnings and errors of the logger are copied in the
+//! scrollback.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{self, Display},
+    fs,
+    sync::{Arc, LazyLock},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ecs::{Component, Entity, Executor, World};
+use egui::{Color32, Key, Modifiers, TextStyle};
+use log::Level;
+use parking_lot::Mutex;
+use winit::event::VirtualKeyCode;
+
+use crate::{
+    localization::Localization,
+    systems::graphics::{focus::UiFocus, screenshot, GraphicContext},
+    tr,
+};
+
+/// Key opening and closing the console
+pub const TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::Grave;
+/// Lines kept in the scrollback
+const SCROLLBACK: usize = 512;
+/// Lines kept in the history
+const HISTORY: usize = 128;
+/// Entities listed per component by `entities <filter>`
+const LISTED_ENTITIES: usize = 16;
+/// How deep `exec` can nest, to stop scripts running themselves
+const MAX_EXEC_DEPTH: u32 = 8;
+
+/// Warnings and errors logged since the console last looked
+static LOG: LazyLock<Mutex<VecDeque<Line>>> = LazyLock::new(Mutex::default);
+
+/// Copy a log record in the console, called by the logger for warnings and errors
+pub fn capture_log(level: Level, text: String) {
+    let kind = if level <= Level::Error {
+        LineKind::Error
+    } else {
+        LineKind::Warning
+    };
+    let mut log = LOG.lock();
+    if log.len() == SCROLLBACK {
+        log.pop_front();
+    }
+    log.push_back(Line { text, kind });
+}
+
+/// An argument of a command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    /// A word or a quoted string
+    Text(String),
+    /// A word that parses as a finite number
+    Number(f64),
+}
+
+impl Arg {
+    pub fn as_number(&self) -> Result<f64> {
+        match self {
+            Self::Number(n) => Ok(*n),
+            Self::Text(text) => bail!("expected a number, got `{text}`"),
+        }
+    }
+    pub fn as_f32(&self) -> Result<f32> {
+        self.as_number().map(|n| n as f32)
+    }
+    pub fn as_u32(&self) -> Result<u32> {
+        match self.as_number()? {
+            n if n >= 0.0 && n <= u32::MAX as f64 && n.fract() == 0.0 => Ok(n as u32),
+            n => bail!("expected a positive integer, got `{n}`"),
+        }
+    }
+    /// `true`/`on`/`1` or `false`/`off`/`0`
+    pub fn as_bool(&self) -> Result<bool> {
+        match self {
+            Self::Number(n) if *n == 1.0 => Ok(true),
+            Self::Number(n) if *n == 0.0 => Ok(false),
+            Self::Text(text) if text == "true" || text == "on" => Ok(true),
+            Self::Text(text) if text == "false" || text == "off" => Ok(false),
+            _ => bail!("expected true or false, got `{self}`"),
+        }
+    }
+}
+
+impl Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text(text) => write!(f, "{text}"),
+            Self::Number(n) => write!(f, "{n}"),
+        }
+    }
+}
+
+/// Split a line into arguments. Words are separated by whitespace, and are numbers if they parse
+/// as one. Quoted strings are always text, and `#` outside of one starts a comment.
+pub fn tokenize(line: &str) -> Result<Vec<Arg>> {
+    let mut args = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((i, '\\')) => match chars.next() {
+                        Some((_, '"')) => text.push('"'),
+                        Some((_, '\\')) => text.push('\\'),
+                        Some((_, 'n')) => text.push('\n'),
+                        Some((_, 't')) => text.push('\t'),
+                        Some((_, c)) => bail!("unknown escape `\\{c}` at column {}", i + 1),
+                        None => bail!("unterminated string at column {}", start + 1),
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => bail!("unterminated string at column {}", start + 1),
+                }
+            }
+            if let Some(&(i, c)) = chars.peek() {
+                if !c.is_whitespace() && c != '#' {
+                    bail!("expected a space after the string at column {}", i + 1);
+                }
+            }
+            args.push(Arg::Text(text));
+        } else {
+            let mut end = line.len();
+            while let Some(&(i, c)) = chars.peek() {
+                if c.is_whitespace() {
+                    end = i;
+                    break;
+                }
+                if c == '"' {
+                    bail!("unexpected quote at column {}", i + 1);
+                }
+                chars.next();
+            }
+            let word = &line[start..end];
+            args.push(match word.parse::<f64>() {
+                Ok(n) if n.is_finite() => Arg::Number(n),
+                _ => Arg::Text(word.to_owned()),
+            });
+        }
+    }
+    Ok(args)
+}
+
+/// The arguments given to a command (without its name)
+#[derive(Debug, Clone, Copy)]
+pub struct Args<'a>(&'a [Arg]);
+
+impl<'a> Args<'a> {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn get(&self, index: usize) -> Option<&'a Arg> {
+        self.0.get(index)
+    }
+    /// An argument that must be there
+    pub fn arg(&self, index: usize) -> Result<&'a Arg> {
+        self.get(index)
+            .ok_or_else(|| anyhow!("missing argument {}", index + 1))
+    }
+    pub fn number(&self, index: usize) -> Result<f64> {
+        self.arg(index)?
+            .as_number()
+            .with_context(|| format!("argument {}", index + 1))
+    }
+    /// An argument as text, numbers are formatted back
+    pub fn text(&self, index: usize) -> Result<String> {
+        self.arg(index).map(Arg::to_string)
+    }
+}
+
+/// A struct whose fields the console can read and write
+pub trait Inspect {
+    /// Names and values of the fields
+    fn fields(&self) -> Vec<(&'static str, String)>;
+    /// Set a field from an argument
+    fn set_field(&mut self, field: &str, value: &Arg) -> Result<()>;
+}
+
+/// Error for a field `Inspect::set_field` doesn't know
+pub fn unknown_field(inspect: &dyn Inspect, field: &str) -> anyhow::Error {
+    let fields = inspect
+        .fields()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    anyhow!("no field `{field}` (fields: {})", fields.join(", "))
+}
+
+type Handler = dyn Fn(Args, &mut ConsoleContext) -> Result<()> + Send + Sync;
+
+struct Command {
+    usage: String,
+    handler: Arc<Handler>,
+}
+
+/// Access to a resource (or part of one) implementing Inspect
+type Inspector = dyn Fn(&mut Executor, &mut dyn FnMut(&mut dyn Inspect)) -> bool + Send + Sync;
+
+/// Commands, inspectable resources and components known by the console
+#[derive(Default, Clone)]
+struct Registry {
+    commands: BTreeMap<String, Arc<Command>>,
+    inspectables: BTreeMap<String, Arc<Inspector>>,
+    components: BTreeMap<String, fn(&World) -> Vec<Entity>>,
+}
+
+impl Registry {
+    fn run(&self, line: &str, ctx: &mut ConsoleContext) -> Result<()> {
+        let args = tokenize(line)?;
+        let Some(name) = args.first() else {
+            return Ok(());
+        };
+        let command = match name {
+            Arg::Text(name) => self.commands.get(name),
+            Arg::Number(_) => None,
+        }
+        .ok_or_else(|| anyhow!("unknown command `{name}`, see `help`"))?;
+        (command.handler)(Args(&args[1..]), ctx)
+            .map_err(|error| anyhow!("{error:#} (usage: {})", command.usage))
+    }
+    /// Commands starting with prefix
+    fn complete(&self, prefix: &str) -> Vec<&str> {
+        self.commands
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// What a command has access to
+pub struct ConsoleContext<'a> {
+    pub world: &'a mut World,
+    pub executor: &'a mut Executor,
+    registry: &'a Registry,
+    output: Vec<Line>,
+    quit: bool,
+    depth: u32,
+}
+
+impl<'a> ConsoleContext<'a> {
+    fn new(world: &'a mut World, executor: &'a mut Executor, registry: &'a Registry) -> Self {
+        Self {
+            world,
+            executor,
+            registry,
+            output: Vec::new(),
+            quit: false,
+            depth: 0,
+        }
+    }
+    /// Print a line in the scrollback
+    pub fn println(&mut self, text: impl Into<String>) {
+        self.output.push(Line {
+            text: text.into(),
+            kind: LineKind::Output,
+        });
+    }
+    fn error(&mut self, text: impl Into<String>) {
+        self.output.push(Line {
+            text: text.into(),
+            kind: LineKind::Error,
+        });
+    }
+    /// Close the game once the command is done
+    pub fn quit(&mut self) {
+        self.quit = true;
+    }
+    /// Run a line as if it was typed in the console
+    pub fn run(&mut self, line: &str) -> Result<()> {
+        let registry = self.registry;
+        registry.run(line, self)
+    }
+    /// Call f with a resource registered with `Console::register_inspectable`
+    pub fn inspect<T>(&mut self, name: &str, f: impl FnOnce(&mut dyn Inspect) -> T) -> Result<T> {
+        let registry = self.registry;
+        let inspector = registry.inspectables.get(name).ok_or_else(|| {
+            let names = registry.inspectables.keys().cloned();
+            anyhow!(
+                "unknown resource `{name}` (resources: {})",
+                names.collect::<Vec<_>>().join(", ")
+            )
+        })?;
+        let mut f = Some(f);
+        let mut result = None;
+        if !inspector(self.executor, &mut |inspect| {
+            result = f.take().map(|f| f(inspect));
+        }) {
+            bail!("resource `{name}` isn't in the executor");
+        }
+        Ok(result.unwrap())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    /// A line typed by the user
+    Input,
+    Output,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Line {
+    text: String,
+    kind: LineKind,
+}
+
+/// Lines typed in the console, browsed with the arrows
+#[derive(Debug, Default)]
+pub struct History {
+    lines: VecDeque<String>,
+    /// Line shown, None when editing a new one
+    cursor: Option<usize>,
+    /// The new line, kept while browsing
+    draft: String,
+}
+
+impl History {
+    /// Add a line, unless it is empty or the same as the last one
+    pub fn push(&mut self, line: &str) {
+        self.cursor = None;
+        if line.trim().is_empty() || self.lines.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.lines.len() == HISTORY {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line.to_owned());
+    }
+    /// The line before the one shown, `current` is the line being edited
+    pub fn up(&mut self, current: &str) -> Option<&str> {
+        let cursor = match self.cursor {
+            Some(cursor) => cursor.checked_sub(1)?,
+            None => {
+                let last = self.lines.len().checked_sub(1)?;
+                self.draft = current.to_owned();
+                last
+            }
+        };
+        self.cursor = Some(cursor);
+        Some(&self.lines[cursor])
+    }
+    /// The line after the one shown, or back to the new line
+    pub fn down(&mut self) -> Option<&str> {
+        let cursor = self.cursor? + 1;
+        if cursor < self.lines.len() {
+            self.cursor = Some(cursor);
+            Some(&self.lines[cursor])
+        } else {
+            self.cursor = None;
+            Some(&self.draft)
+        }
+    }
+}
+
+/// The console: its registry, and the state of the panel
+pub struct Console {
+    pub open: bool,
+    input: String,
+    scrollback: VecDeque<Line>,
+    history: History,
+    /// Lines submitted in the panel, run by `run_pending`
+    pending: Vec<String>,
+    /// Shared with the running commands, cloned if they are registered to while running
+    registry: Arc<Registry>,
+    /// Give the focus to the input on the next frame
+    focus_input: bool,
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Console {
+    /// A closed console with the built-in commands
+    pub fn new() -> Self {
+        let mut console = Self {
+            open: false,
+            input: String::new(),
+            scrollback: VecDeque::new(),
+            history: History::default(),
+            pending: Vec::new(),
+            registry: Arc::default(),
+            focus_input: false,
+        };
+        console.register("help", "help [command]", help);
+        console.register("get", "get <resource>", get);
+        console.register("set", "set <resource.field> <value>", set);
+        console.register("entities", "entities [filter]", entities);
+        console.register("screenshot", "screenshot [path]", take_screenshot);
+        console.register("quit", "quit", |_, ctx| {
+            ctx.quit();
+            Ok(())
+        });
+        console.register("exec", "exec <file>", exec);
+        console
+    }
+    /// Add a command, replacing any other with that name
+    pub fn register(
+        &mut self,
+        name: &str,
+        usage: &str,
+        handler: impl Fn(Args, &mut ConsoleContext) -> Result<()> + Send + Sync + 'static,
+    ) {
+        let command = Command {
+            usage: usage.to_owned(),
+            handler: Arc::new(handler),
+        };
+        Arc::make_mut(&mut self.registry)
+            .commands
+            .insert(name.to_owned(), Arc::new(command));
+    }
+    /// Make part of a resource readable and writable by `get` and `set`, as `name`
+    pub fn register_inspectable<R: Send + 'static, T: Inspect + 'static>(
+        &mut self,
+        name: &str,
+        access: fn(&mut R) -> &mut T,
+    ) {
+        let inspector: Arc<Inspector> =
+            Arc::new(move |executor, f| match executor.get_resource_mut::<R>() {
+                Some(resource) => {
+                    f(access(resource));
+                    true
+                }
+                None => false,
+            });
+        Arc::make_mut(&mut self.registry)
+            .inspectables
+            .insert(name.to_owned(), inspector);
+    }
+    /// Make a component countable by `entities`, as `name`
+    pub fn register_component<T: Component>(&mut self, name: &str) {
+        fn entities<T: Component>(world: &World) -> Vec<Entity> {
+            world
+                .query::<(Entity, &T)>()
+                .map(|(entity, _)| entity)
+                .collect()
+        }
+        Arc::make_mut(&mut self.registry)
+            .components
+            .insert(name.to_owned(), entities::<T>);
+    }
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        self.focus_input = self.open;
+    }
+    fn push(&mut self, line: Line) {
+        if self.scrollback.len() == SCROLLBACK {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.push_back(line);
+    }
+    /// Echo a line and queue it for `run_pending`
+    pub fn submit(&mut self, line: &str) {
+        self.history.push(line);
+        self.push(Line {
+            text: format!("> {line}"),
+            kind: LineKind::Input,
+        });
+        self.pending.push(line.to_owned());
+    }
+    /// Run the lines submitted since the last call, returns true if one of them was `quit`. Does
+    /// nothing if the executor has no console.
+    pub fn run_pending(executor: &mut Executor, world: &mut World) -> bool {
+        let Some(console) = executor.get_resource_mut::<Console>() else {
+            return false;
+        };
+        if console.pending.is_empty() {
+            return false;
+        }
+        let lines = std::mem::take(&mut console.pending);
+        let registry = console.registry.clone();
+        let mut ctx = ConsoleContext::new(world, executor, &registry);
+        for line in lines {
+            if let Err(error) = ctx.run(&line) {
+                ctx.error(format!("{error:#}"));
+            }
+            if ctx.quit {
+                break;
+            }
+        }
+        let (output, quit) = (ctx.output, ctx.quit);
+        let console = executor.get_resource_mut::<Console>().unwrap();
+        for line in output {
+            console.push(line);
+        }
+        quit
+    }
+    /// Complete the command being typed, or list the candidates
+    fn complete(&mut self) {
+        if self.input.contains(char::is_whitespace) {
+            return;
+        }
+        let registry = self.registry.clone();
+        let candidates = registry.complete(&self.input);
+        match candidates[..] {
+            [] => {}
+            [name] => self.input = format!("{name} "),
+            [first, ..] => {
+                let common = candidates.iter().fold(first, |common, name| {
+                    let len = common
+                        .char_indices()
+                        .zip(name.chars())
+                        .find(|((_, a), b)| a != b)
+                        .map_or(common.len().min(name.len()), |((i, _), _)| i);
+                    &common[..len]
+                });
+                if common.len() > self.input.len() {
+                    self.input = common.to_owned();
+                } else {
+                    self.push(Line {
+                        text: candidates.join("  "),
+                        kind: LineKind::Output,
+                    });
+                }
+            }
+        }
+    }
+    pub(crate) fn ui(&mut self, ctx: &egui::Context, focus: &mut UiFocus, loc: &mut Localization) {
+        for line in std::mem::take(&mut *LOG.lock()) {
+            self.push(line);
+        }
+        if !self.open {
+            return;
+        }
+        focus.begin_panel("console");
+        egui::TopBottomPanel::top("console").show(ctx, |ui| {
+            egui::ScrollArea::vertical()
+                .max_height(240.0)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    ui.set_min_width(ui.available_width());
+                    for line in &self.scrollback {
+                        let color = match line.kind {
+                            LineKind::Input => ui.visuals().strong_text_color(),
+                            LineKind::Output => ui.visuals().text_color(),
+                            LineKind::Warning => Color32::YELLOW,
+                            LineKind::Error => Color32::LIGHT_RED,
+                        };
+                        ui.label(
+                            egui::RichText::new(&line.text)
+                                .text_style(TextStyle::Monospace)
+                                .color(color),
+                        );
+                    }
+                });
+            let id = egui::Id::new("console input");
+            // Tab and the arrows go to the console, not to the text edit
+            if ui.memory().has_focus(id) {
+                let consume = |key| ui.input_mut().consume_key(Modifiers::NONE, key);
+                if consume(Key::Tab) {
+                    self.complete();
+                } else if consume(Key::ArrowUp) {
+                    if let Some(line) = self.history.up(&self.input) {
+                        self.input = line.to_owned();
+                    }
+                } else if consume(Key::ArrowDown) {
+                    if let Some(line) = self.history.down() {
+                        self.input = line.to_owned();
+                    }
+                }
+            }
+            let response = focus.track(
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.input)
+                        .id(id)
+                        .font(TextStyle::Monospace)
+                        .desired_width(f32::INFINITY)
+                        .hint_text(tr!(loc, "console.hint")),
+                ),
+            );
+            if response.lost_focus() && ui.input().key_pressed(Key::Enter) {
+                let line = std::mem::take(&mut self.input);
+                self.submit(&line);
+                self.focus_input = true;
+            }
+            if std::mem::take(&mut self.focus_input) {
+                response.request_focus();
+            }
+        });
+    }
+}
+
+fn help(args: Args, ctx: &mut ConsoleContext) -> Result<()> {
+    let registry = ctx.registry;
+    match args.get(0) {
+        Some(name) => {
+            let command = registry
+                .commands
+                .get(&name.to_string())
+                .ok_or_else(|| anyhow!("unknown command `{name}`"))?;
+            ctx.println(format!("usage: {}", command.usage));
+        }
+        None => {
+            for command in registry.commands.values() {
+                ctx.println(command.usage.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn get(args: Args, ctx: &mut ConsoleContext) -> Result<()> {
+    let name = args.text(0)?;
+    for (field, value) in ctx.inspect(&name, |inspect| inspect.fields())? {
+        ctx.println(format!("{name}.{field} = {value}"));
+    }
+    Ok(())
+}
+
+fn set(args: Args, ctx: &mut ConsoleContext) -> Result<()> {
+    let path = args.text(0)?;
+    let value = args.arg(1)?;
+    let (name, field) = path
+        .split_once('.')
+        .ok_or_else(|| anyhow!("expected <resource.field>, got `{path}`"))?;
+    // Setters can clamp, print what was actually set
+    let value = ctx
+        .inspect(name, |inspect| {
+            inspect.set_field(field, value)?;
+            let mut fields = inspect.fields().into_iter();
+            Ok::<_, anyhow::Error>(
+                fields
+                    .find(|(name, _)| *name == field)
+                    .map(|(_, value)| value),
+            )
+        })?
+        .with_context(|| format!("couldn't set {path}"))?
+        .unwrap_or_else(|| value.to_string());
+    ctx.println(format!("{path} = {value}"));
+    Ok(())
+}
+
+fn entities(args: Args, ctx: &mut ConsoleContext) -> Result<()> {
+    let filter = args.get(0).map(Arg::to_string);
+    let registry = ctx.registry;
+    match &filter {
+        Some(filter) => {
+            let mut found = false;
+            for (name, entities) in &registry.components {
+                if name.contains(filter.as_str()) {
+                    found = true;
+                    let entities = entities(ctx.world);
+                    ctx.println(format!("{name}: {}", entities.len()));
+                    for entity in entities.iter().take(LISTED_ENTITIES) {
+                        ctx.println(format!("  {entity:?}"));
+                    }
+                    if entities.len() > LISTED_ENTITIES {
+                        ctx.println(format!("  ... {} more", entities.len() - LISTED_ENTITIES));
+                    }
+                }
+            }
+            if !found {
+                bail!("no component matches `{filter}`");
+            }
+        }
+        None => {
+            ctx.println(format!("{} entities", ctx.world.stats().entities));
+            for (name, entities) in &registry.components {
+                ctx.println(format!("{name}: {}", entities(ctx.world).len()));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn take_screenshot(args: Args, ctx: &mut ConsoleContext) -> Result<()> {
+    let path = match args.get(0) {
+        Some(path) => path.to_string().into(),
+        None => screenshot::default_path(),
+    };
+    let gfx = ctx
+        .executor
+        .get_resource_mut::<GraphicContext>()
+        .context("no graphic context")?;
+    gfx.request_screenshot(path.clone());
+    ctx.println(format!("Saving the next frame to {}", path.display()));
+    Ok(())
+}
+
+fn exec(args: Args, ctx: &mut ConsoleContext) -> Result<()> {
+    let path = args.text(0)?;
+    if ctx.depth >= MAX_EXEC_DEPTH {
+        bail!("too many nested exec");
+    }
+    let script = fs::read_to_string(&path).with_context(|| format!("couldn't read {path}"))?;
+    ctx.depth += 1;
+    let mut failed = 0;
+    for (i, line) in script.lines().enumerate() {
+        if let Err(error) = ctx.run(line) {
+            ctx.error(format!("{path}:{}: {error:#}", i + 1));
+            failed += 1;
+        }
+        if ctx.quit {
+            break;
+        }
+    }
+    ctx.depth -= 1;
+    if failed > 0 {
+        bail!("{failed} line(s) of {path} failed");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Arg {
+        Arg::Text(s.to_owned())
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            vec![
+                text("spawn"),
+                Arg::Number(1.0),
+                Arg::Number(-2.5),
+                text("1e")
+            ],
+            tokenize("  spawn 1\t-2.5 1e ").unwrap()
+        );
+        assert_eq!(
+            vec![text("say"), text("hello \"world\"\n"), text("12"), text("")],
+            tokenize(r#"say "hello \"world\"\n" "12" """#).unwrap()
+        );
+        assert_eq!(vec![text("a"), text("b")], tokenize("a b # c d").unwrap());
+        assert_eq!(vec![text("a#b")], tokenize("a#b").unwrap());
+        assert_eq!(vec![text("inf"), text("NaN")], tokenize("inf NaN").unwrap());
+        assert!(tokenize("   # only a comment").unwrap().is_empty());
+
+        let error = |line| tokenize(line).unwrap_err().to_string();
+        assert_eq!("unterminated string at column 5", error(r#"say "hi"#));
+        assert_eq!("unterminated string at column 5", error(r#"say "hi\"#));
+        assert_eq!("unknown escape `\\q` at column 8", error(r#"say "hi\q""#));
+        assert_eq!("unexpected quote at column 3", error(r#"ab"c""#));
+        assert_eq!(
+            "expected a space after the string at column 5",
+            error(r#""ab"c"#)
+        );
+    }
+
+    #[test]
+    fn args() {
+        let line = tokenize("1 on 2.5 -1 x").unwrap();
+        let args = Args(&line);
+        assert_eq!(1, args.arg(0).unwrap().as_u32().unwrap());
+        assert!(args.arg(1).unwrap().as_bool().unwrap());
+        assert!(args.arg(2).unwrap().as_u32().is_err());
+        assert!(args.arg(3).unwrap().as_u32().is_err());
+        assert_eq!("2.5", args.text(2).unwrap());
+        assert_eq!(
+            "argument 5: expected a number, got `x`",
+            format!("{:#}", args.number(4).unwrap_err())
+        );
+        assert_eq!("missing argument 6", args.arg(5).unwrap_err().to_string());
+    }
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Settings {
+        speed: f32,
+        enabled: bool,
+    }
+
+    impl Inspect for Settings {
+        fn fields(&self) -> Vec<(&'static str, String)> {
+            vec![
+                ("speed", self.speed.to_string()),
+                ("enabled", self.enabled.to_string()),
+            ]
+        }
+        fn set_field(&mut self, field: &str, value: &Arg) -> Result<()> {
+            match field {
+                "speed" => self.speed = value.as_f32()?,
+                "enabled" => self.enabled = value.as_bool()?,
+                _ => return Err(unknown_field(self, field)),
+            }
+            Ok(())
+        }
+    }
+
+    /// A resource holding the settings
+    #[derive(Default)]
+    struct Game {
+        settings: Settings,
+    }
+
+    struct Marker(u32);
+
+    fn setup() -> (Console, Executor, World) {
+        let mut console = Console::new();
+        console.register("add", "add <a> <b>", |args, ctx| {
+            let sum = args.number(0)? + args.number(1)?;
+            ctx.println(sum.to_string());
+            Ok(())
+        });
+        console.register_inspectable("game", |game: &mut Game| &mut game.settings);
+        console.register_component::<Marker>("marker");
+        let mut executor = Executor::new();
+        executor.add_resource(Game::default());
+        (console, executor, World::new())
+    }
+
+    /// Run lines, and give the new scrollback lines
+    fn run(executor: &mut Executor, world: &mut World, lines: &[&str]) -> Vec<(LineKind, String)> {
+        let console = executor.get_resource_mut::<Console>().unwrap();
+        let before = console.scrollback.len();
+        for line in lines {
+            console.submit(line);
+        }
+        Console::run_pending(executor, world);
+        let console = executor.get_resource::<Console>().unwrap();
+        let lines = console.scrollback.iter().skip(before);
+        lines
+            .filter(|line| line.kind != LineKind::Input)
+            .map(|line| (line.kind, line.text.clone()))
+            .collect()
+    }
+
+    fn output(s: &str) -> (LineKind, String) {
+        (LineKind::Output, s.to_owned())
+    }
+
+    fn error(s: &str) -> (LineKind, String) {
+        (LineKind::Error, s.to_owned())
+    }
+
+    #[test]
+    fn dispatch() {
+        let (console, mut executor, mut world) = setup();
+        executor.add_resource(console);
+        assert_eq!(
+            vec![output("3.5")],
+            run(&mut executor, &mut world, &["add 1 2.5", "", "# comment"])
+        );
+        assert_eq!(
+            vec![
+                error("unknown command `sub`, see `help`"),
+                error("unknown command `12`, see `help`"),
+                error("argument 2: expected a number, got `x` (usage: add <a> <b>)"),
+                error("unterminated string at column 5"),
+            ],
+            run(
+                &mut executor,
+                &mut world,
+                &["sub 1 2", "12", "add 1 x", "add \"1 2"]
+            )
+        );
+        assert_eq!(
+            vec![output("usage: add <a> <b>")],
+            run(&mut executor, &mut world, &["help add"])
+        );
+
+        for _ in 0..3 {
+            world.spawn((Marker(0),));
+        }
+        world.spawn((0u8,));
+        let lines = run(&mut executor, &mut world, &["entities", "entities mark"]);
+        assert_eq!(
+            vec![
+                output("4 entities"),
+                output("marker: 3"),
+                output("marker: 3")
+            ],
+            lines[..3]
+        );
+        assert_eq!(6, lines.len());
+
+        assert!(!Console::run_pending(&mut executor, &mut world));
+        executor
+            .get_resource_mut::<Console>()
+            .unwrap()
+            .submit("quit");
+        assert!(Console::run_pending(&mut executor, &mut world));
+    }
+
+    #[test]
+    fn inspect() {
+        let (console, mut executor, mut world) = setup();
+        executor.add_resource(console);
+        assert_eq!(
+            vec![output("game.speed = 2.5"), output("game.enabled = true")],
+            run(
+                &mut executor,
+                &mut world,
+                &["set game.speed 2.5", "set game.enabled on"]
+            )
+        );
+        let expected = Settings {
+            speed: 2.5,
+            enabled: true,
+        };
+        assert_eq!(expected, executor.get_resource::<Game>().unwrap().settings);
+        assert_eq!(
+            vec![output("game.speed = 2.5"), output("game.enabled = true")],
+            run(&mut executor, &mut world, &["get game"])
+        );
+        let usage = |s: &str| error(&format!("{s} (usage: set <resource.field> <value>)"));
+        assert_eq!(
+            vec![
+                usage("couldn't set game.speed: expected a number, got `fast`"),
+                usage("couldn't set game.size: no field `size` (fields: speed, enabled)"),
+                usage("unknown resource `player` (resources: game)"),
+                usage("expected <resource.field>, got `game`"),
+                usage("missing argument 2"),
+                error("unknown resource `player` (resources: game) (usage: get <resource>)"),
+            ],
+            run(
+                &mut executor,
+                &mut world,
+                &[
+                    "set game.speed fast",
+                    "set game.size 1",
+                    "set player.speed 1",
+                    "set game 1",
+                    "set game.speed",
+                    "get player",
+                ]
+            )
+        );
+        assert_eq!(expected, executor.get_resource::<Game>().unwrap().settings);
+
+        // Registered but not in the executor
+        let (console, mut executor, mut world) = setup();
+        executor.add_resource(console);
+        executor
+            .get_resource_mut::<Console>()
+            .unwrap()
+            .register_inspectable("other", |settings: &mut Settings| settings);
+        assert_eq!(
+            vec![error(
+                "resource `other` isn't in the executor (usage: get <resource>)"
+            )],
+            run(&mut executor, &mut world, &["get other"])
+        );
+    }
+
+    #[test]
+    fn history() {
+        let mut history = History::default();
+        assert_eq!(None, history.up("new"));
+        for line in ["a", "b", "b", " ", "c"] {
+            history.push(line);
+        }
+        assert_eq!(None, history.down());
+        assert_eq!(Some("c"), history.up("draft"));
+        assert_eq!(Some("b"), history.up("c"));
+        assert_eq!(Some("a"), history.up("b"));
+        assert_eq!(None, history.up("a"));
+        assert_eq!(Some("b"), history.down());
+        assert_eq!(Some("c"), history.down());
+        assert_eq!(Some("draft"), history.down());
+        assert_eq!(None, history.down());
+        history.push("d");
+        assert_eq!(Some("d"), history.up(""));
+
+        for i in 0..HISTORY * 2 {
+            history.push(&i.to_string());
+        }
+        assert_eq!(HISTORY, history.lines.len());
+    }
+
+    #[test]
+    fn completion() {
+        let (mut console, ..) = setup();
+        console.register("entity", "entity", |_, _| Ok(()));
+        console.input = "q".to_owned();
+        console.complete();
+        assert_eq!("quit ", console.input);
+        console.input = "ent".to_owned();
+        console.complete();
+        assert_eq!("entit", console.input);
+        console.complete();
+        assert_eq!("entit", console.input);
+        assert_eq!("entities  entity", console.scrollback.back().unwrap().text);
+        console.input = "zzz".to_owned();
+        console.complete();
+        assert_eq!("zzz", console.input);
+    }
+
+    #[test]
+    fn exec_script() {
+        let (console, mut executor, mut world) = setup();
+        executor.add_resource(console);
+        let dir = std::env::temp_dir().join(format!("sg-console-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("script.cfg");
+        let nested = dir.join("nested.cfg");
+        fs::write(
+            &script,
+            format!(
+                "# Repro\nset game.speed 4\nadd 1\n\nexec \"{}\"\nsub\nadd 2 2\n",
+                nested.display()
+            ),
+        )
+        .unwrap();
+        fs::write(&nested, "set game.enabled true\nset game.enabled maybe\n").unwrap();
+
+        let (script, nested) = (script.display(), nested.display());
+        let lines = run(
+            &mut executor,
+            &mut world,
+            &[format!("exec {script}").as_str()],
+        );
+        assert_eq!(
+            vec![
+                output("game.speed = 4"),
+                error(&format!(
+                    "{script}:3: missing argument 2 (usage: add <a> <b>)"
+                )),
+                output("game.enabled = true"),
+                error(&format!(
+                    "{nested}:2: couldn't set game.enabled: expected true or false, got `maybe` \
+                     (usage: set <resource.field> <value>)"
+                )),
+                error(&format!(
+                    "{script}:5: 1 line(s) of {nested} failed (usage: exec <file>)"
+                )),
+                error(&format!("{script}:6: unknown command `sub`, see `help`")),
+                output("4"),
+                error(&format!(
+                    "3 line(s) of {script} failed (usage: exec <file>)"
+                )),
+            ],
+            lines
+        );
+        let settings = &executor.get_resource::<Game>().unwrap().settings;
+        assert_eq!((4.0, true), (settings.speed, settings.enabled));
+
+        // Scripts running themselves stop
+        let recursive = dir.join("recursive.cfg");
+        fs::write(&recursive, format!("exec \"{}\"", recursive.display())).unwrap();
+        let line = format!("exec \"{}\"", recursive.display());
+        let lines = run(&mut executor, &mut world, &[&line]);
+        assert!(lines
+            .iter()
+            .any(|(_, text)| text.contains("too many nested exec")));
+
+        let lines = run(&mut executor, &mut world, &["exec missing.cfg"]);
+        assert_eq!(
+            vec![error("couldn't read missing.cfg: No such file or directory (os error 2) (usage: exec <file>)")],
+            lines
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
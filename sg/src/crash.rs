@@ -0,0 +1,340 @@
+//! Crash bundles.
+//!
+//! When the engine panics (or wgpu reports a fatal error), a `crash-<unix time>` directory is
+//! written in `CRASH_DIRECTORY` with whatever could be gathered: the panic message and backtrace,
+//! the last log lines, the world stats of the last frame, the text sections registered with
+//! `set_section` (GPU capabilities, ...) and the last screenshot. Everything is best effort, a
+//! missing or locked piece is skipped.
+
+use std::{
+    backtrace::Backtrace,
+    collections::{BTreeMap, VecDeque},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ecs::{World, WorldStats};
+use log::{Level, Log, Metadata, Record};
+use parking_lot::Mutex;
+
+use crate::console;
+
+/// Directory the crash bundles are written to, relative to the working directory
+pub const CRASH_DIRECTORY: &str = "crashes";
+/// Number of log lines kept for the crash bundles
+const LOG_LINES: usize = 256;
+/// Log records at or above this level are kept even if env_logger filters them out
+const BUFFER_LEVEL: Level = Level::Info;
+
+static LOG: LazyLock<LogBuffer> = LazyLock::new(|| LogBuffer::new(LOG_LINES));
+static CONTEXT: LazyLock<CrashContext> = LazyLock::new(CrashContext::default);
+static HOOK: Reentry = Reentry::new();
+
+/// Ring buffer of the last log lines
+pub struct LogBuffer {
+    lines: Mutex<VecDeque<String>>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+    pub fn push(&self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+    /// The buffered lines, oldest first. Gives nothing if the buffer is locked (we might be
+    /// panicking while holding it).
+    pub fn lines(&self) -> Vec<String> {
+        self.lines
+            .try_lock()
+            .map(|lines| lines.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// env_logger, plus a copy of the records in the crash log buffer (and of the warnings and errors
+/// in the console)
+struct Logger {
+    inner: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= BUFFER_LEVEL || self.inner.enabled(metadata)
+    }
+    fn log(&self, record: &Record) {
+        if record.level() <= Level::Warn {
+            console::capture_log(
+                record.level(),
+                format!("[{}] {}", record.target(), record.args()),
+            );
+        }
+        if record.level() <= BUFFER_LEVEL {
+            LOG.push(format!(
+                "[{} {}] {}",
+                record.level(),
+                record.target(),
+                record.args()
+            ));
+        }
+        if self.inner.matches(record) {
+            self.inner.log(record);
+        }
+    }
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Initialize the logger, replaces `env_logger::init`
+pub fn init_logger() {
+    let inner = env_logger::Builder::from_default_env().build();
+    let max = inner.filter().max(BUFFER_LEVEL.to_level_filter());
+    if log::set_boxed_logger(Box::new(Logger { inner })).is_ok() {
+        log::set_max_level(max);
+    }
+}
+
+/// State the panic hook can read, updated by the engine as it runs
+#[derive(Default)]
+struct CrashContext {
+    sections: Mutex<BTreeMap<&'static str, String>>,
+    world: Mutex<Option<WorldStats>>,
+    screenshot: Mutex<Option<PathBuf>>,
+}
+
+/// Set a text section of the crash bundles (written as `<name>.txt`)
+pub fn set_section(name: &'static str, contents: String) {
+    CONTEXT.sections.lock().insert(name, contents);
+}
+
+/// Remember the stats of the world, called once per frame
+pub fn snapshot_world(world: &World) {
+    *CONTEXT.world.lock() = Some(world.stats());
+}
+
+/// Set the last screenshot taken, copied in the crash bundles
+pub fn set_screenshot(path: PathBuf) {
+    *CONTEXT.screenshot.lock() = Some(path);
+}
+
+/// Everything that goes in a crash bundle
+#[derive(Default)]
+pub struct CrashReport {
+    pub message: String,
+    pub backtrace: Option<String>,
+    pub log: Vec<String>,
+    pub world: Option<WorldStats>,
+    pub sections: BTreeMap<&'static str, String>,
+    pub screenshot: Option<PathBuf>,
+}
+
+impl CrashReport {
+    /// Gather a report from the crash context, skipping anything that is currently locked
+    pub fn collect(message: String, backtrace: Option<String>) -> Self {
+        Self {
+            message,
+            backtrace,
+            log: LOG.lines(),
+            world: CONTEXT.world.try_lock().and_then(|world| *world),
+            sections: CONTEXT
+                .sections
+                .try_lock()
+                .map(|sections| sections.clone())
+                .unwrap_or_default(),
+            screenshot: CONTEXT
+                .screenshot
+                .try_lock()
+                .and_then(|path| path.clone()),
+        }
+    }
+    /// Write the bundle in a new directory of root and return its path. Only failing to create
+    /// the directory is an error, pieces that can't be written are skipped.
+    pub fn write(&self, root: &Path) -> io::Result<PathBuf> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        fs::create_dir_all(root)?;
+        let mut dir = root.join(format!("crash-{time}"));
+        let mut n = 1;
+        // Don't overwrite a bundle written the same second
+        while dir.exists() {
+            dir = root.join(format!("crash-{time}-{n}"));
+            n += 1;
+        }
+        fs::create_dir(&dir)?;
+
+        let mut panic = self.message.clone();
+        if let Some(backtrace) = &self.backtrace {
+            panic.push_str("\n\n");
+            panic.push_str(backtrace);
+        }
+        let _ = fs::write(dir.join("panic.txt"), panic);
+        if !self.log.is_empty() {
+            let _ = fs::write(dir.join("log.txt"), self.log.join("\n"));
+        }
+        if let Some(world) = self.world {
+            let _ = fs::write(
+                dir.join("world.txt"),
+                format!(
+                    "entities: {}\narchetypes: {}\nempty archetypes: {}\n",
+                    world.entities, world.archetypes, world.empty_archetypes
+                ),
+            );
+        }
+        for (name, contents) in &self.sections {
+            let _ = fs::write(dir.join(format!("{name}.txt")), contents);
+        }
+        if let Some(screenshot) = &self.screenshot {
+            if let Some(name) = screenshot.file_name() {
+                let _ = fs::copy(screenshot, dir.join(name));
+            }
+        }
+        Ok(dir)
+    }
+}
+
+/// Flag guarding against a crash handler being entered while it is already running (a panic
+/// while writing a bundle, or several threads panicking at once).
+#[derive(Default)]
+pub struct Reentry(AtomicBool);
+
+pub struct ReentryGuard<'a>(&'a AtomicBool);
+
+impl Reentry {
+    pub const fn new() -> Self {
+        Self(AtomicBool::new(false))
+    }
+    /// Get a guard, or None if one is already alive
+    pub fn enter(&self) -> Option<ReentryGuard<'_>> {
+        match self.0.swap(true, Ordering::AcqRel) {
+            false => Some(ReentryGuard(&self.0)),
+            true => None,
+        }
+    }
+}
+
+impl Drop for ReentryGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Write a crash bundle, unless one is already being written
+fn bundle(message: String) {
+    let _guard = match HOOK.enter() {
+        Some(guard) => guard,
+        None => return,
+    };
+    let backtrace = Backtrace::force_capture().to_string();
+    let report = CrashReport::collect(message, Some(backtrace));
+    // eprintln panics if stderr is gone
+    let mut stderr = io::stderr();
+    let _ = match report.write(Path::new(CRASH_DIRECTORY)) {
+        Ok(dir) => writeln!(stderr, "Crash report written to {}", dir.display()),
+        Err(e) => writeln!(stderr, "Couldn't write crash report: {e}"),
+    };
+}
+
+/// Install the panic hook writing crash bundles, the previous hook still runs first
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        bundle(info.to_string());
+    }));
+}
+
+/// Write a crash bundle and abort, for errors we can't unwind from (lost device, out of memory)
+pub fn fatal(message: String) -> ! {
+    let _ = writeln!(io::stderr(), "Fatal error: {message}");
+    bundle(message);
+    std::process::abort();
+}
+
+/// Path of the most recent crash bundle in root
+pub fn last_crash(root: &Path) -> Option<PathBuf> {
+    fs::read_dir(root)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("crash-"))
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .max()
+        .map(|(_, path)| path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("sg-crash-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn log_buffer() {
+        let buffer = LogBuffer::new(3);
+        assert!(buffer.lines().is_empty());
+        for i in 0..5 {
+            buffer.push(i.to_string());
+        }
+        assert_eq!(vec!["2", "3", "4"], buffer.lines());
+        // A locked buffer gives nothing instead of deadlocking
+        let _lock = buffer.lines.lock();
+        assert!(buffer.lines().is_empty());
+        LogBuffer::new(0).push("a".to_owned());
+    }
+
+    #[test]
+    fn partial_bundle() {
+        let root = temp_dir();
+        let report = CrashReport {
+            message: "boom".to_owned(),
+            log: vec!["[INFO sg] a".to_owned(), "[WARN sg] b".to_owned()],
+            sections: BTreeMap::from([("capabilities", "gpu".to_owned())]),
+            screenshot: Some(root.join("missing.png")),
+            ..Default::default()
+        };
+        let first = report.write(&root).unwrap();
+        let second = report.write(&root).unwrap();
+        assert_ne!(first, second);
+
+        let read = |name: &str| fs::read_to_string(first.join(name)).ok();
+        assert_eq!(Some("boom".to_owned()), read("panic.txt"));
+        assert_eq!(Some("[INFO sg] a\n[WARN sg] b".to_owned()), read("log.txt"));
+        assert_eq!(Some("gpu".to_owned()), read("capabilities.txt"));
+        assert_eq!(None, read("world.txt"));
+        assert_eq!(None, read("missing.png"));
+        assert!(last_crash(&root).is_some());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn reentry() {
+        let reentry = Reentry::new();
+        let guard = reentry.enter();
+        assert!(guard.is_some());
+        assert!(reentry.enter().is_none());
+        drop(guard);
+        assert!(reentry.enter().is_some());
+    }
+}
@@ -0,0 +1,589 @@
+//! The assets a scene is made of: `DependencyCollector` follows the references of the files the
+//! loaders read (gltf buffers and images, OBJ material libraries and textures) without touching
+//! the GPU, and merges them with the relations of the `ResourceManager`. `sg deps` prints the
+//! result for build tooling.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use rmanage::{Resource, ResourceGraph, ResourceKind, ResourceManager, ResourceNode};
+
+use crate::systems::graphics::mesh_import::{self, Format, ImportReport};
+
+/// The assets that went into an entity of the scene
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityDeps {
+    pub name: String,
+    pub assets: Vec<Resource>,
+}
+
+/// Records which assets are referenced by which, and which went into each entity
+#[derive(Default)]
+pub struct DependencyCollector {
+    graph: ResourceGraph,
+    entities: Vec<EntityDeps>,
+    /// Files referenced but not found, with the reason
+    missing: Vec<(PathBuf, String)>,
+}
+
+fn node(resources: &ResourceManager, resource: Resource) -> ResourceNode {
+    let kind = if resources.contains_virtual(resource) {
+        ResourceKind::Virtual
+    } else if resources.contains_mapped(resource) {
+        ResourceKind::Mapped
+    } else {
+        ResourceKind::Physical
+    };
+    ResourceNode {
+        resource,
+        path: resources.path(resource),
+        kind,
+    }
+}
+
+impl DependencyCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn entities(&self) -> &[EntityDeps] {
+        &self.entities
+    }
+    pub fn missing(&self) -> &[(PathBuf, String)] {
+        &self.missing
+    }
+    /// Record that `from` references `to`
+    pub fn record(
+        &mut self,
+        resources: &ResourceManager,
+        from: Resource,
+        relation: &str,
+        to: Resource,
+    ) {
+        self.graph.add_node(node(resources, from));
+        self.graph.add_node(node(resources, to));
+        self.graph.add_edge(from, relation, to);
+    }
+    /// Record the assets an entity was made from
+    pub fn record_entity(
+        &mut self,
+        name: impl Into<String>,
+        assets: impl IntoIterator<Item = Resource>,
+    ) {
+        let assets = assets.into_iter().collect::<BTreeSet<_>>();
+        self.entities.push(EntityDeps {
+            name: name.into(),
+            assets: assets.into_iter().collect(),
+        });
+    }
+    /// Record a reference to a file, which is remembered as missing if it can't be added
+    fn reference(
+        &mut self,
+        resources: &ResourceManager,
+        from: Resource,
+        relation: &str,
+        path: &Path,
+    ) -> Option<Resource> {
+        match resources.add_physical(path) {
+            Ok(to) => {
+                self.record(resources, from, relation, to);
+                Some(to)
+            }
+            Err(e) => {
+                self.missing.push((path.to_owned(), e.to_string()));
+                None
+            }
+        }
+    }
+    /// Add a mesh file and follow its references, the way the loaders of `mesh_import` would
+    pub fn scan(
+        &mut self,
+        resources: &ResourceManager,
+        path: impl AsRef<Path>,
+    ) -> Result<Resource> {
+        let path = path.as_ref();
+        let scene = resources
+            .add_physical(path)
+            .with_context(|| format!("Couldn't add '{}'", path.display()))?;
+        self.graph.add_node(node(resources, scene));
+        let bytes = resources.get_resource(scene)?;
+        let dir = resources
+            .path(scene)
+            .and_then(|path| path.parent().map(Path::to_owned))
+            .unwrap_or_default();
+        match Format::detect(Some(path), &bytes).context("Unknown mesh format")? {
+            Format::Gltf => self.scan_gltf(resources, scene, &bytes, &dir)?,
+            Format::Obj => self.scan_obj(resources, scene, &bytes, &dir)?,
+            Format::Ply => self.record_entity(path.display().to_string(), [scene]),
+        }
+        Ok(scene)
+    }
+    fn scan_gltf(
+        &mut self,
+        resources: &ResourceManager,
+        scene: Resource,
+        bytes: &[u8],
+        dir: &Path,
+    ) -> Result<()> {
+        let gltf = gltf::Gltf::from_slice(bytes)?;
+        // Embedded data has no file, it becomes virtual resources
+        let mut buffers = Vec::new();
+        for buffer in gltf.buffers() {
+            let res = match buffer.source() {
+                gltf::buffer::Source::Bin => {
+                    let blob = gltf.blob.as_deref().context("glb without a binary chunk")?;
+                    let res = resources.add_virtual(blob);
+                    self.record(resources, scene, "buffer", res);
+                    Some(res)
+                }
+                gltf::buffer::Source::Uri(uri) if uri.starts_with("data:") => {
+                    let res = resources.add_virtual(uri.as_bytes());
+                    self.record(resources, scene, "buffer", res);
+                    Some(res)
+                }
+                gltf::buffer::Source::Uri(uri) => {
+                    self.reference(resources, scene, "buffer", &dir.join(uri))
+                }
+            };
+            buffers.push(res);
+        }
+        let mut images = Vec::new();
+        for image in gltf.images() {
+            let res = match image.source() {
+                gltf::image::Source::View { view, .. } => {
+                    let buffer = buffers[view.buffer().index()];
+                    if let Some(buffer) = buffer {
+                        self.record(resources, scene, "image", buffer);
+                    }
+                    buffer
+                }
+                gltf::image::Source::Uri { uri, .. } if uri.starts_with("data:") => {
+                    let res = resources.add_virtual(uri.as_bytes());
+                    self.record(resources, scene, "image", res);
+                    Some(res)
+                }
+                gltf::image::Source::Uri { uri, .. } => {
+                    self.reference(resources, scene, "image", &dir.join(uri))
+                }
+            };
+            images.push(res);
+        }
+
+        for node in gltf.nodes() {
+            let Some(mesh) = node.mesh() else {
+                continue;
+            };
+            let mut assets = vec![scene];
+            for primitive in mesh.primitives() {
+                let accessors = primitive
+                    .attributes()
+                    .map(|(_, accessor)| accessor)
+                    .chain(primitive.indices());
+                for view in accessors.filter_map(|accessor| accessor.view()) {
+                    assets.extend(buffers[view.buffer().index()]);
+                }
+                let material = primitive.material();
+                let pbr = material.pbr_metallic_roughness();
+                let textures = [
+                    pbr.base_color_texture().map(|info| info.texture()),
+                    pbr.metallic_roughness_texture().map(|info| info.texture()),
+                    material.normal_texture().map(|info| info.texture()),
+                    material.occlusion_texture().map(|info| info.texture()),
+                    material.emissive_texture().map(|info| info.texture()),
+                ];
+                for texture in textures.into_iter().flatten() {
+                    assets.extend(images[texture.source().index()]);
+                }
+            }
+            let name = node
+                .name()
+                .map_or_else(|| format!("node {}", node.index()), str::to_owned);
+            self.record_entity(name, assets);
+        }
+        Ok(())
+    }
+    fn scan_obj(
+        &mut self,
+        resources: &ResourceManager,
+        scene: Resource,
+        bytes: &[u8],
+        dir: &Path,
+    ) -> Result<()> {
+        let source = std::str::from_utf8(bytes).context("OBJ file isn't UTF-8")?;
+        let mut report = ImportReport::default();
+        let (meshes, libraries) = mesh_import::parse_obj(source, &mut report)?;
+        // Material name -> the library and textures it comes from
+        let mut materials = Vec::new();
+        for library in libraries {
+            let path = dir.join(library);
+            let Some(res) = self.reference(resources, scene, "material library", &path) else {
+                continue;
+            };
+            let source = resources.get_resource(res)?;
+            let source = std::str::from_utf8(&source).context("MTL file isn't UTF-8")?;
+            let dir = path.parent().unwrap_or(Path::new(""));
+            for material in mesh_import::parse_mtl(source, &mut report)
+                .with_context(|| format!("In '{}'", path.display()))?
+            {
+                let mut assets = vec![res];
+                let maps = [
+                    ("albedo map", &material.albedo_map),
+                    ("normal map", &material.normal_map),
+                ];
+                for (relation, map) in maps {
+                    if let Some(map) = map {
+                        assets.extend(self.reference(resources, res, relation, &dir.join(map)));
+                    }
+                }
+                materials.push((material.name, assets));
+            }
+        }
+        for mesh in meshes {
+            let mut assets = vec![scene];
+            if let Some((_, material)) = materials
+                .iter()
+                .find(|(name, _)| Some(name) == mesh.material.as_ref())
+            {
+                assets.extend(material);
+            }
+            self.record_entity(mesh.name, assets);
+        }
+        Ok(())
+    }
+    /// The relations of the `ResourceManager` together with what was recorded
+    pub fn graph(&self, resources: &ResourceManager) -> ResourceGraph {
+        let mut graph = resources.dependency_graph();
+        graph.merge(self.graph.clone());
+        graph
+    }
+}
+
+/// Physical resources outside of all the roots, the game can't be shipped with those
+pub fn external<'a>(graph: &'a ResourceGraph, roots: &[PathBuf]) -> Vec<&'a ResourceNode> {
+    graph
+        .nodes()
+        .iter()
+        .filter(|node| {
+            node.path
+                .as_ref()
+                .is_some_and(|path| !roots.iter().any(|root| path.starts_with(root)))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepsFormat {
+    Dot,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepsArgs {
+    pub scene: PathBuf,
+    pub format: DepsFormat,
+    /// Print what depends on this asset instead of what the scene depends on
+    pub reverse: Option<PathBuf>,
+}
+
+impl DepsArgs {
+    pub const USAGE: &'static str =
+        "usage: sg deps --scene path [--format dot|json] [--reverse asset_path]";
+
+    /// Parse the arguments following `deps`
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut scene = None;
+        let mut format = DepsFormat::Dot;
+        let mut reverse = None;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("{arg} expects a value"))
+            };
+            match arg.as_str() {
+                "--scene" => scene = Some(PathBuf::from(value()?)),
+                "--reverse" => reverse = Some(PathBuf::from(value()?)),
+                "--format" => {
+                    format = match value()?.as_str() {
+                        "dot" => DepsFormat::Dot,
+                        "json" => DepsFormat::Json,
+                        other => bail!("unknown format '{other}', expected dot or json"),
+                    }
+                }
+                _ => bail!("unexpected argument '{arg}'\n{}", Self::USAGE),
+            }
+        }
+        Ok(Self {
+            scene: scene.with_context(|| format!("missing --scene\n{}", Self::USAGE))?,
+            format,
+            reverse,
+        })
+    }
+}
+
+/// The part of the graph to print: the scene and its dependencies, or the asset and what
+/// depends on it
+fn select(
+    resources: &ResourceManager,
+    graph: &ResourceGraph,
+    scene: Resource,
+    reverse: Option<&Path>,
+) -> Result<ResourceGraph> {
+    let scene_graph = graph.subgraph(&[scene], false);
+    let Some(asset) = reverse else {
+        return Ok(scene_graph);
+    };
+    let asset = resources
+        .add_physical(asset)
+        .ok()
+        .filter(|&asset| scene_graph.node(asset).is_some())
+        .with_context(|| format!("'{}' isn't a dependency of the scene", asset.display()))?;
+    Ok(scene_graph.subgraph(&[asset], true))
+}
+
+/// Run `sg deps`, returns the exit code
+pub fn main(args: &DepsArgs) -> i32 {
+    let result = (|| -> Result<()> {
+        rmanage::init_default()?;
+        let resources = rmanage::instance();
+        // Relative paths are from the resource directory for the manager, but from the working
+        // directory on the command line
+        let scene = std::path::absolute(&args.scene)?;
+        let reverse = args.reverse.as_ref().map(std::path::absolute).transpose()?;
+        let mut collector = DependencyCollector::new();
+        let scene = collector.scan(resources, scene)?;
+        let graph = select(
+            resources,
+            &collector.graph(resources),
+            scene,
+            reverse.as_deref(),
+        )?;
+
+        for (path, reason) in collector.missing() {
+            eprintln!("warning: missing reference '{}': {reason}", path.display());
+        }
+        let dir = resources
+            .path(scene)
+            .and_then(|path| path.parent().map(Path::to_owned))
+            .unwrap_or_default();
+        for node in external(&graph, &[resources.directory().to_owned(), dir.clone()]) {
+            eprintln!("warning: external reference '{}'", node.label());
+        }
+
+        let graph = graph.relative_to(dir);
+        match args.format {
+            DepsFormat::Dot => print!("{}", graph.to_dot()),
+            DepsFormat::Json => println!("{}", graph.to_json()),
+        }
+        Ok(())
+    })();
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{e:#}");
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmanage::ResourceManagerBuilder;
+
+    struct Dir(PathBuf);
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A resource manager with these files in its resource directory
+    fn setup(files: &[(&str, &str)]) -> (ResourceManager, Dir) {
+        let dir = std::env::temp_dir().join(format!("sg-deps-{}", uuid::Uuid::new_v4()));
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let resources = ResourceManagerBuilder::begin()
+            .with_resource_path(&dir)
+            .build();
+        (resources, Dir(dir))
+    }
+
+    // Two images sharing a buffer with the meshes, and an embedded one
+    const GLTF: &str = r#"{
+        "asset": { "version": "2.0" },
+        "buffers": [
+            { "uri": "mesh.bin", "byteLength": 12 },
+            { "uri": "data:application/octet-stream;base64,AAAAAA==", "byteLength": 4 }
+        ],
+        "bufferViews": [
+            { "buffer": 0, "byteLength": 12 },
+            { "buffer": 1, "byteLength": 4 }
+        ],
+        "accessors": [
+            {
+                "bufferView": 0, "componentType": 5126, "count": 1, "type": "VEC3",
+                "min": [0, 0, 0], "max": [0, 0, 0]
+            }
+        ],
+        "images": [
+            { "uri": "textures/albedo.png" },
+            { "uri": "missing.png" },
+            { "bufferView": 1, "mimeType": "image/png" }
+        ],
+        "textures": [{ "source": 0 }, { "source": 1 }, { "source": 2 }],
+        "materials": [
+            { "pbrMetallicRoughness": { "baseColorTexture": { "index": 0 } } },
+            { "normalTexture": { "index": 2 } }
+        ],
+        "meshes": [
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "material": 0 }] },
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "material": 1 }] }
+        ],
+        "nodes": [{ "name": "crate", "mesh": 0 }, { "mesh": 1 }, { "name": "empty" }]
+    }"#;
+
+    #[test]
+    fn gltf() {
+        let (resources, dir) = setup(&[
+            ("scene.gltf", GLTF),
+            ("mesh.bin", "000000000000"),
+            ("textures/albedo.png", "png"),
+        ]);
+        let mut collector = DependencyCollector::new();
+        let scene = collector
+            .scan(&resources, dir.0.join("scene.gltf"))
+            .unwrap();
+        let bin = resources.add_physical("mesh.bin").unwrap();
+        let albedo = resources.add_physical("textures/albedo.png").unwrap();
+
+        assert_eq!(1, collector.missing().len());
+        assert!(collector.missing()[0].0.ends_with("missing.png"));
+
+        let graph = collector.graph(&resources);
+        let dependencies = graph.dependencies(scene);
+        assert_eq!(3, dependencies.len());
+        let embedded = dependencies[1];
+        assert!(graph.node(embedded).unwrap().is_virtual());
+        assert_eq!(vec![bin, embedded, albedo], dependencies);
+        assert_eq!(vec![scene], graph.dependents(albedo));
+
+        let entities = collector.entities();
+        assert_eq!(2, entities.len());
+        let mut crate_assets = vec![scene, bin, albedo];
+        crate_assets.sort();
+        assert_eq!(
+            EntityDeps {
+                name: "crate".into(),
+                assets: crate_assets
+            },
+            entities[0]
+        );
+        assert_eq!("node 1", entities[1].name);
+        assert!(entities[1].assets.contains(&embedded));
+        assert!(!entities[1].assets.contains(&albedo));
+
+        let dot = graph
+            .subgraph(&[scene], false)
+            .relative_to(resources.directory())
+            .to_dot();
+        assert_eq!(
+            "digraph resources {
+    r0 [label=\"mesh.bin\"];
+    r1 [label=\"scene.gltf\"];
+    r2 [label=\"textures/albedo.png\"];
+    r3 [label=\"<virtual>\", style=dashed];
+    r1 -> r0 [label=\"buffer\"];
+    r1 -> r3 [label=\"buffer\"];
+    r1 -> r2 [label=\"image\"];
+    r1 -> r3 [label=\"image\"];
+}
+",
+            dot
+        );
+    }
+
+    #[test]
+    fn obj_materials() {
+        let obj = "mtllib a.mtl\nmtllib b.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\n\
+o left\nusemtl wood\nf 1 2 3\no right\nusemtl metal\nf 1 2 3\n";
+        let (resources, dir) = setup(&[
+            ("model.obj", obj),
+            ("a.mtl", "newmtl wood\nmap_Kd shared.png\nbump wood_n.png\n"),
+            ("b.mtl", "newmtl metal\nmap_Kd shared.png\n"),
+            ("shared.png", "png"),
+            ("wood_n.png", "png"),
+        ]);
+        let mut collector = DependencyCollector::new();
+        let scene = collector.scan(&resources, dir.0.join("model.obj")).unwrap();
+        let [a, b, shared, normal] = ["a.mtl", "b.mtl", "shared.png", "wood_n.png"]
+            .map(|p| resources.add_physical(p).unwrap());
+
+        // Diamond: both libraries use the same texture
+        let graph = collector.graph(&resources);
+        assert_eq!(vec![a, b, shared, normal], graph.dependencies(scene));
+        assert_eq!(vec![a, b, scene], graph.dependents(shared));
+        assert_eq!(vec![a, scene], graph.dependents(normal));
+
+        let entities = collector.entities();
+        assert_eq!(["left", "right"], [&*entities[0].name, &*entities[1].name]);
+        assert!(entities[0].assets.contains(&normal));
+        assert!(!entities[1].assets.contains(&normal));
+        assert!(entities[1].assets.contains(&shared));
+
+        let reverse = select(&resources, &graph, scene, Some(&dir.0.join("wood_n.png"))).unwrap();
+        assert_eq!(3, reverse.nodes().len());
+        assert!(select(&resources, &graph, scene, Some(&dir.0.join("model.obj"))).is_ok());
+        assert!(select(&resources, &graph, normal, Some(&dir.0.join("b.mtl"))).is_err());
+    }
+
+    #[test]
+    fn merges_relations() {
+        let (resources, dir) = setup(&[("model.ply", "ply"), ("outside.png", "png")]);
+        let mut collector = DependencyCollector::new();
+        let scene = collector.scan(&resources, dir.0.join("model.ply")).unwrap();
+        // Derived by another system, only known to the resource manager
+        let baked = resources.add_virtual(&[0]);
+        resources.set_relation("baked", scene, baked).unwrap();
+        let outside = resources.add_physical("outside.png").unwrap();
+        collector.record(&resources, baked, "source", outside);
+
+        let graph = collector.graph(&resources);
+        assert_eq!(vec![baked, outside], graph.dependencies(scene));
+        let outside_root = external(&graph, &[resources.directory().join("nested")]);
+        assert_eq!(2, outside_root.len());
+        assert!(outside_root.iter().all(|node| !node.is_virtual()));
+        assert!(external(&graph, &[resources.directory().to_owned()]).is_empty());
+    }
+
+    #[test]
+    fn args() {
+        let parse = |args: &str| {
+            DepsArgs::parse(
+                &args
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(
+            DepsArgs {
+                scene: "a.gltf".into(),
+                format: DepsFormat::Json,
+                reverse: Some("b.png".into()),
+            },
+            parse("--scene a.gltf --format json --reverse b.png").unwrap()
+        );
+        assert_eq!(DepsFormat::Dot, parse("--scene a.gltf").unwrap().format);
+        assert!(parse("--format dot").is_err());
+        assert!(parse("--scene a.gltf --format svg").is_err());
+        assert!(parse("--scene").is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! Determinism test. The input replay and the networking need the simulation to end up in the same
+//! state from the same inputs. This runs a scripted scenario twice, with the parallel executor
+//! and sequentially, and compares the state of the simulation after every tick.
+//!
+//! The state is the components of the registry, hashed in entity order. Systems in the schedule
+//! must only get their randomness from GameRng, and their time from the fixed step Time.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    fmt::Display,
+    hash::{Hash, Hasher},
+    time::Duration,
+};
+
+use ecs::{ComponentRegistry, Entities, EntityDiff, Executor, Scheduler, World};
+use glam::Vec3;
+use rand::Rng;
+
+use crate::{
+    components::{TransformsComponent, VelocityComponent},
+    systems::{
+        navmesh::{self, NavAgentComponent, NavMesh, NavSettings},
+        path::{self, Interpolation, PathComponent, PathEvents, PathFollowComponent},
+        rng::GameRng,
+        time::Time,
+    },
+};
+
+/// Size of the floor of the scenario, agents walk on [0, FLOOR] on x and z
+const FLOOR: f32 = 16.0;
+const AGENTS: usize = 8;
+const FOLLOWERS: usize = 4;
+/// The agents get a new target every RETARGET_TICKS
+const RETARGET_TICKS: u64 = 60;
+
+/// A component of an entity that differs between the two runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difference {
+    /// `Entity::to_bits`
+    pub entity: u64,
+    /// Name of the component in the registry, None if the entity only exists in one of the runs
+    pub component: Option<String>,
+}
+
+/// The first tick after which the runs weren't in the same state
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub tick: u64,
+    pub differences: Vec<Difference>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "simulation diverged at tick {}:", self.tick)?;
+        for diff in &self.differences {
+            match &diff.component {
+                Some(component) => write!(f, "\n  entity {:#x}: {component}", diff.entity)?,
+                None => write!(f, "\n  entity {:#x}: only in one run", diff.entity)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+pub struct DeterminismTest {
+    pub seed: u64,
+    pub ticks: u64,
+    /// Duration of a tick
+    pub step: Duration,
+}
+
+impl Default for DeterminismTest {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            ticks: 600,
+            step: Duration::from_secs(1) / 60,
+        }
+    }
+}
+
+impl DeterminismTest {
+    /// Run the scenario, returns the hash of the final state
+    pub fn run(&self) -> Result<u64, Divergence> {
+        self.run_with(|schedule| schedule)
+    }
+    /// Run the scenario with more systems after the simulation ones
+    pub fn run_with(&self, extra: impl Fn(Scheduler) -> Scheduler) -> Result<u64, Divergence> {
+        let registry = registry();
+        let (mut parallel, mut parallel_world) = self.scenario();
+        let (mut sequential, mut sequential_world) = self.scenario();
+        let parallel_schedule = simulation(&mut parallel).with(&extra).build();
+        let sequential_schedule = simulation(&mut sequential).with(&extra).build();
+
+        let mut hash = 0;
+        for tick in 1..=self.ticks {
+            parallel.execute(&parallel_schedule, &mut parallel_world);
+            sequential.execute_sequential(&sequential_schedule, &mut sequential_world);
+            let expected = parallel_world.diff_since(0, &registry).spawned;
+            let state = sequential_world.diff_since(0, &registry).spawned;
+            hash = hash_state(&state);
+            if hash_state(&expected) != hash {
+                return Err(Divergence {
+                    tick,
+                    differences: differences(&expected, &state),
+                });
+            }
+        }
+        Ok(hash)
+    }
+    fn scenario(&self) -> (Executor, World) {
+        let mut world = World::new();
+        let mut executor = Executor::new();
+
+        let points = vec![
+            Vec3::new(2.0, 0.0, 2.0),
+            Vec3::new(14.0, 0.0, 2.0),
+            Vec3::new(14.0, 0.0, 14.0),
+            Vec3::new(2.0, 0.0, 14.0),
+        ];
+        let path = world.spawn((PathComponent::new(points, Interpolation::CatmullRom, true),));
+        for i in 0..FOLLOWERS {
+            let mut follow = PathFollowComponent::new(path, 1.0 + i as f32 * 0.7);
+            follow.orient_to_tangent = true;
+            world.spawn((follow, TransformsComponent::new()));
+        }
+        for i in 0..AGENTS {
+            let mut transforms = TransformsComponent::new();
+            transforms.set_translation(Vec3::new(i as f32 * 2.0 + 0.5, 0.0, 0.5));
+            world.spawn((
+                NavAgentComponent::new(1.5 + i as f32 * 0.25),
+                transforms,
+                VelocityComponent::default(),
+            ));
+        }
+
+        executor.add_resource(Time::fixed(self.step));
+        executor.add_resource(GameRng::new(self.seed));
+        executor.add_resource(PathEvents::new());
+        executor.add_resource(floor());
+        (executor, world)
+    }
+}
+
+/// The systems of the simulation, in the order of the game's schedule
+fn simulation(executor: &mut Executor) -> Scheduler<'_> {
+    executor
+        .schedule()
+        .then(Time::update)
+        .then(script)
+        .then(path::follow_paths)
+        .then(navmesh::steer_agents)
+        .then(navmesh::apply_velocities)
+}
+
+/// The scripted inputs, the agents get a new random target on the floor every RETARGET_TICKS
+fn script(time: &Time, rng: &mut GameRng, agents: Entities<&mut NavAgentComponent>) {
+    if time.frame() % RETARGET_TICKS != 1 {
+        return;
+    }
+    for agent in agents {
+        let x = rng.gen_range(0.0..FLOOR);
+        let z = rng.gen_range(0.0..FLOOR);
+        agent.target = Some(Vec3::new(x, 0.0, z));
+    }
+}
+
+fn floor() -> NavMesh {
+    let (a, b) = (Vec3::ZERO, Vec3::new(FLOOR, 0.0, 0.0));
+    let (c, d) = (Vec3::new(FLOOR, 0.0, FLOOR), Vec3::new(0.0, 0.0, FLOOR));
+    let settings = NavSettings {
+        cell_size: 0.5,
+        agent_radius: 0.0,
+        ..Default::default()
+    };
+    NavMesh::build(settings, &[[a, b, c], [a, c, d]])
+}
+
+fn floats(values: impl IntoIterator<Item = f32>) -> Vec<u8> {
+    values
+        .into_iter()
+        .flat_map(|v| v.to_bits().to_le_bytes())
+        .collect()
+}
+
+/// The hashed state of the simulation. The components are only hashed, never applied, so they
+/// can't be deserialized.
+pub fn registry() -> ComponentRegistry {
+    let mut registry = ComponentRegistry::new();
+    registry
+        .register::<TransformsComponent>(
+            "transforms",
+            |t| floats(t.mat().to_cols_array()),
+            |_| None,
+        )
+        .register::<VelocityComponent>("velocity", |v| floats(v.linear.to_array()), |_| None)
+        .register::<PathFollowComponent>(
+            "path_follow",
+            |f| {
+                let mut bytes = f.path.to_bits().to_le_bytes().to_vec();
+                bytes.extend(floats([f.t, f.speed]));
+                bytes.push(f.playing as u8);
+                bytes
+            },
+            |_| None,
+        )
+        .register::<NavAgentComponent>(
+            "nav_agent",
+            |agent| {
+                let target = agent.target.map_or([f32::NAN; 3], |t| t.to_array());
+                let path = agent.path().iter().flat_map(|p| p.to_array());
+                floats(target.into_iter().chain(path))
+            },
+            |_| None,
+        );
+    registry
+}
+
+fn hash_state(state: &[EntityDiff]) -> u64 {
+    // Fixed keys, unlike RandomState
+    let mut hasher = DefaultHasher::new();
+    for entity in state {
+        entity.entity.hash(&mut hasher);
+        entity.components.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn differences(a: &[EntityDiff], b: &[EntityDiff]) -> Vec<Difference> {
+    let a: BTreeMap<u64, &[(String, Vec<u8>)]> =
+        a.iter().map(|e| (e.entity, &e.components[..])).collect();
+    let b: BTreeMap<u64, &[(String, Vec<u8>)]> =
+        b.iter().map(|e| (e.entity, &e.components[..])).collect();
+    let entities: BTreeSet<u64> = a.keys().chain(b.keys()).copied().collect();
+
+    let mut differences = Vec::new();
+    for entity in entities {
+        let (a, b) = match (a.get(&entity), b.get(&entity)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => {
+                differences.push(Difference {
+                    entity,
+                    component: None,
+                });
+                continue;
+            }
+        };
+        let a: BTreeMap<&String, &Vec<u8>> = a.iter().map(|(name, bytes)| (name, bytes)).collect();
+        let b: BTreeMap<&String, &Vec<u8>> = b.iter().map(|(name, bytes)| (name, bytes)).collect();
+        let names: BTreeSet<&String> = a.keys().chain(b.keys()).copied().collect();
+        for name in names {
+            if a.get(name) != b.get(name) {
+                differences.push(Difference {
+                    entity,
+                    component: Some(name.clone()),
+                });
+            }
+        }
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+
+    use super::*;
+
+    #[test]
+    fn green() {
+        let test = DeterminismTest {
+            ticks: 300,
+            ..Default::default()
+        };
+        let hash = test
+            .run()
+            .unwrap_or_else(|divergence| panic!("{divergence}"));
+        // Same from one run to the other, and the seed (the scripted inputs) matters
+        assert_eq!(hash, test.run().unwrap());
+        let other = DeterminismTest { seed: 1, ..test };
+        assert_ne!(hash, other.run().unwrap());
+    }
+
+    /// Nondeterministic on purpose: RandomState has new keys every time
+    fn planted(followers: Entities<(&PathFollowComponent, &mut TransformsComponent)>) {
+        let noise = RandomState::new().build_hasher().finish();
+        for (_, transforms) in followers {
+            let offset = (noise % 1024) as f32 * 1e-3;
+            transforms.set_translation(transforms.translation() + Vec3::X * offset);
+        }
+    }
+
+    #[test]
+    fn planted_nondeterminism() {
+        let test = DeterminismTest {
+            ticks: 100,
+            ..Default::default()
+        };
+        let divergence = test
+            .run_with(|schedule| schedule.then(planted))
+            .unwrap_err();
+        // Only the followers move, and follow_paths puts them back every tick
+        assert_eq!(FOLLOWERS, divergence.differences.len());
+        for difference in &divergence.differences {
+            assert_eq!(Some("transforms"), difference.component.as_deref());
+        }
+        assert!(divergence.to_string().contains("transforms"));
+    }
+
+    #[test]
+    fn fixed_time() {
+        let step = Duration::from_millis(20);
+        let mut time = Time::fixed(step);
+        for _ in 0..3 {
+            time.update();
+        }
+        assert_eq!(step, time.delta());
+        assert_eq!(step * 3, time.elapsed());
+        assert_eq!(3, time.frame());
+    }
+}
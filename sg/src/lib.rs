@@ -0,0 +1,28 @@
+#![deny(unstable_features)]
+#![allow(dead_code)]
+
+use std::ops::Deref;
+
+mod chess;
+pub mod bench;
+pub mod bench_scenes;
+pub mod components;
+pub mod console;
+pub mod crash;
+pub mod deps;
+pub mod determinism;
+pub mod localization;
+pub mod save;
+pub mod systems;
+pub mod thumbnail;
+
+/// Whether the cursor is grabbed, the inputs then move the camera
+#[derive(Clone, Copy)]
+pub struct Grabbed(pub bool);
+
+impl Deref for Grabbed {
+    type Target = bool;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
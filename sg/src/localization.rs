@@ -0,0 +1,398 @@
+//! Localization of the UI strings.
+//!
+//! Translations live in `lang/<code>.lang` in the resources directory, one `key = value` per line.
+//! Lines starting with `#` are comments, values can contain `{name}` placeholders filled with
+//! `tr!(loc, "key", name = value)`, and support the `\n`, `\t`, `\\`, `\{` and `\}` escapes. A
+//! `\` at the end of a line continues the value on the next one.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Write},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use parking_lot::Mutex;
+use rmanage::ResourceManager;
+
+/// Language used when a key is missing from the current one
+pub const FALLBACK_LANGUAGE: &str = "en";
+/// Directory of the translation files, relative to the resources directory
+const LANG_DIRECTORY: &str = "lang";
+/// Key holding the name of a language, in that language
+const LANGUAGE_NAME_KEY: &str = "language.name";
+
+/// Get a translated string from a `Localization`, with optional named arguments.
+///
+/// `tr!(loc, "settings.language")` gives a `&str`, `tr!(loc, "key", player = name)` a `String`.
+#[macro_export]
+macro_rules! tr {
+    ($loc:expr, $key:literal $(,)?) => {
+        $loc.get($key)
+    };
+    ($loc:expr, $key:literal, $($name:ident = $value:expr),+ $(,)?) => {
+        $loc.format(
+            $key,
+            &[$((stringify!($name), &$value as &dyn ::std::fmt::Display)),+],
+        )
+    };
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Argument(String),
+}
+
+/// A parsed translation
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Message {
+    segments: Vec<Segment>,
+    /// The message with the placeholders left as is, returned when no argument is given
+    plain: String,
+}
+
+impl Message {
+    fn parse(value: &str, line: usize) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => text.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some(c @ ('\\' | '{' | '}')) => c,
+                    Some(c) => bail!("line {line}: unknown escape sequence '\\{c}'"),
+                    None => bail!("line {line}: trailing '\\'"),
+                }),
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| anyhow!("line {line}: unclosed placeholder"))?;
+                    let name = rest[..end].trim();
+                    chars = rest[end + 1..].chars();
+                    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                        bail!("line {line}: invalid placeholder '{{{name}}}'");
+                    }
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Argument(name.to_owned()));
+                }
+                '}' => bail!("line {line}: unmatched '}}', use '\\}}' for a literal brace"),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        let plain = segments
+            .iter()
+            .map(|s| match s {
+                Segment::Text(text) => text.clone(),
+                Segment::Argument(name) => format!("{{{name}}}"),
+            })
+            .collect();
+
+        Ok(Self { segments, plain })
+    }
+}
+
+/// Parse the content of a translation file
+fn parse(source: &str) -> Result<HashMap<String, Message>> {
+    let mut messages = HashMap::new();
+    let mut lines = source.lines().enumerate().map(|(i, l)| (i + 1, l));
+    while let Some((number, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {number}: expected 'key = value'"))?;
+        let key = key.trim();
+        if key.is_empty() {
+            bail!("line {number}: empty key");
+        }
+
+        let mut value = value.trim_start().to_owned();
+        // An odd number of trailing backslashes means the last one isn't escaped
+        while value.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1 {
+            value.pop();
+            match lines.next() {
+                Some((_, next)) => value.push_str(next.trim_start()),
+                None => bail!("line {number}: continuation at the end of the file"),
+            }
+        }
+
+        let message = Message::parse(&value, number)?;
+        if messages.insert(key.to_owned(), message).is_some() {
+            bail!("line {number}: duplicate key '{key}'");
+        }
+    }
+    Ok(messages)
+}
+
+fn load(resources: &ResourceManager, language: &str) -> Result<HashMap<String, Message>> {
+    let path = format!("{LANG_DIRECTORY}/{language}.lang");
+    let res = resources
+        .add_physical(&path)
+        .with_context(|| format!("Couldn't find translation file '{path}'"))?;
+    let bytes = resources.get_resource(res)?;
+    let source = std::str::from_utf8(&bytes).with_context(|| format!("'{path}' isn't UTF-8"))?;
+    parse(source).with_context(|| format!("Couldn't parse '{path}'"))
+}
+
+/// The UI strings of the current language, falling back to english (and then to the key itself)
+/// for missing translations.
+pub struct Localization {
+    language: String,
+    messages: HashMap<String, Message>,
+    fallback: HashMap<String, Message>,
+    /// (code, name) of the languages found in the resources directory
+    languages: Vec<(String, String)>,
+    /// Keys a warning has already been logged for, to avoid logging them every frame
+    warned: Mutex<HashSet<String>>,
+}
+
+impl Localization {
+    /// Load the translations for a language from the global ResourceManager
+    pub fn new(language: &str) -> Result<Self> {
+        let resources = rmanage::instance();
+        let fallback = load(resources, FALLBACK_LANGUAGE)?;
+        let mut languages = Vec::new();
+        for entry in std::fs::read_dir(resources.directory().join(LANG_DIRECTORY))? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("lang") {
+                continue;
+            }
+            if let Some(code) = path.file_stem().and_then(|s| s.to_str()) {
+                let name = match load(resources, code) {
+                    Ok(messages) => messages
+                        .get(LANGUAGE_NAME_KEY)
+                        .map(|m| m.plain.clone())
+                        .unwrap_or_else(|| code.to_owned()),
+                    Err(e) => {
+                        log::warn!("Skipping language '{code}': {e:#}");
+                        continue;
+                    }
+                };
+                languages.push((code.to_owned(), name));
+            }
+        }
+        languages.sort();
+
+        let mut loc = Self::from_messages(FALLBACK_LANGUAGE, fallback.clone(), fallback);
+        loc.languages = languages;
+        if language != FALLBACK_LANGUAGE {
+            loc.set_language(language)?;
+        }
+        Ok(loc)
+    }
+    fn from_messages(
+        language: &str,
+        messages: HashMap<String, Message>,
+        fallback: HashMap<String, Message>,
+    ) -> Self {
+        Self {
+            language: language.to_owned(),
+            messages,
+            fallback,
+            languages: Vec::new(),
+            warned: Mutex::new(HashSet::new()),
+        }
+    }
+    /// The code of the current language
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+    /// (code, name) of the available languages
+    pub fn languages(&self) -> &[(String, String)] {
+        &self.languages
+    }
+    /// Switch to another language, the current one is kept on error
+    pub fn set_language(&mut self, language: &str) -> Result<()> {
+        if language == self.language {
+            return Ok(());
+        }
+        self.messages = if language == FALLBACK_LANGUAGE {
+            self.fallback.clone()
+        } else {
+            load(rmanage::instance(), language)?
+        };
+        self.language = language.to_owned();
+        self.warned.lock().clear();
+        Ok(())
+    }
+    fn warn_once(&self, key: &str, message: impl FnOnce() -> String) {
+        let mut warned = self.warned.lock();
+        if !warned.contains(key) {
+            log::warn!("{}", message());
+            warned.insert(key.to_owned());
+        }
+    }
+    fn message<'a>(&'a self, key: &str) -> Option<&'a Message> {
+        if let Some(message) = self.messages.get(key) {
+            return Some(message);
+        }
+        let fallback = self.fallback.get(key);
+        self.warn_once(key, || match fallback {
+            Some(_) => format!("Missing '{}' translation for '{key}'", self.language),
+            None => format!("Unknown translation key '{key}'"),
+        });
+        fallback
+    }
+    /// Get the translation of a key, placeholders are left as is
+    pub fn get<'a>(&'a self, key: &'a str) -> &'a str {
+        self.message(key).map(|m| m.plain.as_str()).unwrap_or(key)
+    }
+    /// Get the translation of a key, replacing its placeholders with the arguments. Placeholders
+    /// without a matching argument are left as is.
+    pub fn format(&self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+        let message = match self.message(key) {
+            Some(message) => message,
+            None => return key.to_owned(),
+        };
+
+        let mut res = String::new();
+        for segment in &message.segments {
+            match segment {
+                Segment::Text(text) => res.push_str(text),
+                Segment::Argument(name) => match args.iter().find(|(n, _)| n == name) {
+                    Some((_, value)) => {
+                        let _ = write!(res, "{value}");
+                    }
+                    None => {
+                        self.warn_once(key, || format!("Missing argument '{name}' for '{key}'"));
+                        let _ = write!(res, "{{{name}}}");
+                    }
+                },
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use regex::Regex;
+
+    use super::*;
+
+    fn loc(source: &str, fallback: &str) -> Localization {
+        Localization::from_messages("xx", parse(source).unwrap(), parse(fallback).unwrap())
+    }
+
+    #[test]
+    fn parsing() {
+        let messages = parse(
+            "# comment\n\
+             \n\
+             a = simple\n\
+             b=  trimmed  \n\
+             c = new\\nline \\{ braces \\} back\\\\slash # not a comment\n\
+             d = first \\\n    second\n\
+             e = a = b\n",
+        )
+        .unwrap();
+        assert_eq!(messages.len(), 5);
+        assert_eq!(messages["a"].plain, "simple");
+        assert_eq!(messages["b"].plain, "trimmed");
+        assert_eq!(
+            messages["c"].plain,
+            "new\nline { braces } back\\slash # not a comment"
+        );
+        assert_eq!(messages["d"].plain, "first second");
+        assert_eq!(messages["e"].plain, "a = b");
+        // An escaped brace isn't a placeholder
+        assert_eq!(
+            messages["c"].segments,
+            [Segment::Text(messages["c"].plain.clone())]
+        );
+
+        assert!(parse("no value").is_err());
+        assert!(parse(" = value").is_err());
+        assert!(parse("a = \\q").is_err());
+        assert!(parse("a = {}").is_err());
+        assert!(parse("a = }").is_err());
+        assert!(parse("a = {b").is_err());
+        assert!(parse("a = b\na = c").is_err());
+        assert!(parse("a = b \\").is_err());
+    }
+
+    #[test]
+    fn interpolation() {
+        let loc = loc("check = {player} is in check ({player}, {count})", "");
+        assert_eq!(
+            tr!(loc, "check", player = "White", count = 2),
+            "White is in check (White, 2)"
+        );
+        assert_eq!(tr!(loc, "check"), "{player} is in check ({player}, {count})");
+        // Missing arguments are left as placeholders, extra ones are ignored
+        assert_eq!(
+            tr!(loc, "check", player = "Black", extra = 0),
+            "Black is in check (Black, {count})"
+        );
+    }
+
+    #[test]
+    fn fallback() {
+        let loc = loc("a = translated", "a = english\nb = english {x}");
+        assert_eq!(loc.get("a"), "translated");
+        assert_eq!(loc.get("b"), "english {x}");
+        assert_eq!(tr!(loc, "b", x = 1), "english 1");
+        assert_eq!(loc.get("c"), "c");
+        assert_eq!(tr!(loc, "c", x = 1), "c");
+        assert!(loc.warned.lock().contains("b"));
+        assert!(loc.warned.lock().contains("c"));
+    }
+
+    /// Every key used with tr! in the crate must exist in the shipped translations
+    #[test]
+    fn keys_exist() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let re = Regex::new(r#"tr!\(\s*[^,]+,\s*"([^"]+)""#).unwrap();
+
+        fn sources(dir: &Path, files: &mut Vec<std::path::PathBuf>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    sources(&path, files);
+                } else if path.extension().and_then(|e| e.to_str()) == Some("rs") {
+                    files.push(path);
+                }
+            }
+        }
+        let mut files = Vec::new();
+        sources(&root.join("src"), &mut files);
+        // Skip the examples of this module
+        files.retain(|f| !f.ends_with("localization.rs"));
+        let keys: HashSet<String> = files
+            .iter()
+            .flat_map(|f| {
+                let source = std::fs::read_to_string(f).unwrap();
+                re.captures_iter(&source)
+                    .map(|c| c[1].to_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert!(!keys.is_empty());
+
+        for entry in std::fs::read_dir(root.join("resources").join(LANG_DIRECTORY)).unwrap() {
+            let path = entry.unwrap().path();
+            let messages = parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            for key in keys.iter().chain(std::iter::once(&LANGUAGE_NAME_KEY.to_owned())) {
+                assert!(
+                    messages.contains_key(key),
+                    "'{key}' is missing from {}",
+                    path.display()
+                );
+            }
+        }
+    }
+}
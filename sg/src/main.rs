@@ -1,63 +1,82 @@
-#![feature(trait_upcasting)]
-#![feature(once_cell)]
-#![feature(trace_macros)]
-#![allow(incomplete_features)]
+#![deny(unstable_features)]
 #![allow(dead_code)]
 
 use std::collections::HashMap;
 use std::f32::consts::PI;
-use std::io::BufReader;
-use std::num::NonZeroU32;
-use std::ops::Deref;
-use std::sync::{Arc, Barrier, mpsc};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 
-use ecs::{Executor, World};
+use anyhow::Context;
+use sg::{bench, bench_scenes, components, console, crash, deps, determinism, localization, save, systems, Grabbed};
+use bench::{BenchArgs, Recorder};
+use bench_scenes::SceneDesc;
+
+use ecs::{Entities, Events, Executor, Local, World};
 use glam::{EulerRot, Quat, Vec2, Vec3, Vec4};
-use image::{GenericImageView, Rgba};
+use image::Rgba;
 use parking_lot::RwLock;
 use slotmap::SlotMap;
-use systems::graphics::convolution::ConvolutionComputer;
-use systems::graphics::cubemap::CubeMapComputer;
+use systems::graphics::brdf_lut::BrdfLutComputer;
+use systems::graphics::env_cache::EnvironmentMaps;
+use systems::graphics::prefilter::SpecularPrefilterComputer;
+use systems::graphics::focus::{InputMode, InputRouter, Route, UiAction, UiFocus};
+use systems::graphics::frame::Frame;
 use systems::graphics::mesh_manager::{Mesh, Primitives};
+use systems::graphics::debug_draw::DebugDraw;
+use systems::graphics::minimap::Minimap;
+use systems::graphics::options::GraphicContextOptions;
+use systems::graphics::paint::{PaintableComponent, TexturePaintTool};
+use systems::graphics::particles::EmitterParams;
+use systems::graphics::picking::{PickRequest, PickResult};
+use systems::graphics::quality::AdaptiveQuality;
+use systems::graphics::memory::{GpuMemory, GpuMemoryStats};
 use systems::graphics::texture_manager::SingleValue;
 use systems::graphics::{GraphicContext, Light, PointLight, Material};
-use systems::graphics::renderer::{WorldRenderer, UIRenderer};
-use winit::dpi::PhysicalPosition;
-use winit::event::{ElementState, Event, KeyboardInput, ScanCode, VirtualKeyCode, WindowEvent, MouseButton};
+use systems::graphics::renderer::{self, WorldRenderer, UIRenderer};
+use systems::graphics::ui_draw::UiDraw;
+use systems::graphics::ui_scale::UiScale;
+use winit::event::{DeviceEvent, ElementState, Event, KeyboardInput, ModifiersState, ScanCode, VirtualKeyCode, WindowEvent, MouseButton};
+use winit::dpi::PhysicalSize;
 use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::WindowBuilder;
+use winit::window::{Window, WindowBuilder};
 use egui_winit::State as EState;
-use systems::graphics::gltf;
-
-use components::{LightComponent, GraphicsComponent, TransformsComponent};
+use systems::animation::{self, AnimationManager};
+use systems::path::{self, Interpolation, PathComponent, PathEvents, PathFollowComponent};
+use systems::ambience::{self, ZoneShape};
+use systems::audio::Mixer;
+use systems::character::{self, CameraMode, CharacterInput, FootstepEvents, ShowcaseCameraComponent};
+use systems::collision::{StaticBvh, SurfaceKind};
+use systems::footsteps::{self, FootstepBank};
+use systems::navmesh::{self, NavDebugDraw, NavMesh, NavSettings};
+use systems::rng::GameRng;
+use systems::time::{self, FixedTime, Time};
+use systems::weather::Weather;
+
+use components::{AudioZoneComponent, CharacterControllerComponent, LightComponent, GraphicsComponent, StaticGeometryComponent, SurfaceMaterialComponent, MinimapMarkerComponent, ParticleEmitterComponent, PrecipitationComponent, SpriteComponent, SpriteSize, TransformsComponent, WorldAnchorComponent};
+use console::Console;
+use localization::Localization;
+use save::{GameLoaded, SaveMenu, Saves};
 
-mod chess;
-pub mod components;
-pub mod systems;
 
 slotmap::new_key_type! {
     struct Input;
 }
 
-const CENTER_POS: PhysicalPosition<f64> = PhysicalPosition::new(100.0, 100.0);
-
 #[derive(Default)]
 struct InputState {
     states: RwLock<SlotMap<Input, RwLock<ElementState>>>,
     keycodes: RwLock<HashMap<VirtualKeyCode, Input>>,
     scancodes: RwLock<HashMap<ScanCode, Input>>,
+    /// Motion of the mouse since the start of the frame
+    mouse_motion: RwLock<Vec2>,
+    /// Motion of the mouse during the last frame
     mouse_delta: RwLock<Vec2>,
 }
 
+/// The window was resized, an event with the new size
 #[derive(Clone, Copy)]
-pub struct Grabbed(bool);
-
-impl Deref for Grabbed {
-    type Target = bool;
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
+pub struct WindowResized(pub PhysicalSize<u32>);
 
 impl InputState {
     fn new() -> Self {
@@ -113,180 +132,113 @@ impl InputState {
         *self.mouse_delta.read()
     }
 
-    fn notify_mouse(&self, pos: PhysicalPosition<f64>) {
-        *self.mouse_delta.write() = Vec2::new(
-            pos.x as f32 - CENTER_POS.x as f32,
-            pos.y as f32 - CENTER_POS.y as f32,
-        );
+    fn notify_mouse(&self, delta: Vec2) {
+        *self.mouse_motion.write() += delta;
+    }
+
+    /// The motion accumulated since the last call becomes the mouse delta of the frame
+    fn begin_frame(&self) {
+        *self.mouse_delta.write() = std::mem::take(&mut *self.mouse_motion.write());
     }
 }
 
-async fn run(mut world: World, mut executor: Executor) {
+async fn run(mut world: World, mut executor: Executor, bench: Option<BenchArgs>) {
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
-    let mut gfx = GraphicContext::new(&window).await;
+    let options = GraphicContextOptions::default().with_env().unwrap_or_else(|e| {
+        log::warn!("{e:#}, using the default graphics options");
+        GraphicContextOptions::default()
+    });
+    let mut gfx = GraphicContext::new(&window, options).await;
     let mut wr = WorldRenderer::new(&mut gfx);
-    let uir = UIRenderer::new(&gfx, window.scale_factor() as f32);
+    let uir = UIRenderer::new(&gfx);
+    let mut draw = UiDraw::new();
+    let scale = UiScale::new(window.inner_size(), window.scale_factor());
+    let minimap = Minimap::new(&gfx);
     let mut estate = EState::new(&event_loop);
     let ui = egui::Context::default();
     let inputs = Arc::new(InputState::new());
 
     estate.set_max_texture_side(gfx.device.limits().max_texture_dimension_2d as usize);
-    estate.set_pixels_per_point(window.scale_factor() as f32);
+    estate.set_pixels_per_point(scale.pixels_per_point());
     wr.camera.set_position(Vec3::new(0.0, 0.0, 2.0));
     wr.camera.set_rotation(Quat::from_rotation_y(PI));
     //world.spawn_many(gltf::open("models/ka.glb", &mut gfx).expect("Error"));
 
     {
-        let mut r = CubeMapComputer::new(&gfx);
-        let mut reader = image::io::Reader::with_format(
-                BufReader::new(std::fs::File::open("hdr.exr").unwrap()),
-                image::ImageFormat::OpenExr,
-            );
-        reader.no_limits();
-        let image = reader
-            .decode()
-            .unwrap()
-            .flipv()
-            .to_rgba32f();
-        let f = 4096;
-        let s = 128;
-        let t = r.render(image, &gfx, f, wgpu::TextureUsages::TEXTURE_BINDING)
-            .create_view(&wgpu::TextureViewDescriptor {
-                dimension: Some(wgpu::TextureViewDimension::Cube),
-                ..Default::default()
-            });
-        let c = ConvolutionComputer::new(&gfx);
-        let e = c.run(&t, s, wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_SRC, &gfx);
-        let v = e
-            .create_view(&wgpu::TextureViewDescriptor {
+        let skybox_size = 4096;
+        let maps = std::path::Path::new("hdr.exr")
+            .canonicalize()
+            .context("Couldn't find hdr.exr")
+            .and_then(|path| Ok(rmanage::instance().add_physical(path)?))
+            .and_then(|hdr| EnvironmentMaps::load_or_compute(&gfx, rmanage::instance(), hdr, skybox_size, 128))
+            .expect("Couldn't load the environment maps");
+        let (skybox, irradiance) = maps.views();
+        let (prefiltered, mips) = SpecularPrefilterComputer::new(&gfx).run(
+            &skybox,
+            skybox_size,
+            wgpu::TextureUsages::TEXTURE_BINDING,
+            &gfx,
+        );
+        let lut = BrdfLutComputer::new(&gfx).run(wgpu::TextureUsages::TEXTURE_BINDING, &gfx);
+        wr.camera.set_skybox(skybox);
+        wr.camera.set_irradiance_map(irradiance);
+        wr.camera.set_prefiltered_env(
+            prefiltered.create_view(&wgpu::TextureViewDescriptor {
                 dimension: Some(wgpu::TextureViewDimension::Cube),
                 ..Default::default()
-            });
-        wr.camera.set_skybox(t);
-        wr.camera.set_irradiance_map(v);
-
-        //let device = &gfx.device;
-        //let queue = &gfx.queue;
-        //let buffer = device.create_buffer(&wgpu::BufferDescriptor {
-        //    label: None,
-        //    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-        //    size: s as u64 * 4 * 4 * s as u64 * 6,
-        //    mapped_at_creation: false,
-        //});
-        //let mut encoder = device.create_command_encoder(&Default::default());
-        //encoder.copy_texture_to_buffer(
-        //    e.as_image_copy(),
-        //    wgpu::ImageCopyBuffer {
-        //        buffer: &buffer,
-        //        layout: wgpu::ImageDataLayout {
-        //            offset: 0,
-        //            bytes_per_row: NonZeroU32::new(s * 4 * 4),
-        //            rows_per_image: NonZeroU32::new(s),
-        //        }
-        //    },
-        //    wgpu::Extent3d {
-        //        width: s,
-        //        height: s,
-        //        depth_or_array_layers: 6,
-        //    }
-        //);
-        //let si = queue.submit(std::iter::once(encoder.finish()));
-
-        //let (se, re) = mpsc::channel();
-
-        //    buffer.slice(..).map_async(wgpu::MapMode::Read, move |b| {
-        //        se.send(b).unwrap();
-        //    });
-
-        //device.poll(wgpu::Maintain::WaitForSubmissionIndex(si));
-
-        //re.recv().unwrap().unwrap();
-
-        //let bytes = buffer.slice(..)
-        //    .get_mapped_range()
-        //    .iter()
-        //    .copied().collect::<Vec<_>>();
-        //let floats: Vec<f32> = bytemuck::cast_slice::<_, f32>(&bytes).to_vec();
-        //let buffer = image::ImageBuffer::<Rgba<f32>, Vec<f32>>::from_raw(s, s*6, floats).unwrap();
-        //buffer.save_with_format("out.exr", image::ImageFormat::OpenExr).unwrap();
-
-        //Box::leak(Box::new(t));
+            }),
+            mips,
+        );
+        wr.camera.set_brdf_lut(lut.create_view(&Default::default()));
     }
 
-    let gfc = {
-        let mesh = gfx.mesh_manager.add(&gfx.device, &Mesh::new_icosphere(3));
-        let material = {
-            let albedo = gfx.texture_manager.get_or_add_single_value_texture(
-                &gfx.device,
-                &gfx.queue,
-                SingleValue::Color(Vec4::new(1.0, 0.0, 0.0, 1.0))
-            );
-            Material::new_with_values(albedo, None, 0.0, 0.8, None, &mut gfx).unwrap()
-        };
-        GraphicsComponent {
-            mesh,
-            material,
+    let bench = bench.map(|args| {
+        let (kind, params) = args.scene.expect("only windowed benchmarks open a window");
+        let scene = SceneDesc::generate(kind, params).expect("validated by BenchArgs::parse");
+        scene.spawn(&mut world, Some(&mut gfx)).expect("Couldn't spawn the benchmark scene");
+        gfx.sync = true;
+        if !gfx.set_vsync(false) {
+            log::warn!("Vsync can't be disabled, the benchmark is capped by the refresh rate");
         }
-    };
-
-    world.spawn((gfc,));
+        draw.add_panel("stress", bench_scenes::stress_windows(params.windows));
+        executor.add_resource(scene.camera());
+        (args, Recorder::new(kind, params))
+    });
+    if bench.is_none() {
+        spawn_demo(&mut world, &mut gfx);
+    }
+    executor.add_resource(StaticBvh::from_scene(world.query::<(&StaticGeometryComponent, &TransformsComponent, Option<&SurfaceMaterialComponent>)>()));
+    executor.add_resource(NavMesh::from_scene(NavSettings::default(), world.query::<(&StaticGeometryComponent, &TransformsComponent)>()));
+    executor.add_resource(NavDebugDraw::default());
 
     let window = Arc::new(window);
 
     executor.add_resource(gfx);
     executor.add_resource(wr);
     executor.add_resource(uir);
+    executor.add_resource(draw);
+    executor.add_resource(scale);
+    executor.add_resource(minimap);
+    executor.add_resource(TexturePaintTool::new());
     executor.add_resource(estate);
     executor.add_resource(ui);
     executor.add_resource(window.clone());
-
-    let mut colors = std::iter::empty()
-        .chain(std::iter::repeat(Vec4::new(7.0, 7.0, 7.0, 1.0)).take(8))
-        .chain(std::iter::repeat(Vec4::new(2.5, 5.0, 10.0, 1.0)).take(8))
-        .chain(std::iter::repeat(Vec4::new(15.0, 15.0, 15.0, 1.0)).take(4));
-    let lights = [
-        Vec3::new( 1.0,  1.0, 6.0),
-        Vec3::new(-1.0,  1.0, 6.0),
-        Vec3::new(-1.0, -1.0, 6.0),
-        Vec3::new( 1.0, -1.0, 6.0),
-        Vec3::new( 1.0,  4.0, 6.0),
-        Vec3::new(-1.0,  4.0, 6.0),
-        Vec3::new(-1.0,  2.0, 6.0),
-        Vec3::new( 1.0,  2.0, 6.0),
-
-        Vec3::new( 1.0,  1.0, -10.0),
-        Vec3::new(-1.0,  1.0, -10.0),
-        Vec3::new(-1.0, -1.0, -10.0),
-        Vec3::new( 1.0, -1.0, -10.0),
-        Vec3::new( 1.0,  4.0, -10.0),
-        Vec3::new(-1.0,  4.0, -10.0),
-        Vec3::new(-1.0,  2.0, -10.0),
-        Vec3::new( 1.0,  2.0, -10.0),
-
-        Vec3::new( 5.0, 0.0, 1.0),
-        Vec3::new(-5.0, 3.0, 1.0),
-        Vec3::new(-5.0, 0.0, 1.0),
-        Vec3::new( 5.0, 3.0, 1.0),
-    ];
-    for pos in lights {
-        let lc = colors.next().unwrap();
-        let mut tsm = TransformsComponent::new();
-        tsm.set_translation(pos);
-        tsm.set_scale(Vec3::splat(0.5));
-        world.spawn((
-            LightComponent::new(Light::Point(PointLight::new(pos, lc))),
-            tsm,
-            gfc,
-        ));
-    }
-
-    executor.add_resource(0f64);
+    executor.add_resource(Localization::new(localization::FALLBACK_LANGUAGE).expect("Couldn't load translations"));
 
     let transforms = {
         let inputs = inputs.clone();
-        move |count: &mut f64, wr: &mut WorldRenderer| {
-            *count += 1.0;
+        move |mut frames: Local<u64>, time: &Time, wr: &mut WorldRenderer, focus: &UiFocus, mode: &CameraMode, input: &mut CharacterInput, characters: Entities<(&CharacterControllerComponent, &TransformsComponent)>, showcase: Entities<(&ShowcaseCameraComponent, &TransformsComponent)>| {
+            *frames += 1;
+            if *mode == CameraMode::Showcase {
+                // The path moves and rotates the camera, the keys and mouse do nothing
+                *input = CharacterInput::default();
+                if let Some((pos, rot)) = showcase.map(|(_, tsm)| (tsm.translation(), tsm.rotation())).next() {
+                    wr.camera.set_position(pos);
+                    wr.camera.set_rotation(rot);
+                }
+                return;
+            }
             let mut changed = false;
             let mut cam_pos = wr.camera.get_position();
             let mut cam_rot = wr.camera.get_rotation();
@@ -294,31 +246,46 @@ async fn run(mut world: World, mut executor: Executor) {
                 let (y, _, _) = cam_rot.to_euler(EulerRot::YXZ);
                 Quat::from_euler(EulerRot::YXZ, y, 0.0, 0.0)
             };
-            let fac = 0.01;
+            // Units per second
+            let fac = 0.6 * time.delta_secs();
             let scale = 0.001;
-            if inputs.is_pressed_keycode(VirtualKeyCode::Z) {
-                changed = true;
-                cam_pos += rot.mul_vec3(Vec3::new(0.0, 0.0, fac));
-            }
-            if inputs.is_pressed_keycode(VirtualKeyCode::Q) {
-                changed = true;
-                cam_pos += rot.mul_vec3(Vec3::new(-fac, 0.0, 0.0));
-            }
-            if inputs.is_pressed_keycode(VirtualKeyCode::S) {
-                changed = true;
-                cam_pos += rot.mul_vec3(Vec3::new(0.0, 0.0, -fac));
-            }
-            if inputs.is_pressed_keycode(VirtualKeyCode::D) {
-                changed = true;
-                cam_pos += rot.mul_vec3(Vec3::new(fac, 0.0, 0.0));
-            }
-            if inputs.is_pressed_keycode(VirtualKeyCode::Space) {
-                changed = true;
-                cam_pos += rot.mul_vec3(Vec3::new(0.0, fac, 0.0));
-            }
-            if inputs.is_pressed_keycode(VirtualKeyCode::Tab) {
-                changed = true;
-                cam_pos += rot.mul_vec3(Vec3::new(0.0, -fac, 0.0));
+            let character = match mode {
+                CameraMode::Character => characters.map(|(controller, tsm)| tsm.translation() + Vec3::Y * controller.eye_height()).next(),
+                CameraMode::FreeFly | CameraMode::Showcase => None,
+            };
+            // The movement actions of the action map, held
+            let held = |action| focus.bindings().held(action, |key| inputs.is_pressed_keycode(key));
+            let moves = [(UiAction::MoveForward, Vec3::Z), (UiAction::MoveLeft, -Vec3::X), (UiAction::MoveBack, -Vec3::Z), (UiAction::MoveRight, Vec3::X)];
+            if let Some(eyes) = character {
+                // The actions move the character, the camera follows at its eyes
+                let mut direction = Vec3::ZERO;
+                for (action, dir) in moves {
+                    if held(action) {
+                        direction += dir;
+                    }
+                }
+                *input = CharacterInput {
+                    direction: rot.mul_vec3(direction.normalize_or_zero()),
+                    jump: held(UiAction::Jump),
+                };
+                changed = eyes != cam_pos;
+                cam_pos = eyes;
+            } else {
+                *input = CharacterInput::default();
+                for (action, dir) in moves {
+                    if held(action) {
+                        changed = true;
+                        cam_pos += rot.mul_vec3(dir * fac);
+                    }
+                }
+                if held(UiAction::Jump) {
+                    changed = true;
+                    cam_pos += rot.mul_vec3(Vec3::new(0.0, fac, 0.0));
+                }
+                if inputs.is_pressed_keycode(VirtualKeyCode::Tab) {
+                    changed = true;
+                    cam_pos += rot.mul_vec3(Vec3::new(0.0, -fac, 0.0));
+                }
             }
             let delta = inputs.get_mouse_delta();
             if delta.length_squared() > 0.0 {
@@ -337,20 +304,120 @@ async fn run(mut world: World, mut executor: Executor) {
     };
 
     executor.add_resource(Grabbed(false));
-
+    executor.add_resource(UiFocus::new());
+    executor.add_resource(AdaptiveQuality::new());
+    executor.add_resource(Weather::load());
+    executor.add_resource(GpuMemory::load());
+    executor.add_resource(GpuMemoryStats::default());
+    // Benchmarks run at a fixed step, so every run sees the same frames
+    executor.add_resource(if bench.is_some() { Time::fixed(bench::STEP) } else { Time::new() });
+    executor.add_resource(PathEvents::new());
+    executor.add_resource(DebugDraw::new());
+    executor.add_resource(AnimationManager::new());
+    executor.add_resource(CharacterInput::default());
+    executor.add_resource(CameraMode::default());
+    executor.add_events::<KeyboardInput>();
+    executor.add_events::<WindowResized>();
+    executor.add_resource(FixedTime::new());
+    executor.add_resource(FootstepEvents::new());
+    executor.add_resource(FootstepBank::load_or_default());
+    executor.add_resource(GameRng::new(rand::random()));
+    executor.add_resource(Mixer::default());
+    executor.add_resource(console());
+    let saves = saves(bench.is_none());
+    executor.add_resource(SaveMenu::new(saves.clone()));
+    executor.add_resource(saves);
+    executor.add_events::<GameLoaded>();
+    executor.add_resource(Frame::default());
+
+    // Runs at the fixed step of Time, zero or more times a frame before the frame's schedule
+    let fixed_schedule = executor
+        .schedule()
+        .then(FixedTime::advance)
+        .then(character::move_characters)
+        .build();
     let schedule = executor
         .schedule()
+        .then(character::toggle_camera)
+        .then(WorldRenderer::game_loaded)
+        .then(StaticBvh::game_loaded)
+        .then(NavMesh::game_loaded)
+        .with(|schedule| match bench {
+            Some(_) => schedule
+                .then(bench_scenes::animate_lights)
+                .then(bench_scenes::follow_camera),
+            None => schedule,
+        })
+        .then(path::follow_paths)
+        .then(path::draw_paths)
+        .then(navmesh::draw_navigation)
+        .then(animation::advance)
+        .then(footsteps::play_footsteps)
+        .then(Minimap::follow_focus)
         .then(WorldRenderer::update_lights)
-        .then(GraphicContext::render)
+        .then(Weather::update)
+        .then(ambience::update_zones)
+        .then(Mixer::flush)
+        .then(WorldRenderer::update_particles)
+        .then(WorldRenderer::update_sprites)
+        .then(WorldRenderer::update_debug_draw)
+        .then(TexturePaintTool::paint)
+        .then(Minimap::render)
+        .then(AdaptiveQuality::adapt)
+        .then(GpuMemory::update)
+        .then(GraphicContext::begin_frame)
+        .then(WorldRenderer::render)
+        .then(UIRenderer::render)
+        .then(GraphicContext::end_frame)
         .then(transforms)
         .build();
 
+    let mut router = InputRouter::new();
+    let mut modifiers = ModifiersState::empty();
+    // The character typed by the console key, not to be given to its input
+    let mut swallow_char = false;
+    let mut bench = bench;
+    let mut exit_code = 0;
+    // Cursor while not grabbed, and the picks of its clicks in flight
+    let mut cursor = None;
+    let mut picks: Vec<PickRequest> = Vec::new();
+
     event_loop.run(move |event, _, control_flow| match event {
         Event::RedrawRequested(id) if id == window.id() => {
-            executor.execute(&schedule, &mut world);
+            let start = Instant::now();
+            inputs.begin_frame();
+            time::execute_frame(&mut executor, &mut world, &fixed_schedule, &schedule);
+            if let Some((args, recorder)) = &mut bench {
+                let gfx = executor.get_resource::<GraphicContext>().unwrap();
+                if recorder.record(start.elapsed(), &gfx.timings) {
+                    let report = recorder.finish(Some(gfx.adapter.name.clone()));
+                    exit_code = bench::exit_code(bench::finish(args, &report));
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+            picks.retain_mut(|pick| match pick.poll() {
+                PickResult::Pending => true,
+                PickResult::Nothing => {
+                    log::info!("Picked nothing");
+                    false
+                }
+                PickResult::Entity(entity) => {
+                    log::info!("Picked {entity:?}");
+                    false
+                }
+            });
+            crash::snapshot_world(&world);
+            if Console::run_pending(&mut executor, &mut world) {
+                *control_flow = ControlFlow::Exit;
+            }
+            SaveMenu::run_pending(&mut executor, &mut world);
+            if let Some(url) = executor.get_resource_mut::<UIRenderer>().unwrap().take_output().open_url {
+                open_url(&url);
+            }
+            let feedback = executor.get_resource::<Frame>().unwrap().feedback();
             let gfx = executor.get_resource_mut::<GraphicContext>().unwrap();
 
-            match gfx.feedback() {
+            match feedback {
                 Ok(_) => {}
                 Err(wgpu::SurfaceError::Lost) => gfx.resize(gfx.size),
                 Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
@@ -360,64 +427,443 @@ async fn run(mut world: World, mut executor: Executor) {
         Event::MainEventsCleared => {
             window.request_redraw();
         }
+        // Raw motion: unaffected by the cursor hitting the edges of the screen, and still there
+        // when the cursor is locked
+        Event::DeviceEvent { event: DeviceEvent::MouseMotion { delta: (x, y) }, .. }
+            if **executor.get_resource::<Grabbed>().unwrap() =>
+        {
+            inputs.notify_mouse(Vec2::new(x as f32, y as f32));
+        }
         Event::WindowEvent {
             window_id,
             ref event,
         } if window_id == window.id() => {
+            executor.get_resource_mut::<UiScale>().unwrap().handle_event(event);
             match event {
                 WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                WindowEvent::Resized(physical_size) => executor
-                    .get_resource_mut::<GraphicContext>()
-                    .unwrap()
-                    .resize(*physical_size),
-                WindowEvent::ScaleFactorChanged { new_inner_size, scale_factor } => {
-                    executor
-                        .get_resource_mut::<GraphicContext>()
-                        .unwrap()
-                        .resize(**new_inner_size);
-                    executor
-                        .get_resource_mut::<EState>()
-                        .unwrap()
-                        .set_pixels_per_point(*scale_factor as f32);
+                WindowEvent::Resized(physical_size) => {
+                    executor.get_resource_mut::<GraphicContext>().unwrap().resize(*physical_size);
+                    executor.get_resource_mut::<Events<WindowResized>>().unwrap().send(WindowResized(*physical_size));
+                }
+                // The scale of egui follows in UIRenderer::render, along with the user scale
+                WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                    executor.get_resource_mut::<GraphicContext>().unwrap().resize(**new_inner_size);
+                    executor.get_resource_mut::<Events<WindowResized>>().unwrap().send(WindowResized(**new_inner_size));
                 }
                 _ => {}
             }
 
+            if let WindowEvent::ModifiersChanged(state) = event {
+                modifiers = *state;
+            }
+            if let WindowEvent::KeyboardInput { input: KeyboardInput { state, virtual_keycode: Some(key), .. }, .. } = event {
+                let pressed = *state == ElementState::Pressed;
+                if *key == console::TOGGLE_KEY {
+                    if pressed {
+                        let console = executor.get_resource_mut::<Console>().unwrap();
+                        console.toggle();
+                        if console.open && router.release() {
+                            apply_mode(&mut executor, &window, &router);
+                        }
+                        swallow_char = true;
+                    }
+                    return;
+                }
+                if *key == renderer::RELOAD_SHADERS_KEY {
+                    if pressed {
+                        let (wr, gfx): (&mut WorldRenderer, &mut GraphicContext) = executor.query_resources().unwrap();
+                        wr.reload_shaders(gfx);
+                    }
+                    return;
+                }
+                if pressed {
+                    swallow_char = false;
+                }
+                let focus = executor.get_resource_mut::<UiFocus>().unwrap();
+                if pressed && focus.capture(*key, modifiers.shift()) {
+                    swallow_char = true;
+                    return;
+                }
+                let mode = router.mode();
+                match router.key(focus.bindings(), *key, pressed, modifiers.shift()) {
+                    Route::Navigate(action) => {
+                        focus.push(action);
+                        return;
+                    }
+                    Route::Consumed => {
+                        if router.mode() != mode {
+                            apply_mode(&mut executor, &window, &router);
+                        }
+                        return;
+                    }
+                    Route::Camera | Route::Ui => {}
+                }
+            }
+
+            if let WindowEvent::ReceivedCharacter(_) = event {
+                if std::mem::take(&mut swallow_char) {
+                    return;
+                }
+            }
+
             if !**executor.get_resource::<Grabbed>().unwrap() {
+                // Releases go through even when over the UI, or the stroke would never end
+                if let WindowEvent::MouseInput { state: ElementState::Released, button: MouseButton::Left, .. } = event {
+                    executor.get_resource_mut::<TexturePaintTool>().unwrap().set_pressed(false);
+                }
+
                 let (estate, ui): (&mut EState, &egui::Context) = executor.query_resources().unwrap();
                 if estate.on_event(ui, event) {
                     return;
                 }
 
-                if let WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Left, .. } = event {
-                    *executor.get_resource_mut::<Grabbed>().unwrap() = Grabbed(true);
-                    window.set_cursor_visible(false);
-                    window.set_cursor_grab(true).unwrap();
-                }
-            } else {
-                if let WindowEvent::KeyboardInput { input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::Escape), .. }, .. } = event {
-                    *executor.get_resource_mut::<Grabbed>().unwrap() = Grabbed(false);
-                    window.set_cursor_visible(true);
-                    window.set_cursor_grab(false).unwrap();
-                }
+                let paint = executor.get_resource_mut::<TexturePaintTool>().unwrap();
                 match event {
                     WindowEvent::CursorMoved { position, .. } => {
-                        inputs.notify_mouse(*position);
-                        window.set_cursor_position(CENTER_POS).unwrap();
+                        paint.cursor_moved(Vec2::new(position.x as f32, position.y as f32));
+                        cursor = Some((position.x as u32, position.y as u32));
                     }
-                    WindowEvent::KeyboardInput { input, .. } => {
-                        inputs.notify(*input);
+                    WindowEvent::CursorLeft { .. } => {
+                        paint.cursor_left();
+                        cursor = None;
+                    }
+                    WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Left, .. } if paint.enabled => {
+                        paint.set_pressed(true);
+                        return;
                     }
                     _ => {}
                 }
+
+                if let WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Left, .. } = event {
+                    if let Some(pixel) = cursor {
+                        picks.push(executor.get_resource_mut::<WorldRenderer>().unwrap().pick(pixel));
+                    }
+                    if router.click() {
+                        apply_mode(&mut executor, &window, &router);
+                    }
+                }
+            } else {
+                if let WindowEvent::KeyboardInput { input, .. } = event {
+                    executor.get_resource_mut::<Events<KeyboardInput>>().unwrap().send(*input);
+                    inputs.notify(*input);
+                }
+            }
+        }
+        Event::LoopDestroyed => {
+            let gfx = executor.get_resource::<GraphicContext>().unwrap();
+            if let Err(e) = gfx.pipelines.save() {
+                log::warn!("Couldn't save the pipeline cache: {e}");
+            }
+            if bench.is_some() {
+                std::process::exit(exit_code);
             }
         }
         _ => {}
     });
 }
 
+/// A red sphere
+fn sphere(gfx: &mut GraphicContext) -> anyhow::Result<GraphicsComponent> {
+    let mesh = gfx.mesh_manager.add_dedup(&gfx.device, &Mesh::new_icosphere(3));
+    let material = {
+        let albedo = gfx.texture_manager.get_or_add_single_value_texture(
+            &gfx.device,
+            &gfx.queue,
+            SingleValue::Color(Vec4::new(1.0, 0.0, 0.0, 1.0))
+        );
+        Material::new_with_values(albedo, None, 0.0, 0.8, None, gfx)?
+    };
+    Ok(GraphicsComponent {
+        mesh,
+        material,
+    })
+}
+
+/// Open a link of the UI in the browser
+fn open_url(url: &str) {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = std::process::Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    if let Err(e) = command.arg(url).spawn() {
+        log::warn!("Couldn't open {url}: {e}");
+    }
+}
+
+/// The console, with the game's commands and what can be inspected
+fn console() -> Console {
+    let mut console = Console::new();
+    console.register_inspectable("fog", |wr: &mut WorldRenderer| &mut wr.fog.settings);
+    console.register_inspectable("ssr", |wr: &mut WorldRenderer| &mut wr.ssr.settings);
+    console.register_inspectable("memory", |memory: &mut GpuMemory| &mut memory.settings);
+    console.register_component::<TransformsComponent>("transforms");
+    console.register_component::<GraphicsComponent>("graphics");
+    console.register_component::<LightComponent>("light");
+    console.register_component::<MinimapMarkerComponent>("minimap_marker");
+    console.register_component::<PaintableComponent>("paintable");
+    console.register("stats", "stats", |_, ctx| {
+        let draw = ctx.executor.get_resource_mut::<UiDraw>().context("no ui")?;
+        draw.show_stats = !draw.show_stats;
+        Ok(())
+    });
+    console.register("navmesh", "navmesh", |_, ctx| {
+        let draw = ctx.executor.get_resource_mut::<NavDebugDraw>().context("no navmesh")?;
+        draw.0 = !draw.0;
+        Ok(())
+    });
+    console.register("spawn_sphere", "spawn_sphere <x> <y> <z>", |args, ctx| {
+        let pos = Vec3::new(args.number(0)? as f32, args.number(1)? as f32, args.number(2)? as f32);
+        let gfx = ctx.executor.get_resource_mut::<GraphicContext>().context("no graphic context")?;
+        let gfc = sphere(gfx)?;
+        let mut tsm = TransformsComponent::new();
+        tsm.set_translation(pos);
+        let entity = ctx.world.spawn((gfc, tsm));
+        ctx.println(format!("Spawned {entity:?} at {pos}"));
+        Ok(())
+    });
+    console
+}
+
+/// The saves in the user's data directory, of games spawned from the demo scene (the benchmarks
+/// spawn others)
+fn saves(demo: bool) -> Saves {
+    let mut registry = save::registry();
+    if demo {
+        registry.set_scene("demo", |world, executor| {
+            let gfx = executor.get_resource_mut::<GraphicContext>().context("no graphics")?;
+            spawn_demo(world, gfx);
+            Ok(())
+        });
+    }
+    let registry = Arc::new(registry);
+    Saves::user(registry.clone()).unwrap_or_else(|e| {
+        log::warn!("Saving in the working directory: {e:#}");
+        Saves::new("saves", registry)
+    })
+}
+
+/// The scene of the game when not benchmarking
+fn spawn_demo(world: &mut World, gfx: &mut GraphicContext) {
+    let gfc = sphere(gfx).unwrap();
+
+    {
+        // Smooth and metallic, to show off the reflections of the lights around it
+        let albedo = gfx.texture_manager.get_or_add_single_value_texture(
+            &gfx.device,
+            &gfx.queue,
+            SingleValue::Color(Vec4::new(1.0, 0.0, 0.0, 1.0))
+        );
+        let material = Material::new_with_values(albedo, None, 0.6, 0.1, None, gfx).unwrap();
+        let sphere = world.spawn((
+            GraphicsComponent { mesh: gfc.mesh, material },
+            TransformsComponent::new(),
+            StaticGeometryComponent::from_mesh(&Mesh::new_icosphere(3)),
+        ));
+
+        // Selection ring under it, and a waypoint above that stays the same size on screen
+        let ring = image::RgbaImage::from_fn(64, 64, |x, y| {
+            let d = Vec2::new(x as f32 - 31.5, y as f32 - 31.5).length() / 32.0;
+            let alpha = (1.0 - (d - 0.85).abs() * 12.0).clamp(0.0, 1.0);
+            Rgba([255, 255, 255, (alpha * 255.0) as u8])
+        });
+        let ring = gfx.texture_manager.add_image_texture(&gfx.device, &gfx.queue, image::DynamicImage::ImageRgba8(ring));
+        world.spawn((
+            SpriteComponent {
+                color: Vec4::new(0.2, 1.0, 0.3, 0.8),
+                ..SpriteComponent::new(ring, SpriteSize::World(Vec2::splat(2.5)))
+            },
+            WorldAnchorComponent { entity: sphere, offset: Vec3::new(0.0, -1.1, 0.0) },
+        ));
+        let mut tsm = TransformsComponent::new();
+        tsm.set_translation(Vec3::new(0.0, 2.5, 0.0));
+        world.spawn((
+            SpriteComponent {
+                color: Vec4::new(1.0, 0.8, 0.1, 1.0),
+                anchor: Vec2::new(0.5, 0.0),
+                sort_key: 1,
+                ..SpriteComponent::new(ring, SpriteSize::Pixels(Vec2::splat(32.0)))
+            },
+            tsm,
+        ));
+    }
+
+    {
+        // Paintable cube, see the paint window
+        let mesh = gfx.mesh_manager.add_with(&gfx.device, &Mesh::new_cube(), true);
+        let paintable = PaintableComponent::new(gfx, image::RgbaImage::from_pixel(256, 256, Rgba([200, 200, 200, 255])));
+        let material = Material::new_with_values(paintable.texture(), None, 0.0, 0.8, None, gfx).unwrap();
+        let mut tsm = TransformsComponent::new();
+        tsm.set_translation(Vec3::new(-2.0, 0.0, 0.0));
+        world.spawn((GraphicsComponent { mesh, material }, tsm, paintable, StaticGeometryComponent::from_mesh(&Mesh::new_cube())));
+    }
+
+    {
+        // A character to walk around with (see character::TOGGLE_KEY), on an invisible floor
+        let floor = [
+            Vec3::new(-20.0, -1.0, -20.0),
+            Vec3::new(-20.0, -1.0, 20.0),
+            Vec3::new(20.0, -1.0, 20.0),
+            Vec3::new(20.0, -1.0, -20.0),
+        ];
+        let triangles = vec![[floor[0], floor[1], floor[2]], [floor[0], floor[2], floor[3]]];
+        world.spawn((StaticGeometryComponent::new(triangles), TransformsComponent::new(), SurfaceMaterialComponent(SurfaceKind::Stone)));
+        let mut tsm = TransformsComponent::new();
+        tsm.set_translation(Vec3::new(0.0, -1.0, 4.0));
+        world.spawn((CharacterControllerComponent::new(0.3, 1.8), tsm));
+        // Birds over the floor, wind past its edges
+        let zones = [
+            (ZoneShape::Aabb { half_extents: Vec3::new(20.0, 10.0, 20.0) }, "birds_loop", 1),
+            (ZoneShape::Sphere { radius: 200.0 }, "wind_loop", 0),
+        ];
+        for (shape, clip, priority) in zones {
+            let mut zone = AudioZoneComponent::new(shape, clip.into());
+            zone.priority = priority;
+            world.spawn((zone, TransformsComponent::new()));
+        }
+    }
+
+    {
+        // Showcase camera (see character::TOGGLE_KEY) orbiting the sphere, its path is drawn
+        let orbit = (0..6)
+            .map(|i| {
+                let angle = i as f32 / 6.0 * 2.0 * PI;
+                Vec3::new(angle.cos() * 6.0, 1.5 + (2.0 * angle).sin(), angle.sin() * 6.0)
+            })
+            .collect();
+        let mut path = PathComponent::new(orbit, Interpolation::CatmullRom, true);
+        path.debug_draw = true;
+        let path = world.spawn((path,));
+        world.spawn((
+            ShowcaseCameraComponent,
+            PathFollowComponent {
+                look_at: Some(Vec3::ZERO),
+                ..PathFollowComponent::new(path, 1.5)
+            },
+            TransformsComponent::new(),
+        ));
+    }
+
+    // Rain and snow, moved and configured by the weather
+    for kind in [PrecipitationComponent::Rain, PrecipitationComponent::Snow] {
+        let emitter = ParticleEmitterComponent::new(EmitterParams::default());
+        world.spawn((kind, emitter, TransformsComponent::new()));
+    }
+
+    let mut colors = std::iter::empty()
+        .chain(std::iter::repeat(Vec4::new(7.0, 7.0, 7.0, 1.0)).take(8))
+        .chain(std::iter::repeat(Vec4::new(2.5, 5.0, 10.0, 1.0)).take(8))
+        .chain(std::iter::repeat(Vec4::new(15.0, 15.0, 15.0, 1.0)).take(4));
+    let lights = [
+        Vec3::new( 1.0,  1.0, 6.0),
+        Vec3::new(-1.0,  1.0, 6.0),
+        Vec3::new(-1.0, -1.0, 6.0),
+        Vec3::new( 1.0, -1.0, 6.0),
+        Vec3::new( 1.0,  4.0, 6.0),
+        Vec3::new(-1.0,  4.0, 6.0),
+        Vec3::new(-1.0,  2.0, 6.0),
+        Vec3::new( 1.0,  2.0, 6.0),
+
+        Vec3::new( 1.0,  1.0, -10.0),
+        Vec3::new(-1.0,  1.0, -10.0),
+        Vec3::new(-1.0, -1.0, -10.0),
+        Vec3::new( 1.0, -1.0, -10.0),
+        Vec3::new( 1.0,  4.0, -10.0),
+        Vec3::new(-1.0,  4.0, -10.0),
+        Vec3::new(-1.0,  2.0, -10.0),
+        Vec3::new( 1.0,  2.0, -10.0),
+
+        Vec3::new( 5.0, 0.0, 1.0),
+        Vec3::new(-5.0, 3.0, 1.0),
+        Vec3::new(-5.0, 0.0, 1.0),
+        Vec3::new( 5.0, 3.0, 1.0),
+    ];
+    for pos in lights {
+        let lc = colors.next().unwrap();
+        let mut tsm = TransformsComponent::new();
+        tsm.set_translation(pos);
+        tsm.set_scale(Vec3::splat(0.5));
+        world.spawn((
+            LightComponent::new(Light::Point(PointLight::new(pos, lc))),
+            tsm,
+            gfc,
+            MinimapMarkerComponent::new(lc / lc.max_element(), 0.5),
+        ));
+    }
+}
+
+/// Grab the cursor and (de)activate the UI navigation to match the router's mode
+fn apply_mode(executor: &mut Executor, window: &Window, router: &InputRouter) {
+    let grabbed = router.grabbed();
+    *executor.get_resource_mut::<Grabbed>().unwrap() = Grabbed(grabbed);
+    executor.get_resource_mut::<UiFocus>().unwrap().set_active(router.mode() == InputMode::Navigation);
+    window.set_cursor_visible(!grabbed);
+    if let Err(e) = window.set_cursor_grab(grabbed) {
+        log::warn!("Couldn't {} the cursor: {e}", if grabbed { "grab" } else { "release" });
+    }
+    if grabbed {
+        // Where it shows up again when released. Can't be moved on Wayland, where it's locked in
+        // place anyway.
+        let _ = window.set_cursor_position(executor.get_resource::<UiScale>().unwrap().center());
+    }
+}
+
 fn main() {
-    env_logger::init();
+    if std::env::args().any(|arg| arg == "--open-last-crash") {
+        match crash::last_crash(Path::new(crash::CRASH_DIRECTORY)) {
+            Some(path) => println!("{}", path.display()),
+            None => println!("No crash report in {}", crash::CRASH_DIRECTORY),
+        }
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--determinism-test") {
+        let test = determinism::DeterminismTest::default();
+        match test.run() {
+            Ok(hash) => println!("Deterministic over {} ticks (final state {hash:016x})", test.ticks),
+            Err(divergence) => {
+                eprintln!("{divergence}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    crash::init_logger();
+    crash::install_panic_hook();
+
+    if std::env::args().nth(1).as_deref() == Some("deps") {
+        let args = std::env::args().skip(2).collect::<Vec<_>>();
+        let code = match deps::DepsArgs::parse(&args) {
+            Ok(args) => deps::main(&args),
+            Err(e) => {
+                eprintln!("{e:#}");
+                2
+            }
+        };
+        std::process::exit(code);
+    }
+
+    let bench = (std::env::args().nth(1).as_deref() == Some("bench")).then(|| {
+        let args = std::env::args().skip(2).collect::<Vec<_>>();
+        let args = BenchArgs::parse(&args).unwrap_or_else(|e| {
+            eprintln!("{e:#}");
+            std::process::exit(2);
+        });
+        if let Some(code) = bench::main(&args) {
+            std::process::exit(code);
+        }
+        args
+    });
+
+    rmanage::init(rmanage::ResourceManagerBuilder::begin().with_cache(Some("sg"))).unwrap();
+    // Fails on the first start, without a cache to sync
+    if let Err(e) = rmanage::instance().sync_cache() {
+        log::warn!("Couldn't sync the resources cache: {e}");
+    }
 
     //let mut client = Client::new("127.0.0.1:50000").unwrap();
     //let _peer = Client::new("127.0.0.1:50001").unwrap();
@@ -426,5 +872,5 @@ fn main() {
 
     let world = World::new();
     let executor = Executor::new();
-    pollster::block_on(run(world, executor));
+    pollster::block_on(run(world, executor, bench));
 }
@@ -0,0 +1,1287 @@
+//! Saved games. A save is a directory (a slot) of `Saves::root`, holding:
+//! - `manifest.json`: versions of the format and of every saved type, the scene and the date
+//! - `world.bin`: a `WorldSnapshot` of the components registered in the `SaveRegistry`
+//! - `resources.json`: the registered resources (see `SaveableResource`)
+//! - `thumbnail.png`: a small screenshot, when the save was made from the menu
+//!
+//! A slot is written in a temporary directory and swapped with the previous one by renames, a
+//! failed save leaves the previous one as it was.
+//!
+//! Loading builds a fresh world: the scene of the registry is spawned first (scenes spawn the same
+//! entities every time), then the snapshot is applied on top of it, updating the entities of the
+//! scene in place so they keep what can't be saved (meshes, materials). A `GameLoaded` event is
+//! sent afterwards, for the systems holding state derived from the previous world.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use directories::BaseDirs;
+use ecs::{
+    Component, ComponentRegistry, Entity, EntityMap, Events, Executor, World, WorldSnapshot,
+};
+use glam::{Quat, Vec3};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    components::{TransformsComponent, VelocityComponent},
+    localization::Localization,
+    systems::{
+        graphics::{focus::UiFocus, renderer::WorldRenderer, GraphicContext},
+        navmesh::NavAgentComponent,
+        path::{Interpolation, PathComponent, PathFollowComponent},
+        time::Time,
+    },
+    tr,
+};
+
+/// Version of the layout of a slot, bumped on any breaking change
+pub const SAVE_FORMAT: u32 = 2;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const WORLD_FILE: &str = "world.bin";
+const RESOURCES_FILE: &str = "resources.json";
+const THUMBNAIL_FILE: &str = "thumbnail.png";
+/// Size the thumbnails are scaled down to fit in
+const THUMBNAIL_SIZE: (u32, u32) = (320, 180);
+
+/// A resource saved with the game, under a stable name
+pub trait SaveableResource: Send + 'static {
+    /// Name in resources.json
+    const NAME: &'static str;
+    /// Bumped when the saved data changes in an incompatible way
+    const VERSION: u32;
+    fn save(&self) -> Value;
+    fn load(&mut self, value: Value) -> Result<()>;
+}
+
+struct ResourceEntry {
+    version: u32,
+    save: fn(&Executor) -> Option<Value>,
+    load: fn(&mut Executor, Value) -> Result<()>,
+}
+
+type SpawnScene = Box<dyn Fn(&mut World, &mut Executor) -> Result<()> + Send + Sync>;
+
+/// The scene every saved world was spawned from
+struct Scene {
+    name: String,
+    spawn: SpawnScene,
+}
+
+/// What goes in a save, and the versions of it
+#[derive(Default)]
+pub struct SaveRegistry {
+    components: ComponentRegistry,
+    component_versions: BTreeMap<String, u32>,
+    resources: BTreeMap<&'static str, ResourceEntry>,
+    scene: Option<Scene>,
+}
+
+impl SaveRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Save a component under a name, see `ComponentRegistry::register`
+    pub fn register_component<T: Component>(
+        &mut self,
+        name: &str,
+        version: u32,
+        serialize: fn(&T) -> Vec<u8>,
+        deserialize: fn(&[u8]) -> Option<T>,
+    ) -> &mut Self {
+        self.register_component_mapped(name, version, serialize, deserialize, |_, _| {})
+    }
+    /// Save a component holding entities, see `ComponentRegistry::register_mapped`
+    pub fn register_component_mapped<T: Component>(
+        &mut self,
+        name: &str,
+        version: u32,
+        serialize: fn(&T) -> Vec<u8>,
+        deserialize: fn(&[u8]) -> Option<T>,
+        map_entities: fn(&mut T, &EntityMap),
+    ) -> &mut Self {
+        self.components
+            .register_mapped(name, serialize, deserialize, map_entities);
+        self.component_versions.insert(name.to_owned(), version);
+        self
+    }
+    /// Save a resource of the executor, it is restored in place so it has to exist when loading
+    pub fn register_resource<R: SaveableResource>(&mut self) -> &mut Self {
+        self.resources.insert(
+            R::NAME,
+            ResourceEntry {
+                version: R::VERSION,
+                save: |executor| executor.get_resource::<R>().map(R::save),
+                load: |executor, value| match executor.get_resource_mut::<R>() {
+                    Some(resource) => resource.load(value),
+                    None => bail!("not in the executor"),
+                },
+            },
+        );
+        self
+    }
+    /// Spawn this scene in the worlds of the loaded games, before the saved entities. The game
+    /// must spawn it first in its own worlds too, for the entities to match.
+    pub fn set_scene(
+        &mut self,
+        name: &str,
+        spawn: impl Fn(&mut World, &mut Executor) -> Result<()> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.scene = Some(Scene {
+            name: name.to_owned(),
+            spawn: Box::new(spawn),
+        });
+        self
+    }
+    fn scene_name(&self) -> Option<&str> {
+        self.scene.as_ref().map(|scene| scene.name.as_str())
+    }
+    /// Everything in the manifest this registry can't load
+    fn check(&self, manifest: &Manifest) -> Result<(), Incompatible> {
+        fn check<'a>(
+            kind: TypeKind,
+            saved: &'a BTreeMap<String, u32>,
+            current: impl Fn(&str) -> Option<u32> + 'a,
+        ) -> impl Iterator<Item = IncompatibleType> + 'a {
+            saved.iter().filter_map(move |(name, &saved)| {
+                let current = current(name);
+                (current != Some(saved)).then(|| IncompatibleType {
+                    name: name.clone(),
+                    kind,
+                    saved,
+                    current,
+                })
+            })
+        }
+        let components = check(TypeKind::Component, &manifest.components, |name| {
+            self.component_versions.get(name).copied()
+        });
+        let resources = check(TypeKind::Resource, &manifest.resources, |name| {
+            self.resources.get(name).map(|entry| entry.version)
+        });
+        let types = components.chain(resources).collect::<Vec<_>>();
+        if types.is_empty() {
+            Ok(())
+        } else {
+            Err(Incompatible {
+                format: None,
+                types,
+            })
+        }
+    }
+}
+
+fn json(value: impl Serialize) -> Vec<u8> {
+    serde_json::to_vec(&value).expect("Couldn't serialize a component")
+}
+
+fn from_json<T: DeserializeOwned>(bytes: &[u8]) -> Option<T> {
+    serde_json::from_slice(bytes).ok()
+}
+
+#[derive(Serialize, Deserialize)]
+struct TransformsData {
+    translation: [f32; 3],
+    scale: [f32; 3],
+    rotation: [f32; 4],
+}
+
+#[derive(Serialize, Deserialize)]
+struct PathData {
+    points: Vec<[f32; 3]>,
+    interpolation: Interpolation,
+    looped: bool,
+    /// Added after version 1, absent from older saves
+    #[serde(default)]
+    debug_draw: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PathFollowData {
+    /// `Entity::to_bits` in the saved world
+    path: u64,
+    t: f32,
+    speed: f32,
+    orient_to_tangent: bool,
+    /// Added after version 1, absent from older saves
+    #[serde(default)]
+    look_at: Option<[f32; 3]>,
+    playing: bool,
+}
+
+/// The path is planned again after loading
+#[derive(Serialize, Deserialize)]
+struct NavAgentData {
+    target: Option<[f32; 3]>,
+    speed: f32,
+    replan_distance: f32,
+    arrival_distance: f32,
+}
+
+/// What the game saves
+pub fn registry() -> SaveRegistry {
+    let mut registry = SaveRegistry::new();
+    registry
+        .register_component::<TransformsComponent>(
+            "transforms",
+            1,
+            |t| {
+                json(TransformsData {
+                    translation: t.translation().to_array(),
+                    scale: t.scale().to_array(),
+                    rotation: t.rotation().to_array(),
+                })
+            },
+            |bytes| {
+                let data: TransformsData = from_json(bytes)?;
+                let mut transforms = TransformsComponent::new();
+                transforms
+                    .set_translation(Vec3::from(data.translation))
+                    .set_scale(Vec3::from(data.scale))
+                    .set_rotation(Quat::from_array(data.rotation));
+                Some(transforms)
+            },
+        )
+        .register_component::<VelocityComponent>(
+            "velocity",
+            1,
+            |v| json(v.linear.to_array()),
+            |bytes| {
+                Some(VelocityComponent {
+                    linear: Vec3::from(from_json::<[f32; 3]>(bytes)?),
+                })
+            },
+        )
+        .register_component::<PathComponent>(
+            "path",
+            1,
+            |p| {
+                json(PathData {
+                    points: p.points().iter().map(|p| p.to_array()).collect(),
+                    interpolation: p.interpolation(),
+                    looped: p.looped(),
+                    debug_draw: p.debug_draw,
+                })
+            },
+            |bytes| {
+                let data: PathData = from_json(bytes)?;
+                let points = data.points.into_iter().map(Vec3::from).collect();
+                let mut path = PathComponent::new(points, data.interpolation, data.looped);
+                path.debug_draw = data.debug_draw;
+                Some(path)
+            },
+        )
+        .register_component_mapped::<PathFollowComponent>(
+            "path_follow",
+            1,
+            |f| {
+                json(PathFollowData {
+                    path: f.path.to_bits(),
+                    t: f.t,
+                    speed: f.speed,
+                    orient_to_tangent: f.orient_to_tangent,
+                    look_at: f.look_at.map(|p| p.to_array()),
+                    playing: f.playing,
+                })
+            },
+            |bytes| {
+                let data: PathFollowData = from_json(bytes)?;
+                Some(PathFollowComponent {
+                    path: Entity::from_bits(data.path),
+                    t: data.t,
+                    speed: data.speed,
+                    orient_to_tangent: data.orient_to_tangent,
+                    look_at: data.look_at.map(Vec3::from),
+                    playing: data.playing,
+                })
+            },
+            |f, map| f.path = map.get(f.path).unwrap_or_default(),
+        )
+        .register_component::<NavAgentComponent>(
+            "nav_agent",
+            1,
+            |a| {
+                json(NavAgentData {
+                    target: a.target.map(|t| t.to_array()),
+                    speed: a.speed,
+                    replan_distance: a.replan_distance,
+                    arrival_distance: a.arrival_distance,
+                })
+            },
+            |bytes| {
+                let data: NavAgentData = from_json(bytes)?;
+                let mut agent = NavAgentComponent::new(data.speed);
+                agent.target = data.target.map(Vec3::from);
+                agent.replan_distance = data.replan_distance;
+                agent.arrival_distance = data.arrival_distance;
+                Some(agent)
+            },
+        )
+        .register_resource::<Time>()
+        .register_resource::<WorldRenderer>();
+    registry
+}
+
+impl SaveableResource for Time {
+    const NAME: &'static str = "clock";
+    const VERSION: u32 = 1;
+    fn save(&self) -> Value {
+        serde_json::json!({
+            "elapsed": self.elapsed().as_secs_f64(),
+            "frame": self.frame(),
+        })
+    }
+    fn load(&mut self, value: Value) -> Result<()> {
+        #[derive(Deserialize)]
+        struct Clock {
+            elapsed: f64,
+            frame: u64,
+        }
+        let clock: Clock = serde_json::from_value(value)?;
+        let elapsed = Duration::try_from_secs_f64(clock.elapsed)?;
+        self.restore(elapsed, clock.frame);
+        Ok(())
+    }
+}
+
+/// The camera
+impl SaveableResource for WorldRenderer {
+    const NAME: &'static str = "camera";
+    const VERSION: u32 = 1;
+    fn save(&self) -> Value {
+        serde_json::json!({
+            "position": self.camera.get_position().to_array(),
+            "rotation": self.camera.get_rotation().to_array(),
+        })
+    }
+    fn load(&mut self, value: Value) -> Result<()> {
+        #[derive(Deserialize)]
+        struct Camera {
+            position: [f32; 3],
+            rotation: [f32; 4],
+        }
+        let camera: Camera = serde_json::from_value(value)?;
+        self.camera.set_position(Vec3::from(camera.position));
+        self.camera.set_rotation(Quat::from_array(camera.rotation));
+        Ok(())
+    }
+}
+
+/// Describes a save, written last so a slot without one is incomplete
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    /// Version of the game that made the save
+    pub engine: String,
+    /// Unix time of the save, in seconds
+    pub timestamp: u64,
+    /// The scene the saved world was spawned from (see `SaveRegistry::set_scene`)
+    pub scene: Option<String>,
+    /// Versions of the saved components
+    pub components: BTreeMap<String, u32>,
+    /// Versions of the saved resources
+    pub resources: BTreeMap<String, u32>,
+    pub thumbnail: bool,
+}
+
+impl Manifest {
+    fn read(slot: &Path) -> Result<Self> {
+        let path = slot.join(MANIFEST_FILE);
+        let bytes = fs::read(&path).with_context(|| format!("can't read {}", path.display()))?;
+        let value: Value = serde_json::from_slice(&bytes)
+            .with_context(|| format!("{} isn't a manifest", path.display()))?;
+        // Checked first, the rest may not parse in other formats
+        match value.get("format").and_then(Value::as_u64) {
+            Some(format) if format == SAVE_FORMAT as u64 => {}
+            format => {
+                return Err(Incompatible {
+                    format: Some(format.unwrap_or(0) as u32),
+                    types: Vec::new(),
+                }
+                .into())
+            }
+        }
+        serde_json::from_value(value)
+            .with_context(|| format!("{} isn't a manifest", path.display()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypeKind {
+    Component,
+    Resource,
+}
+
+/// A saved type that isn't the version the game has
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncompatibleType {
+    pub name: String,
+    pub kind: TypeKind,
+    /// Version in the save
+    pub saved: u32,
+    /// Version of the game, None if it doesn't save this type anymore
+    pub current: Option<u32>,
+}
+
+/// Why a save can't be loaded by this version of the game
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Incompatible {
+    /// Format of the save, if it isn't `SAVE_FORMAT` (the types aren't checked then)
+    pub format: Option<u32>,
+    pub types: Vec<IncompatibleType>,
+}
+
+impl Display for Incompatible {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(format) = self.format {
+            return write!(
+                f,
+                "save format {format} isn't supported (expected {SAVE_FORMAT})"
+            );
+        }
+        write!(f, "save made with incompatible types:")?;
+        for ty in &self.types {
+            let kind = match ty.kind {
+                TypeKind::Component => "component",
+                TypeKind::Resource => "resource",
+            };
+            match ty.current {
+                Some(current) => write!(
+                    f,
+                    "\n  {kind} {}: version {} (expected {current})",
+                    ty.name, ty.saved
+                )?,
+                None => write!(f, "\n  {kind} {}: unknown", ty.name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Incompatible {}
+
+/// Sent after a game is loaded, for the systems holding state derived from the world to reset it
+/// (`EventReader<GameLoaded>`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameLoaded {
+    /// The slot loaded
+    pub slot: String,
+}
+
+/// A slot of `Saves::list`
+#[derive(Debug, Clone)]
+pub struct SlotInfo {
+    pub name: String,
+    pub path: PathBuf,
+    /// The manifest, or why it couldn't be read
+    pub manifest: Result<Manifest, String>,
+}
+
+impl SlotInfo {
+    pub fn thumbnail(&self) -> Option<PathBuf> {
+        let manifest = self.manifest.as_ref().ok()?;
+        manifest.thumbnail.then(|| self.path.join(THUMBNAIL_FILE))
+    }
+}
+
+/// The slots of a directory
+#[derive(Clone)]
+pub struct Saves {
+    root: PathBuf,
+    registry: Arc<SaveRegistry>,
+}
+
+impl Saves {
+    pub fn new(root: impl Into<PathBuf>, registry: Arc<SaveRegistry>) -> Self {
+        Self {
+            root: root.into(),
+            registry,
+        }
+    }
+    /// The saves in the user's data directory
+    pub fn user(registry: Arc<SaveRegistry>) -> Result<Self> {
+        let dirs = BaseDirs::new().context("no home directory")?;
+        Ok(Self::new(
+            dirs.data_dir().join("sg").join("saves"),
+            registry,
+        ))
+    }
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+    /// Where the menu captures the screenshot of the next save
+    pub fn thumbnail_path(&self) -> PathBuf {
+        self.root.join(".thumbnail.png")
+    }
+    fn slot_path(&self, slot: &str) -> Result<PathBuf> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if slot.is_empty() || slot.len() > 64 || !slot.chars().all(valid) {
+            bail!("invalid slot name '{slot}' (letters, digits, - and _ only)");
+        }
+        Ok(self.root.join(slot))
+    }
+    fn temporary_path(&self, slot: &str) -> PathBuf {
+        self.root.join(format!(".{slot}.tmp"))
+    }
+    fn old_path(&self, slot: &str) -> PathBuf {
+        self.root.join(format!(".{slot}.old"))
+    }
+    /// Put back the previous version of a slot if a save stopped between the two renames
+    fn recover(&self, slot: &str) {
+        let (path, old) = (self.root.join(slot), self.old_path(slot));
+        if !path.exists() && old.exists() {
+            if let Err(e) = fs::rename(&old, &path) {
+                log::warn!("Couldn't recover the save {slot}: {e}");
+            }
+        }
+    }
+    /// Write a slot with write (given the directory to write to), replacing the previous version
+    /// only if it succeeds
+    fn write_slot(&self, slot: &str, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+        let path = self.slot_path(slot)?;
+        fs::create_dir_all(&self.root)
+            .with_context(|| format!("can't create {}", self.root.display()))?;
+        self.recover(slot);
+        let (temporary, old) = (self.temporary_path(slot), self.old_path(slot));
+        if temporary.exists() {
+            fs::remove_dir_all(&temporary)?;
+        }
+        fs::create_dir(&temporary)
+            .with_context(|| format!("can't create {}", temporary.display()))?;
+        if let Err(e) = write(&temporary) {
+            let _ = fs::remove_dir_all(&temporary);
+            return Err(e);
+        }
+        if old.exists() {
+            fs::remove_dir_all(&old)?;
+        }
+        if path.exists() {
+            fs::rename(&path, &old).with_context(|| format!("can't move {}", path.display()))?;
+        }
+        if let Err(e) = fs::rename(&temporary, &path) {
+            self.recover(slot);
+            return Err(e).with_context(|| format!("can't move the save to {}", path.display()));
+        }
+        let _ = fs::remove_dir_all(&old);
+        Ok(())
+    }
+    /// Save the world and the registered resources of the executor in a slot, with a thumbnail
+    /// made from a screenshot
+    pub fn save(
+        &self,
+        slot: &str,
+        world: &World,
+        executor: &Executor,
+        thumbnail: Option<&Path>,
+    ) -> Result<()> {
+        let registry = &*self.registry;
+        let snapshot = world.snapshot_with(&registry.components);
+        let resources = registry
+            .resources
+            .iter()
+            .filter_map(|(name, entry)| Some((*name, (entry.save)(executor)?)))
+            .collect::<BTreeMap<_, _>>();
+        let thumbnail = thumbnail
+            .map(|path| -> Result<_> {
+                let image = image::open(path)
+                    .with_context(|| format!("can't open the thumbnail {}", path.display()))?;
+                Ok(image.thumbnail(THUMBNAIL_SIZE.0, THUMBNAIL_SIZE.1))
+            })
+            .transpose()?;
+        let manifest = Manifest {
+            format: SAVE_FORMAT,
+            engine: env!("CARGO_PKG_VERSION").to_owned(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            scene: registry.scene_name().map(str::to_owned),
+            components: registry.component_versions.clone(),
+            resources: resources
+                .keys()
+                .map(|name| (name.to_string(), registry.resources[name].version))
+                .collect(),
+            thumbnail: thumbnail.is_some(),
+        };
+        self.write_slot(slot, |dir| {
+            write_file(&dir.join(WORLD_FILE), &snapshot.to_bytes())?;
+            write_file(&dir.join(RESOURCES_FILE), &serde_json::to_vec(&resources)?)?;
+            if let Some(thumbnail) = thumbnail {
+                thumbnail
+                    .save_with_format(dir.join(THUMBNAIL_FILE), image::ImageFormat::Png)
+                    .context("can't save the thumbnail")?;
+            }
+            write_file(
+                &dir.join(MANIFEST_FILE),
+                &serde_json::to_vec_pretty(&manifest)?,
+            )
+        })
+        .with_context(|| format!("can't save {slot}"))
+    }
+    /// Load a slot in a new world, restoring the saved resources of the executor. Saves made with
+    /// other versions of the saved types fail with `Incompatible`.
+    pub fn load(&self, slot: &str, executor: &mut Executor) -> Result<World> {
+        let path = self.slot_path(slot)?;
+        self.recover(slot);
+        let registry = &*self.registry;
+        let manifest = Manifest::read(&path)?;
+        registry.check(&manifest)?;
+        if manifest.scene.as_deref() != registry.scene_name() {
+            bail!(
+                "{slot} was saved in the scene {:?}, not {:?}",
+                manifest.scene,
+                registry.scene_name()
+            );
+        }
+        let bytes = fs::read(path.join(WORLD_FILE)).context("can't read the world")?;
+        let snapshot = WorldSnapshot::from_bytes(&bytes).context("can't read the world")?;
+        let bytes = fs::read(path.join(RESOURCES_FILE)).context("can't read the resources")?;
+        let resources: BTreeMap<String, Value> =
+            serde_json::from_slice(&bytes).context("can't read the resources")?;
+
+        let mut world = World::new();
+        let mut map = EntityMap::new();
+        if let Some(scene) = &registry.scene {
+            (scene.spawn)(&mut world, executor)
+                .with_context(|| format!("can't spawn the scene {}", scene.name))?;
+        }
+        let scene = world.query::<Entity>().collect::<Vec<_>>();
+        for &entity in &scene {
+            map.insert(entity, entity);
+        }
+        world
+            .restore_snapshot(&snapshot, &registry.components, &mut map)
+            .context("can't restore the world")?;
+        // Despawned before the save
+        let saved = snapshot
+            .entities
+            .iter()
+            .map(|e| e.entity)
+            .collect::<HashSet<_>>();
+        for entity in scene {
+            if !saved.contains(&entity.to_bits()) {
+                world.remove(entity);
+            }
+        }
+
+        for (name, value) in resources {
+            // Every saved resource is registered, see check
+            let entry = &registry.resources[name.as_str()];
+            (entry.load)(executor, value).with_context(|| format!("can't restore {name}"))?;
+        }
+        if executor.get_resource::<Events<GameLoaded>>().is_none() {
+            executor.add_events::<GameLoaded>();
+        }
+        let events = executor.get_resource_mut::<Events<GameLoaded>>().unwrap();
+        events.send(GameLoaded {
+            slot: slot.to_owned(),
+        });
+        Ok(world)
+    }
+    /// The slots, newest first. Slots that can't be read are listed last, with the error.
+    pub fn list(&self) -> Vec<SlotInfo> {
+        // Interrupted saves, listed once recovered
+        for name in directories(&self.root) {
+            if let Some(slot) = name.strip_prefix('.').and_then(|n| n.strip_suffix(".old")) {
+                self.recover(slot);
+            }
+        }
+        let mut slots = directories(&self.root)
+            .into_iter()
+            .filter(|name| !name.starts_with('.'))
+            .map(|name| {
+                let path = self.root.join(&name);
+                let manifest = Manifest::read(&path).map_err(|e| format!("{e:#}"));
+                SlotInfo {
+                    name,
+                    path,
+                    manifest,
+                }
+            })
+            .collect::<Vec<_>>();
+        slots.sort_by_key(|slot| {
+            let timestamp = slot.manifest.as_ref().ok().map(|m| m.timestamp);
+            (std::cmp::Reverse(timestamp), slot.name.clone())
+        });
+        slots
+    }
+}
+
+/// Names of the directories in root
+fn directories(root: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(root) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            entry.file_type().ok()?.is_dir().then_some(name)
+        })
+        .collect()
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut file =
+        fs::File::create(path).with_context(|| format!("can't create {}", path.display()))?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Save the game in a slot of the `Saves` of the executor
+pub fn save_game(slot: &str, world: &World, executor: &Executor) -> Result<()> {
+    let saves = executor.get_resource::<Saves>().context("no saves")?;
+    saves.save(slot, world, executor, None)
+}
+
+/// Load a slot of the `Saves` of the executor, see `Saves::load`
+pub fn load_game(slot: &str, executor: &mut Executor) -> Result<World> {
+    let saves = executor
+        .get_resource::<Saves>()
+        .context("no saves")?
+        .clone();
+    saves.load(slot, executor)
+}
+
+/// "2022-06-01 12:30", in UTC
+fn format_timestamp(timestamp: u64) -> String {
+    // Days to civil date, from Howard Hinnant's algorithms
+    let (days, seconds) = ((timestamp / 86400) as i64, timestamp % 86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60
+    )
+}
+
+enum Action {
+    /// Take the screenshot of the thumbnail, then save
+    Capture(String),
+    Save(String),
+    Load(String),
+}
+
+enum Status {
+    Saved(String),
+    Loaded(String),
+    Failed(String),
+}
+
+/// The save and load window. Actions happen between two frames, in `SaveMenu::run_pending`.
+pub struct SaveMenu {
+    saves: Saves,
+    /// Name of the next save
+    name: String,
+    pending: Option<Action>,
+    /// Listed again after every action
+    slots: Option<Vec<SlotInfo>>,
+    thumbnails: HashMap<String, egui::TextureHandle>,
+    status: Option<Status>,
+}
+
+impl SaveMenu {
+    pub fn new(saves: Saves) -> Self {
+        Self {
+            saves,
+            name: "quicksave".to_owned(),
+            pending: None,
+            slots: None,
+            thumbnails: HashMap::new(),
+            status: None,
+        }
+    }
+    /// Run the action chosen in the menu, a load replaces world. Called between two executions
+    /// of the schedule.
+    pub fn run_pending(executor: &mut Executor, world: &mut World) {
+        let Some(menu) = executor.get_resource_mut::<SaveMenu>() else {
+            return;
+        };
+        let Some(action) = menu.pending.take() else {
+            return;
+        };
+        let saves = menu.saves.clone();
+        let status = match action {
+            Action::Capture(slot) => {
+                let thumbnail = saves.thumbnail_path();
+                let _ = fs::create_dir_all(saves.root());
+                let _ = fs::remove_file(&thumbnail);
+                if let Some(gfx) = executor.get_resource_mut::<GraphicContext>() {
+                    gfx.request_screenshot(thumbnail);
+                }
+                // Once the next frame is rendered
+                executor.get_resource_mut::<SaveMenu>().unwrap().pending = Some(Action::Save(slot));
+                return;
+            }
+            Action::Save(slot) => {
+                let thumbnail = saves.thumbnail_path();
+                let thumbnail = thumbnail.exists().then_some(thumbnail.as_path());
+                match saves.save(&slot, world, executor, thumbnail) {
+                    Ok(()) => Status::Saved(slot),
+                    Err(e) => Status::Failed(format!("{e:#}")),
+                }
+            }
+            Action::Load(slot) => match saves.load(&slot, executor) {
+                Ok(loaded) => {
+                    *world = loaded;
+                    Status::Loaded(slot)
+                }
+                Err(e) => Status::Failed(format!("{e:#}")),
+            },
+        };
+        if let Status::Failed(error) = &status {
+            log::error!("{error}");
+        }
+        let menu = executor.get_resource_mut::<SaveMenu>().unwrap();
+        menu.status = Some(status);
+        menu.slots = None;
+        menu.thumbnails.clear();
+    }
+    fn thumbnail(&mut self, ctx: &egui::Context, slot: &SlotInfo) -> Option<egui::TextureId> {
+        if let Some(texture) = self.thumbnails.get(&slot.name) {
+            return Some(texture.id());
+        }
+        let image = image::open(slot.thumbnail()?).ok()?.to_rgba8();
+        let size = [image.width() as usize, image.height() as usize];
+        let image = egui::ColorImage::from_rgba_unmultiplied(size, image.as_raw());
+        let texture = ctx.load_texture(
+            format!("save thumbnail {}", slot.name),
+            image,
+            egui::TextureFilter::Linear,
+        );
+        let id = texture.id();
+        self.thumbnails.insert(slot.name.clone(), texture);
+        Some(id)
+    }
+    pub(crate) fn ui(&mut self, ctx: &egui::Context, focus: &mut UiFocus, loc: &mut Localization) {
+        focus.begin_panel("saves");
+        egui::Window::new(tr!(loc, "saves.window")).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                focus.track(ui.text_edit_singleline(&mut self.name));
+                if focus.track(ui.button(tr!(loc, "saves.save"))).clicked() {
+                    self.pending = Some(Action::Capture(self.name.trim().to_owned()));
+                }
+            });
+            match &self.status {
+                Some(Status::Saved(slot)) => {
+                    ui.label(tr!(loc, "saves.saved", slot = slot));
+                }
+                Some(Status::Loaded(slot)) => {
+                    ui.label(tr!(loc, "saves.loaded", slot = slot));
+                }
+                Some(Status::Failed(error)) => {
+                    ui.colored_label(egui::Color32::LIGHT_RED, tr!(loc, "saves.failed", error = error));
+                }
+                None => {}
+            }
+            ui.separator();
+
+            let slots = self.slots.get_or_insert_with(|| self.saves.list()).clone();
+            if slots.is_empty() {
+                ui.label(tr!(loc, "saves.empty"));
+            }
+            egui::ScrollArea::vertical()
+                .max_height(400.0)
+                .show(ui, |ui| {
+                    for slot in &slots {
+                        ui.horizontal(|ui| {
+                            let size = egui::vec2(
+                                THUMBNAIL_SIZE.0 as f32 / 2.0,
+                                THUMBNAIL_SIZE.1 as f32 / 2.0,
+                            );
+                            match self.thumbnail(ctx, slot) {
+                                Some(texture) => {
+                                    ui.image(texture, size);
+                                }
+                                None => {
+                                    ui.allocate_space(size);
+                                }
+                            }
+                            ui.vertical(|ui| {
+                                ui.strong(&slot.name);
+                                match &slot.manifest {
+                                    Ok(manifest) => {
+                                        let time = format_timestamp(manifest.timestamp);
+                                        ui.label(tr!(loc, "saves.timestamp", time = time));
+                                        if focus.track(ui.button(tr!(loc, "saves.load"))).clicked()
+                                        {
+                                            self.pending = Some(Action::Load(slot.name.clone()));
+                                        }
+                                    }
+                                    Err(error) => {
+                                        ui.colored_label(
+                                            egui::Color32::LIGHT_RED,
+                                            tr!(loc, "saves.corrupt"),
+                                        )
+                                        .on_hover_text(error);
+                                    }
+                                }
+                            });
+                        });
+                    }
+                });
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+    };
+
+    use ecs::EventReader;
+
+    use super::*;
+
+    /// A temporary directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("sg-saves-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Stands in for the move history of a game
+    #[derive(Debug, Default, Clone, PartialEq)]
+    struct MoveLog(Vec<String>);
+
+    impl SaveableResource for MoveLog {
+        const NAME: &'static str = "moves";
+        const VERSION: u32 = 3;
+        fn save(&self) -> Value {
+            serde_json::to_value(&self.0).unwrap()
+        }
+        fn load(&mut self, value: Value) -> Result<()> {
+            self.0 = serde_json::from_value(value)?;
+            Ok(())
+        }
+    }
+
+    fn saves(dir: &TempDir) -> Saves {
+        let mut registry = registry();
+        registry.register_resource::<MoveLog>();
+        Saves::new(&dir.0, Arc::new(registry))
+    }
+
+    fn executor() -> Executor {
+        let mut executor = Executor::new();
+        executor.add_resource(Time::fixed(Duration::from_millis(10)));
+        executor.add_resource(MoveLog::default());
+        executor
+    }
+
+    /// Hash of the saved state of a world, independent of the entity ids: followers are hashed
+    /// with the points of their path
+    fn world_hash(world: &World) -> u64 {
+        let paths = world
+            .query::<(Entity, &PathComponent)>()
+            .map(|(e, p)| (e, format!("{:?}", p.points())))
+            .collect::<HashMap<_, _>>();
+        let mut entities = world
+            .query::<(
+                Option<&TransformsComponent>,
+                Option<&VelocityComponent>,
+                Option<&PathComponent>,
+                Option<&PathFollowComponent>,
+            )>()
+            .map(|(transforms, velocity, path, follow)| {
+                format!(
+                    "{:?} {:?} {:?} {:?}",
+                    transforms.map(|t| t.mat()),
+                    velocity,
+                    path.map(|p| (p.points().to_vec(), p.interpolation(), p.looped())),
+                    follow.map(|f| (&paths[&f.path], f.t, f.speed, f.playing)),
+                )
+            })
+            .collect::<Vec<_>>();
+        entities.sort();
+        let mut hasher = DefaultHasher::new();
+        entities.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// A few ticks of a scripted game
+    fn game() -> (World, Executor) {
+        let mut world = World::new();
+        let mut executor = executor();
+        let schedule = executor
+            .schedule()
+            .then(Time::update)
+            .then(crate::systems::path::follow_paths)
+            .build();
+        executor.add_resource(crate::systems::path::PathEvents::new());
+        // Despawned entities, so the saved ids have gaps
+        let gaps = world.spawn_many((0..3).map(|_| (VelocityComponent::default(),)));
+        let path = world.spawn((PathComponent::new(
+            vec![Vec3::ZERO, Vec3::X, Vec3::new(1.0, 0.0, 1.0)],
+            Interpolation::CatmullRom,
+            true,
+        ),));
+        for entity in gaps {
+            world.remove(entity);
+        }
+        for i in 0..4 {
+            let mut transforms = TransformsComponent::new();
+            transforms.set_translation(Vec3::splat(i as f32));
+            world.spawn((
+                transforms,
+                PathFollowComponent::new(path, 0.5 + i as f32),
+                VelocityComponent {
+                    linear: Vec3::Y * i as f32,
+                },
+            ));
+        }
+        for tick in 0..20 {
+            executor.execute(&schedule, &mut world);
+            executor
+                .get_resource_mut::<MoveLog>()
+                .unwrap()
+                .0
+                .push(format!("move {tick}"));
+        }
+        (world, executor)
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new();
+        let saves = saves(&dir);
+        let (world, exe) = game();
+        saves.save("slot_1", &world, &exe, None).unwrap();
+
+        let mut loaded_executor = executor();
+        let loaded = saves.load("slot_1", &mut loaded_executor).unwrap();
+        assert_eq!(world_hash(&world), world_hash(&loaded));
+        assert_eq!(world.stats().entities, loaded.stats().entities);
+        assert_eq!(
+            exe.get_resource::<MoveLog>(),
+            loaded_executor.get_resource::<MoveLog>()
+        );
+        let (time, loaded_time) = (
+            exe.get_resource::<Time>().unwrap(),
+            loaded_executor.get_resource::<Time>().unwrap(),
+        );
+        assert_eq!(time.frame(), loaded_time.frame());
+        assert_eq!(time.elapsed(), loaded_time.elapsed());
+        let loaded_events = loaded_executor
+            .get_resource::<Events<GameLoaded>>()
+            .unwrap()
+            .iter()
+            .map(|event| event.slot.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["slot_1"], loaded_events);
+
+        // Saving the loaded game gives the same world again
+        saves
+            .save("slot_2", &loaded, &loaded_executor, None)
+            .unwrap();
+        let reloaded = saves.load("slot_2", &mut executor()).unwrap();
+        assert_eq!(world_hash(&world), world_hash(&reloaded));
+    }
+
+    #[test]
+    fn scene() {
+        let dir = TempDir::new();
+        let spawn = |world: &mut World, _: &mut Executor| {
+            // Stands in for a mesh that can't be saved
+            world.spawn((TransformsComponent::new(), 7u32));
+            world.spawn((TransformsComponent::new(), 8u32));
+            Ok(())
+        };
+        let mut registry = registry();
+        registry.set_scene("test", spawn);
+        let saves = Saves::new(&dir.0, Arc::new(registry));
+
+        let mut world = World::new();
+        let mut exe = executor();
+        spawn(&mut world, &mut exe).unwrap();
+        let scene = world.query::<Entity>().collect::<Vec<_>>();
+        world.remove(scene[0]);
+        for transforms in world.query::<&mut TransformsComponent>() {
+            transforms.set_translation(Vec3::ONE);
+        }
+        world.spawn((TransformsComponent::new(),));
+        saves.save("scene", &world, &exe, None).unwrap();
+
+        let loaded = saves.load("scene", &mut exe).unwrap();
+        assert_eq!(world_hash(&world), world_hash(&loaded));
+        // The scene entity kept what wasn't saved
+        let kept = loaded
+            .query::<(&u32, &TransformsComponent)>()
+            .map(|(tag, t)| (*tag, t.translation()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(8, Vec3::ONE)], kept);
+
+        // Other scenes can't load it
+        let other = Saves::new(&dir.0, Arc::new(super::registry()));
+        assert!(other.load("scene", &mut exe).is_err());
+    }
+
+    #[test]
+    fn atomic_write() {
+        let dir = TempDir::new();
+        let saves = saves(&dir);
+        let (world, exe) = game();
+        saves.save("slot", &world, &exe, None).unwrap();
+        let manifest = fs::read(dir.0.join("slot").join(MANIFEST_FILE)).unwrap();
+
+        // Failing halfway leaves the previous save
+        let result = saves.write_slot("slot", |dir| {
+            write_file(&dir.join(WORLD_FILE), b"partial")?;
+            bail!("disk full")
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            manifest,
+            fs::read(dir.0.join("slot").join(MANIFEST_FILE)).unwrap()
+        );
+        assert!(!saves.temporary_path("slot").exists());
+        saves.load("slot", &mut executor()).unwrap();
+
+        // Interrupted between the renames: the previous save is put back
+        fs::rename(dir.0.join("slot"), saves.old_path("slot")).unwrap();
+        saves.load("slot", &mut executor()).unwrap();
+        assert!(!saves.old_path("slot").exists());
+
+        assert!(saves.save("../escape", &world, &exe, None).is_err());
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let dir = TempDir::new();
+        let saves = saves(&dir);
+        let (world, exe) = game();
+        saves.save("slot", &world, &exe, None).unwrap();
+
+        // A newer game, with other versions of the types
+        let mut registry = SaveRegistry::new();
+        registry
+            .register_component::<TransformsComponent>("transforms", 2, |_| Vec::new(), |_| None)
+            .register_component::<VelocityComponent>("velocity", 1, |_| Vec::new(), |_| None)
+            .register_resource::<Time>();
+        let newer = Saves::new(&dir.0, Arc::new(registry));
+        let error = newer.load("slot", &mut executor()).err().unwrap();
+        let incompatible = error.downcast_ref::<Incompatible>().unwrap();
+        assert_eq!(None, incompatible.format);
+        let types = incompatible
+            .types
+            .iter()
+            .map(|t| (t.name.as_str(), t.kind, t.saved, t.current))
+            .collect::<Vec<_>>();
+        // No camera in these executors, so it isn't saved
+        assert_eq!(
+            vec![
+                ("nav_agent", TypeKind::Component, 1, None),
+                ("path", TypeKind::Component, 1, None),
+                ("path_follow", TypeKind::Component, 1, None),
+                ("transforms", TypeKind::Component, 1, Some(2)),
+                ("moves", TypeKind::Resource, 3, None),
+            ],
+            types
+        );
+        assert!(error
+            .to_string()
+            .contains("component transforms: version 1 (expected 2)"));
+
+        // Another format
+        let path = dir.0.join("slot").join(MANIFEST_FILE);
+        let mut manifest: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        manifest["format"] = Value::from(SAVE_FORMAT + 1);
+        fs::write(&path, manifest.to_string()).unwrap();
+        let error = saves.load("slot", &mut executor()).err().unwrap();
+        assert_eq!(
+            Some(SAVE_FORMAT + 1),
+            error.downcast_ref::<Incompatible>().unwrap().format
+        );
+    }
+
+    #[test]
+    fn listing() {
+        let dir = TempDir::new();
+        let saves = saves(&dir);
+        assert!(saves.list().is_empty());
+        let (world, exe) = game();
+        saves.save("old", &world, &exe, None).unwrap();
+        saves.save("new", &world, &exe, None).unwrap();
+        // Same second, told apart by the timestamp
+        for (slot, timestamp) in [("old", 1000), ("new", 2000)] {
+            let path = dir.0.join(slot).join(MANIFEST_FILE);
+            let mut manifest: Manifest = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+            manifest.timestamp = timestamp;
+            fs::write(&path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+        }
+        fs::create_dir(dir.0.join("corrupt")).unwrap();
+        fs::write(dir.0.join("corrupt").join(MANIFEST_FILE), "{ not json").unwrap();
+        fs::create_dir(dir.0.join("empty")).unwrap();
+        fs::write(dir.0.join("file"), "not a slot").unwrap();
+        // Interrupted save of another slot
+        fs::create_dir(saves.temporary_path("other")).unwrap();
+
+        let slots = saves.list();
+        let names = slots.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["new", "old", "corrupt", "empty"], names);
+        assert_eq!(2000, slots[0].manifest.as_ref().unwrap().timestamp);
+        assert!(slots[2].manifest.is_err());
+        assert!(slots[3].manifest.is_err());
+        assert!(saves.load("corrupt", &mut executor()).is_err());
+    }
+
+    #[test]
+    fn load_events() {
+        let dir = TempDir::new();
+        let saves = saves(&dir);
+        let (world, exe) = game();
+        saves.save("a", &world, &exe, None).unwrap();
+        saves.save("b", &world, &exe, None).unwrap();
+
+        // Two loads between the same frames, the systems see both
+        let mut exe = executor();
+        saves.load("a", &mut exe).unwrap();
+        let mut world = saves.load("b", &mut exe).unwrap();
+        exe.add_resource(Vec::<String>::new());
+        let schedule = exe
+            .schedule()
+            .then(|loaded: EventReader<GameLoaded>, seen: &mut Vec<String>| {
+                seen.extend(loaded.iter().map(|event| event.slot.clone()));
+            })
+            .build();
+        exe.execute(&schedule, &mut world);
+        exe.execute(&schedule, &mut world);
+        assert_eq!(&vec!["a", "b"], exe.get_resource::<Vec<String>>().unwrap());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!("1970-01-01 00:00", format_timestamp(0));
+        assert_eq!("2000-02-29 23:59", format_timestamp(951868799));
+        assert_eq!("2022-06-01 12:30", format_timestamp(1654086600));
+    }
+}
@@ -0,0 +1,327 @@
+//! Ambient sound zones.
+//!
+//! An entity with an `AudioZoneComponent` plays its loop while the listener (the camera, which is
+//! at the eyes of the character when following it) is inside its shape, fading in and out over
+//! `fade_seconds`. Where zones overlap, higher priorities cover the lower ones: each priority
+//! gets what the higher ones leave, so walking into a cave inside a forest fades the forest out
+//! as the cave fades in. Zones of the same priority play together.
+
+use ecs::Entities;
+use glam::Vec3;
+
+use crate::components::TransformsComponent;
+
+use super::{
+    audio::{AudioLoop, AudioSourceHandle, Mixer},
+    graphics::renderer::WorldRenderer,
+    time::Time,
+};
+
+/// Shape of a zone, around the translation of its entity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZoneShape {
+    Aabb { half_extents: Vec3 },
+    Sphere { radius: f32 },
+}
+
+impl ZoneShape {
+    pub fn contains(&self, center: Vec3, point: Vec3) -> bool {
+        let offset = point - center;
+        match *self {
+            Self::Aabb { half_extents } => offset.abs().cmple(half_extents).all(),
+            Self::Sphere { radius } => offset.length_squared() <= radius * radius,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioZoneComponent {
+    pub shape: ZoneShape,
+    /// Volume inside the zone
+    pub volume: f32,
+    /// Time to fade in or out completely
+    pub fade_seconds: f32,
+    /// Covers the zones of lower priority it overlaps
+    pub priority: i32,
+    fade: ZoneFade,
+    audio: AudioLoop,
+}
+
+impl AudioZoneComponent {
+    pub fn new(shape: ZoneShape, clip: AudioSourceHandle) -> Self {
+        Self {
+            shape,
+            volume: 1.0,
+            fade_seconds: 2.0,
+            priority: 0,
+            fade: ZoneFade::default(),
+            audio: AudioLoop::new(clip),
+        }
+    }
+    pub fn clip(&self) -> &AudioSourceHandle {
+        self.audio.sound()
+    }
+    /// How far the zone faded in, from 0 to 1
+    pub fn weight(&self) -> f32 {
+        self.fade.weight
+    }
+    pub fn playing(&self) -> bool {
+        self.audio.playing()
+    }
+}
+
+/// Fade of a zone towards being heard or not. A fade that turns around goes back from where it
+/// is, crossing a boundary back and forth doesn't make the volume jump.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ZoneFade {
+    weight: f32,
+}
+
+impl ZoneFade {
+    pub fn weight(&self) -> f32 {
+        self.weight
+    }
+    pub fn update(&mut self, inside: bool, fade_seconds: f32, dt: f32) -> f32 {
+        let target = if inside { 1.0 } else { 0.0 };
+        self.weight = if fade_seconds <= 0.0 {
+            target
+        } else {
+            let step = dt / fade_seconds;
+            if inside {
+                (self.weight + step).min(target)
+            } else {
+                (self.weight - step).max(target)
+            }
+        };
+        self.weight
+    }
+}
+
+/// Share of the volume of zones given their priorities and weights. Zones of a priority get what
+/// the higher priorities leave, which is less the more faded in the highest of them is.
+pub fn mix(zones: &[(i32, f32)]) -> Vec<f32> {
+    let mut order = (0..zones.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| std::cmp::Reverse(zones[i].0));
+    let mut gains = vec![0.0; zones.len()];
+    let mut left = 1.0;
+    for group in order.chunk_by(|&a, &b| zones[a].0 == zones[b].0) {
+        let mut covered = 0.0f32;
+        for &i in group {
+            gains[i] = zones[i].1 * left;
+            covered = covered.max(zones[i].1);
+        }
+        left *= 1.0 - covered;
+    }
+    gains
+}
+
+/// Fade the zones for a listener, and push the changes of their loops to the mixer
+pub fn apply_zones(
+    listener: Vec3,
+    dt: f32,
+    mixer: &mut Mixer,
+    zones: &mut [(&mut AudioZoneComponent, Vec3)],
+) {
+    let weights = zones
+        .iter_mut()
+        .map(|(zone, center)| {
+            let inside = zone.shape.contains(*center, listener);
+            let weight = zone.fade.update(inside, zone.fade_seconds, dt);
+            (zone.priority, weight)
+        })
+        .collect::<Vec<_>>();
+    for ((zone, _), gain) in zones.iter_mut().zip(mix(&weights)) {
+        if let Some(command) = zone.audio.update(gain * zone.volume) {
+            mixer.push(command);
+        }
+    }
+}
+
+/// System fading the zones around the camera
+pub fn update_zones(
+    time: &Time,
+    wr: &WorldRenderer,
+    mixer: &mut Mixer,
+    zones: Entities<(&mut AudioZoneComponent, &TransformsComponent)>,
+) {
+    let mut zones = zones
+        .map(|(zone, transforms)| (zone, transforms.translation()))
+        .collect::<Vec<_>>();
+    apply_zones(
+        wr.camera.get_position(),
+        time.delta_secs(),
+        mixer,
+        &mut zones,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::systems::audio::AudioCommand;
+
+    use super::*;
+
+    const DT: f32 = 0.1;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn shapes() {
+        let center = Vec3::new(1.0, 0.0, 0.0);
+        let aabb = ZoneShape::Aabb {
+            half_extents: Vec3::new(1.0, 2.0, 3.0),
+        };
+        assert!(aabb.contains(center, Vec3::new(2.0, -2.0, 3.0)));
+        assert!(!aabb.contains(center, Vec3::new(2.1, 0.0, 0.0)));
+        let sphere = ZoneShape::Sphere { radius: 2.0 };
+        assert!(sphere.contains(center, Vec3::new(1.0, 2.0, 0.0)));
+        assert!(!sphere.contains(center, Vec3::new(2.5, 1.5, 0.0)));
+    }
+
+    #[test]
+    fn fades() {
+        let mut fade = ZoneFade::default();
+        // In over a second
+        for i in 1..=10 {
+            assert!(close(i as f32 / 10.0, fade.update(true, 1.0, DT)));
+        }
+        assert_eq!(1.0, fade.update(true, 1.0, DT));
+        // Rapid crossings turn the fade around from where it is
+        assert!(close(0.9, fade.update(false, 1.0, DT)));
+        assert!(close(0.8, fade.update(false, 1.0, DT)));
+        assert!(close(0.9, fade.update(true, 1.0, DT)));
+        assert!(close(0.8, fade.update(false, 1.0, DT)));
+        for _ in 0..20 {
+            fade.update(false, 1.0, DT);
+        }
+        assert_eq!(0.0, fade.weight());
+        // No fade
+        assert_eq!(1.0, fade.update(true, 0.0, DT));
+    }
+
+    #[test]
+    fn overlaps() {
+        assert_eq!(Vec::<f32>::new(), mix(&[]));
+        // A single zone, or zones of the same priority, aren't changed
+        assert_eq!(vec![0.5], mix(&[(0, 0.5)]));
+        assert_eq!(vec![0.5, 1.0], mix(&[(0, 0.5), (0, 1.0)]));
+        // A higher priority covers the lower ones as it fades in
+        assert_eq!(vec![0.0, 1.0], mix(&[(0, 1.0), (1, 1.0)]));
+        let crossfade = mix(&[(0, 1.0), (1, 0.25)]);
+        assert!(close(0.75, crossfade[0]) && close(0.25, crossfade[1]));
+        // Three levels, each covering the ones under
+        let nested = mix(&[(2, 0.5), (0, 1.0), (1, 0.5)]);
+        assert!(close(0.5, nested[0]), "{nested:?}");
+        assert!(close(0.25, nested[2]), "{nested:?}");
+        assert!(close(0.25, nested[1]), "{nested:?}");
+        // The most faded in zone of a priority decides what's left
+        let shared = mix(&[(1, 0.5), (1, 0.25), (0, 1.0)]);
+        assert!(close(0.5, shared[2]), "{shared:?}");
+    }
+
+    #[test]
+    fn zones() {
+        let forest_clip = AudioSourceHandle::from("forest");
+        let cave_clip = AudioSourceHandle::from("cave");
+        let mut forest = AudioZoneComponent::new(
+            ZoneShape::Aabb {
+                half_extents: Vec3::splat(50.0),
+            },
+            forest_clip.clone(),
+        );
+        forest.fade_seconds = 1.0;
+        let mut cave =
+            AudioZoneComponent::new(ZoneShape::Sphere { radius: 5.0 }, cave_clip.clone());
+        cave.fade_seconds = 0.5;
+        cave.priority = 1;
+        cave.volume = 0.5;
+        let mut mixer = Mixer::default();
+        let cave_center = Vec3::new(20.0, 0.0, 0.0);
+        let mut run = |listener: Vec3, steps: usize, mixer: &mut Mixer| {
+            for _ in 0..steps {
+                apply_zones(
+                    listener,
+                    DT,
+                    mixer,
+                    &mut [(&mut forest, Vec3::ZERO), (&mut cave, cave_center)],
+                );
+            }
+            (
+                forest.weight(),
+                cave.weight(),
+                forest.playing(),
+                cave.playing(),
+            )
+        };
+
+        // Outside of everything
+        assert_eq!(
+            (0.0, 0.0, false, false),
+            run(Vec3::splat(100.0), 5, &mut mixer)
+        );
+        assert!(mixer.queued().is_empty());
+        // In the forest, which starts playing
+        run(Vec3::ZERO, 1, &mut mixer);
+        assert_eq!(
+            &[AudioCommand::Play {
+                sound: forest_clip.clone(),
+                volume: 0.1
+            }],
+            mixer.queued()
+        );
+        mixer.flush();
+        let (forest_weight, ..) = run(Vec3::ZERO, 20, &mut mixer);
+        assert_eq!(1.0, forest_weight);
+        assert!(matches!(
+            mixer.queued().last(),
+            Some(AudioCommand::SetVolume { volume, .. }) if *volume == 1.0
+        ));
+        mixer.flush();
+
+        // Into the cave, crossfading
+        let (forest_weight, cave_weight, ..) = run(cave_center, 2, &mut mixer);
+        assert_eq!((1.0, 0.4), (forest_weight, cave_weight));
+        assert!(mixer.queued().contains(&AudioCommand::Play {
+            sound: cave_clip.clone(),
+            volume: 0.1
+        }));
+        mixer.flush();
+        run(cave_center, 5, &mut mixer);
+        assert!(mixer.queued().contains(&AudioCommand::Stop {
+            sound: forest_clip.clone()
+        }));
+        assert!(matches!(
+            mixer.queued().last(),
+            Some(AudioCommand::SetVolume { sound, volume }) if *sound == cave_clip && *volume == 0.5
+        ));
+        mixer.flush();
+
+        // Back and forth on the edge of the cave: no restart, the fade turns around
+        let edge = cave_center + Vec3::X * 5.0;
+        let mut weights = Vec::new();
+        for i in 0..6 {
+            let side = if i % 2 == 0 { 0.01 } else { -0.01 };
+            weights.push(run(edge + Vec3::X * side, 1, &mut mixer).1);
+        }
+        assert!(
+            weights.iter().all(|&w| (0.8 - 1e-4..=1.0).contains(&w)),
+            "{weights:?}"
+        );
+        assert!(!mixer
+            .queued()
+            .iter()
+            .any(|c| matches!(c, AudioCommand::Play { sound, .. } if *sound == cave_clip)));
+        mixer.flush();
+
+        // Out of both
+        let (forest_weight, cave_weight, forest_playing, cave_playing) =
+            run(Vec3::splat(100.0), 20, &mut mixer);
+        assert_eq!((0.0, 0.0), (forest_weight, cave_weight));
+        assert!(!forest_playing && !cave_playing);
+        assert!(mixer
+            .queued()
+            .contains(&AudioCommand::Stop { sound: cave_clip }));
+    }
+}
@@ -0,0 +1,309 @@
+use ecs::Entities;
+use glam::{Quat, Vec3};
+use slotmap::SlotMap;
+
+use crate::components::TransformsComponent;
+
+use super::time::Time;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Keep the value of the previous key until the next one
+    Step,
+    /// Straight lines between the keys, slerp for rotations
+    Linear,
+}
+
+/// Values of a channel, one per key
+#[derive(Debug, Clone)]
+pub enum Keyframes {
+    Translation(Vec<Vec3>),
+    Rotation(Vec<Quat>),
+    Scale(Vec<Vec3>),
+}
+
+/// Animation of one property of a node
+#[derive(Debug, Clone)]
+pub struct Channel {
+    /// Index of the animated node in its glTF document
+    pub node: usize,
+    /// Time of the keys in seconds, increasing
+    pub times: Vec<f32>,
+    pub keyframes: Keyframes,
+    pub interpolation: Interpolation,
+}
+
+impl Channel {
+    /// Keys around `time` and how far between them it is. Before the first key and after the last
+    /// the keys are the same.
+    fn keys(&self, time: f32) -> (usize, usize, f32) {
+        let next = self.times.partition_point(|t| *t <= time);
+        if next == 0 {
+            return (0, 0, 0.0);
+        }
+        if next == self.times.len() {
+            return (next - 1, next - 1, 0.0);
+        }
+        let prev = next - 1;
+        let span = self.times[next] - self.times[prev];
+        let t = match self.interpolation {
+            Interpolation::Step => 0.0,
+            Interpolation::Linear if span > 0.0 => (time - self.times[prev]) / span,
+            Interpolation::Linear => 0.0,
+        };
+        (prev, next, t)
+    }
+
+    /// Set the animated property of `transforms` to its value at `time`
+    fn sample(&self, time: f32, transforms: &mut TransformsComponent) {
+        if self.times.is_empty() {
+            return;
+        }
+        let (a, b, t) = self.keys(time);
+        match &self.keyframes {
+            Keyframes::Translation(values) => {
+                transforms.set_translation(values[a].lerp(values[b], t));
+            }
+            Keyframes::Rotation(values) => {
+                transforms.set_rotation(values[a].slerp(values[b], t).normalize());
+            }
+            Keyframes::Scale(values) => {
+                transforms.set_scale(values[a].lerp(values[b], t));
+            }
+        }
+    }
+}
+
+/// A glTF animation, the channels target nodes of the document it was loaded from
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: Option<String>,
+    pub channels: Vec<Channel>,
+    /// Time of the last key
+    pub duration: f32,
+}
+
+impl AnimationClip {
+    pub fn new(name: Option<String>, channels: Vec<Channel>) -> Self {
+        let duration = channels
+            .iter()
+            .filter_map(|channel| channel.times.last().copied())
+            .fold(0.0, f32::max);
+        Self {
+            name,
+            channels,
+            duration,
+        }
+    }
+    /// Whether the clip moves `node`
+    pub fn animates(&self, node: usize) -> bool {
+        self.channels.iter().any(|channel| channel.node == node)
+    }
+    /// Local transforms of `node` at `time`, the properties not animated keep their value in
+    /// `rest`
+    pub fn sample(&self, node: usize, time: f32, rest: &TransformsComponent) -> TransformsComponent {
+        let mut transforms = rest.clone();
+        for channel in self.channels.iter().filter(|channel| channel.node == node) {
+            channel.sample(time, &mut transforms);
+        }
+        transforms
+    }
+}
+
+slotmap::new_key_type! {
+    pub struct AnimationHandle;
+}
+
+#[derive(Default)]
+pub struct AnimationManager {
+    clips: SlotMap<AnimationHandle, AnimationClip>,
+}
+
+impl AnimationManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add(&mut self, clip: AnimationClip) -> AnimationHandle {
+        self.clips.insert(clip)
+    }
+    pub fn get(&self, handle: AnimationHandle) -> Option<&AnimationClip> {
+        self.clips.get(handle)
+    }
+    pub fn remove(&mut self, handle: AnimationHandle) -> Option<AnimationClip> {
+        self.clips.remove(handle)
+    }
+}
+
+/// Plays a clip on an entity. The loader flattens the node hierarchy, so the player keeps the
+/// nodes from the root of the scene to the entity's with their rest transforms, and the entity's
+/// transforms are rebuilt from them.
+#[derive(Clone)]
+pub struct AnimationPlayer {
+    pub clip: AnimationHandle,
+    /// Position in the clip, in seconds
+    pub time: f32,
+    pub speed: f32,
+    pub looping: bool,
+    nodes: Vec<(usize, TransformsComponent)>,
+}
+
+impl AnimationPlayer {
+    /// `nodes` are the indices and local transforms of the nodes leading to the entity, root first
+    pub fn new(clip: AnimationHandle, nodes: Vec<(usize, TransformsComponent)>) -> Self {
+        Self {
+            clip,
+            time: 0.0,
+            speed: 1.0,
+            looping: true,
+            nodes,
+        }
+    }
+    /// Transforms of the entity at the current time
+    pub fn transforms(&self, clip: &AnimationClip) -> TransformsComponent {
+        self.nodes
+            .iter()
+            .fold(TransformsComponent::new(), |parent, (node, rest)| {
+                let mut local = clip.sample(*node, self.time, rest);
+                local.apply(&parent);
+                local
+            })
+    }
+    /// Move the time forward by `delta` seconds (scaled by the speed), wrapping around the end
+    /// when looping or stopping there otherwise
+    pub fn step(&mut self, delta: f32, duration: f32) {
+        self.time += delta * self.speed;
+        if self.looping && duration > 0.0 {
+            self.time = self.time.rem_euclid(duration);
+        } else {
+            self.time = self.time.clamp(0.0, duration);
+        }
+    }
+    /// Whether a clip that doesn't loop reached its end (or its start when played backwards)
+    pub fn finished(&self, duration: f32) -> bool {
+        !self.looping && (self.time >= duration && self.speed > 0.0 || self.time <= 0.0 && self.speed < 0.0)
+    }
+}
+
+/// System playing the animations
+pub fn advance(
+    players: Entities<(&mut AnimationPlayer, &mut TransformsComponent)>,
+    time: &Time,
+    animations: &AnimationManager,
+) {
+    let delta = time.delta_secs();
+    for (player, transforms) in players {
+        let clip = match animations.get(player.clip) {
+            Some(clip) => clip,
+            None => continue,
+        };
+        player.step(delta, clip.duration);
+        *transforms = player.transforms(clip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!(a.distance(b) < 1e-4, "{a} != {b}");
+    }
+
+    fn clip(interpolation: Interpolation) -> AnimationClip {
+        AnimationClip::new(
+            Some("test".to_owned()),
+            vec![
+                Channel {
+                    node: 0,
+                    times: vec![0.0, 1.0, 3.0],
+                    keyframes: Keyframes::Translation(vec![
+                        Vec3::ZERO,
+                        Vec3::new(2.0, 0.0, 0.0),
+                        Vec3::new(2.0, 4.0, 0.0),
+                    ]),
+                    interpolation,
+                },
+                Channel {
+                    node: 0,
+                    times: vec![1.0, 2.0],
+                    keyframes: Keyframes::Rotation(vec![
+                        Quat::IDENTITY,
+                        Quat::from_rotation_y(std::f32::consts::FRAC_PI_2),
+                    ]),
+                    interpolation,
+                },
+                Channel {
+                    node: 1,
+                    times: vec![0.0, 2.0],
+                    keyframes: Keyframes::Scale(vec![Vec3::ONE, Vec3::splat(3.0)]),
+                    interpolation,
+                },
+            ],
+        )
+    }
+
+    #[test]
+    fn linear() {
+        let clip = clip(Interpolation::Linear);
+        assert_eq!(3.0, clip.duration);
+        assert!(clip.animates(1) && !clip.animates(2));
+        let rest = TransformsComponent::new();
+
+        assert_near(clip.sample(0, 0.5, &rest).translation(), Vec3::new(1.0, 0.0, 0.0));
+        assert_near(clip.sample(0, 2.0, &rest).translation(), Vec3::new(2.0, 2.0, 0.0));
+        // Clamped outside the keys
+        assert_near(clip.sample(0, -1.0, &rest).translation(), Vec3::ZERO);
+        assert_near(clip.sample(0, 5.0, &rest).translation(), Vec3::new(2.0, 4.0, 0.0));
+        // Half way through a quarter turn
+        let rotation = clip.sample(0, 1.5, &rest).rotation();
+        assert!(rotation.angle_between(Quat::from_rotation_y(std::f32::consts::FRAC_PI_4)) < 1e-4);
+        assert!(clip.sample(0, 0.5, &rest).rotation().angle_between(Quat::IDENTITY) < 1e-4);
+
+        // Properties not animated keep their rest value
+        let mut rest = TransformsComponent::new();
+        rest.set_translation(Vec3::Y);
+        let node = clip.sample(1, 1.0, &rest);
+        assert_near(node.translation(), Vec3::Y);
+        assert_near(node.scale(), Vec3::splat(2.0));
+    }
+
+    #[test]
+    fn step() {
+        let clip = clip(Interpolation::Step);
+        let rest = TransformsComponent::new();
+        assert_near(clip.sample(0, 0.0, &rest).translation(), Vec3::ZERO);
+        assert_near(clip.sample(0, 0.99, &rest).translation(), Vec3::ZERO);
+        assert_near(clip.sample(0, 1.0, &rest).translation(), Vec3::new(2.0, 0.0, 0.0));
+        assert_near(clip.sample(0, 2.5, &rest).translation(), Vec3::new(2.0, 0.0, 0.0));
+        assert_near(clip.sample(1, 1.9, &rest).scale(), Vec3::ONE);
+        assert_near(clip.sample(1, 2.0, &rest).scale(), Vec3::splat(3.0));
+    }
+
+    #[test]
+    fn player() {
+        let mut animations = AnimationManager::new();
+        let handle = animations.add(clip(Interpolation::Linear));
+        let clip = animations.get(handle).unwrap();
+        let root = TransformsComponent::new();
+        let mut child = TransformsComponent::new();
+        child.set_translation(Vec3::Z);
+        let mut player = AnimationPlayer::new(handle, vec![(0, root.clone()), (1, child.clone())]);
+
+        // Composed like the loader composes the nodes
+        player.step(1.5, clip.duration);
+        let mut expected = clip.sample(1, 1.5, &child);
+        expected.apply(&clip.sample(0, 1.5, &root));
+        assert!(player.transforms(clip).mat().abs_diff_eq(expected.mat(), 1e-5));
+        let root_only = AnimationPlayer { nodes: vec![(0, root)], ..player.clone() };
+        assert_near(root_only.transforms(clip).translation(), Vec3::new(2.0, 1.0, 0.0));
+
+        // Wraps around when looping, stops at the end otherwise
+        player.step(2.0, clip.duration);
+        assert!((player.time - 0.5).abs() < 1e-5);
+        player.speed = 2.0;
+        player.looping = false;
+        player.step(2.0, clip.duration);
+        assert_eq!(clip.duration, player.time);
+        assert!(player.finished(clip.duration));
+    }
+}
@@ -0,0 +1,208 @@
+//! What the game asks of the audio engine.
+//!
+//! Systems push `AudioCommand`s to the `Mixer` resource, which hands them to its `AudioSink` once
+//! per frame (`Mixer::flush`). There is no audio engine yet, so the sink is a `NullAudioSink` that
+//! only logs the commands. An engine plugs in by implementing `AudioSink` and being given to
+//! `Mixer::new` in `main`, nothing that emits commands has to change.
+
+use std::sync::Arc;
+
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+
+/// A sound, by the name of its clip in the resources directory
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct AudioSourceHandle(Arc<str>);
+
+impl AudioSourceHandle {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for AudioSourceHandle {
+    fn from(name: &str) -> Self {
+        Self(name.into())
+    }
+}
+
+impl From<String> for AudioSourceHandle {
+    fn from(name: String) -> Self {
+        Self(name.into())
+    }
+}
+
+impl From<AudioSourceHandle> for String {
+    fn from(handle: AudioSourceHandle) -> Self {
+        handle.0.to_string()
+    }
+}
+
+/// A command for the audio engine
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioCommand {
+    /// Start a loop
+    Play {
+        sound: AudioSourceHandle,
+        volume: f32,
+    },
+    Stop {
+        sound: AudioSourceHandle,
+    },
+    SetVolume {
+        sound: AudioSourceHandle,
+        volume: f32,
+    },
+    /// Play a sound once at a position, pitch is a playback speed factor
+    PlayAt {
+        sound: AudioSourceHandle,
+        volume: f32,
+        pitch: f32,
+        position: Vec3,
+    },
+}
+
+pub trait AudioSink: Send {
+    fn send(&mut self, command: AudioCommand);
+}
+
+/// Drops the commands, until there is an audio engine. They are logged at the trace level, to
+/// follow what the game asks for (`RUST_LOG=sg::systems::audio=trace`).
+pub struct NullAudioSink;
+
+impl AudioSink for NullAudioSink {
+    fn send(&mut self, command: AudioCommand) {
+        log::trace!("Audio: {command:?}");
+    }
+}
+
+/// The queue of commands for the audio engine, a resource
+pub struct Mixer {
+    queue: Vec<AudioCommand>,
+    sink: Box<dyn AudioSink>,
+}
+
+impl Mixer {
+    pub fn new(sink: Box<dyn AudioSink>) -> Self {
+        Self {
+            queue: Vec::new(),
+            sink,
+        }
+    }
+    pub fn push(&mut self, command: AudioCommand) {
+        self.queue.push(command);
+    }
+    /// The commands pushed since the last flush
+    pub fn queued(&self) -> &[AudioCommand] {
+        &self.queue
+    }
+    /// System sending the commands of the frame to the sink
+    pub fn flush(&mut self) {
+        for command in self.queue.drain(..) {
+            self.sink.send(command);
+        }
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new(Box::new(NullAudioSink))
+    }
+}
+
+/// Starts, stops and sets the volume of a loop from the volume it should have, without a command
+/// per frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioLoop {
+    sound: AudioSourceHandle,
+    playing: bool,
+    /// Volume last sent
+    volume: f32,
+    /// Volume asked for by the last update
+    last: f32,
+}
+
+impl AudioLoop {
+    /// Change of volume worth a command, smaller ones are sent once the volume settles
+    pub const STEP: f32 = 0.05;
+
+    pub fn new(sound: AudioSourceHandle) -> Self {
+        Self {
+            sound,
+            playing: false,
+            volume: 0.0,
+            last: 0.0,
+        }
+    }
+    pub fn sound(&self) -> &AudioSourceHandle {
+        &self.sound
+    }
+    pub fn playing(&self) -> bool {
+        self.playing
+    }
+    /// The loop plays while the volume is above 0
+    pub fn update(&mut self, volume: f32) -> Option<AudioCommand> {
+        let settled = volume == self.last;
+        self.last = volume;
+        let sound = self.sound.clone();
+        let command = if !self.playing {
+            if volume <= 0.0 {
+                return None;
+            }
+            self.playing = true;
+            AudioCommand::Play { sound, volume }
+        } else if volume <= 0.0 {
+            self.playing = false;
+            AudioCommand::Stop { sound }
+        } else if (volume - self.volume).abs() >= Self::STEP || settled && volume != self.volume {
+            AudioCommand::SetVolume { sound, volume }
+        } else {
+            return None;
+        };
+        self.volume = volume;
+        Some(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_loop() {
+        let sound = AudioSourceHandle::from("wind");
+        let mut audio = AudioLoop::new(sound.clone());
+        let set = |volume| AudioCommand::SetVolume {
+            sound: sound.clone(),
+            volume,
+        };
+
+        assert_eq!(None, audio.update(0.0));
+        assert_eq!(
+            Some(AudioCommand::Play {
+                sound: sound.clone(),
+                volume: 0.01
+            }),
+            audio.update(0.01)
+        );
+        assert_eq!(None, audio.update(0.03));
+        assert_eq!(Some(set(0.07)), audio.update(0.07));
+        assert_eq!(None, audio.update(0.08));
+        assert_eq!(Some(set(0.08)), audio.update(0.08));
+        assert_eq!(
+            Some(AudioCommand::Stop {
+                sound: sound.clone()
+            }),
+            audio.update(0.0)
+        );
+        assert_eq!(None, audio.update(0.0));
+        assert!(!audio.playing());
+
+        let mut mixer = Mixer::default();
+        mixer.push(set(1.0));
+        assert_eq!(&[set(1.0)], mixer.queued());
+        mixer.flush();
+        assert!(mixer.queued().is_empty());
+    }
+}
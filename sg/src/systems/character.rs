@@ -0,0 +1,803 @@
+//! First person character controller.
+//!
+//! Characters are kinematic capsules moved at a fixed step against the static geometry: the
+//! motion is swept, and on a hit what's left of it slides along the planes touched so far (at
+//! most `MAX_PLANES`, a corner stops it). Walls under `step_height` are climbed by moving up, across
+//! then down, slopes steeper than `max_slope_deg` are walls, and characters stick to the ground
+//! when walking down slopes and stairs. The feet are at the translation of the entity.
+//!
+//! Walking on the ground emits a `FootstepEvent` every stride, measured in distance rather than
+//! time so the steps follow the speed.
+
+use ecs::{Entities, EventReader};
+use glam::Vec3;
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode};
+
+use crate::components::TransformsComponent;
+
+use super::{
+    collision::{Capsule, StaticBvh, SurfaceKind, SKIN},
+    time::FixedTime,
+};
+
+/// Cycles the camera between free flying, following the character and the showcase camera
+pub const TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::V;
+const GRAVITY: f32 = 9.81;
+/// Time after leaving the ground during which the character still counts as grounded, so that a
+/// jump just after walking off a ledge or a bump in the floor isn't lost
+const COYOTE_TIME: f32 = 0.1;
+/// Distance under the feet looked at for the ground when in the air
+const GROUND_PROBE: f32 = 0.05;
+/// Planes a motion can slide along before stopping
+const MAX_PLANES: usize = 3;
+/// Distance walked between two footsteps
+const STRIDE: f32 = 0.75;
+
+/// What the player wants the character to do
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CharacterInput {
+    /// Horizontal direction in world space, of length at most 1
+    pub direction: Vec3,
+    pub jump: bool,
+}
+
+/// Where the camera is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CameraMode {
+    #[default]
+    FreeFly,
+    /// At the eyes of the character
+    Character,
+    /// At the entity with a `ShowcaseCameraComponent`, moved along a path
+    Showcase,
+}
+
+impl CameraMode {
+    pub fn toggle(&mut self) {
+        *self = match self {
+            Self::FreeFly => Self::Character,
+            Self::Character => Self::Showcase,
+            Self::Showcase => Self::FreeFly,
+        }
+    }
+}
+
+/// Marks the entity the camera is put at in `CameraMode::Showcase`. It usually follows a path
+/// (`PathFollowComponent` with `look_at`), which gives it its rotation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShowcaseCameraComponent;
+
+/// Toggle the camera mode on the presses of `TOGGLE_KEY`
+pub fn toggle_camera(keys: EventReader<KeyboardInput>, mode: &mut CameraMode) {
+    for key in keys {
+        if key.state == ElementState::Pressed && key.virtual_keycode == Some(TOGGLE_KEY) {
+            mode.toggle();
+        }
+    }
+}
+
+/// A foot of a character touching the ground
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FootstepEvent {
+    pub position: Vec3,
+    pub surface: SurfaceKind,
+}
+
+/// The footsteps of the frame, a resource
+#[derive(Debug, Default)]
+pub struct FootstepEvents {
+    steps: Vec<FootstepEvent>,
+}
+
+impl FootstepEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn drain(&mut self) -> impl Iterator<Item = FootstepEvent> + '_ {
+        self.steps.drain(..)
+    }
+}
+
+/// Distance walked since the last footstep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StrideAccumulator {
+    /// Distance between two footsteps
+    pub stride: f32,
+    walked: f32,
+}
+
+impl StrideAccumulator {
+    pub fn new(stride: f32) -> Self {
+        Self {
+            stride,
+            walked: 0.0,
+        }
+    }
+    /// Walk a distance, returns where along it footsteps fell
+    pub fn advance(&mut self, distance: f32) -> impl Iterator<Item = f32> {
+        let start = self.walked;
+        let stride = self.stride;
+        let steps = if stride > 0.0 {
+            ((start + distance) / stride) as u32
+        } else {
+            0
+        };
+        self.walked = (start + distance - steps as f32 * stride).max(0.0);
+        (1..=steps).map(move |i| i as f32 * stride - start)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CharacterControllerComponent {
+    pub radius: f32,
+    /// From the feet to the top of the head, at least twice the radius
+    pub height: f32,
+    /// Highest obstacle walked over
+    pub step_height: f32,
+    /// Steepest walkable slope
+    pub max_slope_deg: f32,
+    pub gravity_scale: f32,
+    /// Horizontal speed, in units per second
+    pub speed: f32,
+    /// Vertical speed of a jump
+    pub jump_speed: f32,
+    pub stride: StrideAccumulator,
+    vertical_speed: f32,
+    /// Normal of the ground under the feet, if standing on any
+    ground: Option<Vec3>,
+    /// What the ground is made of
+    surface: SurfaceKind,
+    /// Time since the ground was last touched
+    air_time: f32,
+}
+
+impl CharacterControllerComponent {
+    pub fn new(radius: f32, height: f32) -> Self {
+        Self {
+            radius,
+            height: height.max(radius * 2.0),
+            step_height: 0.3,
+            max_slope_deg: 45.0,
+            gravity_scale: 1.0,
+            speed: 4.0,
+            jump_speed: 5.0,
+            stride: StrideAccumulator::new(STRIDE),
+            vertical_speed: 0.0,
+            ground: None,
+            surface: SurfaceKind::default(),
+            air_time: COYOTE_TIME,
+        }
+    }
+    /// Standing on the ground, or just left it
+    pub fn grounded(&self) -> bool {
+        self.ground.is_some() || self.air_time < COYOTE_TIME
+    }
+    pub fn vertical_speed(&self) -> f32 {
+        self.vertical_speed
+    }
+    /// The surface under the feet, if standing on the ground
+    pub fn ground_surface(&self) -> Option<SurfaceKind> {
+        self.ground.map(|_| self.surface)
+    }
+    /// Height of the camera above the feet
+    pub fn eye_height(&self) -> f32 {
+        self.height - self.radius * 0.5
+    }
+    fn capsule(&self, feet: Vec3) -> Capsule {
+        Capsule {
+            a: feet + Vec3::Y * self.radius,
+            b: feet + Vec3::Y * (self.height - self.radius),
+            radius: self.radius,
+        }
+    }
+    fn walkable(&self, normal: Vec3) -> bool {
+        normal.y >= self.max_slope_deg.to_radians().cos()
+    }
+    /// Advance the character by a step of dt seconds, returns the new position of its feet
+    pub fn step(
+        &mut self,
+        feet: Vec3,
+        input: &CharacterInput,
+        geometry: &StaticBvh,
+        dt: f32,
+    ) -> Vec3 {
+        let mut pos = self.depenetrate(feet, geometry);
+
+        if input.jump && self.grounded() && self.vertical_speed <= 0.0 {
+            self.vertical_speed = self.jump_speed;
+            self.ground = None;
+            self.air_time = COYOTE_TIME;
+        } else if self.ground.is_some() {
+            self.vertical_speed = 0.0;
+        } else {
+            self.vertical_speed -= GRAVITY * self.gravity_scale * dt;
+        }
+
+        let direction = Vec3::new(input.direction.x, 0.0, input.direction.z).clamp_length_max(1.0);
+        if direction != Vec3::ZERO {
+            pos = self.move_horizontal(pos, direction * self.speed * dt, geometry);
+        }
+
+        let was_grounded = self.ground.is_some();
+        if self.vertical_speed != 0.0 {
+            let (moved, planes) = self.slide(pos, Vec3::Y * self.vertical_speed * dt, geometry);
+            pos = moved;
+            if self.vertical_speed > 0.0 && planes.iter().any(|n| n.y < 0.0) {
+                // Head against a ceiling
+                self.vertical_speed = 0.0;
+            }
+        }
+
+        // Follow the ground down slopes and stairs when walking, only land when falling
+        self.ground = None;
+        if self.vertical_speed <= 0.0 {
+            let probe = if was_grounded {
+                self.step_height.max(GROUND_PROBE)
+            } else {
+                GROUND_PROBE
+            };
+            if let Some(hit) = geometry.sweep(&self.capsule(pos), -Vec3::Y * probe) {
+                // On the edge of a step, the face says whether it can be stood on
+                if self.walkable(hit.face) {
+                    pos.y -= hit.toi * probe;
+                    self.ground = Some(hit.face);
+                    self.surface = geometry.surface(hit.triangle);
+                }
+            }
+        }
+        if self.ground.is_some() {
+            self.vertical_speed = 0.0;
+            self.air_time = 0.0;
+        } else {
+            self.air_time += dt;
+        }
+        pos
+    }
+    /// Move along the ground, climbing the steps in the way
+    fn move_horizontal(&self, pos: Vec3, motion: Vec3, geometry: &StaticBvh) -> Vec3 {
+        let along_ground = match self.ground {
+            Some(normal) => {
+                (motion - normal * motion.dot(normal)).normalize_or_zero() * motion.length()
+            }
+            None => motion,
+        };
+        let (slid, planes) = self.slide(pos, along_ground, geometry);
+        let blocked = planes.iter().any(|&n| !self.walkable(n));
+        if self.ground.is_none() || !blocked || self.step_height <= 0.0 {
+            return slid;
+        }
+
+        let up = Vec3::Y * self.step_height;
+        let raise = geometry
+            .sweep(&self.capsule(pos), up)
+            .map_or(self.step_height, |hit| hit.toi * self.step_height);
+        let (across, _) = self.slide(pos + Vec3::Y * raise, motion, geometry);
+        let down = -Vec3::Y * (raise + GROUND_PROBE);
+        match geometry.sweep(&self.capsule(across), down) {
+            Some(hit) if self.walkable(hit.face) => {
+                let stepped = across + down * hit.toi;
+                // Landing on the edge of something higher is climbing it
+                let contact = stepped + Vec3::Y * self.radius - hit.normal * self.radius;
+                let direction = motion.normalize();
+                let progress = |p: Vec3| (p - pos).dot(direction);
+                if contact.y - pos.y <= self.step_height + SKIN
+                    && progress(stepped) > progress(slid) + 1e-4
+                {
+                    stepped
+                } else {
+                    slid
+                }
+            }
+            _ => slid,
+        }
+    }
+    /// Sweep the motion and slide along what's hit, returns the final position and the planes
+    /// touched
+    fn slide(&self, mut pos: Vec3, motion: Vec3, geometry: &StaticBvh) -> (Vec3, Vec<Vec3>) {
+        let mut planes = Vec::with_capacity(MAX_PLANES);
+        let mut remaining = motion;
+        for _ in 0..=MAX_PLANES {
+            if remaining.length_squared() < 1e-12 {
+                break;
+            }
+            let Some(hit) = geometry.sweep(&self.capsule(pos), remaining) else {
+                pos += remaining;
+                break;
+            };
+            pos += remaining * hit.toi;
+            if planes.len() == MAX_PLANES {
+                break;
+            }
+            let mut normal = hit.normal;
+            if self.ground.is_some() && !self.walkable(normal) {
+                // Walls and steep slopes are vertical, to not climb them when sliding
+                let flat = Vec3::new(normal.x, 0.0, normal.z);
+                if flat.length_squared() > 1e-6 {
+                    normal = flat.normalize();
+                }
+            }
+            planes.push(normal);
+            remaining = clip_motion(remaining * (1.0 - hit.toi), &planes);
+            if remaining.dot(motion) <= 0.0 {
+                // Don't bounce back
+                break;
+            }
+        }
+        (pos, planes)
+    }
+    /// The footsteps of a step from one position to the other, along the ground
+    fn footsteps(&mut self, from: Vec3, to: Vec3) -> impl Iterator<Item = FootstepEvent> {
+        let surface = self.ground_surface();
+        let motion = Vec3::new(to.x - from.x, 0.0, to.z - from.z);
+        let distance = if surface.is_some() {
+            motion.length()
+        } else {
+            0.0
+        };
+        self.stride.advance(distance).map(move |at| FootstepEvent {
+            position: from.lerp(to, at / distance),
+            surface: surface.unwrap_or_default(),
+        })
+    }
+    /// Push the capsule out of the geometry it overlaps
+    fn depenetrate(&self, mut pos: Vec3, geometry: &StaticBvh) -> Vec3 {
+        for _ in 0..MAX_PLANES {
+            match geometry.penetration(&self.capsule(pos)) {
+                Some((depth, normal)) => pos += normal * (depth + SKIN),
+                None => break,
+            }
+        }
+        pos
+    }
+}
+
+/// The part of motion that doesn't go into any of the planes: along one of them, along the crease
+/// of two, or nothing
+pub fn clip_motion(motion: Vec3, planes: &[Vec3]) -> Vec3 {
+    const EPSILON: f32 = 1e-5;
+    let valid = |v: Vec3| planes.iter().all(|n| v.dot(*n) >= -EPSILON);
+    if valid(motion) {
+        return motion;
+    }
+    for n in planes {
+        let clipped = motion - *n * motion.dot(*n).min(0.0);
+        if valid(clipped) {
+            return clipped;
+        }
+    }
+    for (i, a) in planes.iter().enumerate() {
+        for b in &planes[i + 1..] {
+            let crease = a.cross(*b).normalize_or_zero();
+            let along = crease * motion.dot(crease);
+            if valid(along) {
+                return along;
+            }
+        }
+    }
+    Vec3::ZERO
+}
+
+/// Move the characters by a step, in the fixed schedule
+pub fn move_characters(
+    time: &FixedTime,
+    input: &CharacterInput,
+    geometry: &StaticBvh,
+    footsteps: &mut FootstepEvents,
+    characters: Entities<(&mut CharacterControllerComponent, &mut TransformsComponent)>,
+) {
+    let dt = time.delta_secs();
+    for (controller, transforms) in characters {
+        let before = transforms.translation();
+        let pos = controller.step(before, input, geometry, dt);
+        footsteps.steps.extend(controller.footsteps(before, pos));
+        if pos != before {
+            transforms.set_translation(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::systems::time::{FixedStep, DEFAULT_FIXED_STEP};
+
+    const DT: f32 = 1.0 / 60.0;
+
+    fn quad(a: Vec3, b: Vec3, c: Vec3, d: Vec3) -> [[Vec3; 3]; 2] {
+        [[a, b, c], [a, c, d]]
+    }
+
+    /// A floor at y = 0 with the given quads on it
+    fn level(extra: &[[[Vec3; 3]; 2]]) -> StaticBvh {
+        let floor = quad(
+            Vec3::new(-50.0, 0.0, -50.0),
+            Vec3::new(-50.0, 0.0, 50.0),
+            Vec3::new(50.0, 0.0, 50.0),
+            Vec3::new(50.0, 0.0, -50.0),
+        );
+        StaticBvh::build(
+            std::iter::once(&floor)
+                .chain(extra)
+                .flatten()
+                .copied()
+                .collect(),
+        )
+    }
+
+    /// A box from min to max
+    fn block(min: Vec3, max: Vec3) -> Vec<[[Vec3; 3]; 2]> {
+        let corner = |x: bool, y: bool, z: bool| {
+            Vec3::new(
+                if x { max.x } else { min.x },
+                if y { max.y } else { min.y },
+                if z { max.z } else { min.z },
+            )
+        };
+        let c = |i: u8| corner(i & 1 != 0, i & 2 != 0, i & 4 != 0);
+        vec![
+            quad(c(2), c(6), c(7), c(3)),
+            quad(c(0), c(2), c(3), c(1)),
+            quad(c(4), c(5), c(7), c(6)),
+            quad(c(0), c(4), c(6), c(2)),
+            quad(c(1), c(3), c(7), c(5)),
+            quad(c(0), c(1), c(5), c(4)),
+        ]
+    }
+
+    /// A ramp going up along +x from x = 1
+    fn ramp(degrees: f32) -> [[Vec3; 3]; 2] {
+        let rise = 20.0 * degrees.to_radians().tan();
+        quad(
+            Vec3::new(1.0, 0.0, -5.0),
+            Vec3::new(1.0, 0.0, 5.0),
+            Vec3::new(21.0, rise, 5.0),
+            Vec3::new(21.0, rise, -5.0),
+        )
+    }
+
+    fn walk(
+        controller: &mut CharacterControllerComponent,
+        mut pos: Vec3,
+        input: CharacterInput,
+        geometry: &StaticBvh,
+        steps: usize,
+    ) -> Vec3 {
+        for _ in 0..steps {
+            pos = controller.step(pos, &input, geometry, DT);
+        }
+        pos
+    }
+
+    fn forward() -> CharacterInput {
+        CharacterInput {
+            direction: Vec3::X,
+            jump: false,
+        }
+    }
+
+    fn settled(geometry: &StaticBvh, pos: Vec3) -> (CharacterControllerComponent, Vec3) {
+        let mut controller = CharacterControllerComponent::new(0.3, 1.8);
+        let pos = walk(
+            &mut controller,
+            pos,
+            CharacterInput::default(),
+            geometry,
+            60,
+        );
+        assert!(controller.grounded());
+        (controller, pos)
+    }
+
+    #[test]
+    fn wall_slide_planes() {
+        let motion = Vec3::new(1.0, 0.0, 1.0);
+        // Along a single wall
+        assert_eq!(Vec3::new(0.0, 0.0, 1.0), clip_motion(motion, &[-Vec3::X]));
+        // Leaving a wall isn't changed
+        assert_eq!(motion, clip_motion(motion, &[Vec3::X]));
+        // Into a corner of two walls, then the floor is added: along the crease
+        let wedge = [-Vec3::X, Vec3::new(0.0, 0.0, -1.0)];
+        assert_eq!(Vec3::ZERO, clip_motion(motion, &wedge));
+        let sloped = [-Vec3::X, Vec3::new(0.0, 1.0, -1.0).normalize()];
+        let along = clip_motion(Vec3::new(0.0, -1.0, 1.0), &sloped);
+        assert!(along.x.abs() < 1e-6 && along.y.abs() < 1e-6, "{along}");
+        // A V shaped crease pointing forward keeps some of the motion
+        let v = [
+            Vec3::new(-1.0, 0.0, 1.0).normalize(),
+            Vec3::new(-1.0, 0.0, -1.0).normalize(),
+        ];
+        let creased = clip_motion(Vec3::new(1.0, 1.0, 0.0), &v);
+        assert!(creased.distance(Vec3::Y) < 1e-5, "{creased}");
+        // Three planes always stop it
+        let corner = [-Vec3::X, Vec3::new(0.0, 0.0, -1.0), -Vec3::Y];
+        assert_eq!(Vec3::ZERO, clip_motion(Vec3::new(1.0, 1.0, 1.0), &corner));
+    }
+
+    #[test]
+    fn walls() {
+        let geometry = level(&block(Vec3::new(2.0, 0.0, -5.0), Vec3::new(3.0, 3.0, 5.0)));
+        let (mut controller, pos) = settled(&geometry, Vec3::new(0.0, 0.5, 0.0));
+        assert!(pos.y.abs() < SKIN * 2.0, "{pos}");
+        // Straight into it
+        let pos = walk(&mut controller, pos, forward(), &geometry, 120);
+        assert!((pos.x - (2.0 - 0.3 - SKIN)).abs() < 1e-3, "{pos}");
+        // Diagonally, sliding along it at the speed across
+        let input = CharacterInput {
+            direction: Vec3::new(1.0, 0.0, 1.0).normalize(),
+            jump: false,
+        };
+        let slid = walk(&mut controller, pos, input, &geometry, 30);
+        let expected = 30.0 * DT * controller.speed * input.direction.z;
+        assert!((slid.z - pos.z - expected).abs() < 0.01, "{slid}");
+        assert!(
+            (slid.x - pos.x).abs() < 1e-3 && slid.y.abs() < SKIN * 2.0,
+            "{slid}"
+        );
+    }
+
+    #[test]
+    fn steps() {
+        for (height, climbed) in [(0.1, true), (0.25, true), (0.5, false)] {
+            let geometry = level(&block(
+                Vec3::new(1.0, 0.0, -5.0),
+                Vec3::new(4.0, height, 5.0),
+            ));
+            let (mut controller, pos) = settled(&geometry, Vec3::ZERO);
+            let pos = walk(&mut controller, pos, forward(), &geometry, 30);
+            assert!(controller.grounded());
+            if climbed {
+                assert!(pos.x > 1.5, "{height}: {pos}");
+                assert!((pos.y - height).abs() < SKIN * 2.0, "{height}: {pos}");
+            } else {
+                assert!(pos.x < 1.0 - 0.3 + 1e-3, "{height}: {pos}");
+                assert!(pos.y.abs() < SKIN * 2.0, "{height}: {pos}");
+            }
+        }
+    }
+
+    #[test]
+    fn slopes() {
+        // Up a gentle ramp at the usual speed, and back down without leaving it
+        let geometry = level(&[ramp(30.0)]);
+        let (mut controller, pos) = settled(&geometry, Vec3::ZERO);
+        let top = walk(&mut controller, pos, forward(), &geometry, 120);
+        assert!(top.x > 5.0, "{top}");
+        let surface = (top.x - 1.0) * 30f32.to_radians().tan();
+        assert!((top.y - surface).abs() < 0.2, "{top}");
+        let back = CharacterInput {
+            direction: -Vec3::X,
+            jump: false,
+        };
+        let mut pos = top;
+        for _ in 0..60 {
+            pos = walk(&mut controller, pos, back, &geometry, 1);
+            assert!(controller.grounded() && controller.air_time == 0.0, "{pos}");
+        }
+
+        // A steep one is a wall
+        let geometry = level(&[ramp(60.0)]);
+        let (mut controller, pos) = settled(&geometry, Vec3::ZERO);
+        let pos = walk(&mut controller, pos, forward(), &geometry, 120);
+        assert!(pos.y < controller.step_height, "{pos}");
+        assert!(pos.x < 1.2, "{pos}");
+        // And is slid down when landed on
+        let mut controller = CharacterControllerComponent::new(0.3, 1.8);
+        let start = Vec3::new(4.0, 8.0, 0.0);
+        let pos = walk(
+            &mut controller,
+            start,
+            CharacterInput::default(),
+            &geometry,
+            120,
+        );
+        assert!(pos.x < 1.0 && pos.y.abs() < SKIN * 2.0, "{pos}");
+    }
+
+    #[test]
+    fn grounded_hysteresis() {
+        // Walking off a ledge higher than a step
+        let geometry = StaticBvh::build(
+            block(Vec3::new(-5.0, -2.0, -5.0), Vec3::new(1.0, 0.0, 5.0))
+                .into_iter()
+                .flatten()
+                .collect(),
+        );
+        let (mut controller, mut pos) = settled(&geometry, Vec3::ZERO);
+        let mut left = None;
+        for step in 0..60 {
+            pos = controller.step(pos, &forward(), &geometry, DT);
+            match (left, controller.ground) {
+                (None, None) => left = Some(step),
+                (Some(_), Some(_)) => panic!("Grounded again at {step}"),
+                _ => {}
+            }
+        }
+        let left = left.expect("Never left the ledge");
+        assert!(!controller.grounded());
+        // Still grounded for the coyote time, falling all the while
+        let mut controller = CharacterControllerComponent::new(0.3, 1.8);
+        let mut pos = walk(
+            &mut controller,
+            Vec3::ZERO,
+            CharacterInput::default(),
+            &geometry,
+            60,
+        );
+        pos = walk(&mut controller, pos, forward(), &geometry, left + 1);
+        let mut grace = 0;
+        while controller.grounded() {
+            pos = controller.step(pos, &CharacterInput::default(), &geometry, DT);
+            grace += 1;
+        }
+        assert!(
+            grace > 0 && grace as f32 * DT <= COYOTE_TIME + DT,
+            "{grace}"
+        );
+        assert!(controller.vertical_speed() < 0.0);
+        // A jump in the grace period goes up
+        let mut controller = CharacterControllerComponent::new(0.3, 1.8);
+        let pos = walk(
+            &mut controller,
+            Vec3::ZERO,
+            CharacterInput::default(),
+            &geometry,
+            60,
+        );
+        let pos = walk(&mut controller, pos, forward(), &geometry, left + 1);
+        let jump = CharacterInput {
+            direction: Vec3::X,
+            jump: true,
+        };
+        let after = controller.step(pos, &jump, &geometry, DT);
+        assert!(
+            after.y > pos.y && controller.vertical_speed() > 0.0,
+            "{after}"
+        );
+        // But not twice
+        let speed = controller.vertical_speed();
+        controller.step(after, &jump, &geometry, DT);
+        assert!(controller.vertical_speed() < speed);
+    }
+
+    #[test]
+    fn jump() {
+        let geometry = level(&block(Vec3::new(-5.0, 2.2, -5.0), Vec3::new(5.0, 3.0, 5.0)));
+        let (mut controller, pos) = settled(&geometry, Vec3::new(8.0, 0.0, 0.0));
+        let jump = CharacterInput {
+            direction: Vec3::ZERO,
+            jump: true,
+        };
+        let mut pos = controller.step(pos, &jump, &geometry, DT);
+        let mut peak: f32 = 0.0;
+        let mut steps = 1;
+        while !controller.grounded() {
+            pos = controller.step(pos, &CharacterInput::default(), &geometry, DT);
+            peak = peak.max(pos.y);
+            steps += 1;
+            assert!(steps < 200);
+        }
+        // v² / 2g, and back to the floor
+        let expected = controller.jump_speed.powi(2) / (2.0 * GRAVITY);
+        assert!((peak - expected).abs() < 0.1, "{peak}");
+        assert!(pos.y.abs() < SKIN * 2.0, "{pos}");
+        // Under the ceiling, bumping the head
+        let (mut controller, pos) = settled(&geometry, Vec3::ZERO);
+        let mut pos = controller.step(pos, &jump, &geometry, DT);
+        let mut peak: f32 = 0.0;
+        while !controller.grounded() {
+            pos = controller.step(pos, &CharacterInput::default(), &geometry, DT);
+            peak = peak.max(pos.y);
+        }
+        assert!(peak < 2.2 - controller.height + 1e-3, "{peak}");
+    }
+
+    #[test]
+    fn fixed_step() {
+        // The same steps whatever the frames they fall in
+        let geometry = level(&[ramp(20.0)]);
+        let run = |frames: [u64; 3]| {
+            let mut clock = FixedStep::new(DEFAULT_FIXED_STEP);
+            let mut controller = CharacterControllerComponent::new(0.3, 1.8);
+            let mut pos = Vec3::new(-2.0, 1.0, 0.3);
+            let mut steps = 0;
+            for ms in frames.iter().cycle().take(300) {
+                for _ in 0..clock.advance(Duration::from_millis(*ms)) {
+                    let input = CharacterInput {
+                        direction: Vec3::new(1.0, 0.0, (steps as f32 * 0.1).sin()).normalize(),
+                        jump: steps % 50 == 0,
+                    };
+                    pos = controller.step(pos, &input, &geometry, DEFAULT_FIXED_STEP.as_secs_f32());
+                    steps += 1;
+                }
+            }
+            (steps, pos)
+        };
+        let (steps, pos) = run([16, 17, 17]);
+        assert_eq!(299, steps);
+        assert!(pos.x > 10.0, "{pos}");
+        assert_eq!((steps, pos), run([10, 10, 30]));
+        assert_eq!((steps, pos), run([5, 5, 40]));
+    }
+
+    #[test]
+    fn stride() {
+        let mut stride = StrideAccumulator::new(0.5);
+        let mut walked = 0.0;
+        let mut steps = Vec::new();
+        // Slow, then fast, then faster than a stride per step
+        for distance in [0.1, 0.1, 0.15, 0.05, 0.3, 0.45, 1.2, 0.02] {
+            steps.extend(stride.advance(distance).map(|at| {
+                assert!(at > 0.0 && at <= distance, "{at} {distance}");
+                walked + at
+            }));
+            walked += distance;
+        }
+        let expected = [0.5, 1.0, 1.5, 2.0];
+        assert_eq!(expected.len(), steps.len(), "{steps:?}");
+        for (step, expected) in steps.iter().zip(expected) {
+            assert!((step - expected).abs() < 1e-5, "{steps:?}");
+        }
+        // None without a stride
+        assert_eq!(0, StrideAccumulator::new(0.0).advance(10.0).count());
+    }
+
+    #[test]
+    fn footstep_surfaces() {
+        // Stone up to x = 2, wood after
+        let plane = |x0: f32, x1: f32| {
+            quad(
+                Vec3::new(x0, 0.0, -5.0),
+                Vec3::new(x0, 0.0, 5.0),
+                Vec3::new(x1, 0.0, 5.0),
+                Vec3::new(x1, 0.0, -5.0),
+            )
+        };
+        let geometry = StaticBvh::build_with_surfaces(
+            plane(-5.0, 2.0)
+                .into_iter()
+                .map(|tri| (tri, SurfaceKind::Stone))
+                .chain(
+                    plane(2.0, 20.0)
+                        .into_iter()
+                        .map(|tri| (tri, SurfaceKind::Wood)),
+                )
+                .collect(),
+        );
+        let (mut controller, mut pos) = settled(&geometry, Vec3::ZERO);
+        assert_eq!(Some(SurfaceKind::Stone), controller.ground_surface());
+        let mut steps = Vec::new();
+        let mut on_ground = 0.0;
+        for i in 0..150 {
+            let input = CharacterInput {
+                direction: Vec3::X,
+                // A jump in the middle
+                jump: i == 30,
+            };
+            let before = pos;
+            pos = controller.step(pos, &input, &geometry, DT);
+            if controller.ground_surface().is_some() {
+                on_ground += pos.x - before.x;
+            }
+            steps.extend(controller.footsteps(before, pos));
+        }
+        assert!(pos.x > 9.0, "{pos}");
+        // One per stride walked on the ground, none in the air
+        assert!(on_ground < pos.x - 3.0, "{on_ground}");
+        assert_eq!((on_ground / STRIDE) as usize, steps.len());
+        for step in &steps {
+            assert!(step.position.y.abs() < SKIN * 2.0, "{step:?}");
+            let expected = if step.position.x < 2.0 - controller.radius {
+                SurfaceKind::Stone
+            } else if step.position.x > 2.0 + controller.radius {
+                SurfaceKind::Wood
+            } else {
+                continue;
+            };
+            assert_eq!(expected, step.surface, "{step:?}");
+        }
+        assert!(steps.iter().any(|s| s.surface == SurfaceKind::Stone));
+        assert!(steps.iter().any(|s| s.surface == SurfaceKind::Wood));
+    }
+}
@@ -0,0 +1,741 @@
+//! Collisions of capsules against the static geometry of the scene.
+//!
+//! The triangles of every entity with a `StaticGeometryComponent` are put in a bounding volume
+//! hierarchy (median split AABB tree), which answers box queries and capsule sweeps, along with the
+//! surface of their entity (`SurfaceMaterialComponent`). Sweeps use
+//! conservative advancement: the distance between a triangle and a translating capsule is convex
+//! in time, so it can't reach the skin before the time given by its current distance and rate of
+//! approach, and the capsule is advanced to the earliest such time until it touches.
+
+use std::sync::Arc;
+
+use ecs::{Entities, EventReader};
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+
+use crate::{components::TransformsComponent, save::GameLoaded};
+
+use super::graphics::mesh_manager::Mesh;
+
+/// Distance kept between a capsule and the geometry after a sweep
+pub const SKIN: f32 = 0.01;
+/// Triangles per leaf of the BVH
+const LEAF_SIZE: usize = 4;
+/// Advancement steps of a sweep before giving up and reporting a hit
+const SWEEP_ITERATIONS: usize = 16;
+
+/// Static geometry characters collide with, and agents walk on (see `navmesh`)
+#[derive(Clone)]
+pub struct StaticGeometryComponent {
+    /// Triangles in the space of the entity (its TransformsComponent is applied)
+    pub triangles: Arc<[[Vec3; 3]]>,
+}
+
+impl StaticGeometryComponent {
+    pub fn new(triangles: impl Into<Arc<[[Vec3; 3]]>>) -> Self {
+        Self {
+            triangles: triangles.into(),
+        }
+    }
+    pub fn from_mesh(mesh: &Mesh) -> Self {
+        let triangles: Vec<[Vec3; 3]> = mesh
+            .indices
+            .iter()
+            .map(|tri| tri.map(|i| mesh.vertices[i as usize].position))
+            .collect();
+        Self::new(triangles)
+    }
+}
+
+/// What a surface is made of, for the sound of the steps on it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SurfaceKind {
+    #[default]
+    Default,
+    Stone,
+    Wood,
+    Grass,
+    Gravel,
+    Metal,
+    Water,
+}
+
+/// The surface of the static geometry of the entity, `SurfaceKind::Default` without
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SurfaceMaterialComponent(pub SurfaceKind);
+
+/// The points within radius of the segment from a to b
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capsule {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub radius: f32,
+}
+
+impl Capsule {
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        let radius = Vec3::splat(self.radius);
+        (self.a.min(self.b) - radius, self.a.max(self.b) + radius)
+    }
+    pub fn translated(&self, offset: Vec3) -> Self {
+        Self {
+            a: self.a + offset,
+            b: self.b + offset,
+            ..*self
+        }
+    }
+    /// Distance from the surface of the capsule to a triangle (negative when they overlap), and
+    /// the direction from the triangle to the capsule
+    pub fn distance(&self, triangle: &[Vec3; 3]) -> (f32, Vec3) {
+        let (on_segment, on_triangle) = closest_segment_triangle(self.a, self.b, triangle);
+        let offset = on_segment - on_triangle;
+        let length = offset.length();
+        let normal = if length > 1e-6 {
+            offset / length
+        } else {
+            // The segment goes through the triangle, push out on the side of its center
+            let [a, b, c] = *triangle;
+            let face = (b - a).cross(c - a).normalize_or_zero();
+            let side = face.dot((self.a + self.b) * 0.5 - (a + b + c) / 3.0);
+            if side < 0.0 {
+                -face
+            } else {
+                face
+            }
+        };
+        (length - self.radius, normal)
+    }
+}
+
+/// Closest point to p on the triangle abc (Real-Time Collision Detection, 5.1.5)
+pub fn closest_point_triangle(p: Vec3, [a, b, c]: [Vec3; 3]) -> Vec3 {
+    let (ab, ac, ap) = (b - a, c - a, p - a);
+    let (d1, d2) = (ab.dot(ap), ac.dot(ap));
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+    let bp = p - b;
+    let (d3, d4) = (ab.dot(bp), ac.dot(bp));
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+    let cp = p - c;
+    let (d5, d6) = (ab.dot(cp), ac.dot(cp));
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+    let denom = 1.0 / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+/// Closest points of the segments p1q1 and p2q2 (Real-Time Collision Detection, 5.1.9)
+pub fn closest_segments(p1: Vec3, q1: Vec3, p2: Vec3, q2: Vec3) -> (Vec3, Vec3) {
+    let (d1, d2, r) = (q1 - p1, q2 - p2, p1 - p2);
+    let (a, e, f) = (d1.length_squared(), d2.length_squared(), d2.dot(r));
+    const EPSILON: f32 = 1e-12;
+    let (s, t) = if a <= EPSILON && e <= EPSILON {
+        (0.0, 0.0)
+    } else if a <= EPSILON {
+        (0.0, (f / e).clamp(0.0, 1.0))
+    } else {
+        let c = d1.dot(r);
+        if e <= EPSILON {
+            ((-c / a).clamp(0.0, 1.0), 0.0)
+        } else {
+            let b = d1.dot(d2);
+            let denom = a * e - b * b;
+            let mut s = if denom > EPSILON {
+                ((b * f - c * e) / denom).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let mut t = (b * s + f) / e;
+            if t < 0.0 {
+                t = 0.0;
+                s = (-c / a).clamp(0.0, 1.0);
+            } else if t > 1.0 {
+                t = 1.0;
+                s = ((b - c) / a).clamp(0.0, 1.0);
+            }
+            (s, t)
+        }
+    };
+    (p1 + d1 * s, p2 + d2 * t)
+}
+
+/// Closest points of the segment pq and a triangle: where the segment crosses the triangle, or
+/// the closest of the endpoints against the face and of the segment against the edges
+pub fn closest_segment_triangle(p: Vec3, q: Vec3, triangle: &[Vec3; 3]) -> (Vec3, Vec3) {
+    let [a, b, c] = *triangle;
+    let normal = (b - a).cross(c - a);
+    if normal.length_squared() > 1e-12 {
+        let (dp, dq) = (normal.dot(p - a), normal.dot(q - a));
+        if dp * dq <= 0.0 && dp != dq {
+            let crossing = p + (q - p) * (dp / (dp - dq));
+            if closest_point_triangle(crossing, *triangle).distance_squared(crossing) < 1e-10 {
+                return (crossing, crossing);
+            }
+        }
+    }
+    let mut best = (p, closest_point_triangle(p, *triangle));
+    let mut consider = |pair: (Vec3, Vec3)| {
+        if pair.0.distance_squared(pair.1) < best.0.distance_squared(best.1) {
+            best = pair;
+        }
+    };
+    consider((q, closest_point_triangle(q, *triangle)));
+    for (from, to) in [(a, b), (b, c), (c, a)] {
+        consider(closest_segments(p, q, from, to));
+    }
+    best
+}
+
+fn overlaps(min: Vec3, max: Vec3, other_min: Vec3, other_max: Vec3) -> bool {
+    min.cmple(other_max).all() && other_min.cmple(max).all()
+}
+
+fn triangle_bounds(triangle: &[Vec3; 3]) -> (Vec3, Vec3) {
+    let [a, b, c] = *triangle;
+    (a.min(b).min(c), a.max(b).max(c))
+}
+
+/// Where a sweep stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SweepHit {
+    /// Fraction of the motion done before touching, the capsule is then `SKIN` away
+    pub toi: f32,
+    /// From the geometry to the capsule at the contact
+    pub normal: Vec3,
+    /// Normal of the triangle touched, on the side of the capsule. Of the triangles touched at
+    /// once (both sides of an edge), the one most facing the motion.
+    pub face: Vec3,
+    /// Index of that triangle in the ones swept against, in the BVH for `StaticBvh::sweep` (see
+    /// `StaticBvh::surface`)
+    pub triangle: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BvhNode {
+    min: Vec3,
+    max: Vec3,
+    /// First triangle of a leaf, or right child of an inner node (the left one is next)
+    index: u32,
+    /// Triangles of a leaf, 0 for inner nodes
+    count: u32,
+}
+
+/// Bounding volume hierarchy of the static triangles, in world space
+#[derive(Default)]
+pub struct StaticBvh {
+    triangles: Vec<[Vec3; 3]>,
+    /// Surface of each triangle
+    surfaces: Vec<SurfaceKind>,
+    nodes: Vec<BvhNode>,
+}
+
+impl StaticBvh {
+    /// The BVH of the static entities of a scene
+    pub fn from_scene<'a>(
+        statics: impl IntoIterator<
+            Item = (
+                &'a StaticGeometryComponent,
+                &'a TransformsComponent,
+                Option<&'a SurfaceMaterialComponent>,
+            ),
+        >,
+    ) -> Self {
+        let triangles = statics
+            .into_iter()
+            .flat_map(|(geometry, transforms, surface)| {
+                let mat = transforms.mat();
+                let surface = surface.map_or_else(SurfaceKind::default, |s| s.0);
+                geometry
+                    .triangles
+                    .iter()
+                    .map(move |tri| (tri.map(|p| mat.transform_point3(p)), surface))
+            })
+            .collect();
+        Self::build_with_surfaces(triangles)
+    }
+    /// System rebuilding the BVH when a game is loaded, as its scene is spawned again
+    pub fn game_loaded(
+        &mut self,
+        loaded: EventReader<GameLoaded>,
+        statics: Entities<(
+            &StaticGeometryComponent,
+            &TransformsComponent,
+            Option<&SurfaceMaterialComponent>,
+        )>,
+    ) {
+        if !loaded.is_empty() {
+            *self = Self::from_scene(statics);
+        }
+    }
+    /// Build from triangles in world space
+    pub fn build(triangles: Vec<[Vec3; 3]>) -> Self {
+        Self::build_with_surfaces(
+            triangles
+                .into_iter()
+                .map(|tri| (tri, SurfaceKind::default()))
+                .collect(),
+        )
+    }
+    /// Build from triangles in world space and their surfaces
+    pub fn build_with_surfaces(triangles: Vec<([Vec3; 3], SurfaceKind)>) -> Self {
+        let mut items = triangles
+            .into_iter()
+            .map(|(tri, surface)| ((tri[0] + tri[1] + tri[2]) / 3.0, tri, surface))
+            .collect::<Vec<_>>();
+        let mut nodes = Vec::with_capacity(items.len() / LEAF_SIZE * 2 + 1);
+        if !items.is_empty() {
+            Self::build_node(&mut nodes, &mut items, 0);
+        }
+        Self {
+            triangles: items.iter().map(|(_, tri, _)| *tri).collect(),
+            surfaces: items.iter().map(|(_, _, surface)| *surface).collect(),
+            nodes,
+        }
+    }
+    /// Add the node of items (starting at first in the final order), returns its index
+    fn build_node(
+        nodes: &mut Vec<BvhNode>,
+        items: &mut [(Vec3, [Vec3; 3], SurfaceKind)],
+        first: usize,
+    ) -> usize {
+        let (mut min, mut max) = (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY));
+        let (mut centroid_min, mut centroid_max) = (min, max);
+        for (centroid, tri, _) in items.iter() {
+            let (tri_min, tri_max) = triangle_bounds(tri);
+            min = min.min(tri_min);
+            max = max.max(tri_max);
+            centroid_min = centroid_min.min(*centroid);
+            centroid_max = centroid_max.max(*centroid);
+        }
+        let index = nodes.len();
+        nodes.push(BvhNode {
+            min,
+            max,
+            index: first as u32,
+            count: items.len() as u32,
+        });
+        let extent = centroid_max - centroid_min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        if items.len() <= LEAF_SIZE || extent[axis] <= 0.0 {
+            return index;
+        }
+        let mid = items.len() / 2;
+        items.select_nth_unstable_by(mid, |(a, ..), (b, ..)| a[axis].total_cmp(&b[axis]));
+        let (left, right) = items.split_at_mut(mid);
+        Self::build_node(nodes, left, first);
+        let right = Self::build_node(nodes, right, first + mid);
+        nodes[index].index = right as u32;
+        nodes[index].count = 0;
+        index
+    }
+    pub fn len(&self) -> usize {
+        self.triangles.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.triangles.is_empty()
+    }
+    /// The surface of a triangle of the BVH
+    pub fn surface(&self, triangle: usize) -> SurfaceKind {
+        self.surfaces[triangle]
+    }
+    /// Call f on the triangles whose bounds overlap the box
+    pub fn query(&self, min: Vec3, max: Vec3, mut f: impl FnMut(&[Vec3; 3])) {
+        self.query_indexed(min, max, |_, tri| f(tri));
+    }
+    /// `StaticBvh::query`, with the indices of the triangles
+    fn query_indexed(&self, min: Vec3, max: Vec3, mut f: impl FnMut(usize, &[Vec3; 3])) {
+        let mut stack = Vec::with_capacity(32);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !overlaps(node.min, node.max, min, max) {
+                continue;
+            }
+            if node.count == 0 {
+                stack.push(node.index as usize);
+                stack.push(index + 1);
+                continue;
+            }
+            let start = node.index as usize;
+            for (i, tri) in self.triangles[start..start + node.count as usize]
+                .iter()
+                .enumerate()
+            {
+                let (tri_min, tri_max) = triangle_bounds(tri);
+                if overlaps(tri_min, tri_max, min, max) {
+                    f(start + i, tri);
+                }
+            }
+        }
+    }
+    /// The deepest overlap of the capsule with the geometry: its depth, and the direction to push
+    /// the capsule out
+    pub fn penetration(&self, capsule: &Capsule) -> Option<(f32, Vec3)> {
+        let (min, max) = capsule.bounds();
+        let mut deepest: Option<(f32, Vec3)> = None;
+        self.query(min, max, |tri| {
+            let (distance, normal) = capsule.distance(tri);
+            if distance < 0.0 && deepest.map_or(true, |(depth, _)| -distance > depth) {
+                deepest = Some((-distance, normal));
+            }
+        });
+        deepest
+    }
+    /// Move the capsule by motion until it comes within `SKIN` of the geometry. Triangles the
+    /// capsule already touches only stop it if it moves towards them.
+    pub fn sweep(&self, capsule: &Capsule, motion: Vec3) -> Option<SweepHit> {
+        let (min, max) = capsule.bounds();
+        let margin = Vec3::splat(SKIN * 2.0);
+        let mut candidates = Vec::new();
+        let mut indices = Vec::new();
+        self.query_indexed(
+            min.min(min + motion) - margin,
+            max.max(max + motion) + margin,
+            |i, tri| {
+                candidates.push(*tri);
+                indices.push(i);
+            },
+        );
+        sweep_triangles(capsule, motion, &candidates).map(|hit| SweepHit {
+            triangle: indices[hit.triangle],
+            ..hit
+        })
+    }
+}
+
+/// `StaticBvh::sweep` against a list of triangles
+pub fn sweep_triangles(
+    capsule: &Capsule,
+    motion: Vec3,
+    triangles: &[[Vec3; 3]],
+) -> Option<SweepHit> {
+    if motion == Vec3::ZERO {
+        return None;
+    }
+    // Grazing contacts (sliding along a floor) are ignored, the normal of the closest points isn't
+    // exact enough to tell whether they move towards the triangle
+    let grazing = motion.length() * 1e-3;
+    let mut toi = 0.0;
+    let mut last = None;
+    for _ in 0..SWEEP_ITERATIONS {
+        let moved = capsule.translated(motion * toi);
+        let mut next = f32::INFINITY;
+        let mut touched: Option<SweepHit> = None;
+        for (i, tri) in triangles.iter().enumerate() {
+            let (distance, normal) = moved.distance(tri);
+            // Rate at which the distance shrinks per unit of toi
+            let approach = -motion.dot(normal);
+            if approach <= grazing {
+                // Convex in time: a distance that isn't shrinking never will
+                continue;
+            }
+            if distance <= SKIN + 1e-4 {
+                let face = face_normal(tri, normal);
+                if touched.map_or(true, |hit| face.dot(motion) < hit.face.dot(motion)) {
+                    touched = Some(SweepHit {
+                        toi,
+                        normal,
+                        face,
+                        triangle: i,
+                    });
+                }
+                continue;
+            }
+            let reach = toi + (distance - SKIN) / approach;
+            if reach < next {
+                next = reach;
+                last = Some((i, normal));
+            }
+        }
+        if touched.is_some() {
+            return touched;
+        }
+        if next >= 1.0 {
+            return None;
+        }
+        toi = next;
+    }
+    last.map(|(triangle, normal)| SweepHit {
+        toi,
+        normal,
+        face: face_normal(&triangles[triangle], normal),
+        triangle,
+    })
+}
+
+/// Normal of the triangle on the side of normal, or normal if it's degenerate
+fn face_normal([a, b, c]: &[Vec3; 3], normal: Vec3) -> Vec3 {
+    let face = (*b - *a).cross(*c - *a).normalize_or_zero();
+    if face == Vec3::ZERO {
+        normal
+    } else if face.dot(normal) < 0.0 {
+        -face
+    } else {
+        face
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn floor() -> [Vec3; 3] {
+        [
+            Vec3::new(-10.0, 0.0, -10.0),
+            Vec3::new(-10.0, 0.0, 10.0),
+            Vec3::new(10.0, 0.0, 0.0),
+        ]
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn closest_points() {
+        let tri = [Vec3::ZERO, Vec3::X, Vec3::Z];
+        // Above the face, beyond a vertex, beyond an edge
+        let p = Vec3::new(0.25, 2.0, 0.25);
+        assert_eq!(Vec3::new(0.25, 0.0, 0.25), closest_point_triangle(p, tri));
+        assert_eq!(
+            Vec3::X,
+            closest_point_triangle(Vec3::new(3.0, 1.0, -1.0), tri)
+        );
+        let p = closest_point_triangle(Vec3::new(1.0, 0.0, 1.0), tri);
+        assert!(p.distance(Vec3::new(0.5, 0.0, 0.5)) < 1e-6);
+
+        // Skew segments, and parallel ones
+        let (a, b) = closest_segments(
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.5, 1.0, -1.0),
+            Vec3::new(0.5, 1.0, 1.0),
+        );
+        assert_eq!((Vec3::new(0.5, 0.0, 0.0), Vec3::new(0.5, 1.0, 0.0)), (a, b));
+        let (a, b) = closest_segments(
+            Vec3::ZERO,
+            Vec3::X,
+            Vec3::new(2.0, 1.0, 0.0),
+            Vec3::new(3.0, 1.0, 0.0),
+        );
+        assert_eq!((Vec3::X, Vec3::new(2.0, 1.0, 0.0)), (a, b));
+    }
+
+    #[test]
+    fn capsule_distance() {
+        let floor = floor();
+        // Standing, lying, and through the floor
+        let standing = Capsule {
+            a: Vec3::new(0.0, 1.5, 0.0),
+            b: Vec3::new(0.0, 3.0, 0.0),
+            radius: 0.5,
+        };
+        let (distance, normal) = standing.distance(&floor);
+        assert!(close(1.0, distance));
+        assert_eq!(Vec3::Y, normal);
+        let lying = Capsule {
+            a: Vec3::new(-1.0, 0.3, 0.0),
+            b: Vec3::new(1.0, 0.2, 0.0),
+            radius: 0.5,
+        };
+        assert!(close(-0.3, lying.distance(&floor).0));
+        let through = standing.translated(Vec3::new(0.0, -2.0, 0.0));
+        let (distance, normal) = through.distance(&floor);
+        assert!(close(-0.5, distance));
+        assert_eq!(Vec3::Y, normal);
+        // Next to an edge, the normal points away from it
+        let wall = [
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ];
+        let beside = Capsule {
+            a: Vec3::new(1.0, 3.0, 0.0),
+            b: Vec3::new(1.0, 4.0, 0.0),
+            radius: 0.25,
+        };
+        let (distance, normal) = beside.distance(&wall);
+        assert!(close(2.0f32.sqrt() - 0.25, distance));
+        assert!(normal.distance(Vec3::new(1.0, 1.0, 0.0).normalize()) < 1e-5);
+    }
+
+    #[test]
+    fn sweeps() {
+        let floor = floor();
+        let capsule = Capsule {
+            a: Vec3::new(0.0, 2.5, 0.0),
+            b: Vec3::new(0.0, 4.0, 0.0),
+            radius: 0.5,
+        };
+        // Falling 3 units with 2 to go
+        let hit = sweep_triangles(&capsule, Vec3::new(0.0, -3.0, 0.0), &[floor]).unwrap();
+        assert!(close((2.0 - SKIN) / 3.0, hit.toi), "{hit:?}");
+        assert_eq!(Vec3::Y, hit.normal);
+        // Not far enough, away from it, along it
+        assert_eq!(
+            None,
+            sweep_triangles(&capsule, Vec3::new(0.0, -1.0, 0.0), &[floor])
+        );
+        assert_eq!(None, sweep_triangles(&capsule, Vec3::Y, &[floor]));
+        let resting = capsule.translated(Vec3::new(0.0, -2.0 + SKIN, 0.0));
+        assert_eq!(
+            None,
+            sweep_triangles(&resting, Vec3::new(3.0, 0.0, 1.0), &[floor])
+        );
+        // Diagonally against a wall
+        let wall = [
+            Vec3::new(2.0, -10.0, -10.0),
+            Vec3::new(2.0, 10.0, 0.0),
+            Vec3::new(2.0, -10.0, 10.0),
+        ];
+        let hit = sweep_triangles(&capsule, Vec3::new(3.0, 0.0, 3.0), &[wall]).unwrap();
+        assert!(close((1.5 - SKIN) / 3.0, hit.toi), "{hit:?}");
+        assert!(hit.normal.distance(-Vec3::X) < 1e-5);
+        // The first of several
+        let hit = sweep_triangles(&capsule, Vec3::new(3.0, -3.0, 0.0), &[wall, floor]).unwrap();
+        assert!(close((1.5 - SKIN) / 3.0, hit.toi), "{hit:?}");
+        assert_eq!(0, hit.triangle);
+        // On the edge of a box, the top is the face touched
+        let top = [
+            Vec3::new(-4.0, 0.0, -4.0),
+            Vec3::new(-4.0, 0.0, 4.0),
+            Vec3::new(0.0, 0.0, 0.0),
+        ];
+        let side = [
+            Vec3::new(0.0, 0.0, -4.0),
+            Vec3::new(0.0, 0.0, 4.0),
+            Vec3::new(0.0, -4.0, 0.0),
+        ];
+        let edge = capsule.translated(Vec3::new(0.2, 0.0, 0.0));
+        for triangles in [[top, side], [side, top]] {
+            let hit = sweep_triangles(&edge, Vec3::new(0.0, -3.0, 0.0), &triangles).unwrap();
+            assert!((hit.normal.x - 0.2 / (0.5 + SKIN)).abs() < 1e-3, "{hit:?}");
+            assert_eq!(Vec3::Y, hit.face);
+        }
+    }
+
+    fn random_triangles(rng: &mut StdRng, count: usize) -> Vec<[Vec3; 3]> {
+        let mut point = |center: Vec3, spread: f32| {
+            center + Vec3::new(rng.gen(), rng.gen(), rng.gen()) * spread - spread / 2.0
+        };
+        (0..count)
+            .map(|_| {
+                let center = point(Vec3::ZERO, 50.0);
+                [point(center, 2.0), point(center, 2.0), point(center, 2.0)]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bvh_query() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let triangles = random_triangles(&mut rng, 1000);
+        let bvh = StaticBvh::build(triangles.clone());
+        assert_eq!(1000, bvh.len());
+        let key = |tri: &[Vec3; 3]| tri.map(|v| v.to_array().map(f32::to_bits));
+        for _ in 0..200 {
+            let center = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 60.0 - 30.0;
+            let half = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 8.0;
+            let (min, max) = (center - half, center + half);
+            let mut expected = triangles
+                .iter()
+                .filter(|tri| {
+                    let (tri_min, tri_max) = triangle_bounds(tri);
+                    overlaps(tri_min, tri_max, min, max)
+                })
+                .map(key)
+                .collect::<Vec<_>>();
+            let mut found = Vec::new();
+            bvh.query(min, max, |tri| found.push(key(tri)));
+            expected.sort();
+            found.sort();
+            assert_eq!(expected, found);
+        }
+        // Sweeps see the same triangles
+        for _ in 0..50 {
+            let start = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 50.0 - 25.0;
+            let capsule = Capsule {
+                a: start,
+                b: start + Vec3::Y,
+                radius: 0.4,
+            };
+            let motion = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 10.0 - 5.0;
+            // Up to rounding, the triangles are in another order
+            match (
+                sweep_triangles(&capsule, motion, &triangles),
+                bvh.sweep(&capsule, motion),
+            ) {
+                (None, None) => {}
+                (Some(a), Some(b)) => {
+                    assert!(
+                        close(a.toi, b.toi) && a.normal.distance(b.normal) < 1e-3,
+                        "{a:?} {b:?}"
+                    );
+                }
+                (a, b) => panic!("{a:?} {b:?}"),
+            }
+        }
+        assert!(StaticBvh::build(Vec::new())
+            .sweep(&capsule_at(Vec3::ZERO), Vec3::X)
+            .is_none());
+    }
+
+    fn capsule_at(pos: Vec3) -> Capsule {
+        Capsule {
+            a: pos,
+            b: pos + Vec3::Y,
+            radius: 0.5,
+        }
+    }
+
+    #[test]
+    fn surfaces() {
+        let wall = [
+            Vec3::new(2.0, -10.0, -10.0),
+            Vec3::new(2.0, 10.0, 0.0),
+            Vec3::new(2.0, -10.0, 10.0),
+        ];
+        let floor = StaticGeometryComponent::new(vec![floor()]);
+        let wall = StaticGeometryComponent::new(vec![wall]);
+        let mut raised = TransformsComponent::new();
+        raised.set_translation(Vec3::Y);
+        let wood = SurfaceMaterialComponent(SurfaceKind::Wood);
+        let bvh = StaticBvh::from_scene([
+            (&floor, &raised, Some(&wood)),
+            (&wall, &TransformsComponent::new(), None),
+        ]);
+        let capsule = capsule_at(Vec3::new(0.0, 3.0, 0.0));
+        let down = bvh.sweep(&capsule, Vec3::new(0.0, -3.0, 0.0)).unwrap();
+        assert!(close((1.5 - SKIN) / 3.0, down.toi), "{down:?}");
+        assert_eq!(SurfaceKind::Wood, bvh.surface(down.triangle));
+        let side = bvh.sweep(&capsule, Vec3::new(3.0, 0.0, 0.0)).unwrap();
+        assert_eq!(SurfaceKind::Default, bvh.surface(side.triangle));
+    }
+}
@@ -0,0 +1,193 @@
+//! Sounds of the footsteps of the characters.
+//!
+//! Each `FootstepEvent` plays one of the clips of its surface from the `FootstepBank` (or of
+//! `SurfaceKind::Default` if the surface has none), picked at random with a slightly random
+//! pitch so that walking doesn't sound like a loop. The randomness comes from the GameRng.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    audio::{AudioCommand, AudioSourceHandle, Mixer},
+    character::{FootstepEvent, FootstepEvents},
+    collision::SurfaceKind,
+    rng::GameRng,
+};
+
+/// Name of the settings file, in the resources directory
+const SETTINGS_FILE: &str = "footsteps.json";
+
+/// The clips of the footsteps on each surface, a resource
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FootstepBank {
+    pub clips: HashMap<SurfaceKind, Vec<AudioSourceHandle>>,
+    pub volume: f32,
+    /// Largest change of pitch, as a fraction of the normal one
+    pub pitch_variation: f32,
+}
+
+impl Default for FootstepBank {
+    fn default() -> Self {
+        Self {
+            clips: HashMap::new(),
+            volume: 0.6,
+            pitch_variation: 0.08,
+        }
+    }
+}
+
+impl FootstepBank {
+    /// Where the bank is saved
+    pub fn path() -> PathBuf {
+        rmanage::instance().directory().join(SETTINGS_FILE)
+    }
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            std::fs::File::open(path).with_context(|| format!("can't open {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("{} isn't a footstep bank", path.display()))
+    }
+    /// The saved bank, or an empty one if there is none
+    pub fn load_or_default() -> Self {
+        let path = Self::path();
+        if !path.exists() {
+            return Self::default();
+        }
+        Self::load(&path).unwrap_or_else(|e| {
+            log::warn!("Couldn't load the footstep bank: {e:#}");
+            Self::default()
+        })
+    }
+    /// The command playing a footstep, None if there is no clip for it
+    pub fn footstep(&self, event: &FootstepEvent, rng: &mut GameRng) -> Option<AudioCommand> {
+        let clips = self
+            .clips
+            .get(&event.surface)
+            .filter(|clips| !clips.is_empty())
+            .or_else(|| self.clips.get(&SurfaceKind::Default))
+            .filter(|clips| !clips.is_empty())?;
+        let sound = clips[rng.gen_range(0..clips.len())].clone();
+        let variation = self.pitch_variation.abs();
+        let pitch = if variation > 0.0 {
+            1.0 + rng.gen_range(-variation..=variation)
+        } else {
+            1.0
+        };
+        Some(AudioCommand::PlayAt {
+            sound,
+            volume: self.volume,
+            pitch,
+            position: event.position,
+        })
+    }
+}
+
+/// Play the footsteps of the frame
+pub fn play_footsteps(
+    bank: &FootstepBank,
+    events: &mut FootstepEvents,
+    rng: &mut GameRng,
+    mixer: &mut Mixer,
+) {
+    for event in events.drain() {
+        if let Some(command) = bank.footstep(&event, rng) {
+            mixer.push(command);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::*;
+
+    fn bank() -> FootstepBank {
+        let clips = |names: &[&str]| names.iter().map(|&n| n.into()).collect();
+        FootstepBank {
+            clips: HashMap::from([
+                (SurfaceKind::Default, clips(&["step"])),
+                (SurfaceKind::Wood, clips(&["wood_1", "wood_2", "wood_3"])),
+                (SurfaceKind::Grass, Vec::new()),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn step(surface: SurfaceKind) -> FootstepEvent {
+        FootstepEvent {
+            position: Vec3::new(1.0, 0.0, 2.0),
+            surface,
+        }
+    }
+
+    #[test]
+    fn variations() {
+        let bank = bank();
+        let play = |seed| {
+            let mut rng = GameRng::new(seed);
+            (0..32)
+                .map(|_| bank.footstep(&step(SurfaceKind::Wood), &mut rng).unwrap())
+                .collect::<Vec<_>>()
+        };
+        // Same seed, same sounds
+        let steps = play(3);
+        assert_eq!(steps, play(3));
+        assert_ne!(steps, play(4));
+        let mut used = Vec::new();
+        for command in steps {
+            let AudioCommand::PlayAt {
+                sound,
+                pitch,
+                position,
+                ..
+            } = command
+            else {
+                panic!("{command:?}");
+            };
+            assert!(sound.name().starts_with("wood_"), "{sound:?}");
+            assert!((pitch - 1.0).abs() <= bank.pitch_variation, "{pitch}");
+            assert_eq!(Vec3::new(1.0, 0.0, 2.0), position);
+            if !used.contains(&sound) {
+                used.push(sound);
+            }
+        }
+        assert_eq!(3, used.len());
+
+        // Surfaces without clips fall back on the default ones
+        let mut rng = GameRng::new(0);
+        for surface in [SurfaceKind::Grass, SurfaceKind::Stone] {
+            let command = bank.footstep(&step(surface), &mut rng);
+            assert!(
+                matches!(&command, Some(AudioCommand::PlayAt { sound, .. }) if sound.name() == "step"),
+                "{command:?}"
+            );
+        }
+        assert_eq!(
+            None,
+            FootstepBank::default().footstep(&step(SurfaceKind::Stone), &mut rng)
+        );
+    }
+
+    #[test]
+    fn bank_roundtrip() {
+        let bank = bank();
+        let json = serde_json::to_string(&bank).unwrap();
+        assert!(
+            json.contains(r#""wood":["wood_1","wood_2","wood_3"]"#),
+            "{json}"
+        );
+        assert_eq!(bank, serde_json::from_str(&json).unwrap());
+        let partial: FootstepBank = serde_json::from_str(r#"{"volume": 0.5}"#).unwrap();
+        assert_eq!(0.5, partial.volume);
+        assert_eq!(
+            FootstepBank::default().pitch_variation,
+            partial.pitch_variation
+        );
+    }
+}
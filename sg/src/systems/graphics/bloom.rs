@@ -0,0 +1,415 @@
+//! Bloom of the bright parts of the HDR scene.
+//!
+//! The scene is downsampled into a chain of `LEVELS` targets, each half the size of the previous
+//! one, with the 13 taps filter of Jimenez (Call of Duty, 2014). The first downsample only keeps
+//! what is brighter than the threshold, with a soft knee (`soft_threshold`). The chain is then
+//! upsampled back with a 3x3 tent, each level adding the blurred one under it, and the first
+//! level is added to the scene scaled by the intensity, before tonemapping. Every pass is a
+//! fragment pass, there is no compute.
+
+use glam::Vec3;
+
+use crate::include_shader;
+
+use super::{
+    memory::texture_bytes,
+    pipeline::{Pipeline, RenderPipeline},
+    tonemap::HDR_FORMAT,
+};
+
+/// Targets of the chain, the last one is 1/32 of the screen
+pub const LEVELS: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    /// Brightness (largest channel, after the exposure) from which pixels bloom
+    pub threshold: f32,
+    /// Width of the transition around the threshold
+    pub knee: f32,
+    /// How much of the blurred bright parts is added to the scene
+    pub intensity: f32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: 1.0,
+            knee: 0.5,
+            intensity: 0.3,
+        }
+    }
+}
+
+/// What of `color` blooms: nothing below `threshold - knee`, everything above the threshold
+/// minus it, and a quadratic curve between. Mirrors `soft_threshold` of `bloom.wgsl`.
+pub fn soft_threshold(color: Vec3, threshold: f32, knee: f32) -> Vec3 {
+    let brightness = color.max_element();
+    let soft = (brightness - threshold + knee).clamp(0.0, 2.0 * knee);
+    let soft = soft * soft / (4.0 * knee + 1e-4);
+    color * (soft.max(brightness - threshold) / brightness.max(1e-4))
+}
+
+// Mirrors BloomParams in bloom.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct BloomParams {
+    threshold: f32,
+    knee: f32,
+    padding: [f32; 2],
+}
+
+pub struct Bloom {
+    pub settings: BloomSettings,
+    prefilter_pipeline: RenderPipeline,
+    downsample_pipeline: RenderPipeline,
+    upsample_pipeline: RenderPipeline,
+    params: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    /// The chain, from half resolution down
+    levels: Vec<wgpu::TextureView>,
+    /// Bind groups reading the scene, then each level
+    sources: Vec<wgpu::BindGroup>,
+}
+
+impl Bloom {
+    /// Bloom of `hdr`, the scene of `size` before tonemapping
+    pub fn new(device: &wgpu::Device, hdr: &wgpu::TextureView, size: (u32, u32)) -> Self {
+        let layout = create_bind_group_layout!(device, "Bloom Bindgroup Layout": {
+            0 => FRAGMENT | Texture(sample: FloatFilterable, view_dim: D2),
+            1 => FRAGMENT | Sampler(Filtering),
+            2 => FRAGMENT | Buffer(type: Uniform),
+        });
+        let shader = include_shader!("bloom.wgsl", "Bloom Shader");
+        // The upsampled levels are added to the next one (and to the scene) scaled by the blend
+        // constant
+        let add = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::Constant,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let pipeline = |entry_point: &'static str, blend: Option<wgpu::BlendState>, label| {
+            Pipeline::new(
+                device,
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some(label),
+                    bind_group_layouts: &[&layout],
+                    push_constant_ranges: &[],
+                }),
+                shader.clone(),
+                move |device, layout, module| {
+                    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                        label: Some(label),
+                        layout: Some(layout),
+                        vertex: wgpu::VertexState {
+                            module,
+                            entry_point: "vs_main",
+                            buffers: &[],
+                        },
+                        fragment: Some(wgpu::FragmentState {
+                            module,
+                            entry_point,
+                            targets: &[Some(wgpu::ColorTargetState {
+                                format: HDR_FORMAT,
+                                blend,
+                                write_mask: wgpu::ColorWrites::ALL,
+                            })],
+                        }),
+                        primitive: wgpu::PrimitiveState::default(),
+                        depth_stencil: None,
+                        multisample: wgpu::MultisampleState::default(),
+                        multiview: None,
+                    })
+                },
+            )
+        };
+        let prefilter_pipeline = pipeline("fs_prefilter", None, "Bloom Prefilter Pipeline");
+        let downsample_pipeline = pipeline("fs_downsample", None, "Bloom Downsample Pipeline");
+        let upsample_pipeline = pipeline(
+            "fs_upsample",
+            Some(wgpu::BlendState {
+                color: add,
+                alpha: add,
+            }),
+            "Bloom Upsample Pipeline",
+        );
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Bloom Params"),
+            size: std::mem::size_of::<BloomParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Bloom Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let levels = Self::make_levels(device, size);
+        let sources = Self::make_sources(device, &layout, &sampler, &params, hdr, &levels);
+        Self {
+            settings: BloomSettings::default(),
+            prefilter_pipeline,
+            downsample_pipeline,
+            upsample_pipeline,
+            params,
+            sampler,
+            levels,
+            sources,
+        }
+    }
+    /// Size of each level of the chain
+    fn level_sizes(size: (u32, u32)) -> impl Iterator<Item = (u32, u32)> {
+        (1..=LEVELS).map(move |i| ((size.0 >> i).max(1), (size.1 >> i).max(1)))
+    }
+    /// Bytes taken by the chain at a size
+    pub fn memory(size: (u32, u32)) -> u64 {
+        Self::level_sizes(size)
+            .map(|(width, height)| texture_bytes(width, height, HDR_FORMAT, 1))
+            .sum()
+    }
+    fn make_levels(device: &wgpu::Device, size: (u32, u32)) -> Vec<wgpu::TextureView> {
+        Self::level_sizes(size)
+            .map(|(width, height)| {
+                device
+                    .create_texture(&wgpu::TextureDescriptor {
+                        label: Some("Bloom Level"),
+                        size: wgpu::Extent3d {
+                            width,
+                            height,
+                            depth_or_array_layers: 1,
+                        },
+                        mip_level_count: 1,
+                        sample_count: 1,
+                        dimension: wgpu::TextureDimension::D2,
+                        format: HDR_FORMAT,
+                        usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                            | wgpu::TextureUsages::TEXTURE_BINDING,
+                    })
+                    .create_view(&Default::default())
+            })
+            .collect()
+    }
+    fn make_sources(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        params: &wgpu::Buffer,
+        hdr: &wgpu::TextureView,
+        levels: &[wgpu::TextureView],
+    ) -> Vec<wgpu::BindGroup> {
+        std::iter::once(hdr)
+            .chain(levels)
+            .map(|source| {
+                create_bind_group!(device, layout, "Bloom Bindgroup": {
+                    0 | TextureView(source),
+                    1 | Sampler(sampler),
+                    2 | Buffer(buffer: params),
+                })
+            })
+            .collect()
+    }
+    /// The pipelines, to reload their shader
+    pub fn pipelines_mut(&mut self) -> [&mut RenderPipeline; 3] {
+        [
+            &mut self.prefilter_pipeline,
+            &mut self.downsample_pipeline,
+            &mut self.upsample_pipeline,
+        ]
+    }
+    /// Recreate the chain, `hdr` having been resized
+    pub fn resize(&mut self, device: &wgpu::Device, hdr: &wgpu::TextureView, size: (u32, u32)) {
+        self.levels = Self::make_levels(device, size);
+        self.sources = Self::make_sources(
+            device,
+            &self.prefilter_pipeline.pipeline.get_bind_group_layout(0),
+            &self.sampler,
+            &self.params,
+            hdr,
+            &self.levels,
+        );
+    }
+    /// Record the bloom of `hdr` into it, does nothing if the bloom is disabled
+    pub fn render(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        hdr: &wgpu::TextureView,
+    ) {
+        let s = &self.settings;
+        if !s.enabled {
+            return;
+        }
+        let params = BloomParams {
+            threshold: s.threshold,
+            knee: s.knee.max(1e-4),
+            padding: [0.0; 2],
+        };
+        queue.write_buffer(&self.params, 0, bytemuck::bytes_of(&params));
+
+        let pass = |encoder: &'_ mut wgpu::CommandEncoder,
+                    label,
+                    target,
+                    load,
+                    pipeline: &RenderPipeline,
+                    source,
+                    weight: f32| {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(label),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations { load, store: true },
+                })],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(&pipeline.pipeline);
+            pass.set_bind_group(0, &self.sources[source], &[]);
+            let weight = weight as f64;
+            pass.set_blend_constant(wgpu::Color {
+                r: weight,
+                g: weight,
+                b: weight,
+                a: weight,
+            });
+            pass.draw(0..3, 0..1);
+        };
+        let clear = wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT);
+        for (i, level) in self.levels.iter().enumerate() {
+            let pipeline = if i == 0 {
+                &self.prefilter_pipeline
+            } else {
+                &self.downsample_pipeline
+            };
+            pass(encoder, "Bloom Downsample Pass", level, clear, pipeline, i, 1.0);
+        }
+        for i in (1..self.levels.len()).rev() {
+            let target = &self.levels[i - 1];
+            let (load, pipeline) = (wgpu::LoadOp::Load, &self.upsample_pipeline);
+            pass(encoder, "Bloom Upsample Pass", target, load, pipeline, i + 1, 1.0);
+        }
+        let (load, pipeline) = (wgpu::LoadOp::Load, &self.upsample_pipeline);
+        pass(encoder, "Bloom Composite Pass", hdr, load, pipeline, 1, s.intensity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::graphics::test_device::test_device;
+
+    /// Bloom a `SIZE` square scene with a bright block in its center, returns the red channel
+    fn bloom(dim: f32, bright: f32) -> Vec<f32> {
+        const SIZE: u32 = 64;
+        let (device, queue, _) = test_device(wgpu::Features::empty());
+        let extent = wgpu::Extent3d {
+            width: SIZE,
+            height: SIZE,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HDR_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::COPY_SRC,
+        });
+        let pixels: Vec<half::f16> = (0..SIZE * SIZE)
+            .flat_map(|i| {
+                let (x, y) = (i % SIZE, i / SIZE);
+                let center = (30..34).contains(&x) && (30..34).contains(&y);
+                let value = if center { bright } else { dim };
+                [value, value, value, 1.0].map(half::f16::from_f32)
+            })
+            .collect();
+        let layout = wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: std::num::NonZeroU32::new(SIZE * 8),
+            rows_per_image: None,
+        };
+        queue.write_texture(
+            texture.as_image_copy(),
+            bytemuck::cast_slice(&pixels),
+            layout,
+            extent,
+        );
+        let view = texture.create_view(&Default::default());
+        let mut bloom = Bloom::new(&device, &view, (SIZE, SIZE));
+        bloom.settings.enabled = true;
+        bloom.settings.intensity = 1.0;
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: (SIZE * SIZE * 8) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&Default::default());
+        bloom.render(&queue, &mut encoder, &view);
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &readback,
+                layout,
+            },
+            extent,
+        );
+        queue.submit([encoder.finish()]);
+        let slice = readback.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        device.poll(wgpu::Maintain::Wait);
+        let data = slice.get_mapped_range();
+        let pixels: &[half::f16] = bytemuck::cast_slice(&data);
+        pixels.chunks_exact(4).map(|pixel| pixel[0].to_f32()).collect()
+    }
+
+    #[test]
+    #[ignore = "needs an adapter"]
+    fn bloom_spreads() {
+        let red = bloom(0.1, 8.0);
+        let at = |x: usize, y: usize| red[y * 64 + x];
+        assert!(at(31, 31) > 8.0, "{}", at(31, 31));
+        // Spread around the block, less and less far from it
+        let near = at(31, 38);
+        let far = at(31, 50);
+        assert!(near > 0.2 && far > 0.1 && far < near, "{near} {far}");
+        assert!(at(0, 0) < near, "{}", at(0, 0));
+        // Nothing under the threshold blooms
+        let dim = bloom(0.1, 0.4);
+        assert!(dim.iter().all(|v| (v - 0.1).abs() < 1e-3 || (v - 0.4).abs() < 1e-3));
+    }
+
+    #[test]
+    fn threshold() {
+        let t = |brightness: f32| {
+            soft_threshold(Vec3::new(brightness, brightness * 0.5, 0.0), 1.0, 0.5)
+        };
+        assert_eq!(Vec3::ZERO, t(0.0));
+        assert_eq!(Vec3::ZERO, t(0.4));
+        // Past the knee, what is over the threshold, keeping the hue
+        let over = t(3.0);
+        assert!((over.x - 2.0).abs() < 1e-4, "{over}");
+        assert!((over.y - 1.0).abs() < 1e-4, "{over}");
+        // Continuous and increasing through the knee
+        let knee: Vec<f32> = (40..=160).map(|i| t(i as f32 / 100.0).x).collect();
+        assert!(knee.windows(2).all(|w| w[0] <= w[1] && w[1] - w[0] < 0.02));
+        assert!(t(1.0).x > 0.0);
+    }
+
+    #[test]
+    fn levels() {
+        let sizes: Vec<_> = Bloom::level_sizes((320, 180)).collect();
+        assert_eq!(vec![(160, 90), (80, 45), (40, 22), (20, 11), (10, 5)], sizes);
+        assert_eq!(LEVELS as usize, Bloom::level_sizes((1, 1)).count());
+        assert!(Bloom::level_sizes((1, 1)).all(|size| size == (1, 1)));
+        let bytes: u64 = sizes.iter().map(|(w, h)| (w * h * 8) as u64).sum();
+        assert_eq!(Bloom::memory((320, 180)), bytes);
+    }
+}
@@ -0,0 +1,85 @@
+// Bloom of the HDR scene (see bloom.rs): downsampling of the scene into a chain, keeping its
+// bright parts, then upsampling of the chain back into the scene
+
+// Mirrors bloom::BloomParams
+struct BloomParams {
+    threshold: f32,
+    knee: f32,
+    padding: vec2<f32>,
+}
+
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+@group(0) @binding(1)
+var source_sampler: sampler;
+@group(0) @binding(2)
+var<uniform> params: BloomParams;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    var v_out: VertexOutput;
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    v_out.clip_position = vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+    v_out.uv = vec2<f32>(uv.x, 1.0 - uv.y);
+    return v_out;
+}
+
+fn tap(uv: vec2<f32>, texel: vec2<f32>, x: f32, y: f32) -> vec3<f32> {
+    return textureSampleLevel(source, source_sampler, uv + texel * vec2<f32>(x, y), 0.0).rgb;
+}
+
+// The 13 taps filter of Jimenez: five bilinear 4x4 boxes, the center one weighted the most
+fn downsample(uv: vec2<f32>) -> vec3<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source));
+    let a = tap(uv, texel, -2.0, -2.0);
+    let b = tap(uv, texel, 0.0, -2.0);
+    let c = tap(uv, texel, 2.0, -2.0);
+    let d = tap(uv, texel, -2.0, 0.0);
+    let e = tap(uv, texel, 0.0, 0.0);
+    let f = tap(uv, texel, 2.0, 0.0);
+    let g = tap(uv, texel, -2.0, 2.0);
+    let h = tap(uv, texel, 0.0, 2.0);
+    let i = tap(uv, texel, 2.0, 2.0);
+    let j = tap(uv, texel, -1.0, -1.0);
+    let k = tap(uv, texel, 1.0, -1.0);
+    let l = tap(uv, texel, -1.0, 1.0);
+    let m = tap(uv, texel, 1.0, 1.0);
+    return e * 0.125 + (a + c + g + i) * 0.03125 + (b + d + f + h) * 0.0625
+        + (j + k + l + m) * 0.125;
+}
+
+// Mirrors bloom::soft_threshold
+fn soft_threshold(color: vec3<f32>) -> vec3<f32> {
+    let brightness = max(color.r, max(color.g, color.b));
+    var soft = clamp(brightness - params.threshold + params.knee, 0.0, 2.0 * params.knee);
+    soft = soft * soft / (4.0 * params.knee + 1e-4);
+    return color * (max(soft, brightness - params.threshold) / max(brightness, 1e-4));
+}
+
+@fragment
+fn fs_prefilter(v_in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(soft_threshold(downsample(v_in.uv)), 1.0);
+}
+
+@fragment
+fn fs_downsample(v_in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(downsample(v_in.uv), 1.0);
+}
+
+// 3x3 tent over the texels of the smaller level
+@fragment
+fn fs_upsample(v_in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source));
+    let uv = v_in.uv;
+    var color = tap(uv, texel, 0.0, 0.0) * 4.0;
+    color += (tap(uv, texel, -1.0, 0.0) + tap(uv, texel, 1.0, 0.0)) * 2.0;
+    color += (tap(uv, texel, 0.0, -1.0) + tap(uv, texel, 0.0, 1.0)) * 2.0;
+    color += tap(uv, texel, -1.0, -1.0) + tap(uv, texel, 1.0, -1.0);
+    color += tap(uv, texel, -1.0, 1.0) + tap(uv, texel, 1.0, 1.0);
+    return vec4<f32>(color / 16.0, 1.0);
+}
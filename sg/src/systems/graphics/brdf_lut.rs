@@ -0,0 +1,86 @@
+use crate::include_shader;
+
+use super::{pipeline::ComputePipeline, GraphicContext};
+
+/// Computes the lookup table of the split-sum approximation of image based lighting: the scale
+/// and bias of the specular reflectance by the cosine of the view angle and the roughness. It
+/// doesn't depend on the environment, so it is computed once.
+pub struct BrdfLutComputer {
+    pipeline: ComputePipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl BrdfLutComputer {
+    const SAMPLE_COUNT: u32 = 1024;
+    const WG_SIZE: u32 = 8;
+    /// Size of the table, enough for a linearly filtered lookup
+    pub const SIZE: u32 = 256;
+
+    pub fn new(ctx: &GraphicContext) -> Self {
+        let mut shader = include_shader!("brdf_lut.wgsl", "BRDF LUT Shader");
+        shader.set("WG_SIZE", i64::from(Self::WG_SIZE));
+        shader.set("SAMPLE_COUNT", i64::from(Self::SAMPLE_COUNT));
+        let bind_group_layout = create_bind_group_layout!(ctx.device, "BRDF LUT Bind Group Layout": {
+            0 => COMPUTE | StorageTexture(access: WriteOnly, format: Rgba16Float, view_dim: D2),
+        });
+        let pipeline = ComputePipeline::new(
+            &ctx.device,
+            ctx.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("BRDF LUT Pipeline Layout"),
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[]
+            }),
+            shader,
+            |device, layout, module| {
+                device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some("BRDF LUT Pipeline"),
+                    layout: Some(layout),
+                    module,
+                    entry_point: "main"
+                })
+            }
+        );
+
+        Self {
+            pipeline,
+            bind_group_layout,
+        }
+    }
+
+    pub fn run(&self, usage: wgpu::TextureUsages, ctx: &GraphicContext) -> wgpu::Texture {
+        let tex = ctx.device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: Self::SIZE,
+                height: Self::SIZE,
+                depth_or_array_layers: 1,
+            },
+            label: Some("BRDF LUT"),
+            usage: wgpu::TextureUsages::STORAGE_BINDING | usage,
+            format: wgpu::TextureFormat::Rgba16Float,
+            dimension: wgpu::TextureDimension::D2,
+            sample_count: 1,
+            mip_level_count: 1,
+        });
+
+        let view = tex.create_view(&Default::default());
+
+        let bind_group = create_bind_group!(ctx.device, &self.bind_group_layout, "BRDF LUT Bind Group": {
+            0 | TextureView(&view),
+        });
+
+        let mut encoder = ctx.device.create_command_encoder(&Default::default());
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("BRDF LUT Compute Pass")
+        });
+        let workgroups = Self::SIZE.div_ceil(Self::WG_SIZE);
+
+        compute_pass.set_pipeline(&self.pipeline.pipeline);
+        compute_pass.set_bind_group(0, &bind_group, &[]);
+        compute_pass.dispatch_workgroups(workgroups, workgroups, 1);
+        drop(compute_pass);
+
+        let si = ctx.queue.submit(std::iter::once(encoder.finish()));
+        ctx.device.poll(wgpu::Maintain::WaitForSubmissionIndex(si));
+        tex
+    }
+}
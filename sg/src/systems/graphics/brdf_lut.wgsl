@@ -0,0 +1,60 @@
+// Integration of the split-sum BRDF for image based lighting: the scale (r) and bias (g) of f0 by
+// the cosine between the normal and the view direction (x) and the roughness (y)
+@group(0) @binding(0)
+var output: texture_storage_2d<rgba16float, write>;
+
+let sample_count = {{SAMPLE_COUNT}}u;
+
+let PI = 3.14159265359;
+
+fn hammersley(i: u32, n: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(n), f32(reverseBits(i)) * 2.3283064365386963e-10);
+}
+
+// GGX distributed half vector around +z
+fn importance_sample_ggx(xi: vec2<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
+
+// Smith's geometry term with the k of image based lighting
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let k = roughness * roughness / 2.0;
+    let ggx_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let ggx_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return ggx_v * ggx_l;
+}
+
+fn integrate(n_dot_v: f32, roughness: f32) -> vec2<f32> {
+    let v = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    var scale = 0.0;
+    var bias = 0.0;
+    for(var i: u32 = 0u; i < sample_count; i++) {
+        let h = importance_sample_ggx(hammersley(i, sample_count), roughness);
+        let l = normalize(2.0 * dot(v, h) * h - v);
+        let n_dot_l = max(l.z, 0.0);
+        let n_dot_h = max(h.z, 0.0);
+        let v_dot_h = max(dot(v, h), 0.0);
+        if n_dot_l > 0.0 {
+            let g_vis = geometry_smith(n_dot_v, n_dot_l, roughness) * v_dot_h / (n_dot_h * n_dot_v);
+            let fc = pow(1.0 - v_dot_h, 5.0);
+            scale += (1.0 - fc) * g_vis;
+            bias += fc * g_vis;
+        }
+    }
+    return vec2<f32>(scale, bias) / f32(sample_count);
+}
+
+@compute @workgroup_size({{WG_SIZE}}, {{WG_SIZE}}, 1)
+fn main(@builtin(global_invocation_id) param: vec3<u32>) {
+    let size = vec2<u32>(textureDimensions(output));
+    if param.x >= size.x || param.y >= size.y {
+        return;
+    }
+    // Texel centers, so that sampling at the edges doesn't need a cosine of 0
+    let uv = (vec2<f32>(param.xy) + 0.5) / vec2<f32>(size);
+    textureStore(output, vec2<i32>(param.xy), vec4<f32>(integrate(uv.x, uv.y), 0.0, 1.0));
+}
@@ -1,8 +1,24 @@
-use std::{f32::consts::FRAC_PI_2, lazy::OnceCell};
+use std::{cell::OnceCell, f32::consts::FRAC_PI_2};
 
-use glam::{Mat4, Quat, Vec3};
+use glam::{Mat4, Quat, Vec3, Vec4};
 use wgpu::util::DeviceExt;
 
+/// Planes of the frustum of a view projection matrix: left, right, bottom, top, near and far. Each
+/// is a normal pointing inside and a distance, a point `p` is inside the frustum when
+/// `normal.dot(p) + distance >= 0` for all of them. The depth range of clip space is wgpu's (0..1).
+pub fn frustum_planes(view_proj: Mat4) -> [Vec4; 6] {
+    let row = |i| view_proj.row(i);
+    [
+        row(3) + row(0),
+        row(3) - row(0),
+        row(3) + row(1),
+        row(3) - row(1),
+        row(2),
+        row(3) - row(2),
+    ]
+    .map(|plane| plane / plane.truncate().length())
+}
+
 #[derive(Clone, Copy)]
 pub enum Projection {
     Perspective,
@@ -17,6 +33,9 @@ struct CameraInfo {
     view: Mat4,
     camera_pos: Vec3,
     aspect: f32,
+    /// Level of detail of the roughest level of the prefiltered environment
+    env_max_lod: f32,
+    _padding: [f32; 3],
 }
 
 pub struct Camera {
@@ -34,7 +53,10 @@ pub struct Camera {
     bind_group: OnceCell<wgpu::BindGroup>,
     bind_group_layout: OnceCell<wgpu::BindGroupLayout>,
     skybox: OnceCell<wgpu::TextureView>,
-    irradiance_map: OnceCell<wgpu::TextureView>
+    irradiance_map: OnceCell<wgpu::TextureView>,
+    prefiltered_env: OnceCell<wgpu::TextureView>,
+    prefiltered_env_mips: u32,
+    brdf_lut: OnceCell<wgpu::TextureView>,
 }
 
 impl Camera {
@@ -88,6 +110,21 @@ impl Camera {
         self.irradiance_map.set(irr_map).ok();
         self.bind_group.take();
     }
+    /// Set the prefiltered environment of the specular lighting (see `SpecularPrefilterComputer`),
+    /// a cube view of all its `mip_count` levels
+    pub fn set_prefiltered_env(&mut self, env: wgpu::TextureView, mip_count: u32) {
+        self.prefiltered_env.take();
+        self.prefiltered_env.set(env).ok();
+        self.prefiltered_env_mips = mip_count;
+        self.bind_group.take();
+        self.set_dirty();
+    }
+    /// Set the lookup table of the specular lighting (see `BrdfLutComputer`)
+    pub fn set_brdf_lut(&mut self, lut: wgpu::TextureView) {
+        self.brdf_lut.take();
+        self.brdf_lut.set(lut).ok();
+        self.bind_group.take();
+    }
     pub fn get_position(&self) -> Vec3 {
         self.position
     }
@@ -109,6 +146,18 @@ impl Camera {
     pub fn get_aspect(&self) -> f32 {
         self.aspect
     }
+    /// View projection matrix, as of the last update
+    pub fn get_view_projection(&self) -> Mat4 {
+        self.matrix
+    }
+    /// View matrix (left handed, +z forward), as of the last update
+    pub fn get_view(&self) -> Mat4 {
+        self.view_mat
+    }
+    /// Frustum of the camera as of the last update, see `frustum_planes`
+    pub fn frustum_planes(&self) -> [Vec4; 6] {
+        frustum_planes(self.matrix)
+    }
     fn recompute_matrix(&mut self) {
         let mut view = Mat4::from_quat(self.rotation.inverse());
         view *= Mat4::from_translation(-self.position);
@@ -134,6 +183,8 @@ impl Camera {
             view: self.view_mat,
             camera_pos: self.position,
             aspect: self.aspect,
+            env_max_lod: self.prefiltered_env_mips.saturating_sub(1) as f32,
+            _padding: [0.0; 3],
         }
     }
     fn get_buffer(&self, device: &wgpu::Device) -> &wgpu::Buffer {
@@ -146,52 +197,58 @@ impl Camera {
             })
         })
     }
+    /// 1x1 texture of a single color, the default of the textures of the bind group
+    fn single_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        label: &str,
+        color: [u8; 4],
+        dimension: wgpu::TextureViewDimension,
+    ) -> wgpu::TextureView {
+        let layers = match dimension {
+            wgpu::TextureViewDimension::Cube => 6,
+            _ => 1,
+        };
+        device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                size: wgpu::Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: layers,
+                },
+                label: Some(label),
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                dimension: wgpu::TextureDimension::D2,
+                sample_count: 1,
+                mip_level_count: 1,
+            },
+            &color.repeat(layers as usize),
+        ).create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(dimension),
+            ..Default::default()
+        })
+    }
     fn get_skybox(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> &wgpu::TextureView {
         self.skybox.get_or_init(|| {
-            device.create_texture_with_data(
-                queue,
-                &wgpu::TextureDescriptor {
-                    size: wgpu::Extent3d {
-                        width: 1,
-                        height: 1,
-                        depth_or_array_layers: 6,
-                    },
-                    label: Some("Default Skybox"),
-                    usage: wgpu::TextureUsages::TEXTURE_BINDING,
-                    format: wgpu::TextureFormat::Rgba8Unorm,
-                    dimension: wgpu::TextureDimension::D2,
-                    sample_count: 1,
-                    mip_level_count: 1,
-                },
-                bytemuck::cast_slice::<[u8; 4], u8>(&[[255; 4]; 6])
-            ).create_view(&wgpu::TextureViewDescriptor {
-                dimension: Some(wgpu::TextureViewDimension::Cube),
-                ..Default::default()
-            })
+            Self::single_color(device, queue, "Default Skybox", [255; 4], wgpu::TextureViewDimension::Cube)
         })
     }
     pub fn get_irradiance(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> &wgpu::TextureView {
         self.irradiance_map.get_or_init(|| {
-            device.create_texture_with_data(
-                queue,
-                &wgpu::TextureDescriptor {
-                    size: wgpu::Extent3d {
-                        width: 1,
-                        height: 1,
-                        depth_or_array_layers: 6,
-                    },
-                    label: Some("Default Skybox"),
-                    usage: wgpu::TextureUsages::TEXTURE_BINDING,
-                    format: wgpu::TextureFormat::Rgba8Unorm,
-                    dimension: wgpu::TextureDimension::D2,
-                    sample_count: 1,
-                    mip_level_count: 1,
-                },
-                bytemuck::cast_slice::<[u8; 4], u8>(&[[30; 4]; 6])
-            ).create_view(&wgpu::TextureViewDescriptor {
-                dimension: Some(wgpu::TextureViewDimension::Cube),
-                ..Default::default()
-            })
+            Self::single_color(device, queue, "Default Irradiance Map", [30; 4], wgpu::TextureViewDimension::Cube)
+        })
+    }
+    // Black: no specular lighting without an environment
+    fn get_prefiltered_env(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> &wgpu::TextureView {
+        self.prefiltered_env.get_or_init(|| {
+            Self::single_color(device, queue, "Default Prefiltered Environment", [0, 0, 0, 255], wgpu::TextureViewDimension::Cube)
+        })
+    }
+    fn get_brdf_lut(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> &wgpu::TextureView {
+        self.brdf_lut.get_or_init(|| {
+            Self::single_color(device, queue, "Default BRDF LUT", [0, 0, 0, 255], wgpu::TextureViewDimension::D2)
         })
     }
     pub(in crate::systems::graphics) fn update(
@@ -218,7 +275,9 @@ impl Camera {
             create_bind_group!(device, &self.get_bind_group_layout(device), "Camera Bind Group": {
                 0 | Buffer(buffer: (self.get_buffer(device))),
                 1 | TextureView(self.get_skybox(device, queue)),
-                2 | TextureView(self.get_irradiance(device, queue))
+                2 | TextureView(self.get_irradiance(device, queue)),
+                3 | TextureView(self.get_prefiltered_env(device, queue)),
+                4 | TextureView(self.get_brdf_lut(device, queue)),
             })
         })
     }
@@ -232,6 +291,8 @@ impl Camera {
                 0 => VERTEX, FRAGMENT | Buffer(type: Uniform),
                 1 => FRAGMENT | Texture(view_dim: Cube, sample: FloatFilterable),
                 2 => FRAGMENT | Texture(view_dim: Cube, sample: FloatFilterable),
+                3 => FRAGMENT | Texture(view_dim: Cube, sample: FloatFilterable),
+                4 => FRAGMENT | Texture(view_dim: D2, sample: FloatFilterable),
             })
         })
     }
@@ -255,8 +316,60 @@ impl Default for Camera {
             bind_group_layout: OnceCell::new(),
             skybox: OnceCell::new(),
             irradiance_map: OnceCell::new(),
+            prefiltered_env: OnceCell::new(),
+            prefiltered_env_mips: 1,
+            brdf_lut: OnceCell::new(),
         };
         cam.recompute_matrix();
         cam
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_plane(expected: Vec4, plane: Vec4) {
+        assert!(expected.abs_diff_eq(plane, 1e-5), "{expected} != {plane}");
+    }
+
+    #[test]
+    fn planes() {
+        // Clip space is the frustum of the identity
+        let planes = frustum_planes(Mat4::IDENTITY);
+        let expected = [
+            Vec4::new(1.0, 0.0, 0.0, 1.0),
+            Vec4::new(-1.0, 0.0, 0.0, 1.0),
+            Vec4::new(0.0, 1.0, 0.0, 1.0),
+            Vec4::new(0.0, -1.0, 0.0, 1.0),
+            Vec4::new(0.0, 0.0, 1.0, 0.0),
+            Vec4::new(0.0, 0.0, -1.0, 1.0),
+        ];
+        for (expected, plane) in expected.into_iter().zip(planes) {
+            assert_plane(expected, plane);
+        }
+
+        // 90° vertical fov, square, looking down +z from the origin: the side planes are at 45°
+        let planes = frustum_planes(Mat4::perspective_lh(std::f32::consts::FRAC_PI_2, 1.0, 1.0, 10.0));
+        let d = std::f32::consts::FRAC_1_SQRT_2;
+        let expected = [
+            Vec4::new(d, 0.0, d, 0.0),
+            Vec4::new(-d, 0.0, d, 0.0),
+            Vec4::new(0.0, d, d, 0.0),
+            Vec4::new(0.0, -d, d, 0.0),
+            Vec4::new(0.0, 0.0, 1.0, -1.0),
+            Vec4::new(0.0, 0.0, -1.0, 10.0),
+        ];
+        for (expected, plane) in expected.into_iter().zip(planes) {
+            assert_plane(expected, plane);
+        }
+
+        // Moving the camera moves the planes
+        let mut camera = Camera::new();
+        camera.set_position(Vec3::new(0.0, 0.0, -5.0));
+        camera.recompute_matrix();
+        let inside = |p: Vec3| camera.frustum_planes().iter().all(|plane| plane.truncate().dot(p) + plane.w >= 0.0);
+        assert!(inside(Vec3::ZERO));
+        assert!(!inside(Vec3::new(0.0, 0.0, -6.0)));
+    }
+}
@@ -0,0 +1,22 @@
+// The camera's bind group, mirrors camera::CameraInfo. Shaders made of several files start with
+// this one.
+struct CameraInfo {
+    view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+    pos: vec3<f32>,
+    aspect: f32,
+    // Level of detail of the roughest level of env_map
+    env_max_lod: f32,
+}
+
+@group(1) @binding(0)
+var<uniform> cam: CameraInfo;
+@group(1) @binding(1)
+var skybox: texture_cube<f32>;
+@group(1) @binding(2)
+var irr_map: texture_cube<f32>;
+// Environment prefiltered by roughness and the split-sum lookup table of the specular lighting
+@group(1) @binding(3)
+var env_map: texture_cube<f32>;
+@group(1) @binding(4)
+var brdf_lut: texture_2d<f32>;
@@ -1,6 +1,3 @@
-use glam::Mat4;
-use wgpu::util::DeviceExt;
-
 use crate::include_shader;
 
 use super::{pipeline::ComputePipeline, GraphicContext, cubemap::get_cubemap_face_rotations_buffer};
@@ -91,7 +88,7 @@ impl ConvolutionComputer {
         let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
             label: Some("Convolution Compute Pass")
         });
-        let workgroups = (size + self.workgroups_size - 1) / self.workgroups_size;
+        let workgroups = size.div_ceil(self.workgroups_size);
 
         compute_pass.set_pipeline(&self.pipeline.pipeline);
         compute_pass.set_bind_group(0, &bind_group, &[]);
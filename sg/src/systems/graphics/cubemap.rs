@@ -1,4 +1,4 @@
-use std::lazy::{SyncLazy, SyncOnceCell};
+use std::sync::OnceLock;
 
 use half::f16;
 use image::GenericImageView;
@@ -53,7 +53,7 @@ const CUBEMAP_FACE_ROTATION_MATRICES: [[f32; 16]; 6] = [
     ], 
 ];
 
-static CUBEMAP_FACE_ROTATIONS_BUFFER: SyncOnceCell<wgpu::Buffer> = SyncOnceCell::new();
+static CUBEMAP_FACE_ROTATIONS_BUFFER: OnceLock<wgpu::Buffer> = OnceLock::new();
 
 pub fn get_cubemap_face_rotations_buffer(device: &wgpu::Device) -> &wgpu::Buffer {
     CUBEMAP_FACE_ROTATIONS_BUFFER.get_or_init(|| {
@@ -124,9 +124,9 @@ impl CubeMapComputer {
     }
 
     pub fn render(&mut self, image: impl GenericImageView<Pixel = image::Rgba<f32>>, ctx: &GraphicContext, tex_size: u32, usage: wgpu::TextureUsages) -> wgpu::Texture {
-        let bytes = image.pixels().map(|(_, _, image::Rgba(v))| [
+        let bytes = image.pixels().flat_map(|(_, _, image::Rgba(v))| [
            f16::from_f32(v[0]), f16::from_f32(v[1]), f16::from_f32(v[2]), f16::from_f32(v[3])
-        ]).flatten().collect::<Vec<f16>>();
+        ]).collect::<Vec<f16>>();
 
         let input_texture = TextureManager::create_texture_from_bytes(
             &ctx.device,
@@ -170,7 +170,7 @@ impl CubeMapComputer {
         let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
             label: Some("CubeMap Compute Pass"),
         });
-        let workgroups = (tex_size + self.workgroups_size - 1) / self.workgroups_size;
+        let workgroups = tex_size.div_ceil(self.workgroups_size);
         compute_pass.set_pipeline(&self.pipeline.pipeline);
         compute_pass.set_bind_group(0, &bindgroup, &[]);
         compute_pass.dispatch_workgroups(workgroups, workgroups, 6);
@@ -0,0 +1,252 @@
+//! Lines drawn over the scene for debugging: paths, bounds, directions.
+//!
+//! Systems push lines into the `DebugDraw` resource during the frame, the renderer takes them
+//! (see `WorldRenderer::update_debug_draw`) and draws them after the tonemapping, hidden by the
+//! geometry of the scene. Lines only last one frame.
+
+use glam::{Mat4, Vec3, Vec4};
+
+use crate::include_shader;
+
+use super::{pipeline::RenderPipeline, texture_manager::TextureManager};
+
+/// End of a line
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DebugVertex {
+    pub position: Vec3,
+    /// Straight alpha
+    pub color: [f32; 4],
+}
+
+impl DebugVertex {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 2] = wgpu::vertex_attr_array![
+        0 => Float32x3,
+        1 => Float32x4,
+    ];
+
+    fn layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+/// Lines to draw this frame, a resource
+#[derive(Debug, Default)]
+pub struct DebugDraw {
+    /// Pairs of vertices
+    lines: Vec<DebugVertex>,
+}
+
+impl DebugDraw {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn line(&mut self, from: Vec3, to: Vec3, color: Vec4) {
+        let color = color.to_array();
+        self.lines.push(DebugVertex { position: from, color });
+        self.lines.push(DebugVertex { position: to, color });
+    }
+    /// Lines between consecutive points
+    pub fn polyline(&mut self, points: impl IntoIterator<Item = Vec3>, color: Vec4) {
+        let mut points = points.into_iter();
+        let Some(mut last) = points.next() else {
+            return;
+        };
+        for point in points {
+            self.line(last, point, color);
+            last = point;
+        }
+    }
+    /// Three lines of `size` crossing at `at`, along the axes
+    pub fn cross(&mut self, at: Vec3, size: f32, color: Vec4) {
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            self.line(at - axis * size / 2.0, at + axis * size / 2.0, color);
+        }
+    }
+    /// Number of lines
+    pub fn len(&self) -> usize {
+        self.lines.len() / 2
+    }
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+    /// Take the lines, leaving none
+    pub fn take(&mut self) -> Vec<DebugVertex> {
+        std::mem::take(&mut self.lines)
+    }
+}
+
+pub struct DebugLineRenderer {
+    /// Lines of the frame, see `update`
+    lines: Vec<DebugVertex>,
+    pipeline: RenderPipeline,
+    camera: wgpu::Buffer,
+    camera_bind_group: wgpu::BindGroup,
+    /// Vertex buffer and its size
+    vertices: Option<(wgpu::Buffer, u64)>,
+}
+
+impl DebugLineRenderer {
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+        let camera_layout = create_bind_group_layout!(device, "Debug Lines Camera Bindgroup Layout": {
+            0 => VERTEX | Buffer(type: Uniform),
+        });
+        let pipeline = RenderPipeline::new(
+            device,
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Debug Lines Pipeline Layout"),
+                bind_group_layouts: &[&camera_layout],
+                push_constant_ranges: &[],
+            }),
+            include_shader!("debug_draw.wgsl", "Debug Lines Shader"),
+            move |device, layout, module| create_pipeline(device, layout, module, format),
+        );
+        let camera = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Debug Lines Camera"),
+            size: std::mem::size_of::<Mat4>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let camera_bind_group = create_bind_group!(device, &camera_layout, "Debug Lines Camera Bindgroup": {
+            0 | Buffer(buffer: (&camera)),
+        });
+        Self {
+            lines: Vec::new(),
+            pipeline,
+            camera,
+            camera_bind_group,
+            vertices: None,
+        }
+    }
+    /// Take the lines of the frame
+    pub fn update(&mut self, draw: &mut DebugDraw) {
+        self.lines = draw.take();
+    }
+    /// Draw the lines over `target`, tested against the depth of the scene
+    pub fn draw(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        view_proj: Mat4,
+        target: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+    ) {
+        if self.lines.is_empty() {
+            return;
+        }
+        let size = std::mem::size_of_val(self.lines.as_slice()) as u64;
+        if self
+            .vertices
+            .as_ref()
+            .map_or(true, |(_, capacity)| *capacity < size)
+        {
+            let capacity = size.next_power_of_two();
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Debug Lines Vertices"),
+                size: capacity,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            self.vertices = Some((buffer, capacity));
+        }
+        let (buffer, _) = self.vertices.as_ref().unwrap();
+        queue.write_buffer(buffer, 0, bytemuck::cast_slice(&self.lines));
+        queue.write_buffer(&self.camera, 0, bytemuck::bytes_of(&view_proj));
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Debug Lines Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        pass.set_pipeline(&self.pipeline.pipeline);
+        pass.set_bind_group(0, &self.camera_bind_group, &[]);
+        pass.set_vertex_buffer(0, buffer.slice(..size));
+        pass.draw(0..self.lines.len() as u32, 0..1);
+    }
+}
+
+/// Alpha blended lines that don't write the depth
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    module: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Debug Lines Pipeline"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module,
+            entry_point: "vs_main",
+            buffers: &[DebugVertex::layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::LineList,
+            cull_mode: None,
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: TextureManager::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        let red = Vec4::new(1.0, 0.0, 0.0, 1.0);
+        let mut draw = DebugDraw::new();
+        draw.polyline([Vec3::ZERO], red);
+        assert!(draw.is_empty());
+        draw.polyline([Vec3::ZERO, Vec3::X, Vec3::ONE], red);
+        assert_eq!(2, draw.len());
+        draw.cross(Vec3::ONE, 2.0, red);
+        assert_eq!(5, draw.len());
+
+        let lines = draw.take();
+        assert!(draw.is_empty());
+        let positions: Vec<_> = lines.iter().map(|v| v.position).collect();
+        // The polyline goes through its points, the cross is centered
+        assert_eq!(&[Vec3::ZERO, Vec3::X, Vec3::X, Vec3::ONE], &positions[..4]);
+        assert_eq!(Vec3::new(0.0, 1.0, 1.0), positions[4]);
+        assert_eq!(Vec3::new(2.0, 1.0, 1.0), positions[5]);
+        assert_eq!(28, std::mem::size_of::<DebugVertex>());
+    }
+}
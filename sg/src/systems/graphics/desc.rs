@@ -1,13 +1,13 @@
 // This file is for large descriptors that clutter the screen and/or need to be duplicated
 
 #[macro_export]
-macro_rules! geometry_renderpass_desc {
-    ($g_buffer:expr) => {
+macro_rules! shading_renderpass_desc {
+    ($view:expr, $history:expr) => {
         wgpu::RenderPassDescriptor {
-            label: Some("gfx render pass"),
+            label: Some("Shading pass"),
             color_attachments: &[
                 Some(wgpu::RenderPassColorAttachment {
-                    view: &$g_buffer.albedo_tex,
+                    view: $view,
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color {
@@ -19,163 +19,46 @@ macro_rules! geometry_renderpass_desc {
                         store: true,
                     },
                 }),
-                Some(wgpu::RenderPassColorAttachment {
-                    view: &$g_buffer.position_tex,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 0.0,
-                        }),
-                        store: true,
-                    },
-                }),
-                Some(wgpu::RenderPassColorAttachment {
-                    view: &$g_buffer.normal_tex,
+                $history.map(|view| wgpu::RenderPassColorAttachment {
+                    view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 0.0,
-                        }),
-                        store: true,
-                    },
-                }),
-                Some(wgpu::RenderPassColorAttachment {
-                    view: &$g_buffer.mra_tex,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 0.0,
-                        }),
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
                         store: true,
                     },
                 }),
             ],
-            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                view: &$g_buffer.depth_tex,
-                depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
-                    store: true,
-                }),
-                stencil_ops: None,
-            }),
-        }
-    };
-}
-
-#[macro_export]
-macro_rules! shading_renderpass_desc {
-    ($view:expr) => {
-        wgpu::RenderPassDescriptor {
-            label: Some("Shading pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: $view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                        r: 0.0,
-                        g: 0.0,
-                        b: 0.0,
-                        a: 1.0,
-                    }),
-                    store: true,
-                },
-            })],
             depth_stencil_attachment: None,
         }
     };
 }
 #[macro_export]
-macro_rules! geometry_pipeline_desc {
-    ($layout:expr, $shader:expr) => {
+macro_rules! shading_pipeline_desc {
+    ($layout:expr, $shader:expr, $format:expr, $history:expr) => {
         wgpu::RenderPipelineDescriptor {
-            label: Some("Geometry Pipeline"),
+            label: Some("Shading pipeline"),
             layout: Some($layout),
             vertex: wgpu::VertexState {
                 module: $shader,
                 entry_point: "vs_main",
-                buffers: &[Vertex::desc()],
+                buffers: &[],
             },
             fragment: Some(wgpu::FragmentState {
                 module: $shader,
                 entry_point: "fs_main",
                 targets: &[
                     Some(wgpu::ColorTargetState {
-                        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                        format: $format,
                         blend: Some(wgpu::BlendState::REPLACE),
                         write_mask: wgpu::ColorWrites::ALL,
                     }),
-                    Some(wgpu::ColorTargetState {
-                        format: wgpu::TextureFormat::Rgba16Float,
-                        blend: None,
-                        write_mask: wgpu::ColorWrites::ALL,
-                    }),
-                    Some(wgpu::ColorTargetState {
-                        format: wgpu::TextureFormat::Rgba16Float,
-                        blend: None,
-                        write_mask: wgpu::ColorWrites::ALL,
-                    }),
-                    Some(wgpu::ColorTargetState {
-                        format: wgpu::TextureFormat::Rgba8Unorm,
+                    $history.map(|format| wgpu::ColorTargetState {
+                        format,
                         blend: None,
                         write_mask: wgpu::ColorWrites::ALL,
                     }),
                 ],
             }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                polygon_mode: wgpu::PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: TextureManager::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-        }
-    };
-}
-
-#[macro_export]
-macro_rules! shading_pipeline_desc {
-    ($layout:expr, $shader:expr, $format:expr) => {
-        wgpu::RenderPipelineDescriptor {
-            label: Some("Shading pipeline"),
-            layout: Some($layout),
-            vertex: wgpu::VertexState {
-                module: $shader,
-                entry_point: "vs_main",
-                buffers: &[],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: $shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: $format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
             primitive: wgpu::PrimitiveState {
                 topology: wgpu::PrimitiveTopology::TriangleList,
                 strip_index_format: None,
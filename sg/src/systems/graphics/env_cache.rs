@@ -0,0 +1,356 @@
+//! Cache of the environment maps computed from an HDR image.
+//!
+//! Converting the equirectangular image to the skybox cubemap and convolving it into the
+//! irradiance map takes seconds at the size of the skybox. The texels of both are read back and
+//! stored as virtual resources related to the image (`CUBEMAP_RELATION` and
+//! `IRRADIANCE_RELATION`), then uploaded as is on the next starts without running either compute
+//! pass. When the image changes, rmanage drops them with the rest of what was derived from it
+//! (by its hash, see `ResourceManager::sync_cache` and `ResourceManager::revalidate`).
+
+use std::{
+    io::{Cursor, Read},
+    sync::mpsc,
+};
+
+use anyhow::{bail, Context, Result};
+use rmanage::{Resource, ResourceError, ResourceManager};
+
+use super::{convolution::ConvolutionComputer, cubemap::CubeMapComputer, GraphicContext};
+
+const MAGIC: &[u8; 4] = b"ENVM";
+const VERSION: u32 = 1;
+/// Relation from the HDR image to its cached skybox
+pub const CUBEMAP_RELATION: &str = "cubemap";
+/// Relation from the HDR image to its cached irradiance map
+pub const IRRADIANCE_RELATION: &str = "irradiance";
+/// Formats the texels can be stored in, the index is written in the header
+const FORMATS: [wgpu::TextureFormat; 2] = [
+    wgpu::TextureFormat::Rgba16Float,
+    wgpu::TextureFormat::Rgba32Float,
+];
+/// Format of the maps, that of the compute passes
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// The texels of a texture with layers (the faces of a cubemap), with tightly packed rows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Texels {
+    pub width: u32,
+    pub height: u32,
+    pub layers: u32,
+    pub format: wgpu::TextureFormat,
+    pub data: Vec<u8>,
+}
+
+fn bytes_per_texel(format: wgpu::TextureFormat) -> u32 {
+    format.describe().block_size as u32
+}
+
+/// Rows of `row` bytes out of rows padded to `padded` bytes
+fn unpad_rows(data: &[u8], row: usize, padded: usize) -> impl Iterator<Item = &[u8]> {
+    data.chunks(padded).map(move |chunk| &chunk[..row])
+}
+
+impl Texels {
+    /// Size of the data
+    fn expected_len(&self) -> usize {
+        (self.width * self.height * self.layers * bytes_per_texel(self.format)) as usize
+    }
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let format = FORMATS
+            .iter()
+            .position(|f| *f == self.format)
+            .with_context(|| format!("Can't store texels of format {:?}", self.format))?;
+        let mut bytes = Vec::with_capacity(24 + self.data.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        for value in [self.width, self.height, self.layers, format as u32] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.data);
+        Ok(bytes)
+    }
+    /// Read texels written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(bytes);
+        let mut read = || -> Result<[u8; 4]> {
+            let mut buf = [0u8; 4];
+            cursor.read_exact(&mut buf).context("Truncated texels")?;
+            Ok(buf)
+        };
+        if &read()? != MAGIC {
+            bail!("Not texels");
+        }
+        let version = u32::from_le_bytes(read()?);
+        if version != VERSION {
+            bail!("Unsupported texels version {version}");
+        }
+        let width = u32::from_le_bytes(read()?);
+        let height = u32::from_le_bytes(read()?);
+        let layers = u32::from_le_bytes(read()?);
+        let format = u32::from_le_bytes(read()?);
+        let format = *FORMATS
+            .get(format as usize)
+            .with_context(|| format!("Unknown texels format {format}"))?;
+        let texels = Self {
+            width,
+            height,
+            layers,
+            format,
+            data: bytes[cursor.position() as usize..].to_vec(),
+        };
+        if texels.data.len() != texels.expected_len() {
+            bail!(
+                "{} bytes of texels for {width}x{height}x{layers} {format:?}",
+                texels.data.len()
+            );
+        }
+        Ok(texels)
+    }
+    /// Read the texels of a texture (with the COPY_SRC usage) back from the GPU, a layer at a
+    /// time to stay under the size limit of buffers
+    pub fn read(
+        ctx: &GraphicContext,
+        texture: &wgpu::Texture,
+        (width, height, layers): (u32, u32, u32),
+        format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        let row = width * bytes_per_texel(format);
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded = row.div_ceil(align) * align;
+        let buffer = ctx.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Texels Buffer"),
+            size: padded as u64 * height as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut data = Vec::with_capacity((row * height * layers) as usize);
+        for layer in 0..layers {
+            let mut encoder = ctx
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Texels Encoder"),
+                });
+            encoder.copy_texture_to_buffer(
+                wgpu::ImageCopyTexture {
+                    texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::ImageCopyBuffer {
+                    buffer: &buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: std::num::NonZeroU32::new(padded),
+                        rows_per_image: None,
+                    },
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+            );
+            let submission = ctx.queue.submit(std::iter::once(encoder.finish()));
+            let (sender, receiver) = mpsc::channel();
+            let slice = buffer.slice(..);
+            slice.map_async(wgpu::MapMode::Read, move |result| {
+                let _ = sender.send(result);
+            });
+            ctx.device
+                .poll(wgpu::Maintain::WaitForSubmissionIndex(submission));
+            receiver
+                .recv()
+                .context("The texels buffer was dropped")?
+                .context("Couldn't map the texels buffer")?;
+            for row in unpad_rows(&slice.get_mapped_range(), row as usize, padded as usize) {
+                data.extend_from_slice(row);
+            }
+            buffer.unmap();
+        }
+        Ok(Self {
+            width,
+            height,
+            layers,
+            format,
+            data,
+        })
+    }
+    /// Create a texture of the texels, with `usage` and COPY_DST
+    pub fn upload(
+        &self,
+        ctx: &GraphicContext,
+        label: &str,
+        usage: wgpu::TextureUsages,
+    ) -> wgpu::Texture {
+        let size = wgpu::Extent3d {
+            width: self.width,
+            height: self.height,
+            depth_or_array_layers: self.layers,
+        };
+        let texture = ctx.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: usage | wgpu::TextureUsages::COPY_DST,
+        });
+        ctx.queue.write_texture(
+            texture.as_image_copy(),
+            &self.data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(self.width * bytes_per_texel(self.format)),
+                rows_per_image: std::num::NonZeroU32::new(self.height),
+            },
+            size,
+        );
+        texture
+    }
+}
+
+/// The skybox and the irradiance map of an HDR image
+pub struct EnvironmentMaps {
+    pub skybox: wgpu::Texture,
+    pub irradiance: wgpu::Texture,
+}
+
+fn cube_view(texture: &wgpu::Texture) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::Cube),
+        ..Default::default()
+    })
+}
+
+impl EnvironmentMaps {
+    /// The maps of an OpenEXR image, from the cache if they were computed at these sizes,
+    /// computed (and cached) otherwise.
+    pub fn load_or_compute(
+        ctx: &GraphicContext,
+        resources: &ResourceManager,
+        hdr: Resource,
+        skybox_size: u32,
+        irradiance_size: u32,
+    ) -> Result<Self> {
+        let cached = |relation: &str, size: u32| -> Option<Texels> {
+            let res = resources.get_related(hdr, relation)?;
+            let texels = resources
+                .get_resource(res)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Texels::from_bytes(&bytes));
+            match texels {
+                Ok(texels) if texels.width == size && texels.format == FORMAT => Some(texels),
+                Ok(_) => None,
+                Err(e) => {
+                    log::warn!("Ignoring the cached {relation} map: {e:#}");
+                    None
+                }
+            }
+        };
+        let usage = wgpu::TextureUsages::TEXTURE_BINDING;
+        if let (Some(skybox), Some(irradiance)) = (
+            cached(CUBEMAP_RELATION, skybox_size),
+            cached(IRRADIANCE_RELATION, irradiance_size),
+        ) {
+            log::info!("Environment maps loaded from the cache");
+            return Ok(Self {
+                skybox: skybox.upload(ctx, "Skybox", usage),
+                irradiance: irradiance.upload(ctx, "Irradiance Map", usage),
+            });
+        }
+
+        let maps = Self::compute(ctx, resources, hdr, skybox_size, irradiance_size)?;
+        let store = || -> Result<()> {
+            for (relation, texture, size) in [
+                (CUBEMAP_RELATION, &maps.skybox, skybox_size),
+                (IRRADIANCE_RELATION, &maps.irradiance, irradiance_size),
+            ] {
+                let texels = Texels::read(ctx, texture, (size, size, 6), FORMAT)?;
+                let res = resources.add_virtual(&texels.to_bytes()?);
+                // The previous one is pruned from the cache with nothing pointing to it
+                resources.replace_relation(relation, hdr, res);
+            }
+            match resources.cache() {
+                Ok(_) | Err(ResourceError::NoCachePath) => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        };
+        if let Err(e) = store() {
+            log::warn!("Couldn't cache the environment maps: {e:#}");
+        }
+        Ok(maps)
+    }
+    fn compute(
+        ctx: &GraphicContext,
+        resources: &ResourceManager,
+        hdr: Resource,
+        skybox_size: u32,
+        irradiance_size: u32,
+    ) -> Result<Self> {
+        let bytes = resources.get_resource(hdr)?;
+        let mut reader =
+            image::io::Reader::with_format(Cursor::new(&*bytes), image::ImageFormat::OpenExr);
+        reader.no_limits();
+        let image = reader
+            .decode()
+            .context("Couldn't decode the HDR image")?
+            .flipv()
+            .to_rgba32f();
+        drop(bytes);
+        // Large, and only needed again if the maps are
+        resources.free(hdr)?;
+        let usage = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_SRC;
+        let skybox = CubeMapComputer::new(ctx).render(image, ctx, skybox_size, usage);
+        let irradiance =
+            ConvolutionComputer::new(ctx).run(&cube_view(&skybox), irradiance_size, usage, ctx);
+        Ok(Self { skybox, irradiance })
+    }
+    /// Cube views of the skybox and the irradiance map, for the camera
+    pub fn views(&self) -> (wgpu::TextureView, wgpu::TextureView) {
+        (cube_view(&self.skybox), cube_view(&self.irradiance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texels_bytes() {
+        let texels = Texels {
+            width: 2,
+            height: 1,
+            layers: 3,
+            format: wgpu::TextureFormat::Rgba16Float,
+            data: (0..48).collect(),
+        };
+        let bytes = texels.to_bytes().unwrap();
+        assert_eq!(24 + 48, bytes.len());
+        assert_eq!(texels, Texels::from_bytes(&bytes).unwrap());
+        // Missing texels, or a header only
+        assert!(Texels::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Texels::from_bytes(&bytes[..10]).is_err());
+        assert!(Texels::from_bytes(b"NAVM").is_err());
+        let other = Texels {
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            ..texels
+        };
+        assert!(other.to_bytes().is_err());
+    }
+
+    #[test]
+    fn unpadding() {
+        // 2 rows of 3 bytes padded to 8
+        let data = [1, 2, 3, 0, 0, 0, 0, 0, 4, 5, 6, 0, 0, 0, 0, 0];
+        assert_eq!(
+            vec![&[1, 2, 3][..], &[4, 5, 6][..]],
+            unpad_rows(&data, 3, 8).collect::<Vec<_>>()
+        );
+    }
+}
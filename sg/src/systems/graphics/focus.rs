@@ -0,0 +1,740 @@
+//! Keyboard (and later controller) navigation of the UI.
+//!
+//! The panels register their focusable widgets in `UiFocus` while they are drawn, in the order
+//! they are laid out. Up and down move the focus through the widgets of a panel, the panel keys
+//! cycle through the panels, and accept / back / left / right are handed to egui as key presses,
+//! so the focused widget reacts like it would to a keyboard (buttons click, sliders step, popups
+//! close). The keys of the actions are in `ActionMap`, along with the keys moving the character,
+//! they can be rebound from the settings panel.
+//!
+//! `InputRouter` decides where the inputs go: to the camera (cursor grabbed), to egui with the
+//! mouse, or to the UI navigation.
+
+use std::collections::HashMap;
+
+use egui::{Color32, Id, LayerId, Order, Rect, Response, Stroke};
+use winit::event::VirtualKeyCode;
+
+/// Switches the UI navigation on and off
+pub const TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::F1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UiAction {
+    Up,
+    Down,
+    Left,
+    Right,
+    Accept,
+    Back,
+    NextPanel,
+    PrevPanel,
+    /// Moves of the character (and of the free camera), held rather than pressed, see
+    /// `ActionMap::held`. The UI navigation ignores them.
+    MoveForward,
+    MoveBack,
+    MoveLeft,
+    MoveRight,
+    Jump,
+}
+
+impl UiAction {
+    pub const ALL: [Self; 13] = [
+        Self::Up,
+        Self::Down,
+        Self::Left,
+        Self::Right,
+        Self::Accept,
+        Self::Back,
+        Self::NextPanel,
+        Self::PrevPanel,
+        Self::MoveForward,
+        Self::MoveBack,
+        Self::MoveLeft,
+        Self::MoveRight,
+        Self::Jump,
+    ];
+    /// Whether the UI navigation handles the action, the others move the character
+    pub fn navigates(self) -> bool {
+        !matches!(
+            self,
+            Self::MoveForward | Self::MoveBack | Self::MoveLeft | Self::MoveRight | Self::Jump
+        )
+    }
+    /// The key given to egui for the actions the focused widget handles itself
+    fn egui_key(self) -> Option<egui::Key> {
+        match self {
+            Self::Left => Some(egui::Key::ArrowLeft),
+            Self::Right => Some(egui::Key::ArrowRight),
+            Self::Accept => Some(egui::Key::Enter),
+            // Closes popups and color pickers
+            Self::Back => Some(egui::Key::Escape),
+            _ => None,
+        }
+    }
+}
+
+/// A key, with or without shift held
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyBinding {
+    pub key: VirtualKeyCode,
+    pub shift: bool,
+}
+
+impl KeyBinding {
+    pub fn new(key: VirtualKeyCode, shift: bool) -> Self {
+        Self { key, shift }
+    }
+}
+
+impl std::fmt::Display for KeyBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        write!(f, "{:?}", self.key)
+    }
+}
+
+/// The keys of the UI and movement actions, rebound from the settings panel
+#[derive(Debug, Clone)]
+pub struct ActionMap {
+    keys: HashMap<KeyBinding, UiAction>,
+}
+
+impl Default for ActionMap {
+    fn default() -> Self {
+        use VirtualKeyCode::*;
+
+        let mut map = Self {
+            keys: HashMap::new(),
+        };
+        for (key, shift, action) in [
+            (Up, false, UiAction::Up),
+            (Down, false, UiAction::Down),
+            (Left, false, UiAction::Left),
+            (Right, false, UiAction::Right),
+            (Return, false, UiAction::Accept),
+            (NumpadEnter, false, UiAction::Accept),
+            (Escape, false, UiAction::Back),
+            (Tab, false, UiAction::NextPanel),
+            (Tab, true, UiAction::PrevPanel),
+            (Z, false, UiAction::MoveForward),
+            (S, false, UiAction::MoveBack),
+            (Q, false, UiAction::MoveLeft),
+            (D, false, UiAction::MoveRight),
+            (Space, false, UiAction::Jump),
+        ] {
+            map.bind(KeyBinding::new(key, shift), action);
+        }
+        map
+    }
+}
+
+impl ActionMap {
+    /// The action of a key. A binding without shift also matches with shift held.
+    pub fn action(&self, key: VirtualKeyCode, shift: bool) -> Option<UiAction> {
+        self.keys
+            .get(&KeyBinding::new(key, shift))
+            .or_else(|| self.keys.get(&KeyBinding::new(key, false)))
+            .copied()
+    }
+    /// Whether a key of an action is held (with shift or not), given the state of the keys
+    pub fn held(&self, action: UiAction, pressed: impl Fn(VirtualKeyCode) -> bool) -> bool {
+        self.keys
+            .iter()
+            .any(|(binding, a)| *a == action && pressed(binding.key))
+    }
+    /// Add a key to an action, taking it from the action it was bound to
+    pub fn bind(&mut self, binding: KeyBinding, action: UiAction) {
+        self.keys.insert(binding, action);
+    }
+    /// Replace the keys of an action with a single one
+    pub fn rebind(&mut self, action: UiAction, binding: KeyBinding) {
+        self.keys.retain(|_, a| *a != action);
+        self.bind(binding, action);
+    }
+    /// The keys of an action, in a stable order
+    pub fn keys(&self, action: UiAction) -> Vec<KeyBinding> {
+        let mut keys: Vec<_> = self
+            .keys
+            .iter()
+            .filter(|(_, a)| **a == action)
+            .map(|(k, _)| *k)
+            .collect();
+        keys.sort_by_key(|k| (k.key as u32, k.shift));
+        keys
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Widget {
+    id: Id,
+    rect: Rect,
+    enabled: bool,
+}
+
+impl Widget {
+    /// Placeholder of a reserved slot, skipped until filled
+    fn reserved() -> Self {
+        Self {
+            id: Id::null(),
+            rect: Rect::NOTHING,
+            enabled: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Panel {
+    name: &'static str,
+    layer: Option<LayerId>,
+    widgets: Vec<Widget>,
+}
+
+/// The focusable widgets of a frame, by panel. A position in the order is (panel, widget).
+#[derive(Debug, Default)]
+pub struct FocusOrder {
+    panels: Vec<Panel>,
+}
+
+impl FocusOrder {
+    fn begin_panel(&mut self, name: &'static str) {
+        self.panels.push(Panel {
+            name,
+            layer: None,
+            widgets: Vec::new(),
+        });
+    }
+    fn push(&mut self, id: Id, rect: Rect, enabled: bool) {
+        if let Some(panel) = self.panels.last_mut() {
+            panel.widgets.push(Widget { id, rect, enabled });
+        }
+    }
+    fn reserve(&mut self) -> Option<(usize, usize)> {
+        let panel = self.panels.len().checked_sub(1)?;
+        let widgets = &mut self.panels[panel].widgets;
+        widgets.push(Widget::reserved());
+        Some((panel, widgets.len() - 1))
+    }
+    /// Position of a widget
+    fn locate(&self, id: Id) -> Option<(usize, usize)> {
+        self.panels.iter().enumerate().find_map(|(p, panel)| {
+            let w = panel.widgets.iter().position(|w| w.enabled && w.id == id)?;
+            Some((p, w))
+        })
+    }
+    fn panel_index(&self, name: &str) -> Option<usize> {
+        self.panels.iter().position(|p| p.name == name)
+    }
+    fn widget(&self, (p, w): (usize, usize)) -> Widget {
+        self.panels[p].widgets[w]
+    }
+    /// First enabled widget of a panel
+    fn first(&self, panel: usize) -> Option<usize> {
+        self.panels[panel].widgets.iter().position(|w| w.enabled)
+    }
+    /// Move `delta` widgets in a panel, wrapping around and skipping disabled widgets
+    fn step(&self, panel: usize, from: usize, delta: isize) -> Option<usize> {
+        let widgets = &self.panels[panel].widgets;
+        let len = widgets.len() as isize;
+        (1..=len)
+            .map(|i| (from as isize + delta * i).rem_euclid(len) as usize)
+            .find(|&w| widgets[w].enabled)
+    }
+    /// Next panel with an enabled widget in the direction of `delta`, wrapping around
+    fn cycle_panel(&self, from: Option<usize>, delta: isize) -> Option<usize> {
+        let len = self.panels.len() as isize;
+        let from = from.map_or(if delta > 0 { -1 } else { len }, |p| p as isize);
+        (1..=len)
+            .map(|i| (from + delta * i).rem_euclid(len) as usize)
+            .find(|&p| self.first(p).is_some())
+    }
+    /// Where the focus goes after an action
+    pub fn navigate(
+        &self,
+        from: Option<(usize, usize)>,
+        action: UiAction,
+    ) -> Option<(usize, usize)> {
+        let from = match from {
+            Some(from) => from,
+            None => {
+                let panel = self.cycle_panel(None, 1)?;
+                return Some((panel, self.first(panel)?));
+            }
+        };
+        let (panel, widget) = from;
+        match action {
+            UiAction::Up => Some((panel, self.step(panel, widget, -1)?)),
+            UiAction::Down => Some((panel, self.step(panel, widget, 1)?)),
+            UiAction::NextPanel | UiAction::PrevPanel => {
+                let delta = if action == UiAction::NextPanel { 1 } else { -1 };
+                let panel = self.cycle_panel(Some(panel), delta)?;
+                Some((panel, self.first(panel)?))
+            }
+            _ => Some(from),
+        }
+    }
+}
+
+/// The focused widget, kept from one frame to the other
+#[derive(Debug, Clone, Copy)]
+struct Focus {
+    panel: &'static str,
+    id: Id,
+    /// Index in the panel, to land next to the widget if it disappears (closed popup)
+    index: usize,
+}
+
+/// Focus of the keyboard / controller navigation. Inactive outside of `InputMode::Navigation`.
+#[derive(Debug, Default)]
+pub struct UiFocus {
+    active: bool,
+    actions: Vec<UiAction>,
+    order: FocusOrder,
+    focus: Option<Focus>,
+    /// The last focused widget of the panels, to come back to it when switching panels
+    remembered: HashMap<&'static str, Id>,
+    bindings: ActionMap,
+    /// The action whose key is replaced by the next key press
+    rebinding: Option<UiAction>,
+}
+
+impl UiFocus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+        self.actions.clear();
+    }
+    pub fn bindings(&self) -> &ActionMap {
+        &self.bindings
+    }
+    pub fn rebinding(&self) -> Option<UiAction> {
+        self.rebinding
+    }
+    /// Bind the next key press to an action, see `capture`
+    pub fn start_rebinding(&mut self, action: UiAction) {
+        self.rebinding = Some(action);
+    }
+    /// Give a key press to a pending rebinding, returns true if it was used. The mode toggle
+    /// can't be bound, it still switches the mode.
+    pub fn capture(&mut self, key: VirtualKeyCode, shift: bool) -> bool {
+        match self.rebinding {
+            Some(action) if key != TOGGLE_KEY => {
+                self.bindings.rebind(action, KeyBinding::new(key, shift));
+                self.rebinding = None;
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Queue an action for the next frame
+    pub fn push(&mut self, action: UiAction) {
+        if self.active {
+            self.actions.push(action);
+        }
+    }
+    /// Start a frame, before `egui::Context::run`. Gives the actions handled by the widgets to
+    /// egui as key presses.
+    pub fn begin_frame(&mut self, input: &mut egui::RawInput) {
+        self.order = FocusOrder::default();
+        for key in self.actions.iter().filter_map(|a| a.egui_key()) {
+            for pressed in [true, false] {
+                input.events.push(egui::Event::Key {
+                    key,
+                    pressed,
+                    modifiers: egui::Modifiers::default(),
+                });
+            }
+        }
+    }
+    /// Start registering the widgets of a panel
+    pub fn begin_panel(&mut self, name: &'static str) {
+        self.order.begin_panel(name);
+    }
+    /// Add a widget to the focus order of the current panel
+    pub fn track(&mut self, response: Response) -> Response {
+        self.set_layer(&response);
+        self.order
+            .push(response.id, response.rect, response.enabled);
+        response
+    }
+    /// Keep a place in the focus order for a widget whose response comes after the ones it
+    /// contains (combo boxes and their popup), see `fill`
+    pub fn reserve(&mut self) -> Option<(usize, usize)> {
+        self.order.reserve()
+    }
+    pub fn fill(&mut self, slot: Option<(usize, usize)>, response: &Response) {
+        if let Some((panel, widget)) = slot {
+            self.set_layer(response);
+            self.order.panels[panel].widgets[widget] = Widget {
+                id: response.id,
+                rect: response.rect,
+                enabled: response.enabled,
+            };
+        }
+    }
+    fn set_layer(&mut self, response: &Response) {
+        if let Some(panel) = self.order.panels.last_mut() {
+            // The first widget is in the window's layer, the ones after can be in popups
+            panel.layer.get_or_insert(response.layer_id);
+        }
+    }
+    /// End a frame, after `egui::Context::run`. Moves the focus with the queued actions and
+    /// highlights the focused widget.
+    pub fn end_frame(&mut self, ctx: &egui::Context) {
+        let actions = std::mem::take(&mut self.actions);
+        if !self.active {
+            return;
+        }
+
+        // The mouse (or egui itself) can give the focus to a widget too
+        let egui_focus = ctx.memory().focus();
+        let mut position = egui_focus
+            .and_then(|id| self.order.locate(id))
+            .or_else(|| self.position());
+        if position.is_none() {
+            position = self.order.navigate(None, UiAction::Down);
+        }
+
+        for action in actions {
+            let from = match position {
+                Some(from) => from,
+                None => break,
+            };
+            let (panel, widget) = self.order.navigate(Some(from), action).unwrap_or(from);
+            position = Some((panel, widget));
+            if panel == from.0 {
+                continue;
+            }
+            // Switched panel, bring it to the front and go back to where its focus was
+            let old = &self.order.panels[from.0];
+            self.remembered.insert(old.name, self.order.widget(from).id);
+            let new = &self.order.panels[panel];
+            if let Some(layer) = new.layer {
+                ctx.move_to_top(layer);
+            }
+            let remembered = self.remembered.get(new.name);
+            if let Some(widget) = new
+                .widgets
+                .iter()
+                .position(|w| w.enabled && Some(&w.id) == remembered)
+            {
+                position = Some((panel, widget));
+            }
+        }
+
+        self.focus = position.map(|(panel, index)| Focus {
+            panel: self.order.panels[panel].name,
+            id: self.order.widget((panel, index)).id,
+            index,
+        });
+        let widget = match position {
+            Some(position) => self.order.widget(position),
+            None => return,
+        };
+        if egui_focus != Some(widget.id) {
+            ctx.memory().request_focus(widget.id);
+        }
+        let stroke = Stroke::new(2.0f32, Color32::from_rgb(255, 200, 40));
+        ctx.layer_painter(LayerId::new(Order::Foreground, Id::new("ui_focus")))
+            .rect_stroke(widget.rect.expand(2.0), 2.0, stroke);
+    }
+    /// Position of the focus of the last frame in this frame's order
+    fn position(&self) -> Option<(usize, usize)> {
+        let focus = self.focus?;
+        self.order.locate(focus.id).or_else(|| {
+            let panel = self.order.panel_index(focus.panel)?;
+            let widgets = &self.order.panels[panel].widgets;
+            let index = focus.index.min(widgets.len().checked_sub(1)?);
+            if widgets[index].enabled {
+                Some((panel, index))
+            } else {
+                Some((panel, self.order.step(panel, index, -1)?))
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputMode {
+    /// Cursor grabbed, the inputs move the camera
+    Camera,
+    /// Cursor free, the inputs go to egui
+    Pointer,
+    /// Cursor free, the navigation keys move the UI focus and the rest go to egui
+    Navigation,
+}
+
+/// Where an input goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Camera,
+    Ui,
+    Navigate(UiAction),
+    /// Used by the router (mode switch, release of a navigation key)
+    Consumed,
+}
+
+/// Switches between the input modes and routes the inputs of the current one
+#[derive(Debug)]
+pub struct InputRouter {
+    mode: InputMode,
+}
+
+impl Default for InputRouter {
+    fn default() -> Self {
+        Self {
+            mode: InputMode::Pointer,
+        }
+    }
+}
+
+impl InputRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn mode(&self) -> InputMode {
+        self.mode
+    }
+    /// Whether the cursor should be grabbed
+    pub fn grabbed(&self) -> bool {
+        self.mode == InputMode::Camera
+    }
+    pub fn key(
+        &mut self,
+        bindings: &ActionMap,
+        key: VirtualKeyCode,
+        pressed: bool,
+        shift: bool,
+    ) -> Route {
+        match (self.mode, key) {
+            (mode, TOGGLE_KEY) => {
+                if pressed {
+                    self.mode = if mode == InputMode::Navigation {
+                        InputMode::Pointer
+                    } else {
+                        InputMode::Navigation
+                    };
+                }
+                Route::Consumed
+            }
+            (InputMode::Camera, VirtualKeyCode::Escape) => {
+                if pressed {
+                    self.mode = InputMode::Pointer;
+                }
+                Route::Consumed
+            }
+            (InputMode::Camera, _) => Route::Camera,
+            (InputMode::Pointer, _) => Route::Ui,
+            (InputMode::Navigation, key) => {
+                match bindings.action(key, shift).filter(|a| a.navigates()) {
+                    Some(action) if pressed => Route::Navigate(action),
+                    Some(_) => Route::Consumed,
+                    None => Route::Ui,
+                }
+            }
+        }
+    }
+    /// Leave the camera mode, returns true if it was in it
+    pub fn release(&mut self) -> bool {
+        if self.mode == InputMode::Camera {
+            self.mode = InputMode::Pointer;
+            true
+        } else {
+            false
+        }
+    }
+    /// A click that egui didn't use, returns true if it grabbed the cursor
+    pub fn click(&mut self) -> bool {
+        if self.mode == InputMode::Pointer {
+            self.mode = InputMode::Camera;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{pos2, vec2};
+
+    use super::*;
+
+    fn order(panels: &[(&'static str, &[bool])]) -> FocusOrder {
+        let mut order = FocusOrder::default();
+        for (name, widgets) in panels {
+            order.begin_panel(name);
+            for (i, enabled) in widgets.iter().enumerate() {
+                let rect = Rect::from_min_size(pos2(0.0, i as f32 * 20.0), vec2(100.0, 18.0));
+                order.push(Id::new((name, i)), rect, *enabled);
+            }
+        }
+        order
+    }
+
+    #[test]
+    fn widgets() {
+        let order = order(&[("a", &[true, false, true, true])]);
+        assert_eq!(Some((0, 0)), order.navigate(None, UiAction::Down));
+        // Skips the disabled one, and wraps around both ways
+        assert_eq!(Some((0, 2)), order.navigate(Some((0, 0)), UiAction::Down));
+        assert_eq!(Some((0, 0)), order.navigate(Some((0, 3)), UiAction::Down));
+        assert_eq!(Some((0, 3)), order.navigate(Some((0, 0)), UiAction::Up));
+        assert_eq!(Some((0, 2)), order.navigate(Some((0, 2)), UiAction::Accept));
+        assert_eq!(Some((0, 3)), order.locate(Id::new(("a", 3))));
+        assert_eq!(None, order.locate(Id::new(("a", 1))));
+
+        let mut order = order;
+        let slot = order.reserve();
+        order.push(Id::new("entry"), Rect::NOTHING, true);
+        assert_eq!(Some((0, 5)), order.navigate(Some((0, 3)), UiAction::Down));
+        order.panels[0].widgets[slot.unwrap().1] = Widget {
+            id: Id::new("combo"),
+            rect: Rect::NOTHING,
+            enabled: true,
+        };
+        assert_eq!(Some((0, 4)), order.navigate(Some((0, 3)), UiAction::Down));
+    }
+
+    #[test]
+    fn panels() {
+        let order = order(&[
+            ("a", &[true, true]),
+            ("empty", &[false]),
+            ("b", &[false, true]),
+        ]);
+        assert_eq!(
+            Some((2, 1)),
+            order.navigate(Some((0, 1)), UiAction::NextPanel)
+        );
+        assert_eq!(
+            Some((0, 0)),
+            order.navigate(Some((2, 1)), UiAction::NextPanel)
+        );
+        assert_eq!(
+            Some((2, 1)),
+            order.navigate(Some((0, 0)), UiAction::PrevPanel)
+        );
+        assert_eq!(None, FocusOrder::default().navigate(None, UiAction::Down));
+    }
+
+    #[test]
+    fn routes() {
+        use VirtualKeyCode::*;
+
+        let map = ActionMap::default();
+        let mut router = InputRouter::new();
+        assert_eq!(Route::Ui, router.key(&map, Up, true, false));
+        assert!(router.click());
+        assert!(router.grabbed());
+        assert_eq!(Route::Camera, router.key(&map, Z, true, false));
+        assert_eq!(Route::Consumed, router.key(&map, Escape, true, false));
+        assert_eq!(InputMode::Pointer, router.mode());
+        assert!(!router.release());
+        router.click();
+        assert!(router.release());
+        assert_eq!(InputMode::Pointer, router.mode());
+
+        assert_eq!(Route::Consumed, router.key(&map, TOGGLE_KEY, true, false));
+        assert_eq!(Route::Consumed, router.key(&map, TOGGLE_KEY, false, false));
+        assert_eq!(InputMode::Navigation, router.mode());
+        // Clicks never grab the cursor while navigating
+        assert!(!router.click());
+        assert!(!router.grabbed());
+        assert_eq!(
+            Route::Navigate(UiAction::Down),
+            router.key(&map, Down, true, false)
+        );
+        assert_eq!(Route::Consumed, router.key(&map, Down, false, false));
+        assert_eq!(
+            Route::Navigate(UiAction::PrevPanel),
+            router.key(&map, Tab, true, true)
+        );
+        assert_eq!(
+            Route::Navigate(UiAction::Back),
+            router.key(&map, Escape, true, false)
+        );
+        assert_eq!(InputMode::Navigation, router.mode());
+        // Typing in a focused text edit, movement keys included
+        assert_eq!(Route::Ui, router.key(&map, A, true, false));
+        assert_eq!(Route::Ui, router.key(&map, Z, true, false));
+
+        // From the camera too
+        router.key(&map, TOGGLE_KEY, true, false);
+        router.click();
+        router.key(&map, TOGGLE_KEY, true, false);
+        assert_eq!(InputMode::Navigation, router.mode());
+        assert!(!router.grabbed());
+    }
+
+    #[test]
+    fn bindings() {
+        use VirtualKeyCode::*;
+
+        let mut focus = UiFocus::new();
+        let map = focus.bindings();
+        assert_eq!(Some(UiAction::Accept), map.action(Return, false));
+        assert_eq!(Some(UiAction::NextPanel), map.action(Tab, false));
+        assert_eq!(Some(UiAction::PrevPanel), map.action(Tab, true));
+        // Shift doesn't matter for the keys bound without it
+        assert_eq!(Some(UiAction::Up), map.action(Up, true));
+        assert_eq!(None, map.action(W, false));
+
+        // No rebinding in progress
+        assert!(!focus.capture(W, false));
+        focus.start_rebinding(UiAction::Up);
+        assert_eq!(Some(UiAction::Up), focus.rebinding());
+        assert!(!focus.capture(TOGGLE_KEY, false));
+        assert!(focus.capture(W, false));
+        assert_eq!(None, focus.rebinding());
+        let map = focus.bindings();
+        assert_eq!(vec![KeyBinding::new(W, false)], map.keys(UiAction::Up));
+        assert_eq!(None, map.action(Up, false));
+        assert_eq!(Some(UiAction::Up), map.action(W, true));
+
+        // Taking the key of another action
+        focus.start_rebinding(UiAction::Down);
+        focus.capture(Tab, true);
+        let map = focus.bindings();
+        assert_eq!(Some(UiAction::Down), map.action(Tab, true));
+        assert_eq!(Some(UiAction::NextPanel), map.action(Tab, false));
+        assert!(map.keys(UiAction::PrevPanel).is_empty());
+        assert_eq!("Shift+Tab", map.keys(UiAction::Down)[0].to_string());
+
+        let mut router = InputRouter::new();
+        router.key(map, TOGGLE_KEY, true, false);
+        assert_eq!(Route::Navigate(UiAction::Up), router.key(map, W, true, false));
+        assert_eq!(Route::Ui, router.key(map, Up, true, false));
+    }
+
+    #[test]
+    fn movement() {
+        use VirtualKeyCode::*;
+
+        let mut focus = UiFocus::new();
+        let map = focus.bindings();
+        assert_eq!(Some(UiAction::MoveForward), map.action(Z, false));
+        assert_eq!(Some(UiAction::Jump), map.action(Space, false));
+        assert!(map.held(UiAction::MoveLeft, |key| key == Q));
+        assert!(map.held(UiAction::MoveLeft, |key| matches!(key, Q | D)));
+        assert!(!map.held(UiAction::MoveLeft, |key| key == D));
+        // Never given to egui
+        assert!(UiAction::ALL
+            .iter()
+            .filter(|a| !a.navigates())
+            .all(|a| a.egui_key().is_none()));
+
+        // Rebound like the UI actions
+        focus.start_rebinding(UiAction::MoveForward);
+        assert!(focus.capture(W, false));
+        let map = focus.bindings();
+        assert!(map.held(UiAction::MoveForward, |key| key == W));
+        assert!(!map.held(UiAction::MoveForward, |key| key == Z));
+    }
+}
@@ -0,0 +1,773 @@
+//! Volumetric fog.
+//!
+//! A half resolution pass marches each pixel's view ray up to the depth buffer, through an
+//! exponential height fog lit by an ambient term and the first directional light (with a
+//! Henyey-Greenstein phase). Each step integrates the fog analytically
+//! (`height_fog_optical_depth`), and the step offsets are jittered with blue noise (rotated every
+//! frame) so banding turns into fine noise. The shading pass then upsamples the result with depth
+//! aware weights (`upsample_weights`) and applies it before tonemapping.
+//!
+//! The sun is tested against its shadow map at every step (`sun_shadow`), so occluders cast light
+//! shafts through the fog. It is the first directional light, so it always gets the first layer of
+//! the atlas when shadows are on. The other lights don't light the fog.
+
+use glam::{Mat4, Vec2, Vec3};
+use wgpu::util::DeviceExt;
+
+use crate::console::{unknown_field, Arg, Inspect};
+use crate::include_shader;
+use crate::localization::Localization;
+use crate::tr;
+
+use super::{
+    camera::Camera,
+    focus::UiFocus,
+    memory::texture_bytes,
+    pipeline::{Pipeline, RenderPipeline},
+    shadows::{ShadowAtlas, ShadowView},
+    timer::GpuTimer,
+    DiretionalLight,
+};
+
+/// Format of the half resolution fog texture
+pub const FOG_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+/// Side of the blue noise texture
+const BLUE_NOISE_SIZE: u32 = 64;
+/// Golden ratio conjugate, offsets the blue noise every frame
+const JITTER_STEP: f32 = 0.618_034;
+/// Bounds of the adaptive step count
+const MIN_STEPS: u32 = 8;
+const MAX_STEPS: u32 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FogSettings {
+    pub enabled: bool,
+    /// Extinction at `base_height`
+    pub density: f32,
+    /// How fast the density decreases with height
+    pub height_falloff: f32,
+    pub base_height: f32,
+    /// Scattering color (albedo of the fog)
+    pub color: Vec3,
+    /// Intensity of the ambient light scattered by the fog
+    pub ambient: f32,
+    /// Henyey-Greenstein asymmetry, from -1 (back scattering) to 1 (forward scattering)
+    pub anisotropy: f32,
+    /// Raymarch steps per pixel, lowered when the pass goes over `budget_ms`
+    pub steps: u32,
+    /// Distance the rays over the sky are marched to
+    pub max_distance: f32,
+    /// GPU time allowed for the raymarch, in milliseconds (needs timestamp queries)
+    pub budget_ms: f32,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            density: 0.05,
+            height_falloff: 0.2,
+            base_height: 0.0,
+            color: Vec3::new(0.8, 0.85, 0.9),
+            ambient: 0.3,
+            anisotropy: 0.6,
+            steps: 32,
+            max_distance: 50.0,
+            budget_ms: 1.5,
+        }
+    }
+}
+
+impl Inspect for FogSettings {
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("enabled", self.enabled.to_string()),
+            ("density", self.density.to_string()),
+            ("height_falloff", self.height_falloff.to_string()),
+            ("base_height", self.base_height.to_string()),
+            ("ambient", self.ambient.to_string()),
+            ("anisotropy", self.anisotropy.to_string()),
+            ("steps", self.steps.to_string()),
+            ("max_distance", self.max_distance.to_string()),
+            ("budget_ms", self.budget_ms.to_string()),
+        ]
+    }
+    fn set_field(&mut self, field: &str, value: &Arg) -> anyhow::Result<()> {
+        match field {
+            "enabled" => self.enabled = value.as_bool()?,
+            "density" => self.density = value.as_f32()?.max(0.0),
+            "height_falloff" => self.height_falloff = value.as_f32()?.max(0.0),
+            "base_height" => self.base_height = value.as_f32()?,
+            "ambient" => self.ambient = value.as_f32()?.max(0.0),
+            "anisotropy" => self.anisotropy = value.as_f32()?.clamp(-0.99, 0.99),
+            "steps" => self.steps = value.as_u32()?.clamp(MIN_STEPS, MAX_STEPS),
+            "max_distance" => self.max_distance = value.as_f32()?.max(0.0),
+            "budget_ms" => self.budget_ms = value.as_f32()?.max(0.0),
+            _ => return Err(unknown_field(self, field)),
+        }
+        Ok(())
+    }
+}
+
+/// Henyey-Greenstein phase function, the fraction of light scattered at an angle `theta` from its
+/// direction (per steradian)
+pub fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let g2 = g * g;
+    let denom = 1.0 + g2 - 2.0 * g * cos_theta;
+    (1.0 - g2) / (4.0 * std::f32::consts::PI * denom * denom.sqrt())
+}
+
+/// Integral of the fog density `density * exp(-falloff * (height - base))` along a segment of
+/// `len` starting at `start_height`, going up by `dir_y` per unit.
+pub fn height_fog_optical_depth(
+    start_height: f32,
+    dir_y: f32,
+    len: f32,
+    density: f32,
+    falloff: f32,
+    base: f32,
+) -> f32 {
+    let start = density * (-falloff * (start_height - base)).exp();
+    let k = falloff * dir_y * len;
+    // (1 - e^-k) / k tends to 1, but loses precision, on flat segments
+    if k.abs() < 1e-4 {
+        start * len
+    } else {
+        start * len * (1.0 - (-k).exp()) / k
+    }
+}
+
+/// Weights of the four half resolution texels around a pixel, from their bilinear weights and
+/// linear depths compared to the pixel's. Texels across a depth discontinuity get little weight,
+/// so fog doesn't bleed over silhouettes.
+pub fn upsample_weights(bilinear: [f32; 4], depths: [f32; 4], depth: f32) -> [f32; 4] {
+    let mut weights = [0.0; 4];
+    for i in 0..4 {
+        weights[i] = bilinear[i] / (0.01 + (depths[i] - depth).abs() / depth);
+    }
+    let total: f32 = weights.iter().sum();
+    weights.map(|w| w / total)
+}
+
+/// Layer of the shadow atlas and view projection of the sun, the layer being -1 without shadows.
+/// The sun is the first directional light, which `GBuffer::write_lights` shadows first.
+pub fn sun_shadow(sun: &DiretionalLight, shadows: &ShadowView) -> (i32, Mat4) {
+    if shadows.layers == 0 {
+        (-1, Mat4::IDENTITY)
+    } else {
+        (0, shadows.directional(sun))
+    }
+}
+
+/// Where a position is in a shadow map: its uv and depth, `None` outside of the map (which is lit)
+pub fn shadow_coords(view_proj: Mat4, pos: Vec3) -> Option<(Vec2, f32)> {
+    let clip = view_proj * pos.extend(1.0);
+    let ndc = clip.truncate() / clip.w;
+    if clip.w <= 0.0 || ndc.x.abs() > 1.0 || ndc.y.abs() > 1.0 || ndc.z > 1.0 {
+        return None;
+    }
+    Some((Vec2::new(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5), ndc.z))
+}
+
+/// Step count for the next frame, from the time the last raymarch took
+pub fn adapt_steps(current: u32, max: u32, time_ms: f32, budget_ms: f32) -> u32 {
+    if time_ms > budget_ms {
+        (current * 3 / 4).max(MIN_STEPS)
+    } else if time_ms < budget_ms * 0.75 {
+        (current + 2).min(max)
+    } else {
+        current.min(max)
+    }
+}
+
+/// Blue noise (void and cluster without the initial pattern): each texel gets the rank at which
+/// it was picked, always the texel with the least energy from the previous ones.
+pub fn blue_noise(size: u32) -> Vec<u8> {
+    const SIGMA: f32 = 1.9;
+    const RADIUS: i32 = 6;
+    let n = (size * size) as usize;
+    let mut energy = vec![0.0f32; n];
+    let mut ranks = vec![u32::MAX; n];
+    for rank in 0..n as u32 {
+        let (index, _) = energy
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| ranks[*i] == u32::MAX)
+            .min_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        ranks[index] = rank;
+        let (x, y) = ((index as u32 % size) as i32, (index as u32 / size) as i32);
+        for dy in -RADIUS..=RADIUS {
+            for dx in -RADIUS..=RADIUS {
+                let tx = (x + dx).rem_euclid(size as i32) as usize;
+                let ty = (y + dy).rem_euclid(size as i32) as usize;
+                energy[ty * size as usize + tx] +=
+                    (-((dx * dx + dy * dy) as f32) / (2.0 * SIGMA * SIGMA)).exp();
+            }
+        }
+    }
+    ranks
+        .into_iter()
+        .map(|rank| (rank as u64 * 256 / n as u64) as u8)
+        .collect()
+}
+
+// Mirrors FogParams in fog.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct FogParams {
+    inv_view_proj: Mat4,
+    cam_pos: Vec3,
+    density: f32,
+    sun_dir: Vec3,
+    height_falloff: f32,
+    sun_color: Vec3,
+    base_height: f32,
+    color: Vec3,
+    ambient: f32,
+    anisotropy: f32,
+    max_distance: f32,
+    steps: u32,
+    jitter: f32,
+    sun_view_proj: Mat4,
+    sun_shadow: i32,
+    padding: [i32; 3],
+}
+
+// Mirrors FogUpsample in shader.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct FogUpsample {
+    near: f32,
+    far: f32,
+    half_size: [i32; 2],
+}
+
+pub struct VolumetricFog {
+    pub settings: FogSettings,
+    /// Steps actually used, adapted to stay within the budget
+    steps: u32,
+    /// GPU time of the last measured raymarch, in milliseconds
+    gpu_time: Option<f32>,
+    timer: Option<GpuTimer>,
+    frame: u32,
+    sun: Option<DiretionalLight>,
+    /// Multiplies the density of the settings, for the weather
+    density_scale: f32,
+    pipeline: RenderPipeline,
+    params: wgpu::Buffer,
+    upsample: wgpu::Buffer,
+    blue_noise: wgpu::TextureView,
+    target: wgpu::TextureView,
+    half_size: (u32, u32),
+    bind_group: wgpu::BindGroup,
+    /// The shadow atlas, see `set_shadow_atlas`
+    shadows: wgpu::BindGroup,
+    /// Layout of the bind group the shading pass reads the fog from
+    pub composite_layout: wgpu::BindGroupLayout,
+    pub composite: wgpu::BindGroup,
+}
+
+impl VolumetricFog {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        depth: &wgpu::TextureView,
+        atlas: &ShadowAtlas,
+        size: (u32, u32),
+    ) -> Self {
+        let layout = create_bind_group_layout!(device, "Fog Bindgroup Layout": {
+            0 => FRAGMENT | Buffer(type: Uniform),
+            1 => FRAGMENT | Texture(sample: Depth, view_dim: D2),
+            2 => FRAGMENT | Texture(sample: Float, view_dim: D2),
+        });
+        let shadows_layout = create_bind_group_layout!(device, "Fog Shadows Bindgroup Layout": {
+            0 => FRAGMENT | Texture(sample: Depth, view_dim: D2Array),
+            1 => FRAGMENT | Sampler(Comparison),
+        });
+        let composite_layout = create_bind_group_layout!(device, "Fog Composite Bindgroup Layout": {
+            0 => FRAGMENT | Texture(sample: Float, view_dim: D2),
+            1 => FRAGMENT | Buffer(type: Uniform),
+        });
+        let pipeline = Pipeline::new(
+            device,
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Fog Pipeline Layout"),
+                bind_group_layouts: &[&layout, &shadows_layout],
+                push_constant_ranges: &[],
+            }),
+            include_shader!("fog.wgsl", "Fog Shader"),
+            |device, layout, module| {
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Fog Pipeline"),
+                    layout: Some(layout),
+                    vertex: wgpu::VertexState {
+                        module,
+                        entry_point: "vs_main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module,
+                        entry_point: "fs_main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: FOG_FORMAT,
+                            blend: None,
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            },
+        );
+        let uniform = |label, size| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: size as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        let params = uniform("Fog Params", std::mem::size_of::<FogParams>());
+        let upsample = uniform("Fog Upsample", std::mem::size_of::<FogUpsample>());
+        let blue_noise = device
+            .create_texture_with_data(
+                queue,
+                &wgpu::TextureDescriptor {
+                    label: Some("Fog Blue Noise"),
+                    size: wgpu::Extent3d {
+                        width: BLUE_NOISE_SIZE,
+                        height: BLUE_NOISE_SIZE,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: wgpu::TextureFormat::R8Unorm,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                },
+                &blue_noise(BLUE_NOISE_SIZE),
+            )
+            .create_view(&Default::default());
+        let half_size = Self::half_size(size);
+        let target = Self::make_target(device, half_size);
+        let bind_group = Self::make_bind_group(device, &layout, &params, depth, &blue_noise);
+        let shadows = Self::make_shadows(device, &shadows_layout, atlas);
+        let composite = Self::make_composite(device, &composite_layout, &target, &upsample);
+        let settings = FogSettings::default();
+        Self {
+            steps: settings.steps,
+            settings,
+            gpu_time: None,
+            timer: GpuTimer::new(device, queue, "Fog"),
+            frame: 0,
+            sun: None,
+            density_scale: 1.0,
+            pipeline,
+            params,
+            upsample,
+            blue_noise,
+            target,
+            half_size,
+            bind_group,
+            shadows,
+            composite_layout,
+            composite,
+        }
+    }
+    /// Bytes taken by the target and the noise
+    pub fn memory(&self) -> u64 {
+        texture_bytes(self.half_size.0, self.half_size.1, FOG_FORMAT, 1)
+            + texture_bytes(BLUE_NOISE_SIZE, BLUE_NOISE_SIZE, wgpu::TextureFormat::R8Unorm, 1)
+    }
+    fn half_size(size: (u32, u32)) -> (u32, u32) {
+        (size.0.div_ceil(2).max(1), size.1.div_ceil(2).max(1))
+    }
+    fn make_target(device: &wgpu::Device, size: (u32, u32)) -> wgpu::TextureView {
+        device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("Fog Target"),
+                size: wgpu::Extent3d {
+                    width: size.0,
+                    height: size.1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: FOG_FORMAT,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+            })
+            .create_view(&Default::default())
+    }
+    fn make_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        params: &wgpu::Buffer,
+        depth: &wgpu::TextureView,
+        blue_noise: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        create_bind_group!(device, layout, "Fog Bindgroup": {
+            0 | Buffer(buffer: params),
+            1 | TextureView(depth),
+            2 | TextureView(blue_noise),
+        })
+    }
+    fn make_shadows(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        atlas: &ShadowAtlas,
+    ) -> wgpu::BindGroup {
+        create_bind_group!(device, layout, "Fog Shadows Bindgroup": {
+            0 | TextureView(&atlas.view),
+            1 | Sampler(&atlas.sampler),
+        })
+    }
+    fn make_composite(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        target: &wgpu::TextureView,
+        upsample: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        create_bind_group!(device, layout, "Fog Composite Bindgroup": {
+            0 | TextureView(target),
+            1 | Buffer(buffer: upsample),
+        })
+    }
+    /// The pipeline, for `WorldRenderer::reload_shaders`
+    pub fn pipelines_mut(&mut self) -> [&mut RenderPipeline; 1] {
+        [&mut self.pipeline]
+    }
+    /// Recreate the targets, `depth` being the new depth buffer
+    pub fn resize(&mut self, device: &wgpu::Device, depth: &wgpu::TextureView, size: (u32, u32)) {
+        self.half_size = Self::half_size(size);
+        self.target = Self::make_target(device, self.half_size);
+        self.bind_group = Self::make_bind_group(
+            device,
+            &self.pipeline.pipeline.get_bind_group_layout(0),
+            &self.params,
+            depth,
+            &self.blue_noise,
+        );
+        self.composite =
+            Self::make_composite(device, &self.composite_layout, &self.target, &self.upsample);
+    }
+    /// Sample a new shadow atlas (see `WorldRenderer::set_shadow_resolution`)
+    pub fn set_shadow_atlas(&mut self, device: &wgpu::Device, atlas: &ShadowAtlas) {
+        self.shadows =
+            Self::make_shadows(device, &self.pipeline.pipeline.get_bind_group_layout(1), atlas);
+    }
+    /// The directional light lighting the fog
+    pub fn set_sun(&mut self, sun: Option<DiretionalLight>) {
+        self.sun = sun;
+    }
+    /// Scale the density of the settings, without changing them
+    pub fn set_density_scale(&mut self, scale: f32) {
+        self.density_scale = scale;
+    }
+    /// GPU time of the raymarch, if the device has timestamp queries
+    pub fn gpu_time(&self) -> Option<f32> {
+        self.gpu_time
+    }
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+    /// Record the raymarch, does nothing if the fog is disabled. `shadows` is what the shadow maps
+    /// of the frame cover.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        camera: &Camera,
+        shadows: &ShadowView,
+    ) {
+        if let Some(time) = self.timer.as_mut().and_then(|timer| timer.poll(device)) {
+            self.gpu_time = Some(time);
+            self.steps = adapt_steps(
+                self.steps,
+                self.settings.steps,
+                time,
+                self.settings.budget_ms,
+            );
+        }
+        if !self.settings.enabled {
+            return;
+        }
+        self.steps = self
+            .steps
+            .clamp(MIN_STEPS.min(self.settings.steps), self.settings.steps);
+        self.frame = self.frame.wrapping_add(1);
+
+        let s = &self.settings;
+        let (sun_dir, sun_color, (sun_shadow, sun_view_proj)) = match &self.sun {
+            Some(sun) => (
+                sun.direction.normalize_or_zero(),
+                sun.color.truncate() * sun.color.w,
+                sun_shadow(sun, shadows),
+            ),
+            None => (-Vec3::Y, Vec3::ZERO, (-1, Mat4::IDENTITY)),
+        };
+        let params = FogParams {
+            inv_view_proj: camera.get_view_projection().inverse(),
+            cam_pos: camera.get_position(),
+            density: s.density * self.density_scale,
+            sun_dir,
+            height_falloff: s.height_falloff,
+            sun_color,
+            base_height: s.base_height,
+            color: s.color,
+            ambient: s.ambient,
+            anisotropy: s.anisotropy.clamp(-0.99, 0.99),
+            max_distance: s.max_distance,
+            steps: self.steps,
+            jitter: (self.frame as f32 * JITTER_STEP).fract(),
+            sun_view_proj,
+            sun_shadow,
+            padding: [0; 3],
+        };
+        let upsample = FogUpsample {
+            near: camera.get_near(),
+            far: camera.get_far(),
+            half_size: [self.half_size.0 as i32, self.half_size.1 as i32],
+        };
+        queue.write_buffer(&self.params, 0, bytemuck::bytes_of(&params));
+        queue.write_buffer(&self.upsample, 0, bytemuck::bytes_of(&upsample));
+
+        let timer = self.timer.as_mut().filter(|timer| timer.ready());
+        if let Some(timer) = &timer {
+            timer.begin(encoder);
+        }
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Fog Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(&self.pipeline.pipeline);
+            pass.set_bind_group(0, &self.bind_group, &[]);
+            pass.set_bind_group(1, &self.shadows, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        if let Some(timer) = timer {
+            timer.end(encoder);
+        }
+    }
+    /// Must be called once the frame's commands have been submitted
+    pub fn after_submit(&mut self) {
+        if let Some(timer) = &mut self.timer {
+            timer.after_submit();
+        }
+    }
+
+    /// Draw the settings window
+    pub(super) fn ui(&mut self, ctx: &egui::Context, focus: &mut UiFocus, loc: &mut Localization) {
+        let s = &mut self.settings;
+        focus.begin_panel("fog");
+        egui::Window::new(tr!(loc, "fog.window")).show(ctx, |ui| {
+            focus.track(ui.checkbox(&mut s.enabled, tr!(loc, "fog.enabled")));
+            focus.track(
+                ui.add(egui::Slider::new(&mut s.density, 0.0..=1.0).text(tr!(loc, "fog.density"))),
+            );
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.height_falloff, 0.0..=2.0)
+                        .text(tr!(loc, "fog.height_falloff")),
+                ),
+            );
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.base_height, -20.0..=20.0)
+                        .text(tr!(loc, "fog.base_height")),
+                ),
+            );
+            focus.track(
+                ui.add(egui::Slider::new(&mut s.ambient, 0.0..=2.0).text(tr!(loc, "fog.ambient"))),
+            );
+            focus.track(ui.add(
+                egui::Slider::new(&mut s.anisotropy, -0.9..=0.9).text(tr!(loc, "fog.anisotropy")),
+            ));
+            focus.track(ui.add(
+                egui::Slider::new(&mut s.steps, MIN_STEPS..=MAX_STEPS).text(tr!(loc, "fog.steps")),
+            ));
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.max_distance, 1.0..=500.0)
+                        .text(tr!(loc, "fog.max_distance")),
+                ),
+            );
+            let mut color = s.color.to_array();
+            ui.horizontal(|ui| {
+                ui.label(tr!(loc, "fog.color"));
+                focus.track(ui.color_edit_button_rgb(&mut color));
+            });
+            s.color = Vec3::from(color);
+            if self.timer.is_some() {
+                focus.track(ui.add(
+                    egui::Slider::new(&mut s.budget_ms, 0.1..=10.0).text(tr!(loc, "fog.budget")),
+                ));
+            }
+            if let Some(time) = self.gpu_time {
+                ui.label(tr!(
+                    loc,
+                    "fog.gpu_time",
+                    time = format!("{time:.2}"),
+                    steps = self.steps
+                ));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    /// Integral over the sphere of a function of the angle to an axis
+    fn integrate_sphere(f: impl Fn(f32) -> f32) -> f32 {
+        let n = 10000;
+        (0..n)
+            .map(|i| {
+                let theta = (i as f32 + 0.5) / n as f32 * PI;
+                f(theta.cos()) * 2.0 * PI * theta.sin() * PI / n as f32
+            })
+            .sum()
+    }
+
+    #[test]
+    fn phase_function() {
+        // Isotropic without anisotropy
+        assert!((henyey_greenstein(0.3, 0.0) - 1.0 / (4.0 * PI)).abs() < 1e-6);
+        for g in [-0.8, -0.3, 0.0, 0.5, 0.9] {
+            let total = integrate_sphere(|cos| henyey_greenstein(cos, g));
+            assert!((total - 1.0).abs() < 1e-2, "g = {g}: {total}");
+        }
+        // Forward scattering peaks towards the light's direction
+        assert!(henyey_greenstein(1.0, 0.6) > henyey_greenstein(-1.0, 0.6));
+        assert!(henyey_greenstein(1.0, -0.6) < henyey_greenstein(-1.0, -0.6));
+    }
+
+    #[test]
+    fn height_fog_integral() {
+        let (density, falloff, base) = (0.3, 0.5, 1.0);
+        let numeric = |start: f32, dir_y: f32, len: f32| {
+            let n = 100000;
+            let dt = len / n as f32;
+            (0..n)
+                .map(|i| {
+                    let h = start + dir_y * (i as f32 + 0.5) * dt;
+                    density * (-falloff * (h - base)).exp() * dt
+                })
+                .sum::<f32>()
+        };
+        for (start, dir_y, len) in [
+            (0.0, 0.5, 10.0),
+            (5.0, -0.8, 4.0),
+            (2.0, 1.0, 0.5),
+            (3.0, 0.0, 7.0),
+        ] {
+            let analytic = height_fog_optical_depth(start, dir_y, len, density, falloff, base);
+            let expected = numeric(start, dir_y, len);
+            assert!(
+                (analytic - expected).abs() < 1e-3 * expected.max(1.0),
+                "{analytic} != {expected}"
+            );
+        }
+        // Nearly horizontal rays don't lose precision
+        let flat = height_fog_optical_depth(2.0, 1e-7, 3.0, density, falloff, base);
+        let horizontal = height_fog_optical_depth(2.0, 0.0, 3.0, density, falloff, base);
+        assert!((flat - horizontal).abs() < 1e-6);
+        // Splitting a segment doesn't change its integral
+        let whole = height_fog_optical_depth(0.0, 0.6, 8.0, density, falloff, base);
+        let halves = height_fog_optical_depth(0.0, 0.6, 4.0, density, falloff, base)
+            + height_fog_optical_depth(2.4, 0.6, 4.0, density, falloff, base);
+        assert!((whole - halves).abs() < 1e-5);
+    }
+
+    #[test]
+    fn upsample() {
+        let bilinear = [0.25; 4];
+        let same = upsample_weights(bilinear, [10.0; 4], 10.0);
+        assert_eq!([0.25; 4], same);
+        let sum: f32 = upsample_weights([0.1, 0.2, 0.3, 0.4], [1.0, 2.0, 3.0, 4.0], 2.5)
+            .iter()
+            .sum();
+        assert!((sum - 1.0).abs() < 1e-6);
+        // A pixel on a foreground silhouette ignores the background texels
+        let edge = upsample_weights(bilinear, [2.0, 2.0, 50.0, 50.0], 2.0);
+        assert!(edge[0] > 0.49 && edge[1] > 0.49);
+        assert!(edge[2] < 0.01 && edge[3] < 0.01);
+        // And the other way around
+        let edge = upsample_weights([0.7, 0.1, 0.1, 0.1], [2.0, 50.0, 50.0, 50.0], 50.0);
+        assert!(edge[0] < 0.05);
+    }
+
+    #[test]
+    fn sun_shadows() {
+        let sun = DiretionalLight::new(Vec3::new(0.3, -1.0, 0.2), glam::Vec4::ONE);
+        let view = ShadowView {
+            layers: 4,
+            resolution: 1024,
+            center: Vec3::new(5.0, 0.0, -3.0),
+        };
+        let (layer, view_proj) = sun_shadow(&sun, &view);
+        assert_eq!(0, layer);
+        assert_eq!(view.directional(&sun), view_proj);
+        assert_eq!(-1, sun_shadow(&sun, &ShadowView::NONE).0);
+
+        // Around the camera is in the map, farther along the light is deeper
+        let (uv, depth) = shadow_coords(view_proj, view.center).unwrap();
+        assert!((uv - Vec2::splat(0.5)).abs().max_element() < 0.01, "{uv}");
+        let (_, deeper) = shadow_coords(view_proj, view.center + sun.direction).unwrap();
+        assert!(deeper > depth);
+        // Far to the side isn't
+        assert_eq!(None, shadow_coords(view_proj, view.center + Vec3::X * 100.0));
+        // The params keep the layout of fog.wgsl (a multiple of 16 bytes)
+        assert_eq!(224, std::mem::size_of::<FogParams>());
+    }
+
+    #[test]
+    fn step_budget() {
+        assert_eq!(24, adapt_steps(32, 32, 2.0, 1.5));
+        assert_eq!(MIN_STEPS, adapt_steps(9, 32, 2.0, 1.5));
+        assert_eq!(26, adapt_steps(24, 32, 0.5, 1.5));
+        assert_eq!(32, adapt_steps(32, 32, 0.5, 1.5));
+        assert_eq!(24, adapt_steps(24, 32, 1.4, 1.5));
+        // Lowering the setting applies right away
+        assert_eq!(16, adapt_steps(32, 16, 1.4, 1.5));
+    }
+
+    #[test]
+    fn blue_noise_ranks() {
+        let size = 16;
+        let noise = blue_noise(size);
+        // Every value is used as often (the ranks are a permutation)
+        let mut histogram = [0; 256];
+        for v in &noise {
+            histogram[*v as usize] += 1;
+        }
+        assert!(histogram.iter().all(|&c| c == 1));
+        // Neighbours are far apart in value
+        let mut close = 0;
+        for y in 0..size {
+            for x in 0..size {
+                let a = noise[(y * size + x) as usize] as i32;
+                let b = noise[(y * size + (x + 1) % size) as usize] as i32;
+                close += ((a - b).abs() < 16) as u32;
+            }
+        }
+        assert!(close < size * size / 8, "{close}");
+    }
+}
@@ -0,0 +1,125 @@
+// Volumetric fog raymarch, at half resolution. Outputs the light scattered towards the camera
+// (rgb) and the transmittance (a) along each pixel's view ray, up to the depth buffer. The sun is
+// occluded by its shadow map.
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    var v_out: VertexOutput;
+    v_out.uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    v_out.clip_position = vec4<f32>(v_out.uv * 2.0 - 1.0, 0.0, 1.0);
+    return v_out;
+}
+
+// Mirrors fog::FogParams
+struct FogParams {
+    inv_view_proj: mat4x4<f32>,
+    cam_pos: vec3<f32>,
+    density: f32,
+    sun_dir: vec3<f32>,
+    height_falloff: f32,
+    sun_color: vec3<f32>,
+    base_height: f32,
+    color: vec3<f32>,
+    ambient: f32,
+    anisotropy: f32,
+    max_distance: f32,
+    steps: u32,
+    // Offset of the step jitter this frame
+    jitter: f32,
+    sun_view_proj: mat4x4<f32>,
+    // Layer of the shadow atlas, -1 if unshadowed
+    sun_shadow: i32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: FogParams;
+@group(0) @binding(1)
+var depth: texture_depth_2d;
+@group(0) @binding(2)
+var blue_noise: texture_2d<f32>;
+@group(1) @binding(0)
+var shadow_atlas: texture_depth_2d_array;
+@group(1) @binding(1)
+var shadow_sampler: sampler_comparison;
+
+let PI = 3.1415926535;
+
+// Mirrors fog::henyey_greenstein
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let g2 = g * g;
+    let denom = 1.0 + g2 - 2.0 * g * cos_theta;
+    return (1.0 - g2) / (4.0 * PI * denom * sqrt(denom));
+}
+
+// Mirrors fog::height_fog_optical_depth
+fn optical_depth(start_height: f32, dir_y: f32, len: f32) -> f32 {
+    let base = params.density * exp(-params.height_falloff * (start_height - params.base_height));
+    let k = params.height_falloff * dir_y * len;
+    if (abs(k) < 1e-4) {
+        return base * len;
+    }
+    return base * len * (1.0 - exp(-k)) / k;
+}
+
+// How lit a position is by the sun, a single sample of its shadow map (the steps and their jitter
+// soften it). Mirrors fog::shadow_coords, outside of the shadow map it is lit.
+fn sun_visibility(pos: vec3<f32>) -> f32 {
+    if (params.sun_shadow < 0) {
+        return 1.0;
+    }
+    let clip = params.sun_view_proj * vec4<f32>(pos, 1.0);
+    let ndc = clip.xyz / clip.w;
+    if (clip.w <= 0.0 || abs(ndc.x) > 1.0 || abs(ndc.y) > 1.0 || ndc.z > 1.0) {
+        return 1.0;
+    }
+    let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+    return textureSampleCompareLevel(shadow_atlas, shadow_sampler, uv, params.sun_shadow, ndc.z);
+}
+
+@fragment
+fn fs_main(v_in: VertexOutput) -> @location(0) vec4<f32> {
+    let pixel = vec2<i32>(v_in.clip_position.xy);
+    // The full resolution texel the composite pass compares depths against
+    let d = textureLoad(depth, min(pixel * 2, textureDimensions(depth) - 1), 0);
+
+    let ndc = vec2<f32>(v_in.uv.x * 2.0 - 1.0, v_in.uv.y * 2.0 - 1.0);
+    let far_point = params.inv_view_proj * vec4<f32>(ndc, min(d, 1.0), 1.0);
+    let end = far_point.xyz / far_point.w;
+    let ray = end - params.cam_pos;
+    var len = length(ray);
+    let dir = ray / len;
+    if (d >= 1.0 || len > params.max_distance) {
+        len = params.max_distance;
+    }
+
+    let noise = textureLoad(blue_noise, pixel % textureDimensions(blue_noise), 0).r;
+    let offset = fract(noise + params.jitter);
+    let step_len = len / f32(params.steps);
+    let phase = henyey_greenstein(dot(dir, -params.sun_dir), params.anisotropy);
+    let sun = params.sun_color * phase;
+
+    var transmittance = 1.0;
+    var scattered = vec3<f32>(0.0);
+    // The first step is shortened by the jitter, so the steps still cover the whole ray
+    var t = 0.0;
+    for (var i = 0u; i <= params.steps; i++) {
+        let next = min((f32(i) + offset) * step_len, len);
+        let seg = next - t;
+        if (seg > 0.0) {
+            // Lit at the middle of the segment
+            let visibility = sun_visibility(params.cam_pos + dir * (t + seg * 0.5));
+            let light = params.color * (params.ambient + sun * visibility);
+            let od = optical_depth(params.cam_pos.y + dir.y * t, dir.y, seg);
+            let seg_transmittance = exp(-od);
+            scattered += transmittance * light * (1.0 - seg_transmittance);
+            transmittance *= seg_transmittance;
+        }
+        t = next;
+    }
+    return vec4<f32>(scattered, transmittance);
+}
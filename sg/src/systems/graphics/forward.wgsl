@@ -0,0 +1,33 @@
+// Blended materials, lit on their own over the shaded scene. After camera.wgsl, lighting.wgsl and
+// geometry.wgsl.
+
+@group(2) @binding(0)
+var textures: binding_array<texture_2d<f32>>;
+@group(2) @binding(1)
+var samplers: binding_array<sampler>;
+
+@fragment
+fn fs_main(v_in: VertexOutput, @builtin(front_facing) front_facing: bool) -> @location(0) vec4<f32> {
+    let TBN = mat3x3<f32>(v_in.tangent, v_in.bitangent, v_in.normal);
+    let albedo = textureSample(textures[0], samplers[0], v_in.tex_coords);
+    var surface: Surface;
+    surface.position = v_in.world_position;
+    surface.normal = normalize(TBN * (
+        textureSample(textures[1], samplers[1], v_in.tex_coords).xyz * 2.0 - vec3<f32>(1.0)
+    ));
+    if ({{DOUBLE_SIDED}} && !front_facing) {
+        surface.normal = -surface.normal;
+    }
+    surface.albedo = albedo.rgb;
+    surface.metallic = textureSample(textures[2], samplers[2], v_in.tex_coords).x;
+    surface.roughness = textureSample(textures[3], samplers[3], v_in.tex_coords).x;
+    surface.ao = textureSample(textures[4], samplers[4], v_in.tex_coords).x;
+    surface.exposed = v_in.exposed;
+    let emissive = textureSample(textures[5], samplers[5], v_in.tex_coords).rgb * v_in.material.rgb;
+    let s = weathered(surface);
+
+    let view_dir = normalize(cam.pos - s.position);
+    let color = surface_light(s, view_dir) + emissive;
+    // Blended over the scene, which is already exposed
+    return vec4<f32>(color * weather.exposure, albedo.a);
+}
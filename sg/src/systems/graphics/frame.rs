@@ -0,0 +1,102 @@
+//! The frame shared by the rendering systems.
+//!
+//! `GraphicContext::begin_frame` acquires the surface texture and creates the encoder in the
+//! `Frame` resource, the renderers (`WorldRenderer::render`, `UIRenderer::render`) record their
+//! passes into it, and `GraphicContext::end_frame` submits and presents it. A pass (debug lines...)
+//! is inserted by adding a system taking `ResMut<Frame>` between them in the schedule.
+
+use std::time::Instant;
+
+use super::FrameTimings;
+
+/// What the passes of a frame record into
+struct FrameTarget {
+    /// None when rendering into something else than the surface
+    output: Option<wgpu::SurfaceTexture>,
+    view: wgpu::TextureView,
+    encoder: wgpu::CommandEncoder,
+}
+
+/// The frame being recorded
+pub struct Frame {
+    target: Option<FrameTarget>,
+    feedback: Result<(), wgpu::SurfaceError>,
+    timings: FrameTimings,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self {
+            target: None,
+            feedback: Ok(()),
+            timings: FrameTimings::default(),
+        }
+    }
+}
+
+impl Frame {
+    pub(super) fn begin(
+        &mut self,
+        output: Option<wgpu::SurfaceTexture>,
+        view: wgpu::TextureView,
+        encoder: wgpu::CommandEncoder,
+    ) {
+        self.target = Some(FrameTarget {
+            output,
+            view,
+            encoder,
+        });
+    }
+    /// The encoder and the surface texture of the frame, None if there is no frame to render
+    /// (surface error, no surface)
+    pub(super) fn finish(
+        &mut self,
+    ) -> Option<(wgpu::CommandEncoder, Option<wgpu::SurfaceTexture>)> {
+        self.target
+            .take()
+            .map(|target| (target.encoder, target.output))
+    }
+    pub(super) fn set_feedback(&mut self, feedback: Result<(), wgpu::SurfaceError>) {
+        self.feedback = feedback;
+    }
+    /// The timings of the passes recorded so far
+    pub(super) fn take_timings(&mut self) -> FrameTimings {
+        std::mem::take(&mut self.timings)
+    }
+    /// Whether a frame is being recorded
+    pub fn is_active(&self) -> bool {
+        self.target.is_some()
+    }
+    /// Record a pass into the frame (timed as `pass`), does nothing without one
+    pub fn record<R>(
+        &mut self,
+        pass: &'static str,
+        record: impl FnOnce(&mut wgpu::CommandEncoder, &wgpu::TextureView) -> R,
+    ) -> Option<R> {
+        let target = self.target.as_mut()?;
+        let start = Instant::now();
+        let result = record(&mut target.encoder, &target.view);
+        self.timings.record(pass, start);
+        Some(result)
+    }
+    /// Error of the surface when the frame began, handled by the event loop
+    pub fn feedback(&self) -> Result<(), wgpu::SurfaceError> {
+        self.feedback.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inactive() {
+        let mut frame = Frame::default();
+        assert!(!frame.is_active());
+        assert_eq!(None, frame.record("world", |_, _| 1));
+        assert!(frame.take_timings().passes.is_empty());
+        assert!(frame.finish().is_none());
+        frame.set_feedback(Err(wgpu::SurfaceError::Lost));
+        assert_eq!(Err(wgpu::SurfaceError::Lost), frame.feedback());
+    }
+}
@@ -1,8 +1,15 @@
+use std::mem::size_of;
 use std::num::NonZeroU64;
 
+use glam::Mat4;
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
 
-use crate::systems::graphics::Light;
+use crate::systems::graphics::{
+    memory::texture_bytes,
+    shadows::{ShadowAtlas, ShadowView},
+    texture_manager::TextureManager,
+    DiretionalLight, Light, PointLight, SpotLight,
+};
 
 trait Align {
     fn align(self, rhs: Self) -> Self;
@@ -10,31 +17,239 @@ trait Align {
 
 impl Align for u64 {
     fn align(self, rhs: Self) -> Self {
-        (self + rhs - 1) / rhs * rhs
+        self.div_ceil(rhs) * rhs
     }
 }
 
 impl Align for usize {
     fn align(self, rhs: Self) -> Self {
-        (self + rhs - 1) / rhs * rhs
+        self.div_ceil(rhs) * rhs
+    }
+}
+
+/// How the weather affects the surfaces, mirrors SurfaceWeather in lighting.wgsl
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SurfaceWeather {
+    /// From 0 (dry) to 1 (soaked), darkens the albedo and smoothes the surfaces
+    pub wetness: f32,
+    /// From 0 to 1, how much of the surfaces facing up is covered by puddles
+    pub puddles: f32,
+    /// Lowest normal y of a surface puddles form on
+    pub puddle_threshold: f32,
+    /// Multiplies the color before tonemapping
+    pub exposure: f32,
+}
+
+impl Default for SurfaceWeather {
+    fn default() -> Self {
+        Self {
+            wetness: 0.0,
+            puddles: 0.0,
+            puddle_threshold: 0.9,
+            exposure: 1.0,
+        }
+    }
+}
+
+/// What the shading pass outputs instead of the lit scene, to look at the g-buffer. Mirrors the
+/// DEBUG_VIEW constant of shader.wgsl.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DebugView {
+    #[default]
+    Off,
+    Albedo,
+    /// Mapped from -1..1 to 0..1
+    Normal,
+    /// Metallic, roughness and ambient occlusion (with the SSAO) as red, green and blue
+    Material,
+    Emissive,
+    /// Distance to the camera, from black to white in the distance
+    Depth,
+}
+
+impl DebugView {
+    /// Value of the DEBUG_VIEW constant
+    pub fn constant(self) -> i64 {
+        self as i64
     }
 }
 
 pub struct GBuffer {
-    pub albedo_tex: wgpu::TextureView,
-    pub position_tex: wgpu::TextureView,
-    pub normal_tex: wgpu::TextureView,
-    pub mra_tex: wgpu::TextureView,
+    /// Name and format of the color attachments, in the order of the geometry pass' outputs
+    attachments: Vec<(&'static str, wgpu::TextureFormat)>,
+    views: Vec<wgpu::TextureView>,
     pub depth_tex: wgpu::TextureView,
+    /// Entity ids (see `picking`), copied from for picking
+    pub id_texture: wgpu::Texture,
+    pub id_tex: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
     pub lights_buffer: wgpu::Buffer,
+    /// Uploaded with their shadows every frame (`write_lights`)
+    lights: Vec<Light>,
+    weather_buffer: wgpu::Buffer,
+    pub shadow_atlas: ShadowAtlas,
     pub bindgroup: wgpu::BindGroup,
     pub bind_group_layout: wgpu::BindGroupLayout,
     pub max_lights: u32,
 }
 
 impl GBuffer {
-    fn make_textures(device: &wgpu::Device, size: wgpu::Extent3d) -> [wgpu::TextureView; 5] {
+    /// The attachments of the renderer. Emissive is written black until materials have an
+    /// emissive texture.
+    pub const ATTACHMENTS: &'static [(&'static str, wgpu::TextureFormat)] = &[
+        ("albedo", wgpu::TextureFormat::Rgba8UnormSrgb),
+        ("position", wgpu::TextureFormat::Rgba16Float),
+        ("normal", wgpu::TextureFormat::Rgba16Float),
+        ("mra", wgpu::TextureFormat::Rgba8Unorm),
+        ("emissive", wgpu::TextureFormat::Rgba16Float),
+    ];
+    pub const ID_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Uint;
+    /// Binding of the first attachment in the bind group, after the sampler, the depth, the
+    /// lights, the weather and the shadows
+    const FIRST_ATTACHMENT_BINDING: u32 = 8;
+
+    /// Bytes taken by the targets at a size
+    pub fn memory(&self, size: (u32, u32)) -> u64 {
+        self.attachments
+            .iter()
+            .map(|(_, format)| *format)
+            .chain([TextureManager::DEPTH_FORMAT, Self::ID_FORMAT])
+            .map(|format| texture_bytes(size.0, size.1, format, 1))
+            .sum()
+    }
+    /// The view of an attachment, panics if there is none of that name
+    pub fn view(&self, name: &str) -> &wgpu::TextureView {
+        let index = self
+            .attachments
+            .iter()
+            .position(|(n, _)| *n == name)
+            .unwrap_or_else(|| panic!("No GBuffer attachment named {name}"));
+        &self.views[index]
+    }
+    /// Constants of the geometry shader: the location of each attachment (named after it) and
+    /// of the entity id (`id`)
+    pub fn geometry_constants(&self) -> Vec<(&'static str, i64)> {
+        Self::locations(&self.attachments, 0)
+    }
+    /// Constants of the shading shader: the binding of each attachment, named after it
+    pub fn shading_constants(&self) -> Vec<(&'static str, i64)> {
+        let mut constants = Self::locations(&self.attachments, Self::FIRST_ATTACHMENT_BINDING);
+        constants.pop();
+        constants
+    }
+    /// The attachments numbered from `first`, then the id
+    fn locations(attachments: &[(&'static str, wgpu::TextureFormat)], first: u32) -> Vec<(&'static str, i64)> {
+        attachments
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(["id"])
+            .zip(first as i64..)
+            .collect()
+    }
+    /// Targets of the geometry pipeline
+    pub fn color_targets(&self) -> Vec<Option<wgpu::ColorTargetState>> {
+        self.attachments
+            .iter()
+            .map(|(_, format)| *format)
+            .chain([Self::ID_FORMAT])
+            .map(|format| {
+                Some(wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })
+            })
+            .collect()
+    }
+    /// Pipeline of the geometry pass, with the targets of `color_targets`. Back faces are culled
+    /// unless double-sided.
+    pub fn pipeline_desc<'a>(
+        layout: &'a wgpu::PipelineLayout,
+        shader: &'a wgpu::ShaderModule,
+        entry_point: &'a str,
+        buffers: &'a [wgpu::VertexBufferLayout<'a>],
+        targets: &'a [Option<wgpu::ColorTargetState>],
+        double_sided: bool,
+    ) -> wgpu::RenderPipelineDescriptor<'a> {
+        wgpu::RenderPipelineDescriptor {
+            label: Some("Geometry Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point,
+                buffers,
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_main",
+                targets,
+            }),
+            primitive: wgpu::PrimitiveState {
+                cull_mode: (!double_sided).then_some(wgpu::Face::Back),
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: TextureManager::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        }
+    }
+    /// Begin the geometry pass, clearing every attachment (an id of 0 is no entity)
+    pub fn begin_pass<'a>(&'a self, encoder: &'a mut wgpu::CommandEncoder) -> wgpu::RenderPass<'a> {
+        let clear = |view| {
+            Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            })
+        };
+        let color_attachments = self
+            .views
+            .iter()
+            .chain([&self.id_tex])
+            .map(clear)
+            .collect::<Vec<_>>();
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Geometry Pass"),
+            color_attachments: &color_attachments,
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_tex,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        })
+    }
+    fn make_id_texture(device: &wgpu::Device, size: wgpu::Extent3d) -> (wgpu::Texture, wgpu::TextureView) {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            label: Some("entity id"),
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::ID_FORMAT,
+            sample_count: 1,
+            mip_level_count: 1,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        (texture, view)
+    }
+    /// The attachments, then the depth
+    fn make_textures(
+        device: &wgpu::Device,
+        attachments: &[(&'static str, wgpu::TextureFormat)],
+        size: wgpu::Extent3d,
+    ) -> (Vec<wgpu::TextureView>, wgpu::TextureView) {
         let tex = |label, format| {
             device
                 .create_texture(&wgpu::TextureDescriptor {
@@ -49,56 +264,88 @@ impl GBuffer {
                 })
                 .create_view(&wgpu::TextureViewDescriptor::default())
         };
-        [
-            tex("albedo", wgpu::TextureFormat::Rgba8UnormSrgb),
-            tex("position", wgpu::TextureFormat::Rgba16Float),
-            tex("normal", wgpu::TextureFormat::Rgba16Float),
-            tex("metallic roughness ao", wgpu::TextureFormat::Rgba8Unorm),
-            tex("depth", wgpu::TextureFormat::Depth32Float),
-        ]
+        let views = attachments.iter().map(|(label, format)| tex(label, *format)).collect();
+        (views, tex("depth", TextureManager::DEPTH_FORMAT))
+    }
+    fn make_layout(
+        device: &wgpu::Device,
+        attachments: &[(&'static str, wgpu::TextureFormat)],
+    ) -> wgpu::BindGroupLayout {
+        let mut entries = vec![
+            bind_group_layout_entry!(0 => FRAGMENT | Sampler(Filtering)),
+            bind_group_layout_entry!(1 => FRAGMENT | Texture(view_dim: D2, sample: Depth)),
+            bind_group_layout_entry!(2 => FRAGMENT | Buffer(type: Uniform)),
+            bind_group_layout_entry!(3 => FRAGMENT | Buffer(type: Uniform)),
+            bind_group_layout_entry!(4 => FRAGMENT | Buffer(type: Uniform)),
+            bind_group_layout_entry!(5 => FRAGMENT | Buffer(type: Uniform)),
+            bind_group_layout_entry!(6 => FRAGMENT | Texture(view_dim: D2Array, sample: Depth)),
+            bind_group_layout_entry!(7 => FRAGMENT | Sampler(Comparison)),
+        ];
+        entries.extend(attachments.iter().zip(Self::FIRST_ATTACHMENT_BINDING..).map(
+            |((_, format), binding)| wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: format.describe().sample_type,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+        ));
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("GBuffer Bind Group Layout"),
+            entries: &entries,
+        })
     }
     // This is just a function to avoid repeats
     #[allow(clippy::too_many_arguments)]
-    #[inline(always)]
     fn make_bindgroup(
         device: &wgpu::Device,
         layout: &wgpu::BindGroupLayout,
         sampler: &wgpu::Sampler,
-        albedo_tex: &wgpu::TextureView,
-        position_tex: &wgpu::TextureView,
-        normal_tex: &wgpu::TextureView,
-        mra_tex: &wgpu::TextureView,
+        views: &[wgpu::TextureView],
         depth_tex: &wgpu::TextureView,
         lights_buffer: &wgpu::Buffer,
+        weather_buffer: &wgpu::Buffer,
+        shadow_atlas: &ShadowAtlas,
         max_lights: u32,
     ) -> wgpu::BindGroup {
-        let max_lights = max_lights as u64;
-        let alignment = device.limits().min_uniform_buffer_offset_alignment as u64;
-        let dlights_offset = 0;
-        let plights_offset = (16 + max_lights * 32).align(alignment);
-        let slights_offset = (32 + max_lights * 64).align(alignment);
-        create_bind_group!(device, layout, "GBuffer Bindgroup": {
-            0 | Sampler(sampler),
-            1 | TextureView(albedo_tex),
-            2 | TextureView(position_tex),
-            3 | TextureView(normal_tex),
-            4 | TextureView(mra_tex),
-            5 | TextureView(depth_tex),
-            6 | Buffer(
-                size: (Some(NonZeroU64::new(16 + max_lights * 32).unwrap())),
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as usize;
+        let [dlights, plights, slights] = Self::lights_layout(max_lights as usize, alignment)
+            .map(|(offset, size)| (offset as u64, NonZeroU64::new(size as u64)));
+        let mut entries = vec![
+            bind_group_entry!(0 | Sampler(sampler)),
+            bind_group_entry!(1 | TextureView(depth_tex)),
+            bind_group_entry!(2 | Buffer(
+                size: (dlights.1),
                 buffer: lights_buffer,
-                offset: dlights_offset,
-            ),
-            7 | Buffer(
-                size: (Some(NonZeroU64::new(16 + max_lights * 32).unwrap())),
+                offset: (dlights.0),
+            )),
+            bind_group_entry!(3 | Buffer(
+                size: (plights.1),
                 buffer: lights_buffer,
-                offset: plights_offset,
-            ),
-            8 | Buffer(
-                size: (Some(NonZeroU64::new(16 + max_lights * 48).unwrap())),
+                offset: (plights.0),
+            )),
+            bind_group_entry!(4 | Buffer(
+                size: (slights.1),
                 buffer: lights_buffer,
-                offset: slights_offset,
-            ),
+                offset: (slights.0),
+            )),
+            bind_group_entry!(5 | Buffer(buffer: weather_buffer)),
+            bind_group_entry!(6 | TextureView(&shadow_atlas.view)),
+            bind_group_entry!(7 | Sampler(&shadow_atlas.sampler)),
+        ];
+        entries.extend(views.iter().zip(Self::FIRST_ATTACHMENT_BINDING..).map(
+            |(view, binding)| wgpu::BindGroupEntry {
+                binding,
+                resource: wgpu::BindingResource::TextureView(view),
+            },
+        ));
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("GBuffer Bindgroup"),
+            layout,
+            entries: &entries,
         })
     }
     fn update_bindgroup(&mut self, device: &wgpu::Device) {
@@ -106,23 +353,41 @@ impl GBuffer {
             device,
             &self.bind_group_layout,
             &self.sampler,
-            &self.albedo_tex,
-            &self.position_tex,
-            &self.normal_tex,
-            &self.mra_tex,
+            &self.views,
             &self.depth_tex,
             &self.lights_buffer,
+            &self.weather_buffer,
+            &self.shadow_atlas,
             self.max_lights,
         );
     }
-    fn make_lights_buffer<'a>(
-        device: &wgpu::Device,
-        lights: impl IntoIterator<Item = &'a Light>,
-        max: u32,
-    ) -> (wgpu::Buffer, u32) {
-        let mut dlights = Vec::with_capacity(max as usize);
-        let mut plights = Vec::with_capacity(max as usize);
-        let mut slights = Vec::with_capacity(max as usize);
+    /// Offset and size of the directional, point and spot lights in the lights buffer. Each is a
+    /// length (padded to 16 bytes) and an array of `max` lights, starting at an offset uniform
+    /// bindings can use.
+    fn lights_layout(max: usize, alignment: usize) -> [(usize, usize); 3] {
+        let sizes = [
+            16 + max * size_of::<DiretionalLight>(),
+            16 + max * size_of::<PointLight>(),
+            16 + max * size_of::<SpotLight>(),
+        ];
+        let mut offset = 0;
+        sizes.map(|size| {
+            let section = (offset, size);
+            offset = (offset + size).align(alignment);
+            section
+        })
+    }
+    /// Contents of the lights buffer, with the view projections of the shadowed lights (by layer)
+    /// and how many lights didn't fit
+    fn lights_bytes(
+        lights: &[Light],
+        max: usize,
+        alignment: usize,
+        shadows: &ShadowView,
+    ) -> (Vec<u8>, Vec<Mat4>, u32) {
+        let mut dlights = Vec::with_capacity(max);
+        let mut plights = Vec::with_capacity(max);
+        let mut slights = Vec::with_capacity(max);
         for l in lights {
             match l {
                 Light::Directional(l) => dlights.push(*l),
@@ -130,66 +395,66 @@ impl GBuffer {
                 Light::Spot(l) => slights.push(*l),
             }
         }
-        let max = max as usize;
-        let alignment = device.limits().min_uniform_buffer_offset_alignment as usize;
-        let dlights_bytes = (16 + max * 32).align(alignment); // 12 padding + 4 u32 bytes for length
-        let plights_bytes = (16 + max * 32).align(alignment);
-        let slights_bytes = 16 + max * 48; // no alignment because last
-        let mut bytes: Vec<u8> = Vec::with_capacity(dlights_bytes + plights_bytes + slights_bytes);
-        {
-            // directional
-            let len = dlights.len().min(max);
-            bytes.extend_from_slice(bytemuck::bytes_of(&(len as u32))); // length field
-            bytes.extend(std::iter::repeat(0).take(12)); // padding to 16 align the length
-            bytes.extend_from_slice(bytemuck::cast_slice(&dlights[0..len as usize])); // push lights
-            bytes.extend(std::iter::repeat(0).take(dlights_bytes - len * 32 - 16));
-            // fill the rest with zeros
+        let overflow = dlights
+            .len()
+            .saturating_sub(max)
+            .max(plights.len().saturating_sub(max))
+            .max(slights.len().saturating_sub(max));
+        dlights.truncate(max);
+        plights.truncate(max);
+        slights.truncate(max);
+
+        // The first lights that fit get a layer
+        let mut matrices = Vec::new();
+        let layers = shadows.layers as usize;
+        for l in dlights.iter_mut().take(layers) {
+            l.view_proj = shadows.directional(l);
+            l.shadow = matrices.len() as i32;
+            matrices.push(l.view_proj);
         }
-        {
-            // point
-            let len = plights.len().min(max);
-            bytes.extend_from_slice(bytemuck::bytes_of(&(len as u32)));
-            bytes.extend(std::iter::repeat(0).take(12)); // padding to 16 align the length
-            bytes.extend_from_slice(bytemuck::cast_slice(&plights[0..len as usize]));
-            bytes.extend(std::iter::repeat(0).take(plights_bytes - len * 32 - 16));
+        for l in slights.iter_mut().take(layers - matrices.len()) {
+            l.view_proj = shadows.spot(l);
+            l.shadow = matrices.len() as i32;
+            matrices.push(l.view_proj);
         }
-        {
-            // spot
-            let len = slights.len().min(max);
-            bytes.extend_from_slice(bytemuck::bytes_of(&(len as u32)));
-            bytes.extend(std::iter::repeat(0).take(12)); // padding to 16 align the length
-            bytes.extend_from_slice(bytemuck::cast_slice(&plights[0..len as usize]));
-            bytes.extend(std::iter::repeat(0).take(slights_bytes - len * 48 - 16));
+
+        let layout = Self::lights_layout(max, alignment);
+        let (offset, size) = layout[2];
+        let mut bytes = vec![0u8; offset + size];
+        let sections: [(usize, &[u8]); 3] = [
+            (dlights.len(), bytemuck::cast_slice(&dlights)),
+            (plights.len(), bytemuck::cast_slice(&plights)),
+            (slights.len(), bytemuck::cast_slice(&slights)),
+        ];
+        for ((offset, _), (len, lights)) in layout.into_iter().zip(sections) {
+            // The length, padded to 16 bytes, then the lights (the rest stays zeroed)
+            bytes[offset..offset + 4].copy_from_slice(bytemuck::bytes_of(&(len as u32)));
+            bytes[offset + 16..offset + 16 + lights.len()].copy_from_slice(lights);
         }
+        (bytes, matrices, overflow as u32)
+    }
+    fn make_lights_buffer(device: &wgpu::Device, lights: &[Light], max: u32) -> (wgpu::Buffer, u32) {
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as usize;
+        let (bytes, _, overflow) =
+            Self::lights_bytes(lights, max as usize, alignment, &ShadowView::NONE);
         let buf = device.create_buffer_init(&BufferInitDescriptor {
             label: Some("lights buffer"),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             contents: &bytes,
         });
-        let overflow = dlights
-            .len()
-            .saturating_sub(max)
-            .max(plights.len().saturating_sub(max))
-            .max(slights.len().saturating_sub(max));
-        (buf, overflow as u32)
+        (buf, overflow)
     }
+    /// A GBuffer with the color `attachments` (usually `ATTACHMENTS`), besides the depth and the
+    /// entity ids
     pub fn new(
         device: &wgpu::Device,
         size: wgpu::Extent3d,
+        attachments: &[(&'static str, wgpu::TextureFormat)],
         lights: &[Light],
         max_lights: u32,
+        shadow_atlas: ShadowAtlas,
     ) -> Self {
-        let bind_group_layout = create_bind_group_layout!(device, "GBuffer Bind Group Layout": {
-            0 => FRAGMENT | Sampler(Filtering),
-            1 => FRAGMENT | Texture(view_dim: D2, sample: FloatFilterable),
-            2 => FRAGMENT | Texture(view_dim: D2, sample: FloatFilterable),
-            3 => FRAGMENT | Texture(view_dim: D2, sample: FloatFilterable),
-            4 => FRAGMENT | Texture(view_dim: D2, sample: FloatFilterable),
-            5 => FRAGMENT | Texture(view_dim: D2, sample: Depth),
-            6 => FRAGMENT | Buffer(type: Uniform),
-            7 => FRAGMENT | Buffer(type: Uniform),
-            8 => FRAGMENT | Buffer(type: Uniform),
-        });
+        let bind_group_layout = Self::make_layout(device, attachments);
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             label: Some("gbuffer sampler"),
             address_mode_u: wgpu::AddressMode::ClampToEdge,
@@ -200,58 +465,67 @@ impl GBuffer {
             mipmap_filter: wgpu::FilterMode::Linear,
             ..Default::default()
         });
-        let [albedo_tex, position_tex, normal_tex, mra_tex, depth_tex] =
-            Self::make_textures(device, size);
+        let (views, depth_tex) = Self::make_textures(device, attachments, size);
+        let (id_texture, id_tex) = Self::make_id_texture(device, size);
         let (lights_buffer, overflow) = Self::make_lights_buffer(device, lights, max_lights);
 
         if overflow > 0 {
             log::warn!("Lights exceed the limit of {max_lights}");
         }
+        let weather_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("surface weather buffer"),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            contents: bytemuck::bytes_of(&SurfaceWeather::default()),
+        });
 
         let bindgroup = Self::make_bindgroup(
             device,
             &bind_group_layout,
             &sampler,
-            &albedo_tex,
-            &position_tex,
-            &normal_tex,
-            &mra_tex,
+            &views,
             &depth_tex,
             &lights_buffer,
+            &weather_buffer,
+            &shadow_atlas,
             max_lights,
         );
 
         Self {
+            attachments: attachments.to_vec(),
+            views,
             sampler,
-            albedo_tex,
-            position_tex,
-            normal_tex,
-            mra_tex,
             depth_tex,
+            id_texture,
+            id_tex,
             bind_group_layout,
             bindgroup,
             lights_buffer,
+            lights: lights.to_vec(),
+            weather_buffer,
+            shadow_atlas,
             max_lights,
         }
     }
 
+    /// Recreate every attachment at the new size
     pub fn resize(&mut self, device: &wgpu::Device, size: wgpu::Extent3d) {
-        let [albedo_tex, position_tex, normal_tex, mra_tex, depth_tex] =
-            Self::make_textures(device, size);
-        self.albedo_tex = albedo_tex;
-        self.position_tex = position_tex;
-        self.normal_tex = normal_tex;
-        self.mra_tex = mra_tex;
-        self.depth_tex = depth_tex;
+        (self.views, self.depth_tex) = Self::make_textures(device, &self.attachments, size);
+        (self.id_texture, self.id_tex) = Self::make_id_texture(device, size);
         self.update_bindgroup(device);
     }
 
+    pub fn set_weather(&self, queue: &wgpu::Queue, weather: &SurfaceWeather) {
+        queue.write_buffer(&self.weather_buffer, 0, bytemuck::bytes_of(weather));
+    }
+
     pub fn update_lights<'a>(
         &mut self,
         device: &wgpu::Device,
         lights: impl IntoIterator<Item = &'a Light>,
     ) -> Result<(), u32> {
-        let (lights_buffer, overflow) = Self::make_lights_buffer(device, lights, self.max_lights);
+        self.lights = lights.into_iter().copied().collect();
+        let (lights_buffer, overflow) =
+            Self::make_lights_buffer(device, &self.lights, self.max_lights);
         self.lights_buffer = lights_buffer;
         self.update_bindgroup(device);
         if overflow > 0 {
@@ -260,4 +534,107 @@ impl GBuffer {
             Ok(())
         }
     }
+
+    /// Upload the lights with their shadows, returns the view projections of the shadowed lights
+    /// (by layer)
+    pub fn write_lights(&self, device: &wgpu::Device, queue: &wgpu::Queue, shadows: &ShadowView) -> Vec<Mat4> {
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as usize;
+        let (bytes, matrices, _) =
+            Self::lights_bytes(&self.lights, self.max_lights as usize, alignment, shadows);
+        queue.write_buffer(&self.lights_buffer, 0, &bytes);
+        matrices
+    }
+
+    /// Replace the shadow atlas (see `WorldRenderer::set_shadow_resolution`)
+    pub fn set_shadow_atlas(&mut self, device: &wgpu::Device, atlas: ShadowAtlas) {
+        self.shadow_atlas = atlas;
+        self.update_bindgroup(device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec3, Vec4};
+
+    use super::*;
+
+    #[test]
+    fn lights_layout() {
+        assert_eq!(96, size_of::<DiretionalLight>());
+        assert_eq!(32, size_of::<PointLight>());
+        assert_eq!(112, size_of::<SpotLight>());
+        assert_eq!(
+            [(0, 208), (256, 80), (512, 240)],
+            GBuffer::lights_layout(2, 256)
+        );
+    }
+
+    #[test]
+    fn attachment_bindings() {
+        let attachments = [
+            ("albedo", wgpu::TextureFormat::Rgba8UnormSrgb),
+            ("normal", wgpu::TextureFormat::Rgba16Float),
+        ];
+        assert_eq!(
+            vec![("albedo", 0), ("normal", 1), ("id", 2)],
+            GBuffer::locations(&attachments, 0)
+        );
+        let bindings = GBuffer::locations(GBuffer::ATTACHMENTS, GBuffer::FIRST_ATTACHMENT_BINDING);
+        assert_eq!(("albedo", 8), bindings[0]);
+        assert_eq!(("emissive", 12), bindings[4]);
+        // Every attachment can be bound to the shading pass without extra features
+        for (name, format) in GBuffer::ATTACHMENTS {
+            let info = format.describe();
+            assert!(info.required_features.is_empty(), "{name}");
+            assert!(matches!(info.sample_type, wgpu::TextureSampleType::Float { filterable: true }), "{name}");
+        }
+    }
+
+    #[test]
+    fn lights_shadows() {
+        let sun = |x| Light::Directional(DiretionalLight::new(Vec3::new(x, -1.0, 0.0), Vec4::ONE));
+        let spot = |cut_off| {
+            Light::Spot(SpotLight::new(Vec3::Y * 4.0, -Vec3::Y, cut_off, Vec4::ONE))
+        };
+        let lights = [
+            sun(0.1),
+            spot(0.9),
+            Light::Point(PointLight::new(Vec3::ZERO, Vec4::ONE)),
+            sun(0.2),
+            spot(0.8),
+            sun(0.3),
+        ];
+        let shadows = ShadowView {
+            layers: 3,
+            resolution: 1024,
+            center: Vec3::ZERO,
+        };
+        let (bytes, matrices, overflow) = GBuffer::lights_bytes(&lights, 2, 256, &shadows);
+        // The third sun doesn't fit
+        assert_eq!(1, overflow);
+        assert_eq!(512 + 240, bytes.len());
+        let section = |offset: usize, stride: usize, i: usize| {
+            let start = offset + 16 + i * stride;
+            &bytes[start..start + stride]
+        };
+        let length = |offset: usize| bytemuck::pod_read_unaligned::<u32>(&bytes[offset..offset + 4]);
+        assert_eq!([2, 1, 2], [length(0), length(256), length(512)]);
+
+        // The suns first, then the spots until the layers run out
+        let suns: Vec<DiretionalLight> =
+            (0..2).map(|i| bytemuck::pod_read_unaligned(section(0, 96, i))).collect();
+        let spots: Vec<SpotLight> =
+            (0..2).map(|i| bytemuck::pod_read_unaligned(section(512, 112, i))).collect();
+        assert_eq!([0, 1], [suns[0].shadow, suns[1].shadow]);
+        assert_eq!([2, -1], [spots[0].shadow, spots[1].shadow]);
+        assert_eq!([0.9, 0.8], [spots[0].cut_off, spots[1].cut_off]);
+        assert_eq!(vec![suns[0].view_proj, suns[1].view_proj, spots[0].view_proj], matrices);
+        assert_eq!(shadows.spot(&spots[0]), spots[0].view_proj);
+        assert_eq!(Mat4::IDENTITY, spots[1].view_proj);
+
+        let (unshadowed, matrices, _) = GBuffer::lights_bytes(&lights, 2, 256, &ShadowView::NONE);
+        assert!(matrices.is_empty());
+        let sun: DiretionalLight = bytemuck::pod_read_unaligned(&unshadowed[16..16 + 96]);
+        assert_eq!(-1, sun.shadow);
+    }
 }
@@ -1,76 +1,48 @@
-struct VertexInput {
-    @location(0) position: vec3<f32>,
-    @location(1) normal: vec3<f32>,
-    @location(2) tex_coords: vec2<f32>,
-    @location(3) tangent: vec3<f32>,
-}
-struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
-    @location(0) tex_coords: vec2<f32>,
-    @location(1) world_position: vec3<f32>,
-    @location(2) tangent: vec3<f32>,
-    @location(3) bitangent: vec3<f32>,
-    @location(4) normal: vec3<f32>,
-}
-struct CameraInfo {
-    view_proj: mat4x4<f32>,
-    @size(16)
-    pos: vec3<f32>,
-}
-struct PushConstants {
-    model_mat: mat4x4<f32>,
-    normal_mat: mat4x4<f32>,
-}
-var<push_constant> pc: PushConstants;
-
-@group(1) @binding(0)
-var<uniform> cam: CameraInfo;
-
-@vertex
-fn vs_main(model: VertexInput) -> VertexOutput {
-    let normal = normalize((pc.normal_mat * vec4<f32>(model.normal, 0.0)).xyz);
-    var tangent = normalize((pc.normal_mat * vec4<f32>(model.tangent, 0.0)).xyz);
-    tangent = normalize(tangent - dot(tangent, normal) * normal);
-    let bitangent = -cross(normal, tangent);
-
-    var v_out: VertexOutput;
-    v_out.world_position = (pc.model_mat * vec4<f32>(model.position, 1.0)).xyz;
-    v_out.clip_position = cam.view_proj * vec4<f32>(v_out.world_position, 1.0);
-    v_out.tex_coords = model.tex_coords;
-    v_out.tangent = tangent;
-    v_out.bitangent = bitangent;
-    v_out.normal = normal;
-    return v_out;
-}
+// The geometry pass, after camera.wgsl and geometry.wgsl
 
 @group(0) @binding(0)
 var textures: binding_array<texture_2d<f32>>;
 @group(0) @binding(1)
-var smpl: sampler;
+var samplers: binding_array<sampler>;
 
+// Locations from GBuffer::geometry_constants
 struct FragmentOutput {
-    @location(0) albedo: vec4<f32>,
-    @location(1) position: vec4<f32>,
-    @location(2) normal: vec4<f32>,
-    @location(3) mra: vec4<f32>,
+    @location({{albedo}}) albedo: vec4<f32>,
+    @location({{position}}) position: vec4<f32>,
+    @location({{normal}}) normal: vec4<f32>,
+    @location({{mra}}) mra: vec4<f32>,
+    @location({{emissive}}) emissive: vec4<f32>,
+    @location({{id}}) id: u32,
 }
 
 @fragment
-fn fs_main(v_in: VertexOutput) -> FragmentOutput {
+fn fs_main(v_in: VertexOutput, @builtin(front_facing) front_facing: bool) -> FragmentOutput {
     let TBN = mat3x3<f32>(v_in.tangent, v_in.bitangent, v_in.normal);
     var f_out: FragmentOutput;
-    let normal = normalize(TBN * (
-        textureSample(textures[1], smpl, v_in.tex_coords).xyz * 2.0 - vec3<f32>(1.0)
+    f_out.albedo = textureSample(textures[0], samplers[0], v_in.tex_coords);
+    var normal = normalize(TBN * (
+        textureSample(textures[1], samplers[1], v_in.tex_coords).xyz * 2.0 - vec3<f32>(1.0)
     ));
-    f_out.albedo = textureSample(textures[0], smpl, v_in.tex_coords);
+    // The back faces of double-sided materials are lit from their side
+    if ({{DOUBLE_SIDED}} && !front_facing) {
+        normal = -normal;
+    }
     f_out.position = vec4<f32>(v_in.world_position, 1.0);
     f_out.normal = vec4<f32>(normal, 1.0);
     // metallic
-    f_out.mra.x = textureSample(textures[2], smpl, v_in.tex_coords).x;
+    f_out.mra.x = textureSample(textures[2], samplers[2], v_in.tex_coords).x;
     // roughness
-    f_out.mra.y = textureSample(textures[3], smpl, v_in.tex_coords).x;
+    f_out.mra.y = textureSample(textures[3], samplers[3], v_in.tex_coords).x;
     // ambiant occlusion
-    f_out.mra.z = textureSample(textures[4], smpl, v_in.tex_coords).x;
-    f_out.mra.w = 1.0;
+    f_out.mra.z = textureSample(textures[4], samplers[4], v_in.tex_coords).x;
+    // exposure to the weather
+    f_out.mra.w = v_in.exposed;
+    f_out.emissive = vec4<f32>(textureSample(textures[5], samplers[5], v_in.tex_coords).rgb * v_in.material.rgb, 1.0);
+    f_out.id = v_in.id;
+    // Only the masked permutation discards, the others keep early depth tests. Last, the samples
+    // need uniform control flow.
+    if ({{ALPHA_MASK}} && f_out.albedo.a < v_in.material.a) {
+        discard;
+    }
     return f_out;
 }
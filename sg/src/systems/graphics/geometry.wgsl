@@ -0,0 +1,72 @@
+// Vertex stage of the renderables (see instances.rs), after camera.wgsl
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) tex_coords: vec2<f32>,
+    @location(3) tangent: vec3<f32>,
+}
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) tangent: vec3<f32>,
+    @location(3) bitangent: vec3<f32>,
+    @location(4) normal: vec3<f32>,
+    // 1 if the surface is made wet by the weather, 0 if it is weatherproof
+    @location(5) exposed: f32,
+    // Of the entity, for picking (see picking.rs)
+    @location(6) @interpolate(flat) id: u32,
+    // Emissive factor (rgb) and alpha cutoff (a) of the material
+    @location(7) @interpolate(flat) material: vec4<f32>,
+}
+struct InstanceInput {
+    @location(4) model_0: vec4<f32>,
+    @location(5) model_1: vec4<f32>,
+    @location(6) model_2: vec4<f32>,
+    @location(7) model_3: vec4<f32>,
+    @location(8) normal_0: vec4<f32>,
+    @location(9) normal_1: vec4<f32>,
+    @location(10) normal_2: vec4<f32>,
+    @location(11) normal_3: vec4<f32>,
+}
+// Same as InstanceInput, for the devices drawing without instancing (vs_single)
+struct PushConstants {
+    model_mat: mat4x4<f32>,
+    normal_mat: mat4x4<f32>,
+}
+var<push_constant> pc: PushConstants;
+
+// The translation column of the normal matrix holds the exposure to the weather in x, the
+// entity id in y and the alpha cutoff in z, its last row the emissive factor
+fn vertex(model: VertexInput, model_mat: mat4x4<f32>, normal_mat: mat4x4<f32>) -> VertexOutput {
+    let normal = normalize((normal_mat * vec4<f32>(model.normal, 0.0)).xyz);
+    var tangent = normalize((normal_mat * vec4<f32>(model.tangent, 0.0)).xyz);
+    tangent = normalize(tangent - dot(tangent, normal) * normal);
+    let bitangent = -cross(normal, tangent);
+
+    var v_out: VertexOutput;
+    v_out.world_position = (model_mat * vec4<f32>(model.position, 1.0)).xyz;
+    v_out.clip_position = cam.view_proj * vec4<f32>(v_out.world_position, 1.0);
+    v_out.tex_coords = model.tex_coords;
+    v_out.tangent = tangent;
+    v_out.bitangent = bitangent;
+    v_out.normal = normal;
+    v_out.exposed = normal_mat[3].x;
+    v_out.id = u32(normal_mat[3].y);
+    v_out.material = vec4<f32>(normal_mat[0].w, normal_mat[1].w, normal_mat[2].w, normal_mat[3].z);
+    return v_out;
+}
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model_mat = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+    let normal_mat = mat4x4<f32>(instance.normal_0, instance.normal_1, instance.normal_2, instance.normal_3);
+    return vertex(model, model_mat, normal_mat);
+}
+
+@vertex
+fn vs_single(model: VertexInput) -> VertexOutput {
+    return vertex(model, pc.model_mat, pc.normal_mat);
+}
+
@@ -1,18 +1,24 @@
 use std::{num::NonZeroU32, path::Path};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use ecs::World;
 use glam::{Quat, Vec2, Vec3};
 use gltf::image::Data as ImageData;
 use gltf::image::Format;
 use gltf::Node;
 
 use crate::components::{GraphicsComponent, TransformsComponent};
+use crate::systems::animation::{
+    AnimationClip, AnimationHandle, AnimationManager, AnimationPlayer, Channel, Interpolation,
+    Keyframes,
+};
 use crate::systems::graphics::mesh_manager::MeshHandle;
 
-use super::Material;
+use super::{AlphaMode, Material};
 use super::{
+    memory::TextureInfo,
     mesh_manager::{Mesh, Vertex},
-    texture_manager::{SingleValue, TextureHandle},
+    texture_manager::{SamplerDesc, SingleValue, TextureHandle},
     GraphicContext,
 };
 
@@ -137,7 +143,55 @@ impl FormatExt for Format {
     }
 }
 
-fn load_image(gfx: &mut GraphicContext, image: &mut ImageData, srgb: bool) -> wgpu::Texture {
+/// Sampler of a gltf texture, the filters it doesn't specify are the default ones
+fn sampler_desc(sampler: &gltf::texture::Sampler) -> SamplerDesc {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
+        WrappingMode::Repeat => wgpu::AddressMode::Repeat,
+    };
+    let mut desc = SamplerDesc {
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        ..Default::default()
+    };
+    if let Some(mag) = sampler.mag_filter() {
+        desc.mag_filter = match mag {
+            MagFilter::Nearest => wgpu::FilterMode::Nearest,
+            MagFilter::Linear => wgpu::FilterMode::Linear,
+        };
+    }
+    if let Some(min) = sampler.min_filter() {
+        use wgpu::FilterMode::{Linear, Nearest};
+        let (min, mipmap) = match min {
+            MinFilter::Nearest => (Nearest, desc.mipmap_filter),
+            MinFilter::Linear => (Linear, desc.mipmap_filter),
+            MinFilter::NearestMipmapNearest => (Nearest, Nearest),
+            MinFilter::LinearMipmapNearest => (Linear, Nearest),
+            MinFilter::NearestMipmapLinear => (Nearest, Linear),
+            MinFilter::LinearMipmapLinear => (Linear, Linear),
+        };
+        desc.min_filter = min;
+        desc.mipmap_filter = mipmap;
+    }
+    desc
+}
+
+/// Alpha mode of a gltf material, masked ones without a cutoff use the default of 0.5
+fn alpha_mode(material: &gltf::Material) -> AlphaMode {
+    match material.alpha_mode() {
+        gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+        gltf::material::AlphaMode::Mask => AlphaMode::Mask(material.alpha_cutoff().unwrap_or(0.5)),
+        gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+    }
+}
+
+fn load_image(
+    gfx: &mut GraphicContext,
+    image: &mut ImageData,
+    srgb: bool,
+) -> (wgpu::Texture, TextureInfo) {
     let size = wgpu::Extent3d {
         width: image.width,
         height: image.height,
@@ -267,20 +321,145 @@ fn load_image(gfx: &mut GraphicContext, image: &mut ImageData, srgb: bool) -> wg
     );
 
     log::trace!("image loading - gpu texture created");
-    tex
+    (tex, TextureInfo::new(image.width, image.height, format))
+}
+
+/// A glTF scene and its animations
+pub struct Scene {
+    /// Entities of the mesh primitives, with the node hierarchy flattened
+    pub entities: Vec<(GraphicsComponent, TransformsComponent)>,
+    /// Nodes leading to each entity, root first, with their local transforms
+    pub nodes: Vec<Vec<(usize, TransformsComponent)>>,
+    pub animations: Vec<AnimationClip>,
+}
+
+impl Scene {
+    /// Spawn the entities and add the animations to the manager. The entities moved by the
+    /// animation at index `play` get a player for it.
+    pub fn spawn(
+        self,
+        world: &mut World,
+        animations: &mut AnimationManager,
+        play: Option<usize>,
+    ) -> Vec<AnimationHandle> {
+        let handles: Vec<_> = self.animations.into_iter().map(|clip| animations.add(clip)).collect();
+        let played = play
+            .and_then(|index| handles.get(index))
+            .and_then(|handle| Some((*handle, animations.get(*handle)?)));
+        for ((graphics, transforms), nodes) in self.entities.into_iter().zip(self.nodes) {
+            match played {
+                Some((handle, clip)) if nodes.iter().any(|(node, _)| clip.animates(*node)) => {
+                    world.spawn((graphics, transforms, AnimationPlayer::new(handle, nodes)));
+                }
+                _ => {
+                    world.spawn((graphics, transforms));
+                }
+            }
+        }
+        handles
+    }
 }
 
 pub fn open<P: AsRef<Path>>(
     path: P,
     gfx: &mut GraphicContext,
 ) -> Result<Vec<(GraphicsComponent, TransformsComponent)>> {
+    Ok(open_scene(path, gfx)?.entities)
+}
+
+/// Same as `open`, from the content of a file (a glb, or a gltf with embedded buffers)
+pub fn open_slice(
+    bytes: &[u8],
+    gfx: &mut GraphicContext,
+) -> Result<Vec<(GraphicsComponent, TransformsComponent)>> {
+    Ok(open_scene_slice(bytes, gfx)?.entities)
+}
+
+/// Same as `open`, keeping the animations and the nodes of the entities to play them
+pub fn open_scene<P: AsRef<Path>>(path: P, gfx: &mut GraphicContext) -> Result<Scene> {
     log::trace!("Importing gltf...");
-    let (doc, buffers, mut doc_images) = gltf::import(path)?;
+    let import = gltf::import(path)?;
     log::trace!("done");
+    process(import, gfx)
+}
+
+/// Same as `open_scene`, from the content of a file
+pub fn open_scene_slice(bytes: &[u8], gfx: &mut GraphicContext) -> Result<Scene> {
+    log::trace!("Importing gltf...");
+    let import = gltf::import_slice(bytes)?;
+    log::trace!("done");
+    process(import, gfx)
+}
+
+/// Keys of a channel, the tangents of cubic splines are dropped (they are played as linear)
+fn key_values<T: Copy>(values: impl Iterator<Item = T>, cubic: bool) -> Vec<T> {
+    let values: Vec<T> = values.collect();
+    if cubic {
+        values.chunks(3).filter_map(|key| key.get(1).copied()).collect()
+    } else {
+        values
+    }
+}
+
+fn read_animation(
+    animation: gltf::Animation,
+    buffers: &[gltf::buffer::Data],
+) -> Result<AnimationClip> {
+    use gltf::animation::{util::ReadOutputs, Interpolation as GltfInterpolation};
+    let mut channels = Vec::new();
+    for channel in animation.channels() {
+        let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+        let times: Vec<f32> = reader
+            .read_inputs()
+            .context("Couldn't read animation key times")?
+            .collect();
+        let (interpolation, cubic) = match channel.sampler().interpolation() {
+            GltfInterpolation::Step => (Interpolation::Step, false),
+            GltfInterpolation::Linear => (Interpolation::Linear, false),
+            GltfInterpolation::CubicSpline => (Interpolation::Linear, true),
+        };
+        let keyframes = match reader
+            .read_outputs()
+            .context("Couldn't read animation key values")?
+        {
+            ReadOutputs::Translations(values) => {
+                Keyframes::Translation(key_values(values.map(Vec3::from), cubic))
+            }
+            ReadOutputs::Rotations(values) => {
+                Keyframes::Rotation(key_values(values.into_f32().map(Quat::from_array), cubic))
+            }
+            ReadOutputs::Scales(values) => Keyframes::Scale(key_values(values.map(Vec3::from), cubic)),
+            ReadOutputs::MorphTargetWeights(_) => {
+                log::warn!("Morph target animations aren't supported");
+                continue;
+            }
+        };
+        let count = match &keyframes {
+            Keyframes::Translation(values) | Keyframes::Scale(values) => values.len(),
+            Keyframes::Rotation(values) => values.len(),
+        };
+        if count != times.len() {
+            bail!("Animation channel has {} keys but {count} values", times.len());
+        }
+        channels.push(Channel {
+            node: channel.target().node().index(),
+            times,
+            keyframes,
+            interpolation,
+        });
+    }
+    Ok(AnimationClip::new(animation.name().map(String::from), channels))
+}
+
+fn process(
+    (doc, buffers, mut doc_images): (gltf::Document, Vec<gltf::buffer::Data>, Vec<ImageData>),
+    gfx: &mut GraphicContext,
+) -> Result<Scene> {
     let mut mesh_handles = vec![vec![]; doc.meshes().count()];
     let mut materials: Vec<Option<Material>> = vec![None; doc.materials().count() + 1];
     let mut images: Vec<Vec<TextureHandle>> = vec![vec![]; doc.images().count()];
     let mut entities: Vec<(GraphicsComponent, TransformsComponent)> = Vec::new();
+    let mut entity_nodes: Vec<Vec<(usize, TransformsComponent)>> = Vec::new();
 
     let default_material_index = materials.len() - 1;
     log::trace!("Processing gltf 1/3 - meshes");
@@ -351,21 +530,22 @@ pub fn open<P: AsRef<Path>>(
             };
             log::trace!("    - processing tangents");
             m_mesh.recompute_tangents();
-            mesh_handles[mesh.index()].push(gfx.mesh_manager.add(&gfx.device, &m_mesh));
+            mesh_handles[mesh.index()].push(gfx.mesh_manager.add_dedup(&gfx.device, &m_mesh));
         }
     }
     log::trace!("Processing gltf 2/3 - materials");
     for material in doc.materials() {
         let mut load = |gfx: &mut GraphicContext, tex: gltf::Texture, srgb| {
-            // TODO: sampler
             let index = tex.source().index();
 
-            if let Some(handle) = images[index].get(0) {
+            // Textures are cached by image, the first sampler an image is used with is kept
+            if let Some(handle) = images[index].first() {
                 return *handle;
             }
 
-            let view = load_image(gfx, &mut doc_images[index], srgb);
-            let handle = gfx.texture_manager.add_texture(view);
+            let (view, info) = load_image(gfx, &mut doc_images[index], srgb);
+            let sampler = sampler_desc(&tex.sampler());
+            let handle = gfx.texture_manager.add_texture_with_sampler(view, info, sampler);
             images[index] = vec![handle];
             handle
         };
@@ -393,15 +573,18 @@ pub fn open<P: AsRef<Path>>(
         let ao = material
             .occlusion_texture()
             .map(|tex| load(gfx, tex.texture(), false));
+        log::trace!("    - emissive");
+        let emissive = material
+            .emissive_texture()
+            .map(|tex| load(gfx, tex.texture(), true));
         let metallic;
         let roughness;
         log::trace!("    - processing MR");
         if let Some(tex) = pbrmr.metallic_roughness_texture() {
             let tex = tex.texture();
-            // TODO: sampler
             let index = tex.source().index();
 
-            if let (Some(met), Some(rou)) = (images[index].get(0), images[index].get(1)) {
+            if let (Some(met), Some(rou)) = (images[index].first(), images[index].get(1)) {
                 metallic = *met;
                 roughness = *rou;
             } else {
@@ -436,10 +619,11 @@ pub fn open<P: AsRef<Path>>(
                     img_met.pixels.extend_from_slice(met_bytes);
                     img_rou.pixels.extend_from_slice(rou_bytes);
                 }
-                let met = load_image(gfx, &mut img_met, false);
-                let rou = load_image(gfx, &mut img_rou, false);
-                metallic = gfx.texture_manager.add_texture(met);
-                roughness = gfx.texture_manager.add_texture(rou);
+                let (met, met_info) = load_image(gfx, &mut img_met, false);
+                let (rou, rou_info) = load_image(gfx, &mut img_rou, false);
+                let sampler = sampler_desc(&tex.sampler());
+                metallic = gfx.texture_manager.add_texture_with_sampler(met, met_info, sampler);
+                roughness = gfx.texture_manager.add_texture_with_sampler(rou, rou_info, sampler);
             }
         } else {
             metallic = gfx.texture_manager.get_or_add_single_value_texture(
@@ -454,20 +638,25 @@ pub fn open<P: AsRef<Path>>(
             );
         }
         let index = material.index().unwrap_or(default_material_index);
-        materials[index].replace(
-            Material::new(albedo, normal_map, metallic, roughness, ao, gfx)
-                .context("Error on material creation")?,
-        );
+        let mut created = Material::new_with_emissive(albedo, normal_map, metallic, roughness, ao, emissive, gfx)
+            .context("Error on material creation")?;
+        created.emissive = Vec3::from(material.emissive_factor());
+        created.alpha_mode = alpha_mode(&material);
+        created.double_sided = material.double_sided();
+        materials[index].replace(created);
     }
     log::trace!("Processing gltf 3/3 - scenes");
 
+    #[allow(clippy::too_many_arguments)]
     fn process_node(
         node: Node,
         parent_tsm: &TransformsComponent,
+        parent_nodes: &[(usize, TransformsComponent)],
         default_material_index: usize,
         materials: &Vec<Option<Material>>,
         mesh_handles: &Vec<Vec<MeshHandle>>,
         entities: &mut Vec<(GraphicsComponent, TransformsComponent)>,
+        entity_nodes: &mut Vec<Vec<(usize, TransformsComponent)>>,
     ) -> Result<()> {
         log::trace!("  scene: getting node transforms");
         let (translation, rotation, scale) = node.transform().decomposed();
@@ -475,6 +664,8 @@ pub fn open<P: AsRef<Path>>(
         tsm.set_translation(Vec3::from(translation));
         tsm.set_rotation(Quat::from_array(rotation));
         tsm.set_scale(Vec3::from(scale));
+        let mut nodes = parent_nodes.to_vec();
+        nodes.push((node.index(), tsm.clone()));
         tsm.apply(parent_tsm);
         if let Some(mesh) = node.mesh() {
             log::trace!("    - has mesh, making entities");
@@ -488,6 +679,7 @@ pub fn open<P: AsRef<Path>>(
                 let gfc = GraphicsComponent { material, mesh };
                 log::trace!("      - adding entity");
                 entities.push((gfc, tsm.clone()));
+                entity_nodes.push(nodes.clone());
             }
         } else {
             log::trace!("    - no mesh found");
@@ -497,10 +689,12 @@ pub fn open<P: AsRef<Path>>(
             process_node(
                 node,
                 &tsm,
+                &nodes,
                 default_material_index,
                 materials,
                 mesh_handles,
                 entities,
+                entity_nodes,
             )?;
         }
         Ok(())
@@ -511,13 +705,92 @@ pub fn open<P: AsRef<Path>>(
             process_node(
                 node,
                 &TransformsComponent::default(),
+                &[],
                 default_material_index,
                 &materials,
                 &mesh_handles,
                 &mut entities,
+                &mut entity_nodes,
             )?;
         }
     }
+    let animations = doc
+        .animations()
+        .map(|animation| read_animation(animation, &buffers))
+        .collect::<Result<_>>()?;
     log::trace!("Processing gltf - done");
-    Ok(entities)
+    Ok(Scene {
+        entities,
+        nodes: entity_nodes,
+        animations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samplers() {
+        let doc = gltf::Gltf::from_slice(
+            br#"{
+                "asset": { "version": "2.0" },
+                "samplers": [
+                    {},
+                    { "magFilter": 9728, "minFilter": 9984, "wrapS": 33071, "wrapT": 33648 },
+                    { "minFilter": 9987, "wrapT": 10497 }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let descs: Vec<_> = doc.samplers().map(|sampler| sampler_desc(&sampler)).collect();
+        assert_eq!(SamplerDesc::default(), descs[0]);
+        assert_eq!(
+            SamplerDesc {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::MirrorRepeat,
+                mag_filter: wgpu::FilterMode::Nearest,
+                min_filter: wgpu::FilterMode::Nearest,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            },
+            descs[1]
+        );
+        assert_eq!(
+            SamplerDesc {
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            },
+            descs[2]
+        );
+    }
+
+    #[test]
+    fn alpha_modes() {
+        let doc = gltf::Gltf::from_slice(
+            br#"{
+                "asset": { "version": "2.0" },
+                "materials": [
+                    {},
+                    { "alphaMode": "MASK", "alphaCutoff": 0.3, "doubleSided": true },
+                    { "alphaMode": "MASK" },
+                    { "alphaMode": "BLEND" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let modes: Vec<_> = doc.materials().map(|material| alpha_mode(&material)).collect();
+        assert_eq!(
+            vec![
+                AlphaMode::Opaque,
+                AlphaMode::Mask(0.3),
+                AlphaMode::Mask(0.5),
+                AlphaMode::Blend
+            ],
+            modes
+        );
+        assert_eq!(0.3, modes[1].cutoff());
+        assert_eq!(0.0, modes[3].cutoff());
+    }
 }
@@ -0,0 +1,862 @@
+//! Hi-Z occlusion culling.
+//!
+//! After the geometry pass, the depth buffer is reduced into a pyramid (each texel holding the
+//! farthest depth of the texels it covers). The bounding box of each renderable is then projected
+//! on screen and its nearest depth compared to the pyramid level where the box covers at most 2x2
+//! texels. The visibility bits are read back on the CPU and used for the next frame, so there is
+//! one frame of latency: objects crossing the near plane or that weren't tested yet are always
+//! drawn.
+
+use std::{
+    collections::HashSet,
+    num::NonZeroU32,
+    ops::Range,
+    sync::mpsc::{self, Receiver, TryRecvError},
+};
+
+use ecs::Entity;
+use glam::{Mat4, Vec2, Vec4Swizzles};
+
+use crate::include_shader;
+
+use super::{memory::texture_bytes, mesh_manager::BoundingBox, pipeline::ComputePipeline};
+
+/// Workgroup size of the pyramid shaders (on each axis)
+const PYRAMID_WG_SIZE: u32 = 8;
+/// Workgroup size of the culling shader
+const CULL_WG_SIZE: u32 = 64;
+
+/// Size of a level of the pyramid
+pub fn level_size(size: (u32, u32), level: u32) -> (u32, u32) {
+    ((size.0 >> level).max(1), (size.1 >> level).max(1))
+}
+
+/// Number of levels of a pyramid, down to 1x1
+pub fn level_count(size: (u32, u32)) -> u32 {
+    32 - size.0.max(size.1).max(1).leading_zeros()
+}
+
+/// Texels (on one axis) of a level covered by a texel of the next one. Texels cover 2 texels of
+/// the level before, except the last one which also covers the odd one out when the size is odd.
+pub fn footprint(texel: u32, size: u32, source_size: u32) -> Range<u32> {
+    let start = texel * 2;
+    let extent = match texel + 1 == size && source_size % 2 == 1 && source_size > 1 {
+        true => 3,
+        false => 2,
+    };
+    start..(start + extent).min(source_size)
+}
+
+/// The screen space bounds of an object, in uv coordinates (0 to 1, y down)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScreenRect {
+    pub min: Vec2,
+    pub max: Vec2,
+    /// Depth of the nearest point
+    pub depth: f32,
+}
+
+/// Project a bounding box on screen, None if it crosses the near plane (and can't be tested)
+pub fn screen_rect(bounds: &BoundingBox, view_proj: Mat4) -> Option<ScreenRect> {
+    let mut rect = ScreenRect {
+        min: Vec2::ONE,
+        max: Vec2::ZERO,
+        depth: 1.0,
+    };
+    for corner in bounds.corners() {
+        let clip = view_proj * corner.extend(1.0);
+        if clip.w <= 0.0 || clip.z < 0.0 {
+            return None;
+        }
+        let ndc = clip.xyz() / clip.w;
+        let uv = Vec2::new(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        rect.min = rect.min.min(uv);
+        rect.max = rect.max.max(uv);
+        rect.depth = rect.depth.min(ndc.z);
+    }
+    rect.min = rect.min.clamp(Vec2::ZERO, Vec2::ONE);
+    rect.max = rect.max.clamp(Vec2::ZERO, Vec2::ONE);
+    Some(rect)
+}
+
+/// Level of the pyramid at which the rect covers at most 2x2 texels. size is the size of the
+/// first level.
+pub fn select_level(rect: &ScreenRect, size: (u32, u32), levels: u32) -> u32 {
+    let texels = (rect.max - rect.min) * Vec2::new(size.0 as f32, size.1 as f32);
+    let texels = texels.max_element().max(1.0);
+    (texels.log2().ceil() as u32).min(levels.saturating_sub(1))
+}
+
+/// Check a rect against a pyramid, `load(level, x, y)` gives the depth of a texel
+pub fn occluded(
+    rect: &ScreenRect,
+    size: (u32, u32),
+    levels: u32,
+    load: impl Fn(u32, u32, u32) -> f32,
+) -> bool {
+    // Off screen
+    if rect.min.x >= rect.max.x || rect.min.y >= rect.max.y {
+        return false;
+    }
+    let level = select_level(rect, size, levels);
+    let (w, h) = level_size(size, level);
+    let texel = |uv: Vec2| {
+        (
+            ((uv.x * w as f32) as u32).min(w - 1),
+            ((uv.y * h as f32) as u32).min(h - 1),
+        )
+    };
+    let (x0, y0) = texel(rect.min);
+    let (x1, y1) = texel(rect.max);
+    let occluder = load(level, x0, y0)
+        .max(load(level, x1, y0))
+        .max(load(level, x0, y1))
+        .max(load(level, x1, y1));
+    rect.depth > occluder
+}
+
+/// The depth pyramid, the first level is half the size of the depth buffer
+pub struct DepthPyramid {
+    depth_pipeline: ComputePipeline,
+    downsample_pipeline: ComputePipeline,
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    bindgroups: Vec<wgpu::BindGroup>,
+    size: (u32, u32),
+    levels: u32,
+}
+
+impl DepthPyramid {
+    pub fn new(device: &wgpu::Device, depth: &wgpu::TextureView, depth_size: (u32, u32)) -> Self {
+        let depth_layout = create_bind_group_layout!(device, "Depth Pyramid Depth Bindgroup Layout": {
+            0 => COMPUTE | Texture(sample: Depth, view_dim: D2),
+            1 => COMPUTE | StorageTexture(view_dim: D2, format: R32Float, access: WriteOnly),
+        });
+        let downsample_layout = create_bind_group_layout!(device, "Depth Pyramid Downsample Bindgroup Layout": {
+            0 => COMPUTE | Texture(sample: Float, view_dim: D2),
+            1 => COMPUTE | StorageTexture(view_dim: D2, format: R32Float, access: WriteOnly),
+        });
+        let pipeline = |layout, shader, label| {
+            ComputePipeline::new(
+                device,
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Depth Pyramid Pipeline Layout"),
+                    bind_group_layouts: &[layout],
+                    push_constant_ranges: &[],
+                }),
+                shader,
+                move |device, layout, module| {
+                    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                        label: Some(label),
+                        layout: Some(layout),
+                        module,
+                        entry_point: "main",
+                    })
+                },
+            )
+        };
+        let depth_pipeline = pipeline(
+            &depth_layout,
+            include_shader!("hiz_depth.wgsl", "Depth Pyramid Depth Shader"),
+            "Depth Pyramid Depth Pipeline",
+        );
+        let downsample_pipeline = pipeline(
+            &downsample_layout,
+            include_shader!("hiz_downsample.wgsl", "Depth Pyramid Downsample Shader"),
+            "Depth Pyramid Downsample Pipeline",
+        );
+        let (texture, view, bindgroups, size, levels) =
+            Self::make_texture(device, &depth_pipeline, &downsample_pipeline, depth, depth_size);
+        Self {
+            depth_pipeline,
+            downsample_pipeline,
+            texture,
+            view,
+            bindgroups,
+            size,
+            levels,
+        }
+    }
+    #[allow(clippy::type_complexity)]
+    fn make_texture(
+        device: &wgpu::Device,
+        depth_pipeline: &ComputePipeline,
+        downsample_pipeline: &ComputePipeline,
+        depth: &wgpu::TextureView,
+        depth_size: (u32, u32),
+    ) -> (wgpu::Texture, wgpu::TextureView, Vec<wgpu::BindGroup>, (u32, u32), u32) {
+        let size = level_size(depth_size, 1);
+        let levels = level_count(size);
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Depth Pyramid"),
+            size: wgpu::Extent3d {
+                width: size.0,
+                height: size.1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: levels,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC,
+        });
+        let level_view = |level| {
+            texture.create_view(&wgpu::TextureViewDescriptor {
+                base_mip_level: level,
+                mip_level_count: NonZeroU32::new(1),
+                ..Default::default()
+            })
+        };
+        let views = (0..levels).map(level_view).collect::<Vec<_>>();
+        let mut bindgroups = vec![create_bind_group!(device, &depth_pipeline.pipeline.get_bind_group_layout(0), "Depth Pyramid Bindgroup": {
+            0 | TextureView(depth),
+            1 | TextureView(&views[0]),
+        })];
+        for level in 1..levels as usize {
+            bindgroups.push(create_bind_group!(device, &downsample_pipeline.pipeline.get_bind_group_layout(0), "Depth Pyramid Bindgroup": {
+                0 | TextureView(&views[level - 1]),
+                1 | TextureView(&views[level]),
+            }));
+        }
+        let view = texture.create_view(&Default::default());
+        (texture, view, bindgroups, size, levels)
+    }
+    /// Recreate the pyramid for a new depth buffer
+    /// Bytes taken by the pyramid
+    pub fn memory(&self) -> u64 {
+        texture_bytes(self.size.0, self.size.1, wgpu::TextureFormat::R32Float, self.levels)
+    }
+    pub fn resize(&mut self, device: &wgpu::Device, depth: &wgpu::TextureView, depth_size: (u32, u32)) {
+        let (texture, view, bindgroups, size, levels) = Self::make_texture(
+            device,
+            &self.depth_pipeline,
+            &self.downsample_pipeline,
+            depth,
+            depth_size,
+        );
+        self.texture = texture;
+        self.view = view;
+        self.bindgroups = bindgroups;
+        self.size = size;
+        self.levels = levels;
+    }
+    /// Record the passes building the pyramid from the depth buffer
+    pub fn build(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Depth Pyramid Pass"),
+        });
+        for (level, bindgroup) in self.bindgroups.iter().enumerate() {
+            let pipeline = match level {
+                0 => &self.depth_pipeline,
+                _ => &self.downsample_pipeline,
+            };
+            let (w, h) = level_size(self.size, level as u32);
+            pass.set_pipeline(&pipeline.pipeline);
+            pass.set_bind_group(0, bindgroup, &[]);
+            pass.dispatch_workgroups(
+                w.div_ceil(PYRAMID_WG_SIZE),
+                h.div_ceil(PYRAMID_WG_SIZE),
+                1,
+            );
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullParams {
+    view_proj: Mat4,
+    size: [u32; 2],
+    levels: u32,
+    count: u32,
+}
+
+enum Readback {
+    Idle,
+    /// The test has been recorded, waiting for the submission
+    Recorded(Vec<Entity>),
+    Mapping(Vec<Entity>, Receiver<Result<(), wgpu::BufferAsyncError>>),
+}
+
+/// Runs the occlusion test on the gpu and reads the results back
+pub struct OcclusionCuller {
+    pipeline: ComputePipeline,
+    params: wgpu::Buffer,
+    bounds: wgpu::Buffer,
+    visibility: wgpu::Buffer,
+    readback: wgpu::Buffer,
+    capacity: u32,
+    state: Readback,
+    occluded: HashSet<Entity>,
+}
+
+impl OcclusionCuller {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let layout = create_bind_group_layout!(device, "Occlusion Bindgroup Layout": {
+            0 => COMPUTE | Texture(sample: Float, view_dim: D2),
+            1 => COMPUTE | Buffer(type: Uniform),
+            2 => COMPUTE | Buffer(type: ReadOnlyStorage),
+            3 => COMPUTE | Buffer(type: Storage),
+        });
+        let pipeline = ComputePipeline::new(
+            device,
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Occlusion Pipeline Layout"),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            }),
+            include_shader!("occlusion.wgsl", "Occlusion Shader"),
+            |device, layout, module| {
+                device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some("Occlusion Pipeline"),
+                    layout: Some(layout),
+                    module,
+                    entry_point: "main",
+                })
+            },
+        );
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Occlusion Params"),
+            size: std::mem::size_of::<CullParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let capacity = 256;
+        let [bounds, visibility, readback] = Self::make_buffers(device, capacity);
+        Self {
+            pipeline,
+            params,
+            bounds,
+            visibility,
+            readback,
+            capacity,
+            state: Readback::Idle,
+            occluded: HashSet::new(),
+        }
+    }
+    /// Size in bytes of the visibility bits
+    fn visibility_size(capacity: u32) -> u64 {
+        capacity.div_ceil(32) as u64 * 4
+    }
+    fn make_buffers(device: &wgpu::Device, capacity: u32) -> [wgpu::Buffer; 3] {
+        let words = Self::visibility_size(capacity);
+        let buffer = |label, size, usage| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        [
+            buffer(
+                "Occlusion Bounds",
+                capacity as u64 * std::mem::size_of::<[f32; 8]>() as u64,
+                wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            ),
+            buffer(
+                "Occlusion Visibility",
+                words,
+                wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            ),
+            buffer(
+                "Occlusion Readback",
+                words,
+                wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            ),
+        ]
+    }
+    /// Entities found hidden by the last test that came back
+    pub fn occluded(&self) -> &HashSet<Entity> {
+        &self.occluded
+    }
+    /// Check if the last test came back
+    pub fn poll(&mut self, device: &wgpu::Device) {
+        let received = match &self.state {
+            Readback::Mapping(_, receiver) => {
+                device.poll(wgpu::Maintain::Poll);
+                match receiver.try_recv() {
+                    Ok(result) => Some(result.is_ok()),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => Some(false),
+                }
+            }
+            _ => None,
+        };
+        let mapped = match received {
+            Some(mapped) => mapped,
+            None => return,
+        };
+        if let Readback::Mapping(entities, _) = std::mem::replace(&mut self.state, Readback::Idle) {
+            self.occluded.clear();
+            if mapped {
+                {
+                    let bytes = self.readback.slice(..).get_mapped_range();
+                    let words: &[u32] = bytemuck::cast_slice(&bytes);
+                    self.occluded.extend(
+                        entities
+                            .iter()
+                            .enumerate()
+                            .filter(|(i, _)| words[i / 32] & (1 << (i % 32)) == 0)
+                            .map(|(_, e)| *e),
+                    );
+                }
+                self.readback.unmap();
+            }
+        }
+    }
+    /// Record the test of objects (with world space bounds) against the pyramid, does nothing if
+    /// the last one hasn't come back yet.
+    pub fn record(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        pyramid: &DepthPyramid,
+        view_proj: Mat4,
+        objects: &[(Entity, BoundingBox)],
+    ) {
+        if !matches!(self.state, Readback::Idle) || objects.is_empty() {
+            return;
+        }
+        let count = objects.len() as u32;
+        if count > self.capacity {
+            self.capacity = count.next_power_of_two();
+            [self.bounds, self.visibility, self.readback] = Self::make_buffers(device, self.capacity);
+        }
+        let params = CullParams {
+            view_proj,
+            size: [pyramid.size.0, pyramid.size.1],
+            levels: pyramid.levels,
+            count,
+        };
+        let bounds = objects
+            .iter()
+            .map(|(_, b)| [b.min.x, b.min.y, b.min.z, 0.0, b.max.x, b.max.y, b.max.z, 0.0])
+            .collect::<Vec<_>>();
+        queue.write_buffer(&self.params, 0, bytemuck::bytes_of(&params));
+        queue.write_buffer(&self.bounds, 0, bytemuck::cast_slice(&bounds));
+        let visibility_size = Self::visibility_size(self.capacity);
+        queue.write_buffer(&self.visibility, 0, &vec![0; visibility_size as usize]);
+
+        let bindgroup = create_bind_group!(device, &self.pipeline.pipeline.get_bind_group_layout(0), "Occlusion Bindgroup": {
+            0 | TextureView(&pyramid.view),
+            1 | Buffer(buffer: (&self.params)),
+            2 | Buffer(buffer: (&self.bounds)),
+            3 | Buffer(buffer: (&self.visibility)),
+        });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Occlusion Pass"),
+            });
+            pass.set_pipeline(&self.pipeline.pipeline);
+            pass.set_bind_group(0, &bindgroup, &[]);
+            pass.dispatch_workgroups(count.div_ceil(CULL_WG_SIZE), 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&self.visibility, 0, &self.readback, 0, visibility_size);
+        self.state = Readback::Recorded(objects.iter().map(|(e, _)| *e).collect());
+    }
+    /// Start reading back the recorded test, must be called after the submission
+    pub fn after_submit(&mut self) {
+        if let Readback::Recorded(entities) = std::mem::replace(&mut self.state, Readback::Idle) {
+            let (sender, receiver) = mpsc::channel();
+            self.readback
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |result| {
+                    sender.send(result).ok();
+                });
+            self.state = Readback::Mapping(entities, receiver);
+        }
+    }
+    /// Forget the results, when they aren't valid anymore (resize, ...)
+    pub fn invalidate(&mut self) {
+        self.occluded.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec3, Vec4};
+
+    use super::super::{screenshot::padded_bytes_per_row, GraphicContext};
+    use super::*;
+
+    fn view_proj() -> Mat4 {
+        let view = Mat4::look_at_lh(Vec3::ZERO, Vec3::Z, Vec3::Y);
+        Mat4::perspective_lh(std::f32::consts::FRAC_PI_2, 1.0, 0.1, 100.0) * view
+    }
+
+    /// Reference pyramid, built the same way as the shaders do
+    fn pyramid(depth: &[f32], depth_size: (u32, u32)) -> (Vec<Vec<f32>>, (u32, u32)) {
+        let size = level_size(depth_size, 1);
+        let mut levels: Vec<Vec<f32>> = Vec::new();
+        for level in 0..level_count(size) {
+            let (src, src_size) = match level {
+                0 => (depth, depth_size),
+                _ => (&levels[level as usize - 1][..], level_size(size, level - 1)),
+            };
+            let (w, h) = level_size(size, level);
+            let mut out = Vec::with_capacity((w * h) as usize);
+            for y in 0..h {
+                for x in 0..w {
+                    let mut max = 0.0f32;
+                    for sy in footprint(y, h, src_size.1) {
+                        for sx in footprint(x, w, src_size.0) {
+                            max = max.max(src[(sy * src_size.0 + sx) as usize]);
+                        }
+                    }
+                    out.push(max);
+                }
+            }
+            levels.push(out);
+        }
+        (levels, size)
+    }
+
+    #[test]
+    fn footprints() {
+        for source in 1..70 {
+            let size = level_size((source, 1), 1).0;
+            let covered = (0..size).flat_map(|t| footprint(t, size, source)).collect::<Vec<_>>();
+            assert_eq!((0..source).collect::<Vec<_>>(), covered, "source size {source}");
+        }
+        assert_eq!(1, level_count((1, 1)));
+        assert_eq!(10, level_count((960, 540)));
+        assert_eq!((60, 33), level_size((960, 540), 4));
+        assert_eq!((1, 1), level_size((3, 2), 5));
+    }
+
+    #[test]
+    fn projection() {
+        let b = BoundingBox {
+            min: Vec3::new(-1.0, -1.0, 10.0),
+            max: Vec3::new(1.0, 1.0, 12.0),
+        };
+        let rect = screen_rect(&b, view_proj()).unwrap();
+        // Nearest face at z = 10 spans 1/10 of the half screen
+        assert!((rect.min - Vec2::splat(0.45)).length() < 1e-5, "{rect:?}");
+        assert!((rect.max - Vec2::splat(0.55)).length() < 1e-5, "{rect:?}");
+        let near = view_proj() * Vec4::new(0.0, 0.0, 10.0, 1.0);
+        assert!((rect.depth - near.z / near.w).abs() < 1e-6);
+
+        // Crossing the near plane
+        let b = BoundingBox {
+            min: Vec3::new(-1.0, -1.0, -1.0),
+            max: Vec3::new(1.0, 1.0, 12.0),
+        };
+        assert_eq!(None, screen_rect(&b, view_proj()));
+        // Partially off screen
+        let b = BoundingBox {
+            min: Vec3::new(5.0, -1.0, 2.0),
+            max: Vec3::new(50.0, 1.0, 3.0),
+        };
+        let rect = screen_rect(&b, view_proj()).unwrap();
+        assert_eq!(1.0, rect.max.x);
+    }
+
+    #[test]
+    fn level_selection() {
+        let rect = |w: f32, h: f32| ScreenRect {
+            min: Vec2::ZERO,
+            max: Vec2::new(w, h),
+            depth: 0.5,
+        };
+        let size = (512, 256);
+        assert_eq!(0, select_level(&rect(0.0, 0.0), size, 10));
+        assert_eq!(0, select_level(&rect(1.0 / 512.0, 0.0), size, 10));
+        assert_eq!(1, select_level(&rect(2.0 / 512.0, 0.0), size, 10));
+        assert_eq!(2, select_level(&rect(3.0 / 512.0, 0.0), size, 10));
+        assert_eq!(4, select_level(&rect(0.0, 16.0 / 256.0), size, 10));
+        assert_eq!(9, select_level(&rect(1.0, 1.0), size, 10));
+        assert_eq!(5, select_level(&rect(1.0, 1.0), size, 6));
+        // The rect covers at most 2x2 texels of the selected level, wherever it is
+        for i in 1..200 {
+            let w = i as f32 / 200.0;
+            let level = select_level(&rect(w, w), size, 10);
+            let (lw, _) = level_size(size, level);
+            assert!(w * lw as f32 <= 1.0 || level == 9);
+        }
+    }
+
+    #[test]
+    fn wall() {
+        // A wall filling the middle of the screen at z = 5
+        let (w, h) = (64, 64);
+        let wall = view_proj() * Vec4::new(0.0, 0.0, 5.0, 1.0);
+        let wall_depth = wall.z / wall.w;
+        let depth = (0..w * h)
+            .map(|i| {
+                let (x, y) = (i % w, i / w);
+                match (16..48).contains(&x) && (16..48).contains(&y) {
+                    true => wall_depth,
+                    false => 1.0,
+                }
+            })
+            .collect::<Vec<_>>();
+        let (levels, size) = pyramid(&depth, (w, h));
+        let load = |level: u32, x: u32, y: u32| {
+            levels[level as usize][(y * level_size(size, level).0 + x) as usize]
+        };
+        let test = |min: Vec3, max: Vec3| {
+            let rect = screen_rect(&BoundingBox { min, max }, view_proj()).unwrap();
+            occluded(&rect, size, levels.len() as u32, load)
+        };
+
+        // Behind the wall
+        assert!(test(Vec3::new(-1.0, -1.0, 10.0), Vec3::new(1.0, 1.0, 11.0)));
+        assert!(test(Vec3::new(2.0, 2.0, 30.0), Vec3::new(3.0, 3.0, 31.0)));
+        // In front of the wall
+        assert!(!test(Vec3::new(-1.0, -1.0, 3.0), Vec3::new(1.0, 1.0, 4.0)));
+        // Behind, but sticking out of the sides
+        assert!(!test(Vec3::new(-20.0, -1.0, 10.0), Vec3::new(1.0, 1.0, 11.0)));
+        // Beside it
+        assert!(!test(Vec3::new(8.0, -1.0, 10.0), Vec3::new(9.0, 1.0, 11.0)));
+    }
+
+    /// Draws left of a scene where a wall hides hundreds of objects, going through the reference
+    /// pyramid and test the way `WorldRenderer::record` counts them
+    #[test]
+    fn hundreds_behind_a_wall() {
+        let (w, h) = (128, 128);
+        let wall = BoundingBox {
+            min: Vec3::new(-15.0, -10.0, 5.0),
+            max: Vec3::new(15.0, 10.0, 5.5),
+        };
+        let wall_rect = screen_rect(&wall, view_proj()).unwrap();
+        let pixels = |uv: f32, size: u32| (uv * size as f32) as u32;
+        let wall_x = pixels(wall_rect.min.x, w)..pixels(wall_rect.max.x, w);
+        let wall_y = pixels(wall_rect.min.y, h)..pixels(wall_rect.max.y, h);
+        let depth = (0..w * h)
+            .map(|i| {
+                match wall_x.contains(&(i % w)) && wall_y.contains(&(i / w)) {
+                    true => wall_rect.depth,
+                    false => 1.0,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // A 20x20 grid of cubes behind the wall, and a row in front of it
+        let cube = |x: f32, y: f32, z: f32| BoundingBox {
+            min: Vec3::new(x - 0.4, y - 0.4, z - 0.4),
+            max: Vec3::new(x + 0.4, y + 0.4, z + 0.4),
+        };
+        let behind = (0..400).map(|i| {
+            let (x, y) = ((i % 20) as f32 - 9.5, (i / 20) as f32 - 9.5);
+            cube(x, y * 0.5, 10.0 + y.abs())
+        });
+        let in_front = (0..5).map(|i| cube(i as f32 * 1.5 - 3.0, 0.0, 3.0));
+        let objects = behind.chain(in_front).chain([wall]).collect::<Vec<_>>();
+
+        let drawn = |depth: &[f32]| {
+            let (levels, size) = pyramid(depth, (w, h));
+            let load = |level: u32, x: u32, y: u32| {
+                levels[level as usize][(y * level_size(size, level).0 + x) as usize]
+            };
+            objects
+                .iter()
+                .filter(|bounds| match screen_rect(bounds, view_proj()) {
+                    Some(rect) => !occluded(&rect, size, levels.len() as u32, load),
+                    None => true,
+                })
+                .count()
+        };
+        // Without the wall in the depth buffer, everything is drawn
+        assert_eq!(objects.len(), drawn(&vec![1.0; (w * h) as usize]));
+        // With it, only the wall itself and what is in front of it
+        assert_eq!(6, drawn(&depth));
+    }
+
+    /// Depth buffer with `depth` in `rect` (in pixels, from the top left) and the far plane
+    /// elsewhere, drawn with a scissored full screen triangle
+    fn draw_depth(
+        gfx: &GraphicContext,
+        size: (u32, u32),
+        rect: (Range<u32>, Range<u32>),
+        depth: f32,
+    ) -> wgpu::TextureView {
+        let device = &gfx.device;
+        let view = device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("Test Depth"),
+                size: wgpu::Extent3d {
+                    width: size.0,
+                    height: size.1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Depth32Float,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+            })
+            .create_view(&Default::default());
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Test Depth Shader"),
+            source: wgpu::ShaderSource::Wgsl(
+                format!(
+                    "@vertex
+                    fn vs_main(@builtin(vertex_index) i: u32) -> @builtin(position) vec4<f32> {{
+                        let uv = vec2<f32>(f32((i << 1u) & 2u), f32(i & 2u));
+                        return vec4<f32>(uv * 2.0 - 1.0, {depth:?}, 1.0);
+                    }}"
+                )
+                .into(),
+            ),
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Test Depth Pipeline"),
+            layout: None,
+            vertex: wgpu::VertexState {
+                module: &module,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: None,
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: wgpu::TextureFormat::Depth32Float,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Test Depth Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            pass.set_pipeline(&pipeline);
+            let (x, y) = rect;
+            pass.set_scissor_rect(x.start, y.start, x.end - x.start, y.end - y.start);
+            pass.draw(0..3, 0..1);
+        }
+        gfx.queue.submit([encoder.finish()]);
+        view
+    }
+
+    /// The shaders give the same pyramid and visibility as the reference, on the fallback adapter
+    #[test]
+    #[ignore = "needs a fallback adapter that can run the renderer"]
+    fn gpu_wall() {
+        let gfx = pollster::block_on(GraphicContext::headless(1, 1))
+            .expect("No fallback adapter that can run the renderer");
+        let device = &gfx.device;
+        // Odd sizes, so some texels cover three of the level below. The wall is off center, so the
+        // rows aren't symmetric.
+        let (w, h) = (67, 45);
+        let (wall_x, wall_y) = (10..45, 5..30);
+        let wall = view_proj() * Vec4::new(0.0, 0.0, 5.0, 1.0);
+        let wall_depth = wall.z / wall.w;
+        let depth_view = draw_depth(&gfx, (w, h), (wall_x.clone(), wall_y.clone()), wall_depth);
+        let depth = (0..w * h)
+            .map(|i| match wall_x.contains(&(i % w)) && wall_y.contains(&(i / w)) {
+                true => wall_depth,
+                false => 1.0,
+            })
+            .collect::<Vec<_>>();
+        let (levels, size) = pyramid(&depth, (w, h));
+
+        let objects = [
+            (Vec3::new(-1.0, -1.0, 10.0), Vec3::new(1.0, 1.0, 11.0)),
+            (Vec3::new(-3.0, 0.5, 20.0), Vec3::new(-2.0, 1.5, 21.0)),
+            (Vec3::new(-1.0, -1.0, 3.0), Vec3::new(1.0, 1.0, 4.0)),
+            (Vec3::new(-20.0, -1.0, 10.0), Vec3::new(1.0, 1.0, 11.0)),
+            (Vec3::new(8.0, -1.0, 10.0), Vec3::new(9.0, 1.0, 11.0)),
+            (Vec3::new(-1.0, -8.0, 10.0), Vec3::new(1.0, -7.0, 11.0)),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (min, max))| (Entity::from_bits(i as u64), BoundingBox { min, max }))
+        .collect::<Vec<_>>();
+
+        let pyramid = DepthPyramid::new(device, &depth_view, (w, h));
+        assert_eq!((size, levels.len() as u32), (pyramid.size, pyramid.levels));
+        let mut culler = OcclusionCuller::new(device);
+        let mut encoder = device.create_command_encoder(&Default::default());
+        pyramid.build(&mut encoder);
+        culler.record(device, &gfx.queue, &mut encoder, &pyramid, view_proj(), &objects);
+        let readbacks = (0..pyramid.levels)
+            .map(|level| {
+                let (lw, lh) = level_size(pyramid.size, level);
+                let row = padded_bytes_per_row(lw);
+                let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Test Pyramid Level"),
+                    size: (row * lh) as u64,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                encoder.copy_texture_to_buffer(
+                    wgpu::ImageCopyTexture {
+                        texture: &pyramid.texture,
+                        mip_level: level,
+                        origin: wgpu::Origin3d::ZERO,
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    wgpu::ImageCopyBuffer {
+                        buffer: &buffer,
+                        layout: wgpu::ImageDataLayout {
+                            offset: 0,
+                            bytes_per_row: NonZeroU32::new(row),
+                            rows_per_image: None,
+                        },
+                    },
+                    wgpu::Extent3d {
+                        width: lw,
+                        height: lh,
+                        depth_or_array_layers: 1,
+                    },
+                );
+                buffer
+            })
+            .collect::<Vec<_>>();
+        gfx.queue.submit([encoder.finish()]);
+        culler.after_submit();
+        for buffer in &readbacks {
+            buffer.slice(..).map_async(wgpu::MapMode::Read, |_| ());
+        }
+        device.poll(wgpu::Maintain::Wait);
+        culler.poll(device);
+
+        for (level, buffer) in readbacks.iter().enumerate() {
+            let (lw, lh) = level_size(size, level as u32);
+            let row = padded_bytes_per_row(lw) as usize;
+            let bytes = buffer.slice(..).get_mapped_range();
+            let texels = (0..lh as usize)
+                .flat_map(|y| {
+                    bytemuck::cast_slice::<u8, f32>(&bytes[y * row..y * row + lw as usize * 4])
+                })
+                .copied()
+                .collect::<Vec<_>>();
+            assert_eq!(levels[level], texels, "level {level}");
+        }
+        let load = |level: u32, x: u32, y: u32| {
+            levels[level as usize][(y * level_size(size, level).0 + x) as usize]
+        };
+        let expected = objects
+            .iter()
+            .filter(|(_, bounds)| {
+                let rect = screen_rect(bounds, view_proj()).unwrap();
+                occluded(&rect, size, levels.len() as u32, load)
+            })
+            .map(|(e, _)| *e)
+            .collect::<HashSet<_>>();
+        // Some are hidden, some aren't
+        assert!(!expected.is_empty() && expected.len() < objects.len(), "{expected:?}");
+        assert_eq!(&expected, culler.occluded());
+    }
+}
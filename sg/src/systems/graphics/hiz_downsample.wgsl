@@ -0,0 +1,30 @@
+// Build a level of the depth pyramid from the previous one: each texel is the max (farthest) of
+// the texels it covers.
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+@group(0) @binding(1)
+var destination: texture_storage_2d<r32float, write>;
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dst_size = textureDimensions(destination);
+    let dst = vec2<i32>(id.xy);
+    if (dst.x >= dst_size.x || dst.y >= dst_size.y) {
+        return;
+    }
+    let src_size = textureDimensions(source);
+    let start = dst * 2;
+    // The last texel of a row / column also covers the odd one out of the source
+    let odd = ((src_size & vec2<i32>(1, 1)) == vec2<i32>(1, 1)) & (src_size > vec2<i32>(1, 1));
+    let last = dst == dst_size - vec2<i32>(1, 1);
+    let extent = select(vec2<i32>(2, 2), vec2<i32>(3, 3), odd & last);
+    let end = min(start + extent, src_size);
+
+    var depth = 0.0;
+    for (var y = start.y; y < end.y; y = y + 1) {
+        for (var x = start.x; x < end.x; x = x + 1) {
+            depth = max(depth, textureLoad(source, vec2<i32>(x, y), 0).r);
+        }
+    }
+    textureStore(destination, dst, vec4<f32>(depth, 0.0, 0.0, 0.0));
+}
@@ -0,0 +1,245 @@
+//! Instancing of the geometry pass.
+//!
+//! Renderables sharing a mesh and a texture set are drawn with a single call, their matrices
+//! coming from an instance buffer rebuilt every frame. Devices without enough vertex buffers or
+//! attributes for it draw each renderable on its own, with the matrices in push constants.
+
+use std::ops::Range;
+
+use glam::{Mat4, Vec3, Vec4};
+
+use super::{mesh_manager::MeshHandle, texture_manager::TextureSet};
+
+/// Matrices of a renderable, given to the geometry pass per instance
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GeometryInstance {
+    pub model: Mat4,
+    /// Only applied to directions, the translation column is free to carry how exposed to the
+    /// weather the surface is (x), the id of the entity (y, see `picking`) and the alpha cutoff
+    /// of the material (z), the last row its emissive factor
+    pub normal: Mat4,
+}
+
+impl GeometryInstance {
+    /// After the attributes of `Vertex`
+    const ATTRIBUTES: [wgpu::VertexAttribute; 8] = wgpu::vertex_attr_array![
+        4 => Float32x4,
+        5 => Float32x4,
+        6 => Float32x4,
+        7 => Float32x4,
+        8 => Float32x4,
+        9 => Float32x4,
+        10 => Float32x4,
+        11 => Float32x4,
+    ];
+
+    pub fn new(model: Mat4, exposed: bool, id: u32) -> Self {
+        let mut normal = model.inverse().transpose();
+        normal.w_axis = Vec4::new(exposed as u32 as f32, id as f32, 0.0, 0.0);
+        Self { model, normal }
+    }
+
+    /// The emissive factor and alpha cutoff of the material (see `Material`)
+    pub fn with_material(mut self, emissive: Vec3, alpha_cutoff: f32) -> Self {
+        self.normal.x_axis.w = emissive.x;
+        self.normal.y_axis.w = emissive.y;
+        self.normal.z_axis.w = emissive.z;
+        self.normal.w_axis.z = alpha_cutoff;
+        self
+    }
+
+    /// Depth of the origin in the view space of `view` (left handed, farther is greater)
+    pub fn view_depth(&self, view: Mat4) -> f32 {
+        view.transform_point3(self.model.w_axis.truncate()).z
+    }
+
+    pub fn layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+/// Whether the device can draw the geometry pass with instancing (the vertices and the instances
+/// in two buffers, with 12 attributes in total)
+pub fn supports_instancing(limits: &wgpu::Limits) -> bool {
+    limits.max_vertex_buffers >= 2 && limits.max_vertex_attributes >= 12
+}
+
+/// Renderables drawn together
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceGroup {
+    pub mesh: MeshHandle,
+    pub textures: TextureSet,
+    pub instances: Range<u32>,
+}
+
+/// Sort the instances by mesh and texture set and group them, the instances of a group keep their
+/// order
+pub fn group_instances(
+    mut draws: Vec<(MeshHandle, TextureSet, GeometryInstance)>,
+) -> (Vec<GeometryInstance>, Vec<InstanceGroup>) {
+    draws.sort_by_key(|&(mesh, textures, _)| (mesh, textures));
+    let mut groups: Vec<InstanceGroup> = Vec::new();
+    for (i, &(mesh, textures, _)) in draws.iter().enumerate() {
+        let i = i as u32;
+        match groups.last_mut() {
+            Some(group) if group.mesh == mesh && group.textures == textures => {
+                group.instances.end = i + 1
+            }
+            _ => groups.push(InstanceGroup {
+                mesh,
+                textures,
+                instances: i..i + 1,
+            }),
+        }
+    }
+    let instances = draws.into_iter().map(|(_, _, instance)| instance).collect();
+    (instances, groups)
+}
+
+/// Group draws and append their instances, the ranges of the groups are into `instances`
+pub fn append_groups(
+    instances: &mut Vec<GeometryInstance>,
+    draws: Vec<(MeshHandle, TextureSet, GeometryInstance)>,
+) -> Vec<InstanceGroup> {
+    let (appended, mut groups) = group_instances(draws);
+    let offset = instances.len() as u32;
+    for group in &mut groups {
+        group.instances = group.instances.start + offset..group.instances.end + offset;
+    }
+    instances.extend(appended);
+    groups
+}
+
+/// Instance buffer reused across frames, grown to the next power of two when too small
+#[derive(Default)]
+pub struct InstanceBuffer {
+    buffer: Option<(wgpu::Buffer, u64)>,
+}
+
+impl InstanceBuffer {
+    /// Capacity (in bytes) of the buffer holding `size` bytes, None if the current one is enough
+    fn grown(current: Option<u64>, size: u64) -> Option<u64> {
+        match current {
+            Some(capacity) if capacity >= size => None,
+            _ => Some(size.max(1).next_power_of_two()),
+        }
+    }
+
+    /// Upload the instances, growing the buffer if needed
+    pub fn write(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        instances: &[GeometryInstance],
+    ) -> &wgpu::Buffer {
+        let size = std::mem::size_of_val(instances) as u64;
+        if let Some(capacity) = Self::grown(self.buffer.as_ref().map(|(_, c)| *c), size) {
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Geometry Instances"),
+                size: capacity,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            self.buffer = Some((buffer, capacity));
+        }
+        let (buffer, _) = self.buffer.as_ref().unwrap();
+        queue.write_buffer(buffer, 0, bytemuck::cast_slice(instances));
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slotmap::SlotMap;
+
+    use super::*;
+
+    #[test]
+    fn grouping() {
+        let mut meshes = SlotMap::<MeshHandle, ()>::with_key();
+        let mut sets = SlotMap::<TextureSet, ()>::with_key();
+        let (cube, sphere) = (meshes.insert(()), meshes.insert(()));
+        let (red, blue) = (sets.insert(()), sets.insert(()));
+        let at = |x: f32| GeometryInstance::new(Mat4::from_translation(Vec3::X * x), true, 1);
+        let draws = vec![
+            (sphere, red, at(0.0)),
+            (cube, red, at(1.0)),
+            (sphere, red, at(2.0)),
+            (sphere, blue, at(3.0)),
+            (sphere, red, at(4.0)),
+        ];
+        let (instances, groups) = group_instances(draws);
+        assert_eq!(
+            vec![
+                InstanceGroup { mesh: cube, textures: red, instances: 0..1 },
+                InstanceGroup { mesh: sphere, textures: red, instances: 1..4 },
+                InstanceGroup { mesh: sphere, textures: blue, instances: 4..5 },
+            ],
+            groups
+        );
+        let xs: Vec<_> = instances.iter().map(|i| i.model.w_axis.x).collect();
+        assert_eq!(vec![1.0, 0.0, 2.0, 4.0, 3.0], xs);
+        assert_eq!((vec![], vec![]), group_instances(vec![]));
+
+        // Appended after what is already there
+        let mut instances = vec![at(5.0)];
+        let groups = append_groups(&mut instances, vec![(cube, blue, at(6.0)), (cube, blue, at(7.0))]);
+        assert_eq!(vec![InstanceGroup { mesh: cube, textures: blue, instances: 1..3 }], groups);
+        assert_eq!(3, instances.len());
+    }
+
+    #[test]
+    fn instance_data() {
+        let model = Mat4::from_scale(Vec3::new(2.0, 1.0, 1.0));
+        let instance = GeometryInstance::new(model, false, 0);
+        assert_eq!(Vec4::ZERO, instance.normal.w_axis);
+        // Normals of a surface stretched along x lean away from x
+        let normal = instance.normal.transform_vector3(Vec3::new(1.0, 1.0, 0.0));
+        assert_eq!(Vec3::new(0.5, 1.0, 0.0), normal);
+        let instance = GeometryInstance::new(model, true, 70000);
+        assert_eq!(Vec4::new(1.0, 70000.0, 0.0, 0.0), instance.normal.w_axis);
+        assert_eq!(128, std::mem::size_of::<GeometryInstance>());
+        // The material doesn't change how normals are transformed
+        let instance = instance.with_material(Vec3::new(0.25, 0.5, 1.0), 0.3);
+        assert_eq!(Vec4::new(1.0, 70000.0, 0.3, 0.0), instance.normal.w_axis);
+        assert_eq!(Vec4::new(0.25, 0.5, 1.0, 0.0), instance.normal.row(3));
+        assert_eq!(normal, instance.normal.transform_vector3(Vec3::new(1.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn view_depth() {
+        // Looking down -x from x = 10
+        let view = Mat4::look_at_lh(Vec3::X * 10.0, Vec3::ZERO, Vec3::Y);
+        let mut draws: Vec<_> = [2.0, 8.0, -3.0]
+            .map(|x| GeometryInstance::new(Mat4::from_translation(Vec3::X * x), true, 0))
+            .into();
+        assert_eq!(2.0, draws[1].view_depth(view));
+        draws.sort_by(|a, b| b.view_depth(view).total_cmp(&a.view_depth(view)));
+        let xs: Vec<_> = draws.iter().map(|i| i.model.w_axis.x).collect();
+        assert_eq!(vec![-3.0, 2.0, 8.0], xs);
+    }
+
+    #[test]
+    fn limits() {
+        assert!(supports_instancing(&wgpu::Limits::default()));
+        assert!(supports_instancing(&wgpu::Limits::downlevel_webgl2_defaults()));
+        let limits = wgpu::Limits {
+            max_vertex_attributes: 8,
+            ..Default::default()
+        };
+        assert!(!supports_instancing(&limits));
+    }
+
+    #[test]
+    fn growth() {
+        assert_eq!(Some(256), InstanceBuffer::grown(None, 256));
+        assert_eq!(Some(1), InstanceBuffer::grown(None, 0));
+        assert_eq!(None, InstanceBuffer::grown(Some(256), 128));
+        assert_eq!(Some(512), InstanceBuffer::grown(Some(256), 384));
+    }
+}
@@ -0,0 +1,252 @@
+// Lighting of a surface by the lights of the g-buffer's bind group and the environment, shared
+// by the shading pass (shader.wgsl) and the forward pass of blended materials (forward.wgsl).
+// Comes after camera.wgsl.
+
+// The lights mirror their struct in mod.rs, shadow is the layer of the shadow atlas (-1 if
+// unshadowed)
+struct DiretionalLight {
+    direction: vec3<f32>,
+    shadow: i32,
+    color: vec4<f32>,
+    view_proj: mat4x4<f32>,
+}
+
+struct PointLight {
+    @size(16)
+    position: vec3<f32>,
+    color: vec4<f32>
+}
+
+struct SpotLight {
+    position: vec3<f32>,
+    shadow: i32,
+    direction: vec3<f32>,
+    // Cosine of the half angle of the cone
+    cut_off: f32,
+    color: vec4<f32>,
+    view_proj: mat4x4<f32>,
+}
+
+struct DiretionalLights {
+    @size(16)
+    length: u32,
+    lights: array<DiretionalLight, {{LIGHTS_MAX}}>,
+}
+struct PointLights {
+    @size(16)
+    length: u32,
+    lights: array<PointLight, {{LIGHTS_MAX}}>,
+}
+struct SpotLights {
+    @size(16)
+    length: u32,
+    lights: array<SpotLight, {{LIGHTS_MAX}}>,
+}
+// Mirrors g_buffer::SurfaceWeather
+struct SurfaceWeather {
+    wetness: f32,
+    puddles: f32,
+    puddle_threshold: f32,
+    exposure: f32,
+}
+
+@group(0) @binding(0)
+var g_sampler: sampler;
+@group(0) @binding(1)
+var g_depth: texture_depth_2d;
+@group(0) @binding(2)
+var<uniform> d_lights: DiretionalLights;
+@group(0) @binding(3)
+var<uniform> p_lights: PointLights;
+@group(0) @binding(4)
+var<uniform> s_lights: SpotLights;
+@group(0) @binding(5)
+var<uniform> weather: SurfaceWeather;
+@group(0) @binding(6)
+var shadow_atlas: texture_depth_2d_array;
+@group(0) @binding(7)
+var shadow_sampler: sampler_comparison;
+
+let PI = 3.1415926535;
+
+// Smooth noise in [0, 1] deciding where puddles form first, mirrors weather::puddle_noise
+fn puddle_noise(p: vec2<f32>) -> f32 {
+    let a = sin(p.x * 0.73 + sin(p.y * 0.41) * 2.0);
+    let b = sin(p.y * 0.67 + sin(p.x * 0.37) * 2.0);
+    return 0.5 + 0.25 * (a + b);
+}
+
+// Puddle coverage of a surface, mirrors weather::puddle_mask
+fn puddle_mask(p: vec2<f32>, normal_y: f32) -> f32 {
+    let facing = smoothstep(weather.puddle_threshold, 1.0, normal_y);
+    let fill = weather.puddles * 1.2 - 0.2;
+    return facing * (1.0 - smoothstep(fill - 0.1, fill + 0.1, puddle_noise(p)));
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32>{
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32>{
+    return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+fn distribution_ggx(n: vec3<f32>, h: vec3<f32>, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a*a;
+    let ndot_h = max(dot(n, h), 0.0);
+    let ndot_h2 = ndot_h * ndot_h;
+
+    let num = a2;
+    var denom = (ndot_h2 * (a2 - 1.0) + 1.0);
+    denom = PI * denom * denom;
+
+    return num / denom;
+}
+
+fn geometry_schlick_ggx(ndot_v: f32, roughness: f32) -> f32 {
+    let r = (roughness + 1.0);
+    let k = (r*r) / 8.0;
+
+    let num = ndot_v;
+    let denom = ndot_v * (1.0 - k) + k;
+
+    return num / denom;
+}
+
+fn geometry_smith(n: vec3<f32> , v: vec3<f32>, l: vec3<f32>, roughness: f32) -> f32 {
+    let ndot_v = max(dot(n, v), 0.0);
+    let ndot_l = max(dot(n, l), 0.0);
+    let ggx2 = geometry_schlick_ggx(ndot_v, roughness);
+    let ggx1 = geometry_schlick_ggx(ndot_l, roughness);
+
+    return ggx1 * ggx2;
+}
+
+// How lit a position is by a shadowed light, 3x3 PCF of its layer of the shadow atlas. Outside
+// of the shadow map (and without one) it is lit.
+fn shadow(layer: i32, view_proj: mat4x4<f32>, pos: vec3<f32>) -> f32 {
+    if (layer < 0 || layer >= {{SHADOWS_MAX}}) {
+        return 1.0;
+    }
+    let clip = view_proj * vec4<f32>(pos, 1.0);
+    let ndc = clip.xyz / clip.w;
+    if (clip.w <= 0.0 || abs(ndc.x) > 1.0 || abs(ndc.y) > 1.0 || ndc.z > 1.0) {
+        return 1.0;
+    }
+    let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+    let texel = 1.0 / vec2<f32>(textureDimensions(shadow_atlas));
+    var lit = 0.0;
+    for (var i = 0; i < 9; i++) {
+        let offset = vec2<f32>(f32(i % 3 - 1), f32(i / 3 - 1)) * texel;
+        lit += textureSampleCompareLevel(shadow_atlas, shadow_sampler, uv + offset, layer, ndc.z);
+    }
+    return lit / 9.0;
+}
+
+// Cook-Torrance, for a light coming from light_dir
+fn brdf(light_dir: vec3<f32>, radiance: vec3<f32>, normal: vec3<f32>, albedo: vec3<f32>, metallic: f32, roughness: f32, view_dir: vec3<f32>, f0: vec3<f32>) -> vec3<f32> {
+    let halfway = normalize(light_dir + view_dir);
+    let F = fresnel_schlick(max(dot(halfway, view_dir), 0.0), f0);
+    let NDF = distribution_ggx(normal, halfway, roughness);       
+    let G = geometry_smith(normal, view_dir, light_dir, roughness);
+    let numerator = NDF * G * F;
+    let denominator = 4.0 * max(dot(normal, view_dir), 0.0) * max(dot(normal, light_dir), 0.0)  + 0.0001;
+    let specular = numerator / denominator;
+    let kS = F;
+    let kD = (vec3<f32>(1.0) - kS) * (1.0 - metallic);
+    let ndot_l = max(dot(normal, light_dir), 0.0);        
+    return (kD * albedo / PI + specular) * radiance * ndot_l;
+}
+
+fn dir_light(light: DiretionalLight, normal: vec3<f32>, albedo: vec3<f32>, metallic: f32, roughness: f32, frag_pos: vec3<f32>, view_dir: vec3<f32>, f0: vec3<f32>) -> vec3<f32>  {
+    let light_dir = normalize(-light.direction);
+    let lit = shadow(light.shadow, light.view_proj, frag_pos);
+    return brdf(light_dir, light.color.xyz * lit, normal, albedo, metallic, roughness, view_dir, f0);
+}
+
+fn point_light(light: PointLight, normal: vec3<f32>, albedo: vec3<f32>, metallic: f32, roughness: f32, frag_pos: vec3<f32>, view_dir: vec3<f32>, f0: vec3<f32>) -> vec3<f32>  {
+    let light_dir = normalize(light.position - frag_pos);
+    let distance = length(light.position - frag_pos);
+    let attenuation = 1.0 / (distance * distance);
+    return brdf(light_dir, light.color.xyz * attenuation, normal, albedo, metallic, roughness, view_dir, f0);
+}
+
+fn spot_light(light: SpotLight, normal: vec3<f32>, albedo: vec3<f32>, metallic: f32, roughness: f32, frag_pos: vec3<f32>, view_dir: vec3<f32>, f0: vec3<f32>) -> vec3<f32>  {
+    let light_dir = normalize(light.position - frag_pos);
+    let distance = length(light.position - frag_pos);
+    let attenuation = 1.0 / (distance * distance);
+    // Soft edge over the outer 10% of the cone
+    let theta = dot(light_dir, normalize(-light.direction));
+    let cone = smoothstep(light.cut_off, mix(light.cut_off, 1.0, 0.1), theta);
+    let lit = shadow(light.shadow, light.view_proj, frag_pos);
+    return brdf(light_dir, light.color.xyz * attenuation * cone * lit, normal, albedo, metallic, roughness, view_dir, f0);
+}
+
+// Reflectance of the environment's specular light (split-sum approximation), what multiplies
+// env_specular
+fn env_reflectance(normal: vec3<f32>, view: vec3<f32>, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let n_dot_v = max(dot(normal, view), 0.0);
+    let kS = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    let lut = textureSampleLevel(brdf_lut, g_sampler, vec2<f32>(n_dot_v, roughness), 0.0).rg;
+    return kS * lut.x + lut.y;
+}
+
+// Light of the environment reflected towards view, before env_reflectance
+fn env_specular(normal: vec3<f32>, view: vec3<f32>, roughness: f32) -> vec3<f32> {
+    return textureSampleLevel(env_map, g_sampler, reflect(-view, normal), roughness * cam.env_max_lod).rgb;
+}
+
+fn ambiant_light(normal: vec3<f32>, view: vec3<f32>, f0: vec3<f32>, roughness: f32, metallic: f32, albedo: vec3<f32>, ao: f32) -> vec3<f32> {
+    let kS = fresnel_schlick_roughness(max(dot(normal, view), 0.0), f0, roughness);
+    // Metals have no diffuse reflection
+    let kD = (1.0 - kS) * (1.0 - metallic);
+    let irr = textureSample(irr_map, g_sampler, normal).xyz;
+    let diff = irr * albedo;
+    let spec = env_specular(normal, view, roughness) * env_reflectance(normal, view, f0, roughness);
+    return (kD * diff + spec) * ao;
+}
+
+
+// A surface to light, read from the g-buffer or from its material
+struct Surface {
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    albedo: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    ao: f32,
+    // How exposed to the weather it is
+    exposed: f32,
+}
+
+// Wet surfaces are darker and smoother, puddles are flat mirrors
+fn weathered(surface: Surface) -> Surface {
+    var s = surface;
+    let wet = weather.wetness * s.exposed;
+    let puddle = puddle_mask(s.position.xz, s.normal.y) * wet;
+    s.albedo = s.albedo * (1.0 - 0.4 * wet) * (1.0 - 0.3 * puddle);
+    s.roughness = mix(mix(s.roughness, s.roughness * 0.3, wet), 0.02, puddle);
+    s.normal = normalize(mix(s.normal, vec3<f32>(0.0, 1.0, 0.0), puddle));
+    return s;
+}
+
+fn base_reflectivity(s: Surface) -> vec3<f32> {
+    return mix(vec3<f32>(0.04), s.albedo, s.metallic);
+}
+
+// Light of the lights and of the environment's irradiance leaving a surface towards view_dir
+fn surface_light(s: Surface, view_dir: vec3<f32>) -> vec3<f32> {
+    let f0 = base_reflectivity(s);
+    var l = vec3<f32>(0.0);
+    for(var i: u32 = 0u; i < d_lights.length; i++) {
+        l += dir_light(d_lights.lights[i], s.normal, s.albedo, s.metallic, s.roughness, s.position, view_dir, f0);
+    }
+    for(var i: u32 = 0u; i < p_lights.length; i++) {
+        l += point_light(p_lights.lights[i], s.normal, s.albedo, s.metallic, s.roughness, s.position, view_dir, f0);
+    }
+    for(var i: u32 = 0u; i < s_lights.length; i++) {
+        l += spot_light(s_lights.lights[i], s.normal, s.albedo, s.metallic, s.roughness, s.position, view_dir, f0);
+    }
+    return l + ambiant_light(s.normal, view_dir, f0, s.roughness, s.metallic, s.albedo, s.ao);
+}
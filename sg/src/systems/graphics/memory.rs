@@ -0,0 +1,833 @@
+//! GPU memory accounting and texture residency. The sizes of textures, meshes and render targets
+//! are recorded when they are created (wgpu doesn't report them), and when the total goes over the
+//! budget the streamable textures (loaded from image files) that weren't used for the longest are
+//! swapped for a low resolution copy, then brought back once they are used and there is room.
+//!
+//! Render targets and the UI textures are never demoted, the targets are only reported and the UI
+//! textures (owned by egui) aren't measured.
+
+use std::{collections::HashMap, hash::Hash, path::PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    console::{unknown_field, Arg, Inspect},
+    localization::Localization,
+    tr,
+};
+
+use super::{focus::UiFocus, renderer::WorldRenderer, GraphicContext};
+
+const MIB: u64 = 1024 * 1024;
+
+/// Bytes taken by a 2D texture and its mips
+pub fn texture_bytes(
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+    mip_level_count: u32,
+) -> u64 {
+    let info = format.describe();
+    let (block_width, block_height) = info.block_dimensions;
+    (0..mip_level_count)
+        .map(|level| {
+            let width = (width >> level).max(1).div_ceil(block_width as u32);
+            let height = (height >> level).max(1).div_ceil(block_height as u32);
+            width as u64 * height as u64 * info.block_size as u64
+        })
+        .sum()
+}
+
+/// Size and format of a texture, which wgpu doesn't give back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextureInfo {
+    pub width: u32,
+    pub height: u32,
+    pub format: wgpu::TextureFormat,
+    pub mip_level_count: u32,
+}
+
+impl TextureInfo {
+    /// A texture without mips
+    pub fn new(width: u32, height: u32, format: wgpu::TextureFormat) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            mip_level_count: 1,
+        }
+    }
+    pub fn bytes(&self) -> u64 {
+        texture_bytes(self.width, self.height, self.format, self.mip_level_count)
+    }
+    /// The same texture `factor` times smaller on each side
+    pub fn downscaled(&self, factor: u32) -> Self {
+        Self {
+            width: (self.width / factor).max(1),
+            height: (self.height / factor).max(1),
+            ..*self
+        }
+    }
+}
+
+/// Bytes taken by each resource of a manager, and their total
+#[derive(Debug, Clone)]
+pub struct MemoryLedger<K> {
+    sizes: HashMap<K, u64>,
+    total: u64,
+}
+
+impl<K: Copy + Eq + Hash> MemoryLedger<K> {
+    pub fn new() -> Self {
+        Self {
+            sizes: HashMap::new(),
+            total: 0,
+        }
+    }
+    /// Record the size of a resource, returns its previous size if it was replaced
+    pub fn insert(&mut self, key: K, bytes: u64) -> Option<u64> {
+        let previous = self.sizes.insert(key, bytes);
+        self.total = self.total - previous.unwrap_or(0) + bytes;
+        previous
+    }
+    pub fn remove(&mut self, key: K) -> Option<u64> {
+        let bytes = self.sizes.remove(&key)?;
+        self.total -= bytes;
+        Some(bytes)
+    }
+    /// Exchange the sizes of two resources, for managers swapping them between handles
+    pub fn swap(&mut self, a: K, b: K) {
+        let (sa, sb) = (self.sizes.remove(&a), self.sizes.remove(&b));
+        if let Some(bytes) = sa {
+            self.sizes.insert(b, bytes);
+        }
+        if let Some(bytes) = sb {
+            self.sizes.insert(a, bytes);
+        }
+    }
+    pub fn get(&self, key: K) -> Option<u64> {
+        self.sizes.get(&key).copied()
+    }
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+}
+
+impl<K: Copy + Eq + Hash> Default for MemoryLedger<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Residency {
+    /// The texture as it was loaded
+    Full,
+    /// A downscaled copy (see `TextureManager::LOW_RES_FACTOR`)
+    Low,
+}
+
+/// A streamable texture, as seen by the residency policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate<K> {
+    pub key: K,
+    pub residency: Residency,
+    pub full_bytes: u64,
+    pub low_bytes: u64,
+    /// Frame a material using the texture was last bound at
+    pub last_used: u64,
+    /// Frame the texture was loaded, demoted or promoted at
+    pub changed: u64,
+}
+
+impl<K> Candidate<K> {
+    /// Bytes freed by a demotion, or taken by a promotion
+    fn delta(&self) -> u64 {
+        self.full_bytes.saturating_sub(self.low_bytes)
+    }
+}
+
+/// Decides which textures to demote and promote. There are two thresholds: textures are demoted
+/// when the usage goes over the budget until it is under `low_water` times the budget, and only
+/// promoted if the usage stays under `low_water` after it. A texture that changed is left alone for
+/// `cooldown_frames`, together this keeps a texture from flipping every frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResidencyPolicy {
+    /// Fraction of the budget demotions go down to and promotions stay under
+    pub low_water: f32,
+    /// A demoted texture used in the last `recent_frames` frames is promoted when there is room
+    pub recent_frames: u64,
+    pub cooldown_frames: u64,
+    /// Changes per frame, each one reads and uploads an image
+    pub max_changes: usize,
+}
+
+impl Default for ResidencyPolicy {
+    fn default() -> Self {
+        Self {
+            low_water: 0.9,
+            recent_frames: 30,
+            cooldown_frames: 120,
+            max_changes: 4,
+        }
+    }
+}
+
+impl ResidencyPolicy {
+    /// The residency changes to make this frame, given the total usage in bytes
+    pub fn decide<K: Copy>(
+        &self,
+        usage: u64,
+        budget: u64,
+        frame: u64,
+        candidates: &[Candidate<K>],
+    ) -> Vec<(K, Residency)> {
+        let low_water = (budget as f64 * self.low_water as f64) as u64;
+        let settled = |c: &&Candidate<K>| frame.saturating_sub(c.changed) >= self.cooldown_frames;
+        let mut changes = Vec::new();
+        if usage > budget {
+            // Least recently used first
+            let mut full = candidates
+                .iter()
+                .filter(|c| c.residency == Residency::Full && c.delta() > 0)
+                .filter(settled)
+                .collect::<Vec<_>>();
+            full.sort_by_key(|c| c.last_used);
+            let mut usage = usage;
+            for c in full {
+                if usage <= low_water || changes.len() == self.max_changes {
+                    break;
+                }
+                usage -= c.delta();
+                changes.push((c.key, Residency::Low));
+            }
+        } else if usage <= low_water {
+            // Most recently used first
+            let mut low = candidates
+                .iter()
+                .filter(|c| c.residency == Residency::Low)
+                .filter(|c| frame.saturating_sub(c.last_used) <= self.recent_frames)
+                .filter(settled)
+                .collect::<Vec<_>>();
+            low.sort_by_key(|c| std::cmp::Reverse(c.last_used));
+            let mut usage = usage;
+            for c in low {
+                if changes.len() == self.max_changes {
+                    break;
+                }
+                if usage + c.delta() <= low_water {
+                    usage += c.delta();
+                    changes.push((c.key, Residency::Full));
+                }
+            }
+        }
+        changes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GpuMemorySettings {
+    /// Soft budget, in MiB: going over it demotes textures, nothing stops allocations
+    pub budget_mb: u32,
+    /// Demote and promote the streamable textures, the usage is tracked either way
+    pub residency: bool,
+    /// Fraction of the budget demotions go down to (see `ResidencyPolicy`)
+    pub low_water: f32,
+    /// Frames a texture is left alone after it changed
+    pub cooldown_frames: u32,
+}
+
+impl Default for GpuMemorySettings {
+    fn default() -> Self {
+        Self {
+            budget_mb: 1024,
+            residency: true,
+            low_water: 0.9,
+            cooldown_frames: 120,
+        }
+    }
+}
+
+impl GpuMemorySettings {
+    pub fn path() -> PathBuf {
+        rmanage::instance().directory().join("gpu_memory.json")
+    }
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        let file =
+            std::fs::File::open(path).with_context(|| format!("can't open {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("{} isn't a gpu memory settings file", path.display()))
+    }
+    pub fn save(&self, path: &std::path::Path) -> Result<()> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("can't create {}", path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+    pub fn budget(&self) -> u64 {
+        self.budget_mb as u64 * MIB
+    }
+    pub fn policy(&self) -> ResidencyPolicy {
+        ResidencyPolicy {
+            low_water: self.low_water,
+            cooldown_frames: self.cooldown_frames as u64,
+            ..Default::default()
+        }
+    }
+}
+
+impl Inspect for GpuMemorySettings {
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("budget_mb", self.budget_mb.to_string()),
+            ("residency", self.residency.to_string()),
+            ("low_water", self.low_water.to_string()),
+            ("cooldown_frames", self.cooldown_frames.to_string()),
+        ]
+    }
+    fn set_field(&mut self, field: &str, value: &Arg) -> Result<()> {
+        match field {
+            "budget_mb" => self.budget_mb = value.as_u32()?.max(1),
+            "residency" => self.residency = value.as_bool()?,
+            "low_water" => self.low_water = value.as_f32()?.clamp(0.1, 1.0),
+            "cooldown_frames" => self.cooldown_frames = value.as_u32()?,
+            _ => return Err(unknown_field(self, field)),
+        }
+        Ok(())
+    }
+}
+
+/// Residency of a streamable texture, for the stats panel
+#[derive(Debug, Clone)]
+pub struct ResidencyStat {
+    pub name: String,
+    pub residency: Residency,
+    pub bytes: u64,
+    /// Frames since a material using it was bound
+    pub idle_frames: u64,
+}
+
+/// Usage of the GPU memory by category, in bytes, updated by `GpuMemory::update`
+#[derive(Debug, Clone, Default)]
+pub struct GpuMemoryStats {
+    /// All the textures of the TextureManager, demoted ones at their low resolution size
+    pub textures: u64,
+    /// Vertex and index buffers of the MeshManager
+    pub meshes: u64,
+    /// Render targets of the WorldRenderer, by name
+    pub targets: Vec<(&'static str, u64)>,
+    pub budget: u64,
+    pub streamable: Vec<ResidencyStat>,
+    pub demotions: u64,
+    pub promotions: u64,
+}
+
+impl GpuMemoryStats {
+    pub fn targets_total(&self) -> u64 {
+        self.targets.iter().map(|(_, bytes)| bytes).sum()
+    }
+    pub fn total(&self) -> u64 {
+        self.textures + self.meshes + self.targets_total()
+    }
+    pub fn demoted(&self) -> usize {
+        self.streamable
+            .iter()
+            .filter(|stat| stat.residency == Residency::Low)
+            .count()
+    }
+}
+
+fn mib(bytes: u64) -> String {
+    format!("{:.1}", bytes as f64 / MIB as f64)
+}
+
+/// The memory budget and residency settings
+pub struct GpuMemory {
+    pub settings: GpuMemorySettings,
+}
+
+impl GpuMemory {
+    pub fn new(settings: GpuMemorySettings) -> Self {
+        Self { settings }
+    }
+    /// Load the settings saved in the instance directory, or use the defaults
+    pub fn load() -> Self {
+        let path = GpuMemorySettings::path();
+        let settings = if path.exists() {
+            GpuMemorySettings::load(&path).unwrap_or_else(|e| {
+                log::warn!("Couldn't load the gpu memory settings: {e:#}");
+                GpuMemorySettings::default()
+            })
+        } else {
+            GpuMemorySettings::default()
+        };
+        Self::new(settings)
+    }
+    /// System measuring the usage and demoting or promoting textures to stay under the budget
+    pub fn update(
+        &mut self,
+        stats: &mut GpuMemoryStats,
+        gfx: &mut GraphicContext,
+        wr: &WorldRenderer,
+    ) {
+        let frame = gfx.texture_manager.advance_frame();
+        let targets = wr.targets_memory();
+        let usage = |gfx: &GraphicContext| {
+            gfx.texture_manager.memory().total()
+                + gfx.mesh_manager.memory().total()
+                + targets.iter().map(|(_, bytes)| bytes).sum::<u64>()
+        };
+        let budget = self.settings.budget();
+        if self.settings.residency {
+            let candidates = gfx.texture_manager.residency_candidates();
+            let changes = self
+                .settings
+                .policy()
+                .decide(usage(gfx), budget, frame, &candidates);
+            for (tex, residency) in changes {
+                let GraphicContext {
+                    device,
+                    queue,
+                    texture_manager,
+                    ..
+                } = gfx;
+                match texture_manager.set_residency(device, queue, tex, residency) {
+                    Ok(()) if residency == Residency::Low => stats.demotions += 1,
+                    Ok(()) => stats.promotions += 1,
+                    Err(e) => log::warn!("Couldn't change the residency of a texture: {e:#}"),
+                }
+            }
+        }
+        stats.textures = gfx.texture_manager.memory().total();
+        stats.meshes = gfx.mesh_manager.memory().total();
+        stats.targets = targets;
+        stats.budget = budget;
+        stats.streamable = gfx
+            .texture_manager
+            .streamable()
+            .map(|(tex, streamable)| ResidencyStat {
+                name: streamable
+                    .path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                residency: streamable.residency,
+                bytes: gfx.texture_manager.memory().get(tex).unwrap_or(0),
+                idle_frames: frame.saturating_sub(gfx.texture_manager.last_used(tex)),
+            })
+            .collect();
+    }
+    pub(super) fn ui(
+        &mut self,
+        ctx: &egui::Context,
+        focus: &mut UiFocus,
+        loc: &mut Localization,
+        stats: &GpuMemoryStats,
+    ) {
+        let s = &mut self.settings;
+        focus.begin_panel("gpu_memory");
+        egui::Window::new(tr!(loc, "memory.window")).show(ctx, |ui| {
+            ui.label(tr!(
+                loc,
+                "memory.total",
+                used = mib(stats.total()),
+                budget = mib(stats.budget),
+            ));
+            ui.add(egui::ProgressBar::new(
+                (stats.total() as f64 / stats.budget.max(1) as f64) as f32,
+            ));
+            ui.label(tr!(loc, "memory.textures", size = mib(stats.textures)));
+            ui.label(tr!(loc, "memory.meshes", size = mib(stats.meshes)));
+            ui.label(tr!(
+                loc,
+                "memory.targets",
+                size = mib(stats.targets_total())
+            ));
+            for (name, bytes) in &stats.targets {
+                ui.label(tr!(loc, "memory.target", name = name, size = mib(*bytes)));
+            }
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.budget_mb, 64..=16384)
+                        .logarithmic(true)
+                        .text(tr!(loc, "memory.budget")),
+                ),
+            );
+            focus.track(ui.checkbox(&mut s.residency, tr!(loc, "memory.residency")));
+            ui.label(tr!(
+                loc,
+                "memory.residency_stats",
+                demoted = stats.demoted(),
+                streamable = stats.streamable.len(),
+                demotions = stats.demotions,
+                promotions = stats.promotions,
+            ));
+            egui::CollapsingHeader::new(tr!(loc, "memory.streamable")).show(ui, |ui| {
+                egui::ScrollArea::vertical()
+                    .max_height(200.0)
+                    .show(ui, |ui| {
+                        for stat in &stats.streamable {
+                            let residency = match stat.residency {
+                                Residency::Full => tr!(loc, "memory.full"),
+                                Residency::Low => tr!(loc, "memory.low"),
+                            };
+                            ui.label(tr!(
+                                loc,
+                                "memory.texture",
+                                name = stat.name,
+                                residency = residency,
+                                size = mib(stat.bytes),
+                                frames = stat.idle_frames,
+                            ));
+                        }
+                    });
+            });
+            if focus.track(ui.button(tr!(loc, "memory.save"))).clicked() {
+                let path = GpuMemorySettings::path();
+                match s.save(&path) {
+                    Ok(()) => log::info!("Saved the gpu memory settings to {}", path.display()),
+                    Err(e) => log::error!("Couldn't save the gpu memory settings: {e:#}"),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::graphics::{
+        mesh_manager::{mesh_bytes, Mesh, MeshManager, Primitives, Vertex},
+        test_device::test_device,
+        texture_manager::TextureManager,
+    };
+
+    fn candidate(key: usize, last_used: u64) -> Candidate<usize> {
+        Candidate {
+            key,
+            residency: Residency::Full,
+            full_bytes: 100,
+            low_bytes: 10,
+            last_used,
+            changed: 0,
+        }
+    }
+
+    /// Streamable textures and the rest of the usage, applying the decisions of a policy
+    struct Scene {
+        policy: ResidencyPolicy,
+        candidates: Vec<Candidate<usize>>,
+        other: u64,
+        budget: u64,
+        frame: u64,
+        changes: usize,
+    }
+
+    impl Scene {
+        fn new(textures: usize, other: u64, budget: u64) -> Self {
+            Self {
+                policy: ResidencyPolicy {
+                    cooldown_frames: 10,
+                    recent_frames: 5,
+                    ..Default::default()
+                },
+                candidates: (0..textures).map(|key| candidate(key, 0)).collect(),
+                other,
+                budget,
+                frame: 0,
+                changes: 0,
+            }
+        }
+        fn usage(&self) -> u64 {
+            self.other
+                + self
+                    .candidates
+                    .iter()
+                    .map(|c| match c.residency {
+                        Residency::Full => c.full_bytes,
+                        Residency::Low => c.low_bytes,
+                    })
+                    .sum::<u64>()
+        }
+        /// Use some textures then run the policy, returns the changes
+        fn step(&mut self, used: &[usize]) -> Vec<(usize, Residency)> {
+            self.frame += 1;
+            for &key in used {
+                self.candidates[key].last_used = self.frame;
+            }
+            let changes =
+                self.policy
+                    .decide(self.usage(), self.budget, self.frame, &self.candidates);
+            for &(key, residency) in &changes {
+                self.candidates[key].residency = residency;
+                self.candidates[key].changed = self.frame;
+            }
+            self.changes += changes.len();
+            changes
+        }
+        fn residency(&self, key: usize) -> Residency {
+            self.candidates[key].residency
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        use wgpu::TextureFormat::*;
+        assert_eq!(4 * 256 * 256, texture_bytes(256, 256, Rgba8UnormSrgb, 1));
+        assert_eq!(8 * 3 * 5, texture_bytes(3, 5, Rgba16Float, 1));
+        assert_eq!(
+            64 * 32 + 32 * 16 + 16 * 8,
+            texture_bytes(64, 32, R8Unorm, 3)
+        );
+        // Down to 1x1, the smaller side stops at 1
+        assert_eq!(4 * (8 * 2 + 4 + 2 + 1), texture_bytes(8, 2, R32Float, 4));
+        // Compressed formats go by 4x4 blocks of 8 bytes
+        assert_eq!(8 * 2 * 2, texture_bytes(5, 8, Bc1RgbaUnorm, 1));
+        let info = TextureInfo::new(1024, 512, Rgba8Unorm);
+        assert_eq!(info.bytes(), 16 * info.downscaled(4).bytes());
+        assert_eq!((1, 1), {
+            let low = TextureInfo::new(2, 3, Rgba8Unorm).downscaled(4);
+            (low.width, low.height)
+        });
+    }
+
+    #[test]
+    fn ledger() {
+        let mut ledger = MemoryLedger::new();
+        assert_eq!(None, ledger.insert(1, 100));
+        assert_eq!(None, ledger.insert(2, 50));
+        assert_eq!(150, ledger.total());
+        // Replaced, the old size goes away
+        assert_eq!(Some(100), ledger.insert(1, 25));
+        assert_eq!(75, ledger.total());
+        ledger.swap(1, 2);
+        assert_eq!((Some(50), Some(25)), (ledger.get(1), ledger.get(2)));
+        ledger.swap(2, 3);
+        assert_eq!((None, Some(25)), (ledger.get(2), ledger.get(3)));
+        assert_eq!(75, ledger.total());
+        assert_eq!(Some(50), ledger.remove(1));
+        assert_eq!(None, ledger.remove(1));
+        assert_eq!(25, ledger.total());
+        assert_eq!(Some(25), ledger.remove(3));
+        assert!(ledger.is_empty());
+        assert_eq!(0, ledger.total());
+    }
+
+    #[test]
+    fn mesh_sizes() {
+        let vertex = std::mem::size_of::<Vertex>() as u64;
+        assert_eq!(
+            24 * vertex + 36 * 2,
+            mesh_bytes(24, 36, wgpu::IndexFormat::Uint16)
+        );
+        // Odd u16 counts are padded to 4 bytes
+        assert_eq!(3 * vertex + 8, mesh_bytes(3, 3, wgpu::IndexFormat::Uint16));
+        assert_eq!(3 * vertex + 12, mesh_bytes(3, 3, wgpu::IndexFormat::Uint32));
+    }
+
+    #[test]
+    #[ignore = "needs an adapter"]
+    fn managers() {
+        let (device, queue, _) = test_device(wgpu::Features::empty());
+        let mut meshes = MeshManager::new();
+        let cube = Mesh::new_cube();
+        let sphere = Mesh::new_icosphere(2);
+        let cube_bytes = mesh_bytes(24, 36, wgpu::IndexFormat::Uint16);
+        let sphere_bytes = mesh_bytes(
+            sphere.vertices.len(),
+            sphere.indices.len() * 3,
+            wgpu::IndexFormat::Uint16,
+        );
+        let a = meshes.add(&device, &cube);
+        let b = meshes.add(&device, &cube);
+        assert_eq!(2 * cube_bytes, meshes.memory().total());
+        meshes.update(a, &device, &sphere).unwrap();
+        assert_eq!(cube_bytes + sphere_bytes, meshes.memory().total());
+        assert_eq!(Some(sphere_bytes), meshes.get(a).map(|mesh| mesh.bytes));
+        meshes.remove(b);
+        assert_eq!(sphere_bytes, meshes.memory().total());
+        meshes.remove(a);
+        assert_eq!(0, meshes.memory().total());
+
+        // Identical meshes share their buffers, freed with the last reference
+        let a = meshes.add_dedup(&device, &cube);
+        let b = meshes.add_dedup(&device, &Mesh::new_cube());
+        assert_eq!(a, b);
+        assert_eq!(cube_bytes, meshes.memory().total());
+        assert_eq!(vec![(a, 2)], meshes.live_handles().collect::<Vec<_>>());
+        assert!(!meshes.release(a));
+        assert!(meshes.get(a).is_some());
+        assert!(meshes.release(a));
+        assert!(meshes.get(a).is_none());
+        assert!(!meshes.retain(a));
+        assert_eq!(0, meshes.memory().total());
+        // Not shared with the freed one
+        let c = meshes.add_dedup(&device, &cube);
+        assert_ne!(a, c);
+        assert!(meshes.retain(c));
+        assert!(meshes.remove(c).is_none());
+        assert!(meshes.remove(c).is_some());
+        assert_eq!(0, meshes.live_handles().count());
+
+        let mut textures = TextureManager::new();
+        let image =
+            |width, height| image::DynamicImage::ImageRgba8(image::RgbaImage::new(width, height));
+        let small = textures.add_image_texture(&device, &queue, image(16, 16));
+        let large = textures.add_image_texture(&device, &queue, image(64, 32));
+        assert_eq!(4 * (16 * 16 + 64 * 32), textures.memory().total());
+        textures.swap(small, large).unwrap();
+        assert_eq!(Some(4 * 64 * 32), textures.memory().get(small));
+        let info = TextureInfo::new(8, 8, wgpu::TextureFormat::R32Float);
+        let tex = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 8,
+                height: 8,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: info.format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        textures.replace_texture(small, tex, info).unwrap();
+        assert_eq!(4 * (8 * 8 + 16 * 16), textures.memory().total());
+        textures.remove_texture(large).unwrap();
+        assert_eq!(4 * 8 * 8, textures.memory().total());
+
+        // A streamable texture goes down to a 16th and back
+        let path = std::env::temp_dir().join(format!("sg-streamable-{}.png", std::process::id()));
+        image::RgbaImage::new(128, 64).save(&path).unwrap();
+        let streamed = textures
+            .add_streamable_texture(&device, &queue, &path, true)
+            .unwrap();
+        assert_eq!(4 * (8 * 8 + 128 * 64), textures.memory().total());
+        let candidates = textures.residency_candidates();
+        assert_eq!(1, candidates.len());
+        assert_eq!(4 * 32 * 16, candidates[0].low_bytes);
+        textures
+            .set_residency(&device, &queue, streamed, Residency::Low)
+            .unwrap();
+        assert_eq!(4 * (8 * 8 + 32 * 16), textures.memory().total());
+        textures
+            .set_residency(&device, &queue, streamed, Residency::Full)
+            .unwrap();
+        assert_eq!(4 * (8 * 8 + 128 * 64), textures.memory().total());
+        std::fs::remove_file(&path).unwrap();
+        textures.remove_texture(streamed).unwrap();
+        assert_eq!(0, textures.streamable().count());
+        assert_eq!(4 * 8 * 8, textures.memory().total());
+    }
+
+    #[test]
+    fn demotes_least_recently_used() {
+        // 500 bytes of textures, budget of 400: demoting two (90 each) gets under 360
+        let mut scene = Scene::new(5, 0, 400);
+        scene.frame = 200;
+        scene.candidates[2].last_used = 150;
+        scene.candidates[4].last_used = 120;
+        let changes = scene.step(&[0, 1, 3]);
+        assert_eq!(vec![(4, Residency::Low), (2, Residency::Low)], changes);
+        assert!(scene.usage() <= 360);
+        // Under budget, nothing more to do
+        assert!(scene.step(&[]).is_empty());
+
+        // Frames with nothing to demote (all recently changed) do nothing
+        let mut scene = Scene::new(3, 0, 100);
+        scene.frame = 1;
+        for c in &mut scene.candidates {
+            c.changed = 1;
+        }
+        assert!(scene.step(&[]).is_empty());
+    }
+
+    #[test]
+    fn max_changes() {
+        let mut scene = Scene::new(20, 0, 100);
+        scene.frame = 100;
+        assert_eq!(4, scene.step(&[]).len());
+        assert_eq!(4, scene.step(&[]).len());
+        for _ in 0..10 {
+            scene.step(&[]);
+        }
+        // Still over, but there is nothing left to demote
+        assert_eq!(20, scene.changes);
+        assert_eq!(200, scene.usage());
+    }
+
+    #[test]
+    fn hysteresis() {
+        // 3 textures over a budget of 250, the one demoted stays in use
+        let mut scene = Scene::new(3, 0, 250);
+        scene.frame = 100;
+        let mut demoted = None;
+        for _ in 0..500 {
+            let changes = scene.step(&[0, 1, 2]);
+            if let Some(&(key, _)) = changes.first() {
+                assert!(demoted.is_none(), "changed twice");
+                demoted = Some(key);
+            }
+        }
+        // Promoting it back would go over the low water mark
+        assert_eq!(1, scene.changes);
+        assert_eq!(210, scene.usage());
+
+        // Usage hovering around the budget because of something else
+        let mut scene = Scene::new(10, 0, 1000);
+        scene.frame = 100;
+        for i in 0..1000 {
+            scene.other = if i % 2 == 0 { 60 } else { 120 };
+            scene.step(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        }
+        // Two demotions, then the usage stays between the thresholds
+        assert_eq!(2, scene.changes);
+
+        // The cooldown paces the changes of a texture
+        let mut scene = Scene::new(1, 0, 1000);
+        scene.candidates[0].residency = Residency::Low;
+        scene.frame = 100;
+        scene.candidates[0].changed = 95;
+        assert!(scene.step(&[0]).is_empty());
+        scene.frame = 104;
+        assert_eq!(vec![(0, Residency::Full)], scene.step(&[0]));
+    }
+
+    #[test]
+    fn promotes_on_reuse() {
+        let mut scene = Scene::new(4, 300, 600);
+        scene.frame = 100;
+        // 700 > 600, demote the two oldest to get under 540
+        scene.candidates[2].last_used = 99;
+        scene.candidates[3].last_used = 99;
+        let changes = scene.step(&[]);
+        assert_eq!(vec![(0, Residency::Low), (1, Residency::Low)], changes);
+        assert_eq!(520, scene.usage());
+
+        // Something else goes away, but the demoted textures aren't used
+        scene.other = 0;
+        for _ in 0..50 {
+            assert!(scene.step(&[2, 3]).is_empty());
+        }
+        // Texture 1 is used again, it comes back
+        assert_eq!(vec![(1, Residency::Full)], scene.step(&[1, 2, 3]));
+        assert_eq!(Residency::Low, scene.residency(0));
+        // Used a while ago, not anymore
+        scene.frame += 10;
+        assert!(scene.step(&[2]).is_empty());
+        assert_eq!(Residency::Low, scene.residency(0));
+        // No room for a promotion: still low
+        scene.other = 250;
+        assert!(scene.step(&[0]).is_empty());
+        scene.other = 0;
+        assert_eq!(vec![(0, Residency::Full)], scene.step(&[0]));
+    }
+}
@@ -0,0 +1,1166 @@
+//! Mesh import from the common formats (gltf, OBJ and PLY) behind a single entry point.
+//!
+//! OBJ and PLY files are parsed into `ImportedMesh`es without touching the gpu, then go through
+//! the same post-processing (normals when missing, tangents, bounds and index width) before being
+//! uploaded. Anything odd about a file that doesn't prevent its import ends up as a warning in the
+//! `ImportReport` instead of an error. Gltf files go to the gltf loader, which has its own path.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use glam::{Vec2, Vec3, Vec4};
+use rmanage::{Resource, ResourceBytes};
+
+use crate::components::{GraphicsComponent, TransformsComponent};
+
+use super::{
+    gltf,
+    mesh_manager::{self, BoundingBox, Mesh, Vertex},
+    texture_manager::{SingleValue, TextureHandle},
+    GraphicContext, Material,
+};
+
+/// Where to load a mesh from
+#[derive(Debug, Clone, Copy)]
+pub enum Source<'a> {
+    Path(&'a Path),
+    /// A resource of the global ResourceManager. Files referenced by virtual resources (OBJ
+    /// material libraries, textures) can't be found, the format is guessed from the content.
+    Resource(Resource),
+}
+
+impl<'a> From<&'a Path> for Source<'a> {
+    fn from(path: &'a Path) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl<'a> From<&'a PathBuf> for Source<'a> {
+    fn from(path: &'a PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl<'a> From<&'a str> for Source<'a> {
+    fn from(path: &'a str) -> Self {
+        Self::Path(Path::new(path))
+    }
+}
+
+impl From<Resource> for Source<'static> {
+    fn from(res: Resource) -> Self {
+        Self::Resource(res)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gltf,
+    Obj,
+    Ply,
+}
+
+/// Statements an OBJ file can start with, used to recognize one without its extension
+const OBJ_STATEMENTS: &[&str] = &["v", "vt", "vn", "f", "o", "g", "s", "mtllib", "usemtl"];
+
+impl Format {
+    /// Detect the format from the extension of the path, or from the content of the file
+    pub fn detect(path: Option<&Path>, bytes: &[u8]) -> Option<Self> {
+        path.and_then(Path::extension)
+            .and_then(|ext| ext.to_str())
+            .and_then(Self::from_extension)
+            .or_else(|| Self::from_magic(bytes))
+    }
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_ascii_lowercase().as_str() {
+            "gltf" | "glb" => Some(Self::Gltf),
+            "obj" => Some(Self::Obj),
+            "ply" => Some(Self::Ply),
+            _ => None,
+        }
+    }
+    pub fn from_magic(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"glTF") {
+            return Some(Self::Gltf);
+        }
+        if bytes.starts_with(b"ply\n") || bytes.starts_with(b"ply\r\n") {
+            return Some(Self::Ply);
+        }
+        // Text formats, only the start is needed
+        let start = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]);
+        if start.trim_start().starts_with('{') {
+            return Some(Self::Gltf);
+        }
+        let statement = start
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))?
+            .split_whitespace()
+            .next()?;
+        OBJ_STATEMENTS.contains(&statement).then_some(Self::Obj)
+    }
+}
+
+/// Something wrong with an imported file that didn't prevent its import
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportWarning {
+    /// The mesh doesn't have texture coordinates, they are all 0
+    MissingUvs { mesh: String },
+    /// The mesh doesn't have normals for all its vertices, they were recomputed
+    MissingNormals { mesh: String },
+    /// Edges shared by more than two triangles, the mesh probably isn't manifold
+    NonManifold { mesh: String, edges: usize },
+    /// Faces with less than 3 vertices, they were skipped
+    DegenerateFaces { mesh: String, count: usize },
+    /// A material used by a mesh isn't defined, the default material is used instead
+    MissingMaterial { name: String },
+    /// A file referenced by the imported one (material library, texture) couldn't be loaded
+    MissingFile { path: PathBuf, reason: String },
+    /// A statement that isn't supported and was ignored (only reported once per statement)
+    Unsupported { statement: String, line: usize },
+}
+
+impl fmt::Display for ImportWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingUvs { mesh } => write!(f, "mesh '{mesh}' has no texture coordinates"),
+            Self::MissingNormals { mesh } => {
+                write!(f, "mesh '{mesh}' is missing normals, they were recomputed")
+            }
+            Self::NonManifold { mesh, edges } => write!(
+                f,
+                "mesh '{mesh}' has {edges} edges shared by more than two triangles (non manifold)"
+            ),
+            Self::DegenerateFaces { mesh, count } => {
+                write!(
+                    f,
+                    "mesh '{mesh}' has {count} faces with less than 3 vertices"
+                )
+            }
+            Self::MissingMaterial { name } => write!(f, "material '{name}' isn't defined"),
+            Self::MissingFile { path, reason } => {
+                write!(f, "couldn't load '{}': {reason}", path.display())
+            }
+            Self::Unsupported { statement, line } => {
+                write!(
+                    f,
+                    "line {line}: unsupported statement '{statement}' ignored"
+                )
+            }
+        }
+    }
+}
+
+/// What was imported, the meshes are only listed for OBJ and PLY files
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    pub format: Option<Format>,
+    pub meshes: Vec<MeshInfo>,
+    pub warnings: Vec<ImportWarning>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MeshInfo {
+    pub name: String,
+    pub vertices: usize,
+    pub triangles: usize,
+    pub bounds: BoundingBox,
+    pub index_format: wgpu::IndexFormat,
+}
+
+impl ImportReport {
+    fn warn(&mut self, warning: ImportWarning) {
+        self.warnings.push(warning);
+    }
+    /// Warn about an unsupported statement, unless it already was
+    fn unsupported(&mut self, statement: &str, line: usize) {
+        let reported = self.warnings.iter().any(
+            |w| matches!(w, ImportWarning::Unsupported { statement: s, .. } if s == statement),
+        );
+        if !reported {
+            self.warn(ImportWarning::Unsupported {
+                statement: statement.to_owned(),
+                line,
+            });
+        }
+    }
+}
+
+/// A mesh parsed from a file, not yet on the gpu
+pub struct ImportedMesh {
+    pub name: String,
+    pub mesh: Mesh,
+    /// Name of the material (OBJ only)
+    pub material: Option<String>,
+    /// Whether every vertex had a normal
+    pub normals: bool,
+    /// Whether every vertex had texture coordinates
+    pub uvs: bool,
+}
+
+/// An OBJ material, mapped to the inputs of `Material`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaterialDesc {
+    pub name: String,
+    /// Kd, with d as alpha. Ignored when there is an albedo map as it can't be multiplied in.
+    pub albedo: Vec4,
+    /// map_Kd
+    pub albedo_map: Option<PathBuf>,
+    /// bump / map_Bump / norm, taken as a normal map since that is what exporters put there
+    pub normal_map: Option<PathBuf>,
+    /// Pm (PBR extension), 0 otherwise
+    pub metallic: f32,
+    /// Pr (PBR extension), otherwise approximated from the specular exponent Ns
+    pub roughness: f32,
+}
+
+impl Default for MaterialDesc {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            albedo: Vec4::ONE,
+            albedo_map: None,
+            normal_map: None,
+            metallic: 0.0,
+            roughness: 1.0,
+        }
+    }
+}
+
+/// Roughness of the Blinn-Phong specular exponent `ns`: the Beckmann slope sqrt(2 / (ns + 2)),
+/// square rooted because the shader squares roughness.
+fn roughness_from_exponent(ns: f32) -> f32 {
+    (2.0 / (ns.max(0.0) + 2.0)).sqrt().sqrt()
+}
+
+fn floats<const N: usize>(
+    args: std::str::SplitWhitespace,
+    required: usize,
+    line: usize,
+) -> Result<[f32; N]> {
+    let mut values = [0.0; N];
+    let mut count = 0;
+    for (value, arg) in values.iter_mut().zip(args) {
+        *value = arg
+            .parse()
+            .with_context(|| format!("line {line}: invalid number '{arg}'"))?;
+        count += 1;
+    }
+    if count < required {
+        bail!("line {line}: expected {required} numbers, got {count}");
+    }
+    Ok(values)
+}
+
+/// Resolve an OBJ index (1 based, or relative to the end when negative) into `len` elements
+fn resolve(index: &str, len: usize, line: usize) -> Result<usize> {
+    let i: i64 = index
+        .parse()
+        .with_context(|| format!("line {line}: invalid index '{index}'"))?;
+    let resolved = match i {
+        0 => bail!("line {line}: indices start at 1"),
+        i if i > 0 => i - 1,
+        i => len as i64 + i,
+    };
+    if resolved < 0 || resolved >= len as i64 {
+        bail!("line {line}: index {i} out of range ({len} elements)");
+    }
+    Ok(resolved as usize)
+}
+
+/// A mesh being built from an OBJ file
+struct ObjGroup {
+    name: String,
+    material: Option<String>,
+    mesh: Mesh,
+    /// Vertex of each (position, uv, normal) combination
+    vertices: HashMap<(usize, Option<usize>, Option<usize>), u32>,
+    normals: bool,
+    uvs: bool,
+    degenerate: usize,
+}
+
+impl ObjGroup {
+    fn new(name: String, material: Option<String>) -> Self {
+        Self {
+            name,
+            material,
+            mesh: Mesh {
+                vertices: Vec::new(),
+                indices: Vec::new(),
+            },
+            vertices: HashMap::new(),
+            normals: true,
+            uvs: true,
+            degenerate: 0,
+        }
+    }
+    fn finish(self, meshes: &mut Vec<ImportedMesh>, report: &mut ImportReport) {
+        if self.degenerate > 0 {
+            report.warn(ImportWarning::DegenerateFaces {
+                mesh: self.name.clone(),
+                count: self.degenerate,
+            });
+        }
+        if !self.mesh.indices.is_empty() {
+            meshes.push(ImportedMesh {
+                name: self.name,
+                mesh: self.mesh,
+                material: self.material,
+                normals: self.normals,
+                uvs: self.uvs,
+            });
+        }
+    }
+}
+
+/// Parse an OBJ file into one mesh per object and material. Also returns the material libraries
+/// it uses.
+pub fn parse_obj(
+    source: &str,
+    report: &mut ImportReport,
+) -> Result<(Vec<ImportedMesh>, Vec<String>)> {
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut libraries = Vec::new();
+    let mut meshes = Vec::new();
+    let mut group = ObjGroup::new("default".to_owned(), None);
+    for (number, line) in source.lines().enumerate() {
+        let number = number + 1;
+        let mut args = line.split_whitespace();
+        let statement = match args.next() {
+            Some(statement) if !statement.starts_with('#') => statement,
+            _ => continue,
+        };
+        match statement {
+            "v" => positions.push(Vec3::from(floats::<3>(args, 3, number)?)),
+            "vn" => normals.push(Vec3::from(floats::<3>(args, 3, number)?)),
+            "vt" => {
+                let [u, v] = floats::<2>(args, 1, number)?;
+                // OBJ has the origin of textures at the bottom left
+                uvs.push(Vec2::new(u, 1.0 - v));
+            }
+            "f" => {
+                let mut face = Vec::new();
+                for vertex in args {
+                    let mut indices = vertex.split('/');
+                    let position = resolve(indices.next().unwrap_or(""), positions.len(), number)?;
+                    let uv = match indices.next() {
+                        Some(i) if !i.is_empty() => Some(resolve(i, uvs.len(), number)?),
+                        _ => None,
+                    };
+                    let normal = match indices.next() {
+                        Some(i) if !i.is_empty() => Some(resolve(i, normals.len(), number)?),
+                        _ => None,
+                    };
+                    group.uvs &= uv.is_some();
+                    group.normals &= normal.is_some();
+                    let mesh = &mut group.mesh;
+                    let index =
+                        *group
+                            .vertices
+                            .entry((position, uv, normal))
+                            .or_insert_with(|| {
+                                mesh.vertices.push(Vertex {
+                                    position: positions[position],
+                                    normal: normal.map(|i| normals[i]).unwrap_or(Vec3::ZERO),
+                                    tex_coords: uv.map(|i| uvs[i]).unwrap_or(Vec2::ZERO),
+                                    tangent: Vec3::ONE,
+                                });
+                                mesh.vertices.len() as u32 - 1
+                            });
+                    face.push(index);
+                }
+                if face.len() < 3 {
+                    group.degenerate += 1;
+                    continue;
+                }
+                // Fan triangulation, swapping to invert the winding (like the gltf loader)
+                for i in 1..face.len() - 1 {
+                    group.mesh.indices.push([face[0], face[i + 1], face[i]]);
+                }
+            }
+            "o" | "usemtl" => {
+                let arg = args.collect::<Vec<_>>().join(" ");
+                let (name, material) = if statement == "o" {
+                    (arg, group.material.clone())
+                } else {
+                    (group.name.clone(), Some(arg))
+                };
+                let previous = std::mem::replace(&mut group, ObjGroup::new(name, material));
+                previous.finish(&mut meshes, report);
+            }
+            "mtllib" => libraries.extend(args.map(str::to_owned)),
+            // Smoothing and groups don't change anything here
+            "s" | "g" => {}
+            _ => report.unsupported(statement, number),
+        }
+    }
+    group.finish(&mut meshes, report);
+    Ok((meshes, libraries))
+}
+
+/// Parse an MTL file. Texture paths are left as written (relative to the file).
+pub fn parse_mtl(source: &str, report: &mut ImportReport) -> Result<Vec<MaterialDesc>> {
+    let mut materials: Vec<MaterialDesc> = Vec::new();
+    // Pr takes precedence over Ns whatever the order
+    let mut explicit_roughness = false;
+    for (number, line) in source.lines().enumerate() {
+        let number = number + 1;
+        let mut args = line.split_whitespace();
+        let statement = match args.next() {
+            Some(statement) if !statement.starts_with('#') => statement,
+            _ => continue,
+        };
+        if statement == "newmtl" {
+            materials.push(MaterialDesc {
+                name: args.collect::<Vec<_>>().join(" "),
+                ..Default::default()
+            });
+            explicit_roughness = false;
+            continue;
+        }
+        let material = materials
+            .last_mut()
+            .with_context(|| format!("line {number}: '{statement}' before any newmtl"))?;
+        // Texture statements can have options before the file name
+        let texture = |args: std::str::SplitWhitespace| {
+            args.last()
+                .map(PathBuf::from)
+                .with_context(|| format!("line {number}: missing texture file"))
+        };
+        match statement {
+            "Kd" => {
+                let [r, g, b] = floats::<3>(args, 3, number)?;
+                material.albedo = Vec4::new(r, g, b, material.albedo.w);
+            }
+            "d" => material.albedo.w = floats::<1>(args, 1, number)?[0],
+            "Tr" => material.albedo.w = 1.0 - floats::<1>(args, 1, number)?[0],
+            "Ns" if !explicit_roughness => {
+                material.roughness = roughness_from_exponent(floats::<1>(args, 1, number)?[0])
+            }
+            "Pr" => {
+                material.roughness = floats::<1>(args, 1, number)?[0];
+                explicit_roughness = true;
+            }
+            "Pm" => material.metallic = floats::<1>(args, 1, number)?[0],
+            "map_Kd" => material.albedo_map = Some(texture(args)?),
+            "bump" | "map_Bump" | "map_bump" | "norm" => material.normal_map = Some(texture(args)?),
+            // Phong terms with no equivalent
+            "Ns" | "Ka" | "Ks" | "Ke" | "Ni" | "illum" | "Tf" => {}
+            _ => report.unsupported(statement, number),
+        }
+    }
+    Ok(materials)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return None,
+        })
+    }
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+}
+
+enum Property {
+    Scalar(Scalar),
+    /// Count type, item type
+    List(Scalar, Scalar),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<(String, Property)>,
+}
+
+/// Values of the body of a PLY file, in order
+enum PlyValues<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    BinaryLe(&'a [u8]),
+}
+
+impl<'a> PlyValues<'a> {
+    fn next(&mut self, scalar: Scalar) -> Result<f64> {
+        match self {
+            Self::Ascii(values) => {
+                let value = values.next().context("Unexpected end of PLY data")?;
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid PLY value '{value}'"))
+            }
+            Self::BinaryLe(bytes) => {
+                if bytes.len() < scalar.size() {
+                    bail!("Unexpected end of PLY data");
+                }
+                let (value, rest) = bytes.split_at(scalar.size());
+                *bytes = rest;
+                Ok(match scalar {
+                    Scalar::I8 => value[0] as i8 as f64,
+                    Scalar::U8 => value[0] as f64,
+                    Scalar::I16 => i16::from_le_bytes([value[0], value[1]]) as f64,
+                    Scalar::U16 => u16::from_le_bytes([value[0], value[1]]) as f64,
+                    Scalar::I32 => i32::from_le_bytes(value.try_into().unwrap()) as f64,
+                    Scalar::U32 => u32::from_le_bytes(value.try_into().unwrap()) as f64,
+                    Scalar::F32 => f32::from_le_bytes(value.try_into().unwrap()) as f64,
+                    Scalar::F64 => f64::from_le_bytes(value.try_into().unwrap()),
+                })
+            }
+        }
+    }
+    /// Values of a property (a single one for scalar properties)
+    fn property(&mut self, property: &Property) -> Result<Vec<f64>> {
+        match *property {
+            Property::Scalar(scalar) => Ok(vec![self.next(scalar)?]),
+            Property::List(count, item) => {
+                let count = self.next(count)? as usize;
+                (0..count).map(|_| self.next(item)).collect()
+            }
+        }
+    }
+}
+
+/// Parse a PLY file (ascii or binary little endian) into a single mesh. Only the vertex and face
+/// elements are used, vertices can have x/y/z, nx/ny/nz and s/t (or u/v) properties.
+pub fn parse_ply(bytes: &[u8], report: &mut ImportReport) -> Result<Vec<ImportedMesh>> {
+    const END: &[u8] = b"end_header";
+    let end = bytes
+        .windows(END.len())
+        .position(|w| w == END)
+        .context("PLY header has no end")?;
+    let body_start = bytes[end..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map(|i| end + i + 1)
+        .unwrap_or(bytes.len());
+    let header = std::str::from_utf8(&bytes[..end]).context("PLY header isn't UTF-8")?;
+
+    let mut lines = header.lines().map(str::trim);
+    if lines.next() != Some("ply") {
+        bail!("Not a PLY file");
+    }
+    let mut binary = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        match args.as_slice() {
+            ["format", "ascii", _] => binary = Some(false),
+            ["format", "binary_little_endian", _] => binary = Some(true),
+            ["format", format, _] => bail!("Unsupported PLY format '{format}'"),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .with_context(|| format!("Invalid PLY element count '{count}'"))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => {
+                let property = match (Scalar::parse(count), Scalar::parse(item)) {
+                    (Some(count), Some(item)) => Property::List(count, item),
+                    _ => bail!("Invalid PLY list property '{line}'"),
+                };
+                elements
+                    .last_mut()
+                    .context("PLY property outside of an element")?
+                    .properties
+                    .push((name.to_string(), property));
+            }
+            ["property", scalar, name] => {
+                let scalar = Scalar::parse(scalar)
+                    .with_context(|| format!("Invalid PLY property '{line}'"))?;
+                elements
+                    .last_mut()
+                    .context("PLY property outside of an element")?
+                    .properties
+                    .push((name.to_string(), Property::Scalar(scalar)));
+            }
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => bail!("Invalid PLY header line '{line}'"),
+        }
+    }
+    let body = &bytes[body_start..];
+    let mut values = match binary.context("PLY header has no format")? {
+        true => PlyValues::BinaryLe(body),
+        false => PlyValues::Ascii(
+            std::str::from_utf8(body)
+                .context("PLY data isn't UTF-8")?
+                .split_ascii_whitespace(),
+        ),
+    };
+
+    let mut mesh = ImportedMesh {
+        name: "mesh".to_owned(),
+        mesh: Mesh {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+        },
+        material: None,
+        normals: false,
+        uvs: false,
+    };
+    let mut degenerate = 0;
+    let mut faces = Vec::new();
+    for element in &elements {
+        let find = |names: &[&str]| {
+            element
+                .properties
+                .iter()
+                .position(|(name, _)| names.contains(&name.as_str()))
+        };
+        match element.name.as_str() {
+            "vertex" => {
+                let position = [find(&["x"]), find(&["y"]), find(&["z"])];
+                let normal = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+                let uv = [
+                    find(&["s", "u", "texture_u"]),
+                    find(&["t", "v", "texture_v"]),
+                ];
+                if position.contains(&None) {
+                    bail!("PLY vertices need x, y and z properties");
+                }
+                mesh.normals = !normal.contains(&None);
+                mesh.uvs = !uv.contains(&None);
+                for _ in 0..element.count {
+                    let mut properties = Vec::with_capacity(element.properties.len());
+                    for (_, property) in &element.properties {
+                        properties.push(values.property(property)?.first().copied());
+                    }
+                    let get =
+                        |i: Option<usize>| i.and_then(|i| properties[i]).unwrap_or_default() as f32;
+                    mesh.mesh.vertices.push(Vertex {
+                        position: Vec3::from(position.map(get)),
+                        normal: Vec3::from(normal.map(get)),
+                        // Same origin as OBJ
+                        tex_coords: Vec2::new(get(uv[0]), 1.0 - get(uv[1])),
+                        tangent: Vec3::ONE,
+                    });
+                }
+            }
+            "face" => {
+                let indices = find(&["vertex_indices", "vertex_index"])
+                    .context("PLY faces need a vertex_indices property")?;
+                for _ in 0..element.count {
+                    for (i, (_, property)) in element.properties.iter().enumerate() {
+                        let face = values.property(property)?;
+                        if i != indices {
+                            continue;
+                        }
+                        if face.len() < 3 {
+                            degenerate += 1;
+                            continue;
+                        }
+                        let face = face.into_iter().map(|i| i as u32).collect::<Vec<_>>();
+                        for i in 1..face.len() - 1 {
+                            faces.push([face[0], face[i + 1], face[i]]);
+                        }
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    for (_, property) in &element.properties {
+                        values.property(property)?;
+                    }
+                }
+            }
+        }
+    }
+    let count = mesh.mesh.vertices.len();
+    if let Some(index) = faces.iter().flatten().find(|&&i| i as usize >= count) {
+        bail!("PLY face index {index} out of range ({count} vertices)");
+    }
+    if degenerate > 0 {
+        report.warn(ImportWarning::DegenerateFaces {
+            mesh: mesh.name.clone(),
+            count: degenerate,
+        });
+    }
+    mesh.mesh.indices = faces;
+    Ok(vec![mesh])
+}
+
+/// Number of edges shared by more than two triangles. Edges are compared by position, so
+/// vertices split for uv seams still count as one.
+fn non_manifold_edges(mesh: &Mesh) -> usize {
+    let key = |i: u32| {
+        mesh.vertices[i as usize]
+            .position
+            .to_array()
+            .map(f32::to_bits)
+    };
+    let mut edges = HashMap::new();
+    for tri in &mesh.indices {
+        for (a, b) in [(tri[0], tri[1]), (tri[1], tri[2]), (tri[2], tri[0])] {
+            let (a, b) = (key(a), key(b));
+            let edge = if a < b { (a, b) } else { (b, a) };
+            *edges.entry(edge).or_insert(0) += 1;
+        }
+    }
+    edges.values().filter(|&&count| count > 2).count()
+}
+
+/// Post-processing shared by the OBJ and PLY loaders: fill in what the file didn't have, check
+/// the mesh, and record it in the report.
+pub fn post_process(imported: &mut ImportedMesh, report: &mut ImportReport) {
+    let name = &imported.name;
+    if !imported.uvs {
+        report.warn(ImportWarning::MissingUvs { mesh: name.clone() });
+    }
+    if !imported.normals {
+        imported.mesh.recompute_normals();
+        report.warn(ImportWarning::MissingNormals { mesh: name.clone() });
+    }
+    imported.mesh.recompute_tangents();
+    let edges = non_manifold_edges(&imported.mesh);
+    if edges > 0 {
+        report.warn(ImportWarning::NonManifold {
+            mesh: name.clone(),
+            edges,
+        });
+    }
+    report.meshes.push(MeshInfo {
+        name: name.clone(),
+        vertices: imported.mesh.vertices.len(),
+        triangles: imported.mesh.indices.len(),
+        bounds: imported.mesh.bounds(),
+        index_format: mesh_manager::index_format(imported.mesh.vertices.len()),
+    });
+}
+
+/// Load the material libraries of an OBJ file, resolving texture paths
+fn load_libraries(
+    libraries: &[String],
+    dir: Option<&Path>,
+    report: &mut ImportReport,
+) -> Result<Vec<MaterialDesc>> {
+    let mut materials = Vec::new();
+    for library in libraries {
+        let path = match dir {
+            Some(dir) => dir.join(library),
+            None => {
+                report.warn(ImportWarning::MissingFile {
+                    path: library.into(),
+                    reason: "the OBJ file has no directory to find it in".to_owned(),
+                });
+                continue;
+            }
+        };
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                report.warn(ImportWarning::MissingFile {
+                    path,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for mut material in
+            parse_mtl(&source, report).with_context(|| format!("In '{}'", path.display()))?
+        {
+            material.albedo_map = material.albedo_map.map(|p| dir.join(p));
+            material.normal_map = material.normal_map.map(|p| dir.join(p));
+            materials.push(material);
+        }
+    }
+    Ok(materials)
+}
+
+fn load_texture(
+    path: &Path,
+    srgb: bool,
+    gfx: &mut GraphicContext,
+    report: &mut ImportReport,
+) -> Option<TextureHandle> {
+    // Streamable: the residency policy can reload it at a lower resolution
+    match gfx
+        .texture_manager
+        .add_streamable_texture(&gfx.device, &gfx.queue, path, srgb)
+    {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            report.warn(ImportWarning::MissingFile {
+                path: path.to_owned(),
+                reason: e.root_cause().to_string(),
+            });
+            None
+        }
+    }
+}
+
+fn create_material(
+    desc: &MaterialDesc,
+    gfx: &mut GraphicContext,
+    report: &mut ImportReport,
+) -> Result<Material> {
+    let albedo = desc
+        .albedo_map
+        .as_deref()
+        .and_then(|path| load_texture(path, true, gfx, report))
+        .unwrap_or_else(|| {
+            gfx.texture_manager.get_or_add_single_value_texture(
+                &gfx.device,
+                &gfx.queue,
+                SingleValue::Color(desc.albedo),
+            )
+        });
+    let normal_map = desc
+        .normal_map
+        .as_deref()
+        .and_then(|path| load_texture(path, false, gfx, report));
+    Material::new_with_values(albedo, normal_map, desc.metallic, desc.roughness, None, gfx)
+        .context("Error on material creation")
+}
+
+/// Upload post-processed meshes, creating their materials
+fn upload(
+    meshes: Vec<ImportedMesh>,
+    materials: &[MaterialDesc],
+    gfx: &mut GraphicContext,
+    report: &mut ImportReport,
+) -> Result<Vec<(GraphicsComponent, TransformsComponent)>> {
+    let default = MaterialDesc::default();
+    let mut created: HashMap<Option<String>, Material> = HashMap::new();
+    let mut missing = HashSet::new();
+    let mut entities = Vec::new();
+    for imported in meshes {
+        let material = match created.get(&imported.material) {
+            Some(material) => *material,
+            None => {
+                let desc = match &imported.material {
+                    Some(name) => materials.iter().find(|m| &m.name == name).or_else(|| {
+                        if missing.insert(name.clone()) {
+                            report.warn(ImportWarning::MissingMaterial { name: name.clone() });
+                        }
+                        None
+                    }),
+                    None => None,
+                };
+                let material = create_material(desc.unwrap_or(&default), gfx, report)?;
+                created.insert(imported.material.clone(), material);
+                material
+            }
+        };
+        let mesh = gfx.mesh_manager.add(&gfx.device, &imported.mesh);
+        entities.push((
+            GraphicsComponent { mesh, material },
+            TransformsComponent::new(),
+        ));
+    }
+    Ok(entities)
+}
+
+/// Load the entities of a mesh file, logging the warnings of the import
+pub fn load<'a>(
+    source: impl Into<Source<'a>>,
+    gfx: &mut GraphicContext,
+) -> Result<Vec<(GraphicsComponent, TransformsComponent)>> {
+    let (entities, report) = load_with_report(source, gfx)?;
+    for warning in &report.warnings {
+        log::warn!("Mesh import: {warning}");
+    }
+    Ok(entities)
+}
+
+/// Load the entities of a mesh file, and what went wrong doing so
+pub fn load_with_report<'a>(
+    source: impl Into<Source<'a>>,
+    gfx: &mut GraphicContext,
+) -> Result<(Vec<(GraphicsComponent, TransformsComponent)>, ImportReport)> {
+    let (path, bytes): (Option<PathBuf>, ResourceBytes) = match source.into() {
+        Source::Path(path) => (
+            Some(path.to_owned()),
+            std::fs::read(path)
+                .with_context(|| format!("Couldn't read '{}'", path.display()))?
+                .into(),
+        ),
+        Source::Resource(res) => {
+            let resources = rmanage::instance();
+            (resources.path(res), resources.get_resource(res)?)
+        }
+    };
+    let format = Format::detect(path.as_deref(), &bytes).context("Unknown mesh format")?;
+    let mut report = ImportReport {
+        format: Some(format),
+        ..Default::default()
+    };
+    let (mut meshes, materials) = match format {
+        Format::Gltf => {
+            let entities = match &path {
+                Some(path) => gltf::open(path, gfx)?,
+                None => gltf::open_slice(&bytes, gfx)?,
+            };
+            return Ok((entities, report));
+        }
+        Format::Obj => {
+            let source = std::str::from_utf8(&bytes).context("OBJ file isn't UTF-8")?;
+            let (meshes, libraries) = parse_obj(source, &mut report)?;
+            let dir = path.as_deref().and_then(Path::parent);
+            (meshes, load_libraries(&libraries, dir, &mut report)?)
+        }
+        Format::Ply => (parse_ply(&bytes, &mut report)?, Vec::new()),
+    };
+    for mesh in &mut meshes {
+        post_process(mesh, &mut report);
+    }
+    let entities = upload(meshes, &materials, gfx, &mut report)?;
+    Ok((entities, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-5
+    }
+
+    #[test]
+    fn obj() {
+        let source = std::fs::read_to_string(fixture("quads.obj")).unwrap();
+        let mut report = ImportReport::default();
+        let (mut meshes, libraries) = parse_obj(&source, &mut report).unwrap();
+        assert_eq!(vec!["quads.mtl"], libraries);
+
+        let summary = meshes
+            .iter()
+            .map(|m| {
+                let material = m.material.as_deref();
+                let counts = (m.mesh.vertices.len(), m.mesh.indices.len());
+                (m.name.as_str(), material, counts)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("plane", Some("red"), (4, 2)),
+                ("plane", Some("textured"), (4, 2)),
+                ("triangle", Some("red"), (3, 1)),
+            ],
+            summary
+        );
+
+        // Negative indices are relative to the elements defined so far
+        let textured = &meshes[1].mesh;
+        let corners = textured.vertices.iter().map(|v| v.position);
+        assert!(corners.clone().any(|p| p == Vec3::new(2.0, 1.0, 0.0)));
+        assert!(!corners.clone().any(|p| p == Vec3::ZERO));
+        assert!(textured.vertices.iter().all(|v| v.normal == Vec3::Z));
+        // Texture coordinates are flipped vertically
+        assert_eq!(Vec2::new(0.0, 1.0), textured.vertices[0].tex_coords);
+
+        assert!(meshes[0].uvs && meshes[0].normals);
+        assert!(!meshes[2].uvs && !meshes[2].normals);
+        for mesh in &mut meshes {
+            post_process(mesh, &mut report);
+        }
+        let triangle = "triangle".to_owned();
+        assert_eq!(
+            vec![
+                ImportWarning::MissingUvs {
+                    mesh: triangle.clone()
+                },
+                ImportWarning::MissingNormals { mesh: triangle },
+            ],
+            report.warnings
+        );
+        // Recomputed normals match the winding of the face
+        assert!(meshes[2]
+            .mesh
+            .vertices
+            .iter()
+            .all(|v| close(v.normal, Vec3::Z)));
+        assert_eq!(3, report.meshes.len());
+        assert_eq!(Vec3::new(2.0, 1.0, 0.0), report.meshes[1].bounds.max);
+        assert_eq!(wgpu::IndexFormat::Uint16, report.meshes[1].index_format);
+    }
+
+    #[test]
+    fn obj_errors() {
+        let mut report = ImportReport::default();
+        assert!(parse_obj("v 0 0 0\nf 1 2 3", &mut report).is_err());
+        assert!(parse_obj("v 0 0 0\nf -2 1 1", &mut report).is_err());
+        assert!(parse_obj("v 0 0 0\nf 0 1 1", &mut report).is_err());
+
+        let (meshes, _) = parse_obj("v 0 0 0\nv 1 0 0\nf 1 2\nl 1 2\nl 2 1", &mut report).unwrap();
+        assert!(meshes.is_empty());
+        assert_eq!(
+            vec![
+                ImportWarning::Unsupported {
+                    statement: "l".to_owned(),
+                    line: 4
+                },
+                ImportWarning::DegenerateFaces {
+                    mesh: "default".to_owned(),
+                    count: 1
+                },
+            ],
+            report.warnings
+        );
+    }
+
+    #[test]
+    fn mtl() {
+        let mut report = ImportReport::default();
+        let materials =
+            load_libraries(&["quads.mtl".to_owned()], Some(&fixture("")), &mut report).unwrap();
+        assert!(report.warnings.is_empty());
+        assert_eq!(
+            vec![
+                MaterialDesc {
+                    name: "red".to_owned(),
+                    albedo: Vec4::new(1.0, 0.0, 0.0, 1.0),
+                    roughness: roughness_from_exponent(98.0),
+                    ..Default::default()
+                },
+                MaterialDesc {
+                    name: "textured".to_owned(),
+                    albedo: Vec4::new(1.0, 1.0, 1.0, 0.5),
+                    albedo_map: Some(fixture("checker.png")),
+                    normal_map: Some(fixture("normal.png")),
+                    metallic: 1.0,
+                    roughness: 0.25,
+                },
+            ],
+            materials
+        );
+        // Shiny is smooth
+        assert!(roughness_from_exponent(1000.0) < roughness_from_exponent(10.0));
+        assert!((roughness_from_exponent(0.0) - 1.0).abs() < 1e-6);
+
+        load_libraries(&["missing.mtl".to_owned()], Some(&fixture("")), &mut report).unwrap();
+        assert!(matches!(
+            report.warnings.as_slice(),
+            [ImportWarning::MissingFile { .. }]
+        ));
+    }
+
+    #[test]
+    fn ply_binary() {
+        let bytes = std::fs::read(fixture("quad.ply")).unwrap();
+        let mut report = ImportReport::default();
+        let mut meshes = parse_ply(&bytes, &mut report).unwrap();
+        assert_eq!(1, meshes.len());
+        let mesh = &mut meshes[0];
+        assert!(mesh.normals && mesh.uvs);
+        assert_eq!(4, mesh.mesh.vertices.len());
+        assert_eq!(vec![[0, 2, 1], [0, 3, 2]], mesh.mesh.indices);
+        let vertex = mesh.mesh.vertices[2];
+        assert_eq!(Vec3::new(1.0, 1.0, 0.0), vertex.position);
+        assert_eq!(Vec3::Z, vertex.normal);
+        assert_eq!(Vec2::new(1.0, 0.0), vertex.tex_coords);
+
+        post_process(mesh, &mut report);
+        assert!(report.warnings.is_empty());
+
+        // Cut in the middle of the data
+        assert!(parse_ply(&bytes[..bytes.len() - 3], &mut report).is_err());
+    }
+
+    #[test]
+    fn ply_ascii() {
+        let source = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\n\
+            property float y\nproperty float z\nelement edge 1\nproperty int vertex1\n\
+            property int vertex2\nelement face 2\nproperty list uchar int vertex_index\n\
+            end_header\n0 0 0\n1 0 0\n0 1 0\n0 1\n3 0 1 2\n2 0 1\n";
+        let mut report = ImportReport::default();
+        let mut meshes = parse_ply(source.as_bytes(), &mut report).unwrap();
+        let mesh = &mut meshes[0];
+        assert_eq!(3, mesh.mesh.vertices.len());
+        assert_eq!(vec![[0, 2, 1]], mesh.mesh.indices);
+        assert!(!mesh.normals && !mesh.uvs);
+        post_process(mesh, &mut report);
+        assert!(mesh.mesh.vertices.iter().all(|v| close(v.normal, Vec3::Z)));
+        assert_eq!(3, report.warnings.len());
+
+        let big_endian = source.replace("ascii", "binary_big_endian");
+        assert!(parse_ply(big_endian.as_bytes(), &mut report).is_err());
+        let out_of_range = source.replace("3 0 1 2", "3 0 1 3");
+        assert!(parse_ply(out_of_range.as_bytes(), &mut report).is_err());
+    }
+
+    #[test]
+    fn non_manifold() {
+        let vertex = |x, y, z| Vertex {
+            position: Vec3::new(x, y, z),
+            normal: Vec3::Z,
+            tex_coords: Vec2::ZERO,
+            tangent: Vec3::X,
+        };
+        let mut mesh = Mesh {
+            vertices: vec![
+                vertex(0.0, 0.0, 0.0),
+                vertex(1.0, 0.0, 0.0),
+                vertex(0.0, 1.0, 0.0),
+                vertex(0.0, -1.0, 0.0),
+                vertex(0.0, 0.0, 1.0),
+            ],
+            indices: vec![[0, 1, 2], [0, 3, 1]],
+        };
+        assert_eq!(0, non_manifold_edges(&mesh));
+        // A third triangle on the 0-1 edge
+        mesh.indices.push([0, 1, 4]);
+        assert_eq!(1, non_manifold_edges(&mesh));
+    }
+
+    #[test]
+    fn detection() {
+        let obj = std::fs::read(fixture("quads.obj")).unwrap();
+        let ply = std::fs::read(fixture("quad.ply")).unwrap();
+        let path = |p: &'static str| Some(Path::new(p));
+        // The extension wins
+        assert_eq!(Some(Format::Gltf), Format::detect(path("a/b.GLB"), &obj));
+        assert_eq!(Some(Format::Ply), Format::detect(path("b.ply"), b""));
+        // Otherwise the content is used
+        assert_eq!(Some(Format::Obj), Format::detect(path("model"), &obj));
+        assert_eq!(Some(Format::Obj), Format::detect(None, b"v 1 2 3\n"));
+        assert_eq!(Some(Format::Ply), Format::detect(path("x.bin"), &ply));
+        assert_eq!(Some(Format::Gltf), Format::detect(None, b"glTF\x02\0\0\0"));
+        assert_eq!(
+            Some(Format::Gltf),
+            Format::detect(None, b"  {\"asset\": {}}")
+        );
+        assert_eq!(None, Format::detect(None, b"\x89PNG\r\n"));
+        assert_eq!(None, Format::detect(path("a.txt"), b"# only a comment"));
+        assert_eq!(None, Format::detect(None, b""));
+    }
+}
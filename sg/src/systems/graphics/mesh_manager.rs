@@ -1,9 +1,19 @@
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::Hasher,
+};
+
 use anyhow::{anyhow, Result};
+use glam::Mat4;
 use glam::Vec2;
 use glam::Vec3;
-use slotmap::SlotMap;
+use glam::Vec4;
+use slotmap::{SecondaryMap, SlotMap};
 use wgpu::util::DeviceExt;
 
+use super::memory::MemoryLedger;
+
 #[repr(C)]
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
@@ -54,6 +64,84 @@ pub struct BufferedMesh {
     pub vertices: wgpu::Buffer,
     pub indices: wgpu::Buffer,
     pub num_indices: u32,
+    /// Width of the indices in the index buffer
+    pub index_format: wgpu::IndexFormat,
+    /// Bounds of the mesh, in model space
+    pub bounds: BoundingBox,
+    /// Size of the buffers
+    pub bytes: u64,
+}
+
+/// Narrowest index format able to address `vertices` vertices
+pub fn index_format(vertices: usize) -> wgpu::IndexFormat {
+    if vertices <= u16::MAX as usize {
+        wgpu::IndexFormat::Uint16
+    } else {
+        wgpu::IndexFormat::Uint32
+    }
+}
+
+/// Bytes taken by the vertex and index buffers of a mesh, `indices` being the number of indices
+/// (not triangles)
+pub fn mesh_bytes(vertices: usize, indices: usize, index_format: wgpu::IndexFormat) -> u64 {
+    let index_size = match index_format {
+        wgpu::IndexFormat::Uint16 => 2,
+        wgpu::IndexFormat::Uint32 => 4,
+    };
+    // Buffers created with contents are padded to COPY_BUFFER_ALIGNMENT
+    let buffer = |bytes: usize| {
+        (bytes as u64).div_ceil(wgpu::COPY_BUFFER_ALIGNMENT) * wgpu::COPY_BUFFER_ALIGNMENT
+    };
+    buffer(vertices * std::mem::size_of::<Vertex>()) + buffer(indices * index_size)
+}
+
+/// Axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl BoundingBox {
+    /// Smallest box containing all the points (an empty box at the origin if there are none)
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Self {
+        let mut points = points.into_iter();
+        let first = match points.next() {
+            Some(first) => first,
+            None => return Self { min: Vec3::ZERO, max: Vec3::ZERO },
+        };
+        points.fold(Self { min: first, max: first }, |acc, p| Self {
+            min: acc.min.min(p),
+            max: acc.max.max(p),
+        })
+    }
+    pub fn corners(&self) -> [Vec3; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Vec3::new(a.x, a.y, a.z),
+            Vec3::new(b.x, a.y, a.z),
+            Vec3::new(a.x, b.y, a.z),
+            Vec3::new(b.x, b.y, a.z),
+            Vec3::new(a.x, a.y, b.z),
+            Vec3::new(b.x, a.y, b.z),
+            Vec3::new(a.x, b.y, b.z),
+            Vec3::new(b.x, b.y, b.z),
+        ]
+    }
+    /// Bounding box of this box once transformed
+    pub fn transform(&self, mat: Mat4) -> Self {
+        Self::from_points(self.corners().map(|c| mat.transform_point3(c)))
+    }
+    /// Whether the box is at least partly in the frustum (see `camera::frustum_planes`). Boxes
+    /// near an edge of the frustum can be found in it while being just outside.
+    pub fn in_frustum(&self, planes: &[Vec4; 6]) -> bool {
+        planes.iter().all(|plane| {
+            // The corner furthest along the normal is the last one to leave
+            let normal = plane.truncate();
+            let corner = Vec3::select(normal.cmpge(Vec3::ZERO), self.max, self.min);
+            normal.dot(corner) + plane.w >= 0.0
+        })
+    }
 }
 
 slotmap::new_key_type! {
@@ -61,8 +149,21 @@ slotmap::new_key_type! {
 }
 
 impl Mesh {
+    /// Format and contents of the index buffer, narrowed to u16 when possible
+    fn index_buffer(&self) -> (wgpu::IndexFormat, Cow<'_, [u8]>) {
+        let index_format = index_format(self.vertices.len());
+        let contents = match index_format {
+            wgpu::IndexFormat::Uint16 => {
+                let short: Vec<u16> = self.indices.iter().flatten().map(|&i| i as u16).collect();
+                Cow::Owned(bytemuck::cast_slice(&short).to_vec())
+            }
+            wgpu::IndexFormat::Uint32 => Cow::Borrowed(bytemuck::cast_slice(&self.indices)),
+        };
+        (index_format, contents)
+    }
     fn buffered(&self, device: &wgpu::Device) -> BufferedMesh {
         let num_indices = self.indices.len() as u32 * 3;
+        let (index_format, contents) = self.index_buffer();
         BufferedMesh {
             vertices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Vertex Buffer"),
@@ -71,12 +172,28 @@ impl Mesh {
             }),
             indices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Index Buffer"),
-                contents: bytemuck::cast_slice(&self.indices),
+                contents: &contents,
                 usage: wgpu::BufferUsages::INDEX,
             }),
             num_indices,
+            index_format,
+            bounds: self.bounds(),
+            bytes: mesh_bytes(self.vertices.len(), num_indices as usize, index_format),
         }
     }
+    /// Hash of the vertices and indices, meshes with the same one are deduplicated by
+    /// `MeshManager::add_dedup`
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_usize(self.vertices.len());
+        hasher.write(bytemuck::cast_slice(&self.vertices));
+        hasher.write(bytemuck::cast_slice(&self.indices));
+        hasher.finish()
+    }
+    /// Bounds of the mesh, in model space
+    pub fn bounds(&self) -> BoundingBox {
+        BoundingBox::from_points(self.vertices.iter().map(|v| v.position))
+    }
     pub fn recompute_normals(&mut self) {
         let mut normals = vec![Vec3::ZERO; self.vertices.len()];
         for tri in &self.indices {
@@ -224,8 +341,7 @@ impl Primitives for Mesh {
             [ 1,  4,  8],
         ];
         for _ in 0..detail {
-            for _ in 0..indices.len() {
-                let tri = indices.remove(0);
+            for tri in std::mem::take(&mut indices) {
                 let (i1, i2, i3) = (tri[0], tri[1], tri[2]);
                 let p1 = vertices[i1 as usize].position;
                 let p2 = vertices[i2 as usize].position;
@@ -405,42 +521,147 @@ impl Primitives for Mesh {
 
 pub struct MeshManager {
     meshes: SlotMap<MeshHandle, BufferedMesh>,
+    /// Copy of the meshes added with `keep_cpu_data`, for picking
+    cpu_meshes: SecondaryMap<MeshHandle, Mesh>,
+    /// Size of each mesh's buffers
+    memory: MemoryLedger<MeshHandle>,
+    /// Number of references to each mesh, it is freed when it reaches 0
+    refs: SecondaryMap<MeshHandle, usize>,
+    /// Meshes added with `add_dedup`, by content hash
+    dedup_cache: HashMap<u64, MeshHandle>,
+    /// Same but opposite direction
+    mesh_hash: SecondaryMap<MeshHandle, u64>,
 }
 
 impl MeshManager {
     pub fn new() -> Self {
         Self {
             meshes: SlotMap::with_key(),
+            cpu_meshes: SecondaryMap::new(),
+            memory: MemoryLedger::new(),
+            refs: SecondaryMap::new(),
+            dedup_cache: HashMap::new(),
+            mesh_hash: SecondaryMap::new(),
         }
     }
 
+    /// Add a mesh, with a single reference
     pub fn add(&mut self, device: &wgpu::Device, mesh: &Mesh) -> MeshHandle {
-        self.meshes.insert(mesh.buffered(device))
+        self.add_buffered(mesh.buffered(device))
+    }
+
+    /// Add a mesh, keeping a copy of its data on the cpu if `keep_cpu_data` is set (see
+    /// `MeshManager::get_cpu`). Needed for meshes that are picked, like paintable ones.
+    pub fn add_with(
+        &mut self,
+        device: &wgpu::Device,
+        mesh: &Mesh,
+        keep_cpu_data: bool,
+    ) -> MeshHandle {
+        let handle = self.add(device, mesh);
+        if keep_cpu_data {
+            self.cpu_meshes.insert(handle, mesh.clone());
+        }
+        handle
+    }
+
+    /// Add a mesh, or retain the one added with the same vertices and indices
+    pub fn add_dedup(&mut self, device: &wgpu::Device, mesh: &Mesh) -> MeshHandle {
+        let hash = mesh.content_hash();
+        if let Some(&handle) = self.dedup_cache.get(&hash) {
+            self.retain(handle);
+            return handle;
+        }
+        let handle = self.add(device, mesh);
+        self.dedup_cache.insert(hash, handle);
+        self.mesh_hash.insert(handle, hash);
+        handle
     }
 
     pub fn add_buffered(&mut self, mesh: BufferedMesh) -> MeshHandle {
-        self.meshes.insert(mesh)
+        let bytes = mesh.bytes;
+        let handle = self.meshes.insert(mesh);
+        self.memory.insert(handle, bytes);
+        self.refs.insert(handle, 1);
+        handle
     }
 
+    /// Add a reference to a mesh, returns false if it doesn't exist
+    pub fn retain(&mut self, handle: MeshHandle) -> bool {
+        match self.refs.get_mut(handle) {
+            Some(refs) => {
+                *refs += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop a reference to a mesh, returns true if it was the last one and the mesh was freed
+    pub fn release(&mut self, handle: MeshHandle) -> bool {
+        self.remove(handle).is_some()
+    }
+
+    /// Drop a reference to a mesh, freeing it (and returning it) if it was the last one
     pub fn remove(&mut self, handle: MeshHandle) -> Option<BufferedMesh> {
+        let refs = self.refs.get_mut(handle)?;
+        *refs -= 1;
+        if *refs > 0 {
+            return None;
+        }
+        self.refs.remove(handle);
+        if let Some(hash) = self.mesh_hash.remove(handle) {
+            self.dedup_cache.remove(&hash);
+        }
+        self.cpu_meshes.remove(handle);
+        self.memory.remove(handle);
         self.meshes.remove(handle)
     }
 
+    /// Update a mesh, the cpu copy is kept in sync if there is one
     pub fn update(&mut self, handle: MeshHandle, device: &wgpu::Device, mesh: &Mesh) -> Result<()> {
-        self.update_buffered(handle, mesh.buffered(device))
+        let keep_cpu_data = self.cpu_meshes.contains_key(handle);
+        self.update_buffered(handle, mesh.buffered(device))?;
+        if keep_cpu_data {
+            self.cpu_meshes.insert(handle, mesh.clone());
+        }
+        Ok(())
     }
 
+    /// Update the gpu side of a mesh, this drops its cpu copy as it can't be kept in sync
     pub fn update_buffered(&mut self, handle: MeshHandle, mesh: BufferedMesh) -> Result<()> {
+        let bytes = mesh.bytes;
         *self
             .meshes
             .get_mut(handle)
             .ok_or_else(|| anyhow!("Handle doesn't point to any mesh"))? = mesh;
+        self.cpu_meshes.remove(handle);
+        // The content changed, it can't be shared by add_dedup anymore
+        if let Some(hash) = self.mesh_hash.remove(handle) {
+            self.dedup_cache.remove(&hash);
+        }
+        self.memory.insert(handle, bytes);
         Ok(())
     }
 
     pub fn get(&self, handle: MeshHandle) -> Option<&BufferedMesh> {
         self.meshes.get(handle)
     }
+
+    /// Cpu copy of a mesh, only there if it was added with `keep_cpu_data`
+    pub fn get_cpu(&self, handle: MeshHandle) -> Option<&Mesh> {
+        self.cpu_meshes.get(handle)
+    }
+
+    /// Size of the meshes' buffers
+    pub fn memory(&self) -> &MemoryLedger<MeshHandle> {
+        &self.memory
+    }
+
+    /// The meshes and their number of references, for debugging
+    pub fn live_handles(&self) -> impl Iterator<Item = (MeshHandle, usize)> + '_ {
+        self.refs.iter().map(|(handle, &refs)| (handle, refs))
+    }
 }
 
 impl Default for MeshManager {
@@ -448,3 +669,71 @@ impl Default for MeshManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_indices() {
+        // Past u16::MAX vertices (81912)
+        let sphere = Mesh::new_icosphere(6);
+        assert!(sphere.vertices.len() > u16::MAX as usize);
+        assert!(sphere.indices.iter().flatten().all(|&i| (i as usize) < sphere.vertices.len()));
+        assert!(sphere.indices.iter().flatten().any(|&i| i > u16::MAX as u32));
+        let (format, contents) = sphere.index_buffer();
+        assert_eq!(wgpu::IndexFormat::Uint32, format);
+        assert_eq!(sphere.indices.len() * 3 * 4, contents.len());
+
+        let sphere = Mesh::new_cubic_sphere(5);
+        assert!(sphere.indices.iter().flatten().all(|&i| (i as usize) < sphere.vertices.len()));
+        let (format, contents) = sphere.index_buffer();
+        assert_eq!(wgpu::IndexFormat::Uint16, format);
+        let short = contents.chunks_exact(2).map(|b| u16::from_ne_bytes([b[0], b[1]]) as u32);
+        assert!(short.eq(sphere.indices.iter().flatten().copied()));
+    }
+
+    #[test]
+    fn frustum() {
+        // x and y in -1..1, z in 0..1
+        let planes = [
+            Vec4::new(1.0, 0.0, 0.0, 1.0),
+            Vec4::new(-1.0, 0.0, 0.0, 1.0),
+            Vec4::new(0.0, 1.0, 0.0, 1.0),
+            Vec4::new(0.0, -1.0, 0.0, 1.0),
+            Vec4::new(0.0, 0.0, 1.0, 0.0),
+            Vec4::new(0.0, 0.0, -1.0, 1.0),
+        ];
+        let aabb = |min: [f32; 3], max: [f32; 3]| BoundingBox {
+            min: Vec3::from(min),
+            max: Vec3::from(max),
+        };
+        assert!(aabb([-0.5, -0.5, 0.2], [0.5, 0.5, 0.8]).in_frustum(&planes));
+        // Larger than the frustum, or partly in it
+        assert!(aabb([-5.0, -5.0, -5.0], [5.0, 5.0, 5.0]).in_frustum(&planes));
+        assert!(aabb([0.9, 0.9, 0.9], [2.0, 2.0, 2.0]).in_frustum(&planes));
+        // Touching a face
+        assert!(aabb([1.0, 0.0, 0.5], [2.0, 0.5, 0.6]).in_frustum(&planes));
+        // Outside of a single plane
+        assert!(!aabb([1.1, -0.5, 0.2], [2.0, 0.5, 0.8]).in_frustum(&planes));
+        assert!(!aabb([-0.5, -3.0, 0.2], [0.5, -1.5, 0.8]).in_frustum(&planes));
+        assert!(!aabb([-0.5, -0.5, -2.0], [0.5, 0.5, -0.1]).in_frustum(&planes));
+        assert!(!aabb([-0.5, -0.5, 1.5], [0.5, 0.5, 3.0]).in_frustum(&planes));
+        // Transformed into the frustum
+        let outside = aabb([4.0, 0.0, 0.4], [4.5, 0.5, 0.6]);
+        assert!(outside.transform(Mat4::from_translation(Vec3::new(-4.0, 0.0, 0.0))).in_frustum(&planes));
+    }
+
+    #[test]
+    fn content_hash() {
+        let cube = Mesh::new_cube();
+        assert_eq!(cube.content_hash(), Mesh::new_cube().content_hash());
+        assert_ne!(cube.content_hash(), Mesh::new_icosphere(1).content_hash());
+        let mut moved = cube.clone();
+        moved.vertices[0].position.x += 1.0;
+        assert_ne!(cube.content_hash(), moved.content_hash());
+        let mut flipped = cube.clone();
+        flipped.indices[0].swap(1, 2);
+        assert_ne!(cube.content_hash(), flipped.content_hash());
+    }
+}
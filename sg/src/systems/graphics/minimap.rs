@@ -0,0 +1,556 @@
+//! Top-down minimap.
+//!
+//! Entities with a `MinimapMarkerComponent` are drawn as round markers by an orthographic camera
+//! looking straight down, into a texture shown in an egui window. Clicking the map moves the main
+//! camera to look at the clicked point.
+
+use ecs::Entities;
+use egui::TextureId;
+use egui_wgpu::renderer::RenderPass;
+use glam::{Mat4, Quat, Vec2, Vec3, Vec4};
+use wgpu::util::DeviceExt;
+
+use crate::{components::TransformsComponent, include_shader, systems::time::Time};
+
+use super::{
+    pipeline::{Pipeline, RenderPipeline},
+    renderer::WorldRenderer,
+    GraphicContext,
+};
+
+/// Size of the minimap texture
+pub const MINIMAP_SIZE: u32 = 512;
+const MINIMAP_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+/// Height of the minimap camera, everything between it and as far below is visible
+const MINIMAP_HEIGHT: f32 = 1000.0;
+/// Duration of the camera move when clicking the minimap, in seconds
+const FOCUS_DURATION: f32 = 0.5;
+
+/// Shows the entity on the minimap
+#[derive(Debug, Clone, Copy)]
+pub struct MinimapMarkerComponent {
+    pub color: Vec4,
+    /// Diameter of the marker, in world units
+    pub size: f32,
+}
+
+impl MinimapMarkerComponent {
+    pub fn new(color: Vec4, size: f32) -> Self {
+        Self { color, size }
+    }
+}
+
+/// A marker as read by the minimap shader, one per instance
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MarkerInstance {
+    /// World XZ position
+    pub position: Vec2,
+    pub size: f32,
+    _padding: f32,
+    pub color: Vec4,
+}
+
+impl MarkerInstance {
+    pub fn new(position: Vec3, marker: &MinimapMarkerComponent) -> Self {
+        Self {
+            position: Vec2::new(position.x, position.z),
+            size: marker.size,
+            _padding: 0.0,
+            color: marker.color,
+        }
+    }
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<MarkerInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<Vec2>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+/// The area of the XZ plane shown by the minimap. +X is right and +Z is up on the map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MinimapView {
+    pub center: Vec2,
+    /// Half of the height of the shown area, in world units
+    pub extent: f32,
+    /// Width over height of the shown area, should match the widget the map is shown in
+    pub aspect: f32,
+}
+
+impl Default for MinimapView {
+    fn default() -> Self {
+        Self {
+            center: Vec2::ZERO,
+            extent: 10.0,
+            aspect: 1.0,
+        }
+    }
+}
+
+impl MinimapView {
+    /// Half size of the shown area
+    fn half_size(&self) -> Vec2 {
+        Vec2::new(self.extent * self.aspect, self.extent)
+    }
+    pub fn view_projection(&self) -> Mat4 {
+        let eye = Vec3::new(self.center.x, MINIMAP_HEIGHT, self.center.y);
+        let view = Mat4::look_at_lh(eye, eye - Vec3::Y, Vec3::Z);
+        let half = self.half_size();
+        let projection =
+            Mat4::orthographic_lh(-half.x, half.x, -half.y, half.y, 0.0, 2.0 * MINIMAP_HEIGHT);
+        projection * view
+    }
+    /// Position of a world point on the map, (0, 0) is the top left corner and (1, 1) the bottom
+    /// right one.
+    pub fn world_to_uv(&self, position: Vec3) -> Vec2 {
+        let ndc = self.view_projection().project_point3(position);
+        Vec2::new(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5)
+    }
+    /// World XZ position of a point of the map
+    pub fn uv_to_world(&self, uv: Vec2) -> Vec2 {
+        let offset = Vec2::new(uv.x - 0.5, 0.5 - uv.y) * 2.0 * self.half_size();
+        self.center + offset
+    }
+    /// World XZ position of a point of a widget showing the map (stretched to its size)
+    pub fn widget_to_world(&self, position: Vec2, widget_size: Vec2) -> Option<Vec2> {
+        let uv = position / widget_size;
+        match uv.cmpge(Vec2::ZERO).all() && uv.cmple(Vec2::ONE).all() {
+            true => Some(self.uv_to_world(uv)),
+            false => None,
+        }
+    }
+}
+
+/// Runs something every `interval` frames
+#[derive(Debug, Clone, Copy)]
+pub struct Throttle {
+    interval: u32,
+    remaining: u32,
+}
+
+impl Throttle {
+    pub fn new(interval: u32) -> Self {
+        Self {
+            interval: interval.max(1),
+            remaining: 0,
+        }
+    }
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+    pub fn set_interval(&mut self, interval: u32) {
+        self.interval = interval.max(1);
+        self.remaining = self.remaining.min(self.interval - 1);
+    }
+    /// Run on the next tick, whatever the interval
+    pub fn force(&mut self) {
+        self.remaining = 0;
+    }
+    /// Advance a frame, true if it should run on this one (the first one always does)
+    pub fn tick(&mut self) -> bool {
+        match self.remaining {
+            0 => {
+                self.remaining = self.interval - 1;
+                true
+            }
+            _ => {
+                self.remaining -= 1;
+                false
+            }
+        }
+    }
+}
+
+/// Where a camera must be moved (keeping its rotation) to look at a point of the ground (y = 0).
+/// If the camera doesn't look down, it is just moved above the point.
+pub fn focus_position(position: Vec3, rotation: Quat, target: Vec2) -> Vec3 {
+    let forward = rotation.mul_vec3(Vec3::Z);
+    // Where the camera currently looks on the ground
+    let look = match forward.y < -1e-4 && position.y > 0.0 {
+        true => position + forward * (-position.y / forward.y),
+        false => position,
+    };
+    position + Vec3::new(target.x - look.x, 0.0, target.y - look.z)
+}
+
+/// Smooth camera move
+#[derive(Debug, Clone, Copy)]
+pub struct CameraFocus {
+    from: Vec3,
+    to: Vec3,
+    elapsed: f32,
+}
+
+impl CameraFocus {
+    pub fn new(from: Vec3, to: Vec3) -> Self {
+        Self {
+            from,
+            to,
+            elapsed: 0.0,
+        }
+    }
+    /// Advance the move, gives the new position and whether it is done
+    pub fn step(&mut self, delta: f32) -> (Vec3, bool) {
+        self.elapsed += delta;
+        let t = (self.elapsed / FOCUS_DURATION).clamp(0.0, 1.0);
+        let t = t * t * (3.0 - 2.0 * t);
+        (self.from.lerp(self.to, t), self.elapsed >= FOCUS_DURATION)
+    }
+}
+
+pub struct Minimap {
+    pipeline: RenderPipeline,
+    target: wgpu::TextureView,
+    uniform: wgpu::Buffer,
+    bindgroup: wgpu::BindGroup,
+    instances: wgpu::Buffer,
+    capacity: usize,
+    throttle: Throttle,
+    /// View of the last render, clicks are converted with it
+    rendered: MinimapView,
+    pub view: MinimapView,
+    pub open: bool,
+    texture_id: Option<TextureId>,
+    /// Clicked point, waiting for follow_focus
+    clicked: Option<Vec2>,
+    focus: Option<CameraFocus>,
+}
+
+impl Minimap {
+    pub fn new(ctx: &GraphicContext) -> Self {
+        let device = &ctx.device;
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Minimap Texture"),
+            size: wgpu::Extent3d {
+                width: MINIMAP_SIZE,
+                height: MINIMAP_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: MINIMAP_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        let view = MinimapView::default();
+        let uniform = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Minimap Uniform"),
+            contents: bytemuck::bytes_of(&view.view_projection()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let layout = create_bind_group_layout!(device, "Minimap Bindgroup Layout": {
+            0 => VERTEX | Buffer(type: Uniform),
+        });
+        let bindgroup = create_bind_group!(device, &layout, "Minimap Bindgroup": {
+            0 | Buffer(buffer: (&uniform)),
+        });
+        let pipeline = Pipeline::new(
+            device,
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Minimap Pipeline Layout"),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            }),
+            include_shader!("minimap.wgsl", "Minimap Shader"),
+            |device, layout, module| {
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Minimap Pipeline"),
+                    layout: Some(layout),
+                    vertex: wgpu::VertexState {
+                        module,
+                        entry_point: "vs_main",
+                        buffers: &[MarkerInstance::desc()],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module,
+                        entry_point: "fs_main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: MINIMAP_FORMAT,
+                            blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            },
+        );
+        let capacity = 64;
+        Self {
+            pipeline,
+            target: texture.create_view(&Default::default()),
+            uniform,
+            bindgroup,
+            instances: Self::make_instances(device, capacity),
+            capacity,
+            throttle: Throttle::new(1),
+            rendered: view,
+            view,
+            open: true,
+            texture_id: None,
+            clicked: None,
+            focus: None,
+        }
+    }
+    fn make_instances(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Minimap Instances"),
+            size: (capacity * std::mem::size_of::<MarkerInstance>()) as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+    /// Only render the minimap every `frames` frames
+    pub fn set_update_interval(&mut self, frames: u32) {
+        self.throttle.set_interval(frames);
+    }
+    pub fn update_interval(&self) -> u32 {
+        self.throttle.interval()
+    }
+    /// System rendering the minimap (if it is open and due)
+    pub fn render(
+        &mut self,
+        ctx: &GraphicContext,
+        markers: Entities<(&MinimapMarkerComponent, Option<&TransformsComponent>)>,
+    ) {
+        if !self.open {
+            return;
+        }
+        if self.view != self.rendered {
+            self.throttle.force();
+        }
+        if !self.throttle.tick() {
+            return;
+        }
+        let instances = markers
+            .map(|(marker, tsm)| {
+                let position = tsm.map(|tsm| tsm.mat().w_axis.truncate()).unwrap_or_default();
+                MarkerInstance::new(position, marker)
+            })
+            .collect::<Vec<_>>();
+        if instances.len() > self.capacity {
+            self.capacity = instances.len().next_power_of_two();
+            self.instances = Self::make_instances(&ctx.device, self.capacity);
+        }
+        self.rendered = self.view;
+        ctx.queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::bytes_of(&self.view.view_projection()),
+        );
+        ctx.queue
+            .write_buffer(&self.instances, 0, bytemuck::cast_slice(&instances));
+
+        let mut encoder = ctx
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Minimap Encoder"),
+            });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Minimap Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: 0.02,
+                            g: 0.02,
+                            b: 0.02,
+                            a: 0.9,
+                        }),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            if !instances.is_empty() {
+                render_pass.set_pipeline(&self.pipeline.pipeline);
+                render_pass.set_bind_group(0, &self.bindgroup, &[]);
+                render_pass.set_vertex_buffer(0, self.instances.slice(..));
+                render_pass.draw(0..6, 0..instances.len() as u32);
+            }
+        }
+        ctx.queue.submit(std::iter::once(encoder.finish()));
+    }
+    /// The egui texture of the minimap, registered on first use
+    pub(super) fn texture_id(&mut self, device: &wgpu::Device, render_pass: &mut RenderPass) -> TextureId {
+        *self.texture_id.get_or_insert_with(|| {
+            render_pass.register_native_texture(device, &self.target, wgpu::FilterMode::Linear)
+        })
+    }
+    /// Draw the minimap window
+    pub(super) fn ui(&mut self, ctx: &egui::Context, texture: TextureId, title: &str) {
+        if !self.open {
+            return;
+        }
+        let mut open = self.open;
+        egui::Window::new(title)
+            .open(&mut open)
+            .anchor(egui::Align2::RIGHT_TOP, [-8.0, 8.0])
+            .resizable(false)
+            .show(ctx, |ui| {
+                let size = egui::vec2(256.0 * self.view.aspect, 256.0);
+                let response =
+                    ui.add(egui::Image::new(texture, size).sense(egui::Sense::click()));
+                if let Some(pos) = response
+                    .interact_pointer_pos()
+                    .filter(|_| response.clicked())
+                {
+                    let pos = pos - response.rect.min;
+                    let size = response.rect.size();
+                    self.clicked = self
+                        .rendered
+                        .widget_to_world(Vec2::new(pos.x, pos.y), Vec2::new(size.x, size.y));
+                }
+            });
+        self.open = open;
+    }
+    /// System moving the main camera to the last clicked point of the minimap
+    pub fn follow_focus(&mut self, wr: &mut WorldRenderer, time: &Time) {
+        if let Some(target) = self.clicked.take() {
+            let from = wr.camera.get_position();
+            let to = focus_position(from, wr.camera.get_rotation(), target);
+            self.focus = Some(CameraFocus::new(from, to));
+        }
+        if let Some(focus) = &mut self.focus {
+            let (position, done) = focus.step(time.delta_secs());
+            wr.camera.set_position(position);
+            if done {
+                self.focus = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    fn close(a: Vec2, b: Vec2) -> bool {
+        (a - b).length() < 1e-4
+    }
+
+    #[test]
+    fn uv_round_trip() {
+        let view = MinimapView {
+            center: Vec2::new(3.0, -2.0),
+            extent: 5.0,
+            aspect: 1.0,
+        };
+        assert!(close(Vec2::splat(0.5), view.world_to_uv(Vec3::new(3.0, 0.0, -2.0))));
+        // +X right, +Z up, height doesn't matter
+        assert!(close(Vec2::new(1.0, 0.0), view.world_to_uv(Vec3::new(8.0, 7.0, 3.0))));
+        assert!(close(Vec2::new(0.0, 1.0), view.world_to_uv(Vec3::new(-2.0, -3.0, -7.0))));
+        for (x, z) in [(0.0, 0.0), (1.5, -4.0), (7.9, 2.5), (-1.0, -6.5)] {
+            let world = Vec2::new(x, z);
+            let uv = view.world_to_uv(Vec3::new(x, 0.0, z));
+            assert!(close(world, view.uv_to_world(uv)), "{world} {uv}");
+        }
+    }
+
+    #[test]
+    fn marker_layout() {
+        assert_eq!(32, std::mem::size_of::<MarkerInstance>());
+        let marker = MarkerInstance::new(
+            Vec3::new(1.0, 2.0, 3.0),
+            &MinimapMarkerComponent::new(Vec4::new(0.1, 0.2, 0.3, 0.4), 5.0),
+        );
+        let floats: &[f32] = bytemuck::cast_slice(std::slice::from_ref(&marker));
+        let desc = MarkerInstance::desc();
+        assert_eq!(wgpu::VertexStepMode::Instance, desc.step_mode);
+        assert_eq!(32, desc.array_stride);
+        let read = |location: u32| {
+            let attribute = desc
+                .attributes
+                .iter()
+                .find(|a| a.shader_location == location)
+                .unwrap();
+            let start = attribute.offset as usize / 4;
+            let len = attribute.format.size() as usize / 4;
+            floats[start..start + len].to_vec()
+        };
+        assert_eq!(vec![1.0, 3.0], read(0));
+        assert_eq!(vec![5.0], read(1));
+        assert_eq!(vec![0.1, 0.2, 0.3, 0.4], read(2));
+    }
+
+    #[test]
+    fn throttle() {
+        let run = |throttle: &mut Throttle, frames| {
+            (0..frames).map(|_| throttle.tick()).collect::<Vec<_>>()
+        };
+        let mut every = Throttle::new(1);
+        assert_eq!(vec![true; 3], run(&mut every, 3));
+        let mut third = Throttle::new(3);
+        assert_eq!(
+            vec![true, false, false, true, false, false, true],
+            run(&mut third, 7)
+        );
+        third.force();
+        assert_eq!(vec![true, false], run(&mut third, 2));
+        // Shortening the interval doesn't wait longer than the new one
+        let mut long = Throttle::new(10);
+        long.tick();
+        long.set_interval(2);
+        assert_eq!(vec![false, true, false, true], run(&mut long, 4));
+        assert_eq!(1, Throttle::new(0).interval());
+    }
+
+    #[test]
+    fn click_to_focus() {
+        let view = MinimapView {
+            center: Vec2::new(10.0, 0.0),
+            extent: 4.0,
+            aspect: 2.0,
+        };
+        let widget = Vec2::new(512.0, 256.0);
+        // Aspect 2: the widget shows 16 by 8 world units
+        assert!(close(Vec2::new(10.0, 0.0), view.widget_to_world(widget / 2.0, widget).unwrap()));
+        assert!(close(Vec2::new(2.0, 4.0), view.widget_to_world(Vec2::ZERO, widget).unwrap()));
+        assert!(close(Vec2::new(18.0, -4.0), view.widget_to_world(widget, widget).unwrap()));
+        assert!(close(
+            Vec2::new(14.0, 2.0),
+            view.widget_to_world(Vec2::new(384.0, 64.0), widget).unwrap()
+        ));
+        assert_eq!(None, view.widget_to_world(Vec2::new(-1.0, 10.0), widget));
+        // Consistent with the rendering
+        let uv = view.world_to_uv(Vec3::new(14.0, 0.0, 2.0));
+        assert!(close(Vec2::new(0.75, 0.25), uv));
+
+        // Camera looking down at 45 degrees toward +Z from (0, 5, -5): looks at the origin
+        let rotation = Quat::from_rotation_x(PI / 4.0);
+        let position = focus_position(Vec3::new(0.0, 5.0, -5.0), rotation, Vec2::new(3.0, 4.0));
+        assert!((position - Vec3::new(3.0, 5.0, -1.0)).length() < 1e-4, "{position}");
+        // Looking at the horizon: moved above the point
+        let position = focus_position(Vec3::new(1.0, 2.0, 3.0), Quat::IDENTITY, Vec2::new(3.0, 4.0));
+        assert_eq!(Vec3::new(3.0, 2.0, 4.0), position);
+
+        let mut focus = CameraFocus::new(Vec3::ZERO, Vec3::X);
+        let (mid, done) = focus.step(FOCUS_DURATION / 2.0);
+        assert!(!done && (mid.x - 0.5).abs() < 1e-5);
+        assert_eq!((Vec3::X, true), focus.step(FOCUS_DURATION));
+    }
+}
@@ -0,0 +1,52 @@
+// Minimap markers: one instanced quad per marker, on the XZ plane
+
+struct Minimap {
+    view_proj: mat4x4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> minimap: Minimap;
+
+struct Marker {
+    // World XZ position
+    @location(0) position: vec2<f32>,
+    // Side of the quad in world units
+    @location(1) size: f32,
+    @location(2) color: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    // Position in the quad, from -1 to 1
+    @location(1) local: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32, marker: Marker) -> VertexOutput {
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, 1.0),
+    );
+    let corner = corners[index];
+    let position = marker.position + corner * marker.size * 0.5;
+
+    var out: VertexOutput;
+    out.clip_position = minimap.view_proj * vec4<f32>(position.x, 0.0, position.y, 1.0);
+    out.color = marker.color;
+    out.local = corner;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Round markers
+    if (length(in.local) > 1.0) {
+        discard;
+    }
+    return in.color;
+}
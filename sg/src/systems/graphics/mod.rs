@@ -1,14 +1,25 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use ecs::{Entities, Entity};
-use glam::{Vec3, Vec4};
+use glam::{Mat4, Vec3, Vec4};
 use winit::window::Window;
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Instant};
 
-use crate::{components::{GraphicsComponent, TransformsComponent}, Grabbed};
+use crate::{components::{GraphicsComponent, TransformsComponent}, console::Console, crash, localization::Localization, Grabbed};
+use crate::systems::weather::Weather;
+use crate::save::SaveMenu;
 
 use self::{
+    focus::UiFocus,
+    frame::Frame,
+    memory::{GpuMemory, GpuMemoryStats},
     mesh_manager::MeshManager,
+    minimap::Minimap,
+    options::{choose_format, choose_present_mode, GraphicContextOptions},
+    paint::TexturePaintTool,
+    pipeline_cache::{PipelineCache, EVICT_AFTER, PIPELINE_CACHE_FILE},
+    quality::AdaptiveQuality,
     texture_manager::{SingleValue, TextureHandle, TextureManager, TextureSet}, renderer::{WorldRenderer, UIRenderer},
+    ui_scale::UiScale, ui_draw::UiDraw,
 };
 
 #[macro_use] // avoid importing each and every macro
@@ -16,19 +27,52 @@ pub mod desc; // Large descriptors
 pub mod camera; // Camera
 pub mod g_buffer; // GBuffer
 pub mod gltf; // Gltf loading (-> ECS)
+pub mod mesh_import; // Mesh loading from gltf, OBJ and PLY files (-> ECS)
 pub mod mesh_manager; // Mesh Manager
 pub mod pipeline; // Abstraction over pipelines and shaders (with ad hoc specialization constant)
+pub mod pipeline_cache; // Cache of pipeline permutations
 pub mod texture_manager; // Texture manager
 pub mod renderer; // UI and World rendered
 pub mod cubemap; // Equirectangular to cubemap conversion
 pub mod convolution; // Convolution of environment maps
+pub mod prefilter; // Prefiltering of environment maps for specular lighting
+pub mod brdf_lut; // Lookup table of the specular lighting
+pub mod env_cache; // Cache of the environment maps
+pub mod hiz; // Hi-Z occlusion culling
+pub mod instances; // Instancing of the geometry pass
+pub mod minimap; // Top-down minimap
+pub mod particles; // Particle simulation (GPU compute with a CPU fallback)
+pub mod sprites; // Camera facing and screen space sprites
+pub mod debug_draw; // Lines drawn over the scene for debugging
+pub mod paint; // Runtime texture painting tool
+pub mod picking; // Entity under a pixel, read back from the GBuffer
+pub mod fog; // Volumetric fog
+pub mod ssr; // Screen-space reflections
+pub mod tonemap; // HDR target and its tonemapping to the output
+pub mod bloom; // Bloom of the bright parts of the HDR scene
+pub mod shadows; // Shadow maps of the directional and spot lights
+pub mod timer; // GPU timestamp queries
+pub mod focus; // Keyboard/controller navigation of the UI
+pub mod frame; // The frame shared by the rendering systems
+pub mod quality; // Adaptive quality, to hold a frame rate
+pub mod screenshot; // Captures of the presented frames
+pub mod memory; // GPU memory accounting and texture residency
+pub mod ui_scale; // DPI and user scale of the UI
+pub mod ui_draw; // Panels of the application and the output of egui
+pub mod options; // Backend, adapter, surface format and present mode selection
+#[cfg(test)]
+mod test_device; // Device the tests run on
+#[cfg(test)]
+mod visual; // Visual regression tests against golden images
 
 #[repr(C)]
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct DiretionalLight {
     direction: Vec3,
-    padding: f32,
+    /// Layer of the shadow atlas, -1 if unshadowed (set on upload, see `shadows`)
+    shadow: i32,
     color: Vec4,
+    view_proj: Mat4,
 }
 
 #[repr(C)]
@@ -43,18 +87,22 @@ pub struct PointLight {
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct SpotLight {
     position: Vec3,
-    padding: f32,
+    /// Same as `DiretionalLight::shadow`
+    shadow: i32,
     direction: Vec3,
+    /// Cosine of the half angle of the cone
     cut_off: f32,
     color: Vec4,
+    view_proj: Mat4,
 }
 
 impl DiretionalLight {
     pub fn new(direction: Vec3, color: Vec4) -> Self {
         Self {
             direction,
-            padding: 0.0,
+            shadow: -1,
             color,
+            view_proj: Mat4::IDENTITY,
         }
     }
 }
@@ -67,16 +115,21 @@ impl PointLight {
             color,
         }
     }
+    pub fn set_position(&mut self, position: Vec3) {
+        self.position = position;
+    }
 }
 
 impl SpotLight {
+    /// `cut_off` is the cosine of the half angle of the cone
     pub fn new(position: Vec3, direction: Vec3, cut_off: f32, color: Vec4) -> Self {
         Self {
             position,
-            padding: 0.0,
+            shadow: -1,
             direction,
             cut_off,
             color,
+            view_proj: Mat4::IDENTITY,
         }
     }
 }
@@ -88,9 +141,38 @@ pub enum Light {
     Spot(SpotLight),
 }
 
+/// What the alpha of a material's albedo does
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AlphaMode {
+    /// Ignored, the surface is opaque
+    #[default]
+    Opaque,
+    /// Opaque where the alpha is at least the cutoff, nothing elsewhere (foliage, fences)
+    Mask(f32),
+    /// Blended over what is behind, drawn after everything opaque (glass, water)
+    Blend,
+}
+
+impl AlphaMode {
+    /// Alpha under which fragments are discarded, 0 when none are
+    pub fn cutoff(&self) -> f32 {
+        match self {
+            Self::Mask(cutoff) => *cutoff,
+            _ => 0.0,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Material {
     textures: TextureSet,
+    /// Not made wet by the weather (indoor or covered surfaces)
+    pub weatherproof: bool,
+    /// Multiplies the emissive texture, black (nothing is emitted) by default
+    pub emissive: Vec3,
+    pub alpha_mode: AlphaMode,
+    /// Back faces aren't culled, and are lit as seen from their side
+    pub double_sided: bool,
 }
 
 impl Material {
@@ -121,6 +203,19 @@ impl Material {
         roughness: TextureHandle,
         ao: Option<TextureHandle>,
         gfx: &mut GraphicContext,
+    ) -> Result<Self> {
+        Self::new_with_emissive(albedo, normal_map, metallic, roughness, ao, None, gfx)
+    }
+    /// The emissive texture is multiplied by `emissive` (see `Material::emissive`), without one
+    /// it is white
+    pub fn new_with_emissive(
+        albedo: TextureHandle,
+        normal_map: Option<TextureHandle>,
+        metallic: TextureHandle,
+        roughness: TextureHandle,
+        ao: Option<TextureHandle>,
+        emissive: Option<TextureHandle>,
+        gfx: &mut GraphicContext,
     ) -> Result<Self> {
         let set = gfx.texture_manager.add_set();
         let normal_map = normal_map.unwrap_or_else(|| {
@@ -137,129 +232,453 @@ impl Material {
                 SingleValue::Factor(1.0),
             )
         });
+        let emissive = emissive.unwrap_or_else(|| {
+            gfx.texture_manager.get_or_add_single_value_texture(
+                &gfx.device,
+                &gfx.queue,
+                SingleValue::Color(Vec4::ONE),
+            )
+        });
         gfx.texture_manager.add_texture_to_set(albedo, set)?;
         gfx.texture_manager.add_texture_to_set(normal_map, set)?;
         gfx.texture_manager.add_texture_to_set(metallic, set)?;
         gfx.texture_manager.add_texture_to_set(roughness, set)?;
         gfx.texture_manager.add_texture_to_set(ao, set)?;
-        Ok(Self { textures: set })
+        gfx.texture_manager.add_texture_to_set(emissive, set)?;
+        Ok(Self {
+            textures: set,
+            weatherproof: false,
+            emissive: Vec3::ZERO,
+            alpha_mode: AlphaMode::Opaque,
+            double_sided: false,
+        })
     }
 }
 
 pub struct GraphicContext {
     pub size: winit::dpi::PhysicalSize<u32>,
-    pub device: wgpu::Device,
+    pub device: Arc<wgpu::Device>,
     pub queue: wgpu::Queue,
-    surface: wgpu::Surface,
+    /// What the adapter can do below the WebGPU baseline
+    pub downlevel: wgpu::DownlevelCapabilities,
+    pub adapter: wgpu::AdapterInfo,
+    /// None when headless (see `headless`)
+    surface: Option<wgpu::Surface>,
+    /// Where the frames go without a surface, and `render_offscreen` renders. See `read_back`.
+    target: Option<wgpu::Texture>,
     config: wgpu::SurfaceConfiguration,
+    present_modes: Vec<wgpu::PresentMode>,
+    /// Wait for the GPU after each frame, so `timings` has the GPU time (benchmarks)
+    pub sync: bool,
+    pub timings: FrameTimings,
     feedback: Result<(), wgpu::SurfaceError>,
+    /// Where to save the next frame, see `request_screenshot`
+    screenshot: Option<PathBuf>,
     pub mesh_manager: MeshManager,
     pub texture_manager: TextureManager,
+    pub pipelines: PipelineCache,
 }
 
 impl GraphicContext {
-    pub async fn new(window: &Window) -> Self {
+    pub async fn new(window: &Window, options: GraphicContextOptions) -> Self {
         let size = window.inner_size();
 
-        let instance = wgpu::Instance::new(wgpu::Backends::VULKAN);
+        let instance = wgpu::Instance::new(options.backends);
         let surface = unsafe { instance.create_surface(window) };
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
+                power_preference: options.power_preference,
                 compatible_surface: Some(&surface),
                 force_fallback_adapter: false,
             })
             .await
-            .unwrap();
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::PUSH_CONSTANTS |
-                        wgpu::Features::TEXTURE_BINDING_ARRAY |
-                        wgpu::Features::PARTIALLY_BOUND_BINDING_ARRAY |
-                        wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING,
-                    limits: wgpu::Limits {
-                        max_push_constant_size: 128,
-                        max_texture_dimension_2d: 20000,
-                        max_buffer_size: 1024u64.pow(3) * 4,
-                        ..Default::default()
-                    },
-                    label: None,
-                },
-                None,
-            )
+            .unwrap_or_else(|| panic!("No adapter for the backends {:?}", options.backends));
+        let (device, queue) = request_device(&adapter, options.limits, options.features)
             .await
             .unwrap();
+
+        let downlevel = adapter.get_downlevel_capabilities();
+        crash::set_section("capabilities", capability_report(&adapter, &device));
+        device.on_uncaptured_error(|error| match error {
+            wgpu::Error::OutOfMemory { .. } => crash::fatal(format!("wgpu error: {error}")),
+            // Same as the default handler, the panic hook writes the crash bundle
+            wgpu::Error::Validation { .. } => panic!("wgpu error: {error}"),
+        });
+
+        let present_modes = surface.get_supported_modes(&adapter);
+        let present_mode = choose_present_mode(&present_modes, options.present_mode)
+            .unwrap_or_else(|| {
+                log::warn!("Present mode {:?} isn't supported, using Fifo", options.present_mode);
+                wgpu::PresentMode::Fifo
+            });
         let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: surface.get_supported_formats(&adapter)[0],
+            // COPY_SRC for the screenshots
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: choose_format(&surface.get_supported_formats(&adapter), options.format)
+                .expect("The surface isn't supported by the adapter"),
             width: size.width,
             height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode,
         };
+        log::info!(
+            "Using {:?} on {} ({:?}), {:?}",
+            config.format,
+            adapter.get_info().name,
+            adapter.get_info().backend,
+            config.present_mode
+        );
 
         let texture_manager = TextureManager::new();
 
         surface.configure(&device, &config);
 
         Self {
-            surface,
-            device,
+            surface: Some(surface),
+            target: None,
+            device: Arc::new(device),
             queue,
+            downlevel,
+            adapter: adapter.get_info(),
             config,
+            present_modes,
+            sync: false,
+            timings: FrameTimings::default(),
             size,
             feedback: Ok(()),
+            screenshot: None,
             mesh_manager: MeshManager::new(),
             texture_manager,
+            pipelines: PipelineCache::with_warm_list(EVICT_AFTER, PIPELINE_CACHE_FILE),
         }
     }
+    /// A context without a window, on the fallback (software) adapter so that the output is the
+    /// same on every machine. Frames are rendered with `render_offscreen`. None if there is no
+    /// fallback adapter or it lacks the features the renderer needs.
+    pub async fn headless(width: u32, height: u32) -> Option<Self> {
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter: true,
+            })
+            .await?;
+        Self::offscreen(adapter, width, height).await.ok()
+    }
+    /// A context without a window on any adapter (the GPU if there is one), for the tools
+    /// rendering images. The output isn't reproducible across machines, see `headless` for that.
+    pub async fn new_headless(width: u32, height: u32) -> Result<Self> {
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            })
+            .await
+            .context("No adapter")?;
+        Self::offscreen(adapter, width, height).await
+    }
+    async fn offscreen(adapter: wgpu::Adapter, width: u32, height: u32) -> Result<Self> {
+        let (device, queue) = request_device(&adapter, wgpu::Limits::default(), wgpu::Features::empty())
+            .await
+            .with_context(|| format!("Couldn't get a device on {}", adapter.get_info().name))?;
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+        };
+        let target = create_target(&device, &config);
+        Ok(Self {
+            surface: None,
+            target: Some(target),
+            device: Arc::new(device),
+            queue,
+            downlevel: adapter.get_downlevel_capabilities(),
+            adapter: adapter.get_info(),
+            config,
+            present_modes: Vec::new(),
+            sync: false,
+            timings: FrameTimings::default(),
+            size: winit::dpi::PhysicalSize::new(width, height),
+            feedback: Ok(()),
+            screenshot: None,
+            mesh_manager: MeshManager::new(),
+            texture_manager: TextureManager::new(),
+            // Nothing to warm, nor to save for the next run
+            pipelines: PipelineCache::new(EVICT_AFTER),
+        })
+    }
+    pub fn is_headless(&self) -> bool {
+        self.surface.is_none()
+    }
+    /// Format of the frames, the surface's or that of the target of `read_back`
+    pub fn target_format(&self) -> wgpu::TextureFormat {
+        self.config.format
+    }
+    /// Surface error of the last `render`, the frame systems keep theirs in `Frame`
     pub fn feedback(&self) -> Result<(), wgpu::SurfaceError> {
         self.feedback.as_ref().map_err(|err| err.clone())?;
         Ok(())
     }
+    /// Disabling vsync presents immediately if the surface supports it, returns true if it does
+    pub fn set_vsync(&mut self, vsync: bool) -> bool {
+        self.set_present_mode(if vsync {
+            wgpu::PresentMode::Fifo
+        } else {
+            wgpu::PresentMode::Immediate
+        })
+    }
+    /// Reconfigure the surface with a present mode, returns false (and keeps the current one) if
+    /// the surface doesn't support it
+    pub fn set_present_mode(&mut self, mode: wgpu::PresentMode) -> bool {
+        if choose_present_mode(&self.present_modes, mode).is_none() {
+            return false;
+        }
+        self.config.present_mode = mode;
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
+        }
+        true
+    }
+    pub fn present_mode(&self) -> wgpu::PresentMode {
+        self.config.present_mode
+    }
+    /// Save the next frame as a PNG
+    pub fn request_screenshot(&mut self, path: PathBuf) {
+        self.screenshot = Some(path);
+    }
+    /// Does nothing headless, where the size is the one of the creation
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        if self.is_headless() {
+            return;
+        }
         if new_size.width > 0 && new_size.height > 0 {
+            // Created again at the new size when needed
+            self.target = None;
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+            if let Some(surface) = &self.surface {
+                surface.configure(&self.device, &self.config);
+            }
         }
     }
 
-    pub fn render(
-        &mut self,
-        wr: &mut WorldRenderer,
-        uir: &mut UIRenderer,
-        estate: &mut egui_winit::State,
-        ui: &egui::Context,
-        window: &Arc<Window>,
-        grabbed: &Grabbed,
-        renderables: Entities<(Entity, &GraphicsComponent, Option<&TransformsComponent>)>,
-    ) {
-        self.feedback = Ok(());
-        
-        let output = self.surface.get_current_texture();
+    /// System acquiring the surface texture and creating the encoder of the frame
+    pub fn begin_frame(&mut self, frame: &mut Frame) {
+        *frame = Frame::default();
+        self.pipelines.maintain();
+
+        let output = match (&self.surface, &self.target) {
+            (Some(surface), _) => surface.get_current_texture(),
+            (None, Some(target)) => {
+                let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+                let encoder = self
+                    .device
+                    .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                        label: Some("gfx render encoder"),
+                    });
+                frame.begin(None, view, encoder);
+                return;
+            }
+            (None, None) => return,
+        };
         match output {
             Ok(output) => {
                 let view = output
                     .texture
                     .create_view(&wgpu::TextureViewDescriptor::default());
-                let mut encoder = self
+                let encoder = self
                     .device
                     .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                         label: Some("gfx render encoder"),
                     });
-                
-                wr.render(self, &mut encoder, &view, renderables);
-                uir.render(self, &mut encoder, &view, estate, ui, grabbed, window);
-
-                self.queue.submit(std::iter::once(encoder.finish()));
-                output.present();
+                frame.begin(Some(output), view, encoder);
             }
             Err(error) => {
                 log::info!("Error on surface");
-                self.feedback = Err(error);
+                frame.set_feedback(Err(error));
             }
         }
     }
+
+    /// System submitting and presenting the frame
+    pub fn end_frame(&mut self, wr: &mut WorldRenderer, frame: &mut Frame) {
+        let mut timings = frame.take_timings();
+        let (encoder, output) = match frame.finish() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let start = Instant::now();
+        self.queue.submit(std::iter::once(encoder.finish()));
+        timings.record("submit", start);
+        if self.sync {
+            let start = Instant::now();
+            self.device.poll(wgpu::Maintain::Wait);
+            timings.gpu_ms = Some(start.elapsed().as_secs_f32() * 1000.0);
+        }
+        wr.after_submit();
+        timings.gpu_passes = wr.gpu_timings();
+        let texture = output.as_ref().map(|output| &output.texture).or(self.target.as_ref());
+        if let (Some(texture), Some(path)) = (texture, self.screenshot.take()) {
+            let size = (self.config.width, self.config.height);
+            match screenshot::capture(&self.device, &self.queue, texture, self.config.format, size, &path) {
+                Ok(()) => {
+                    log::info!("Saved a screenshot to {}", path.display());
+                    crash::set_screenshot(path);
+                }
+                Err(e) => log::error!("Couldn't take a screenshot: {e:#}"),
+            }
+        }
+        if let Some(output) = output {
+            output.present();
+        }
+        self.timings = timings;
+    }
+
+    /// The frame systems in a single call (`feedback` has the surface error), for the callers
+    /// that don't use them yet
+    #[allow(clippy::too_many_arguments)]
+    pub fn render(
+        &mut self,
+        wr: &mut WorldRenderer,
+        uir: &mut UIRenderer,
+        estate: &mut egui_winit::State,
+        ui: &egui::Context,
+        window: &Arc<Window>,
+        grabbed: &Grabbed,
+        focus: &mut UiFocus,
+        loc: &mut Localization,
+        minimap: &mut Minimap,
+        paint: &mut TexturePaintTool,
+        quality: &mut AdaptiveQuality,
+        weather: &mut Weather,
+        memory: &mut GpuMemory,
+        memory_stats: &GpuMemoryStats,
+        console: &mut Console,
+        saves: &mut SaveMenu,
+        scale: &mut UiScale,
+        draw: &mut UiDraw,
+        renderables: Entities<(Entity, &GraphicsComponent, Option<&TransformsComponent>)>,
+    ) {
+        let mut frame = Frame::default();
+        self.begin_frame(&mut frame);
+        frame.record("world", |encoder, view| wr.record(self, encoder, view, renderables));
+        frame.record("ui", |encoder, view| {
+            uir.record(self, encoder, view, estate, ui, grabbed, focus, window, loc, minimap, paint, &mut wr.fog, &mut wr.ssr, wr.stats, quality, weather, memory, memory_stats, console, saves, scale, draw)
+        });
+        self.end_frame(wr, &mut frame);
+        self.feedback = frame.feedback();
+    }
+
+    /// Render the world (without the UI) into the target of the context (see `read_back`), and
+    /// read it back as RGBA pixels. This waits for the GPU.
+    pub fn render_offscreen<'a>(
+        &mut self,
+        wr: &mut WorldRenderer,
+        renderables: impl IntoIterator<Item = (Entity, &'a GraphicsComponent, Option<&'a TransformsComponent>)>,
+    ) -> Result<Vec<u8>> {
+        self.pipelines.maintain();
+        let target = self
+            .target
+            .get_or_insert_with(|| create_target(&self.device, &self.config));
+        let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("offscreen render encoder"),
+            });
+        wr.record(self, &mut encoder, &view, renderables);
+        self.queue.submit(std::iter::once(encoder.finish()));
+        wr.after_submit();
+        Ok(self.read_back()?.into_raw())
+    }
+    /// Copy the last frame rendered into the target (headless, or by `render_offscreen`) to an
+    /// image. This waits for the GPU.
+    pub fn read_back(&self) -> Result<image::RgbaImage> {
+        let target = self.target.as_ref().context("Nothing rendered offscreen")?;
+        let (width, height) = (self.config.width, self.config.height);
+        let pixels = screenshot::read_pixels(&self.device, &self.queue, target, self.config.format, (width, height))?;
+        image::RgbaImage::from_raw(width, height, pixels).context("Pixels of the wrong size")
+    }
+}
+
+/// Texture the frames of a context are rendered into instead of the surface
+fn create_target(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("offscreen target"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: config.usage,
+    })
+}
+
+async fn request_device(
+    adapter: &wgpu::Adapter,
+    limits: wgpu::Limits,
+    features: wgpu::Features,
+) -> Result<(wgpu::Device, wgpu::Queue), wgpu::RequestDeviceError> {
+    adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                features: wgpu::Features::PUSH_CONSTANTS |
+                    wgpu::Features::TEXTURE_BINDING_ARRAY |
+                    wgpu::Features::PARTIALLY_BOUND_BINDING_ARRAY |
+                    wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING |
+                    // Optional, only used to time the fog and the reflections
+                    (adapter.features() & wgpu::Features::TIMESTAMP_QUERY) |
+                    features,
+                limits: wgpu::Limits {
+                    max_push_constant_size: 128,
+                    ..limits
+                },
+                label: None,
+            },
+            None,
+        )
+        .await
+}
+
+/// CPU time spent recording each part of the last frame
+#[derive(Debug, Clone, Default)]
+pub struct FrameTimings {
+    /// Milliseconds, in the order of the frame
+    pub passes: Vec<(&'static str, f32)>,
+    /// Milliseconds waited for the GPU to finish the frame, with `GraphicContext::sync`
+    pub gpu_ms: Option<f32>,
+    /// GPU milliseconds of the passes that are timed (see `timer`), a few frames old
+    pub gpu_passes: Vec<(&'static str, f32)>,
+}
+
+impl FrameTimings {
+    fn record(&mut self, pass: &'static str, start: Instant) {
+        self.passes.push((pass, start.elapsed().as_secs_f32() * 1000.0));
+    }
+}
+
+/// Human readable summary of the adapter and device, for the crash bundles
+fn capability_report(adapter: &wgpu::Adapter, device: &wgpu::Device) -> String {
+    let info = adapter.get_info();
+    format!(
+        "adapter: {} ({:?}, {:?})\nvendor: {:#x}, device: {:#x}\n\nadapter features: {:?}\ndevice features: {:?}\n\nadapter limits: {:#?}\ndevice limits: {:#?}\n",
+        info.name,
+        info.device_type,
+        info.backend,
+        info.vendor,
+        info.device,
+        adapter.features(),
+        device.features(),
+        adapter.limits(),
+        device.limits(),
+    )
 }
@@ -0,0 +1,74 @@
+// Test the bounding boxes of the renderables against the depth pyramid, and set the visibility bit
+// of those that aren't hidden. Mirrors hiz::screen_rect, hiz::select_mip and hiz::occluded.
+struct Params {
+    view_proj: mat4x4<f32>,
+    // Size of the first level of the pyramid
+    size: vec2<u32>,
+    levels: u32,
+    count: u32,
+}
+struct Bounds {
+    min: vec4<f32>,
+    max: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var pyramid: texture_2d<f32>;
+@group(0) @binding(1)
+var<uniform> params: Params;
+@group(0) @binding(2)
+var<storage, read> bounds: array<Bounds>;
+@group(0) @binding(3)
+var<storage, read_write> visibility: array<atomic<u32>>;
+
+fn is_visible(b: Bounds) -> bool {
+    var rect_min = vec2<f32>(1.0, 1.0);
+    var rect_max = vec2<f32>(0.0, 0.0);
+    var depth = 1.0;
+    for (var c = 0u; c < 8u; c = c + 1u) {
+        let corner = vec3<f32>(
+            select(b.min.x, b.max.x, (c & 1u) != 0u),
+            select(b.min.y, b.max.y, (c & 2u) != 0u),
+            select(b.min.z, b.max.z, (c & 4u) != 0u),
+        );
+        let clip = params.view_proj * vec4<f32>(corner, 1.0);
+        // Crosses the near plane, can't be tested
+        if (clip.w <= 0.0 || clip.z < 0.0) {
+            return true;
+        }
+        let ndc = clip.xyz / clip.w;
+        let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        rect_min = min(rect_min, uv);
+        rect_max = max(rect_max, uv);
+        depth = min(depth, ndc.z);
+    }
+    rect_min = clamp(rect_min, vec2<f32>(0.0, 0.0), vec2<f32>(1.0, 1.0));
+    rect_max = clamp(rect_max, vec2<f32>(0.0, 0.0), vec2<f32>(1.0, 1.0));
+    // Off screen, that's the frustum's business
+    if (rect_min.x >= rect_max.x || rect_min.y >= rect_max.y) {
+        return true;
+    }
+
+    let texels = (rect_max - rect_min) * vec2<f32>(params.size);
+    let level = min(u32(ceil(log2(max(max(texels.x, texels.y), 1.0)))), params.levels - 1u);
+    let size = max(vec2<i32>(params.size >> vec2<u32>(level)), vec2<i32>(1, 1));
+    let low = clamp(vec2<i32>(rect_min * vec2<f32>(size)), vec2<i32>(0, 0), size - 1);
+    let high = clamp(vec2<i32>(rect_max * vec2<f32>(size)), vec2<i32>(0, 0), size - 1);
+    let lvl = i32(level);
+    let occluder = max(
+        max(textureLoad(pyramid, low, lvl).r, textureLoad(pyramid, vec2<i32>(high.x, low.y), lvl).r),
+        max(textureLoad(pyramid, vec2<i32>(low.x, high.y), lvl).r, textureLoad(pyramid, high, lvl).r),
+    );
+    return depth <= occluder;
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if (i >= params.count) {
+        return;
+    }
+    if (is_visible(bounds[i])) {
+        atomicOr(&visibility[i / 32u], 1u << (i % 32u));
+    }
+}
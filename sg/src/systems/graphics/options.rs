@@ -0,0 +1,151 @@
+//! How the `GraphicContext` picks its backend, adapter, surface format and present mode.
+//!
+//! The defaults suit the game, the benchmarks and the platforms without Vulkan override them,
+//! either in code or with the `SG_BACKEND` (`vulkan,metal`, `gl`...) and `SG_PRESENT`
+//! (`fifo`, `immediate`...) environment variables.
+
+use anyhow::{anyhow, Result};
+
+/// Options of `GraphicContext::new`
+#[derive(Debug, Clone)]
+pub struct GraphicContextOptions {
+    pub backends: wgpu::Backends,
+    pub power_preference: wgpu::PowerPreference,
+    /// Falls back to `Fifo` if the surface doesn't support it
+    pub present_mode: wgpu::PresentMode,
+    /// Surface format to use if supported, otherwise an sRGB one is preferred
+    pub format: Option<wgpu::TextureFormat>,
+    /// Required on top of the features the renderer needs
+    pub features: wgpu::Features,
+    pub limits: wgpu::Limits,
+}
+
+impl Default for GraphicContextOptions {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::Backends::PRIMARY,
+            power_preference: wgpu::PowerPreference::default(),
+            present_mode: wgpu::PresentMode::Fifo,
+            format: None,
+            features: wgpu::Features::empty(),
+            limits: wgpu::Limits {
+                max_texture_dimension_2d: 20000,
+                max_buffer_size: 1024u64.pow(3) * 4,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl GraphicContextOptions {
+    /// Override the backends and present mode with `SG_BACKEND` and `SG_PRESENT`, if set
+    pub fn with_env(self) -> Result<Self> {
+        let backend = std::env::var("SG_BACKEND").ok();
+        let present = std::env::var("SG_PRESENT").ok();
+        self.with_overrides(backend.as_deref(), present.as_deref())
+    }
+    fn with_overrides(mut self, backend: Option<&str>, present: Option<&str>) -> Result<Self> {
+        if let Some(backend) = backend {
+            self.backends = parse_backends(backend)?;
+        }
+        if let Some(present) = present {
+            self.present_mode = parse_present_mode(present)?;
+        }
+        Ok(self)
+    }
+}
+
+/// A comma separated list of backends
+pub fn parse_backends(list: &str) -> Result<wgpu::Backends> {
+    let mut backends = wgpu::Backends::empty();
+    for name in list.split(',').map(str::trim) {
+        backends |= match name.to_lowercase().as_str() {
+            "vulkan" | "vk" => wgpu::Backends::VULKAN,
+            "metal" | "mtl" => wgpu::Backends::METAL,
+            "dx12" | "d3d12" => wgpu::Backends::DX12,
+            "dx11" | "d3d11" => wgpu::Backends::DX11,
+            "gl" | "gles" | "opengl" => wgpu::Backends::GL,
+            "webgpu" => wgpu::Backends::BROWSER_WEBGPU,
+            "primary" => wgpu::Backends::PRIMARY,
+            "all" => wgpu::Backends::all(),
+            _ => return Err(anyhow!("Unknown backend {name:?}")),
+        };
+    }
+    Ok(backends)
+}
+
+pub fn parse_present_mode(name: &str) -> Result<wgpu::PresentMode> {
+    Ok(match name.trim().to_lowercase().as_str() {
+        "fifo" | "vsync" => wgpu::PresentMode::Fifo,
+        "relaxed" | "fifo_relaxed" => wgpu::PresentMode::FifoRelaxed,
+        "mailbox" => wgpu::PresentMode::Mailbox,
+        "immediate" | "novsync" => wgpu::PresentMode::Immediate,
+        "auto" => wgpu::PresentMode::AutoVsync,
+        "auto_novsync" => wgpu::PresentMode::AutoNoVsync,
+        _ => return Err(anyhow!("Unknown present mode {name:?}")),
+    })
+}
+
+/// The preferred format if supported, otherwise the first sRGB one, otherwise the first one
+pub fn choose_format(
+    supported: &[wgpu::TextureFormat],
+    preferred: Option<wgpu::TextureFormat>,
+) -> Option<wgpu::TextureFormat> {
+    preferred
+        .filter(|format| supported.contains(format))
+        .or_else(|| supported.iter().copied().find(|f| f.describe().srgb))
+        .or_else(|| supported.first().copied())
+}
+
+/// The mode if supported (the automatic ones always are), otherwise `Fifo`
+pub fn choose_present_mode(
+    supported: &[wgpu::PresentMode],
+    mode: wgpu::PresentMode,
+) -> Option<wgpu::PresentMode> {
+    use wgpu::PresentMode::*;
+    match mode {
+        AutoVsync | AutoNoVsync => Some(mode),
+        _ if supported.contains(&mode) => Some(mode),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wgpu::{PresentMode, TextureFormat};
+
+    #[test]
+    fn overrides() {
+        let options = GraphicContextOptions::default()
+            .with_overrides(Some("gl"), Some("Immediate"))
+            .unwrap();
+        assert_eq!(wgpu::Backends::GL, options.backends);
+        assert_eq!(PresentMode::Immediate, options.present_mode);
+        assert_eq!(
+            wgpu::Backends::VULKAN | wgpu::Backends::METAL,
+            parse_backends("vulkan, metal").unwrap()
+        );
+        let options = GraphicContextOptions::default().with_overrides(None, None).unwrap();
+        assert_eq!(wgpu::Backends::PRIMARY, options.backends);
+        assert!(parse_backends("vulkan,glide").is_err());
+        assert!(parse_present_mode("sometimes").is_err());
+    }
+
+    #[test]
+    fn choices() {
+        let formats = [TextureFormat::Bgra8Unorm, TextureFormat::Bgra8UnormSrgb];
+        assert_eq!(Some(TextureFormat::Bgra8UnormSrgb), choose_format(&formats, None));
+        let preferred = Some(TextureFormat::Bgra8Unorm);
+        assert_eq!(preferred, choose_format(&formats, preferred));
+        let unsupported = Some(TextureFormat::Rgba16Float);
+        assert_eq!(Some(TextureFormat::Bgra8UnormSrgb), choose_format(&formats, unsupported));
+        assert_eq!(Some(TextureFormat::Bgra8Unorm), choose_format(&formats[..1], None));
+        assert_eq!(None, choose_format(&[], None));
+
+        let modes = [PresentMode::Fifo, PresentMode::Mailbox];
+        assert_eq!(Some(PresentMode::Mailbox), choose_present_mode(&modes, PresentMode::Mailbox));
+        assert_eq!(None, choose_present_mode(&modes, PresentMode::Immediate));
+        assert_eq!(Some(PresentMode::AutoNoVsync), choose_present_mode(&[], PresentMode::AutoNoVsync));
+    }
+}
@@ -0,0 +1,817 @@
+//! Runtime texture painting, a prototyping tool.
+//!
+//! A `PaintableComponent` keeps a CPU copy of its texture. While the tool is enabled, clicking on
+//! a paintable entity casts a ray from the cursor, finds the hit triangle (on the mesh's CPU copy,
+//! so its mesh must be added with `keep_cpu_data`) and splats the brush around the interpolated
+//! UV. The region touched during a frame is uploaded at most once per frame, and each stroke
+//! records the pixels it overwrote in a bounded undo ring.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use ecs::{Entities, Entity};
+use glam::{Mat4, Vec2, Vec3};
+
+use crate::components::{GraphicsComponent, TransformsComponent};
+use crate::localization::Localization;
+use crate::tr;
+
+use super::{
+    focus::UiFocus,
+    memory::TextureInfo,
+    mesh_manager::Mesh,
+    renderer::WorldRenderer,
+    texture_manager::{TextureHandle, TextureManager},
+    GraphicContext,
+};
+
+/// Memory budget of the undo ring of a paintable, in bytes
+pub const UNDO_BUDGET: usize = 16 * 1024 * 1024;
+/// Directory (in the resources directory) where the painted textures are saved
+pub const PAINTED_DIRECTORY: &str = "textures/painted";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    /// Not necessarily normalized
+    pub direction: Vec3,
+}
+
+impl Ray {
+    /// Ray going through the point `ndc` (in normalized device coordinates) of the screen
+    pub fn from_screen(inv_view_proj: Mat4, ndc: Vec2) -> Self {
+        let near = inv_view_proj.project_point3(ndc.extend(0.0));
+        let far = inv_view_proj.project_point3(ndc.extend(1.0));
+        Self {
+            origin: near,
+            direction: (far - near).normalize(),
+        }
+    }
+    /// The same ray in another space, distances along it are kept in units of `direction`
+    pub fn transform(&self, mat: Mat4) -> Self {
+        Self {
+            origin: mat.transform_point3(self.origin),
+            direction: mat.transform_vector3(self.direction),
+        }
+    }
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + self.direction * t
+    }
+}
+
+/// Intersection of a ray with a (double sided) triangle: the distance along the ray and the
+/// barycentric coordinates of the hit point (Möller–Trumbore).
+pub fn ray_triangle(ray: &Ray, tri: [Vec3; 3]) -> Option<(f32, Vec3)> {
+    const EPSILON: f32 = 1e-7;
+    let e1 = tri[1] - tri[0];
+    let e2 = tri[2] - tri[0];
+    let p = ray.direction.cross(e2);
+    let det = e1.dot(p);
+    if det.abs() < EPSILON {
+        return None; // parallel
+    }
+    let inv_det = 1.0 / det;
+    let s = ray.origin - tri[0];
+    let u = s.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(e1);
+    let v = ray.direction.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = e2.dot(q) * inv_det;
+    (t > EPSILON).then(|| (t, Vec3::new(1.0 - u - v, u, v)))
+}
+
+/// Interpolate per vertex UVs with barycentric coordinates
+pub fn interpolate_uv(uvs: [Vec2; 3], barycentric: Vec3) -> Vec2 {
+    uvs[0] * barycentric.x + uvs[1] * barycentric.y + uvs[2] * barycentric.z
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshHit {
+    /// Distance along the ray
+    pub distance: f32,
+    pub triangle: usize,
+    pub uv: Vec2,
+}
+
+/// Closest triangle of `mesh` hit by `ray`
+pub fn raycast_mesh(ray: &Ray, mesh: &Mesh) -> Option<MeshHit> {
+    mesh.indices
+        .iter()
+        .enumerate()
+        .filter_map(|(triangle, tri)| {
+            let vertices = tri.map(|i| mesh.vertices[i as usize]);
+            let (distance, barycentric) = ray_triangle(ray, vertices.map(|v| v.position))?;
+            Some(MeshHit {
+                distance,
+                triangle,
+                uv: interpolate_uv(vertices.map(|v| v.tex_coords), barycentric),
+            })
+        })
+        .min_by(|a, b| a.distance.total_cmp(&b.distance))
+}
+
+/// Brush weight at `distance` from its center: 1 up to `radius * hardness`, then smoothly down to
+/// 0 at `radius`.
+pub fn brush_falloff(distance: f32, radius: f32, hardness: f32) -> f32 {
+    let inner = radius * hardness.clamp(0.0, 1.0);
+    if distance >= radius {
+        0.0
+    } else if distance <= inner {
+        1.0
+    } else {
+        let t = (distance - inner) / (radius - inner);
+        1.0 - t * t * (3.0 - 2.0 * t)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Brush {
+    /// In texels
+    pub radius: f32,
+    /// Fraction of the radius painted at full strength
+    pub hardness: f32,
+    /// RGBA, in the texture's encoding (sRGB for albedo textures)
+    pub color: [u8; 4],
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Self {
+            radius: 8.0,
+            hardness: 0.5,
+            color: [255, 255, 255, 255],
+        }
+    }
+}
+
+/// A rectangle of texels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DirtyRect {
+    /// Smallest rectangle containing both
+    pub fn union(self, other: Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Self {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+    /// Merge `rect` into an accumulated region
+    pub fn coalesce(acc: Option<Self>, rect: Self) -> Option<Self> {
+        Some(acc.map_or(rect, |acc| acc.union(rect)))
+    }
+    pub fn area(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+/// CPU copy of an RGBA8 texture
+#[derive(Clone)]
+pub struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    pub fn new(image: image::RgbaImage) -> Self {
+        Self {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.into_raw(),
+        }
+    }
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+    /// Copy of the pixels of `rect`, as tightly packed rows
+    pub fn read(&self, rect: DirtyRect) -> Vec<u8> {
+        Self::read_from(&self.pixels, self.width, rect)
+    }
+    fn read_from(pixels: &[u8], width: u32, rect: DirtyRect) -> Vec<u8> {
+        let row = rect.width as usize * 4;
+        let mut res = Vec::with_capacity(rect.height as usize * row);
+        for y in rect.y..rect.y + rect.height {
+            let start = (y as usize * width as usize + rect.x as usize) * 4;
+            res.extend_from_slice(&pixels[start..start + row]);
+        }
+        res
+    }
+    /// Overwrite the pixels of `rect` (the inverse of `Canvas::read`)
+    pub fn write(&mut self, rect: DirtyRect, data: &[u8]) {
+        let row = rect.width as usize * 4;
+        for (i, y) in (rect.y..rect.y + rect.height).enumerate() {
+            let start = (y as usize * self.width as usize + rect.x as usize) * 4;
+            self.pixels[start..start + row].copy_from_slice(&data[i * row..(i + 1) * row]);
+        }
+    }
+    /// Paint a brush dab centered on `uv` (wrapped to 0..1), returns the texels touched
+    pub fn splat(&mut self, brush: &Brush, uv: Vec2) -> Option<DirtyRect> {
+        let uv = Vec2::new(uv.x.rem_euclid(1.0), uv.y.rem_euclid(1.0));
+        let center = uv * Vec2::new(self.width as f32, self.height as f32);
+        let min = (center - brush.radius).floor().max(Vec2::ZERO);
+        let max = (center + brush.radius)
+            .ceil()
+            .min(Vec2::new(self.width as f32, self.height as f32));
+        if min.x >= max.x || min.y >= max.y {
+            return None;
+        }
+        let rect = DirtyRect {
+            x: min.x as u32,
+            y: min.y as u32,
+            width: (max.x - min.x) as u32,
+            height: (max.y - min.y) as u32,
+        };
+        let opacity = brush.color[3] as f32 / 255.0;
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                let texel = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+                let weight =
+                    brush_falloff(texel.distance(center), brush.radius, brush.hardness) * opacity;
+                if weight <= 0.0 {
+                    continue;
+                }
+                let i = (y as usize * self.width as usize + x as usize) * 4;
+                for (c, &target) in self.pixels[i..i + 4].iter_mut().zip(&brush.color) {
+                    *c = (*c as f32 + (target as f32 - *c as f32) * weight).round() as u8;
+                }
+            }
+        }
+        Some(rect)
+    }
+    pub fn save_png(&self, path: impl AsRef<Path>) -> Result<()> {
+        image::save_buffer_with_format(
+            path,
+            &self.pixels,
+            self.width,
+            self.height,
+            image::ColorType::Rgba8,
+            image::ImageFormat::Png,
+        )?;
+        Ok(())
+    }
+}
+
+/// Pixels of a region before a stroke
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub rect: DirtyRect,
+    pub pixels: Vec<u8>,
+}
+
+/// Ring of snapshots, dropping the oldest ones to stay within a memory budget
+#[derive(Debug, Clone)]
+pub struct UndoRing {
+    snapshots: VecDeque<Snapshot>,
+    budget: usize,
+    used: usize,
+}
+
+impl UndoRing {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            snapshots: VecDeque::new(),
+            budget,
+            used: 0,
+        }
+    }
+    /// A snapshot larger than the whole budget clears the ring, as restoring the older ones
+    /// without it would leave a mix of both states.
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if snapshot.pixels.len() > self.budget {
+            self.snapshots.clear();
+            self.used = 0;
+            return;
+        }
+        while self.used + snapshot.pixels.len() > self.budget {
+            let oldest = self.snapshots.pop_front().unwrap();
+            self.used -= oldest.pixels.len();
+        }
+        self.used += snapshot.pixels.len();
+        self.snapshots.push_back(snapshot);
+    }
+    pub fn pop(&mut self) -> Option<Snapshot> {
+        let snapshot = self.snapshots.pop_back()?;
+        self.used -= snapshot.pixels.len();
+        Some(snapshot)
+    }
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+    /// Bytes used by the snapshots
+    pub fn used(&self) -> usize {
+        self.used
+    }
+}
+
+struct Stroke {
+    /// The canvas at the start of the stroke
+    before: Vec<u8>,
+    rect: Option<DirtyRect>,
+}
+
+/// An entity whose texture can be painted on. Its mesh must be added with `keep_cpu_data` (see
+/// `MeshManager::add_with`), and `texture` used in its material.
+pub struct PaintableComponent {
+    texture: TextureHandle,
+    canvas: Canvas,
+    /// Region not uploaded yet
+    dirty: Option<DirtyRect>,
+    stroke: Option<Stroke>,
+    undo: UndoRing,
+}
+
+impl PaintableComponent {
+    /// Create the (sRGB) texture holding `image`, see `PaintableComponent::texture`
+    pub fn new(gfx: &mut GraphicContext, image: image::RgbaImage) -> Self {
+        let canvas = Canvas::new(image);
+        let texture = TextureManager::create_texture_from_bytes(
+            &gfx.device,
+            &gfx.queue,
+            canvas.pixels(),
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            canvas.width(),
+            canvas.height(),
+            wgpu::TextureUsages::TEXTURE_BINDING,
+            4,
+        );
+        let info = TextureInfo::new(
+            canvas.width(),
+            canvas.height(),
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+        );
+        Self {
+            texture: gfx.texture_manager.add_texture(texture, info),
+            canvas,
+            dirty: None,
+            stroke: None,
+            undo: UndoRing::new(UNDO_BUDGET),
+        }
+    }
+    pub fn texture(&self) -> TextureHandle {
+        self.texture
+    }
+    pub fn canvas(&self) -> &Canvas {
+        &self.canvas
+    }
+    pub fn undo_ring(&self) -> &UndoRing {
+        &self.undo
+    }
+    /// Paint a dab, starting a stroke if there isn't one
+    pub fn paint(&mut self, brush: &Brush, uv: Vec2) {
+        let canvas = &mut self.canvas;
+        let stroke = self.stroke.get_or_insert_with(|| Stroke {
+            before: canvas.pixels.clone(),
+            rect: None,
+        });
+        if let Some(rect) = canvas.splat(brush, uv) {
+            stroke.rect = DirtyRect::coalesce(stroke.rect, rect);
+            self.dirty = DirtyRect::coalesce(self.dirty, rect);
+        }
+    }
+    /// Record the current stroke in the undo ring
+    pub fn end_stroke(&mut self) {
+        if let Some(Stroke {
+            before,
+            rect: Some(rect),
+        }) = self.stroke.take()
+        {
+            self.undo.push(Snapshot {
+                rect,
+                pixels: Canvas::read_from(&before, self.canvas.width, rect),
+            });
+        }
+    }
+    /// Revert the last stroke, false if there was nothing to undo
+    pub fn undo(&mut self) -> bool {
+        self.end_stroke();
+        match self.undo.pop() {
+            Some(snapshot) => {
+                self.canvas.write(snapshot.rect, &snapshot.pixels);
+                self.dirty = DirtyRect::coalesce(self.dirty, snapshot.rect);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Upload the region changed since the last flush
+    pub fn flush(&mut self, queue: &wgpu::Queue, textures: &TextureManager) {
+        let rect = match self.dirty.take() {
+            Some(rect) => rect,
+            None => return,
+        };
+        if let Some(texture) = textures.get_texture(self.texture) {
+            TextureManager::write_texture_region(
+                queue,
+                texture,
+                (rect.x, rect.y),
+                rect.width,
+                rect.height,
+                &self.canvas.read(rect),
+                4,
+            );
+        }
+    }
+}
+
+/// The painting tool: its settings, window and input state.
+pub struct TexturePaintTool {
+    pub enabled: bool,
+    pub brush: Brush,
+    /// Cursor position, in physical pixels
+    cursor: Option<Vec2>,
+    pressed: bool,
+    undo_requested: bool,
+    save_requested: bool,
+    file_name: String,
+    /// Last entity painted on, the one undo and save act on
+    target: Option<Entity>,
+}
+
+impl TexturePaintTool {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            brush: Brush::default(),
+            cursor: None,
+            pressed: false,
+            undo_requested: false,
+            save_requested: false,
+            file_name: "painted".to_owned(),
+            target: None,
+        }
+    }
+    pub fn cursor_moved(&mut self, position: Vec2) {
+        self.cursor = Some(position);
+    }
+    pub fn cursor_left(&mut self) {
+        self.cursor = None;
+        self.pressed = false;
+    }
+    /// Left mouse button state (not captured by the UI)
+    pub fn set_pressed(&mut self, pressed: bool) {
+        self.pressed = pressed;
+    }
+    /// Where a texture named `name` is saved
+    pub fn save_path(name: &str) -> PathBuf {
+        rmanage::instance()
+            .directory()
+            .join(PAINTED_DIRECTORY)
+            .join(format!("{name}.png"))
+    }
+
+    /// Draw the tool's window
+    pub(super) fn ui(&mut self, ctx: &egui::Context, focus: &mut UiFocus, loc: &mut Localization) {
+        focus.begin_panel("paint");
+        egui::Window::new(tr!(loc, "paint.window")).show(ctx, |ui| {
+            focus.track(ui.checkbox(&mut self.enabled, tr!(loc, "paint.enabled")));
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut self.brush.radius, 1.0..=128.0)
+                        .text(tr!(loc, "paint.radius")),
+                ),
+            );
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut self.brush.hardness, 0.0..=1.0)
+                        .text(tr!(loc, "paint.hardness")),
+                ),
+            );
+            let [r, g, b, a] = self.brush.color;
+            let mut color = egui::Color32::from_rgba_unmultiplied(r, g, b, a);
+            ui.horizontal(|ui| {
+                ui.label(tr!(loc, "paint.color"));
+                focus.track(ui.color_edit_button_srgba(&mut color));
+            });
+            self.brush.color = color.to_srgba_unmultiplied();
+            ui.horizontal(|ui| {
+                self.undo_requested |= focus.track(ui.button(tr!(loc, "paint.undo"))).clicked();
+                focus.track(ui.text_edit_singleline(&mut self.file_name));
+                self.save_requested |= focus.track(ui.button(tr!(loc, "paint.save"))).clicked();
+            });
+        });
+        let input = ctx.input();
+        self.undo_requested |=
+            self.enabled && input.modifiers.command && input.key_pressed(egui::Key::Z);
+    }
+
+    /// Paint under the cursor and upload the changes of the paintables
+    pub fn paint(
+        &mut self,
+        ctx: &GraphicContext,
+        wr: &WorldRenderer,
+        paintables: Entities<(
+            Entity,
+            &GraphicsComponent,
+            Option<&TransformsComponent>,
+            &mut PaintableComponent,
+        )>,
+    ) {
+        let mut paintables = paintables.collect::<Vec<_>>();
+        let ray = self
+            .cursor
+            .filter(|_| self.enabled && self.pressed)
+            .map(|cursor| {
+                let size = Vec2::new(ctx.size.width as f32, ctx.size.height as f32);
+                let ndc = Vec2::new(cursor.x / size.x * 2.0 - 1.0, 1.0 - cursor.y / size.y * 2.0);
+                Ray::from_screen(wr.camera.get_view_projection().inverse(), ndc)
+            });
+
+        if let Some(ray) = ray {
+            let hit = paintables
+                .iter()
+                .enumerate()
+                .filter_map(|(i, (_, gfc, tsm, _))| {
+                    let mesh = ctx.mesh_manager.get_cpu(gfc.mesh)?;
+                    let model = tsm.map(|tsm| tsm.mat()).unwrap_or(Mat4::IDENTITY);
+                    let local = ray.transform(model.inverse());
+                    let hit = raycast_mesh(&local, mesh)?;
+                    let distance = model
+                        .transform_point3(local.at(hit.distance))
+                        .distance(ray.origin);
+                    Some((i, distance, hit.uv))
+                })
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            if let Some((i, _, uv)) = hit {
+                let entity = paintables[i].0;
+                if self.target != Some(entity) {
+                    for (.., paintable) in paintables.iter_mut() {
+                        paintable.end_stroke();
+                    }
+                    self.target = Some(entity);
+                }
+                paintables[i].3.paint(&self.brush, uv);
+            }
+        } else {
+            for (.., paintable) in paintables.iter_mut() {
+                paintable.end_stroke();
+            }
+        }
+
+        let target = paintables
+            .iter_mut()
+            .find(|(entity, ..)| Some(*entity) == self.target);
+        if let Some((_, _, _, paintable)) = target {
+            if std::mem::take(&mut self.undo_requested) {
+                paintable.undo();
+            }
+            if std::mem::take(&mut self.save_requested) {
+                paintable.end_stroke();
+                let path = Self::save_path(&self.file_name);
+                match self.save(paintable, &path) {
+                    Ok(()) => log::info!("Saved painted texture to {}", path.display()),
+                    Err(e) => log::error!("Couldn't save painted texture: {e:#}"),
+                }
+            }
+        } else {
+            self.undo_requested = false;
+            self.save_requested = false;
+        }
+
+        for (_, _, _, paintable) in paintables {
+            paintable.flush(&ctx.queue, &ctx.texture_manager);
+        }
+    }
+
+    fn save(&self, paintable: &PaintableComponent, path: &Path) -> Result<()> {
+        if self.file_name.is_empty() || self.file_name.contains(['/', '\\']) {
+            return Err(anyhow!("Invalid file name '{}'", self.file_name));
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Couldn't create {}", dir.display()))?;
+        }
+        paintable.canvas.save_png(path)
+    }
+}
+
+impl Default for TexturePaintTool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRI: [Vec3; 3] = [Vec3::ZERO, Vec3::X, Vec3::Y];
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> DirtyRect {
+        DirtyRect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn ray_triangle_hit() {
+        let ray = Ray {
+            origin: Vec3::new(0.25, 0.5, -2.0),
+            direction: Vec3::Z,
+        };
+        let (t, bary) = ray_triangle(&ray, TRI).unwrap();
+        assert!((t - 2.0).abs() < 1e-5);
+        assert!((bary - Vec3::new(0.25, 0.25, 0.5)).length() < 1e-5);
+        // Both sides are hit
+        let back = Ray {
+            origin: Vec3::new(0.25, 0.5, 2.0),
+            direction: -Vec3::Z,
+        };
+        assert!(ray_triangle(&back, TRI).is_some());
+        // Outside, behind and parallel
+        let outside = Ray {
+            origin: Vec3::new(0.75, 0.75, -2.0),
+            direction: Vec3::Z,
+        };
+        assert_eq!(None, ray_triangle(&outside, TRI));
+        let behind = Ray {
+            origin: Vec3::new(0.25, 0.25, 2.0),
+            direction: Vec3::Z,
+        };
+        assert_eq!(None, ray_triangle(&behind, TRI));
+        let parallel = Ray {
+            origin: Vec3::new(-1.0, 0.25, 0.0),
+            direction: Vec3::X,
+        };
+        assert_eq!(None, ray_triangle(&parallel, TRI));
+    }
+
+    #[test]
+    fn barycentric_uv() {
+        let uvs = [
+            Vec2::new(0.0, 1.0),
+            Vec2::new(1.0, 1.0),
+            Vec2::new(0.0, 0.0),
+        ];
+        assert_eq!(uvs[1], interpolate_uv(uvs, Vec3::Y));
+        let ray = Ray {
+            origin: Vec3::new(0.5, 0.25, 1.0),
+            direction: -Vec3::Z,
+        };
+        let (_, bary) = ray_triangle(&ray, TRI).unwrap();
+        let uv = interpolate_uv(uvs, bary);
+        assert!((uv - Vec2::new(0.5, 0.75)).length() < 1e-5);
+
+        // Closest triangle of a mesh
+        let vertex = |position: Vec3, tex_coords: Vec2| super::super::mesh_manager::Vertex {
+            position,
+            normal: Vec3::Z,
+            tex_coords,
+            tangent: Vec3::X,
+        };
+        let mut vertices: Vec<_> = TRI.iter().zip(uvs).map(|(&p, uv)| vertex(p, uv)).collect();
+        vertices.extend(TRI.iter().map(|&p| vertex(p + Vec3::Z * 0.5, Vec2::ZERO)));
+        let mesh = Mesh {
+            vertices,
+            indices: vec![[0, 1, 2], [3, 4, 5]],
+        };
+        let hit = raycast_mesh(&ray, &mesh).unwrap();
+        assert_eq!(1, hit.triangle);
+        assert!((hit.distance - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn screen_ray() {
+        let view_proj = Mat4::perspective_lh(1.0, 1.0, 0.1, 100.0);
+        let ray = Ray::from_screen(view_proj.inverse(), Vec2::ZERO);
+        assert!((ray.direction - Vec3::Z).length() < 1e-4);
+        assert!((ray.origin.z - 0.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn falloff() {
+        assert_eq!(1.0, brush_falloff(0.0, 10.0, 0.5));
+        assert_eq!(1.0, brush_falloff(5.0, 10.0, 0.5));
+        assert_eq!(0.5, brush_falloff(7.5, 10.0, 0.5));
+        assert_eq!(0.0, brush_falloff(10.0, 10.0, 0.5));
+        assert_eq!(0.0, brush_falloff(20.0, 10.0, 1.0));
+        // Hard brushes are flat, soft ones decrease from the center
+        assert_eq!(1.0, brush_falloff(9.9, 10.0, 1.0));
+        let mut last = 1.0;
+        for d in 1..10 {
+            let w = brush_falloff(d as f32, 10.0, 0.0);
+            assert!(w < last);
+            last = w;
+        }
+    }
+
+    #[test]
+    fn dirty_rect_coalescing() {
+        let a = rect(2, 3, 4, 4);
+        assert_eq!(a, a.union(a));
+        assert_eq!(rect(2, 3, 10, 10), a.union(rect(10, 10, 2, 3)));
+        assert_eq!(rect(0, 0, 6, 7), rect(0, 0, 1, 1).union(a));
+        let total = [a, rect(8, 1, 1, 1), rect(3, 4, 1, 1)]
+            .into_iter()
+            .fold(None, DirtyRect::coalesce);
+        assert_eq!(Some(rect(2, 1, 7, 6)), total);
+    }
+
+    #[test]
+    fn splat() {
+        let mut canvas = Canvas::new(image::RgbaImage::new(16, 16));
+        let brush = Brush {
+            radius: 2.0,
+            hardness: 1.0,
+            color: [255, 0, 0, 255],
+        };
+        let dirty = canvas.splat(&brush, Vec2::splat(0.5)).unwrap();
+        assert_eq!(rect(6, 6, 4, 4), dirty);
+        assert_eq!(&[255, 0, 0, 255], &canvas.read(rect(8, 8, 1, 1))[..]);
+        // Nothing outside the dirty rect changed
+        let inside = canvas
+            .read(dirty)
+            .iter()
+            .map(|&c| c as usize)
+            .sum::<usize>();
+        let total = canvas.pixels().iter().map(|&c| c as usize).sum::<usize>();
+        assert_eq!(inside, total);
+        // Clamped to the canvas
+        assert_eq!(Some(rect(0, 0, 2, 2)), canvas.splat(&brush, Vec2::ZERO));
+    }
+
+    #[test]
+    fn undo_ring() {
+        let snapshot = |x: u32, size: usize| Snapshot {
+            rect: rect(x, 0, 1, 1),
+            pixels: vec![0; size],
+        };
+        let mut ring = UndoRing::new(10);
+        ring.push(snapshot(0, 4));
+        ring.push(snapshot(1, 4));
+        assert_eq!(8, ring.used());
+        // Evicts the oldest
+        ring.push(snapshot(2, 4));
+        assert_eq!(2, ring.len());
+        assert_eq!(8, ring.used());
+        assert_eq!(2, ring.pop().unwrap().rect.x);
+        assert_eq!(1, ring.pop().unwrap().rect.x);
+        assert_eq!(None, ring.pop());
+        assert_eq!(0, ring.used());
+        // Too large, clears it
+        ring.push(snapshot(0, 4));
+        ring.push(snapshot(1, 11));
+        assert!(ring.is_empty());
+        assert_eq!(0, ring.used());
+    }
+
+    #[test]
+    fn stroke_undo() {
+        let canvas = Canvas::new(image::RgbaImage::new(8, 8));
+        let brush = Brush {
+            radius: 1.5,
+            hardness: 1.0,
+            color: [10, 20, 30, 255],
+        };
+        let original = canvas.pixels().to_vec();
+        let mut paintable = PaintableComponent {
+            texture: TextureHandle::default(),
+            canvas,
+            dirty: None,
+            stroke: None,
+            undo: UndoRing::new(UNDO_BUDGET),
+        };
+        paintable.paint(&brush, Vec2::splat(0.25));
+        paintable.paint(&brush, Vec2::splat(0.75));
+        paintable.end_stroke();
+        let first = paintable.canvas().pixels().to_vec();
+        paintable.paint(&brush, Vec2::new(0.25, 0.75));
+        // The current stroke is only recorded when it ends (undo ends it)
+        assert_eq!(1, paintable.undo_ring().len());
+
+        assert!(paintable.undo());
+        assert_eq!(first, paintable.canvas().pixels());
+        assert!(paintable.undo());
+        assert_eq!(original, paintable.canvas().pixels());
+        assert!(!paintable.undo());
+        // Everything painted since the last flush is pending
+        assert_eq!(Some(rect(0, 0, 8, 8)), paintable.dirty);
+    }
+}
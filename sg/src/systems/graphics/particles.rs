@@ -0,0 +1,1404 @@
+//! Particle effects.
+//!
+//! Emitters are simulated on the GPU when the device has compute shaders, indirect draws and
+//! storage buffers in vertex shaders. Each frame a compute pass moves, ages and respawns the
+//! particles of an emitter, and appends the alive ones to a draw list keyed by their view depth.
+//! The list is then bitonic sorted (back to front, for the alpha blending) and drawn with an
+//! indirect draw whose instance count was counted by the simulation, so particles are never read
+//! back. Other devices (or emitters too large for a storage buffer) run the same simulation on
+//! the CPU.
+//!
+//! With a fixed timestep, a simulation is reproducible: the same particles are alive with the
+//! same state, though on the GPU not necessarily in the same slots (see `state_hash`).
+//!
+//! The GPU backend is tested with 50k particles (`gpu_many_particles`) for correctness only: every
+//! particle is alive and drawn once, back to front. The test prints the frame time, but no frame
+//! rate is asserted, and none has been measured on real hardware yet.
+
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroU64,
+    sync::mpsc,
+};
+
+use ecs::Entity;
+use glam::{Mat4, Vec3, Vec4};
+use wgpu::util::{DeviceExt, DrawIndirect};
+
+use crate::include_shader;
+
+use super::{
+    camera::Camera,
+    pipeline::{ComputePipeline, RenderPipeline},
+    texture_manager::TextureManager,
+};
+
+/// Workgroup size of the compute shaders
+const WG_SIZE: u32 = 64;
+/// Vertices of a particle quad (two triangles)
+const QUAD_VERTICES: u32 = 6;
+/// Sort key of the unused entries of a draw list, sorted after every particle
+const NO_KEY: f32 = 3.0e38;
+/// Largest draw list that can be sorted
+const MAX_SORT_SIZE: u32 = 1 << 22;
+/// Offset of the instance count in the indirect draw arguments
+pub const INSTANCE_COUNT_OFFSET: u64 = 4;
+
+/// What an emitter spawns
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmitterParams {
+    /// Maximum number of particles alive at once
+    pub capacity: u32,
+    /// Particles spawned per second
+    pub rate: f32,
+    /// Initial velocity of the particles
+    pub velocity: Vec3,
+    /// Random offset added to the velocity, on each axis
+    pub spread: f32,
+    pub gravity: Vec3,
+    /// Lifetime of the particles, in seconds
+    pub lifetime: f32,
+    /// Average size of the particles (their size varies by 25% either way)
+    pub size: f32,
+    /// Color of the particles, they fade out over their life
+    pub color: Vec4,
+    pub seed: u32,
+    /// Half size of the box particles spawn in, around the emitter
+    pub volume: Vec3,
+    /// Particles going below this height die (collision with the ground)
+    pub kill_height: f32,
+}
+
+impl Default for EmitterParams {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            rate: 100.0,
+            velocity: Vec3::Y,
+            spread: 0.5,
+            gravity: Vec3::new(0.0, -9.81, 0.0),
+            lifetime: 2.0,
+            size: 0.1,
+            color: Vec4::ONE,
+            seed: 0,
+            volume: Vec3::ZERO,
+            kill_height: f32::MIN,
+        }
+    }
+}
+
+/// A particle emitter, at the translation of the entity's TransformsComponent
+#[derive(Debug, Clone)]
+pub struct ParticleEmitterComponent {
+    /// Can be changed at any time, changing the capacity restarts the simulation
+    pub params: EmitterParams,
+    /// Stop simulating and spawning, the particles stay where they are
+    pub paused: bool,
+    /// Simulate with this timestep instead of the frame's, for reproducible simulations
+    pub fixed_step: Option<f32>,
+    /// Fraction of a particle left to spawn
+    spawn_accumulator: f32,
+    frame: u32,
+}
+
+impl ParticleEmitterComponent {
+    pub fn new(params: EmitterParams) -> Self {
+        Self {
+            params,
+            paused: false,
+            fixed_step: None,
+            spawn_accumulator: 0.0,
+            frame: 0,
+        }
+    }
+    /// Advance to the next step of the simulation
+    fn step(&mut self, delta: f32) -> SimStep {
+        if self.paused {
+            return SimStep {
+                dt: 0.0,
+                spawn: 0,
+                frame: self.frame,
+            };
+        }
+        let dt = self.fixed_step.unwrap_or(delta);
+        self.spawn_accumulator += self.params.rate * dt;
+        let spawn = self.spawn_accumulator.floor();
+        self.spawn_accumulator -= spawn;
+        self.frame = self.frame.wrapping_add(1);
+        SimStep {
+            dt,
+            spawn: spawn as u32,
+            frame: self.frame,
+        }
+    }
+}
+
+/// A step of simulation of an emitter
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SimStep {
+    dt: f32,
+    /// Particles to spawn
+    spawn: u32,
+    /// Index of the step, to seed the spawned particles
+    frame: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Particle {
+    pub position: Vec3,
+    /// Dead once it reaches the lifetime of the emitter
+    pub age: f32,
+    pub velocity: Vec3,
+    /// Random value of the particle, decides its size
+    pub seed: u32,
+}
+
+impl Particle {
+    const DEAD: Self = Self {
+        position: Vec3::ZERO,
+        age: f32::MAX,
+        velocity: Vec3::ZERO,
+        seed: 0,
+    };
+}
+
+/// Integer hash (PCG) used as random number generator, same as the shader's
+pub fn hash(value: u32) -> u32 {
+    let state = value.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+/// Map a random value to [-1, 1], same as the shader's
+fn signed_unorm(value: u32) -> f32 {
+    value as f32 / 2147483647.5 - 1.0
+}
+
+/// Size of a particle, same as the shader's
+fn particle_size(size: f32, seed: u32) -> f32 {
+    size * (0.75 + 0.5 * (seed & 0xffff) as f32 / 65535.0)
+}
+
+/// The particle spawned by the nth spawn ticket of a step
+fn spawn(params: &EmitterParams, frame: u32, ticket: u32, position: Vec3) -> Particle {
+    let seed = hash(params.seed ^ hash(frame ^ hash(ticket)));
+    let x = hash(seed);
+    let y = hash(x);
+    let z = hash(y);
+    let offset = Vec3::new(signed_unorm(x), signed_unorm(y), signed_unorm(z));
+    let u = hash(z);
+    let v = hash(u);
+    let w = hash(v);
+    let spawn_offset = Vec3::new(signed_unorm(u), signed_unorm(v), signed_unorm(w));
+    Particle {
+        position: position + spawn_offset * params.volume,
+        age: 0.0,
+        velocity: params.velocity + offset * params.spread,
+        seed,
+    }
+}
+
+/// Order independent hash of the alive particles, to compare simulations
+pub fn state_hash(particles: &[Particle], lifetime: f32) -> u64 {
+    particles
+        .iter()
+        .filter(|p| p.age < lifetime)
+        .fold(0u64, |acc, p| {
+            // FNV-1a of the words of the particle
+            let words: &[u32] = bytemuck::cast_slice(std::slice::from_ref(p));
+            let hash = words.iter().fold(0xcbf29ce484222325u64, |h, w| {
+                (h ^ *w as u64).wrapping_mul(0x100000001b3)
+            });
+            acc.wrapping_add(hash)
+        })
+}
+
+/// The simulation of an emitter on the CPU, mirrors particles.wgsl
+pub struct CpuParticles {
+    particles: Vec<Particle>,
+}
+
+impl CpuParticles {
+    pub fn new(capacity: u32) -> Self {
+        Self {
+            particles: vec![Particle::DEAD; capacity as usize],
+        }
+    }
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+    fn step(&mut self, params: &EmitterParams, step: SimStep, position: Vec3) {
+        let mut ticket = 0;
+        for p in &mut self.particles {
+            if p.age >= params.lifetime {
+                if ticket < step.spawn {
+                    *p = spawn(params, step.frame, ticket, position);
+                    ticket += 1;
+                }
+            } else {
+                p.velocity += params.gravity * step.dt;
+                p.position += p.velocity * step.dt;
+                p.age += step.dt;
+                if p.position.y < params.kill_height {
+                    p.age = params.lifetime;
+                }
+            }
+        }
+    }
+}
+
+/// The compare and exchange passes (k, j) of a bitonic sort of n elements (a power of two). The
+/// passes of n are the first passes of any larger size.
+pub fn bitonic_passes(n: u32) -> Vec<(u32, u32)> {
+    let mut passes = Vec::new();
+    let mut k = 2;
+    while k <= n {
+        let mut j = k / 2;
+        while j > 0 {
+            passes.push((k, j));
+            j /= 2;
+        }
+        k *= 2;
+    }
+    passes
+}
+
+/// A pass of bitonic sort, same as particles_sort.wgsl
+pub fn bitonic_pass(keys: &mut [f32], values: &mut [u32], k: u32, j: u32) {
+    for i in 0..keys.len() {
+        let l = i ^ j as usize;
+        if l <= i || l >= keys.len() {
+            continue;
+        }
+        let ascending = i & k as usize == 0;
+        let (a, b) = (keys[i], keys[l]);
+        if (ascending && a > b) || (!ascending && a < b) {
+            keys.swap(i, l);
+            values.swap(i, l);
+        }
+    }
+}
+
+/// Arguments of the indirect draw of the particles, the simulation counts the instances
+pub fn indirect_args(instance_count: u32) -> DrawIndirect {
+    DrawIndirect {
+        vertex_count: QUAD_VERTICES,
+        instance_count,
+        base_vertex: 0,
+        base_instance: 0,
+    }
+}
+
+/// Indirect draw arguments followed by the spawn tickets counter, reset every step
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct Counters {
+    args: [u32; 4],
+    spawned: u32,
+    padding: [u32; 3],
+}
+
+impl Counters {
+    fn new() -> Self {
+        let args = indirect_args(0);
+        Self {
+            args: bytemuck::pod_read_unaligned(args.as_bytes()),
+            spawned: 0,
+            padding: [0; 3],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParticleBackend {
+    /// Compute shaders and indirect draws
+    Gpu,
+    Cpu,
+}
+
+impl ParticleBackend {
+    /// The backend a device can run
+    pub fn select(downlevel: &wgpu::DownlevelCapabilities, limits: &wgpu::Limits) -> Self {
+        let flags = wgpu::DownlevelFlags::COMPUTE_SHADERS
+            | wgpu::DownlevelFlags::INDIRECT_EXECUTION
+            | wgpu::DownlevelFlags::VERTEX_STORAGE;
+        let supported = downlevel.flags.contains(flags)
+            && limits.max_storage_buffers_per_shader_stage >= 4
+            && limits.max_compute_workgroup_size_x >= WG_SIZE
+            && limits.max_compute_invocations_per_workgroup >= WG_SIZE;
+        match supported {
+            true => Self::Gpu,
+            false => Self::Cpu,
+        }
+    }
+    /// The backend of an emitter, emitters too large for the GPU backend fall back to the CPU
+    fn for_capacity(self, capacity: u32, limits: &wgpu::Limits) -> Self {
+        let size = capacity as u64 * std::mem::size_of::<Particle>() as u64;
+        let fits = size <= limits.max_storage_buffer_binding_size as u64
+            && capacity.next_power_of_two() <= MAX_SORT_SIZE;
+        match (self, fits) {
+            (Self::Gpu, true) => Self::Gpu,
+            _ => Self::Cpu,
+        }
+    }
+}
+
+/// The camera, as seen by the particles
+#[derive(Debug, Clone, Copy)]
+pub struct ParticleCamera {
+    pub view_proj: Mat4,
+    pub position: Vec3,
+    pub right: Vec3,
+    pub up: Vec3,
+    pub forward: Vec3,
+}
+
+impl ParticleCamera {
+    pub fn new(camera: &Camera) -> Self {
+        let rotation = camera.get_rotation();
+        Self {
+            view_proj: camera.get_view_projection(),
+            position: camera.get_position(),
+            right: rotation * Vec3::X,
+            up: rotation * Vec3::Y,
+            forward: rotation * Vec3::Z,
+        }
+    }
+    fn depth(&self, position: Vec3) -> f32 {
+        (position - self.position).dot(self.forward)
+    }
+}
+
+/// Uniforms of particles.wgsl and particles_draw.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct SimParams {
+    view_proj: Mat4,
+    camera_position: Vec4,
+    camera_right: Vec4,
+    camera_up: Vec4,
+    camera_forward: Vec4,
+    color: Vec4,
+    position: Vec3,
+    spread: f32,
+    velocity: Vec3,
+    lifetime: f32,
+    gravity: Vec3,
+    size: f32,
+    dt: f32,
+    spawn: u32,
+    seed: u32,
+    frame: u32,
+    capacity: u32,
+    sort_size: u32,
+    kill_height: f32,
+    padding: u32,
+    volume: Vec3,
+    volume_padding: f32,
+}
+
+/// Uniforms of particles_cpu.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct CpuCamera {
+    view_proj: Mat4,
+    right: Vec4,
+    up: Vec4,
+}
+
+/// A particle drawn by the CPU backend
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct CpuInstance {
+    position: Vec3,
+    size: f32,
+    color: Vec4,
+}
+
+/// Read a buffer back, blocking until the copy is done
+fn read_buffer(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    buffer: &wgpu::Buffer,
+    size: u64,
+) -> Vec<u8> {
+    let staging = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Particles Readback"),
+        size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&Default::default());
+    encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+    queue.submit(std::iter::once(encoder.finish()));
+    let (sender, receiver) = mpsc::channel();
+    staging
+        .slice(..)
+        .map_async(wgpu::MapMode::Read, move |result| {
+            sender.send(result).ok();
+        });
+    device.poll(wgpu::Maintain::Wait);
+    receiver
+        .recv()
+        .expect("Readback dropped")
+        .expect("Couldn't map the readback buffer");
+    let bytes = staging.slice(..).get_mapped_range().to_vec();
+    staging.unmap();
+    bytes
+}
+
+/// Bitonic sort of (f32 key, u32 value) pairs, one dispatch per pass. The (k, j) of every pass
+/// are in a uniform buffer, selected with a dynamic offset.
+struct BitonicSorter {
+    pipeline: ComputePipeline,
+    passes: wgpu::Buffer,
+    /// Distance between the passes in the buffer
+    stride: u64,
+}
+
+impl BitonicSorter {
+    fn new(device: &wgpu::Device) -> Self {
+        let layout = create_bind_group_layout!(device, "Particles Sort Bindgroup Layout": {
+            0 => COMPUTE | Buffer(type: Uniform, dyn_off: true, min_size: (NonZeroU64::new(8))),
+            1 => COMPUTE | Buffer(type: Storage),
+            2 => COMPUTE | Buffer(type: Storage),
+        });
+        let pipeline = ComputePipeline::new(
+            device,
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Particles Sort Pipeline Layout"),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            }),
+            include_shader!("particles_sort.wgsl", "Particles Sort Shader"),
+            |device, layout, module| {
+                device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some("Particles Sort Pipeline"),
+                    layout: Some(layout),
+                    module,
+                    entry_point: "main",
+                })
+            },
+        );
+        let stride = device.limits().min_uniform_buffer_offset_alignment as u64;
+        let mut contents = Vec::new();
+        for (k, j) in bitonic_passes(MAX_SORT_SIZE) {
+            contents.extend_from_slice(bytemuck::cast_slice(&[k, j]));
+            contents.resize(contents.len() - 8 + stride as usize, 0);
+        }
+        let passes = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Particles Sort Passes"),
+            contents: &contents,
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        Self {
+            pipeline,
+            passes,
+            stride,
+        }
+    }
+    fn bind_group(
+        &self,
+        device: &wgpu::Device,
+        keys: &wgpu::Buffer,
+        values: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        create_bind_group!(device, &self.pipeline.pipeline.get_bind_group_layout(0), "Particles Sort Bindgroup": {
+            0 | Buffer(buffer: (&self.passes), size: (NonZeroU64::new(8))),
+            1 | Buffer(buffer: keys),
+            2 | Buffer(buffer: values),
+        })
+    }
+    /// Record the sort of n (a power of two) pairs
+    fn record<'a>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        bind_group: &'a wgpu::BindGroup,
+        n: u32,
+    ) {
+        pass.set_pipeline(&self.pipeline.pipeline);
+        for i in 0..bitonic_passes(n).len() {
+            pass.set_bind_group(0, bind_group, &[(i as u64 * self.stride) as u32]);
+            pass.dispatch_workgroups(n.div_ceil(WG_SIZE), 1, 1);
+        }
+    }
+}
+
+struct GpuPipelines {
+    clear: ComputePipeline,
+    simulate: ComputePipeline,
+    sorter: BitonicSorter,
+    draw: RenderPipeline,
+}
+
+impl GpuPipelines {
+    fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+        let sim_layout = create_bind_group_layout!(device, "Particles Simulation Bindgroup Layout": {
+            0 => COMPUTE | Buffer(type: Uniform),
+            1 => COMPUTE | Buffer(type: Storage),
+            2 => COMPUTE | Buffer(type: Storage),
+            3 => COMPUTE | Buffer(type: Storage),
+            4 => COMPUTE | Buffer(type: Storage),
+        });
+        let shader = include_shader!("particles.wgsl", "Particles Simulation Shader");
+        let compute = |entry_point: &'static str, label: &'static str| {
+            ComputePipeline::new(
+                device,
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Particles Simulation Pipeline Layout"),
+                    bind_group_layouts: &[&sim_layout],
+                    push_constant_ranges: &[],
+                }),
+                shader.clone(),
+                move |device, layout, module| {
+                    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                        label: Some(label),
+                        layout: Some(layout),
+                        module,
+                        entry_point,
+                    })
+                },
+            )
+        };
+        let clear = compute("clear", "Particles Clear Pipeline");
+        let simulate = compute("simulate", "Particles Simulation Pipeline");
+
+        let draw_layout = create_bind_group_layout!(device, "Particles Draw Bindgroup Layout": {
+            0 => VERTEX | Buffer(type: Uniform),
+            1 => VERTEX | Buffer(type: ReadOnlyStorage),
+            2 => VERTEX | Buffer(type: ReadOnlyStorage),
+        });
+        let draw = RenderPipeline::new(
+            device,
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Particles Draw Pipeline Layout"),
+                bind_group_layouts: &[&draw_layout],
+                push_constant_ranges: &[],
+            }),
+            include_shader!("particles_draw.wgsl", "Particles Draw Shader"),
+            move |device, layout, module| {
+                create_draw_pipeline(
+                    device,
+                    "Particles Draw Pipeline",
+                    layout,
+                    module,
+                    &[],
+                    format,
+                )
+            },
+        );
+        Self {
+            clear,
+            simulate,
+            sorter: BitonicSorter::new(device),
+            draw,
+        }
+    }
+}
+
+struct CpuPipeline {
+    draw: RenderPipeline,
+    camera: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl CpuPipeline {
+    fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+        let layout = create_bind_group_layout!(device, "Particles CPU Bindgroup Layout": {
+            0 => VERTEX | Buffer(type: Uniform),
+        });
+        const ATTRIBUTES: [wgpu::VertexAttribute; 3] =
+            wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32, 2 => Float32x4];
+        let draw = RenderPipeline::new(
+            device,
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Particles CPU Pipeline Layout"),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            }),
+            include_shader!("particles_cpu.wgsl", "Particles CPU Shader"),
+            move |device, layout, module| {
+                let instances = wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<CpuInstance>() as wgpu::BufferAddress,
+                    step_mode: wgpu::VertexStepMode::Instance,
+                    attributes: &ATTRIBUTES,
+                };
+                create_draw_pipeline(
+                    device,
+                    "Particles CPU Pipeline",
+                    layout,
+                    module,
+                    &[instances],
+                    format,
+                )
+            },
+        );
+        let camera = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particles CPU Camera"),
+            size: std::mem::size_of::<CpuCamera>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = create_bind_group!(device, &layout, "Particles CPU Bindgroup": {
+            0 | Buffer(buffer: (&camera)),
+        });
+        Self {
+            draw,
+            camera,
+            bind_group,
+        }
+    }
+}
+
+/// Alpha blended quads tested against (but not writing) the depth of the scene
+fn create_draw_pipeline(
+    device: &wgpu::Device,
+    label: &'static str,
+    layout: &wgpu::PipelineLayout,
+    module: &wgpu::ShaderModule,
+    buffers: &[wgpu::VertexBufferLayout],
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module,
+            entry_point: "vs_main",
+            buffers,
+        },
+        fragment: Some(wgpu::FragmentState {
+            module,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            cull_mode: None,
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: TextureManager::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// The buffers of an emitter simulated on the GPU
+struct GpuEmitter {
+    capacity: u32,
+    /// Size of the draw list, the next power of two of the capacity
+    sort_size: u32,
+    particles: wgpu::Buffer,
+    counters: wgpu::Buffer,
+    /// The draw list: indices of the alive particles, back to front once sorted
+    values: wgpu::Buffer,
+    params: wgpu::Buffer,
+    sim_bind_group: wgpu::BindGroup,
+    sort_bind_group: wgpu::BindGroup,
+    draw_bind_group: wgpu::BindGroup,
+}
+
+impl GpuEmitter {
+    fn new(device: &wgpu::Device, pipelines: &GpuPipelines, capacity: u32) -> Self {
+        let sort_size = capacity.next_power_of_two();
+        let particles = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Particles"),
+            contents: bytemuck::cast_slice(&vec![Particle::DEAD; capacity as usize]),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+        let counters = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Particles Counters"),
+            contents: bytemuck::bytes_of(&Counters::new()),
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::INDIRECT
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+        });
+        let buffer = |label, size, usage| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        let list_size = sort_size as u64 * 4;
+        let keys = buffer("Particles Keys", list_size, wgpu::BufferUsages::STORAGE);
+        let values = buffer(
+            "Particles Values",
+            list_size,
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        );
+        let params = buffer(
+            "Particles Params",
+            std::mem::size_of::<SimParams>() as u64,
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        );
+        let sim_bind_group = create_bind_group!(device, &pipelines.simulate.pipeline.get_bind_group_layout(0), "Particles Simulation Bindgroup": {
+            0 | Buffer(buffer: (&params)),
+            1 | Buffer(buffer: (&particles)),
+            2 | Buffer(buffer: (&counters)),
+            3 | Buffer(buffer: (&keys)),
+            4 | Buffer(buffer: (&values)),
+        });
+        let draw_bind_group = create_bind_group!(device, &pipelines.draw.pipeline.get_bind_group_layout(0), "Particles Draw Bindgroup": {
+            0 | Buffer(buffer: (&params)),
+            1 | Buffer(buffer: (&particles)),
+            2 | Buffer(buffer: (&values)),
+        });
+        let sort_bind_group = pipelines.sorter.bind_group(device, &keys, &values);
+        Self {
+            capacity,
+            sort_size,
+            particles,
+            counters,
+            values,
+            params,
+            sim_bind_group,
+            sort_bind_group,
+            draw_bind_group,
+        }
+    }
+    /// Read the particles back (slow, for tests and debugging)
+    fn read_particles(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<Particle> {
+        let size = self.capacity as u64 * std::mem::size_of::<Particle>() as u64;
+        bytemuck::cast_slice(&read_buffer(device, queue, &self.particles, size)).to_vec()
+    }
+}
+
+/// The simulation and instance buffer of an emitter simulated on the CPU
+struct CpuEmitter {
+    sim: CpuParticles,
+    instances: Option<wgpu::Buffer>,
+    /// Particles in the instance buffer
+    count: u32,
+}
+
+enum EmitterBackend {
+    Gpu(Box<GpuEmitter>),
+    Cpu(CpuEmitter),
+}
+
+impl EmitterBackend {
+    fn capacity(&self) -> u32 {
+        match self {
+            Self::Gpu(gpu) => gpu.capacity,
+            Self::Cpu(cpu) => cpu.sim.particles.len() as u32,
+        }
+    }
+}
+
+struct Emitter {
+    backend: EmitterBackend,
+    params: EmitterParams,
+    position: Vec3,
+    /// Step to simulate on the next record (GPU backend)
+    step: Option<SimStep>,
+}
+
+/// Simulates and draws the particle emitters
+pub struct ParticleRenderer {
+    backend: ParticleBackend,
+    limits: wgpu::Limits,
+    gpu: Option<GpuPipelines>,
+    cpu: CpuPipeline,
+    emitters: HashMap<Entity, Emitter>,
+}
+
+impl ParticleRenderer {
+    pub fn new(
+        device: &wgpu::Device,
+        downlevel: &wgpu::DownlevelCapabilities,
+        format: wgpu::TextureFormat,
+    ) -> Self {
+        let limits = device.limits();
+        let backend = ParticleBackend::select(downlevel, &limits);
+        if backend == ParticleBackend::Cpu {
+            log::info!("Particles are simulated on the CPU (missing GPU capabilities)");
+        }
+        Self {
+            backend,
+            gpu: (backend == ParticleBackend::Gpu).then(|| GpuPipelines::new(device, format)),
+            cpu: CpuPipeline::new(device, format),
+            limits,
+            emitters: HashMap::new(),
+        }
+    }
+    pub fn backend(&self) -> ParticleBackend {
+        self.backend
+    }
+    /// Advance the emitters by a frame, the GPU ones are simulated when recording
+    pub fn update<'a>(
+        &mut self,
+        device: &wgpu::Device,
+        delta: f32,
+        emitters: impl IntoIterator<Item = (Entity, &'a mut ParticleEmitterComponent, Vec3)>,
+    ) {
+        let mut seen = HashSet::new();
+        for (entity, component, position) in emitters {
+            seen.insert(entity);
+            let params = component.params;
+            let capacity = self.emitters.get(&entity).map(|e| e.backend.capacity());
+            // New emitter, or the capacity changed
+            if capacity != Some(params.capacity) {
+                let backend = match (
+                    self.backend.for_capacity(params.capacity, &self.limits),
+                    &self.gpu,
+                ) {
+                    (ParticleBackend::Gpu, Some(pipelines)) => EmitterBackend::Gpu(Box::new(
+                        GpuEmitter::new(device, pipelines, params.capacity),
+                    )),
+                    _ => EmitterBackend::Cpu(CpuEmitter {
+                        sim: CpuParticles::new(params.capacity),
+                        instances: None,
+                        count: 0,
+                    }),
+                };
+                let emitter = Emitter {
+                    backend,
+                    params,
+                    position,
+                    step: None,
+                };
+                self.emitters.insert(entity, emitter);
+            }
+            let emitter = self.emitters.get_mut(&entity).unwrap();
+            let step = component.step(delta);
+            emitter.params = params;
+            emitter.position = position;
+            emitter.step = Some(step);
+            if let EmitterBackend::Cpu(cpu) = &mut emitter.backend {
+                cpu.sim.step(&params, step, position);
+            }
+        }
+        self.emitters.retain(|entity, _| seen.contains(entity));
+    }
+    /// Record the simulation of the GPU emitters, and update the instances of the CPU ones
+    pub fn simulate(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        camera: &ParticleCamera,
+    ) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Particles Simulation Pass"),
+        });
+        for emitter in self.emitters.values_mut() {
+            let step = match emitter.step.take() {
+                Some(step) => step,
+                None => continue,
+            };
+            match &mut emitter.backend {
+                EmitterBackend::Gpu(gpu) => {
+                    let pipelines = self
+                        .gpu
+                        .as_ref()
+                        .expect("GPU emitter without GPU pipelines");
+                    let p = &emitter.params;
+                    let params = SimParams {
+                        view_proj: camera.view_proj,
+                        camera_position: camera.position.extend(0.0),
+                        camera_right: camera.right.extend(0.0),
+                        camera_up: camera.up.extend(0.0),
+                        camera_forward: camera.forward.extend(0.0),
+                        color: p.color,
+                        position: emitter.position,
+                        spread: p.spread,
+                        velocity: p.velocity,
+                        lifetime: p.lifetime,
+                        gravity: p.gravity,
+                        size: p.size,
+                        dt: step.dt,
+                        spawn: step.spawn,
+                        seed: p.seed,
+                        frame: step.frame,
+                        capacity: gpu.capacity,
+                        sort_size: gpu.sort_size,
+                        kill_height: p.kill_height,
+                        padding: 0,
+                        volume: p.volume,
+                        volume_padding: 0.0,
+                    };
+                    queue.write_buffer(&gpu.params, 0, bytemuck::bytes_of(&params));
+                    queue.write_buffer(&gpu.counters, 0, bytemuck::bytes_of(&Counters::new()));
+                    pass.set_bind_group(0, &gpu.sim_bind_group, &[]);
+                    pass.set_pipeline(&pipelines.clear.pipeline);
+                    pass.dispatch_workgroups(gpu.sort_size.div_ceil(WG_SIZE), 1, 1);
+                    pass.set_pipeline(&pipelines.simulate.pipeline);
+                    pass.dispatch_workgroups(gpu.capacity.div_ceil(WG_SIZE), 1, 1);
+                    pipelines
+                        .sorter
+                        .record(&mut pass, &gpu.sort_bind_group, gpu.sort_size);
+                }
+                EmitterBackend::Cpu(cpu) => {
+                    let p = &emitter.params;
+                    let mut alive = cpu
+                        .sim
+                        .particles()
+                        .iter()
+                        .filter(|particle| particle.age < p.lifetime)
+                        .collect::<Vec<_>>();
+                    // Back to front
+                    alive.sort_by(|a, b| {
+                        camera
+                            .depth(b.position)
+                            .total_cmp(&camera.depth(a.position))
+                    });
+                    let instances = alive
+                        .iter()
+                        .map(|particle| CpuInstance {
+                            position: particle.position,
+                            size: particle_size(p.size, particle.seed),
+                            color: p.color
+                                * Vec4::new(1.0, 1.0, 1.0, 1.0 - particle.age / p.lifetime),
+                        })
+                        .collect::<Vec<_>>();
+                    cpu.count = instances.len() as u32;
+                    let size = (cpu.sim.particles.len().max(1) * std::mem::size_of::<CpuInstance>())
+                        as u64;
+                    let buffer = cpu.instances.get_or_insert_with(|| {
+                        device.create_buffer(&wgpu::BufferDescriptor {
+                            label: Some("Particles Instances"),
+                            size,
+                            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                            mapped_at_creation: false,
+                        })
+                    });
+                    queue.write_buffer(buffer, 0, bytemuck::cast_slice(&instances));
+                }
+            }
+        }
+        let cpu_camera = CpuCamera {
+            view_proj: camera.view_proj,
+            right: camera.right.extend(0.0),
+            up: camera.up.extend(0.0),
+        };
+        queue.write_buffer(&self.cpu.camera, 0, bytemuck::bytes_of(&cpu_camera));
+    }
+    /// Draw the particles over a view, tested against the depth of the scene. Emitters are drawn
+    /// from the farthest to the nearest, the particles of different emitters aren't mixed.
+    pub fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        camera: &ParticleCamera,
+        view: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+    ) {
+        if self.emitters.is_empty() {
+            return;
+        }
+        let mut emitters = self.emitters.values().collect::<Vec<_>>();
+        emitters.sort_by(|a, b| {
+            camera
+                .depth(b.position)
+                .total_cmp(&camera.depth(a.position))
+        });
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Particles Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        for emitter in emitters {
+            match &emitter.backend {
+                EmitterBackend::Gpu(gpu) => {
+                    let pipelines = self
+                        .gpu
+                        .as_ref()
+                        .expect("GPU emitter without GPU pipelines");
+                    pass.set_pipeline(&pipelines.draw.pipeline);
+                    pass.set_bind_group(0, &gpu.draw_bind_group, &[]);
+                    pass.draw_indirect(&gpu.counters, 0);
+                }
+                EmitterBackend::Cpu(cpu) => {
+                    let instances = match &cpu.instances {
+                        Some(instances) if cpu.count > 0 => instances,
+                        _ => continue,
+                    };
+                    pass.set_pipeline(&self.cpu.draw.pipeline);
+                    pass.set_bind_group(0, &self.cpu.bind_group, &[]);
+                    pass.set_vertex_buffer(0, instances.slice(..));
+                    pass.draw(0..QUAD_VERTICES, 0..cpu.count);
+                }
+            }
+        }
+    }
+    /// Hash of the alive particles of an emitter (see `state_hash`), reads the particles back if
+    /// it is simulated on the GPU.
+    pub fn state_hash(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        entity: Entity,
+    ) -> Option<u64> {
+        let emitter = self.emitters.get(&entity)?;
+        Some(match &emitter.backend {
+            EmitterBackend::Gpu(gpu) => {
+                state_hash(&gpu.read_particles(device, queue), emitter.params.lifetime)
+            }
+            EmitterBackend::Cpu(cpu) => state_hash(cpu.sim.particles(), emitter.params.lifetime),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::graphics::test_device::test_device;
+
+    /// Pseudo random keys, with duplicates
+    fn keys(n: usize) -> Vec<f32> {
+        (0..n as u32)
+            .map(|i| (hash(i) % 64) as f32 - 32.0)
+            .collect()
+    }
+
+    #[test]
+    fn bitonic_sort() {
+        assert_eq!(bitonic_passes(1), []);
+        assert_eq!(
+            bitonic_passes(8),
+            [(2, 1), (4, 2), (4, 1), (8, 4), (8, 2), (8, 1)]
+        );
+        assert_eq!(bitonic_passes(16)[..6], bitonic_passes(8));
+        for n in 1..70 {
+            let mut keys = keys(n);
+            let size = (n as u32).next_power_of_two() as usize;
+            keys.resize(size, NO_KEY);
+            let mut values = (0..size as u32).collect::<Vec<_>>();
+            let original = keys.clone();
+            for (k, j) in bitonic_passes(size as u32) {
+                bitonic_pass(&mut keys, &mut values, k, j);
+            }
+            assert!(keys.windows(2).all(|w| w[0] <= w[1]), "{keys:?}");
+            // Values moved with their keys
+            for (key, value) in keys.iter().zip(&values) {
+                assert_eq!(*key, original[*value as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn indirect_arguments() {
+        let words: [u32; 4] = bytemuck::pod_read_unaligned(indirect_args(42).as_bytes());
+        assert_eq!(words, [QUAD_VERTICES, 42, 0, 0]);
+        assert_eq!(words[INSTANCE_COUNT_OFFSET as usize / 4], 42);
+        // The counters start with the arguments, as the shader expects
+        let counters = Counters::new();
+        assert_eq!(counters.args, [QUAD_VERTICES, 0, 0, 0]);
+        assert_eq!(std::mem::size_of::<Counters>(), 32);
+        let bytes = bytemuck::bytes_of(&counters);
+        assert_eq!(&bytes[..16], indirect_args(0).as_bytes());
+    }
+
+    #[test]
+    fn backend_selection() {
+        let full = wgpu::DownlevelCapabilities::default();
+        let limits = wgpu::Limits::default();
+        assert_eq!(
+            ParticleBackend::select(&full, &limits),
+            ParticleBackend::Gpu
+        );
+        for missing in [
+            wgpu::DownlevelFlags::COMPUTE_SHADERS,
+            wgpu::DownlevelFlags::INDIRECT_EXECUTION,
+            wgpu::DownlevelFlags::VERTEX_STORAGE,
+        ] {
+            let downlevel = wgpu::DownlevelCapabilities {
+                flags: full.flags - missing,
+                ..full.clone()
+            };
+            assert_eq!(
+                ParticleBackend::select(&downlevel, &limits),
+                ParticleBackend::Cpu
+            );
+        }
+        let webgl = wgpu::Limits::downlevel_webgl2_defaults();
+        assert_eq!(ParticleBackend::select(&full, &webgl), ParticleBackend::Cpu);
+
+        // Emitters too large for a storage buffer fall back to the CPU
+        let gpu = ParticleBackend::Gpu;
+        assert_eq!(gpu.for_capacity(50_000, &limits), ParticleBackend::Gpu);
+        let small = wgpu::Limits {
+            max_storage_buffer_binding_size: 1 << 20,
+            ..Default::default()
+        };
+        assert_eq!(gpu.for_capacity(50_000, &small), ParticleBackend::Cpu);
+        assert_eq!(
+            ParticleBackend::Cpu.for_capacity(10, &limits),
+            ParticleBackend::Cpu
+        );
+    }
+
+    fn emitter(seed: u32) -> ParticleEmitterComponent {
+        let mut emitter = ParticleEmitterComponent::new(EmitterParams {
+            capacity: 64,
+            rate: 20.0,
+            lifetime: 1.0,
+            seed,
+            ..Default::default()
+        });
+        emitter.fixed_step = Some(0.05);
+        emitter
+    }
+
+    fn run(emitter: &mut ParticleEmitterComponent, sim: &mut CpuParticles, steps: usize) {
+        for _ in 0..steps {
+            let step = emitter.step(1.0);
+            sim.step(&emitter.params, step, Vec3::ZERO);
+        }
+    }
+
+    #[test]
+    fn cpu_simulation() {
+        let (mut a, mut b) = (emitter(7), emitter(7));
+        let (mut sim_a, mut sim_b) = (CpuParticles::new(64), CpuParticles::new(64));
+        run(&mut a, &mut sim_a, 100);
+        run(&mut b, &mut sim_b, 100);
+        // 20 particles per second living 1 second
+        let alive = |sim: &CpuParticles| sim.particles().iter().filter(|p| p.age < 1.0).count();
+        assert_eq!(alive(&sim_a), 20);
+        assert!(sim_a
+            .particles()
+            .iter()
+            .all(|p| p.age >= 1.0 || p.position.y < 1.0));
+        // Reproducible with a fixed step and seed
+        let hash = state_hash(sim_a.particles(), 1.0);
+        assert_eq!(hash, state_hash(sim_b.particles(), 1.0));
+        let (mut c, mut sim_c) = (emitter(8), CpuParticles::new(64));
+        run(&mut c, &mut sim_c, 100);
+        assert_ne!(hash, state_hash(sim_c.particles(), 1.0));
+
+        // The hash doesn't depend on the slots of the particles
+        let mut shuffled = sim_a.particles().to_vec();
+        shuffled.reverse();
+        assert_eq!(hash, state_hash(&shuffled, 1.0));
+
+        // Paused emitters don't move
+        a.paused = true;
+        run(&mut a, &mut sim_a, 10);
+        assert_eq!(hash, state_hash(sim_a.particles(), 1.0));
+        a.paused = false;
+        run(&mut a, &mut sim_a, 1);
+        assert_ne!(hash, state_hash(sim_a.particles(), 1.0));
+    }
+
+    #[test]
+    fn volume_and_ground() {
+        let mut emitter = emitter(3);
+        emitter.params.volume = Vec3::new(4.0, 0.0, 2.0);
+        emitter.params.velocity = Vec3::ZERO;
+        emitter.params.spread = 0.0;
+        emitter.params.kill_height = -0.5;
+        let mut sim = CpuParticles::new(64);
+        run(&mut emitter, &mut sim, 1);
+        let spawned = sim.particles().iter().filter(|p| p.age < 1.0).collect::<Vec<_>>();
+        assert!(!spawned.is_empty());
+        assert!(spawned.iter().all(|p| p.position.x.abs() <= 4.0 && p.position.z.abs() <= 2.0));
+        assert!(spawned.iter().any(|p| p.position.x.abs() > 1.0));
+        // Falling for 0.5s goes 1.2m down, below the ground
+        run(&mut emitter, &mut sim, 9);
+        assert!(sim
+            .particles()
+            .iter()
+            .all(|p| p.age >= 1.0 || p.position.y >= -0.5));
+    }
+
+    #[test]
+    #[ignore = "needs an adapter with compute shaders"]
+    fn gpu_bitonic_sort() {
+        let (device, queue, _) = test_device(wgpu::Features::empty());
+        let sorter = BitonicSorter::new(&device);
+        for n in [1u32, 2, 8, 64, 256, 1024] {
+            let keys = keys(n as usize);
+            let values = (0..n).collect::<Vec<_>>();
+            let usage = wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC;
+            let key_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&keys),
+                usage,
+            });
+            let value_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&values),
+                usage,
+            });
+            let bind_group = sorter.bind_group(&device, &key_buffer, &value_buffer);
+            let mut encoder = device.create_command_encoder(&Default::default());
+            {
+                let mut pass = encoder.begin_compute_pass(&Default::default());
+                sorter.record(&mut pass, &bind_group, n);
+            }
+            queue.submit(std::iter::once(encoder.finish()));
+            let sorted_keys = read_buffer(&device, &queue, &key_buffer, n as u64 * 4);
+            let sorted_values = read_buffer(&device, &queue, &value_buffer, n as u64 * 4);
+            let sorted_keys: &[f32] = bytemuck::cast_slice(&sorted_keys);
+            let sorted_values: &[u32] = bytemuck::cast_slice(&sorted_values);
+
+            let mut expected = keys.clone();
+            expected.sort_by(f32::total_cmp);
+            assert_eq!(sorted_keys, expected);
+            for (key, value) in sorted_keys.iter().zip(sorted_values) {
+                assert_eq!(*key, keys[*value as usize]);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an adapter with compute shaders and indirect draws"]
+    fn gpu_simulation() {
+        let (device, queue, downlevel) = test_device(wgpu::Features::empty());
+        let camera = ParticleCamera {
+            view_proj: Mat4::IDENTITY,
+            position: Vec3::new(0.0, 0.0, -5.0),
+            right: Vec3::X,
+            up: Vec3::Y,
+            forward: Vec3::Z,
+        };
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let run = |seed| {
+            let mut renderer = ParticleRenderer::new(&device, &downlevel, format);
+            let mut component = emitter(seed);
+            let entity = Entity::default();
+            for _ in 0..100 {
+                renderer.update(&device, 1.0, [(entity, &mut component, Vec3::ZERO)]);
+                let mut encoder = device.create_command_encoder(&Default::default());
+                renderer.simulate(&device, &queue, &mut encoder, &camera);
+                queue.submit(std::iter::once(encoder.finish()));
+            }
+            (
+                renderer.backend(),
+                renderer.state_hash(&device, &queue, entity).unwrap(),
+            )
+        };
+        let (backend, hash) = run(7);
+        assert_eq!(ParticleBackend::Gpu, backend, "No GPU backend");
+        // Reproducible with a fixed step and seed, even though the particles don't end up in the
+        // same slots
+        assert_eq!(hash, run(7).1);
+        assert_ne!(hash, run(8).1);
+    }
+
+    /// 50k particles through the simulation, sort and draw of the GPU backend. The draw list must
+    /// hold all of them, back to front. The frame time is printed, not checked.
+    #[test]
+    #[ignore = "needs an adapter with compute shaders and indirect draws"]
+    fn gpu_many_particles() {
+        let (device, queue, downlevel) = test_device(wgpu::Features::empty());
+        const COUNT: u32 = 50_000;
+        const FRAMES: u32 = 10;
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let mut renderer = ParticleRenderer::new(&device, &downlevel, format);
+        assert_eq!(ParticleBackend::Gpu, renderer.backend(), "No GPU backend");
+        // Every particle spawns on the first step, in a box in front of the camera, and lives on
+        let mut component = ParticleEmitterComponent::new(EmitterParams {
+            capacity: COUNT,
+            rate: COUNT as f32 * 60.0,
+            velocity: Vec3::ZERO,
+            spread: 0.1,
+            gravity: Vec3::ZERO,
+            lifetime: 1000.0,
+            volume: Vec3::splat(5.0),
+            ..Default::default()
+        });
+        component.fixed_step = Some(1.0 / 60.0);
+        let camera = ParticleCamera {
+            view_proj: Mat4::perspective_lh(1.0, 16.0 / 9.0, 0.1, 100.0)
+                * Mat4::look_at_lh(Vec3::new(0.0, 0.0, -15.0), Vec3::ZERO, Vec3::Y),
+            position: Vec3::new(0.0, 0.0, -15.0),
+            right: Vec3::X,
+            up: Vec3::Y,
+            forward: Vec3::Z,
+        };
+        let texture = |format, usage| {
+            device
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: None,
+                    size: wgpu::Extent3d {
+                        width: 640,
+                        height: 360,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format,
+                    usage,
+                })
+                .create_view(&Default::default())
+        };
+        let target = texture(format, wgpu::TextureUsages::RENDER_ATTACHMENT);
+        let depth = texture(
+            TextureManager::DEPTH_FORMAT,
+            wgpu::TextureUsages::RENDER_ATTACHMENT,
+        );
+
+        let entity = Entity::default();
+        let mut frame = || {
+            let start = std::time::Instant::now();
+            renderer.update(&device, 1.0, [(entity, &mut component, Vec3::ZERO)]);
+            let mut encoder = device.create_command_encoder(&Default::default());
+            renderer.simulate(&device, &queue, &mut encoder, &camera);
+            renderer.draw(&mut encoder, &camera, &target, &depth);
+            queue.submit(std::iter::once(encoder.finish()));
+            device.poll(wgpu::Maintain::Wait);
+            start.elapsed()
+        };
+        // The first frames build the pipelines
+        for _ in 0..3 {
+            frame();
+        }
+        let total: std::time::Duration = (0..FRAMES).map(|_| frame()).sum();
+        eprintln!(
+            "{COUNT} particles: {:.2} ms a frame (simulation, sort and draw)",
+            total.as_secs_f64() * 1000.0 / FRAMES as f64
+        );
+
+        let gpu = match &renderer.emitters[&entity].backend {
+            EmitterBackend::Gpu(gpu) => gpu,
+            EmitterBackend::Cpu(_) => panic!("50k particles fit the GPU backend"),
+        };
+        let counters = read_buffer(&device, &queue, &gpu.counters, 8);
+        let count = bytemuck::cast_slice::<u8, u32>(&counters)[1];
+        assert_eq!(COUNT, count);
+        let values = read_buffer(&device, &queue, &gpu.values, COUNT as u64 * 4);
+        let values: &[u32] = bytemuck::cast_slice(&values);
+        let particles = gpu.read_particles(&device, &queue);
+        let depths = values
+            .iter()
+            .map(|&i| camera.depth(particles[i as usize].position))
+            .collect::<Vec<_>>();
+        // Back to front, up to the rounding of the depths computed on the GPU
+        assert!(depths.windows(2).all(|pair| pair[0] >= pair[1] - 1e-4));
+        // Each particle is drawn once
+        assert_eq!(
+            COUNT as usize,
+            values.iter().collect::<HashSet<_>>().len()
+        );
+    }
+}
@@ -0,0 +1,115 @@
+// Particle simulation, mirrors particles::CpuParticles::step. Dead particles (age >= lifetime)
+// take a spawn ticket, the first `spawn` tickets respawn. Particles alive after the step are
+// appended to the draw list with their view depth as sort key, and counted in the indirect draw
+// arguments.
+struct Particle {
+    position: vec3<f32>,
+    age: f32,
+    velocity: vec3<f32>,
+    seed: u32,
+}
+struct Params {
+    view_proj: mat4x4<f32>,
+    camera_position: vec4<f32>,
+    camera_right: vec4<f32>,
+    camera_up: vec4<f32>,
+    camera_forward: vec4<f32>,
+    color: vec4<f32>,
+    position: vec3<f32>,
+    spread: f32,
+    velocity: vec3<f32>,
+    lifetime: f32,
+    gravity: vec3<f32>,
+    size: f32,
+    dt: f32,
+    spawn: u32,
+    seed: u32,
+    frame: u32,
+    capacity: u32,
+    sort_size: u32,
+    kill_height: f32,
+    volume: vec3<f32>,
+}
+// DrawIndirect arguments, followed by the spawn tickets counter
+struct Counters {
+    vertex_count: u32,
+    instance_count: atomic<u32>,
+    base_vertex: u32,
+    base_instance: u32,
+    spawned: atomic<u32>,
+}
+
+// Larger than any key, sorted after the alive particles
+let NO_KEY: f32 = 3.0e38;
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+@group(0) @binding(1)
+var<storage, read_write> particles: array<Particle>;
+@group(0) @binding(2)
+var<storage, read_write> counters: Counters;
+@group(0) @binding(3)
+var<storage, read_write> keys: array<f32>;
+@group(0) @binding(4)
+var<storage, read_write> values: array<u32>;
+
+// Same as particles::hash
+fn hash(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// In [-1, 1]
+fn signed_unorm(value: u32) -> f32 {
+    return f32(value) / 2147483647.5 - 1.0;
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn clear(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x < params.sort_size) {
+        keys[id.x] = NO_KEY;
+        values[id.x] = 0u;
+    }
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn simulate(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if (i >= params.capacity) {
+        return;
+    }
+    var p = particles[i];
+    if (p.age >= params.lifetime) {
+        let ticket = atomicAdd(&counters.spawned, 1u);
+        if (ticket >= params.spawn) {
+            return;
+        }
+        let seed = hash(params.seed ^ hash(params.frame ^ hash(ticket)));
+        let x = hash(seed);
+        let y = hash(x);
+        let z = hash(y);
+        let offset = vec3<f32>(signed_unorm(x), signed_unorm(y), signed_unorm(z));
+        let u = hash(z);
+        let v = hash(u);
+        let w = hash(v);
+        let spawn_offset = vec3<f32>(signed_unorm(u), signed_unorm(v), signed_unorm(w));
+        p.position = params.position + spawn_offset * params.volume;
+        p.velocity = params.velocity + offset * params.spread;
+        p.age = 0.0;
+        p.seed = seed;
+    } else {
+        p.velocity = p.velocity + params.gravity * params.dt;
+        p.position = p.position + p.velocity * params.dt;
+        p.age = p.age + params.dt;
+        if (p.position.y < params.kill_height) {
+            p.age = params.lifetime;
+        }
+    }
+    particles[i] = p;
+    if (p.age < params.lifetime) {
+        let index = atomicAdd(&counters.instance_count, 1u);
+        keys[index] = -dot(p.position - params.camera_position.xyz, params.camera_forward.xyz);
+        values[index] = i;
+    }
+}
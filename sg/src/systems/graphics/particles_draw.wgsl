@@ -0,0 +1,68 @@
+// Camera facing quads for the particles of the GPU backend, in the order of the sorted draw list
+struct Particle {
+    position: vec3<f32>,
+    age: f32,
+    velocity: vec3<f32>,
+    seed: u32,
+}
+struct Params {
+    view_proj: mat4x4<f32>,
+    camera_position: vec4<f32>,
+    camera_right: vec4<f32>,
+    camera_up: vec4<f32>,
+    camera_forward: vec4<f32>,
+    color: vec4<f32>,
+    position: vec3<f32>,
+    spread: f32,
+    velocity: vec3<f32>,
+    lifetime: f32,
+    gravity: vec3<f32>,
+    size: f32,
+    dt: f32,
+    spawn: u32,
+    seed: u32,
+    frame: u32,
+    capacity: u32,
+    sort_size: u32,
+    kill_height: f32,
+    volume: vec3<f32>,
+}
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+@group(0) @binding(1)
+var<storage, read> particles: array<Particle>;
+@group(0) @binding(2)
+var<storage, read> values: array<u32>;
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex: u32, @builtin(instance_index) instance: u32) -> VertexOutput {
+    let p = particles[values[instance]];
+    // Two triangles: (0, 0) (1, 0) (0, 1), (0, 1) (1, 0) (1, 1)
+    var corner = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0), vec2<f32>(1.0, 0.0), vec2<f32>(0.0, 1.0),
+        vec2<f32>(0.0, 1.0), vec2<f32>(1.0, 0.0), vec2<f32>(1.0, 1.0),
+    );
+    let uv = corner[vertex];
+    // Same as particles::particle_size
+    let size = params.size * (0.75 + 0.5 * f32(p.seed & 0xffffu) / 65535.0);
+    let offset = (uv - 0.5) * size;
+    let position = p.position + params.camera_right.xyz * offset.x + params.camera_up.xyz * offset.y;
+    var out: VertexOutput;
+    out.clip_position = params.view_proj * vec4<f32>(position, 1.0);
+    out.uv = uv;
+    out.color = vec4<f32>(params.color.rgb, params.color.a * (1.0 - p.age / params.lifetime));
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Round particles with soft edges
+    let distance = length(in.uv * 2.0 - 1.0);
+    return vec4<f32>(in.color.rgb, in.color.a * (1.0 - smoothstep(0.5, 1.0, distance)));
+}
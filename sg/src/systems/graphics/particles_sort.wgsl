@@ -0,0 +1,34 @@
+// One compare and exchange pass of a bitonic sort of (key, value) pairs, in ascending key order.
+// Mirrors particles::bitonic_pass, the passes come from particles::bitonic_passes.
+struct SortPass {
+    // Size of the sequences being merged
+    k: u32,
+    // Distance between the compared elements
+    j: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> sort_pass: SortPass;
+@group(0) @binding(1)
+var<storage, read_write> keys: array<f32>;
+@group(0) @binding(2)
+var<storage, read_write> values: array<u32>;
+
+@compute @workgroup_size(64, 1, 1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    let l = i ^ sort_pass.j;
+    if (l <= i || l >= arrayLength(&keys)) {
+        return;
+    }
+    let ascending = (i & sort_pass.k) == 0u;
+    let a = keys[i];
+    let b = keys[l];
+    if (select(a < b, a > b, ascending)) {
+        keys[i] = b;
+        keys[l] = a;
+        let value = values[i];
+        values[i] = values[l];
+        values[l] = value;
+    }
+}
@@ -0,0 +1,247 @@
+//! Picking of the entity under a pixel.
+//!
+//! The geometry pass writes a stable id of each renderable's entity (`EntityIds`) to the id target
+//! of the GBuffer. A pick copies its pixel to a small buffer after the geometry pass and maps it
+//! once the frame is submitted, the `PickRequest` resolves on a later frame without stalling.
+
+use std::num::NonZeroU32;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+
+use ecs::Entity;
+use slotmap::SecondaryMap;
+
+/// Stable ids of the entities drawn by the geometry pass, 0 is no entity. Ids are never reused, the
+/// entity of an id may have been despawned since.
+#[derive(Default)]
+pub struct EntityIds {
+    ids: SecondaryMap<Entity, u32>,
+    entities: Vec<Entity>,
+}
+
+impl EntityIds {
+    /// The id of an entity, given one the first time. They go through the vertex attributes as
+    /// floats, exact up to 2^24.
+    pub fn id(&mut self, entity: Entity) -> u32 {
+        if let Some(&id) = self.ids.get(entity) {
+            return id;
+        }
+        self.entities.push(entity);
+        let id = self.entities.len() as u32;
+        self.ids.insert(entity, id);
+        id
+    }
+    pub fn entity(&self, id: u32) -> Option<Entity> {
+        let index = id.checked_sub(1)?;
+        self.entities.get(index as usize).copied()
+    }
+    /// Forget every entity (the world changed)
+    pub fn clear(&mut self) {
+        self.ids.clear();
+        self.entities.clear();
+    }
+}
+
+/// What is under a picked pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PickResult {
+    /// The readback hasn't come back yet
+    Pending,
+    /// The background, or the pick was lost (outside of the screen, resize...)
+    Nothing,
+    Entity(Entity),
+}
+
+/// A pick in flight, see `WorldRenderer::pick`
+pub struct PickRequest {
+    receiver: Receiver<Option<Entity>>,
+    result: PickResult,
+}
+
+impl PickRequest {
+    /// The result, once the readback came back
+    pub fn poll(&mut self) -> PickResult {
+        if self.result == PickResult::Pending {
+            self.result = match self.receiver.try_recv() {
+                Ok(Some(entity)) => PickResult::Entity(entity),
+                Ok(None) | Err(TryRecvError::Disconnected) => PickResult::Nothing,
+                Err(TryRecvError::Empty) => PickResult::Pending,
+            };
+        }
+        self.result
+    }
+}
+
+enum Pick {
+    /// Waiting for the geometry pass
+    Queued((u32, u32)),
+    /// The copy has been recorded, waiting for the submission
+    Recorded(wgpu::Buffer),
+    Mapping(wgpu::Buffer, Receiver<Result<(), wgpu::BufferAsyncError>>),
+}
+
+/// Reads back the ids under the picked pixels
+#[derive(Default)]
+pub struct Picker {
+    pub ids: EntityIds,
+    picks: Vec<(Pick, Sender<Option<Entity>>)>,
+    /// Readback buffers of the picks that came back
+    free: Vec<wgpu::Buffer>,
+}
+
+impl Picker {
+    /// Pick the entity under a pixel of the next geometry pass
+    pub fn request(&mut self, pixel: (u32, u32)) -> PickRequest {
+        let (sender, receiver) = mpsc::channel();
+        self.picks.push((Pick::Queued(pixel), sender));
+        PickRequest {
+            receiver,
+            result: PickResult::Pending,
+        }
+    }
+    /// Record the copies of the queued picks from the id target (of `size`), after the geometry
+    /// pass
+    pub fn record(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        ids: &wgpu::Texture,
+        size: (u32, u32),
+    ) {
+        let free = &mut self.free;
+        self.picks.retain_mut(|(pick, sender)| {
+            let (x, y) = match *pick {
+                Pick::Queued(pixel) => pixel,
+                _ => return true,
+            };
+            if x >= size.0 || y >= size.1 {
+                sender.send(None).ok();
+                return false;
+            }
+            let buffer = free.pop().unwrap_or_else(|| {
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Pick Readback"),
+                    size: 4,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            });
+            encoder.copy_texture_to_buffer(
+                wgpu::ImageCopyTexture {
+                    texture: ids,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d { x, y, z: 0 },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::ImageCopyBuffer {
+                    buffer: &buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: NonZeroU32::new(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT),
+                        rows_per_image: None,
+                    },
+                },
+                wgpu::Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: 1,
+                },
+            );
+            *pick = Pick::Recorded(buffer);
+            true
+        });
+    }
+    /// Start reading back the recorded picks, must be called after the submission
+    pub fn after_submit(&mut self) {
+        for (pick, sender) in std::mem::take(&mut self.picks) {
+            let pick = match pick {
+                Pick::Recorded(buffer) => {
+                    let (sender, receiver) = mpsc::channel();
+                    buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+                        sender.send(result).ok();
+                    });
+                    Pick::Mapping(buffer, receiver)
+                }
+                pick => pick,
+            };
+            self.picks.push((pick, sender));
+        }
+    }
+    /// Resolve the picks that came back
+    pub fn poll(&mut self, device: &wgpu::Device) {
+        if !self.picks.iter().any(|(pick, _)| matches!(pick, Pick::Mapping(..))) {
+            return;
+        }
+        device.poll(wgpu::Maintain::Poll);
+        for (pick, sender) in std::mem::take(&mut self.picks) {
+            let (buffer, mapped) = match pick {
+                Pick::Mapping(buffer, receiver) => match receiver.try_recv() {
+                    Ok(result) => (buffer, result.is_ok()),
+                    Err(TryRecvError::Empty) => {
+                        self.picks.push((Pick::Mapping(buffer, receiver), sender));
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => (buffer, false),
+                },
+                pick => {
+                    self.picks.push((pick, sender));
+                    continue;
+                }
+            };
+            let mut entity = None;
+            if mapped {
+                let id = bytemuck::pod_read_unaligned::<u32>(&buffer.slice(..).get_mapped_range());
+                entity = self.ids.entity(id);
+                buffer.unmap();
+                self.free.push(buffer);
+            }
+            sender.send(entity).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slotmap::SlotMap;
+
+    use super::*;
+
+    #[test]
+    fn entity_ids() {
+        let mut entities = SlotMap::<Entity, ()>::with_key();
+        let (a, b) = (entities.insert(()), entities.insert(()));
+        let mut ids = EntityIds::default();
+        assert_eq!(1, ids.id(a));
+        assert_eq!(2, ids.id(b));
+        assert_eq!(1, ids.id(a));
+        assert_eq!(Some(b), ids.entity(2));
+        assert_eq!(None, ids.entity(0));
+        assert_eq!(None, ids.entity(3));
+        // A new entity in the slot of a despawned one gets a new id
+        entities.remove(a);
+        let c = entities.insert(());
+        assert_eq!(3, ids.id(c));
+        assert_eq!(Some(a), ids.entity(1));
+        ids.clear();
+        assert_eq!(None, ids.entity(1));
+        assert_eq!(1, ids.id(b));
+    }
+
+    #[test]
+    fn requests() {
+        let mut entities = SlotMap::<Entity, ()>::with_key();
+        let entity = entities.insert(());
+        let mut picker = Picker::default();
+        let mut hit = picker.request((3, 4));
+        let mut missed = picker.request((5, 6));
+        let mut lost = picker.request((7, 8));
+        assert_eq!(PickResult::Pending, hit.poll());
+        // What the readbacks would send
+        picker.picks[0].1.send(Some(entity)).unwrap();
+        picker.picks[1].1.send(None).unwrap();
+        picker.picks.truncate(0);
+        assert_eq!(PickResult::Entity(entity), hit.poll());
+        assert_eq!(PickResult::Entity(entity), hit.poll());
+        assert_eq!(PickResult::Nothing, missed.poll());
+        assert_eq!(PickResult::Nothing, lost.poll());
+    }
+}
@@ -1,12 +1,22 @@
-use std::{collections::HashMap, lazy::SyncLazy, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::LazyLock,
+};
 
+use anyhow::{anyhow, bail, Context, Result};
 use codespan_reporting::{
     diagnostic::{Diagnostic, Label},
     files::SimpleFiles,
     term::termcolor::StandardStream,
 };
 use regex::Regex;
+use rmanage::{Resource, ResourceError, ResourceManager};
+
+/// A constant in a shader's source
+static CONSTANT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{\{(.+?)\}\}").unwrap());
 
+#[derive(Clone)]
 pub enum ShaderConstant {
     Integer(i64),
     Float(f64),
@@ -48,31 +58,84 @@ impl IntoShaderConstant for String {
     }
 }
 
-impl ToString for ShaderConstant {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for ShaderConstant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Integer(i) => i.to_string(),
-            Self::Float(f) => f.to_string(),
-            Self::Bool(b) => b.to_string(),
-            Self::Any(a) => a.to_string(),
+            Self::Integer(i) => write!(f, "{i}"),
+            Self::Float(v) => write!(f, "{v}"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Any(a) => write!(f, "{a}"),
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Shader {
     name: &'static str,
     source: String,
     constants: HashMap<&'static str, ShaderConstant>,
+    /// Resources the source is read from when reloading, in order
+    sources: Vec<Resource>,
 }
 
+/// Embed a shader of the graphics module, reloadable from its files in debug builds (see
+/// `Shader::watch`)
 #[macro_export]
 macro_rules! include_shader {
     ($path:literal, $name:literal) => {
-        $crate::systems::graphics::pipeline::Shader::new(include_str!($path).to_owned(), $name)
+        $crate::include_shader!([$path], $name)
+    };
+    // Files concatenated in order, WGSL needs declarations before their use
+    ([$($path:literal),+ $(,)?], $name:literal) => {
+        $crate::systems::graphics::pipeline::Shader::new(concat!($(include_str!($path)),+).to_owned(), $name)
+            .watch(&[$(concat!(env!("CARGO_MANIFEST_DIR"), "/src/systems/graphics/", $path)),+])
     };
 }
 
-impl<'a> Shader {
+/// The sources of shaders concatenated
+fn read_sources(resources: &ResourceManager, sources: &[Resource]) -> Result<String> {
+    let mut source = String::new();
+    for res in sources {
+        let bytes = resources.get_resource(*res)?;
+        source.push_str(std::str::from_utf8(&bytes).context("shader source isn't utf-8")?);
+    }
+    Ok(source)
+}
+
+/// The sources that changed since they were read (see `ResourceManager::revalidate`), which
+/// frees them so that the next read gets the new file
+pub fn changed_sources(
+    resources: &ResourceManager,
+    sources: impl IntoIterator<Item = Resource>,
+) -> HashSet<Resource> {
+    sources
+        .into_iter()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .filter(|res| resources.revalidate(*res).unwrap_or(false))
+        .collect()
+}
+
+/// Build something (a pipeline) from a shader, failing instead of panicking if the shader has
+/// unset constants or doesn't compile
+pub fn try_build<T>(
+    device: &wgpu::Device,
+    shader: &Shader,
+    build: impl FnOnce(&Shader) -> T,
+) -> Result<T> {
+    let unset = shader.unset_constants();
+    if !unset.is_empty() {
+        bail!("no value for {}", unset.join(", "));
+    }
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let value = build(shader);
+    match pollster::block_on(device.pop_error_scope()) {
+        Some(error) => Err(anyhow!("{error}")),
+        None => Ok(value),
+    }
+}
+
+impl Shader {
     pub fn from_file(path: impl AsRef<Path>, name: &'static str) -> Self {
         Self::new(
             std::fs::read_to_string(path).expect("Error on file read"),
@@ -84,8 +147,55 @@ impl<'a> Shader {
             name,
             source,
             constants: HashMap::new(),
+            sources: Vec::new(),
         }
     }
+    /// A shader read from resources, concatenated in order
+    pub fn from_resources(
+        resources: &ResourceManager,
+        sources: &[Resource],
+        name: &'static str,
+    ) -> Result<Self> {
+        let mut shader = Self::new(read_sources(resources, sources)?, name);
+        shader.sources = sources.to_vec();
+        Ok(shader)
+    }
+    /// Reload the source from these files, in debug builds when there is a resource manager, so
+    /// that shaders can be edited while the game runs. Release builds keep the embedded source.
+    pub fn watch(mut self, paths: &[&str]) -> Self {
+        if !cfg!(debug_assertions) {
+            return self;
+        }
+        let Some(resources) = rmanage::try_instance() else {
+            return self;
+        };
+        let sources = paths
+            .iter()
+            .map(|path| {
+                let res = resources.add_physical(path)?;
+                // Read once, for revalidate to tell when it changes
+                resources.ensure_loaded(res)?;
+                Ok(res)
+            })
+            .collect::<Result<Vec<_>, ResourceError>>();
+        match sources {
+            Ok(sources) => self.sources = sources,
+            Err(e) => log::debug!("Can't reload {} from its files: {e}", self.name),
+        }
+        self
+    }
+    /// Resources the source is read from when reloading, empty if it can't be
+    pub fn sources(&self) -> &[Resource] {
+        &self.sources
+    }
+    /// Read the source again, the constants keep their values
+    pub fn reload(&mut self, resources: &ResourceManager) -> Result<()> {
+        if self.sources.is_empty() {
+            bail!("{} has no source to reload from", self.name);
+        }
+        self.source = read_sources(resources, &self.sources)?;
+        Ok(())
+    }
     pub fn set(&mut self, key: &'static str, value: impl IntoShaderConstant) {
         self.constants.insert(key, value.into_shaderconstant());
     }
@@ -98,6 +208,29 @@ impl<'a> Shader {
     pub fn set_bool(&mut self, key: &'static str, value: bool) {
         self.set(key, ShaderConstant::Bool(value));
     }
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    /// The constants and their values, sorted by name
+    pub fn constants(&self) -> Vec<(&'static str, String)> {
+        let mut constants = self
+            .constants
+            .iter()
+            .map(|(k, v)| (*k, v.to_string()))
+            .collect::<Vec<_>>();
+        constants.sort();
+        constants
+    }
+    /// A copy of the shader with other values for some constants, None if one of them isn't
+    /// already set on this shader
+    pub fn specialize(&self, values: &[(String, String)]) -> Option<Shader> {
+        let mut shader = self.clone();
+        for (key, value) in values {
+            let key = *self.constants.keys().find(|k| **k == key)?;
+            shader.set(key, ShaderConstant::Any(value.clone()));
+        }
+        Some(shader)
+    }
     pub fn get(&self, key: &'static str) -> Option<&ShaderConstant> {
         self.constants.get(key)
     }
@@ -119,13 +252,24 @@ impl<'a> Shader {
             _ => None,
         }
     }
-    pub fn module(&self, device: &wgpu::Device) -> wgpu::ShaderModule {
+    fn preprocess(&self) -> String {
         let mut source = self.source.to_owned();
         let mut pat = "{{_}}".to_owned();
         for (p, val) in &self.constants {
             pat.replace_range(2..(pat.len() - 2), p);
             source = source.replace(&pat, &val.to_string());
         }
+        source
+    }
+    /// Constants used by the source but not given a value
+    pub fn unset_constants(&self) -> Vec<String> {
+        CONSTANT
+            .captures_iter(&self.preprocess())
+            .map(|cap| cap[1].to_owned())
+            .collect()
+    }
+    pub fn module(&self, device: &wgpu::Device) -> wgpu::ShaderModule {
+        let source = self.preprocess();
         // check for unset constants in debug builds
         #[cfg(debug_assertions)]
         {
@@ -135,8 +279,7 @@ impl<'a> Shader {
             let writer =
                 StandardStream::stderr(codespan_reporting::term::termcolor::ColorChoice::Always);
             let config = codespan_reporting::term::Config::default();
-            static RE: SyncLazy<Regex> = SyncLazy::new(|| Regex::new(r"\{\{(.+?)\}\}").unwrap());
-            for cap in RE.captures_iter(&source) {
+            for cap in CONSTANT.captures_iter(&source) {
                 err_count += 1;
                 let m = cap.get(1).unwrap();
                 let diagnostic = Diagnostic::error()
@@ -162,6 +305,7 @@ impl<'a> Shader {
 
 pub struct Pipeline<P> {
     layout: wgpu::PipelineLayout,
+    #[allow(clippy::type_complexity)]
     build: Box<
         dyn Fn(&wgpu::Device, &wgpu::PipelineLayout, &wgpu::ShaderModule) -> P
             + Send,
@@ -192,9 +336,64 @@ impl<P> Pipeline<P> {
         }
     }
 
+    /// A pipeline of a shader read from a resource of the global resource manager
+    pub fn from_resource<F>(
+        device: &wgpu::Device,
+        layout: wgpu::PipelineLayout,
+        res: Resource,
+        name: &'static str,
+        build: F,
+    ) -> Result<Self>
+    where
+        F: Fn(&wgpu::Device, &wgpu::PipelineLayout, &wgpu::ShaderModule) -> P
+            + 'static
+            + Send,
+    {
+        let shader = Shader::from_resources(rmanage::instance(), &[res], name)?;
+        Ok(Self::new(device, layout, shader, build))
+    }
+
     pub fn rebuild(&mut self, device: &wgpu::Device) {
         self.pipeline = (self.build)(device, &self.layout, &self.shader.module(device));
     }
+
+    /// Rebuild, keeping the current pipeline if the shader doesn't compile
+    pub fn try_rebuild(&mut self, device: &wgpu::Device) -> Result<()> {
+        self.pipeline = try_build(device, &self.shader, |shader| {
+            (self.build)(device, &self.layout, &shader.module(device))
+        })?;
+        Ok(())
+    }
+
+    /// Reload the shader and rebuild if one of its sources changed. If the new source doesn't
+    /// compile the error is logged and the previous shader and pipeline are kept. Returns whether
+    /// it was reloaded.
+    pub fn reload(
+        &mut self,
+        device: &wgpu::Device,
+        resources: &ResourceManager,
+        changed: &HashSet<Resource>,
+    ) -> bool {
+        if !self.shader.sources().iter().any(|res| changed.contains(res)) {
+            return false;
+        }
+        let previous = self.shader.clone();
+        let result = self
+            .shader
+            .reload(resources)
+            .and_then(|()| self.try_rebuild(device));
+        match result {
+            Ok(()) => {
+                log::info!("Reloaded {}", self.shader.name());
+                true
+            }
+            Err(e) => {
+                log::error!("Couldn't reload {}: {e:#}", self.shader.name());
+                self.shader = previous;
+                false
+            }
+        }
+    }
 }
 
 pub type RenderPipeline = Pipeline<wgpu::RenderPipeline>;
@@ -0,0 +1,739 @@
+//! Cache of pipeline permutations.
+//!
+//! A pipeline family is a shader (with default values for all its constants) and a function
+//! building a pipeline from it, registered once. Each set of constant values is a permutation,
+//! built on first use or ahead of time on a worker thread with `prewarm`, so that growing a limit
+//! like LIGHTS_MAX doesn't stall a frame. Permutations unused for a while are evicted.
+//!
+//! Where a worker thread isn't possible (see `time_sliced`), prewarmed permutations are built on
+//! the main thread instead, a few each frame in `maintain`.
+//!
+//! wgpu 0.13 doesn't expose driver pipeline caches, so no compiled pipeline is persisted: only the
+//! keys in use are written to `PIPELINE_CACHE_FILE` on exit. On the next start they are compiled
+//! again, prewarmed as soon as their family is registered, which moves the compilation out of the
+//! frames but doesn't make it any faster.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use rmanage::{Resource, ResourceManager};
+
+use super::pipeline::Shader;
+
+/// File the keys of the used permutations are written to (not the pipelines), relative to the
+/// working directory
+pub const PIPELINE_CACHE_FILE: &str = "pipeline_cache.txt";
+/// Frames a permutation can go unused before being evicted
+pub const EVICT_AFTER: u64 = 600;
+/// Time `maintain` spends building prewarmed permutations when there is no worker thread
+pub const PREWARM_SLICE: Duration = Duration::from_millis(4);
+
+type Build<P> = Arc<dyn Fn(&Shader) -> P + Send + Sync>;
+
+/// A permutation: shader, constant values sorted by name, and layout
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PermutationKey {
+    pub shader: String,
+    pub defines: Vec<(String, String)>,
+    pub layout: u64,
+}
+
+impl PermutationKey {
+    pub fn new<K: ToString, V: ToString>(
+        shader: &str,
+        defines: impl IntoIterator<Item = (K, V)>,
+        layout: u64,
+    ) -> Self {
+        let mut defines = defines
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        defines.sort();
+        defines.dedup_by(|a, b| a.0 == b.0);
+        Self {
+            shader: shader.to_owned(),
+            defines,
+            layout,
+        }
+    }
+    /// The key of a shader with its current constants
+    pub fn of(shader: &Shader, layout: u64) -> Self {
+        Self::new(shader.name(), shader.constants(), layout)
+    }
+    /// The same permutation with another value for a constant
+    pub fn with(&self, key: &str, value: impl ToString) -> Self {
+        let mut defines = self.defines.clone();
+        defines.retain(|(k, _)| k != key);
+        defines.push((key.to_owned(), value.to_string()));
+        Self::new(&self.shader, defines, self.layout)
+    }
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.defines
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+    fn family(&self) -> (String, u64) {
+        (self.shader.clone(), self.layout)
+    }
+    fn to_line(&self) -> String {
+        let defines = self
+            .defines
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join(";");
+        format!("{}\t{}\t{}", self.shader, self.layout, defines)
+    }
+    fn from_line(line: &str) -> Option<Self> {
+        let mut parts = line.split('\t');
+        let shader = parts.next()?;
+        let layout = parts.next()?.parse().ok()?;
+        let defines = parts
+            .next()?
+            .split(';')
+            .filter(|d| !d.is_empty())
+            .map(|d| d.split_once('='))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self::new(shader, defines, layout))
+    }
+}
+
+/// Hash of a pipeline layout description, stable across runs (unlike DefaultHasher) since it ends
+/// up on disk.
+pub fn layout_hash(desc: impl Hash) -> u64 {
+    struct Fnv(u64);
+    impl Hasher for Fnv {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+        fn write(&mut self, bytes: &[u8]) {
+            for b in bytes {
+                self.0 = (self.0 ^ *b as u64).wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+    let mut hasher = Fnv(0xcbf29ce484222325);
+    desc.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Counters, mostly to check that the hot path doesn't build anything
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Permutations built synchronously because they were needed right away
+    pub hot_builds: usize,
+    /// Times a frame had to wait for a permutation still being prewarmed
+    pub stalls: usize,
+    pub prewarmed: usize,
+    pub evicted: usize,
+}
+
+struct Job<P> {
+    key: PermutationKey,
+    shader: Shader,
+    build: Build<P>,
+}
+
+struct Entry<P> {
+    pipeline: P,
+    last_used: u64,
+}
+
+struct Worker<P> {
+    jobs: Sender<Job<P>>,
+    results: Receiver<(PermutationKey, P)>,
+}
+
+impl<P: Send + 'static> Worker<P> {
+    fn spawn() -> io::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Job<P>>();
+        let (sender, results) = mpsc::channel();
+        thread::Builder::new()
+            .name("pipeline prewarm".to_owned())
+            .spawn(move || {
+                for job in receiver {
+                    let pipeline = (job.build)(&job.shader);
+                    if sender.send((job.key, pipeline)).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Worker { jobs, results })
+    }
+}
+
+/// Prewarms built on the main thread
+struct TimeSliced<P> {
+    /// Time spent building each frame, at least one permutation is built
+    budget: Duration,
+    jobs: VecDeque<Job<P>>,
+}
+
+impl<P> TimeSliced<P> {
+    fn new(budget: Duration) -> Self {
+        Self {
+            budget,
+            jobs: VecDeque::new(),
+        }
+    }
+}
+
+pub struct PipelineCache<P = wgpu::RenderPipeline> {
+    families: HashMap<(String, u64), (Shader, Build<P>)>,
+    pipelines: HashMap<PermutationKey, Entry<P>>,
+    /// Sent to the worker, not received yet
+    pending: HashSet<PermutationKey>,
+    worker: Option<Worker<P>>,
+    /// Set when prewarms are built on the main thread
+    sliced: Option<TimeSliced<P>>,
+    /// Keys loaded from disk, waiting for their family to be registered
+    warm_list: Vec<PermutationKey>,
+    path: Option<PathBuf>,
+    frame: u64,
+    evict_after: u64,
+    pub stats: CacheStats,
+}
+
+impl<P: Send + 'static> PipelineCache<P> {
+    pub fn new(evict_after: u64) -> Self {
+        Self {
+            families: HashMap::new(),
+            pipelines: HashMap::new(),
+            pending: HashSet::new(),
+            worker: None,
+            sliced: None,
+            warm_list: Vec::new(),
+            path: None,
+            frame: 0,
+            evict_after,
+            stats: CacheStats::default(),
+        }
+    }
+    /// A cache prewarming the permutations saved at path by the last run (see `save`)
+    pub fn with_warm_list(evict_after: u64, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut cache = Self::new(evict_after);
+        if let Ok(list) = fs::read_to_string(&path) {
+            cache.warm_list = list.lines().filter_map(PermutationKey::from_line).collect();
+        }
+        cache.path = Some(path);
+        cache
+    }
+    /// Build the prewarmed permutations on the main thread, at most `budget` each frame (see
+    /// `maintain`), for devices that can't be used from another thread. The cache falls back to
+    /// this with `PREWARM_SLICE` by itself when it can't spawn its worker.
+    pub fn time_sliced(mut self, budget: Duration) -> Self {
+        self.sliced = Some(TimeSliced::new(budget));
+        self
+    }
+    /// Register a family, and return the key of the shader with its current constants. Every
+    /// constant a permutation may change must have a value in shader.
+    pub fn register(
+        &mut self,
+        shader: Shader,
+        layout: u64,
+        build: impl Fn(&Shader) -> P + Send + Sync + 'static,
+    ) -> PermutationKey {
+        let key = PermutationKey::of(&shader, layout);
+        self.families
+            .insert(key.family(), (shader, Arc::new(build)));
+        let (warm, rest) = std::mem::take(&mut self.warm_list)
+            .into_iter()
+            .partition::<Vec<_>, _>(|k| k.family() == key.family());
+        self.warm_list = rest;
+        self.prewarm(warm);
+        key
+    }
+    fn job(&self, key: &PermutationKey) -> Option<Job<P>> {
+        let (template, build) = self.families.get(&key.family())?;
+        Some(Job {
+            key: key.clone(),
+            shader: template.specialize(&key.defines)?,
+            build: build.clone(),
+        })
+    }
+    /// Queue a prewarm on the worker, or for `maintain` if time sliced. False if it can't be
+    /// built.
+    fn send(&mut self, job: Job<P>) -> bool {
+        if self.sliced.is_none() && self.worker.is_none() {
+            match Worker::spawn() {
+                Ok(worker) => self.worker = Some(worker),
+                Err(e) => {
+                    log::warn!(
+                        "Couldn't spawn the pipeline prewarm thread ({e}), prewarming between frames"
+                    );
+                    self.sliced = Some(TimeSliced::new(PREWARM_SLICE));
+                }
+            }
+        }
+        match (&mut self.sliced, &self.worker) {
+            (Some(sliced), _) => {
+                sliced.jobs.push_back(job);
+                true
+            }
+            (None, Some(worker)) => worker.jobs.send(job).is_ok(),
+            (None, None) => false,
+        }
+    }
+    /// Build missing permutations on the worker thread. Keys of unknown families, or setting
+    /// constants their shader doesn't have, are ignored.
+    pub fn prewarm(&mut self, keys: impl IntoIterator<Item = PermutationKey>) {
+        for key in keys {
+            if self.pipelines.contains_key(&key) || self.pending.contains(&key) {
+                continue;
+            }
+            let job = match self.job(&key) {
+                Some(job) => job,
+                None => continue,
+            };
+            if self.send(job) {
+                log::debug!("Prewarming {}", key.to_line());
+                self.pending.insert(key);
+            }
+        }
+    }
+    fn receive(&mut self, (key, pipeline): (PermutationKey, P)) {
+        if self.pending.remove(&key) {
+            self.stats.prewarmed += 1;
+        }
+        self.pipelines.entry(key).or_insert(Entry {
+            pipeline,
+            last_used: self.frame,
+        });
+    }
+    /// Wait for a pending permutation, false if the worker is gone (it panicked)
+    fn wait_for(&mut self, key: &PermutationKey) -> bool {
+        if let Some(sliced) = &mut self.sliced {
+            // Built now instead of in its turn
+            if let Some(i) = sliced.jobs.iter().position(|job| &job.key == key) {
+                let job = sliced.jobs.remove(i).unwrap();
+                let pipeline = (job.build)(&job.shader);
+                self.receive((job.key, pipeline));
+            }
+        }
+        while !self.pipelines.contains_key(key) {
+            let result = match &self.worker {
+                Some(worker) => worker.results.recv(),
+                None => return false,
+            };
+            match result {
+                Ok(result) => self.receive(result),
+                Err(_) => {
+                    self.worker = None;
+                    self.pending.clear();
+                    return false;
+                }
+            }
+        }
+        true
+    }
+    /// Block until every prewarm is done, e.g. during a loading screen
+    pub fn wait_idle(&mut self) {
+        while let Some(key) = self.pending.iter().next().cloned() {
+            if !self.wait_for(&key) {
+                break;
+            }
+        }
+    }
+    /// Whether a permutation is built (prewarms are only received by `maintain`)
+    pub fn contains(&self, key: &PermutationKey) -> bool {
+        self.pipelines.contains_key(key)
+    }
+    /// Get a permutation, building it right away if it isn't built or being prewarmed
+    ///
+    /// # Panics
+    ///
+    /// If the family of key wasn't registered
+    pub fn get(&mut self, key: &PermutationKey) -> &P {
+        if !self.pipelines.contains_key(key) {
+            if self.pending.contains(key) {
+                self.stats.stalls += 1;
+                self.wait_for(key);
+            }
+            if !self.pipelines.contains_key(key) {
+                let job = self
+                    .job(key)
+                    .unwrap_or_else(|| panic!("Unknown permutation {}", key.to_line()));
+                log::debug!("Building {} on the hot path", key.to_line());
+                self.stats.hot_builds += 1;
+                let pipeline = (job.build)(&job.shader);
+                self.pipelines.insert(
+                    key.clone(),
+                    Entry {
+                        pipeline,
+                        last_used: self.frame,
+                    },
+                );
+            }
+        }
+        let entry = self.pipelines.get_mut(key).unwrap();
+        entry.last_used = self.frame;
+        &entry.pipeline
+    }
+    /// A permutation if it is built, without building it or marking it used. For binding several
+    /// permutations in a pass, after `get` made sure they were built.
+    pub fn built(&self, key: &PermutationKey) -> Option<&P> {
+        self.pipelines.get(key).map(|entry| &entry.pipeline)
+    }
+    /// Start a new frame: receive prewarmed permutations (or build some if time sliced) and evict
+    /// unused ones
+    pub fn maintain(&mut self) {
+        self.frame += 1;
+        while let Some(result) = self.worker.as_ref().and_then(|w| w.results.try_recv().ok()) {
+            self.receive(result);
+        }
+        if let Some(sliced) = &mut self.sliced {
+            let start = Instant::now();
+            let mut built = Vec::new();
+            while let Some(job) = sliced.jobs.pop_front() {
+                built.push((job.key, (job.build)(&job.shader)));
+                if start.elapsed() >= sliced.budget {
+                    break;
+                }
+            }
+            for result in built {
+                self.receive(result);
+            }
+        }
+        let (frame, evict_after) = (self.frame, self.evict_after);
+        let before = self.pipelines.len();
+        self.pipelines
+            .retain(|_, entry| frame - entry.last_used <= evict_after);
+        self.stats.evicted += before - self.pipelines.len();
+    }
+    pub fn len(&self) -> usize {
+        self.pipelines.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pipelines.is_empty()
+    }
+    /// Sources of the shaders of the families, see `Shader::watch`
+    pub fn sources(&self) -> impl Iterator<Item = Resource> + '_ {
+        self.families
+            .values()
+            .flat_map(|(shader, _)| shader.sources().iter().copied())
+    }
+    /// Reload the shaders of the families with a changed source, and rebuild their permutations
+    /// with `build`, given the family's build function, which fails instead of panicking on an
+    /// invalid shader (see `pipeline::try_build`). A family is only reloaded if all its built
+    /// permutations (or the default one if there is none) build, otherwise the error is logged
+    /// and it keeps its shader. Returns the families reloaded.
+    pub fn reload(
+        &mut self,
+        resources: &ResourceManager,
+        changed: &HashSet<Resource>,
+        build: impl Fn(&Shader, &dyn Fn(&Shader) -> P) -> Result<P>,
+    ) -> usize {
+        // Prewarms of the old shaders would come after
+        self.wait_idle();
+        let families = self
+            .families
+            .iter()
+            .filter(|(_, (shader, _))| shader.sources().iter().any(|res| changed.contains(res)))
+            .map(|(family, _)| family.clone())
+            .collect::<Vec<_>>();
+        let mut reloaded = 0;
+        for family in families {
+            let (template, family_build) = &self.families[&family];
+            let keys = self
+                .pipelines
+                .keys()
+                .filter(|key| key.family() == family)
+                .cloned()
+                .collect::<Vec<_>>();
+            let mut shader = template.clone();
+            let result = shader.reload(resources).and_then(|()| {
+                if keys.is_empty() {
+                    build(&shader, family_build.as_ref())?;
+                }
+                keys.into_iter()
+                    .map(|key| {
+                        let permutation = shader
+                            .specialize(&key.defines)
+                            .with_context(|| format!("can't specialize {}", key.to_line()))?;
+                        Ok((key, build(&permutation, family_build.as_ref())?))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            });
+            match result {
+                Ok(pipelines) => {
+                    log::info!("Reloaded {}", shader.name());
+                    self.families.get_mut(&family).unwrap().0 = shader;
+                    for (key, pipeline) in pipelines {
+                        self.pipelines.get_mut(&key).unwrap().pipeline = pipeline;
+                    }
+                    reloaded += 1;
+                }
+                Err(e) => log::error!("Couldn't reload {}: {e:#}", shader.name()),
+            }
+        }
+        reloaded
+    }
+    /// Write the keys of the built permutations to the warm list, if the cache has one
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut keys = self.pipelines.keys().collect::<Vec<_>>();
+        keys.sort();
+        let lines = keys.iter().map(|k| k.to_line()).collect::<Vec<_>>();
+        write_atomic(path, lines.join("\n"))
+    }
+}
+
+fn write_atomic(path: &Path, contents: String) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)
+}
+
+/// Decides when to prewarm the next value of a growable limit (like LIGHTS_MAX): fires once when
+/// the usage goes past `high` of the limit, then only after going back under `low`, or once the
+/// limit changed.
+pub struct PrewarmTrigger {
+    high: f32,
+    low: f32,
+    armed: bool,
+    limit: u32,
+}
+
+impl PrewarmTrigger {
+    pub fn new(high: f32, low: f32) -> Self {
+        Self {
+            high,
+            low,
+            armed: true,
+            limit: 0,
+        }
+    }
+    pub fn update(&mut self, used: u32, limit: u32) -> bool {
+        if limit != self.limit {
+            self.limit = limit;
+            self.armed = true;
+        }
+        let used = used as f32 / limit as f32;
+        if self.armed && used > self.high {
+            self.armed = false;
+            true
+        } else {
+            if used < self.low {
+                self.armed = true;
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::super::pipeline::changed_sources;
+    use super::*;
+
+    fn shader() -> Shader {
+        let mut shader = Shader::new("{{A}} {{B}}".to_owned(), "test");
+        shader.set_integer("A", 1);
+        shader.set_integer("B", 2);
+        shader
+    }
+
+    /// A cache of u64s counting how many are built
+    fn cache() -> (PipelineCache<u64>, PermutationKey, Arc<AtomicUsize>) {
+        let builds = Arc::new(AtomicUsize::new(0));
+        let mut cache = PipelineCache::new(3);
+        let counter = builds.clone();
+        let key = cache.register(shader(), 7, move |shader| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            shader.get("A").unwrap().to_string().parse().unwrap()
+        });
+        (cache, key, builds)
+    }
+
+    #[test]
+    fn canonical_keys() {
+        let a = PermutationKey::new("test", [("B", "2"), ("A", "1")], 3);
+        let b = PermutationKey::new("test", [("A", "1"), ("B", "2")], 3);
+        assert_eq!(a, b);
+        assert_eq!(
+            a,
+            PermutationKey::of(&shader(), 3).with("A", 1).with("B", 2)
+        );
+        assert_ne!(a, PermutationKey::new("test", [("A", "1"), ("B", "2")], 4));
+        assert_eq!(
+            a.with("A", 5),
+            PermutationKey::new("test", [("B", "2"), ("A", "5")], 3)
+        );
+        assert_eq!(Some("5"), a.with("A", 5).get("A"));
+        assert_eq!(Some(a.clone()), PermutationKey::from_line(&a.to_line()));
+        assert_eq!(layout_hash("layout"), layout_hash("layout"));
+    }
+
+    #[test]
+    fn trigger_hysteresis() {
+        let mut trigger = PrewarmTrigger::new(0.75, 0.5);
+        let fired = [40, 48, 49, 60, 49, 40, 31, 49, 50].map(|used| trigger.update(used, 64));
+        assert_eq!(
+            [false, false, true, false, false, false, false, true, false],
+            fired
+        );
+        // A new limit re-arms
+        assert!(!trigger.update(90, 128));
+        assert!(trigger.update(97, 128));
+    }
+
+    #[test]
+    fn eviction() {
+        let (mut cache, key, builds) = cache();
+        let other = key.with("A", 2);
+        cache.get(&key);
+        cache.get(&other);
+        for _ in 0..3 {
+            cache.maintain();
+            cache.get(&key);
+        }
+        assert_eq!(2, cache.len());
+        cache.maintain();
+        assert!(!cache.contains(&other));
+        assert!(cache.contains(&key));
+        assert_eq!(None, cache.built(&other));
+        assert_eq!(Some(&1), cache.built(&key));
+        assert_eq!(1, cache.stats.evicted);
+        // Evicted permutations are built again
+        assert_eq!(2, *cache.get(&other));
+        assert_eq!(3, builds.load(Ordering::SeqCst));
+        assert_eq!(3, cache.stats.hot_builds);
+    }
+
+    #[test]
+    fn prewarm() {
+        let (mut cache, key, builds) = cache();
+        assert_eq!(1, *cache.get(&key));
+        let next = key.with("A", 4);
+        cache.prewarm([next.clone(), next.clone(), key.with("C", 1)]);
+        cache.wait_idle();
+        cache.maintain();
+        assert_eq!(4, *cache.get(&next));
+        assert_eq!(2, builds.load(Ordering::SeqCst));
+        assert_eq!(
+            CacheStats {
+                hot_builds: 1,
+                stalls: 0,
+                prewarmed: 1,
+                evicted: 0
+            },
+            cache.stats
+        );
+    }
+
+    #[test]
+    fn time_sliced() {
+        let (cache, key, builds) = cache();
+        // A budget of zero builds one permutation a frame
+        let mut cache = cache.time_sliced(Duration::ZERO);
+        let keys = [2, 3, 4].map(|a| key.with("A", a));
+        cache.prewarm(keys.clone());
+        assert_eq!(0, builds.load(Ordering::SeqCst));
+        cache.maintain();
+        assert!(cache.contains(&keys[0]));
+        assert!(!cache.contains(&keys[1]));
+        // Needed before its turn, it is built right away
+        assert_eq!(4, *cache.get(&keys[2]));
+        cache.maintain();
+        assert!(cache.contains(&keys[1]));
+        cache.maintain();
+        assert_eq!(3, builds.load(Ordering::SeqCst));
+        assert_eq!(
+            CacheStats {
+                hot_builds: 0,
+                stalls: 1,
+                prewarmed: 3,
+                evicted: 0
+            },
+            cache.stats
+        );
+    }
+
+    #[test]
+    fn warm_list() {
+        let path = std::env::temp_dir().join(format!("sg-pipelines-{}", uuid::Uuid::new_v4()));
+        let builds = Arc::new(AtomicUsize::new(0));
+        let build = |builds: Arc<AtomicUsize>| {
+            move |_: &Shader| {
+                builds.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let mut cache = PipelineCache::with_warm_list(10, &path);
+        let key = cache.register(shader(), 1, build(builds.clone()));
+        cache.get(&key.with("B", 3));
+        cache.save().unwrap();
+
+        let mut cache = PipelineCache::with_warm_list(10, &path);
+        cache.register(shader(), 1, build(builds.clone()));
+        cache.wait_idle();
+        assert!(cache.contains(&key.with("B", 3)));
+        assert_eq!(0, cache.stats.hot_builds);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reload() {
+        let dir = std::env::temp_dir().join(format!("sg-shaders-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.wgsl");
+        fs::write(&path, "{{A}} {{B}}").unwrap();
+        let resources = rmanage::ResourceManagerBuilder::begin()
+            .with_resource_path(&dir)
+            .build();
+        let res = resources.add_physical("test.wgsl").unwrap();
+        let mut shader = Shader::from_resources(&resources, &[res], "test").unwrap();
+        shader.set_integer("A", 1);
+        shader.set_integer("B", 2);
+
+        // The constants left unset tell the sources apart
+        let mut cache = PipelineCache::new(10);
+        let key = cache.register(shader, 1, |shader| {
+            let a: u64 = shader.get("A").unwrap().to_string().parse().unwrap();
+            a + 10 * shader.unset_constants().len() as u64
+        });
+        let other = key.with("A", 2);
+        cache.get(&key);
+        cache.get(&other);
+        // Shaders with more than one unset constant don't compile
+        let build = |shader: &Shader, build: &dyn Fn(&Shader) -> u64| {
+            anyhow::ensure!(shader.unset_constants().len() <= 1, "unset constants");
+            Ok(build(shader))
+        };
+        assert!(changed_sources(&resources, cache.sources()).is_empty());
+
+        fs::write(&path, "{{A}} {{C}}").unwrap();
+        let changed = changed_sources(&resources, cache.sources());
+        assert_eq!(HashSet::from([res]), changed);
+        assert_eq!(1, cache.reload(&resources, &changed, build));
+        assert_eq!(Some(&11), cache.built(&key));
+        assert_eq!(Some(&12), cache.built(&other));
+
+        // Failed reloads keep the previous shader
+        fs::write(&path, "{{A}} {{C}} {{D}}").unwrap();
+        let changed = changed_sources(&resources, cache.sources());
+        assert_eq!(0, cache.reload(&resources, &changed, build));
+        assert_eq!(Some(&11), cache.built(&key));
+        assert_eq!(11, *cache.get(&key.with("B", 3)));
+        assert!(changed_sources(&resources, cache.sources()).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+use std::num::NonZeroU32;
+
+use wgpu::util::DeviceExt;
+
+use crate::include_shader;
+
+use super::{pipeline::ComputePipeline, GraphicContext, cubemap::get_cubemap_face_rotations_buffer};
+
+/// Prefilters environment cubemaps for the specular term of image based lighting: each mip level
+/// is the environment convolved with GGX at a roughness, from 0 at the first to 1 at the last.
+pub struct SpecularPrefilterComputer {
+    pipeline: ComputePipeline,
+    sampler: wgpu::Sampler,
+    bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl SpecularPrefilterComputer {
+    const SAMPLE_COUNT: u32 = 512;
+    const WG_SIZE: u32 = 8;
+    /// Size of the first level, smaller sources keep their size
+    pub const MAX_SIZE: u32 = 256;
+    /// Size of the last level, rougher reflections are blurry enough to be sampled from it
+    const MIN_SIZE: u32 = 8;
+
+    pub fn new(ctx: &GraphicContext) -> Self {
+        let mut shader = include_shader!("prefilter.wgsl", "Prefilter Shader");
+        shader.set("WG_SIZE", i64::from(Self::WG_SIZE));
+        shader.set("SAMPLE_COUNT", i64::from(Self::SAMPLE_COUNT));
+        let bind_group_layout = create_bind_group_layout!(ctx.device, "Prefilter Bind Group Layout": {
+            0 => COMPUTE | Buffer(type: Uniform),
+            1 => COMPUTE | Texture(sample: FloatFilterable, view_dim: Cube),
+            2 => COMPUTE | StorageTexture(access: WriteOnly, format: Rgba16Float, view_dim: D2Array),
+            3 => COMPUTE | Sampler(Filtering),
+            4 => COMPUTE | Buffer(type: Uniform),
+        });
+        let sampler = ctx.device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let pipeline = ComputePipeline::new(
+            &ctx.device,
+            ctx.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Prefilter Pipeline Layout"),
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[]
+            }),
+            shader,
+            |device, layout, module| {
+                device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some("Prefilter Pipeline"),
+                    layout: Some(layout),
+                    module,
+                    entry_point: "main"
+                })
+            }
+        );
+
+        Self {
+            pipeline,
+            bind_group_layout,
+            sampler,
+        }
+    }
+
+    /// Size of the first level and number of levels of the prefiltered map of a cubemap of
+    /// `env_size`
+    pub fn levels(env_size: u32) -> (u32, u32) {
+        let size = env_size.clamp(1, Self::MAX_SIZE);
+        let mips = (size / Self::MIN_SIZE.min(size)).ilog2() + 1;
+        (size, mips)
+    }
+
+    /// Prefilter `env_map` (a cubemap of `env_size`), returns the texture and its number of mip
+    /// levels
+    pub fn run(&self, env_map: &wgpu::TextureView, env_size: u32, usage: wgpu::TextureUsages, ctx: &GraphicContext) -> (wgpu::Texture, u32) {
+        let (size, mips) = Self::levels(env_size);
+        let tex = ctx.device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 6,
+            },
+            label: Some("Prefiltered Environment"),
+            usage: wgpu::TextureUsages::STORAGE_BINDING | usage,
+            format: wgpu::TextureFormat::Rgba16Float,
+            dimension: wgpu::TextureDimension::D2,
+            sample_count: 1,
+            mip_level_count: mips,
+        });
+
+        let mut encoder = ctx.device.create_command_encoder(&Default::default());
+        for mip in 0..mips {
+            let roughness = if mips > 1 { mip as f32 / (mips - 1) as f32 } else { 0.0 };
+            let params = ctx.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Prefilter Params"),
+                usage: wgpu::BufferUsages::UNIFORM,
+                contents: bytemuck::cast_slice(&[roughness, 0.0, 0.0, 0.0]),
+            });
+            let view = tex.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::D2Array),
+                base_mip_level: mip,
+                mip_level_count: NonZeroU32::new(1),
+                ..Default::default()
+            });
+            let bind_group = create_bind_group!(ctx.device, &self.bind_group_layout, "Prefilter Bind Group": {
+                0 | Buffer(buffer: (get_cubemap_face_rotations_buffer(&ctx.device))),
+                1 | TextureView(env_map),
+                2 | TextureView(&view),
+                3 | Sampler(&self.sampler),
+                4 | Buffer(buffer: (&params)),
+            });
+
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Prefilter Compute Pass")
+            });
+            let workgroups = (size >> mip).div_ceil(Self::WG_SIZE);
+            compute_pass.set_pipeline(&self.pipeline.pipeline);
+            compute_pass.set_bind_group(0, &bind_group, &[]);
+            compute_pass.dispatch_workgroups(workgroups, workgroups, 6);
+        }
+
+        let si = ctx.queue.submit(std::iter::once(encoder.finish()));
+        ctx.device.poll(wgpu::Maintain::WaitForSubmissionIndex(si));
+        (tex, mips)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        // Down to 8x8
+        assert_eq!((256, 6), SpecularPrefilterComputer::levels(4096));
+        assert_eq!((128, 5), SpecularPrefilterComputer::levels(128));
+        // Smaller than the last level
+        assert_eq!((8, 1), SpecularPrefilterComputer::levels(8));
+        assert_eq!((4, 1), SpecularPrefilterComputer::levels(4));
+    }
+}
@@ -0,0 +1,76 @@
+// Prefiltering of an environment cubemap for the specular term of image based lighting: a mip
+// level of the output per roughness, convolved with GGX by importance sampling
+@group(0) @binding(0)
+var<uniform> rotations: array<mat4x4<f32>, 6>;
+@group(0) @binding(1)
+var input: texture_cube<f32>;
+@group(0) @binding(2)
+var output: texture_storage_2d_array<rgba16float, write>;
+@group(0) @binding(3)
+var t_sampler: sampler;
+
+struct Params {
+    roughness: f32,
+}
+
+@group(0) @binding(4)
+var<uniform> params: Params;
+
+let sample_count = {{SAMPLE_COUNT}}u;
+
+let PI = 3.14159265359;
+
+fn hammersley(i: u32, n: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(n), f32(reverseBits(i)) * 2.3283064365386963e-10);
+}
+
+// Half vector around the normal n, distributed as GGX
+fn importance_sample_ggx(xi: vec2<f32>, n: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let h = vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+
+    var up = vec3<f32>(1.0, 0.0, 0.0);
+    if abs(n.z) < 0.999 {
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let tangent = normalize(cross(up, n));
+    let bitangent = cross(n, tangent);
+    return normalize(tangent * h.x + bitangent * h.y + n * h.z);
+}
+
+// Assumes the view direction is the normal (and the reflection direction)
+fn prefilter(n: vec3<f32>) -> vec3<f32> {
+    var color = vec3<f32>(0.0);
+    var weight = 0.0;
+    for(var i: u32 = 0u; i < sample_count; i++) {
+        let h = importance_sample_ggx(hammersley(i, sample_count), n, params.roughness);
+        let l = normalize(2.0 * dot(n, h) * h - n);
+        let n_dot_l = dot(n, l);
+        if n_dot_l > 0.0 {
+            color += textureSampleLevel(input, t_sampler, l, 0.0).rgb * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+    return color / max(weight, 1e-4);
+}
+
+@compute @workgroup_size({{WG_SIZE}}, {{WG_SIZE}}, 1)
+fn main(@builtin(global_invocation_id) param: vec3<u32>) {
+    let size = vec2<u32>(textureDimensions(output));
+    if param.x >= size.x || param.y >= size.y {
+        return;
+    }
+    let unorm_loc = (vec2<f32>(param.xy) + 0.5) / vec2<f32>(size);
+
+    let rot = rotations[param.z];
+    let top_left = (vec4<f32>(-1.0,  1.0, 1.0, 0.0) * rot).xyz;
+    let right =    (vec4<f32>( 2.0,  0.0, 0.0, 0.0) * rot).xyz;
+    let bottom =   (vec4<f32>( 0.0, -2.0, 0.0, 0.0) * rot).xyz;
+
+    let n = normalize(top_left + unorm_loc.x * right + unorm_loc.y * bottom);
+
+    textureStore(output, vec2<i32>(param.xy), i32(param.z), vec4<f32>(prefilter(n), 1.0));
+}
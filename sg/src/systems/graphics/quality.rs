@@ -0,0 +1,428 @@
+//! Adaptive quality: lowers the render settings one knob at a time when frames take longer than
+//! the target, and restores them once there is headroom again. A knob is never raised above the
+//! value the user set, and a knob edited by hand stops being adapted until it is resumed.
+
+use std::fmt::Display;
+
+use crate::{localization::Localization, systems::time::Time, tr};
+
+use super::{focus::UiFocus, minimap::Minimap, renderer::WorldRenderer, GraphicContext};
+
+/// A render setting the controller can change, in the order they are lowered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Knob {
+    /// Frames between two renders of the minimap
+    MinimapInterval,
+    /// Max raymarch steps of the fog
+    FogSteps,
+    /// Volumetric fog on or off
+    Fog,
+}
+
+impl Knob {
+    pub const ALL: [Self; 3] = [Self::MinimapInterval, Self::FogSteps, Self::Fog];
+
+    /// The values of the knob, from the lowest quality to the highest
+    pub fn levels(self) -> &'static [u32] {
+        match self {
+            Self::MinimapInterval => &[8, 4, 2, 1],
+            Self::FogSteps => &[8, 16, 24, 32, 48, 64, 96, 128],
+            Self::Fog => &[0, 1],
+        }
+    }
+    /// Level of the closest value
+    fn level(self, value: u32) -> usize {
+        let levels = self.levels();
+        (0..levels.len())
+            .min_by_key(|&level| levels[level].abs_diff(value))
+            .unwrap()
+    }
+}
+
+impl Display for Knob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::MinimapInterval => "minimap interval",
+            Self::FogSteps => "fog steps",
+            Self::Fog => "fog",
+        })
+    }
+}
+
+/// The current values of the knobs, indexed by `Knob as usize`
+pub type KnobValues = [u32; Knob::ALL.len()];
+
+/// A change of a knob made by the controller
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Change {
+    pub knob: Knob,
+    pub from: u32,
+    pub to: u32,
+    /// Smoothed frame time that triggered the change
+    pub frame_ms: f32,
+}
+
+#[derive(Debug, Clone)]
+struct KnobState {
+    level: usize,
+    /// Highest level allowed, the one of the user's setting or the bound
+    ceiling: usize,
+    /// Value last seen or written, a different one means the knob was edited by hand
+    applied: Option<u32>,
+    paused: bool,
+}
+
+pub struct AdaptiveQuality {
+    pub enabled: bool,
+    pub target_frame_ms: f32,
+    /// Fraction under the target the frame time must be to raise the quality
+    pub headroom: f32,
+    /// Fraction over the target the frame time must be to lower the quality
+    pub tolerance: f32,
+    /// Frames measured between two changes
+    pub cooldown: u32,
+    /// Frames in a row the smoothed frame time must be past a threshold, so spikes are ignored
+    pub sustain: u32,
+    /// Weight of the last frame in the smoothed frame time
+    pub smoothing: f32,
+    /// Lowest and highest values of every knob, indexed by `Knob as usize`
+    pub bounds: [(u32, u32); Knob::ALL.len()],
+    knobs: Vec<KnobState>,
+    smoothed: Option<f32>,
+    measured: u32,
+    /// Frames in a row over the lower threshold (positive) or under the raise one (negative)
+    past: i32,
+}
+
+impl Default for AdaptiveQuality {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            target_frame_ms: 1000.0 / 60.0,
+            headroom: 0.25,
+            tolerance: 0.1,
+            cooldown: 30,
+            sustain: 15,
+            smoothing: 0.1,
+            bounds: [(8, 1), (8, 64), (0, 1)],
+            knobs: Vec::new(),
+            smoothed: None,
+            measured: 0,
+            past: 0,
+        }
+    }
+}
+
+impl AdaptiveQuality {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The smoothed frame time, None until a frame was measured since the last change
+    pub fn frame_ms(&self) -> Option<f32> {
+        self.smoothed
+    }
+    pub fn is_paused(&self, knob: Knob) -> bool {
+        self.knobs
+            .get(knob as usize)
+            .is_some_and(|state| state.paused)
+    }
+    /// Pause or resume the adaptation of a knob, a resumed knob won't go above its current value
+    pub fn set_paused(&mut self, knob: Knob, paused: bool) {
+        if let Some(state) = self.knobs.get_mut(knob as usize) {
+            state.paused = paused;
+            // Taken as the user's setting on the next update
+            state.applied = None;
+        }
+    }
+    fn bounds(&self, knob: Knob) -> (usize, usize) {
+        let (lowest, highest) = self.bounds[knob as usize];
+        (knob.level(lowest), knob.level(highest))
+    }
+    /// Take the values set by hand, pausing the knobs edited since the last update
+    fn sync(&mut self, values: &KnobValues) {
+        if self.knobs.is_empty() {
+            self.knobs = vec![
+                KnobState {
+                    level: 0,
+                    ceiling: 0,
+                    applied: None,
+                    paused: false,
+                };
+                Knob::ALL.len()
+            ];
+        }
+        for knob in Knob::ALL {
+            let (lowest, highest) = self.bounds(knob);
+            let value = values[knob as usize];
+            let state = &mut self.knobs[knob as usize];
+            match state.applied {
+                Some(applied) if applied == value => continue,
+                Some(applied) if !state.paused => {
+                    log::info!(
+                        "Adaptive quality: {knob} set by hand ({applied} -> {value}), paused"
+                    );
+                    state.paused = true;
+                }
+                _ => {}
+            }
+            state.level = knob.level(value);
+            state.ceiling = state.level.clamp(lowest, highest);
+            state.applied = Some(value);
+        }
+    }
+    /// Measure a frame, returns the change to apply to values if one is due. Values are the
+    /// current values of the knobs.
+    pub fn update(&mut self, frame_ms: f32, values: &mut KnobValues) -> Option<Change> {
+        self.sync(values);
+        if !self.enabled {
+            self.reset();
+            return None;
+        }
+        let smoothed = match self.smoothed {
+            Some(smoothed) => smoothed + (frame_ms - smoothed) * self.smoothing,
+            None => frame_ms,
+        };
+        self.smoothed = Some(smoothed);
+        self.measured += 1;
+        self.past = if smoothed > self.target_frame_ms * (1.0 + self.tolerance) {
+            self.past.max(0) + 1
+        } else if smoothed < self.target_frame_ms * (1.0 - self.headroom) {
+            self.past.min(0) - 1
+        } else {
+            0
+        };
+        if self.measured < self.cooldown || self.past.unsigned_abs() < self.sustain {
+            return None;
+        }
+
+        let change = if self.past > 0 {
+            // Lower the first knob that can be
+            Knob::ALL.into_iter().find_map(|knob| {
+                let (lowest, _) = self.bounds(knob);
+                let state = &self.knobs[knob as usize];
+                (!state.paused && state.level > lowest).then(|| (knob, state.level - 1))
+            })
+        } else {
+            // Raise the last knob lowered
+            Knob::ALL.into_iter().rev().find_map(|knob| {
+                let state = &self.knobs[knob as usize];
+                (!state.paused && state.level < state.ceiling).then(|| (knob, state.level + 1))
+            })
+        };
+
+        let (knob, level) = change?;
+        let state = &mut self.knobs[knob as usize];
+        let change = Change {
+            knob,
+            from: values[knob as usize],
+            to: knob.levels()[level],
+            frame_ms: smoothed,
+        };
+        state.level = level;
+        state.applied = Some(change.to);
+        values[knob as usize] = change.to;
+        // Measure the new settings from scratch
+        self.reset();
+        Some(change)
+    }
+    fn reset(&mut self) {
+        self.smoothed = None;
+        self.measured = 0;
+        self.past = 0;
+    }
+    /// System adapting the render settings. The frame time is the GPU time when the GPU is synced
+    /// (see `GraphicContext::sync`), the time between frames otherwise.
+    pub fn adapt(
+        &mut self,
+        gfx: &GraphicContext,
+        time: &Time,
+        wr: &mut WorldRenderer,
+        minimap: &mut Minimap,
+    ) {
+        let frame_ms = gfx
+            .timings
+            .gpu_ms
+            .unwrap_or_else(|| time.delta_secs() * 1000.0);
+        let fog = &mut wr.fog.settings;
+        let mut values = [minimap.update_interval(), fog.steps, fog.enabled as u32];
+        if let Some(change) = self.update(frame_ms, &mut values) {
+            log::info!(
+                "Adaptive quality: {} {} -> {} (frame {:.2} ms, target {:.2} ms)",
+                change.knob,
+                change.from,
+                change.to,
+                change.frame_ms,
+                self.target_frame_ms
+            );
+            minimap.set_update_interval(values[Knob::MinimapInterval as usize]);
+            fog.steps = values[Knob::FogSteps as usize];
+            fog.enabled = values[Knob::Fog as usize] != 0;
+        }
+    }
+
+    pub(super) fn ui(&mut self, ctx: &egui::Context, focus: &mut UiFocus, loc: &mut Localization) {
+        focus.begin_panel("quality");
+        egui::Window::new(tr!(loc, "quality.window")).show(ctx, |ui| {
+            focus.track(ui.checkbox(&mut self.enabled, tr!(loc, "quality.enabled")));
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut self.target_frame_ms, 4.0..=50.0)
+                        .text(tr!(loc, "quality.target")),
+                ),
+            );
+            if let Some(frame) = self.smoothed {
+                ui.label(tr!(loc, "quality.frame_time", time = format!("{frame:.2}")));
+            }
+            for knob in Knob::ALL {
+                let state = match self.knobs.get(knob as usize) {
+                    Some(state) => state,
+                    None => break,
+                };
+                let name = match knob {
+                    Knob::MinimapInterval => tr!(loc, "quality.minimap_interval"),
+                    Knob::FogSteps => tr!(loc, "quality.fog_steps"),
+                    Knob::Fog => tr!(loc, "quality.fog"),
+                };
+                let value = state.applied.unwrap_or_default();
+                let mut adaptive = !state.paused;
+                ui.horizontal(|ui| {
+                    ui.label(tr!(loc, "quality.knob", name = name, value = value));
+                    focus.track(ui.checkbox(&mut adaptive, tr!(loc, "quality.adaptive")));
+                });
+                if adaptive == self.is_paused(knob) {
+                    self.set_paused(knob, !adaptive);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: f32 = 16.0;
+
+    fn controller() -> AdaptiveQuality {
+        AdaptiveQuality {
+            enabled: true,
+            target_frame_ms: TARGET,
+            cooldown: 10,
+            bounds: [(8, 1), (8, 128), (0, 1)],
+            ..Default::default()
+        }
+    }
+
+    /// Run a trace of frame times, returns the changes in order
+    fn run(
+        quality: &mut AdaptiveQuality,
+        values: &mut KnobValues,
+        trace: impl IntoIterator<Item = f32>,
+    ) -> Vec<(Knob, u32)> {
+        trace
+            .into_iter()
+            .filter_map(|frame| quality.update(frame, values))
+            .map(|change| (change.knob, change.to))
+            .collect()
+    }
+
+    #[test]
+    fn spike_sustained_recovery() {
+        let mut quality = controller();
+        let mut values = [1, 32, 1];
+
+        // A single slow frame doesn't change anything
+        let spike = std::iter::repeat(TARGET).take(50)
+            .chain([60.0])
+            .chain(std::iter::repeat(TARGET).take(50));
+        assert!(run(&mut quality, &mut values, spike).is_empty());
+
+        // Sustained load lowers the knobs in order, one at a time
+        let changes = run(&mut quality, &mut values, std::iter::repeat(40.0).take(105));
+        use Knob::*;
+        assert_eq!(
+            vec![
+                (MinimapInterval, 2),
+                (MinimapInterval, 4),
+                (MinimapInterval, 8),
+                (FogSteps, 24),
+                (FogSteps, 16),
+                (FogSteps, 8),
+                (Fog, 0),
+            ],
+            changes
+        );
+        assert_eq!([8, 8, 0], values);
+        // Nothing left to lower
+        assert!(run(&mut quality, &mut values, std::iter::repeat(40.0).take(100)).is_empty());
+
+        // Recovery raises them back in reverse, up to the user's settings only
+        let changes = run(&mut quality, &mut values, std::iter::repeat(8.0).take(200));
+        assert_eq!(
+            vec![
+                (Fog, 1),
+                (FogSteps, 16),
+                (FogSteps, 24),
+                (FogSteps, 32),
+                (MinimapInterval, 4),
+                (MinimapInterval, 2),
+                (MinimapInterval, 1),
+            ],
+            changes
+        );
+        assert_eq!([1, 32, 1], values);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut quality = controller();
+        let mut values = [2, 32, 1];
+        // Noisy around the target, inside the thresholds once smoothed
+        let noisy =
+            (0..1000).map(|i| TARGET + if i % 2 == 0 { 5.0 } else { -6.0 } + (i % 7) as f32 * 0.3);
+        assert!(run(&mut quality, &mut values, noisy).is_empty());
+
+        // A load just over the lower threshold lowers one knob, the lighter frames that follow
+        // aren't light enough to raise it back
+        let changes = run(
+            &mut quality,
+            &mut values,
+            std::iter::repeat(TARGET * 1.15).take(30),
+        );
+        assert_eq!(vec![(Knob::MinimapInterval, 4)], changes);
+        let changes = run(
+            &mut quality,
+            &mut values,
+            std::iter::repeat(TARGET * 0.85).take(500),
+        );
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn manual_edit() {
+        let mut quality = controller();
+        let mut values = [1, 32, 1];
+        run(&mut quality, &mut values, std::iter::repeat(TARGET).take(5));
+
+        // The minimap interval is edited by hand, the fog is lowered instead
+        values[Knob::MinimapInterval as usize] = 2;
+        let changes = run(&mut quality, &mut values, std::iter::repeat(40.0).take(15));
+        assert!(quality.is_paused(Knob::MinimapInterval));
+        assert_eq!(vec![(Knob::FogSteps, 24)], changes);
+        assert_eq!([2, 24, 1], values);
+
+        // Resumed, its value is the new ceiling
+        quality.set_paused(Knob::MinimapInterval, false);
+        let changes = run(&mut quality, &mut values, std::iter::repeat(40.0).take(15));
+        assert_eq!(vec![(Knob::MinimapInterval, 4)], changes);
+        let changes = run(&mut quality, &mut values, std::iter::repeat(8.0).take(100));
+        assert_eq!(
+            vec![(Knob::FogSteps, 32), (Knob::MinimapInterval, 2)],
+            changes
+        );
+
+        // Disabled, nothing changes
+        quality.enabled = false;
+        assert!(run(&mut quality, &mut values, std::iter::repeat(40.0).take(100)).is_empty());
+    }
+}
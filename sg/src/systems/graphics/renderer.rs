@@ -1,38 +1,189 @@
-use std::num::NonZeroU64;
-use std::{collections::HashSet, num::NonZeroU32};
+use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
 
-use bimap::BiMap;
-use ecs::{Entity, Entities};
+use ecs::{Entity, Entities, EventReader, ResMut};
 use egui::TextureId;
-use egui_wgpu::renderer::{RenderPass, ScreenDescriptor};
-use slotmap::SecondaryMap;
-use wgpu::util::DeviceExt;
+use egui_wgpu::renderer::RenderPass;
+use winit::event::VirtualKeyCode;
 use winit::window::Window;
 
-use crate::Grabbed;
-use crate::{include_shader, components::{LightComponent, GraphicsComponent, TransformsComponent}};
+use crate::console::Console;
+use crate::localization::Localization;
+use crate::save::{GameLoaded, SaveMenu};
+use crate::{tr, Grabbed};
+use crate::systems::time::Time;
+use crate::systems::weather::Weather;
+use crate::{include_shader, components::{LightComponent, GraphicsComponent, ParticleEmitterComponent, SpriteComponent, TransformsComponent, WorldAnchorComponent}};
 
-use super::mesh_manager::Mesh;
-use super::pipeline::RenderPipeline;
-use super::{pipeline::Pipeline, g_buffer::GBuffer, camera::Camera, GraphicContext, mesh_manager::Vertex, texture_manager::{TextureManager, TextureHandle}};
+use super::focus::{UiAction, UiFocus};
+use super::frame::Frame;
+use super::fog::VolumetricFog;
+use super::memory::{GpuMemory, GpuMemoryStats};
+use super::ssr::{self, ScreenSpaceReflections};
+use super::bloom::Bloom;
+use super::tonemap::{Tonemap, Tonemapper, HDR_FORMAT};
+use super::hiz::{DepthPyramid, OcclusionCuller};
+use super::shadows::{ShadowAtlas, ShadowPass, ShadowView, SHADOW_RESOLUTION};
+use super::instances::{append_groups, supports_instancing, GeometryInstance, InstanceBuffer, InstanceGroup};
+use super::minimap::Minimap;
+use super::picking::{PickRequest, Picker};
+use super::paint::TexturePaintTool;
+use super::quality::AdaptiveQuality;
+use super::ui_draw::{UiDraw, UiOutput};
+use super::ui_scale::{UiScale, USER_SCALE_MAX, USER_SCALE_MIN};
+use super::particles::{ParticleCamera, ParticleRenderer};
+use super::sprites::{SpriteRenderer, SpriteView};
+use super::debug_draw::{DebugDraw, DebugLineRenderer};
+use super::mesh_manager::BoundingBox;
+use super::pipeline::{changed_sources, try_build};
+use super::pipeline_cache::{layout_hash, PermutationKey, PipelineCache, PrewarmTrigger};
+use super::{g_buffer::{DebugView, GBuffer, SurfaceWeather}, camera::Camera, AlphaMode, GraphicContext, Light, mesh_manager::Vertex};
+
+/// Counters of the last rendered frame
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderStats {
+    /// Renderables drawn by the geometry and forward passes
+    pub drawn: usize,
+    /// Draw calls of the geometry and forward passes, renderables sharing a mesh and textures are
+    /// drawn together
+    pub draw_calls: usize,
+    /// Renderables skipped because they were outside of the camera's frustum
+    pub culled: usize,
+    /// Renderables skipped because they were found occluded
+    pub occluded: usize,
+}
+
+/// Initial LIGHTS_MAX of the shading shader
+const LIGHTS_MAX: u32 = 64;
+/// Lights with a shadow map (the layers of the shadow atlas), the next ones are unshadowed
+const SHADOWS_MAX: u32 = 4;
+
+/// LIGHTS_MAX of the shading pipeline, grown to the next power of two when the lights don't fit.
+/// The next permutation is prewarmed once 75% of the limit is used, so growing doesn't stall.
+struct LightsLimit {
+    max: u32,
+    trigger: PrewarmTrigger,
+}
+
+impl LightsLimit {
+    fn new(max: u32) -> Self {
+        Self {
+            max,
+            trigger: PrewarmTrigger::new(0.75, 0.5),
+        }
+    }
+    /// Update with the number of lights, returns the permutation to switch to if the limit grew
+    fn update<P: Send + 'static>(
+        &mut self,
+        cache: &mut PipelineCache<P>,
+        key: &PermutationKey,
+        lights: u32,
+    ) -> Option<PermutationKey> {
+        let grown = (lights > self.max).then(|| {
+            self.max = lights.next_power_of_two();
+            log::debug!("Max lights reached, increasing the limit to {}", self.max);
+            key.with("LIGHTS_MAX", self.max)
+        });
+        if self.trigger.update(lights, self.max) {
+            let key = grown.as_ref().unwrap_or(key);
+            cache.prewarm([key.with("LIGHTS_MAX", self.max * 2)]);
+        }
+        grown
+    }
+}
+
+/// Entry point and vertex buffers of the geometry (see `instances`)
+fn geometry_vertex_state(instancing: bool) -> (&'static str, Vec<wgpu::VertexBufferLayout<'static>>) {
+    if instancing {
+        ("vs_main", vec![Vertex::desc(), GeometryInstance::layout()])
+    } else {
+        ("vs_single", vec![Vertex::desc()])
+    }
+}
+
+/// Draw groups of instances, from the instance buffer bound at slot 1 or with each instance in
+/// the push constants, and the texture set at `textures`. Returns the draw calls.
+fn draw_groups<'a>(
+    pass: &mut wgpu::RenderPass<'a>,
+    ctx: &'a GraphicContext,
+    textures: u32,
+    instancing: bool,
+    instances: &[GeometryInstance],
+    groups: &[InstanceGroup],
+) -> usize {
+    let mut draw_calls = 0;
+    for group in groups {
+        let mesh = ctx.mesh_manager.get(group.mesh).unwrap();
+        let tex_bindgroup = ctx.texture_manager.get_bindgroup(&ctx.device, group.textures);
+        pass.set_vertex_buffer(0, mesh.vertices.slice(..));
+        pass.set_index_buffer(mesh.indices.slice(..), mesh.index_format);
+        pass.set_bind_group(textures, tex_bindgroup, &[]);
+        if instancing {
+            pass.draw_indexed(0..mesh.num_indices, 0, group.instances.clone());
+            draw_calls += 1;
+        } else {
+            for instance in &instances[group.instances.start as usize..group.instances.end as usize] {
+                pass.set_push_constants(wgpu::ShaderStages::VERTEX, 0, bytemuck::bytes_of(instance));
+                pass.draw_indexed(0..mesh.num_indices, 0, 0..1);
+                draw_calls += 1;
+            }
+        }
+    }
+    draw_calls
+}
+
+/// Debug key reloading the shaders edited since they were read, see `WorldRenderer::reload_shaders`
+pub const RELOAD_SHADERS_KEY: VirtualKeyCode = VirtualKeyCode::F5;
 
 pub struct WorldRenderer {
-    shading_pipeline: RenderPipeline,
-    geometry_pipeline: RenderPipeline,
+    shading_key: PermutationKey,
+    lights_limit: LightsLimit,
+    /// Permutations of the geometry pass (ALPHA_MASK, DOUBLE_SIDED)
+    geometry_key: PermutationKey,
+    /// Permutations of the pass drawing the blended materials (DOUBLE_SIDED, LIGHTS_MAX of the
+    /// shading key)
+    forward_key: PermutationKey,
+    /// Whether the geometry pipeline takes the matrices as instances (see `instances`)
+    instancing: bool,
+    instances: InstanceBuffer,
+    shadows: ShadowPass,
     g_buffer: GBuffer,
+    tonemap: Tonemap,
+    pyramid: DepthPyramid,
+    culler: OcclusionCuller,
+    picker: Picker,
     pub camera: Camera,
+    pub particles: ParticleRenderer,
+    pub sprites: SpriteRenderer,
+    pub debug_lines: DebugLineRenderer,
+    pub fog: VolumetricFog,
+    pub ssr: ScreenSpaceReflections,
+    pub bloom: Bloom,
+    /// Ambient occlusion of the shading pass, from the g-buffer (see `shader.wgsl`)
+    pub ssao: bool,
+    pub tonemapper: Tonemapper,
+    /// Shows a g-buffer attachment instead of the lit scene, without bloom nor tonemapping
+    pub debug_view: DebugView,
+    /// Wetness of the surfaces, uploaded every frame (set by the weather)
+    pub surface_weather: SurfaceWeather,
+    /// Skip renderables hidden in the last frame's depth pyramid (see `hiz`)
+    pub occlusion_culling: bool,
+    pub stats: RenderStats,
     lights_cache: HashSet<Entity>,
     size: winit::dpi::PhysicalSize<u32>,
 }
 
 impl WorldRenderer {
     pub fn new(ctx: &mut GraphicContext) -> Self {
+        let format = ctx.target_format();
         let GraphicContext {
             device,
             config,
+            queue,
             texture_manager,
             size,
+            pipelines,
+            downlevel,
             ..
         } = ctx;
 
@@ -40,63 +191,187 @@ impl WorldRenderer {
         camera.set_aspect(size.width as f32 / size.height as f32);
 
         let g_buffer = GBuffer::new(
-            &device,
+            device,
             wgpu::Extent3d {
                 width: config.width,
                 height: config.height,
                 depth_or_array_layers: 1,
             },
+            GBuffer::ATTACHMENTS,
             &[],
-            64,
+            LIGHTS_MAX,
+            ShadowAtlas::new(device, SHADOW_RESOLUTION, SHADOWS_MAX),
         );
 
-        let geometry_pipeline = {
-            let shader = include_shader!("g_buffer.wgsl", "geometry shader");
+        let instancing = supports_instancing(&device.limits());
+        if !instancing {
+            log::warn!("Not enough vertex buffers or attributes for instancing, drawing renderables one by one");
+        }
+        // Permutations by alpha mode and culling (see `Material`)
+        let geometry_key = {
+            let mut shader = include_shader!(["camera.wgsl", "geometry.wgsl", "g_buffer.wgsl"], "geometry shader");
+            for (name, location) in g_buffer.geometry_constants() {
+                shader.set_integer(name, location);
+            }
+            shader.set_bool("ALPHA_MASK", false);
+            shader.set_bool("DOUBLE_SIDED", false);
+            let targets = g_buffer.color_targets();
             let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("geometry pipeline layout"),
                 bind_group_layouts: &[
-                    texture_manager.layout(&device),
-                    camera.get_bind_group_layout(&device),
+                    texture_manager.layout(device),
+                    camera.get_bind_group_layout(device),
                 ],
                 push_constant_ranges: &[wgpu::PushConstantRange {
                     stages: wgpu::ShaderStages::VERTEX,
                     range: 0..128,
                 }],
             });
-            Pipeline::new(&device, layout, shader, |device, layout, shader| {
-                device.create_render_pipeline(&geometry_pipeline_desc!(layout, shader))
+            let device = device.clone();
+            pipelines.register(shader, layout_hash("geometry pipeline layout"), move |shader| {
+                let module = shader.module(&device);
+                let double_sided = shader.get("DOUBLE_SIDED").is_some_and(|d| d.to_string() == "true");
+                let (entry_point, buffers) = geometry_vertex_state(instancing);
+                device.create_render_pipeline(&GBuffer::pipeline_desc(&layout, &module, entry_point, &buffers, &targets, double_sided))
             })
         };
+        pipelines.prewarm([
+            geometry_key.with("ALPHA_MASK", true),
+            geometry_key.with("DOUBLE_SIDED", true),
+            geometry_key.with("ALPHA_MASK", true).with("DOUBLE_SIDED", true),
+        ]);
 
-        let shading_pipeline = {
-            let mut shader = include_shader!("shader.wgsl", "shading shader");
+        let fog = VolumetricFog::new(
+            device,
+            queue,
+            &g_buffer.depth_tex,
+            &g_buffer.shadow_atlas,
+            (config.width, config.height),
+        );
+        let ssr = ScreenSpaceReflections::new(device, queue, &g_buffer, (config.width, config.height));
+
+        let shading_key = {
+            let mut shader = include_shader!(["camera.wgsl", "lighting.wgsl", "shader.wgsl"], "shading shader");
             // default value
-            shader.set_integer("LIGHTS_MAX", 64);
+            shader.set_integer("LIGHTS_MAX", LIGHTS_MAX as i64);
+            shader.set_integer("SHADOWS_MAX", SHADOWS_MAX as i64);
+            shader.set_bool("FOG", false);
+            shader.set_bool("SSR", false);
+            shader.set_bool("SSAO", false);
+            shader.set_integer("DEBUG_VIEW", DebugView::Off.constant());
+            for (name, binding) in g_buffer.shading_constants() {
+                shader.set_integer(name, binding);
+            }
             let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("shading pipeline layout"),
                 bind_group_layouts: &[
                     &g_buffer.bind_group_layout,
-                    camera.get_bind_group_layout(&device),
+                    camera.get_bind_group_layout(device),
+                    &fog.composite_layout,
+                    &ssr.composite_layout,
                 ],
                 push_constant_ranges: &[],
             });
-            let format = config.format;
-            Pipeline::new(&device, layout, shader, move |device, layout, shader| {
-                device.create_render_pipeline(&shading_pipeline_desc!(layout, shader, format))
+            let device = device.clone();
+            pipelines.register(shader, layout_hash("shading pipeline layout"), move |shader| {
+                let module = shader.module(&device);
+                // The SSR permutation also writes the lit scene for the next frame
+                let ssr = shader.get("SSR").is_some_and(|ssr| ssr.to_string() == "true");
+                let history = ssr.then_some(ssr::HISTORY_FORMAT);
+                device.create_render_pipeline(&shading_pipeline_desc!(&layout, &module, HDR_FORMAT, history))
             })
         };
 
+        // Toggling the fog, the reflections or the ambient occlusion shouldn't stall
+        pipelines.prewarm([
+            shading_key.with("FOG", true),
+            shading_key.with("SSR", true),
+            shading_key.with("FOG", true).with("SSR", true),
+            shading_key.with("SSAO", true),
+        ]);
+
+        // Blended materials are lit like the g-buffer, over the shaded scene
+        let forward_key = {
+            let mut shader = include_shader!(["camera.wgsl", "lighting.wgsl", "geometry.wgsl", "forward.wgsl"], "forward shader");
+            shader.set_integer("LIGHTS_MAX", LIGHTS_MAX as i64);
+            shader.set_integer("SHADOWS_MAX", SHADOWS_MAX as i64);
+            shader.set_bool("DOUBLE_SIDED", false);
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("forward pipeline layout"),
+                bind_group_layouts: &[
+                    &g_buffer.bind_group_layout,
+                    camera.get_bind_group_layout(device),
+                    texture_manager.layout(device),
+                ],
+                push_constant_ranges: &[wgpu::PushConstantRange {
+                    stages: wgpu::ShaderStages::VERTEX,
+                    range: 0..128,
+                }],
+            });
+            let device = device.clone();
+            pipelines.register(shader, layout_hash("forward pipeline layout"), move |shader| {
+                let module = shader.module(&device);
+                let double_sided = shader.get("DOUBLE_SIDED").is_some_and(|d| d.to_string() == "true");
+                let (entry_point, buffers) = geometry_vertex_state(instancing);
+                let targets = [Some(wgpu::ColorTargetState {
+                    format: HDR_FORMAT,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })];
+                // Tested against the depth of the g-buffer, without writing it
+                let mut desc = GBuffer::pipeline_desc(&layout, &module, entry_point, &buffers, &targets, double_sided);
+                desc.label = Some("Forward Pipeline");
+                if let Some(depth) = &mut desc.depth_stencil {
+                    depth.depth_write_enabled = false;
+                }
+                device.create_render_pipeline(&desc)
+            })
+        };
+        pipelines.prewarm([forward_key.clone(), forward_key.with("DOUBLE_SIDED", true)]);
+
+        let pyramid = DepthPyramid::new(device, &g_buffer.depth_tex, (config.width, config.height));
+        let culler = OcclusionCuller::new(device);
+        let shadows = ShadowPass::new(device, instancing);
+        let tonemap = Tonemap::new(device, (config.width, config.height), format);
+        let bloom = Bloom::new(device, tonemap.target(), (config.width, config.height));
+        // The particles are lit with the scene, the sprites and debug lines are drawn over the
+        // tonemapped output
+        let particles = ParticleRenderer::new(device, downlevel, HDR_FORMAT);
+        let sprites = SpriteRenderer::new(device, format);
+        let debug_lines = DebugLineRenderer::new(device, format);
+
         Self {
             camera,
             g_buffer,
+            tonemap,
+            pyramid,
+            culler,
+            picker: Picker::default(),
+            particles,
+            sprites,
+            debug_lines,
+            fog,
+            ssr,
+            bloom,
+            ssao: false,
+            tonemapper: Tonemapper::default(),
+            debug_view: DebugView::Off,
+            surface_weather: SurfaceWeather::default(),
+            occlusion_culling: false,
+            stats: RenderStats::default(),
             lights_cache: HashSet::new(),
-            shading_pipeline,
-            geometry_pipeline,
+            shading_key,
+            lights_limit: LightsLimit::new(LIGHTS_MAX),
+            geometry_key,
+            forward_key,
+            instancing,
+            instances: InstanceBuffer::default(),
+            shadows,
             size: *size,
         }
     } 
 
-    pub fn update_lights(&mut self, ctx: &GraphicContext, lights: Entities<(Entity, &LightComponent)>) {
+    pub fn update_lights(&mut self, ctx: &mut GraphicContext, lights: Entities<(Entity, &LightComponent)>) {
         let lights = lights.collect::<Vec<_>>();
         let mut lights_changed = lights.len() != self.lights_cache.len();
         for (id, _) in &lights {
@@ -110,27 +385,113 @@ impl WorldRenderer {
             // update the cache
             self.lights_cache.clear();
             self.lights_cache.extend(lights.iter().map(|(id, _)| id));
+            // The fog is lit by the first directional light
+            self.fog.set_sun(lights.iter().find_map(|(_, light)| match light.light {
+                Light::Directional(sun) => Some(sun),
+                _ => None,
+            }));
+            let count = lights.len() as u32;
+            if let Some(key) = self.lights_limit.update(&mut ctx.pipelines, &self.shading_key, count) {
+                // Built ahead of time if the lights grew gradually
+                self.shading_key = key;
+                self.g_buffer.max_lights = self.lights_limit.max;
+            }
             // TODO make this take an impl IntoIterator
             if let Err(overflow) = self
                 .g_buffer
                 .update_lights(&ctx.device, lights.iter().map(|(_, light)| &light.light))
             {
-                let current_max = self
-                    .shading_pipeline
-                    .shader
-                    .get_integer("LIGHTS_MAX")
-                    .unwrap() as u32;
-                let new_max = (current_max * 2).max(current_max + overflow);
-                self.shading_pipeline
-                    .shader
-                    .set_integer("LIGHTS_MAX", new_max as i64);
-                log::debug!("Max lights reached increasing limit, rebuilding shader and pipeline");
-                self.shading_pipeline.rebuild(&ctx.device); // very expensive
+                log::warn!("{overflow} lights over the limit");
             };
         }
     }
 
-    pub fn render<'a>(
+    /// Re-upload the lights on the next `update_lights`, for when they moved
+    pub fn invalidate_lights(&mut self) {
+        self.lights_cache.clear();
+    }
+
+    /// Reload the shaders whose files changed, keeping the constants they were given (see
+    /// `Shader::watch`, only debug builds read shaders from their files). Those that fail to
+    /// compile are logged and keep their previous version.
+    pub fn reload_shaders(&mut self, ctx: &mut GraphicContext) {
+        let resources = rmanage::instance();
+        let mut pipelines = Vec::new();
+        pipelines.extend(self.shadows.pipelines_mut());
+        pipelines.extend(self.fog.pipelines_mut());
+        pipelines.extend(self.ssr.pipelines_mut());
+        pipelines.extend(self.bloom.pipelines_mut());
+        pipelines.extend(self.tonemap.pipelines_mut());
+        let sources = pipelines
+            .iter()
+            .flat_map(|pipeline| pipeline.shader.sources().iter().copied())
+            .chain(ctx.pipelines.sources())
+            .collect::<Vec<_>>();
+        let changed = changed_sources(resources, sources);
+        if changed.is_empty() {
+            log::info!("No shader changed");
+            return;
+        }
+        let GraphicContext { device, pipelines: cache, .. } = ctx;
+        let mut reloaded = pipelines
+            .into_iter()
+            .map(|pipeline| pipeline.reload(device, resources, &changed))
+            .filter(|reloaded| *reloaded)
+            .count();
+        reloaded += cache.reload(resources, &changed, |shader, build| try_build(device, shader, build));
+        log::info!("Reloaded {reloaded} shaders");
+    }
+
+    /// Forget what was uploaded for the entities of the previous world when a game is loaded
+    pub fn game_loaded(&mut self, loaded: EventReader<GameLoaded>) {
+        if !loaded.is_empty() {
+            self.invalidate_lights();
+            self.culler.invalidate();
+            self.picker.ids.clear();
+        }
+    }
+
+    /// Advance the particle emitters by a frame
+    pub fn update_particles(
+        &mut self,
+        ctx: &GraphicContext,
+        time: &Time,
+        emitters: Entities<(Entity, &mut ParticleEmitterComponent, Option<&TransformsComponent>)>,
+    ) {
+        self.particles.update(
+            &ctx.device,
+            time.delta_secs(),
+            emitters.map(|(entity, emitter, tsm)| {
+                (entity, emitter, tsm.map(|tsm| tsm.translation()).unwrap_or_default())
+            }),
+        );
+    }
+
+    /// Gather the sprites of the frame
+    pub fn update_sprites(
+        &mut self,
+        sprites: Entities<(&SpriteComponent, Option<&TransformsComponent>, Option<&WorldAnchorComponent>)>,
+        transforms: Entities<(Entity, &TransformsComponent)>,
+    ) {
+        self.sprites.update(sprites, transforms);
+    }
+
+    /// Take the debug lines of the frame
+    pub fn update_debug_draw(&mut self, draw: &mut DebugDraw) {
+        self.debug_lines.update(draw);
+    }
+
+    /// System rendering the world into the frame
+    pub fn render(
+        &mut self,
+        ctx: &mut GraphicContext,
+        mut frame: ResMut<Frame>,
+        renderables: Entities<(Entity, &GraphicsComponent, Option<&TransformsComponent>)>,
+    ) {
+        frame.record("world", |encoder, view| self.record(ctx, encoder, view, renderables));
+    }
+
+    pub fn record<'a>(
         &mut self,
         ctx: &mut GraphicContext,
         encoder: &mut wgpu::CommandEncoder,
@@ -141,46 +502,199 @@ impl WorldRenderer {
             self.resize(ctx, ctx.size);
         }
 
+        self.culler.poll(&ctx.device);
+        self.picker.poll(&ctx.device);
+        self.stats = RenderStats::default();
+        // World space bounds of everything, to test against the pyramid
+        let mut bounds: Vec<(Entity, BoundingBox)> = Vec::new();
+
+        self.camera.update(&ctx.device, &ctx.queue);
+        let frustum = self.camera.frustum_planes();
+        // Opaque draws by permutation of the geometry pipeline
+        let mut draws: BTreeMap<PermutationKey, Vec<_>> = BTreeMap::new();
+        // Blended draws, and whether they are double-sided
+        let mut blended = Vec::new();
+        // Everything casts shadows, even outside of the camera's view
+        let mut casters = Vec::new();
+
+        for (entity, gfx, tsm) in renderables {
+            let tsm = tsm.cloned().unwrap_or_default();
+            let mesh = ctx
+                .mesh_manager
+                .get(gfx.mesh)
+                .unwrap_or_else(|| panic!("Unknown mesh"));
+
+            let id = self.picker.ids.id(entity);
+            let material = &gfx.material;
+            let instance = GeometryInstance::new(tsm.mat(), !material.weatherproof, id)
+                .with_material(material.emissive, material.alpha_mode.cutoff());
+            casters.push((gfx.mesh, material.textures, instance));
+            let world_bounds = mesh.bounds.transform(tsm.mat());
+            if !world_bounds.in_frustum(&frustum) {
+                self.stats.culled += 1;
+                continue;
+            }
+            if self.occlusion_culling {
+                bounds.push((entity, world_bounds));
+                // Entities that weren't tested yet (just spawned) aren't in the set
+                if self.culler.occluded().contains(&entity) {
+                    self.stats.occluded += 1;
+                    continue;
+                }
+            }
+            self.stats.drawn += 1;
+            let draw = (gfx.mesh, material.textures, instance);
+            match material.alpha_mode {
+                AlphaMode::Blend => blended.push((material.double_sided, draw)),
+                mode => {
+                    let key = self
+                        .geometry_key
+                        .with("ALPHA_MASK", matches!(mode, AlphaMode::Mask(_)))
+                        .with("DOUBLE_SIDED", material.double_sided);
+                    draws.entry(key).or_default().push(draw);
+                }
+            }
+        }
+        let camera_view = self.camera.get_view();
+        blended.sort_by(|(_, (_, _, a)), (_, (_, _, b))| {
+            b.view_depth(camera_view).total_cmp(&a.view_depth(camera_view))
+        });
+
+        // The opaque instances, the blended ones back to front (one draw each), then the casters
+        let mut instances = Vec::new();
+        let passes: Vec<_> = draws
+            .into_iter()
+            .map(|(key, draws)| (key, append_groups(&mut instances, draws)))
+            .collect();
+        let blended: Vec<_> = blended
+            .into_iter()
+            .map(|(double_sided, (mesh, textures, instance))| {
+                let i = instances.len() as u32;
+                instances.push(instance);
+                (self.forward_key(double_sided), InstanceGroup { mesh, textures, instances: i..i + 1 })
+            })
+            .collect();
+        let caster_groups = append_groups(&mut instances, casters);
+        let buffer = (self.instancing && !instances.is_empty())
+            .then(|| self.instances.write(&ctx.device, &ctx.queue, &instances));
+
+        let shadow_view = ShadowView {
+            layers: SHADOWS_MAX,
+            resolution: self.g_buffer.shadow_atlas.resolution,
+            center: self.camera.get_position(),
+        };
+        let matrices = self.g_buffer.write_lights(&ctx.device, &ctx.queue, &shadow_view);
+        self.shadows.render(
+            encoder,
+            &self.g_buffer.shadow_atlas,
+            &matrices,
+            &ctx.mesh_manager,
+            buffer,
+            &instances,
+            &caster_groups,
+        );
+
+        // Bound together in their pass
+        for key in passes.iter().map(|(key, _)| key).chain(blended.iter().map(|(key, _)| key)) {
+            ctx.pipelines.get(key);
+        }
         {
-            let mut render_pass =
-                encoder.begin_render_pass(&geometry_renderpass_desc!(self.g_buffer));
-
-            render_pass.set_pipeline(&self.geometry_pipeline.pipeline);
-            self.camera.update(&ctx.device, &ctx.queue);
-
-            for (_, gfx, tsm) in renderables {
-                let tsm = tsm.cloned().unwrap_or_default();
-                let mesh = ctx
-                    .mesh_manager
-                    .get(gfx.mesh)
-                    .unwrap_or_else(|| panic!("Unknown mesh"));
-
-                let tex_bindgroup = ctx
-                    .texture_manager
-                    .get_bindgroup(&ctx.device, gfx.material.textures);
-                let cam_bindgroup = self.camera.get_bind_group(&ctx.device, &ctx.queue);
-
-                render_pass.set_vertex_buffer(0, mesh.vertices.slice(..));
-                render_pass.set_index_buffer(mesh.indices.slice(..), wgpu::IndexFormat::Uint32);
-                render_pass.set_bind_group(0, tex_bindgroup, &[]);
-                render_pass.set_bind_group(1, cam_bindgroup, &[]);
-                render_pass.set_push_constants(
-                    wgpu::ShaderStages::VERTEX,
-                    0,
-                    bytemuck::cast_slice(&[tsm.mat(), tsm.mat().inverse().transpose()]),
-                );
-                render_pass.draw_indexed(0..mesh.num_indices, 0, 0..1);
+            let mut render_pass = self.g_buffer.begin_pass(encoder);
+            let cam_bindgroup = self.camera.get_bind_group(&ctx.device, &ctx.queue);
+            render_pass.set_bind_group(1, cam_bindgroup, &[]);
+            if let Some(buffer) = buffer {
+                render_pass.set_vertex_buffer(1, buffer.slice(..));
+            }
+            for (key, groups) in &passes {
+                render_pass.set_pipeline(ctx.pipelines.built(key).unwrap());
+                self.stats.draw_calls += draw_groups(&mut render_pass, ctx, 0, self.instancing, &instances, groups);
             }
         }
+        let size = (self.size.width, self.size.height);
+        self.picker.record(&ctx.device, encoder, &self.g_buffer.id_texture, size);
+        if self.occlusion_culling {
+            self.pyramid.build(encoder);
+            self.culler.record(
+                &ctx.device,
+                &ctx.queue,
+                encoder,
+                &self.pyramid,
+                self.camera.get_view_projection(),
+                &bounds,
+            );
+        }
+        self.fog.render(&ctx.device, &ctx.queue, encoder, &self.camera, &shadow_view);
+        self.g_buffer.set_weather(&ctx.queue, &self.surface_weather);
+        self.ssr.render(&ctx.device, &ctx.queue, encoder, &self.camera);
         {
-            let mut render_pass = encoder.begin_render_pass(&shading_renderpass_desc!(view));
+            let mut render_pass =
+                encoder.begin_render_pass(&shading_renderpass_desc!(self.tonemap.target(), self.ssr.history()));
             let cam_bindgroup = self.camera.get_bind_group(&ctx.device, &ctx.queue);
 
-            render_pass.set_pipeline(&self.shading_pipeline.pipeline);
+            // Composited before tonemapping, the permutation without fog doesn't read it
+            let key = self
+                .shading_key
+                .with("FOG", self.fog.settings.enabled)
+                .with("SSR", self.ssr.settings.enabled)
+                .with("SSAO", self.ssao)
+                .with("DEBUG_VIEW", self.debug_view.constant());
+            render_pass.set_pipeline(ctx.pipelines.get(&key));
             render_pass.set_bind_group(0, &self.g_buffer.bindgroup, &[]);
             render_pass.set_bind_group(1, cam_bindgroup, &[]);
+            render_pass.set_bind_group(2, &self.fog.composite, &[]);
+            render_pass.set_bind_group(3, &self.ssr.composite, &[]);
             render_pass.draw(0..3, 0..1);
         }
+        if !blended.is_empty() {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Forward Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: self.tonemap.target(),
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                })],
+                // Read only, the shading bind group samples it
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.g_buffer.depth_tex,
+                    depth_ops: None,
+                    stencil_ops: None,
+                }),
+            });
+            let cam_bindgroup = self.camera.get_bind_group(&ctx.device, &ctx.queue);
+            render_pass.set_bind_group(0, &self.g_buffer.bindgroup, &[]);
+            render_pass.set_bind_group(1, cam_bindgroup, &[]);
+            if let Some(buffer) = buffer {
+                render_pass.set_vertex_buffer(1, buffer.slice(..));
+            }
+            for (key, group) in &blended {
+                render_pass.set_pipeline(ctx.pipelines.built(key).unwrap());
+                self.stats.draw_calls += draw_groups(&mut render_pass, ctx, 2, self.instancing, &instances, std::slice::from_ref(group));
+            }
+        }
+        let particle_camera = ParticleCamera::new(&self.camera);
+        self.particles.simulate(&ctx.device, &ctx.queue, encoder, &particle_camera);
+        self.particles.draw(encoder, &particle_camera, self.tonemap.target(), &self.g_buffer.depth_tex);
+        let tonemapper = if self.debug_view == DebugView::Off {
+            self.bloom.render(&ctx.queue, encoder, self.tonemap.target());
+            self.tonemapper
+        } else {
+            Tonemapper::Linear
+        };
+        self.tonemap.render(&ctx.queue, encoder, view, tonemapper);
+        self.debug_lines.draw(&ctx.device, &ctx.queue, encoder, self.camera.get_view_projection(), view, &self.g_buffer.depth_tex);
+        let sprite_view = SpriteView::new(&self.camera, glam::Vec2::new(ctx.size.width as f32, ctx.size.height as f32));
+        self.sprites.draw(&ctx.device, &ctx.queue, &ctx.texture_manager, encoder, &sprite_view, view, &self.g_buffer.depth_tex);
+    }
+
+    /// Permutation of the forward pass, with as many lights as the shading pass
+    fn forward_key(&self, double_sided: bool) -> PermutationKey {
+        let lights_max = self.shading_key.get("LIGHTS_MAX").unwrap_or_default();
+        self.forward_key
+            .with("LIGHTS_MAX", lights_max)
+            .with("DOUBLE_SIDED", double_sided)
     }
 
     pub fn resize(&mut self, ctx: &GraphicContext, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -192,16 +706,85 @@ impl WorldRenderer {
                 depth_or_array_layers: 1,
             },
         );
+        self.pyramid.resize(
+            &ctx.device,
+            &self.g_buffer.depth_tex,
+            (new_size.width, new_size.height),
+        );
+        self.fog.resize(
+            &ctx.device,
+            &self.g_buffer.depth_tex,
+            (new_size.width, new_size.height),
+        );
+        self.ssr.resize(
+            &ctx.device,
+            &self.g_buffer,
+            (new_size.width, new_size.height),
+        );
+        self.tonemap.resize(&ctx.device, (new_size.width, new_size.height));
+        self.bloom.resize(&ctx.device, self.tonemap.target(), (new_size.width, new_size.height));
+        self.culler.invalidate();
         self.camera
             .set_aspect(new_size.width as f32 / new_size.height as f32);
         self.size = new_size;
     }
+
+    /// Bytes taken by each render target (the surface isn't counted, the driver owns it)
+    pub fn targets_memory(&self) -> Vec<(&'static str, u64)> {
+        let size = (self.size.width, self.size.height);
+        vec![
+            ("g-buffer", self.g_buffer.memory(size)),
+            ("hdr", Tonemap::memory(size)),
+            ("bloom", Bloom::memory(size)),
+            ("hi-z", self.pyramid.memory()),
+            ("fog", self.fog.memory()),
+            ("ssr", ScreenSpaceReflections::memory(size)),
+            ("shadows", ShadowAtlas::memory(self.g_buffer.shadow_atlas.resolution, SHADOWS_MAX)),
+        ]
+    }
+
+    /// Pick the entity under a pixel (physical, from the top left) of the next frame, resolves on
+    /// a later one
+    pub fn pick(&mut self, pixel: (u32, u32)) -> PickRequest {
+        self.picker.request(pixel)
+    }
+
+    /// Side of the shadow maps
+    pub fn shadow_resolution(&self) -> u32 {
+        self.g_buffer.shadow_atlas.resolution
+    }
+
+    /// Recreate the shadow atlas with shadow maps of `resolution` texels a side
+    pub fn set_shadow_resolution(&mut self, ctx: &GraphicContext, resolution: u32) {
+        let resolution = resolution.clamp(1, ctx.device.limits().max_texture_dimension_2d);
+        if resolution != self.shadow_resolution() {
+            let atlas = ShadowAtlas::new(&ctx.device, resolution, SHADOWS_MAX);
+            self.fog.set_shadow_atlas(&ctx.device, &atlas);
+            self.g_buffer.set_shadow_atlas(&ctx.device, atlas);
+        }
+    }
+
+    /// Must be called once the frame's commands have been submitted
+    pub fn after_submit(&mut self) {
+        self.culler.after_submit();
+        self.picker.after_submit();
+        self.fog.after_submit();
+        self.ssr.after_submit();
+    }
+
+    /// GPU time of the passes that have a timer, as of their last measure
+    pub fn gpu_timings(&self) -> Vec<(&'static str, f32)> {
+        [("fog", self.fog.gpu_time()), ("ssr", self.ssr.gpu_time())]
+            .into_iter()
+            .filter_map(|(pass, time)| Some((pass, time?)))
+            .collect()
+    }
 }
 
 pub struct UIRenderer {
-    size: winit::dpi::PhysicalSize<u32>,
     render_pass: RenderPass,
-    screen_desc: ScreenDescriptor,
+    /// What egui asked of the platform in the last frame
+    output: UiOutput,
 }
 
 /// SAFETY: This isn't lmao
@@ -213,27 +796,118 @@ pub struct UIRenderer {
 unsafe impl Send for UIRenderer {}
 
 impl UIRenderer {
-    pub fn new(ctx: &GraphicContext, ppp: f32) -> Self {
+    pub fn new(ctx: &GraphicContext) -> Self {
         Self {
-            size: ctx.size,
-            render_pass: RenderPass::new(&ctx.device, ctx.config.format, 1),
-            screen_desc: ScreenDescriptor {
-                size_in_pixels: [ctx.size.width, ctx.size.height],
-                pixels_per_point: ppp
-            },
+            render_pass: RenderPass::new(&ctx.device, ctx.target_format(), 1),
+            output: UiOutput::default(),
         }
     }
 
-    pub fn draw(&self, ctx: &egui::Context) {
-        egui::Window::new("Test").show(ctx, |ui| {
-            ui.heading("Test 2");
-            if ui.button("Click").clicked() {
-                log::info!("Clicked");
+    /// What egui asked of the platform in the last frame, for the event loop
+    pub fn take_output(&mut self) -> UiOutput {
+        std::mem::take(&mut self.output)
+    }
+
+    /// The game's windows, then the panels of `draw`
+    #[allow(clippy::too_many_arguments)] // The resources the UI shows
+    pub fn draw(&self, ctx: &egui::Context, focus: &mut UiFocus, loc: &mut Localization, minimap: &mut Minimap, minimap_texture: TextureId, paint: &mut TexturePaintTool, fog: &mut VolumetricFog, ssr: &mut ScreenSpaceReflections, stats: RenderStats, quality: &mut AdaptiveQuality, weather: &mut Weather, memory: &mut GpuMemory, memory_stats: &GpuMemoryStats, console: &mut Console, saves: &mut SaveMenu, scale: &mut UiScale, draw: &mut UiDraw) {
+        console.ui(ctx, focus, loc);
+        minimap.ui(ctx, minimap_texture, tr!(loc, "minimap.window"));
+        paint.ui(ctx, focus, loc);
+        fog.ui(ctx, focus, loc);
+        ssr.ui(ctx, focus, loc);
+        quality.ui(ctx, focus, loc);
+        weather.ui(ctx, focus, loc);
+        memory.ui(ctx, focus, loc, memory_stats);
+        saves.ui(ctx, focus, loc);
+
+        focus.begin_panel("settings");
+        egui::Window::new(tr!(loc, "settings.window")).show(ctx, |ui| {
+            let current = loc
+                .languages()
+                .iter()
+                .find(|(code, _)| code == loc.language())
+                .map(|(_, name)| name.clone())
+                .unwrap_or_else(|| loc.language().to_owned());
+            let mut selected = loc.language().to_owned();
+            // The entries of the popup are registered before the combo box's response exists
+            let slot = focus.reserve();
+            let combo = egui::ComboBox::from_label(tr!(loc, "settings.language"))
+                .selected_text(current)
+                .show_ui(ui, |ui| {
+                    for (code, name) in loc.languages() {
+                        focus.track(ui.selectable_value(&mut selected, code.clone(), name));
+                    }
+                });
+            focus.fill(slot, &combo.response);
+            if selected != loc.language() {
+                if let Err(e) = loc.set_language(&selected) {
+                    log::error!("Couldn't switch language to '{selected}': {e:#}");
+                }
+            }
+
+            // Applied on the next frame, the layout of this one is done at the current scale
+            let mut user = scale.user();
+            let slider = egui::Slider::new(&mut user, USER_SCALE_MIN..=USER_SCALE_MAX)
+                .step_by(0.05)
+                .text(tr!(loc, "settings.ui_scale"));
+            if focus.track(ui.add(slider)).changed() {
+                scale.set_user(user);
             }
+
+            ui.separator();
+            ui.label(tr!(loc, "settings.controls"));
+            egui::Grid::new("controls").show(ui, |ui| {
+                for action in UiAction::ALL {
+                    ui.label(action_name(loc, action));
+                    let keys = if focus.rebinding() == Some(action) {
+                        tr!(loc, "settings.press_key").to_owned()
+                    } else {
+                        let keys = focus.bindings().keys(action);
+                        keys.iter().map(|k| k.to_string()).collect::<Vec<_>>().join(", ")
+                    };
+                    if focus.track(ui.button(keys)).clicked() {
+                        focus.start_rebinding(action);
+                    }
+                    ui.end_row();
+                }
+            });
         });
+
+        draw.draw(ctx, loc, stats);
     }
 
+    /// System rendering the UI into the frame, on top of the world
+    #[allow(clippy::too_many_arguments)] // The resources the UI shows
     pub fn render(
+        &mut self,
+        ctx: &GraphicContext,
+        mut frame: ResMut<Frame>,
+        wr: &mut WorldRenderer,
+        estate: &mut egui_winit::State,
+        ui: &egui::Context,
+        window: &Arc<Window>,
+        grabbed: &Grabbed,
+        focus: &mut UiFocus,
+        loc: &mut Localization,
+        minimap: &mut Minimap,
+        paint: &mut TexturePaintTool,
+        quality: &mut AdaptiveQuality,
+        weather: &mut Weather,
+        memory: &mut GpuMemory,
+        memory_stats: &GpuMemoryStats,
+        console: &mut Console,
+        saves: &mut SaveMenu,
+        scale: &mut UiScale,
+        draw: &mut UiDraw,
+    ) {
+        frame.record("ui", |encoder, view| {
+            self.record(ctx, encoder, view, estate, ui, grabbed, focus, window, loc, minimap, paint, &mut wr.fog, &mut wr.ssr, wr.stats, quality, weather, memory, memory_stats, console, saves, scale, draw)
+        });
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
         &mut self,
         ctx: &GraphicContext,
         encoder: &mut wgpu::CommandEncoder,
@@ -241,25 +915,48 @@ impl UIRenderer {
         estate: &mut egui_winit::State,
         ui: &egui::Context,
         grabbed: &Grabbed,
+        focus: &mut UiFocus,
         window: &Arc<Window>,
+        loc: &mut Localization,
+        minimap: &mut Minimap,
+        paint: &mut TexturePaintTool,
+        fog: &mut VolumetricFog,
+        ssr: &mut ScreenSpaceReflections,
+        stats: RenderStats,
+        quality: &mut AdaptiveQuality,
+        weather: &mut Weather,
+        memory: &mut GpuMemory,
+        memory_stats: &GpuMemoryStats,
+        console: &mut Console,
+        saves: &mut SaveMenu,
+        scale: &mut UiScale,
+        draw: &mut UiDraw,
     ) {
-        if ctx.size != self.size {
-            self.size = ctx.size;
-            self.screen_desc.size_in_pixels = [ctx.size.width, ctx.size.height];
+        // egui-winit resets its scale to the system one on ScaleFactorChanged, and converts the
+        // input with it, so it has to agree with the descriptor before the input is taken
+        scale.sync(ctx.size, window.scale_factor());
+        let screen_desc = scale.screen_descriptor();
+        if estate.pixels_per_point() != screen_desc.pixels_per_point {
+            estate.set_pixels_per_point(screen_desc.pixels_per_point);
         }
 
-        if window.scale_factor() as f32 != self.screen_desc.pixels_per_point {
-            self.screen_desc.pixels_per_point = window.scale_factor() as f32;
-        }
+        let minimap_texture = minimap.texture_id(&ctx.device, &mut self.render_pass);
+        let mut input = estate.take_egui_input(window);
+        focus.begin_frame(&mut input);
 
-        let input = estate.take_egui_input(&window);
-
-        let output = ui.run(input, |ui| {
-            self.draw(ui)
+        let mut output = ui.run(input, |ui| {
+            self.draw(ui, focus, loc, minimap, minimap_texture, paint, fog, ssr, stats, quality, weather, memory, memory_stats, console, saves, scale, draw)
         });
-        
+        focus.end_frame(ui);
+        // Kept until the event loop takes it, a link clicked in a frame it didn't see isn't lost
+        let new = UiOutput::extract(&mut output);
+        self.output = UiOutput {
+            open_url: new.open_url.or_else(|| self.output.open_url.take()),
+            ..new
+        };
+
         if !**grabbed {
-            estate.handle_platform_output(&window, ui, output.platform_output);
+            estate.handle_platform_output(window, ui, output.platform_output);
         }
 
         for (id, delta) in output.textures_delta.set {
@@ -267,11 +964,120 @@ impl UIRenderer {
         }
             
         let primitives = ui.tessellate(output.shapes);
-        self.render_pass.update_buffers(&ctx.device, &ctx.queue, &primitives, &self.screen_desc);
-        self.render_pass.execute(encoder, view, &primitives, &self.screen_desc, None);
+        self.render_pass.update_buffers(&ctx.device, &ctx.queue, &primitives, &screen_desc);
+        self.render_pass.execute(encoder, view, &primitives, &screen_desc, None);
 
         for id in output.textures_delta.free {
             self.render_pass.free_texture(&id);
         }
     }
 }
+
+fn action_name(loc: &Localization, action: UiAction) -> &str {
+    match action {
+        UiAction::Up => tr!(loc, "controls.up"),
+        UiAction::Down => tr!(loc, "controls.down"),
+        UiAction::Left => tr!(loc, "controls.left"),
+        UiAction::Right => tr!(loc, "controls.right"),
+        UiAction::Accept => tr!(loc, "controls.accept"),
+        UiAction::Back => tr!(loc, "controls.back"),
+        UiAction::NextPanel => tr!(loc, "controls.next_panel"),
+        UiAction::PrevPanel => tr!(loc, "controls.prev_panel"),
+        UiAction::MoveForward => tr!(loc, "controls.move_forward"),
+        UiAction::MoveBack => tr!(loc, "controls.move_back"),
+        UiAction::MoveLeft => tr!(loc, "controls.move_left"),
+        UiAction::MoveRight => tr!(loc, "controls.move_right"),
+        UiAction::Jump => tr!(loc, "controls.jump"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec3, Vec4};
+
+    use crate::systems::graphics::mesh_manager::{Mesh, Primitives};
+    use crate::systems::graphics::pipeline::Shader;
+    use crate::systems::graphics::pipeline_cache::EVICT_AFTER;
+    use crate::systems::graphics::texture_manager::SingleValue;
+    use crate::systems::graphics::Material;
+
+    use super::*;
+
+    #[test]
+    fn lights_threshold() {
+        let mut cache = PipelineCache::new(EVICT_AFTER);
+        let mut shader = Shader::new("{{LIGHTS_MAX}}".to_owned(), "shading");
+        shader.set_integer("LIGHTS_MAX", LIGHTS_MAX as i64);
+        let mut key = cache.register(shader, 0, |shader| {
+            shader.get("LIGHTS_MAX").unwrap().to_string().parse::<u32>().unwrap()
+        });
+        let mut limit = LightsLimit::new(LIGHTS_MAX);
+        for lights in 40..=64 {
+            cache.maintain();
+            assert_eq!(None, limit.update(&mut cache, &key, lights));
+            assert_eq!(64, *cache.get(&key));
+        }
+        cache.wait_idle();
+        cache.maintain();
+        key = limit.update(&mut cache, &key, 65).unwrap();
+        assert_eq!(128, *cache.get(&key));
+        // Only the initial permutation was built on the hot path
+        assert_eq!(1, cache.stats.hot_builds);
+        assert_eq!(0, cache.stats.stalls);
+        assert_eq!(1, cache.stats.prewarmed);
+    }
+
+    /// A wall hiding 400 cubes, with 5 in front of it: once the visibility of the first frames is
+    /// read back, only the wall and those 5 are drawn. The CPU side of the same scene is
+    /// `hiz::tests::hundreds_behind_a_wall`.
+    #[test]
+    #[ignore = "needs a fallback adapter that can run the renderer"]
+    fn occluded_draws() {
+        let mut gfx = pollster::block_on(GraphicContext::headless(320, 180))
+            .expect("No fallback adapter that can run the renderer");
+        let cube = gfx.mesh_manager.add(&gfx.device, &Mesh::new_cube());
+        let albedo = gfx.texture_manager.get_or_add_single_value_texture(
+            &gfx.device,
+            &gfx.queue,
+            SingleValue::Color(Vec4::ONE),
+        );
+        let material = Material::new_with_values(albedo, None, 0.0, 0.5, None, &mut gfx).unwrap();
+        let gfx_component = GraphicsComponent {
+            mesh: cube,
+            material,
+        };
+        let transforms = |position: Vec3, scale: Vec3| {
+            let mut tsm = TransformsComponent::new();
+            tsm.set_translation(position).set_scale(scale);
+            tsm
+        };
+        let behind = (0..400).map(|i| {
+            let (x, y) = ((i % 20) as f32 - 9.5, (i / 20) as f32 - 9.5);
+            transforms(Vec3::new(x, y * 0.5, 10.0 + y.abs()), Vec3::splat(0.8))
+        });
+        let in_front = (0..5)
+            .map(|i| transforms(Vec3::new(i as f32 * 1.5 - 3.0, 0.0, 3.0), Vec3::splat(0.8)));
+        let wall = transforms(Vec3::new(0.0, 0.0, 5.25), Vec3::new(30.0, 20.0, 0.5));
+        let renderables = behind
+            .chain(in_front)
+            .chain([wall])
+            .enumerate()
+            .map(|(i, tsm)| (Entity::from_bits(i as u64), tsm))
+            .collect::<Vec<_>>();
+
+        let mut wr = WorldRenderer::new(&mut gfx);
+        wr.camera.set_position(Vec3::ZERO);
+        wr.occlusion_culling = true;
+        let mut stats = Vec::new();
+        for _ in 0..4 {
+            let frame = renderables.iter().map(|(e, tsm)| (*e, &gfx_component, Some(tsm)));
+            gfx.render_offscreen(&mut wr, frame).unwrap();
+            stats.push(wr.stats);
+        }
+        // Nothing was tested before the first frame
+        assert_eq!((406, 0), (stats[0].drawn, stats[0].occluded), "{stats:?}");
+        let last = stats.last().unwrap();
+        assert_eq!((6, 400), (last.drawn, last.occluded), "{stats:?}");
+        assert_eq!(0, last.culled);
+    }
+}
@@ -0,0 +1,140 @@
+//! Captures of the frame presented on the surface, requested with
+//! `GraphicContext::request_screenshot` and saved as PNG once the frame is rendered.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+
+/// Directory of the screenshots without an explicit path, relative to the working directory
+pub const SCREENSHOT_DIRECTORY: &str = "screenshots";
+
+/// A new file in `SCREENSHOT_DIRECTORY`
+pub fn default_path() -> PathBuf {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis());
+    Path::new(SCREENSHOT_DIRECTORY).join(format!("screenshot-{millis}.png"))
+}
+
+/// Bytes per row of a texture to buffer copy of an 8 bits RGBA texture, rows have to be aligned
+pub fn padded_bytes_per_row(width: u32) -> u32 {
+    (width * 4).div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT
+}
+
+/// Tightly packed RGBA pixels from the rows of a copy, swapping the channels of BGRA data
+pub fn unpad(data: &[u8], width: u32, height: u32, bgra: bool) -> Vec<u8> {
+    let padded = padded_bytes_per_row(width) as usize;
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+    for row in data.chunks(padded).take(height as usize) {
+        pixels.extend_from_slice(&row[..width as usize * 4]);
+    }
+    if bgra {
+        for pixel in pixels.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+    pixels
+}
+
+/// Copy a texture of the surface (which needs the COPY_SRC usage) and save it as a PNG. This waits
+/// for the GPU.
+pub fn capture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    format: wgpu::TextureFormat,
+    (width, height): (u32, u32),
+    path: &Path,
+) -> Result<()> {
+    let pixels = read_pixels(device, queue, texture, format, (width, height))?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Couldn't create {}", parent.display()))?;
+    }
+    image::save_buffer(path, &pixels, width, height, image::ColorType::Rgba8)
+        .with_context(|| format!("Couldn't write {}", path.display()))
+}
+
+/// Copy an 8 bits RGBA or BGRA texture (which needs the COPY_SRC usage) to tightly packed RGBA
+/// pixels. This waits for the GPU.
+pub fn read_pixels(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    format: wgpu::TextureFormat,
+    (width, height): (u32, u32),
+) -> Result<Vec<u8>> {
+    let bgra = match format {
+        wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => true,
+        wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => false,
+        format => bail!("Can't capture a surface of format {format:?}"),
+    };
+    let bytes_per_row = padded_bytes_per_row(width);
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("screenshot buffer"),
+        size: bytes_per_row as u64 * height as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("screenshot encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(bytes_per_row),
+                rows_per_image: None,
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+    let submission = queue.submit(std::iter::once(encoder.finish()));
+
+    let (sender, receiver) = mpsc::channel();
+    let slice = buffer.slice(..);
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::Maintain::WaitForSubmissionIndex(submission));
+    receiver
+        .recv()
+        .context("The screenshot buffer was dropped")?
+        .context("Couldn't map the screenshot buffer")?;
+    let pixels = unpad(&slice.get_mapped_range(), width, height, bgra);
+    buffer.unmap();
+    Ok(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpadding() {
+        assert_eq!(256, padded_bytes_per_row(1));
+        assert_eq!(256, padded_bytes_per_row(64));
+        assert_eq!(512, padded_bytes_per_row(65));
+
+        // 2x2 BGRA, rows padded to 256 bytes
+        let mut data = vec![0; 512];
+        data[..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        data[256..264].copy_from_slice(&[9, 10, 11, 12, 13, 14, 15, 16]);
+        let rgba = [3, 2, 1, 4, 7, 6, 5, 8, 11, 10, 9, 12, 15, 14, 13, 16];
+        assert_eq!(rgba.to_vec(), unpad(&data, 2, 2, true));
+        assert_eq!(
+            [&data[..8], &data[256..264]].concat(),
+            unpad(&data, 2, 2, false)
+        );
+    }
+}
@@ -1,3 +1,5 @@
+// Shading of the g-buffer, after camera.wgsl and lighting.wgsl
+
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) uv: vec2<f32>,
@@ -11,178 +13,201 @@ fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
     return v_out;
 }
 
-struct DiretionalLight {
-    @size(16)
-    direction: vec3<f32>,
-    color: vec4<f32>
-}
-
-struct PointLight {
-    @size(16)
-    position: vec3<f32>,
-    color: vec4<f32>
-}
-
-struct SpotLight {
-    @size(16)
-    position: vec3<f32>,
-    direction_cutoff: vec4<f32>,
-    color: vec4<f32>
-}
-
-struct DiretionalLights {
-    @size(16)
-    length: u32,
-    lights: array<DiretionalLight, {{LIGHTS_MAX}}>,
-}
-struct PointLights {
-    @size(16)
-    length: u32,
-    lights: array<PointLight, {{LIGHTS_MAX}}>,
-}
-struct SpotLights {
-    @size(16)
-    length: u32,
-    lights: array<SpotLight, {{LIGHTS_MAX}}>,
-}
-struct CameraInfo {
-    view_proj: mat4x4<f32>,
-    view: mat4x4<f32>,
-    pos: vec3<f32>,
-    aspect: f32,
-}
-
-@group(0) @binding(0)
-var g_sampler: sampler;
-@group(0) @binding(1)
+// The attachments, bindings from GBuffer::shading_constants
+@group(0) @binding({{albedo}})
 var g_albedo: texture_2d<f32>;
-@group(0) @binding(2)
+@group(0) @binding({{position}})
 var g_position: texture_2d<f32>;
-@group(0) @binding(3)
+@group(0) @binding({{normal}})
 var g_normals: texture_2d<f32>;
-@group(0) @binding(4)
+@group(0) @binding({{mra}})
 var g_mra: texture_2d<f32>;
-@group(0) @binding(5)
-var g_depth: texture_depth_2d;
-@group(0) @binding(6)
-var<uniform> d_lights: DiretionalLights;
-@group(0) @binding(7)
-var<uniform> p_lights: PointLights;
-@group(0) @binding(8)
-var<uniform> s_lights: SpotLights;
-@group(1) @binding(0)
-var<uniform> cam: CameraInfo;
-@group(1) @binding(1)
-var skybox: texture_cube<f32>;
-@group(1) @binding(2)
-var irr_map: texture_cube<f32>;
-
-let PI = 3.1415926535;
-
-fn filmic(x: vec3<f32>) -> vec3<f32> {
-  let X = max(vec3<f32>(0.0), x - 0.004);
-  let result = (X * (6.2 * X + 0.5)) / (X * (6.2 * X + 1.7) + 0.06);
-  return pow(result, vec3<f32>(2.2));
-}
-
-fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32>{
-    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
-}
-
-fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32>{
-    return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
-}
-
-fn distribution_ggx(n: vec3<f32>, h: vec3<f32>, roughness: f32) -> f32 {
-    let a = roughness * roughness;
-    let a2 = a*a;
-    let ndot_h = max(dot(n, h), 0.0);
-    let ndot_h2 = ndot_h * ndot_h;
-
-    let num = a2;
-    var denom = (ndot_h2 * (a2 - 1.0) + 1.0);
-    denom = PI * denom * denom;
+@group(0) @binding({{emissive}})
+var g_emissive: texture_2d<f32>;
 
-    return num / denom;
+// Mirrors fog::FogUpsample
+struct FogUpsample {
+    near: f32,
+    far: f32,
+    half_size: vec2<i32>,
 }
 
-fn geometry_schlick_ggx(ndot_v: f32, roughness: f32) -> f32 {
-    let r = (roughness + 1.0);
-    let k = (r*r) / 8.0;
+// Half resolution volumetric fog, scattered light (rgb) and transmittance (a)
+@group(2) @binding(0)
+var fog: texture_2d<f32>;
+@group(2) @binding(1)
+var<uniform> fog_upsample: FogUpsample;
 
-    let num = ndot_v;
-    let denom = ndot_v * (1.0 - k) + k;
-
-    return num / denom;
-}
-
-fn geometry_smith(n: vec3<f32> , v: vec3<f32>, l: vec3<f32>, roughness: f32) -> f32 {
-    let ndot_v = max(dot(n, v), 0.0);
-    let ndot_l = max(dot(n, l), 0.0);
-    let ggx2 = geometry_schlick_ggx(ndot_v, roughness);
-    let ggx1 = geometry_schlick_ggx(ndot_l, roughness);
-
-    return ggx1 * ggx2;
-}
-
-fn dir_light(light: DiretionalLight, normal: vec3<f32>, view_dir: vec3<f32>) -> vec4<f32>  {
-    let light_dir = normalize(-light.direction);
-    let diff = max(dot(normal, light_dir), 0.0);
-    let diffuse = light.color  * diff;
-    return diffuse;
-}
-
-fn point_light(light: PointLight, normal: vec3<f32>, albedo: vec3<f32>, metallic: f32, roughness: f32, frag_pos: vec3<f32>, view_dir: vec3<f32>, f0: vec3<f32>) -> vec3<f32>  {
-    let light_dir = normalize(light.position - frag_pos);
-    let halfway = normalize(light_dir + view_dir);
-    let distance = length(light.position - frag_pos);
-    let attenuation = 1.0 / (distance * distance);
-    let radiance = light.color.xyz * attenuation;
-    // cook-torrance
-    let F = fresnel_schlick(max(dot(halfway, view_dir), 0.0), f0);
-    let NDF = distribution_ggx(normal, halfway, roughness);       
-    let G = geometry_smith(normal, view_dir, light_dir, roughness);
-    let numerator = NDF * G * F;
-    let denominator = 4.0 * max(dot(normal, view_dir), 0.0) * max(dot(normal, light_dir), 0.0)  + 0.0001;
-    let specular = numerator / denominator;
-    let kS = F;
-    let kD = (vec3<f32>(1.0) - kS) * (1.0 - metallic);
-    let ndot_l = max(dot(normal, light_dir), 0.0);        
-    return (kD * albedo / PI + specular) * radiance * ndot_l;
-}
-
-fn ambiant_light(normal: vec3<f32>, view: vec3<f32>, f0: vec3<f32>, roughness: f32, albedo: vec3<f32>, ao: f32) -> vec3<f32> {
-    let kS = fresnel_schlick_roughness(max(dot(normal, view), 0.0), f0, roughness);
-    let kD = 1.0 - kS;
-    let irr = textureSample(irr_map, g_sampler, normal).xyz;
-    let diff = irr * albedo;
-    return (kD * diff) * ao;
+// Mirrors ssr::SsrParams
+struct SsrParams {
+    view_proj: mat4x4<f32>,
+    prev_view_proj: mat4x4<f32>,
+    cam_pos: vec3<f32>,
+    near: f32,
+    far: f32,
+    thickness: f32,
+    max_distance: f32,
+    max_roughness: f32,
+    edge_fade: f32,
+    steps: u32,
+    refine: u32,
+    frame: u32,
+    history: u32,
+    max_blur: f32,
+    padding: vec2<u32>,
+}
+
+// Half resolution screen-space reflections, color (rgb) and confidence (a)
+@group(3) @binding(0)
+var reflections: texture_2d<f32>;
+@group(3) @binding(1)
+var<uniform> ssr_params: SsrParams;
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    // The lit scene before fog, for the next frame's reflections (only bound with SSR)
+    @location(1) history: vec4<f32>,
+}
+
+fn linear_depth(depth: f32) -> f32 {
+    let near = fog_upsample.near;
+    let far = fog_upsample.far;
+    return near * far / (far - depth * (far - near));
+}
+
+// Bilateral upsample of the fog: the four closest half resolution texels, weighted by distance
+// and by how close their depth is to this pixel's (mirrors fog::upsample_weights)
+fn upsample_fog(pixel: vec2<i32>) -> vec4<f32> {
+    let full_size = textureDimensions(g_depth);
+    let z = linear_depth(textureLoad(g_depth, pixel, 0));
+    let h = (vec2<f32>(pixel) + 0.5) * 0.5 - 0.5;
+    let base = vec2<i32>(floor(h));
+    let f = fract(h);
+    var sum = vec4<f32>(0.0);
+    var total = 0.0;
+    for (var i = 0; i < 4; i++) {
+        let offset = vec2<i32>(i & 1, i >> 1u);
+        let texel = clamp(base + offset, vec2<i32>(0), fog_upsample.half_size - 1);
+        let bilinear = mix(1.0 - f.x, f.x, f32(offset.x)) * mix(1.0 - f.y, f.y, f32(offset.y));
+        let sample_z = linear_depth(textureLoad(g_depth, min(texel * 2, full_size - 1), 0));
+        let w = bilinear / (0.01 + abs(sample_z - z) / z);
+        sum += textureLoad(fog, texel, 0) * w;
+        total += w;
+    }
+    return sum / total;
+}
+
+// Mirrors ssr::roughness_fade
+fn roughness_fade(roughness: f32, max_roughness: f32) -> f32 {
+    return clamp((max_roughness - roughness) / max(max_roughness * 0.25, 1e-4), 0.0, 1.0);
+}
+
+// Screen-space ambient occlusion, with the SSAO permutation: the g-buffer positions of pixels on
+// a spiral around this one, within SSAO_RADIUS world units, occlude it when they are above its
+// surface (the estimator of McGuire's scalable ambient obscurance). The spiral is the same for
+// every pixel, so the result is deterministic, without noise to hide.
+let SSAO_SAMPLES = 16;
+let SSAO_RADIUS = 0.5;
+let SSAO_BIAS = 0.02;
+let SSAO_INTENSITY = 1.0;
+
+fn ssao(pixel: vec2<i32>, position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    let size = textureDimensions(g_depth);
+    // The radius on the screen, along the camera's up (a row of the view matrix)
+    let up = vec3<f32>(cam.view[0][1], cam.view[1][1], cam.view[2][1]);
+    let center = cam.view_proj * vec4<f32>(position, 1.0);
+    let top = cam.view_proj * vec4<f32>(position + up * SSAO_RADIUS, 1.0);
+    let radius = abs(top.y / top.w - center.y / center.w) * 0.5 * f32(size.y);
+    if (radius < 1.0) {
+        return 1.0;
+    }
+    var occlusion = 0.0;
+    for (var i = 0; i < SSAO_SAMPLES; i++) {
+        let alpha = (f32(i) + 0.5) / f32(SSAO_SAMPLES);
+        let angle = alpha * 14.0 * PI;
+        let offset = vec2<f32>(cos(angle), sin(angle)) * alpha * radius;
+        let texel = clamp(pixel + vec2<i32>(offset), vec2<i32>(0), size - 1);
+        if (textureLoad(g_depth, texel, 0) >= 1.0) {
+            continue;
+        }
+        let v = textureLoad(g_position, texel, 0).xyz - position;
+        let vv = dot(v, v);
+        let falloff = max(SSAO_RADIUS * SSAO_RADIUS - vv, 0.0);
+        let above = max((dot(v, normal) - SSAO_BIAS) / (vv + 0.01), 0.0);
+        occlusion += falloff * falloff * falloff * above;
+    }
+    let r3 = SSAO_RADIUS * SSAO_RADIUS * SSAO_RADIUS;
+    return max(1.0 - occlusion * SSAO_INTENSITY * 5.0 / (r3 * r3 * f32(SSAO_SAMPLES)), 0.0);
+}
+
+// The g-buffer debug views, mirrors g_buffer::DebugView
+fn debug_view(s: Surface, emissive: vec3<f32>, depth: f32) -> vec3<f32> {
+    let view = {{DEBUG_VIEW}};
+    if (view == 1) {
+        return s.albedo;
+    } else if (view == 2) {
+        return s.normal * 0.5 + 0.5;
+    } else if (view == 3) {
+        return vec3<f32>(s.metallic, s.roughness, s.ao);
+    } else if (view == 4) {
+        return emissive;
+    }
+    if (depth >= 1.0) {
+        return vec3<f32>(1.0);
+    }
+    return vec3<f32>(1.0 - exp(-distance(s.position, cam.pos) / 16.0));
 }
 
 @fragment
-fn fs_main(v_in: VertexOutput) -> @location(0) vec4<f32> {
+fn fs_main(v_in: VertexOutput) -> FragmentOutput {
     let uv = vec2<f32>(v_in.uv.x, 1.0 - v_in.uv.y);
-    let normal = textureSample(g_normals, g_sampler, uv).xyz;
-    let albedo = textureSample(g_albedo, g_sampler, uv).xyz;
-    let metallic = textureSample(g_mra, g_sampler, uv).x;
-    let roughness = textureSample(g_mra, g_sampler, uv).y;
-    let ao = textureSample(g_mra, g_sampler, uv).z;
-    let pos = textureSample(g_position, g_sampler, uv).xyz;
-
-    let view_dir = normalize(cam.pos - pos);
-    let f0 = mix(vec3<f32>(0.04), albedo, metallic);
-    var l = vec3<f32>(0.0);
-    for(var i: u32 = 0u; i < p_lights.length; i++) {
-        l += point_light(p_lights.lights[i], normal, albedo, metallic, roughness, pos, view_dir, f0);
+    let mra = textureSample(g_mra, g_sampler, uv);
+    var surface: Surface;
+    surface.position = textureSample(g_position, g_sampler, uv).xyz;
+    surface.normal = textureSample(g_normals, g_sampler, uv).xyz;
+    surface.albedo = textureSample(g_albedo, g_sampler, uv).xyz;
+    surface.metallic = mra.x;
+    surface.roughness = mra.y;
+    surface.ao = mra.z;
+    surface.exposed = mra.w;
+    if ({{SSAO}}) {
+        surface.ao *= ssao(vec2<i32>(v_in.clip_position.xy), surface.position, surface.normal);
+    }
+    let emissive = textureSample(g_emissive, g_sampler, uv).rgb;
+    if ({{DEBUG_VIEW}} != 0) {
+        // Before the weather, as the geometry pass wrote it
+        let depth = textureSample(g_depth, g_sampler, uv);
+        var out: FragmentOutput;
+        out.color = vec4<f32>(debug_view(surface, emissive, depth), 1.0);
+        out.history = out.color;
+        return out;
+    }
+    let s = weathered(surface);
+
+    let view_dir = normalize(cam.pos - s.position);
+    var color = surface_light(s, view_dir) + emissive;
+    if ({{SSR}}) {
+        // The screen-space reflections replace the specular reflection of the environment
+        // (in surface_light) where they hit
+        let reflectance = env_reflectance(s.normal, view_dir, base_reflectivity(s), s.roughness);
+        let env = env_specular(s.normal, view_dir, s.roughness);
+        let ssr = textureSampleLevel(reflections, g_sampler, uv, 0.0);
+        let fade = roughness_fade(s.roughness, ssr_params.max_roughness);
+        color += (ssr.rgb - env) * reflectance * s.ao * ssr.a * fade;
     }
-    let ambiant = ambiant_light(normal, view_dir, f0, roughness, albedo, ao);
-    var color = l + ambiant;
     let depth = textureSample(g_depth, g_sampler, uv);
     let background = textureSample(skybox, g_sampler, (vec4<f32>((uv.x * 2.0 - 1.0) * cam.aspect *
     PI * 0.5, uv.y * -2.0 + 1.0, 1.0, 0.0) * cam.view).xyz).xyz;
     if (depth >= 1.0) {
         color = background;
     }
-    color = filmic(color);
-    return vec4<f32>(color, 1.0);
+    var out: FragmentOutput;
+    out.history = vec4<f32>(color, 1.0);
+    if ({{FOG}}) {
+        let fog = upsample_fog(vec2<i32>(v_in.clip_position.xy));
+        color = color * fog.a + fog.rgb;
+    }
+    // Tonemapped by the tonemap pass
+    out.color = vec4<f32>(color * weather.exposure, 1.0);
+    return out;
 }
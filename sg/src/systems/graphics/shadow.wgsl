@@ -0,0 +1,29 @@
+// Depth of the renderables seen from a light (see shadows.rs)
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+}
+// The model matrix of instances::GeometryInstance
+struct InstanceInput {
+    @location(4) model_0: vec4<f32>,
+    @location(5) model_1: vec4<f32>,
+    @location(6) model_2: vec4<f32>,
+    @location(7) model_3: vec4<f32>,
+}
+// The light's view projection, times the model matrix for the devices drawing without
+// instancing (vs_single)
+struct PushConstants {
+    transform: mat4x4<f32>,
+}
+var<push_constant> pc: PushConstants;
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> @builtin(position) vec4<f32> {
+    let model_mat = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+    return pc.transform * model_mat * vec4<f32>(model.position, 1.0);
+}
+
+@vertex
+fn vs_single(model: VertexInput) -> @builtin(position) vec4<f32> {
+    return pc.transform * vec4<f32>(model.position, 1.0);
+}
@@ -0,0 +1,345 @@
+//! Shadow maps of the directional and spot lights.
+//!
+//! The first lights (directional lights first, then spot lights, up to the layers of the atlas)
+//! each get a layer of a depth texture array, rendered from the light before the shading pass.
+//! Their view projection and layer are uploaded with the lights, and the shading pass filters the
+//! shadows with a comparison sampler (3x3 PCF). The other lights are unshadowed.
+//!
+//! A directional light covers a box around the camera, snapped to its texels so the shadows don't
+//! shimmer when the camera moves. A spot light is a perspective matching its cone.
+
+use glam::{Mat4, Vec3};
+
+use crate::include_shader;
+
+use super::{
+    instances::{GeometryInstance, InstanceGroup},
+    memory::texture_bytes,
+    mesh_manager::{MeshManager, Vertex},
+    pipeline::{Pipeline, RenderPipeline},
+    DiretionalLight, SpotLight,
+};
+
+pub const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+/// Default side of a shadow map
+pub const SHADOW_RESOLUTION: u32 = 1024;
+/// Half side of the box around the camera covered by a directional light
+const DIRECTIONAL_EXTENT: f32 = 30.0;
+/// Casters this far behind the box (towards the light) still cast into it
+const DIRECTIONAL_DEPTH: f32 = 100.0;
+const SPOT_NEAR: f32 = 0.05;
+const SPOT_FAR: f32 = 100.0;
+/// Widest cone a spot light shadow covers, wider cones are cut at the edges
+const SPOT_FOV_MAX: f32 = 2.8;
+
+/// An up vector that isn't parallel to a direction
+fn up(direction: Vec3) -> Vec3 {
+    if direction.y.abs() > 0.99 {
+        Vec3::Z
+    } else {
+        Vec3::Y
+    }
+}
+
+/// View projection of a directional light, covering a box around `center`
+pub fn directional_matrix(direction: Vec3, center: Vec3, resolution: u32) -> Mat4 {
+    let direction = direction.normalize();
+    let rotation = Mat4::look_at_lh(Vec3::ZERO, direction, up(direction));
+    let texel = 2.0 * DIRECTIONAL_EXTENT / resolution as f32;
+    let center = rotation.transform_point3(center);
+    let (x, y) = ((center.x / texel).round() * texel, (center.y / texel).round() * texel);
+    let projection = Mat4::orthographic_lh(
+        x - DIRECTIONAL_EXTENT,
+        x + DIRECTIONAL_EXTENT,
+        y - DIRECTIONAL_EXTENT,
+        y + DIRECTIONAL_EXTENT,
+        center.z - DIRECTIONAL_DEPTH,
+        center.z + DIRECTIONAL_EXTENT,
+    );
+    projection * rotation
+}
+
+/// View projection of a spot light, `cut_off` being the cosine of the half angle of its cone
+pub fn spot_matrix(position: Vec3, direction: Vec3, cut_off: f32) -> Mat4 {
+    let direction = direction.normalize();
+    let fov = (2.0 * cut_off.clamp(-1.0, 1.0).acos()).clamp(0.01, SPOT_FOV_MAX);
+    let view = Mat4::look_at_lh(position, position + direction, up(direction));
+    Mat4::perspective_lh(fov, 1.0, SPOT_NEAR, SPOT_FAR) * view
+}
+
+/// What the shadow maps of a frame cover
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowView {
+    /// Lights that can be shadowed
+    pub layers: u32,
+    pub resolution: u32,
+    /// Center of the box covered by the directional lights
+    pub center: Vec3,
+}
+
+impl ShadowView {
+    /// No light is shadowed
+    pub const NONE: Self = Self {
+        layers: 0,
+        resolution: 1,
+        center: Vec3::ZERO,
+    };
+
+    pub fn directional(&self, light: &DiretionalLight) -> Mat4 {
+        directional_matrix(light.direction, self.center, self.resolution)
+    }
+    pub fn spot(&self, light: &SpotLight) -> Mat4 {
+        spot_matrix(light.position, light.direction, light.cut_off)
+    }
+}
+
+/// Depth texture array, a layer per shadowed light
+pub struct ShadowAtlas {
+    /// A view per layer, rendered to by the shadow pass
+    pub layers: Vec<wgpu::TextureView>,
+    /// All the layers, sampled by the shading pass
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+    pub resolution: u32,
+}
+
+impl ShadowAtlas {
+    pub fn new(device: &wgpu::Device, resolution: u32, layers: u32) -> Self {
+        // Bound even when no light is shadowed
+        let count = layers.max(1);
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Shadow Atlas"),
+            size: wgpu::Extent3d {
+                width: resolution,
+                height: resolution,
+                depth_or_array_layers: count,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: SHADOW_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        let layers = (0..layers)
+            .map(|layer| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("Shadow Map"),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Shadow Atlas View"),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+        Self {
+            layers,
+            view,
+            sampler,
+            resolution,
+        }
+    }
+    /// Bytes taken by an atlas
+    pub fn memory(resolution: u32, layers: u32) -> u64 {
+        texture_bytes(resolution, resolution, SHADOW_FORMAT, 1) * layers.max(1) as u64
+    }
+}
+
+/// Depth only pass rendering the renderables from each shadowed light
+pub struct ShadowPass {
+    pipeline: RenderPipeline,
+    /// Same as the geometry pass, see `instances`
+    instancing: bool,
+}
+
+impl ShadowPass {
+    pub fn new(device: &wgpu::Device, instancing: bool) -> Self {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow Pipeline Layout"),
+            bind_group_layouts: &[],
+            push_constant_ranges: &[wgpu::PushConstantRange {
+                stages: wgpu::ShaderStages::VERTEX,
+                range: 0..64,
+            }],
+        });
+        let pipeline = Pipeline::new(
+            device,
+            layout,
+            include_shader!("shadow.wgsl", "Shadow Shader"),
+            move |device, layout, module| {
+                let instanced = [Vertex::desc(), GeometryInstance::layout()];
+                let single = [Vertex::desc()];
+                let (entry_point, buffers): (_, &[_]) = if instancing {
+                    ("vs_main", &instanced)
+                } else {
+                    ("vs_single", &single)
+                };
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Shadow Pipeline"),
+                    layout: Some(layout),
+                    vertex: wgpu::VertexState {
+                        module,
+                        entry_point,
+                        buffers,
+                    },
+                    fragment: None,
+                    primitive: wgpu::PrimitiveState {
+                        // Open meshes (planes) cast from both sides
+                        cull_mode: None,
+                        ..Default::default()
+                    },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: SHADOW_FORMAT,
+                        depth_write_enabled: true,
+                        depth_compare: wgpu::CompareFunction::Less,
+                        stencil: wgpu::StencilState::default(),
+                        // Against shadow acne
+                        bias: wgpu::DepthBiasState {
+                            constant: 2,
+                            slope_scale: 2.0,
+                            clamp: 0.0,
+                        },
+                    }),
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            },
+        );
+        Self {
+            pipeline,
+            instancing,
+        }
+    }
+
+    /// The pipeline, to reload the shader
+    pub fn pipelines_mut(&mut self) -> [&mut RenderPipeline; 1] {
+        [&mut self.pipeline]
+    }
+
+    /// Render the instances into the layers of the atlas, a view projection per layer. `buffer`
+    /// holds the instances, only when instancing.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        atlas: &ShadowAtlas,
+        matrices: &[Mat4],
+        meshes: &MeshManager,
+        buffer: Option<&wgpu::Buffer>,
+        instances: &[GeometryInstance],
+        groups: &[InstanceGroup],
+    ) {
+        for (view, view_proj) in atlas.layers.iter().zip(matrices) {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_pipeline(&self.pipeline.pipeline);
+            if let Some(buffer) = buffer {
+                render_pass.set_vertex_buffer(1, buffer.slice(..));
+                render_pass.set_push_constants(wgpu::ShaderStages::VERTEX, 0, bytemuck::bytes_of(view_proj));
+            }
+            for group in groups {
+                let mesh = meshes.get(group.mesh).unwrap();
+                render_pass.set_vertex_buffer(0, mesh.vertices.slice(..));
+                render_pass.set_index_buffer(mesh.indices.slice(..), mesh.index_format);
+                if self.instancing {
+                    render_pass.draw_indexed(0..mesh.num_indices, 0, group.instances.clone());
+                } else {
+                    for instance in &instances[group.instances.start as usize..group.instances.end as usize] {
+                        let matrix = *view_proj * instance.model;
+                        render_pass.set_push_constants(wgpu::ShaderStages::VERTEX, 0, bytemuck::bytes_of(&matrix));
+                        render_pass.draw_indexed(0..mesh.num_indices, 0, 0..1);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec4Swizzles;
+
+    use super::*;
+
+    fn project(matrix: Mat4, point: Vec3) -> Vec3 {
+        let clip = matrix * point.extend(1.0);
+        clip.xyz() / clip.w
+    }
+
+    fn inside(ndc: Vec3) -> bool {
+        ndc.x.abs() <= 1.0 && ndc.y.abs() <= 1.0 && (0.0..=1.0).contains(&ndc.z)
+    }
+
+    #[test]
+    fn directional() {
+        let direction = Vec3::new(0.4, -1.0, 0.6);
+        let center = Vec3::new(12.3, 1.0, -4.7);
+        let matrix = directional_matrix(direction, center, 1024);
+        // Centered up to the snapping
+        let texel = 2.0 / 1024.0;
+        let ndc = project(matrix, center);
+        assert!(ndc.x.abs() <= texel && ndc.y.abs() <= texel);
+        assert!(inside(ndc));
+        // Closer to the light is shallower, along the light is the same texel
+        let higher = project(matrix, center - direction * 5.0);
+        assert!(higher.z < ndc.z);
+        assert!((higher.x - ndc.x).abs() < 1e-4 && (higher.y - ndc.y).abs() < 1e-4);
+        assert!(inside(project(matrix, center + Vec3::X * 20.0)));
+        assert!(!inside(project(matrix, center + Vec3::X * 50.0)));
+        // Moving by less than a texel doesn't move the shadows
+        let nudged = directional_matrix(direction, center + Vec3::X * 0.001, 1024);
+        assert_eq!(ndc.x, project(nudged, center).x);
+        // Straight down still has a valid basis
+        assert!(directional_matrix(-Vec3::Y, center, 1024).is_finite());
+    }
+
+    #[test]
+    fn spot() {
+        let position = Vec3::new(0.0, 4.0, -2.0);
+        let direction = Vec3::new(0.0, -1.0, 0.4).normalize();
+        // 30 degrees half angle
+        let matrix = spot_matrix(position, direction, 30f32.to_radians().cos());
+        let ndc = project(matrix, position + direction * 3.0);
+        assert!(ndc.x.abs() < 1e-4 && ndc.y.abs() < 1e-4 && inside(ndc));
+        assert!(project(matrix, position + direction * 6.0).z > ndc.z);
+        let side = Vec3::X;
+        let edge = |angle: f32| {
+            let dir = direction * angle.to_radians().cos() + side * angle.to_radians().sin();
+            project(matrix, position + dir * 3.0)
+        };
+        assert!(inside(edge(29.0)));
+        assert!(!inside(edge(31.0)));
+        assert!(spot_matrix(position, -Vec3::Y, 0.3).is_finite());
+    }
+
+    #[test]
+    fn memory() {
+        assert_eq!(4 * 1024 * 1024 * 4, ShadowAtlas::memory(1024, 4));
+        // An atlas always has a layer
+        assert_eq!(4 * 16 * 16, ShadowAtlas::memory(16, 0));
+    }
+}
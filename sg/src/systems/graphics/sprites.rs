@@ -0,0 +1,659 @@
+//! Textured quads for markers and icons: health bars, waypoints, selection rings.
+//!
+//! World space sprites face the camera and are drawn after the shading, tested against the depth
+//! of the scene unless they shouldn't be hidden by it. Screen space sprites are positioned in
+//! surface pixels and drawn over the scene, under the UI. Sprites are sorted by layer, then back
+//! to front, and consecutive ones sharing a texture are drawn together.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
+
+use ecs::Entity;
+use glam::{Mat4, Vec2, Vec3, Vec4};
+
+use crate::{components::TransformsComponent, include_shader};
+
+use super::{
+    camera::{Camera, Projection},
+    pipeline::RenderPipeline,
+    texture_manager::{TextureHandle, TextureManager},
+};
+
+/// Vertices of a sprite quad (two triangles)
+const QUAD_VERTICES: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpriteSize {
+    /// In world units, the sprite gets smaller with distance
+    World(Vec2),
+    /// In pixels, the sprite keeps the same size on screen whatever its distance
+    Pixels(Vec2),
+}
+
+/// A textured quad at the translation of the entity's TransformsComponent (or of its
+/// WorldAnchorComponent). Screen space sprites take the x and y of the translation as the pixel
+/// they are at, from the top left corner of the surface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpriteComponent {
+    pub texture: TextureHandle,
+    /// `SpriteSize::World` is taken as pixels for screen space sprites
+    pub size: SpriteSize,
+    /// Point of the sprite at its position, from (0, 0) at the bottom left to (1, 1) at the top
+    /// right
+    pub anchor: Vec2,
+    /// Multiplied with the texture, straight alpha
+    pub color: Vec4,
+    pub screen_space: bool,
+    /// Sprites with a greater key are drawn over the others, screen space ones are always over
+    /// world space ones
+    pub sort_key: i32,
+    /// Hide the parts behind the geometry of the scene (world space only)
+    pub clip_behind_geometry: bool,
+}
+
+impl SpriteComponent {
+    /// A centered, world space sprite hidden by the geometry
+    pub fn new(texture: TextureHandle, size: SpriteSize) -> Self {
+        Self {
+            texture,
+            size,
+            anchor: Vec2::splat(0.5),
+            color: Vec4::ONE,
+            screen_space: false,
+            sort_key: 0,
+            clip_behind_geometry: true,
+        }
+    }
+}
+
+/// Place a sprite at another entity's translation, plus an offset. The sprite is hidden while the
+/// entity has no TransformsComponent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldAnchorComponent {
+    pub entity: Entity,
+    pub offset: Vec3,
+}
+
+/// Where a sprite is, None if it is anchored to an entity without a translation
+fn sprite_position(
+    translation: Option<Vec3>,
+    anchor: Option<&WorldAnchorComponent>,
+    targets: &HashMap<Entity, Vec3>,
+) -> Option<Vec3> {
+    match anchor {
+        Some(anchor) => targets
+            .get(&anchor.entity)
+            .map(|target| *target + anchor.offset),
+        None => Some(translation.unwrap_or_default()),
+    }
+}
+
+/// Vertex buffer entry of a sprite
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SpriteInstance {
+    /// World position, or pixel for screen space sprites
+    pub position: Vec3,
+    /// Boolean
+    pub screen_space: u32,
+    /// World units, or pixels for screen space sprites
+    pub size: Vec2,
+    pub anchor: Vec2,
+    pub color: Vec4,
+}
+
+impl SpriteInstance {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 5] = wgpu::vertex_attr_array![
+        0 => Float32x3,
+        1 => Uint32,
+        2 => Float32x2,
+        3 => Float32x2,
+        4 => Float32x4,
+    ];
+
+    fn layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+/// The camera as the sprites need it
+#[derive(Clone, Copy)]
+pub struct SpriteView {
+    pub view_proj: Mat4,
+    pub position: Vec3,
+    pub right: Vec3,
+    pub up: Vec3,
+    pub forward: Vec3,
+    pub projection: Projection,
+    /// Vertical field of view (see `Camera::get_fov`)
+    pub fov: f32,
+    pub near: f32,
+    /// Surface size in pixels
+    pub viewport: Vec2,
+}
+
+impl SpriteView {
+    pub fn new(camera: &Camera, viewport: Vec2) -> Self {
+        let rotation = camera.get_rotation();
+        Self {
+            view_proj: camera.get_view_projection(),
+            position: camera.get_position(),
+            right: rotation * Vec3::X,
+            up: rotation * Vec3::Y,
+            forward: rotation * Vec3::Z,
+            projection: camera.get_projection(),
+            fov: camera.get_fov(),
+            near: camera.get_near(),
+            viewport,
+        }
+    }
+    fn depth(&self, position: Vec3) -> f32 {
+        (position - self.position).dot(self.forward)
+    }
+    /// World size of `pixels` at a distance of `depth` along the view direction, following the
+    /// projections of `Camera`
+    pub fn pixels_to_world(&self, pixels: Vec2, depth: f32) -> Vec2 {
+        let height = match self.projection {
+            Projection::Perspective => 2.0 * depth * (self.fov / 2.0).tan(),
+            Projection::Orthograhic => 2.0 * self.near * self.fov.tan(),
+            Projection::None => 2.0,
+        };
+        pixels * height / self.viewport.y
+    }
+}
+
+/// How a batch is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum SpriteLayer {
+    /// World space, tested against the depth
+    Clipped,
+    /// World space, over the geometry
+    Unclipped,
+    Screen,
+}
+
+/// A sprite ready to be sorted
+#[derive(Debug, Clone, Copy)]
+struct SpriteDraw {
+    texture: TextureHandle,
+    layer: SpriteLayer,
+    sort_key: i32,
+    depth: f32,
+    instance: SpriteInstance,
+}
+
+impl SpriteDraw {
+    /// None for world space sprites behind the camera
+    fn new(sprite: &SpriteComponent, position: Vec3, view: &SpriteView) -> Option<Self> {
+        let (layer, depth, size) = if sprite.screen_space {
+            let (SpriteSize::World(size) | SpriteSize::Pixels(size)) = sprite.size;
+            (SpriteLayer::Screen, 0.0, size)
+        } else {
+            let depth = view.depth(position);
+            if matches!(view.projection, Projection::Perspective) && depth <= 0.0 {
+                return None;
+            }
+            let size = match sprite.size {
+                SpriteSize::World(size) => size,
+                SpriteSize::Pixels(pixels) => view.pixels_to_world(pixels, depth),
+            };
+            let layer = if sprite.clip_behind_geometry {
+                SpriteLayer::Clipped
+            } else {
+                SpriteLayer::Unclipped
+            };
+            (layer, depth, size)
+        };
+        Some(Self {
+            texture: sprite.texture,
+            layer,
+            sort_key: sprite.sort_key,
+            depth,
+            instance: SpriteInstance {
+                position,
+                screen_space: sprite.screen_space as u32,
+                size,
+                anchor: sprite.anchor,
+                color: sprite.color,
+            },
+        })
+    }
+}
+
+/// Consecutive sprites drawn with the same texture and pipeline
+#[derive(Debug, Clone, PartialEq)]
+struct Batch {
+    texture: TextureHandle,
+    layer: SpriteLayer,
+    instances: Range<u32>,
+}
+
+/// Sort the sprites (screen space last, then by sort key, then back to front) and group them in
+/// batches
+fn batch(mut draws: Vec<SpriteDraw>) -> (Vec<SpriteInstance>, Vec<Batch>) {
+    draws.sort_by(|a, b| {
+        let key = |d: &SpriteDraw| (d.layer == SpriteLayer::Screen, d.sort_key);
+        key(a).cmp(&key(b)).then(b.depth.total_cmp(&a.depth))
+    });
+    let mut batches: Vec<Batch> = Vec::new();
+    for (i, draw) in draws.iter().enumerate() {
+        let i = i as u32;
+        match batches.last_mut() {
+            Some(last) if last.texture == draw.texture && last.layer == draw.layer => {
+                last.instances.end = i + 1;
+            }
+            _ => batches.push(Batch {
+                texture: draw.texture,
+                layer: draw.layer,
+                instances: i..i + 1,
+            }),
+        }
+    }
+    let instances = draws.into_iter().map(|draw| draw.instance).collect();
+    (instances, batches)
+}
+
+/// Uniforms of sprites.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct SpriteCamera {
+    view_proj: Mat4,
+    right: Vec4,
+    up: Vec4,
+    viewport: Vec4,
+}
+
+pub struct SpriteRenderer {
+    /// Sprites of the frame and where they are, see `update`
+    sprites: Vec<(SpriteComponent, Vec3)>,
+    clipped: RenderPipeline,
+    unclipped: RenderPipeline,
+    screen: RenderPipeline,
+    camera: wgpu::Buffer,
+    camera_bind_group: wgpu::BindGroup,
+    texture_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    /// Instance buffer and its size
+    instances: Option<(wgpu::Buffer, u64)>,
+    /// Sprites drawn last frame
+    pub drawn: usize,
+}
+
+impl SpriteRenderer {
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+        let camera_layout = create_bind_group_layout!(device, "Sprites Camera Bindgroup Layout": {
+            0 => VERTEX | Buffer(type: Uniform),
+        });
+        let texture_layout = create_bind_group_layout!(device, "Sprites Texture Bindgroup Layout": {
+            0 => FRAGMENT | Texture(sample: FloatFilterable, view_dim: D2),
+            1 => FRAGMENT | Sampler(Filtering),
+        });
+        let pipeline = |label: &'static str, depth_compare| {
+            RenderPipeline::new(
+                device,
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Sprites Pipeline Layout"),
+                    bind_group_layouts: &[&camera_layout, &texture_layout],
+                    push_constant_ranges: &[],
+                }),
+                include_shader!("sprites.wgsl", "Sprites Shader"),
+                move |device, layout, module| {
+                    create_pipeline(device, label, layout, module, format, depth_compare)
+                },
+            )
+        };
+        let camera = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Sprites Camera"),
+            size: std::mem::size_of::<SpriteCamera>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let camera_bind_group = create_bind_group!(device, &camera_layout, "Sprites Camera Bindgroup": {
+            0 | Buffer(buffer: (&camera)),
+        });
+        Self {
+            sprites: Vec::new(),
+            clipped: pipeline("Sprites Clipped Pipeline", wgpu::CompareFunction::Less),
+            unclipped: pipeline("Sprites Unclipped Pipeline", wgpu::CompareFunction::Always),
+            screen: pipeline("Sprites Screen Pipeline", wgpu::CompareFunction::Always),
+            camera,
+            camera_bind_group,
+            texture_layout,
+            sampler: device.create_sampler(&wgpu::SamplerDescriptor {
+                label: Some("Sprites Sampler"),
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            }),
+            instances: None,
+            drawn: 0,
+        }
+    }
+    /// Gather the sprites of the frame. `transforms` is only read for the entities sprites are
+    /// anchored to.
+    pub fn update<'a>(
+        &mut self,
+        sprites: impl IntoIterator<
+            Item = (
+                &'a SpriteComponent,
+                Option<&'a TransformsComponent>,
+                Option<&'a WorldAnchorComponent>,
+            ),
+        >,
+        transforms: impl IntoIterator<Item = (Entity, &'a TransformsComponent)>,
+    ) {
+        let sprites = sprites.into_iter().collect::<Vec<_>>();
+        let mut targets = HashMap::new();
+        if sprites.iter().any(|(_, _, anchor)| anchor.is_some()) {
+            let wanted = sprites
+                .iter()
+                .filter_map(|(_, _, anchor)| Some(anchor.as_ref()?.entity))
+                .collect::<HashSet<_>>();
+            targets.extend(
+                transforms
+                    .into_iter()
+                    .filter(|(entity, _)| wanted.contains(entity))
+                    .map(|(entity, tsm)| (entity, tsm.translation())),
+            );
+        }
+        self.sprites.clear();
+        for (sprite, tsm, anchor) in sprites {
+            if let Some(position) =
+                sprite_position(tsm.map(TransformsComponent::translation), anchor, &targets)
+            {
+                self.sprites.push((*sprite, position));
+            }
+        }
+    }
+    /// Draw the sprites over `view`, after the shading of the scene
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        textures: &TextureManager,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &SpriteView,
+        target: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+    ) {
+        let draws = self
+            .sprites
+            .iter()
+            .filter_map(|(sprite, position)| SpriteDraw::new(sprite, *position, view))
+            .collect::<Vec<_>>();
+        self.drawn = draws.len();
+        if draws.is_empty() {
+            return;
+        }
+        let (instances, batches) = batch(draws);
+
+        let size = std::mem::size_of_val(instances.as_slice()) as u64;
+        if self
+            .instances
+            .as_ref()
+            .map_or(true, |(_, capacity)| *capacity < size)
+        {
+            let capacity = size.next_power_of_two();
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Sprites Instances"),
+                size: capacity,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            self.instances = Some((buffer, capacity));
+        }
+        let (buffer, _) = self.instances.as_ref().unwrap();
+        queue.write_buffer(buffer, 0, bytemuck::cast_slice(&instances));
+        let camera = SpriteCamera {
+            view_proj: view.view_proj,
+            right: view.right.extend(0.0),
+            up: view.up.extend(0.0),
+            viewport: view.viewport.extend(0.0).extend(0.0),
+        };
+        queue.write_buffer(&self.camera, 0, bytemuck::bytes_of(&camera));
+
+        // Textures can be replaced at any time, so the bind groups don't outlive the frame
+        let mut bind_groups = HashMap::new();
+        for batch in &batches {
+            if bind_groups.contains_key(&batch.texture) {
+                continue;
+            }
+            match textures.get_view(batch.texture) {
+                Some(texture) => {
+                    let bind_group = create_bind_group!(device, &self.texture_layout, "Sprites Texture Bindgroup": {
+                        0 | TextureView(texture),
+                        1 | Sampler(&self.sampler),
+                    });
+                    bind_groups.insert(batch.texture, bind_group);
+                }
+                None => log::warn!("Sprite with an unknown texture"),
+            }
+        }
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Sprites Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        pass.set_vertex_buffer(0, buffer.slice(..));
+        pass.set_bind_group(0, &self.camera_bind_group, &[]);
+        for batch in batches {
+            let Some(bind_group) = bind_groups.get(&batch.texture) else {
+                continue;
+            };
+            let pipeline = match batch.layer {
+                SpriteLayer::Clipped => &self.clipped,
+                SpriteLayer::Unclipped => &self.unclipped,
+                SpriteLayer::Screen => &self.screen,
+            };
+            pass.set_pipeline(&pipeline.pipeline);
+            pass.set_bind_group(1, bind_group, &[]);
+            pass.draw(0..QUAD_VERTICES, batch.instances);
+        }
+    }
+}
+
+/// Premultiplied alpha blended quads that don't write the depth
+fn create_pipeline(
+    device: &wgpu::Device,
+    label: &'static str,
+    layout: &wgpu::PipelineLayout,
+    module: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    depth_compare: wgpu::CompareFunction,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module,
+            entry_point: "vs_main",
+            buffers: &[SpriteInstance::layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            cull_mode: None,
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: TextureManager::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Quat;
+    use slotmap::SlotMap;
+
+    use super::*;
+
+    fn handles<const N: usize>() -> [TextureHandle; N] {
+        let mut keys = SlotMap::<TextureHandle, ()>::with_key();
+        [(); N].map(|_| keys.insert(()))
+    }
+
+    /// A perspective camera at the origin looking down +Z (with a quarter turn to check the
+    /// basis), over a 800x600 surface
+    fn view(projection: Projection) -> SpriteView {
+        let (fov, near) = (1.0, 0.1);
+        let rotation = Quat::from_rotation_z(0.5);
+        let proj = match projection {
+            Projection::Perspective => Mat4::perspective_lh(fov, 800.0 / 600.0, near, 100.0),
+            _ => Mat4::IDENTITY,
+        };
+        SpriteView {
+            view_proj: proj * Mat4::from_quat(rotation.inverse()),
+            position: Vec3::ZERO,
+            right: rotation * Vec3::X,
+            up: rotation * Vec3::Y,
+            forward: rotation * Vec3::Z,
+            projection,
+            fov,
+            near,
+            viewport: Vec2::new(800.0, 600.0),
+        }
+    }
+
+    #[test]
+    fn instance_layout() {
+        let offsets = [
+            std::mem::offset_of!(SpriteInstance, position),
+            std::mem::offset_of!(SpriteInstance, screen_space),
+            std::mem::offset_of!(SpriteInstance, size),
+            std::mem::offset_of!(SpriteInstance, anchor),
+            std::mem::offset_of!(SpriteInstance, color),
+        ];
+        for (attribute, offset) in SpriteInstance::ATTRIBUTES.iter().zip(offsets) {
+            assert_eq!(offset as u64, attribute.offset);
+        }
+        assert_eq!(48, std::mem::size_of::<SpriteInstance>());
+    }
+
+    #[test]
+    fn pixel_size() {
+        let view = view(Projection::Perspective);
+        for depth in [0.5, 4.0, 30.0] {
+            let center = view.forward * depth;
+            let size = view.pixels_to_world(Vec2::new(32.0, 16.0), depth);
+            // Corners of the billboard, projected to pixels
+            let pixel = |offset: Vec2| {
+                let world = center + view.right * offset.x + view.up * offset.y;
+                let clip = view.view_proj * world.extend(1.0);
+                clip.truncate().truncate() / clip.w * view.viewport / 2.0
+            };
+            let projected = pixel(size / 2.0) - pixel(-size / 2.0);
+            assert!((projected - Vec2::new(32.0, 16.0)).abs().max_element() < 1e-3);
+        }
+        // Without projection the surface spans [-1, 1]
+        let view = super::tests::view(Projection::None);
+        assert_eq!(
+            Vec2::new(2.0, 1.0),
+            view.pixels_to_world(Vec2::new(600.0, 300.0), 10.0)
+        );
+    }
+
+    #[test]
+    fn anchors() {
+        let mut entities = SlotMap::<Entity, ()>::with_key();
+        let [target, gone] = [(); 2].map(|_| entities.insert(()));
+        let targets = HashMap::from([(target, Vec3::new(1.0, 2.0, 3.0))]);
+        let anchor = |entity| WorldAnchorComponent {
+            entity,
+            offset: -Vec3::Y,
+        };
+        assert_eq!(
+            Some(Vec3::X),
+            sprite_position(Some(Vec3::X), None, &targets)
+        );
+        assert_eq!(
+            Some(Vec3::new(1.0, 1.0, 3.0)),
+            sprite_position(Some(Vec3::X), Some(&anchor(target)), &targets)
+        );
+        assert_eq!(None, sprite_position(None, Some(&anchor(gone)), &targets));
+    }
+
+    #[test]
+    fn sorting_and_batches() {
+        let [a, b] = handles();
+        let view = view(Projection::Perspective);
+        let sprite = |texture, depth: f32, sort_key, screen_space, clip| {
+            let sprite = SpriteComponent {
+                sort_key,
+                screen_space,
+                clip_behind_geometry: clip,
+                ..SpriteComponent::new(texture, SpriteSize::Pixels(Vec2::ONE))
+            };
+            SpriteDraw::new(&sprite, view.forward * depth, &view)
+        };
+        // Behind the camera
+        assert!(sprite(a, -1.0, 0, false, true).is_none());
+        let draws = [
+            sprite(b, 0.0, 0, true, false),
+            sprite(a, 5.0, 0, false, true),
+            sprite(a, 1.0, 1, false, true),
+            sprite(a, 2.0, 0, false, true),
+            sprite(b, 20.0, 0, false, true),
+            sprite(a, 10.0, 0, false, false),
+            sprite(a, 0.0, 0, true, false),
+        ];
+        let (instances, batches) = batch(draws.into_iter().map(Option::unwrap).collect());
+        let depths = instances
+            .iter()
+            .map(|instance| view.depth(instance.position).round())
+            .collect::<Vec<_>>();
+        // Back to front in the world, the greater sort key over the rest, then the screen
+        assert_eq!(vec![20.0, 10.0, 5.0, 2.0, 1.0, 0.0, 0.0], depths);
+        let batch = |texture, layer, instances| Batch {
+            texture,
+            layer,
+            instances,
+        };
+        assert_eq!(
+            vec![
+                batch(b, SpriteLayer::Clipped, 0..1),
+                batch(a, SpriteLayer::Unclipped, 1..2),
+                batch(a, SpriteLayer::Clipped, 2..5),
+                batch(b, SpriteLayer::Screen, 5..6),
+                batch(a, SpriteLayer::Screen, 6..7),
+            ],
+            batches
+        );
+    }
+}
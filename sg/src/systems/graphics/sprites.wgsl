@@ -0,0 +1,59 @@
+// Textured quads, camera facing in the world or in surface pixels. Colors are premultiplied here,
+// the blending expects premultiplied alpha.
+struct Camera {
+    view_proj: mat4x4<f32>,
+    right: vec4<f32>,
+    up: vec4<f32>,
+    // Size of the surface in pixels in xy
+    viewport: vec4<f32>,
+}
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+@group(1) @binding(0)
+var texture: texture_2d<f32>;
+@group(1) @binding(1)
+var texture_sampler: sampler;
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex: u32,
+    @location(0) position: vec3<f32>,
+    @location(1) screen_space: u32,
+    @location(2) size: vec2<f32>,
+    @location(3) anchor: vec2<f32>,
+    @location(4) color: vec4<f32>,
+) -> VertexOutput {
+    // Two triangles: (0, 0) (1, 0) (0, 1), (0, 1) (1, 0) (1, 1)
+    var corner = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0), vec2<f32>(1.0, 0.0), vec2<f32>(0.0, 1.0),
+        vec2<f32>(0.0, 1.0), vec2<f32>(1.0, 0.0), vec2<f32>(1.0, 1.0),
+    );
+    let uv = corner[vertex];
+    let offset = (uv - anchor) * size;
+    var out: VertexOutput;
+    if (screen_space != 0u) {
+        // Pixels go down from the top left corner, the sprite's up goes up
+        let pixel = position.xy + vec2<f32>(offset.x, -offset.y);
+        let ndc = pixel / camera.viewport.xy * 2.0 - 1.0;
+        out.clip_position = vec4<f32>(ndc.x, -ndc.y, 0.0, 1.0);
+    } else {
+        let world = position + camera.right.xyz * offset.x + camera.up.xyz * offset.y;
+        out.clip_position = camera.view_proj * vec4<f32>(world, 1.0);
+    }
+    // The first row of a texture is its top
+    out.uv = vec2<f32>(uv.x, 1.0 - uv.y);
+    out.color = color;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(texture, texture_sampler, in.uv) * in.color;
+    return vec4<f32>(color.rgb * color.a, color.a);
+}
@@ -0,0 +1,701 @@
+//! Screen-space reflections.
+//!
+//! A half resolution pass reflects the view ray of every smooth enough pixel (roughness from the
+//! MRA g-buffer) and marches it through the depth buffer: linear steps along the ray projected on
+//! the screen (`screen_ray`), with its linear depth interpolated in a perspective correct way
+//! (`ScreenRay::depth`), then a binary search between the last two steps once it goes behind the
+//! scene. Hits on backfaces, rays leaving the screen and rays longer than `max_distance` are
+//! rejected. The color comes from the last frame (reprojected), which the shading pass writes
+//! before fog and tonemapping. The result (color and confidence) is blurred according to the
+//! roughness (`blur_radius`), and the shading pass blends it over the environment map.
+//!
+//! The depth pyramid (see `hiz`) keeps the farthest depth for culling, not the closest, so it
+//! can't be used to skip empty space. There is no TAA either, the noise of the jittered steps is
+//! only hidden by the blur.
+
+use glam::{Mat4, Vec2, Vec3};
+
+use crate::console::{unknown_field, Arg, Inspect};
+use crate::include_shader;
+use crate::localization::Localization;
+use crate::tr;
+
+use super::{
+    camera::Camera,
+    focus::UiFocus,
+    g_buffer::GBuffer,
+    memory::texture_bytes,
+    pipeline::{Pipeline, RenderPipeline},
+    timer::GpuTimer,
+};
+
+/// Format of the half resolution reflection targets
+pub const SSR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+/// Format of the lit scene, before fog and tonemapping, kept for the next frame's reflections
+pub const HISTORY_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+/// Blur radius at `max_roughness`, in half resolution texels
+pub const MAX_BLUR_RADIUS: f32 = 6.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsrQuality {
+    Low,
+    Medium,
+    High,
+}
+
+impl SsrQuality {
+    pub const ALL: [SsrQuality; 3] = [Self::Low, Self::Medium, Self::High];
+
+    /// Linear steps along the ray, and binary search steps once a hit is found
+    pub fn steps(self) -> (u32, u32) {
+        match self {
+            Self::Low => (16, 4),
+            Self::Medium => (32, 6),
+            Self::High => (64, 8),
+        }
+    }
+    fn key(self) -> &'static str {
+        match self {
+            Self::Low => "ssr.quality.low",
+            Self::Medium => "ssr.quality.medium",
+            Self::High => "ssr.quality.high",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SsrSettings {
+    pub enabled: bool,
+    pub quality: SsrQuality,
+    /// Rougher pixels only reflect the environment map
+    pub max_roughness: f32,
+    /// How far behind the depth buffer a ray can be and still hit it, in view space units
+    pub thickness: f32,
+    /// Length of the rays, in world units
+    pub max_distance: f32,
+    /// Width of the fade near the screen borders, in uv
+    pub edge_fade: f32,
+}
+
+impl Default for SsrSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            quality: SsrQuality::Medium,
+            max_roughness: 0.4,
+            thickness: 0.3,
+            max_distance: 15.0,
+            edge_fade: 0.1,
+        }
+    }
+}
+
+impl Inspect for SsrSettings {
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let quality = format!("{:?}", self.quality).to_lowercase();
+        vec![
+            ("enabled", self.enabled.to_string()),
+            ("quality", quality),
+            ("max_roughness", self.max_roughness.to_string()),
+            ("thickness", self.thickness.to_string()),
+            ("max_distance", self.max_distance.to_string()),
+            ("edge_fade", self.edge_fade.to_string()),
+        ]
+    }
+    fn set_field(&mut self, field: &str, value: &Arg) -> anyhow::Result<()> {
+        match field {
+            "enabled" => self.enabled = value.as_bool()?,
+            "quality" => {
+                self.quality = match value.to_string().as_str() {
+                    "low" => SsrQuality::Low,
+                    "medium" => SsrQuality::Medium,
+                    "high" => SsrQuality::High,
+                    other => anyhow::bail!("expected low, medium or high, got `{other}`"),
+                }
+            }
+            "max_roughness" => self.max_roughness = value.as_f32()?.clamp(0.0, 1.0),
+            "thickness" => self.thickness = value.as_f32()?.max(0.0),
+            "max_distance" => self.max_distance = value.as_f32()?.max(0.0),
+            "edge_fade" => self.edge_fade = value.as_f32()?.clamp(0.0, 0.5),
+            _ => return Err(unknown_field(self, field)),
+        }
+        Ok(())
+    }
+}
+
+/// A ray projected on the screen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScreenRay {
+    /// Uv (y down) and NDC depth of the start of the ray
+    pub start: Vec3,
+    pub end: Vec3,
+    /// 1/w of both ends, which (unlike w) is linear in screen space
+    pub inv_w: [f32; 2],
+}
+
+impl ScreenRay {
+    /// Uv and NDC depth at `t` along the ray on the screen
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.start.lerp(self.end, t)
+    }
+    /// Linear (view space) depth at `t` along the ray on the screen
+    pub fn depth(&self, t: f32) -> f32 {
+        1.0 / (self.inv_w[0] + (self.inv_w[1] - self.inv_w[0]) * t)
+    }
+}
+
+/// Project the ray from `origin` along `dir` (normalized) up to `length` on the screen. The end
+/// is pulled back to the near plane if the ray goes towards the camera, None if the origin is
+/// behind it.
+pub fn screen_ray(
+    view_proj: Mat4,
+    origin: Vec3,
+    dir: Vec3,
+    length: f32,
+    near: f32,
+) -> Option<ScreenRay> {
+    let start = view_proj * origin.extend(1.0);
+    let mut end = view_proj * (origin + dir * length).extend(1.0);
+    if start.w < near {
+        return None;
+    }
+    if end.w < near {
+        end = start.lerp(end, (start.w - near) / (start.w - end.w));
+    }
+    let screen = |p: glam::Vec4| Vec3::new(p.x / p.w * 0.5 + 0.5, 0.5 - p.y / p.w * 0.5, p.z / p.w);
+    Some(ScreenRay {
+        start: screen(start),
+        end: screen(end),
+        inv_w: [1.0 / start.w, 1.0 / end.w],
+    })
+}
+
+/// Linear depth of a depth buffer value (mirrors `linear_depth` in the shaders)
+pub fn linear_depth(depth: f32, near: f32, far: f32) -> f32 {
+    near * far / (far - depth * (far - near))
+}
+
+/// Confidence of a hit at `uv`, fading to 0 over `margin` near the screen borders
+pub fn edge_fade(uv: Vec2, margin: f32) -> f32 {
+    let border = uv.min(Vec2::ONE - uv).min_element();
+    (border / margin.max(1e-4)).clamp(0.0, 1.0)
+}
+
+/// Blur radius of the reflections, in half resolution texels. Follows the width of the specular
+/// lobe (roughness squared), from sharp at 0 to `MAX_BLUR_RADIUS` at `max_roughness`.
+pub fn blur_radius(roughness: f32, max_roughness: f32) -> f32 {
+    let alpha = roughness.clamp(0.0, max_roughness) / max_roughness.max(1e-4);
+    MAX_BLUR_RADIUS * alpha * alpha
+}
+
+/// Weight of the reflections over the last quarter of the roughness range, so they don't stop
+/// abruptly at `max_roughness`
+pub fn roughness_fade(roughness: f32, max_roughness: f32) -> f32 {
+    ((max_roughness - roughness) / (max_roughness * 0.25).max(1e-4)).clamp(0.0, 1.0)
+}
+
+// Mirrors SsrParams in ssr.wgsl, ssr_blur.wgsl and shader.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct SsrParams {
+    view_proj: Mat4,
+    prev_view_proj: Mat4,
+    cam_pos: Vec3,
+    near: f32,
+    far: f32,
+    thickness: f32,
+    max_distance: f32,
+    max_roughness: f32,
+    edge_fade: f32,
+    steps: u32,
+    refine: u32,
+    frame: u32,
+    /// If the history has the last frame
+    history: u32,
+    max_blur: f32,
+    padding: [u32; 2],
+}
+
+pub struct ScreenSpaceReflections {
+    pub settings: SsrSettings,
+    /// GPU time of the last measured trace and blur, in milliseconds
+    gpu_time: Option<f32>,
+    timer: Option<GpuTimer>,
+    frame: u32,
+    /// View projection of the frame in the history
+    prev_view_proj: Mat4,
+    history_valid: bool,
+    trace_pipeline: RenderPipeline,
+    blur_pipeline: RenderPipeline,
+    params: wgpu::Buffer,
+    history: wgpu::TextureView,
+    trace: wgpu::TextureView,
+    blurred: wgpu::TextureView,
+    trace_bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
+    /// Layout of the bind group the shading pass reads the reflections from
+    pub composite_layout: wgpu::BindGroupLayout,
+    pub composite: wgpu::BindGroup,
+}
+
+impl ScreenSpaceReflections {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        g_buffer: &GBuffer,
+        size: (u32, u32),
+    ) -> Self {
+        let trace_layout = create_bind_group_layout!(device, "SSR Bindgroup Layout": {
+            0 => FRAGMENT | Buffer(type: Uniform),
+            1 => FRAGMENT | Sampler(Filtering),
+            2 => FRAGMENT | Texture(sample: Depth, view_dim: D2),
+            3 => FRAGMENT | Texture(sample: FloatFilterable, view_dim: D2),
+            4 => FRAGMENT | Texture(sample: FloatFilterable, view_dim: D2),
+            5 => FRAGMENT | Texture(sample: FloatFilterable, view_dim: D2),
+            6 => FRAGMENT | Texture(sample: FloatFilterable, view_dim: D2),
+        });
+        let blur_layout = create_bind_group_layout!(device, "SSR Blur Bindgroup Layout": {
+            0 => FRAGMENT | Buffer(type: Uniform),
+            1 => FRAGMENT | Texture(sample: Float, view_dim: D2),
+            2 => FRAGMENT | Texture(sample: Depth, view_dim: D2),
+            3 => FRAGMENT | Texture(sample: FloatFilterable, view_dim: D2),
+        });
+        let composite_layout = create_bind_group_layout!(device, "SSR Composite Bindgroup Layout": {
+            0 => FRAGMENT | Texture(sample: FloatFilterable, view_dim: D2),
+            1 => FRAGMENT | Buffer(type: Uniform),
+        });
+        let pipeline = |layout: &wgpu::BindGroupLayout, shader, label: &'static str| {
+            Pipeline::new(
+                device,
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some(label),
+                    bind_group_layouts: &[layout],
+                    push_constant_ranges: &[],
+                }),
+                shader,
+                move |device, layout, module| {
+                    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                        label: Some(label),
+                        layout: Some(layout),
+                        vertex: wgpu::VertexState {
+                            module,
+                            entry_point: "vs_main",
+                            buffers: &[],
+                        },
+                        fragment: Some(wgpu::FragmentState {
+                            module,
+                            entry_point: "fs_main",
+                            targets: &[Some(wgpu::ColorTargetState {
+                                format: SSR_FORMAT,
+                                blend: None,
+                                write_mask: wgpu::ColorWrites::ALL,
+                            })],
+                        }),
+                        primitive: wgpu::PrimitiveState::default(),
+                        depth_stencil: None,
+                        multisample: wgpu::MultisampleState::default(),
+                        multiview: None,
+                    })
+                },
+            )
+        };
+        let trace_pipeline = pipeline(
+            &trace_layout,
+            include_shader!("ssr.wgsl", "SSR Shader"),
+            "SSR Pipeline",
+        );
+        let blur_pipeline = pipeline(
+            &blur_layout,
+            include_shader!("ssr_blur.wgsl", "SSR Blur Shader"),
+            "SSR Blur Pipeline",
+        );
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SSR Params"),
+            size: std::mem::size_of::<SsrParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let [history, trace, blurred] = Self::make_targets(device, size);
+        let (trace_bind_group, blur_bind_group) = Self::make_bind_groups(
+            device,
+            (&trace_layout, &blur_layout),
+            &params,
+            g_buffer,
+            &history,
+            &trace,
+        );
+        let composite = Self::make_composite(device, &composite_layout, &blurred, &params);
+        Self {
+            settings: SsrSettings::default(),
+            gpu_time: None,
+            timer: GpuTimer::new(device, queue, "SSR"),
+            frame: 0,
+            prev_view_proj: Mat4::IDENTITY,
+            history_valid: false,
+            trace_pipeline,
+            blur_pipeline,
+            params,
+            history,
+            trace,
+            blurred,
+            trace_bind_group,
+            blur_bind_group,
+            composite_layout,
+            composite,
+        }
+    }
+    fn half_size(size: (u32, u32)) -> (u32, u32) {
+        (size.0.div_ceil(2).max(1), size.1.div_ceil(2).max(1))
+    }
+    /// Bytes taken by the targets at a size
+    pub fn memory(size: (u32, u32)) -> u64 {
+        let half = Self::half_size(size);
+        texture_bytes(size.0, size.1, HISTORY_FORMAT, 1)
+            + 2 * texture_bytes(half.0, half.1, SSR_FORMAT, 1)
+    }
+    /// The history (full resolution), and the trace and blur targets (half resolution)
+    fn make_targets(device: &wgpu::Device, size: (u32, u32)) -> [wgpu::TextureView; 3] {
+        let target = |label, (width, height), format| {
+            device
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: Some(label),
+                    size: wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                        | wgpu::TextureUsages::TEXTURE_BINDING,
+                })
+                .create_view(&Default::default())
+        };
+        let half = Self::half_size(size);
+        [
+            target("SSR History", size, HISTORY_FORMAT),
+            target("SSR Trace", half, SSR_FORMAT),
+            target("SSR Blurred", half, SSR_FORMAT),
+        ]
+    }
+    fn make_bind_groups(
+        device: &wgpu::Device,
+        (trace_layout, blur_layout): (&wgpu::BindGroupLayout, &wgpu::BindGroupLayout),
+        params: &wgpu::Buffer,
+        g_buffer: &GBuffer,
+        history: &wgpu::TextureView,
+        trace: &wgpu::TextureView,
+    ) -> (wgpu::BindGroup, wgpu::BindGroup) {
+        let trace_bind_group = create_bind_group!(device, trace_layout, "SSR Bindgroup": {
+            0 | Buffer(buffer: params),
+            1 | Sampler(&g_buffer.sampler),
+            2 | TextureView(&g_buffer.depth_tex),
+            3 | TextureView(g_buffer.view("position")),
+            4 | TextureView(g_buffer.view("normal")),
+            5 | TextureView(g_buffer.view("mra")),
+            6 | TextureView(history),
+        });
+        let blur_bind_group = create_bind_group!(device, blur_layout, "SSR Blur Bindgroup": {
+            0 | Buffer(buffer: params),
+            1 | TextureView(trace),
+            2 | TextureView(&g_buffer.depth_tex),
+            3 | TextureView(g_buffer.view("mra")),
+        });
+        (trace_bind_group, blur_bind_group)
+    }
+    fn make_composite(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        blurred: &wgpu::TextureView,
+        params: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        create_bind_group!(device, layout, "SSR Composite Bindgroup": {
+            0 | TextureView(blurred),
+            1 | Buffer(buffer: params),
+        })
+    }
+    /// The trace and blur pipelines, to reload their shaders
+    pub fn pipelines_mut(&mut self) -> [&mut RenderPipeline; 2] {
+        [&mut self.trace_pipeline, &mut self.blur_pipeline]
+    }
+    /// Recreate the targets, `g_buffer` having been resized
+    pub fn resize(&mut self, device: &wgpu::Device, g_buffer: &GBuffer, size: (u32, u32)) {
+        let [history, trace, blurred] = Self::make_targets(device, size);
+        let (trace_bind_group, blur_bind_group) = Self::make_bind_groups(
+            device,
+            (
+                &self.trace_pipeline.pipeline.get_bind_group_layout(0),
+                &self.blur_pipeline.pipeline.get_bind_group_layout(0),
+            ),
+            &self.params,
+            g_buffer,
+            &history,
+            &trace,
+        );
+        self.composite =
+            Self::make_composite(device, &self.composite_layout, &blurred, &self.params);
+        self.trace_bind_group = trace_bind_group;
+        self.blur_bind_group = blur_bind_group;
+        self.history = history;
+        self.trace = trace;
+        self.blurred = blurred;
+        self.history_valid = false;
+    }
+    /// Where the shading pass writes the lit scene, if the reflections are enabled
+    pub fn history(&self) -> Option<&wgpu::TextureView> {
+        self.settings.enabled.then_some(&self.history)
+    }
+    /// GPU time of the trace and blur, if the device has timestamp queries
+    pub fn gpu_time(&self) -> Option<f32> {
+        self.gpu_time
+    }
+    /// Record the trace and the blur, does nothing if the reflections are disabled
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        camera: &Camera,
+    ) {
+        if let Some(time) = self.timer.as_mut().and_then(|timer| timer.poll(device)) {
+            self.gpu_time = Some(time);
+        }
+        if !self.settings.enabled {
+            // The shading pass stops writing it
+            self.history_valid = false;
+            return;
+        }
+        self.frame = self.frame.wrapping_add(1);
+
+        let s = &self.settings;
+        let (steps, refine) = s.quality.steps();
+        let view_proj = camera.get_view_projection();
+        let params = SsrParams {
+            view_proj,
+            prev_view_proj: self.prev_view_proj,
+            cam_pos: camera.get_position(),
+            near: camera.get_near(),
+            far: camera.get_far(),
+            thickness: s.thickness,
+            max_distance: s.max_distance,
+            max_roughness: s.max_roughness.max(1e-3),
+            edge_fade: s.edge_fade,
+            steps,
+            refine,
+            frame: self.frame,
+            history: self.history_valid as u32,
+            max_blur: MAX_BLUR_RADIUS,
+            padding: [0; 2],
+        };
+        queue.write_buffer(&self.params, 0, bytemuck::bytes_of(&params));
+        // Written by this frame's shading pass, for the next one
+        self.prev_view_proj = view_proj;
+        self.history_valid = true;
+
+        let timer = self.timer.as_mut().filter(|timer| timer.ready());
+        if let Some(timer) = &timer {
+            timer.begin(encoder);
+        }
+        for (label, pipeline, bind_group, target) in [
+            (
+                "SSR Pass",
+                &self.trace_pipeline,
+                &self.trace_bind_group,
+                &self.trace,
+            ),
+            (
+                "SSR Blur Pass",
+                &self.blur_pipeline,
+                &self.blur_bind_group,
+                &self.blurred,
+            ),
+        ] {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(label),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(&pipeline.pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        if let Some(timer) = timer {
+            timer.end(encoder);
+        }
+    }
+    /// Must be called once the frame's commands have been submitted
+    pub fn after_submit(&mut self) {
+        if let Some(timer) = &mut self.timer {
+            timer.after_submit();
+        }
+    }
+
+    /// Draw the settings window
+    pub(super) fn ui(&mut self, ctx: &egui::Context, focus: &mut UiFocus, loc: &mut Localization) {
+        let s = &mut self.settings;
+        focus.begin_panel("ssr");
+        egui::Window::new(tr!(loc, "ssr.window")).show(ctx, |ui| {
+            focus.track(ui.checkbox(&mut s.enabled, tr!(loc, "ssr.enabled")));
+            ui.horizontal(|ui| {
+                ui.label(tr!(loc, "ssr.quality"));
+                for quality in SsrQuality::ALL {
+                    focus.track(ui.radio_value(&mut s.quality, quality, loc.get(quality.key())));
+                }
+            });
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.max_roughness, 0.05..=1.0)
+                        .text(tr!(loc, "ssr.max_roughness")),
+                ),
+            );
+            focus.track(ui.add(
+                egui::Slider::new(&mut s.thickness, 0.01..=2.0).text(tr!(loc, "ssr.thickness")),
+            ));
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.max_distance, 1.0..=100.0)
+                        .text(tr!(loc, "ssr.max_distance")),
+                ),
+            );
+            if let Some(time) = self.gpu_time {
+                ui.label(tr!(loc, "ssr.gpu_time", time = format!("{time:.2}")));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec4Swizzles;
+
+    use super::*;
+
+    const NEAR: f32 = 0.1;
+    const FAR: f32 = 100.0;
+
+    fn view_proj() -> Mat4 {
+        let view = Mat4::look_at_lh(Vec3::new(0.0, 2.0, -5.0), Vec3::ZERO, Vec3::Y);
+        Mat4::perspective_lh(1.0, 16.0 / 9.0, NEAR, FAR) * view
+    }
+
+    /// Uv, NDC depth and linear depth of a point
+    fn project(view_proj: Mat4, p: Vec3) -> (Vec2, f32, f32) {
+        let clip = view_proj * p.extend(1.0);
+        let ndc = clip.xyz() / clip.w;
+        (
+            Vec2::new(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5),
+            ndc.z,
+            clip.w,
+        )
+    }
+
+    #[test]
+    fn ray_endpoints() {
+        let vp = view_proj();
+        let (origin, dir) = (
+            Vec3::new(-1.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.5, 0.5).normalize(),
+        );
+        let ray = screen_ray(vp, origin, dir, 4.0, NEAR).unwrap();
+        let (uv, z, w) = project(vp, origin);
+        assert!((ray.start.truncate() - uv).length() < 1e-5);
+        assert!((ray.start.z - z).abs() < 1e-5);
+        assert!((ray.depth(0.0) - w).abs() < 1e-4);
+        let (uv, z, w) = project(vp, origin + dir * 4.0);
+        assert!((ray.end.truncate() - uv).length() < 1e-5);
+        assert!((ray.end.z - z).abs() < 1e-5);
+        assert!((ray.depth(1.0) - w).abs() < 1e-4);
+        // The depth buffer value is consistent with the linear depth
+        assert!((linear_depth(ray.end.z, NEAR, FAR) - w).abs() < 1e-3);
+
+        // Behind the camera
+        assert_eq!(
+            None,
+            screen_ray(vp, Vec3::new(0.0, 2.0, -6.0), dir, 4.0, NEAR)
+        );
+    }
+
+    #[test]
+    fn ray_towards_camera() {
+        let vp = view_proj();
+        let origin = Vec3::ZERO;
+        // Straight at the camera and past it
+        let dir = (Vec3::new(0.0, 2.0, -5.0) - origin).normalize();
+        let ray = screen_ray(vp, origin, dir, 20.0, NEAR).unwrap();
+        assert!((ray.depth(1.0) - NEAR).abs() < 1e-4);
+        assert!(ray.end.z.abs() < 1e-4);
+        // Still in front of the camera everywhere
+        for i in 0..=10 {
+            assert!(ray.depth(i as f32 / 10.0) >= NEAR - 1e-4);
+        }
+    }
+
+    #[test]
+    fn perspective_correct_depth() {
+        let vp = view_proj();
+        let (origin, dir) = (
+            Vec3::new(0.5, 0.0, -1.0),
+            Vec3::new(0.2, 0.1, 1.0).normalize(),
+        );
+        let ray = screen_ray(vp, origin, dir, 30.0, NEAR).unwrap();
+        let screen = ray.end.truncate() - ray.start.truncate();
+        for i in 0..=20 {
+            // A point on the ray in world space, and where it lands along the ray on screen
+            let p = origin + dir * (30.0 * i as f32 / 20.0);
+            let (uv, z, w) = project(vp, p);
+            let t = (uv - ray.start.truncate()).dot(screen) / screen.length_squared();
+            assert!(
+                (ray.depth(t) - w).abs() < 1e-3 * w,
+                "{} != {w}",
+                ray.depth(t)
+            );
+            // NDC depth is linear in screen space
+            assert!((ray.at(t).z - z).abs() < 1e-4);
+            assert!((linear_depth(ray.at(t).z, NEAR, FAR) - w).abs() < 1e-2 * w);
+        }
+        // Interpolating the linear depth itself is wrong far from the ends
+        let naive = ray.depth(0.0) + (ray.depth(1.0) - ray.depth(0.0)) * 0.5;
+        assert!((naive - ray.depth(0.5)).abs() > 1.0);
+    }
+
+    #[test]
+    fn edges() {
+        assert_eq!(1.0, edge_fade(Vec2::splat(0.5), 0.1));
+        assert_eq!(0.0, edge_fade(Vec2::new(0.0, 0.5), 0.1));
+        assert!((edge_fade(Vec2::new(0.5, 0.95), 0.1) - 0.5).abs() < 1e-5);
+        // Outside the screen
+        assert_eq!(0.0, edge_fade(Vec2::new(1.2, 0.5), 0.1));
+        // Fades progressively rather than cutting off
+        let fades = (0..=10)
+            .map(|i| edge_fade(Vec2::new(i as f32 / 100.0, 0.5), 0.1))
+            .collect::<Vec<_>>();
+        assert!(fades.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn roughness_blur() {
+        assert_eq!(0.0, blur_radius(0.0, 0.4));
+        assert_eq!(MAX_BLUR_RADIUS, blur_radius(0.4, 0.4));
+        assert_eq!(MAX_BLUR_RADIUS, blur_radius(0.9, 0.4));
+        // Follows the lobe width, rougher surfaces blur faster
+        assert!((blur_radius(0.2, 0.4) - MAX_BLUR_RADIUS / 4.0).abs() < 1e-5);
+        let radii = (0..=10)
+            .map(|i| blur_radius(i as f32 * 0.04, 0.4))
+            .collect::<Vec<_>>();
+        assert!(radii.windows(2).all(|w| w[0] < w[1]));
+
+        assert_eq!(1.0, roughness_fade(0.0, 0.4));
+        assert_eq!(1.0, roughness_fade(0.25, 0.4));
+        assert_eq!(0.0, roughness_fade(0.4, 0.4));
+        assert!((roughness_fade(0.35, 0.4) - 0.5).abs() < 1e-5);
+    }
+}
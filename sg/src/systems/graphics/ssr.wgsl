@@ -0,0 +1,171 @@
+// Screen-space reflections, at half resolution. Marches each smooth pixel's reflected view ray
+// through the depth buffer, outputs the color of the last frame where it hits (rgb) and how much
+// the hit can be trusted (a).
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    var v_out: VertexOutput;
+    v_out.uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    v_out.clip_position = vec4<f32>(v_out.uv * 2.0 - 1.0, 0.0, 1.0);
+    return v_out;
+}
+
+// Mirrors ssr::SsrParams
+struct SsrParams {
+    view_proj: mat4x4<f32>,
+    prev_view_proj: mat4x4<f32>,
+    cam_pos: vec3<f32>,
+    near: f32,
+    far: f32,
+    thickness: f32,
+    max_distance: f32,
+    max_roughness: f32,
+    edge_fade: f32,
+    steps: u32,
+    refine: u32,
+    frame: u32,
+    // If the history has the last frame
+    history: u32,
+    max_blur: f32,
+    padding: vec2<u32>,
+}
+
+@group(0) @binding(0)
+var<uniform> params: SsrParams;
+@group(0) @binding(1)
+var g_sampler: sampler;
+@group(0) @binding(2)
+var g_depth: texture_depth_2d;
+@group(0) @binding(3)
+var g_position: texture_2d<f32>;
+@group(0) @binding(4)
+var g_normals: texture_2d<f32>;
+@group(0) @binding(5)
+var g_mra: texture_2d<f32>;
+@group(0) @binding(6)
+var history: texture_2d<f32>;
+
+// Mirrors ssr::linear_depth
+fn linear_depth(depth: f32) -> f32 {
+    return params.near * params.far / (params.far - depth * (params.far - params.near));
+}
+
+// Mirrors ssr::edge_fade
+fn edge_fade(uv: vec2<f32>) -> f32 {
+    let border = min(min(uv.x, 1.0 - uv.x), min(uv.y, 1.0 - uv.y));
+    return clamp(border / max(params.edge_fade, 1e-4), 0.0, 1.0);
+}
+
+// Uv (y down) and NDC depth of a clip space position
+fn to_screen(p: vec4<f32>) -> vec3<f32> {
+    return vec3<f32>(p.x / p.w * 0.5 + 0.5, 0.5 - p.y / p.w * 0.5, p.z / p.w);
+}
+
+// Interleaved gradient noise, moved every frame
+fn noise(pixel: vec2<f32>) -> f32 {
+    let p = pixel + f32(params.frame % 64u) * 5.588238;
+    return fract(52.9829189 * fract(dot(p, vec2<f32>(0.06711056, 0.00583715))));
+}
+
+fn texel_at(uv: vec2<f32>) -> vec2<i32> {
+    let size = textureDimensions(g_depth);
+    return clamp(vec2<i32>(uv * vec2<f32>(size)), vec2<i32>(0), size - 1);
+}
+
+// How far behind the depth buffer the ray is at t (negative in front of it), in view space.
+// The linear depth of the ray comes from 1/w, which is linear in screen space (mirrors
+// ssr::ScreenRay::depth).
+fn depth_delta(s0: vec3<f32>, s1: vec3<f32>, inv_w: vec2<f32>, t: f32) -> f32 {
+    let uv = s0.xy + (s1.xy - s0.xy) * t;
+    let scene = linear_depth(textureLoad(g_depth, texel_at(uv), 0));
+    return 1.0 / mix(inv_w.x, inv_w.y, t) - scene;
+}
+
+@fragment
+fn fs_main(v_in: VertexOutput) -> @location(0) vec4<f32> {
+    let full = textureDimensions(g_depth);
+    let pixel = min(vec2<i32>(v_in.clip_position.xy) * 2, full - 1);
+    let miss = vec4<f32>(0.0);
+    if (params.history == 0u || textureLoad(g_depth, pixel, 0) >= 1.0) {
+        return miss;
+    }
+    if (textureLoad(g_mra, pixel, 0).y > params.max_roughness) {
+        return miss;
+    }
+
+    let origin = textureLoad(g_position, pixel, 0).xyz;
+    let normal = normalize(textureLoad(g_normals, pixel, 0).xyz);
+    let dir = reflect(normalize(origin - params.cam_pos), normal);
+
+    // Mirrors ssr::screen_ray
+    let start = params.view_proj * vec4<f32>(origin, 1.0);
+    var end = params.view_proj * vec4<f32>(origin + dir * params.max_distance, 1.0);
+    if (end.w < params.near) {
+        end = start + (end - start) * ((start.w - params.near) / (start.w - end.w));
+    }
+    let s0 = to_screen(start);
+    let s1 = to_screen(end);
+    let inv_w = vec2<f32>(1.0 / start.w, 1.0 / end.w);
+
+    // Start two pixels away, the ray would hit its own surface
+    let pixels = length((s1.xy - s0.xy) * vec2<f32>(full));
+    let t_min = min(2.0 / max(pixels, 1.0), 1.0);
+    let jitter = noise(v_in.clip_position.xy);
+    var last = t_min;
+    var t = t_min;
+    var hit = false;
+    for (var i = 0u; i < params.steps; i++) {
+        t = t_min + (1.0 - t_min) * (f32(i) + jitter) / f32(params.steps);
+        let uv = s0.xy + (s1.xy - s0.xy) * t;
+        if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0))) {
+            // Left the screen
+            break;
+        }
+        let delta = depth_delta(s0, s1, inv_w, t);
+        if (delta > 0.0 && delta < params.thickness) {
+            hit = true;
+            break;
+        }
+        last = t;
+    }
+    if (!hit) {
+        return miss;
+    }
+    // Binary search between the last step in front of the depth buffer and the first behind it
+    var front = last;
+    var behind = t;
+    for (var i = 0u; i < params.refine; i++) {
+        let mid = (front + behind) * 0.5;
+        if (depth_delta(s0, s1, inv_w, mid) > 0.0) {
+            behind = mid;
+        } else {
+            front = mid;
+        }
+    }
+    t = behind;
+    let uv = s0.xy + (s1.xy - s0.xy) * t;
+    let texel = texel_at(uv);
+    // The ray went through the back of a surface
+    if (dot(textureLoad(g_normals, texel, 0).xyz, dir) > 0.0) {
+        return miss;
+    }
+
+    // Where the hit was in the last frame
+    let prev = params.prev_view_proj * vec4<f32>(textureLoad(g_position, texel, 0).xyz, 1.0);
+    if (prev.w <= 0.0) {
+        return miss;
+    }
+    let prev_uv = to_screen(prev).xy;
+    // Fades out near the borders (in both frames) and the end of the ray
+    let confidence = edge_fade(uv) * edge_fade(prev_uv) * (1.0 - smoothstep(0.8, 1.0, t));
+    if (confidence <= 0.0) {
+        return miss;
+    }
+    let color = textureSampleLevel(history, g_sampler, prev_uv, 0.0).rgb;
+    return vec4<f32>(min(color, vec3<f32>(64.0)), confidence);
+}
@@ -0,0 +1,85 @@
+// Blur of the reflections, at half resolution. Wider on rougher pixels, weighted by depth so it
+// doesn't bleed over silhouettes, and by confidence so misses don't darken the hits.
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    var v_out: VertexOutput;
+    v_out.uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    v_out.clip_position = vec4<f32>(v_out.uv * 2.0 - 1.0, 0.0, 1.0);
+    return v_out;
+}
+
+// Mirrors ssr::SsrParams
+struct SsrParams {
+    view_proj: mat4x4<f32>,
+    prev_view_proj: mat4x4<f32>,
+    cam_pos: vec3<f32>,
+    near: f32,
+    far: f32,
+    thickness: f32,
+    max_distance: f32,
+    max_roughness: f32,
+    edge_fade: f32,
+    steps: u32,
+    refine: u32,
+    frame: u32,
+    history: u32,
+    max_blur: f32,
+    padding: vec2<u32>,
+}
+
+@group(0) @binding(0)
+var<uniform> params: SsrParams;
+@group(0) @binding(1)
+var trace: texture_2d<f32>;
+@group(0) @binding(2)
+var g_depth: texture_depth_2d;
+@group(0) @binding(3)
+var g_mra: texture_2d<f32>;
+
+fn linear_depth(depth: f32) -> f32 {
+    return params.near * params.far / (params.far - depth * (params.far - params.near));
+}
+
+// Mirrors ssr::blur_radius
+fn blur_radius(roughness: f32) -> f32 {
+    let alpha = clamp(roughness, 0.0, params.max_roughness) / max(params.max_roughness, 1e-4);
+    return params.max_blur * alpha * alpha;
+}
+
+@fragment
+fn fs_main(v_in: VertexOutput) -> @location(0) vec4<f32> {
+    let half_size = textureDimensions(trace);
+    let full = textureDimensions(g_depth);
+    let pixel = vec2<i32>(v_in.clip_position.xy);
+    let center = textureLoad(trace, pixel, 0);
+    let full_pixel = min(pixel * 2, full - 1);
+    let radius = blur_radius(textureLoad(g_mra, full_pixel, 0).y);
+    if (radius < 0.5) {
+        return center;
+    }
+
+    let z = linear_depth(textureLoad(g_depth, full_pixel, 0));
+    var color = vec3<f32>(0.0);
+    var confidence = 0.0;
+    var total = 0.0;
+    // 5x5 taps spread over the radius
+    for (var y = -2; y <= 2; y++) {
+        for (var x = -2; x <= 2; x++) {
+            let offset = vec2<i32>(round(vec2<f32>(f32(x), f32(y)) * radius * 0.5));
+            let texel = clamp(pixel + offset, vec2<i32>(0), half_size - 1);
+            let sample_z = linear_depth(textureLoad(g_depth, min(texel * 2, full - 1), 0));
+            let w = exp(-f32(x * x + y * y) / 4.0) / (0.01 + abs(sample_z - z) / z);
+            let s = textureLoad(trace, texel, 0);
+            color += s.rgb * s.a * w;
+            confidence += s.a * w;
+            total += w;
+        }
+    }
+    return vec4<f32>(color / max(confidence, 1e-4), confidence / total);
+}
@@ -0,0 +1,22 @@
+//! The device the tests run their shaders and resources on. Tests using it need an adapter, they
+//! are `#[ignore]`d and run with `--ignored`.
+
+/// A device with `features`, panics if there is no adapter, or if the adapter lacks some of the
+/// features
+pub fn test_device(
+    features: wgpu::Features,
+) -> (wgpu::Device, wgpu::Queue, wgpu::DownlevelCapabilities) {
+    let instance = wgpu::Instance::new(wgpu::Backends::all());
+    let adapter =
+        pollster::block_on(instance.request_adapter(&Default::default())).expect("No adapter");
+    let missing = features - adapter.features();
+    assert!(missing.is_empty(), "Adapter without {missing:?}");
+    let downlevel = adapter.get_downlevel_capabilities();
+    let desc = wgpu::DeviceDescriptor {
+        features,
+        ..Default::default()
+    };
+    let (device, queue) =
+        pollster::block_on(adapter.request_device(&desc, None)).expect("Couldn't get a device");
+    (device, queue, downlevel)
+}
@@ -1,15 +1,17 @@
 use std::{
-    cell::UnsafeCell,
+    cell::{OnceCell, RefCell, UnsafeCell},
     collections::HashMap,
     hash::{Hash, Hasher},
-    lazy::OnceCell,
+    path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use glam::{Vec3, Vec4};
 use image::DynamicImage;
 use slotmap::{SecondaryMap, SlotMap};
 
+use super::memory::{Candidate, MemoryLedger, Residency, TextureInfo};
+
 slotmap::new_key_type! {
     pub struct TextureHandle;
     pub struct TextureSet;
@@ -115,12 +117,138 @@ impl Eq for SingleValue {}
 
 type SecondarySet<T> = SecondaryMap<T, ()>;
 
+/// How a texture is sampled, textures with the same one share a sampler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SamplerDesc {
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub address_mode_w: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+}
+
+impl Default for SamplerDesc {
+    /// Repeating and linearly filtered
+    fn default() -> Self {
+        Self {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+        }
+    }
+}
+
+impl SamplerDesc {
+    pub fn descriptor(&self) -> wgpu::SamplerDescriptor<'static> {
+        wgpu::SamplerDescriptor {
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            address_mode_w: self.address_mode_w,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            label: Some("TextureSet sampler"),
+            ..Default::default()
+        }
+    }
+}
+
+/// Samplers by description, each is only created once
+pub struct SamplerCache<S = wgpu::Sampler> {
+    samplers: HashMap<SamplerDesc, S>,
+}
+
+impl<S> SamplerCache<S> {
+    pub fn new() -> Self {
+        Self {
+            samplers: HashMap::new(),
+        }
+    }
+    pub fn get_or_create(
+        &mut self,
+        desc: SamplerDesc,
+        create: impl FnOnce(&SamplerDesc) -> S,
+    ) -> &S {
+        self.samplers.entry(desc).or_insert_with(|| create(&desc))
+    }
+    pub fn get(&self, desc: SamplerDesc) -> Option<&S> {
+        self.samplers.get(&desc)
+    }
+    pub fn len(&self) -> usize {
+        self.samplers.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.samplers.is_empty()
+    }
+}
+
+impl<S> Default for SamplerCache<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The textures of a set by position. Materials and shaders index into sets, so a removed texture
+/// leaves an empty slot instead of moving the ones after it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct SetSlots(Vec<Option<TextureHandle>>);
+
+impl SetSlots {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+    fn push(&mut self, tex: TextureHandle) {
+        self.0.push(Some(tex));
+    }
+    fn position(&self, tex: TextureHandle) -> Option<usize> {
+        self.0.iter().position(|slot| *slot == Some(tex))
+    }
+    fn contains(&self, tex: TextureHandle) -> bool {
+        self.position(tex).is_some()
+    }
+    /// Empty every slot of the texture, returns whether there was one
+    fn clear(&mut self, tex: TextureHandle) -> bool {
+        let mut found = false;
+        for slot in self.0.iter_mut().filter(|slot| **slot == Some(tex)) {
+            *slot = None;
+            found = true;
+        }
+        found
+    }
+    /// Put a texture in an existing slot, returns what was there
+    fn replace(&mut self, index: usize, tex: TextureHandle) -> Option<Option<TextureHandle>> {
+        self.0.get_mut(index).map(|slot| slot.replace(tex))
+    }
+    fn textures(&self) -> impl Iterator<Item = TextureHandle> + '_ {
+        self.0.iter().flatten().copied()
+    }
+    fn slots(&self) -> &[Option<TextureHandle>] {
+        &self.0
+    }
+}
+
+/// A texture loaded from an image file, which can be reloaded at a lower resolution to save memory
+/// (see `memory::ResidencyPolicy`)
+pub struct Streamable {
+    pub path: PathBuf,
+    pub srgb: bool,
+    /// The texture at full resolution
+    pub full: TextureInfo,
+    pub residency: Residency,
+    /// Frame the texture was loaded, demoted or promoted at
+    pub changed: u64,
+}
+
 pub struct TextureManager {
     textures: SlotMap<TextureHandle, wgpu::Texture>,
     /// All views of the textures
     views: SecondaryMap<TextureHandle, wgpu::TextureView>,
     /// All sets existing in the TextureManager (mapped to their textures)
-    sets: SlotMap<TextureSet, Vec<TextureHandle>>,
+    sets: SlotMap<TextureSet, SetSlots>,
     /// The set mapped to each texture
     textures_set: SecondaryMap<TextureHandle, Vec<TextureSet>>,
     /// currently cached set bind_groups, all groups have to be valid (i.e. contain all textures
@@ -128,17 +256,30 @@ pub struct TextureManager {
     cache_bind_groups: UnsafeCell<SecondaryMap<TextureSet, wgpu::BindGroup>>,
     /// Cache for bindgroup layout
     bind_group_layout: OnceCell<wgpu::BindGroupLayout>,
-    /// Cache for sampler
-    sampler: OnceCell<wgpu::Sampler>,
+    /// 1x1 texture bound in the empty slots of the sets
+    empty_slot: OnceCell<wgpu::TextureView>,
+    /// Samplers of the textures, created when a bind group needs them
+    samplers: RefCell<SamplerCache>,
+    /// Sampler of each texture
+    texture_sampler: SecondaryMap<TextureHandle, SamplerDesc>,
     /// Cache for single value textures
     single_value_cache: HashMap<SingleValue, TextureHandle>,
     /// Same but opposit direction
     texture_value: SecondaryMap<TextureHandle, SingleValue>,
+    /// Size of each texture
+    memory: MemoryLedger<TextureHandle>,
+    streamable: SecondaryMap<TextureHandle, Streamable>,
+    /// Frame each set's bind group was last asked for, behind a RefCell for the same reason as
+    /// `cache_bind_groups`
+    last_used: RefCell<SecondaryMap<TextureSet, u64>>,
+    frame: u64,
 }
 
 impl TextureManager {
     pub const TEXTURE_SET_MAX: u32 = 16;
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+    /// Demoted textures are this many times smaller on each side
+    pub const LOW_RES_FACTOR: u32 = 4;
 
     pub fn new() -> Self {
         Self {
@@ -148,15 +289,21 @@ impl TextureManager {
             textures: SlotMap::with_key(),
             cache_bind_groups: UnsafeCell::new(SecondaryMap::new()),
             bind_group_layout: OnceCell::new(),
-            sampler: OnceCell::new(),
+            empty_slot: OnceCell::new(),
+            samplers: RefCell::new(SamplerCache::new()),
+            texture_sampler: SecondaryMap::new(),
             single_value_cache: HashMap::new(),
             texture_value: SecondaryMap::new(),
+            memory: MemoryLedger::new(),
+            streamable: SecondaryMap::new(),
+            last_used: RefCell::new(SecondaryMap::new()),
+            frame: 0,
         }
     }
 
     /// Create a new set
     pub fn add_set(&mut self) -> TextureSet {
-        self.sets.insert(Vec::new())
+        self.sets.insert(SetSlots::default())
     }
 
     pub fn add_image_texture(
@@ -165,8 +312,52 @@ impl TextureManager {
         queue: &wgpu::Queue,
         img: DynamicImage,
     ) -> TextureHandle {
+        let info = TextureInfo::new(img.width(), img.height(), wgpu::TextureFormat::Rgba8Unorm);
         let tex = Self::create_texture(device, queue, img);
-        self.add_texture(tex)
+        self.add_texture(tex, info)
+    }
+
+    /// Load an image file as a texture the residency policy can demote, see `Streamable`
+    pub fn add_streamable_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &Path,
+        srgb: bool,
+    ) -> Result<TextureHandle> {
+        let img = image::open(path)
+            .with_context(|| format!("can't load {}", path.display()))?
+            .into_rgba8();
+        let full = TextureInfo::new(img.width(), img.height(), Self::image_format(srgb));
+        let tex = Self::create_texture_from_bytes(
+            device,
+            queue,
+            &img,
+            full.format,
+            full.width,
+            full.height,
+            wgpu::TextureUsages::TEXTURE_BINDING,
+            4,
+        );
+        let handle = self.add_texture(tex, full);
+        self.streamable.insert(
+            handle,
+            Streamable {
+                path: path.to_owned(),
+                srgb,
+                full,
+                residency: Residency::Full,
+                changed: self.frame,
+            },
+        );
+        Ok(handle)
+    }
+
+    fn image_format(srgb: bool) -> wgpu::TextureFormat {
+        match srgb {
+            true => wgpu::TextureFormat::Rgba8UnormSrgb,
+            false => wgpu::TextureFormat::Rgba8Unorm,
+        }
     }
 
     pub fn add_depth_texture(
@@ -175,7 +366,7 @@ impl TextureManager {
         config: &wgpu::SurfaceConfiguration,
     ) -> TextureHandle {
         let tex = Self::create_depth_texture(device, config);
-        self.add_texture(tex)
+        self.add_texture(tex, TextureInfo::new(config.width, config.height, Self::DEPTH_FORMAT))
     }
 
     /// Return a handle to a texture with the SingleValue as contant, may create it if needed
@@ -190,7 +381,7 @@ impl TextureManager {
             Some(handle) => *handle,
             None => {
                 let tex = Self::create_single_value_texture(device, queue, value);
-                let handle = self.add_texture(tex);
+                let handle = self.add_texture(tex, TextureInfo::new(1, 1, value.format()));
                 self.single_value_cache.insert(value, handle);
                 self.texture_value.insert(handle, value);
                 handle
@@ -202,19 +393,58 @@ impl TextureManager {
         Some(self.textures.get(tex)?.create_view(&Default::default()))
     }
 
-    /// Add a texture to the TextureManager and assign it to a set
-    pub fn add_texture(&mut self, tex: wgpu::Texture) -> TextureHandle {
+    /// Add a texture to the TextureManager, `info` describes it for the memory accounting
+    pub fn add_texture(&mut self, tex: wgpu::Texture, info: TextureInfo) -> TextureHandle {
+        self.add_texture_with_sampler(tex, info, SamplerDesc::default())
+    }
+
+    /// Add a texture sampled with `sampler` in the sets it is part of
+    pub fn add_texture_with_sampler(
+        &mut self,
+        tex: wgpu::Texture,
+        info: TextureInfo,
+        sampler: SamplerDesc,
+    ) -> TextureHandle {
         let view = tex.create_view(&wgpu::TextureViewDescriptor::default());
         let handle = self.textures.insert(tex);
         self.textures_set.insert(handle, Vec::new());
         self.views.insert(handle, view);
+        self.memory.insert(handle, info.bytes());
+        self.texture_sampler.insert(handle, sampler);
         handle
     }
 
+    pub fn get_sampler_desc(&self, tex: TextureHandle) -> Option<SamplerDesc> {
+        self.texture_sampler.get(tex).copied()
+    }
+
     pub fn add_texture_to_set(&mut self, tex: TextureHandle, set: TextureSet) -> Result<()> {
         self.textures.get(tex).context("No such texture")?;
-        self.sets.get_mut(set).context("No such set")?.push(tex);
+        let textures = self.sets.get_mut(set).context("No such set")?;
+        if textures.len() >= Self::TEXTURE_SET_MAX as usize {
+            return Err(anyhow!("Set already has {} textures", Self::TEXTURE_SET_MAX));
+        }
+        textures.push(tex);
         self.textures_set.get_mut(tex).unwrap().push(set);
+        self.cache_bind_groups.get_mut().remove(set);
+        Ok(())
+    }
+
+    /// Put a texture at `index` of a set in place of the one there (or of the removed one)
+    pub fn replace_in_set(&mut self, set: TextureSet, index: usize, tex: TextureHandle) -> Result<()> {
+        self.textures.get(tex).context("No such texture")?;
+        let textures = self.sets.get_mut(set).context("No such set")?;
+        let old = textures
+            .replace(index, tex)
+            .with_context(|| format!("Set has no texture {index}"))?;
+        if let Some(old) = old.filter(|old| !textures.contains(*old)) {
+            self.textures_set[old].retain(|oset| *oset != set);
+        }
+        let sets = self.textures_set.get_mut(tex).unwrap();
+        if !sets.contains(&set) {
+            sets.push(set);
+        }
+        self.cache_bind_groups.get_mut().remove(set);
         Ok(())
     }
 
@@ -264,6 +494,14 @@ impl TextureManager {
         }
         let [va, vb] = self.views.get_disjoint_mut([a, b]).unwrap();
         std::mem::swap(va, vb);
+        self.memory.swap(a, b);
+        let (sa, sb) = (self.streamable.remove(a), self.streamable.remove(b));
+        if let Some(streamable) = sa {
+            self.streamable.insert(b, streamable);
+        }
+        if let Some(streamable) = sb {
+            self.streamable.insert(a, streamable);
+        }
         Ok(())
     }
     
@@ -277,7 +515,8 @@ impl TextureManager {
     pub fn remove_set(&mut self, set: TextureSet) {
         if let Some(texs) = self.sets.remove(set) {
             self.cache_bind_groups.get_mut().remove(set);
-            for tex in texs {
+            self.last_used.get_mut().remove(set);
+            for tex in texs.textures() {
                 self.textures_set.get_mut(tex)
                     .unwrap()
                     .retain(|oset| *oset != set);
@@ -285,15 +524,30 @@ impl TextureManager {
         }
     }
 
+    /// Replace the texture of a handle, it stops being streamable as it may not come from its
+    /// file anymore
     pub fn replace_texture(
         &mut self,
         tex: TextureHandle,
         new_tex: wgpu::Texture,
+        info: TextureInfo,
+    ) -> Result<()> {
+        self.replace(tex, new_tex, info)?;
+        self.streamable.remove(tex);
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        tex: TextureHandle,
+        new_tex: wgpu::Texture,
+        info: TextureInfo,
     ) -> Result<()> {
         *self
             .textures
             .get_mut(tex)
             .context("Can't replace unknown texture")? = new_tex;
+        self.memory.insert(tex, info.bytes());
         for set in self.textures_set.get(tex).unwrap() {
             // delete cache as it has a reference to the old view.
             self.cache_bind_groups.get_mut().remove(*set);
@@ -309,47 +563,12 @@ impl TextureManager {
         Ok(())
     }
 
+    /// Textures of the set at binding 0, and their samplers (in the same order) at binding 1
     pub fn layout(&self, device: &wgpu::Device) -> &wgpu::BindGroupLayout {
         self.bind_group_layout.get_or_init(|| {
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: std::num::NonZeroU32::new(Self::TEXTURE_SET_MAX),
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                ],
-                label: Some("TextureSet bind group layout"),
-            });
             create_bind_group_layout!(device, "TexturerSet Bind Group Layout": {
                 0 => FRAGMENT | Texture(view_dim: D2, sample: FloatFilterable)[Self::TEXTURE_SET_MAX],
-                1 => FRAGMENT | Sampler(Filtering)
-            })
-        })
-    }
-
-    pub fn sampler(&self, device: &wgpu::Device) -> &wgpu::Sampler {
-        self.sampler.get_or_init(|| {
-            device.create_sampler(&wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::Repeat,
-                address_mode_v: wgpu::AddressMode::Repeat,
-                address_mode_w: wgpu::AddressMode::Repeat,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Linear,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                label: Some("TextureSet sampler"),
-                ..Default::default()
+                1 => FRAGMENT | Sampler(Filtering)[Self::TEXTURE_SET_MAX]
             })
         })
     }
@@ -362,18 +581,36 @@ impl TextureManager {
     // in the middle of a draw call (or its recording).
     // TODO: Make this safe ?
     pub fn get_bindgroup(&self, device: &wgpu::Device, set: TextureSet) -> &wgpu::BindGroup {
+        self.last_used.borrow_mut().insert(set, self.frame);
         // There's no way this'll ever fail... Right ?
         let bindgroups = unsafe { &mut *self.cache_bind_groups.get() };
         if !bindgroups.contains_key(set) {
             let layout = self.layout(device);
-            let sampler = self.sampler(device);
             let handles = self
                 .sets
                 .get(set)
                 .expect("Attempting to build bind group for unknown set");
+            let empty_slot = self.empty_slot.get_or_init(|| Self::create_empty_slot(device));
             let views: Vec<_> = handles
+                .slots()
+                .iter()
+                .map(|slot| slot.map_or(empty_slot, |handle| &self.views[handle]))
+                .collect();
+            let descs: Vec<_> = handles
+                .slots()
                 .iter()
-                .map(|handle| &self.views[*handle])
+                .map(|slot| slot.map_or_else(SamplerDesc::default, |handle| self.texture_sampler[handle]))
+                .collect();
+            {
+                let mut cache = self.samplers.borrow_mut();
+                for desc in &descs {
+                    cache.get_or_create(*desc, |desc| device.create_sampler(&desc.descriptor()));
+                }
+            }
+            let cache = self.samplers.borrow();
+            let samplers: Vec<_> = descs
+                .iter()
+                .map(|desc| cache.get(*desc).unwrap())
                 .collect();
 
             bindgroups.insert(
@@ -387,7 +624,7 @@ impl TextureManager {
                         },
                         wgpu::BindGroupEntry {
                             binding: 1,
-                            resource: wgpu::BindingResource::Sampler(sampler),
+                            resource: wgpu::BindingResource::SamplerArray(&samplers),
                         },
                     ],
                     label: Some("TextureSet bind group"),
@@ -397,8 +634,27 @@ impl TextureManager {
         bindgroups.get(set).unwrap()
     }
 
+    /// Index of the texture in the set, counting the empty slots
     pub fn get_index_of_texture(&self, tex: TextureHandle, set: TextureSet) -> Option<usize> {
-        self.sets.get(set)?.iter().position(|a| *a == tex)
+        self.sets.get(set)?.position(tex)
+    }
+
+    fn create_empty_slot(device: &wgpu::Device) -> wgpu::TextureView {
+        device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("TextureSet empty slot"),
+                size: wgpu::Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            })
+            .create_view(&Default::default())
     }
 
     pub fn remove_texture(&mut self, tex: TextureHandle) -> Result<wgpu::Texture> {
@@ -407,15 +663,12 @@ impl TextureManager {
             .remove(tex)
             .context("Trying to remove unknown texture.")?; // remove wgpu texture
         self.views.remove(tex);
+        self.memory.remove(tex);
+        self.streamable.remove(tex);
+        self.texture_sampler.remove(tex);
         for set in self.textures_set.remove(tex).unwrap() {
-            let index = self
-                .sets
-                .get(set)
-                .unwrap()
-                .iter()
-                .position(|s| *s == tex)
-                .unwrap();
-            self.sets.get_mut(set).unwrap().remove(index); // remove texture from set
+            // empty its slots, the other textures of the set keep their index
+            self.sets.get_mut(set).unwrap().clear(tex);
             self.cache_bind_groups.get_mut().remove(set); // delete cached bind group as it is no longer valid and needs to be recreated
         }
         if let Some(value) = self.texture_value.get(tex) {
@@ -425,6 +678,93 @@ impl TextureManager {
         Ok(res)
     }
 
+    /// Size of the textures
+    pub fn memory(&self) -> &MemoryLedger<TextureHandle> {
+        &self.memory
+    }
+
+    /// Start a new frame for the use tracking, returns its number
+    pub fn advance_frame(&mut self) -> u64 {
+        self.frame += 1;
+        self.frame
+    }
+
+    /// Frame a set containing the texture was last bound at (0 if never)
+    pub fn last_used(&self, tex: TextureHandle) -> u64 {
+        let last_used = self.last_used.borrow();
+        self.get_texture_sets(tex)
+            .iter()
+            .filter_map(|set| last_used.get(*set).copied())
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn streamable(&self) -> impl Iterator<Item = (TextureHandle, &Streamable)> {
+        self.streamable.iter()
+    }
+
+    /// The streamable textures, for `ResidencyPolicy::decide`
+    pub fn residency_candidates(&self) -> Vec<Candidate<TextureHandle>> {
+        self.streamable
+            .iter()
+            .map(|(tex, streamable)| Candidate {
+                key: tex,
+                residency: streamable.residency,
+                full_bytes: streamable.full.bytes(),
+                low_bytes: streamable.full.downscaled(Self::LOW_RES_FACTOR).bytes(),
+                last_used: self.last_used(tex),
+                changed: streamable.changed,
+            })
+            .collect()
+    }
+
+    /// Reload a streamable texture from its file, at full or low resolution
+    pub fn set_residency(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        tex: TextureHandle,
+        residency: Residency,
+    ) -> Result<()> {
+        let streamable = self
+            .streamable
+            .get(tex)
+            .context("Texture isn't streamable")?;
+        if streamable.residency == residency {
+            return Ok(());
+        }
+        let info = match residency {
+            Residency::Full => streamable.full,
+            Residency::Low => streamable.full.downscaled(Self::LOW_RES_FACTOR),
+        };
+        let mut img = image::open(&streamable.path)
+            .with_context(|| format!("can't load {}", streamable.path.display()))?
+            .into_rgba8();
+        if img.dimensions() != (info.width, info.height) {
+            img = image::imageops::resize(
+                &img,
+                info.width,
+                info.height,
+                image::imageops::FilterType::Triangle,
+            );
+        }
+        let new_tex = Self::create_texture_from_bytes(
+            device,
+            queue,
+            &img,
+            info.format,
+            info.width,
+            info.height,
+            wgpu::TextureUsages::TEXTURE_BINDING,
+            4,
+        );
+        self.replace(tex, new_tex, info)?;
+        let streamable = self.streamable.get_mut(tex).unwrap();
+        streamable.residency = residency;
+        streamable.changed = self.frame;
+        Ok(())
+    }
+
     pub fn create_single_value_texture(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
@@ -485,6 +825,7 @@ impl TextureManager {
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_texture_from_bytes(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
@@ -530,6 +871,42 @@ impl TextureManager {
         gtex
     }
 
+    /// Overwrite the `width` x `height` region at `origin` of the first mip with `bytes` (tightly
+    /// packed rows). The texture needs the COPY_DST usage.
+    pub fn write_texture_region(
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        origin: (u32, u32),
+        width: u32,
+        height: u32,
+        bytes: &[u8],
+        bytes_per_pixel: u32,
+    ) {
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: origin.0,
+                    y: origin.1,
+                    z: 0,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytes,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(width * bytes_per_pixel),
+                rows_per_image: std::num::NonZeroU32::new(height),
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
     pub fn create_depth_texture(
         device: &wgpu::Device,
         config: &wgpu::SurfaceConfiguration,
@@ -539,7 +916,7 @@ impl TextureManager {
             height: config.height,
             depth_or_array_layers: 1,
         };
-        let tex = device.create_texture(&wgpu::TextureDescriptor {
+        device.create_texture(&wgpu::TextureDescriptor {
             label: Some("TextureManager Depth Texture"),
             size,
             mip_level_count: 1,
@@ -547,8 +924,7 @@ impl TextureManager {
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
-        });
-        tex
+        })
     }
 }
 
@@ -557,3 +933,99 @@ impl Default for TextureManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::graphics::test_device::test_device;
+
+    #[test]
+    fn set_slots() {
+        let mut handles = SlotMap::<TextureHandle, ()>::with_key();
+        let [a, b, c] = [(); 3].map(|_| handles.insert(()));
+        let mut slots = SetSlots::default();
+        for tex in [a, b, a, c] {
+            slots.push(tex);
+        }
+        assert!(slots.clear(a));
+        assert!(!slots.clear(a));
+        assert_eq!(&[None, Some(b), None, Some(c)], slots.slots());
+        assert_eq!(Some(3), slots.position(c));
+        assert_eq!(vec![b, c], slots.textures().collect::<Vec<_>>());
+        assert_eq!(Some(None), slots.replace(2, a));
+        assert_eq!(Some(Some(b)), slots.replace(1, c));
+        assert_eq!(None, slots.replace(4, a));
+        assert_eq!(&[None, Some(c), Some(a), Some(c)], slots.slots());
+        assert_eq!(4, slots.len());
+    }
+
+    #[test]
+    #[ignore = "needs an adapter with texture binding arrays"]
+    fn removal_keeps_indices() {
+        // What the sets' bind groups need
+        let features =
+            wgpu::Features::TEXTURE_BINDING_ARRAY | wgpu::Features::PARTIALLY_BOUND_BINDING_ARRAY;
+        let (device, queue, _) = test_device(features);
+        let mut manager = TextureManager::new();
+        let set = manager.add_set();
+        let textures: Vec<_> = (0..5)
+            .map(|i| {
+                let tex = manager.get_or_add_single_value_texture(&device, &queue, SingleValue::Factor(i as f32 / 10.0));
+                manager.add_texture_to_set(tex, set).unwrap();
+                tex
+            })
+            .collect();
+        manager.get_bindgroup(&device, set);
+        assert!(manager.cache_bind_groups.get_mut().contains_key(set));
+
+        manager.remove_texture(textures[2]).unwrap();
+        assert!(!manager.cache_bind_groups.get_mut().contains_key(set));
+        for (index, tex) in textures.iter().enumerate().filter(|(index, _)| *index != 2) {
+            assert_eq!(Some(index), manager.get_index_of_texture(*tex, set));
+        }
+        assert_eq!(None, manager.get_index_of_texture(textures[2], set));
+        // The empty slot is bound to a placeholder
+        manager.get_bindgroup(&device, set);
+        assert!(manager.cache_bind_groups.get_mut().contains_key(set));
+
+        let new = manager.get_or_add_single_value_texture(&device, &queue, SingleValue::Factor(1.0));
+        manager.replace_in_set(set, 2, new).unwrap();
+        assert!(!manager.cache_bind_groups.get_mut().contains_key(set));
+        assert_eq!(Some(2), manager.get_index_of_texture(new, set));
+        assert_eq!(&[set], manager.get_texture_sets(new));
+        manager.replace_in_set(set, 2, textures[0]).unwrap();
+        assert!(manager.get_texture_sets(new).is_empty());
+        assert!(manager.replace_in_set(set, 5, new).is_err());
+    }
+
+    #[test]
+    fn sampler_cache() {
+        let mut cache = SamplerCache::new();
+        let mut created = 0;
+        let pixel_art = SamplerDesc {
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        };
+        let clamped = SamplerDesc {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            ..Default::default()
+        };
+        for desc in [SamplerDesc::default(), pixel_art, SamplerDesc::default(), clamped, pixel_art] {
+            let sampler = *cache.get_or_create(desc, |desc| {
+                created += 1;
+                *desc
+            });
+            assert_eq!(desc, sampler);
+        }
+        assert_eq!(3, created);
+        assert_eq!(3, cache.len());
+        assert_eq!(Some(&clamped), cache.get(clamped));
+        let mirrored = SamplerDesc {
+            address_mode_u: wgpu::AddressMode::MirrorRepeat,
+            ..Default::default()
+        };
+        assert_eq!(None, cache.get(mirrored));
+    }
+}
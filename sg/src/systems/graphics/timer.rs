@@ -0,0 +1,90 @@
+//! GPU timestamp queries, to time parts of a frame.
+
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+enum Readback {
+    Idle,
+    Recorded,
+    Mapping(Receiver<Result<(), wgpu::BufferAsyncError>>),
+}
+
+/// Times a part of a frame with timestamp queries, the result comes back a few frames later
+pub struct GpuTimer {
+    queries: wgpu::QuerySet,
+    readback: wgpu::Buffer,
+    /// Nanoseconds per tick
+    period: f32,
+    state: Readback,
+}
+
+impl GpuTimer {
+    /// None if the device doesn't have timestamp queries
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, label: &str) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+        Some(Self {
+            queries: device.create_query_set(&wgpu::QuerySetDescriptor {
+                label: Some(&format!("{label} Timer Queries")),
+                ty: wgpu::QueryType::Timestamp,
+                count: 2,
+            }),
+            readback: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&format!("{label} Timer Readback")),
+                size: 16,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            period: queue.get_timestamp_period(),
+            state: Readback::Idle,
+        })
+    }
+    /// If a measure can be recorded this frame (the last one came back)
+    pub fn ready(&self) -> bool {
+        matches!(self.state, Readback::Idle)
+    }
+    pub fn begin(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.write_timestamp(&self.queries, 0);
+    }
+    pub fn end(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.write_timestamp(&self.queries, 1);
+        encoder.resolve_query_set(&self.queries, 0..2, &self.readback, 0);
+        self.state = Readback::Recorded;
+    }
+    pub fn after_submit(&mut self) {
+        if let Readback::Recorded = self.state {
+            let (sender, receiver) = mpsc::channel();
+            self.readback
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |result| {
+                    sender.send(result).ok();
+                });
+            self.state = Readback::Mapping(receiver);
+        }
+    }
+    /// The last measure in milliseconds, if it just came back
+    pub fn poll(&mut self, device: &wgpu::Device) -> Option<f32> {
+        let mapped = match &self.state {
+            Readback::Mapping(receiver) => {
+                device.poll(wgpu::Maintain::Poll);
+                match receiver.try_recv() {
+                    Ok(result) => result.is_ok(),
+                    Err(TryRecvError::Empty) => return None,
+                    Err(TryRecvError::Disconnected) => false,
+                }
+            }
+            _ => return None,
+        };
+        self.state = Readback::Idle;
+        if !mapped {
+            return None;
+        }
+        let ticks = {
+            let bytes = self.readback.slice(..).get_mapped_range();
+            let ticks: &[u64] = bytemuck::cast_slice(&bytes);
+            ticks[1].saturating_sub(ticks[0])
+        };
+        self.readback.unmap();
+        Some(ticks as f32 * self.period / 1e6)
+    }
+}
@@ -0,0 +1,216 @@
+//! Tonemapping of the HDR scene.
+//!
+//! The shading pass and the particles render into an `HDR_FORMAT` target of the size of the
+//! screen, unbounded and linear. The tonemap pass maps it to the output with a `Tonemapper`
+//! (`tonemap.wgsl`), encoding it to sRGB itself when the output format doesn't. The exposure is
+//! applied before, by the shading pass.
+
+use crate::include_shader;
+
+use super::{memory::texture_bytes, pipeline::{Pipeline, RenderPipeline}};
+
+/// Format of the scene before tonemapping
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Curve mapping the HDR scene to the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Tonemapper {
+    /// The ACES fit of Krzysztof Narkowicz
+    #[default]
+    Aces,
+    /// `x / (1 + x)`, per channel
+    Reinhard,
+    /// Clamped to 1, for the g-buffer debug views
+    Linear,
+}
+
+impl Tonemapper {
+    pub const ALL: [Tonemapper; 3] = [Self::Aces, Self::Reinhard, Self::Linear];
+
+    /// Mirrors the operators of `tonemap.wgsl`
+    pub fn map(self, x: f32) -> f32 {
+        match self {
+            Self::Aces => {
+                let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+                ((x * (a * x + b)) / (x * (c * x + d) + e)).clamp(0.0, 1.0)
+            }
+            Self::Reinhard => x / (1.0 + x),
+            Self::Linear => x.clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// Mirrors `TonemapParams` of `tonemap.wgsl`
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct TonemapParams {
+    tonemapper: u32,
+    padding: [u32; 3],
+}
+
+pub struct Tonemap {
+    pipeline: RenderPipeline,
+    params: wgpu::Buffer,
+    target: wgpu::TextureView,
+    bind_group: wgpu::BindGroup,
+}
+
+impl Tonemap {
+    /// Tonemapping to an output of `format`
+    pub fn new(device: &wgpu::Device, size: (u32, u32), format: wgpu::TextureFormat) -> Self {
+        let layout = create_bind_group_layout!(device, "Tonemap Bindgroup Layout": {
+            0 => FRAGMENT | Texture(sample: Float, view_dim: D2),
+            1 => FRAGMENT | Buffer(type: Uniform),
+        });
+        let mut shader = include_shader!("tonemap.wgsl", "Tonemap Shader");
+        shader.set_bool("SRGB", format.describe().srgb);
+        let pipeline = Pipeline::new(
+            device,
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Tonemap Pipeline Layout"),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            }),
+            shader,
+            move |device, layout, module| {
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Tonemap Pipeline"),
+                    layout: Some(layout),
+                    vertex: wgpu::VertexState {
+                        module,
+                        entry_point: "vs_main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module,
+                        entry_point: "fs_main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format,
+                            blend: None,
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            },
+        );
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Tonemap Params"),
+            size: std::mem::size_of::<TonemapParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let target = Self::make_target(device, size);
+        let bind_group = Self::make_bind_group(device, &layout, &target, &params);
+        Self {
+            pipeline,
+            params,
+            target,
+            bind_group,
+        }
+    }
+    /// Bytes taken by the target at a size
+    pub fn memory(size: (u32, u32)) -> u64 {
+        texture_bytes(size.0, size.1, HDR_FORMAT, 1)
+    }
+    fn make_target(device: &wgpu::Device, size: (u32, u32)) -> wgpu::TextureView {
+        device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("HDR Target"),
+                size: wgpu::Extent3d {
+                    width: size.0.max(1),
+                    height: size.1.max(1),
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: HDR_FORMAT,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+            })
+            .create_view(&Default::default())
+    }
+    fn make_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        target: &wgpu::TextureView,
+        params: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        create_bind_group!(device, layout, "Tonemap Bindgroup": {
+            0 | TextureView(target),
+            1 | Buffer(buffer: params),
+        })
+    }
+    /// The pipeline, to reload its shader
+    pub fn pipelines_mut(&mut self) -> [&mut RenderPipeline; 1] {
+        [&mut self.pipeline]
+    }
+    /// Recreate the target at the new size
+    pub fn resize(&mut self, device: &wgpu::Device, size: (u32, u32)) {
+        self.target = Self::make_target(device, size);
+        self.bind_group = Self::make_bind_group(
+            device,
+            &self.pipeline.pipeline.get_bind_group_layout(0),
+            &self.target,
+            &self.params,
+        );
+    }
+    /// What the scene is rendered into before tonemapping
+    pub fn target(&self) -> &wgpu::TextureView {
+        &self.target
+    }
+    /// Record the tonemapping of the target into `view`, with `tonemapper`
+    pub fn render(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        tonemapper: Tonemapper,
+    ) {
+        let params = TonemapParams {
+            tonemapper: tonemapper as u32,
+            padding: [0; 3],
+        };
+        queue.write_buffer(&self.params, 0, bytemuck::bytes_of(&params));
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Tonemap Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operators() {
+        for operator in Tonemapper::ALL {
+            assert_eq!(0.0, operator.map(0.0), "{operator:?}");
+            let mapped = [0.1, 0.5, 1.0, 4.0, 100.0].map(|x| operator.map(x));
+            assert!(mapped.windows(2).all(|w| w[0] <= w[1]), "{operator:?}");
+            assert!(mapped.iter().all(|y| (0.0..=1.0).contains(y)), "{operator:?}");
+        }
+        assert_eq!(0.5, Tonemapper::Reinhard.map(1.0));
+        assert_eq!(0.5, Tonemapper::Linear.map(0.5));
+        assert_eq!(1.0, Tonemapper::Linear.map(4.0));
+        // The highlights roll off instead of clipping
+        assert!(Tonemapper::Aces.map(1.0) < 1.0);
+        assert!(Tonemapper::Aces.map(100.0) > 0.99);
+    }
+}
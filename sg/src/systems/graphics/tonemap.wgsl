@@ -0,0 +1,54 @@
+// Tonemapping of the HDR scene to the output, with an operator of tonemap::Tonemapper
+
+@group(0) @binding(0)
+var hdr: texture_2d<f32>;
+
+// Mirrors tonemap::TonemapParams
+struct TonemapParams {
+    // Index in tonemap::Tonemapper
+    tonemapper: u32,
+    padding: vec3<u32>,
+}
+
+@group(0) @binding(1)
+var<uniform> params: TonemapParams;
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> @builtin(position) vec4<f32> {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+
+// The ACES fit of Krzysztof Narkowicz
+fn aces(x: vec3<f32>) -> vec3<f32> {
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+// Outputs that aren't sRGB get the encoding the hardware would have done
+fn srgb_encode(x: vec3<f32>) -> vec3<f32> {
+    let low = x * 12.92;
+    let high = 1.055 * pow(x, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, x <= vec3<f32>(0.0031308));
+}
+
+@fragment
+fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let color = textureLoad(hdr, vec2<i32>(position.xy), 0);
+    var mapped: vec3<f32>;
+    if (params.tonemapper == 0u) {
+        mapped = aces(color.rgb);
+    } else if (params.tonemapper == 1u) {
+        mapped = color.rgb / (1.0 + color.rgb);
+    } else {
+        mapped = clamp(color.rgb, vec3<f32>(0.0), vec3<f32>(1.0));
+    }
+    if (!{{SRGB}}) {
+        mapped = srgb_encode(mapped);
+    }
+    return vec4<f32>(mapped, 1.0);
+}
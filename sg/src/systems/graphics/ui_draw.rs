@@ -0,0 +1,193 @@
+//! What the UI draws on top of the game's own windows, and what it asks of the event loop.
+//!
+//! Applications add their panels to the `UiDraw` resource, as callbacks run by `UIRenderer::render`
+//! every frame in the order they were added. The requests of egui to the platform (links to open,
+//! cursor, when to repaint) come back in a `UiOutput` the event loop takes after the frame.
+
+use std::time::{Duration, Instant};
+
+use crate::localization::Localization;
+use crate::tr;
+
+use super::renderer::RenderStats;
+
+/// A panel drawn every frame
+pub type PanelCallback = Box<dyn FnMut(&egui::Context) + Send>;
+
+/// Frames per second averaged over the last frames
+#[derive(Debug, Clone, Copy, Default)]
+struct FrameRate {
+    last: Option<Instant>,
+    /// Seconds per frame
+    average: Option<f32>,
+}
+
+impl FrameRate {
+    /// Weight of the last frame in the average
+    const SMOOTHING: f32 = 0.1;
+
+    fn frame(&mut self, now: Instant) {
+        if let Some(last) = self.last.replace(now) {
+            self.add(now - last);
+        }
+    }
+    fn add(&mut self, delta: Duration) {
+        let delta = delta.as_secs_f32();
+        self.average = Some(match self.average {
+            Some(average) => average + (delta - average) * Self::SMOOTHING,
+            None => delta,
+        });
+    }
+    fn fps(&self) -> f32 {
+        match self.average {
+            Some(average) if average > 0.0 => 1.0 / average,
+            _ => 0.0,
+        }
+    }
+}
+
+/// The panels of the application, drawn after the game's windows
+#[derive(Default)]
+pub struct UiDraw {
+    panels: Vec<(String, PanelCallback)>,
+    /// Show the frame rate and the counters of the renderer
+    pub show_stats: bool,
+    frame_rate: FrameRate,
+}
+
+impl UiDraw {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add a panel, replacing the one of the same name (which keeps its place)
+    pub fn add_panel(
+        &mut self,
+        name: impl Into<String>,
+        callback: impl FnMut(&egui::Context) + Send + 'static,
+    ) {
+        let name = name.into();
+        let callback = Box::new(callback);
+        match self.panels.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, panel)) => *panel = callback,
+            None => self.panels.push((name, callback)),
+        }
+    }
+    /// Remove a panel, returns whether there was one
+    pub fn remove_panel(&mut self, name: &str) -> bool {
+        let len = self.panels.len();
+        self.panels.retain(|(n, _)| n != name);
+        self.panels.len() != len
+    }
+    /// Names of the panels, in the order they are drawn
+    pub fn panels(&self) -> impl Iterator<Item = &str> {
+        self.panels.iter().map(|(name, _)| name.as_str())
+    }
+    pub fn fps(&self) -> f32 {
+        self.frame_rate.fps()
+    }
+    /// Draw the stats (if shown) and the panels
+    pub fn draw(&mut self, ctx: &egui::Context, loc: &Localization, stats: RenderStats) {
+        self.frame_rate.frame(Instant::now());
+        if self.show_stats {
+            let fps = self.frame_rate.fps();
+            egui::Window::new(tr!(loc, "stats.window")).show(ctx, |ui| {
+                ui.label(tr!(loc, "stats.fps", fps = format!("{fps:.0}")));
+                ui.label(tr!(loc, "stats.render", drawn = stats.drawn, draw_calls = stats.draw_calls, culled = stats.culled, occluded = stats.occluded));
+            });
+        }
+        self.draw_panels(ctx);
+    }
+    fn draw_panels(&mut self, ctx: &egui::Context) {
+        for (_, panel) in &mut self.panels {
+            panel(ctx);
+        }
+    }
+}
+
+/// What egui asked of the platform during the last frame. The cursor icon and the clipboard are
+/// handled by egui-winit when the cursor isn't grabbed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UiOutput {
+    /// Link clicked in the UI, for the event loop to open
+    pub open_url: Option<String>,
+    pub cursor_icon: egui::CursorIcon,
+    /// How long until the UI needs to be redrawn, `Duration::MAX` if it doesn't (the game redraws
+    /// every frame anyway, this matters to loops waiting for events)
+    pub repaint_after: Duration,
+}
+
+impl Default for UiOutput {
+    fn default() -> Self {
+        Self {
+            open_url: None,
+            cursor_icon: egui::CursorIcon::Default,
+            repaint_after: Duration::MAX,
+        }
+    }
+}
+
+impl UiOutput {
+    /// Take what the event loop acts on out of the output of egui, the rest goes to egui-winit
+    pub fn extract(output: &mut egui::FullOutput) -> Self {
+        Self {
+            open_url: output.platform_output.open_url.take().map(|open| open.url),
+            cursor_icon: output.platform_output.cursor_icon,
+            repaint_after: output.repaint_after,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn panels() {
+        let drawn = Arc::new(Mutex::new(Vec::new()));
+        let panel = |name: &'static str| {
+            let drawn = drawn.clone();
+            move |_: &egui::Context| drawn.lock().unwrap().push(name)
+        };
+        let mut draw = UiDraw::new();
+        draw.add_panel("a", panel("a"));
+        draw.add_panel("b", panel("b"));
+        draw.add_panel("c", panel("c"));
+        // Replaced in place
+        draw.add_panel("a", panel("A"));
+        assert!(draw.remove_panel("b"));
+        assert!(!draw.remove_panel("b"));
+        assert_eq!(vec!["a", "c"], draw.panels().collect::<Vec<_>>());
+
+        let ctx = egui::Context::default();
+        let _ = ctx.run(Default::default(), |ctx| draw.draw_panels(ctx));
+        assert_eq!(vec!["A", "c"], *drawn.lock().unwrap());
+    }
+
+    #[test]
+    fn frame_rate() {
+        let mut rate = FrameRate::default();
+        assert_eq!(0.0, rate.fps());
+        let start = Instant::now();
+        rate.frame(start);
+        assert_eq!(0.0, rate.fps());
+        rate.frame(start + Duration::from_millis(10));
+        assert!((rate.fps() - 100.0).abs() < 0.01);
+        // A slow frame only moves the average by a tenth of the difference
+        rate.add(Duration::from_millis(110));
+        assert!((rate.fps() - 50.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn output() {
+        let mut full = egui::FullOutput::default();
+        full.platform_output.open_url("https://example.com");
+        full.platform_output.cursor_icon = egui::CursorIcon::PointingHand;
+        let output = UiOutput::extract(&mut full);
+        assert_eq!(Some("https://example.com".to_owned()), output.open_url);
+        assert_eq!(egui::CursorIcon::PointingHand, output.cursor_icon);
+        assert!(full.platform_output.open_url.is_none());
+        assert_eq!(UiOutput::default().repaint_after, Duration::MAX);
+    }
+}
@@ -0,0 +1,168 @@
+//! Scale of the UI and the window metrics derived from it.
+//!
+//! The UI is drawn at the scale factor of the monitor the window is on, times a user setting.
+//! Both can change at any time (moving the window to another monitor, the settings slider), so
+//! the screen descriptor of egui and the center of the window are derived from the current state
+//! every frame rather than from the one at startup.
+
+use egui_wgpu::renderer::ScreenDescriptor;
+use winit::{
+    dpi::{PhysicalPosition, PhysicalSize},
+    event::WindowEvent,
+};
+
+/// Bounds of the user scale
+pub const USER_SCALE_MIN: f32 = 0.75;
+pub const USER_SCALE_MAX: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UiScale {
+    /// Inner size of the window
+    size: PhysicalSize<u32>,
+    /// Scale factor of the monitor
+    system: f32,
+    /// Scale chosen by the user, on top of the system one
+    user: f32,
+}
+
+impl UiScale {
+    pub fn new(size: PhysicalSize<u32>, system: f64) -> Self {
+        Self {
+            size,
+            system: system as f32,
+            user: 1.0,
+        }
+    }
+    /// Track the size and scale factor of the window
+    pub fn handle_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::Resized(size) => self.size = *size,
+            WindowEvent::ScaleFactorChanged {
+                scale_factor,
+                new_inner_size,
+            } => self.sync(**new_inner_size, *scale_factor),
+            _ => {}
+        }
+    }
+    /// Catch up with the window, in case an event was missed
+    pub fn sync(&mut self, size: PhysicalSize<u32>, system: f64) {
+        self.size = size;
+        if system.is_finite() && system > 0.0 {
+            self.system = system as f32;
+        }
+    }
+    pub fn size(&self) -> PhysicalSize<u32> {
+        self.size
+    }
+    pub fn system(&self) -> f32 {
+        self.system
+    }
+    pub fn user(&self) -> f32 {
+        self.user
+    }
+    /// Clamped between `USER_SCALE_MIN` and `USER_SCALE_MAX`, non finite scales are ignored
+    pub fn set_user(&mut self, user: f32) {
+        if user.is_finite() {
+            self.user = user.clamp(USER_SCALE_MIN, USER_SCALE_MAX);
+        }
+    }
+    /// The pixels per point of egui
+    pub fn pixels_per_point(&self) -> f32 {
+        self.system * self.user
+    }
+    pub fn screen_descriptor(&self) -> ScreenDescriptor {
+        ScreenDescriptor {
+            size_in_pixels: [self.size.width, self.size.height],
+            pixels_per_point: self.pixels_per_point(),
+        }
+    }
+    /// Center of the window, where the cursor is put when grabbed
+    pub fn center(&self) -> PhysicalPosition<f64> {
+        PhysicalPosition::new((self.size.width / 2) as f64, (self.size.height / 2) as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(width: u32, height: u32) -> PhysicalSize<u32> {
+        PhysicalSize::new(width, height)
+    }
+
+    #[test]
+    fn combined_scale() {
+        let mut scale = UiScale::new(size(1920, 1080), 1.5);
+        assert_eq!(1.5, scale.pixels_per_point());
+        scale.set_user(2.0);
+        assert_eq!(3.0, scale.pixels_per_point());
+        scale.set_user(0.8);
+        assert_eq!(1.2, scale.pixels_per_point());
+        scale.set_user(5.0);
+        assert_eq!(USER_SCALE_MAX, scale.user());
+        scale.set_user(0.1);
+        assert_eq!(USER_SCALE_MIN, scale.user());
+        scale.set_user(f32::NAN);
+        assert_eq!(USER_SCALE_MIN, scale.user());
+        // Bogus factors from the platform are ignored too
+        scale.sync(size(1920, 1080), 0.0);
+        assert_eq!(1.5, scale.system());
+    }
+
+    #[test]
+    fn screen_descriptor() {
+        let mut scale = UiScale::new(size(1280, 720), 1.0);
+        scale.set_user(1.25);
+        let desc = scale.screen_descriptor();
+        assert_eq!([1280, 720], desc.size_in_pixels);
+        assert_eq!(1.25, desc.pixels_per_point);
+        scale.sync(size(2560, 1440), 2.0);
+        let desc = scale.screen_descriptor();
+        assert_eq!([2560, 1440], desc.size_in_pixels);
+        assert_eq!(2.5, desc.pixels_per_point);
+    }
+
+    #[test]
+    fn recenter() {
+        for (width, height, factor) in [(800, 600, 1.0), (1921, 1081, 1.25), (3840, 2160, 2.0)] {
+            let scale = UiScale::new(size(width, height), factor);
+            let center = scale.center();
+            assert_eq!((width / 2) as f64, center.x);
+            assert_eq!((height / 2) as f64, center.y);
+        }
+    }
+
+    #[test]
+    fn monitor_change() {
+        let mut scale = UiScale::new(size(1600, 900), 1.0);
+        scale.set_user(1.5);
+        // Dragged to a HiDPI monitor: the platform doubles the size, then confirms it
+        let mut doubled = size(3200, 1800);
+        scale.handle_event(&WindowEvent::ScaleFactorChanged {
+            scale_factor: 2.0,
+            new_inner_size: &mut doubled,
+        });
+        scale.handle_event(&WindowEvent::Resized(size(3200, 1800)));
+        assert_eq!(3.0, scale.pixels_per_point());
+        assert_eq!(PhysicalPosition::new(1600.0, 900.0), scale.center());
+        // Resized by the user while there, then dragged back
+        scale.handle_event(&WindowEvent::Resized(size(2400, 1400)));
+        let mut halved = size(1200, 700);
+        scale.handle_event(&WindowEvent::ScaleFactorChanged {
+            scale_factor: 1.0,
+            new_inner_size: &mut halved,
+        });
+        scale.handle_event(&WindowEvent::Resized(size(1200, 700)));
+        scale.handle_event(&WindowEvent::Focused(true));
+
+        let mut expected = UiScale::new(size(1200, 700), 1.0);
+        expected.set_user(1.5);
+        assert_eq!(expected, scale);
+        let desc = scale.screen_descriptor();
+        assert_eq!([1200, 700], desc.size_in_pixels);
+        assert_eq!(1.5, desc.pixels_per_point);
+        assert_eq!(PhysicalPosition::new(600.0, 350.0), scale.center());
+        // The egui screen in points
+        assert_eq!(800.0, 1200.0 / desc.pixels_per_point);
+    }
+}
@@ -0,0 +1,868 @@
+//! Visual regression tests: every case of `cases` is rendered on the fallback adapter and compared
+//! to its golden image, `sg/tests/visual/<golden>.png`.
+//!
+//! To add a case, push a `Case` to `cases` with a unique name: the scene closure spawns the
+//! entities (meshes, materials and lights) in a new world, `features` sets the passes of the
+//! renderer. Cases that must render identically to another one (a pass that shouldn't change the
+//! image) share its golden. Then run `UPDATE_GOLDENS=1 cargo test -p sg visual` to write the golden,
+//! look at it, and check it in.
+//!
+//! When a case doesn't match, the actual, expected and difference images are written to
+//! `target/visual_failures/`. A case without a golden fails like a mismatch, unless the goldens are
+//! being updated.
+//!
+//! The suite needs a fallback adapter that can run the renderer, it is ignored by default and run
+//! with `cargo test -p sg visual -- --ignored`, where it fails if there is no such adapter.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use ecs::{Entities, Entity, Executor, World};
+use glam::{Mat4, Quat, Vec3, Vec4};
+
+use crate::components::{GraphicsComponent, LightComponent, TransformsComponent};
+
+use super::{
+    bloom::BloomSettings,
+    g_buffer::{DebugView, SurfaceWeather},
+    mesh_manager::{Mesh, Primitives},
+    renderer::WorldRenderer,
+    texture_manager::SingleValue,
+    tonemap::Tonemapper,
+    AlphaMode, DiretionalLight, GraphicContext, Light, Material, PointLight, SpotLight,
+};
+
+/// 8 bits RGBA pixels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, pixels: Vec<u8>) -> Self {
+        assert_eq!((width * height * 4) as usize, pixels.len());
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+    pub fn load(path: &Path) -> Result<Self> {
+        let image = image::open(path)
+            .with_context(|| format!("Couldn't read {}", path.display()))?
+            .to_rgba8();
+        Ok(Self::new(image.width(), image.height(), image.into_raw()))
+    }
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Couldn't create {}", parent.display()))?;
+        }
+        image::save_buffer(
+            path,
+            &self.pixels,
+            self.width,
+            self.height,
+            image::ColorType::Rgba8,
+        )
+        .with_context(|| format!("Couldn't write {}", path.display()))
+    }
+    fn pixels(&self) -> impl Iterator<Item = &[u8]> {
+        self.pixels.chunks_exact(4)
+    }
+    /// Rec. 601 luma of a pixel
+    fn luma(&self, x: u32, y: u32) -> f32 {
+        let i = ((y * self.width + x) * 4) as usize;
+        let [r, g, b] = [0, 1, 2].map(|c| self.pixels[i + c] as f32);
+        0.299 * r + 0.587 * g + 0.114 * b
+    }
+}
+
+/// Largest difference of a channel between two images of the same size
+pub fn max_channel_delta(a: &Image, b: &Image) -> u8 {
+    a.pixels
+        .iter()
+        .zip(&b.pixels)
+        .map(|(a, b)| a.abs_diff(*b))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Pixels that have a channel differing by more than `threshold`
+pub fn differing_pixels(a: &Image, b: &Image, threshold: u8) -> usize {
+    a.pixels()
+        .zip(b.pixels())
+        .filter(|(a, b)| a.iter().zip(*b).any(|(a, b)| a.abs_diff(*b) > threshold))
+        .count()
+}
+
+/// Mean SSIM of the luma over blocks of `block` pixels (the last ones are cropped at the edges):
+/// 1 when the images are identical, lower as the structure of the blocks differs
+pub fn block_ssim(a: &Image, b: &Image, block: u32) -> f32 {
+    const C1: f32 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f32 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let mut total = 0.0;
+    let mut blocks = 0;
+    for by in (0..a.height).step_by(block as usize) {
+        for bx in (0..a.width).step_by(block as usize) {
+            let coords = (by..(by + block).min(a.height))
+                .flat_map(|y| (bx..(bx + block).min(a.width)).map(move |x| (x, y)));
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab, mut n) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+            for (x, y) in coords {
+                let (la, lb) = (a.luma(x, y), b.luma(x, y));
+                sa += la;
+                sb += lb;
+                saa += la * la;
+                sbb += lb * lb;
+                sab += la * lb;
+                n += 1.0;
+            }
+            let (ma, mb) = (sa / n, sb / n);
+            let (va, vb) = (saa / n - ma * ma, sbb / n - mb * mb);
+            let cov = sab / n - ma * mb;
+            total += ((2.0 * ma * mb + C1) * (2.0 * cov + C2))
+                / ((ma * ma + mb * mb + C1) * (va + vb + C2));
+            blocks += 1;
+        }
+    }
+    if blocks == 0 {
+        1.0
+    } else {
+        total / blocks as f32
+    }
+}
+
+/// Absolute difference of the channels, amplified to be visible, with an opaque alpha
+pub fn diff_image(a: &Image, b: &Image) -> Image {
+    let pixels = a
+        .pixels()
+        .zip(b.pixels())
+        .flat_map(|(a, b)| {
+            let [r, g, bl] = [0, 1, 2].map(|c| a[c].abs_diff(b[c]).saturating_mul(8));
+            [r, g, bl, 255]
+        })
+        .collect();
+    Image::new(a.width, a.height, pixels)
+}
+
+/// How close to its golden a render has to be
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tolerance {
+    /// Every byte the same, for the passes that are deterministic
+    Exact,
+    /// For the passes that can vary a bit between devices
+    Perceptual {
+        /// Channel difference a pixel can have without counting as different
+        channel_delta: u8,
+        /// Fraction of the pixels that can be different
+        outliers: f32,
+        /// Lowest `block_ssim` over 8x8 blocks
+        ssim: f32,
+    },
+}
+
+impl Tolerance {
+    pub const PERCEPTUAL: Self = Self::Perceptual {
+        channel_delta: 8,
+        outliers: 0.005,
+        ssim: 0.98,
+    };
+
+    /// Why `actual` doesn't match `expected`, if it doesn't
+    pub fn compare(self, expected: &Image, actual: &Image) -> Option<String> {
+        if (expected.width, expected.height) != (actual.width, actual.height) {
+            return Some(format!(
+                "the size is {}x{}, expected {}x{}",
+                actual.width, actual.height, expected.width, expected.height
+            ));
+        }
+        match self {
+            Self::Exact => (expected != actual).then(|| {
+                format!(
+                    "{} pixels differ, by up to {}",
+                    differing_pixels(expected, actual, 0),
+                    max_channel_delta(expected, actual)
+                )
+            }),
+            Self::Perceptual {
+                channel_delta,
+                outliers,
+                ssim,
+            } => {
+                let differing = differing_pixels(expected, actual, channel_delta);
+                let allowed = (outliers * (actual.width * actual.height) as f32) as usize;
+                let score = block_ssim(expected, actual, 8);
+                (differing > allowed || score < ssim).then(|| {
+                    format!(
+                        "{differing} pixels differ by more than {channel_delta} (at most \
+                         {allowed}), SSIM {score:.4} (at least {ssim})"
+                    )
+                })
+            }
+        }
+    }
+}
+
+/// Passes of the renderer a case uses, everything else is off
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Features {
+    pub fog: bool,
+    pub ssr: bool,
+    pub ssao: bool,
+    pub bloom: bool,
+    pub occlusion_culling: bool,
+    pub weather: SurfaceWeather,
+    pub tonemapper: Tonemapper,
+    pub debug_view: DebugView,
+}
+
+impl Features {
+    fn apply(&self, wr: &mut WorldRenderer) {
+        wr.fog.settings.enabled = self.fog;
+        wr.ssr.settings.enabled = self.ssr;
+        wr.ssao = self.ssao;
+        wr.bloom.settings = BloomSettings {
+            enabled: self.bloom,
+            ..BloomSettings::default()
+        };
+        wr.occlusion_culling = self.occlusion_culling;
+        wr.surface_weather = self.weather;
+        wr.tonemapper = self.tonemapper;
+        wr.debug_view = self.debug_view;
+    }
+}
+
+type Scene = fn(&mut World, &mut GraphicContext) -> Result<()>;
+
+pub struct Case {
+    pub name: &'static str,
+    /// File name (without extension) of the golden, the name of the case unless it is shared
+    pub golden: &'static str,
+    pub scene: Scene,
+    pub eye: Vec3,
+    pub target: Vec3,
+    pub size: (u32, u32),
+    pub features: Features,
+    /// Frames rendered before the one compared, for the passes that use the previous frame
+    pub frames: u32,
+    pub tolerance: Tolerance,
+}
+
+impl Case {
+    fn new(name: &'static str, scene: Scene) -> Self {
+        Self {
+            name,
+            golden: name,
+            scene,
+            eye: Vec3::new(0.0, 3.0, -8.0),
+            target: Vec3::ZERO,
+            size: (320, 180),
+            features: Features::default(),
+            frames: 1,
+            tolerance: Tolerance::Exact,
+        }
+    }
+    fn eye(self, eye: Vec3) -> Self {
+        Self { eye, ..self }
+    }
+    fn golden(self, golden: &'static str) -> Self {
+        Self { golden, ..self }
+    }
+    fn features(self, features: Features) -> Self {
+        Self { features, ..self }
+    }
+    fn frames(self, frames: u32) -> Self {
+        Self { frames, ..self }
+    }
+    fn tolerance(self, tolerance: Tolerance) -> Self {
+        Self { tolerance, ..self }
+    }
+
+    /// Render the last frame of the case
+    fn render(&self, gfx: GraphicContext) -> Result<Image> {
+        let mut world = World::new();
+        let mut exe = Executor::new();
+        exe.add_resource(gfx);
+        let gfx = exe.get_resource_mut::<GraphicContext>().unwrap();
+        (self.scene)(&mut world, gfx)?;
+
+        let mut wr = WorldRenderer::new(gfx);
+        // The camera looks down +z without rotation
+        let look = Mat4::look_at_lh(self.eye, self.target, Vec3::Y);
+        wr.camera.set_position(self.eye);
+        wr.camera.set_rotation(Quat::from_mat4(&look).inverse());
+        self.features.apply(&mut wr);
+        exe.add_resource(wr);
+        exe.add_resource(None::<Result<Vec<u8>>>);
+
+        exe.run_once(&mut world, WorldRenderer::update_lights);
+        for _ in 0..self.frames {
+            exe.run_once(
+                &mut world,
+                |gfx: &mut GraphicContext,
+                 wr: &mut WorldRenderer,
+                 frame: &mut Option<Result<Vec<u8>>>,
+                 renderables: Entities<(
+                    Entity,
+                    &GraphicsComponent,
+                    Option<&TransformsComponent>,
+                )>| {
+                    *frame = Some(gfx.render_offscreen(wr, renderables));
+                },
+            );
+        }
+        let pixels = exe
+            .get_resource_mut::<Option<Result<Vec<u8>>>>()
+            .unwrap()
+            .take()
+            .context("No frame rendered")??;
+        Ok(Image::new(self.size.0, self.size.1, pixels))
+    }
+}
+
+fn material(
+    gfx: &mut GraphicContext,
+    color: Vec4,
+    metallic: f32,
+    roughness: f32,
+) -> Result<Material> {
+    let albedo = gfx.texture_manager.get_or_add_single_value_texture(
+        &gfx.device,
+        &gfx.queue,
+        SingleValue::Color(color),
+    );
+    Material::new_with_values(albedo, None, metallic, roughness, None, gfx)
+}
+
+fn transforms(position: Vec3, scale: Vec3) -> TransformsComponent {
+    let mut tsm = TransformsComponent::new();
+    tsm.set_translation(position).set_scale(scale);
+    tsm
+}
+
+fn sun() -> LightComponent {
+    let direction = Vec3::new(0.4, -1.0, 0.6).normalize();
+    LightComponent::new(Light::Directional(DiretionalLight::new(
+        direction,
+        Vec4::splat(1.0),
+    )))
+}
+
+/// A flat box under the origin
+fn ground(
+    world: &mut World,
+    gfx: &mut GraphicContext,
+    metallic: f32,
+    roughness: f32,
+) -> Result<()> {
+    let cube = gfx.mesh_manager.add(&gfx.device, &Mesh::new_cube());
+    let material = material(gfx, Vec4::new(0.6, 0.6, 0.6, 1.0), metallic, roughness)?;
+    world.spawn((
+        GraphicsComponent {
+            mesh: cube,
+            material,
+        },
+        transforms(Vec3::new(0.0, -1.1, 0.0), Vec3::new(12.0, 0.2, 12.0)),
+    ));
+    Ok(())
+}
+
+/// Three spheres on a ground, lit by nothing
+fn spheres(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    let sphere = gfx.mesh_manager.add(&gfx.device, &Mesh::new_icosphere(3));
+    let colors = [
+        Vec4::new(0.8, 0.2, 0.2, 1.0),
+        Vec4::new(0.2, 0.8, 0.2, 1.0),
+        Vec4::new(0.2, 0.2, 0.8, 1.0),
+    ];
+    for (i, color) in colors.into_iter().enumerate() {
+        let material = material(gfx, color, 0.0, 0.5)?;
+        world.spawn((
+            GraphicsComponent {
+                mesh: sphere,
+                material,
+            },
+            transforms(Vec3::new(i as f32 * 2.5 - 2.5, 0.0, 0.0), Vec3::ONE),
+        ));
+    }
+    ground(world, gfx, 0.0, 0.9)
+}
+
+/// Metallic from 0 to 1 along x, roughness from 0 to 1 along y
+fn pbr_grid(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    let sphere = gfx.mesh_manager.add(&gfx.device, &Mesh::new_icosphere(3));
+    for y in 0..5 {
+        for x in 0..5 {
+            let material = material(
+                gfx,
+                Vec4::new(0.9, 0.6, 0.3, 1.0),
+                x as f32 / 4.0,
+                y as f32 / 4.0,
+            )?;
+            world.spawn((
+                GraphicsComponent {
+                    mesh: sphere,
+                    material,
+                },
+                transforms(
+                    Vec3::new(x as f32 - 2.0, y as f32 - 2.0, 0.0),
+                    Vec3::splat(0.45),
+                ),
+            ));
+        }
+    }
+    world.spawn((sun(),));
+    Ok(())
+}
+
+fn directional(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    world.spawn((sun(),));
+    spheres(world, gfx)
+}
+
+fn points(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    let lights = [
+        (Vec3::new(-2.5, 1.5, -1.5), Vec4::new(4.0, 0.5, 0.5, 1.0)),
+        (Vec3::new(0.0, 1.5, -1.5), Vec4::new(0.5, 4.0, 0.5, 1.0)),
+        (Vec3::new(2.5, 1.5, -1.5), Vec4::new(0.5, 0.5, 4.0, 1.0)),
+    ];
+    for (position, color) in lights {
+        world.spawn((LightComponent::new(Light::Point(PointLight::new(
+            position, color,
+        ))),));
+    }
+    spheres(world, gfx)
+}
+
+fn spot(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    let light = SpotLight::new(
+        Vec3::new(0.0, 4.0, -2.0),
+        Vec3::new(0.0, -1.0, 0.4).normalize(),
+        0.3,
+        Vec4::splat(6.0),
+    );
+    world.spawn((LightComponent::new(Light::Spot(light)),));
+    spheres(world, gfx)
+}
+
+/// More point lights than the initial limit of the shading pipeline
+fn many_lights(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    for i in 0..100 {
+        let angle = i as f32 / 100.0 * std::f32::consts::TAU;
+        let position = Vec3::new(angle.cos() * 5.0, 0.5, angle.sin() * 5.0);
+        let color = Vec4::new(angle.cos().abs(), 0.3, angle.sin().abs(), 1.0) * 0.3;
+        world.spawn((LightComponent::new(Light::Point(PointLight::new(
+            position, color,
+        ))),));
+    }
+    spheres(world, gfx)
+}
+
+/// Spheres over a mirror
+fn mirror(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    let sphere = gfx.mesh_manager.add(&gfx.device, &Mesh::new_icosphere(3));
+    let material = material(gfx, Vec4::new(0.8, 0.3, 0.1, 1.0), 0.0, 0.5)?;
+    for x in [-2.0, 2.0] {
+        world.spawn((
+            GraphicsComponent {
+                mesh: sphere,
+                material,
+            },
+            transforms(Vec3::new(x, 0.0, 1.0), Vec3::ONE),
+        ));
+    }
+    world.spawn((sun(),));
+    ground(world, gfx, 1.0, 0.05)
+}
+
+/// A leaf cut out of a square by its alpha (with a hole), in front of a sphere
+fn alpha_mask(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    let leaf = image::RgbaImage::from_fn(64, 64, |x, y| {
+        let (u, v) = (x as f32 / 32.0 - 1.0, y as f32 / 32.0 - 1.0);
+        let inside = u * u + 4.0 * v * v < 0.9 && u * u + v * v > 0.04;
+        image::Rgba([40, 160, 30, if inside { 255 } else { 0 }])
+    });
+    let albedo = gfx.texture_manager.add_image_texture(
+        &gfx.device,
+        &gfx.queue,
+        image::DynamicImage::ImageRgba8(leaf),
+    );
+    let metallic = gfx.texture_manager.get_or_add_single_value_texture(
+        &gfx.device,
+        &gfx.queue,
+        SingleValue::Factor(0.0),
+    );
+    let roughness = gfx.texture_manager.get_or_add_single_value_texture(
+        &gfx.device,
+        &gfx.queue,
+        SingleValue::Factor(0.8),
+    );
+    let mut material = Material::new(albedo, None, metallic, roughness, None, gfx)?;
+    material.alpha_mode = AlphaMode::Mask(0.5);
+    material.double_sided = true;
+    let cube = gfx.mesh_manager.add(&gfx.device, &Mesh::new_cube());
+    world.spawn((
+        GraphicsComponent {
+            mesh: cube,
+            material,
+        },
+        transforms(Vec3::new(0.0, 0.5, -2.0), Vec3::new(2.0, 2.0, 0.01)),
+    ));
+    let sphere = gfx.mesh_manager.add(&gfx.device, &Mesh::new_icosphere(3));
+    let material = self::material(gfx, Vec4::new(0.8, 0.2, 0.2, 1.0), 0.0, 0.5)?;
+    world.spawn((
+        GraphicsComponent {
+            mesh: sphere,
+            material,
+        },
+        transforms(Vec3::ZERO, Vec3::ONE),
+    ));
+    world.spawn((sun(),));
+    ground(world, gfx, 0.0, 0.9)
+}
+
+/// Spheres behind two overlapping glass panes, and a glowing one
+fn alpha_blend(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    let cube = gfx.mesh_manager.add(&gfx.device, &Mesh::new_cube());
+    let panes = [
+        (Vec3::new(-1.0, 0.5, -2.0), Vec4::new(0.2, 0.4, 0.9, 0.4)),
+        (Vec3::new(1.0, 0.5, -3.0), Vec4::new(0.9, 0.8, 0.2, 0.5)),
+    ];
+    for (position, color) in panes {
+        let mut material = material(gfx, color, 0.0, 0.1)?;
+        material.alpha_mode = AlphaMode::Blend;
+        world.spawn((
+            GraphicsComponent {
+                mesh: cube,
+                material,
+            },
+            transforms(position, Vec3::new(2.0, 1.5, 0.02)),
+        ));
+    }
+    let sphere = gfx.mesh_manager.add(&gfx.device, &Mesh::new_icosphere(3));
+    let mut material = material(gfx, Vec4::new(0.1, 0.1, 0.1, 1.0), 0.0, 0.5)?;
+    material.emissive = Vec3::new(1.0, 0.6, 0.2);
+    world.spawn((
+        GraphicsComponent {
+            mesh: sphere,
+            material,
+        },
+        transforms(Vec3::new(0.0, 2.0, 1.0), Vec3::splat(0.5)),
+    ));
+    directional(world, gfx)
+}
+
+/// Spheres glowing brighter and brighter, on a ground
+fn glowing(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    let sphere = gfx.mesh_manager.add(&gfx.device, &Mesh::new_icosphere(3));
+    for (i, glow) in [0.5, 2.0, 8.0].into_iter().enumerate() {
+        let mut material = material(gfx, Vec4::new(0.1, 0.1, 0.1, 1.0), 0.0, 0.5)?;
+        material.emissive = Vec3::new(1.0, 0.5, 0.2) * glow;
+        world.spawn((
+            GraphicsComponent {
+                mesh: sphere,
+                material,
+            },
+            transforms(Vec3::new(i as f32 * 2.5 - 2.5, 0.0, 0.0), Vec3::ONE),
+        ));
+    }
+    ground(world, gfx, 0.0, 0.9)
+}
+
+/// Spheres in the corner of two walls, for the ambient occlusion
+fn corner(world: &mut World, gfx: &mut GraphicContext) -> Result<()> {
+    let cube = gfx.mesh_manager.add(&gfx.device, &Mesh::new_cube());
+    let walls = [
+        (Vec3::new(0.0, 1.0, 2.0), Vec3::new(8.0, 4.0, 0.2)),
+        (Vec3::new(3.0, 1.0, 0.0), Vec3::new(0.2, 4.0, 8.0)),
+    ];
+    for (position, scale) in walls {
+        let material = material(gfx, Vec4::new(0.7, 0.7, 0.7, 1.0), 0.0, 0.9)?;
+        world.spawn((
+            GraphicsComponent {
+                mesh: cube,
+                material,
+            },
+            transforms(position, scale),
+        ));
+    }
+    world.spawn((sun(),));
+    spheres(world, gfx)
+}
+
+pub fn cases() -> Vec<Case> {
+    let wet = SurfaceWeather {
+        wetness: 1.0,
+        puddles: 0.6,
+        ..SurfaceWeather::default()
+    };
+    vec![
+        Case::new("pbr_grid", pbr_grid).eye(Vec3::new(0.0, 0.0, -7.0)),
+        // Nothing is hidden on the first frame, culling mustn't change the image
+        Case::new("pbr_grid_culled", pbr_grid)
+            .eye(Vec3::new(0.0, 0.0, -7.0))
+            .golden("pbr_grid")
+            .features(Features {
+                occlusion_culling: true,
+                ..Features::default()
+            }),
+        Case::new("light_directional", directional),
+        Case::new("light_point", points),
+        Case::new("light_spot", spot),
+        Case::new("light_many", many_lights),
+        Case::new("fog", directional)
+            .features(Features {
+                fog: true,
+                ..Features::default()
+            })
+            .tolerance(Tolerance::PERCEPTUAL),
+        // The second frame has the history of the first to reflect
+        Case::new("ssr", mirror)
+            .features(Features {
+                ssr: true,
+                ..Features::default()
+            })
+            .frames(2)
+            .tolerance(Tolerance::PERCEPTUAL),
+        Case::new("wet", directional)
+            .features(Features {
+                weather: wet,
+                ..Features::default()
+            })
+            .tolerance(Tolerance::PERCEPTUAL),
+        Case::new("exposure_low", directional).features(Features {
+            weather: SurfaceWeather {
+                exposure: 0.25,
+                ..SurfaceWeather::default()
+            },
+            ..Features::default()
+        }),
+        Case::new("exposure_high", directional).features(Features {
+            weather: SurfaceWeather {
+                exposure: 4.0,
+                ..SurfaceWeather::default()
+            },
+            ..Features::default()
+        }),
+        Case::new("alpha_mask", alpha_mask),
+        Case::new("alpha_blend", alpha_blend),
+        Case::new("gbuffer_albedo", directional).features(debug(DebugView::Albedo)),
+        Case::new("gbuffer_normal", directional).features(debug(DebugView::Normal)),
+        Case::new("gbuffer_material", pbr_grid)
+            .eye(Vec3::new(0.0, 0.0, -7.0))
+            .features(debug(DebugView::Material)),
+        Case::new("gbuffer_emissive", glowing).features(debug(DebugView::Emissive)),
+        Case::new("gbuffer_depth", corner).features(debug(DebugView::Depth)),
+        Case::new("ssao", corner)
+            .features(Features {
+                ssao: true,
+                ..Features::default()
+            })
+            .tolerance(Tolerance::PERCEPTUAL),
+        // The occlusion alone, in the blue channel
+        Case::new("ssao_material", corner)
+            .features(Features {
+                ssao: true,
+                ..debug(DebugView::Material)
+            })
+            .tolerance(Tolerance::PERCEPTUAL),
+        Case::new("bloom", glowing)
+            .features(Features {
+                bloom: true,
+                ..Features::default()
+            })
+            .tolerance(Tolerance::PERCEPTUAL),
+        // The glowing spheres go past 1, where the operators differ the most
+        Case::new("tonemap_aces", glowing).features(tonemapper(Tonemapper::Aces)),
+        Case::new("tonemap_reinhard", glowing).features(tonemapper(Tonemapper::Reinhard)),
+        Case::new("tonemap_linear", glowing).features(tonemapper(Tonemapper::Linear)),
+    ]
+}
+
+fn debug(debug_view: DebugView) -> Features {
+    Features {
+        debug_view,
+        ..Features::default()
+    }
+}
+
+fn tonemapper(tonemapper: Tonemapper) -> Features {
+    Features {
+        tonemapper,
+        ..Features::default()
+    }
+}
+
+fn golden_path(golden: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/visual")
+        .join(golden)
+        .with_extension("png")
+}
+
+fn failures_directory() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../target/visual_failures")
+}
+
+mod tests {
+    use super::*;
+
+    /// Deterministic noise
+    fn noise(width: u32, height: u32, seed: u32) -> Image {
+        let pixels = (0..width * height * 4)
+            .map(|i| {
+                if i % 4 == 3 {
+                    return 255;
+                }
+                let mut x = i.wrapping_mul(0x9e3779b9) ^ seed;
+                x ^= x >> 16;
+                x = x.wrapping_mul(0x85ebca6b);
+                (x >> 24) as u8
+            })
+            .collect();
+        Image::new(width, height, pixels)
+    }
+
+    fn gradient(width: u32, height: u32) -> Image {
+        let pixels = (0..height)
+            .flat_map(|_| {
+                (0..width).flat_map(move |x| [(x * 255 / width) as u8; 3].into_iter().chain([255]))
+            })
+            .collect();
+        Image::new(width, height, pixels)
+    }
+
+    #[test]
+    fn metrics() {
+        let a = gradient(32, 16);
+        assert_eq!(0, max_channel_delta(&a, &a));
+        assert_eq!(0, differing_pixels(&a, &a, 0));
+        assert!((block_ssim(&a, &a, 8) - 1.0).abs() < 1e-4);
+
+        let mut b = a.clone();
+        b.pixels[0] = b.pixels[0].wrapping_add(3);
+        b.pixels[4 * 40 + 2] = b.pixels[4 * 40 + 2].wrapping_add(20);
+        assert_eq!(20, max_channel_delta(&a, &b));
+        assert_eq!(2, differing_pixels(&a, &b, 0));
+        assert_eq!(1, differing_pixels(&a, &b, 3));
+        assert!(block_ssim(&a, &b, 8) > 0.98);
+
+        // Same brightness on average, but nothing in common
+        let (c, d) = (noise(32, 16, 1), noise(32, 16, 2));
+        assert!(block_ssim(&c, &d, 8) < 0.2);
+        assert!(block_ssim(&a, &c, 8) < 0.2);
+        // Partial blocks at the edges count too
+        assert!((block_ssim(&c, &c, 5) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn diff() {
+        let a = Image::new(2, 1, vec![10, 20, 30, 255, 0, 0, 0, 0]);
+        let b = Image::new(2, 1, vec![12, 20, 0, 0, 0, 100, 0, 0]);
+        assert_eq!(
+            vec![16, 0, 240, 255, 0, 255, 0, 255],
+            diff_image(&a, &b).pixels
+        );
+    }
+
+    #[test]
+    fn tolerance() {
+        let a = gradient(64, 32);
+        assert_eq!(None, Tolerance::Exact.compare(&a, &a));
+        assert_eq!(None, Tolerance::PERCEPTUAL.compare(&a, &a));
+
+        // A few slightly off pixels
+        let mut b = a.clone();
+        for i in 0..20 {
+            b.pixels[i * 40] = b.pixels[i * 40].saturating_add(2);
+        }
+        b.pixels[100] = b.pixels[100].saturating_add(30);
+        assert!(Tolerance::Exact.compare(&a, &b).is_some());
+        assert_eq!(None, Tolerance::PERCEPTUAL.compare(&a, &b));
+
+        // Too many outliers
+        let mut c = a.clone();
+        for pixel in c.pixels.chunks_exact_mut(4).step_by(50) {
+            pixel[1] = pixel[1].wrapping_add(128);
+        }
+        assert!(Tolerance::PERCEPTUAL.compare(&a, &c).is_some());
+
+        // Structure lost, with every pixel within the delta
+        let d = Image::new(
+            64,
+            32,
+            a.pixels
+                .iter()
+                .enumerate()
+                .map(|(i, v)| {
+                    if i % 4 == 3 {
+                        255
+                    } else if (i / 4) % 2 == 0 {
+                        v.saturating_add(8)
+                    } else {
+                        v.saturating_sub(8)
+                    }
+                })
+                .collect(),
+        );
+        let strict = Tolerance::Perceptual {
+            channel_delta: 8,
+            outliers: 0.0,
+            ssim: 0.999,
+        };
+        assert!(strict.compare(&a, &d).is_some());
+
+        assert!(Tolerance::PERCEPTUAL
+            .compare(&a, &gradient(32, 32))
+            .is_some());
+    }
+
+    #[test]
+    #[ignore = "needs a fallback adapter that can run the renderer"]
+    fn goldens() {
+        let update = std::env::var_os("UPDATE_GOLDENS").is_some_and(|v| v == "1");
+        let mut failures = Vec::new();
+        for case in cases() {
+            let gfx = pollster::block_on(GraphicContext::headless(case.size.0, case.size.1))
+                .expect("No fallback adapter that can run the renderer");
+            let actual = case
+                .render(gfx)
+                .unwrap_or_else(|e| panic!("Couldn't render {}: {e:#}", case.name));
+            let golden = golden_path(case.golden);
+            let failed = failures_directory().join(case.name);
+            if update && case.golden == case.name {
+                actual.save(&golden).unwrap();
+                continue;
+            }
+            if !golden.exists() {
+                actual.save(&failed.join("actual.png")).unwrap();
+                failures.push(format!(
+                    "{}: no golden at {}, run with UPDATE_GOLDENS=1 to write it",
+                    case.name,
+                    golden.display()
+                ));
+                continue;
+            }
+            let expected = Image::load(&golden).unwrap();
+            if let Some(reason) = case.tolerance.compare(&expected, &actual) {
+                actual.save(&failed.join("actual.png")).unwrap();
+                expected.save(&failed.join("expected.png")).unwrap();
+                if (expected.width, expected.height) == (actual.width, actual.height) {
+                    diff_image(&expected, &actual)
+                        .save(&failed.join("diff.png"))
+                        .unwrap();
+                }
+                failures.push(format!("{}: {reason}", case.name));
+            }
+        }
+        assert!(
+            failures.is_empty(),
+            "Renders differ from their golden (see {}):\n{}",
+            failures_directory().display(),
+            failures.join("\n")
+        );
+    }
+}
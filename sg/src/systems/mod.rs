@@ -1 +1,12 @@
+pub mod ambience;
+pub mod animation;
+pub mod audio;
+pub mod character;
+pub mod collision;
+pub mod footsteps;
 pub mod graphics;
+pub mod navmesh;
+pub mod path;
+pub mod rng;
+pub mod time;
+pub mod weather;
@@ -0,0 +1,1142 @@
+//! Navigation on the static geometry of the scene.
+//!
+//! The triangles of every entity with a `StaticGeometryComponent` (shared with the collisions) are
+//! rasterized into a heightfield
+//! (columns of solid spans on a grid), the top of a span is walkable if its slope is gentle
+//! enough and there is room above it for an agent. Walkable surfaces become the nodes of the
+//! navmesh, linked to the surfaces of the neighbouring columns that can be stepped on, and nodes
+//! closer to an edge than the agent radius are removed. Paths are found with A* on the nodes then
+//! shortened by skipping the waypoints in line of sight of each other.
+
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, VecDeque},
+    io::{Cursor, Read},
+};
+
+use anyhow::{bail, Context, Result};
+use ecs::{Entities, EventReader};
+use glam::Vec3;
+use rmanage::{Resource, ResourceManager};
+
+use crate::{
+    components::{StaticGeometryComponent, TransformsComponent, VelocityComponent},
+    save::GameLoaded,
+};
+
+use super::{graphics::debug_draw::DebugDraw, time::Time};
+
+/// Link of a node without a neighbour in that direction
+const NO_LINK: u32 = u32::MAX;
+/// Cell offset of the links of a node: +x, +z, -x, -z then the diagonals. The diagonal `i` is
+/// between the orthogonal directions `i - 4` and `i - 3`.
+const DIRECTIONS: [(i32, i32); 8] = [
+    (1, 0),
+    (0, 1),
+    (-1, 0),
+    (0, -1),
+    (1, 1),
+    (-1, 1),
+    (-1, -1),
+    (1, -1),
+];
+const MAGIC: &[u8; 4] = b"NAVM";
+/// Colors of the links of the navmesh and of the agents' paths, see `draw_navigation`
+const NAVMESH_COLOR: [f32; 4] = [0.2, 0.9, 0.4, 1.0];
+const AGENT_PATH_COLOR: [f32; 4] = [1.0, 0.8, 0.1, 1.0];
+const VERSION: u32 = 1;
+
+fn direction(dx: i32, dz: i32) -> usize {
+    DIRECTIONS
+        .iter()
+        .position(|&d| d == (dx, dz))
+        .expect("Not a neighbour")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavSettings {
+    /// Size of the cells of the grid on the XZ plane
+    pub cell_size: f32,
+    /// Vertical resolution of the heightfield
+    pub cell_height: f32,
+    /// Steepest walkable slope, in radians
+    pub max_slope: f32,
+    /// Highest step an agent can climb
+    pub max_climb: f32,
+    pub agent_height: f32,
+    pub agent_radius: f32,
+}
+
+impl Default for NavSettings {
+    fn default() -> Self {
+        Self {
+            cell_size: 0.25,
+            cell_height: 0.1,
+            max_slope: 45f32.to_radians(),
+            max_climb: 0.3,
+            agent_height: 1.8,
+            agent_radius: 0.4,
+        }
+    }
+}
+
+impl NavSettings {
+    /// Name of the relation from a scene to its cached navmesh, different settings give a
+    /// different navmesh
+    fn relation(&self) -> String {
+        let fields = [
+            self.cell_size,
+            self.cell_height,
+            self.max_slope,
+            self.max_climb,
+            self.agent_height,
+            self.agent_radius,
+        ];
+        let bits: Vec<String> = fields
+            .iter()
+            .map(|f| format!("{:08x}", f.to_bits()))
+            .collect();
+        format!("navmesh-{}", bits.join(""))
+    }
+}
+
+/// A solid vertical range of a column, in units of cell height
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+    min: i32,
+    max: i32,
+    /// Whether the top of the span is walkable
+    walkable: bool,
+}
+
+/// Grid of columns of solid spans, sorted from bottom to top
+struct Heightfield {
+    origin: Vec3,
+    width: usize,
+    depth: usize,
+    columns: Vec<Vec<Span>>,
+}
+
+/// Clip a polygon to the half space where `axis` (0 for x, 2 for z) is on the `keep_above` side
+/// of `value`
+fn clip(polygon: &[Vec3], axis: usize, value: f32, keep_above: bool) -> Vec<Vec3> {
+    let distance = |p: Vec3| {
+        let d = p[axis] - value;
+        if keep_above {
+            d
+        } else {
+            -d
+        }
+    };
+    let mut res = Vec::with_capacity(polygon.len() + 1);
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let (da, db) = (distance(a), distance(b));
+        if da >= 0.0 {
+            res.push(a);
+        }
+        if (da >= 0.0) != (db >= 0.0) {
+            res.push(a + (b - a) * (da / (da - db)));
+        }
+    }
+    res
+}
+
+impl Heightfield {
+    fn new(origin: Vec3, width: usize, depth: usize) -> Self {
+        Self {
+            origin,
+            width,
+            depth,
+            columns: vec![Vec::new(); width * depth],
+        }
+    }
+    fn column(&self, x: usize, z: usize) -> &[Span] {
+        &self.columns[x + z * self.width]
+    }
+    /// Add a span to a column, merging it with the spans it overlaps. When merging, the top of
+    /// the highest span decides if the result is walkable (both count if they are within a step).
+    fn add_span(&mut self, x: usize, z: usize, mut span: Span, climb: i32) {
+        let column = &mut self.columns[x + z * self.width];
+        let mut i = 0;
+        while i < column.len() {
+            let other = column[i];
+            if other.min > span.max {
+                break;
+            }
+            if other.max < span.min {
+                i += 1;
+                continue;
+            }
+            if (other.max - span.max).abs() <= climb {
+                span.walkable |= other.walkable;
+            } else if other.max > span.max {
+                span.walkable = other.walkable;
+            }
+            span.min = span.min.min(other.min);
+            span.max = span.max.max(other.max);
+            column.remove(i);
+        }
+        column.insert(i, span);
+    }
+    fn rasterize(&mut self, triangle: [Vec3; 3], settings: &NavSettings) {
+        let [a, b, c] = triangle;
+        let normal = (b - a).cross(c - a).normalize_or_zero();
+        let walkable = normal.y.abs() >= settings.max_slope.cos();
+        let climb = (settings.max_climb / settings.cell_height).floor() as i32;
+        let cs = settings.cell_size;
+        let min = a.min(b).min(c) - self.origin;
+        let max = a.max(b).max(c) - self.origin;
+        let cell = |v: f32, len: usize| ((v / cs).floor().max(0.0) as usize).min(len - 1);
+        let (x0, x1) = (cell(min.x, self.width), cell(max.x, self.width));
+        let (z0, z1) = (cell(min.z, self.depth), cell(max.z, self.depth));
+        let epsilon = cs * 1e-5;
+        for z in z0..=z1 {
+            let zmin = self.origin.z + z as f32 * cs;
+            let row = clip(&clip(&triangle, 2, zmin, true), 2, zmin + cs, false);
+            if row.len() < 3 {
+                continue;
+            }
+            for x in x0..=x1 {
+                let xmin = self.origin.x + x as f32 * cs;
+                let mut polygon = clip(&clip(&row, 0, xmin, true), 0, xmin + cs, false);
+                // Triangles only touching the cell leave a degenerate polygon
+                polygon.dedup_by(|a, b| a.distance(*b) < epsilon);
+                if polygon.len() > 1 && polygon[0].distance(polygon[polygon.len() - 1]) < epsilon {
+                    polygon.pop();
+                }
+                if polygon.len() < 3 {
+                    continue;
+                }
+                let (low, high) = polygon
+                    .iter()
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(l, h), p| {
+                        (l.min(p.y), h.max(p.y))
+                    });
+                let quantize =
+                    |y: f32| ((y - self.origin.y) / settings.cell_height + 1e-4).floor() as i32;
+                let span = Span {
+                    min: quantize(low),
+                    max: quantize(high),
+                    walkable,
+                };
+                self.add_span(x, z, span, climb);
+            }
+        }
+    }
+    fn from_triangles(triangles: &[[Vec3; 3]], settings: &NavSettings) -> Option<Self> {
+        let mut points = triangles.iter().flatten();
+        let first = *points.next()?;
+        let (min, max) = points.fold((first, first), |(min, max), &p| (min.min(p), max.max(p)));
+        let size = (max - min) / settings.cell_size;
+        let width = (size.x.ceil() as usize).max(1);
+        let depth = (size.z.ceil() as usize).max(1);
+        let mut heightfield = Self::new(min, width, depth);
+        for &triangle in triangles {
+            heightfield.rasterize(triangle, settings);
+        }
+        Some(heightfield)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct NavNode {
+    x: u32,
+    z: u32,
+    height: f32,
+    /// Neighbouring nodes, in the order of DIRECTIONS
+    links: [u32; 8],
+    /// Nodes are in the same region if there is a path between them
+    region: u32,
+}
+
+/// Walkable surfaces of the static geometry, stored as a grid of nodes
+#[derive(Debug, Clone, PartialEq)]
+pub struct NavMesh {
+    settings: NavSettings,
+    origin: Vec3,
+    width: usize,
+    depth: usize,
+    /// Nodes of the column `i` are `nodes[columns[i]..columns[i + 1]]`
+    columns: Vec<u32>,
+    nodes: Vec<NavNode>,
+}
+
+/// Entry of the A* open list, ordered by lowest estimated cost first
+struct Open {
+    estimate: f32,
+    node: usize,
+}
+
+impl PartialEq for Open {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Open {}
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Open {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate)
+    }
+}
+
+impl NavMesh {
+    /// Build the navmesh of the static entities of a scene
+    pub fn from_scene<'a>(
+        settings: NavSettings,
+        statics: impl IntoIterator<Item = (&'a StaticGeometryComponent, &'a TransformsComponent)>,
+    ) -> Self {
+        let triangles: Vec<[Vec3; 3]> = statics
+            .into_iter()
+            .flat_map(|(nav, transforms)| {
+                let mat = transforms.mat();
+                nav.triangles
+                    .iter()
+                    .map(move |tri| tri.map(|p| mat.transform_point3(p)))
+            })
+            .collect();
+        Self::build(settings, &triangles)
+    }
+    /// Build the navmesh again when a game is loaded, with the same settings
+    pub fn game_loaded(
+        &mut self,
+        loaded: EventReader<GameLoaded>,
+        statics: Entities<(&StaticGeometryComponent, &TransformsComponent)>,
+    ) {
+        if !loaded.is_empty() {
+            *self = Self::from_scene(self.settings, statics);
+        }
+    }
+    /// Build a navmesh from triangles in world space
+    pub fn build(settings: NavSettings, triangles: &[[Vec3; 3]]) -> Self {
+        let heightfield = match Heightfield::from_triangles(triangles, &settings) {
+            Some(heightfield) => heightfield,
+            None => return Self::from_surfaces(settings, Vec3::ZERO, 0, 0, Vec::new()),
+        };
+        let ch = settings.cell_height;
+        // Walkable tops with enough room above them
+        let mut surfaces = Vec::new();
+        for z in 0..heightfield.depth {
+            for x in 0..heightfield.width {
+                let column = heightfield.column(x, z);
+                for (i, span) in column.iter().enumerate() {
+                    let ceiling = column.get(i + 1).map(|s| s.min).unwrap_or(i32::MAX);
+                    let room = (ceiling as f32 - span.max as f32) * ch;
+                    if span.walkable && room >= settings.agent_height {
+                        let height = heightfield.origin.y + span.max as f32 * ch;
+                        surfaces.push((x as u32, z as u32, height));
+                    }
+                }
+            }
+        }
+        let mesh = Self::from_surfaces(
+            settings,
+            heightfield.origin,
+            heightfield.width,
+            heightfield.depth,
+            surfaces,
+        );
+        mesh.erode()
+    }
+    /// Build the nodes from walkable surfaces (x, z, height), sorted by column
+    fn from_surfaces(
+        settings: NavSettings,
+        origin: Vec3,
+        width: usize,
+        depth: usize,
+        surfaces: Vec<(u32, u32, f32)>,
+    ) -> Self {
+        let mut columns = vec![0u32; width * depth + 1];
+        for &(x, z, _) in &surfaces {
+            columns[x as usize + z as usize * width + 1] += 1;
+        }
+        for i in 1..columns.len() {
+            columns[i] += columns[i - 1];
+        }
+        let nodes = surfaces
+            .into_iter()
+            .map(|(x, z, height)| NavNode {
+                x,
+                z,
+                height,
+                links: [NO_LINK; 8],
+                region: 0,
+            })
+            .collect();
+        let mut mesh = Self {
+            settings,
+            origin,
+            width,
+            depth,
+            columns,
+            nodes,
+        };
+        mesh.link();
+        mesh.find_regions();
+        mesh
+    }
+    fn column(&self, x: i32, z: i32) -> std::ops::Range<usize> {
+        if x < 0 || z < 0 || x as usize >= self.width || z as usize >= self.depth {
+            return 0..0;
+        }
+        let i = x as usize + z as usize * self.width;
+        self.columns[i] as usize..self.columns[i + 1] as usize
+    }
+    /// Link every node to the closest surface within a step in the neighbouring columns.
+    /// Diagonal links need both orthogonal links so paths never squeeze between two corners.
+    fn link(&mut self) {
+        for i in 0..self.nodes.len() {
+            let NavNode { x, z, height, .. } = self.nodes[i];
+            for (dir, &(dx, dz)) in DIRECTIONS.iter().enumerate().take(4) {
+                let closest = self
+                    .column(x as i32 + dx, z as i32 + dz)
+                    .map(|n| (n, (self.nodes[n].height - height).abs()))
+                    .filter(|&(_, dy)| dy <= self.settings.max_climb)
+                    .min_by(|a, b| a.1.total_cmp(&b.1));
+                if let Some((n, _)) = closest {
+                    self.nodes[i].links[dir] = n as u32;
+                }
+            }
+        }
+        for i in 0..self.nodes.len() {
+            for dir in 4..8 {
+                let (a, b) = (
+                    self.nodes[i].links[dir - 4],
+                    self.nodes[i].links[(dir - 3) % 4],
+                );
+                if a == NO_LINK || b == NO_LINK {
+                    continue;
+                }
+                // Going around either way must lead to the same node
+                let (via_a, via_b) = (
+                    self.nodes[a as usize].links[(dir - 3) % 4],
+                    self.nodes[b as usize].links[dir - 4],
+                );
+                if via_a != NO_LINK && via_a == via_b {
+                    self.nodes[i].links[dir] = via_a;
+                }
+            }
+        }
+    }
+    fn find_regions(&mut self) {
+        let mut regions = vec![u32::MAX; self.nodes.len()];
+        let mut next = 0;
+        let mut queue = VecDeque::new();
+        for start in 0..self.nodes.len() {
+            if regions[start] != u32::MAX {
+                continue;
+            }
+            regions[start] = next;
+            queue.push_back(start);
+            while let Some(node) = queue.pop_front() {
+                for &link in &self.nodes[node].links {
+                    if link != NO_LINK && regions[link as usize] == u32::MAX {
+                        regions[link as usize] = next;
+                        queue.push_back(link as usize);
+                    }
+                }
+            }
+            next += 1;
+        }
+        for (node, region) in self.nodes.iter_mut().zip(regions) {
+            node.region = region;
+        }
+    }
+    /// Remove the nodes closer to an edge than the agent radius
+    fn erode(self) -> Self {
+        // Distance in cells to the closest node on an edge
+        let mut distances = vec![u32::MAX; self.nodes.len()];
+        let mut queue = VecDeque::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.links[..4].contains(&NO_LINK) {
+                distances[i] = 0;
+                queue.push_back(i);
+            }
+        }
+        while let Some(node) = queue.pop_front() {
+            for &link in &self.nodes[node].links {
+                if link != NO_LINK && distances[link as usize] == u32::MAX {
+                    distances[link as usize] = distances[node] + 1;
+                    queue.push_back(link as usize);
+                }
+            }
+        }
+        let surfaces = self
+            .nodes
+            .iter()
+            .zip(distances)
+            .filter(|&(_, d)| {
+                (d as f32 + 0.5) * self.settings.cell_size >= self.settings.agent_radius
+            })
+            .map(|(node, _)| (node.x, node.z, node.height))
+            .collect();
+        Self::from_surfaces(self.settings, self.origin, self.width, self.depth, surfaces)
+    }
+    pub fn settings(&self) -> &NavSettings {
+        &self.settings
+    }
+    /// Number of walkable cells
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+    fn center(&self, node: usize) -> Vec3 {
+        let node = &self.nodes[node];
+        let cs = self.settings.cell_size;
+        Vec3::new(
+            self.origin.x + (node.x as f32 + 0.5) * cs,
+            node.height,
+            self.origin.z + (node.z as f32 + 0.5) * cs,
+        )
+    }
+    /// Grid coordinates of the cell containing a point
+    fn cell(&self, pos: Vec3) -> (i32, i32) {
+        let p = (pos - self.origin) / self.settings.cell_size;
+        (p.x.floor() as i32, p.z.floor() as i32)
+    }
+    /// The surface under (or closest to) a point
+    fn locate(&self, pos: Vec3) -> Option<usize> {
+        let (x, z) = self.cell(pos);
+        self.column(x, z).min_by(|&a, &b| {
+            let (da, db) = (self.nodes[a].height - pos.y, self.nodes[b].height - pos.y);
+            da.abs().total_cmp(&db.abs())
+        })
+    }
+    /// Position on the walkable surface under (or closest to) a point
+    pub fn project(&self, pos: Vec3) -> Option<Vec3> {
+        self.locate(pos)
+            .map(|node| Vec3::new(pos.x, self.nodes[node].height, pos.z))
+    }
+    /// Shortest path between two nodes, A* going from cell centers to cell centers
+    fn find_nodes(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        if self.nodes[from].region != self.nodes[to].region {
+            return None;
+        }
+        let goal = self.center(to);
+        let mut costs = vec![f32::INFINITY; self.nodes.len()];
+        let mut parents = vec![usize::MAX; self.nodes.len()];
+        let mut open = BinaryHeap::new();
+        costs[from] = 0.0;
+        open.push(Open {
+            estimate: self.center(from).distance(goal),
+            node: from,
+        });
+        while let Some(Open { estimate, node }) = open.pop() {
+            if node == to {
+                let mut path = vec![to];
+                while let Some(&last) = path.last() {
+                    if last == from {
+                        break;
+                    }
+                    path.push(parents[last]);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let pos = self.center(node);
+            // Stale entry, the node has been reached for cheaper since
+            if estimate > costs[node] + pos.distance(goal) + 1e-4 {
+                continue;
+            }
+            for &link in &self.nodes[node].links {
+                if link == NO_LINK {
+                    continue;
+                }
+                let link = link as usize;
+                let next = self.center(link);
+                let cost = costs[node] + pos.distance(next);
+                if cost < costs[link] {
+                    costs[link] = cost;
+                    parents[link] = node;
+                    open.push(Open {
+                        estimate: cost + next.distance(goal),
+                        node: link,
+                    });
+                }
+            }
+        }
+        None
+    }
+    /// Whether an agent can walk in a straight line from a to b (on the nodes `from` and `to`).
+    /// Every cell touched by the segment is walked through the links of the nodes, when the
+    /// segment goes exactly through a corner the diagonal link (which needs both sides) is used.
+    fn walkable_line(&self, a: Vec3, from: usize, b: Vec3, to: usize) -> bool {
+        let cs = self.settings.cell_size;
+        let (start, end) = ((a - self.origin) / cs, (b - self.origin) / cs);
+        let (dx, dz) = (end.x - start.x, end.z - start.z);
+        let step = |d: f32| if d > 0.0 { 1 } else { -1 };
+        let (sx, sz) = (step(dx), step(dz));
+        let next = |pos: f32, d: f32| {
+            if d == 0.0 {
+                f32::INFINITY
+            } else {
+                let boundary = if d > 0.0 {
+                    pos.floor() + 1.0
+                } else {
+                    pos.floor()
+                };
+                (boundary - pos) / d
+            }
+        };
+        let (mut tx, mut tz) = (next(start.x, dx), next(start.z, dz));
+        let (delta_x, delta_z) = (1.0 / dx.abs(), 1.0 / dz.abs());
+        let mut node = from;
+        let target = (self.nodes[to].x, self.nodes[to].z);
+        let epsilon = 1e-5;
+        for _ in 0..self.width + self.depth + 2 {
+            if (self.nodes[node].x, self.nodes[node].z) == target {
+                break;
+            }
+            let dir = if (tx - tz).abs() <= epsilon {
+                tx += delta_x;
+                tz += delta_z;
+                direction(sx, sz)
+            } else if tx < tz {
+                tx += delta_x;
+                direction(sx, 0)
+            } else {
+                tz += delta_z;
+                direction(0, sz)
+            };
+            match self.nodes[node].links[dir] {
+                NO_LINK => return false,
+                link => node = link as usize,
+            }
+        }
+        node == to
+    }
+    /// Find a path between two points, None if one isn't on the navmesh or they aren't connected.
+    /// The path starts and ends at the points (projected on the surface) and only has corners
+    /// where going straight would leave the walkable area.
+    pub fn find_path(&self, from: Vec3, to: Vec3) -> Option<Vec<Vec3>> {
+        let (start, goal) = (self.locate(from)?, self.locate(to)?);
+        let nodes = self.find_nodes(start, goal)?;
+        let mut points: Vec<Vec3> = nodes.iter().map(|&n| self.center(n)).collect();
+        points[0] = Vec3::new(from.x, self.nodes[start].height, from.z);
+        let last = points.len() - 1;
+        points[last] = Vec3::new(to.x, self.nodes[goal].height, to.z);
+
+        // String pulling: go as far as possible in a straight line from the last corner
+        let mut path = vec![points[0]];
+        let mut corner = 0;
+        for i in 2..points.len() {
+            if !self.walkable_line(points[corner], nodes[corner], points[i], nodes[i]) {
+                corner = i - 1;
+                path.push(points[corner]);
+            }
+        }
+        if last > 0 {
+            path.push(points[last]);
+        }
+        Some(path)
+    }
+    /// Segments between the centers of linked cells, to draw the navmesh
+    pub fn debug_lines(&self) -> Vec<(Vec3, Vec3)> {
+        let mut lines = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            for &link in &node.links[..4] {
+                if link != NO_LINK && (link as usize) > i {
+                    lines.push((self.center(i), self.center(link as usize)));
+                }
+            }
+        }
+        lines
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let s = &self.settings;
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        for f in [
+            s.cell_size,
+            s.cell_height,
+            s.max_slope,
+            s.max_climb,
+            s.agent_height,
+            s.agent_radius,
+            self.origin.x,
+            self.origin.y,
+            self.origin.z,
+        ] {
+            bytes.extend_from_slice(&f.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.width as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.depth as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.nodes.len() as u32).to_le_bytes());
+        for node in &self.nodes {
+            bytes.extend_from_slice(&node.x.to_le_bytes());
+            bytes.extend_from_slice(&node.z.to_le_bytes());
+            bytes.extend_from_slice(&node.height.to_le_bytes());
+        }
+        bytes
+    }
+    /// Read a navmesh written by `to_bytes`, links and regions are rebuilt
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(bytes);
+        let mut read = || -> Result<[u8; 4]> {
+            let mut buf = [0u8; 4];
+            cursor.read_exact(&mut buf).context("Truncated navmesh")?;
+            Ok(buf)
+        };
+        if &read()? != MAGIC {
+            bail!("Not a navmesh");
+        }
+        let version = u32::from_le_bytes(read()?);
+        if version != VERSION {
+            bail!("Unsupported navmesh version {version}");
+        }
+        let mut f = [0.0; 9];
+        for f in &mut f {
+            *f = f32::from_le_bytes(read()?);
+        }
+        let settings = NavSettings {
+            cell_size: f[0],
+            cell_height: f[1],
+            max_slope: f[2],
+            max_climb: f[3],
+            agent_height: f[4],
+            agent_radius: f[5],
+        };
+        let origin = Vec3::new(f[6], f[7], f[8]);
+        let width = u32::from_le_bytes(read()?) as usize;
+        let depth = u32::from_le_bytes(read()?) as usize;
+        let count = u32::from_le_bytes(read()?);
+        let mut surfaces = Vec::new();
+        for _ in 0..count {
+            let x = u32::from_le_bytes(read()?);
+            let z = u32::from_le_bytes(read()?);
+            let height = f32::from_le_bytes(read()?);
+            if x as usize >= width || z as usize >= depth {
+                bail!("Navmesh cell out of the grid");
+            }
+            surfaces.push((x, z, height));
+        }
+        Ok(Self::from_surfaces(
+            settings, origin, width, depth, surfaces,
+        ))
+    }
+    /// The navmesh of a scene from the cache, built (and added to the cache) if there isn't one
+    /// for these settings yet.
+    pub fn load_or_build(
+        resources: &ResourceManager,
+        scene: Resource,
+        settings: NavSettings,
+        build: impl FnOnce(NavSettings) -> NavMesh,
+    ) -> Result<Self> {
+        let relation = settings.relation();
+        if let Some(res) = resources.get_related(scene, &relation) {
+            return Self::from_bytes(&resources.get_resource(res)?);
+        }
+        let mesh = build(settings);
+        let res = resources.add_virtual(&mesh.to_bytes());
+        resources.set_relation(&relation, scene, res)?;
+        Ok(mesh)
+    }
+}
+
+/// Moves an entity to a target along paths on the NavMesh resource, see `steer_agents`
+#[derive(Debug, Clone)]
+pub struct NavAgentComponent {
+    pub target: Option<Vec3>,
+    /// Speed, in units per second
+    pub speed: f32,
+    /// How far the target can move before the path is computed again
+    pub replan_distance: f32,
+    /// Distance at which a waypoint is reached
+    pub arrival_distance: f32,
+    path: Vec<Vec3>,
+    /// Index of the waypoint the agent is going to
+    next: usize,
+    /// Target the path was computed for
+    planned_for: Option<Vec3>,
+}
+
+impl NavAgentComponent {
+    pub fn new(speed: f32) -> Self {
+        Self {
+            target: None,
+            speed,
+            replan_distance: 0.5,
+            arrival_distance: 0.1,
+            path: Vec::new(),
+            next: 0,
+            planned_for: None,
+        }
+    }
+    /// The current path, to draw it
+    pub fn path(&self) -> &[Vec3] {
+        &self.path
+    }
+    fn needs_replan(&self) -> bool {
+        match (self.target, self.planned_for) {
+            (Some(target), Some(planned)) => target.distance(planned) > self.replan_distance,
+            (target, planned) => target.is_some() != planned.is_some(),
+        }
+    }
+    fn replan(&mut self, navmesh: &NavMesh, pos: Vec3) {
+        self.planned_for = self.target;
+        self.path = self
+            .target
+            .and_then(|target| navmesh.find_path(pos, target))
+            .unwrap_or_default();
+        self.next = 1;
+    }
+    /// Velocity towards the next waypoint, zero once arrived (or without a path)
+    fn velocity(&mut self, pos: Vec3) -> Vec3 {
+        while let Some(&waypoint) = self.path.get(self.next) {
+            let offset = waypoint - pos;
+            if offset.length() > self.arrival_distance {
+                return offset.normalize() * self.speed;
+            }
+            self.next += 1;
+        }
+        Vec3::ZERO
+    }
+}
+
+/// Set the velocity of the agents to follow their path, computing it again when the target moved
+pub fn steer_agents(
+    navmesh: &NavMesh,
+    agents: Entities<(
+        &mut NavAgentComponent,
+        &TransformsComponent,
+        &mut VelocityComponent,
+    )>,
+) {
+    for (agent, transforms, velocity) in agents {
+        let pos = transforms.translation();
+        if agent.needs_replan() {
+            agent.replan(navmesh, pos);
+        }
+        velocity.linear = agent.velocity(pos);
+    }
+}
+
+/// Whether `draw_navigation` draws, a resource
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NavDebugDraw(pub bool);
+
+/// Draw the links of the navmesh and the paths of the agents, if enabled
+pub fn draw_navigation(
+    enabled: &NavDebugDraw,
+    navmesh: &NavMesh,
+    agents: Entities<&NavAgentComponent>,
+    draw: &mut DebugDraw,
+) {
+    if !enabled.0 {
+        return;
+    }
+    for (from, to) in navmesh.debug_lines() {
+        draw.line(from, to, NAVMESH_COLOR.into());
+    }
+    for agent in agents {
+        draw.polyline(agent.path().iter().copied(), AGENT_PATH_COLOR.into());
+    }
+}
+
+/// Move the entities by their velocity
+pub fn apply_velocities(
+    time: &Time,
+    entities: Entities<(&VelocityComponent, &mut TransformsComponent)>,
+) {
+    let delta = time.delta_secs();
+    for (velocity, transforms) in entities {
+        if velocity.linear != Vec3::ZERO {
+            let pos = transforms.translation() + velocity.linear * delta;
+            transforms.set_translation(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> NavSettings {
+        NavSettings {
+            cell_size: 1.0,
+            cell_height: 0.1,
+            max_climb: 0.3,
+            agent_height: 1.8,
+            agent_radius: 0.0,
+            ..Default::default()
+        }
+    }
+
+    fn quad(min: Vec3, max: Vec3) -> [[Vec3; 3]; 2] {
+        let (a, b) = (min, Vec3::new(max.x, min.y, min.z));
+        let (c, d) = (max, Vec3::new(min.x, max.y, max.z));
+        [[a, b, c], [a, c, d]]
+    }
+
+    /// Flat floor at y = 0 with a unit cell for every '.', rows are along x
+    fn grid(rows: &[&str]) -> NavMesh {
+        let mut triangles = Vec::new();
+        for (z, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                if c == '.' {
+                    let min = Vec3::new(x as f32, 0.0, z as f32);
+                    triangles.extend(quad(min, min + Vec3::new(1.0, 0.0, 1.0)));
+                }
+            }
+        }
+        NavMesh::build(settings(), &triangles)
+    }
+
+    fn cell(x: usize, z: usize) -> Vec3 {
+        Vec3::new(x as f32 + 0.5, 0.0, z as f32 + 0.5)
+    }
+
+    fn walkable(mesh: &NavMesh, pos: Vec3) -> bool {
+        let (x, z) = mesh.cell(pos);
+        !mesh.column(x, z).is_empty()
+    }
+
+    #[test]
+    fn rasterization() {
+        let settings = settings();
+        // Right triangle covering the cells under x + z = 4
+        let triangle = [
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(4.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 4.0),
+        ];
+        let heightfield = Heightfield::from_triangles(&[triangle], &settings).unwrap();
+        assert_eq!((heightfield.width, heightfield.depth), (4, 4));
+        for z in 0..4 {
+            for x in 0..4 {
+                let column = heightfield.column(x, z);
+                if x + z < 4 {
+                    let expected = Span {
+                        min: 0,
+                        max: 0,
+                        walkable: true,
+                    };
+                    assert_eq!(column, [expected], "cell {x} {z}");
+                } else {
+                    assert!(column.is_empty(), "cell {x} {z}");
+                }
+            }
+        }
+
+        // A 1 unit wall on a floor: the spans merge, and the wall's top isn't reached by a step
+        let mut triangles = quad(Vec3::ZERO, Vec3::new(2.0, 0.0, 1.0)).to_vec();
+        triangles.push([
+            Vec3::new(0.0, 0.0, 0.5),
+            Vec3::new(1.0, 0.0, 0.5),
+            Vec3::new(0.5, 1.0, 0.5),
+        ]);
+        let heightfield = Heightfield::from_triangles(&triangles, &settings).unwrap();
+        assert_eq!(
+            heightfield.column(0, 0),
+            [Span {
+                min: 0,
+                max: 10,
+                walkable: false
+            }]
+        );
+        assert!(heightfield.column(1, 0)[0].walkable);
+
+        // Too steep
+        let ramp = quad(Vec3::ZERO, Vec3::new(1.0, 2.0, 1.0));
+        let heightfield = Heightfield::from_triangles(&ramp, &settings).unwrap();
+        assert!(!heightfield.column(0, 0)[0].walkable);
+    }
+
+    #[test]
+    fn clearance() {
+        // 4x4 floor with a low ceiling over the first two columns
+        let mut triangles = quad(Vec3::ZERO, Vec3::new(4.0, 0.0, 4.0)).to_vec();
+        triangles.extend(quad(Vec3::new(0.0, 1.0, 0.0), Vec3::new(2.0, 1.0, 4.0)));
+        let mesh = NavMesh::build(settings(), &triangles);
+        for z in 0..4 {
+            for x in 0..4 {
+                let floor = mesh.project(cell(x, z)).filter(|p| p.y == 0.0);
+                assert_eq!(floor.is_some(), x >= 2, "cell {x} {z}");
+            }
+        }
+        // The top of the ceiling can be walked on, but isn't reachable
+        let top = Vec3::new(0.5, 1.0, 0.5);
+        assert_eq!(mesh.project(top).unwrap().y, 1.0);
+        assert!(mesh.find_path(top, cell(3, 3)).is_none());
+
+        // The agent radius keeps it away from the edges
+        let floor = quad(Vec3::ZERO, Vec3::new(5.0, 0.0, 5.0));
+        let settings = NavSettings {
+            agent_radius: 0.6,
+            ..settings()
+        };
+        let mesh = NavMesh::build(settings, &floor);
+        assert_eq!(mesh.len(), 9);
+        assert!(walkable(&mesh, cell(1, 1)));
+        assert!(!walkable(&mesh, cell(0, 2)));
+        let settings = NavSettings {
+            agent_radius: 1.0,
+            ..settings
+        };
+        assert_eq!(NavMesh::build(settings, &floor).len(), 9);
+    }
+
+    #[test]
+    fn shortest_path() {
+        let mesh = grid(&[
+            "........", //
+            "..####..", ".....#..", "####.#..", "........",
+        ]);
+        let (from, to) = (
+            mesh.locate(cell(0, 2)).unwrap(),
+            mesh.locate(cell(7, 4)).unwrap(),
+        );
+        let nodes = mesh.find_nodes(from, to).unwrap();
+        let length: f32 = nodes
+            .windows(2)
+            .map(|w| mesh.center(w[0]).distance(mesh.center(w[1])))
+            .sum();
+        // Through the gap at (4, 3), going over the top is 9 + 2 * sqrt(2)
+        assert!((length - 9.0).abs() < 1e-4, "{length}");
+        // Every step is between neighbours
+        for w in nodes.windows(2) {
+            assert!(mesh.center(w[0]).distance(mesh.center(w[1])) < 1.5);
+        }
+
+        // Octile distance on open ground
+        let mesh = grid(&["......"; 6]);
+        let (from, to) = (
+            mesh.locate(cell(0, 0)).unwrap(),
+            mesh.locate(cell(3, 5)).unwrap(),
+        );
+        let nodes = mesh.find_nodes(from, to).unwrap();
+        assert_eq!(nodes.len(), 6);
+
+        let mesh = grid(&[
+            "...#...", //
+            "...#...", "...#...",
+        ]);
+        assert!(mesh.find_path(cell(0, 0), cell(6, 2)).is_none());
+        // Diagonals can't squeeze between corners
+        let mesh = grid(&[
+            ".#", //
+            "#.",
+        ]);
+        assert!(mesh.find_path(cell(0, 0), cell(1, 1)).is_none());
+    }
+
+    #[test]
+    fn smoothing() {
+        // Open ground: straight line
+        let mesh = grid(&["......"; 6]);
+        let path = mesh.find_path(cell(0, 0), cell(5, 3)).unwrap();
+        assert_eq!(path, vec![cell(0, 0), cell(5, 3)]);
+
+        let mesh = grid(&[
+            "......", //
+            "......", "###...", "###...", "......", "......",
+        ]);
+        let path = mesh.find_path(cell(0, 0), cell(0, 5)).unwrap();
+        assert!(path.len() > 2 && path.len() < 6, "{path:?}");
+        assert_eq!((path[0], path[path.len() - 1]), (cell(0, 0), cell(0, 5)));
+        // No segment goes through the obstacle, not even through its corners
+        for w in path.windows(2) {
+            let side = (w[1] - w[0]).cross(Vec3::Y).normalize() * 0.01;
+            for i in 0..=1000 {
+                let p = w[0].lerp(w[1], i as f32 / 1000.0);
+                for p in [p, p + side, p - side] {
+                    assert!(walkable(&mesh, p), "{p} on {w:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn replan() {
+        let mesh = grid(&["......"; 3]);
+        let mut agent = NavAgentComponent::new(2.0);
+        assert!(!agent.needs_replan());
+        agent.target = Some(cell(5, 0));
+        assert!(agent.needs_replan());
+        agent.replan(&mesh, cell(0, 0));
+        assert_eq!(agent.path(), [cell(0, 0), cell(5, 0)]);
+        assert!(!agent.needs_replan());
+        // Small moves of the target keep the path
+        agent.target = Some(cell(5, 0) + Vec3::Z * 0.4);
+        assert!(!agent.needs_replan());
+        agent.target = Some(cell(5, 2));
+        assert!(agent.needs_replan());
+        agent.replan(&mesh, cell(0, 0));
+        assert_eq!(agent.path().last(), Some(&cell(5, 2)));
+        agent.target = None;
+        assert!(agent.needs_replan());
+        agent.replan(&mesh, cell(0, 0));
+        assert!(agent.path().is_empty());
+
+        // Steering goes to the next waypoint at the agent's speed, then stops
+        agent.target = Some(cell(5, 0));
+        agent.replan(&mesh, cell(0, 0));
+        assert_eq!(agent.velocity(cell(0, 0)), Vec3::X * 2.0);
+        assert_eq!(agent.velocity(cell(5, 0)), Vec3::ZERO);
+    }
+
+    #[test]
+    fn drawn() {
+        let mesh = grid(&["..."; 2]);
+        let mut agent = NavAgentComponent::new(1.0);
+        agent.target = Some(cell(2, 1));
+        agent.replan(&mesh, cell(0, 0));
+        let path = agent.path().to_vec();
+        assert!(path.len() >= 2);
+        // 3 links along x on each row, and 2 between the rows
+        let links = mesh.debug_lines();
+        assert_eq!(7, links.len());
+
+        let mut world = ecs::World::new();
+        let mut executor = ecs::Executor::new();
+        executor.add_resource(NavDebugDraw(false));
+        executor.add_resource(mesh);
+        executor.add_resource(DebugDraw::new());
+        world.spawn((agent,));
+        executor.run_once(&mut world, draw_navigation);
+        assert!(executor.get_resource::<DebugDraw>().unwrap().is_empty());
+
+        executor.get_resource_mut::<NavDebugDraw>().unwrap().0 = true;
+        executor.run_once(&mut world, draw_navigation);
+        let lines = executor
+            .get_resource_mut::<DebugDraw>()
+            .unwrap()
+            .take()
+            .chunks_exact(2)
+            .map(|pair| ((pair[0].position, pair[1].position), pair[0].color))
+            .collect::<Vec<_>>();
+        let (navmesh, paths) = lines.split_at(links.len());
+        assert!(navmesh.iter().all(|(_, color)| *color == NAVMESH_COLOR));
+        assert_eq!(
+            links,
+            navmesh.iter().map(|(line, _)| *line).collect::<Vec<_>>()
+        );
+        // The agent's path, segment by segment
+        assert!(paths.iter().all(|(_, color)| *color == AGENT_PATH_COLOR));
+        let segments = path.windows(2).map(|w| (w[0], w[1])).collect::<Vec<_>>();
+        assert_eq!(
+            segments,
+            paths.iter().map(|(line, _)| *line).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn serialization() {
+        let mesh = grid(&[
+            "....", //
+            ".#..", "....",
+        ]);
+        let bytes = mesh.to_bytes();
+        assert_eq!(NavMesh::from_bytes(&bytes).unwrap(), mesh);
+        assert!(NavMesh::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(NavMesh::from_bytes(b"nope").is_err());
+    }
+}
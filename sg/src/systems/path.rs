@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+
+use ecs::{Entities, Entity};
+use glam::{Mat3, Quat, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::components::TransformsComponent;
+
+use super::{graphics::debug_draw::DebugDraw, time::Time};
+
+/// Number of samples per segment of the arc length table
+const SAMPLES_PER_SEGMENT: usize = 32;
+/// Lines per segment of the curve when drawn (see `draw_paths`)
+const DRAWN_LINES_PER_SEGMENT: usize = 8;
+const CURVE_COLOR: [f32; 4] = [0.2, 0.8, 1.0, 1.0];
+const POINT_COLOR: [f32; 4] = [1.0, 0.6, 0.1, 1.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Interpolation {
+    /// Straight lines between the points
+    Linear,
+    /// Smooth curve going through every point
+    CatmullRom,
+    /// Cubic bezier curves, points are (point, control, control, point, control, ...)
+    Bezier,
+}
+
+/// A spline path, followed by entities with a `PathFollowComponent`
+#[derive(Clone)]
+pub struct PathComponent {
+    points: Vec<Vec3>,
+    interpolation: Interpolation,
+    looped: bool,
+    /// Arc length at regularly spaced values of the curve parameter, used to move at constant
+    /// speed.
+    lengths: Vec<f32>,
+    /// Draw the curve and its points, see `draw_paths`
+    pub debug_draw: bool,
+}
+
+impl PathComponent {
+    pub fn new(points: Vec<Vec3>, interpolation: Interpolation, looped: bool) -> Self {
+        let mut path = Self {
+            points,
+            interpolation,
+            looped,
+            lengths: Vec::new(),
+            debug_draw: false,
+        };
+        path.update_lengths();
+        path
+    }
+    pub fn points(&self) -> &[Vec3] {
+        &self.points
+    }
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+    pub fn looped(&self) -> bool {
+        self.looped
+    }
+    /// Change the points of the path (this rebuilds the arc length table)
+    pub fn set_points(&mut self, points: Vec<Vec3>) {
+        self.points = points;
+        self.update_lengths();
+    }
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+        self.update_lengths();
+    }
+    pub fn set_looped(&mut self, looped: bool) {
+        self.looped = looped;
+        self.update_lengths();
+    }
+    /// Number of curve segments, 0 if there aren't enough points for the interpolation
+    pub fn segments(&self) -> usize {
+        let n = self.points.len();
+        match (self.interpolation, self.looped) {
+            (_, _) if n < 2 => 0,
+            (Interpolation::Linear | Interpolation::CatmullRom, false) => n - 1,
+            (Interpolation::Linear | Interpolation::CatmullRom, true) => n,
+            (Interpolation::Bezier, false) if n % 3 == 1 => n / 3,
+            (Interpolation::Bezier, true) if n % 3 == 0 => n / 3,
+            (Interpolation::Bezier, _) => 0,
+        }
+    }
+    /// Total length of the path
+    pub fn length(&self) -> f32 {
+        self.lengths.last().copied().unwrap_or(0.0)
+    }
+    fn point(&self, index: isize) -> Vec3 {
+        let n = self.points.len() as isize;
+        let index = if self.looped {
+            index.rem_euclid(n)
+        } else {
+            index.clamp(0, n - 1)
+        };
+        self.points[index as usize]
+    }
+    /// Position and derivative of a segment, at t in [0, 1]
+    fn evaluate_segment(&self, segment: usize, t: f32) -> (Vec3, Vec3) {
+        let i = segment as isize;
+        match self.interpolation {
+            Interpolation::Linear => {
+                let (a, b) = (self.point(i), self.point(i + 1));
+                (a.lerp(b, t), b - a)
+            }
+            Interpolation::CatmullRom => {
+                let (p0, p1, p2, p3) = (
+                    self.point(i - 1),
+                    self.point(i),
+                    self.point(i + 1),
+                    self.point(i + 2),
+                );
+                let a = 2.0 * p1;
+                let b = p2 - p0;
+                let c = 2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3;
+                let d = -p0 + 3.0 * p1 - 3.0 * p2 + p3;
+                (
+                    0.5 * (a + b * t + c * t * t + d * t * t * t),
+                    0.5 * (b + 2.0 * c * t + 3.0 * d * t * t),
+                )
+            }
+            Interpolation::Bezier => {
+                let (p0, p1, p2, p3) = (
+                    self.point(3 * i),
+                    self.point(3 * i + 1),
+                    self.point(3 * i + 2),
+                    self.point(3 * i + 3),
+                );
+                let u = 1.0 - t;
+                (
+                    u * u * u * p0 + 3.0 * u * u * t * p1 + 3.0 * u * t * t * p2 + t * t * t * p3,
+                    3.0 * u * u * (p1 - p0) + 6.0 * u * t * (p2 - p1) + 3.0 * t * t * (p3 - p2),
+                )
+            }
+        }
+    }
+    /// Position and derivative at u in [0, segments] (the curve's own parameter, which doesn't
+    /// move at constant speed).
+    fn evaluate(&self, u: f32) -> (Vec3, Vec3) {
+        let segments = self.segments();
+        if segments == 0 {
+            return (self.points.first().copied().unwrap_or(Vec3::ZERO), Vec3::ZERO);
+        }
+        let u = u.clamp(0.0, segments as f32);
+        let segment = (u as usize).min(segments - 1);
+        self.evaluate_segment(segment, u - segment as f32)
+    }
+    fn update_lengths(&mut self) {
+        let samples = self.segments() * SAMPLES_PER_SEGMENT;
+        self.lengths.clear();
+        if samples == 0 {
+            return;
+        }
+        self.lengths.reserve(samples + 1);
+        let mut length = 0.0;
+        let mut last = self.evaluate(0.0).0;
+        self.lengths.push(0.0);
+        for i in 1..=samples {
+            let pos = self.evaluate(i as f32 / SAMPLES_PER_SEGMENT as f32).0;
+            length += pos.distance(last);
+            last = pos;
+            self.lengths.push(length);
+        }
+    }
+    /// Curve parameter at a distance along the path
+    fn parameter_at(&self, distance: f32) -> f32 {
+        if self.lengths.len() < 2 {
+            return 0.0;
+        }
+        let distance = distance.clamp(0.0, self.length());
+        let i = self
+            .lengths
+            .partition_point(|&l| l < distance)
+            .clamp(1, self.lengths.len() - 1);
+        let (a, b) = (self.lengths[i - 1], self.lengths[i]);
+        let frac = if b > a { (distance - a) / (b - a) } else { 0.0 };
+        (i - 1) as f32 / SAMPLES_PER_SEGMENT as f32 + frac / SAMPLES_PER_SEGMENT as f32
+    }
+    /// Points along the curve, `per_segment` lines per segment, from its start to its end (back
+    /// to the start if looped)
+    pub fn curve_points(&self, per_segment: usize) -> Vec<Vec3> {
+        let samples = self.segments() * per_segment;
+        (0..=samples)
+            .map(|i| self.evaluate(i as f32 / per_segment as f32).0)
+            .collect()
+    }
+    /// Position and (normalized, possibly zero) tangent at t in [0, 1], t being proportional to
+    /// the distance along the path.
+    pub fn sample(&self, t: f32) -> (Vec3, Vec3) {
+        let (pos, derivative) = self.evaluate(self.parameter_at(t * self.length()));
+        (pos, derivative.normalize_or_zero())
+    }
+}
+
+/// Makes an entity follow a path
+#[derive(Clone, Copy)]
+pub struct PathFollowComponent {
+    /// The entity holding the PathComponent
+    pub path: Entity,
+    /// Progress along the path, in [0, 1]
+    pub t: f32,
+    /// Speed along the path, in units per second
+    pub speed: f32,
+    /// Rotate the entity to face the direction of the path
+    pub orient_to_tangent: bool,
+    /// Rotate the entity to face this point instead
+    pub look_at: Option<Vec3>,
+    pub playing: bool,
+}
+
+impl PathFollowComponent {
+    pub fn new(path: Entity, speed: f32) -> Self {
+        Self {
+            path,
+            t: 0.0,
+            speed,
+            orient_to_tangent: false,
+            look_at: None,
+            playing: true,
+        }
+    }
+    /// Move along a path, returns true if a non looped path has just been finished
+    fn advance(&mut self, path: &PathComponent, delta: f32) -> bool {
+        let length = path.length();
+        if !self.playing || length <= 0.0 {
+            return false;
+        }
+        self.t += self.speed * delta / length;
+        if path.looped() {
+            self.t = self.t.rem_euclid(1.0);
+            false
+        } else if self.t >= 1.0 || self.t <= 0.0 {
+            self.t = self.t.clamp(0.0, 1.0);
+            self.playing = false;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Rotation facing a direction, keeping the Y axis up when possible
+fn look_rotation(direction: Vec3) -> Quat {
+    let right = Vec3::Y.cross(direction);
+    if right.length_squared() < 1e-6 {
+        return Quat::from_rotation_arc(Vec3::Z, direction);
+    }
+    let right = right.normalize();
+    let up = direction.cross(right);
+    Quat::from_mat3(&Mat3::from_cols(right, up, direction))
+}
+
+/// Entities that finished following a (non looped) path, since the last drain
+#[derive(Default)]
+pub struct PathEvents {
+    finished: Vec<Entity>,
+}
+
+impl PathEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn drain(&mut self) -> impl Iterator<Item = Entity> + '_ {
+        self.finished.drain(..)
+    }
+}
+
+/// Move the entities with a PathFollowComponent along their path
+pub fn follow_paths(
+    time: &Time,
+    events: &mut PathEvents,
+    paths: Entities<(Entity, &PathComponent)>,
+    followers: Entities<(Entity, &mut PathFollowComponent, &mut TransformsComponent)>,
+) {
+    let paths: HashMap<Entity, &PathComponent> = paths.collect();
+    let delta = time.delta_secs();
+    for (entity, follow, transforms) in followers {
+        let path = match paths.get(&follow.path) {
+            Some(path) => path,
+            None => continue,
+        };
+        if follow.advance(path, delta) {
+            events.finished.push(entity);
+        }
+        let (pos, tangent) = path.sample(follow.t);
+        transforms.set_translation(pos);
+        let facing = match follow.look_at {
+            Some(target) => (target - pos).normalize_or_zero(),
+            None if follow.orient_to_tangent => tangent,
+            None => Vec3::ZERO,
+        };
+        if facing != Vec3::ZERO {
+            transforms.set_rotation(look_rotation(facing));
+        }
+    }
+}
+
+/// Draw the paths with `debug_draw` set: their curve, and their points as crosses
+pub fn draw_paths(paths: Entities<&PathComponent>, draw: &mut DebugDraw) {
+    for path in paths.filter(|path| path.debug_draw) {
+        draw.polyline(path.curve_points(DRAWN_LINES_PER_SEGMENT), CURVE_COLOR.into());
+        for point in path.points() {
+            draw.cross(*point, 0.2, POINT_COLOR.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!(a.distance(b) < 1e-4, "{a} != {b}");
+    }
+
+    #[test]
+    fn catmull_rom() {
+        let points = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 2.0, 0.0),
+            Vec3::new(3.0, 1.0, 1.0),
+            Vec3::new(4.0, 0.0, 0.0),
+        ];
+        let path = PathComponent::new(points.clone(), Interpolation::CatmullRom, false);
+        assert_eq!(path.segments(), 3);
+        // The curve goes through every point
+        for (i, p) in points.iter().enumerate() {
+            assert_near(path.evaluate(i as f32).0, *p);
+        }
+        // Tangent at an inner point is half the vector between its neighbours
+        assert_near(path.evaluate(1.0).1, (points[2] - points[0]) * 0.5);
+        // Known value: middle of the inner segment
+        // 0.5 * (2p1 + 0.5(p2 - p0) + 0.25(2p0 - 5p1 + 4p2 - p3) + 0.125(-p0 + 3p1 - 3p2 + p3))
+        assert_near(path.evaluate(1.5).0, Vec3::new(2.0, 1.6875, 0.5625));
+
+        // Equally spaced collinear points give a straight line
+        let line = PathComponent::new(
+            (0..4).map(|i| Vec3::X * i as f32).collect(),
+            Interpolation::CatmullRom,
+            false,
+        );
+        assert_near(line.evaluate(1.5).0, Vec3::X * 1.5);
+    }
+
+    #[test]
+    fn bezier() {
+        let points = vec![Vec3::ZERO, Vec3::Y, Vec3::new(1.0, 1.0, 0.0), Vec3::X];
+        let path = PathComponent::new(points, Interpolation::Bezier, false);
+        assert_eq!(path.segments(), 1);
+        assert_near(path.evaluate(0.0).0, Vec3::ZERO);
+        assert_near(path.evaluate(1.0).0, Vec3::X);
+        assert_near(path.evaluate(0.5).0, Vec3::new(0.5, 0.75, 0.0));
+        // Wrong number of points
+        let path = PathComponent::new(vec![Vec3::ZERO; 5], Interpolation::Bezier, false);
+        assert_eq!(path.segments(), 0);
+        assert_eq!(path.length(), 0.0);
+    }
+
+    #[test]
+    fn constant_speed() {
+        // Unevenly spaced points, the curve parameter alone would move at very different speeds
+        let path = PathComponent::new(
+            vec![
+                Vec3::ZERO,
+                Vec3::new(0.2, 0.0, 0.0),
+                Vec3::new(5.0, 1.0, 0.0),
+                Vec3::new(6.0, 4.0, 2.0),
+            ],
+            Interpolation::CatmullRom,
+            false,
+        );
+        let steps = 200;
+        let speeds: Vec<f32> = (0..steps)
+            .map(|i| {
+                let a = path.sample(i as f32 / steps as f32).0;
+                let b = path.sample((i + 1) as f32 / steps as f32).0;
+                a.distance(b) * steps as f32 / path.length()
+            })
+            .collect();
+        let mean = speeds.iter().sum::<f32>() / steps as f32;
+        let variance = speeds.iter().map(|s| (s - mean).powi(2)).sum::<f32>() / steps as f32;
+        assert!((mean - 1.0).abs() < 0.01, "mean speed {mean}");
+        assert!(variance < 1e-3, "speed variance {variance}");
+    }
+
+    #[test]
+    fn looping() {
+        let square = vec![Vec3::ZERO, Vec3::X, Vec3::new(1.0, 0.0, 1.0), Vec3::Z];
+        let path = PathComponent::new(square, Interpolation::Linear, true);
+        assert_eq!(path.segments(), 4);
+        assert!((path.length() - 4.0).abs() < 1e-4);
+        assert_near(path.sample(0.0).0, path.sample(1.0).0);
+
+        let mut follow = PathFollowComponent::new(Entity::default(), 1.0);
+        // 5 units along a 4 units loop
+        assert!(!follow.advance(&path, 5.0));
+        assert!(follow.playing);
+        assert!((follow.t - 0.25).abs() < 1e-4);
+        assert_near(path.sample(follow.t).0, Vec3::X);
+    }
+
+    #[test]
+    fn drawn() {
+        let square = vec![Vec3::ZERO, Vec3::X, Vec3::new(1.0, 0.0, 1.0), Vec3::Z];
+        let path = PathComponent::new(square.clone(), Interpolation::Linear, true);
+        let points = path.curve_points(2);
+        // Through the points and their middles, closed
+        assert_eq!(9, points.len());
+        assert_near(Vec3::new(0.5, 0.0, 0.0), points[1]);
+        assert_near(square[2], points[4]);
+        assert_near(points[0], points[8]);
+
+        let mut world = ecs::World::new();
+        let mut executor = ecs::Executor::new();
+        executor.add_resource(DebugDraw::new());
+        world.spawn((path.clone(),));
+        executor.run_once(&mut world, draw_paths);
+        assert!(executor.get_resource::<DebugDraw>().unwrap().is_empty());
+        world.spawn((PathComponent { debug_draw: true, ..path },));
+        executor.run_once(&mut world, draw_paths);
+        // The 4 segments, and a cross of 3 lines per point
+        let lines = 4 * DRAWN_LINES_PER_SEGMENT + 4 * 3;
+        assert_eq!(lines, executor.get_resource::<DebugDraw>().unwrap().len());
+    }
+
+    #[test]
+    fn looking_at() {
+        let mut world = ecs::World::new();
+        let mut executor = ecs::Executor::new();
+        executor.add_resource(Time::fixed(std::time::Duration::from_millis(250)));
+        executor.add_resource(PathEvents::new());
+        let path = PathComponent::new(vec![Vec3::X * 4.0, Vec3::Z * 4.0], Interpolation::Linear, false);
+        let path = world.spawn((path,));
+        let target = Vec3::new(0.0, 1.0, 0.0);
+        world.spawn((
+            PathFollowComponent {
+                look_at: Some(target),
+                orient_to_tangent: true,
+                ..PathFollowComponent::new(path, 1.0)
+            },
+            TransformsComponent::new(),
+        ));
+        executor.run_once(&mut world, follow_paths);
+        // The target wins over the tangent, and the entity stays upright
+        let transforms = world.query_single::<&TransformsComponent>().unwrap();
+        let forward = transforms.rotation() * Vec3::Z;
+        assert_near((target - transforms.translation()).normalize(), forward);
+        assert!((transforms.rotation() * Vec3::X).y.abs() < 1e-5);
+    }
+
+    #[test]
+    fn completion() {
+        let path = PathComponent::new(vec![Vec3::ZERO, Vec3::X * 2.0], Interpolation::Linear, false);
+        let mut follow = PathFollowComponent::new(Entity::default(), 1.0);
+        assert!(!follow.advance(&path, 1.0));
+        assert!((follow.t - 0.5).abs() < 1e-4);
+        assert!(follow.advance(&path, 1.5));
+        assert_eq!(follow.t, 1.0);
+        assert!(!follow.playing);
+        // Only once
+        assert!(!follow.advance(&path, 1.0));
+        assert_eq!(follow.t, 1.0);
+    }
+}
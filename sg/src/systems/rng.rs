@@ -0,0 +1,73 @@
+//! Seeded randomness of the simulation. Systems that must be reproducible (see `determinism`) get
+//! their random numbers from the GameRng resource, never from `rand::thread_rng` or `random`.
+
+use rand::{RngCore, SeedableRng};
+
+/// SplitMix64 generator, small and fast, with the same sequence on every platform for a seed.
+/// Implements `RngCore`, so it works with the `rand::Rng` methods.
+#[derive(Debug, Clone)]
+pub struct GameRng {
+    state: u64,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+}
+
+impl RngCore for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl SeedableRng for GameRng {
+    type Seed = [u8; 8];
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        Self::new(u64::from_le_bytes(seed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn sequence() {
+        // Reference values of SplitMix64 for seed 0
+        let mut rng = GameRng::new(0);
+        assert_eq!(0xE220A8397B1DCDAF, rng.next_u64());
+        assert_eq!(0x6E789E6AA1B965F4, rng.next_u64());
+
+        let mut a = GameRng::seed_from_u64(7);
+        let mut b = GameRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let x: f32 = a.gen_range(-1.0..1.0);
+            assert_eq!(x.to_bits(), b.gen_range(-1.0f32..1.0).to_bits());
+        }
+        let mut bytes = [0; 5];
+        a.fill_bytes(&mut bytes);
+        assert_eq!(b.next_u64().to_le_bytes()[..5], bytes);
+    }
+}
@@ -0,0 +1,264 @@
+use std::time::{Duration, Instant};
+
+use ecs::{Executor, Schedule, World};
+
+/// Step of the fixed schedule, unless changed with `Time::with_fixed_step`
+pub const DEFAULT_FIXED_STEP: Duration = Duration::from_micros(16_667);
+
+/// Frame timing, updated once per frame by `Time::update` (which should run first in the
+/// schedule, or by `execute_frame`).
+pub struct Time {
+    start: Instant,
+    last: Instant,
+    delta: Duration,
+    frame: u64,
+    /// Fixed step of every frame, instead of the wall clock
+    step: Option<Duration>,
+    /// Time elapsed before `start` (restored clocks)
+    offset: Duration,
+    /// Accumulator of the fixed schedule
+    fixed: FixedStep,
+    /// Steps of the fixed schedule due this frame
+    fixed_steps: u32,
+}
+
+impl Time {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+            delta: Duration::ZERO,
+            frame: 0,
+            step: None,
+            offset: Duration::ZERO,
+            fixed: FixedStep::new(DEFAULT_FIXED_STEP),
+            fixed_steps: 0,
+        }
+    }
+    /// A clock advancing by step every frame whatever the time it took, for simulations that must
+    /// be reproducible
+    pub fn fixed(step: Duration) -> Self {
+        Self {
+            step: Some(step),
+            ..Self::new()
+        }
+    }
+    /// Set the step of the fixed schedule
+    pub fn with_fixed_step(self, step: Duration) -> Self {
+        Self {
+            fixed: FixedStep::new(step),
+            ..self
+        }
+    }
+    /// System advancing the clock to the current frame
+    pub fn update(&mut self) {
+        let now = match self.step {
+            Some(step) => self.last + step,
+            None => Instant::now(),
+        };
+        self.delta = now - self.last;
+        self.last = now;
+        self.frame += 1;
+        self.fixed_steps = self.fixed.advance(self.delta);
+    }
+    /// Time elapsed since the last frame
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+    /// Time elapsed since the last frame, in seconds
+    pub fn delta_secs(&self) -> f32 {
+        self.delta.as_secs_f32()
+    }
+    /// Time elapsed since the creation of the clock, at the start of the frame
+    pub fn elapsed(&self) -> Duration {
+        self.last - self.start + self.offset
+    }
+    /// Number of frames so far
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+    /// Number of times the fixed schedule runs this frame
+    pub fn fixed_steps(&self) -> u32 {
+        self.fixed_steps
+    }
+    pub fn fixed_step(&self) -> Duration {
+        self.fixed.step()
+    }
+    /// How far the frame is from the last fixed step to the next (0 to 1), to interpolate the
+    /// state of the fixed schedule
+    pub fn alpha(&self) -> f32 {
+        self.fixed.alpha()
+    }
+    /// Move the clock as if it had been running for `elapsed` and `frame` frames (loaded games)
+    pub fn restore(&mut self, elapsed: Duration, frame: u64) {
+        self.start = self.last;
+        self.offset = elapsed;
+        self.frame = frame;
+    }
+}
+
+impl Default for Time {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits the frames into steps of a fixed duration, for the simulations that must not depend on
+/// the frame rate
+#[derive(Debug, Clone, Copy)]
+pub struct FixedStep {
+    step: Duration,
+    /// Time not yet simulated
+    accumulator: Duration,
+}
+
+impl FixedStep {
+    /// Most steps run for a frame, so that a long frame doesn't make the next one longer still
+    pub const MAX_STEPS: u32 = 8;
+
+    pub fn new(step: Duration) -> Self {
+        Self {
+            step,
+            accumulator: Duration::ZERO,
+        }
+    }
+    /// Add the duration of a frame, returns the number of steps to run
+    pub fn advance(&mut self, delta: Duration) -> u32 {
+        self.accumulator += delta;
+        let mut steps = 0;
+        while self.accumulator >= self.step && steps < Self::MAX_STEPS {
+            self.accumulator -= self.step;
+            steps += 1;
+        }
+        if steps == Self::MAX_STEPS {
+            self.accumulator = self.accumulator.min(self.step);
+        }
+        steps
+    }
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+    /// Time not yet simulated, in steps
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator.as_secs_f64() / self.step.as_secs_f64()) as f32
+    }
+}
+
+/// Clock of the fixed schedule, the same step every run
+#[derive(Debug, Clone, Copy)]
+pub struct FixedTime {
+    step: Duration,
+    ticks: u64,
+}
+
+impl FixedTime {
+    pub fn new() -> Self {
+        Self {
+            step: DEFAULT_FIXED_STEP,
+            ticks: 0,
+        }
+    }
+    /// System advancing the clock by a step, first in the fixed schedule
+    pub fn advance(&mut self, time: &Time) {
+        self.step = time.fixed_step();
+        self.ticks += 1;
+    }
+    /// Duration of a step
+    pub fn delta(&self) -> Duration {
+        self.step
+    }
+    /// Duration of a step, in seconds
+    pub fn delta_secs(&self) -> f32 {
+        self.step.as_secs_f32()
+    }
+    /// Number of steps so far
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+    /// Simulated time, at the end of the step
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos((self.step.as_nanos() * self.ticks as u128) as u64)
+    }
+}
+
+impl Default for FixedTime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run a frame: advance the `Time`, run `fixed` as many times as fixed steps are due, then `frame`
+/// once. `fixed` should start with `FixedTime::advance`.
+pub fn execute_frame(executor: &mut Executor, world: &mut World, fixed: &Schedule, frame: &Schedule) {
+    let time = executor.get_resource_mut::<Time>().expect("No Time resource");
+    time.update();
+    for _ in 0..time.fixed_steps() {
+        executor.execute(fixed, world);
+    }
+    executor.execute(frame, world);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn fixed_steps() {
+        // 10ms frames, 4ms steps
+        let mut time = Time::fixed(10 * MS).with_fixed_step(4 * MS);
+        let mut steps = Vec::new();
+        let mut alphas = Vec::new();
+        for _ in 0..4 {
+            time.update();
+            steps.push(time.fixed_steps());
+            alphas.push(time.alpha());
+        }
+        assert_eq!(vec![2, 3, 2, 3], steps);
+        for (alpha, expected) in alphas.into_iter().zip([0.5, 0.0, 0.5, 0.0]) {
+            assert!((alpha - expected).abs() < 1e-6, "{alpha} {expected}");
+        }
+
+        // Frames shorter than a step run none most of the time
+        let mut time = Time::fixed(5 * MS).with_fixed_step(DEFAULT_FIXED_STEP);
+        let steps = (0..12).map(|_| {
+            time.update();
+            time.fixed_steps()
+        });
+        assert_eq!(3, steps.sum::<u32>());
+
+        // A long frame doesn't run more than MAX_STEPS
+        let mut time = Time::fixed(Duration::from_secs(1));
+        time.update();
+        assert_eq!(FixedStep::MAX_STEPS, time.fixed_steps());
+        assert!(time.alpha() <= 1.0);
+    }
+
+    #[derive(Default)]
+    struct Steps(Vec<(u64, Duration)>);
+
+    #[test]
+    fn fixed_schedule() {
+        let mut world = World::new();
+        let mut executor = Executor::new();
+        executor.add_resource(Time::fixed(10 * MS).with_fixed_step(4 * MS));
+        executor.add_resource(FixedTime::new());
+        executor.add_resource(Steps::default());
+        executor.add_resource(0u32);
+        let fixed = executor
+            .schedule()
+            .then(FixedTime::advance)
+            .then(|time: &FixedTime, steps: &mut Steps| steps.0.push((time.ticks(), time.delta())))
+            .build();
+        let frame = executor.schedule().then(|frames: &mut u32| *frames += 1).build();
+        for _ in 0..3 {
+            execute_frame(&mut executor, &mut world, &fixed, &frame);
+        }
+        assert_eq!(3, *executor.get_resource::<u32>().unwrap());
+        let steps = &executor.get_resource::<Steps>().unwrap().0;
+        assert_eq!((1..=7).map(|tick| (tick, 4 * MS)).collect::<Vec<_>>(), *steps);
+        assert_eq!(28 * MS, executor.get_resource::<FixedTime>().unwrap().elapsed());
+    }
+}
@@ -0,0 +1,745 @@
+//! Weather: rain and snow, with the surfaces getting wet and puddles forming on the ground.
+//!
+//! The `Weather` resource eases between the current weather and the one of its settings over
+//! `transition_seconds`, and drives from there the precipitation emitters (entities with a
+//! `PrecipitationComponent`, spawning in a volume that follows the camera), the wetness and
+//! puddles of the shading pass, the fog and exposure of storms, and a rain loop through the
+//! `Mixer`.
+//!
+//! The particles only collide with the ground plane at `ground_height`, not with the depth buffer.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use ecs::Entities;
+use glam::{Vec2, Vec3, Vec4};
+use serde::{Deserialize, Serialize};
+
+use crate::components::{ParticleEmitterComponent, TransformsComponent};
+use crate::localization::Localization;
+use crate::systems::audio::{AudioCommand, Mixer};
+use crate::systems::graphics::{
+    focus::UiFocus, g_buffer::SurfaceWeather, particles::EmitterParams, renderer::WorldRenderer,
+};
+use crate::systems::time::Time;
+use crate::tr;
+
+/// Name of the settings file, in the resources directory
+const SETTINGS_FILE: &str = "weather.json";
+/// Name of the rain loop given to the mixer
+pub const RAIN_SOUND: &str = "rain_loop";
+/// Falling speed of the rain drops and the snowflakes, in meters per second
+const RAIN_SPEED: f32 = 9.0;
+const SNOW_SPEED: f32 = 1.2;
+
+/// Serde for glam vectors, as arrays
+mod vec3 {
+    use glam::Vec3;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Vec3, serializer: S) -> Result<S::Ok, S::Error> {
+        value.to_array().serialize(serializer)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec3, D::Error> {
+        <[f32; 3]>::deserialize(deserializer).map(Vec3::from)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WeatherKind {
+    Clear,
+    /// Intensity from 0 to 1
+    Rain {
+        intensity: f32,
+    },
+    Snow {
+        intensity: f32,
+    },
+}
+
+impl WeatherKind {
+    pub fn precipitation(self) -> Precipitation {
+        match self {
+            Self::Clear => Precipitation::default(),
+            Self::Rain { intensity } => Precipitation {
+                rain: intensity.clamp(0.0, 1.0),
+                snow: 0.0,
+            },
+            Self::Snow { intensity } => Precipitation {
+                rain: 0.0,
+                snow: intensity.clamp(0.0, 1.0),
+            },
+        }
+    }
+}
+
+/// Intensities of the rain and the snow, both eased separately so rain can turn into snow
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Precipitation {
+    pub rain: f32,
+    pub snow: f32,
+}
+
+impl Precipitation {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        Self {
+            rain: self.rain + (other.rain - self.rain) * t,
+            snow: self.snow + (other.snow - self.snow) * t,
+        }
+    }
+    /// How stormy it is, for the fog and the exposure
+    pub fn storm(self) -> f32 {
+        self.rain.max(self.snow)
+    }
+}
+
+/// Eases from a state to another. Changing the target mid-transition restarts from the current
+/// state, so the weather never jumps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transition {
+    from: Precipitation,
+    to: Precipitation,
+    elapsed: f32,
+    duration: f32,
+}
+
+impl Transition {
+    /// Settled on a state
+    pub fn new(state: Precipitation) -> Self {
+        Self {
+            from: state,
+            to: state,
+            elapsed: 0.0,
+            duration: 0.0,
+        }
+    }
+    pub fn current(&self) -> Precipitation {
+        let t = if self.elapsed >= self.duration {
+            1.0
+        } else {
+            self.elapsed / self.duration
+        };
+        self.from.lerp(self.to, t * t * (3.0 - 2.0 * t))
+    }
+    pub fn target(&self) -> Precipitation {
+        self.to
+    }
+    /// Start easing toward `to` over `duration` seconds, does nothing if it already is the target
+    pub fn retarget(&mut self, to: Precipitation, duration: f32) {
+        if to != self.to {
+            *self = Self {
+                from: self.current(),
+                to,
+                elapsed: 0.0,
+                duration,
+            };
+        }
+    }
+    pub fn advance(&mut self, delta: f32) {
+        self.elapsed += delta;
+    }
+}
+
+/// Wetness and puddles of the surfaces, from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Surfaces {
+    pub wetness: f32,
+    pub puddles: f32,
+}
+
+impl Surfaces {
+    /// Advance by `delta` seconds of rain at an intensity. The surfaces get wet first, then the
+    /// puddles grow on the soaked ground, and both dry once the rain stops.
+    pub fn update(&mut self, rain: f32, delta: f32, settings: &WeatherSettings) {
+        if rain > 0.0 {
+            let soaked = self.wetness >= 1.0;
+            self.wetness += rain * delta / settings.wetting_seconds.max(f32::EPSILON);
+            if soaked {
+                self.puddles += rain * delta / settings.puddle_seconds.max(f32::EPSILON);
+            }
+        } else {
+            let dried = delta / settings.drying_seconds.max(f32::EPSILON);
+            self.wetness -= dried;
+            self.puddles -= dried;
+        }
+        self.wetness = self.wetness.clamp(0.0, 1.0);
+        // Puddles don't outlast the wetness
+        self.puddles = self.puddles.clamp(0.0, self.wetness);
+    }
+}
+
+/// Smooth noise in [0, 1] deciding where puddles form first, mirrors lighting.wgsl
+pub fn puddle_noise(p: Vec2) -> f32 {
+    let a = (p.x * 0.73 + (p.y * 0.41).sin() * 2.0).sin();
+    let b = (p.y * 0.67 + (p.x * 0.37).sin() * 2.0).sin();
+    0.5 + 0.25 * (a + b)
+}
+
+fn smoothstep(low: f32, high: f32, x: f32) -> f32 {
+    let t = ((x - low) / (high - low)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Puddle coverage of a surface at `p` (its xz position), mirrors lighting.wgsl
+pub fn puddle_mask(p: Vec2, normal_y: f32, puddles: f32, threshold: f32) -> f32 {
+    let facing = smoothstep(threshold, 1.0, normal_y);
+    let fill = puddles * 1.2 - 0.2;
+    facing * (1.0 - smoothstep(fill - 0.1, fill + 0.1, puddle_noise(p)))
+}
+
+/// Center of the spawn volume: it stays put until the camera gets within `margin` of its side,
+/// then jumps back above the camera. The spawns stay still while the camera sways, and the
+/// volume never lags behind a fast camera.
+pub fn follow(center: Vec3, camera: Vec3, half_size: Vec3, margin: f32) -> Vec3 {
+    let reach = (Vec2::new(half_size.x, half_size.z) - Vec2::splat(margin)).max(Vec2::ZERO);
+    let offset = Vec2::new(camera.x - center.x, camera.z - center.z);
+    let (x, z) = if offset.abs().cmpgt(reach).any() {
+        (camera.x, camera.z)
+    } else {
+        (center.x, center.z)
+    };
+    // Always right above the camera
+    Vec3::new(x, camera.y + half_size.y, z)
+}
+
+/// Starts and stops the rain loop, with hysteresis so a light drizzle doesn't toggle it every
+/// frame, and follows its volume without a command per frame.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RainAudio {
+    playing: bool,
+    /// Volume last sent
+    volume: f32,
+    /// Volume asked for by the last update
+    last: f32,
+}
+
+impl RainAudio {
+    /// Volume the loop starts at
+    pub const START: f32 = 0.05;
+    /// Volume the loop stops under
+    pub const STOP: f32 = 0.02;
+    /// Change of volume worth a command, smaller ones are sent once the volume settles
+    pub const STEP: f32 = 0.05;
+
+    pub fn playing(&self) -> bool {
+        self.playing
+    }
+    pub fn update(&mut self, volume: f32) -> Option<AudioCommand> {
+        let settled = volume == self.last;
+        self.last = volume;
+        let command = if !self.playing {
+            if volume < Self::START {
+                return None;
+            }
+            self.playing = true;
+            AudioCommand::Play {
+                sound: RAIN_SOUND.into(),
+                volume,
+            }
+        } else if volume < Self::STOP {
+            self.playing = false;
+            AudioCommand::Stop {
+                sound: RAIN_SOUND.into(),
+            }
+        } else if (volume - self.volume).abs() >= Self::STEP || settled && volume != self.volume {
+            AudioCommand::SetVolume {
+                sound: RAIN_SOUND.into(),
+                volume,
+            }
+        } else {
+            return None;
+        };
+        self.volume = volume;
+        Some(command)
+    }
+}
+
+/// An emitter of the weather's particles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrecipitationComponent {
+    Rain,
+    Snow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WeatherSettings {
+    pub kind: WeatherKind,
+    /// Added to the velocity of the particles
+    #[serde(with = "vec3")]
+    pub wind: Vec3,
+    /// Time to ease into a new weather, in seconds
+    pub transition_seconds: f32,
+    /// Height of the ground the particles die on
+    pub ground_height: f32,
+    /// Half size of the volume the particles spawn in, above the camera
+    #[serde(with = "vec3")]
+    pub volume: Vec3,
+    /// Distance to the side of the volume the camera can go to before it is moved
+    pub follow_margin: f32,
+    /// Particles of each emitter at full intensity
+    pub particles: u32,
+    /// Seconds of full rain to soak the surfaces
+    pub wetting_seconds: f32,
+    /// Seconds of full rain for the puddles to cover the soaked ground
+    pub puddle_seconds: f32,
+    /// Seconds to dry from soaked
+    pub drying_seconds: f32,
+    /// Lowest normal y of a surface puddles form on
+    pub puddle_threshold: f32,
+    /// Fog density multiplier at full intensity
+    pub storm_fog: f32,
+    /// Exposure at full intensity
+    pub storm_exposure: f32,
+    /// Volume of the rain loop at full intensity
+    pub rain_volume: f32,
+}
+
+impl Default for WeatherSettings {
+    fn default() -> Self {
+        Self {
+            kind: WeatherKind::Clear,
+            wind: Vec3::new(1.0, 0.0, 0.5),
+            transition_seconds: 5.0,
+            ground_height: 0.0,
+            volume: Vec3::new(15.0, 5.0, 15.0),
+            follow_margin: 5.0,
+            particles: 8192,
+            wetting_seconds: 20.0,
+            puddle_seconds: 60.0,
+            drying_seconds: 90.0,
+            puddle_threshold: 0.9,
+            storm_fog: 3.0,
+            storm_exposure: 0.7,
+            rain_volume: 0.8,
+        }
+    }
+}
+
+impl WeatherSettings {
+    /// Where the settings are saved
+    pub fn path() -> PathBuf {
+        rmanage::instance().directory().join(SETTINGS_FILE)
+    }
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            std::fs::File::open(path).with_context(|| format!("can't open {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("{} isn't a weather settings file", path.display()))
+    }
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("can't create {}", path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+    /// Particles spawned by an emitter at full intensity
+    fn emitter(&self, kind: PrecipitationComponent, center: Vec3) -> EmitterParams {
+        let (speed, size, color, spread) = match kind {
+            PrecipitationComponent::Rain => {
+                (RAIN_SPEED, 0.03, Vec4::new(0.7, 0.75, 0.85, 0.6), 0.2)
+            }
+            PrecipitationComponent::Snow => (SNOW_SPEED, 0.06, Vec4::ONE, 0.4),
+        };
+        // Enough to fall from the top of the volume to the ground
+        let height = (center.y + self.volume.y - self.ground_height).max(1.0);
+        let lifetime = height / speed * 1.2;
+        EmitterParams {
+            capacity: self.particles,
+            rate: self.particles as f32 / lifetime,
+            velocity: self.wind - Vec3::Y * speed,
+            spread,
+            gravity: Vec3::ZERO,
+            lifetime,
+            size,
+            color,
+            seed: kind as u32,
+            volume: self.volume,
+            kill_height: self.ground_height,
+        }
+    }
+}
+
+/// The weather, a resource updated by `Weather::update`
+pub struct Weather {
+    pub settings: WeatherSettings,
+    transition: Transition,
+    surfaces: Surfaces,
+    audio: RainAudio,
+    /// Center of the spawn volume
+    center: Option<Vec3>,
+}
+
+impl Weather {
+    pub fn new(settings: WeatherSettings) -> Self {
+        Self {
+            transition: Transition::new(settings.kind.precipitation()),
+            settings,
+            surfaces: Surfaces::default(),
+            audio: RainAudio::default(),
+            center: None,
+        }
+    }
+    /// The saved settings, or the default ones if there are none
+    pub fn load() -> Self {
+        let path = WeatherSettings::path();
+        let settings = if path.exists() {
+            WeatherSettings::load(&path).unwrap_or_else(|e| {
+                log::warn!("Couldn't load the weather settings: {e:#}");
+                WeatherSettings::default()
+            })
+        } else {
+            WeatherSettings::default()
+        };
+        Self::new(settings)
+    }
+    pub fn precipitation(&self) -> Precipitation {
+        self.transition.current()
+    }
+    pub fn surfaces(&self) -> Surfaces {
+        self.surfaces
+    }
+
+    /// System easing the weather and applying it to the renderer and the emitters
+    pub fn update(
+        &mut self,
+        time: &Time,
+        wr: &mut WorldRenderer,
+        mixer: &mut Mixer,
+        emitters: Entities<(
+            &PrecipitationComponent,
+            &mut ParticleEmitterComponent,
+            &mut TransformsComponent,
+        )>,
+    ) {
+        let s = &self.settings;
+        let delta = time.delta_secs();
+        self.transition
+            .retarget(s.kind.precipitation(), s.transition_seconds);
+        self.transition.advance(delta);
+        let precipitation = self.transition.current();
+        self.surfaces.update(precipitation.rain, delta, s);
+
+        let storm = precipitation.storm();
+        wr.fog.set_density_scale(1.0 + (s.storm_fog - 1.0) * storm);
+        wr.surface_weather = SurfaceWeather {
+            wetness: self.surfaces.wetness,
+            puddles: self.surfaces.puddles,
+            puddle_threshold: s.puddle_threshold,
+            exposure: 1.0 + (s.storm_exposure - 1.0) * storm,
+        };
+
+        let camera = wr.camera.get_position();
+        let center = follow(
+            self.center.unwrap_or(camera),
+            camera,
+            s.volume,
+            s.follow_margin,
+        );
+        self.center = Some(center);
+        for (&kind, emitter, tsm) in emitters {
+            let intensity = match kind {
+                PrecipitationComponent::Rain => precipitation.rain,
+                PrecipitationComponent::Snow => precipitation.snow,
+            };
+            let mut params = s.emitter(kind, center);
+            params.rate *= intensity;
+            emitter.params = params;
+            // Upwind, so the particles drift over the camera
+            let drift = s.wind * (center.y - s.ground_height).max(0.0) / -params.velocity.y;
+            tsm.set_translation(center - drift * Vec3::new(0.5, 0.0, 0.5));
+        }
+
+        if let Some(command) = self.audio.update(precipitation.rain * s.rain_volume) {
+            mixer.push(command);
+        }
+    }
+
+    /// Draw the settings window
+    pub fn ui(&mut self, ctx: &egui::Context, focus: &mut UiFocus, loc: &mut Localization) {
+        let s = &mut self.settings;
+        focus.begin_panel("weather");
+        egui::Window::new(tr!(loc, "weather.window")).show(ctx, |ui| {
+            let (mut kind, mut intensity) = match s.kind {
+                WeatherKind::Clear => (0, 0.5),
+                WeatherKind::Rain { intensity } => (1, intensity),
+                WeatherKind::Snow { intensity } => (2, intensity),
+            };
+            ui.horizontal(|ui| {
+                focus.track(ui.radio_value(&mut kind, 0, tr!(loc, "weather.clear")));
+                focus.track(ui.radio_value(&mut kind, 1, tr!(loc, "weather.rain")));
+                focus.track(ui.radio_value(&mut kind, 2, tr!(loc, "weather.snow")));
+            });
+            focus.track(ui.add(
+                egui::Slider::new(&mut intensity, 0.0..=1.0).text(tr!(loc, "weather.intensity")),
+            ));
+            s.kind = match kind {
+                0 => WeatherKind::Clear,
+                1 => WeatherKind::Rain { intensity },
+                _ => WeatherKind::Snow { intensity },
+            };
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.transition_seconds, 0.0..=60.0)
+                        .text(tr!(loc, "weather.transition")),
+                ),
+            );
+            focus.track(ui.add(
+                egui::Slider::new(&mut s.wind.x, -10.0..=10.0).text(tr!(loc, "weather.wind_x")),
+            ));
+            focus.track(ui.add(
+                egui::Slider::new(&mut s.wind.z, -10.0..=10.0).text(tr!(loc, "weather.wind_z")),
+            ));
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.ground_height, -20.0..=20.0)
+                        .text(tr!(loc, "weather.ground_height")),
+                ),
+            );
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.particles, 256..=65536)
+                        .logarithmic(true)
+                        .text(tr!(loc, "weather.particles")),
+                ),
+            );
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.wetting_seconds, 1.0..=300.0)
+                        .text(tr!(loc, "weather.wetting")),
+                ),
+            );
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.puddle_seconds, 1.0..=600.0)
+                        .text(tr!(loc, "weather.puddling")),
+                ),
+            );
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.drying_seconds, 1.0..=600.0)
+                        .text(tr!(loc, "weather.drying")),
+                ),
+            );
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.puddle_threshold, 0.0..=0.99)
+                        .text(tr!(loc, "weather.puddle_threshold")),
+                ),
+            );
+            focus.track(ui.add(
+                egui::Slider::new(&mut s.storm_fog, 1.0..=10.0).text(tr!(loc, "weather.storm_fog")),
+            ));
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.storm_exposure, 0.1..=1.0)
+                        .text(tr!(loc, "weather.storm_exposure")),
+                ),
+            );
+            focus.track(
+                ui.add(
+                    egui::Slider::new(&mut s.rain_volume, 0.0..=1.0)
+                        .text(tr!(loc, "weather.rain_volume")),
+                ),
+            );
+            ui.label(tr!(
+                loc,
+                "weather.surfaces",
+                wetness = format!("{:.0}", self.surfaces.wetness * 100.0),
+                puddles = format!("{:.0}", self.surfaces.puddles * 100.0),
+            ));
+            if focus.track(ui.button(tr!(loc, "weather.save"))).clicked() {
+                let path = WeatherSettings::path();
+                match s.save(&path) {
+                    Ok(()) => log::info!("Saved the weather settings to {}", path.display()),
+                    Err(e) => log::error!("Couldn't save the weather settings: {e:#}"),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rain(rain: f32) -> Precipitation {
+        Precipitation { rain, snow: 0.0 }
+    }
+
+    #[test]
+    fn transitions() {
+        let mut transition = Transition::new(rain(0.0));
+        transition.retarget(rain(1.0), 4.0);
+        let mut previous = transition.current();
+        let mut max_step: f32 = 0.0;
+        for frame in 1..=300 {
+            // Switch to snow half way, then back to clear
+            match frame {
+                100 => {
+                    transition.retarget(WeatherKind::Snow { intensity: 1.0 }.precipitation(), 4.0)
+                }
+                180 => transition.retarget(WeatherKind::Clear.precipitation(), 2.0),
+                _ => (),
+            }
+            // Retargeting alone doesn't move the weather
+            assert_eq!(previous, transition.current());
+            transition.advance(1.0 / 60.0);
+            let current = transition.current();
+            max_step = max_step
+                .max((current.rain - previous.rain).abs())
+                .max((current.snow - previous.snow).abs());
+            previous = current;
+        }
+        // Eased over seconds, at most 1.5 / duration per second
+        assert!(max_step < 1.5 / 2.0 / 60.0 + 1e-4, "{max_step}");
+        assert_eq!(Precipitation::default(), previous);
+
+        // Same target, the transition goes on
+        let mut transition = Transition::new(rain(0.0));
+        transition.retarget(rain(1.0), 2.0);
+        transition.advance(1.0);
+        transition.retarget(rain(1.0), 2.0);
+        assert_eq!(rain(0.5), transition.current());
+        transition.advance(1.0);
+        assert_eq!(rain(1.0), transition.current());
+        // Instant
+        transition.retarget(rain(0.25), 0.0);
+        assert_eq!(rain(0.25), transition.current());
+    }
+
+    #[test]
+    fn wetness_and_puddles() {
+        let settings = WeatherSettings {
+            wetting_seconds: 10.0,
+            puddle_seconds: 20.0,
+            drying_seconds: 40.0,
+            ..Default::default()
+        };
+        let mut surfaces = Surfaces::default();
+        let run = |surfaces: &mut Surfaces, rain, seconds| {
+            for _ in 0..seconds * 10 {
+                surfaces.update(rain, 0.1, &settings);
+            }
+        };
+        // Half the rain, twice as long to soak
+        run(&mut surfaces, 0.5, 10);
+        assert!((surfaces.wetness - 0.5).abs() < 1e-4);
+        assert_eq!(0.0, surfaces.puddles);
+        run(&mut surfaces, 1.0, 5);
+        assert!(surfaces.wetness > 0.999);
+        // Puddles once soaked (a step late)
+        run(&mut surfaces, 1.0, 10);
+        assert!((surfaces.puddles - 0.5).abs() < 0.01, "{surfaces:?}");
+        run(&mut surfaces, 1.0, 30);
+        assert_eq!(
+            Surfaces {
+                wetness: 1.0,
+                puddles: 1.0
+            },
+            surfaces
+        );
+        // Drying
+        run(&mut surfaces, 0.0, 20);
+        assert!((surfaces.wetness - 0.5).abs() < 1e-4);
+        assert!(surfaces.puddles <= surfaces.wetness);
+        run(&mut surfaces, 0.0, 40);
+        assert_eq!(Surfaces::default(), surfaces);
+    }
+
+    #[test]
+    fn puddle_coverage() {
+        let points = (0..40)
+            .flat_map(|x| (0..40).map(move |z| Vec2::new(x as f32, z as f32) * 0.7))
+            .collect::<Vec<_>>();
+        let coverage = |puddles, normal_y| {
+            points
+                .iter()
+                .map(|&p| puddle_mask(p, normal_y, puddles, 0.9))
+                .sum::<f32>()
+                / points.len() as f32
+        };
+        assert_eq!(0.0, coverage(0.0, 1.0));
+        assert!(coverage(1.0, 1.0) > 0.95);
+        // Walls stay dry
+        assert_eq!(0.0, coverage(1.0, 0.5));
+        // Grows with the puddles, everywhere
+        let mut previous = vec![0.0; points.len()];
+        for step in 1..=20 {
+            let puddles = step as f32 / 20.0;
+            for (p, previous) in points.iter().zip(&mut previous) {
+                let mask = puddle_mask(*p, 1.0, puddles, 0.9);
+                assert!(mask >= *previous);
+                *previous = mask;
+            }
+        }
+    }
+
+    #[test]
+    fn spawn_volume() {
+        let half = Vec3::new(10.0, 5.0, 10.0);
+        let center = follow(Vec3::ZERO, Vec3::new(3.0, 2.0, -4.0), half, 4.0);
+        // Inside the inner region: only the height follows
+        assert_eq!(Vec3::new(0.0, 7.0, 0.0), center);
+        let center = follow(center, Vec3::new(5.9, 0.0, -5.9), half, 4.0);
+        assert_eq!(Vec3::new(0.0, 5.0, 0.0), center);
+        // Past it on one axis, recentered on both
+        let center = follow(center, Vec3::new(6.5, 0.0, 1.0), half, 4.0);
+        assert_eq!(Vec3::new(6.5, 5.0, 1.0), center);
+        // A teleport
+        let center = follow(center, Vec3::new(-100.0, 1.0, 50.0), half, 4.0);
+        assert_eq!(Vec3::new(-100.0, 6.0, 50.0), center);
+        // A margin wider than the volume follows every move
+        let center = follow(center, Vec3::new(-99.9, 1.0, 50.0), half, 20.0);
+        assert_eq!(Vec3::new(-99.9, 6.0, 50.0), center);
+    }
+
+    #[test]
+    fn rain_audio() {
+        let mut audio = RainAudio::default();
+        let play = |volume| AudioCommand::Play {
+            sound: RAIN_SOUND.into(),
+            volume,
+        };
+        let set = |volume| AudioCommand::SetVolume {
+            sound: RAIN_SOUND.into(),
+            volume,
+        };
+        let stop = AudioCommand::Stop {
+            sound: RAIN_SOUND.into(),
+        };
+
+        assert_eq!(None, audio.update(0.0));
+        assert_eq!(None, audio.update(0.04));
+        assert_eq!(Some(play(0.06)), audio.update(0.06));
+        // Small changes wait for a step, or for the volume to settle
+        assert_eq!(None, audio.update(0.08));
+        assert_eq!(Some(set(0.12)), audio.update(0.12));
+        assert_eq!(None, audio.update(0.13));
+        assert_eq!(Some(set(0.13)), audio.update(0.13));
+        assert_eq!(None, audio.update(0.13));
+        // Hysteresis, no stop between the thresholds
+        assert_eq!(Some(set(0.03)), audio.update(0.03));
+        assert_eq!(None, audio.update(0.03));
+        assert!(audio.playing());
+        assert_eq!(Some(stop), audio.update(0.01));
+        assert_eq!(None, audio.update(0.03));
+        assert!(!audio.playing());
+        assert_eq!(Some(play(0.5)), audio.update(0.5));
+    }
+
+    #[test]
+    fn settings_roundtrip() {
+        let settings = WeatherSettings {
+            kind: WeatherKind::Snow { intensity: 0.3 },
+            wind: Vec3::new(1.0, 2.0, 3.0),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&settings).unwrap();
+        assert_eq!(settings, serde_json::from_str(&json).unwrap());
+        // Missing fields are defaulted
+        let partial: WeatherSettings = serde_json::from_str(r#"{"kind": "clear"}"#).unwrap();
+        assert_eq!(WeatherSettings::default(), partial);
+    }
+}
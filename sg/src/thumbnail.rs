@@ -0,0 +1,177 @@
+//! Thumbnails of models: renders a glTF model headless (see `GraphicContext::new_headless`), lit
+//! by a sun and framed by its bounds, and saves the image. Run by the `render_thumbnail` example.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use ecs::{Entities, Entity, Executor, World};
+use glam::{Mat4, Quat, Vec3, Vec4};
+
+use crate::{
+    components::{GraphicsComponent, LightComponent, TransformsComponent},
+    systems::graphics::{
+        gltf, mesh_manager::BoundingBox, renderer::WorldRenderer, DiretionalLight, GraphicContext,
+        Light,
+    },
+};
+
+/// Default width and height of the thumbnails
+pub const SIZE: u32 = 256;
+const USAGE: &str = "usage: render_thumbnail <model.glb> <out.png> [--size N]";
+/// Direction the model is seen from: in front, a bit above and to the side
+const VIEW: [f32; 3] = [-0.5, 0.4, -1.0];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThumbnailArgs {
+    pub model: PathBuf,
+    pub out: PathBuf,
+    pub size: u32,
+}
+
+impl ThumbnailArgs {
+    /// Parse the arguments, without the program name
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut paths = Vec::new();
+        let mut size = SIZE;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--size" => {
+                    size = args
+                        .next()
+                        .and_then(|s| s.parse().ok())
+                        .filter(|s| *s > 0)
+                        .context("--size needs a positive number")?;
+                }
+                flag if flag.starts_with("--") => bail!("Unknown option {flag}\n{USAGE}"),
+                path => paths.push(PathBuf::from(path)),
+            }
+        }
+        match <[PathBuf; 2]>::try_from(paths) {
+            Ok([model, out]) => Ok(Self { model, out, size }),
+            Err(_) => bail!("{USAGE}"),
+        }
+    }
+}
+
+/// Where the camera goes to see all of `bounds` with a vertical field of view of `fov` (and a
+/// square aspect): the eye, and the near and far planes
+fn framing(bounds: BoundingBox, fov: f32) -> (Vec3, f32, f32) {
+    let center = (bounds.min + bounds.max) / 2.0;
+    let radius = ((bounds.max - bounds.min).length() / 2.0).max(1e-3);
+    // The bounding sphere touches the sides of the view
+    let distance = radius / (fov / 2.0).sin();
+    let eye = center + Vec3::from(VIEW).normalize() * distance;
+    (eye, (distance - radius).max(distance * 0.01), distance + radius)
+}
+
+/// Render the thumbnail of a glTF model
+pub fn render(model: &Path, size: u32) -> Result<image::RgbaImage> {
+    let mut gfx = pollster::block_on(GraphicContext::new_headless(size, size))?;
+    let objects = gltf::open(model, &mut gfx)
+        .with_context(|| format!("Couldn't open {}", model.display()))?;
+    if objects.is_empty() {
+        bail!("{} has no meshes", model.display());
+    }
+    let bounds = BoundingBox::from_points(objects.iter().flat_map(|(graphics, transforms)| {
+        let bounds = gfx.mesh_manager.get(graphics.mesh).map(|mesh| mesh.bounds);
+        bounds.map(|b| b.transform(transforms.mat()).corners()).into_iter().flatten()
+    }));
+
+    let mut world = World::new();
+    world.spawn_many(objects);
+    world.spawn((LightComponent::new(Light::Directional(DiretionalLight::new(
+        Vec3::new(0.4, -1.0, 0.6).normalize(),
+        Vec4::splat(1.0),
+    ))),));
+
+    let mut wr = WorldRenderer::new(&mut gfx);
+    let center = (bounds.min + bounds.max) / 2.0;
+    let (eye, near, far) = framing(bounds, wr.camera.get_fov());
+    let look = Mat4::look_at_lh(eye, center, Vec3::Y);
+    wr.camera.set_position(eye);
+    wr.camera.set_rotation(Quat::from_mat4(&look).inverse());
+    wr.camera.set_aspect(1.0);
+    wr.camera.set_near(near);
+    wr.camera.set_far(far);
+
+    let mut executor = Executor::new();
+    executor.add_resource(gfx);
+    executor.add_resource(wr);
+    executor.add_resource(None::<Result<Vec<u8>>>);
+    executor.run_once(&mut world, WorldRenderer::update_lights);
+    executor.run_once(
+        &mut world,
+        |gfx: &mut GraphicContext,
+         wr: &mut WorldRenderer,
+         frame: &mut Option<Result<Vec<u8>>>,
+         renderables: Entities<(Entity, &GraphicsComponent, Option<&TransformsComponent>)>| {
+            *frame = Some(gfx.render_offscreen(wr, renderables));
+        },
+    );
+    let pixels = executor
+        .get_resource_mut::<Option<Result<Vec<u8>>>>()
+        .unwrap()
+        .take()
+        .context("No frame rendered")??;
+    image::RgbaImage::from_raw(size, size, pixels).context("Pixels of the wrong size")
+}
+
+/// Run the command, returns the exit code
+pub fn main(args: &[String]) -> i32 {
+    let result = ThumbnailArgs::parse(args).and_then(|args| {
+        let image = render(&args.model, args.size)?;
+        image
+            .save(&args.out)
+            .with_context(|| format!("Couldn't write {}", args.out.display()))
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{e:#}");
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<ThumbnailArgs> {
+        ThumbnailArgs::parse(&line.split_whitespace().map(String::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn arguments() {
+        let parsed = args("model.glb out.png").unwrap();
+        assert_eq!(PathBuf::from("model.glb"), parsed.model);
+        assert_eq!(PathBuf::from("out.png"), parsed.out);
+        assert_eq!(SIZE, parsed.size);
+        assert_eq!(64, args("model.glb --size 64 out.png").unwrap().size);
+        assert!(args("model.glb").is_err());
+        assert!(args("a.glb b.png c.png").is_err());
+        assert!(args("a.glb b.png --size 0").is_err());
+        assert!(args("a.glb b.png --size").is_err());
+        assert!(args("a.glb b.png --sise 64").is_err());
+    }
+
+    #[test]
+    fn framed() {
+        let bounds = BoundingBox {
+            min: Vec3::new(1.0, 0.0, -2.0),
+            max: Vec3::new(3.0, 4.0, 2.0),
+        };
+        let fov = std::f32::consts::FRAC_PI_2;
+        let (eye, near, far) = framing(bounds, fov);
+        let center = Vec3::new(2.0, 2.0, 0.0);
+        let radius = 3.0;
+        let distance = eye.distance(center);
+        // The bounding sphere fits the view, between the planes
+        assert!((radius / distance - (fov / 2.0).sin()).abs() < 1e-5);
+        assert!(near <= distance - radius + 1e-4 && near > 0.0);
+        assert!((far - distance - radius).abs() < 1e-4);
+        // Seen from the front
+        assert!(eye.z < bounds.min.z);
+    }
+}